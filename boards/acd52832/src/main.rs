@@ -109,6 +109,9 @@ pub unsafe fn reset_handler() {
     // Loads relocations and clears BSS
     nrf52832::init();
 
+    // Mark the base of the kernel stack so that an overflow is detected.
+    kernel::debug::set_stack_canary(STACK_MEMORY.as_mut_ptr());
+
     // Create capabilities that the board needs to call certain protected kernel
     // functions.
     let process_management_capability =
@@ -84,6 +84,9 @@ pub unsafe fn reset_handler() {
     // Basic setup of the platform.
     rv32i::init_memory();
 
+    // Mark the base of the kernel stack so that an overflow is detected.
+    kernel::debug::set_stack_canary(STACK_MEMORY.as_mut_ptr());
+
     let chip = static_init!(
         arty_e21_chip::chip::ArtyExx,
         arty_e21_chip::chip::ArtyExx::new()
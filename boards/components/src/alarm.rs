@@ -1,8 +1,9 @@
 //! Components for hardware timer Alarms.
 //!
-//! This provides two components, `AlarmMuxComponent`, which provides a
-//! multiplexed interface to a hardware alarm, and `AlarmDriverComponent`,
-//! which provides an alarm system call interface.
+//! This provides three components, `AlarmMuxComponent`, which provides a
+//! multiplexed interface to a hardware alarm, `AlarmWheelMuxComponent`, which
+//! provides the same with a timing wheel for boards with many alarms, and
+//! `AlarmDriverComponent`, which provides an alarm system call interface.
 //!
 //! Usage
 //! -----
@@ -13,6 +14,9 @@
 //! ast.configure(mux_alarm);
 //! let alarm = components::alarm::AlarmDriverComponent::new(board_kernel, mux_alarm)
 //!     .finalize(components::alarm_component_helper!(sam4l::ast::Ast));
+//!
+//! let mux_alarm_wheel = components::alarm::AlarmWheelMuxComponent::new(ast, 4)
+//!     .finalize(components::alarm_wheel_mux_component_helper!(sam4l::ast::Ast));
 //! ```
 
 // Author: Philip Levis <pal@cs.stanford.edu>
@@ -22,6 +26,7 @@ use core::mem::MaybeUninit;
 
 use capsules::alarm::AlarmDriver;
 use capsules::virtual_alarm::{MuxAlarm, VirtualMuxAlarm};
+use capsules::virtual_alarm_wheel::MuxAlarmWheel;
 use kernel::capabilities;
 use kernel::component::Component;
 use kernel::create_capability;
@@ -39,6 +44,17 @@ macro_rules! alarm_mux_component_helper {
     };};
 }
 
+// Setup static space for the objects.
+#[macro_export]
+macro_rules! alarm_wheel_mux_component_helper {
+    ($A:ty) => {{
+        use capsules::virtual_alarm_wheel::MuxAlarmWheel;
+        use core::mem::MaybeUninit;
+        static mut BUF: MaybeUninit<MuxAlarmWheel<'static, $A>> = MaybeUninit::uninit();
+        &mut BUF
+    };};
+}
+
 // Setup static space for the objects.
 #[macro_export]
 macro_rules! alarm_component_helper {
@@ -79,6 +95,37 @@ impl<A: 'static + time::Alarm<'static>> Component for AlarmMuxComponent<A> {
     }
 }
 
+pub struct AlarmWheelMuxComponent<A: 'static + time::Alarm<'static>> {
+    alarm: &'static A,
+    resolution: u32,
+}
+
+impl<A: 'static + time::Alarm<'static>> AlarmWheelMuxComponent<A> {
+    /// A slot of the first wheel spans `2^resolution` ticks of `alarm`.
+    pub fn new(alarm: &'static A, resolution: u32) -> AlarmWheelMuxComponent<A> {
+        AlarmWheelMuxComponent {
+            alarm: alarm,
+            resolution: resolution,
+        }
+    }
+}
+
+impl<A: 'static + time::Alarm<'static>> Component for AlarmWheelMuxComponent<A> {
+    type StaticInput = &'static mut MaybeUninit<MuxAlarmWheel<'static, A>>;
+    type Output = &'static MuxAlarmWheel<'static, A>;
+
+    unsafe fn finalize(self, static_buffer: Self::StaticInput) -> Self::Output {
+        let mux_alarm = static_init_half!(
+            static_buffer,
+            MuxAlarmWheel<'static, A>,
+            MuxAlarmWheel::new(self.alarm, self.resolution)
+        );
+
+        self.alarm.set_alarm_client(mux_alarm);
+        mux_alarm
+    }
+}
+
 pub struct AlarmDriverComponent<A: 'static + time::Alarm<'static>> {
     board_kernel: &'static kernel::Kernel,
     alarm_mux: &'static MuxAlarm<'static, A>,
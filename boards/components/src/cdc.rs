@@ -16,12 +16,14 @@
 //!     capsules::usb::usbc_client::MAX_CTRL_PACKET_SIZE_NRF52840,
 //!     0x2341,
 //!     0x005a,
-//!     STRINGS)
+//!     STRINGS,
+//!     dynamic_deferred_caller)
 //! .finalize(components::usb_cdc_acm_component_helper!(nrf52::usbd::Usbd));
 //! ```
 
 use core::mem::MaybeUninit;
 
+use kernel::common::dynamic_deferred_call::DynamicDeferredCall;
 use kernel::component::Component;
 use kernel::hil;
 use kernel::static_init_half;
@@ -43,6 +45,7 @@ pub struct CdcAcmComponent<U: 'static + hil::usb::UsbController<'static>> {
     vendor_id: u16,
     product_id: u16,
     strings: &'static [&'static str; 3],
+    deferred_caller: &'static DynamicDeferredCall,
 }
 
 impl<U: 'static + hil::usb::UsbController<'static>> CdcAcmComponent<U> {
@@ -52,6 +55,7 @@ impl<U: 'static + hil::usb::UsbController<'static>> CdcAcmComponent<U> {
         vendor_id: u16,
         product_id: u16,
         strings: &'static [&'static str; 3],
+        deferred_caller: &'static DynamicDeferredCall,
     ) -> CdcAcmComponent<U> {
         CdcAcmComponent {
             usb,
@@ -59,6 +63,7 @@ impl<U: 'static + hil::usb::UsbController<'static>> CdcAcmComponent<U> {
             vendor_id,
             product_id,
             strings,
+            deferred_caller,
         }
     }
 }
@@ -76,9 +81,15 @@ impl<U: 'static + hil::usb::UsbController<'static>> Component for CdcAcmComponen
                 self.max_ctrl_packet_size,
                 self.vendor_id,
                 self.product_id,
-                self.strings
+                self.strings,
+                self.deferred_caller,
             )
         );
+        cdc.initialize_callback_handle(
+            self.deferred_caller
+                .register(cdc)
+                .expect("no deferred call slot available for CDC-ACM"),
+        );
         self.usb.set_client(cdc);
 
         cdc
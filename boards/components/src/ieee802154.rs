@@ -3,11 +3,13 @@
 //! This provides one Component, `Ieee802154Component`, which implements a
 //! userspace syscall interface to a full 802.15.4 stack with a
 //! always-on MAC implementation, as well as multiplexed access to that MAC implementation.
+//! The AES-CCM engine used for link-layer security is also multiplexed, so the
+//! returned `MuxAES128CCM` can be used to create additional AES-CCM users.
 //!
 //! Usage
 //! -----
 //! ```rust
-//! let (radio, mux_mac) = components::ieee802154::Ieee802154Component::new(
+//! let (radio, mux_mac, mux_ccm) = components::ieee802154::Ieee802154Component::new(
 //!     board_kernel,
 //!     &nrf52::ieee802154_radio::RADIO,
 //!     &nrf52::aes::AESECB,
@@ -23,6 +25,7 @@
 use capsules;
 use capsules::ieee802154::device::MacDevice;
 use capsules::ieee802154::mac::{AwakeMac, Mac};
+use capsules::virtual_aes_ccm::{MuxAES128CCM, VirtualAES128CCM};
 use core::mem::MaybeUninit;
 use kernel::capabilities;
 use kernel::component::Component;
@@ -38,6 +41,8 @@ macro_rules! ieee802154_component_helper {
         use core::mem::MaybeUninit;
         use kernel::hil::symmetric_encryption::{AES128Ctr, AES128, AES128CBC, AES128CCM};
 
+        use capsules::virtual_aes_ccm::{MuxAES128CCM, VirtualAES128CCM};
+
         static mut BUF1: MaybeUninit<capsules::aes_ccm::AES128CCM<'static, $A>> =
             MaybeUninit::uninit();
         static mut BUF2: MaybeUninit<AwakeMac<'static, $R>> = MaybeUninit::uninit();
@@ -45,10 +50,16 @@ macro_rules! ieee802154_component_helper {
             capsules::ieee802154::framer::Framer<
                 'static,
                 AwakeMac<'static, $R>,
-                capsules::aes_ccm::AES128CCM<'static, $A>,
+                VirtualAES128CCM<'static, capsules::aes_ccm::AES128CCM<'static, $A>>,
             >,
         > = MaybeUninit::uninit();
-        (&mut BUF1, &mut BUF2, &mut BUF3)
+        static mut BUF4: MaybeUninit<
+            MuxAES128CCM<'static, capsules::aes_ccm::AES128CCM<'static, $A>>,
+        > = MaybeUninit::uninit();
+        static mut BUF5: MaybeUninit<
+            VirtualAES128CCM<'static, capsules::aes_ccm::AES128CCM<'static, $A>>,
+        > = MaybeUninit::uninit();
+        (&mut BUF1, &mut BUF2, &mut BUF3, &mut BUF4, &mut BUF5)
     };};
 }
 
@@ -107,13 +118,18 @@ impl<
             capsules::ieee802154::framer::Framer<
                 'static,
                 AwakeMac<'static, R>,
-                capsules::aes_ccm::AES128CCM<'static, A>,
+                VirtualAES128CCM<'static, capsules::aes_ccm::AES128CCM<'static, A>>,
             >,
         >,
+        &'static mut MaybeUninit<MuxAES128CCM<'static, capsules::aes_ccm::AES128CCM<'static, A>>>,
+        &'static mut MaybeUninit<
+            VirtualAES128CCM<'static, capsules::aes_ccm::AES128CCM<'static, A>>,
+        >,
     );
     type Output = (
         &'static capsules::ieee802154::RadioDriver<'static>,
         &'static capsules::ieee802154::virtual_mac::MuxMac<'static>,
+        &'static MuxAES128CCM<'static, capsules::aes_ccm::AES128CCM<'static, A>>,
     );
 
     unsafe fn finalize(self, static_buffer: Self::StaticInput) -> Self::Output {
@@ -128,6 +144,20 @@ impl<
         self.aes.set_client(aes_ccm);
         self.aes.enable();
 
+        let mux_ccm = static_init_half!(
+            static_buffer.3,
+            MuxAES128CCM<'static, capsules::aes_ccm::AES128CCM<'static, A>>,
+            MuxAES128CCM::new(aes_ccm)
+        );
+        aes_ccm.set_client(mux_ccm);
+
+        let framer_ccm = static_init_half!(
+            static_buffer.4,
+            VirtualAES128CCM<'static, capsules::aes_ccm::AES128CCM<'static, A>>,
+            VirtualAES128CCM::new(mux_ccm)
+        );
+        framer_ccm.setup();
+
         // Keeps the radio on permanently; pass-through layer
         let awake_mac = static_init_half!(
             static_buffer.1,
@@ -142,11 +172,11 @@ impl<
             capsules::ieee802154::framer::Framer<
                 'static,
                 AwakeMac<'static, R>,
-                capsules::aes_ccm::AES128CCM<'static, A>,
+                VirtualAES128CCM<'static, capsules::aes_ccm::AES128CCM<'static, A>>,
             >,
-            capsules::ieee802154::framer::Framer::new(awake_mac, aes_ccm)
+            capsules::ieee802154::framer::Framer::new(awake_mac, framer_ccm)
         );
-        aes_ccm.set_client(mac_device);
+        framer_ccm.set_client(mac_device);
         awake_mac.set_transmit_client(mac_device);
         awake_mac.set_receive_client(mac_device);
         awake_mac.set_config_client(mac_device);
@@ -180,6 +210,6 @@ impl<
         userspace_mac.set_pan(self.pan_id);
         userspace_mac.set_address(self.short_addr);
 
-        (radio_driver, mux_mac)
+        (radio_driver, mux_mac, mux_ccm)
     }
 }
@@ -20,6 +20,7 @@ pub mod isl29035;
 pub mod l3gd20;
 pub mod led;
 pub mod lldb;
+pub mod log;
 pub mod lsm303dlhc;
 pub mod mlx90614;
 pub mod mx25r6435f;
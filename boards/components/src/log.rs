@@ -0,0 +1,44 @@
+//! Component for Log, the implementation for `log_error!`, `log_warn!`,
+//! `log_info!` and `log_trace!`.
+//!
+//! This provides one `Component`, `LogComponent`, which creates a kernel log
+//! using the provided buffer, keeping messages up to `level`. Without it,
+//! messages are printed with `debug!` up to `kernel::log::DEFAULT_LEVEL`.
+//!
+//! Usage
+//! -----
+//! ```rust
+//! let buf = static_init!([u8; 1024], [0; 1024]);
+//! let log = LogComponent::new(buf, Level::Trace).finalize(());
+//! ```
+
+use kernel::component::Component;
+use kernel::log::{Level, Log};
+use kernel::static_init;
+
+pub struct LogComponent {
+    buffer: &'static mut [u8],
+    level: Level,
+}
+
+impl LogComponent {
+    pub fn new(buffer: &'static mut [u8], level: Level) -> Self {
+        Self { buffer, level }
+    }
+}
+
+impl Component for LogComponent {
+    type StaticInput = ();
+    type Output = &'static Log;
+
+    unsafe fn finalize(self, _s: Self::StaticInput) -> Self::Output {
+        let ring_buffer = static_init!(
+            kernel::common::RingBuffer<'static, u8>,
+            kernel::common::RingBuffer::new(self.buffer)
+        );
+        let log = static_init!(Log, Log::new(ring_buffer));
+        log.set_level(self.level);
+        kernel::log::set_log(log);
+        log
+    }
+}
@@ -177,6 +177,9 @@ unsafe fn set_pin_primary_functions() {
 pub unsafe fn reset_handler() {
     sam4l::init();
 
+    // Mark the base of the kernel stack so that an overflow is detected.
+    kernel::debug::set_stack_canary(STACK_MEMORY.as_mut_ptr());
+
     sam4l::pm::PM.setup_system_clock(sam4l::pm::SystemClockSource::PllExternalOscillatorAt48MHz {
         frequency: sam4l::pm::OscillatorFrequency::Frequency16MHz,
         startup_mode: sam4l::pm::OscillatorStartup::SlowStart,
@@ -88,6 +88,9 @@ pub unsafe fn reset_handler() {
     // only machine mode
     rv32i::configure_trap_handler(rv32i::PermissionMode::Machine);
 
+    // Mark the base of the kernel stack so that an overflow is detected.
+    kernel::debug::set_stack_canary(STACK_MEMORY.as_mut_ptr());
+
     // initialize capabilities
     let process_mgmt_cap = create_capability!(capabilities::ProcessManagementCapability);
     let memory_allocation_cap = create_capability!(capabilities::MemoryAllocationCapability);
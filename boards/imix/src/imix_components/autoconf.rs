@@ -0,0 +1,198 @@
+//! Component to initialize IPv6 address autoconfiguration over 6LoWPAN.
+//!
+//! This provides one Component, AutoconfComponent, which returns the
+//! AddressAutoconf capsule, with its own MAC user and ICMPv6 sender and
+//! receiver. Add the capsules that need the addresses of the interface, such
+//! as the NeighborDiscovery capsule, as its clients, then call `start`.
+//!
+//! Usage
+//! -----
+//! ```rust
+//!    let autoconf = AutoconfComponent::new(
+//!        mux_mac,
+//!        DEFAULT_CTX_PREFIX_LEN,
+//!        DEFAULT_CTX_PREFIX,
+//!        DST_MAC_ADDR,
+//!        src_mac_from_serial_num,
+//!        mux_alarm,
+//!    )
+//!    .finalize(());
+//!    autoconf.add_client(ndp);
+//!    autoconf.start();
+//! ```
+
+#![allow(dead_code)] // Components are intended to be conditionally included
+
+use capsules;
+use capsules::ieee802154::device::MacDevice;
+use capsules::net::icmpv6::icmpv6::{ICMP6Header, ICMP6Type};
+use capsules::net::icmpv6::icmpv6_recv::{ICMP6Receiver, ICMP6RecvStruct};
+use capsules::net::icmpv6::icmpv6_send::{ICMP6SendStruct, ICMP6Sender};
+use capsules::net::ieee802154::MacAddress;
+use capsules::net::ipv6::autoconf::{AddressAutoconf, AUTOCONF_BUF_LEN};
+use capsules::net::ipv6::ipv6::{IP6Packet, IPPayload, TransportHeader};
+use capsules::net::ipv6::ipv6_recv::IP6Receiver;
+use capsules::net::ipv6::ipv6_send::{IP6SendStruct, IP6Sender};
+use capsules::net::network_capabilities::{
+    AddrRange, IpVisibilityCapability, NetworkCapability, PortRange,
+};
+use capsules::net::sixlowpan::{sixlowpan_compression, sixlowpan_state};
+use capsules::virtual_alarm::{MuxAlarm, VirtualMuxAlarm};
+use kernel;
+use kernel::capabilities;
+use kernel::component::Component;
+use kernel::create_capability;
+use kernel::hil::radio;
+use kernel::hil::time::Alarm;
+use kernel::static_init;
+
+use sam4l;
+
+static mut RF233_BUF: [u8; radio::MAX_BUF_SIZE] = [0x00; radio::MAX_BUF_SIZE];
+static mut SIXLOWPAN_RX_BUF: [u8; 1280] = [0x00; 1280];
+static mut ICMP_PAYLOAD: [u8; AUTOCONF_BUF_LEN] = [0; AUTOCONF_BUF_LEN];
+static mut AUTOCONF_BUF: [u8; AUTOCONF_BUF_LEN] = [0; AUTOCONF_BUF_LEN];
+
+type Ip6Send = IP6SendStruct<'static, VirtualMuxAlarm<'static, sam4l::ast::Ast<'static>>>;
+
+pub struct AutoconfComponent {
+    mux_mac: &'static capsules::ieee802154::virtual_mac::MuxMac<'static>,
+    ctx_pfix_len: u8,
+    ctx_pfix: [u8; 16],
+    dst_mac_addr: MacAddress,
+    src_mac_addr: MacAddress,
+    alarm_mux: &'static MuxAlarm<'static, sam4l::ast::Ast<'static>>,
+}
+
+impl AutoconfComponent {
+    pub fn new(
+        mux_mac: &'static capsules::ieee802154::virtual_mac::MuxMac<'static>,
+        ctx_pfix_len: u8,
+        ctx_pfix: [u8; 16],
+        dst_mac_addr: MacAddress,
+        src_mac_addr: MacAddress,
+        alarm: &'static MuxAlarm<'static, sam4l::ast::Ast<'static>>,
+    ) -> AutoconfComponent {
+        AutoconfComponent {
+            mux_mac: mux_mac,
+            ctx_pfix_len: ctx_pfix_len,
+            ctx_pfix: ctx_pfix,
+            dst_mac_addr: dst_mac_addr,
+            src_mac_addr: src_mac_addr,
+            alarm_mux: alarm,
+        }
+    }
+}
+
+impl Component for AutoconfComponent {
+    type StaticInput = ();
+    type Output =
+        &'static AddressAutoconf<'static, VirtualMuxAlarm<'static, sam4l::ast::Ast<'static>>>;
+
+    unsafe fn finalize(self, _s: Self::StaticInput) -> Self::Output {
+        let ipsender_virtual_alarm = static_init!(
+            VirtualMuxAlarm<'static, sam4l::ast::Ast>,
+            VirtualMuxAlarm::new(self.alarm_mux)
+        );
+        let autoconf_virtual_alarm = static_init!(
+            VirtualMuxAlarm<'static, sam4l::ast::Ast>,
+            VirtualMuxAlarm::new(self.alarm_mux)
+        );
+
+        let autoconf_mac = static_init!(
+            capsules::ieee802154::virtual_mac::MacUser<'static>,
+            capsules::ieee802154::virtual_mac::MacUser::new(self.mux_mac)
+        );
+        self.mux_mac.add_user(autoconf_mac);
+        let create_cap = create_capability!(capabilities::NetworkCapabilityCreationCapability);
+        let ip_vis = static_init!(
+            IpVisibilityCapability,
+            IpVisibilityCapability::new(&create_cap)
+        );
+        let net_cap = static_init!(
+            NetworkCapability,
+            NetworkCapability::new(AddrRange::Any, PortRange::Any, PortRange::Any, &create_cap)
+        );
+
+        let sixlowpan = static_init!(
+            sixlowpan_state::Sixlowpan<
+                'static,
+                sam4l::ast::Ast<'static>,
+                sixlowpan_compression::Context,
+            >,
+            sixlowpan_state::Sixlowpan::new(
+                sixlowpan_compression::Context {
+                    prefix: self.ctx_pfix,
+                    prefix_len: self.ctx_pfix_len,
+                    id: 0,
+                    compress: false,
+                },
+                &sam4l::ast::AST
+            )
+        );
+
+        let sixlowpan_state = sixlowpan as &dyn sixlowpan_state::SixlowpanState;
+        let sixlowpan_tx = sixlowpan_state::TxState::new(sixlowpan_state);
+        let default_rx_state = static_init!(
+            sixlowpan_state::RxState<'static>,
+            sixlowpan_state::RxState::new(&mut SIXLOWPAN_RX_BUF)
+        );
+        sixlowpan_state.add_rx_state(default_rx_state);
+        autoconf_mac.set_receive_client(sixlowpan);
+
+        let tr_hdr = TransportHeader::ICMP(ICMP6Header::new(ICMP6Type::Type135));
+        let ip_pyld: IPPayload = IPPayload {
+            header: tr_hdr,
+            payload: &mut ICMP_PAYLOAD,
+        };
+        let ip6_dg = static_init!(IP6Packet<'static>, IP6Packet::new(ip_pyld));
+
+        let ip_send = static_init!(
+            Ip6Send,
+            IP6SendStruct::new(
+                ip6_dg,
+                ipsender_virtual_alarm,
+                &mut RF233_BUF,
+                sixlowpan_tx,
+                autoconf_mac,
+                self.dst_mac_addr,
+                self.src_mac_addr,
+                ip_vis,
+            )
+        );
+        // The source address is left unspecified, as duplicate address
+        // detection needs
+        ipsender_virtual_alarm.set_alarm_client(ip_send);
+        autoconf_mac.set_transmit_client(ip_send);
+
+        let ip_receive = static_init!(
+            capsules::net::ipv6::ipv6_recv::IP6RecvStruct<'static>,
+            capsules::net::ipv6::ipv6_recv::IP6RecvStruct::new()
+        );
+        sixlowpan_state.set_rx_client(ip_receive);
+
+        let icmp_send = static_init!(
+            ICMP6SendStruct<'static, Ip6Send>,
+            ICMP6SendStruct::new(ip_send)
+        );
+        ip_send.set_client(icmp_send);
+        let icmp_receive = static_init!(ICMP6RecvStruct<'static>, ICMP6RecvStruct::new());
+        ip_receive.set_client(icmp_receive);
+
+        let autoconf = static_init!(
+            AddressAutoconf<'static, VirtualMuxAlarm<'static, sam4l::ast::Ast<'static>>>,
+            AddressAutoconf::new(
+                icmp_send,
+                autoconf_virtual_alarm,
+                &mut AUTOCONF_BUF,
+                self.src_mac_addr,
+                net_cap,
+            )
+        );
+        autoconf_virtual_alarm.set_alarm_client(autoconf);
+        icmp_send.set_client(autoconf);
+        icmp_receive.set_client(autoconf);
+
+        autoconf
+    }
+}
@@ -1,14 +1,20 @@
 pub mod adc;
+pub mod autoconf;
 pub mod fxos8700;
+pub mod ndp;
 pub mod rf233;
+pub mod tcp_mux;
 pub mod test;
 pub mod udp_driver;
 pub mod udp_mux;
 pub mod usb;
 
 pub use self::adc::AdcComponent;
+pub use self::autoconf::AutoconfComponent;
 pub use self::fxos8700::NineDofComponent;
+pub use self::ndp::NDPComponent;
 pub use self::rf233::RF233Component;
+pub use self::tcp_mux::TCPMuxComponent;
 pub use self::udp_driver::UDPDriverComponent;
 pub use self::udp_mux::UDPMuxComponent;
 pub use self::usb::UsbComponent;
@@ -231,6 +231,8 @@ impl Component for UDPMuxComponent {
             UdpPortManager,
             UdpPortManager::new(&create_table_cap, &mut USED_KERNEL_PORTS, udp_vis)
         );
+        udp_recv_mux.set_port_table(udp_port_table);
+        udp_send_mux.set_port_table(udp_port_table);
 
         (udp_send_mux, udp_recv_mux, udp_port_table)
     }
@@ -40,7 +40,6 @@ use components::isl29035::AmbientLightComponent;
 use components::led::LedsComponent;
 use components::nrf51822::Nrf51822Component;
 use components::process_console::ProcessConsoleComponent;
-use components::rng::RngComponent;
 use components::si7021::{HumidityComponent, SI7021Component};
 use components::spi::{SpiComponent, SpiSyscallComponent};
 use imix_components::adc::AdcComponent;
@@ -262,6 +261,9 @@ unsafe fn set_pin_primary_functions() {
 pub unsafe fn reset_handler() {
     sam4l::init();
 
+    // Mark the base of the kernel stack so that an overflow is detected.
+    kernel::debug::set_stack_canary(STACK_MEMORY.as_mut_ptr());
+
     sam4l::pm::PM.setup_system_clock(sam4l::pm::SystemClockSource::PllExternalOscillatorAt48MHz {
         frequency: sam4l::pm::OscillatorFrequency::Frequency16MHz,
         startup_mode: sam4l::pm::OscillatorStartup::FastStart,
@@ -286,9 +288,14 @@ pub unsafe fn reset_handler() {
     });
 
     let board_kernel = static_init!(kernel::Kernel, kernel::Kernel::new(&PROCESSES));
+    let power_manager = static_init!(
+        kernel::power::PowerManager<'static>,
+        kernel::power::PowerManager::new()
+    );
+    board_kernel.set_power_manager(power_manager);
 
     let dynamic_deferred_call_clients =
-        static_init!([DynamicDeferredCallClientState; 2], Default::default());
+        static_init!([DynamicDeferredCallClientState; 3], Default::default());
     let dynamic_deferred_caller = static_init!(
         DynamicDeferredCall,
         DynamicDeferredCall::new(dynamic_deferred_call_clients)
@@ -304,6 +311,10 @@ pub unsafe fn reset_handler() {
     let pconsole = ProcessConsoleComponent::new(board_kernel, uart_mux).finalize(());
     let console = ConsoleComponent::new(board_kernel, uart_mux).finalize(());
     DebugWriterComponent::new(uart_mux).finalize(());
+    // Keep the per-packet messages of the network stack for the `log`
+    // command of the process console, instead of printing them.
+    let log_buffer = static_init!([u8; 1024], [0; 1024]);
+    components::log::LogComponent::new(log_buffer, kernel::log::Level::Trace).finalize(());
 
     // Allow processes to communicate over BLE through the nRF51822
     sam4l::usart::USART2.set_mode(sam4l::usart::UsartMode::Uart);
@@ -338,6 +349,12 @@ pub unsafe fn reset_handler() {
     // SPI MUX, SPI syscall driver and RF233 radio
     let mux_spi = components::spi::SpiMuxComponent::new(&sam4l::spi::SPI)
         .finalize(components::spi_mux_component_helper!(sam4l::spi::SpiHw));
+    let spi_constraint = static_init!(
+        kernel::power::PowerConstraint<'static>,
+        kernel::power::PowerConstraint::new("spi")
+    );
+    power_manager.register(spi_constraint);
+    mux_spi.set_power_constraint(spi_constraint);
 
     let spi_syscalls = SpiSyscallComponent::new(mux_spi, 3)
         .finalize(components::spi_syscall_component_helper!(sam4l::spi::SpiHw));
@@ -352,6 +369,9 @@ pub unsafe fn reset_handler() {
         RADIO_CHANNEL,
     )
     .finalize(());
+    // Timestamp received frames with the counter behind the alarm driver, so
+    // apps can compare arrival times with alarm times.
+    rf233.set_timestamp_source(ast);
 
     let adc = AdcComponent::new(board_kernel).finalize(());
     let gpio = GpioComponent::new(
@@ -402,7 +422,37 @@ pub unsafe fn reset_handler() {
         ),
     )
     .finalize(components::acomp_component_buf!(sam4l::acifc::Acifc));
-    let rng = RngComponent::new(board_kernel, &sam4l::trng::TRNG).finalize(());
+
+    // The TRNG seeds a DRBG shared by userspace and the AES-CCM jitter.
+    let entropy_pool = static_init!(
+        capsules::entropy_pool::EntropyPool<'static>,
+        capsules::entropy_pool::EntropyPool::new(&sam4l::trng::TRNG, dynamic_deferred_caller)
+    );
+    entropy_pool.initialize_callback_handle(
+        dynamic_deferred_caller
+            .register(entropy_pool)
+            .expect("no deferred call slot available for the entropy pool"),
+    );
+    kernel::hil::entropy::Entropy32::set_client(&sam4l::trng::TRNG, entropy_pool);
+    let app_rng = static_init!(
+        capsules::entropy_pool::PoolRng<'static>,
+        capsules::entropy_pool::PoolRng::new(entropy_pool)
+    );
+    let rng = static_init!(
+        capsules::rng::RngDriver<'static>,
+        capsules::rng::RngDriver::new(app_rng, board_kernel.create_grant(&grant_cap))
+    );
+    kernel::hil::rng::Rng::set_client(app_rng, rng);
+    let jitter_rng = static_init!(
+        capsules::entropy_pool::PoolRng<'static>,
+        capsules::entropy_pool::PoolRng::new(entropy_pool)
+    );
+    let jitter = static_init!(
+        capsules::jitter::Jitter<'static, capsules::entropy_pool::PoolRng<'static>>,
+        capsules::jitter::Jitter::new(jitter_rng, 256)
+    );
+    kernel::hil::rng::Rng::set_client(jitter_rng, jitter);
+    jitter.start();
 
     // For now, assign the 802.15.4 MAC address on the device as
     // simply a 16-bit short address which represents the last 16 bits
@@ -415,7 +465,7 @@ pub unsafe fn reset_handler() {
 
     // Can this initialize be pushed earlier, or into component? -pal
     rf233.initialize(&mut RF233_BUF, &mut RF233_REG_WRITE, &mut RF233_REG_READ);
-    let (radio_driver, mux_mac) = components::ieee802154::Ieee802154Component::new(
+    let (radio_driver, mux_mac, mux_ccm) = components::ieee802154::Ieee802154Component::new(
         board_kernel,
         rf233,
         &sam4l::aes::AES,
@@ -426,6 +476,7 @@ pub unsafe fn reset_handler() {
         capsules::rf233::RF233<'static, VirtualSpiMasterDevice<'static, sam4l::spi::SpiHw>>,
         sam4l::aes::Aes<'static>
     ));
+    mux_ccm.set_jitter(jitter);
 
     let usb_driver = UsbComponent::new(board_kernel).finalize(());
 
@@ -475,6 +526,8 @@ pub unsafe fn reset_handler() {
         mux_alarm,
     )
     .finalize(());
+    pconsole.set_udp_port_table(udp_port_table);
+    pconsole.set_interface_addrs(local_ip_ifaces);
 
     // UDP driver initialization happens here
     let udp_driver = UDPDriverComponent::new(
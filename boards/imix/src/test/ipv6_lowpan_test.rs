@@ -435,7 +435,7 @@ impl<'a, A: time::Alarm<'a>> time::AlarmClient for LowpanTest<'a, A> {
 }
 
 impl<'a, A: time::Alarm<'a>> SixlowpanRxClient for LowpanTest<'a, A> {
-    fn receive(&self, buf: &[u8], len: usize, retcode: ReturnCode) {
+    fn receive(&self, buf: &[u8], len: usize, _timestamp: Option<u32>, retcode: ReturnCode) {
         debug!("Receive completed: {:?}", retcode);
         let test_num = self.test_counter.get();
         self.test_counter.set((test_num + 1) % self.num_tests());
@@ -153,7 +153,7 @@ enum TestMode {
     DualRxMode,
 }
 
-pub struct LowpanTest<'a, A: time::Alarm<'a>> {
+pub struct LowpanTest<'a, A: time::PeriodicAlarm<'a>> {
     alarm: &'a A,
     test_counter: Cell<usize>,
     port_table: &'static UdpPortManager,
@@ -227,7 +227,7 @@ pub unsafe fn initialize_all(
     udp_lowpan_test
 }
 
-impl<'a, A: time::Alarm<'a>> LowpanTest<'a, A> {
+impl<'a, A: time::PeriodicAlarm<'a>> LowpanTest<'a, A> {
     pub fn new(
         alarm: &'a A,
         port_table: &'static UdpPortManager,
@@ -686,7 +686,7 @@ impl<'a, A: time::Alarm<'a>> LowpanTest<'a, A> {
     }
 }
 
-impl<'a, A: time::Alarm<'a>> time::AlarmClient for LowpanTest<'a, A> {
+impl<'a, A: time::PeriodicAlarm<'a>> time::AlarmClient for LowpanTest<'a, A> {
     fn alarm(&self) {
         self.run_test_and_increment();
     }
@@ -13,6 +13,7 @@ use kernel::capabilities;
 use kernel::common::dynamic_deferred_call::DynamicDeferredCall;
 use kernel::common::dynamic_deferred_call::DynamicDeferredCallClientState;
 use kernel::component::Component;
+use kernel::liveness::{LivenessMonitor, LivenessSource};
 use kernel::Platform;
 use kernel::{create_capability, debug, static_init};
 
@@ -100,6 +101,9 @@ unsafe fn startup_intilialisation() {
 pub unsafe fn reset_handler() {
     startup_intilialisation();
 
+    // Mark the base of the kernel stack so that an overflow is detected.
+    kernel::debug::set_stack_canary(STACK_MEMORY.as_mut_ptr());
+
     // Setup the GPIO pins to use the HFXT (high frequency external) oscillator (48MHz)
     msp432::gpio::PINS_J[msp432::gpio::PinJNr::PJ_2 as usize].enable_primary_function();
     msp432::gpio::PINS_J[msp432::gpio::PinJNr::PJ_3 as usize].enable_primary_function();
@@ -193,6 +197,17 @@ pub unsafe fn reset_handler() {
     let alarm = components::alarm::AlarmDriverComponent::new(board_kernel, mux_alarm)
         .finalize(components::alarm_component_helper!(msp432::timer::TimerA));
 
+    // Stop tickling the watchdog if the console UART stops finishing its
+    // transmissions.
+    let liveness = static_init!(
+        LivenessMonitor<'static, msp432::timer::TimerA<'static>>,
+        LivenessMonitor::new(timer0)
+    );
+    board_kernel.set_liveness(liveness);
+    let uart_liveness = static_init!(LivenessSource<'static>, LivenessSource::new("uart0", 1000));
+    liveness.register(uart_liveness);
+    uart_mux.set_liveness(uart_liveness);
+
     let msp_exp432p4014 = MspExp432P401R {
         led: leds,
         console: console,
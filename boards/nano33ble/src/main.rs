@@ -123,6 +123,9 @@ pub unsafe fn reset_handler() {
     // Loads relocations and clears BSS
     nrf52840::init();
 
+    // Mark the base of the kernel stack so that an overflow is detected.
+    kernel::debug::set_stack_canary(STACK_MEMORY.as_mut_ptr());
+
     let board_kernel = static_init!(kernel::Kernel, kernel::Kernel::new(&PROCESSES));
 
     //--------------------------------------------------------------------------
@@ -183,7 +186,7 @@ pub unsafe fn reset_handler() {
     //--------------------------------------------------------------------------
 
     let dynamic_deferred_call_clients =
-        static_init!([DynamicDeferredCallClientState; 2], Default::default());
+        static_init!([DynamicDeferredCallClientState; 3], Default::default());
     let dynamic_deferred_caller = static_init!(
         DynamicDeferredCall,
         DynamicDeferredCall::new(dynamic_deferred_call_clients)
@@ -229,6 +232,7 @@ pub unsafe fn reset_handler() {
         0x2341,
         0x005a,
         strings,
+        dynamic_deferred_caller,
     )
     .finalize(components::usb_cdc_acm_component_helper!(nrf52::usbd::Usbd));
 
@@ -296,7 +300,7 @@ pub unsafe fn reset_handler() {
     // let ble_radio =
     //     BLEComponent::new(board_kernel, &nrf52::ble_radio::RADIO, mux_alarm).finalize(());
 
-    // let (ieee802154_radio, _) = Ieee802154Component::new(
+    // let (ieee802154_radio, _, _) = Ieee802154Component::new(
     //     board_kernel,
     //     &nrf52::ieee802154_radio::RADIO,
     //     PAN_ID,
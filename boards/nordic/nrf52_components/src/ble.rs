@@ -74,6 +74,8 @@ impl Component for BLEComponent {
             self.radio, ble_radio,
         );
         ble_radio_virtual_alarm.set_alarm_client(ble_radio);
+        // For the delayed transmissions of extended advertising
+        nrf52::timer::TIMER0.set_alarm_client(self.radio);
 
         ble_radio
     }
@@ -181,6 +181,9 @@ pub unsafe fn reset_handler() {
     // Loads relocations and clears BSS
     nrf52832::init();
 
+    // Mark the base of the kernel stack so that an overflow is detected.
+    kernel::debug::set_stack_canary(STACK_MEMORY.as_mut_ptr());
+
     let board_kernel = static_init!(kernel::Kernel, kernel::Kernel::new(&PROCESSES));
 
     let gpio = components::gpio::GpioComponent::new(
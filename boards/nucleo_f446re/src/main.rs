@@ -168,6 +168,9 @@ unsafe fn setup_peripherals() {
 pub unsafe fn reset_handler() {
     stm32f446re::init();
 
+    // Mark the base of the kernel stack so that an overflow is detected.
+    kernel::debug::set_stack_canary(STACK_MEMORY.as_mut_ptr());
+
     // We use the default HSI 16Mhz clock
 
     set_pin_primary_functions();
@@ -0,0 +1,893 @@
+//! Implements AES-CCM* and AES-GCM authenticated encryption on an
+//! underlying AES-CTR implementation.
+//!
+//! `AesAead` implements `hil::symmetric_encryption::AES128Aead` with
+//! separate buffers for the associated data, the payload and the tag, so
+//! that 802.15.4 frames, DTLS records and other messages do not have to be
+//! laid out for the cipher. Every AES block is computed on its own, as the
+//! CTR encryption of a zero block with the block as the counter, so any
+//! `AES128` with CTR mode can be used: the sam4l AES, the nRF5x ECB
+//! peripheral, or `SoftwareAes128` on chips without one. The rest of each
+//! mode, the CBC-MAC of CCM* and the GHASH of GCM, are computed here.
+//!
+//! For CCM*, the payload is authenticated before it is encrypted and after
+//! it is decrypted, as in RFC 3610. For GCM, it is authenticated as
+//! ciphertext, block by block as it is encrypted or decrypted. Only 96-bit
+//! GCM nonces are supported.
+//!
+//! As for `AES128CCM`, the AES engine has to be used by `AesAead` alone,
+//! since the key and counter are set for every block.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! static mut AEAD_BUF: [u8; 2 * symmetric_encryption::AES128_BLOCK_SIZE] =
+//!     [0; 2 * symmetric_encryption::AES128_BLOCK_SIZE];
+//!
+//! let aead = static_init!(
+//!     capsules::aes_aead::AesAead<'static, nrf5x::aes::AesECB<'static>>,
+//!     capsules::aes_aead::AesAead::new(&nrf5x::aes::AESECB, &mut AEAD_BUF)
+//! );
+//! nrf5x::aes::AESECB.set_client(aead);
+//! nrf5x::aes::AESECB.enable();
+//! ```
+
+use core::cell::Cell;
+use kernel::common::cells::{MapCell, OptionalCell, TakeCell};
+use kernel::common::constant_time;
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::hil::symmetric_encryption;
+use kernel::hil::symmetric_encryption::{
+    AES128Ctr, AeadClient, AeadMode, AEAD_MAX_NONCE_LENGTH, AEAD_MAX_TAG_LENGTH, AES128,
+    AES128_BLOCK_SIZE, AES128_KEY_SIZE, GCM_NONCE_LENGTH,
+};
+use kernel::ReturnCode;
+
+type Block = [u8; AES128_BLOCK_SIZE];
+
+/// The next AES block to compute.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum Step {
+    Idle,
+    /// The next block of the CBC-MAC.
+    CcmMac,
+    /// The keystream block that encrypts the tag.
+    CcmTagKey,
+    /// The keystream block of the next block of the payload.
+    CcmCtr,
+    /// The hash key `H`.
+    GcmHashKey,
+    /// The keystream block that encrypts the tag.
+    GcmTagKey,
+    GcmCtr,
+}
+
+/// Where the next block of the input to the CBC-MAC comes from.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum MacInput {
+    First,
+    Aad(usize),
+    Payload(usize),
+    Done,
+}
+
+/// Multiply in GF(2^128), as in NIST SP 800-38D, 6.3.
+fn gf128_mul(x: &Block, y: &Block) -> Block {
+    let mut z = [0; AES128_BLOCK_SIZE];
+    let mut v = *y;
+    for i in 0..128 {
+        if (x[i / 8] >> (7 - i % 8)) & 1 == 1 {
+            for j in 0..AES128_BLOCK_SIZE {
+                z[j] ^= v[j];
+            }
+        }
+        let lsb = v[15] & 1;
+        for j in (1..AES128_BLOCK_SIZE).rev() {
+            v[j] = (v[j] >> 1) | (v[j - 1] << 7);
+        }
+        v[0] >>= 1;
+        if lsb == 1 {
+            v[0] ^= 0xe1;
+        }
+    }
+    z
+}
+
+fn xor(a: &Block, b: &Block) -> Block {
+    let mut out = *a;
+    for (out, b) in out.iter_mut().zip(b.iter()) {
+        *out ^= *b;
+    }
+    out
+}
+
+/// Returns the block at `offset` in `data`, padded with zeros.
+fn padded_block(data: &[u8], offset: usize) -> Block {
+    let mut block = [0; AES128_BLOCK_SIZE];
+    let len = core::cmp::min(AES128_BLOCK_SIZE, data.len() - offset);
+    block[..len].copy_from_slice(&data[offset..offset + len]);
+    block
+}
+
+pub struct AesAead<'a, A: AES128<'a> + AES128Ctr> {
+    aes: &'a A,
+    /// A zero block, and the block the AES output is written to.
+    zero: TakeCell<'a, [u8]>,
+    output: TakeCell<'a, [u8]>,
+    client: OptionalCell<&'a dyn AeadClient>,
+
+    mode: Cell<AeadMode>,
+    key: Cell<[u8; AES128_KEY_SIZE]>,
+    nonce: Cell<[u8; AEAD_MAX_NONCE_LENGTH]>,
+    nonce_len: Cell<usize>,
+
+    aad: MapCell<LeasableBuffer<'static, u8>>,
+    payload: MapCell<LeasableBuffer<'static, u8>>,
+    tag: MapCell<LeasableBuffer<'static, u8>>,
+    encrypting: Cell<bool>,
+    step: Cell<Step>,
+    mac_input: Cell<MacInput>,
+    /// The offset in the payload of the next block to encrypt or decrypt.
+    ctr_offset: Cell<usize>,
+    /// The CBC-MAC or GHASH so far.
+    mac: Cell<Block>,
+    hash_key: Cell<Block>,
+    tag_key: Cell<Block>,
+}
+
+impl<'a, A: AES128<'a> + AES128Ctr> AesAead<'a, A> {
+    /// `buf` holds the two blocks the engine is given.
+    pub fn new(aes: &'a A, buf: &'static mut [u8; 2 * AES128_BLOCK_SIZE]) -> AesAead<'a, A> {
+        let (zero, output) = buf.split_at_mut(AES128_BLOCK_SIZE);
+        AesAead {
+            aes: aes,
+            zero: TakeCell::new(zero),
+            output: TakeCell::new(output),
+            client: OptionalCell::empty(),
+            mode: Cell::new(AeadMode::Ccm),
+            key: Cell::new(Default::default()),
+            nonce: Cell::new(Default::default()),
+            nonce_len: Cell::new(0),
+            aad: MapCell::empty(),
+            payload: MapCell::empty(),
+            tag: MapCell::empty(),
+            encrypting: Cell::new(false),
+            step: Cell::new(Step::Idle),
+            mac_input: Cell::new(MacInput::Done),
+            ctr_offset: Cell::new(0),
+            mac: Cell::new(Default::default()),
+            hash_key: Cell::new(Default::default()),
+            tag_key: Cell::new(Default::default()),
+        }
+    }
+
+    fn tag_len(&self) -> usize {
+        self.tag.map_or(0, |tag| tag.len())
+    }
+
+    fn payload_len(&self) -> usize {
+        self.payload.map_or(0, |payload| payload.len())
+    }
+
+    fn aad_len(&self) -> usize {
+        self.aad.map_or(0, |aad| aad.len())
+    }
+
+    fn valid_tag_len(&self, len: usize) -> bool {
+        match self.mode.get() {
+            AeadMode::Ccm => len <= AEAD_MAX_TAG_LENGTH && (len == 0 || (len >= 4 && len % 2 == 0)),
+            AeadMode::Gcm => len == 4 || len == 8 || (len >= 12 && len <= AEAD_MAX_TAG_LENGTH),
+        }
+    }
+
+    fn valid_payload_len(&self, len: usize) -> bool {
+        match self.mode.get() {
+            // The length is encoded in the 15 - nonce_len bytes after the
+            // nonce
+            AeadMode::Ccm => {
+                let bits = 8 * (15 - self.nonce_len.get());
+                bits >= 32 || len < (1 << bits)
+            }
+            AeadMode::Gcm => true,
+        }
+    }
+
+    /// Returns the CCM* counter block `i`.
+    fn ccm_counter(&self, i: usize) -> Block {
+        let nonce_len = self.nonce_len.get();
+        let mut block = [0; AES128_BLOCK_SIZE];
+        block[0] = (14 - nonce_len) as u8;
+        block[1..1 + nonce_len].copy_from_slice(&self.nonce.get()[..nonce_len]);
+        // The counter is in the bytes after the nonce
+        let counter = (i as u64).to_be_bytes();
+        block[1 + nonce_len..].copy_from_slice(&counter[counter.len() - (15 - nonce_len)..]);
+        block
+    }
+
+    /// Returns the GCM counter block `i`, counting from `J0`.
+    fn gcm_counter(&self, i: u32) -> Block {
+        let mut block = [0; AES128_BLOCK_SIZE];
+        block[..GCM_NONCE_LENGTH].copy_from_slice(&self.nonce.get()[..GCM_NONCE_LENGTH]);
+        block[12..].copy_from_slice(&(1 + i).to_be_bytes());
+        block
+    }
+
+    /// Returns the next block of the input to the CBC-MAC, if there is one.
+    fn next_mac_block(&self) -> Option<Block> {
+        let aad_len = self.aad_len();
+        let payload_len = self.payload_len();
+        match self.mac_input.get() {
+            MacInput::First => {
+                let nonce_len = self.nonce_len.get();
+                let mut block = self.ccm_counter(0);
+                block[0] |= (((self.tag_len() - 2) / 2) << 3) as u8;
+                if aad_len > 0 {
+                    block[0] |= 0x40;
+                }
+                let len = (payload_len as u64).to_be_bytes();
+                block[1 + nonce_len..].copy_from_slice(&len[len.len() - (15 - nonce_len)..]);
+                self.mac_input.set(if aad_len > 0 {
+                    MacInput::Aad(0)
+                } else {
+                    MacInput::Payload(0)
+                });
+                Some(block)
+            }
+            MacInput::Aad(offset) => {
+                // The associated data follows its length
+                let mut prefix = [0; 6];
+                let prefix_len = if aad_len < 0xff00 {
+                    prefix[..2].copy_from_slice(&(aad_len as u16).to_be_bytes());
+                    2
+                } else {
+                    prefix[..2].copy_from_slice(&[0xff, 0xfe]);
+                    prefix[2..].copy_from_slice(&(aad_len as u32).to_be_bytes());
+                    6
+                };
+                let mut block = [0; AES128_BLOCK_SIZE];
+                self.aad.map(|aad| {
+                    for (i, byte) in block.iter_mut().enumerate() {
+                        let index = offset + i;
+                        if index < prefix_len {
+                            *byte = prefix[index];
+                        } else if index < prefix_len + aad_len {
+                            *byte = aad[index - prefix_len];
+                        }
+                    }
+                });
+                let offset = offset + AES128_BLOCK_SIZE;
+                self.mac_input.set(if offset < prefix_len + aad_len {
+                    MacInput::Aad(offset)
+                } else {
+                    MacInput::Payload(0)
+                });
+                Some(block)
+            }
+            MacInput::Payload(offset) if offset < payload_len => {
+                self.mac_input
+                    .set(MacInput::Payload(offset + AES128_BLOCK_SIZE));
+                self.payload
+                    .map(|payload| padded_block(&payload[..], offset))
+            }
+            MacInput::Payload(_) | MacInput::Done => {
+                self.mac_input.set(MacInput::Done);
+                None
+            }
+        }
+    }
+
+    fn ghash(&self, block: &Block) {
+        self.mac.set(gf128_mul(
+            &xor(&self.mac.get(), block),
+            &self.hash_key.get(),
+        ));
+    }
+
+    /// Starts computing the AES encryption of `block`.
+    fn encrypt_block(&self, block: &Block) -> ReturnCode {
+        match (self.zero.take(), self.output.take()) {
+            (Some(zero), Some(output)) => {
+                self.aes.set_mode_aes128ctr(true);
+                self.aes.set_key(&self.key.get());
+                self.aes.set_iv(block);
+                self.aes.start_message();
+                match self.aes.crypt(Some(zero), output, 0, AES128_BLOCK_SIZE) {
+                    None => ReturnCode::SUCCESS,
+                    Some((rcode, zero, output)) => {
+                        zero.map(|zero| self.zero.replace(zero));
+                        self.output.replace(output);
+                        rcode
+                    }
+                }
+            }
+            (zero, output) => {
+                zero.map(|zero| self.zero.replace(zero));
+                output.map(|output| self.output.replace(output));
+                ReturnCode::EBUSY
+            }
+        }
+    }
+
+    /// Starts the next AES block of the operation, or ends it. Returns the
+    /// error if the block could not be started.
+    fn advance(&self) -> ReturnCode {
+        let block = match self.step.get() {
+            Step::Idle => return ReturnCode::SUCCESS,
+            Step::CcmMac => match self.next_mac_block() {
+                Some(block) => xor(&self.mac.get(), &block),
+                None => {
+                    if self.encrypting.get() {
+                        self.step.set(Step::CcmTagKey);
+                        return self.advance();
+                    }
+                    self.finish(ReturnCode::SUCCESS);
+                    return ReturnCode::SUCCESS;
+                }
+            },
+            Step::CcmTagKey => self.ccm_counter(0),
+            Step::CcmCtr | Step::GcmCtr if self.ctr_offset.get() >= self.payload_len() => {
+                if self.step.get() == Step::CcmCtr && !self.encrypting.get() && self.tag_len() > 0 {
+                    self.mac_input.set(MacInput::First);
+                    self.step.set(Step::CcmMac);
+                    return self.advance();
+                }
+                self.finish(ReturnCode::SUCCESS);
+                return ReturnCode::SUCCESS;
+            }
+            Step::CcmCtr => self.ccm_counter(1 + self.ctr_offset.get() / AES128_BLOCK_SIZE),
+            Step::GcmHashKey => [0; AES128_BLOCK_SIZE],
+            Step::GcmTagKey => self.gcm_counter(0),
+            Step::GcmCtr => {
+                self.gcm_counter(1 + (self.ctr_offset.get() / AES128_BLOCK_SIZE) as u32)
+            }
+        };
+        self.encrypt_block(&block)
+    }
+
+    /// Uses the AES encryption of the block of the current step.
+    fn block_done(&self, output: Block) {
+        match self.step.get() {
+            Step::Idle => {}
+            Step::CcmMac => self.mac.set(output),
+            Step::CcmTagKey => {
+                self.tag_key.set(output);
+                self.step.set(Step::CcmCtr);
+            }
+            Step::GcmHashKey => {
+                self.hash_key.set(output);
+                self.aad.map(|aad| {
+                    for offset in (0..aad.len()).step_by(AES128_BLOCK_SIZE) {
+                        self.ghash(&padded_block(&aad[..], offset));
+                    }
+                });
+                self.step.set(Step::GcmTagKey);
+            }
+            Step::GcmTagKey => {
+                self.tag_key.set(output);
+                self.step.set(Step::GcmCtr);
+            }
+            Step::CcmCtr | Step::GcmCtr => {
+                let offset = self.ctr_offset.get();
+                let gcm = self.step.get() == Step::GcmCtr;
+                let encrypting = self.encrypting.get();
+                let ciphertext = self.payload.map(|payload| {
+                    let len = core::cmp::min(AES128_BLOCK_SIZE, payload.len() - offset);
+                    if !encrypting {
+                        // GCM authenticates the ciphertext
+                        let block = padded_block(&payload[..], offset);
+                        for i in 0..len {
+                            payload[offset + i] ^= output[i];
+                        }
+                        block
+                    } else {
+                        for i in 0..len {
+                            payload[offset + i] ^= output[i];
+                        }
+                        padded_block(&payload[..], offset)
+                    }
+                });
+                if gcm {
+                    ciphertext.map(|block| self.ghash(&block));
+                }
+                self.ctr_offset.set(offset + AES128_BLOCK_SIZE);
+            }
+        }
+        let rcode = self.advance();
+        if rcode != ReturnCode::SUCCESS {
+            self.finish(rcode);
+        }
+    }
+
+    /// Computes the tag, and gives the buffers back to the client.
+    fn finish(&self, rcode: ReturnCode) {
+        let tag_len = self.tag_len();
+        let mut tag_is_valid = false;
+        if rcode == ReturnCode::SUCCESS {
+            if self.mode.get() == AeadMode::Gcm {
+                let mut lengths = [0; AES128_BLOCK_SIZE];
+                lengths[..8].copy_from_slice(&(8 * self.aad_len() as u64).to_be_bytes());
+                lengths[8..].copy_from_slice(&(8 * self.payload_len() as u64).to_be_bytes());
+                self.ghash(&lengths);
+            }
+            let computed = xor(&self.mac.get(), &self.tag_key.get());
+            let encrypting = self.encrypting.get();
+            tag_is_valid = self.tag.map_or(false, |tag| {
+                if encrypting {
+                    tag[..].copy_from_slice(&computed[..tag_len]);
+                    true
+                } else {
+                    constant_time::eq(&tag[..], &computed[..tag_len])
+                }
+            });
+        }
+        if !self.encrypting.get() && !tag_is_valid && tag_len > 0 {
+            // Do not leave the plaintext of a forged message
+            self.payload.map(|payload| {
+                for byte in payload[..].iter_mut() {
+                    *byte = 0;
+                }
+            });
+        }
+        self.step.set(Step::Idle);
+        if let (Some(aad), Some(payload), Some(tag)) =
+            (self.aad.take(), self.payload.take(), self.tag.take())
+        {
+            self.client.map(move |client| {
+                client.crypt_done(
+                    aad.take(),
+                    payload.take(),
+                    tag.take(),
+                    rcode,
+                    tag_is_valid || (tag_len == 0 && rcode == ReturnCode::SUCCESS),
+                )
+            });
+        }
+    }
+}
+
+impl<'a, A: AES128<'a> + AES128Ctr> symmetric_encryption::AES128Aead<'a> for AesAead<'a, A> {
+    fn set_client(&'a self, client: &'a dyn AeadClient) {
+        self.client.set(client);
+    }
+
+    fn set_mode(&self, mode: AeadMode) -> ReturnCode {
+        if self.step.get() != Step::Idle {
+            return ReturnCode::EBUSY;
+        }
+        self.mode.set(mode);
+        self.nonce_len.set(0);
+        ReturnCode::SUCCESS
+    }
+
+    fn set_key(&self, key: &[u8]) -> ReturnCode {
+        if key.len() != AES128_KEY_SIZE {
+            return ReturnCode::EINVAL;
+        }
+        let mut new_key = [0; AES128_KEY_SIZE];
+        new_key.copy_from_slice(key);
+        self.key.set(new_key);
+        ReturnCode::SUCCESS
+    }
+
+    fn set_nonce(&self, nonce: &[u8]) -> ReturnCode {
+        let valid = match self.mode.get() {
+            AeadMode::Ccm => nonce.len() >= 7 && nonce.len() <= AEAD_MAX_NONCE_LENGTH,
+            AeadMode::Gcm => nonce.len() == GCM_NONCE_LENGTH,
+        };
+        if !valid {
+            return ReturnCode::EINVAL;
+        }
+        let mut new_nonce = [0; AEAD_MAX_NONCE_LENGTH];
+        new_nonce[..nonce.len()].copy_from_slice(nonce);
+        self.nonce.set(new_nonce);
+        self.nonce_len.set(nonce.len());
+        ReturnCode::SUCCESS
+    }
+
+    fn crypt(
+        &self,
+        aad: LeasableBuffer<'static, u8>,
+        payload: LeasableBuffer<'static, u8>,
+        tag: LeasableBuffer<'static, u8>,
+        encrypting: bool,
+    ) -> Result<
+        (),
+        (
+            ReturnCode,
+            &'static mut [u8],
+            &'static mut [u8],
+            &'static mut [u8],
+        ),
+    > {
+        if self.step.get() != Step::Idle {
+            return Err((ReturnCode::EBUSY, aad.take(), payload.take(), tag.take()));
+        }
+        if self.nonce_len.get() == 0
+            || !self.valid_tag_len(tag.len())
+            || !self.valid_payload_len(payload.len())
+        {
+            return Err((ReturnCode::EINVAL, aad.take(), payload.take(), tag.take()));
+        }
+        let tag_len = tag.len();
+        self.aad.replace(aad);
+        self.payload.replace(payload);
+        self.tag.replace(tag);
+        self.encrypting.set(encrypting);
+        self.ctr_offset.set(0);
+        self.mac.set([0; AES128_BLOCK_SIZE]);
+        self.tag_key.set([0; AES128_BLOCK_SIZE]);
+        self.mac_input.set(MacInput::First);
+        self.step.set(match self.mode.get() {
+            AeadMode::Ccm if tag_len == 0 => Step::CcmCtr,
+            AeadMode::Ccm if encrypting => Step::CcmMac,
+            AeadMode::Ccm => Step::CcmTagKey,
+            AeadMode::Gcm => Step::GcmHashKey,
+        });
+        let rcode = self.advance();
+        if rcode != ReturnCode::SUCCESS {
+            self.step.set(Step::Idle);
+            if let (Some(aad), Some(payload), Some(tag)) =
+                (self.aad.take(), self.payload.take(), self.tag.take())
+            {
+                return Err((rcode, aad.take(), payload.take(), tag.take()));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'a, A: AES128<'a> + AES128Ctr> symmetric_encryption::Client<'a> for AesAead<'a, A> {
+    fn crypt_done(&'a self, source: Option<&'a mut [u8]>, output: &'a mut [u8]) {
+        let mut block = [0; AES128_BLOCK_SIZE];
+        block.copy_from_slice(&output[..AES128_BLOCK_SIZE]);
+        source.map(|zero| self.zero.replace(zero));
+        self.output.replace(output);
+        self.block_done(block);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use super::*;
+    use crate::software_aes::SoftwareAes128;
+    use kernel::common::dynamic_deferred_call::{
+        DeferredCallHandle, DynamicDeferredCall, DynamicDeferredCallClient,
+        DynamicDeferredCallClientState,
+    };
+    use kernel::hil::symmetric_encryption::AES128Aead;
+    use std::boxed::Box;
+    use std::vec::Vec;
+
+    fn bytes(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn leak(data: &[u8]) -> LeasableBuffer<'static, u8> {
+        LeasableBuffer::new(Box::leak(data.to_vec().into_boxed_slice()))
+    }
+
+    /// Keeps what `crypt_done()` gives back.
+    struct Collector {
+        payload: TakeCell<'static, [u8]>,
+        tag: TakeCell<'static, [u8]>,
+        result: Cell<Option<(ReturnCode, bool)>>,
+    }
+
+    impl AeadClient for Collector {
+        fn crypt_done(
+            &self,
+            _aad: &'static mut [u8],
+            payload: &'static mut [u8],
+            tag: &'static mut [u8],
+            res: ReturnCode,
+            tag_is_valid: bool,
+        ) {
+            self.payload.replace(payload);
+            self.tag.replace(tag);
+            self.result.set(Some((res, tag_is_valid)));
+        }
+    }
+
+    struct Engine {
+        aes: &'static SoftwareAes128<'static>,
+        aead: &'static AesAead<'static, SoftwareAes128<'static>>,
+        collector: &'static Collector,
+        deferred_caller: &'static DynamicDeferredCall,
+        handle: DeferredCallHandle,
+    }
+
+    impl Engine {
+        fn new() -> Engine {
+            let states = Box::leak(Box::new([DynamicDeferredCallClientState::default()]));
+            let deferred_caller: &'static DynamicDeferredCall =
+                Box::leak(Box::new(DynamicDeferredCall::new(states)));
+            let aes: &'static SoftwareAes128 =
+                Box::leak(Box::new(SoftwareAes128::new(deferred_caller)));
+            let handle = deferred_caller.register(aes).unwrap();
+            aes.initialize_callback_handle(handle);
+            let aead: &'static AesAead<SoftwareAes128> = Box::leak(Box::new(AesAead::new(
+                aes,
+                Box::leak(Box::new([0; 2 * AES128_BLOCK_SIZE])),
+            )));
+            AES128::set_client(aes, aead);
+            let collector: &'static Collector = Box::leak(Box::new(Collector {
+                payload: TakeCell::empty(),
+                tag: TakeCell::empty(),
+                result: Cell::new(None),
+            }));
+            aead.set_client(collector);
+            Engine {
+                aes: aes,
+                aead: aead,
+                collector: collector,
+                deferred_caller: deferred_caller,
+                handle: handle,
+            }
+        }
+
+        /// Runs one operation to its end, and returns the payload, the tag
+        /// and whether the tag is valid.
+        fn crypt(
+            &self,
+            mode: AeadMode,
+            key: &str,
+            nonce: &str,
+            aad: &str,
+            payload: &str,
+            tag: &str,
+            encrypting: bool,
+        ) -> (Vec<u8>, Vec<u8>, bool) {
+            assert_eq!(self.aead.set_mode(mode), ReturnCode::SUCCESS);
+            assert_eq!(self.aead.set_key(&bytes(key)), ReturnCode::SUCCESS);
+            assert_eq!(self.aead.set_nonce(&bytes(nonce)), ReturnCode::SUCCESS);
+            let started = self.aead.crypt(
+                leak(&bytes(aad)),
+                leak(&bytes(payload)),
+                leak(&bytes(tag)),
+                encrypting,
+            );
+            assert!(started.is_ok());
+            while self.deferred_caller.cancel(self.handle) {
+                self.aes.call(self.handle);
+            }
+            let (res, tag_is_valid) = self.collector.result.take().unwrap();
+            assert_eq!(res, ReturnCode::SUCCESS);
+            (
+                self.collector.payload.take().unwrap().to_vec(),
+                self.collector.tag.take().unwrap().to_vec(),
+                tag_is_valid,
+            )
+        }
+    }
+
+    // RFC 3610, packet vectors #1, #2, #4 and #7, with the first `aad` bytes
+    // of each packet authenticated only.
+    const CCM_KEY: &str = "c0c1c2c3c4c5c6c7c8c9cacbcccdcecf";
+    const CCM_VECTORS: [(&str, &str, &str, &str, &str); 4] = [
+        (
+            "00000003020100a0a1a2a3a4a5",
+            "0001020304050607",
+            "08090a0b0c0d0e0f101112131415161718191a1b1c1d1e",
+            "588c979a61c663d2f066d0c2c0f989806d5f6b61dac384",
+            "17e8d12cfdf926e0",
+        ),
+        (
+            "00000004030201a0a1a2a3a4a5",
+            "0001020304050607",
+            "08090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f",
+            "72c91a36e135f8cf291ca894085c87e3cc15c439c9e43a3b",
+            "a091d56e10400916",
+        ),
+        (
+            "00000006050403a0a1a2a3a4a5",
+            "000102030405060708090a0b",
+            "0c0d0e0f101112131415161718191a1b1c1d1e",
+            "a28c6865939a9a79faaa5c4c2a9d4a91cdac8c",
+            "96c861b9c9e61ef1",
+        ),
+        (
+            "00000009080706a0a1a2a3a4a5",
+            "0001020304050607",
+            "08090a0b0c0d0e0f101112131415161718191a1b1c1d1e",
+            "0135d1b2c95f41d5d1d4fec185d166b8094e999dfed96c",
+            "048c56602c97acbb7490",
+        ),
+    ];
+
+    // NIST SP 800-38D, with the test cases 2, 3 and 4 of the GCM
+    // specification of McGrew and Viega.
+    const GCM_KEY: &str = "feffe9928665731c6d6a8f9467308308";
+    const GCM_NONCE: &str = "cafebabefacedbaddecaf888";
+    const GCM_PLAINTEXT: &str = "d9313225f88406e5a55909c5aff5269a86a7a9531534f7da2e4c303d8a318a72\
+                                 1c3c0c95956809532fcf0e2449a6b525b16aedf5aa0de657ba637b39";
+    const GCM_CIPHERTEXT: &str = "42831ec2217774244b7221b784d0d49ce3aa212f2c02a4e035c17e2329aca12e\
+                                  21d514b25466931c7d8f6a5aac84aa051ba30b396a0aac973d58e091";
+
+    #[test]
+    fn ccm_rfc_3610() {
+        let engine = Engine::new();
+        for &(nonce, aad, plaintext, ciphertext, tag) in CCM_VECTORS.iter() {
+            let zeros = "00".repeat(tag.len() / 2);
+            let encrypted =
+                engine.crypt(AeadMode::Ccm, CCM_KEY, nonce, aad, plaintext, &zeros, true);
+            assert_eq!(encrypted, (bytes(ciphertext), bytes(tag), true));
+
+            let decrypted =
+                engine.crypt(AeadMode::Ccm, CCM_KEY, nonce, aad, ciphertext, tag, false);
+            assert_eq!(decrypted, (bytes(plaintext), bytes(tag), true));
+        }
+    }
+
+    #[test]
+    fn ccm_rejects_forgeries() {
+        let engine = Engine::new();
+        let (nonce, aad, _, ciphertext, tag) = CCM_VECTORS[0];
+        let mut forged = bytes(tag);
+        forged[0] ^= 1;
+        let forged: std::string::String =
+            forged.iter().map(|b| std::format!("{:02x}", b)).collect();
+        let (payload, _, valid) = engine.crypt(
+            AeadMode::Ccm,
+            CCM_KEY,
+            nonce,
+            aad,
+            ciphertext,
+            &forged,
+            false,
+        );
+        assert!(!valid);
+        // The plaintext of a forged message is not given out.
+        assert_eq!(payload, std::vec![0; ciphertext.len() / 2]);
+
+        let (payload, _, valid) = engine.crypt(
+            AeadMode::Ccm,
+            CCM_KEY,
+            nonce,
+            "0001020304050608",
+            ciphertext,
+            tag,
+            false,
+        );
+        assert!(!valid);
+        assert_eq!(payload, std::vec![0; ciphertext.len() / 2]);
+    }
+
+    #[test]
+    fn ccm_star_without_a_tag() {
+        // CCM* encrypts with the keystream of CCM when there is no tag.
+        let engine = Engine::new();
+        let (nonce, aad, plaintext, ciphertext, _) = CCM_VECTORS[0];
+        let encrypted = engine.crypt(AeadMode::Ccm, CCM_KEY, nonce, aad, plaintext, "", true);
+        assert_eq!(encrypted, (bytes(ciphertext), std::vec![], true));
+        let decrypted = engine.crypt(AeadMode::Ccm, CCM_KEY, nonce, aad, ciphertext, "", false);
+        assert_eq!(decrypted, (bytes(plaintext), std::vec![], true));
+    }
+
+    #[test]
+    fn gcm_sp_800_38d() {
+        let engine = Engine::new();
+        let zero_key = "00000000000000000000000000000000";
+        let encrypted = engine.crypt(
+            AeadMode::Gcm,
+            zero_key,
+            "000000000000000000000000",
+            "",
+            zero_key,
+            zero_key,
+            true,
+        );
+        assert_eq!(
+            encrypted,
+            (
+                bytes("0388dace60b6a392f328c2b971b2fe78"),
+                bytes("ab6e47d42cec13bdf53a67b21257bddf"),
+                true
+            )
+        );
+
+        // Four whole blocks, without associated data.
+        let plaintext = std::format!("{}1aafd255", GCM_PLAINTEXT);
+        let ciphertext = std::format!("{}473f5985", GCM_CIPHERTEXT);
+        let tag = "4d5c2af327cd64a62cf35abd2ba6fab4";
+        let encrypted = engine.crypt(
+            AeadMode::Gcm,
+            GCM_KEY,
+            GCM_NONCE,
+            "",
+            &plaintext,
+            zero_key,
+            true,
+        );
+        assert_eq!(encrypted, (bytes(&ciphertext), bytes(tag), true));
+        let decrypted = engine.crypt(
+            AeadMode::Gcm,
+            GCM_KEY,
+            GCM_NONCE,
+            "",
+            &ciphertext,
+            tag,
+            false,
+        );
+        assert_eq!(decrypted, (bytes(&plaintext), bytes(tag), true));
+
+        // A partial last block, with associated data.
+        let aad = "feedfacedeadbeeffeedfacedeadbeefabaddad2";
+        let tag = "5bc94fbc3221a5db94fae95ae7121a47";
+        let encrypted = engine.crypt(
+            AeadMode::Gcm,
+            GCM_KEY,
+            GCM_NONCE,
+            aad,
+            GCM_PLAINTEXT,
+            zero_key,
+            true,
+        );
+        assert_eq!(encrypted, (bytes(GCM_CIPHERTEXT), bytes(tag), true));
+        let decrypted = engine.crypt(
+            AeadMode::Gcm,
+            GCM_KEY,
+            GCM_NONCE,
+            aad,
+            GCM_CIPHERTEXT,
+            tag,
+            false,
+        );
+        assert_eq!(decrypted, (bytes(GCM_PLAINTEXT), bytes(tag), true));
+
+        // Truncated tags are checked as well.
+        let decrypted = engine.crypt(
+            AeadMode::Gcm,
+            GCM_KEY,
+            GCM_NONCE,
+            aad,
+            GCM_CIPHERTEXT,
+            &tag[..24],
+            false,
+        );
+        assert_eq!(decrypted, (bytes(GCM_PLAINTEXT), bytes(&tag[..24]), true));
+        let (payload, _, valid) = engine.crypt(
+            AeadMode::Gcm,
+            GCM_KEY,
+            GCM_NONCE,
+            "",
+            GCM_CIPHERTEXT,
+            tag,
+            false,
+        );
+        assert!(!valid);
+        assert_eq!(payload, std::vec![0; GCM_CIPHERTEXT.len() / 2]);
+    }
+
+    #[test]
+    fn rejects_invalid_parameters() {
+        let engine = Engine::new();
+        let aead = engine.aead;
+        assert_eq!(aead.set_key(&[0; 15]), ReturnCode::EINVAL);
+        assert_eq!(aead.set_mode(AeadMode::Ccm), ReturnCode::SUCCESS);
+        assert_eq!(aead.set_nonce(&[0; 6]), ReturnCode::EINVAL);
+        assert_eq!(aead.set_nonce(&[0; 14]), ReturnCode::EINVAL);
+        // No nonce is set yet.
+        let rejected = aead.crypt(leak(&[]), leak(&[0; 4]), leak(&[0; 8]), true);
+        assert_eq!(rejected.map_err(|err| err.0), Err(ReturnCode::EINVAL));
+        assert_eq!(aead.set_nonce(&[0; 13]), ReturnCode::SUCCESS);
+        let rejected = aead.crypt(leak(&[]), leak(&[0; 4]), leak(&[0; 5]), true);
+        assert_eq!(rejected.map_err(|err| err.0), Err(ReturnCode::EINVAL));
+        // With a 13 byte nonce, two bytes hold the length of the payload.
+        let rejected = aead.crypt(leak(&[]), leak(&[0; 0x10000]), leak(&[0; 8]), true);
+        assert_eq!(rejected.map_err(|err| err.0), Err(ReturnCode::EINVAL));
+
+        assert_eq!(aead.set_mode(AeadMode::Gcm), ReturnCode::SUCCESS);
+        assert_eq!(aead.set_nonce(&[0; 13]), ReturnCode::EINVAL);
+        assert_eq!(aead.set_nonce(&[0; GCM_NONCE_LENGTH]), ReturnCode::SUCCESS);
+        let rejected = aead.crypt(leak(&[]), leak(&[0; 4]), leak(&[0; 6]), true);
+        assert_eq!(rejected.map_err(|err| err.0), Err(ReturnCode::EINVAL));
+    }
+}
@@ -58,6 +58,7 @@ use crate::net::stream::SResult;
 use crate::net::stream::{encode_bytes, encode_u16};
 use core::cell::Cell;
 use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::constant_time;
 use kernel::hil::symmetric_encryption;
 use kernel::hil::symmetric_encryption::{
     AES128Ctr, AES128, AES128CBC, AES128_BLOCK_SIZE, AES128_KEY_SIZE, CCM_NONCE_LENGTH,
@@ -337,10 +338,10 @@ impl<'a, A: AES128<'a> + AES128Ctr + AES128CBC> AES128CCM<'a, A> {
                     } else {
                         // Compare the computed encrypted tag to the received
                         // encrypted tag
-                        buf[m_end..m_end + mic_len]
-                            .iter()
-                            .zip(cbuf[tag_off..tag_off + mic_len].iter())
-                            .all(|(a, b)| *a == *b)
+                        constant_time::eq(
+                            &buf[m_end..m_end + mic_len],
+                            &cbuf[tag_off..tag_off + mic_len],
+                        )
                     }
                 },
             )
@@ -374,10 +375,10 @@ impl<'a, A: AES128<'a> + AES128Ctr + AES128CBC> AES128CCM<'a, A> {
 
                     // Compare the computed encrypted tag to the received
                     // encrypted tag
-                    buf[m_off + m_len..m_off + m_len + mic_len]
-                        .iter()
-                        .zip(cbuf[tag_off..tag_off + mic_len].iter())
-                        .all(|(a, b)| *a == *b)
+                    constant_time::eq(
+                        &buf[m_off + m_len..m_off + m_len + mic_len],
+                        &cbuf[tag_off..tag_off + mic_len],
+                    )
                 },
             )
         });
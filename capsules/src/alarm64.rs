@@ -0,0 +1,139 @@
+//! Extends a narrower `Alarm` to the 64-bit `Time64` and `Alarm64`
+//! interfaces.
+//!
+//! `Alarm64Adapter` counts the wraps of the counter of its alarm: each time it
+//! reads a value smaller than the last one it read, the counter has wrapped.
+//! This only works if the counter is read at least once per wrap, so the
+//! adapter keeps its alarm armed at most half a wrap ahead, even when no 64-bit
+//! alarm is set. With a 32-bit counter at 16 kHz that is a wakeup every 37
+//! hours; with a 24-bit counter at 32 kHz, every 4 minutes. A 64-bit alarm
+//! further away than half a wrap is reached in several such steps.
+//!
+//! The alarm used is usually a virtual alarm of its own, and the counter starts
+//! at the time the client is set.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//! # use capsules::alarm64::Alarm64Adapter;
+//! # use capsules::virtual_alarm::VirtualMuxAlarm;
+//!
+//! let alarm64_virtual_alarm = static_init!(
+//!     VirtualMuxAlarm<'static, sam4l::ast::Ast>,
+//!     VirtualMuxAlarm::new(mux_alarm)
+//! );
+//! let alarm64 = static_init!(
+//!     Alarm64Adapter<'static, VirtualMuxAlarm<'static, sam4l::ast::Ast>>,
+//!     Alarm64Adapter::new(alarm64_virtual_alarm)
+//! );
+//! alarm64_virtual_alarm.set_alarm_client(alarm64);
+//! alarm64.set_alarm_client(client);
+//! alarm64.set_alarm_in(Duration::from_secs(5));
+//! ```
+
+use core::cell::Cell;
+use kernel::common::cells::OptionalCell;
+use kernel::hil::time::{self, Alarm, Alarm64, Ticks, Ticks64, Time64};
+use kernel::ReturnCode;
+
+pub struct Alarm64Adapter<'a, A: Alarm<'a>> {
+    alarm: &'a A,
+    /// The ticks counted in the wraps of the counter so far.
+    wraps: Cell<u64>,
+    /// The value of the counter when it was last read.
+    last: Cell<A::Ticks>,
+    /// The time of the 64-bit alarm, if it is armed.
+    target: Cell<Option<u64>>,
+    /// The time of the last 64-bit alarm set.
+    last_target: Cell<u64>,
+    client: OptionalCell<&'a dyn time::AlarmClient>,
+}
+
+impl<'a, A: Alarm<'a>> Alarm64Adapter<'a, A> {
+    pub fn new(alarm: &'a A) -> Alarm64Adapter<'a, A> {
+        Alarm64Adapter {
+            alarm: alarm,
+            wraps: Cell::new(0),
+            last: Cell::new(A::Ticks::from(0 as u32)),
+            target: Cell::new(None),
+            last_target: Cell::new(0),
+            client: OptionalCell::empty(),
+        }
+    }
+
+    /// Extends the counter value `now` to 64 bits.
+    fn extend(&self, now: A::Ticks) -> u64 {
+        if now < self.last.get() {
+            self.wraps
+                .set(self.wraps.get() + A::Ticks::max_value().into_u64() + 1);
+        }
+        self.last.set(now);
+        self.wraps.get() + now.into_u64()
+    }
+
+    /// Sets the alarm to the 64-bit alarm, if it is less than half a wrap
+    /// away, or else to half a wrap from now.
+    fn update_alarm(&self) {
+        let now = self.alarm.now();
+        let now64 = self.extend(now);
+        let half_wrap = A::Ticks::max_value().into_u64() / 2;
+        let dt = match self.target.get() {
+            Some(target) => target.saturating_sub(now64).min(half_wrap),
+            None => half_wrap,
+        };
+        self.alarm.set_alarm(now, A::Ticks::from_u64_wrapping(dt));
+    }
+}
+
+impl<'a, A: Alarm<'a>> Time64 for Alarm64Adapter<'a, A> {
+    type Frequency = A::Frequency;
+
+    fn now(&self) -> Ticks64 {
+        Ticks64::from(self.extend(self.alarm.now()))
+    }
+}
+
+impl<'a, A: Alarm<'a>> Alarm64<'a> for Alarm64Adapter<'a, A> {
+    fn set_alarm_client(&'a self, client: &'a dyn time::AlarmClient) {
+        self.client.set(client);
+        self.last.set(self.alarm.now());
+        self.update_alarm();
+    }
+
+    fn set_alarm_at(&self, ticks: Ticks64) {
+        self.target.set(Some(ticks.into_u64()));
+        self.last_target.set(ticks.into_u64());
+        self.update_alarm();
+    }
+
+    fn get_alarm(&self) -> Ticks64 {
+        Ticks64::from(self.last_target.get())
+    }
+
+    fn disarm(&self) -> ReturnCode {
+        // The alarm stays armed to keep counting wraps.
+        self.target.set(None);
+        self.update_alarm();
+        ReturnCode::SUCCESS
+    }
+
+    fn is_armed(&self) -> bool {
+        self.target.get().is_some()
+    }
+}
+
+impl<'a, A: Alarm<'a>> time::AlarmClient for Alarm64Adapter<'a, A> {
+    fn alarm(&self) {
+        let now64 = self.extend(self.alarm.now());
+        let expired = self.target.get().map_or(false, |target| target <= now64);
+        if expired {
+            self.target.set(None);
+        }
+        self.update_alarm();
+        if expired {
+            self.client.map(|client| client.alarm());
+        }
+    }
+}
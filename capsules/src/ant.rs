@@ -0,0 +1,432 @@
+//! Provides userspace with ANT and ANT+ channels through an ANT network
+//! processor.
+//!
+//! ANT's over the air protocol is not public, so the nRF radio cannot speak
+//! it directly. Instead the driver talks the documented ANT serial message
+//! protocol to a network processor on a UART: an nRF24AP2, or an nRF52
+//! running Nordic's ANT network processor firmware. ANT+ profiles also need
+//! the ANT+ network key, which is licensed and so supplied by the app.
+//!
+//! Each command sends one message, and the processor's response to it is
+//! passed to the response callback; only one command can be waiting for its
+//! response. To pair with a sensor, open a slave channel with device number
+//! 0 and the pairing bit (0x80) set in the device type, then request the
+//! channel ID once data arrives to learn the sensor's device number. The
+//! first app to use a command other than the driver check owns the driver;
+//! other apps get `ERESERVE`.
+//!
+//! ## Instantiation
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let ant = static_init!(
+//!     capsules::ant::Ant<'static>,
+//!     capsules::ant::Ant::new(
+//!         uart,
+//!         &mut capsules::ant::TX_BUF,
+//!         &mut capsules::ant::RX_BUF,
+//!         board_kernel.create_grant(&grant_cap)));
+//! hil::uart::Transmit::set_transmit_client(uart, ant);
+//! hil::uart::Receive::set_receive_client(uart, ant);
+//! ant.initialize();
+//! ```
+//!
+//! ## Syscall Interface
+//!
+//! - allow `0`: the 8 byte network key used by command `1`.
+//! - allow `1`: the 8 bytes of data sent by commands `8` and `9`.
+//! - allow `2`: received data is copied here.
+//! - subscribe `0`: a response, `fn(channel, message_id, code)`. For
+//!   command `10` the third argument instead holds the device number in its
+//!   low 16 bits, then the device type and the transmission type.
+//! - subscribe `1`: a channel event such as `EVENT_TRANSFER_TX_COMPLETED`,
+//!   `fn(channel, event, 0)`.
+//! - subscribe `2`: data arrived, `fn(channel, message_id, len)`, with the
+//!   message ID of a broadcast, acknowledged or burst message.
+//! - command `0`: driver check.
+//! - command `1`: set network `data` to the key in allow `0`.
+//! - command `2`: assign channel `data` as a slave, or as a master if bit 0
+//!   of `data2` is set, on the network in bits 8 to 15 of `data2`.
+//! - command `3`: set the ID of channel `data` to `data2`, packed as for
+//!   subscribe `0`.
+//! - command `4`: set the period of channel `data` to `data2` / 32768 s.
+//! - command `5`: set channel `data` to 2400 + `data2` MHz.
+//! - command `6`: open channel `data`.
+//! - command `7`: close channel `data`.
+//! - command `8`: send a broadcast on channel `data`.
+//! - command `9`: send acknowledged data on channel `data`.
+//! - command `10`: request the ID of channel `data`.
+
+use core::cell::Cell;
+use core::cmp;
+
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::uart;
+use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
+
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::Ant as usize;
+
+const SYNC: u8 = 0xa4;
+/// Sync, length, message ID and checksum.
+const OVERHEAD: usize = 4;
+const MAX_MESSAGE_LEN: usize = 32;
+
+pub const MSG_CHANNEL_EVENT: u8 = 0x40;
+pub const MSG_UNASSIGN_CHANNEL: u8 = 0x41;
+pub const MSG_ASSIGN_CHANNEL: u8 = 0x42;
+pub const MSG_CHANNEL_PERIOD: u8 = 0x43;
+pub const MSG_CHANNEL_RF_FREQUENCY: u8 = 0x45;
+pub const MSG_NETWORK_KEY: u8 = 0x46;
+pub const MSG_RESET_SYSTEM: u8 = 0x4a;
+pub const MSG_OPEN_CHANNEL: u8 = 0x4b;
+pub const MSG_CLOSE_CHANNEL: u8 = 0x4c;
+pub const MSG_REQUEST: u8 = 0x4d;
+pub const MSG_BROADCAST_DATA: u8 = 0x4e;
+pub const MSG_ACKNOWLEDGED_DATA: u8 = 0x4f;
+pub const MSG_BURST_DATA: u8 = 0x50;
+pub const MSG_CHANNEL_ID: u8 = 0x51;
+pub const MSG_STARTUP: u8 = 0x6f;
+
+/// The message ID of a channel event that is an RF event rather than a
+/// response.
+const RF_EVENT: u8 = 0x01;
+
+pub const EVENT_RX_SEARCH_TIMEOUT: u8 = 0x01;
+pub const EVENT_RX_FAIL: u8 = 0x02;
+pub const EVENT_TX: u8 = 0x03;
+pub const EVENT_TRANSFER_TX_COMPLETED: u8 = 0x05;
+pub const EVENT_TRANSFER_TX_FAILED: u8 = 0x06;
+pub const EVENT_CHANNEL_CLOSED: u8 = 0x07;
+
+const CHANNEL_TYPE_SLAVE: u8 = 0x00;
+const CHANNEL_TYPE_MASTER: u8 = 0x10;
+
+pub static mut TX_BUF: [u8; MAX_MESSAGE_LEN] = [0; MAX_MESSAGE_LEN];
+pub static mut RX_BUF: [u8; MAX_MESSAGE_LEN] = [0; MAX_MESSAGE_LEN];
+
+#[derive(Copy, Clone, PartialEq)]
+enum RxState {
+    /// Waiting for the sync byte.
+    Sync,
+    /// Waiting for the length byte.
+    Length,
+    /// Waiting for the message ID, data and checksum.
+    Body(usize),
+}
+
+#[derive(Default)]
+pub struct App {
+    response_callback: Option<Callback>,
+    event_callback: Option<Callback>,
+    data_callback: Option<Callback>,
+    network_key: Option<AppSlice<Shared, u8>>,
+    tx_data: Option<AppSlice<Shared, u8>>,
+    rx_data: Option<AppSlice<Shared, u8>>,
+}
+
+pub struct Ant<'a> {
+    uart: &'a dyn uart::UartData<'a>,
+    tx_buffer: TakeCell<'static, [u8]>,
+    rx_buffer: TakeCell<'static, [u8]>,
+    rx_state: Cell<RxState>,
+    /// The message ID of the response being waited for.
+    pending: OptionalCell<u8>,
+    apps: Grant<App>,
+    owner: OptionalCell<AppId>,
+}
+
+impl<'a> Ant<'a> {
+    pub fn new(
+        uart: &'a dyn uart::UartData<'a>,
+        tx_buffer: &'static mut [u8],
+        rx_buffer: &'static mut [u8],
+        apps: Grant<App>,
+    ) -> Ant<'a> {
+        Ant {
+            uart: uart,
+            tx_buffer: TakeCell::new(tx_buffer),
+            rx_buffer: TakeCell::new(rx_buffer),
+            rx_state: Cell::new(RxState::Sync),
+            pending: OptionalCell::empty(),
+            apps: apps,
+            owner: OptionalCell::empty(),
+        }
+    }
+
+    /// Reset the network processor and start receiving its messages.
+    pub fn initialize(&self) -> ReturnCode {
+        self.receive(RxState::Sync);
+        self.send(MSG_RESET_SYSTEM, &[0], Some(MSG_STARTUP))
+    }
+
+    fn receive(&self, state: RxState) {
+        let len = match state {
+            RxState::Sync | RxState::Length => 1,
+            RxState::Body(len) => len,
+        };
+        self.rx_state.set(state);
+        self.rx_buffer.take().map(|buffer| {
+            let (rcode, buffer) = self.uart.receive_buffer(buffer, len);
+            if rcode != ReturnCode::SUCCESS {
+                buffer.map(|buffer| self.rx_buffer.replace(buffer));
+            }
+        });
+    }
+
+    /// Send message `id`, waiting for the message `response` if there is
+    /// one. Data messages only get a response if they fail.
+    fn send(&self, id: u8, data: &[u8], response: Option<u8>) -> ReturnCode {
+        if self.pending.is_some() {
+            return ReturnCode::EBUSY;
+        }
+        self.tx_buffer.take().map_or(ReturnCode::EBUSY, |buffer| {
+            let len = data.len() + OVERHEAD;
+            buffer[0] = SYNC;
+            buffer[1] = data.len() as u8;
+            buffer[2] = id;
+            buffer[3..len - 1].copy_from_slice(data);
+            buffer[len - 1] = buffer[..len - 1].iter().fold(0, |sum, b| sum ^ b);
+            match self.uart.transmit_buffer(buffer, len) {
+                (ReturnCode::SUCCESS, _) => {
+                    self.pending.insert(response);
+                    ReturnCode::SUCCESS
+                }
+                (rcode, buffer) => {
+                    buffer.map(|buffer| self.tx_buffer.replace(buffer));
+                    rcode
+                }
+            }
+        })
+    }
+
+    fn send_data(&self, app: &mut App, id: u8, channel: u8) -> ReturnCode {
+        let mut data = [channel, 0, 0, 0, 0, 0, 0, 0, 0];
+        match app.tx_data {
+            Some(ref slice) if slice.len() >= 8 => data[1..].copy_from_slice(&slice.as_ref()[..8]),
+            _ => return ReturnCode::EINVAL,
+        }
+        self.send(id, &data, None)
+    }
+
+    fn set_network_key(&self, app: &mut App, network: u8) -> ReturnCode {
+        let mut data = [network, 0, 0, 0, 0, 0, 0, 0, 0];
+        match app.network_key {
+            Some(ref slice) if slice.len() == 8 => data[1..].copy_from_slice(slice.as_ref()),
+            _ => return ReturnCode::EINVAL,
+        }
+        self.send(MSG_NETWORK_KEY, &data, Some(MSG_NETWORK_KEY))
+    }
+
+    fn with_owner<F: FnOnce(&mut App)>(&self, f: F) {
+        self.owner.map(|owner| {
+            let _ = self.apps.enter(*owner, |app, _| f(app));
+        });
+    }
+
+    /// Handle a complete message, `id` followed by `data`.
+    fn handle_message(&self, id: u8, data: &[u8]) {
+        match id {
+            MSG_CHANNEL_EVENT if data.len() >= 3 => {
+                let (channel, message, code) = (data[0] as usize, data[1], data[2]);
+                if message == RF_EVENT {
+                    self.with_owner(|app| {
+                        app.event_callback
+                            .map(|mut cb| cb.schedule(channel, code as usize, 0));
+                    });
+                } else {
+                    self.response(channel, message, code as usize);
+                }
+            }
+            MSG_CHANNEL_ID if data.len() >= 5 => {
+                let id = data[1] as usize
+                    | (data[2] as usize) << 8
+                    | (data[3] as usize) << 16
+                    | (data[4] as usize) << 24;
+                self.response(data[0] as usize, MSG_CHANNEL_ID, id);
+            }
+            MSG_BROADCAST_DATA | MSG_ACKNOWLEDGED_DATA | MSG_BURST_DATA if data.len() >= 9 => {
+                self.with_owner(|app| {
+                    let copied = app.rx_data.as_mut().map_or(0, |slice| {
+                        let copied = cmp::min(slice.len(), 8);
+                        slice.as_mut()[..copied].copy_from_slice(&data[1..1 + copied]);
+                        copied
+                    });
+                    app.data_callback
+                        .map(|mut cb| cb.schedule(data[0] as usize, id as usize, copied));
+                });
+            }
+            MSG_STARTUP => {
+                if self.pending.contains(&MSG_STARTUP) {
+                    self.pending.clear();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn response(&self, channel: usize, message: u8, value: usize) {
+        if self.pending.contains(&message) {
+            self.pending.clear();
+        }
+        self.with_owner(|app| {
+            app.response_callback
+                .map(|mut cb| cb.schedule(channel, message as usize, value));
+        });
+    }
+}
+
+impl uart::TransmitClient for Ant<'_> {
+    fn transmitted_buffer(&self, buffer: &'static mut [u8], _tx_len: usize, rval: ReturnCode) {
+        self.tx_buffer.replace(buffer);
+        if rval != ReturnCode::SUCCESS {
+            // No response will come.
+            self.pending.take().map(|message| {
+                self.with_owner(|app| {
+                    app.response_callback
+                        .map(|mut cb| cb.schedule(0, message as usize, usize::from(rval)));
+                });
+            });
+        }
+    }
+}
+
+impl uart::ReceiveClient for Ant<'_> {
+    fn received_buffer(
+        &self,
+        buffer: &'static mut [u8],
+        rx_len: usize,
+        rval: ReturnCode,
+        _error: uart::Error,
+    ) {
+        let next = if rval != ReturnCode::SUCCESS {
+            RxState::Sync
+        } else {
+            match self.rx_state.get() {
+                RxState::Sync if buffer[0] == SYNC => RxState::Length,
+                RxState::Sync => RxState::Sync,
+                // The message ID, the data and the checksum follow.
+                RxState::Length if buffer[0] as usize + 2 <= buffer.len() => {
+                    RxState::Body(buffer[0] as usize + 2)
+                }
+                RxState::Length => RxState::Sync,
+                RxState::Body(len) => {
+                    let data_len = len - 2;
+                    let checksum = buffer[..len]
+                        .iter()
+                        .fold(SYNC ^ data_len as u8, |sum, b| sum ^ b);
+                    if rx_len == len && checksum == 0 {
+                        self.handle_message(buffer[0], &buffer[1..1 + data_len]);
+                    }
+                    RxState::Sync
+                }
+            }
+        };
+        self.rx_buffer.replace(buffer);
+        self.receive(next);
+    }
+}
+
+impl Driver for Ant<'_> {
+    fn allow(
+        &self,
+        appid: AppId,
+        allow_num: usize,
+        slice: Option<AppSlice<Shared, u8>>,
+    ) -> ReturnCode {
+        self.apps
+            .enter(appid, |app, _| {
+                match allow_num {
+                    0 => app.network_key = slice,
+                    1 => app.tx_data = slice,
+                    2 => app.rx_data = slice,
+                    _ => return ReturnCode::ENOSUPPORT,
+                }
+                ReturnCode::SUCCESS
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        callback: Option<Callback>,
+        appid: AppId,
+    ) -> ReturnCode {
+        self.apps
+            .enter(appid, |app, _| {
+                match subscribe_num {
+                    0 => app.response_callback = callback,
+                    1 => app.event_callback = callback,
+                    2 => app.data_callback = callback,
+                    _ => return ReturnCode::ENOSUPPORT,
+                }
+                ReturnCode::SUCCESS
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    fn command(&self, command_num: usize, data: usize, data2: usize, appid: AppId) -> ReturnCode {
+        if command_num == 0 {
+            return ReturnCode::SUCCESS;
+        }
+        if command_num > 10 {
+            return ReturnCode::ENOSUPPORT;
+        }
+        if self.owner.map_or(false, |owner| *owner != appid) {
+            return ReturnCode::ERESERVE;
+        }
+        self.owner.set(appid);
+
+        let channel = data as u8;
+        self.apps
+            .enter(appid, |app, _| match command_num {
+                1 => self.set_network_key(app, channel),
+                2 => {
+                    let channel_type = if data2 & 0x1 != 0 {
+                        CHANNEL_TYPE_MASTER
+                    } else {
+                        CHANNEL_TYPE_SLAVE
+                    };
+                    self.send(
+                        MSG_ASSIGN_CHANNEL,
+                        &[channel, channel_type, (data2 >> 8) as u8],
+                        Some(MSG_ASSIGN_CHANNEL),
+                    )
+                }
+                3 => self.send(
+                    MSG_CHANNEL_ID,
+                    &[
+                        channel,
+                        data2 as u8,
+                        (data2 >> 8) as u8,
+                        (data2 >> 16) as u8,
+                        (data2 >> 24) as u8,
+                    ],
+                    Some(MSG_CHANNEL_ID),
+                ),
+                4 => self.send(
+                    MSG_CHANNEL_PERIOD,
+                    &[channel, data2 as u8, (data2 >> 8) as u8],
+                    Some(MSG_CHANNEL_PERIOD),
+                ),
+                5 => self.send(
+                    MSG_CHANNEL_RF_FREQUENCY,
+                    &[channel, data2 as u8],
+                    Some(MSG_CHANNEL_RF_FREQUENCY),
+                ),
+                6 => self.send(MSG_OPEN_CHANNEL, &[channel], Some(MSG_OPEN_CHANNEL)),
+                7 => self.send(MSG_CLOSE_CHANNEL, &[channel], Some(MSG_CLOSE_CHANNEL)),
+                8 => self.send_data(app, MSG_BROADCAST_DATA, channel),
+                9 => self.send_data(app, MSG_ACKNOWLEDGED_DATA, channel),
+                10 => self.send(
+                    MSG_REQUEST,
+                    &[channel, MSG_CHANNEL_ID],
+                    Some(MSG_CHANNEL_ID),
+                ),
+                _ => ReturnCode::ENOSUPPORT,
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+}
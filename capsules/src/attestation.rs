@@ -0,0 +1,681 @@
+//! Boot measurement and remote attestation.
+//!
+//! At boot the `Attestation` capsule hashes the kernel image and the flash
+//! image of every loaded process into a measurement log. Applications can then
+//! ask for an attestation report: a signature, made with a device private key
+//! provisioned by the board, over the SHA-256 of a caller-supplied challenge
+//! followed by every digest in the measurement log. A remote verifier that
+//! knows the device public key and the expected images can check the report to
+//! confirm which software the device booted, without holding any secret, and
+//! the challenge prevents replaying old reports.
+//!
+//! Measurements and the hash of a report are both computed with the digest
+//! engine in SHA-256 mode, and the report is signed with a
+//! `hil::public_key_crypto::SignatureSign`, such as ECDSA P-256 with
+//! `ecdsa_p256::SoftwareEcdsaP256Signer`. Both operations are serialized by
+//! this capsule.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let measurements = static_init!(
+//!     [capsules::attestation::Measurement; 5],
+//!     [capsules::attestation::Measurement::empty(); 5]
+//! );
+//! let attestation = static_init!(
+//!     capsules::attestation::Attestation<
+//!         'static,
+//!         VirtualMuxSha256,
+//!         capsules::ecdsa_p256::SoftwareEcdsaP256Signer<'static>,
+//!         Capability,
+//!     >,
+//!     capsules::attestation::Attestation::new(
+//!         virtual_sha256_user,
+//!         signer,
+//!         board_kernel,
+//!         Capability,
+//!         kernel_image,
+//!         measurements,
+//!         &mut capsules::attestation::DATA_BUF,
+//!         &mut capsules::attestation::DIGEST_BUF,
+//!         &mut capsules::attestation::SIGNATURE_BUF,
+//!         board_kernel.create_grant(&memory_allocation_cap)
+//!     )
+//! );
+//! digest::Digest::set_client(virtual_sha256_user, attestation);
+//! SignatureSign::set_client(signer, attestation);
+//! // After processes are loaded:
+//! attestation.measure();
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! - allow `0`: the 32 byte challenge.
+//! - allow `1`: output buffer.
+//! - subscribe `0`: report done, `fn(result: usize, len: usize)`.
+//! - command `0`: driver check.
+//! - command `1`: number of measurements in the log, returns `EBUSY` while
+//!   boot measurement is still running.
+//! - command `2`: copy measurement `data` into the output buffer as a kind
+//!   byte (1 for kernel, 2 for process) followed by the 32 byte digest.
+//! - command `3`: generate a report over the challenge. The signature, 64
+//!   bytes for ECDSA P-256 (`r` followed by `s`), is written to the output
+//!   buffer before the callback is issued, whose `len` is its length.
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::capabilities::ProcessManagementCapability;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::debug;
+use kernel::hil::digest;
+use kernel::hil::public_key_crypto::{ClientSign, SignatureSign};
+use kernel::introspection::KernelInfo;
+use kernel::{AppId, AppSlice, Callback, Driver, Grant, Kernel, ReturnCode, Shared};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::Attestation as usize;
+
+/// Length of a measurement digest and of the hash a report signs.
+pub const DIGEST_LEN: usize = 32;
+/// Length of the challenge an app must provide to request a report.
+pub const CHALLENGE_LEN: usize = 32;
+/// Length of an ECDSA P-256 signature.
+pub const SIGNATURE_LEN: usize = 64;
+
+pub static mut DATA_BUF: [u8; 64] = [0; 64];
+pub static mut DIGEST_BUF: [u8; DIGEST_LEN] = [0; DIGEST_LEN];
+pub static mut SIGNATURE_BUF: [u8; SIGNATURE_LEN] = [0; SIGNATURE_LEN];
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MeasurementKind {
+    Empty = 0,
+    Kernel = 1,
+    Process = 2,
+}
+
+/// One entry in the measurement log.
+#[derive(Copy, Clone)]
+pub struct Measurement {
+    pub kind: MeasurementKind,
+    pub name: &'static str,
+    pub digest: [u8; DIGEST_LEN],
+}
+
+impl Measurement {
+    pub const fn empty() -> Measurement {
+        Measurement {
+            kind: MeasurementKind::Empty,
+            name: "",
+            digest: [0; DIGEST_LEN],
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum State {
+    Idle,
+    Measuring,
+    Reporting,
+    Signing,
+}
+
+#[derive(Default)]
+pub struct App {
+    callback: Option<Callback>,
+    challenge: Option<AppSlice<Shared, u8>>,
+    output: Option<AppSlice<Shared, u8>>,
+    pending: bool,
+}
+
+pub struct Attestation<'a, D: digest::Digest<'a, [u8; DIGEST_LEN]> + digest::Sha256, S, C>
+where
+    S: SignatureSign<'a>,
+    C: ProcessManagementCapability,
+{
+    digest: &'a D,
+    signer: &'a S,
+    kernel_info: KernelInfo,
+    capability: C,
+    kernel_image: &'static [u8],
+    log: TakeCell<'static, [Measurement]>,
+    log_len: Cell<usize>,
+    state: Cell<State>,
+
+    /// The image currently being measured and how much of it has been fed to
+    /// the digest engine.
+    region: OptionalCell<(MeasurementKind, &'static str, &'static [u8])>,
+    region_offset: Cell<usize>,
+    next_process: Cell<usize>,
+
+    /// Position in the challenge plus log stream when generating a report.
+    report_offset: Cell<usize>,
+    challenge: Cell<[u8; CHALLENGE_LEN]>,
+
+    /// Also holds the hash of a report while it is signed.
+    data_buffer: TakeCell<'static, [u8]>,
+    digest_buffer: TakeCell<'static, [u8; DIGEST_LEN]>,
+    signature_buffer: TakeCell<'static, [u8]>,
+    apps: Grant<App>,
+    current_app: OptionalCell<AppId>,
+}
+
+impl<'a, D: digest::Digest<'a, [u8; DIGEST_LEN]> + digest::Sha256, S, C> Attestation<'a, D, S, C>
+where
+    S: SignatureSign<'a>,
+    C: ProcessManagementCapability,
+{
+    /// `data_buffer` must hold at least the hash length of `signer`, and
+    /// `signature_buffer` its signature length.
+    pub fn new(
+        digest: &'a D,
+        signer: &'a S,
+        kernel: &'static Kernel,
+        capability: C,
+        kernel_image: &'static [u8],
+        log: &'static mut [Measurement],
+        data_buffer: &'static mut [u8],
+        digest_buffer: &'static mut [u8; DIGEST_LEN],
+        signature_buffer: &'static mut [u8],
+        grant: Grant<App>,
+    ) -> Attestation<'a, D, S, C> {
+        Attestation {
+            digest: digest,
+            signer: signer,
+            kernel_info: KernelInfo::new(kernel),
+            capability: capability,
+            kernel_image: kernel_image,
+            log: TakeCell::new(log),
+            log_len: Cell::new(0),
+            state: Cell::new(State::Idle),
+            region: OptionalCell::empty(),
+            region_offset: Cell::new(0),
+            next_process: Cell::new(0),
+            report_offset: Cell::new(0),
+            challenge: Cell::new([0; CHALLENGE_LEN]),
+            data_buffer: TakeCell::new(data_buffer),
+            digest_buffer: TakeCell::new(digest_buffer),
+            signature_buffer: TakeCell::new(signature_buffer),
+            apps: grant,
+            current_app: OptionalCell::empty(),
+        }
+    }
+
+    /// Start measuring the kernel image and all loaded processes. This must
+    /// be called after processes have been loaded. Returns `EINVAL` if the
+    /// log has no room for the kernel.
+    pub fn measure(&self) -> ReturnCode {
+        if self.state.get() != State::Idle {
+            return ReturnCode::EBUSY;
+        }
+        if self.log.map_or(0, |log| log.len()) == 0 {
+            return ReturnCode::EINVAL;
+        }
+        self.log_len.set(0);
+        self.next_process.set(0);
+        self.state.set(State::Measuring);
+        self.start_region(MeasurementKind::Kernel, "kernel", self.kernel_image);
+        ReturnCode::SUCCESS
+    }
+
+    /// Returns a copy of the `index`th measurement in the log, if it exists.
+    pub fn measurement(&self, index: usize) -> Option<Measurement> {
+        if index >= self.log_len.get() {
+            return None;
+        }
+        self.log.map_or(None, |log| log.get(index).copied())
+    }
+
+    fn start_region(&self, kind: MeasurementKind, name: &'static str, image: &'static [u8]) {
+        self.region.set((kind, name, image));
+        self.region_offset.set(0);
+        match self.digest.set_mode_sha256() {
+            Ok(()) => self.measure_next_chunk(),
+            Err(_) => self.abort_region(),
+        }
+    }
+
+    /// Move on to the next process, or finish measurement if every process has
+    /// been hashed or the log is full.
+    fn start_next_process(&self) {
+        let capacity = self.log.map_or(0, |log| log.len());
+        let index = self.next_process.get();
+        let next = if self.log_len.get() < capacity {
+            self.kernel_info
+                .process_flash_region(index, &self.capability)
+        } else {
+            None
+        };
+        match next {
+            Some((name, flash)) => {
+                self.next_process.set(index + 1);
+                self.start_region(MeasurementKind::Process, name, flash);
+            }
+            None => {
+                self.region.clear();
+                self.state.set(State::Idle);
+                self.check_queue();
+            }
+        }
+    }
+
+    fn measure_next_chunk(&self) {
+        let offset = self.region_offset.get();
+        let image = self.region.map_or(&[][..], |(_, _, image)| *image);
+
+        // Without a buffer the region cannot be hashed, so it is skipped
+        // rather than left unfinished, which would hold up every report.
+        if offset >= image.len() {
+            match self.digest_buffer.take() {
+                Some(digest_buffer) => {
+                    if let Err((_, buf)) = self.digest.run(digest_buffer) {
+                        self.digest_buffer.replace(buf);
+                        self.abort_region();
+                    }
+                }
+                None => self.abort_region(),
+            }
+            return;
+        }
+
+        match self.data_buffer.take() {
+            None => self.abort_region(),
+            Some(buf) => {
+                let len = cmp::min(buf.len(), image.len() - offset);
+                buf[..len].copy_from_slice(&image[offset..offset + len]);
+                self.region_offset.set(offset + len);
+
+                let mut lease = LeasableBuffer::new(buf);
+                lease.slice(..len);
+                if let Err((_, buf)) = self.digest.add_data(lease) {
+                    self.data_buffer.replace(buf);
+                    self.abort_region();
+                }
+            }
+        }
+    }
+
+    /// Skip an image that could not be hashed. It is not recorded in the log,
+    /// which a verifier will notice as a missing measurement.
+    fn abort_region(&self) {
+        self.region.map(|(_, name, _)| {
+            debug!("attestation: failed to measure {}", name);
+        });
+        self.digest.clear_data();
+        self.start_next_process();
+    }
+
+    /// Fill `buf` with the next chunk of the stream a report is computed
+    /// over: the challenge followed by every digest in the log.
+    fn fill_report_chunk(&self, buf: &mut [u8]) -> usize {
+        let offset = self.report_offset.get();
+        let total = CHALLENGE_LEN + self.log_len.get() * DIGEST_LEN;
+        let len = cmp::min(buf.len(), total - offset);
+        let challenge = self.challenge.get();
+        self.log.map(|log| {
+            for (i, b) in buf[..len].iter_mut().enumerate() {
+                let pos = offset + i;
+                *b = if pos < CHALLENGE_LEN {
+                    challenge[pos]
+                } else {
+                    let pos = pos - CHALLENGE_LEN;
+                    log[pos / DIGEST_LEN].digest[pos % DIGEST_LEN]
+                };
+            }
+        });
+        self.report_offset.set(offset + len);
+        len
+    }
+
+    fn report_next_chunk(&self) -> ReturnCode {
+        let total = CHALLENGE_LEN + self.log_len.get() * DIGEST_LEN;
+        if self.report_offset.get() >= total {
+            return self.digest_buffer.take().map_or(ReturnCode::FAIL, |buf| {
+                match self.digest.run(buf) {
+                    Ok(()) => ReturnCode::SUCCESS,
+                    Err((e, buf)) => {
+                        self.digest_buffer.replace(buf);
+                        e
+                    }
+                }
+            });
+        }
+
+        self.data_buffer.take().map_or(ReturnCode::FAIL, |buf| {
+            let len = self.fill_report_chunk(buf);
+            let mut lease = LeasableBuffer::new(buf);
+            lease.slice(..len);
+            match self.digest.add_data(lease) {
+                Ok(_) => ReturnCode::SUCCESS,
+                Err((e, buf)) => {
+                    self.data_buffer.replace(buf);
+                    e
+                }
+            }
+        })
+    }
+
+    /// Start a report for `appid`, copying its challenge into the kernel.
+    fn start_report(&self, appid: AppId) -> ReturnCode {
+        let loaded = self
+            .apps
+            .enter(appid, |app, _| match app.challenge {
+                Some(ref challenge) if challenge.len() >= CHALLENGE_LEN => {
+                    let mut copy = [0; CHALLENGE_LEN];
+                    copy.copy_from_slice(&challenge.as_ref()[..CHALLENGE_LEN]);
+                    self.challenge.set(copy);
+                    ReturnCode::SUCCESS
+                }
+                _ => ReturnCode::ERESERVE,
+            })
+            .unwrap_or_else(|err| err.into());
+        if loaded != ReturnCode::SUCCESS {
+            return loaded;
+        }
+
+        if let Err(e) = self.digest.set_mode_sha256() {
+            self.challenge.set([0; CHALLENGE_LEN]);
+            return e;
+        }
+        self.current_app.set(appid);
+        self.state.set(State::Reporting);
+        self.report_offset.set(0);
+        let ret = self.report_next_chunk();
+        if ret != ReturnCode::SUCCESS {
+            self.reset();
+        }
+        ret
+    }
+
+    /// Sign the hash of the report, which is in `hash`.
+    fn sign_report(&self, hash: &'static mut [u8]) {
+        match self.signature_buffer.take() {
+            Some(signature) => match self.signer.sign(hash, signature) {
+                Ok(()) => self.state.set(State::Signing),
+                Err((e, hash, signature)) => {
+                    self.signature_buffer.replace(signature);
+                    self.replace_hash(hash);
+                    self.finish_report(Err(e));
+                }
+            },
+            None => {
+                self.replace_hash(hash);
+                self.finish_report(Err(ReturnCode::FAIL));
+            }
+        }
+    }
+
+    /// Clear the hash of a report from `hash` and put it back.
+    fn replace_hash(&self, hash: &'static mut [u8]) {
+        for b in hash.iter_mut() {
+            *b = 0;
+        }
+        self.data_buffer.replace(hash);
+    }
+
+    /// Go back to `Idle` after a report, returning the app it was for.
+    fn reset(&self) -> Option<AppId> {
+        self.digest.clear_data();
+        self.challenge.set([0; CHALLENGE_LEN]);
+        self.state.set(State::Idle);
+        self.current_app.take()
+    }
+
+    fn finish_report(&self, result: Result<&[u8], ReturnCode>) {
+        self.reset().map(|appid| {
+            let _ = self.apps.enter(appid, |app, _| {
+                let (rcode, len) = match result {
+                    Ok(signature) => match app.output {
+                        Some(ref mut output) if output.len() >= signature.len() => {
+                            output.as_mut()[..signature.len()].copy_from_slice(signature);
+                            (ReturnCode::SUCCESS, signature.len())
+                        }
+                        _ => (ReturnCode::ESIZE, 0),
+                    },
+                    Err(e) => (e, 0),
+                };
+                app.callback
+                    .map(|mut cb| cb.schedule(usize::from(rcode), len, 0));
+            });
+        });
+        self.check_queue();
+    }
+
+    fn check_queue(&self) {
+        if self.state.get() != State::Idle {
+            return;
+        }
+        for cntr in self.apps.iter() {
+            let appid = cntr.enter(|app, _| {
+                if app.pending {
+                    app.pending = false;
+                    Some(app.appid())
+                } else {
+                    None
+                }
+            });
+            if let Some(appid) = appid {
+                let rcode = self.start_report(appid);
+                if rcode == ReturnCode::SUCCESS {
+                    break;
+                }
+                let _ = self.apps.enter(appid, |app, _| {
+                    app.callback
+                        .map(|mut cb| cb.schedule(usize::from(rcode), 0, 0));
+                });
+            }
+        }
+    }
+}
+
+impl<'a, D: digest::Digest<'a, [u8; DIGEST_LEN]> + digest::Sha256, S, C>
+    digest::Client<'a, [u8; DIGEST_LEN]> for Attestation<'a, D, S, C>
+where
+    S: SignatureSign<'a>,
+    C: ProcessManagementCapability,
+{
+    fn add_data_done(&'a self, result: Result<(), ReturnCode>, data: &'static mut [u8]) {
+        self.data_buffer.replace(data);
+        match self.state.get() {
+            State::Measuring => match result {
+                Ok(()) => self.measure_next_chunk(),
+                Err(_) => self.abort_region(),
+            },
+            State::Reporting => {
+                let ret = result.map_or_else(|e| e, |_| self.report_next_chunk());
+                if ret != ReturnCode::SUCCESS {
+                    self.finish_report(Err(ret));
+                }
+            }
+            State::Signing | State::Idle => {}
+        }
+    }
+
+    fn hash_done(&'a self, result: Result<(), ReturnCode>, digest: &'static mut [u8; DIGEST_LEN]) {
+        match self.state.get() {
+            State::Measuring => {
+                if result.is_ok() {
+                    let index = self.log_len.get();
+                    let stored = self.region.map_or(false, |(kind, name, _)| {
+                        self.log.map_or(false, |log| match log.get_mut(index) {
+                            Some(entry) => {
+                                *entry = Measurement {
+                                    kind: *kind,
+                                    name: name,
+                                    digest: *digest,
+                                };
+                                true
+                            }
+                            None => false,
+                        })
+                    });
+                    if stored {
+                        self.log_len.set(index + 1);
+                    }
+                }
+                self.digest_buffer.replace(digest);
+                self.digest.clear_data();
+                self.start_next_process();
+            }
+            State::Reporting => {
+                let hash = match result {
+                    Ok(()) => self
+                        .data_buffer
+                        .take()
+                        .map_or(Err(ReturnCode::FAIL), |buf| {
+                            buf[..DIGEST_LEN].copy_from_slice(&digest[..]);
+                            Ok(buf)
+                        }),
+                    Err(e) => Err(e),
+                };
+                *digest = [0; DIGEST_LEN];
+                self.digest_buffer.replace(digest);
+                match hash {
+                    Ok(hash) => self.sign_report(hash),
+                    Err(e) => self.finish_report(Err(e)),
+                }
+            }
+            State::Signing | State::Idle => {
+                self.digest_buffer.replace(digest);
+            }
+        }
+    }
+}
+
+impl<'a, D: digest::Digest<'a, [u8; DIGEST_LEN]> + digest::Sha256, S, C> ClientSign
+    for Attestation<'a, D, S, C>
+where
+    S: SignatureSign<'a>,
+    C: ProcessManagementCapability,
+{
+    fn signing_done(
+        &self,
+        result: ReturnCode,
+        hash: &'static mut [u8],
+        signature: &'static mut [u8],
+    ) {
+        self.replace_hash(hash);
+        if self.state.get() == State::Signing {
+            let len = cmp::min(self.signer.signature_len(), signature.len());
+            if result == ReturnCode::SUCCESS {
+                self.finish_report(Ok(&signature[..len]));
+            } else {
+                self.finish_report(Err(result));
+            }
+        }
+        for b in signature.iter_mut() {
+            *b = 0;
+        }
+        self.signature_buffer.replace(signature);
+    }
+}
+
+impl<'a, D: digest::Digest<'a, [u8; DIGEST_LEN]> + digest::Sha256, S, C> Driver
+    for Attestation<'a, D, S, C>
+where
+    S: SignatureSign<'a>,
+    C: ProcessManagementCapability,
+{
+    fn allow(
+        &self,
+        appid: AppId,
+        allow_num: usize,
+        slice: Option<AppSlice<Shared, u8>>,
+    ) -> ReturnCode {
+        match allow_num {
+            0 => self
+                .apps
+                .enter(appid, |app, _| {
+                    app.challenge = slice;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+            1 => self
+                .apps
+                .enter(appid, |app, _| {
+                    app.output = slice;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        callback: Option<Callback>,
+        appid: AppId,
+    ) -> ReturnCode {
+        match subscribe_num {
+            0 => self
+                .apps
+                .enter(appid, |app, _| {
+                    app.callback = callback;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    fn command(&self, command_num: usize, data: usize, _: usize, appid: AppId) -> ReturnCode {
+        match command_num {
+            0 => ReturnCode::SUCCESS,
+
+            // Number of measurements
+            1 => {
+                if self.state.get() == State::Measuring {
+                    ReturnCode::EBUSY
+                } else {
+                    ReturnCode::SuccessWithValue {
+                        value: self.log_len.get(),
+                    }
+                }
+            }
+
+            // Read one measurement
+            2 => match self.measurement(data) {
+                Some(measurement) => self
+                    .apps
+                    .enter(appid, |app, _| match app.output {
+                        Some(ref mut output) if output.len() > DIGEST_LEN => {
+                            let out = output.as_mut();
+                            out[0] = measurement.kind as u8;
+                            out[1..DIGEST_LEN + 1].copy_from_slice(&measurement.digest);
+                            ReturnCode::SUCCESS
+                        }
+                        Some(_) => ReturnCode::ESIZE,
+                        None => ReturnCode::ERESERVE,
+                    })
+                    .unwrap_or_else(|err| err.into()),
+                None => ReturnCode::EINVAL,
+            },
+
+            // Generate a report
+            3 => {
+                if self.state.get() == State::Idle {
+                    self.start_report(appid)
+                } else {
+                    self.apps
+                        .enter(appid, |app, _| {
+                            if app.pending {
+                                ReturnCode::EBUSY
+                            } else {
+                                app.pending = true;
+                                ReturnCode::SUCCESS
+                            }
+                        })
+                        .unwrap_or_else(|err| err.into())
+                }
+            }
+
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
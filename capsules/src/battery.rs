@@ -0,0 +1,201 @@
+//! Provides userspace with access to a battery fuel gauge.
+//!
+//! Apps can read the state of charge, voltage, and charging state of the
+//! battery, and subscribe to be told when the battery runs low. The
+//! low-battery threshold is chosen by the board with
+//! `hil::battery::Battery::set_low_battery_threshold`.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let battery = static_init!(
+//!     capsules::battery::BatteryDriver<'static>,
+//!     capsules::battery::BatteryDriver::new(max17048, board_kernel.create_grant(&grant_cap))
+//! );
+//! kernel::hil::battery::Battery::set_client(max17048, battery);
+//! kernel::hil::battery::Battery::set_low_battery_threshold(max17048, 10);
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! - subscribe `0`: reading done, `fn(reading: usize, result: usize, value:
+//!   usize)` where `reading` is the command number of the reading. `value` is
+//!   the state of charge in hundredths of a percent, the voltage in
+//!   millivolts, or the charging state (0 unknown, 1 discharging, 2 charging,
+//!   3 full).
+//! - subscribe `1`: low battery, `fn(0, 0, 0)`.
+//! - command `0`: driver check.
+//! - command `1`: read the state of charge.
+//! - command `2`: read the voltage.
+//! - command `3`: read the charging state.
+
+use kernel::common::cells::OptionalCell;
+use kernel::hil::battery::{Battery, BatteryClient, ChargingState};
+use kernel::{AppId, Callback, Driver, Grant, ReturnCode};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::Battery as usize;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Reading {
+    StateOfCharge = 1,
+    Voltage = 2,
+    ChargingState = 3,
+}
+
+#[derive(Default)]
+pub struct App {
+    callback: Option<Callback>,
+    alert_callback: Option<Callback>,
+    /// Reading the app is waiting for.
+    waiting: Option<Reading>,
+}
+
+pub struct BatteryDriver<'a> {
+    battery: &'a dyn Battery<'a>,
+    apps: Grant<App>,
+    /// Reading the gauge is currently taking.
+    busy: OptionalCell<Reading>,
+}
+
+impl<'a> BatteryDriver<'a> {
+    pub fn new(battery: &'a dyn Battery<'a>, grant: Grant<App>) -> BatteryDriver<'a> {
+        BatteryDriver {
+            battery: battery,
+            apps: grant,
+            busy: OptionalCell::empty(),
+        }
+    }
+
+    fn start(&self, reading: Reading) -> ReturnCode {
+        let rcode = match reading {
+            Reading::StateOfCharge => self.battery.read_state_of_charge(),
+            Reading::Voltage => self.battery.read_voltage(),
+            Reading::ChargingState => self.battery.read_charging_state(),
+        };
+        if rcode == ReturnCode::SUCCESS {
+            self.busy.set(reading);
+        }
+        rcode
+    }
+
+    fn enqueue(&self, appid: AppId, reading: Reading) -> ReturnCode {
+        self.apps
+            .enter(appid, |app, _| {
+                if app.waiting.is_some() {
+                    return ReturnCode::EBUSY;
+                }
+                // Apps asking for the reading in progress share its result.
+                if self.busy.is_none() {
+                    let rcode = self.start(reading);
+                    if rcode != ReturnCode::SUCCESS {
+                        return rcode;
+                    }
+                }
+                app.waiting = Some(reading);
+                ReturnCode::SUCCESS
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    /// Deliver `result` to every app waiting for `reading`, then start the
+    /// next reading some app is waiting for.
+    fn done(&self, reading: Reading, result: Result<usize, ReturnCode>) {
+        self.busy.clear();
+        let (rcode, value) = match result {
+            Ok(value) => (ReturnCode::SUCCESS, value),
+            Err(rcode) => (rcode, 0),
+        };
+        self.apps.each(|app| {
+            if app.waiting == Some(reading) {
+                app.waiting = None;
+                app.callback
+                    .map(|mut cb| cb.schedule(reading as usize, usize::from(rcode), value));
+            }
+        });
+
+        for cntr in self.apps.iter() {
+            let started = cntr.enter(|app, _| match app.waiting {
+                Some(next) => {
+                    let rcode = self.start(next);
+                    if rcode != ReturnCode::SUCCESS {
+                        app.waiting = None;
+                        app.callback
+                            .map(|mut cb| cb.schedule(next as usize, usize::from(rcode), 0));
+                    }
+                    rcode == ReturnCode::SUCCESS
+                }
+                None => false,
+            });
+            if started {
+                break;
+            }
+        }
+    }
+}
+
+impl BatteryClient for BatteryDriver<'_> {
+    fn state_of_charge(&self, result: Result<u16, ReturnCode>) {
+        self.done(Reading::StateOfCharge, result.map(|soc| soc as usize));
+    }
+
+    fn voltage(&self, result: Result<u32, ReturnCode>) {
+        self.done(Reading::Voltage, result.map(|mv| mv as usize));
+    }
+
+    fn charging_state(&self, result: Result<ChargingState, ReturnCode>) {
+        self.done(Reading::ChargingState, result.map(|state| state as usize));
+    }
+
+    fn threshold_set(&self, _result: ReturnCode) {
+        // The board sets the threshold, so there is no app to tell.
+    }
+
+    fn low_battery(&self) {
+        self.apps.each(|app| {
+            app.alert_callback.map(|mut cb| cb.schedule(0, 0, 0));
+        });
+    }
+}
+
+impl Driver for BatteryDriver<'_> {
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        callback: Option<Callback>,
+        app_id: AppId,
+    ) -> ReturnCode {
+        match subscribe_num {
+            0 => self
+                .apps
+                .enter(app_id, |app, _| {
+                    app.callback = callback;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+            1 => self
+                .apps
+                .enter(app_id, |app, _| {
+                    app.alert_callback = callback;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    fn command(&self, command_num: usize, _: usize, _: usize, appid: AppId) -> ReturnCode {
+        match command_num {
+            0 => ReturnCode::SUCCESS,
+            1 => self.enqueue(appid, Reading::StateOfCharge),
+            2 => self.enqueue(appid, Reading::Voltage),
+            3 => self.enqueue(appid, Reading::ChargingState),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
@@ -0,0 +1,798 @@
+//! Driver for Quectel BG96 family LTE-M and NB-IoT modems.
+//!
+//! The modem is driven with AT commands over a UART. `attach()` checks that
+//! the modem has registered with the network, activates a PDP context with
+//! the board's APN and opens a UDP socket on one local port, and
+//! `read_signal_quality()` reports the received signal strength. The BG95
+//! and BG77 share the TCP/IP AT commands used here.
+//!
+//! Datagrams are tunnelled through the modem's socket so that existing UDP
+//! clients work over the cellular link: the driver implements `UDPSender`,
+//! and passes received datagrams, behind an IPv6 and a UDP header, to an
+//! `IP6RecvClient` such as a `MuxUdpReceiver`. Cellular networks carry IPv4,
+//! so addresses are IPv4-mapped IPv6 addresses (`::ffff:a.b.c.d`), and
+//! datagrams are always sent from the socket's port, so a binding for any
+//! other port is refused.
+//!
+//! Only one AT command runs at a time and one datagram can wait for it to be
+//! sent. The modem is trusted to answer every command.
+//!
+//! ## Instantiation
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let bg96 = static_init!(
+//!     capsules::bg96::Bg96<'static>,
+//!     capsules::bg96::Bg96::new(
+//!         uart,
+//!         &mut capsules::bg96::TX_BUF,
+//!         &mut capsules::bg96::RX_BUF,
+//!         &mut capsules::bg96::LINE_BUF,
+//!         &mut capsules::bg96::DATAGRAM_BUF,
+//!         "iot.example",
+//!         16000,
+//!         ip_visibility,
+//!         udp_visibility));
+//! hil::uart::Transmit::set_transmit_client(uart, bg96);
+//! hil::uart::Receive::set_receive_client(uart, bg96);
+//! bg96.set_receive_client(udp_recv_mux);
+//! bg96.set_modem_client(modem_client);
+//! bg96.initialize();
+//! bg96.attach();
+//! ```
+
+use core::cell::Cell;
+use core::fmt::{self, Write};
+
+use crate::net::ipv6::ip_utils::{ip6_nh, IPAddr};
+use crate::net::ipv6::ipv6::{IP6Header, UDP_HDR_LEN};
+use crate::net::ipv6::ipv6_recv::IP6RecvClient;
+use crate::net::network_capabilities::{
+    IpVisibilityCapability, NetworkCapability, UdpVisibilityCapability,
+};
+use crate::net::udp::udp::UDPHeader;
+use crate::net::udp::udp_port_table::UdpPortBindingTx;
+use crate::net::udp::udp_send::{UDPSendClient, UDPSender};
+use kernel::capabilities::UdpDriverCapability;
+use kernel::common::cells::{MapCell, OptionalCell, TakeCell};
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::hil::uart;
+use kernel::ReturnCode;
+
+/// The longest datagram sent or received.
+pub const MAX_DATAGRAM_LEN: usize = 512;
+const MAX_LINE_LEN: usize = 64;
+
+/// The PDP context and socket the driver uses.
+const CONTEXT: u8 = 1;
+const SOCKET: u8 = 0;
+
+pub static mut TX_BUF: [u8; MAX_DATAGRAM_LEN] = [0; MAX_DATAGRAM_LEN];
+pub static mut RX_BUF: [u8; 1] = [0; 1];
+pub static mut LINE_BUF: [u8; MAX_LINE_LEN] = [0; MAX_LINE_LEN];
+pub static mut DATAGRAM_BUF: [u8; UDP_HDR_LEN + MAX_DATAGRAM_LEN] =
+    [0; UDP_HDR_LEN + MAX_DATAGRAM_LEN];
+
+pub trait ModemClient {
+    /// `attach()` finished. `result` is `EOFF` if the modem has not
+    /// registered with the network yet.
+    fn attached(&self, result: ReturnCode);
+
+    /// The socket and PDP context are gone, because of `detach()` or because
+    /// the network closed them.
+    fn detached(&self);
+
+    /// The received signal strength and the bit error rate class (0 to 7),
+    /// or `None` for either if the modem does not know it.
+    fn signal_quality(&self, rssi_dbm: Option<i16>, ber: Option<u8>);
+}
+
+/// The AT command waiting for its result.
+#[derive(Copy, Clone, PartialEq)]
+enum Step {
+    Idle,
+    EchoOff,
+    Registration,
+    DefineContext,
+    Activate,
+    QueryAddress,
+    OpenSocket,
+    /// The socket open was accepted; its result follows as a URC.
+    WaitOpen,
+    CloseSocket,
+    Deactivate,
+    Signal,
+    /// Waiting for the `> ` prompt for the datagram.
+    SendCommand,
+    /// Waiting for `SEND OK`.
+    SendData,
+    Read,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum RxState {
+    Line,
+    /// Receiving datagram data, with this many bytes to go.
+    Data(usize),
+}
+
+pub struct Bg96<'a> {
+    uart: &'a dyn uart::UartData<'a>,
+    tx_buffer: TakeCell<'static, [u8]>,
+    rx_buffer: TakeCell<'static, [u8]>,
+    line: TakeCell<'static, [u8]>,
+    line_len: Cell<usize>,
+    rx_state: Cell<RxState>,
+    step: Cell<Step>,
+    apn: &'static str,
+    local_port: u16,
+    address: OptionalCell<[u8; 4]>,
+    attached: Cell<bool>,
+    registered: Cell<bool>,
+    signal: Cell<(Option<i16>, Option<u8>)>,
+    /// The modem holds datagrams not read yet.
+    read_pending: Cell<bool>,
+    /// The last read returned a datagram, so there may be more.
+    read_more: Cell<bool>,
+    /// The datagram being received: room for the UDP header, then data.
+    datagram: TakeCell<'static, [u8]>,
+    datagram_len: Cell<usize>,
+    datagram_src: Cell<([u8; 4], u16)>,
+    tx_datagram: MapCell<LeasableBuffer<'static, u8>>,
+    tx_dest: Cell<([u8; 4], u16)>,
+    binding: MapCell<UdpPortBindingTx>,
+    client: OptionalCell<&'a dyn ModemClient>,
+    send_client: OptionalCell<&'a dyn UDPSendClient>,
+    receive_client: OptionalCell<&'a dyn IP6RecvClient>,
+    ip_vis: &'static IpVisibilityCapability,
+    udp_vis: &'static UdpVisibilityCapability,
+}
+
+impl<'a> Bg96<'a> {
+    pub fn new(
+        uart: &'a dyn uart::UartData<'a>,
+        tx_buffer: &'static mut [u8],
+        rx_buffer: &'static mut [u8],
+        line_buffer: &'static mut [u8],
+        datagram_buffer: &'static mut [u8],
+        apn: &'static str,
+        local_port: u16,
+        ip_vis: &'static IpVisibilityCapability,
+        udp_vis: &'static UdpVisibilityCapability,
+    ) -> Bg96<'a> {
+        Bg96 {
+            uart: uart,
+            tx_buffer: TakeCell::new(tx_buffer),
+            rx_buffer: TakeCell::new(rx_buffer),
+            line: TakeCell::new(line_buffer),
+            line_len: Cell::new(0),
+            rx_state: Cell::new(RxState::Line),
+            step: Cell::new(Step::Idle),
+            apn: apn,
+            local_port: local_port,
+            address: OptionalCell::empty(),
+            attached: Cell::new(false),
+            registered: Cell::new(false),
+            signal: Cell::new((None, None)),
+            read_pending: Cell::new(false),
+            read_more: Cell::new(false),
+            datagram: TakeCell::new(datagram_buffer),
+            datagram_len: Cell::new(0),
+            datagram_src: Cell::new(([0; 4], 0)),
+            tx_datagram: MapCell::empty(),
+            tx_dest: Cell::new(([0; 4], 0)),
+            binding: MapCell::empty(),
+            client: OptionalCell::empty(),
+            send_client: OptionalCell::empty(),
+            receive_client: OptionalCell::empty(),
+            ip_vis: ip_vis,
+            udp_vis: udp_vis,
+        }
+    }
+
+    pub fn set_modem_client(&self, client: &'a dyn ModemClient) {
+        self.client.set(client);
+    }
+
+    /// Received datagrams are passed to `client`.
+    pub fn set_receive_client(&self, client: &'a dyn IP6RecvClient) {
+        self.receive_client.set(client);
+    }
+
+    /// Start receiving from the modem.
+    pub fn initialize(&self) -> ReturnCode {
+        self.receive();
+        ReturnCode::SUCCESS
+    }
+
+    /// Activate the PDP context and open the socket. `attached()` is called
+    /// with the result.
+    pub fn attach(&self) -> ReturnCode {
+        if self.step.get() != Step::Idle {
+            return ReturnCode::EBUSY;
+        }
+        if self.attached.get() {
+            return ReturnCode::EALREADY;
+        }
+        self.registered.set(false);
+        self.address.clear();
+        self.command(Step::EchoOff, format_args!("ATE0\r"))
+    }
+
+    /// Close the socket and deactivate the PDP context. `detached()` is
+    /// called once done.
+    pub fn detach(&self) -> ReturnCode {
+        if self.step.get() != Step::Idle {
+            return ReturnCode::EBUSY;
+        }
+        if !self.attached.get() {
+            return ReturnCode::EALREADY;
+        }
+        self.attached.set(false);
+        self.command(Step::CloseSocket, format_args!("AT+QICLOSE={}\r", SOCKET))
+    }
+
+    /// Ask the modem for the signal quality, which is passed to
+    /// `signal_quality()`.
+    pub fn read_signal_quality(&self) -> ReturnCode {
+        if self.step.get() != Step::Idle {
+            return ReturnCode::EBUSY;
+        }
+        self.signal.set((None, None));
+        self.command(Step::Signal, format_args!("AT+CSQ\r"))
+    }
+
+    /// The address the network assigned, as an IPv4-mapped address.
+    pub fn local_address(&self) -> Option<IPAddr> {
+        if self.attached.get() {
+            self.address.map(|address| ipv4_mapped(*address))
+        } else {
+            None
+        }
+    }
+
+    fn receive(&self) {
+        self.rx_buffer.take().map(|buffer| {
+            let (rcode, buffer) = self.uart.receive_buffer(buffer, 1);
+            if rcode != ReturnCode::SUCCESS {
+                buffer.map(|buffer| self.rx_buffer.replace(buffer));
+            }
+        });
+    }
+
+    /// Send an AT command, and wait for its result in `step`.
+    fn command(&self, step: Step, args: fmt::Arguments) -> ReturnCode {
+        self.tx_buffer.take().map_or(ReturnCode::EBUSY, |buffer| {
+            let mut writer = WriteAdapter::new(buffer);
+            if writer.write_fmt(args).is_err() {
+                self.tx_buffer.replace(writer.buffer);
+                return ReturnCode::ESIZE;
+            }
+            let (buffer, len) = (writer.buffer, writer.used);
+            match self.uart.transmit_buffer(buffer, len) {
+                (ReturnCode::SUCCESS, _) => {
+                    self.step.set(step);
+                    ReturnCode::SUCCESS
+                }
+                (rcode, buffer) => {
+                    buffer.map(|buffer| self.tx_buffer.replace(buffer));
+                    rcode
+                }
+            }
+        })
+    }
+
+    /// Go on to the next command of a sequence.
+    fn next_step(&self, step: Step, args: fmt::Arguments) {
+        if self.command(step, args) != ReturnCode::SUCCESS {
+            self.step.set(step);
+            self.error();
+        }
+    }
+
+    /// Start the next command once idle: read datagrams the modem holds,
+    /// then send the one waiting.
+    fn next(&self) {
+        if self.step.get() != Step::Idle {
+            return;
+        }
+        if self.read_pending.get() && self.attached.get() {
+            self.read_pending.set(false);
+            self.read_more.set(false);
+            let _ = self.command(Step::Read, format_args!("AT+QIRD={}\r", SOCKET));
+        } else if self.tx_datagram.is_some() {
+            if !self.attached.get() {
+                self.send_done(ReturnCode::EOFF);
+                return;
+            }
+            let len = self.tx_datagram.map_or(0, |datagram| datagram.len());
+            let ([a, b, c, d], port) = self.tx_dest.get();
+            let rcode = self.command(
+                Step::SendCommand,
+                format_args!(
+                    "AT+QISEND={},{},\"{}.{}.{}.{}\",{}\r",
+                    SOCKET, len, a, b, c, d, port
+                ),
+            );
+            if rcode != ReturnCode::SUCCESS {
+                self.send_done(rcode);
+            }
+        }
+    }
+
+    fn finish_attach(&self, result: ReturnCode) {
+        self.attached.set(result == ReturnCode::SUCCESS);
+        self.step.set(Step::Idle);
+        self.client.map(|client| client.attached(result));
+        self.next();
+    }
+
+    fn finish_detach(&self) {
+        self.step.set(Step::Idle);
+        self.client.map(|client| client.detached());
+        self.next();
+    }
+
+    fn send_done(&self, result: ReturnCode) {
+        self.step.set(Step::Idle);
+        self.tx_datagram.take().map(|datagram| {
+            self.send_client
+                .map(|client| client.send_done(result, datagram));
+        });
+        self.next();
+    }
+
+    /// The modem prompted for the datagram.
+    fn prompt(&self) {
+        let rcode = self.tx_buffer.take().map_or(ReturnCode::EBUSY, |buffer| {
+            let len = self.tx_datagram.map_or(0, |datagram| {
+                let len = datagram.len();
+                buffer[..len].copy_from_slice(&datagram[..]);
+                len
+            });
+            match self.uart.transmit_buffer(buffer, len) {
+                (ReturnCode::SUCCESS, _) => {
+                    self.step.set(Step::SendData);
+                    ReturnCode::SUCCESS
+                }
+                (rcode, buffer) => {
+                    buffer.map(|buffer| self.tx_buffer.replace(buffer));
+                    rcode
+                }
+            }
+        });
+        if rcode != ReturnCode::SUCCESS {
+            self.send_done(rcode);
+        }
+    }
+
+    /// Pass the datagram just read up behind IPv6 and UDP headers.
+    fn deliver(&self) {
+        let len = self.datagram_len.get();
+        let (src, src_port) = self.datagram_src.get();
+        let dst = self.address.map_or([0; 4], |address| *address);
+        self.datagram.map(|buffer| {
+            let mut udp_header = UDPHeader::new();
+            udp_header.set_src_port(src_port);
+            udp_header.set_dst_port(self.local_port);
+            udp_header.set_len((UDP_HDR_LEN + len) as u16);
+            if udp_header.encode(buffer, 0).done().is_none() {
+                return;
+            }
+            let mut ip_header = IP6Header::new();
+            ip_header.src_addr = ipv4_mapped(src);
+            ip_header.dst_addr = ipv4_mapped(dst);
+            ip_header.set_next_header(ip6_nh::UDP);
+            ip_header.set_payload_len((UDP_HDR_LEN + len) as u16);
+            self.receive_client
+                .map(|client| client.receive(ip_header, &buffer[..UDP_HDR_LEN + len], None));
+        });
+    }
+
+    fn received_byte(&self, byte: u8) {
+        match self.rx_state.get() {
+            RxState::Data(left) => {
+                let len = self.datagram_len.get();
+                // Datagrams too long for the buffer are dropped.
+                if len <= MAX_DATAGRAM_LEN {
+                    self.datagram
+                        .map(|buffer| buffer[UDP_HDR_LEN + len - left] = byte);
+                }
+                if left > 1 {
+                    self.rx_state.set(RxState::Data(left - 1));
+                } else {
+                    self.rx_state.set(RxState::Line);
+                    if len <= MAX_DATAGRAM_LEN {
+                        self.deliver();
+                    }
+                }
+            }
+            RxState::Line => {
+                self.line.take().map(|line| {
+                    let len = self.line_len.get();
+                    if byte == b'\n' {
+                        self.line_len.set(0);
+                        let end = if len > 0 && line[len - 1] == b'\r' {
+                            len - 1
+                        } else {
+                            len
+                        };
+                        if end > 0 {
+                            self.handle_line(&line[..end]);
+                        }
+                    } else if len < line.len() {
+                        line[len] = byte;
+                        self.line_len.set(len + 1);
+                        // The prompt is not followed by a newline.
+                        if self.step.get() == Step::SendCommand && &line[..len + 1] == b"> " {
+                            self.line_len.set(0);
+                            self.prompt();
+                        }
+                    }
+                    self.line.replace(line);
+                });
+            }
+        }
+    }
+
+    fn handle_line(&self, line: &[u8]) {
+        if let Some(rest) = strip(line, b"+QIURC: ") {
+            self.urc(rest);
+            return;
+        }
+        match self.step.get() {
+            Step::Registration => {
+                if let Some(rest) = strip(line, b"+CEREG: ") {
+                    // 1 is registered on the home network, 5 roaming.
+                    let stat = field(rest, 1).and_then(parse_number);
+                    self.registered.set(stat == Some(1) || stat == Some(5));
+                }
+            }
+            Step::QueryAddress => {
+                if let Some(rest) = strip(line, b"+QIACT: ") {
+                    if field(rest, 0).and_then(parse_number) == Some(CONTEXT as u32) {
+                        field(rest, 3)
+                            .and_then(parse_ipv4)
+                            .map(|address| self.address.set(address));
+                    }
+                }
+            }
+            Step::Signal => {
+                if let Some(rest) = strip(line, b"+CSQ: ") {
+                    let rssi = field(rest, 0)
+                        .and_then(parse_number)
+                        .filter(|&rssi| rssi <= 31)
+                        .map(|rssi| -113 + 2 * rssi as i16);
+                    let ber = field(rest, 1)
+                        .and_then(parse_number)
+                        .filter(|&ber| ber <= 7)
+                        .map(|ber| ber as u8);
+                    self.signal.set((rssi, ber));
+                }
+            }
+            Step::Read => {
+                if let Some(rest) = strip(line, b"+QIRD: ") {
+                    let len = field(rest, 0).and_then(parse_number).unwrap_or(0) as usize;
+                    let src = field(rest, 1).and_then(parse_ipv4);
+                    let port = field(rest, 2).and_then(parse_number);
+                    self.read_more.set(len > 0);
+                    if len > 0 {
+                        self.datagram_src
+                            .set((src.unwrap_or([0; 4]), port.unwrap_or(0) as u16));
+                        self.datagram_len.set(len);
+                        self.rx_state.set(RxState::Data(len));
+                    }
+                    return;
+                }
+            }
+            Step::WaitOpen => {
+                if let Some(rest) = strip(line, b"+QIOPEN: ") {
+                    if field(rest, 1).and_then(parse_number) == Some(0) {
+                        self.finish_attach(ReturnCode::SUCCESS);
+                    } else {
+                        self.finish_attach(ReturnCode::FAIL);
+                    }
+                    return;
+                }
+            }
+            Step::SendData => {
+                if line == b"SEND OK" {
+                    self.send_done(ReturnCode::SUCCESS);
+                    return;
+                } else if line == b"SEND FAIL" {
+                    self.send_done(ReturnCode::FAIL);
+                    return;
+                }
+            }
+            _ => {}
+        }
+        if line == b"OK" {
+            self.ok();
+        } else if line == b"ERROR" || line.starts_with(b"+CME ERROR") {
+            self.error();
+        }
+    }
+
+    /// Handle an unsolicited result code about the socket.
+    fn urc(&self, rest: &[u8]) {
+        let kind = field(rest, 0);
+        if kind == Some(b"recv") {
+            self.read_pending.set(true);
+            self.next();
+        } else if (kind == Some(b"closed") || kind == Some(b"pdpdeact")) && self.attached.get() {
+            self.attached.set(false);
+            self.client.map(|client| client.detached());
+        }
+    }
+
+    fn ok(&self) {
+        match self.step.get() {
+            Step::EchoOff => self.next_step(Step::Registration, format_args!("AT+CEREG?\r")),
+            Step::Registration => {
+                if self.registered.get() {
+                    self.next_step(
+                        Step::DefineContext,
+                        format_args!("AT+CGDCONT={},\"IP\",\"{}\"\r", CONTEXT, self.apn),
+                    );
+                } else {
+                    self.finish_attach(ReturnCode::EOFF);
+                }
+            }
+            Step::DefineContext => {
+                self.next_step(Step::Activate, format_args!("AT+QIACT={}\r", CONTEXT))
+            }
+            Step::Activate => self.next_step(Step::QueryAddress, format_args!("AT+QIACT?\r")),
+            Step::QueryAddress => {
+                if self.address.is_some() {
+                    self.next_step(
+                        Step::OpenSocket,
+                        format_args!(
+                            "AT+QIOPEN={},{},\"UDP SERVICE\",\"127.0.0.1\",0,{},0\r",
+                            CONTEXT, SOCKET, self.local_port
+                        ),
+                    );
+                } else {
+                    self.finish_attach(ReturnCode::FAIL);
+                }
+            }
+            Step::OpenSocket => self.step.set(Step::WaitOpen),
+            Step::CloseSocket => {
+                self.next_step(Step::Deactivate, format_args!("AT+QIDEACT={}\r", CONTEXT))
+            }
+            Step::Deactivate => self.finish_detach(),
+            Step::Signal => {
+                self.step.set(Step::Idle);
+                let (rssi, ber) = self.signal.get();
+                self.client.map(|client| client.signal_quality(rssi, ber));
+                self.next();
+            }
+            Step::Read => {
+                self.step.set(Step::Idle);
+                self.read_pending.set(self.read_more.get());
+                self.next();
+            }
+            _ => {}
+        }
+    }
+
+    fn error(&self) {
+        match self.step.get() {
+            Step::EchoOff
+            | Step::Registration
+            | Step::DefineContext
+            | Step::QueryAddress
+            | Step::OpenSocket
+            | Step::WaitOpen => self.finish_attach(ReturnCode::FAIL),
+            // The context may already be active, which the query shows.
+            Step::Activate => self.next_step(Step::QueryAddress, format_args!("AT+QIACT?\r")),
+            Step::CloseSocket => {
+                self.next_step(Step::Deactivate, format_args!("AT+QIDEACT={}\r", CONTEXT))
+            }
+            Step::Deactivate => self.finish_detach(),
+            Step::Signal => {
+                self.step.set(Step::Idle);
+                self.client.map(|client| client.signal_quality(None, None));
+                self.next();
+            }
+            Step::Read => {
+                self.step.set(Step::Idle);
+                self.next();
+            }
+            Step::SendCommand | Step::SendData => self.send_done(ReturnCode::FAIL),
+            Step::Idle => {}
+        }
+    }
+}
+
+impl uart::TransmitClient for Bg96<'_> {
+    fn transmitted_buffer(&self, buffer: &'static mut [u8], _tx_len: usize, rval: ReturnCode) {
+        self.tx_buffer.replace(buffer);
+        if rval != ReturnCode::SUCCESS {
+            // No result will come.
+            self.error();
+        }
+    }
+}
+
+impl uart::ReceiveClient for Bg96<'_> {
+    fn received_buffer(
+        &self,
+        buffer: &'static mut [u8],
+        rx_len: usize,
+        rval: ReturnCode,
+        _error: uart::Error,
+    ) {
+        let byte = buffer[0];
+        self.rx_buffer.replace(buffer);
+        if rval == ReturnCode::SUCCESS && rx_len == 1 {
+            self.received_byte(byte);
+        }
+        self.receive();
+    }
+}
+
+impl<'a> UDPSender<'a> for Bg96<'a> {
+    fn set_client(&self, client: &'a dyn UDPSendClient) {
+        self.send_client.set(client);
+    }
+
+    fn send_to(
+        &'a self,
+        dest: IPAddr,
+        dst_port: u16,
+        buf: LeasableBuffer<'static, u8>,
+        net_cap: &'static NetworkCapability,
+    ) -> Result<(), LeasableBuffer<'static, u8>> {
+        let src_port = self.binding.map_or(0, |binding| binding.get_port());
+        let mut udp_header = UDPHeader::new();
+        udp_header.set_dst_port(dst_port);
+        udp_header.set_src_port(src_port);
+        self.send(dest, udp_header, buf, net_cap)
+    }
+
+    fn driver_send_to(
+        &'a self,
+        dest: IPAddr,
+        dst_port: u16,
+        src_port: u16,
+        buf: LeasableBuffer<'static, u8>,
+        _driver_send_cap: &dyn UdpDriverCapability,
+        net_cap: &'static NetworkCapability,
+    ) -> Result<(), LeasableBuffer<'static, u8>> {
+        let mut udp_header = UDPHeader::new();
+        udp_header.set_dst_port(dst_port);
+        udp_header.set_src_port(src_port);
+        self.send(dest, udp_header, buf, net_cap)
+    }
+
+    fn send(
+        &'a self,
+        dest: IPAddr,
+        udp_header: UDPHeader,
+        buf: LeasableBuffer<'static, u8>,
+        net_cap: &'static NetworkCapability,
+    ) -> Result<(), LeasableBuffer<'static, u8>> {
+        let dst_port = udp_header.get_dst_port();
+        let address = match ipv4_of(dest) {
+            Some(address) => address,
+            None => return Err(buf),
+        };
+        if !self.attached.get()
+            || self.tx_datagram.is_some()
+            || buf.len() > MAX_DATAGRAM_LEN
+            || udp_header.get_src_port() != self.local_port
+            || !net_cap.remote_addr_valid(dest, self.ip_vis)
+            || !net_cap.remote_port_valid(dst_port, self.udp_vis)
+            || !net_cap.local_port_valid(self.local_port, self.udp_vis)
+        {
+            return Err(buf);
+        }
+        self.tx_dest.set((address, dst_port));
+        self.tx_datagram.replace(buf);
+        self.next();
+        Ok(())
+    }
+
+    fn get_binding(&self) -> Option<UdpPortBindingTx> {
+        self.binding.take()
+    }
+
+    fn is_bound(&self) -> bool {
+        self.binding.is_some()
+    }
+
+    fn set_binding(&self, binding: UdpPortBindingTx) -> Option<UdpPortBindingTx> {
+        self.binding.replace(binding)
+    }
+}
+
+/// Lets `write!` format AT commands into a buffer.
+struct WriteAdapter {
+    buffer: &'static mut [u8],
+    used: usize,
+}
+
+impl WriteAdapter {
+    fn new(buffer: &'static mut [u8]) -> WriteAdapter {
+        WriteAdapter {
+            buffer: buffer,
+            used: 0,
+        }
+    }
+}
+
+impl Write for WriteAdapter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.used + s.len();
+        if end > self.buffer.len() {
+            return Err(fmt::Error);
+        }
+        self.buffer[self.used..end].copy_from_slice(s.as_bytes());
+        self.used = end;
+        Ok(())
+    }
+}
+
+fn strip<'b>(line: &'b [u8], prefix: &[u8]) -> Option<&'b [u8]> {
+    if line.starts_with(prefix) {
+        Some(&line[prefix.len()..])
+    } else {
+        None
+    }
+}
+
+/// Field `n` of a comma separated result, without its quotes.
+fn field(s: &[u8], n: usize) -> Option<&[u8]> {
+    s.split(|&b| b == b',').nth(n).map(|field| {
+        if field.len() >= 2 && field[0] == b'"' && field[field.len() - 1] == b'"' {
+            &field[1..field.len() - 1]
+        } else {
+            field
+        }
+    })
+}
+
+fn parse_number(s: &[u8]) -> Option<u32> {
+    if s.is_empty() {
+        return None;
+    }
+    s.iter().try_fold(0u32, |n, &b| {
+        if b.is_ascii_digit() {
+            n.checked_mul(10)?.checked_add((b - b'0') as u32)
+        } else {
+            None
+        }
+    })
+}
+
+fn parse_ipv4(s: &[u8]) -> Option<[u8; 4]> {
+    let mut address = [0; 4];
+    let mut parts = s.split(|&b| b == b'.');
+    for byte in address.iter_mut() {
+        *byte = parse_number(parts.next()?).filter(|&n| n <= 255)? as u8;
+    }
+    if parts.next().is_some() {
+        None
+    } else {
+        Some(address)
+    }
+}
+
+fn ipv4_mapped(address: [u8; 4]) -> IPAddr {
+    let mut mapped = IPAddr::new();
+    mapped.0[10] = 0xff;
+    mapped.0[11] = 0xff;
+    mapped.0[12..].copy_from_slice(&address);
+    mapped
+}
+
+fn ipv4_of(address: IPAddr) -> Option<[u8; 4]> {
+    if address.0[..10].iter().all(|&b| b == 0) && address.0[10] == 0xff && address.0[11] == 0xff {
+        let mut ipv4 = [0; 4];
+        ipv4.copy_from_slice(&address.0[12..]);
+        Some(ipv4)
+    } else {
+        None
+    }
+}
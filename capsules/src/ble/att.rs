@@ -0,0 +1,102 @@
+//! Attribute Protocol definitions
+//!
+//! Opcodes, error codes and UUIDs shared by the GATT server and client. ATT
+//! PDUs are carried on the `l2cap::CID_ATT` channel and start with a one byte
+//! opcode.
+
+/// The ATT MTU we use, which is the minimum for LE.
+pub const ATT_MTU: usize = 23;
+
+/// Error codes, which `gatt_server::CharacteristicClient`s can also return.
+pub const ERROR_INVALID_HANDLE: u8 = 0x01;
+pub const ERROR_READ_NOT_PERMITTED: u8 = 0x02;
+pub const ERROR_WRITE_NOT_PERMITTED: u8 = 0x03;
+pub const ERROR_INVALID_PDU: u8 = 0x04;
+pub const ERROR_INSUFFICIENT_AUTHENTICATION: u8 = 0x05;
+pub const ERROR_REQUEST_NOT_SUPPORTED: u8 = 0x06;
+pub const ERROR_INVALID_OFFSET: u8 = 0x07;
+pub const ERROR_ATTRIBUTE_NOT_FOUND: u8 = 0x0a;
+pub const ERROR_INVALID_ATTRIBUTE_VALUE_LENGTH: u8 = 0x0d;
+pub const ERROR_UNLIKELY: u8 = 0x0e;
+pub const ERROR_UNSUPPORTED_GROUP_TYPE: u8 = 0x10;
+
+/// ATT opcodes.
+pub const ERROR_RSP: u8 = 0x01;
+pub const EXCHANGE_MTU_REQ: u8 = 0x02;
+pub const EXCHANGE_MTU_RSP: u8 = 0x03;
+pub const FIND_INFORMATION_REQ: u8 = 0x04;
+pub const FIND_INFORMATION_RSP: u8 = 0x05;
+pub const FIND_BY_TYPE_VALUE_REQ: u8 = 0x06;
+pub const FIND_BY_TYPE_VALUE_RSP: u8 = 0x07;
+pub const READ_BY_TYPE_REQ: u8 = 0x08;
+pub const READ_BY_TYPE_RSP: u8 = 0x09;
+pub const READ_REQ: u8 = 0x0a;
+pub const READ_RSP: u8 = 0x0b;
+pub const READ_BLOB_REQ: u8 = 0x0c;
+pub const READ_BLOB_RSP: u8 = 0x0d;
+pub const READ_BY_GROUP_TYPE_REQ: u8 = 0x10;
+pub const READ_BY_GROUP_TYPE_RSP: u8 = 0x11;
+pub const WRITE_REQ: u8 = 0x12;
+pub const WRITE_RSP: u8 = 0x13;
+pub const HANDLE_VALUE_NTF: u8 = 0x1b;
+pub const HANDLE_VALUE_IND: u8 = 0x1d;
+pub const HANDLE_VALUE_CFM: u8 = 0x1e;
+pub const WRITE_CMD: u8 = 0x52;
+/// Set in the opcode of commands, which get no response.
+pub const COMMAND_FLAG: u8 = 0x40;
+
+/// Attribute types.
+pub const PRIMARY_SERVICE: Uuid = Uuid::Uuid16(0x2800);
+pub const CHARACTERISTIC: Uuid = Uuid::Uuid16(0x2803);
+pub const CLIENT_CHARACTERISTIC_CONFIGURATION: Uuid = Uuid::Uuid16(0x2902);
+
+/// The Bluetooth Base UUID, least significant byte first, with zeros where
+/// a 16 bit UUID goes.
+pub const BASE_UUID: [u8; 16] = [
+    0xfb, 0x34, 0x9b, 0x5f, 0x80, 0x00, 0x00, 0x80, 0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Uuid {
+    /// A UUID assigned by the Bluetooth SIG, shortened.
+    Uuid16(u16),
+    /// Any other UUID, least significant byte first.
+    Uuid128([u8; 16]),
+}
+
+impl Uuid {
+    /// Parse a UUID as sent in ATT PDUs: 2 or 16 bytes, least significant
+    /// byte first. 128 bit forms of SIG UUIDs are shortened.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Uuid> {
+        match bytes.len() {
+            2 => Some(Uuid::Uuid16(u16::from_le_bytes([bytes[0], bytes[1]]))),
+            16 => {
+                if bytes[..12] == BASE_UUID[..12] && bytes[14..] == BASE_UUID[14..] {
+                    Some(Uuid::Uuid16(u16::from_le_bytes([bytes[12], bytes[13]])))
+                } else {
+                    let mut uuid = [0; 16];
+                    uuid.copy_from_slice(bytes);
+                    Some(Uuid::Uuid128(uuid))
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// Length as sent in ATT PDUs.
+    pub fn size(&self) -> usize {
+        match *self {
+            Uuid::Uuid16(_) => 2,
+            Uuid::Uuid128(_) => 16,
+        }
+    }
+
+    /// Write the UUID as sent in ATT PDUs and return its length.
+    pub fn write_to(&self, buf: &mut [u8]) -> usize {
+        match *self {
+            Uuid::Uuid16(uuid) => buf[..2].copy_from_slice(&uuid.to_le_bytes()),
+            Uuid::Uuid128(ref uuid) => buf[..16].copy_from_slice(uuid),
+        }
+        self.size()
+    }
+}
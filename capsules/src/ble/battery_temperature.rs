@@ -0,0 +1,231 @@
+//! Battery and temperature GATT services
+//!
+//! An example of a capsule serving characteristics from the kernel: the
+//! Battery Service reports the state of charge of a `hil::battery` gauge in
+//! its Battery Level characteristic, and the Environmental Sensing Service
+//! the reading of a `hil::sensors::TemperatureDriver` in its Temperature
+//! characteristic. Both can be read and notified.
+//!
+//! The sensors are read every `period_ms` milliseconds, since the GATT server
+//! needs an answer to a read right away. Reads before the first measurement
+//! fail with Unlikely Error. A central that enabled notifications gets every
+//! changed value.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//! # use capsules::virtual_alarm::VirtualMuxAlarm;
+//! # use capsules::ble::battery_temperature::{self, BatteryTemperature};
+//! # use capsules::ble::gatt_server::{Characteristic, Service};
+//! # use capsules::ble::gatt_server::{PROPERTY_NOTIFY, PROPERTY_READ};
+//!
+//! let battery_level = static_init!(
+//!     [Characteristic<'static>; 1],
+//!     [Characteristic::new(battery_temperature::BATTERY_LEVEL, PROPERTY_READ | PROPERTY_NOTIFY)]
+//! );
+//! let temperature = static_init!(
+//!     [Characteristic<'static>; 1],
+//!     [Characteristic::new(battery_temperature::TEMPERATURE, PROPERTY_READ | PROPERTY_NOTIFY)]
+//! );
+//! let battery_service = static_init!(
+//!     Service<'static>,
+//!     Service::new(battery_temperature::BATTERY_SERVICE, battery_level)
+//! );
+//! let sensing_service = static_init!(
+//!     Service<'static>,
+//!     Service::new(battery_temperature::ENVIRONMENTAL_SENSING_SERVICE, temperature)
+//! );
+//! let sensors_alarm = static_init!(VirtualMuxAlarm<'static, Rtc>, VirtualMuxAlarm::new(mux_alarm));
+//! let sensors = static_init!(
+//!     BatteryTemperature<'static, VirtualMuxAlarm<'static, Rtc>>,
+//!     BatteryTemperature::new(
+//!         gatt,
+//!         &battery_level[0],
+//!         &temperature[0],
+//!         max17048,
+//!         &nrf52::temperature::TEMP,
+//!         sensors_alarm,
+//!         10000
+//!     )
+//! );
+//! battery_level[0].set_client(sensors);
+//! temperature[0].set_client(sensors);
+//! kernel::hil::battery::Battery::set_client(max17048, sensors);
+//! kernel::hil::sensors::TemperatureDriver::set_client(&nrf52::temperature::TEMP, sensors);
+//! sensors_alarm.set_client(sensors);
+//! gatt.add_service(battery_service);
+//! gatt.add_service(sensing_service);
+//! sensors.start();
+//! ```
+
+use core::cell::Cell;
+
+use kernel::hil::battery::{Battery, BatteryClient, ChargingState};
+use kernel::hil::sensors::{TemperatureClient, TemperatureDriver};
+use kernel::hil::time::{self, Alarm};
+use kernel::ReturnCode;
+
+use super::att::{self, Uuid};
+use super::gatt_server::{Characteristic, CharacteristicClient, GattServer};
+
+pub const BATTERY_SERVICE: Uuid = Uuid::Uuid16(0x180f);
+/// The state of charge in percent, a `u8`.
+pub const BATTERY_LEVEL: Uuid = Uuid::Uuid16(0x2a19);
+
+pub const ENVIRONMENTAL_SENSING_SERVICE: Uuid = Uuid::Uuid16(0x181a);
+/// The temperature in hundredths of a degree Celsius, an `i16`.
+pub const TEMPERATURE: Uuid = Uuid::Uuid16(0x2a6e);
+
+pub struct BatteryTemperature<'a, A: Alarm<'a>> {
+    gatt: &'a GattServer<'a>,
+    battery_level: &'a Characteristic<'a>,
+    temperature: &'a Characteristic<'a>,
+    battery: &'a dyn Battery<'a>,
+    sensor: &'a dyn TemperatureDriver<'a>,
+    alarm: &'a A,
+    period_ms: u32,
+
+    /// The last measurements, `None` until the first one.
+    level: Cell<Option<u8>>,
+    celsius: Cell<Option<i16>>,
+    /// The value changed and still has to be notified.
+    notify_level: Cell<bool>,
+    notify_celsius: Cell<bool>,
+}
+
+impl<'a, A: Alarm<'a>> BatteryTemperature<'a, A> {
+    pub fn new(
+        gatt: &'a GattServer<'a>,
+        battery_level: &'a Characteristic<'a>,
+        temperature: &'a Characteristic<'a>,
+        battery: &'a dyn Battery<'a>,
+        sensor: &'a dyn TemperatureDriver<'a>,
+        alarm: &'a A,
+        period_ms: u32,
+    ) -> BatteryTemperature<'a, A> {
+        BatteryTemperature {
+            gatt: gatt,
+            battery_level: battery_level,
+            temperature: temperature,
+            battery: battery,
+            sensor: sensor,
+            alarm: alarm,
+            period_ms: period_ms,
+            level: Cell::new(None),
+            celsius: Cell::new(None),
+            notify_level: Cell::new(false),
+            notify_celsius: Cell::new(false),
+        }
+    }
+
+    /// Take the first measurements and keep measuring.
+    pub fn start(&self) {
+        self.measure();
+    }
+
+    fn measure(&self) {
+        self.battery.read_state_of_charge();
+        self.sensor.read_temperature();
+        self.alarm
+            .set_alarm(self.alarm.now(), A::ticks_from_ms(self.period_ms));
+    }
+
+    fn is(&self, characteristic: &Characteristic, ours: &Characteristic) -> bool {
+        characteristic.value_handle() == ours.value_handle()
+    }
+
+    /// Notify the values that changed. Whatever cannot be sent yet is sent
+    /// from `notification_sent`.
+    fn send_notifications(&self) {
+        if self.notify_level.get() {
+            if let Some(level) = self.level.get() {
+                if self.gatt.notify(self.battery_level, &[level]) != ReturnCode::EBUSY {
+                    self.notify_level.set(false);
+                }
+            }
+        }
+        if self.notify_celsius.get() {
+            if let Some(celsius) = self.celsius.get() {
+                if self.gatt.notify(self.temperature, &celsius.to_le_bytes()) != ReturnCode::EBUSY {
+                    self.notify_celsius.set(false);
+                }
+            }
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> time::AlarmClient for BatteryTemperature<'a, A> {
+    fn alarm(&self) {
+        self.measure();
+    }
+}
+
+impl<'a, A: Alarm<'a>> BatteryClient for BatteryTemperature<'a, A> {
+    fn state_of_charge(&self, result: Result<u16, ReturnCode>) {
+        if let Ok(hundredths) = result {
+            let level = Some((core::cmp::min(hundredths, 10000) / 100) as u8);
+            if self.level.replace(level) != level {
+                self.notify_level
+                    .set(self.battery_level.notifications_enabled());
+                self.send_notifications();
+            }
+        }
+    }
+
+    fn voltage(&self, _result: Result<u32, ReturnCode>) {}
+
+    fn charging_state(&self, _result: Result<ChargingState, ReturnCode>) {}
+
+    fn threshold_set(&self, _result: ReturnCode) {}
+
+    fn low_battery(&self) {}
+}
+
+impl<'a, A: Alarm<'a>> TemperatureClient for BatteryTemperature<'a, A> {
+    fn callback(&self, value: usize) {
+        // Negative readings arrive in two's complement.
+        let celsius = Some(value as i16);
+        if self.celsius.replace(celsius) != celsius {
+            self.notify_celsius
+                .set(self.temperature.notifications_enabled());
+            self.send_notifications();
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> CharacteristicClient for BatteryTemperature<'a, A> {
+    fn read_value(&self, characteristic: &Characteristic, value: &mut [u8]) -> Result<usize, u8> {
+        if self.is(characteristic, self.battery_level) {
+            self.level.get().map(|level| {
+                value[0] = level;
+                1
+            })
+        } else {
+            self.celsius.get().map(|celsius| {
+                value[..2].copy_from_slice(&celsius.to_le_bytes());
+                2
+            })
+        }
+        .ok_or(att::ERROR_UNLIKELY)
+    }
+
+    fn write_value(&self, _characteristic: &Characteristic, _value: &[u8]) -> Result<(), u8> {
+        Err(att::ERROR_WRITE_NOT_PERMITTED)
+    }
+
+    fn notifications_changed(&self, characteristic: &Characteristic, enabled: bool) {
+        // Start the central off with the current value.
+        if self.is(characteristic, self.battery_level) {
+            self.notify_level.set(enabled);
+        } else {
+            self.notify_celsius.set(enabled);
+        }
+        self.send_notifications();
+    }
+
+    fn notification_sent(&self, _characteristic: &Characteristic, _result: ReturnCode) {
+        self.send_notifications();
+    }
+}
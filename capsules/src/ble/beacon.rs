@@ -0,0 +1,405 @@
+//! BLE beacons
+//!
+//! A system call driver that advertises an Eddystone-UID, Eddystone-URL or
+//! iBeacon frame, so apps describe the beacon instead of building raw
+//! advertising data. The frame is formatted in the kernel when it is
+//! configured and sent as a non-connectable advertisement on all three
+//! advertising channels every interval, plus the random delay of up to 10 ms
+//! the specification asks for.
+//!
+//! The beacon uses the advertising radio on its own, so boards offer either
+//! this driver or `ble_advertising_driver`. The first app to use a command
+//! other than the driver check owns the driver; other apps get `ERESERVE`.
+//!
+//! ## Instantiation
+//!
+//! ```rust
+//! # use kernel::static_init;
+//! # use capsules::virtual_alarm::VirtualMuxAlarm;
+//!
+//! let beacon_alarm = static_init!(VirtualMuxAlarm<'static, Rtc>, VirtualMuxAlarm::new(mux_alarm));
+//! let beacon = static_init!(
+//!     capsules::ble::beacon::Beacon<'static, nrf52::radio::Radio, VirtualMuxAlarm<'static, Rtc>>,
+//!     capsules::ble::beacon::Beacon::new(
+//!         &nrf52::radio::RADIO,
+//!         beacon_alarm,
+//!         [0x01, 0x02, 0x03, 0x04, 0x05, 0xc6],
+//!         static_init!([u8; 39], [0; 39]),
+//!         board_kernel.create_grant(&grant_cap)));
+//! kernel::hil::ble_advertising::BleAdvertisementDriver::set_transmit_client(
+//!     &nrf52::radio::RADIO, beacon);
+//! beacon_alarm.set_client(beacon);
+//! ```
+//!
+//! ## Syscall Interface
+//!
+//! - allow `0`: the contents of the frame, read when it is configured:
+//!   - Eddystone-UID: the 10 byte namespace followed by the 6 byte instance.
+//!   - Eddystone-URL: the URL, such as `https://example.com/`. It must start
+//!     with `http://` or `https://` and be at most 17 bytes once the scheme
+//!     and common endings like `.com/` are encoded.
+//!   - iBeacon: the 16 byte proximity UUID, then the major and minor numbers,
+//!     most significant byte first.
+//! - command `0`: driver check.
+//! - command `1`: configure an Eddystone-UID frame. `data` is the TX power
+//!   at 0 m in dBm, as an `i8`.
+//! - command `2`: configure an Eddystone-URL frame. `data` is the TX power at
+//!   0 m in dBm, as an `i8`.
+//! - command `3`: configure an iBeacon frame. `data` is the RSSI at 1 m in
+//!   dBm, as an `i8`.
+//! - command `4`: start advertising every `data` milliseconds, at least
+//!   `MIN_INTERVAL_MS`.
+//! - command `5`: stop advertising.
+//!
+//! Configuring a frame while advertising changes the frame sent from the
+//! next advertising event on. Commands `1` to `3` return `ESIZE` if the
+//! contents are too short or too long and `EINVAL` for an unknown URL
+//! scheme.
+
+use core::cell::Cell;
+
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::ble_advertising::{self, BleAdvertisementDriver, RadioChannel};
+use kernel::hil::time::{self, Alarm};
+use kernel::{AppId, AppSlice, Driver, Grant, ReturnCode, Shared};
+
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::BleBeacon as usize;
+
+/// The shortest advertising interval of non-connectable advertising.
+pub const MIN_INTERVAL_MS: u32 = 100;
+
+/// The longest advertising data.
+const MAX_DATA_LEN: usize = 31;
+const ADDRESS_LEN: usize = 6;
+
+/// ADV_NONCONN_IND with a random advertiser address.
+const ADV_NONCONN_IND_RANDOM: u8 = 0x42;
+
+/// The Flags AD structure: LE General Discoverable, BR/EDR not supported.
+const FLAGS: [u8; 3] = [0x02, 0x01, 0x06];
+
+/// The Eddystone service UUID, least significant byte first.
+const EDDYSTONE_UUID: [u8; 2] = [0xaa, 0xfe];
+const EDDYSTONE_UID: u8 = 0x00;
+const EDDYSTONE_URL: u8 = 0x10;
+const MAX_URL_LEN: usize = 17;
+
+const URL_SCHEMES: [&[u8]; 4] = [b"http://www.", b"https://www.", b"http://", b"https://"];
+/// Endings encoded as their index. Those with a `/` come first so that they
+/// take precedence.
+const URL_EXPANSIONS: [&[u8]; 14] = [
+    b".com/", b".org/", b".edu/", b".net/", b".info/", b".biz/", b".gov/", b".com", b".org",
+    b".edu", b".net", b".info", b".biz", b".gov",
+];
+
+/// The Apple company ID, least significant byte first, and the iBeacon type
+/// and length.
+const IBEACON_PREFIX: [u8; 4] = [0x4c, 0x00, 0x02, 0x15];
+
+/// Random delay added to each advertising interval.
+const MAX_DELAY_MS: u32 = 10;
+
+#[derive(Default)]
+pub struct App {
+    contents: Option<AppSlice<Shared, u8>>,
+}
+
+/// Write the advertising data of an Eddystone-UID frame to `data`.
+fn eddystone_uid(data: &mut [u8], tx_power: u8, contents: &[u8]) -> Result<usize, ReturnCode> {
+    if contents.len() != 16 {
+        return Err(ReturnCode::ESIZE);
+    }
+    let frame = eddystone_header(data, 18, EDDYSTONE_UID, tx_power);
+    data[frame..frame + 16].copy_from_slice(contents);
+    // Reserved.
+    data[frame + 16] = 0;
+    data[frame + 17] = 0;
+    Ok(frame + 18)
+}
+
+/// Write the advertising data of an Eddystone-URL frame to `data`.
+fn eddystone_url(data: &mut [u8], tx_power: u8, url: &[u8]) -> Result<usize, ReturnCode> {
+    let (scheme, mut rest) = URL_SCHEMES
+        .iter()
+        .enumerate()
+        .filter(|(_, prefix)| url.starts_with(prefix))
+        .max_by_key(|(_, prefix)| prefix.len())
+        .map(|(i, prefix)| (i as u8, &url[prefix.len()..]))
+        .ok_or(ReturnCode::EINVAL)?;
+
+    let mut encoded = [0; MAX_URL_LEN];
+    let mut len = 0;
+    while !rest.is_empty() {
+        if len == MAX_URL_LEN {
+            return Err(ReturnCode::ESIZE);
+        }
+        match URL_EXPANSIONS
+            .iter()
+            .position(|expansion| rest.starts_with(expansion))
+        {
+            Some(code) => {
+                encoded[len] = code as u8;
+                rest = &rest[URL_EXPANSIONS[code].len()..];
+            }
+            None => {
+                // Bytes that could be mistaken for codes can't be sent.
+                if rest[0] <= 0x20 || rest[0] >= 0x7f {
+                    return Err(ReturnCode::EINVAL);
+                }
+                encoded[len] = rest[0];
+                rest = &rest[1..];
+            }
+        }
+        len += 1;
+    }
+
+    let frame = eddystone_header(data, 1 + len, EDDYSTONE_URL, tx_power);
+    data[frame] = scheme;
+    data[frame + 1..frame + 1 + len].copy_from_slice(&encoded[..len]);
+    Ok(frame + 1 + len)
+}
+
+/// Write the flags, the Eddystone service UUID and the start of the service
+/// data for a frame with `len` bytes after the TX power. Returns where those
+/// bytes go.
+fn eddystone_header(data: &mut [u8], len: usize, frame_type: u8, tx_power: u8) -> usize {
+    data[..3].copy_from_slice(&FLAGS);
+    // Complete list of 16-bit service UUIDs.
+    data[3..7].copy_from_slice(&[0x03, 0x03, EDDYSTONE_UUID[0], EDDYSTONE_UUID[1]]);
+    // Service data: type, UUID, frame type and TX power.
+    data[7] = (5 + len) as u8;
+    data[8] = 0x16;
+    data[9..11].copy_from_slice(&EDDYSTONE_UUID);
+    data[11] = frame_type;
+    data[12] = tx_power;
+    13
+}
+
+/// Write the advertising data of an iBeacon frame to `data`.
+fn ibeacon(data: &mut [u8], measured_power: u8, contents: &[u8]) -> Result<usize, ReturnCode> {
+    if contents.len() != 20 {
+        return Err(ReturnCode::ESIZE);
+    }
+    data[..3].copy_from_slice(&FLAGS);
+    // Manufacturer specific data.
+    data[3] = 0x1a;
+    data[4] = 0xff;
+    data[5..9].copy_from_slice(&IBEACON_PREFIX);
+    data[9..29].copy_from_slice(contents);
+    data[29] = measured_power;
+    Ok(30)
+}
+
+pub struct Beacon<'a, R, A>
+where
+    R: BleAdvertisementDriver<'a>,
+    A: Alarm<'a>,
+{
+    radio: &'a R,
+    alarm: &'a A,
+    address: [u8; ADDRESS_LEN],
+    /// The frame, formatted as advertising data.
+    data: Cell<[u8; MAX_DATA_LEN]>,
+    data_len: Cell<usize>,
+    interval_ms: Cell<u32>,
+    advertising: Cell<bool>,
+    /// The channel being sent on during an advertising event.
+    channel: OptionalCell<RadioChannel>,
+    random: Cell<u32>,
+    buffer: TakeCell<'static, [u8]>,
+    apps: Grant<App>,
+    owner: OptionalCell<AppId>,
+}
+
+impl<'a, R, A> Beacon<'a, R, A>
+where
+    R: BleAdvertisementDriver<'a>,
+    A: Alarm<'a>,
+{
+    /// Advertise with the static random `address`, least significant byte
+    /// first. `buffer` must be 39 bytes long.
+    pub fn new(
+        radio: &'a R,
+        alarm: &'a A,
+        address: [u8; 6],
+        buffer: &'static mut [u8],
+        apps: Grant<App>,
+    ) -> Beacon<'a, R, A> {
+        Beacon {
+            radio: radio,
+            alarm: alarm,
+            address: address,
+            data: Cell::new([0; MAX_DATA_LEN]),
+            data_len: Cell::new(0),
+            interval_ms: Cell::new(0),
+            advertising: Cell::new(false),
+            channel: OptionalCell::empty(),
+            random: Cell::new(u32::from_le_bytes([
+                address[0], address[1], address[2], address[3],
+            ])),
+            buffer: TakeCell::new(buffer),
+            apps: apps,
+            owner: OptionalCell::empty(),
+        }
+    }
+
+    fn configure<F>(&self, app: &mut App, format: F) -> ReturnCode
+    where
+        F: FnOnce(&mut [u8], &[u8]) -> Result<usize, ReturnCode>,
+    {
+        let mut data = [0; MAX_DATA_LEN];
+        let result = app
+            .contents
+            .as_ref()
+            .map_or(Err(ReturnCode::EINVAL), |contents| {
+                format(&mut data, contents.as_ref())
+            });
+        match result {
+            Ok(len) => {
+                self.data.set(data);
+                self.data_len.set(len);
+                ReturnCode::SUCCESS
+            }
+            Err(rcode) => rcode,
+        }
+    }
+
+    fn start(&self, interval_ms: u32) -> ReturnCode {
+        if self.data_len.get() == 0 {
+            return ReturnCode::EOFF;
+        }
+        if interval_ms < MIN_INTERVAL_MS {
+            return ReturnCode::EINVAL;
+        }
+        self.interval_ms.set(interval_ms);
+        if !self.advertising.replace(true) {
+            self.schedule(0);
+        }
+        ReturnCode::SUCCESS
+    }
+
+    fn stop(&self) -> ReturnCode {
+        if !self.advertising.replace(false) {
+            return ReturnCode::EALREADY;
+        }
+        // An event in progress finishes on its own.
+        if self.channel.is_none() {
+            self.alarm.disarm();
+        }
+        ReturnCode::SUCCESS
+    }
+
+    /// Start the next advertising event after `ms` and the random delay.
+    fn schedule(&self, ms: u32) {
+        // Xorshift is plenty to spread out beacons.
+        let mut random = self.random.get();
+        random ^= random << 13;
+        random ^= random >> 17;
+        random ^= random << 5;
+        self.random.set(random);
+        let delay = ms + random % (MAX_DELAY_MS + 1);
+        self.alarm
+            .set_alarm(self.alarm.now(), A::ticks_from_ms(delay));
+    }
+
+    fn send(&self, channel: RadioChannel) {
+        match self.buffer.take() {
+            Some(buffer) => {
+                let len = self.data_len.get();
+                buffer[0] = ADV_NONCONN_IND_RANDOM;
+                buffer[1] = (ADDRESS_LEN + len) as u8;
+                buffer[2..2 + ADDRESS_LEN].copy_from_slice(&self.address);
+                buffer[2 + ADDRESS_LEN..2 + ADDRESS_LEN + len]
+                    .copy_from_slice(&self.data.get()[..len]);
+                self.channel.set(channel);
+                self.radio
+                    .transmit_advertisement(buffer, 2 + ADDRESS_LEN + len, channel);
+            }
+            None => self.schedule(self.interval_ms.get()),
+        }
+    }
+}
+
+impl<'a, R, A> time::AlarmClient for Beacon<'a, R, A>
+where
+    R: BleAdvertisementDriver<'a>,
+    A: Alarm<'a>,
+{
+    fn alarm(&self) {
+        if self.advertising.get() {
+            self.send(RadioChannel::AdvertisingChannel37);
+        }
+    }
+}
+
+impl<'a, R, A> ble_advertising::TxClient for Beacon<'a, R, A>
+where
+    R: BleAdvertisementDriver<'a>,
+    A: Alarm<'a>,
+{
+    fn transmit_event(&self, buf: &'static mut [u8], _result: ReturnCode) {
+        self.buffer.replace(buf);
+        let next = match self.channel.take() {
+            Some(RadioChannel::AdvertisingChannel37) => Some(RadioChannel::AdvertisingChannel38),
+            Some(RadioChannel::AdvertisingChannel38) => Some(RadioChannel::AdvertisingChannel39),
+            _ => None,
+        };
+        if !self.advertising.get() {
+            return;
+        }
+        match next {
+            Some(channel) => self.send(channel),
+            None => self.schedule(self.interval_ms.get()),
+        }
+    }
+}
+
+impl<'a, R, A> Driver for Beacon<'a, R, A>
+where
+    R: BleAdvertisementDriver<'a>,
+    A: Alarm<'a>,
+{
+    fn allow(
+        &self,
+        appid: AppId,
+        allow_num: usize,
+        slice: Option<AppSlice<Shared, u8>>,
+    ) -> ReturnCode {
+        match allow_num {
+            0 => self
+                .apps
+                .enter(appid, |app, _| {
+                    app.contents = slice;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    fn command(&self, command_num: usize, data: usize, _: usize, appid: AppId) -> ReturnCode {
+        if command_num == 0 {
+            return ReturnCode::SUCCESS;
+        }
+        if command_num > 5 {
+            return ReturnCode::ENOSUPPORT;
+        }
+        if self.owner.map_or(false, |owner| *owner != appid) {
+            return ReturnCode::ERESERVE;
+        }
+        self.owner.set(appid);
+
+        let power = data as u8;
+        self.apps
+            .enter(appid, |app, _| match command_num {
+                1 => self.configure(app, |data, contents| eddystone_uid(data, power, contents)),
+                2 => self.configure(app, |data, contents| eddystone_url(data, power, contents)),
+                3 => self.configure(app, |data, contents| ibeacon(data, power, contents)),
+                4 => self.start(data as u32),
+                5 => self.stop(),
+                _ => ReturnCode::ENOSUPPORT,
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+}
@@ -0,0 +1,246 @@
+//! Storage for BLE bonds
+//!
+//! Keeps the keys of peers we paired with in a region of nonvolatile storage
+//! so that they can encrypt the connection again after a reboot without
+//! pairing. Bonds are cached in RAM; the whole region is rewritten when one
+//! changes. Once all `MAX_BONDS` slots are used the oldest bond is replaced.
+//!
+//! Each slot holds:
+//!
+//! ```text
+//! 0        flags: valid (0x01), authenticated (0x02), has IRK (0x04),
+//!          random identity address (0x08)
+//! 1..7     identity address, least significant byte first
+//! 7..23    long term key, least significant byte first
+//! 23..39   identity resolving key, least significant byte first
+//! ```
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//! # use capsules::ble::bonds::{BondStore, REGION_LEN};
+//!
+//! let bond_buffer = static_init!([u8; REGION_LEN], [0; REGION_LEN]);
+//! let bonds = static_init!(
+//!     BondStore<'static>,
+//!     BondStore::new(nv_storage, 0x0, bond_buffer)
+//! );
+//! hil::nonvolatile_storage::NonvolatileStorage::set_client(nv_storage, bonds);
+//! bonds.load();
+//! ```
+
+use core::cell::Cell;
+
+use kernel::common::cells::TakeCell;
+use kernel::hil::ble_connection::DeviceAddress;
+use kernel::hil::nonvolatile_storage::{NonvolatileStorage, NonvolatileStorageClient};
+use kernel::ReturnCode;
+
+use super::crypto;
+
+pub const MAX_BONDS: usize = 4;
+const SLOT_LEN: usize = 40;
+/// Bytes of storage, and of the buffer, the bonds take.
+pub const REGION_LEN: usize = MAX_BONDS * SLOT_LEN;
+
+const FLAG_VALID: u8 = 0x01;
+const FLAG_AUTHENTICATED: u8 = 0x02;
+const FLAG_IRK: u8 = 0x04;
+const FLAG_RANDOM: u8 = 0x08;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Bond {
+    /// The peer's identity address. With an `irk` the peer may also connect
+    /// from resolvable private addresses.
+    pub address: DeviceAddress,
+    pub ltk: [u8; 16],
+    pub irk: Option<[u8; 16]>,
+    /// Whether pairing protected against man-in-the-middle attacks.
+    pub authenticated: bool,
+}
+
+impl Bond {
+    /// Whether `address` is this peer's, either its identity address or a
+    /// private address made with its IRK.
+    pub fn matches(&self, address: &DeviceAddress) -> bool {
+        if *address == self.address {
+            return true;
+        }
+        // Resolvable private addresses have 01 in the top bits of their
+        // random part, which is the three most significant bytes.
+        match self.irk {
+            Some(ref irk) if address.random && address.address[5] & 0xc0 == 0x40 => {
+                let mut key = *irk;
+                key.reverse();
+                let prand = [address.address[5], address.address[4], address.address[3]];
+                let hash = crypto::ah(&key, &prand);
+                hash == [address.address[2], address.address[1], address.address[0]]
+            }
+            _ => false,
+        }
+    }
+
+    fn write_to(&self, slot: &mut [u8]) {
+        let mut flags = FLAG_VALID;
+        if self.authenticated {
+            flags |= FLAG_AUTHENTICATED;
+        }
+        if self.address.random {
+            flags |= FLAG_RANDOM;
+        }
+        slot[23..39].copy_from_slice(&self.irk.map_or([0; 16], |irk| {
+            flags |= FLAG_IRK;
+            irk
+        }));
+        slot[0] = flags;
+        slot[1..7].copy_from_slice(&self.address.address);
+        slot[7..23].copy_from_slice(&self.ltk);
+    }
+
+    fn read_from(slot: &[u8]) -> Option<Bond> {
+        let flags = slot[0];
+        // Erased flash reads as 0xff.
+        if flags & FLAG_VALID == 0 || flags == 0xff {
+            return None;
+        }
+        let mut address = [0; 6];
+        address.copy_from_slice(&slot[1..7]);
+        let mut ltk = [0; 16];
+        ltk.copy_from_slice(&slot[7..23]);
+        let mut irk = [0; 16];
+        irk.copy_from_slice(&slot[23..39]);
+        Some(Bond {
+            address: DeviceAddress {
+                address: address,
+                random: flags & FLAG_RANDOM != 0,
+            },
+            ltk: ltk,
+            irk: if flags & FLAG_IRK != 0 {
+                Some(irk)
+            } else {
+                None
+            },
+            authenticated: flags & FLAG_AUTHENTICATED != 0,
+        })
+    }
+}
+
+pub struct BondStore<'a> {
+    storage: &'a dyn NonvolatileStorage<'static>,
+    address: usize,
+    buffer: TakeCell<'static, [u8]>,
+    bonds: Cell<[Option<Bond>; MAX_BONDS]>,
+    /// The slot replaced when all are used.
+    oldest: Cell<usize>,
+    /// Whether the bonds changed while the buffer was being written.
+    dirty: Cell<bool>,
+}
+
+impl<'a> BondStore<'a> {
+    /// Use `REGION_LEN` bytes of `storage` at `address`. `buffer` must be
+    /// `REGION_LEN` bytes long.
+    pub fn new(
+        storage: &'a dyn NonvolatileStorage<'static>,
+        address: usize,
+        buffer: &'static mut [u8],
+    ) -> BondStore<'a> {
+        BondStore {
+            storage: storage,
+            address: address,
+            buffer: TakeCell::new(buffer),
+            bonds: Cell::new([None; MAX_BONDS]),
+            oldest: Cell::new(0),
+            dirty: Cell::new(false),
+        }
+    }
+
+    /// Read the stored bonds. Until this completes no bonds are known.
+    pub fn load(&self) -> ReturnCode {
+        self.buffer.take().map_or(ReturnCode::EBUSY, |buffer| {
+            self.storage.read(buffer, self.address, REGION_LEN)
+        })
+    }
+
+    /// Find the bond with the peer using `address`.
+    pub fn lookup(&self, address: &DeviceAddress) -> Option<Bond> {
+        self.bonds
+            .get()
+            .iter()
+            .filter_map(|bond| *bond)
+            .find(|bond| bond.matches(address))
+    }
+
+    /// Add `bond`, replacing any earlier bond with the same peer, and write
+    /// the bonds to storage.
+    pub fn store(&self, bond: Bond) {
+        let mut bonds = self.bonds.get();
+        let slot = match bonds
+            .iter()
+            .position(|b| b.map_or(false, |b| b.address == bond.address))
+            .or_else(|| bonds.iter().position(|b| b.is_none()))
+        {
+            Some(slot) => slot,
+            None => {
+                let oldest = self.oldest.get();
+                self.oldest.set((oldest + 1) % MAX_BONDS);
+                oldest
+            }
+        };
+        bonds[slot] = Some(bond);
+        self.bonds.set(bonds);
+        self.write();
+    }
+
+    /// Forget all bonds.
+    pub fn clear(&self) {
+        self.bonds.set([None; MAX_BONDS]);
+        self.write();
+    }
+
+    fn write(&self) {
+        match self.buffer.take() {
+            Some(buffer) => {
+                for (slot, bond) in buffer
+                    .chunks_exact_mut(SLOT_LEN)
+                    .zip(self.bonds.get().iter())
+                {
+                    match bond {
+                        Some(bond) => bond.write_to(slot),
+                        None => {
+                            for b in slot.iter_mut() {
+                                *b = 0;
+                            }
+                        }
+                    }
+                }
+                self.storage.write(buffer, self.address, REGION_LEN);
+            }
+            None => self.dirty.set(true),
+        }
+    }
+}
+
+impl<'a> NonvolatileStorageClient<'static> for BondStore<'a> {
+    fn read_done(&self, buffer: &'static mut [u8], _length: usize) {
+        let mut bonds = [None; MAX_BONDS];
+        for (bond, slot) in bonds.iter_mut().zip(buffer.chunks_exact(SLOT_LEN)) {
+            *bond = Bond::read_from(slot);
+        }
+        self.oldest
+            .set(bonds.iter().filter(|b| b.is_some()).count() % MAX_BONDS);
+        self.bonds.set(bonds);
+        self.buffer.replace(buffer);
+        if self.dirty.replace(false) {
+            self.write();
+        }
+    }
+
+    fn write_done(&self, buffer: &'static mut [u8], _length: usize) {
+        self.buffer.replace(buffer);
+        if self.dirty.replace(false) {
+            self.write();
+        }
+    }
+}
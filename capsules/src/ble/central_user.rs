@@ -0,0 +1,362 @@
+//! System call interface for acting as a BLE central
+//!
+//! Lets an app scan for advertisements, connect to peripherals and use their
+//! GATT services through a `GattClient`, for example on a hub board that
+//! collects data from BLE sensors. The first app to use a command other than
+//! the driver check owns the driver; other apps get `ERESERVE`.
+//!
+//! ## Instantiation
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let central_driver = static_init!(
+//!     capsules::ble::central_user::CentralDriver<'static>,
+//!     capsules::ble::central_user::CentralDriver::new(
+//!         &nrf52::ble_radio::RADIO, &nrf52::ble_radio::RADIO, gatt_client,
+//!         board_kernel.create_grant(&grant_cap)));
+//! kernel::hil::ble_connection::BleCentral::set_central_client(
+//!     &nrf52::ble_radio::RADIO, central_driver);
+//! gatt_client.set_client(central_driver);
+//! ```
+//!
+//! ## Syscall Interface
+//!
+//! - allow `0`: advertisement reports are written here: the 6 byte address,
+//!   whether it is random, the PDU type, the RSSI, the data length and the
+//!   advertising data.
+//! - allow `1`: filter accept list, 7 bytes per device: the address followed
+//!   by whether it is random. At most `MAX_ACCEPT_LIST` are used.
+//! - allow `2`: UUID to find, 2 or 16 bytes, least significant byte first.
+//! - allow `3`: values read are copied here and values written taken from
+//!   here.
+//! - allow `4`: notified values are copied here.
+//! - subscribe `0`: an advertisement was reported, `fn(len, 0, 0)`.
+//! - subscribe `1`: connection events, `fn(0, handle, 0)` when connected,
+//!   `fn(1, handle, 0)` when disconnected and `fn(2, ReturnCode, 0)` when a
+//!   connection attempt failed.
+//! - subscribe `2`: a GATT request completed, `fn(command, error, value)`
+//!   with `command` the number of the command that started it and `error`
+//!   the ATT error code or 0. `value` is the value handle found, or 0, for
+//!   command `6` and the length read for command `7`.
+//! - subscribe `3`: the peer on connection `handle` notified the value of
+//!   `attribute`, `fn(handle, attribute, len)`.
+//! - command `0`: driver check.
+//! - command `1`: start scanning every `data2` milliseconds. Bit 0 of `data`
+//!   only reports devices on the accept list, bit 1 scans actively.
+//! - command `2`: stop scanning.
+//! - command `3`: connect to device `data` of the accept list, or to any of
+//!   them if `data` is past its end, with a connection interval of `data2`
+//!   milliseconds.
+//! - command `4`: stop connecting.
+//! - command `5`: disconnect connection `data`.
+//! - command `6`: find the characteristic with the UUID in allow `2` on
+//!   connection `data`.
+//! - command `7`: read attribute `data2` on connection `data`.
+//! - command `8`: write the contents of allow `3` to attribute `data2` on
+//!   connection `data`.
+
+use core::cmp;
+
+use kernel::common::cells::OptionalCell;
+use kernel::hil::ble_connection::{
+    BleCentral, BleConnection, CentralClient, ConnectionHandle, DeviceAddress, FilterPolicy,
+};
+use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
+
+use super::att::Uuid;
+use super::gatt_client::{self, GattClient};
+
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::BleCentral as usize;
+
+/// The most accept list entries used.
+pub const MAX_ACCEPT_LIST: usize = 8;
+
+/// Remote-disconnect reason sent when an app disconnects.
+const REMOTE_USER_TERMINATED: u8 = 0x13;
+
+const ADDRESS_ENTRY_LEN: usize = 7;
+const REPORT_HEADER_LEN: usize = 10;
+
+const NO_ADDRESS: DeviceAddress = DeviceAddress {
+    address: [0; 6],
+    random: false,
+};
+
+#[derive(Default)]
+pub struct App {
+    scan_callback: Option<Callback>,
+    connection_callback: Option<Callback>,
+    gatt_callback: Option<Callback>,
+    notification_callback: Option<Callback>,
+    reports: Option<AppSlice<Shared, u8>>,
+    accept_list: Option<AppSlice<Shared, u8>>,
+    uuid: Option<AppSlice<Shared, u8>>,
+    value: Option<AppSlice<Shared, u8>>,
+    notification: Option<AppSlice<Shared, u8>>,
+}
+
+impl App {
+    /// Parse the accept list into `peers` and return how many there are.
+    fn accept_list(&self, peers: &mut [DeviceAddress; MAX_ACCEPT_LIST]) -> usize {
+        self.accept_list.as_ref().map_or(0, |list| {
+            let mut count = 0;
+            for (peer, entry) in peers
+                .iter_mut()
+                .zip(list.as_ref().chunks_exact(ADDRESS_ENTRY_LEN))
+            {
+                peer.address.copy_from_slice(&entry[..6]);
+                peer.random = entry[6] != 0;
+                count += 1;
+            }
+            count
+        })
+    }
+}
+
+pub struct CentralDriver<'a> {
+    central: &'a dyn BleCentral<'a>,
+    link: &'a dyn BleConnection<'a>,
+    gatt: &'a GattClient<'a>,
+    apps: Grant<App>,
+    owner: OptionalCell<AppId>,
+}
+
+impl<'a> CentralDriver<'a> {
+    pub fn new(
+        central: &'a dyn BleCentral<'a>,
+        link: &'a dyn BleConnection<'a>,
+        gatt: &'a GattClient<'a>,
+        apps: Grant<App>,
+    ) -> CentralDriver<'a> {
+        CentralDriver {
+            central: central,
+            link: link,
+            gatt: gatt,
+            apps: apps,
+            owner: OptionalCell::empty(),
+        }
+    }
+
+    /// Run `f` with the owner's state, if there is an owner.
+    fn with_owner<F: FnOnce(&mut App)>(&self, f: F) {
+        self.owner.map(|appid| {
+            let _ = self.apps.enter(*appid, |app, _| f(app));
+        });
+    }
+
+    fn start_scanning(&self, app: &mut App, flags: usize, interval_ms: u32) -> ReturnCode {
+        let policy = if flags & 0x01 != 0 {
+            let mut peers = [NO_ADDRESS; MAX_ACCEPT_LIST];
+            let count = app.accept_list(&mut peers);
+            let rcode = self.central.set_accept_list(&peers[..count]);
+            if rcode != ReturnCode::SUCCESS {
+                return rcode;
+            }
+            FilterPolicy::AcceptListOnly
+        } else {
+            FilterPolicy::AcceptAll
+        };
+        self.central
+            .start_scanning(policy, flags & 0x02 != 0, interval_ms, interval_ms)
+    }
+
+    fn connect(&self, app: &mut App, index: usize, interval_ms: u32) -> ReturnCode {
+        let mut peers = [NO_ADDRESS; MAX_ACCEPT_LIST];
+        let count = app.accept_list(&mut peers);
+        if index < count {
+            return self
+                .central
+                .connect(FilterPolicy::AcceptAll, peers[index], interval_ms);
+        }
+        let rcode = self.central.set_accept_list(&peers[..count]);
+        if rcode != ReturnCode::SUCCESS {
+            return rcode;
+        }
+        self.central
+            .connect(FilterPolicy::AcceptListOnly, NO_ADDRESS, interval_ms)
+    }
+
+    fn find(&self, app: &mut App, connection: ConnectionHandle) -> ReturnCode {
+        match app
+            .uuid
+            .as_ref()
+            .and_then(|uuid| Uuid::from_bytes(uuid.as_ref()))
+        {
+            Some(uuid) => self.gatt.find_characteristic(connection, uuid),
+            None => ReturnCode::EINVAL,
+        }
+    }
+
+    fn write(&self, app: &mut App, connection: ConnectionHandle, attribute: u16) -> ReturnCode {
+        match app.value {
+            Some(ref value) => self.gatt.write(connection, attribute, value.as_ref()),
+            None => ReturnCode::EINVAL,
+        }
+    }
+
+    fn gatt_done(&self, command: usize, error: u8, value: usize) {
+        self.with_owner(|app| {
+            app.gatt_callback
+                .map(|mut cb| cb.schedule(command, error as usize, value));
+        });
+    }
+}
+
+impl<'a> CentralClient for CentralDriver<'a> {
+    fn advertisement_received(&self, peer: DeviceAddress, pdu_type: u8, rssi: i8, data: &[u8]) {
+        self.with_owner(|app| {
+            if let (Some(mut callback), Some(reports)) = (app.scan_callback, app.reports.as_mut()) {
+                let reports = reports.as_mut();
+                if reports.len() < REPORT_HEADER_LEN {
+                    return;
+                }
+                let len = cmp::min(data.len(), reports.len() - REPORT_HEADER_LEN);
+                reports[..6].copy_from_slice(&peer.address);
+                reports[6] = peer.random as u8;
+                reports[7] = pdu_type;
+                reports[8] = rssi as u8;
+                reports[9] = len as u8;
+                reports[REPORT_HEADER_LEN..REPORT_HEADER_LEN + len].copy_from_slice(&data[..len]);
+                callback.schedule(REPORT_HEADER_LEN + len, 0, 0);
+            }
+        });
+    }
+
+    fn connection_failed(&self, result: ReturnCode) {
+        self.with_owner(|app| {
+            app.connection_callback
+                .map(|mut cb| cb.schedule(2, From::from(result), 0));
+        });
+    }
+}
+
+impl<'a> gatt_client::Client for CentralDriver<'a> {
+    fn connected(&self, handle: ConnectionHandle) {
+        self.with_owner(|app| {
+            app.connection_callback
+                .map(|mut cb| cb.schedule(0, handle as usize, 0));
+        });
+    }
+
+    fn disconnected(&self, handle: ConnectionHandle) {
+        self.with_owner(|app| {
+            app.connection_callback
+                .map(|mut cb| cb.schedule(1, handle as usize, 0));
+        });
+    }
+
+    fn characteristic_found(
+        &self,
+        _handle: ConnectionHandle,
+        value_handle: Result<Option<u16>, u8>,
+    ) {
+        match value_handle {
+            Ok(value_handle) => self.gatt_done(6, 0, value_handle.unwrap_or(0) as usize),
+            Err(error) => self.gatt_done(6, error, 0),
+        }
+    }
+
+    fn read_done(&self, _handle: ConnectionHandle, _attribute: u16, value: Result<&[u8], u8>) {
+        let value = match value {
+            Ok(value) => value,
+            Err(error) => return self.gatt_done(7, error, 0),
+        };
+        let mut len = 0;
+        self.with_owner(|app| {
+            app.value.as_mut().map(|buffer| {
+                len = cmp::min(value.len(), buffer.len());
+                buffer.as_mut()[..len].copy_from_slice(&value[..len]);
+            });
+        });
+        self.gatt_done(7, 0, len);
+    }
+
+    fn write_done(&self, _handle: ConnectionHandle, _attribute: u16, result: Result<(), u8>) {
+        self.gatt_done(8, result.err().unwrap_or(0), 0);
+    }
+
+    fn notification_received(&self, handle: ConnectionHandle, attribute: u16, value: &[u8]) {
+        self.with_owner(|app| {
+            if let (Some(mut callback), Some(buffer)) =
+                (app.notification_callback, app.notification.as_mut())
+            {
+                let len = cmp::min(value.len(), buffer.len());
+                buffer.as_mut()[..len].copy_from_slice(&value[..len]);
+                callback.schedule(handle as usize, attribute as usize, len);
+            }
+        });
+    }
+}
+
+impl<'a> Driver for CentralDriver<'a> {
+    fn allow(
+        &self,
+        appid: AppId,
+        allow_num: usize,
+        slice: Option<AppSlice<Shared, u8>>,
+    ) -> ReturnCode {
+        self.apps
+            .enter(appid, |app, _| {
+                match allow_num {
+                    0 => app.reports = slice,
+                    1 => app.accept_list = slice,
+                    2 => app.uuid = slice,
+                    3 => app.value = slice,
+                    4 => app.notification = slice,
+                    _ => return ReturnCode::ENOSUPPORT,
+                }
+                ReturnCode::SUCCESS
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        callback: Option<Callback>,
+        appid: AppId,
+    ) -> ReturnCode {
+        self.apps
+            .enter(appid, |app, _| {
+                match subscribe_num {
+                    0 => app.scan_callback = callback,
+                    1 => app.connection_callback = callback,
+                    2 => app.gatt_callback = callback,
+                    3 => app.notification_callback = callback,
+                    _ => return ReturnCode::ENOSUPPORT,
+                }
+                ReturnCode::SUCCESS
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    fn command(&self, command_num: usize, data: usize, data2: usize, appid: AppId) -> ReturnCode {
+        if command_num == 0 {
+            return ReturnCode::SUCCESS;
+        }
+        if command_num > 8 {
+            return ReturnCode::ENOSUPPORT;
+        }
+        if self.owner.map_or(false, |owner| *owner != appid) {
+            return ReturnCode::ERESERVE;
+        }
+        self.owner.set(appid);
+
+        let connection = data as ConnectionHandle;
+        self.apps
+            .enter(appid, |app, _| match command_num {
+                1 => self.start_scanning(app, data, data2 as u32),
+                2 => self.central.stop_scanning(),
+                3 => self.connect(app, data, data2 as u32),
+                4 => self.central.cancel_connect(),
+                5 => self.link.disconnect(connection, REMOTE_USER_TERMINATED),
+                6 => self.find(app, connection),
+                7 => self.gatt.read(connection, data2 as u16),
+                8 => self.write(app, connection, data2 as u16),
+                _ => ReturnCode::ENOSUPPORT,
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+}
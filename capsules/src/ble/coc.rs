@@ -0,0 +1,843 @@
+//! L2CAP connection-oriented channels
+//!
+//! LE credit based connection-oriented channels carry SDUs of up to the
+//! receiver's MTU between two protocols or applications identified by an
+//! SPSM, with flow control: each side may only send as many K-frames as the
+//! other granted it credits for.
+//!
+//! `LeSignaling` serves the LE signaling channel, `l2cap::CID_LE_SIGNALING`,
+//! opening and closing channels. Each `CocChannel` is one channel end on a
+//! dynamic CID, with its own `L2capChannel` for that CID. It either
+//! `listen()`s for peers opening a channel to an SPSM, or `connect()`s to an
+//! SPSM on the peer. Once open, `send()` takes a whole SDU and segments it
+//! into K-frames as credits allow, and SDUs from the peer are reassembled
+//! and passed up whole. The buffer they are reassembled into sets our MTU,
+//! and we grant the peer enough credits for one SDU of that size at a time,
+//! returning them once the SDU has been passed up.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//! # use capsules::ble::coc::{CocChannel, LeSignaling};
+//! # use capsules::ble::l2cap::{L2capChannel, CID_LE_SIGNALING};
+//!
+//! let signaling_channel = static_init!(
+//!     L2capChannel<'static>,
+//!     L2capChannel::new(l2cap, CID_LE_SIGNALING)
+//! );
+//! signaling_channel.setup();
+//! let signaling = static_init!(
+//!     LeSignaling<'static>,
+//!     LeSignaling::new(signaling_channel, static_init!([u8; 27], [0; 27]))
+//! );
+//! signaling_channel.set_client(signaling);
+//!
+//! let coc_l2cap = static_init!(L2capChannel<'static>, L2capChannel::new(l2cap, 0x0040));
+//! coc_l2cap.setup();
+//! let coc = static_init!(
+//!     CocChannel<'static>,
+//!     CocChannel::new(
+//!         signaling,
+//!         coc_l2cap,
+//!         static_init!([u8; 1280], [0; 1280]),
+//!         static_init!([u8; 251], [0; 251]),
+//!     )
+//! );
+//! coc_l2cap.set_client(coc);
+//! coc.setup();
+//! coc.set_client(transfer_capsule);
+//! coc.listen(0x0080);
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::{List, ListLink, ListNode};
+use kernel::hil::ble_connection::{ConnectionHandle, DeviceAddress};
+use kernel::ReturnCode;
+
+use super::l2cap::{L2capChannel, L2capClient, HEADER_LEN};
+
+/// The smallest MTU and MPS either side may use.
+pub const MIN_MTU: usize = 23;
+
+/// Signaling command codes.
+const COMMAND_REJECT: u8 = 0x01;
+const DISCONNECTION_REQUEST: u8 = 0x06;
+const DISCONNECTION_RESPONSE: u8 = 0x07;
+const LE_CONNECTION_REQUEST: u8 = 0x14;
+const LE_CONNECTION_RESPONSE: u8 = 0x15;
+const FLOW_CONTROL_CREDIT: u8 = 0x16;
+
+/// LE credit based connection results.
+const RESULT_SUCCESS: u16 = 0x0000;
+const RESULT_SPSM_NOT_SUPPORTED: u16 = 0x0002;
+const RESULT_NO_RESOURCES: u16 = 0x0004;
+const RESULT_INVALID_SOURCE_CID: u16 = 0x0009;
+const RESULT_UNACCEPTABLE_PARAMETERS: u16 = 0x000b;
+
+/// Dynamic CIDs of LE.
+const FIRST_DYNAMIC_CID: u16 = 0x0040;
+const LAST_DYNAMIC_CID: u16 = 0x007f;
+
+/// The number of connections whose peer addresses are kept.
+pub const NUM_PEERS: usize = 4;
+
+/// Length of the SDU length field at the start of the first K-frame of an
+/// SDU.
+const SDU_LEN_LEN: usize = 2;
+
+/// Commands waiting to be sent, in the order they go out.
+const SEND_CONNECTION_RESPONSE: u8 = 0x01;
+const SEND_CONNECTION_REQUEST: u8 = 0x02;
+const SEND_DISCONNECTION_RESPONSE: u8 = 0x04;
+const SEND_DISCONNECTION_REQUEST: u8 = 0x08;
+const SEND_CREDITS: u8 = 0x10;
+
+pub trait CocClient {
+    /// The channel opened on connection `handle`, or `connect()` failed.
+    fn opened(&self, handle: ConnectionHandle, result: ReturnCode);
+
+    /// The channel closed, because either side disconnected it or the
+    /// connection closed.
+    fn closed(&self);
+
+    /// An SDU arrived.
+    fn received(&self, sdu: &[u8]);
+
+    /// The SDU passed to `CocChannel::send()` was sent, or could not be.
+    fn sent(&self, buffer: &'static mut [u8], result: ReturnCode);
+}
+
+/// Write a signaling command whose fields are all 16 bits wide.
+fn write_command(buf: &mut [u8], code: u8, identifier: u8, fields: &[u16]) -> usize {
+    buf[0] = code;
+    buf[1] = identifier;
+    buf[2..4].copy_from_slice(&((fields.len() * 2) as u16).to_le_bytes());
+    for (i, field) in fields.iter().enumerate() {
+        buf[4 + 2 * i..6 + 2 * i].copy_from_slice(&field.to_le_bytes());
+    }
+    4 + fields.len() * 2
+}
+
+/// The `i`th 16 bit field of a command's data.
+fn field(data: &[u8], i: usize) -> u16 {
+    u16::from_le_bytes([data[2 * i], data[2 * i + 1]])
+}
+
+pub struct LeSignaling<'a> {
+    channel: &'a L2capChannel<'a>,
+    buffer: TakeCell<'static, [u8]>,
+    cocs: List<'a, CocChannel<'a>>,
+    next_identifier: Cell<u8>,
+    /// A connection request nobody accepted: its connection, identifier and
+    /// the result to answer with.
+    refused: Cell<Option<(ConnectionHandle, u8, u16)>>,
+    /// A command we do not understand.
+    rejected: Cell<Option<(ConnectionHandle, u8)>>,
+    /// The addresses of the peers of open connections.
+    peers: Cell<[Option<(ConnectionHandle, DeviceAddress)>; NUM_PEERS]>,
+}
+
+impl<'a> LeSignaling<'a> {
+    /// `buffer` holds outgoing commands and must be at least 18 bytes long.
+    pub fn new(channel: &'a L2capChannel<'a>, buffer: &'static mut [u8]) -> LeSignaling<'a> {
+        LeSignaling {
+            channel: channel,
+            buffer: TakeCell::new(buffer),
+            cocs: List::new(),
+            next_identifier: Cell::new(1),
+            refused: Cell::new(None),
+            rejected: Cell::new(None),
+            peers: Cell::new([None; NUM_PEERS]),
+        }
+    }
+
+    fn peer_address(&self, handle: ConnectionHandle) -> Option<DeviceAddress> {
+        self.peers
+            .get()
+            .iter()
+            .filter_map(|entry| *entry)
+            .find(|&(h, _)| h == handle)
+            .map(|(_, peer)| peer)
+    }
+
+    /// Identifiers of our requests, which are never 0.
+    fn identifier(&self) -> u8 {
+        let identifier = self.next_identifier.get();
+        self.next_identifier
+            .set(identifier.checked_add(1).unwrap_or(1));
+        identifier
+    }
+
+    /// Send the next waiting command, if the buffer is free.
+    fn do_next_op(&self) {
+        let buffer = match self.buffer.take() {
+            Some(buffer) => buffer,
+            None => return,
+        };
+        let out = &mut buffer[HEADER_LEN..];
+
+        if let Some((handle, identifier, result)) = self.refused.take() {
+            let len = write_command(
+                out,
+                LE_CONNECTION_RESPONSE,
+                identifier,
+                &[0, 0, 0, 0, result],
+            );
+            self.send(handle, buffer, len);
+            return;
+        }
+        if let Some((handle, identifier)) = self.rejected.take() {
+            // Command not understood.
+            let len = write_command(out, COMMAND_REJECT, identifier, &[0]);
+            self.send(handle, buffer, len);
+            return;
+        }
+        for coc in self.cocs.iter() {
+            if let Some(len) = coc.next_command(out) {
+                self.send(coc.handle.get(), buffer, len);
+                coc.command_sent();
+                return;
+            }
+        }
+        self.buffer.replace(buffer);
+    }
+
+    fn send(&self, handle: ConnectionHandle, buffer: &'static mut [u8], len: usize) {
+        if let Err((_, buffer)) = self.channel.send(handle, buffer, len) {
+            self.buffer.replace(buffer);
+        }
+    }
+
+    fn connection_request(&self, handle: ConnectionHandle, identifier: u8, data: &[u8]) {
+        let spsm = field(data, 0);
+        let source_cid = field(data, 1);
+        let mtu = field(data, 2);
+        let mps = field(data, 3);
+        let credits = field(data, 4);
+
+        let mut supported = false;
+        let coc = self.cocs.iter().find(|coc| {
+            if coc.listening.contains(&spsm) {
+                supported = true;
+                coc.state.get() == State::Closed
+            } else {
+                false
+            }
+        });
+        let result = if !supported {
+            RESULT_SPSM_NOT_SUPPORTED
+        } else if source_cid < FIRST_DYNAMIC_CID || source_cid > LAST_DYNAMIC_CID {
+            RESULT_INVALID_SOURCE_CID
+        } else if (mtu as usize) < MIN_MTU || (mps as usize) < MIN_MTU {
+            RESULT_UNACCEPTABLE_PARAMETERS
+        } else if self.cocs.iter().any(|coc| {
+            coc.state.get() != State::Closed
+                && coc.handle.get() == handle
+                && coc.peer_cid.get() == source_cid
+        }) {
+            // The peer already uses the CID for another channel.
+            RESULT_INVALID_SOURCE_CID
+        } else {
+            RESULT_SUCCESS
+        };
+
+        match coc {
+            Some(coc) if result == RESULT_SUCCESS => {
+                coc.handle.set(handle);
+                coc.identifier.set(identifier);
+                coc.open(source_cid, mtu, mps, credits);
+                coc.state.set(State::Accepting);
+                coc.queue(SEND_CONNECTION_RESPONSE);
+            }
+            Some(_) => self.refused.set(Some((handle, identifier, result))),
+            None if supported => self
+                .refused
+                .set(Some((handle, identifier, RESULT_NO_RESOURCES))),
+            None => self.refused.set(Some((handle, identifier, result))),
+        }
+    }
+
+    /// The open channel the peer calls `peer_cid` on connection `handle`.
+    fn peer_channel(&self, handle: ConnectionHandle, peer_cid: u16) -> Option<&'a CocChannel<'a>> {
+        self.cocs.iter().find(|coc| {
+            coc.state.get() == State::Open
+                && coc.handle.get() == handle
+                && coc.peer_cid.get() == peer_cid
+        })
+    }
+}
+
+impl<'a> L2capClient for LeSignaling<'a> {
+    fn connected(&self, handle: ConnectionHandle, peer: DeviceAddress) {
+        let mut peers = self.peers.get();
+        if let Some(entry) = peers.iter_mut().find(|entry| entry.is_none()) {
+            *entry = Some((handle, peer));
+            self.peers.set(peers);
+        }
+    }
+
+    fn disconnected(&self, handle: ConnectionHandle) {
+        let mut peers = self.peers.get();
+        for entry in peers.iter_mut() {
+            if entry.map_or(false, |(h, _)| h == handle) {
+                *entry = None;
+            }
+        }
+        self.peers.set(peers);
+        // Answers meant for the connection that just closed.
+        if self.refused.get().map_or(false, |(h, _, _)| h == handle) {
+            self.refused.set(None);
+        }
+        if self.rejected.get().map_or(false, |(h, _)| h == handle) {
+            self.rejected.set(None);
+        }
+    }
+
+    fn received(&self, handle: ConnectionHandle, payload: &[u8]) {
+        if payload.len() < 4 {
+            return;
+        }
+        let code = payload[0];
+        let identifier = payload[1];
+        let len = u16::from_le_bytes([payload[2], payload[3]]) as usize;
+        if payload.len() < 4 + len {
+            return;
+        }
+        let data = &payload[4..4 + len];
+
+        match code {
+            LE_CONNECTION_REQUEST if len >= 10 => {
+                self.connection_request(handle, identifier, data);
+            }
+            LE_CONNECTION_RESPONSE if len >= 10 => {
+                self.cocs
+                    .iter()
+                    .find(|coc| {
+                        coc.state.get() == State::Connecting
+                            && coc.handle.get() == handle
+                            && coc.identifier.get() == identifier
+                    })
+                    .map(|coc| {
+                        if field(data, 4) == RESULT_SUCCESS {
+                            coc.open(
+                                field(data, 0),
+                                field(data, 1),
+                                field(data, 2),
+                                field(data, 3),
+                            );
+                            coc.state.set(State::Open);
+                            coc.client
+                                .map(|client| client.opened(handle, ReturnCode::SUCCESS));
+                        } else {
+                            coc.state.set(State::Closed);
+                            coc.client
+                                .map(|client| client.opened(handle, ReturnCode::FAIL));
+                        }
+                    });
+            }
+            FLOW_CONTROL_CREDIT if len >= 4 => {
+                self.peer_channel(handle, field(data, 0)).map(|coc| {
+                    coc.tx_credits
+                        .set(coc.tx_credits.get().saturating_add(field(data, 1)));
+                    coc.send_frame();
+                });
+            }
+            DISCONNECTION_REQUEST if len >= 4 => {
+                let (cid, peer_cid) = (field(data, 0), field(data, 1));
+                self.peer_channel(handle, peer_cid)
+                    .filter(|coc| coc.cid() == cid)
+                    .map(|coc| {
+                        coc.identifier.set(identifier);
+                        coc.queue(SEND_DISCONNECTION_RESPONSE);
+                        coc.close();
+                    });
+            }
+            DISCONNECTION_RESPONSE if len >= 4 => {
+                self.cocs
+                    .iter()
+                    .find(|coc| {
+                        coc.state.get() == State::Disconnecting
+                            && coc.handle.get() == handle
+                            && coc.identifier.get() == identifier
+                    })
+                    .map(|coc| coc.close());
+            }
+            COMMAND_REJECT => {
+                // Only our connection requests are worth answering.
+                self.cocs
+                    .iter()
+                    .find(|coc| {
+                        coc.state.get() == State::Connecting
+                            && coc.handle.get() == handle
+                            && coc.identifier.get() == identifier
+                    })
+                    .map(|coc| {
+                        coc.state.set(State::Closed);
+                        coc.client
+                            .map(|client| client.opened(handle, ReturnCode::ENOSUPPORT));
+                    });
+            }
+            LE_CONNECTION_REQUEST
+            | LE_CONNECTION_RESPONSE
+            | FLOW_CONTROL_CREDIT
+            | DISCONNECTION_REQUEST
+            | DISCONNECTION_RESPONSE => {
+                // Too short to act on.
+            }
+            _ => self.rejected.set(Some((handle, identifier))),
+        }
+        self.do_next_op();
+    }
+
+    fn sent(&self, buffer: &'static mut [u8], _result: ReturnCode) {
+        self.buffer.replace(buffer);
+        self.do_next_op();
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum State {
+    Closed,
+    /// Waiting for the answer to our connection request.
+    Connecting,
+    /// We accepted the peer's request; open once the answer is sent.
+    Accepting,
+    Open,
+    /// Waiting for the answer to our disconnection request.
+    Disconnecting,
+}
+
+pub struct CocChannel<'a> {
+    signaling: &'a LeSignaling<'a>,
+    channel: &'a L2capChannel<'a>,
+    state: Cell<State>,
+    /// SPSM whose connection requests we accept.
+    listening: OptionalCell<u16>,
+    spsm: Cell<u16>,
+    handle: Cell<ConnectionHandle>,
+    /// Identifier of the request being answered or waited on.
+    identifier: Cell<u8>,
+    pending: Cell<u8>,
+    /// Our MTU and MPS, set by the buffers we were given.
+    mtu: u16,
+    mps: u16,
+
+    peer_cid: Cell<u16>,
+    peer_mtu: Cell<u16>,
+    peer_mps: Cell<u16>,
+    /// K-frames we may still send.
+    tx_credits: Cell<u16>,
+    /// K-frames the peer may still send.
+    rx_credits: Cell<u16>,
+    /// Credits to give back to the peer.
+    credits_owed: Cell<u16>,
+
+    /// The SDU being sent, its length and how much of it was sent.
+    tx_sdu: TakeCell<'static, [u8]>,
+    tx_len: Cell<usize>,
+    tx_offset: Cell<usize>,
+    /// Holds one K-frame on its way to the link layer.
+    frame: TakeCell<'static, [u8]>,
+
+    /// The SDU being reassembled, its length once known and how much of it
+    /// arrived.
+    rx_sdu: TakeCell<'static, [u8]>,
+    rx_len: OptionalCell<usize>,
+    rx_offset: Cell<usize>,
+    rx_frames: Cell<u16>,
+
+    client: OptionalCell<&'a dyn CocClient>,
+    next: ListLink<'a, CocChannel<'a>>,
+}
+
+impl<'a> ListNode<'a, CocChannel<'a>> for CocChannel<'a> {
+    fn next(&'a self) -> &'a ListLink<'a, CocChannel<'a>> {
+        &self.next
+    }
+}
+
+impl<'a> CocChannel<'a> {
+    /// `channel` must be on a dynamic CID, from 0x0040 to 0x007f. SDUs are
+    /// reassembled into `rx_sdu`, whose length is our MTU. K-frames are sent
+    /// from `frame`, whose length less `HEADER_LEN` is our MPS. Both must be
+    /// at least `MIN_MTU` long.
+    pub fn new(
+        signaling: &'a LeSignaling<'a>,
+        channel: &'a L2capChannel<'a>,
+        rx_sdu: &'static mut [u8],
+        frame: &'static mut [u8],
+    ) -> CocChannel<'a> {
+        let mps = cmp::min(frame.len() - HEADER_LEN, channel.max_payload_len());
+        CocChannel {
+            mtu: rx_sdu.len() as u16,
+            mps: mps as u16,
+            signaling: signaling,
+            channel: channel,
+            state: Cell::new(State::Closed),
+            listening: OptionalCell::empty(),
+            spsm: Cell::new(0),
+            handle: Cell::new(0),
+            identifier: Cell::new(0),
+            pending: Cell::new(0),
+            peer_cid: Cell::new(0),
+            peer_mtu: Cell::new(0),
+            peer_mps: Cell::new(0),
+            tx_credits: Cell::new(0),
+            rx_credits: Cell::new(0),
+            credits_owed: Cell::new(0),
+            tx_sdu: TakeCell::empty(),
+            tx_len: Cell::new(0),
+            tx_offset: Cell::new(0),
+            frame: TakeCell::new(frame),
+            rx_sdu: TakeCell::new(rx_sdu),
+            rx_len: OptionalCell::empty(),
+            rx_offset: Cell::new(0),
+            rx_frames: Cell::new(0),
+            client: OptionalCell::empty(),
+            next: ListLink::empty(),
+        }
+    }
+
+    /// Register with the signaling channel. Must be called once before the
+    /// channel is used.
+    pub fn setup(&'a self) {
+        self.signaling.cocs.push_head(self);
+    }
+
+    pub fn set_client(&self, client: &'a dyn CocClient) {
+        self.client.set(client);
+    }
+
+    pub fn cid(&self) -> u16 {
+        self.channel.cid()
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.state.get() == State::Open
+    }
+
+    /// The longest SDU `send()` accepts, once open.
+    pub fn peer_mtu(&self) -> usize {
+        self.peer_mtu.get() as usize
+    }
+
+    /// The address of the peer the channel is open to. `None` if `NUM_PEERS`
+    /// other connections were open when its connection was made.
+    pub fn peer_address(&self) -> Option<DeviceAddress> {
+        if self.state.get() == State::Closed {
+            return None;
+        }
+        self.signaling.peer_address(self.handle.get())
+    }
+
+    /// Accept the peer opening a channel to `spsm` whenever this channel is
+    /// closed.
+    pub fn listen(&self, spsm: u16) {
+        self.listening.set(spsm);
+    }
+
+    pub fn stop_listening(&self) {
+        self.listening.clear();
+    }
+
+    /// Open a channel to `spsm` on the peer of connection `handle`.
+    /// `CocClient::opened` is called once the peer answered.
+    pub fn connect(&self, handle: ConnectionHandle, spsm: u16) -> ReturnCode {
+        if self.state.get() != State::Closed {
+            return ReturnCode::EBUSY;
+        }
+        self.handle.set(handle);
+        self.spsm.set(spsm);
+        self.identifier.set(self.signaling.identifier());
+        self.state.set(State::Connecting);
+        self.queue(SEND_CONNECTION_REQUEST);
+        self.signaling.do_next_op();
+        ReturnCode::SUCCESS
+    }
+
+    /// Close the channel. `CocClient::closed` is called once the peer
+    /// answered.
+    pub fn disconnect(&self) -> ReturnCode {
+        match self.state.get() {
+            State::Open | State::Accepting => {
+                self.identifier.set(self.signaling.identifier());
+                self.state.set(State::Disconnecting);
+                self.queue(SEND_DISCONNECTION_REQUEST);
+                self.cancel_send();
+                self.signaling.do_next_op();
+                ReturnCode::SUCCESS
+            }
+            State::Disconnecting => ReturnCode::EALREADY,
+            _ => ReturnCode::EOFF,
+        }
+    }
+
+    /// Send the first `len` bytes of `buffer` as one SDU, which must not be
+    /// longer than `peer_mtu()`. Returns `EBUSY` while the previous SDU is
+    /// being sent.
+    pub fn send(
+        &self,
+        buffer: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        if self.state.get() != State::Open {
+            return Err((ReturnCode::EOFF, buffer));
+        }
+        if self.tx_sdu.is_some() {
+            return Err((ReturnCode::EBUSY, buffer));
+        }
+        if len > self.peer_mtu() || len > buffer.len() {
+            return Err((ReturnCode::ESIZE, buffer));
+        }
+        self.tx_sdu.replace(buffer);
+        self.tx_len.set(len);
+        self.tx_offset.set(0);
+        self.send_frame();
+        Ok(())
+    }
+
+    /// The credits that let the peer send one SDU of our MTU.
+    fn initial_credits(&self) -> u16 {
+        (self.mtu + SDU_LEN_LEN as u16 + self.mps - 1) / self.mps
+    }
+
+    /// Both sides agreed on the channel.
+    fn open(&self, peer_cid: u16, mtu: u16, mps: u16, credits: u16) {
+        self.peer_cid.set(peer_cid);
+        self.peer_mtu.set(mtu);
+        self.peer_mps.set(mps);
+        self.tx_credits.set(credits);
+        self.rx_credits.set(self.initial_credits());
+        self.credits_owed.set(0);
+        self.rx_len.clear();
+        self.rx_offset.set(0);
+        self.rx_frames.set(0);
+    }
+
+    fn close(&self) {
+        self.state.set(State::Closed);
+        self.pending
+            .set(self.pending.get() & SEND_DISCONNECTION_RESPONSE);
+        self.cancel_send();
+        self.client.map(|client| client.closed());
+    }
+
+    fn cancel_send(&self) {
+        self.tx_sdu.take().map(|buffer| {
+            self.client
+                .map(move |client| client.sent(buffer, ReturnCode::FAIL));
+        });
+    }
+
+    fn queue(&self, command: u8) {
+        self.pending.set(self.pending.get() | command);
+    }
+
+    /// Write the next command this channel is waiting to send.
+    fn next_command(&self, out: &mut [u8]) -> Option<usize> {
+        let pending = self.pending.get();
+        let command = pending & pending.wrapping_neg();
+        self.pending.set(pending & !command);
+        let identifier = self.identifier.get();
+        match command {
+            SEND_CONNECTION_RESPONSE => Some(write_command(
+                out,
+                LE_CONNECTION_RESPONSE,
+                identifier,
+                &[
+                    self.cid(),
+                    self.mtu,
+                    self.mps,
+                    self.rx_credits.get(),
+                    RESULT_SUCCESS,
+                ],
+            )),
+            SEND_CONNECTION_REQUEST => Some(write_command(
+                out,
+                LE_CONNECTION_REQUEST,
+                identifier,
+                &[
+                    self.spsm.get(),
+                    self.cid(),
+                    self.mtu,
+                    self.mps,
+                    self.initial_credits(),
+                ],
+            )),
+            SEND_DISCONNECTION_RESPONSE => Some(write_command(
+                out,
+                DISCONNECTION_RESPONSE,
+                identifier,
+                &[self.cid(), self.peer_cid.get()],
+            )),
+            SEND_DISCONNECTION_REQUEST => Some(write_command(
+                out,
+                DISCONNECTION_REQUEST,
+                identifier,
+                &[self.peer_cid.get(), self.cid()],
+            )),
+            SEND_CREDITS => {
+                let credits = self.credits_owed.take();
+                self.rx_credits
+                    .set(self.rx_credits.get().saturating_add(credits));
+                Some(write_command(
+                    out,
+                    FLOW_CONTROL_CREDIT,
+                    0,
+                    &[self.cid(), credits],
+                ))
+            }
+            _ => None,
+        }
+    }
+
+    /// A command from `next_command()` was handed to the link layer.
+    fn command_sent(&self) {
+        if self.state.get() == State::Accepting {
+            // Our answer goes out before any K-frame the client sends.
+            self.state.set(State::Open);
+            let handle = self.handle.get();
+            self.client
+                .map(|client| client.opened(handle, ReturnCode::SUCCESS));
+        }
+    }
+
+    /// Send the next K-frame of the SDU, if we have credits for it.
+    fn send_frame(&self) {
+        if self.state.get() != State::Open || self.tx_credits.get() == 0 {
+            return;
+        }
+        let frame = match self.frame.take() {
+            Some(frame) => frame,
+            None => return,
+        };
+        let mps = cmp::min(self.peer_mps.get() as usize, frame.len() - HEADER_LEN);
+        let offset = self.tx_offset.get();
+        let sent = self.tx_sdu.map(|sdu| {
+            let out = &mut frame[HEADER_LEN..];
+            let mut len = 0;
+            if offset == 0 {
+                out[0..2].copy_from_slice(&(self.tx_len.get() as u16).to_le_bytes());
+                len = SDU_LEN_LEN;
+            }
+            let chunk = cmp::min(mps - len, self.tx_len.get() - offset);
+            out[len..len + chunk].copy_from_slice(&sdu[offset..offset + chunk]);
+            (len + chunk, chunk)
+        });
+        match sent {
+            Some((len, chunk)) => match self.channel.send(self.handle.get(), frame, len) {
+                Ok(()) => {
+                    self.tx_credits.set(self.tx_credits.get() - 1);
+                    self.tx_offset.set(offset + chunk);
+                }
+                Err((rcode, frame)) => {
+                    self.frame.replace(frame);
+                    self.tx_sdu.take().map(|buffer| {
+                        self.client.map(move |client| client.sent(buffer, rcode));
+                    });
+                }
+            },
+            None => {
+                self.frame.replace(frame);
+            }
+        }
+    }
+
+    /// The peer broke the rules of the channel.
+    fn protocol_error(&self) {
+        self.rx_len.clear();
+        self.disconnect();
+    }
+}
+
+impl<'a> L2capClient for CocChannel<'a> {
+    fn connected(&self, _handle: ConnectionHandle, _peer: DeviceAddress) {}
+
+    fn disconnected(&self, handle: ConnectionHandle) {
+        if self.state.get() != State::Closed && self.handle.get() == handle {
+            self.pending.set(0);
+            if self.state.get() == State::Connecting {
+                self.state.set(State::Closed);
+                self.client
+                    .map(|client| client.opened(handle, ReturnCode::FAIL));
+            } else {
+                self.close();
+            }
+        }
+    }
+
+    fn received(&self, handle: ConnectionHandle, payload: &[u8]) {
+        if self.state.get() != State::Open || self.handle.get() != handle {
+            return;
+        }
+        if self.rx_credits.get() == 0 || payload.len() > self.mps as usize {
+            self.protocol_error();
+            return;
+        }
+        self.rx_credits.set(self.rx_credits.get() - 1);
+        self.rx_frames.set(self.rx_frames.get() + 1);
+
+        let data = if self.rx_len.is_none() {
+            if payload.len() < SDU_LEN_LEN {
+                self.protocol_error();
+                return;
+            }
+            let sdu_len = u16::from_le_bytes([payload[0], payload[1]]) as usize;
+            if sdu_len > self.mtu as usize {
+                self.protocol_error();
+                return;
+            }
+            self.rx_len.set(sdu_len);
+            self.rx_offset.set(0);
+            &payload[SDU_LEN_LEN..]
+        } else {
+            payload
+        };
+
+        let sdu_len = self.rx_len.unwrap_or(0);
+        let offset = self.rx_offset.get();
+        if offset + data.len() > sdu_len {
+            self.protocol_error();
+            return;
+        }
+        self.rx_sdu.map(|sdu| {
+            sdu[offset..offset + data.len()].copy_from_slice(data);
+        });
+        self.rx_offset.set(offset + data.len());
+
+        if self.rx_offset.get() == sdu_len {
+            self.rx_len.clear();
+            self.rx_sdu.map(|sdu| {
+                self.client.map(|client| client.received(&sdu[..sdu_len]));
+            });
+            // The buffer is free again: let the peer send the next SDU.
+            self.credits_owed
+                .set(self.credits_owed.get() + self.rx_frames.take());
+            self.queue(SEND_CREDITS);
+            self.signaling.do_next_op();
+        }
+    }
+
+    fn sent(&self, buffer: &'static mut [u8], result: ReturnCode) {
+        self.frame.replace(buffer);
+        if result != ReturnCode::SUCCESS {
+            self.tx_sdu.take().map(|buffer| {
+                self.client.map(move |client| client.sent(buffer, result));
+            });
+            return;
+        }
+        if self.tx_offset.get() >= self.tx_len.get() {
+            self.tx_sdu.take().map(|buffer| {
+                self.client
+                    .map(move |client| client.sent(buffer, ReturnCode::SUCCESS));
+            });
+        } else {
+            self.send_frame();
+        }
+    }
+}
@@ -0,0 +1,194 @@
+//! Security functions for BLE pairing
+//!
+//! The toolbox functions of LE Secure Connections (`f4`, `f5`, `f6` and `g2`)
+//! and the random address hash `ah`, built on AES-CMAC. They need only a
+//! handful of AES blocks per pairing, so AES-128 is done in software here
+//! rather than through the asynchronous `symmetric_encryption` HIL.
+//!
+//! As in the Core specification, all values are big-endian: `key[0]` is the
+//! most significant byte. SMP sends values least significant byte first, so
+//! `smp` reverses them on the way in and out.
+
+/// The AES S-box.
+const SBOX: [u8; 256] = [
+    0x63, 0x7c, 0x77, 0x7b, 0xf2, 0x6b, 0x6f, 0xc5, 0x30, 0x01, 0x67, 0x2b, 0xfe, 0xd7, 0xab, 0x76,
+    0xca, 0x82, 0xc9, 0x7d, 0xfa, 0x59, 0x47, 0xf0, 0xad, 0xd4, 0xa2, 0xaf, 0x9c, 0xa4, 0x72, 0xc0,
+    0xb7, 0xfd, 0x93, 0x26, 0x36, 0x3f, 0xf7, 0xcc, 0x34, 0xa5, 0xe5, 0xf1, 0x71, 0xd8, 0x31, 0x15,
+    0x04, 0xc7, 0x23, 0xc3, 0x18, 0x96, 0x05, 0x9a, 0x07, 0x12, 0x80, 0xe2, 0xeb, 0x27, 0xb2, 0x75,
+    0x09, 0x83, 0x2c, 0x1a, 0x1b, 0x6e, 0x5a, 0xa0, 0x52, 0x3b, 0xd6, 0xb3, 0x29, 0xe3, 0x2f, 0x84,
+    0x53, 0xd1, 0x00, 0xed, 0x20, 0xfc, 0xb1, 0x5b, 0x6a, 0xcb, 0xbe, 0x39, 0x4a, 0x4c, 0x58, 0xcf,
+    0xd0, 0xef, 0xaa, 0xfb, 0x43, 0x4d, 0x33, 0x85, 0x45, 0xf9, 0x02, 0x7f, 0x50, 0x3c, 0x9f, 0xa8,
+    0x51, 0xa3, 0x40, 0x8f, 0x92, 0x9d, 0x38, 0xf5, 0xbc, 0xb6, 0xda, 0x21, 0x10, 0xff, 0xf3, 0xd2,
+    0xcd, 0x0c, 0x13, 0xec, 0x5f, 0x97, 0x44, 0x17, 0xc4, 0xa7, 0x7e, 0x3d, 0x64, 0x5d, 0x19, 0x73,
+    0x60, 0x81, 0x4f, 0xdc, 0x22, 0x2a, 0x90, 0x88, 0x46, 0xee, 0xb8, 0x14, 0xde, 0x5e, 0x0b, 0xdb,
+    0xe0, 0x32, 0x3a, 0x0a, 0x49, 0x06, 0x24, 0x5c, 0xc2, 0xd3, 0xac, 0x62, 0x91, 0x95, 0xe4, 0x79,
+    0xe7, 0xc8, 0x37, 0x6d, 0x8d, 0xd5, 0x4e, 0xa9, 0x6c, 0x56, 0xf4, 0xea, 0x65, 0x7a, 0xae, 0x08,
+    0xba, 0x78, 0x25, 0x2e, 0x1c, 0xa6, 0xb4, 0xc6, 0xe8, 0xdd, 0x74, 0x1f, 0x4b, 0xbd, 0x8b, 0x8a,
+    0x70, 0x3e, 0xb5, 0x66, 0x48, 0x03, 0xf6, 0x0e, 0x61, 0x35, 0x57, 0xb9, 0x86, 0xc1, 0x1d, 0x9e,
+    0xe1, 0xf8, 0x98, 0x11, 0x69, 0xd9, 0x8e, 0x94, 0x9b, 0x1e, 0x87, 0xe9, 0xce, 0x55, 0x28, 0xdf,
+    0x8c, 0xa1, 0x89, 0x0d, 0xbf, 0xe6, 0x42, 0x68, 0x41, 0x99, 0x2d, 0x0f, 0xb0, 0x54, 0xbb, 0x16,
+];
+
+/// Round constants of the key schedule.
+const RCON: [u8; 10] = [0x01, 0x02, 0x04, 0x08, 0x10, 0x20, 0x40, 0x80, 0x1b, 0x36];
+
+/// Multiply by x in GF(2^8).
+fn xtime(b: u8) -> u8 {
+    (b << 1) ^ if b & 0x80 != 0 { 0x1b } else { 0 }
+}
+
+/// Encrypt one block with AES-128, the security function `e`.
+pub fn aes128(key: &[u8; 16], block: &[u8; 16]) -> [u8; 16] {
+    let mut round_key = *key;
+    let mut state = *block;
+    for (s, k) in state.iter_mut().zip(round_key.iter()) {
+        *s ^= *k;
+    }
+
+    for round in 0..10 {
+        // SubBytes and ShiftRows. The state is column major.
+        let mut shifted = [0; 16];
+        for col in 0..4 {
+            for row in 0..4 {
+                shifted[col * 4 + row] = SBOX[state[((col + row) % 4) * 4 + row] as usize];
+            }
+        }
+        state = shifted;
+
+        // MixColumns, except in the last round.
+        if round != 9 {
+            for col in state.chunks_exact_mut(4) {
+                let all = col[0] ^ col[1] ^ col[2] ^ col[3];
+                let first = col[0];
+                col[0] ^= all ^ xtime(col[0] ^ col[1]);
+                col[1] ^= all ^ xtime(col[1] ^ col[2]);
+                col[2] ^= all ^ xtime(col[2] ^ col[3]);
+                col[3] ^= all ^ xtime(col[3] ^ first);
+            }
+        }
+
+        // Next round key.
+        let mut word = [
+            SBOX[round_key[13] as usize] ^ RCON[round],
+            SBOX[round_key[14] as usize],
+            SBOX[round_key[15] as usize],
+            SBOX[round_key[12] as usize],
+        ];
+        for i in 0..16 {
+            round_key[i] ^= word[i % 4];
+            word[i % 4] = round_key[i];
+        }
+
+        for (s, k) in state.iter_mut().zip(round_key.iter()) {
+            *s ^= *k;
+        }
+    }
+    state
+}
+
+/// Double a CMAC subkey.
+fn double(block: &[u8; 16]) -> [u8; 16] {
+    let mut out = [0; 16];
+    for i in 0..16 {
+        out[i] = block[i] << 1 | block.get(i + 1).map_or(0, |next| next >> 7);
+    }
+    if block[0] & 0x80 != 0 {
+        out[15] ^= 0x87;
+    }
+    out
+}
+
+/// AES-CMAC, as in RFC 4493, over the concatenation of `parts`.
+pub fn aes_cmac(key: &[u8; 16], parts: &[&[u8]]) -> [u8; 16] {
+    let k1 = double(&aes128(key, &[0; 16]));
+    let k2 = double(&k1);
+
+    let mut mac = [0; 16];
+    let mut block = [0; 16];
+    let mut filled = 0;
+    for byte in parts.iter().flat_map(|part| part.iter()) {
+        if filled == 16 {
+            // Only the last block gets a subkey, so wait for more data
+            // before encrypting a full one.
+            for i in 0..16 {
+                block[i] ^= mac[i];
+            }
+            mac = aes128(key, &block);
+            filled = 0;
+        }
+        block[filled] = *byte;
+        filled += 1;
+    }
+
+    if filled == 16 {
+        for i in 0..16 {
+            block[i] ^= k1[i];
+        }
+    } else {
+        block[filled] = 0x80;
+        for b in block[filled + 1..].iter_mut() {
+            *b = 0;
+        }
+        for i in 0..16 {
+            block[i] ^= k2[i];
+        }
+    }
+    for i in 0..16 {
+        block[i] ^= mac[i];
+    }
+    aes128(key, &block)
+}
+
+/// The confirm value generation function.
+pub fn f4(u: &[u8; 32], v: &[u8; 32], x: &[u8; 16], z: u8) -> [u8; 16] {
+    aes_cmac(x, &[u, v, &[z]])
+}
+
+/// The key generation function, returning `(MacKey, LTK)`. `a1` and `a2` are
+/// the address type followed by the address.
+pub fn f5(
+    w: &[u8; 32],
+    n1: &[u8; 16],
+    n2: &[u8; 16],
+    a1: &[u8; 7],
+    a2: &[u8; 7],
+) -> ([u8; 16], [u8; 16]) {
+    const SALT: [u8; 16] = [
+        0x6c, 0x88, 0x83, 0x91, 0xaa, 0xf5, 0xa5, 0x38, 0x60, 0x37, 0x0b, 0xdb, 0x5a, 0x60, 0x83,
+        0xbe,
+    ];
+    const KEY_ID: [u8; 4] = [0x62, 0x74, 0x6c, 0x65];
+    const LENGTH: [u8; 2] = [0x01, 0x00];
+
+    let t = aes_cmac(&SALT, &[w]);
+    let key = |counter: u8| aes_cmac(&t, &[&[counter], &KEY_ID, n1, n2, a1, a2, &LENGTH]);
+    (key(0), key(1))
+}
+
+/// The check value generation function.
+pub fn f6(
+    w: &[u8; 16],
+    n1: &[u8; 16],
+    n2: &[u8; 16],
+    r: &[u8; 16],
+    io_cap: &[u8; 3],
+    a1: &[u8; 7],
+    a2: &[u8; 7],
+) -> [u8; 16] {
+    aes_cmac(w, &[n1, n2, r, io_cap, a1, a2])
+}
+
+/// The numeric comparison value generation function. Returns the six digit
+/// number both users compare.
+pub fn g2(u: &[u8; 32], v: &[u8; 32], x: &[u8; 16], y: &[u8; 16]) -> u32 {
+    let mac = aes_cmac(x, &[u, v, y]);
+    u32::from_be_bytes([mac[12], mac[13], mac[14], mac[15]]) % 1_000_000
+}
+
+/// The random address hash function, used to resolve private addresses.
+pub fn ah(k: &[u8; 16], r: &[u8; 3]) -> [u8; 3] {
+    let mut block = [0; 16];
+    block[13..].copy_from_slice(r);
+    let out = aes128(k, &block);
+    [out[13], out[14], out[15]]
+}
@@ -0,0 +1,383 @@
+//! GATT client
+//!
+//! `GattClient` makes Attribute Protocol requests to the peers of the
+//! connections a central opened with `hil::ble_connection::BleCentral`, for
+//! example to collect readings from off-the-shelf sensors. It can find a
+//! characteristic by UUID, read and write its value, and reports the
+//! notifications and indications the peer sends; notifications are enabled
+//! by writing `1` to the characteristic's CCCD, which is usually the handle
+//! after the value.
+//!
+//! One request is outstanding at a time, across all connections. A board
+//! uses either a `GattClient` or a `gatt_server::GattServer` on its ATT
+//! channel: requests the peer makes of us are answered with "request not
+//! supported", except for the MTU exchange.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//! # use capsules::ble::gatt_client::GattClient;
+//!
+//! let att_buffer = static_init!([u8; 27], [0; 27]);
+//! let gatt_client = static_init!(
+//!     GattClient<'static>,
+//!     GattClient::new(att_channel, att_buffer)
+//! );
+//! att_channel.set_client(gatt_client);
+//! gatt_client.set_client(hub);
+//! ```
+
+use core::cell::Cell;
+
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::ble_connection::{ConnectionHandle, DeviceAddress};
+use kernel::ReturnCode;
+
+use super::att::{self, Uuid, ATT_MTU};
+use super::l2cap::{L2capChannel, L2capClient, HEADER_LEN};
+
+pub trait Client {
+    fn connected(&self, handle: ConnectionHandle);
+
+    /// The connection closed. A request on it that was still outstanding
+    /// failed and was reported before this.
+    fn disconnected(&self, handle: ConnectionHandle);
+
+    /// The characteristic `find_characteristic()` looked for has its value
+    /// at `value_handle`, or `None` if the peer has no such characteristic.
+    /// `Err` carries an ATT error code, or `ERROR_LINK` if the request could
+    /// not be made.
+    fn characteristic_found(&self, handle: ConnectionHandle, value_handle: Result<Option<u16>, u8>);
+
+    /// `read()` completed with the first bytes of the value, at most
+    /// `ATT_MTU - 1`.
+    fn read_done(&self, handle: ConnectionHandle, attribute: u16, value: Result<&[u8], u8>);
+
+    fn write_done(&self, handle: ConnectionHandle, attribute: u16, result: Result<(), u8>);
+
+    /// The peer notified or indicated the value of `attribute`.
+    fn notification_received(&self, handle: ConnectionHandle, attribute: u16, value: &[u8]);
+}
+
+/// Reported instead of an ATT error code when the request could not be sent
+/// or the connection closed before the response arrived.
+pub const ERROR_LINK: u8 = 0xff;
+
+#[derive(Copy, Clone)]
+enum Request {
+    Idle,
+    /// Looking through characteristic declarations from `start` on.
+    Find {
+        connection: ConnectionHandle,
+        uuid: Uuid,
+        start: u16,
+    },
+    Read {
+        connection: ConnectionHandle,
+        attribute: u16,
+    },
+    Write {
+        connection: ConnectionHandle,
+        attribute: u16,
+    },
+}
+
+pub struct GattClient<'a> {
+    channel: &'a L2capChannel<'a>,
+    /// Must hold `HEADER_LEN + ATT_MTU` bytes.
+    buffer: TakeCell<'static, [u8]>,
+    request: Cell<Request>,
+    /// Whether the PDU being sent is the one for `request`.
+    sending_request: Cell<bool>,
+    client: OptionalCell<&'a dyn Client>,
+}
+
+impl<'a> GattClient<'a> {
+    pub fn new(channel: &'a L2capChannel<'a>, buffer: &'static mut [u8]) -> GattClient<'a> {
+        GattClient {
+            channel: channel,
+            buffer: TakeCell::new(buffer),
+            request: Cell::new(Request::Idle),
+            sending_request: Cell::new(false),
+            client: OptionalCell::empty(),
+        }
+    }
+
+    pub fn set_client(&self, client: &'a dyn Client) {
+        self.client.set(client);
+    }
+
+    /// Find the first characteristic of type `uuid` on the peer of
+    /// `connection`.
+    pub fn find_characteristic(&self, connection: ConnectionHandle, uuid: Uuid) -> ReturnCode {
+        self.start(Request::Find {
+            connection: connection,
+            uuid: uuid,
+            start: 1,
+        })
+    }
+
+    /// Read the value of `attribute`.
+    pub fn read(&self, connection: ConnectionHandle, attribute: u16) -> ReturnCode {
+        self.start(Request::Read {
+            connection: connection,
+            attribute: attribute,
+        })
+    }
+
+    /// Write `value`, which must be at most `ATT_MTU - 3` bytes, to
+    /// `attribute` and wait for the peer to confirm.
+    pub fn write(&self, connection: ConnectionHandle, attribute: u16, value: &[u8]) -> ReturnCode {
+        if value.len() > ATT_MTU - 3 {
+            return ReturnCode::ESIZE;
+        }
+        match self.request.get() {
+            Request::Idle => {}
+            _ => return ReturnCode::EBUSY,
+        }
+        let request = Request::Write {
+            connection: connection,
+            attribute: attribute,
+        };
+        self.send(connection, |pdu| {
+            pdu[0] = att::WRITE_REQ;
+            pdu[1..3].copy_from_slice(&attribute.to_le_bytes());
+            pdu[3..3 + value.len()].copy_from_slice(value);
+            3 + value.len()
+        })
+        .map_or_else(|rcode| rcode, |()| self.wait_for(request))
+    }
+
+    fn start(&self, request: Request) -> ReturnCode {
+        match self.request.get() {
+            Request::Idle => self.send_request(request),
+            _ => ReturnCode::EBUSY,
+        }
+    }
+
+    fn wait_for(&self, request: Request) -> ReturnCode {
+        self.request.set(request);
+        self.sending_request.set(true);
+        ReturnCode::SUCCESS
+    }
+
+    /// Send the PDU for `request`, other than writes.
+    fn send_request(&self, request: Request) -> ReturnCode {
+        let result = match request {
+            Request::Find {
+                connection, start, ..
+            } => self.send(connection, |pdu| {
+                pdu[0] = att::READ_BY_TYPE_REQ;
+                pdu[1..3].copy_from_slice(&start.to_le_bytes());
+                pdu[3..5].copy_from_slice(&0xffffu16.to_le_bytes());
+                5 + att::CHARACTERISTIC.write_to(&mut pdu[5..])
+            }),
+            Request::Read {
+                connection,
+                attribute,
+            } => self.send(connection, |pdu| {
+                pdu[0] = att::READ_REQ;
+                pdu[1..3].copy_from_slice(&attribute.to_le_bytes());
+                3
+            }),
+            Request::Write { .. } | Request::Idle => Err(ReturnCode::EINVAL),
+        };
+        result.map_or_else(|rcode| rcode, |()| self.wait_for(request))
+    }
+
+    /// Fill in a PDU with `fill`, which returns its length, and send it.
+    fn send<F>(&self, connection: ConnectionHandle, fill: F) -> Result<(), ReturnCode>
+    where
+        F: FnOnce(&mut [u8]) -> usize,
+    {
+        let buffer = self.buffer.take().ok_or(ReturnCode::EBUSY)?;
+        let len = fill(&mut buffer[HEADER_LEN..HEADER_LEN + ATT_MTU]);
+        self.channel
+            .send(connection, buffer, len)
+            .map_err(|(rcode, buffer)| {
+                self.buffer.replace(buffer);
+                rcode
+            })
+    }
+
+    /// End the outstanding request with `error` if it is on `connection`.
+    fn fail(&self, connection: ConnectionHandle, error: u8) {
+        let request = self.request.get();
+        match request {
+            Request::Find { connection: c, .. }
+            | Request::Read { connection: c, .. }
+            | Request::Write { connection: c, .. }
+                if c == connection =>
+            {
+                self.request.set(Request::Idle)
+            }
+            _ => return,
+        }
+        self.client.map(|client| match request {
+            Request::Find { .. } => client.characteristic_found(connection, Err(error)),
+            Request::Read { attribute, .. } => client.read_done(connection, attribute, Err(error)),
+            Request::Write { attribute, .. } => {
+                client.write_done(connection, attribute, Err(error))
+            }
+            Request::Idle => {}
+        });
+    }
+
+    /// Handle a response to the outstanding request.
+    fn response(&self, connection: ConnectionHandle, pdu: &[u8]) {
+        let request = self.request.get();
+        self.request.set(Request::Idle);
+        let error = if pdu[0] == att::ERROR_RSP && pdu.len() == 5 {
+            Some(pdu[4])
+        } else {
+            None
+        };
+        match request {
+            Request::Find { uuid, .. } => {
+                if error == Some(att::ERROR_ATTRIBUTE_NOT_FOUND) {
+                    self.client
+                        .map(|client| client.characteristic_found(connection, Ok(None)));
+                    return;
+                }
+                if let Some(code) = error {
+                    self.client
+                        .map(|client| client.characteristic_found(connection, Err(code)));
+                    return;
+                }
+                // Each entry is a declaration handle, properties, value
+                // handle and the characteristic UUID.
+                let entry_len = pdu.get(1).map_or(0, |len| *len as usize);
+                if pdu[0] != att::READ_BY_TYPE_RSP || entry_len < 7 {
+                    self.client.map(|client| {
+                        client.characteristic_found(connection, Err(att::ERROR_INVALID_PDU))
+                    });
+                    return;
+                }
+                let mut last = 0;
+                for entry in pdu[2..].chunks_exact(entry_len) {
+                    last = u16::from_le_bytes([entry[0], entry[1]]);
+                    if Uuid::from_bytes(&entry[5..]) == Some(uuid) {
+                        let value_handle = u16::from_le_bytes([entry[3], entry[4]]);
+                        self.client.map(|client| {
+                            client.characteristic_found(connection, Ok(Some(value_handle)))
+                        });
+                        return;
+                    }
+                }
+                if last == 0xffff {
+                    self.client
+                        .map(|client| client.characteristic_found(connection, Ok(None)));
+                    return;
+                }
+                // Ask for the declarations after the ones we got.
+                let rcode = self.send_request(Request::Find {
+                    connection: connection,
+                    uuid: uuid,
+                    start: last + 1,
+                });
+                if rcode != ReturnCode::SUCCESS {
+                    self.client
+                        .map(|client| client.characteristic_found(connection, Err(ERROR_LINK)));
+                }
+            }
+            Request::Read { attribute, .. } => {
+                let value = match error {
+                    Some(code) => Err(code),
+                    None if pdu[0] == att::READ_RSP => Ok(&pdu[1..]),
+                    None => Err(att::ERROR_INVALID_PDU),
+                };
+                self.client
+                    .map(|client| client.read_done(connection, attribute, value));
+            }
+            Request::Write { attribute, .. } => {
+                let result = match error {
+                    Some(code) => Err(code),
+                    None if pdu[0] == att::WRITE_RSP => Ok(()),
+                    None => Err(att::ERROR_INVALID_PDU),
+                };
+                self.client
+                    .map(|client| client.write_done(connection, attribute, result));
+            }
+            Request::Idle => {}
+        }
+    }
+
+    /// Answer a request the peer made.
+    fn answer(&self, connection: ConnectionHandle, opcode: u8) {
+        let _ = self.send(connection, |pdu| {
+            if opcode == att::EXCHANGE_MTU_REQ {
+                pdu[0] = att::EXCHANGE_MTU_RSP;
+                pdu[1..3].copy_from_slice(&(ATT_MTU as u16).to_le_bytes());
+                3
+            } else {
+                pdu[0] = att::ERROR_RSP;
+                pdu[1] = opcode;
+                pdu[2..4].copy_from_slice(&0u16.to_le_bytes());
+                pdu[4] = att::ERROR_REQUEST_NOT_SUPPORTED;
+                5
+            }
+        });
+    }
+}
+
+impl<'a> L2capClient for GattClient<'a> {
+    fn connected(&self, handle: ConnectionHandle, _peer: DeviceAddress) {
+        self.client.map(|client| client.connected(handle));
+    }
+
+    fn disconnected(&self, handle: ConnectionHandle) {
+        self.fail(handle, ERROR_LINK);
+        self.client.map(|client| client.disconnected(handle));
+    }
+
+    fn received(&self, handle: ConnectionHandle, payload: &[u8]) {
+        if payload.is_empty() || payload.len() > ATT_MTU {
+            return;
+        }
+        let opcode = payload[0];
+        match opcode {
+            att::HANDLE_VALUE_NTF | att::HANDLE_VALUE_IND if payload.len() >= 3 => {
+                if opcode == att::HANDLE_VALUE_IND {
+                    let _ = self.send(handle, |pdu| {
+                        pdu[0] = att::HANDLE_VALUE_CFM;
+                        1
+                    });
+                }
+                let attribute = u16::from_le_bytes([payload[1], payload[2]]);
+                self.client
+                    .map(|client| client.notification_received(handle, attribute, &payload[3..]));
+            }
+            att::ERROR_RSP | att::READ_BY_TYPE_RSP | att::READ_RSP | att::WRITE_RSP => {
+                let ours = match self.request.get() {
+                    Request::Find { connection, .. }
+                    | Request::Read { connection, .. }
+                    | Request::Write { connection, .. } => connection == handle,
+                    Request::Idle => false,
+                };
+                if ours {
+                    self.response(handle, payload);
+                }
+            }
+            _ if opcode & att::COMMAND_FLAG != 0 => {}
+            // Requests have even opcodes, responses odd ones.
+            _ if opcode & 0x01 == 0 => self.answer(handle, opcode),
+            _ => {}
+        }
+    }
+
+    fn sent(&self, buffer: &'static mut [u8], result: ReturnCode) {
+        self.buffer.replace(buffer);
+        if self.sending_request.replace(false) && result != ReturnCode::SUCCESS {
+            // The request never reached the peer, so no response will come.
+            let connection = match self.request.get() {
+                Request::Find { connection, .. }
+                | Request::Read { connection, .. }
+                | Request::Write { connection, .. } => connection,
+                Request::Idle => return,
+            };
+            self.fail(connection, ERROR_LINK);
+        }
+    }
+}
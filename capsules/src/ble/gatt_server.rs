@@ -0,0 +1,739 @@
+//! GATT server
+//!
+//! `GattServer` answers the Attribute Protocol requests a connected central
+//! makes on the ATT channel, serving an attribute table built from the
+//! `Service`s registered with `add_service()`. Each service is a primary
+//! service holding `Characteristic`s; a characteristic's value is read and
+//! written through its `CharacteristicClient`, so the capsule or app behind
+//! it decides what the value is. Characteristics with `PROPERTY_NOTIFY` get a
+//! Client Characteristic Configuration descriptor, and once the central
+//! enabled notifications, `notify()` sends it the new value.
+//!
+//! Handles are assigned in registration order, starting at 1:
+//!
+//! ```text
+//! service declaration        handle
+//!   characteristic declaration handle + 1
+//!   characteristic value       handle + 2  (value_handle())
+//!   CCCD, if notifiable        handle + 3
+//!   next characteristic ...
+//! ```
+//!
+//! The ATT MTU is always the default of 23 bytes, values longer than
+//! `MAX_VALUE_LEN` are not supported, and only notifications, not
+//! indications, are sent. CCCDs are reset when the connection closes.
+//!
+//! A characteristic can `require_security()` of the link: until `Smp`
+//! reports, through `SecurityClient`, that the connection is encrypted well
+//! enough, reading or writing its value and enabling its notifications fail
+//! with Insufficient Authentication, which makes the central pair.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//! # use capsules::ble::att::Uuid;
+//! # use capsules::ble::gatt_server::{Characteristic, GattServer, Service};
+//! # use capsules::ble::gatt_server::{PROPERTY_NOTIFY, PROPERTY_READ};
+//!
+//! let battery_level = static_init!(
+//!     [Characteristic<'static>; 1],
+//!     [Characteristic::new(Uuid::Uuid16(0x2a19), PROPERTY_READ | PROPERTY_NOTIFY)]
+//! );
+//! let battery_service = static_init!(
+//!     Service<'static>,
+//!     Service::new(Uuid::Uuid16(0x180f), battery_level)
+//! );
+//! let att_buffer = static_init!([u8; 27], [0; 27]);
+//! let gatt = static_init!(GattServer<'static>, GattServer::new(att_channel, att_buffer));
+//! att_channel.set_client(gatt);
+//! battery_level[0].set_client(battery_capsule);
+//! gatt.add_service(battery_service);
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::{List, ListLink, ListNode};
+use kernel::hil::ble_connection::{ConnectionHandle, DeviceAddress};
+use kernel::ReturnCode;
+
+use super::att::{self, Uuid, ATT_MTU};
+use super::l2cap::{L2capChannel, L2capClient, HEADER_LEN};
+use super::smp::{SecurityClient, SecurityLevel};
+
+/// The longest characteristic value that can be read.
+pub const MAX_VALUE_LEN: usize = 64;
+
+/// Characteristic properties.
+pub const PROPERTY_READ: u8 = 0x02;
+pub const PROPERTY_WRITE_WITHOUT_RESPONSE: u8 = 0x04;
+pub const PROPERTY_WRITE: u8 = 0x08;
+pub const PROPERTY_NOTIFY: u8 = 0x10;
+
+/// Implemented by whatever holds a characteristic's value.
+pub trait CharacteristicClient {
+    /// Copy the value of `characteristic` into `value` and return its
+    /// length, or return an ATT error code.
+    fn read_value(&self, characteristic: &Characteristic, value: &mut [u8]) -> Result<usize, u8>;
+
+    /// The central wrote `value`. Return an ATT error code to reject it.
+    fn write_value(&self, characteristic: &Characteristic, value: &[u8]) -> Result<(), u8>;
+
+    /// The central enabled or disabled notifications.
+    fn notifications_changed(&self, characteristic: &Characteristic, enabled: bool);
+
+    /// The notification passed to `GattServer::notify()` was sent. Also
+    /// called with `EBUSY` once a `notify()` that returned `EBUSY` can be
+    /// retried.
+    fn notification_sent(&self, characteristic: &Characteristic, result: ReturnCode);
+}
+
+pub struct Characteristic<'a> {
+    uuid: Cell<Uuid>,
+    properties: Cell<u8>,
+    /// Assigned by `GattServer::add_service()`.
+    value_handle: Cell<u16>,
+    notifications: Cell<bool>,
+    /// What the link needs before the value can be accessed.
+    security: Cell<SecurityLevel>,
+    client: OptionalCell<&'a dyn CharacteristicClient>,
+}
+
+impl<'a> Characteristic<'a> {
+    pub const fn new(uuid: Uuid, properties: u8) -> Characteristic<'a> {
+        Characteristic {
+            uuid: Cell::new(uuid),
+            properties: Cell::new(properties),
+            value_handle: Cell::new(0),
+            notifications: Cell::new(false),
+            security: Cell::new(SecurityLevel::None),
+            client: OptionalCell::empty(),
+        }
+    }
+
+    pub fn set_client(&self, client: &'a dyn CharacteristicClient) {
+        self.client.set(client);
+    }
+
+    /// Change the type and properties. Only allowed before the service is
+    /// added to the server.
+    pub fn configure(&self, uuid: Uuid, properties: u8) {
+        self.uuid.set(uuid);
+        self.properties.set(properties);
+    }
+
+    /// Only allow access to the value, and notifications, once the link is
+    /// secured to at least `level`.
+    pub fn require_security(&self, level: SecurityLevel) {
+        self.security.set(level);
+    }
+
+    pub fn uuid(&self) -> Uuid {
+        self.uuid.get()
+    }
+
+    pub fn value_handle(&self) -> u16 {
+        self.value_handle.get()
+    }
+
+    /// Whether the central enabled notifications.
+    pub fn notifications_enabled(&self) -> bool {
+        self.notifications.get()
+    }
+
+    fn check_security(&self, level: SecurityLevel) -> Result<(), u8> {
+        if level < self.security.get() {
+            Err(att::ERROR_INSUFFICIENT_AUTHENTICATION)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn has_cccd(&self) -> bool {
+        self.properties.get() & PROPERTY_NOTIFY != 0
+    }
+
+    /// Handles used by the declaration, value and CCCD.
+    fn num_handles(&self) -> u16 {
+        if self.has_cccd() {
+            3
+        } else {
+            2
+        }
+    }
+}
+
+pub struct Service<'a> {
+    uuid: Cell<Uuid>,
+    characteristics: &'a [Characteristic<'a>],
+    /// How many of `characteristics` are part of the service.
+    count: Cell<usize>,
+    /// Handle of the service declaration, assigned by `add_service()`.
+    handle: Cell<u16>,
+    next: ListLink<'a, Service<'a>>,
+}
+
+impl<'a> ListNode<'a, Service<'a>> for Service<'a> {
+    fn next(&'a self) -> &'a ListLink<'a, Service<'a>> {
+        &self.next
+    }
+}
+
+impl<'a> Service<'a> {
+    pub const fn new(uuid: Uuid, characteristics: &'a [Characteristic<'a>]) -> Service<'a> {
+        Service {
+            uuid: Cell::new(uuid),
+            characteristics: characteristics,
+            count: Cell::new(characteristics.len()),
+            handle: Cell::new(0),
+            next: ListLink::empty(),
+        }
+    }
+
+    /// Change the type and use only the first `count` characteristics. Only
+    /// allowed before the service is added to the server.
+    pub fn configure(&self, uuid: Uuid, count: usize) {
+        self.uuid.set(uuid);
+        self.count.set(cmp::min(count, self.characteristics.len()));
+    }
+
+    pub fn characteristics(&self) -> &'a [Characteristic<'a>] {
+        &self.characteristics[..self.count.get()]
+    }
+
+    fn num_handles(&self) -> u16 {
+        1 + self
+            .characteristics()
+            .iter()
+            .map(|c| c.num_handles())
+            .sum::<u16>()
+    }
+
+    fn end_handle(&self) -> u16 {
+        self.handle.get() + self.num_handles() - 1
+    }
+}
+
+/// An entry in the attribute table.
+#[derive(Copy, Clone)]
+enum Attribute<'a> {
+    Service(&'a Service<'a>),
+    Declaration(&'a Characteristic<'a>),
+    Value(&'a Characteristic<'a>),
+    Cccd(&'a Characteristic<'a>),
+}
+
+impl<'a> Attribute<'a> {
+    fn uuid(&self) -> Uuid {
+        match *self {
+            Attribute::Service(_) => att::PRIMARY_SERVICE,
+            Attribute::Declaration(_) => att::CHARACTERISTIC,
+            Attribute::Value(c) => c.uuid(),
+            Attribute::Cccd(_) => att::CLIENT_CHARACTERISTIC_CONFIGURATION,
+        }
+    }
+
+    /// Write the attribute's value into `buf` and return its length, on a
+    /// link secured to `level`.
+    fn read(&self, buf: &mut [u8], level: SecurityLevel) -> Result<usize, u8> {
+        match *self {
+            Attribute::Service(s) => Ok(s.uuid.get().write_to(buf)),
+            Attribute::Declaration(c) => {
+                buf[0] = c.properties.get();
+                buf[1..3].copy_from_slice(&c.value_handle().to_le_bytes());
+                Ok(3 + c.uuid().write_to(&mut buf[3..]))
+            }
+            Attribute::Value(c) => {
+                if c.properties.get() & PROPERTY_READ == 0 {
+                    return Err(att::ERROR_READ_NOT_PERMITTED);
+                }
+                c.check_security(level)?;
+                c.client
+                    .map_or(Err(att::ERROR_UNLIKELY), |client| client.read_value(c, buf))
+                    .map(|len| cmp::min(len, buf.len()))
+            }
+            Attribute::Cccd(c) => {
+                buf[0] = c.notifications.get() as u8;
+                buf[1] = 0;
+                Ok(2)
+            }
+        }
+    }
+}
+
+/// A request that arrived while the buffer was busy with a notification.
+type DeferredRequest = Option<([u8; ATT_MTU], usize)>;
+
+pub struct GattServer<'a> {
+    channel: &'a L2capChannel<'a>,
+    services: List<'a, Service<'a>>,
+    next_handle: Cell<u16>,
+    connection: OptionalCell<ConnectionHandle>,
+    security: Cell<SecurityLevel>,
+    /// Holds responses and notifications while they are sent. Must hold
+    /// `HEADER_LEN + ATT_MTU` bytes.
+    buffer: TakeCell<'static, [u8]>,
+    deferred: Cell<DeferredRequest>,
+    /// Value handle of the characteristic a notification is being sent for.
+    notifying: OptionalCell<u16>,
+    /// Value handle of a characteristic `notify()` returned `EBUSY` for.
+    retry: OptionalCell<u16>,
+}
+
+impl<'a> GattServer<'a> {
+    pub fn new(channel: &'a L2capChannel<'a>, buffer: &'static mut [u8]) -> GattServer<'a> {
+        GattServer {
+            channel: channel,
+            services: List::new(),
+            next_handle: Cell::new(1),
+            connection: OptionalCell::empty(),
+            security: Cell::new(SecurityLevel::None),
+            buffer: TakeCell::new(buffer),
+            deferred: Cell::new(None),
+            notifying: OptionalCell::empty(),
+            retry: OptionalCell::empty(),
+        }
+    }
+
+    /// Add `service` to the end of the attribute table and assign handles to
+    /// it and its characteristics.
+    pub fn add_service(&self, service: &'a Service<'a>) {
+        let mut handle = self.next_handle.get();
+        service.handle.set(handle);
+        for c in service.characteristics() {
+            c.value_handle.set(handle + 2);
+            handle += c.num_handles();
+        }
+        self.next_handle.set(handle + 1);
+        self.services.push_tail(service);
+    }
+
+    /// Notify the central of the new `value` of `characteristic`.
+    ///
+    /// Returns `EOFF` if nobody is connected or notifications are disabled,
+    /// and `EBUSY` while the previous notification or a response is being
+    /// sent.
+    pub fn notify(&self, characteristic: &Characteristic, value: &[u8]) -> ReturnCode {
+        let handle = match self.connection.map(|handle| *handle) {
+            Some(handle) if characteristic.notifications_enabled() => handle,
+            _ => return ReturnCode::EOFF,
+        };
+        let buffer = match self.buffer.take() {
+            Some(buffer) => buffer,
+            None => {
+                self.retry.set(characteristic.value_handle());
+                return ReturnCode::EBUSY;
+            }
+        };
+        let pdu = &mut buffer[HEADER_LEN..];
+        let len = cmp::min(value.len(), ATT_MTU - 3);
+        pdu[0] = att::HANDLE_VALUE_NTF;
+        pdu[1..3].copy_from_slice(&characteristic.value_handle().to_le_bytes());
+        pdu[3..3 + len].copy_from_slice(&value[..len]);
+        match self.channel.send(handle, buffer, 3 + len) {
+            Ok(()) => {
+                self.notifying.set(characteristic.value_handle());
+                ReturnCode::SUCCESS
+            }
+            Err((rcode, buffer)) => {
+                self.buffer.replace(buffer);
+                rcode
+            }
+        }
+    }
+
+    /// Call `f` with each attribute with a handle in `start..=end`, in order,
+    /// until it returns `false`.
+    fn for_each_attribute<F>(&self, start: u16, end: u16, mut f: F)
+    where
+        F: FnMut(u16, Attribute<'a>) -> bool,
+    {
+        let mut visit = |handle: u16, attribute: Attribute<'a>| {
+            if handle < start || handle > end {
+                true
+            } else {
+                f(handle, attribute)
+            }
+        };
+        for service in self.services.iter() {
+            if service.end_handle() < start {
+                continue;
+            }
+            let mut handle = service.handle.get();
+            if handle > end || !visit(handle, Attribute::Service(service)) {
+                return;
+            }
+            for c in service.characteristics() {
+                if !visit(handle + 1, Attribute::Declaration(c))
+                    || !visit(handle + 2, Attribute::Value(c))
+                    || (c.has_cccd() && !visit(handle + 3, Attribute::Cccd(c)))
+                {
+                    return;
+                }
+                handle += c.num_handles();
+            }
+        }
+    }
+
+    fn attribute(&self, handle: u16) -> Option<Attribute<'a>> {
+        let mut found = None;
+        self.for_each_attribute(handle, handle, |_, attribute| {
+            found = Some(attribute);
+            false
+        });
+        found
+    }
+
+    fn characteristic(&self, value_handle: u16) -> Option<&'a Characteristic<'a>> {
+        match self.attribute(value_handle) {
+            Some(Attribute::Value(c)) => Some(c),
+            _ => None,
+        }
+    }
+
+    /// Handle an ATT PDU and write the response, if any, to `rsp`. Returns
+    /// the length of the response.
+    fn handle_pdu(&self, req: &[u8], rsp: &mut [u8]) -> Option<usize> {
+        let opcode = req[0];
+        let u16_at = |i: usize| u16::from_le_bytes([req[i], req[i + 1]]);
+        let error = |rsp: &mut [u8], handle: u16, code: u8| {
+            rsp[0] = att::ERROR_RSP;
+            rsp[1] = opcode;
+            rsp[2..4].copy_from_slice(&handle.to_le_bytes());
+            rsp[4] = code;
+            Some(5)
+        };
+        // Check the handle range of requests that have one.
+        let range = |start: u16, end: u16| start != 0 && start <= end;
+
+        match opcode {
+            att::EXCHANGE_MTU_REQ if req.len() == 3 => {
+                rsp[0] = att::EXCHANGE_MTU_RSP;
+                rsp[1..3].copy_from_slice(&(ATT_MTU as u16).to_le_bytes());
+                Some(3)
+            }
+
+            att::FIND_INFORMATION_REQ if req.len() == 5 => {
+                let (start, end) = (u16_at(1), u16_at(3));
+                if !range(start, end) {
+                    return error(rsp, start, att::ERROR_INVALID_HANDLE);
+                }
+                rsp[0] = att::FIND_INFORMATION_RSP;
+                let mut len = 2;
+                let mut uuid_len = 0;
+                self.for_each_attribute(start, end, |handle, attribute| {
+                    let uuid = attribute.uuid();
+                    if uuid_len == 0 {
+                        uuid_len = uuid.size();
+                        rsp[1] = if uuid_len == 2 { 1 } else { 2 };
+                    }
+                    if uuid.size() != uuid_len || len + 2 + uuid_len > ATT_MTU {
+                        return false;
+                    }
+                    rsp[len..len + 2].copy_from_slice(&handle.to_le_bytes());
+                    uuid.write_to(&mut rsp[len + 2..]);
+                    len += 2 + uuid_len;
+                    true
+                });
+                if uuid_len == 0 {
+                    error(rsp, start, att::ERROR_ATTRIBUTE_NOT_FOUND)
+                } else {
+                    Some(len)
+                }
+            }
+
+            att::FIND_BY_TYPE_VALUE_REQ if req.len() >= 7 => {
+                let (start, end) = (u16_at(1), u16_at(3));
+                if !range(start, end) {
+                    return error(rsp, start, att::ERROR_INVALID_HANDLE);
+                }
+                // Only used to discover services by UUID.
+                let uuid = Uuid::from_bytes(&req[7..]);
+                rsp[0] = att::FIND_BY_TYPE_VALUE_RSP;
+                let mut len = 1;
+                if Uuid::Uuid16(u16_at(5)) == att::PRIMARY_SERVICE {
+                    for service in self.services.iter() {
+                        let handle = service.handle.get();
+                        if handle < start || handle > end || Some(service.uuid.get()) != uuid {
+                            continue;
+                        }
+                        if len + 4 > ATT_MTU {
+                            break;
+                        }
+                        rsp[len..len + 2].copy_from_slice(&handle.to_le_bytes());
+                        rsp[len + 2..len + 4].copy_from_slice(&service.end_handle().to_le_bytes());
+                        len += 4;
+                    }
+                }
+                if len == 1 {
+                    error(rsp, start, att::ERROR_ATTRIBUTE_NOT_FOUND)
+                } else {
+                    Some(len)
+                }
+            }
+
+            att::READ_BY_TYPE_REQ if req.len() == 7 || req.len() == 21 => {
+                let (start, end) = (u16_at(1), u16_at(3));
+                if !range(start, end) {
+                    return error(rsp, start, att::ERROR_INVALID_HANDLE);
+                }
+                let uuid = Uuid::from_bytes(&req[5..]);
+                rsp[0] = att::READ_BY_TYPE_RSP;
+                let mut len = 2;
+                let mut value_len = 0;
+                let mut failed = None;
+                self.for_each_attribute(start, end, |handle, attribute| {
+                    if Some(attribute.uuid()) != uuid {
+                        return true;
+                    }
+                    let mut value = [0; MAX_VALUE_LEN];
+                    match attribute.read(&mut value, self.security.get()) {
+                        Ok(read) => {
+                            // Each entry is at most 255 bytes and all have
+                            // the length of the first.
+                            let read = cmp::min(read, ATT_MTU - 4);
+                            if value_len == 0 {
+                                value_len = read;
+                                rsp[1] = (2 + read) as u8;
+                            } else if read != value_len || len + 2 + read > ATT_MTU {
+                                return false;
+                            }
+                            rsp[len..len + 2].copy_from_slice(&handle.to_le_bytes());
+                            rsp[len + 2..len + 2 + read].copy_from_slice(&value[..read]);
+                            len += 2 + read;
+                            true
+                        }
+                        Err(code) => {
+                            if len == 2 {
+                                failed = Some((handle, code));
+                            }
+                            false
+                        }
+                    }
+                });
+                match failed {
+                    Some((handle, code)) => error(rsp, handle, code),
+                    None if len == 2 => error(rsp, start, att::ERROR_ATTRIBUTE_NOT_FOUND),
+                    None => Some(len),
+                }
+            }
+
+            att::READ_REQ | att::READ_BLOB_REQ
+                if (opcode == att::READ_REQ && req.len() == 3)
+                    || (opcode == att::READ_BLOB_REQ && req.len() == 5) =>
+            {
+                let handle = u16_at(1);
+                let offset = if opcode == att::READ_BLOB_REQ {
+                    u16_at(3) as usize
+                } else {
+                    0
+                };
+                let attribute = match self.attribute(handle) {
+                    Some(attribute) => attribute,
+                    None => return error(rsp, handle, att::ERROR_INVALID_HANDLE),
+                };
+                let mut value = [0; MAX_VALUE_LEN];
+                match attribute.read(&mut value, self.security.get()) {
+                    Ok(read) if offset <= read => {
+                        let len = cmp::min(read - offset, ATT_MTU - 1);
+                        rsp[0] = if opcode == att::READ_REQ {
+                            att::READ_RSP
+                        } else {
+                            att::READ_BLOB_RSP
+                        };
+                        rsp[1..1 + len].copy_from_slice(&value[offset..offset + len]);
+                        Some(1 + len)
+                    }
+                    Ok(_) => error(rsp, handle, att::ERROR_INVALID_OFFSET),
+                    Err(code) => error(rsp, handle, code),
+                }
+            }
+
+            att::READ_BY_GROUP_TYPE_REQ if req.len() == 7 || req.len() == 21 => {
+                let (start, end) = (u16_at(1), u16_at(3));
+                if !range(start, end) {
+                    return error(rsp, start, att::ERROR_INVALID_HANDLE);
+                }
+                if Uuid::from_bytes(&req[5..]) != Some(att::PRIMARY_SERVICE) {
+                    return error(rsp, start, att::ERROR_UNSUPPORTED_GROUP_TYPE);
+                }
+                rsp[0] = att::READ_BY_GROUP_TYPE_RSP;
+                let mut len = 2;
+                let mut uuid_len = 0;
+                for service in self.services.iter() {
+                    let handle = service.handle.get();
+                    if handle < start || handle > end {
+                        continue;
+                    }
+                    let uuid = service.uuid.get();
+                    if uuid_len == 0 {
+                        uuid_len = uuid.size();
+                        rsp[1] = (4 + uuid_len) as u8;
+                    } else if uuid.size() != uuid_len || len + 4 + uuid_len > ATT_MTU {
+                        break;
+                    }
+                    rsp[len..len + 2].copy_from_slice(&handle.to_le_bytes());
+                    rsp[len + 2..len + 4].copy_from_slice(&service.end_handle().to_le_bytes());
+                    uuid.write_to(&mut rsp[len + 4..]);
+                    len += 4 + uuid_len;
+                }
+                if uuid_len == 0 {
+                    error(rsp, start, att::ERROR_ATTRIBUTE_NOT_FOUND)
+                } else {
+                    Some(len)
+                }
+            }
+
+            att::WRITE_REQ | att::WRITE_CMD if req.len() >= 3 => {
+                let handle = u16_at(1);
+                let value = &req[3..];
+                let result = match self.attribute(handle) {
+                    Some(Attribute::Value(c)) => {
+                        let permitted = if opcode == att::WRITE_REQ {
+                            PROPERTY_WRITE
+                        } else {
+                            PROPERTY_WRITE_WITHOUT_RESPONSE
+                        };
+                        if c.properties.get() & permitted == 0 {
+                            Err(att::ERROR_WRITE_NOT_PERMITTED)
+                        } else {
+                            c.check_security(self.security.get()).and_then(|()| {
+                                c.client.map_or(Err(att::ERROR_UNLIKELY), |client| {
+                                    client.write_value(c, value)
+                                })
+                            })
+                        }
+                    }
+                    Some(Attribute::Cccd(c)) => {
+                        if value.len() != 2 {
+                            Err(att::ERROR_INVALID_ATTRIBUTE_VALUE_LENGTH)
+                        } else if value[0] & 0x01 != 0
+                            && c.check_security(self.security.get()).is_err()
+                        {
+                            Err(att::ERROR_INSUFFICIENT_AUTHENTICATION)
+                        } else {
+                            let enabled = value[0] & 0x01 != 0;
+                            if c.notifications.replace(enabled) != enabled {
+                                c.client
+                                    .map(|client| client.notifications_changed(c, enabled));
+                            }
+                            Ok(())
+                        }
+                    }
+                    Some(_) => Err(att::ERROR_WRITE_NOT_PERMITTED),
+                    None => Err(att::ERROR_INVALID_HANDLE),
+                };
+                match result {
+                    _ if opcode == att::WRITE_CMD => None,
+                    Ok(()) => {
+                        rsp[0] = att::WRITE_RSP;
+                        Some(1)
+                    }
+                    Err(code) => error(rsp, handle, code),
+                }
+            }
+
+            att::HANDLE_VALUE_CFM => None,
+
+            _ if opcode & att::COMMAND_FLAG != 0 => None,
+
+            att::EXCHANGE_MTU_REQ
+            | att::FIND_INFORMATION_REQ
+            | att::FIND_BY_TYPE_VALUE_REQ
+            | att::READ_BY_TYPE_REQ
+            | att::READ_REQ
+            | att::READ_BLOB_REQ
+            | att::READ_BY_GROUP_TYPE_REQ
+            | att::WRITE_REQ => error(rsp, 0, att::ERROR_INVALID_PDU),
+
+            _ => error(rsp, 0, att::ERROR_REQUEST_NOT_SUPPORTED),
+        }
+    }
+
+    /// Handle `req` and send the response using `buffer`.
+    fn respond(&self, handle: ConnectionHandle, buffer: &'static mut [u8], req: &[u8]) {
+        match self.handle_pdu(req, &mut buffer[HEADER_LEN..HEADER_LEN + ATT_MTU]) {
+            Some(len) => {
+                if let Err((_, buffer)) = self.channel.send(handle, buffer, len) {
+                    self.buffer.replace(buffer);
+                }
+            }
+            None => {
+                self.buffer.replace(buffer);
+            }
+        }
+    }
+}
+
+impl<'a> L2capClient for GattServer<'a> {
+    fn connected(&self, handle: ConnectionHandle, _peer: DeviceAddress) {
+        self.connection.set(handle);
+    }
+
+    fn disconnected(&self, handle: ConnectionHandle) {
+        if !self.connection.contains(&handle) {
+            return;
+        }
+        self.connection.clear();
+        self.security.set(SecurityLevel::None);
+        self.deferred.set(None);
+        for service in self.services.iter() {
+            for c in service.characteristics() {
+                if c.notifications.replace(false) {
+                    c.client
+                        .map(|client| client.notifications_changed(c, false));
+                }
+            }
+        }
+    }
+
+    fn received(&self, handle: ConnectionHandle, payload: &[u8]) {
+        if payload.is_empty() || payload.len() > ATT_MTU || !self.connection.contains(&handle) {
+            return;
+        }
+        match self.buffer.take() {
+            Some(buffer) => self.respond(handle, buffer, payload),
+            None => {
+                // Answer once the notification was sent. The central only
+                // has one request outstanding at a time.
+                let mut req = [0; ATT_MTU];
+                req[..payload.len()].copy_from_slice(payload);
+                self.deferred.set(Some((req, payload.len())));
+            }
+        }
+    }
+
+    fn sent(&self, buffer: &'static mut [u8], result: ReturnCode) {
+        match (self.deferred.take(), self.connection.map(|handle| *handle)) {
+            (Some((req, len)), Some(handle)) => self.respond(handle, buffer, &req[..len]),
+            _ => {
+                self.buffer.replace(buffer);
+            }
+        }
+
+        self.notifying
+            .take()
+            .and_then(|value_handle| self.characteristic(value_handle))
+            .map(|c| c.client.map(|client| client.notification_sent(c, result)));
+        if self.buffer.is_some() {
+            self.retry
+                .take()
+                .and_then(|value_handle| self.characteristic(value_handle))
+                .map(|c| {
+                    c.client
+                        .map(|client| client.notification_sent(c, ReturnCode::EBUSY))
+                });
+        }
+    }
+}
+
+impl<'a> SecurityClient for GattServer<'a> {
+    fn security_changed(&self, handle: ConnectionHandle, level: SecurityLevel) {
+        if self.connection.contains(&handle) {
+            self.security.set(level);
+        }
+    }
+}
@@ -0,0 +1,300 @@
+//! System call interface to the GATT server
+//!
+//! Lets one app define a GATT service, hold the values of its
+//! characteristics in its own buffers and advertise so that a central can
+//! connect to it. The board provides the `Service` and a pool of
+//! `Characteristic`s; the app picks the UUIDs and properties when it
+//! registers, and can use as many characteristics as the board provided.
+//!
+//! ## Instantiation
+//!
+//! ```rust
+//! # use kernel::static_init;
+//! # use capsules::ble::att::Uuid;
+//! # use capsules::ble::gatt_server::{Characteristic, Service};
+//!
+//! let app_characteristics = static_init!(
+//!     [Characteristic<'static>; 4],
+//!     [
+//!         Characteristic::new(Uuid::Uuid16(0), 0),
+//!         Characteristic::new(Uuid::Uuid16(0), 0),
+//!         Characteristic::new(Uuid::Uuid16(0), 0),
+//!         Characteristic::new(Uuid::Uuid16(0), 0),
+//!     ]
+//! );
+//! let app_service = static_init!(
+//!     Service<'static>,
+//!     Service::new(Uuid::Uuid16(0), app_characteristics)
+//! );
+//! let gatt_driver = static_init!(
+//!     capsules::ble::gatt_user::GattDriver<'static>,
+//!     capsules::ble::gatt_user::GattDriver::new(
+//!         gatt, &nrf52::ble_radio::RADIO, app_service,
+//!         board_kernel.create_grant(&grant_cap)));
+//! for characteristic in app_characteristics.iter() {
+//!     characteristic.set_client(gatt_driver);
+//! }
+//! ```
+//!
+//! ## Syscall Interface
+//!
+//! - allow `0`: service definition: the 16 byte service UUID followed by, for
+//!   each characteristic, its 16 byte UUID and one byte of properties. UUIDs
+//!   are least significant byte first.
+//! - allow `1`: advertising data, at most 31 bytes.
+//! - allow `2 + i`: value of characteristic `i`. Reads return the whole
+//!   buffer; writes are copied into it.
+//! - subscribe `0`: the central wrote characteristic `i`, `fn(i, len, 0)`.
+//! - subscribe `1`: the central enabled or disabled notifications for
+//!   characteristic `i`, `fn(i, enabled, 0)`.
+//! - subscribe `2`: a notification was sent, `fn(i, ReturnCode, 0)`. `EBUSY`
+//!   means a notify command that returned `EBUSY` can now be retried.
+//! - command `0`: driver check.
+//! - command `1`: add the service defined in allow `0` to the server. This can
+//!   be done once, and makes the calling app the owner of the driver.
+//! - command `2`: notify the central of the first `data2` bytes of the value
+//!   of characteristic `data`.
+//! - command `3`: start connectable advertising every `data` milliseconds.
+//! - command `4`: stop advertising.
+
+use core::cmp;
+
+use kernel::common::cells::OptionalCell;
+use kernel::hil::ble_connection::BleConnection;
+use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
+
+use super::att::{Uuid, ERROR_INVALID_ATTRIBUTE_VALUE_LENGTH, ERROR_UNLIKELY};
+use super::gatt_server::{Characteristic, CharacteristicClient, GattServer, Service};
+
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::BleGatt as usize;
+
+/// How many characteristics an app can have values for.
+pub const MAX_CHARACTERISTICS: usize = 4;
+
+const UUID_LEN: usize = 16;
+const CHARACTERISTIC_DEFINITION_LEN: usize = UUID_LEN + 1;
+const MAX_ADV_DATA_LEN: usize = 31;
+
+#[derive(Default)]
+pub struct App {
+    write_callback: Option<Callback>,
+    notifications_callback: Option<Callback>,
+    notification_sent_callback: Option<Callback>,
+    definition: Option<AppSlice<Shared, u8>>,
+    adv_data: Option<AppSlice<Shared, u8>>,
+    values: [Option<AppSlice<Shared, u8>>; MAX_CHARACTERISTICS],
+}
+
+pub struct GattDriver<'a> {
+    gatt: &'a GattServer<'a>,
+    link: &'a dyn BleConnection<'a>,
+    service: &'a Service<'a>,
+    apps: Grant<App>,
+    /// The app that registered the service.
+    owner: OptionalCell<AppId>,
+}
+
+impl<'a> GattDriver<'a> {
+    pub fn new(
+        gatt: &'a GattServer<'a>,
+        link: &'a dyn BleConnection<'a>,
+        service: &'a Service<'a>,
+        apps: Grant<App>,
+    ) -> GattDriver<'a> {
+        GattDriver {
+            gatt: gatt,
+            link: link,
+            service: service,
+            apps: apps,
+            owner: OptionalCell::empty(),
+        }
+    }
+
+    fn index(&self, characteristic: &Characteristic) -> Option<usize> {
+        self.service
+            .characteristics()
+            .iter()
+            .position(|c| c.value_handle() == characteristic.value_handle())
+    }
+
+    /// Run `f` with the owner's state and the index of `characteristic`.
+    fn with_owner<F, R>(&self, characteristic: &Characteristic, default: R, f: F) -> R
+    where
+        F: FnOnce(&mut App, usize) -> R,
+        R: Copy,
+    {
+        match (self.owner.map(|appid| *appid), self.index(characteristic)) {
+            (Some(appid), Some(index)) => self
+                .apps
+                .enter(appid, |app, _| f(app, index))
+                .unwrap_or(default),
+            _ => default,
+        }
+    }
+
+    fn register(&self, appid: AppId) -> ReturnCode {
+        if self.owner.is_some() {
+            return ReturnCode::EALREADY;
+        }
+        self.apps
+            .enter(appid, |app, _| {
+                let definition = match app.definition {
+                    Some(ref definition) if definition.len() >= UUID_LEN => definition.as_ref(),
+                    _ => return ReturnCode::EINVAL,
+                };
+                let count = cmp::min(
+                    (definition.len() - UUID_LEN) / CHARACTERISTIC_DEFINITION_LEN,
+                    cmp::min(self.service.characteristics().len(), MAX_CHARACTERISTICS),
+                );
+                // Both unwraps are on 16 byte slices.
+                self.service
+                    .configure(Uuid::from_bytes(&definition[..UUID_LEN]).unwrap(), count);
+                for (i, c) in self.service.characteristics().iter().enumerate() {
+                    let start = UUID_LEN + i * CHARACTERISTIC_DEFINITION_LEN;
+                    c.configure(
+                        Uuid::from_bytes(&definition[start..start + UUID_LEN]).unwrap(),
+                        definition[start + UUID_LEN],
+                    );
+                }
+                self.gatt.add_service(self.service);
+                self.owner.set(appid);
+                ReturnCode::SUCCESS
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    fn notify(&self, appid: AppId, index: usize, len: usize) -> ReturnCode {
+        let characteristic = match self.service.characteristics().get(index) {
+            Some(c) if index < MAX_CHARACTERISTICS => c,
+            _ => return ReturnCode::EINVAL,
+        };
+        self.apps
+            .enter(appid, |app, _| match app.values[index] {
+                Some(ref value) if value.len() >= len => {
+                    self.gatt.notify(characteristic, &value.as_ref()[..len])
+                }
+                _ => ReturnCode::ESIZE,
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    fn start_advertising(&self, appid: AppId, interval_ms: u32) -> ReturnCode {
+        self.apps
+            .enter(appid, |app, _| {
+                let mut adv_data = [0; MAX_ADV_DATA_LEN];
+                let len = app.adv_data.as_ref().map_or(0, |data| {
+                    let len = cmp::min(data.len(), MAX_ADV_DATA_LEN);
+                    adv_data[..len].copy_from_slice(&data.as_ref()[..len]);
+                    len
+                });
+                self.link.start_advertising(&adv_data[..len], interval_ms)
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+}
+
+impl<'a> CharacteristicClient for GattDriver<'a> {
+    fn read_value(&self, characteristic: &Characteristic, value: &mut [u8]) -> Result<usize, u8> {
+        self.with_owner(characteristic, Err(ERROR_UNLIKELY), |app, index| {
+            app.values[index].as_ref().map_or(Err(ERROR_UNLIKELY), |v| {
+                let len = cmp::min(v.len(), value.len());
+                value[..len].copy_from_slice(&v.as_ref()[..len]);
+                Ok(len)
+            })
+        })
+    }
+
+    fn write_value(&self, characteristic: &Characteristic, value: &[u8]) -> Result<(), u8> {
+        self.with_owner(characteristic, Err(ERROR_UNLIKELY), |app, index| {
+            match app.values[index] {
+                Some(ref mut v) if v.len() >= value.len() => {
+                    v.as_mut()[..value.len()].copy_from_slice(value);
+                }
+                Some(_) => return Err(ERROR_INVALID_ATTRIBUTE_VALUE_LENGTH),
+                None => return Err(ERROR_UNLIKELY),
+            }
+            app.write_callback
+                .map(|mut cb| cb.schedule(index, value.len(), 0));
+            Ok(())
+        })
+    }
+
+    fn notifications_changed(&self, characteristic: &Characteristic, enabled: bool) {
+        self.with_owner(characteristic, (), |app, index| {
+            app.notifications_callback
+                .map(|mut cb| cb.schedule(index, enabled as usize, 0));
+        });
+    }
+
+    fn notification_sent(&self, characteristic: &Characteristic, result: ReturnCode) {
+        self.with_owner(characteristic, (), |app, index| {
+            app.notification_sent_callback
+                .map(|mut cb| cb.schedule(index, From::from(result), 0));
+        });
+    }
+}
+
+impl<'a> Driver for GattDriver<'a> {
+    fn allow(
+        &self,
+        appid: AppId,
+        allow_num: usize,
+        slice: Option<AppSlice<Shared, u8>>,
+    ) -> ReturnCode {
+        self.apps
+            .enter(appid, |app, _| match allow_num {
+                0 => {
+                    app.definition = slice;
+                    ReturnCode::SUCCESS
+                }
+                1 => {
+                    app.adv_data = slice;
+                    ReturnCode::SUCCESS
+                }
+                n if n >= 2 && n < 2 + MAX_CHARACTERISTICS => {
+                    app.values[n - 2] = slice;
+                    ReturnCode::SUCCESS
+                }
+                _ => ReturnCode::ENOSUPPORT,
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        callback: Option<Callback>,
+        appid: AppId,
+    ) -> ReturnCode {
+        self.apps
+            .enter(appid, |app, _| match subscribe_num {
+                0 => {
+                    app.write_callback = callback;
+                    ReturnCode::SUCCESS
+                }
+                1 => {
+                    app.notifications_callback = callback;
+                    ReturnCode::SUCCESS
+                }
+                2 => {
+                    app.notification_sent_callback = callback;
+                    ReturnCode::SUCCESS
+                }
+                _ => ReturnCode::ENOSUPPORT,
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    fn command(&self, command_num: usize, data: usize, data2: usize, appid: AppId) -> ReturnCode {
+        match command_num {
+            0 => ReturnCode::SUCCESS,
+            1 => self.register(appid),
+            2 | 3 | 4 if !self.owner.contains(&appid) => ReturnCode::ERESERVE,
+            2 => self.notify(appid, data, data2),
+            3 => self.start_advertising(appid, data as u32),
+            4 => self.link.stop_advertising(),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
@@ -0,0 +1,223 @@
+//! L2CAP for BLE connections
+//!
+//! `MuxL2cap` sits on a `BleConnection` and shares it between the fixed
+//! channels of LE L2CAP, such as the Attribute Protocol and the Security
+//! Manager. Each user creates an `L2capChannel` for its CID, gets the
+//! payloads of PDUs for that CID, and can send one PDU at a time. PDUs of
+//! different channels are sent in turn.
+//!
+//! PDUs for a CID nobody registered are dropped.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//! # use capsules::ble::l2cap::{L2capChannel, MuxL2cap, CID_ATT};
+//!
+//! let l2cap = static_init!(MuxL2cap<'static>, MuxL2cap::new(&nrf52::ble_radio::RADIO));
+//! kernel::hil::ble_connection::BleConnection::set_client(&nrf52::ble_radio::RADIO, l2cap);
+//! let att_channel = static_init!(L2capChannel<'static>, L2capChannel::new(l2cap, CID_ATT));
+//! att_channel.setup();
+//! ```
+
+use core::cell::Cell;
+
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::{List, ListLink, ListNode};
+use kernel::hil::ble_connection::{
+    BleConnection, ConnectionClient, ConnectionHandle, DeviceAddress,
+};
+use kernel::ReturnCode;
+
+/// Attribute Protocol.
+pub const CID_ATT: u16 = 0x0004;
+/// LE signaling channel.
+pub const CID_LE_SIGNALING: u16 = 0x0005;
+/// Security Manager Protocol.
+pub const CID_SMP: u16 = 0x0006;
+
+/// Length of the basic L2CAP header (payload length and CID) at the start of
+/// every PDU.
+pub const HEADER_LEN: usize = 4;
+
+pub trait L2capClient {
+    fn connected(&self, handle: ConnectionHandle, peer: DeviceAddress);
+
+    fn disconnected(&self, handle: ConnectionHandle);
+
+    /// A PDU for this channel arrived. `payload` excludes the header.
+    fn received(&self, handle: ConnectionHandle, payload: &[u8]);
+
+    /// The buffer passed to `L2capChannel::send()` was sent, or could not be.
+    fn sent(&self, buffer: &'static mut [u8], result: ReturnCode);
+}
+
+pub struct MuxL2cap<'a> {
+    link: &'a dyn BleConnection<'a>,
+    channels: List<'a, L2capChannel<'a>>,
+    /// CID of the channel whose PDU the link layer is sending.
+    inflight: OptionalCell<u16>,
+}
+
+impl<'a> MuxL2cap<'a> {
+    pub const fn new(link: &'a dyn BleConnection<'a>) -> MuxL2cap<'a> {
+        MuxL2cap {
+            link: link,
+            channels: List::new(),
+            inflight: OptionalCell::empty(),
+        }
+    }
+
+    fn channel(&self, cid: u16) -> Option<&'a L2capChannel<'a>> {
+        self.channels.iter().find(|channel| channel.cid == cid)
+    }
+
+    /// Send the next queued PDU, if any, now that the link layer is free.
+    fn do_next_op(&self) {
+        for channel in self.channels.iter() {
+            if let Some(buffer) = channel.tx_buffer.take() {
+                let len = channel.tx_len.get();
+                match self.link.send(channel.tx_handle.get(), buffer, len) {
+                    Ok(()) => {
+                        self.inflight.set(channel.cid);
+                        return;
+                    }
+                    Err((rcode, buffer)) => {
+                        channel.client.map(move |client| client.sent(buffer, rcode));
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<'a> ConnectionClient for MuxL2cap<'a> {
+    fn connected(&self, handle: ConnectionHandle, peer: DeviceAddress) {
+        for channel in self.channels.iter() {
+            channel.client.map(|client| client.connected(handle, peer));
+        }
+    }
+
+    fn disconnected(&self, handle: ConnectionHandle, _reason: u8) {
+        for channel in self.channels.iter() {
+            // Queued PDUs were meant for the connection that just closed.
+            if channel.tx_handle.get() == handle {
+                channel.tx_buffer.take().map(|buffer| {
+                    channel
+                        .client
+                        .map(move |client| client.sent(buffer, ReturnCode::FAIL));
+                });
+            }
+            channel.client.map(|client| client.disconnected(handle));
+        }
+    }
+
+    fn pdu_received(&self, handle: ConnectionHandle, pdu: &[u8]) {
+        if pdu.len() < HEADER_LEN {
+            return;
+        }
+        let len = u16::from_le_bytes([pdu[0], pdu[1]]) as usize;
+        let cid = u16::from_le_bytes([pdu[2], pdu[3]]);
+        if HEADER_LEN + len > pdu.len() {
+            return;
+        }
+        self.channel(cid).map(|channel| {
+            channel
+                .client
+                .map(|client| client.received(handle, &pdu[HEADER_LEN..HEADER_LEN + len]));
+        });
+    }
+
+    fn pdu_sent(&self, _handle: ConnectionHandle, buffer: &'static mut [u8], result: ReturnCode) {
+        self.inflight
+            .take()
+            .and_then(|cid| self.channel(cid))
+            .map(move |channel| {
+                channel
+                    .client
+                    .map(move |client| client.sent(buffer, result));
+            });
+        self.do_next_op();
+    }
+}
+
+pub struct L2capChannel<'a> {
+    mux: &'a MuxL2cap<'a>,
+    cid: u16,
+    /// A PDU waiting for the link layer, its length and its connection.
+    tx_buffer: TakeCell<'static, [u8]>,
+    tx_len: Cell<usize>,
+    tx_handle: Cell<ConnectionHandle>,
+    client: OptionalCell<&'a dyn L2capClient>,
+    next: ListLink<'a, L2capChannel<'a>>,
+}
+
+impl<'a> ListNode<'a, L2capChannel<'a>> for L2capChannel<'a> {
+    fn next(&'a self) -> &'a ListLink<'a, L2capChannel<'a>> {
+        &self.next
+    }
+}
+
+impl<'a> L2capChannel<'a> {
+    pub const fn new(mux: &'a MuxL2cap<'a>, cid: u16) -> L2capChannel<'a> {
+        L2capChannel {
+            mux: mux,
+            cid: cid,
+            tx_buffer: TakeCell::empty(),
+            tx_len: Cell::new(0),
+            tx_handle: Cell::new(0),
+            client: OptionalCell::empty(),
+            next: ListLink::empty(),
+        }
+    }
+
+    /// Register with the mux. Must be called once before the channel is used.
+    pub fn setup(&'a self) {
+        self.mux.channels.push_head(self);
+    }
+
+    pub fn set_client(&self, client: &'a dyn L2capClient) {
+        self.client.set(client);
+    }
+
+    pub fn cid(&self) -> u16 {
+        self.cid
+    }
+
+    /// The longest payload `send()` accepts.
+    pub fn max_payload_len(&self) -> usize {
+        self.mux.link.max_pdu_len() - HEADER_LEN
+    }
+
+    /// Send `len` bytes of payload on connection `handle`. The payload starts
+    /// at `buffer[HEADER_LEN]`; the header is filled in here.
+    ///
+    /// Returns `EBUSY` while the previous PDU of this channel is being sent.
+    pub fn send(
+        &self,
+        handle: ConnectionHandle,
+        buffer: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        if self.tx_buffer.is_some() || self.mux.inflight.contains(&self.cid) {
+            return Err((ReturnCode::EBUSY, buffer));
+        }
+        if len > self.max_payload_len() || HEADER_LEN + len > buffer.len() {
+            return Err((ReturnCode::ESIZE, buffer));
+        }
+        buffer[0..2].copy_from_slice(&(len as u16).to_le_bytes());
+        buffer[2..4].copy_from_slice(&self.cid.to_le_bytes());
+
+        if self.mux.inflight.is_some() {
+            // Wait for the link layer to be free.
+            self.tx_buffer.replace(buffer);
+            self.tx_len.set(HEADER_LEN + len);
+            self.tx_handle.set(handle);
+            return Ok(());
+        }
+        self.mux.link.send(handle, buffer, HEADER_LEN + len)?;
+        self.mux.inflight.set(self.cid);
+        Ok(())
+    }
+}
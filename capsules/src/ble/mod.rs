@@ -0,0 +1,13 @@
+pub mod att;
+pub mod battery_temperature;
+pub mod beacon;
+pub mod bonds;
+pub mod central_user;
+pub mod coc;
+pub mod crypto;
+pub mod gatt_client;
+pub mod gatt_server;
+pub mod gatt_user;
+pub mod l2cap;
+pub mod nus;
+pub mod smp;
@@ -0,0 +1,267 @@
+//! Nordic UART Service
+//!
+//! Exposes a `hil::uart::Uart` over the Nordic UART Service, the GATT service
+//! phone terminal apps use as a serial port. The central writes to the RX
+//! characteristic to send us bytes and enables notifications on the TX
+//! characteristic to receive ours, so a `Console` on top of it can be used
+//! without a cable.
+//!
+//! Transmissions are sent in notifications of `ATT_MTU - 3` bytes and wait
+//! until a central enabled notifications, and bytes the central writes while
+//! nobody is receiving are dropped, like a disconnected CDC port.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//! # use capsules::ble::gatt_server::{Characteristic, Service};
+//! # use capsules::ble::gatt_server::{PROPERTY_NOTIFY, PROPERTY_WRITE};
+//! # use capsules::ble::gatt_server::PROPERTY_WRITE_WITHOUT_RESPONSE;
+//! # use capsules::ble::nus::{self, Nus};
+//!
+//! let nus_characteristics = static_init!(
+//!     [Characteristic<'static>; 2],
+//!     [
+//!         Characteristic::new(
+//!             nus::RX_CHARACTERISTIC,
+//!             PROPERTY_WRITE | PROPERTY_WRITE_WITHOUT_RESPONSE
+//!         ),
+//!         Characteristic::new(nus::TX_CHARACTERISTIC, PROPERTY_NOTIFY),
+//!     ]
+//! );
+//! let nus_service = static_init!(
+//!     Service<'static>,
+//!     Service::new(nus::SERVICE, nus_characteristics)
+//! );
+//! let nus = static_init!(
+//!     Nus<'static>,
+//!     Nus::new(gatt, &nus_characteristics[0], &nus_characteristics[1])
+//! );
+//! nus_characteristics[0].set_client(nus);
+//! nus_characteristics[1].set_client(nus);
+//! gatt.add_service(nus_service);
+//!
+//! let uart_mux =
+//!     components::console::UartMuxComponent::new(nus, 115200, dynamic_deferred_caller)
+//!         .finalize(());
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::uart;
+use kernel::ReturnCode;
+
+use super::att::{Uuid, ATT_MTU};
+use super::gatt_server::{Characteristic, CharacteristicClient, GattServer};
+
+/// 6E400001-B5A3-F393-E0A9-E50E24DCCA9E
+pub const SERVICE: Uuid = Uuid::Uuid128([
+    0x9e, 0xca, 0xdc, 0x24, 0x0e, 0xe5, 0xa9, 0xe0, 0x93, 0xf3, 0xa3, 0xb5, 0x01, 0x00, 0x40, 0x6e,
+]);
+/// 6E400002-B5A3-F393-E0A9-E50E24DCCA9E, written by the central.
+pub const RX_CHARACTERISTIC: Uuid = Uuid::Uuid128([
+    0x9e, 0xca, 0xdc, 0x24, 0x0e, 0xe5, 0xa9, 0xe0, 0x93, 0xf3, 0xa3, 0xb5, 0x02, 0x00, 0x40, 0x6e,
+]);
+/// 6E400003-B5A3-F393-E0A9-E50E24DCCA9E, notified to the central.
+pub const TX_CHARACTERISTIC: Uuid = Uuid::Uuid128([
+    0x9e, 0xca, 0xdc, 0x24, 0x0e, 0xe5, 0xa9, 0xe0, 0x93, 0xf3, 0xa3, 0xb5, 0x03, 0x00, 0x40, 0x6e,
+]);
+
+/// The most bytes sent in one notification.
+const MAX_CHUNK_LEN: usize = ATT_MTU - 3;
+
+pub struct Nus<'a> {
+    gatt: &'a GattServer<'a>,
+    rx: &'a Characteristic<'a>,
+    tx: &'a Characteristic<'a>,
+
+    /// The buffer being transmitted, how much of it and how far we got.
+    tx_buffer: TakeCell<'static, [u8]>,
+    tx_len: Cell<usize>,
+    tx_offset: Cell<usize>,
+    /// Length of the notification in flight, 0 if there is none.
+    tx_chunk: Cell<usize>,
+    tx_client: OptionalCell<&'a dyn uart::TransmitClient>,
+
+    rx_buffer: TakeCell<'static, [u8]>,
+    rx_len: Cell<usize>,
+    rx_offset: Cell<usize>,
+    rx_client: OptionalCell<&'a dyn uart::ReceiveClient>,
+}
+
+impl<'a> Nus<'a> {
+    pub fn new(
+        gatt: &'a GattServer<'a>,
+        rx: &'a Characteristic<'a>,
+        tx: &'a Characteristic<'a>,
+    ) -> Nus<'a> {
+        Nus {
+            gatt: gatt,
+            rx: rx,
+            tx: tx,
+            tx_buffer: TakeCell::empty(),
+            tx_len: Cell::new(0),
+            tx_offset: Cell::new(0),
+            tx_chunk: Cell::new(0),
+            tx_client: OptionalCell::empty(),
+            rx_buffer: TakeCell::empty(),
+            rx_len: Cell::new(0),
+            rx_offset: Cell::new(0),
+            rx_client: OptionalCell::empty(),
+        }
+    }
+
+    fn is(&self, characteristic: &Characteristic, ours: &Characteristic) -> bool {
+        characteristic.value_handle() == ours.value_handle()
+    }
+
+    /// Notify the next part of the transmission, or finish it.
+    fn send_next(&self) {
+        if self.tx_chunk.get() != 0 || !self.tx.notifications_enabled() {
+            return;
+        }
+        let offset = self.tx_offset.get();
+        let remaining = self.tx_len.get() - offset;
+        if remaining == 0 {
+            self.tx_buffer.take().map(|buffer| {
+                self.tx_client.map(move |client| {
+                    client.transmitted_buffer(buffer, self.tx_len.get(), ReturnCode::SUCCESS)
+                });
+            });
+            return;
+        }
+        let len = cmp::min(remaining, MAX_CHUNK_LEN);
+        let rcode = self.tx_buffer.map_or(ReturnCode::FAIL, |buffer| {
+            self.gatt.notify(self.tx, &buffer[offset..offset + len])
+        });
+        if rcode == ReturnCode::SUCCESS {
+            self.tx_chunk.set(len);
+        }
+        // Otherwise wait for `notification_sent` or notifications to be
+        // enabled again.
+    }
+}
+
+impl<'a> CharacteristicClient for Nus<'a> {
+    fn read_value(&self, _characteristic: &Characteristic, _value: &mut [u8]) -> Result<usize, u8> {
+        Ok(0)
+    }
+
+    fn write_value(&self, characteristic: &Characteristic, value: &[u8]) -> Result<(), u8> {
+        if !self.is(characteristic, self.rx) {
+            return Ok(());
+        }
+        self.rx_buffer.take().map(|rx_buf| {
+            let rx_offset = self.rx_offset.get();
+            let copy_length = cmp::min(value.len(), rx_buf.len() - rx_offset);
+            rx_buf[rx_offset..rx_offset + copy_length].copy_from_slice(&value[..copy_length]);
+            let total_received_bytes = rx_offset + copy_length;
+            self.rx_offset.set(total_received_bytes);
+
+            if total_received_bytes >= self.rx_len.get() {
+                self.rx_client.map(move |client| {
+                    client.received_buffer(
+                        rx_buf,
+                        total_received_bytes,
+                        ReturnCode::SUCCESS,
+                        uart::Error::None,
+                    );
+                });
+            } else {
+                self.rx_buffer.replace(rx_buf);
+            }
+        });
+        Ok(())
+    }
+
+    fn notifications_changed(&self, characteristic: &Characteristic, enabled: bool) {
+        if self.is(characteristic, self.tx) && enabled {
+            self.send_next();
+        }
+    }
+
+    fn notification_sent(&self, _characteristic: &Characteristic, result: ReturnCode) {
+        let chunk = self.tx_chunk.replace(0);
+        if result == ReturnCode::SUCCESS {
+            self.tx_offset.set(self.tx_offset.get() + chunk);
+        }
+        // Failed parts are sent again.
+        self.send_next();
+    }
+}
+
+impl<'a> uart::Configure for Nus<'a> {
+    fn configure(&self, _parameters: uart::Parameters) -> ReturnCode {
+        // Like CDC, there is no line to configure.
+        ReturnCode::SUCCESS
+    }
+}
+
+impl<'a> uart::Transmit<'a> for Nus<'a> {
+    fn set_transmit_client(&self, client: &'a dyn uart::TransmitClient) {
+        self.tx_client.set(client);
+    }
+
+    fn transmit_buffer(
+        &self,
+        tx_buffer: &'static mut [u8],
+        tx_len: usize,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        if self.tx_buffer.is_some() {
+            (ReturnCode::EBUSY, Some(tx_buffer))
+        } else if tx_len > tx_buffer.len() {
+            (ReturnCode::ESIZE, Some(tx_buffer))
+        } else {
+            self.tx_len.set(tx_len);
+            self.tx_offset.set(0);
+            self.tx_buffer.replace(tx_buffer);
+            self.send_next();
+            (ReturnCode::SUCCESS, None)
+        }
+    }
+
+    fn transmit_abort(&self) -> ReturnCode {
+        ReturnCode::FAIL
+    }
+
+    fn transmit_word(&self, _word: u32) -> ReturnCode {
+        ReturnCode::FAIL
+    }
+}
+
+impl<'a> uart::Receive<'a> for Nus<'a> {
+    fn set_receive_client(&self, client: &'a dyn uart::ReceiveClient) {
+        self.rx_client.set(client);
+    }
+
+    fn receive_buffer(
+        &self,
+        rx_buffer: &'static mut [u8],
+        rx_len: usize,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        if self.rx_buffer.is_some() {
+            (ReturnCode::EBUSY, Some(rx_buffer))
+        } else if rx_len > rx_buffer.len() {
+            (ReturnCode::ESIZE, Some(rx_buffer))
+        } else {
+            self.rx_buffer.replace(rx_buffer);
+            self.rx_offset.set(0);
+            self.rx_len.set(rx_len);
+            (ReturnCode::SUCCESS, None)
+        }
+    }
+
+    fn receive_abort(&self) -> ReturnCode {
+        ReturnCode::FAIL
+    }
+
+    fn receive_word(&self) -> ReturnCode {
+        ReturnCode::FAIL
+    }
+}
+
+impl<'a> uart::Uart<'a> for Nus<'a> {}
+impl<'a> uart::UartData<'a> for Nus<'a> {}
@@ -0,0 +1,854 @@
+//! Security Manager Protocol
+//!
+//! `Smp` pairs with centrals on the `l2cap::CID_SMP` channel using LE Secure
+//! Connections, as the responder, and gives the link layer the resulting
+//! long term key when the central encrypts the connection. Bonds are kept in
+//! a `BondStore`, so bonded peers get the same key after a reboot and can
+//! encrypt again without pairing.
+//!
+//! With an IO capability of `IO_DISPLAY_YES_NO` or `IO_KEYBOARD_DISPLAY`,
+//! and a peer that can also display and confirm, numeric comparison is used
+//! and the link is authenticated: the client is asked to show a six digit
+//! number and call `confirm()` once the user agreed it matches the peer's.
+//! Otherwise Just Works pairing gives an encrypted but unauthenticated link.
+//! Passkey entry, out of band data and legacy pairing are not supported, and
+//! peers asking for them are refused.
+//!
+//! The central may distribute its identity key, which is stored with the
+//! bond so that it is recognized when it uses a private address; we do not
+//! distribute keys. Only one connection is served at a time.
+//!
+//! The security level of the connection is reported to a `SecurityClient`,
+//! such as the GATT server, which uses it to protect characteristics.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//! # use capsules::ble::l2cap::{L2capChannel, CID_SMP};
+//! # use capsules::ble::smp::{Smp, IO_NO_INPUT_NO_OUTPUT};
+//!
+//! let smp_channel = static_init!(L2capChannel<'static>, L2capChannel::new(l2cap, CID_SMP));
+//! smp_channel.setup();
+//! let smp = static_init!(
+//!     Smp<'static>,
+//!     Smp::new(
+//!         smp_channel,
+//!         &nrf52::ble_radio::RADIO,
+//!         ecdh,
+//!         rng,
+//!         bonds,
+//!         IO_NO_INPUT_NO_OUTPUT,
+//!         static_init!([u8; 64], [0; 64]),
+//!         static_init!([u8; 32], [0; 32]),
+//!         static_init!([u8; 69], [0; 69]),
+//!     )
+//! );
+//! smp_channel.set_client(smp);
+//! kernel::hil::ble_connection::BleEncryption::set_encryption_client(&nrf52::ble_radio::RADIO, smp);
+//! ecdh.set_client(smp);
+//! rng.set_client(smp);
+//! smp.set_security_client(gatt);
+//! smp.setup();
+//! ```
+
+use core::cell::Cell;
+
+use kernel::common::cells::{MapCell, OptionalCell, TakeCell};
+use kernel::common::constant_time;
+use kernel::hil::ble_connection::{
+    BleConnection, ConnectionHandle, DeviceAddress, EncryptionClient,
+};
+use kernel::hil::ecdh::{self, EcdhP256};
+use kernel::hil::rng::{self, Rng};
+use kernel::ReturnCode;
+
+use super::bonds::{Bond, BondStore};
+use super::crypto;
+use super::l2cap::{L2capChannel, L2capClient, HEADER_LEN};
+
+/// IO capabilities.
+pub const IO_DISPLAY_ONLY: u8 = 0x00;
+pub const IO_DISPLAY_YES_NO: u8 = 0x01;
+pub const IO_KEYBOARD_ONLY: u8 = 0x02;
+pub const IO_NO_INPUT_NO_OUTPUT: u8 = 0x03;
+pub const IO_KEYBOARD_DISPLAY: u8 = 0x04;
+
+/// Reasons pairing failed.
+pub const FAILED_PASSKEY_ENTRY: u8 = 0x01;
+pub const FAILED_OOB_NOT_AVAILABLE: u8 = 0x02;
+pub const FAILED_AUTHENTICATION_REQUIREMENTS: u8 = 0x03;
+pub const FAILED_CONFIRM_VALUE: u8 = 0x04;
+pub const FAILED_PAIRING_NOT_SUPPORTED: u8 = 0x05;
+pub const FAILED_ENCRYPTION_KEY_SIZE: u8 = 0x06;
+pub const FAILED_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+pub const FAILED_UNSPECIFIED: u8 = 0x08;
+pub const FAILED_INVALID_PARAMETERS: u8 = 0x0a;
+pub const FAILED_DHKEY_CHECK: u8 = 0x0b;
+pub const FAILED_NUMERIC_COMPARISON: u8 = 0x0c;
+
+/// Opcodes.
+const PAIRING_REQUEST: u8 = 0x01;
+const PAIRING_RESPONSE: u8 = 0x02;
+const PAIRING_RANDOM: u8 = 0x04;
+const PAIRING_FAILED: u8 = 0x05;
+const IDENTITY_INFORMATION: u8 = 0x08;
+const IDENTITY_ADDRESS_INFORMATION: u8 = 0x09;
+const SECURITY_REQUEST: u8 = 0x0b;
+const PAIRING_PUBLIC_KEY: u8 = 0x0c;
+const PAIRING_CONFIRM: u8 = 0x03;
+const PAIRING_DHKEY_CHECK: u8 = 0x0d;
+
+/// Authentication requirement flags.
+const AUTH_BONDING: u8 = 0x01;
+const AUTH_MITM: u8 = 0x04;
+const AUTH_SC: u8 = 0x08;
+
+/// Key distribution flags.
+const DIST_ID_KEY: u8 = 0x02;
+
+/// The only key size we accept.
+const KEY_SIZE: u8 = 16;
+
+/// PDUs waiting to be sent, in the order they go out.
+const SEND_FAILED: u8 = 0x01;
+const SEND_RESPONSE: u8 = 0x02;
+const SEND_PUBLIC_KEY: u8 = 0x04;
+const SEND_CONFIRM: u8 = 0x08;
+const SEND_RANDOM: u8 = 0x10;
+const SEND_DHKEY_CHECK: u8 = 0x20;
+const SEND_SECURITY_REQUEST: u8 = 0x40;
+
+/// How well a connection is protected.
+#[derive(Copy, Clone, Debug, PartialEq, PartialOrd)]
+pub enum SecurityLevel {
+    None,
+    /// Encrypted with a key from Just Works pairing.
+    Encrypted,
+    /// Encrypted with a key from pairing that authenticated the peer.
+    Authenticated,
+}
+
+pub trait SecurityClient {
+    fn security_changed(&self, handle: ConnectionHandle, level: SecurityLevel);
+}
+
+pub trait SmpClient {
+    /// Show `value` to the user, who compares it with the peer's and says
+    /// whether they match through `Smp::confirm()`.
+    fn confirm_value(&self, handle: ConnectionHandle, value: u32);
+
+    /// Pairing finished with the given level, or failed for the given
+    /// reason.
+    fn pairing_complete(&self, handle: ConnectionHandle, result: Result<SecurityLevel, u8>);
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Phase {
+    PublicKey,
+    Random,
+    DhKeyCheck,
+    Encryption,
+    Keys,
+}
+
+/// The state of a pairing. Values are big-endian, as the security functions
+/// take them.
+struct Pairing {
+    phase: Phase,
+    /// Whether numeric comparison, rather than Just Works, is used.
+    numeric: bool,
+    bonding: bool,
+    /// The central's and our address, for `f5` and `f6`.
+    a: [u8; 7],
+    b: [u8; 7],
+    /// The AuthReq, OOB and IO capability fields of both sides.
+    io_cap_a: [u8; 3],
+    io_cap_b: [u8; 3],
+    /// The body of our Pairing Response.
+    response: [u8; 6],
+    peer_key: [u8; 64],
+    na: [u8; 16],
+    nb: Option<[u8; 16]>,
+    ea: Option<[u8; 16]>,
+    dhkey: Option<[u8; 32]>,
+    confirmed: bool,
+    eb: [u8; 16],
+    ltk: [u8; 16],
+    /// Keys the central still has to distribute.
+    keys_expected: u8,
+    irk: Option<[u8; 16]>,
+    identity: Option<DeviceAddress>,
+}
+
+pub struct Smp<'a> {
+    channel: &'a L2capChannel<'a>,
+    link: &'a dyn BleConnection<'a>,
+    ecdh: &'a dyn EcdhP256<'a>,
+    rng: &'a dyn Rng<'a>,
+    bonds: &'a BondStore<'a>,
+    io_capability: u8,
+
+    /// Our public key, once generated.
+    public_key: TakeCell<'static, [u8; 64]>,
+    key_ready: Cell<bool>,
+    dhkey: TakeCell<'static, [u8; 32]>,
+    buffer: TakeCell<'static, [u8]>,
+
+    connection: OptionalCell<(ConnectionHandle, DeviceAddress)>,
+    level: Cell<SecurityLevel>,
+    pairing: MapCell<Pairing>,
+    pending: Cell<u8>,
+    failure: Cell<u8>,
+
+    client: OptionalCell<&'a dyn SmpClient>,
+    security_client: OptionalCell<&'a dyn SecurityClient>,
+}
+
+/// The address type followed by the address, most significant byte first.
+fn address_bytes(address: &DeviceAddress) -> [u8; 7] {
+    let mut bytes = [0; 7];
+    bytes[0] = address.random as u8;
+    for i in 0..6 {
+        bytes[1 + i] = address.address[5 - i];
+    }
+    bytes
+}
+
+/// Copy a value sent least significant byte first into big-endian order.
+fn reversed16(bytes: &[u8]) -> [u8; 16] {
+    let mut out = [0; 16];
+    for (o, b) in out.iter_mut().zip(bytes[..16].iter().rev()) {
+        *o = *b;
+    }
+    out
+}
+
+fn reversed32(bytes: &[u8]) -> [u8; 32] {
+    let mut out = [0; 32];
+    for (o, b) in out.iter_mut().zip(bytes[..32].iter().rev()) {
+        *o = *b;
+    }
+    out
+}
+
+/// Write `value` least significant byte first.
+fn write_reversed(out: &mut [u8], value: &[u8]) {
+    for (o, b) in out.iter_mut().zip(value.iter().rev()) {
+        *o = *b;
+    }
+}
+
+/// Whether both IO capabilities allow numeric comparison.
+fn can_compare(io_capability: u8) -> bool {
+    io_capability == IO_DISPLAY_YES_NO || io_capability == IO_KEYBOARD_DISPLAY
+}
+
+impl<'a> Smp<'a> {
+    pub fn new(
+        channel: &'a L2capChannel<'a>,
+        link: &'a dyn BleConnection<'a>,
+        ecdh: &'a dyn EcdhP256<'a>,
+        rng: &'a dyn Rng<'a>,
+        bonds: &'a BondStore<'a>,
+        io_capability: u8,
+        public_key: &'static mut [u8; 64],
+        dhkey: &'static mut [u8; 32],
+        buffer: &'static mut [u8],
+    ) -> Smp<'a> {
+        Smp {
+            channel: channel,
+            link: link,
+            ecdh: ecdh,
+            rng: rng,
+            bonds: bonds,
+            io_capability: io_capability,
+            public_key: TakeCell::new(public_key),
+            key_ready: Cell::new(false),
+            dhkey: TakeCell::new(dhkey),
+            buffer: TakeCell::new(buffer),
+            connection: OptionalCell::empty(),
+            level: Cell::new(SecurityLevel::None),
+            pairing: MapCell::empty(),
+            pending: Cell::new(0),
+            failure: Cell::new(0),
+            client: OptionalCell::empty(),
+            security_client: OptionalCell::empty(),
+        }
+    }
+
+    pub fn set_client(&self, client: &'a dyn SmpClient) {
+        self.client.set(client);
+    }
+
+    pub fn set_security_client(&self, client: &'a dyn SecurityClient) {
+        self.security_client.set(client);
+    }
+
+    /// Generate our key pair. Pairing requests are refused until this
+    /// completes.
+    pub fn setup(&self) -> ReturnCode {
+        self.public_key.take().map_or(ReturnCode::EALREADY, |key| {
+            match self.ecdh.generate_key_pair(key) {
+                Ok(()) => ReturnCode::SUCCESS,
+                Err((rcode, key)) => {
+                    self.public_key.replace(key);
+                    rcode
+                }
+            }
+        })
+    }
+
+    /// The security level of connection `handle`.
+    pub fn security_level(&self, handle: ConnectionHandle) -> SecurityLevel {
+        if self.connection.map_or(false, |(h, _)| *h == handle) {
+            self.level.get()
+        } else {
+            SecurityLevel::None
+        }
+    }
+
+    /// Ask the central to pair, or to encrypt with the bond's key.
+    pub fn request_security(&self) -> ReturnCode {
+        if self.connection.is_none() {
+            return ReturnCode::EOFF;
+        }
+        self.pending.set(self.pending.get() | SEND_SECURITY_REQUEST);
+        self.do_next();
+        ReturnCode::SUCCESS
+    }
+
+    /// Answer `SmpClient::confirm_value`.
+    pub fn confirm(&self, handle: ConnectionHandle, accept: bool) -> ReturnCode {
+        if !self.connection.map_or(false, |(h, _)| *h == handle) {
+            return ReturnCode::EINVAL;
+        }
+        let numeric = self
+            .pairing
+            .map_or(false, |p| p.numeric && p.phase == Phase::DhKeyCheck);
+        if !numeric {
+            return ReturnCode::EINVAL;
+        }
+        if accept {
+            self.pairing.map(|p| p.confirmed = true);
+            self.check();
+        } else {
+            self.fail(FAILED_NUMERIC_COMPARISON);
+        }
+        ReturnCode::SUCCESS
+    }
+
+    fn handle(&self) -> Option<ConnectionHandle> {
+        self.connection.map(|(handle, _)| *handle)
+    }
+
+    /// Abandon pairing and tell the central why.
+    fn fail(&self, reason: u8) {
+        self.pairing.take();
+        self.failure.set(reason);
+        self.pending.set(SEND_FAILED);
+        self.do_next();
+        if let Some(handle) = self.handle() {
+            self.client
+                .map(|client| client.pairing_complete(handle, Err(reason)));
+        }
+    }
+
+    fn pairing_request(&self, req: &[u8]) {
+        if req.len() != 7 {
+            return self.fail(FAILED_INVALID_PARAMETERS);
+        }
+        let (peer_io, peer_oob, peer_auth, peer_key_size, peer_dist) =
+            (req[1], req[2], req[3], req[4], req[5]);
+        if !self.key_ready.get() {
+            return self.fail(FAILED_UNSPECIFIED);
+        }
+        if peer_auth & AUTH_SC == 0 {
+            return self.fail(FAILED_AUTHENTICATION_REQUIREMENTS);
+        }
+        if peer_key_size < KEY_SIZE {
+            return self.fail(FAILED_ENCRYPTION_KEY_SIZE);
+        }
+        let numeric = can_compare(peer_io) && can_compare(self.io_capability);
+        let mitm = peer_auth & AUTH_MITM != 0;
+        if mitm && !numeric && peer_io != IO_NO_INPUT_NO_OUTPUT {
+            // The peer wants passkey entry.
+            return self.fail(FAILED_PAIRING_NOT_SUPPORTED);
+        }
+        let peer = match self.connection.map(|(_, peer)| *peer) {
+            Some(peer) => peer,
+            None => return,
+        };
+
+        let bonding = peer_auth & AUTH_BONDING != 0;
+        let mut auth = AUTH_SC | if bonding { AUTH_BONDING } else { 0 };
+        if numeric {
+            auth |= AUTH_MITM;
+        }
+        let response = [
+            self.io_capability,
+            0,
+            auth,
+            KEY_SIZE,
+            peer_dist & DIST_ID_KEY,
+            0,
+        ];
+        self.pairing.replace(Pairing {
+            phase: Phase::PublicKey,
+            numeric: numeric,
+            bonding: bonding,
+            a: address_bytes(&peer),
+            b: address_bytes(&self.link.local_address()),
+            io_cap_a: [peer_auth, peer_oob, peer_io],
+            io_cap_b: [auth, 0, self.io_capability],
+            response: response,
+            peer_key: [0; 64],
+            na: [0; 16],
+            nb: None,
+            ea: None,
+            dhkey: None,
+            confirmed: !numeric,
+            eb: [0; 16],
+            ltk: [0; 16],
+            keys_expected: peer_dist & DIST_ID_KEY,
+            irk: None,
+            identity: None,
+        });
+        if self.rng.get() != ReturnCode::SUCCESS {
+            return self.fail(FAILED_UNSPECIFIED);
+        }
+        self.pending.set(SEND_RESPONSE);
+        self.do_next();
+    }
+
+    fn public_key_received(&self, pdu: &[u8]) {
+        if pdu.len() != 65 {
+            return self.fail(FAILED_INVALID_PARAMETERS);
+        }
+        let mut peer_key = [0; 64];
+        peer_key[..32].copy_from_slice(&reversed32(&pdu[1..33]));
+        peer_key[32..].copy_from_slice(&reversed32(&pdu[33..65]));
+        // A peer sending our own key back is attacking us.
+        if self.public_key.map_or(true, |key| key[..] == peer_key[..]) {
+            return self.fail(FAILED_UNSPECIFIED);
+        }
+        let started = self.dhkey.take().map_or(false, |dhkey| {
+            match self.ecdh.compute_shared_secret(&peer_key, dhkey) {
+                Ok(()) => true,
+                Err((_, dhkey)) => {
+                    self.dhkey.replace(dhkey);
+                    false
+                }
+            }
+        });
+        if !started {
+            return self.fail(FAILED_UNSPECIFIED);
+        }
+        self.pairing.map(|p| {
+            p.peer_key = peer_key;
+            p.phase = Phase::Random;
+        });
+        self.pending
+            .set(self.pending.get() | SEND_PUBLIC_KEY | SEND_CONFIRM);
+        self.do_next();
+    }
+
+    fn random_received(&self, pdu: &[u8]) {
+        if pdu.len() != 17 {
+            return self.fail(FAILED_INVALID_PARAMETERS);
+        }
+        let na = reversed16(&pdu[1..17]);
+        let value = self.public_key.map_or(None, |pkb| {
+            self.pairing.and_then(|p| {
+                p.na = na;
+                p.phase = Phase::DhKeyCheck;
+                let mut pkbx = [0; 32];
+                pkbx.copy_from_slice(&pkb[..32]);
+                let mut pkax = [0; 32];
+                pkax.copy_from_slice(&p.peer_key[..32]);
+                match (p.numeric, p.nb) {
+                    (true, Some(nb)) => Some(crypto::g2(&pkax, &pkbx, &na, &nb)),
+                    _ => None,
+                }
+            })
+        });
+        self.pending.set(self.pending.get() | SEND_RANDOM);
+        self.do_next();
+        if let (Some(value), Some(handle)) = (value, self.handle()) {
+            self.client
+                .map(|client| client.confirm_value(handle, value));
+        }
+    }
+
+    fn dhkey_check_received(&self, pdu: &[u8]) {
+        if pdu.len() != 17 {
+            return self.fail(FAILED_INVALID_PARAMETERS);
+        }
+        let ea = reversed16(&pdu[1..17]);
+        self.pairing.map(|p| p.ea = Some(ea));
+        self.check();
+    }
+
+    /// Check the central's DHKey check value once we have everything it
+    /// depends on, and answer with ours.
+    fn check(&self) {
+        let result = self.pairing.and_then(|p| {
+            let (ea, dhkey, nb) = match (p.ea, p.dhkey, p.nb) {
+                (Some(ea), Some(dhkey), Some(nb)) if p.confirmed => (ea, dhkey, nb),
+                _ => return None,
+            };
+            let (mac_key, ltk) = crypto::f5(&dhkey, &p.na, &nb, &p.a, &p.b);
+            let expected = crypto::f6(&mac_key, &p.na, &nb, &[0; 16], &p.io_cap_a, &p.a, &p.b);
+            if !constant_time::eq(&expected, &ea) {
+                return Some(false);
+            }
+            p.eb = crypto::f6(&mac_key, &nb, &p.na, &[0; 16], &p.io_cap_b, &p.b, &p.a);
+            p.ltk = ltk;
+            p.phase = Phase::Encryption;
+            Some(true)
+        });
+        match result {
+            Some(true) => {
+                self.pending.set(self.pending.get() | SEND_DHKEY_CHECK);
+                self.do_next();
+            }
+            Some(false) => self.fail(FAILED_DHKEY_CHECK),
+            None => {}
+        }
+    }
+
+    /// Store the bond, if the central wanted one, and finish.
+    fn complete(&self) {
+        let pairing = match self.pairing.take() {
+            Some(pairing) => pairing,
+            None => return,
+        };
+        let (handle, peer) = match self.connection.map(|c| *c) {
+            Some(connection) => connection,
+            None => return,
+        };
+        let level = if pairing.numeric {
+            SecurityLevel::Authenticated
+        } else {
+            SecurityLevel::Encrypted
+        };
+        if pairing.bonding {
+            let mut ltk = pairing.ltk;
+            ltk.reverse();
+            self.bonds.store(Bond {
+                address: pairing.identity.unwrap_or(peer),
+                ltk: ltk,
+                irk: pairing.irk,
+                authenticated: pairing.numeric,
+            });
+        }
+        self.client
+            .map(|client| client.pairing_complete(handle, Ok(level)));
+    }
+
+    fn identity_received(&self, pdu: &[u8]) {
+        let done = self.pairing.map_or(false, |p| {
+            if p.phase != Phase::Keys || p.keys_expected & DIST_ID_KEY == 0 {
+                return false;
+            }
+            match pdu[0] {
+                IDENTITY_INFORMATION if pdu.len() == 17 => {
+                    let mut irk = [0; 16];
+                    irk.copy_from_slice(&pdu[1..17]);
+                    p.irk = Some(irk);
+                    false
+                }
+                IDENTITY_ADDRESS_INFORMATION if pdu.len() == 8 => {
+                    let mut address = [0; 6];
+                    address.copy_from_slice(&pdu[2..8]);
+                    p.identity = Some(DeviceAddress {
+                        address: address,
+                        random: pdu[1] != 0,
+                    });
+                    p.keys_expected &= !DIST_ID_KEY;
+                    p.keys_expected == 0
+                }
+                _ => false,
+            }
+        });
+        if done {
+            self.complete();
+        }
+    }
+
+    /// Send the next pending PDU whose contents we have.
+    fn do_next(&self) {
+        let handle = match self.handle() {
+            Some(handle) => handle,
+            None => return,
+        };
+        let pending = self.pending.get();
+        for &flag in [
+            SEND_FAILED,
+            SEND_RESPONSE,
+            SEND_PUBLIC_KEY,
+            SEND_CONFIRM,
+            SEND_RANDOM,
+            SEND_DHKEY_CHECK,
+            SEND_SECURITY_REQUEST,
+        ]
+        .iter()
+        {
+            if pending & flag == 0 {
+                continue;
+            }
+            let buffer = match self.buffer.take() {
+                Some(buffer) => buffer,
+                None => return,
+            };
+            let len = self.fill(flag, &mut buffer[HEADER_LEN..]);
+            if len == 0 {
+                // Not ready yet; later PDUs depend on this one.
+                self.buffer.replace(buffer);
+                return;
+            }
+            self.pending.set(pending & !flag);
+            if let Err((_, buffer)) = self.channel.send(handle, buffer, len) {
+                self.buffer.replace(buffer);
+            }
+            return;
+        }
+    }
+
+    /// Write the PDU for `flag` and return its length, or 0 if it cannot be
+    /// sent yet.
+    fn fill(&self, flag: u8, pdu: &mut [u8]) -> usize {
+        match flag {
+            SEND_FAILED => {
+                pdu[0] = PAIRING_FAILED;
+                pdu[1] = self.failure.get();
+                2
+            }
+            SEND_RESPONSE => self.pairing.map_or(0, |p| {
+                pdu[0] = PAIRING_RESPONSE;
+                pdu[1..7].copy_from_slice(&p.response);
+                7
+            }),
+            SEND_PUBLIC_KEY => self.public_key.map_or(0, |key| {
+                pdu[0] = PAIRING_PUBLIC_KEY;
+                write_reversed(&mut pdu[1..33], &key[..32]);
+                write_reversed(&mut pdu[33..65], &key[32..]);
+                65
+            }),
+            SEND_CONFIRM => self.public_key.map_or(0, |pkb| {
+                self.pairing.map_or(0, |p| match p.nb {
+                    Some(nb) => {
+                        let mut pkbx = [0; 32];
+                        pkbx.copy_from_slice(&pkb[..32]);
+                        let mut pkax = [0; 32];
+                        pkax.copy_from_slice(&p.peer_key[..32]);
+                        pdu[0] = PAIRING_CONFIRM;
+                        write_reversed(&mut pdu[1..17], &crypto::f4(&pkbx, &pkax, &nb, 0));
+                        17
+                    }
+                    None => 0,
+                })
+            }),
+            SEND_RANDOM => self.pairing.map_or(0, |p| match p.nb {
+                Some(nb) => {
+                    pdu[0] = PAIRING_RANDOM;
+                    write_reversed(&mut pdu[1..17], &nb);
+                    17
+                }
+                None => 0,
+            }),
+            SEND_DHKEY_CHECK => self.pairing.map_or(0, |p| {
+                pdu[0] = PAIRING_DHKEY_CHECK;
+                write_reversed(&mut pdu[1..17], &p.eb);
+                17
+            }),
+            SEND_SECURITY_REQUEST => {
+                pdu[0] = SECURITY_REQUEST;
+                pdu[1] = AUTH_BONDING
+                    | AUTH_SC
+                    | if can_compare(self.io_capability) {
+                        AUTH_MITM
+                    } else {
+                        0
+                    };
+                2
+            }
+            _ => 0,
+        }
+    }
+
+    fn set_level(&self, handle: ConnectionHandle, level: SecurityLevel) {
+        self.level.set(level);
+        self.security_client
+            .map(|client| client.security_changed(handle, level));
+    }
+}
+
+impl<'a> L2capClient for Smp<'a> {
+    fn connected(&self, handle: ConnectionHandle, peer: DeviceAddress) {
+        if self.connection.is_none() {
+            self.connection.set((handle, peer));
+            self.level.set(SecurityLevel::None);
+        }
+    }
+
+    fn disconnected(&self, handle: ConnectionHandle) {
+        if self.handle() == Some(handle) {
+            self.connection.clear();
+            self.pairing.take();
+            self.pending.set(0);
+            self.level.set(SecurityLevel::None);
+        }
+    }
+
+    fn received(&self, handle: ConnectionHandle, payload: &[u8]) {
+        if payload.is_empty() || self.handle() != Some(handle) {
+            return;
+        }
+        let phase = self.pairing.map(|p| p.phase);
+        match (payload[0], phase) {
+            (PAIRING_REQUEST, _) => self.pairing_request(payload),
+            (PAIRING_PUBLIC_KEY, Some(Phase::PublicKey)) => self.public_key_received(payload),
+            (PAIRING_RANDOM, Some(Phase::Random)) => self.random_received(payload),
+            (PAIRING_DHKEY_CHECK, Some(Phase::DhKeyCheck)) => self.dhkey_check_received(payload),
+            (IDENTITY_INFORMATION, Some(Phase::Keys))
+            | (IDENTITY_ADDRESS_INFORMATION, Some(Phase::Keys)) => self.identity_received(payload),
+            (PAIRING_FAILED, Some(_)) => {
+                self.pairing.take();
+                let reason = payload.get(1).map_or(FAILED_UNSPECIFIED, |r| *r);
+                self.client
+                    .map(|client| client.pairing_complete(handle, Err(reason)));
+            }
+            (PAIRING_FAILED, None) => {}
+            (_, Some(_)) => self.fail(FAILED_UNSPECIFIED),
+            (_, None) => {
+                self.failure.set(FAILED_COMMAND_NOT_SUPPORTED);
+                self.pending.set(self.pending.get() | SEND_FAILED);
+                self.do_next();
+            }
+        }
+    }
+
+    fn sent(&self, buffer: &'static mut [u8], _result: ReturnCode) {
+        self.buffer.replace(buffer);
+        self.do_next();
+    }
+}
+
+impl<'a> EncryptionClient for Smp<'a> {
+    fn long_term_key(&self, handle: ConnectionHandle, ediv: u16, rand: u64) -> Option<[u8; 16]> {
+        if self.handle() != Some(handle) || ediv != 0 || rand != 0 {
+            return None;
+        }
+        let pairing_key = self.pairing.and_then(|p| {
+            if p.phase == Phase::Encryption {
+                let mut ltk = p.ltk;
+                ltk.reverse();
+                Some(ltk)
+            } else {
+                None
+            }
+        });
+        pairing_key.or_else(|| {
+            self.connection
+                .and_then(|(_, peer)| self.bonds.lookup(&peer))
+                .map(|bond| bond.ltk)
+        })
+    }
+
+    fn encryption_changed(&self, handle: ConnectionHandle, encrypted: bool) {
+        if self.handle() != Some(handle) {
+            return;
+        }
+        if !encrypted {
+            self.set_level(handle, SecurityLevel::None);
+            if self.pairing.is_some() {
+                self.fail(FAILED_UNSPECIFIED);
+            }
+            return;
+        }
+
+        let pairing = self.pairing.and_then(|p| {
+            if p.phase == Phase::Encryption {
+                p.phase = Phase::Keys;
+                Some((p.numeric, p.keys_expected))
+            } else {
+                None
+            }
+        });
+        match pairing {
+            Some((numeric, keys_expected)) => {
+                self.set_level(
+                    handle,
+                    if numeric {
+                        SecurityLevel::Authenticated
+                    } else {
+                        SecurityLevel::Encrypted
+                    },
+                );
+                if keys_expected == 0 {
+                    self.complete();
+                }
+            }
+            None => {
+                // Encrypted with a bond's key.
+                let authenticated = self
+                    .connection
+                    .and_then(|(_, peer)| self.bonds.lookup(&peer))
+                    .map_or(false, |bond| bond.authenticated);
+                self.set_level(
+                    handle,
+                    if authenticated {
+                        SecurityLevel::Authenticated
+                    } else {
+                        SecurityLevel::Encrypted
+                    },
+                );
+            }
+        }
+    }
+}
+
+impl<'a> ecdh::Client for Smp<'a> {
+    fn key_pair_generated(&self, public_key: &'static mut [u8; 64], result: ReturnCode) {
+        self.public_key.replace(public_key);
+        self.key_ready.set(result == ReturnCode::SUCCESS);
+    }
+
+    fn shared_secret_computed(&self, secret: &'static mut [u8; 32], result: ReturnCode) {
+        let dhkey = *secret;
+        self.dhkey.replace(secret);
+        if result != ReturnCode::SUCCESS {
+            // Most likely the peer's public key was not on the curve.
+            if self.pairing.is_some() {
+                self.fail(FAILED_DHKEY_CHECK);
+            }
+            return;
+        }
+        self.pairing.map(|p| p.dhkey = Some(dhkey));
+        self.check();
+    }
+}
+
+impl<'a> rng::Client for Smp<'a> {
+    fn randomness_available(
+        &self,
+        randomness: &mut dyn Iterator<Item = u32>,
+        error: ReturnCode,
+    ) -> rng::Continue {
+        if error != ReturnCode::SUCCESS || self.pairing.is_none() {
+            return rng::Continue::Done;
+        }
+        let mut nb = [0; 16];
+        for chunk in nb.chunks_exact_mut(4) {
+            match randomness.next() {
+                Some(word) => chunk.copy_from_slice(&word.to_le_bytes()),
+                None => return rng::Continue::More,
+            }
+        }
+        self.pairing.map(|p| p.nb = Some(nb));
+        self.do_next();
+        rng::Continue::Done
+    }
+}
@@ -10,13 +10,22 @@
 //! Data payloads are limited to 31 bytes since the maximum advertising channel
 //! protocol data unit (PDU) is 37 bytes and includes a 6-byte header.
 //!
+//! On radios with Bluetooth 5 support, processes can instead use extended
+//! advertising, which sends a short `ADV_EXT_IND` on the primary channels
+//! pointing to an `AUX_ADV_IND` with up to 245 bytes of data on a secondary
+//! channel. The primary channels can use the 1 Mb/s or the coded PHY, for up
+//! to four times the range, and the secondary channel any of the PHYs.
+//! Periodic advertising adds an `AUX_SYNC_IND` with up to 254 bytes of data,
+//! sent at a fixed interval that scanners synchronize to.
+//!
 //! ### Allow system call
 //!
 //! The allow systems calls are used for buffers from allocated by userland
 //!
-//! There are two different buffers:
+//! There are three different buffers:
 //! * 0: Advertising data
 //! * 1: Passive scanning buffer
+//! * 2: Periodic advertising data
 //!
 //! The possible return codes from the 'allow' system call indicate the following:
 //!
@@ -48,7 +57,12 @@
 //!
 //! * 0: start advertisement
 //! * 1: stop advertisement or scanning
+//! * 2: configure the transmitting power
 //! * 5: start scanning
+//! * 6: configure the PHYs of extended advertising, the primary one (1: 1 Mb/s
+//!      or 3: coded) as the subcommand and the secondary one (1: 1 Mb/s, 2:
+//!      2 Mb/s or 3: coded) as the last argument
+//! * 7: start extended advertising with a periodic advertising train
 //!
 //! The possible return codes from the `command` system call indicate the following:
 //!
@@ -99,13 +113,16 @@
 // This means that advertising events can collide. In this case, we just defer one of the
 // advertisements. Because we add a pseudo random pad to the timer interval each time (as required
 // by the Bluetooth specification) multiple collisions of the same processes are highly unlikely.
+//
+// Once the board sets the power the radio draws while transmitting with
+// `set_transmit_active_power()`, each process is charged for the time its packets spend on air.
 
 use core::cell::Cell;
 use core::cmp;
 use kernel::common::cells::OptionalCell;
 use kernel::debug;
 use kernel::hil::ble_advertising;
-use kernel::hil::ble_advertising::RadioChannel;
+use kernel::hil::ble_advertising::{Phy, RadioChannel};
 use kernel::hil::time::{Frequency, Ticks};
 use kernel::ReturnCode;
 
@@ -114,12 +131,34 @@ use crate::driver;
 pub const DRIVER_NUM: usize = driver::NUM::BleAdvertising as usize;
 
 /// Advertisement Buffer
-pub static mut BUF: [u8; PACKET_LENGTH] = [0; PACKET_LENGTH];
+pub static mut BUF: [u8; EXT_PACKET_LENGTH] = [0; EXT_PACKET_LENGTH];
 
 const PACKET_ADDR_LEN: usize = 6;
 const PACKET_LENGTH: usize = 39;
 const ADV_HEADER_TXADD_OFFSET: usize = 6;
 
+// BLUETOOTH SPECIFICATION Version 5.0 [Vol 6, Part B], section 2.3.4
+const EXT_PACKET_LENGTH: usize = 2 + 255;
+const EXT_HEADER_ADV_A: u8 = 1 << 0;
+const EXT_HEADER_ADI: u8 = 1 << 3;
+const EXT_HEADER_AUX_PTR: u8 = 1 << 4;
+const EXT_HEADER_SYNC_INFO: u8 = 1 << 5;
+const ADI_LEN: usize = 2;
+const AUX_PTR_LEN: usize = 3;
+const SYNC_INFO_LEN: usize = 18;
+
+// Extended advertising events, BLUETOOTH SPECIFICATION Version 5.0 [Vol 6, Part B], section
+// 4.4.2. Offsets are announced in 300 us units and the auxiliary packets are sent half a unit
+// later, in the middle of the window scanners listen in.
+const OFFSET_UNIT_US: u32 = 300;
+/// Between the starts of the `ADV_EXT_IND`s, long enough for one on the coded PHY.
+const PRIMARY_SPACING_US: u32 = 2100;
+/// From the start of the last `ADV_EXT_IND` to the `AUX_ADV_IND`.
+const AUX_OFFSET_US: u32 = 3000;
+/// From the start of the `AUX_ADV_IND` to the `AUX_SYNC_IND`, long enough for the longest
+/// `AUX_ADV_IND` on the coded PHY.
+const SYNC_OFFSET_US: u32 = 18000;
+
 #[derive(PartialEq, Debug)]
 enum BLEState {
     NotInitialized,
@@ -128,6 +167,8 @@ enum BLEState {
     Scanning(RadioChannel),
     AdvertisingIdle,
     Advertising(RadioChannel),
+    AuxAdvertising,
+    SyncAdvertising,
 }
 
 #[derive(Copy, Clone)]
@@ -165,6 +206,82 @@ const SCAN_RESP: AdvPduType = 0b0100;
 #[allow(dead_code)]
 const CONNECT_IND: AdvPduType = 0b0101;
 const ADV_SCAN_IND: AdvPduType = 0b0110;
+// BLUETOOTH SPECIFICATION Version 5.0 [Vol 6, Part B], section 2.3, also `AUX_ADV_IND` and
+// `AUX_SYNC_IND`
+const ADV_EXT_IND: AdvPduType = 0b0111;
+
+// BLUETOOTH SPECIFICATION Version 5.0 [Vol 6, Part B], section 2.3.4.5
+fn aux_phy(phy: Phy) -> u8 {
+    match phy {
+        Phy::Le1M => 0,
+        Phy::Le2M => 1,
+        Phy::LeCodedS8 | Phy::LeCodedS2 => 2,
+    }
+}
+
+// BLUETOOTH SPECIFICATION Version 5.0 [Vol 6, Part B], section 2.1 and 2.2
+//
+// The time on air of a packet with a `pdu_len` byte PDU, including the preamble, access address
+// and CRC, and on the coded PHY the coding indicator and terms.
+fn airtime_us(phy: Phy, pdu_len: usize) -> u32 {
+    let coded_len = (pdu_len + 3) as u32 * 8;
+    match phy {
+        Phy::Le1M => (1 + 4 + pdu_len as u32 + 3) * 8,
+        Phy::Le2M => (2 + 4 + pdu_len as u32 + 3) * 4,
+        Phy::LeCodedS8 => 80 + 256 + 16 + 24 + coded_len * 8 + 3 * 8,
+        Phy::LeCodedS2 => 80 + 256 + 16 + 24 + coded_len * 2 + 3 * 2,
+    }
+}
+
+// BLUETOOTH SPECIFICATION Version 5.0 [Vol 6, Part B], section 2.1.2
+//
+// The access address of a periodic advertising train must look unlike noise and unlike the
+// advertising access address.
+fn valid_access_address(access_address: u32) -> bool {
+    let transitions = (access_address ^ (access_address >> 1)) & 0x7fff_ffff;
+    let bytes = access_address.to_le_bytes();
+    let mut run = 1;
+    let mut longest_run = 1;
+    for bit in 1..32 {
+        if (transitions >> (bit - 1)) & 1 == 0 {
+            run += 1;
+            longest_run = cmp::max(longest_run, run);
+        } else {
+            run = 1;
+        }
+    }
+    (access_address ^ ble_advertising::ADVERTISING_ACCESS_ADDRESS).count_ones() > 1
+        && bytes.iter().any(|b| *b != bytes[0])
+        && longest_run <= 6
+        && transitions.count_ones() <= 24
+        && (transitions >> 26).count_ones() >= 2
+}
+
+// BLUETOOTH SPECIFICATION Version 5.0 [Vol 6, Part B], section 4.5.8.3, Channel Selection
+// Algorithm #2 with all 37 data channels used
+fn periodic_channel(counter: u16, access_address: u32) -> u8 {
+    let channel_id = ((access_address >> 16) ^ access_address) as u16;
+    let mut prn = counter ^ channel_id;
+    for _ in 0..3 {
+        let [low, high] = prn.to_le_bytes();
+        prn = u16::from_le_bytes([low.reverse_bits(), high.reverse_bits()]);
+        prn = prn.wrapping_mul(17).wrapping_add(channel_id);
+    }
+    ((prn ^ channel_id) % 37) as u8
+}
+
+/// A periodic advertising train
+#[derive(Copy, Clone)]
+struct PeriodicTrain {
+    access_address: u32,
+    crc_init: u32,
+    /// `paEventCounter` of the next event
+    counter: u16,
+    /// The start of the next event, in alarm ticks
+    next_event: u32,
+    /// Thousandths of a tick left over from the periods so far
+    remainder: u32,
+}
 
 /// Process specific memory
 pub struct App {
@@ -184,6 +301,15 @@ pub struct App {
     /// well.
     random_nonce: u32,
 
+    // Extended advertising meta-data
+    primary_phy: Phy,
+    secondary_phy: Phy,
+    /// The Advertising Data ID, which scanners use to tell new data from a repetition.
+    data_id: u16,
+    aux_channel: RadioChannel,
+    periodic_data: Option<kernel::AppSlice<kernel::Shared, u8>>,
+    periodic: Option<PeriodicTrain>,
+
     // Scanning meta-data
     scan_buffer: Option<kernel::AppSlice<kernel::Shared, u8>>,
     scan_callback: Option<kernel::Callback>,
@@ -203,6 +329,12 @@ impl Default for App {
             advertisement_interval_ms: 200,
             // Just use any non-zero starting value by default
             random_nonce: 0xdeadbeef,
+            primary_phy: Phy::Le1M,
+            secondary_phy: Phy::Le1M,
+            data_id: 0,
+            aux_channel: RadioChannel::DataChannel0,
+            periodic_data: None,
+            periodic: None,
         }
     }
 }
@@ -243,7 +375,7 @@ impl App {
     {
         self.adv_data.as_ref().map_or(ReturnCode::FAIL, |adv_data| {
             ble.kernel_tx.take().map_or(ReturnCode::FAIL, |kernel_tx| {
-                let adv_data_len = cmp::min(kernel_tx.len() - PACKET_ADDR_LEN - 2, adv_data.len());
+                let adv_data_len = cmp::min(PACKET_LENGTH - PACKET_ADDR_LEN - 2, adv_data.len());
                 let adv_data_corrected = &adv_data.as_ref()[..adv_data_len];
                 let payload_len = adv_data_corrected.len() + PACKET_ADDR_LEN;
                 {
@@ -272,6 +404,148 @@ impl App {
         })
     }
 
+    // Advertising Data Info, BLUETOOTH SPECIFICATION Version 5.0 [Vol 6, Part B], section 2.3.4.4,
+    // with advertising set ID 0 since each process is its own device
+    fn adv_data_info(&self) -> [u8; ADI_LEN] {
+        (self.data_id & 0xfff).to_le_bytes()
+    }
+
+    // An `ADV_EXT_IND` on a primary channel, pointing to this event's `AUX_ADV_IND`
+    fn send_extended_advertisement<'a, B, A>(
+        &self,
+        ble: &BLE<'a, B, A>,
+        channel: RadioChannel,
+        delay_us: Option<u32>,
+    ) -> ReturnCode
+    where
+        B: ble_advertising::BleAdvertisementDriver<'a> + ble_advertising::BleConfig,
+        A: kernel::hil::time::Alarm<'a>,
+    {
+        let aux_offset_us = match channel {
+            RadioChannel::AdvertisingChannel37 => 2 * PRIMARY_SPACING_US + AUX_OFFSET_US,
+            RadioChannel::AdvertisingChannel38 => PRIMARY_SPACING_US + AUX_OFFSET_US,
+            _ => AUX_OFFSET_US,
+        };
+        let aux_offset = (aux_offset_us / OFFSET_UNIT_US) as u16;
+        let ext_header_len = 1 + ADI_LEN + AUX_PTR_LEN;
+
+        ble.kernel_tx.take().map_or(ReturnCode::FAIL, |kernel_tx| {
+            kernel_tx[0] = ADV_EXT_IND;
+            kernel_tx[1] = (1 + ext_header_len) as u8;
+            // AdvMode 0, non-connectable and non-scannable
+            kernel_tx[2] = ext_header_len as u8;
+            kernel_tx[3] = EXT_HEADER_ADI | EXT_HEADER_AUX_PTR;
+            kernel_tx[4..6].copy_from_slice(&self.adv_data_info());
+            // Offsets in 300 us units
+            kernel_tx[6] = self.aux_channel.get_channel_index() as u8 | 1 << 7;
+            kernel_tx[7] = aux_offset as u8;
+            kernel_tx[8] = (aux_offset >> 8) as u8 & 0x1f | aux_phy(self.secondary_phy) << 5;
+            ble.transmit(kernel_tx, 3 + ext_header_len, channel, delay_us)
+        })
+    }
+
+    // This event's `AUX_ADV_IND`, with the advertising data and, when periodic advertising, the
+    // timing of the next `AUX_SYNC_IND`
+    fn send_aux_advertisement<'a, B, A>(&self, ble: &BLE<'a, B, A>, delay_us: u32) -> ReturnCode
+    where
+        B: ble_advertising::BleAdvertisementDriver<'a> + ble_advertising::BleConfig,
+        A: kernel::hil::time::Alarm<'a>,
+    {
+        self.adv_data.as_ref().map_or(ReturnCode::FAIL, |adv_data| {
+            ble.kernel_tx.take().map_or(ReturnCode::FAIL, |kernel_tx| {
+                let sync_info_len = self.periodic.map_or(0, |_| SYNC_INFO_LEN);
+                let ext_header_len = 1 + PACKET_ADDR_LEN + ADI_LEN + sync_info_len;
+                let adv_data_len = cmp::min(EXT_PACKET_LENGTH - 3 - ext_header_len, adv_data.len());
+
+                kernel_tx[0] = ADV_EXT_IND | 1 << ADV_HEADER_TXADD_OFFSET;
+                kernel_tx[1] = (1 + ext_header_len + adv_data_len) as u8;
+                kernel_tx[2] = ext_header_len as u8;
+                kernel_tx[3] = EXT_HEADER_ADV_A | EXT_HEADER_ADI;
+                kernel_tx[4..10].copy_from_slice(&self.address);
+                kernel_tx[10..12].copy_from_slice(&self.adv_data_info());
+                let mut index = 12;
+                if let Some(train) = self.periodic {
+                    kernel_tx[3] |= EXT_HEADER_SYNC_INFO;
+                    kernel_tx[index..index + SYNC_INFO_LEN].copy_from_slice(&self.sync_info(train));
+                    index += SYNC_INFO_LEN;
+                }
+                kernel_tx[index..index + adv_data_len]
+                    .copy_from_slice(&adv_data.as_ref()[..adv_data_len]);
+
+                ble.transmit(
+                    kernel_tx,
+                    index + adv_data_len,
+                    self.aux_channel,
+                    Some(delay_us),
+                )
+            })
+        })
+    }
+
+    // SyncInfo, BLUETOOTH SPECIFICATION Version 5.0 [Vol 6, Part B], section 2.3.4.6
+    fn sync_info(&self, train: PeriodicTrain) -> [u8; SYNC_INFO_LEN] {
+        let mut sync_info = [0; SYNC_INFO_LEN];
+        // Offset in 300 us units
+        let offset = (SYNC_OFFSET_US / OFFSET_UNIT_US) as u16 | 1 << 13;
+        sync_info[0..2].copy_from_slice(&offset.to_le_bytes());
+        // Interval in 1.25 ms units
+        let interval = (self.advertisement_interval_ms * 4 / 5) as u16;
+        sync_info[2..4].copy_from_slice(&interval.to_le_bytes());
+        // All 37 data channels, with a 251 ppm to 500 ppm sleep clock accuracy
+        sync_info[4..9].copy_from_slice(&[0xff, 0xff, 0xff, 0xff, 0x1f]);
+        sync_info[9..13].copy_from_slice(&train.access_address.to_le_bytes());
+        sync_info[13..16].copy_from_slice(&train.crc_init.to_le_bytes()[..3]);
+        sync_info[16..18].copy_from_slice(&train.counter.to_le_bytes());
+        sync_info
+    }
+
+    // This event's `AUX_SYNC_IND`, with the periodic advertising data
+    fn send_sync<'a, B, A>(&self, ble: &BLE<'a, B, A>, delay_us: u32) -> ReturnCode
+    where
+        B: ble_advertising::BleAdvertisementDriver<'a> + ble_advertising::BleConfig,
+        A: kernel::hil::time::Alarm<'a>,
+    {
+        self.periodic.map_or(ReturnCode::FAIL, |train| {
+            ble.kernel_tx.take().map_or(ReturnCode::FAIL, |kernel_tx| {
+                let data = self
+                    .periodic_data
+                    .as_ref()
+                    .map_or(&[][..], |data| data.as_ref());
+                let data_len = cmp::min(EXT_PACKET_LENGTH - 3, data.len());
+
+                kernel_tx[0] = ADV_EXT_IND;
+                kernel_tx[1] = (1 + data_len) as u8;
+                // No extended header
+                kernel_tx[2] = 0;
+                kernel_tx[3..3 + data_len].copy_from_slice(&data[..data_len]);
+
+                let channel = RadioChannel::from_channel_index(periodic_channel(
+                    train.counter,
+                    train.access_address,
+                ) as u32)
+                .unwrap_or(RadioChannel::DataChannel0);
+                ble.radio
+                    .set_access_address(train.access_address, train.crc_init);
+                ble.transmit(kernel_tx, 3 + data_len, channel, Some(delay_us))
+            })
+        })
+    }
+
+    // Pick a new access address and CRC initialization value for a periodic advertising train.
+    fn new_periodic_train(&mut self, now: u32) -> PeriodicTrain {
+        let mut access_address = self.random_nonce();
+        while !valid_access_address(access_address) {
+            access_address = self.random_nonce();
+        }
+        PeriodicTrain {
+            access_address: access_address,
+            crc_init: self.random_nonce() & 0xff_ffff,
+            counter: 0,
+            next_event: now,
+            remainder: 0,
+        }
+    }
+
     // Returns a new pseudo-random number and updates the randomness state.
     //
     // Uses the [Xorshift](https://en.wikipedia.org/wiki/Xorshift) algorithm to
@@ -288,6 +562,24 @@ impl App {
 
     // Set the next alarm for this app using the period and provided start time.
     fn set_next_alarm<F: Frequency>(&mut self, now: u32) {
+        if let Some(mut train) = self.periodic {
+            // Periodic advertising events keep to the interval, without the pseudo random pad,
+            // so that synchronized scanners find each `AUX_SYNC_IND` where they expect it. Events
+            // that are past, because they were sent or had to be skipped, still count.
+            let period = self.advertisement_interval_ms * F::frequency() / 1000;
+            while train.next_event.wrapping_sub(now) > period {
+                let ticks = train.remainder + self.advertisement_interval_ms * F::frequency();
+                train.next_event = train.next_event.wrapping_add(ticks / 1000);
+                train.remainder = ticks % 1000;
+                train.counter = train.counter.wrapping_add(1);
+            }
+            self.periodic = Some(train);
+            self.alarm_data.t0 = now;
+            self.alarm_data.expiration =
+                Expiration::Enabled(now, train.next_event.wrapping_sub(now));
+            return;
+        }
+
         self.alarm_data.t0 = now;
         let nonce = self.random_nonce() % 10;
 
@@ -308,6 +600,7 @@ where
     alarm: &'a A,
     sending_app: OptionalCell<kernel::AppId>,
     receiving_app: OptionalCell<kernel::AppId>,
+    transmit_power_uw: Cell<u32>,
 }
 
 impl<'a, B, A> BLE<'a, B, A>
@@ -329,9 +622,56 @@ where
             alarm: alarm,
             sending_app: OptionalCell::empty(),
             receiving_app: OptionalCell::empty(),
+            transmit_power_uw: Cell::new(0),
         }
     }
 
+    /// Charge processes for their advertisements at `microwatts` while on air.
+    /// Until this is set they are not charged for the radio.
+    pub fn set_transmit_active_power(&self, microwatts: u32) {
+        self.transmit_power_uw.set(microwatts);
+    }
+
+    // Transmit at once, or `delay_us` after the previous transmission started.
+    fn transmit(
+        &self,
+        buf: &'static mut [u8],
+        len: usize,
+        channel: RadioChannel,
+        delay_us: Option<u32>,
+    ) -> ReturnCode {
+        match delay_us {
+            None => {
+                self.radio.transmit_advertisement(buf, len, channel);
+                ReturnCode::SUCCESS
+            }
+            Some(delay_us) => {
+                match self
+                    .radio
+                    .transmit_advertisement_delayed(buf, len, channel, delay_us)
+                {
+                    Ok(()) => ReturnCode::SUCCESS,
+                    Err((rcode, buf)) => {
+                        self.kernel_tx.replace(buf);
+                        rcode
+                    }
+                }
+            }
+        }
+    }
+
+    // Ends an extended advertising event, leaving the radio as legacy advertising expects it.
+    fn finish_extended_event(&self, app: &mut App) {
+        self.radio.set_phy(Phy::Le1M);
+        self.radio.set_access_address(
+            ble_advertising::ADVERTISING_ACCESS_ADDRESS,
+            ble_advertising::ADVERTISING_CRC_INIT,
+        );
+        self.busy.set(false);
+        app.process_status = Some(BLEState::AdvertisingIdle);
+        app.set_next_alarm::<A::Frequency>(self.alarm.now().into_u32());
+    }
+
     // Determines which app timer will expire next and sets the underlying alarm
     // to it.
     //
@@ -405,12 +745,32 @@ where
                     app.alarm_data.expiration = Expiration::Disabled;
 
                     match app.process_status {
+                        Some(BLEState::AdvertisingIdle) if app.pdu_type == ADV_EXT_IND => {
+                            self.busy.set(true);
+                            app.process_status =
+                                Some(BLEState::Advertising(RadioChannel::AdvertisingChannel37));
+                            self.sending_app.set(app.appid());
+                            self.radio.set_tx_power(app.tx_power);
+                            self.radio.set_phy(app.primary_phy);
+                            app.aux_channel =
+                                RadioChannel::from_channel_index(app.random_nonce() % 37)
+                                    .unwrap_or(RadioChannel::DataChannel0);
+                            let rcode = app.send_extended_advertisement(
+                                &self,
+                                RadioChannel::AdvertisingChannel37,
+                                None,
+                            );
+                            if rcode != ReturnCode::SUCCESS {
+                                self.finish_extended_event(app);
+                            }
+                        }
                         Some(BLEState::AdvertisingIdle) => {
                             self.busy.set(true);
                             app.process_status =
                                 Some(BLEState::Advertising(RadioChannel::AdvertisingChannel37));
                             self.sending_app.set(app.appid());
                             self.radio.set_tx_power(app.tx_power);
+                            self.radio.set_phy(Phy::Le1M);
                             app.send_advertisement(&self, RadioChannel::AdvertisingChannel37);
                         }
                         Some(BLEState::ScanningIdle) => {
@@ -419,6 +779,7 @@ where
                                 Some(BLEState::Scanning(RadioChannel::AdvertisingChannel37));
                             self.receiving_app.set(app.appid());
                             self.radio.set_tx_power(app.tx_power);
+                            self.radio.set_phy(Phy::Le1M);
                             self.radio
                                 .receive_advertisement(RadioChannel::AdvertisingChannel37);
                         }
@@ -511,10 +872,72 @@ where
     // The ReturnCode indicates valid CRC or not, not used yet but could be used for
     // re-transmissions for invalid CRCs
     fn transmit_event(&self, buf: &'static mut [u8], _crc_ok: ReturnCode) {
+        let pdu_len = 2 + buf[1] as usize;
         self.kernel_tx.replace(buf);
         self.sending_app.map(|appid| {
             let _ = self.app.enter(*appid, |app, _| {
+                let phy = match app.process_status {
+                    Some(BLEState::Advertising(_)) if app.pdu_type == ADV_EXT_IND => {
+                        app.primary_phy
+                    }
+                    Some(BLEState::AuxAdvertising) | Some(BLEState::SyncAdvertising) => {
+                        app.secondary_phy
+                    }
+                    _ => Phy::Le1M,
+                };
+                appid.charge_energy(kernel::power::energy_nj(
+                    self.transmit_power_uw.get(),
+                    airtime_us(phy, pdu_len),
+                ));
+
                 match app.process_status {
+                    Some(BLEState::Advertising(channel)) if app.pdu_type == ADV_EXT_IND => {
+                        // The primary channels in turn, then the secondary one
+                        let rcode = match channel {
+                            RadioChannel::AdvertisingChannel37 => {
+                                app.process_status =
+                                    Some(BLEState::Advertising(RadioChannel::AdvertisingChannel38));
+                                app.send_extended_advertisement(
+                                    &self,
+                                    RadioChannel::AdvertisingChannel38,
+                                    Some(PRIMARY_SPACING_US),
+                                )
+                            }
+                            RadioChannel::AdvertisingChannel38 => {
+                                app.process_status =
+                                    Some(BLEState::Advertising(RadioChannel::AdvertisingChannel39));
+                                app.send_extended_advertisement(
+                                    &self,
+                                    RadioChannel::AdvertisingChannel39,
+                                    Some(PRIMARY_SPACING_US),
+                                )
+                            }
+                            _ => {
+                                app.process_status = Some(BLEState::AuxAdvertising);
+                                self.radio.set_phy(app.secondary_phy);
+                                app.send_aux_advertisement(
+                                    &self,
+                                    AUX_OFFSET_US + OFFSET_UNIT_US / 2,
+                                )
+                            }
+                        };
+                        if rcode != ReturnCode::SUCCESS {
+                            self.finish_extended_event(app);
+                        }
+                    }
+
+                    Some(BLEState::AuxAdvertising) if app.periodic.is_some() => {
+                        app.process_status = Some(BLEState::SyncAdvertising);
+                        let rcode = app.send_sync(&self, SYNC_OFFSET_US + OFFSET_UNIT_US / 2);
+                        if rcode != ReturnCode::SUCCESS {
+                            self.finish_extended_event(app);
+                        }
+                    }
+
+                    Some(BLEState::AuxAdvertising) | Some(BLEState::SyncAdvertising) => {
+                        self.finish_extended_event(app);
+                    }
+
                     Some(BLEState::Advertising(RadioChannel::AdvertisingChannel37)) => {
                         app.process_status =
                             Some(BLEState::Advertising(RadioChannel::AdvertisingChannel38));
@@ -565,8 +988,9 @@ where
                     if let Some(BLEState::Initialized) = app.process_status {
                         let pdu_type = data as AdvPduType;
                         match pdu_type {
-                            ADV_IND | ADV_NONCONN_IND | ADV_SCAN_IND => {
+                            ADV_IND | ADV_NONCONN_IND | ADV_SCAN_IND | ADV_EXT_IND => {
                                 app.pdu_type = pdu_type;
+                                app.periodic = None;
                                 app.process_status = Some(BLEState::AdvertisingIdle);
                                 app.random_nonce = self.alarm.now().into_u32();
                                 app.advertisement_interval_ms = cmp::max(20, interval as u32);
@@ -640,6 +1064,84 @@ where
                 })
                 .unwrap_or_else(|err| err.into()),
 
+            // Configure the PHYs of extended advertising
+            // BLUETOOTH SPECIFICATION Version 5.0 [Vol 6, Part B], section 4.4.2
+            //
+            // The primary channels can use the 1 Mb/s or coded PHY and the secondary
+            // channel any
+            //
+            // data - The primary PHY
+            // interval - The secondary PHY
+            6 => {
+                let phy = |number| match number {
+                    1 => Some(Phy::Le1M),
+                    2 => Some(Phy::Le2M),
+                    3 => Some(Phy::LeCodedS8),
+                    _ => None,
+                };
+                let (primary, secondary) = match (phy(data), phy(interval)) {
+                    (Some(Phy::Le2M), _) | (None, _) | (_, None) => return ReturnCode::EINVAL,
+                    (Some(primary), Some(secondary)) => (primary, secondary),
+                };
+                self.app
+                    .enter(appid, |app, _| {
+                        if app.process_status != Some(BLEState::ScanningIdle)
+                            && app.process_status != Some(BLEState::AdvertisingIdle)
+                        {
+                            // query the underlying chip if the PHYs are supported
+                            let status = match self.radio.set_phy(primary) {
+                                ReturnCode::SUCCESS => self.radio.set_phy(secondary),
+                                status => status,
+                            };
+                            self.radio.set_phy(Phy::Le1M);
+                            if let ReturnCode::SUCCESS = status {
+                                app.primary_phy = primary;
+                                app.secondary_phy = secondary;
+                            }
+                            status
+                        } else {
+                            ReturnCode::EBUSY
+                        }
+                    })
+                    .unwrap_or_else(|err| err.into())
+            }
+
+            // Start periodic advertisements with a periodic advertising train
+            //
+            // interval - The advertising interval in ms, rounded down to a multiple of 5 ms
+            // since the train's is in 1.25 ms units, and between 30 ms, long enough for an
+            // event, and the longest train interval
+            7 => self
+                .app
+                .enter(appid, |app, _| {
+                    if let Some(BLEState::Initialized) = app.process_status {
+                        app.random_nonce = self.alarm.now().into_u32();
+                        let train = app.new_periodic_train(self.alarm.now().into_u32());
+                        // query the underlying chip if it can use other access addresses
+                        let status = self
+                            .radio
+                            .set_access_address(train.access_address, train.crc_init);
+                        self.radio.set_access_address(
+                            ble_advertising::ADVERTISING_ACCESS_ADDRESS,
+                            ble_advertising::ADVERTISING_CRC_INIT,
+                        );
+                        if status != ReturnCode::SUCCESS {
+                            return status;
+                        }
+                        app.pdu_type = ADV_EXT_IND;
+                        app.periodic = Some(train);
+                        app.process_status = Some(BLEState::AdvertisingIdle);
+                        app.advertisement_interval_ms =
+                            cmp::min(cmp::max(30, interval as u32), 81_915) / 5 * 5;
+                        app.set_next_alarm::<A::Frequency>(self.alarm.now().into_u32());
+                        self.reset_active_alarm();
+                        ReturnCode::SUCCESS
+                    } else {
+                        ReturnCode::EBUSY
+                    }
+                })
+                .unwrap_or_else(|err| err.into()),
+
             _ => ReturnCode::ENOSUPPORT,
         }
     }
@@ -656,6 +1158,7 @@ where
                 .app
                 .enter(appid, |app, _| {
                     app.adv_data = slice;
+                    app.data_id = app.data_id.wrapping_add(1);
                     if let ReturnCode::SUCCESS = app.generate_random_address(appid) {
                         app.process_status = Some(BLEState::Initialized);
                         ReturnCode::SUCCESS
@@ -678,6 +1181,15 @@ where
                 })
                 .unwrap_or_else(|err| err.into()),
 
+            // Periodic advertising buffer
+            2 => self
+                .app
+                .enter(appid, |app, _| {
+                    app.periodic_data = slice;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+
             // Operation not supported
             _ => ReturnCode::ENOSUPPORT,
         }
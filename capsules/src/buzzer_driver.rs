@@ -42,7 +42,6 @@ use core::cmp;
 
 use kernel::common::cells::OptionalCell;
 use kernel::hil;
-use kernel::hil::time::Frequency;
 use kernel::{AppId, Callback, Driver, Grant, ReturnCode};
 
 /// Syscall driver number.
@@ -138,9 +137,8 @@ impl<'a, A: hil::time::Alarm<'a>> Buzzer<'a, A> {
                 }
 
                 // Now start a timer so we know when to stop the PWM.
-                let interval = (duration_ms as u32) * <A::Frequency>::frequency() / 1000;
-                self.alarm
-                    .set_alarm(self.alarm.now(), A::Ticks::from(interval));
+                let interval = A::ticks_from_ms(duration_ms as u32);
+                self.alarm.set_alarm(self.alarm.now(), interval);
                 ReturnCode::SUCCESS
             }
         }
@@ -0,0 +1,365 @@
+//! Provides userspace with access to a CAN bus.
+//!
+//! Works with any `hil::can::Can`. Apps send frames, which are queued and
+//! sent one at a time, and add acceptance filters; each received frame goes
+//! to the app that added the filter it matched. The bitrate is shared by
+//! all apps, so enabling at a different one while the bus is in use returns
+//! `EBUSY` until every app has disabled.
+//!
+//! Frames are exchanged in 16 byte records: the identifier as a little
+//! endian `u32`, with bit 31 set for an extended identifier and bit 30 for a
+//! remote frame, the data length in byte 4, three reserved bytes and the 8
+//! data bytes.
+//!
+//! ## Instantiation
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let can = static_init!(
+//!     capsules::can::CanDriver<'static>,
+//!     capsules::can::CanDriver::new(&stm32f4xx::can::CAN1, board_kernel.create_grant(&grant_cap))
+//! );
+//! kernel::hil::can::Can::set_transmit_client(&stm32f4xx::can::CAN1, can);
+//! kernel::hil::can::Can::set_receive_client(&stm32f4xx::can::CAN1, can);
+//! ```
+//!
+//! ## Syscall Interface
+//!
+//! - allow `0`: the frame record to send.
+//! - allow `1`: received frame records are copied here; the next frame
+//!   overwrites the last.
+//! - subscribe `0`: a frame was sent, `fn(ReturnCode, 0, 0)`.
+//! - subscribe `1`: a frame arrived, `fn(filter, 0, 0)`.
+//! - command `0`: driver check.
+//! - command `1`: join the bus at bitrate `data`.
+//! - command `2`: leave the bus, once no other app uses it.
+//! - command `3`: send the frame in allow `0`.
+//! - command `4`: add a filter for identifier `data`, with bit 31 set for an
+//!   extended one, and mask `data2`. Returns the filter number.
+//! - command `5`: remove filter `data`.
+
+use core::cell::Cell;
+use core::cmp;
+
+use kernel::common::cells::OptionalCell;
+use kernel::hil::can::{self, Filter, Frame, Id};
+use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
+
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::Can as usize;
+
+/// The length of a frame record.
+pub const RECORD_LEN: usize = 16;
+
+/// The most filters handed out to apps.
+pub const MAX_FILTERS: usize = 16;
+
+const EXTENDED: u32 = 1 << 31;
+const REMOTE: u32 = 1 << 30;
+
+#[derive(Default)]
+pub struct App {
+    tx_callback: Option<Callback>,
+    rx_callback: Option<Callback>,
+    tx_record: Option<AppSlice<Shared, u8>>,
+    rx_record: Option<AppSlice<Shared, u8>>,
+    /// A frame waiting for the one being sent.
+    pending: Option<Frame>,
+    joined: bool,
+}
+
+/// Decodes a frame record.
+fn frame_from_record(record: &[u8]) -> Option<Frame> {
+    if record.len() < RECORD_LEN {
+        return None;
+    }
+    let mut header = [0; 4];
+    header.copy_from_slice(&record[..4]);
+    let header = u32::from_le_bytes(header);
+    let raw_id = header & !(EXTENDED | REMOTE);
+    let id = if header & EXTENDED != 0 {
+        Id::Extended(raw_id)
+    } else {
+        if raw_id > can::MAX_STANDARD_ID as u32 {
+            return None;
+        }
+        Id::Standard(raw_id as u16)
+    };
+    let mut data = [0; can::MAX_DATA_LEN];
+    data.copy_from_slice(&record[8..RECORD_LEN]);
+    Some(Frame {
+        id: id,
+        remote: header & REMOTE != 0,
+        len: record[4],
+        data: data,
+    })
+}
+
+/// Encodes `frame` as a frame record.
+fn record_from_frame(frame: &Frame) -> [u8; RECORD_LEN] {
+    let mut header = match frame.id {
+        Id::Standard(id) => id as u32,
+        Id::Extended(id) => id | EXTENDED,
+    };
+    if frame.remote {
+        header |= REMOTE;
+    }
+    let mut record = [0; RECORD_LEN];
+    record[..4].copy_from_slice(&header.to_le_bytes());
+    record[4] = frame.len;
+    record[8..].copy_from_slice(&frame.data);
+    record
+}
+
+pub struct CanDriver<'a> {
+    can: &'a dyn can::Can<'a>,
+    apps: Grant<App>,
+    bitrate: Cell<u32>,
+    /// The app of the frame being sent.
+    sending_app: OptionalCell<AppId>,
+    /// The app that added each filter.
+    filter_owners: Cell<[Option<AppId>; MAX_FILTERS]>,
+}
+
+impl<'a> CanDriver<'a> {
+    pub fn new(can: &'a dyn can::Can<'a>, apps: Grant<App>) -> CanDriver<'a> {
+        CanDriver {
+            can: can,
+            apps: apps,
+            bitrate: Cell::new(0),
+            sending_app: OptionalCell::empty(),
+            filter_owners: Cell::new([None; MAX_FILTERS]),
+        }
+    }
+
+    /// Whether an app other than `appid` has joined the bus.
+    fn in_use_by_others(&self, appid: AppId) -> bool {
+        self.apps
+            .iter()
+            .any(|cntr| cntr.enter(|app, _| app.joined && app.appid() != appid))
+    }
+
+    fn join(&self, bitrate: u32, appid: AppId) -> ReturnCode {
+        if self.bitrate.get() != 0 {
+            if bitrate != self.bitrate.get() && self.in_use_by_others(appid) {
+                return ReturnCode::EBUSY;
+            }
+            if bitrate == self.bitrate.get() {
+                return self.set_joined(appid, true);
+            }
+        }
+        let rcode = self.can.enable(bitrate);
+        if rcode != ReturnCode::SUCCESS {
+            return rcode;
+        }
+        self.bitrate.set(bitrate);
+        self.set_joined(appid, true)
+    }
+
+    fn leave(&self, appid: AppId) -> ReturnCode {
+        let rcode = self.set_joined(appid, false);
+        if rcode != ReturnCode::SUCCESS || self.in_use_by_others(appid) {
+            return rcode;
+        }
+        self.bitrate.set(0);
+        self.can.disable()
+    }
+
+    fn set_joined(&self, appid: AppId, joined: bool) -> ReturnCode {
+        self.apps
+            .enter(appid, |app, _| {
+                app.joined = joined;
+                ReturnCode::SUCCESS
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    fn send(&self, appid: AppId) -> ReturnCode {
+        let frame = self
+            .apps
+            .enter(appid, |app, _| {
+                if !app.joined {
+                    return Err(ReturnCode::EOFF);
+                }
+                if app.pending.is_some() || self.sending_app.contains(&appid) {
+                    return Err(ReturnCode::EBUSY);
+                }
+                let frame =
+                    app.tx_record
+                        .as_ref()
+                        .ok_or(ReturnCode::ENOMEM)
+                        .and_then(|record| {
+                            frame_from_record(record.as_ref()).ok_or(ReturnCode::EINVAL)
+                        })?;
+                if frame.len as usize > can::MAX_DATA_LEN {
+                    return Err(ReturnCode::ESIZE);
+                }
+                if self.sending_app.is_some() {
+                    app.pending = Some(frame);
+                    return Ok(None);
+                }
+                Ok(Some(frame))
+            })
+            .unwrap_or_else(|err| Err(err.into()));
+        match frame {
+            Ok(Some(frame)) => {
+                let rcode = self.can.send(frame);
+                if rcode == ReturnCode::SUCCESS {
+                    self.sending_app.set(appid);
+                }
+                rcode
+            }
+            Ok(None) => ReturnCode::SUCCESS,
+            Err(rcode) => rcode,
+        }
+    }
+
+    /// Sends the frame of the next app that queued one.
+    fn send_next(&self) {
+        for cntr in self.apps.iter() {
+            let started = cntr.enter(|app, _| {
+                app.pending.take().map_or(false, |frame| {
+                    let rcode = self.can.send(frame);
+                    if rcode == ReturnCode::SUCCESS {
+                        self.sending_app.set(app.appid());
+                        true
+                    } else {
+                        app.tx_callback
+                            .map(|mut cb| cb.schedule(From::from(rcode), 0, 0));
+                        false
+                    }
+                })
+            });
+            if started {
+                break;
+            }
+        }
+    }
+
+    fn add_filter(&self, id: usize, mask: usize, appid: AppId) -> ReturnCode {
+        let id = id as u32;
+        let filter = Filter {
+            id: if id & EXTENDED != 0 {
+                Id::Extended(id & !EXTENDED)
+            } else if id <= can::MAX_STANDARD_ID as u32 {
+                Id::Standard(id as u16)
+            } else {
+                return ReturnCode::EINVAL;
+            },
+            mask: mask as u32,
+        };
+        let count = cmp::min(self.can.filter_count(), MAX_FILTERS);
+        let mut owners = self.filter_owners.get();
+        let index = match owners[..count].iter().position(|owner| owner.is_none()) {
+            Some(index) => index,
+            None => return ReturnCode::ENOMEM,
+        };
+        let rcode = self.can.set_filter(index, Some(filter));
+        if rcode != ReturnCode::SUCCESS {
+            return rcode;
+        }
+        owners[index] = Some(appid);
+        self.filter_owners.set(owners);
+        ReturnCode::SuccessWithValue { value: index }
+    }
+
+    fn remove_filter(&self, index: usize, appid: AppId) -> ReturnCode {
+        let mut owners = self.filter_owners.get();
+        match owners.get(index) {
+            Some(Some(owner)) if *owner == appid => {}
+            _ => return ReturnCode::EINVAL,
+        }
+        let rcode = self.can.set_filter(index, None);
+        if rcode == ReturnCode::SUCCESS {
+            owners[index] = None;
+            self.filter_owners.set(owners);
+        }
+        rcode
+    }
+}
+
+impl can::TransmitClient for CanDriver<'_> {
+    fn transmit_complete(&self, result: ReturnCode) {
+        self.sending_app.take().map(|appid| {
+            let _ = self.apps.enter(appid, |app, _| {
+                app.tx_callback
+                    .map(|mut cb| cb.schedule(From::from(result), 0, 0));
+            });
+        });
+        self.send_next();
+    }
+}
+
+impl can::ReceiveClient for CanDriver<'_> {
+    fn frame_received(&self, frame: Frame, filter: usize) {
+        let owner = match self.filter_owners.get().get(filter) {
+            Some(Some(owner)) => *owner,
+            _ => return,
+        };
+        let _ = self.apps.enter(owner, |app, _| {
+            let record = record_from_frame(&frame);
+            app.rx_record.as_mut().map(|rx_record| {
+                let len = cmp::min(rx_record.len(), RECORD_LEN);
+                rx_record.as_mut()[..len].copy_from_slice(&record[..len]);
+            });
+            app.rx_callback.map(|mut cb| cb.schedule(filter, 0, 0));
+        });
+    }
+}
+
+impl Driver for CanDriver<'_> {
+    fn allow(
+        &self,
+        appid: AppId,
+        allow_num: usize,
+        slice: Option<AppSlice<Shared, u8>>,
+    ) -> ReturnCode {
+        self.apps
+            .enter(appid, |app, _| match allow_num {
+                0 => {
+                    app.tx_record = slice;
+                    ReturnCode::SUCCESS
+                }
+                1 => {
+                    app.rx_record = slice;
+                    ReturnCode::SUCCESS
+                }
+                _ => ReturnCode::ENOSUPPORT,
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        callback: Option<Callback>,
+        appid: AppId,
+    ) -> ReturnCode {
+        self.apps
+            .enter(appid, |app, _| match subscribe_num {
+                0 => {
+                    app.tx_callback = callback;
+                    ReturnCode::SUCCESS
+                }
+                1 => {
+                    app.rx_callback = callback;
+                    ReturnCode::SUCCESS
+                }
+                _ => ReturnCode::ENOSUPPORT,
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    fn command(&self, command_num: usize, data: usize, data2: usize, appid: AppId) -> ReturnCode {
+        match command_num {
+            0 => ReturnCode::SUCCESS,
+            1 => self.join(data as u32, appid),
+            2 => self.leave(appid),
+            3 => self.send(appid),
+            4 => self.add_filter(data, data2, appid),
+            5 => self.remove_filter(data, appid),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
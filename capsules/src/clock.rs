@@ -0,0 +1,260 @@
+//! Provides userspace with access to the calendar date and time.
+//!
+//! `ClockDriver` sits on top of a real-time clock implementing
+//! `hil::date_time::DateTime`. Apps can read and set the clock, and can
+//! subscribe to be told whenever the clock is set, for example to redraw a
+//! watch face. Kernel capsules that learn the time from the network, such as
+//! an SNTP client, use `adjust()`, which also notifies subscribed apps.
+//!
+//! Requests from several apps are queued and served in turn.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let clock = static_init!(
+//!     capsules::clock::ClockDriver<'static, Rtc>,
+//!     capsules::clock::ClockDriver::new(rtc, board_kernel.create_grant(&grant_cap))
+//! );
+//! rtc.set_client(clock);
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! Dates and times are passed packed into two words:
+//!
+//! - date: `year << 9 | month << 5 | day`
+//! - time: `day_of_week << 17 | hour << 12 | minute << 6 | seconds`
+//!
+//! with the field ranges of `DateTimeValues`.
+//!
+//! - subscribe `0`: request done, `fn(result: usize, date: usize, time:
+//!   usize)`. `date` and `time` are only valid for a successful read.
+//! - subscribe `1`: clock changed, `fn(date: usize, time: usize, 0)`, called
+//!   whenever the clock is set by an app or by the kernel.
+//! - command `0`: driver check.
+//! - command `1`: read the date and time.
+//! - command `2`: set the clock to date `data` and time `data2`.
+
+use kernel::common::cells::OptionalCell;
+use kernel::hil::date_time::{DateTime, DateTimeClient, DateTimeValues};
+use kernel::{AppId, Callback, Driver, Grant, ReturnCode};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::Clock as usize;
+
+#[derive(Clone, Copy)]
+enum Operation {
+    Get,
+    Set(DateTimeValues),
+}
+
+/// Who started the operation that is in progress.
+#[derive(Clone, Copy)]
+enum Requester {
+    App(AppId),
+    Kernel,
+}
+
+#[derive(Default)]
+pub struct App {
+    callback: Option<Callback>,
+    change_callback: Option<Callback>,
+    pending: Option<Operation>,
+}
+
+fn pack_date(values: &DateTimeValues) -> usize {
+    (values.year as usize) << 9 | (values.month as usize) << 5 | values.day as usize
+}
+
+fn pack_time(values: &DateTimeValues) -> usize {
+    (values.day_of_week as usize) << 17
+        | (values.hour as usize) << 12
+        | (values.minute as usize) << 6
+        | values.seconds as usize
+}
+
+fn unpack(date: usize, time: usize) -> DateTimeValues {
+    DateTimeValues {
+        year: (date >> 9) as u16,
+        month: ((date >> 5) & 0xf) as u8,
+        day: (date & 0x1f) as u8,
+        day_of_week: ((time >> 17) & 0x7) as u8,
+        hour: ((time >> 12) & 0x1f) as u8,
+        minute: ((time >> 6) & 0x3f) as u8,
+        seconds: (time & 0x3f) as u8,
+    }
+}
+
+pub struct ClockDriver<'a, R: DateTime<'a>> {
+    rtc: &'a R,
+    apps: Grant<App>,
+    in_progress: OptionalCell<(Requester, Operation)>,
+    /// Latest time passed to `adjust()` while the clock was busy.
+    kernel_pending: OptionalCell<DateTimeValues>,
+}
+
+impl<'a, R: DateTime<'a>> ClockDriver<'a, R> {
+    pub fn new(rtc: &'a R, grant: Grant<App>) -> ClockDriver<'a, R> {
+        ClockDriver {
+            rtc: rtc,
+            apps: grant,
+            in_progress: OptionalCell::empty(),
+            kernel_pending: OptionalCell::empty(),
+        }
+    }
+
+    /// Set the clock from within the kernel. If the clock is busy the new
+    /// time is applied once it is free; if `adjust()` is called again before
+    /// then, only the latest time is applied.
+    pub fn adjust(&self, date_time: DateTimeValues) -> ReturnCode {
+        if !date_time.is_valid() {
+            return ReturnCode::EINVAL;
+        }
+        if self.in_progress.is_some() {
+            self.kernel_pending.set(date_time);
+            return ReturnCode::SUCCESS;
+        }
+        self.start(Requester::Kernel, Operation::Set(date_time))
+    }
+
+    fn start(&self, requester: Requester, operation: Operation) -> ReturnCode {
+        let rcode = match operation {
+            Operation::Get => self.rtc.get_date_time(),
+            Operation::Set(values) => self.rtc.set_date_time(values),
+        };
+        if rcode == ReturnCode::SUCCESS {
+            self.in_progress.set((requester, operation));
+        }
+        rcode
+    }
+
+    fn enqueue(&self, appid: AppId, operation: Operation) -> ReturnCode {
+        self.apps
+            .enter(appid, |app, _| {
+                if app.pending.is_some() {
+                    return ReturnCode::EBUSY;
+                }
+                if self.in_progress.is_some() {
+                    app.pending = Some(operation);
+                    return ReturnCode::SUCCESS;
+                }
+                self.start(Requester::App(appid), operation)
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    fn notify(&self, requester: Requester, result: ReturnCode, date: usize, time: usize) {
+        if let Requester::App(appid) = requester {
+            let _ = self.apps.enter(appid, |app, _| {
+                app.callback
+                    .map(|mut cb| cb.schedule(usize::from(result), date, time));
+            });
+        }
+    }
+
+    /// Start the next queued request, if any. Requests from the kernel go
+    /// first.
+    fn check_queue(&self) {
+        if self.in_progress.is_some() {
+            return;
+        }
+        if let Some(values) = self.kernel_pending.take() {
+            if self.start(Requester::Kernel, Operation::Set(values)) == ReturnCode::SUCCESS {
+                return;
+            }
+        }
+        for cntr in self.apps.iter() {
+            let started = cntr.enter(|app, _| {
+                app.pending.take().map_or(false, |operation| {
+                    let rcode = self.start(Requester::App(app.appid()), operation);
+                    if rcode != ReturnCode::SUCCESS {
+                        app.callback
+                            .map(|mut cb| cb.schedule(usize::from(rcode), 0, 0));
+                    }
+                    rcode == ReturnCode::SUCCESS
+                })
+            });
+            if started {
+                break;
+            }
+        }
+    }
+}
+
+impl<'a, R: DateTime<'a>> DateTimeClient for ClockDriver<'a, R> {
+    fn get_date_time_done(&self, date_time: Result<DateTimeValues, ReturnCode>) {
+        self.in_progress
+            .take()
+            .map(|(requester, _)| match date_time {
+                Ok(values) => self.notify(
+                    requester,
+                    ReturnCode::SUCCESS,
+                    pack_date(&values),
+                    pack_time(&values),
+                ),
+                Err(rcode) => self.notify(requester, rcode, 0, 0),
+            });
+        self.check_queue();
+    }
+
+    fn set_date_time_done(&self, result: ReturnCode) {
+        self.in_progress.take().map(|(requester, operation)| {
+            self.notify(requester, result, 0, 0);
+            if let (ReturnCode::SUCCESS, Operation::Set(values)) = (result, operation) {
+                let (date, time) = (pack_date(&values), pack_time(&values));
+                self.apps.each(|app| {
+                    app.change_callback.map(|mut cb| cb.schedule(date, time, 0));
+                });
+            }
+        });
+        self.check_queue();
+    }
+}
+
+impl<'a, R: DateTime<'a>> Driver for ClockDriver<'a, R> {
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        callback: Option<Callback>,
+        app_id: AppId,
+    ) -> ReturnCode {
+        match subscribe_num {
+            0 => self
+                .apps
+                .enter(app_id, |app, _| {
+                    app.callback = callback;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+            1 => self
+                .apps
+                .enter(app_id, |app, _| {
+                    app.change_callback = callback;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    fn command(&self, command_num: usize, data: usize, data2: usize, appid: AppId) -> ReturnCode {
+        match command_num {
+            0 => ReturnCode::SUCCESS,
+            1 => self.enqueue(appid, Operation::Get),
+            2 => {
+                let values = unpack(data, data2);
+                if values.is_valid() {
+                    self.enqueue(appid, Operation::Set(values))
+                } else {
+                    ReturnCode::EINVAL
+                }
+            }
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
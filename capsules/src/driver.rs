@@ -16,9 +16,13 @@ pub enum NUM {
     Adc                   = 0x00005,
     Dac                   = 0x00006,
     AnalogComparator      = 0x00007,
+    Stopwatch             = 0x00009,
 
     // Kernel
     Ipc                   = 0x10000,
+    Energy                = 0x10001,
+    SleepWindow           = 0x10002,
+    ProcessLoader         = 0x10003,
 
     // HW Buses
     Spi                   = 0x20001,
@@ -26,21 +30,42 @@ pub enum NUM {
     I2cMaster             = 0x20003,
     UsbUser               = 0x20005,
     I2cMasterSlave        = 0x20006,
+    UsbHid                = 0x20007,
+    Ctap                  = 0x20008,
+    UsbMidi               = 0x20009,
+    UsbGenericHid         = 0x2000A,
+    Can                   = 0x2000B,
 
     // Radio
     BleAdvertising        = 0x30000,
     Ieee802154            = 0x30001,
     Udp                   = 0x30002,
+    SecureSession         = 0x30003,
+    BleGatt               = 0x30004,
+    BleCentral            = 0x30005,
+    BleBeacon             = 0x30006,
+    LoRaWan               = 0x30007,
+    Fsk                   = 0x30008,
+    Ieee802154Raw         = 0x30009,
+    Ant                   = 0x3000A,
+    NfcTag                = 0x3000B,
+    RadioConfig           = 0x3000C,
+    Ip6Raw                = 0x3000D,
+    MqttSn                = 0x3000E,
 
     // Cryptography
     Rng                   = 0x40001,
     Crc                   = 0x40002,
     Hmac                  = 0x40003,
+    Attestation           = 0x40004,
 
     // Storage
     AppFlash              = 0x50000,
     NvmStorage            = 0x50001,
     SdCard                = 0x50002,
+    KVStore               = 0x50003,
+    Fat                   = 0x50004,
+    LogStorage            = 0x50005,
 
     // Sensors
     Temperature           = 0x60000,
@@ -48,6 +73,7 @@ pub enum NUM {
     AmbientLight          = 0x60002,
     NINEDOF               = 0x60004,
     Proximity             = 0x60005,
+    Battery               = 0x60006,
 
     // Sensor ICs
     Tsl2561               = 0x70000,
@@ -70,6 +96,7 @@ pub enum NUM {
     // Misc
     Buzzer                = 0x90000,
     Screen                = 0x90001,
-    Touch                 = 0x90002
+    Touch                 = 0x90002,
+    Clock                 = 0x90003
 }
 }
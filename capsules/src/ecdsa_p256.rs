@@ -0,0 +1,1318 @@
+//! Software ECDSA signatures and ECDH over NIST P-256.
+//!
+//! `SoftwareEcdsaP256` implements `hil::public_key_crypto::SignatureVerify`
+//! for chips without a public key accelerator. Verification runs in a
+//! deferred call, as one long computation: it takes tens of milliseconds on
+//! a Cortex-M4, during which the kernel does nothing else, so it suits
+//! occasional checks such as those of process images. `verify_signature()`
+//! verifies synchronously, for code that cannot wait for a callback.
+//!
+//! `SoftwareEcdsaP256Signer` implements `SignatureSign` the same way, with a
+//! private key given by the board. Its nonces are derived deterministically
+//! as in RFC 6979, and `sign_signature()` signs synchronously.
+//!
+//! `SoftwareEcdhP256` implements `hil::ecdh::EcdhP256` the same way, for
+//! BLE Secure Connections pairing, drawing its private keys from an `Rng`.
+//! Each key pair or shared secret takes about as long as a verification.
+//!
+//! Numbers are eight 32-bit little-endian limbs, and are multiplied in
+//! Montgomery form, modulo the prime of the field or the order of the curve.
+//! Points are in Jacobian coordinates. The modular arithmetic does not
+//! branch on its operands, and private keys and nonces only go through it
+//! and a Montgomery ladder that runs the same steps for every key; the rest
+//! of verification computes with public values only, and does not run in
+//! constant time.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let ecdsa = static_init!(
+//!     capsules::ecdsa_p256::SoftwareEcdsaP256<'static>,
+//!     capsules::ecdsa_p256::SoftwareEcdsaP256::new(dynamic_deferred_caller)
+//! );
+//! ecdsa.initialize_callback_handle(
+//!     dynamic_deferred_caller
+//!         .register(ecdsa)
+//!         .expect("no deferred call slot available for ECDSA"),
+//! );
+//! ecdsa.set_public_key(&APP_SIGNING_KEY);
+//!
+//! let signer = static_init!(
+//!     capsules::ecdsa_p256::SoftwareEcdsaP256Signer<'static>,
+//!     capsules::ecdsa_p256::SoftwareEcdsaP256Signer::new(&DEVICE_KEY, dynamic_deferred_caller)
+//! );
+//! signer.initialize_callback_handle(
+//!     dynamic_deferred_caller
+//!         .register(signer)
+//!         .expect("no deferred call slot available for ECDSA signing"),
+//! );
+//!
+//! let ecdh = static_init!(
+//!     capsules::ecdsa_p256::SoftwareEcdhP256<'static>,
+//!     capsules::ecdsa_p256::SoftwareEcdhP256::new(rng, dynamic_deferred_caller)
+//! );
+//! ecdh.initialize_callback_handle(
+//!     dynamic_deferred_caller
+//!         .register(ecdh)
+//!         .expect("no deferred call slot available for ECDH"),
+//! );
+//! rng.set_client(ecdh);
+//! ```
+
+use crate::net::dtls::sha256::hmac_sha256;
+use core::cell::Cell;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::dynamic_deferred_call::{
+    DeferredCallHandle, DynamicDeferredCall, DynamicDeferredCallClient,
+};
+use kernel::hil::ecdh::{self, EcdhP256};
+use kernel::hil::public_key_crypto::{ClientSign, ClientVerify, SignatureSign, SignatureVerify};
+use kernel::hil::rng::{self, Rng};
+use kernel::ReturnCode;
+
+pub const PRIVATE_KEY_LEN: usize = 32;
+pub const PUBLIC_KEY_LEN: usize = 64;
+pub const HASH_LEN: usize = 32;
+pub const SIGNATURE_LEN: usize = 64;
+
+type U256 = [u32; 8];
+
+/// A modulus, with `-m^-1 mod 2^32` and `2^512 mod m` for Montgomery
+/// multiplication.
+struct Modulus {
+    m: U256,
+    inv: u32,
+    r2: U256,
+}
+
+/// The prime of the field.
+const P: Modulus = Modulus {
+    m: [
+        0xffffffff, 0xffffffff, 0xffffffff, 0x00000000, 0x00000000, 0x00000000, 0x00000001,
+        0xffffffff,
+    ],
+    inv: 0x00000001,
+    r2: [
+        0x00000003, 0x00000000, 0xffffffff, 0xfffffffb, 0xfffffffe, 0xffffffff, 0xfffffffd,
+        0x00000004,
+    ],
+};
+
+/// The order of the curve.
+const N: Modulus = Modulus {
+    m: [
+        0xfc632551, 0xf3b9cac2, 0xa7179e84, 0xbce6faad, 0xffffffff, 0xffffffff, 0x00000000,
+        0xffffffff,
+    ],
+    inv: 0xee00bc4f,
+    r2: [
+        0xbe79eea2, 0x83244c95, 0x49bd6fa6, 0x4699799c, 0x2b6bec59, 0x2845b239, 0xf3d95620,
+        0x66e12d94,
+    ],
+};
+
+const B: U256 = [
+    0x27d2604b, 0x3bce3c3e, 0xcc53b0f6, 0x651d06b0, 0x769886bc, 0xb3ebbd55, 0xaa3a93e7, 0x5ac635d8,
+];
+
+const GX: U256 = [
+    0xd898c296, 0xf4a13945, 0x2deb33a0, 0x77037d81, 0x63a440f2, 0xf8bce6e5, 0xe12c4247, 0x6b17d1f2,
+];
+
+const GY: U256 = [
+    0x37bf51f5, 0xcbb64068, 0x6b315ece, 0x2bce3357, 0x7c0f9e16, 0x8ee7eb4a, 0xfe1a7f9b, 0x4fe342e2,
+];
+
+const ZERO: U256 = [0; 8];
+const ONE: U256 = [1, 0, 0, 0, 0, 0, 0, 0];
+
+fn from_be_bytes(bytes: &[u8]) -> U256 {
+    let mut x = ZERO;
+    for (i, limb) in x.iter_mut().enumerate() {
+        let start = 28 - 4 * i;
+        *limb = u32::from_be_bytes([
+            bytes[start],
+            bytes[start + 1],
+            bytes[start + 2],
+            bytes[start + 3],
+        ]);
+    }
+    x
+}
+
+fn is_zero(a: &U256) -> bool {
+    a.iter().all(|&limb| limb == 0)
+}
+
+fn less_than(a: &U256, b: &U256) -> bool {
+    for i in (0..8).rev() {
+        if a[i] != b[i] {
+            return a[i] < b[i];
+        }
+    }
+    false
+}
+
+/// Returns `a + b`, and whether it carried.
+fn add(a: &U256, b: &U256) -> (U256, bool) {
+    let mut sum = ZERO;
+    let mut carry = 0u64;
+    for i in 0..8 {
+        let s = a[i] as u64 + b[i] as u64 + carry;
+        sum[i] = s as u32;
+        carry = s >> 32;
+    }
+    (sum, carry != 0)
+}
+
+/// Returns `a - b`, and whether it borrowed.
+fn sub(a: &U256, b: &U256) -> (U256, bool) {
+    let mut difference = ZERO;
+    let mut borrow = 0i64;
+    for i in 0..8 {
+        let d = a[i] as i64 - b[i] as i64 + borrow;
+        difference[i] = d as u32;
+        borrow = d >> 32;
+    }
+    (difference, borrow != 0)
+}
+
+/// Returns all ones if `condition` holds, and zero otherwise.
+fn mask(condition: bool) -> u32 {
+    (condition as u32).wrapping_neg()
+}
+
+/// Returns `b` where `mask` is all ones and `a` where it is zero, without
+/// branching on it.
+fn select(a: &U256, b: &U256, mask: u32) -> U256 {
+    let mut selected = ZERO;
+    for i in 0..8 {
+        selected[i] = (a[i] & !mask) | (b[i] & mask);
+    }
+    selected
+}
+
+impl Modulus {
+    /// Returns `a mod m`, for `a < 2m`.
+    fn reduce(&self, a: &U256) -> U256 {
+        let (difference, borrow) = sub(a, &self.m);
+        select(&difference, a, mask(borrow))
+    }
+
+    fn add(&self, a: &U256, b: &U256) -> U256 {
+        let (sum, carry) = add(a, b);
+        let (difference, borrow) = sub(&sum, &self.m);
+        select(&difference, &sum, mask(borrow & !carry))
+    }
+
+    fn sub(&self, a: &U256, b: &U256) -> U256 {
+        let (difference, borrow) = sub(a, b);
+        add(&difference, &select(&ZERO, &self.m, mask(borrow))).0
+    }
+
+    /// Returns `a * b / 2^256 mod m`.
+    fn mul(&self, a: &U256, b: &U256) -> U256 {
+        let mut t = [0u32; 10];
+        for i in 0..8 {
+            let mut carry = 0u64;
+            for j in 0..8 {
+                let s = t[j] as u64 + a[j] as u64 * b[i] as u64 + carry;
+                t[j] = s as u32;
+                carry = s >> 32;
+            }
+            let s = t[8] as u64 + carry;
+            t[8] = s as u32;
+            t[9] = (s >> 32) as u32;
+
+            // Add a multiple of m that clears the lowest limb, and shift
+            let u = t[0].wrapping_mul(self.inv);
+            let mut carry = (t[0] as u64 + u as u64 * self.m[0] as u64) >> 32;
+            for j in 1..8 {
+                let s = t[j] as u64 + u as u64 * self.m[j] as u64 + carry;
+                t[j - 1] = s as u32;
+                carry = s >> 32;
+            }
+            let s = t[8] as u64 + carry;
+            t[7] = s as u32;
+            t[8] = t[9] + (s >> 32) as u32;
+            t[9] = 0;
+        }
+        let mut result = ZERO;
+        result.copy_from_slice(&t[..8]);
+        let (difference, borrow) = sub(&result, &self.m);
+        select(&difference, &result, mask(borrow & (t[8] == 0)))
+    }
+
+    fn to_montgomery(&self, a: &U256) -> U256 {
+        self.mul(a, &self.r2)
+    }
+
+    fn from_montgomery(&self, a: &U256) -> U256 {
+        self.mul(a, &ONE)
+    }
+
+    /// Returns `1 / a`, in Montgomery form, as `a^(m - 2)`.
+    fn invert(&self, a: &U256) -> U256 {
+        let exponent = sub(&self.m, &[2, 0, 0, 0, 0, 0, 0, 0]).0;
+        let mut result = self.to_montgomery(&ONE);
+        for i in (0..256).rev() {
+            result = self.mul(&result, &result);
+            if (exponent[i / 32] >> (i % 32)) & 1 == 1 {
+                result = self.mul(&result, a);
+            }
+        }
+        result
+    }
+}
+
+/// A point in Jacobian coordinates, in Montgomery form. `z` is zero for the
+/// point at infinity.
+#[derive(Copy, Clone)]
+struct Point {
+    x: U256,
+    y: U256,
+    z: U256,
+}
+
+impl Point {
+    const INFINITY: Point = Point {
+        x: ZERO,
+        y: ZERO,
+        z: ZERO,
+    };
+
+    /// Returns the point with affine coordinates `x` and `y`, if it is on
+    /// the curve.
+    fn from_affine(x: &U256, y: &U256) -> Option<Point> {
+        if !less_than(x, &P.m) || !less_than(y, &P.m) {
+            return None;
+        }
+        let x = P.to_montgomery(x);
+        let y = P.to_montgomery(y);
+        // y^2 = x^3 - 3x + b
+        let three_x = P.add(&P.add(&x, &x), &x);
+        let right = P.add(
+            &P.sub(&P.mul(&P.mul(&x, &x), &x), &three_x),
+            &P.to_montgomery(&B),
+        );
+        if P.mul(&y, &y) != right {
+            return None;
+        }
+        Some(Point {
+            x: x,
+            y: y,
+            z: P.to_montgomery(&ONE),
+        })
+    }
+
+    fn is_infinity(&self) -> bool {
+        is_zero(&self.z)
+    }
+
+    /// Returns the affine X coordinate, not in Montgomery form.
+    fn affine_x(&self) -> U256 {
+        let z_inv = P.invert(&self.z);
+        P.from_montgomery(&P.mul(&self.x, &P.mul(&z_inv, &z_inv)))
+    }
+
+    /// Returns the affine coordinates, not in Montgomery form.
+    fn affine(&self) -> (U256, U256) {
+        let z_inv = P.invert(&self.z);
+        let z_inv2 = P.mul(&z_inv, &z_inv);
+        (
+            P.from_montgomery(&P.mul(&self.x, &z_inv2)),
+            P.from_montgomery(&P.mul(&self.y, &P.mul(&z_inv2, &z_inv))),
+        )
+    }
+
+    /// Swaps `a` and `b` where `mask` is all ones, without branching on it.
+    fn swap(a: &mut Point, b: &mut Point, mask: u32) {
+        let (x, y, z) = (a.x, a.y, a.z);
+        a.x = select(&a.x, &b.x, mask);
+        a.y = select(&a.y, &b.y, mask);
+        a.z = select(&a.z, &b.z, mask);
+        b.x = select(&b.x, &x, mask);
+        b.y = select(&b.y, &y, mask);
+        b.z = select(&b.z, &z, mask);
+    }
+
+    /// Returns `k` times this point, for a secret `k` from 1 to `n - 1`,
+    /// with a Montgomery ladder whose steps do not depend on `k`.
+    fn multiply_secret(&self, k: &U256) -> Point {
+        // k + n or k + 2n, whichever is from 2^256 to 2^257, has the same
+        // multiples as k and the same length for every k. Its top bit
+        // starts the ladder.
+        let (k_n, carry) = add(k, &N.m);
+        let k_2n = add(&k_n, &N.m).0;
+        let k = select(&k_2n, &k_n, mask(carry));
+        let mut r0 = *self;
+        let mut r1 = self.double();
+        // r1 - r0 is this point throughout, so neither the additions nor
+        // the doublings meet the exceptional cases but with negligible
+        // probability.
+        for i in (0..256).rev() {
+            let bit = ((k[i / 32] >> (i % 32)) & 1).wrapping_neg();
+            Point::swap(&mut r0, &mut r1, bit);
+            r1 = r0.add(&r1);
+            r0 = r0.double();
+            Point::swap(&mut r0, &mut r1, bit);
+        }
+        r0
+    }
+
+    fn double(&self) -> Point {
+        if self.is_infinity() || is_zero(&self.y) {
+            return Point::INFINITY;
+        }
+        let delta = P.mul(&self.z, &self.z);
+        let gamma = P.mul(&self.y, &self.y);
+        let beta = P.mul(&self.x, &gamma);
+        // alpha = 3 (x - delta) (x + delta), as a = -3
+        let t = P.mul(&P.sub(&self.x, &delta), &P.add(&self.x, &delta));
+        let alpha = P.add(&P.add(&t, &t), &t);
+        let beta4 = P.add(&P.add(&beta, &beta), &P.add(&beta, &beta));
+        let x = P.sub(&P.mul(&alpha, &alpha), &P.add(&beta4, &beta4));
+        let yz = P.add(&self.y, &self.z);
+        let z = P.sub(&P.sub(&P.mul(&yz, &yz), &gamma), &delta);
+        let gamma2 = P.mul(&gamma, &gamma);
+        let gamma4 = P.add(&P.add(&gamma2, &gamma2), &P.add(&gamma2, &gamma2));
+        let y = P.sub(&P.mul(&alpha, &P.sub(&beta4, &x)), &P.add(&gamma4, &gamma4));
+        Point { x: x, y: y, z: z }
+    }
+
+    fn add(&self, other: &Point) -> Point {
+        if self.is_infinity() {
+            return *other;
+        }
+        if other.is_infinity() {
+            return *self;
+        }
+        let z1z1 = P.mul(&self.z, &self.z);
+        let z2z2 = P.mul(&other.z, &other.z);
+        let u1 = P.mul(&self.x, &z2z2);
+        let u2 = P.mul(&other.x, &z1z1);
+        let s1 = P.mul(&P.mul(&self.y, &other.z), &z2z2);
+        let s2 = P.mul(&P.mul(&other.y, &self.z), &z1z1);
+        let h = P.sub(&u2, &u1);
+        let r = P.sub(&s2, &s1);
+        if is_zero(&h) {
+            return if is_zero(&r) {
+                self.double()
+            } else {
+                Point::INFINITY
+            };
+        }
+        let r = P.add(&r, &r);
+        let h2 = P.add(&h, &h);
+        let i = P.mul(&h2, &h2);
+        let j = P.mul(&h, &i);
+        let v = P.mul(&u1, &i);
+        let x = P.sub(&P.sub(&P.mul(&r, &r), &j), &P.add(&v, &v));
+        let s1j = P.mul(&s1, &j);
+        let y = P.sub(&P.mul(&r, &P.sub(&v, &x)), &P.add(&s1j, &s1j));
+        let z1z2 = P.add(&self.z, &other.z);
+        let z = P.mul(&P.sub(&P.sub(&P.mul(&z1z2, &z1z2), &z1z1), &z2z2), &h);
+        Point { x: x, y: y, z: z }
+    }
+}
+
+/// Returns whether `signature` is a valid signature over `hash` with the
+/// private key of `public_key`, in the formats of `public_key_crypto`.
+pub fn verify_signature(
+    public_key: &[u8; PUBLIC_KEY_LEN],
+    hash: &[u8; HASH_LEN],
+    signature: &[u8; SIGNATURE_LEN],
+) -> bool {
+    let q = match Point::from_affine(
+        &from_be_bytes(&public_key[..32]),
+        &from_be_bytes(&public_key[32..]),
+    ) {
+        Some(q) => q,
+        None => return false,
+    };
+    let r = from_be_bytes(&signature[..32]);
+    let s = from_be_bytes(&signature[32..]);
+    if is_zero(&r) || is_zero(&s) || !less_than(&r, &N.m) || !less_than(&s, &N.m) {
+        return false;
+    }
+    let e = N.reduce(&from_be_bytes(hash));
+
+    // u1 = e / s and u2 = r / s, modulo the order
+    let w = N.invert(&N.to_montgomery(&s));
+    let u1 = N.from_montgomery(&N.mul(&N.to_montgomery(&e), &w));
+    let u2 = N.from_montgomery(&N.mul(&N.to_montgomery(&r), &w));
+
+    // u1 G + u2 Q, with the doublings shared
+    let g = match Point::from_affine(&GX, &GY) {
+        Some(g) => g,
+        None => return false,
+    };
+    let gq = g.add(&q);
+    let mut point = Point::INFINITY;
+    for i in (0..256).rev() {
+        point = point.double();
+        let bit1 = (u1[i / 32] >> (i % 32)) & 1 == 1;
+        let bit2 = (u2[i / 32] >> (i % 32)) & 1 == 1;
+        point = match (bit1, bit2) {
+            (true, true) => point.add(&gq),
+            (true, false) => point.add(&g),
+            (false, true) => point.add(&q),
+            (false, false) => point,
+        };
+    }
+    if point.is_infinity() {
+        return false;
+    }
+    N.reduce(&point.affine_x()) == r
+}
+
+/// Returns the public key of `private_key`, or `None` if it is not a number
+/// from 1 to `n - 1`.
+pub fn public_key(private_key: &[u8; PRIVATE_KEY_LEN]) -> Option<[u8; PUBLIC_KEY_LEN]> {
+    let d = from_be_bytes(private_key);
+    if is_zero(&d) || !less_than(&d, &N.m) {
+        return None;
+    }
+    let (x, y) = Point::from_affine(&GX, &GY)?.multiply_secret(&d).affine();
+    let mut public_key = [0; PUBLIC_KEY_LEN];
+    to_be_bytes(&x, &mut public_key[..32]);
+    to_be_bytes(&y, &mut public_key[32..]);
+    Some(public_key)
+}
+
+/// Signs `hash` with `private_key` into `signature`, in the formats of
+/// `public_key_crypto`. The nonce is derived from the key and the hash as in
+/// RFC 6979, so signing needs no random numbers. Returns whether
+/// `private_key` is a number from 1 to `n - 1`.
+pub fn sign_signature(
+    private_key: &[u8; PRIVATE_KEY_LEN],
+    hash: &[u8; HASH_LEN],
+    signature: &mut [u8; SIGNATURE_LEN],
+) -> bool {
+    let d = from_be_bytes(private_key);
+    if is_zero(&d) || !less_than(&d, &N.m) {
+        return false;
+    }
+    let g = match Point::from_affine(&GX, &GY) {
+        Some(g) => g,
+        None => return false,
+    };
+    let e = N.reduce(&from_be_bytes(hash));
+    let mut h1 = [0; HASH_LEN];
+    to_be_bytes(&e, &mut h1);
+
+    // RFC 6979, 3.2
+    let mut v = [0x01; 32];
+    let mut k = hmac_sha256(&[0; 32], &[&v, &[0x00], private_key, &h1]);
+    v = hmac_sha256(&k, &[&v]);
+    k = hmac_sha256(&k, &[&v, &[0x01], private_key, &h1]);
+    v = hmac_sha256(&k, &[&v]);
+    loop {
+        v = hmac_sha256(&k, &[&v]);
+        let nonce = from_be_bytes(&v);
+        if !is_zero(&nonce) && less_than(&nonce, &N.m) {
+            let r = N.reduce(&g.multiply_secret(&nonce).affine_x());
+            // s = (e + r d) / k, modulo the order
+            let rd = N.mul(&N.to_montgomery(&r), &N.to_montgomery(&d));
+            let sum = N.add(&N.to_montgomery(&e), &rd);
+            let s = N.from_montgomery(&N.mul(&N.invert(&N.to_montgomery(&nonce)), &sum));
+            if !is_zero(&r) && !is_zero(&s) {
+                to_be_bytes(&r, &mut signature[..32]);
+                to_be_bytes(&s, &mut signature[32..]);
+                return true;
+            }
+        }
+        k = hmac_sha256(&k, &[&v, &[0x00]]);
+        v = hmac_sha256(&k, &[&v]);
+    }
+}
+
+pub struct SoftwareEcdsaP256<'a> {
+    client: OptionalCell<&'a dyn ClientVerify>,
+    public_key: OptionalCell<[u8; PUBLIC_KEY_LEN]>,
+    hash: TakeCell<'static, [u8]>,
+    signature: TakeCell<'static, [u8]>,
+    /// Whether a verification is waiting for the deferred call.
+    pending: Cell<bool>,
+    deferred_caller: &'a DynamicDeferredCall,
+    handle: OptionalCell<DeferredCallHandle>,
+}
+
+impl<'a> SoftwareEcdsaP256<'a> {
+    pub fn new(deferred_caller: &'a DynamicDeferredCall) -> SoftwareEcdsaP256<'a> {
+        SoftwareEcdsaP256 {
+            client: OptionalCell::empty(),
+            public_key: OptionalCell::empty(),
+            hash: TakeCell::empty(),
+            signature: TakeCell::empty(),
+            pending: Cell::new(false),
+            deferred_caller: deferred_caller,
+            handle: OptionalCell::empty(),
+        }
+    }
+
+    pub fn initialize_callback_handle(&self, handle: DeferredCallHandle) {
+        self.handle.replace(handle);
+    }
+}
+
+impl<'a> SignatureVerify<'a> for SoftwareEcdsaP256<'a> {
+    fn set_client(&self, client: &'a dyn ClientVerify) {
+        self.client.set(client);
+    }
+
+    fn public_key_len(&self) -> usize {
+        PUBLIC_KEY_LEN
+    }
+
+    fn hash_len(&self) -> usize {
+        HASH_LEN
+    }
+
+    fn signature_len(&self) -> usize {
+        SIGNATURE_LEN
+    }
+
+    fn set_public_key(&self, key: &[u8]) -> ReturnCode {
+        if key.len() != PUBLIC_KEY_LEN {
+            return ReturnCode::ESIZE;
+        }
+        if Point::from_affine(&from_be_bytes(&key[..32]), &from_be_bytes(&key[32..])).is_none() {
+            return ReturnCode::EINVAL;
+        }
+        let mut public_key = [0; PUBLIC_KEY_LEN];
+        public_key.copy_from_slice(key);
+        self.public_key.set(public_key);
+        ReturnCode::SUCCESS
+    }
+
+    fn verify(
+        &self,
+        hash: &'static mut [u8],
+        signature: &'static mut [u8],
+    ) -> Result<(), (ReturnCode, &'static mut [u8], &'static mut [u8])> {
+        if self.pending.get() {
+            return Err((ReturnCode::EBUSY, hash, signature));
+        }
+        if hash.len() < HASH_LEN || signature.len() < SIGNATURE_LEN {
+            return Err((ReturnCode::ESIZE, hash, signature));
+        }
+        if self.public_key.is_none() {
+            return Err((ReturnCode::EOFF, hash, signature));
+        }
+        match self.handle.map(|handle| *handle) {
+            Some(handle) => {
+                self.hash.replace(hash);
+                self.signature.replace(signature);
+                self.pending.set(true);
+                self.deferred_caller.set(handle);
+                Ok(())
+            }
+            None => Err((ReturnCode::FAIL, hash, signature)),
+        }
+    }
+}
+
+impl<'a> DynamicDeferredCallClient for SoftwareEcdsaP256<'a> {
+    fn call(&self, _handle: DeferredCallHandle) {
+        if !self.pending.get() {
+            return;
+        }
+        self.pending.set(false);
+        if let (Some(hash), Some(signature)) = (self.hash.take(), self.signature.take()) {
+            let valid = self.public_key.map_or(false, |public_key| {
+                let mut digest = [0; HASH_LEN];
+                digest.copy_from_slice(&hash[..HASH_LEN]);
+                let mut sig = [0; SIGNATURE_LEN];
+                sig.copy_from_slice(&signature[..SIGNATURE_LEN]);
+                verify_signature(public_key, &digest, &sig)
+            });
+            self.client
+                .map(move |client| client.verification_done(Ok(valid), hash, signature));
+        }
+    }
+}
+
+/// Signs with a private key given by the board, such as a device key for
+/// attestation.
+pub struct SoftwareEcdsaP256Signer<'a> {
+    client: OptionalCell<&'a dyn ClientSign>,
+    private_key: &'a [u8; PRIVATE_KEY_LEN],
+    hash: TakeCell<'static, [u8]>,
+    signature: TakeCell<'static, [u8]>,
+    /// Whether a signature is waiting for the deferred call.
+    pending: Cell<bool>,
+    deferred_caller: &'a DynamicDeferredCall,
+    handle: OptionalCell<DeferredCallHandle>,
+}
+
+impl<'a> SoftwareEcdsaP256Signer<'a> {
+    pub fn new(
+        private_key: &'a [u8; PRIVATE_KEY_LEN],
+        deferred_caller: &'a DynamicDeferredCall,
+    ) -> SoftwareEcdsaP256Signer<'a> {
+        SoftwareEcdsaP256Signer {
+            client: OptionalCell::empty(),
+            private_key: private_key,
+            hash: TakeCell::empty(),
+            signature: TakeCell::empty(),
+            pending: Cell::new(false),
+            deferred_caller: deferred_caller,
+            handle: OptionalCell::empty(),
+        }
+    }
+
+    pub fn initialize_callback_handle(&self, handle: DeferredCallHandle) {
+        self.handle.replace(handle);
+    }
+}
+
+impl<'a> SignatureSign<'a> for SoftwareEcdsaP256Signer<'a> {
+    fn set_client(&self, client: &'a dyn ClientSign) {
+        self.client.set(client);
+    }
+
+    fn hash_len(&self) -> usize {
+        HASH_LEN
+    }
+
+    fn signature_len(&self) -> usize {
+        SIGNATURE_LEN
+    }
+
+    /// Signing completes with `EINVAL` if the private key is not a number
+    /// from 1 to `n - 1`.
+    fn sign(
+        &self,
+        hash: &'static mut [u8],
+        signature: &'static mut [u8],
+    ) -> Result<(), (ReturnCode, &'static mut [u8], &'static mut [u8])> {
+        if self.pending.get() {
+            return Err((ReturnCode::EBUSY, hash, signature));
+        }
+        if hash.len() < HASH_LEN || signature.len() < SIGNATURE_LEN {
+            return Err((ReturnCode::ESIZE, hash, signature));
+        }
+        match self.handle.map(|handle| *handle) {
+            Some(handle) => {
+                self.hash.replace(hash);
+                self.signature.replace(signature);
+                self.pending.set(true);
+                self.deferred_caller.set(handle);
+                Ok(())
+            }
+            None => Err((ReturnCode::FAIL, hash, signature)),
+        }
+    }
+}
+
+impl<'a> DynamicDeferredCallClient for SoftwareEcdsaP256Signer<'a> {
+    fn call(&self, _handle: DeferredCallHandle) {
+        if !self.pending.get() {
+            return;
+        }
+        self.pending.set(false);
+        if let (Some(hash), Some(signature)) = (self.hash.take(), self.signature.take()) {
+            let mut digest = [0; HASH_LEN];
+            digest.copy_from_slice(&hash[..HASH_LEN]);
+            let mut sig = [0; SIGNATURE_LEN];
+            let result = if sign_signature(self.private_key, &digest, &mut sig) {
+                signature[..SIGNATURE_LEN].copy_from_slice(&sig);
+                ReturnCode::SUCCESS
+            } else {
+                ReturnCode::EINVAL
+            };
+            self.client
+                .map(move |client| client.signing_done(result, hash, signature));
+        }
+    }
+}
+
+fn to_be_bytes(a: &U256, bytes: &mut [u8]) {
+    for (i, limb) in a.iter().enumerate() {
+        let start = 28 - 4 * i;
+        bytes[start..start + 4].copy_from_slice(&limb.to_be_bytes());
+    }
+}
+
+pub struct SoftwareEcdhP256<'a> {
+    rng: &'a dyn Rng<'a>,
+    client: OptionalCell<&'a dyn ecdh::Client>,
+    /// The private key, not in Montgomery form.
+    private_key: OptionalCell<U256>,
+    /// The random words drawn so far for the next private key.
+    candidate: Cell<U256>,
+    candidate_words: Cell<usize>,
+    /// The buffers of the operations waiting for the deferred call.
+    public_key: TakeCell<'static, [u8; PUBLIC_KEY_LEN]>,
+    peer_key: Cell<[u8; PUBLIC_KEY_LEN]>,
+    secret: TakeCell<'static, [u8; 32]>,
+    deferred_caller: &'a DynamicDeferredCall,
+    handle: OptionalCell<DeferredCallHandle>,
+}
+
+impl<'a> SoftwareEcdhP256<'a> {
+    pub fn new(
+        rng: &'a dyn Rng<'a>,
+        deferred_caller: &'a DynamicDeferredCall,
+    ) -> SoftwareEcdhP256<'a> {
+        SoftwareEcdhP256 {
+            rng: rng,
+            client: OptionalCell::empty(),
+            private_key: OptionalCell::empty(),
+            candidate: Cell::new(ZERO),
+            candidate_words: Cell::new(0),
+            public_key: TakeCell::empty(),
+            peer_key: Cell::new([0; PUBLIC_KEY_LEN]),
+            secret: TakeCell::empty(),
+            deferred_caller: deferred_caller,
+            handle: OptionalCell::empty(),
+        }
+    }
+
+    pub fn initialize_callback_handle(&self, handle: DeferredCallHandle) {
+        self.handle.replace(handle);
+    }
+
+    fn schedule(&self) -> bool {
+        self.handle
+            .map_or(false, |handle| self.deferred_caller.set(*handle).is_some())
+    }
+
+    /// Computes the public key of the drawn private key, which replaces the
+    /// current one.
+    fn finish_key_pair(&self, public_key: &'static mut [u8; PUBLIC_KEY_LEN]) {
+        let private_key = self.candidate.replace(ZERO);
+        self.candidate_words.set(0);
+        let result = match Point::from_affine(&GX, &GY) {
+            Some(g) => {
+                let (x, y) = g.multiply_secret(&private_key).affine();
+                to_be_bytes(&x, &mut public_key[..32]);
+                to_be_bytes(&y, &mut public_key[32..]);
+                self.private_key.set(private_key);
+                ReturnCode::SUCCESS
+            }
+            None => ReturnCode::FAIL,
+        };
+        self.client
+            .map(move |client| client.key_pair_generated(public_key, result));
+    }
+
+    fn finish_shared_secret(&self, secret: &'static mut [u8; 32]) {
+        let peer_key = self.peer_key.get();
+        let peer = Point::from_affine(
+            &from_be_bytes(&peer_key[..32]),
+            &from_be_bytes(&peer_key[32..]),
+        );
+        let result = match (peer, self.private_key.map(|key| *key)) {
+            (Some(peer), Some(private_key)) => {
+                let point = peer.multiply_secret(&private_key);
+                if point.is_infinity() {
+                    ReturnCode::EINVAL
+                } else {
+                    to_be_bytes(&point.affine_x(), &mut secret[..]);
+                    ReturnCode::SUCCESS
+                }
+            }
+            (None, _) => ReturnCode::EINVAL,
+            (_, None) => ReturnCode::EOFF,
+        };
+        self.client
+            .map(move |client| client.shared_secret_computed(secret, result));
+    }
+}
+
+impl<'a> EcdhP256<'a> for SoftwareEcdhP256<'a> {
+    fn set_client(&self, client: &'a dyn ecdh::Client) {
+        self.client.set(client);
+    }
+
+    /// The private key is drawn from the `Rng` given to `new()`, whose
+    /// client this has to be.
+    fn generate_key_pair(
+        &self,
+        public_key: &'static mut [u8; PUBLIC_KEY_LEN],
+    ) -> Result<(), (ReturnCode, &'static mut [u8; PUBLIC_KEY_LEN])> {
+        if self.public_key.is_some() {
+            return Err((ReturnCode::EBUSY, public_key));
+        }
+        self.candidate_words.set(0);
+        let rcode = self.rng.get();
+        if rcode != ReturnCode::SUCCESS {
+            return Err((rcode, public_key));
+        }
+        self.public_key.replace(public_key);
+        Ok(())
+    }
+
+    /// Returns `EOFF` until a key pair is generated.
+    fn compute_shared_secret(
+        &self,
+        peer_public_key: &[u8; PUBLIC_KEY_LEN],
+        secret: &'static mut [u8; 32],
+    ) -> Result<(), (ReturnCode, &'static mut [u8; 32])> {
+        if self.secret.is_some() {
+            return Err((ReturnCode::EBUSY, secret));
+        }
+        if self.private_key.is_none() {
+            return Err((ReturnCode::EOFF, secret));
+        }
+        if !self.schedule() {
+            return Err((ReturnCode::FAIL, secret));
+        }
+        self.peer_key.set(*peer_public_key);
+        self.secret.replace(secret);
+        Ok(())
+    }
+}
+
+impl<'a> rng::Client for SoftwareEcdhP256<'a> {
+    fn randomness_available(
+        &self,
+        randomness: &mut dyn Iterator<Item = u32>,
+        error: ReturnCode,
+    ) -> rng::Continue {
+        if self.public_key.is_none() {
+            return rng::Continue::Done;
+        }
+        if error != ReturnCode::SUCCESS {
+            self.candidate.set(ZERO);
+            self.public_key.take().map(|public_key| {
+                self.client
+                    .map(move |client| client.key_pair_generated(public_key, error))
+            });
+            return rng::Continue::Done;
+        }
+        let mut candidate = self.candidate.get();
+        let mut words = self.candidate_words.get();
+        while words < 8 {
+            match randomness.next() {
+                Some(word) => {
+                    candidate[words] = word;
+                    words += 1;
+                }
+                None => break,
+            }
+        }
+        // Draw again when the number is not a valid private key.
+        if words == 8 && (is_zero(&candidate) || !less_than(&candidate, &N.m)) {
+            words = 0;
+        }
+        self.candidate.set(candidate);
+        self.candidate_words.set(words);
+        if words < 8 {
+            return rng::Continue::More;
+        }
+        if !self.schedule() {
+            self.candidate.set(ZERO);
+            self.public_key.take().map(|public_key| {
+                self.client
+                    .map(move |client| client.key_pair_generated(public_key, ReturnCode::FAIL))
+            });
+        }
+        rng::Continue::Done
+    }
+}
+
+impl<'a> DynamicDeferredCallClient for SoftwareEcdhP256<'a> {
+    fn call(&self, _handle: DeferredCallHandle) {
+        if self.candidate_words.get() == 8 {
+            if let Some(public_key) = self.public_key.take() {
+                self.finish_key_pair(public_key);
+            }
+        }
+        if let Some(secret) = self.secret.take() {
+            self.finish_shared_secret(secret);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use super::*;
+    use crate::net::dtls::sha256::Sha256;
+    use kernel::common::dynamic_deferred_call::DynamicDeferredCallClientState;
+    use std::boxed::Box;
+
+    /// The key of RFC 6979, A.2.5.
+    const RFC6979_KEY: &str = "60fed4ba255a9d31c961eb74c6356d68c049b8923b61fa6ce669622e60f29fb6\
+                               7903fe1008b8bc99a41ae9e95628bc64f2f1b20c2d7e9f5177a3c294d4462299";
+    const SAMPLE: &str = "efd48b2aacb6a8fd1140dd9cd45e81d69d2c877b56aaf991c34d0ea84eaf3716\
+                          f7cb1c942d657c41d436c7a1b6e29f65f3e900dbb9aff4064dc4ab2f843acda8";
+    const TEST: &str = "f1abb023518351cd71d881567b1ea663ed3efcf6c5132b354f28d3b0b7d38367\
+                        019f4113742a2b14bd25926b49c649155f267e60d3814b4c0cc84250e46f0083";
+
+    /// The private key of RFC 6979, A.2.5.
+    const RFC6979_PRIVATE_KEY: &str =
+        "c9afa9d845ba75166b5c215767b1d6934e50c3db36e89b127b8a622b120f6721";
+
+    /// The public key of the first P-256 case of the CAVS ECC CDH vectors,
+    /// with signatures over hashes at the ends of the range.
+    const CAVS_KEY: &str = "ead218590119e8876b29146ff89ca61770c4edbbf97d38ce385ed281d8a6b230\
+                            28af61281fd35e2fa7002523acc85a429cb06ee6648325389f59edfce1405141";
+    const ALL_ONES: &str = "a620269d4347614e90c9c86203f07226644b4faaa1e277075760b4ec12e49959\
+                            c53a8c033062ce2913684ca0b76ac452fbd6dfccfabe0b1df390b98235b3bebd";
+    const ALL_ZEROS: &str = "2b58b381f669ddce21272d5fa1253387c9645256ee4fb742f010d412ac914f13\
+                             0ff90c521f6611c3baf290e219b901afaf9e780a13e03a96e29d38ef77ec8c9a";
+
+    const ORDER: &str = "ffffffff00000000ffffffffffffffffbce6faada7179e84f3b9cac2fc632551";
+
+    fn hex(s: &str) -> [u8; 64] {
+        let mut bytes = [0; 64];
+        for (i, byte) in bytes.iter_mut().enumerate().take(s.len() / 2) {
+            *byte = u8::from_str_radix(&s[2 * i..2 * i + 2], 16).unwrap();
+        }
+        bytes
+    }
+
+    fn sha256(message: &[u8]) -> [u8; HASH_LEN] {
+        let mut sha = Sha256::new();
+        sha.update(message);
+        sha.finish()
+    }
+
+    #[test]
+    fn accepts_rfc_6979_signatures() {
+        let key = hex(RFC6979_KEY);
+        assert!(verify_signature(&key, &sha256(b"sample"), &hex(SAMPLE)));
+        assert!(verify_signature(&key, &sha256(b"test"), &hex(TEST)));
+        assert!(!verify_signature(&key, &sha256(b"test"), &hex(SAMPLE)));
+        assert!(!verify_signature(&key, &sha256(b"sample"), &hex(TEST)));
+    }
+
+    #[test]
+    fn reduces_hashes_modulo_the_order() {
+        let key = hex(CAVS_KEY);
+        assert!(verify_signature(&key, &[0xff; HASH_LEN], &hex(ALL_ONES)));
+        assert!(verify_signature(&key, &[0; HASH_LEN], &hex(ALL_ZEROS)));
+        assert!(!verify_signature(
+            &hex(RFC6979_KEY),
+            &[0; HASH_LEN],
+            &hex(ALL_ZEROS)
+        ));
+    }
+
+    #[test]
+    fn rejects_altered_signatures() {
+        let key = hex(RFC6979_KEY);
+        let hash = sha256(b"sample");
+        for i in 0..SIGNATURE_LEN {
+            let mut signature = hex(SAMPLE);
+            signature[i] ^= 1 << (i % 8);
+            assert!(!verify_signature(&key, &hash, &signature));
+        }
+        let mut altered = hash;
+        altered[HASH_LEN - 1] ^= 1;
+        assert!(!verify_signature(&key, &altered, &hex(SAMPLE)));
+    }
+
+    #[test]
+    fn rejects_out_of_range_signatures() {
+        let key = hex(RFC6979_KEY);
+        let hash = sha256(b"sample");
+        let sample = hex(SAMPLE);
+        let order = hex(ORDER);
+        let with = |r: &[u8], s: &[u8]| {
+            let mut signature = [0; SIGNATURE_LEN];
+            signature[..32].copy_from_slice(r);
+            signature[32..].copy_from_slice(s);
+            verify_signature(&key, &hash, &signature)
+        };
+        assert!(with(&sample[..32], &sample[32..]));
+        assert!(!with(&[0; 32], &sample[32..]));
+        assert!(!with(&sample[..32], &[0; 32]));
+        assert!(!with(&[0; 32], &[0; 32]));
+        assert!(!with(&order[..32], &sample[32..]));
+        assert!(!with(&sample[..32], &order[..32]));
+        assert!(!with(&[0xff; 32], &sample[32..]));
+        assert!(!with(&sample[..32], &[0xff; 32]));
+    }
+
+    #[test]
+    fn rejects_public_keys_off_the_curve() {
+        let states = Box::leak(Box::new([DynamicDeferredCallClientState::default()]));
+        let ecdsa = SoftwareEcdsaP256::new(Box::leak(Box::new(DynamicDeferredCall::new(states))));
+        let mut key = hex(RFC6979_KEY);
+        assert_eq!(ecdsa.set_public_key(&key[..]), ReturnCode::SUCCESS);
+        assert_eq!(ecdsa.set_public_key(&key[..32]), ReturnCode::ESIZE);
+        assert_eq!(
+            ecdsa.set_public_key(&[0; PUBLIC_KEY_LEN]),
+            ReturnCode::EINVAL
+        );
+        key[PUBLIC_KEY_LEN - 1] ^= 1;
+        assert_eq!(ecdsa.set_public_key(&key[..]), ReturnCode::EINVAL);
+        assert!(!verify_signature(&key, &sha256(b"sample"), &hex(SAMPLE)));
+    }
+
+    fn private_key(s: &str) -> [u8; PRIVATE_KEY_LEN] {
+        let mut key = [0; PRIVATE_KEY_LEN];
+        key.copy_from_slice(&hex(s)[..PRIVATE_KEY_LEN]);
+        key
+    }
+
+    #[test]
+    fn signs_with_rfc_6979_nonces() {
+        let key = private_key(RFC6979_PRIVATE_KEY);
+        assert_eq!(&public_key(&key).unwrap()[..], &hex(RFC6979_KEY)[..]);
+        let mut signature = [0; SIGNATURE_LEN];
+        assert!(sign_signature(&key, &sha256(b"sample"), &mut signature));
+        assert_eq!(&signature[..], &hex(SAMPLE)[..]);
+        assert!(sign_signature(&key, &sha256(b"test"), &mut signature));
+        assert_eq!(&signature[..], &hex(TEST)[..]);
+    }
+
+    #[test]
+    fn refuses_out_of_range_private_keys() {
+        let mut signature = [0; SIGNATURE_LEN];
+        for key in [
+            [0; PRIVATE_KEY_LEN],
+            private_key(ORDER),
+            [0xff; PRIVATE_KEY_LEN],
+        ]
+        .iter()
+        {
+            assert!(public_key(key).is_none());
+            assert!(!sign_signature(key, &sha256(b"sample"), &mut signature));
+        }
+        assert_eq!(&signature[..], &[0; SIGNATURE_LEN][..]);
+    }
+
+    struct SignCollector {
+        result: Cell<Option<ReturnCode>>,
+        signature: Cell<[u8; SIGNATURE_LEN]>,
+    }
+
+    impl ClientSign for SignCollector {
+        fn signing_done(
+            &self,
+            result: ReturnCode,
+            _hash: &'static mut [u8],
+            signature: &'static mut [u8],
+        ) {
+            let mut copy = [0; SIGNATURE_LEN];
+            copy.copy_from_slice(&signature[..SIGNATURE_LEN]);
+            self.signature.set(copy);
+            self.result.set(Some(result));
+        }
+    }
+
+    #[test]
+    fn signs_from_a_deferred_call() {
+        let states = Box::leak(Box::new([DynamicDeferredCallClientState::default()]));
+        let deferred_caller: &'static DynamicDeferredCall =
+            Box::leak(Box::new(DynamicDeferredCall::new(states)));
+        let key = Box::leak(Box::new(private_key(RFC6979_PRIVATE_KEY)));
+        let signer: &'static SoftwareEcdsaP256Signer =
+            Box::leak(Box::new(SoftwareEcdsaP256Signer::new(key, deferred_caller)));
+        let handle = deferred_caller.register(signer).unwrap();
+        signer.initialize_callback_handle(handle);
+        let client: &'static SignCollector = Box::leak(Box::new(SignCollector {
+            result: Cell::new(None),
+            signature: Cell::new([0; SIGNATURE_LEN]),
+        }));
+        signer.set_client(client);
+
+        let hash = Box::leak(Box::new(sha256(b"test")));
+        let signature = Box::leak(Box::new([0; SIGNATURE_LEN]));
+        let short = Box::leak(Box::new([0; SIGNATURE_LEN - 1]));
+        let (rcode, hash, _) = signer.sign(hash, short).unwrap_err();
+        assert_eq!(rcode, ReturnCode::ESIZE);
+        assert!(signer.sign(hash, signature).is_ok());
+        assert_eq!(client.result.get(), None);
+        while deferred_caller.cancel(handle) {
+            signer.call(handle);
+        }
+        assert_eq!(client.result.get(), Some(ReturnCode::SUCCESS));
+        assert_eq!(&client.signature.get()[..], &hex(TEST)[..]);
+    }
+
+    struct Collector {
+        result: Cell<Option<Result<bool, ReturnCode>>>,
+    }
+
+    impl ClientVerify for Collector {
+        fn verification_done(
+            &self,
+            result: Result<bool, ReturnCode>,
+            _hash: &'static mut [u8],
+            _signature: &'static mut [u8],
+        ) {
+            self.result.set(Some(result));
+        }
+    }
+
+    #[test]
+    fn verifies_from_a_deferred_call() {
+        let states = Box::leak(Box::new([DynamicDeferredCallClientState::default()]));
+        let deferred_caller: &'static DynamicDeferredCall =
+            Box::leak(Box::new(DynamicDeferredCall::new(states)));
+        let ecdsa: &'static SoftwareEcdsaP256 =
+            Box::leak(Box::new(SoftwareEcdsaP256::new(deferred_caller)));
+        let handle = deferred_caller.register(ecdsa).unwrap();
+        ecdsa.initialize_callback_handle(handle);
+        let client: &'static Collector = Box::leak(Box::new(Collector {
+            result: Cell::new(None),
+        }));
+        ecdsa.set_client(client);
+
+        let hash = || Box::leak(Box::new(sha256(b"test"))) as &'static mut [u8];
+        let signature = |s| Box::leak(Box::new(hex(s))) as &'static mut [u8];
+        assert!(ecdsa.verify(hash(), signature(TEST)).is_err());
+        assert_eq!(ecdsa.set_public_key(&hex(RFC6979_KEY)), ReturnCode::SUCCESS);
+
+        assert!(ecdsa.verify(hash(), signature(TEST)).is_ok());
+        assert!(ecdsa.verify(hash(), signature(TEST)).is_err());
+        assert!(deferred_caller.cancel(handle));
+        ecdsa.call(handle);
+        assert_eq!(client.result.take(), Some(Ok(true)));
+
+        assert!(ecdsa.verify(hash(), signature(SAMPLE)).is_ok());
+        assert!(deferred_caller.cancel(handle));
+        ecdsa.call(handle);
+        assert_eq!(client.result.take(), Some(Ok(false)));
+    }
+
+    // The first P-256 case of the CAVS ECC CDH Primitive vectors: our
+    // private key, the peer's public key, the shared secret, and our
+    // public key.
+    const CDH_PRIVATE_KEY: &str =
+        "7d7dc5f71eb29ddaf80d6214632eeae03d9058af1fb6d22ed80badb62bc1a534";
+    const CDH_PEER_KEY: &str = "700c48f77f56584c5cc632ca65640db91b6bacce3a4df6b42ce7cc838833d287\
+                                db71e509e3fd9b060ddb20ba5c51dcc5948d46fbf640dfe0441782cab85fa4ac";
+    const CDH_SECRET: &str = "46fc62106420ff012e54a434fbdd2d25ccc5852060561e68040dd7778997bd7b";
+
+    #[test]
+    fn multiplies_with_a_montgomery_ladder() {
+        let g = Point::from_affine(&GX, &GY).unwrap();
+        let d = from_be_bytes(&hex(CDH_PRIVATE_KEY));
+        let (x, y) = g.multiply_secret(&d).affine();
+        let public_key = hex(CAVS_KEY);
+        assert_eq!(x, from_be_bytes(&public_key[..32]));
+        assert_eq!(y, from_be_bytes(&public_key[32..]));
+
+        let peer_key = hex(CDH_PEER_KEY);
+        let peer = Point::from_affine(
+            &from_be_bytes(&peer_key[..32]),
+            &from_be_bytes(&peer_key[32..]),
+        );
+        let secret = peer.unwrap().multiply_secret(&d).affine_x();
+        assert_eq!(secret, from_be_bytes(&hex(CDH_SECRET)));
+
+        // Small keys, whose ladder takes k + 2n, and the largest key.
+        assert_eq!(g.multiply_secret(&ONE).affine(), (GX, GY));
+        assert_eq!(
+            g.multiply_secret(&[2, 0, 0, 0, 0, 0, 0, 0]).affine(),
+            g.double().affine()
+        );
+        let n_minus_1 = sub(&N.m, &ONE).0;
+        assert_eq!(
+            g.multiply_secret(&n_minus_1).affine(),
+            (GX, sub(&P.m, &GY).0)
+        );
+    }
+
+    /// A random number generator whose numbers the test gives.
+    struct TestRng {
+        requests: Cell<usize>,
+    }
+
+    impl<'a> Rng<'a> for TestRng {
+        fn get(&self) -> ReturnCode {
+            self.requests.set(self.requests.get() + 1);
+            ReturnCode::SUCCESS
+        }
+
+        fn cancel(&self) -> ReturnCode {
+            ReturnCode::SUCCESS
+        }
+
+        fn set_client(&'a self, _client: &'a dyn rng::Client) {}
+    }
+
+    struct EcdhCollector {
+        public_key: Cell<Option<([u8; PUBLIC_KEY_LEN], ReturnCode)>>,
+        secret: Cell<Option<([u8; 32], ReturnCode)>>,
+    }
+
+    impl ecdh::Client for EcdhCollector {
+        fn key_pair_generated(&self, public_key: &'static mut [u8; 64], result: ReturnCode) {
+            self.public_key.set(Some((*public_key, result)));
+        }
+
+        fn shared_secret_computed(&self, secret: &'static mut [u8; 32], result: ReturnCode) {
+            self.secret.set(Some((*secret, result)));
+        }
+    }
+
+    #[test]
+    fn generates_keys_and_shared_secrets() {
+        let states = Box::leak(Box::new([DynamicDeferredCallClientState::default()]));
+        let deferred_caller: &'static DynamicDeferredCall =
+            Box::leak(Box::new(DynamicDeferredCall::new(states)));
+        let rng: &'static TestRng = Box::leak(Box::new(TestRng {
+            requests: Cell::new(0),
+        }));
+        let ecdh: &'static SoftwareEcdhP256 =
+            Box::leak(Box::new(SoftwareEcdhP256::new(rng, deferred_caller)));
+        let handle = deferred_caller.register(ecdh).unwrap();
+        ecdh.initialize_callback_handle(handle);
+        let client: &'static EcdhCollector = Box::leak(Box::new(EcdhCollector {
+            public_key: Cell::new(None),
+            secret: Cell::new(None),
+        }));
+        ecdh.set_client(client);
+        let secret = || Box::leak(Box::new([0; 32]));
+        let peer_key = hex(CDH_PEER_KEY);
+
+        let started = ecdh.compute_shared_secret(&peer_key, secret());
+        assert_eq!(started.map_err(|err| err.0), Err(ReturnCode::EOFF));
+
+        assert!(ecdh.generate_key_pair(Box::leak(Box::new([0; 64]))).is_ok());
+        assert_eq!(rng.requests.get(), 1);
+        // A number above the order is drawn again, and the key may come in
+        // several callbacks.
+        let too_large = [0xffff_ffff; 8];
+        let mut words = too_large.iter().cloned();
+        assert!(
+            rng::Client::randomness_available(ecdh, &mut words, ReturnCode::SUCCESS)
+                == rng::Continue::More
+        );
+        let private_key = from_be_bytes(&hex(CDH_PRIVATE_KEY));
+        let mut words = private_key[..3].iter().cloned();
+        assert!(
+            rng::Client::randomness_available(ecdh, &mut words, ReturnCode::SUCCESS)
+                == rng::Continue::More
+        );
+        let mut words = private_key[3..].iter().cloned();
+        assert!(
+            rng::Client::randomness_available(ecdh, &mut words, ReturnCode::SUCCESS)
+                == rng::Continue::Done
+        );
+        assert!(deferred_caller.cancel(handle));
+        ecdh.call(handle);
+        let (public_key, result) = client.public_key.take().unwrap();
+        assert_eq!(result, ReturnCode::SUCCESS);
+        assert_eq!(&public_key[..], &hex(CAVS_KEY)[..]);
+
+        assert!(ecdh.compute_shared_secret(&peer_key, secret()).is_ok());
+        assert!(ecdh.compute_shared_secret(&peer_key, secret()).is_err());
+        assert!(deferred_caller.cancel(handle));
+        ecdh.call(handle);
+        let (shared, result) = client.secret.take().unwrap();
+        assert_eq!(result, ReturnCode::SUCCESS);
+        assert_eq!(&shared[..], &hex(CDH_SECRET)[..32]);
+
+        // A peer key off the curve.
+        let mut invalid = peer_key;
+        invalid[63] ^= 1;
+        assert!(ecdh.compute_shared_secret(&invalid, secret()).is_ok());
+        assert!(deferred_caller.cancel(handle));
+        ecdh.call(handle);
+        assert_eq!(client.secret.take().unwrap().1, ReturnCode::EINVAL);
+    }
+}
@@ -0,0 +1,670 @@
+//! Driver for the Microchip ENC28J60 SPI Ethernet controller.
+//!
+//! Implements `hil::ethernet::Mac` over SPI. The controller's 8 KiB of
+//! buffer memory is split into a 6.5 KiB receive ring, which holds the frames
+//! that arrived until they are read, and a transmit buffer for one frame.
+//! The `INT` pin must be wired to signal received frames and finished
+//! transmissions. The link runs at half duplex, which a switch finds by
+//! parallel detection.
+//!
+//! The board is expected to have reset the controller, with its `RESET` pin
+//! or by powering it up, before `initialize()`, which waits for the
+//! oscillator to be ready. Per the silicon errata, SPI must run at 8 MHz or
+//! more for the MAC registers to be written reliably.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//! # use capsules::virtual_spi::VirtualSpiMasterDevice;
+//!
+//! let enc28j60_spi = static_init!(
+//!     VirtualSpiMasterDevice<'static, sam4l::spi::SpiHw>,
+//!     VirtualSpiMasterDevice::new(mux_spi, 2)
+//! );
+//! let enc28j60 = static_init!(
+//!     capsules::enc28j60::Enc28j60<'static, VirtualSpiMasterDevice<'static, sam4l::spi::SpiHw>>,
+//!     capsules::enc28j60::Enc28j60::new(
+//!         enc28j60_spi,
+//!         &sam4l::gpio::PA[10],
+//!         [0x02, 0x00, 0x00, 0x12, 0x34, 0x56],
+//!         static_init!([u8; 1516], [0; 1516]),
+//!         static_init!([u8; 1516], [0; 1516]),
+//!     )
+//! );
+//! enc28j60_spi.set_client(enc28j60);
+//! sam4l::gpio::PA[10].set_client(enc28j60);
+//! enc28j60.initialize();
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::ethernet::{self, Filter, ADDRESS_LEN, HEADER_LEN, MAX_FRAME_LEN};
+use kernel::hil::gpio;
+use kernel::hil::spi;
+use kernel::ReturnCode;
+
+// SPI commands.
+const RCR: u8 = 0x00;
+const WCR: u8 = 0x40;
+const BFS: u8 = 0x80;
+const BFC: u8 = 0xa0;
+const RBM: u8 = 0x3a;
+const WBM: u8 = 0x7a;
+
+// Control registers, with their bank in bits 5 and 6 and their address in
+// bits 0 to 4. Those from `COMMON` on are in every bank.
+const COMMON: u8 = 0x1b;
+
+const ERDPTL: u8 = 0x00;
+const ERDPTH: u8 = 0x01;
+const EWRPTL: u8 = 0x02;
+const EWRPTH: u8 = 0x03;
+const ETXSTL: u8 = 0x04;
+const ETXSTH: u8 = 0x05;
+const ETXNDL: u8 = 0x06;
+const ETXNDH: u8 = 0x07;
+const ERXSTL: u8 = 0x08;
+const ERXSTH: u8 = 0x09;
+const ERXNDL: u8 = 0x0a;
+const ERXNDH: u8 = 0x0b;
+const ERXRDPTL: u8 = 0x0c;
+const ERXRDPTH: u8 = 0x0d;
+
+const ERXFCON: u8 = 0x38;
+const EPKTCNT: u8 = 0x39;
+
+const MACON1: u8 = 0x40;
+const MACON3: u8 = 0x42;
+const MACON4: u8 = 0x43;
+const MABBIPG: u8 = 0x44;
+const MAIPGL: u8 = 0x46;
+const MAIPGH: u8 = 0x47;
+const MAMXFLL: u8 = 0x4a;
+const MAMXFLH: u8 = 0x4b;
+const MIREGADR: u8 = 0x54;
+const MIWRL: u8 = 0x56;
+const MIWRH: u8 = 0x57;
+
+const MAADR5: u8 = 0x60;
+const MAADR6: u8 = 0x61;
+const MAADR3: u8 = 0x62;
+const MAADR4: u8 = 0x63;
+const MAADR1: u8 = 0x64;
+const MAADR2: u8 = 0x65;
+
+const EIE: u8 = 0x1b;
+const EIR: u8 = 0x1c;
+const ESTAT: u8 = 0x1d;
+const ECON2: u8 = 0x1e;
+const ECON1: u8 = 0x1f;
+
+// PHY registers.
+const PHCON2: u8 = 0x10;
+
+// Register bits.
+const ECON1_TXRST: u8 = 0x80;
+const ECON1_TXRTS: u8 = 0x08;
+const ECON1_RXEN: u8 = 0x04;
+const ECON1_BSEL: u8 = 0x03;
+const ECON2_PKTDEC: u8 = 0x40;
+const ESTAT_CLKRDY: u8 = 0x01;
+const EIE_INTIE: u8 = 0x80;
+const EIE_PKTIE: u8 = 0x40;
+const EIE_TXIE: u8 = 0x08;
+const EIE_TXERIE: u8 = 0x02;
+const EIR_TXIF: u8 = 0x08;
+const EIR_TXERIF: u8 = 0x02;
+const ERXFCON_UCEN: u8 = 0x80;
+const ERXFCON_CRCEN: u8 = 0x20;
+const ERXFCON_MCEN: u8 = 0x02;
+const ERXFCON_BCEN: u8 = 0x01;
+const MACON1_MARXEN: u8 = 0x01;
+/// Pad short frames to 60 bytes and append the CRC, and check the length
+/// field of received frames.
+const MACON3_HALF_DUPLEX: u8 = 0x32;
+const MACON4_DEFER: u8 = 0x40;
+const PHCON2_HDLDIS: u16 = 0x0100;
+
+/// The buffer memory: the receive ring, and the transmit buffer after it.
+const RX_START: u16 = 0x0000;
+const RX_END: u16 = 0x19ff;
+const TX_START: u16 = 0x1a00;
+
+/// The longest frame with its CRC.
+const MAX_FRAME_CRC_LEN: u16 = MAX_FRAME_LEN as u16 + 4;
+
+/// The receive status vector before each received frame: the address of
+/// the next frame, the length of this one and its status.
+const RX_HEADER_LEN: usize = 6;
+const RX_OK: u8 = 0x80;
+
+/// The bank of `BSEL` is not known until it is first set.
+const UNKNOWN_BANK: u8 = 0xff;
+
+/// The most register operations before a step.
+const MAX_OPS: usize = 32;
+
+#[derive(Copy, Clone, PartialEq)]
+enum Op {
+    Write(u8, u8),
+    /// Set bits of a register, which must not be a MAC or MII one.
+    Set(u8, u8),
+    /// Clear bits of a register, which must not be a MAC or MII one.
+    Clear(u8, u8),
+    /// Read a register, which must not be a MAC or MII one, into
+    /// `read_value`.
+    Read(u8),
+}
+
+impl Op {
+    fn register(&self) -> u8 {
+        match *self {
+            Op::Write(register, _)
+            | Op::Set(register, _)
+            | Op::Clear(register, _)
+            | Op::Read(register) => register,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Next {
+    /// Nothing; look for pending work.
+    Idle,
+    /// Configure the controller if `ESTAT` shows its clock is ready.
+    ClockChecked,
+    /// Write the frame being sent to the transmit buffer.
+    WriteFrame,
+    /// Handle the interrupt flags read from `EIR`.
+    InterruptRead,
+    /// Tell the transmit client we are done.
+    Transmitted(ReturnCode),
+    /// Read `EPKTCNT`.
+    CountPackets,
+    /// Read a frame if `EPKTCNT` shows one arrived.
+    PacketCountRead,
+    /// Read the receive status vector of the next frame.
+    ReadHeader,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum State {
+    /// Not initialized.
+    Off,
+    Idle,
+    /// Waiting for the SPI transaction of a register operation.
+    Ops(Next),
+    WritingFrame,
+    ReadingHeader,
+    /// Reading a received frame of this length.
+    ReadingFrame(usize),
+}
+
+pub struct Enc28j60<'a, S: spi::SpiMasterDevice> {
+    spi: &'a S,
+    int: &'a dyn gpio::InterruptPin<'a>,
+    mac_address: Cell<[u8; ADDRESS_LEN]>,
+    filter: Cell<Filter>,
+    /// The MAC address or filter changed since they were written.
+    config_pending: Cell<bool>,
+    state: Cell<State>,
+    /// Register operations still to do, and how many of them were done.
+    ops: Cell<[Op; MAX_OPS]>,
+    op_count: Cell<usize>,
+    op_index: Cell<usize>,
+    bank: Cell<u8>,
+    read_value: Cell<u8>,
+    /// An interrupt arrived while busy.
+    irq_pending: Cell<bool>,
+    /// The address of the next received frame in the receive ring.
+    next_packet: Cell<u16>,
+    spi_write: TakeCell<'static, [u8]>,
+    spi_read: TakeCell<'static, [u8]>,
+    buf_len: usize,
+    /// The frame being sent, which was written to the transmit buffer if
+    /// `transmitting`.
+    tx_frame: TakeCell<'static, [u8]>,
+    tx_len: Cell<usize>,
+    transmitting: Cell<bool>,
+    tx_client: OptionalCell<&'a dyn ethernet::TxClient>,
+    rx_client: OptionalCell<&'a dyn ethernet::RxClient>,
+}
+
+impl<'a, S: spi::SpiMasterDevice> Enc28j60<'a, S> {
+    /// `spi_write` and `spi_read` must be equally long; frames longer than
+    /// two bytes less than them are neither sent nor received.
+    pub fn new(
+        spi: &'a S,
+        int: &'a dyn gpio::InterruptPin<'a>,
+        mac_address: [u8; ADDRESS_LEN],
+        spi_write: &'static mut [u8],
+        spi_read: &'static mut [u8],
+    ) -> Enc28j60<'a, S> {
+        let buf_len = cmp::min(spi_write.len(), spi_read.len());
+        Enc28j60 {
+            spi: spi,
+            int: int,
+            mac_address: Cell::new(mac_address),
+            filter: Cell::new(Filter::DEFAULT),
+            config_pending: Cell::new(false),
+            state: Cell::new(State::Off),
+            ops: Cell::new([Op::Read(ESTAT); MAX_OPS]),
+            op_count: Cell::new(0),
+            op_index: Cell::new(0),
+            bank: Cell::new(UNKNOWN_BANK),
+            read_value: Cell::new(0),
+            irq_pending: Cell::new(false),
+            next_packet: Cell::new(RX_START),
+            spi_write: TakeCell::new(spi_write),
+            spi_read: TakeCell::new(spi_read),
+            buf_len: buf_len,
+            tx_frame: TakeCell::empty(),
+            tx_len: Cell::new(0),
+            transmitting: Cell::new(false),
+            tx_client: OptionalCell::empty(),
+            rx_client: OptionalCell::empty(),
+        }
+    }
+
+    /// Configure SPI and the interrupt pin, and start the controller once
+    /// its clock is ready.
+    pub fn initialize(&self) -> ReturnCode {
+        if self.state.get() != State::Off {
+            return ReturnCode::EALREADY;
+        }
+        self.spi.configure(
+            spi::ClockPolarity::IdleLow,
+            spi::ClockPhase::SampleLeading,
+            8_000_000,
+        );
+        self.int.make_input();
+        self.int.set_floating_state(gpio::FloatingState::PullUp);
+        self.int.enable_interrupts(gpio::InterruptEdge::FallingEdge);
+        self.start_ops(&[Op::Read(ESTAT)], Next::ClockChecked)
+    }
+
+    /// The register operations that set the receive filter and the MAC
+    /// address.
+    fn configuration(&self) -> [Op; 7] {
+        let filter = self.filter.get();
+        let erxfcon = if filter.promiscuous {
+            ERXFCON_CRCEN
+        } else {
+            ERXFCON_UCEN
+                | ERXFCON_CRCEN
+                | if filter.broadcast { ERXFCON_BCEN } else { 0 }
+                | if filter.multicast { ERXFCON_MCEN } else { 0 }
+        };
+        let address = self.mac_address.get();
+        [
+            Op::Write(ERXFCON, erxfcon),
+            Op::Write(MAADR1, address[0]),
+            Op::Write(MAADR2, address[1]),
+            Op::Write(MAADR3, address[2]),
+            Op::Write(MAADR4, address[3]),
+            Op::Write(MAADR5, address[4]),
+            Op::Write(MAADR6, address[5]),
+        ]
+    }
+
+    /// The register operations that set up the buffer memory and the MAC,
+    /// and enable reception.
+    fn initialization(&self) -> [Op; 30] {
+        let mut ops = [Op::Read(ESTAT); 30];
+        ops[..22].copy_from_slice(&[
+            Op::Clear(ECON1, ECON1_TXRTS | ECON1_RXEN),
+            Op::Write(ERXSTL, RX_START as u8),
+            Op::Write(ERXSTH, (RX_START >> 8) as u8),
+            Op::Write(ERXNDL, RX_END as u8),
+            Op::Write(ERXNDH, (RX_END >> 8) as u8),
+            Op::Write(ERXRDPTL, RX_END as u8),
+            Op::Write(ERXRDPTH, (RX_END >> 8) as u8),
+            Op::Write(ETXSTL, TX_START as u8),
+            Op::Write(ETXSTH, (TX_START >> 8) as u8),
+            Op::Write(MACON1, MACON1_MARXEN),
+            Op::Write(MACON3, MACON3_HALF_DUPLEX),
+            Op::Write(MACON4, MACON4_DEFER),
+            Op::Write(MAMXFLL, MAX_FRAME_CRC_LEN as u8),
+            Op::Write(MAMXFLH, (MAX_FRAME_CRC_LEN >> 8) as u8),
+            Op::Write(MABBIPG, 0x12),
+            Op::Write(MAIPGL, 0x12),
+            Op::Write(MAIPGH, 0x0c),
+            // Keep half duplex frames from looping back.
+            Op::Write(MIREGADR, PHCON2),
+            Op::Write(MIWRL, PHCON2_HDLDIS as u8),
+            Op::Write(MIWRH, (PHCON2_HDLDIS >> 8) as u8),
+            Op::Write(EIE, EIE_INTIE | EIE_PKTIE | EIE_TXIE | EIE_TXERIE),
+            Op::Clear(EIR, 0xff),
+        ]);
+        ops[22..29].copy_from_slice(&self.configuration());
+        ops[29] = Op::Set(ECON1, ECON1_RXEN);
+        ops
+    }
+
+    /// Do `ops` one by one, and then continue with `next`.
+    fn start_ops(&self, ops: &[Op], next: Next) -> ReturnCode {
+        let mut all = [Op::Read(ESTAT); MAX_OPS];
+        all[..ops.len()].copy_from_slice(ops);
+        self.ops.set(all);
+        self.op_count.set(ops.len());
+        self.op_index.set(0);
+        self.state.set(State::Ops(next));
+        self.next_op()
+    }
+
+    fn next_op(&self) -> ReturnCode {
+        let index = self.op_index.get();
+        if index == self.op_count.get() {
+            if let State::Ops(next) = self.state.get() {
+                self.continue_with(next);
+            }
+            return ReturnCode::SUCCESS;
+        }
+        let op = self.ops.get()[index];
+        let register = op.register();
+        let bank = register >> 5;
+        let (command, value, read) = if register & 0x1f < COMMON && bank != self.bank.get() {
+            // Select the bank of the register first, by clearing `BSEL`
+            // and then setting the bits it needs.
+            if self.bank.get() != 0 {
+                self.bank.set(0);
+                (BFC | ECON1, ECON1_BSEL, false)
+            } else {
+                self.bank.set(bank);
+                (BFS | ECON1, bank, false)
+            }
+        } else {
+            self.op_index.set(index + 1);
+            let address = register & 0x1f;
+            match op {
+                Op::Write(_, value) => (WCR | address, value, false),
+                Op::Set(_, bits) => (BFS | address, bits, false),
+                Op::Clear(_, bits) => (BFC | address, bits, false),
+                Op::Read(_) => (RCR | address, 0, true),
+            }
+        };
+        self.spi_write.take().map_or(ReturnCode::EBUSY, |wbuf| {
+            wbuf[0] = command;
+            wbuf[1] = value;
+            let rbuf = if read { self.spi_read.take() } else { None };
+            self.spi.read_write_bytes(wbuf, rbuf, 2)
+        })
+    }
+
+    fn continue_with(&self, next: Next) {
+        match next {
+            Next::Idle => self.idle(),
+            Next::ClockChecked => {
+                if self.read_value.get() & ESTAT_CLKRDY == 0 {
+                    self.start_ops(&[Op::Read(ESTAT)], Next::ClockChecked);
+                } else {
+                    self.config_pending.set(false);
+                    self.next_packet.set(RX_START);
+                    self.start_ops(&self.initialization(), Next::Idle);
+                }
+            }
+            Next::WriteFrame => {
+                let len = self.tx_len.get();
+                let rcode = self.spi_write.take().map_or(ReturnCode::FAIL, |wbuf| {
+                    wbuf[0] = WBM;
+                    // Send the frame as `MACON3` sets.
+                    wbuf[1] = 0x00;
+                    self.tx_frame
+                        .map(|frame| wbuf[2..2 + len].copy_from_slice(&frame[..len]));
+                    self.state.set(State::WritingFrame);
+                    self.spi.read_write_bytes(wbuf, None, 2 + len)
+                });
+                if rcode != ReturnCode::SUCCESS {
+                    self.continue_with(Next::Transmitted(rcode));
+                }
+            }
+            Next::InterruptRead => {
+                let eir = self.read_value.get();
+                if eir & (EIR_TXIF | EIR_TXERIF) != 0 {
+                    let next = if !self.transmitting.get() {
+                        Next::CountPackets
+                    } else if eir & EIR_TXERIF != 0 {
+                        Next::Transmitted(ReturnCode::FAIL)
+                    } else {
+                        Next::Transmitted(ReturnCode::SUCCESS)
+                    };
+                    self.start_ops(&[Op::Clear(EIR, EIR_TXIF | EIR_TXERIF)], next);
+                } else {
+                    self.continue_with(Next::CountPackets);
+                }
+            }
+            Next::Transmitted(rcode) => {
+                self.transmitting.set(false);
+                let frame = self.tx_frame.take();
+                // A frame sent from the callback waits for the received ones.
+                self.continue_with(Next::CountPackets);
+                frame.map(|frame| {
+                    self.tx_client
+                        .map(move |client| client.transmit_done(frame, rcode));
+                });
+            }
+            Next::CountPackets => {
+                self.start_ops(&[Op::Read(EPKTCNT)], Next::PacketCountRead);
+            }
+            Next::PacketCountRead => {
+                if self.read_value.get() == 0 {
+                    self.idle();
+                } else {
+                    let next_packet = self.next_packet.get();
+                    self.start_ops(
+                        &[
+                            Op::Write(ERDPTL, next_packet as u8),
+                            Op::Write(ERDPTH, (next_packet >> 8) as u8),
+                        ],
+                        Next::ReadHeader,
+                    );
+                }
+            }
+            Next::ReadHeader => match (self.spi_write.take(), self.spi_read.take()) {
+                (Some(wbuf), Some(rbuf)) => {
+                    wbuf[0] = RBM;
+                    self.state.set(State::ReadingHeader);
+                    self.spi
+                        .read_write_bytes(wbuf, Some(rbuf), 1 + RX_HEADER_LEN);
+                }
+                (wbuf, rbuf) => {
+                    wbuf.map(|buf| self.spi_write.replace(buf));
+                    rbuf.map(|buf| self.spi_read.replace(buf));
+                    self.idle();
+                }
+            },
+        }
+    }
+
+    /// Decide what to do about the receive status vector in `header`.
+    fn header_read(&self, header: &[u8]) {
+        let next_packet = header[0] as u16 | (header[1] as u16) << 8;
+        let len = (header[2] as usize | (header[3] as usize) << 8).saturating_sub(4);
+        let ok = header[4] & RX_OK != 0;
+        self.next_packet.set(next_packet);
+        if !ok || len < HEADER_LEN || len > self.buf_len - 1 {
+            self.free_frame();
+            return;
+        }
+        match (self.spi_write.take(), self.spi_read.take()) {
+            (Some(wbuf), Some(rbuf)) => {
+                wbuf[0] = RBM;
+                self.state.set(State::ReadingFrame(len));
+                self.spi.read_write_bytes(wbuf, Some(rbuf), 1 + len);
+            }
+            (wbuf, rbuf) => {
+                wbuf.map(|buf| self.spi_write.replace(buf));
+                rbuf.map(|buf| self.spi_read.replace(buf));
+                self.free_frame();
+            }
+        }
+    }
+
+    /// Release the space of the frame just read, and look for the next.
+    fn free_frame(&self) {
+        let next_packet = self.next_packet.get();
+        // `ERXRDPT` must be odd, and is just before the next frame.
+        let read_pointer = if next_packet == RX_START {
+            RX_END
+        } else {
+            next_packet - 1
+        };
+        self.irq_pending.set(true);
+        self.start_ops(
+            &[
+                Op::Write(ERXRDPTL, read_pointer as u8),
+                Op::Write(ERXRDPTH, (read_pointer >> 8) as u8),
+                Op::Set(ECON2, ECON2_PKTDEC),
+            ],
+            Next::Idle,
+        );
+    }
+
+    /// Start the pending work: handle an interrupt, apply a new
+    /// configuration or send a frame.
+    fn idle(&self) {
+        self.state.set(State::Idle);
+        if self.irq_pending.get() {
+            self.irq_pending.set(false);
+            self.start_ops(&[Op::Read(EIR)], Next::InterruptRead);
+        } else if self.config_pending.get() {
+            self.config_pending.set(false);
+            self.start_ops(&self.configuration(), Next::Idle);
+        } else if self.tx_frame.is_some() && !self.transmitting.get() {
+            let end = TX_START + self.tx_len.get() as u16;
+            self.start_ops(
+                &[
+                    // Reset the transmit logic, as the errata recommends
+                    // after a transmit error.
+                    Op::Set(ECON1, ECON1_TXRST),
+                    Op::Clear(ECON1, ECON1_TXRST),
+                    Op::Clear(EIR, EIR_TXIF | EIR_TXERIF),
+                    Op::Write(EWRPTL, TX_START as u8),
+                    Op::Write(EWRPTH, (TX_START >> 8) as u8),
+                    Op::Write(ETXNDL, end as u8),
+                    Op::Write(ETXNDH, (end >> 8) as u8),
+                ],
+                Next::WriteFrame,
+            );
+        }
+    }
+}
+
+impl<'a, S: spi::SpiMasterDevice> ethernet::Mac<'a> for Enc28j60<'a, S> {
+    fn set_transmit_client(&self, client: &'a dyn ethernet::TxClient) {
+        self.tx_client.set(client);
+    }
+
+    fn set_receive_client(&self, client: &'a dyn ethernet::RxClient) {
+        self.rx_client.set(client);
+    }
+
+    fn mac_address(&self) -> [u8; ADDRESS_LEN] {
+        self.mac_address.get()
+    }
+
+    fn set_mac_address(&self, address: [u8; ADDRESS_LEN]) -> ReturnCode {
+        self.mac_address.set(address);
+        self.config_pending.set(true);
+        if self.state.get() == State::Idle {
+            self.idle();
+        }
+        ReturnCode::SUCCESS
+    }
+
+    fn set_filter(&self, filter: Filter) -> ReturnCode {
+        self.filter.set(filter);
+        self.config_pending.set(true);
+        if self.state.get() == State::Idle {
+            self.idle();
+        }
+        ReturnCode::SUCCESS
+    }
+
+    fn max_frame_len(&self) -> usize {
+        cmp::min(MAX_FRAME_LEN, self.buf_len - 2)
+    }
+
+    /// Returns `EOFF` until `initialize()` has started.
+    fn transmit(
+        &self,
+        frame: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        if self.state.get() == State::Off {
+            return Err((ReturnCode::EOFF, frame));
+        }
+        if self.tx_frame.is_some() {
+            return Err((ReturnCode::EBUSY, frame));
+        }
+        if len < HEADER_LEN || len > cmp::min(frame.len(), self.max_frame_len()) {
+            return Err((ReturnCode::ESIZE, frame));
+        }
+        self.tx_frame.replace(frame);
+        self.tx_len.set(len);
+        if self.state.get() == State::Idle {
+            self.idle();
+        }
+        Ok(())
+    }
+}
+
+impl<'a, S: spi::SpiMasterDevice> spi::SpiMasterClient for Enc28j60<'a, S> {
+    fn read_write_done(
+        &self,
+        write: &'static mut [u8],
+        read: Option<&'static mut [u8]>,
+        _len: usize,
+    ) {
+        self.spi_write.replace(write);
+        match self.state.get() {
+            State::Ops(_) => {
+                read.map(|buf| {
+                    self.read_value.set(buf[1]);
+                    self.spi_read.replace(buf);
+                });
+                self.next_op();
+            }
+            State::WritingFrame => {
+                read.map(|buf| self.spi_read.replace(buf));
+                self.transmitting.set(true);
+                self.start_ops(&[Op::Set(ECON1, ECON1_TXRTS)], Next::Idle);
+            }
+            State::ReadingHeader => {
+                if let Some(buf) = read {
+                    let mut header = [0; RX_HEADER_LEN];
+                    header.copy_from_slice(&buf[1..1 + RX_HEADER_LEN]);
+                    self.spi_read.replace(buf);
+                    self.header_read(&header);
+                }
+            }
+            State::ReadingFrame(len) => {
+                if let Some(buf) = read {
+                    self.rx_client
+                        .map(|client| client.frame_received(&buf[1..1 + len]));
+                    self.spi_read.replace(buf);
+                }
+                self.free_frame();
+            }
+            State::Off | State::Idle => {
+                read.map(|buf| self.spi_read.replace(buf));
+            }
+        }
+    }
+}
+
+impl<'a, S: spi::SpiMasterDevice> gpio::Client for Enc28j60<'a, S> {
+    fn fired(&self) {
+        match self.state.get() {
+            State::Off => {}
+            State::Idle => {
+                self.start_ops(&[Op::Read(EIR)], Next::InterruptRead);
+            }
+            _ => self.irq_pending.set(true),
+        }
+    }
+}
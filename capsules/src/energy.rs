@@ -0,0 +1,70 @@
+//! Reports the energy the kernel estimates each process has consumed.
+//!
+//! The kernel charges processes for the time they spend on the CPU and for
+//! the peripheral use drivers attribute to them with
+//! `AppId::charge_energy()`. This capsule lets an app read its own total and
+//! the total of all processes, so that it can tell what share of the energy
+//! budget it is responsible for. The same figures are shown by the process
+//! console's `energy` command.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let energy = static_init!(
+//!     capsules::energy::EnergyDriver<Capability>,
+//!     capsules::energy::EnergyDriver::new(board_kernel, Capability)
+//! );
+//! board_kernel.set_cpu_active_power(7_000);
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! Energy is reported in microjoules and wraps around at `usize::MAX`.
+//!
+//! - command `0`: driver check.
+//! - command `1`: energy consumed by the calling app.
+//! - command `2`: energy consumed by all processes.
+
+use kernel::capabilities::ProcessManagementCapability;
+use kernel::introspection::KernelInfo;
+use kernel::{AppId, Driver, Kernel, ReturnCode};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::Energy as usize;
+
+pub struct EnergyDriver<C: ProcessManagementCapability> {
+    kernel: &'static Kernel,
+    capability: C,
+}
+
+impl<C: ProcessManagementCapability> EnergyDriver<C> {
+    pub fn new(kernel: &'static Kernel, capability: C) -> EnergyDriver<C> {
+        EnergyDriver {
+            kernel: kernel,
+            capability: capability,
+        }
+    }
+
+    fn microjoules(nanojoules: u64) -> ReturnCode {
+        ReturnCode::SuccessWithValue {
+            value: (nanojoules / 1000) as usize,
+        }
+    }
+}
+
+impl<C: ProcessManagementCapability> Driver for EnergyDriver<C> {
+    fn command(&self, command_num: usize, _: usize, _: usize, appid: AppId) -> ReturnCode {
+        let info = KernelInfo::new(self.kernel);
+        match command_num {
+            0 => ReturnCode::SUCCESS,
+            1 => Self::microjoules(info.app_energy_consumed(appid, &self.capability)),
+            2 => Self::microjoules(info.energy_consumed(&self.capability)),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
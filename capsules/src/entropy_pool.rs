@@ -0,0 +1,491 @@
+//! Shares a hardware entropy source through a DRBG.
+//!
+//! `EntropyPool` seeds a CTR_DRBG (NIST SP 800-90A, with AES-128 and no
+//! derivation function) with 256 bits from an `Entropy32` source, and serves
+//! random numbers from it to any number of `PoolRng` users, each of which
+//! implements `hil::rng::Rng`. Userspace gets its randomness by giving one of
+//! them to `RngDriver`. Once seeded, requests are answered from a deferred
+//! call without waiting for the hardware: the DRBG is reseeded in the
+//! background every `RESEED_INTERVAL` requests, and keeps serving from its
+//! current state while the entropy source is slow. Requests made before the
+//! first seed wait for it, and a failed seed is retried on the next request.
+//!
+//! Each callback gives a user up to `CALLBACK_WORDS` random words; a user
+//! that needs more returns `Continue::More` and is called again. The DRBG
+//! state is updated after each round of callbacks, so the output already
+//! given out cannot be recomputed from a later state.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let entropy_pool = static_init!(
+//!     capsules::entropy_pool::EntropyPool<'static>,
+//!     capsules::entropy_pool::EntropyPool::new(&sam4l::trng::TRNG, dynamic_deferred_caller)
+//! );
+//! entropy_pool.initialize_callback_handle(
+//!     dynamic_deferred_caller
+//!         .register(entropy_pool)
+//!         .expect("no deferred call slot available for the entropy pool"),
+//! );
+//! sam4l::trng::TRNG.set_client(entropy_pool);
+//!
+//! let app_rng = static_init!(
+//!     capsules::entropy_pool::PoolRng<'static>,
+//!     capsules::entropy_pool::PoolRng::new(entropy_pool)
+//! );
+//! let rng = static_init!(
+//!     capsules::rng::RngDriver<'static>,
+//!     capsules::rng::RngDriver::new(app_rng, board_kernel.create_grant(&grant_cap))
+//! );
+//! kernel::hil::rng::Rng::set_client(app_rng, rng);
+//! ```
+
+use crate::ble::crypto;
+use core::cell::Cell;
+use kernel::common::cells::OptionalCell;
+use kernel::common::dynamic_deferred_call::{
+    DeferredCallHandle, DynamicDeferredCall, DynamicDeferredCallClient,
+};
+use kernel::common::{List, ListLink, ListNode};
+use kernel::hil::entropy;
+use kernel::hil::entropy::Entropy32;
+use kernel::hil::rng;
+use kernel::ReturnCode;
+
+/// The number of requests between reseeds of the DRBG.
+pub const RESEED_INTERVAL: u32 = 1 << 10;
+
+/// The most random words given to a user in one callback.
+pub const CALLBACK_WORDS: usize = 64;
+
+const BLOCK_LEN: usize = 16;
+const SEED_LEN: usize = 2 * BLOCK_LEN;
+
+/// The state of CTR_DRBG with AES-128 and no derivation function.
+#[derive(Copy, Clone)]
+struct CtrDrbg {
+    key: [u8; BLOCK_LEN],
+    v: [u8; BLOCK_LEN],
+    reseed_counter: u32,
+}
+
+impl CtrDrbg {
+    const fn new() -> CtrDrbg {
+        CtrDrbg {
+            key: [0; BLOCK_LEN],
+            v: [0; BLOCK_LEN],
+            reseed_counter: 0,
+        }
+    }
+
+    fn increment_v(&mut self) {
+        for byte in self.v.iter_mut().rev() {
+            *byte = byte.wrapping_add(1);
+            if *byte != 0 {
+                break;
+            }
+        }
+    }
+
+    /// `CTR_DRBG_Update`, which is also instantiation and reseeding from
+    /// the zero state or the current one.
+    fn update(&mut self, provided_data: &[u8; SEED_LEN]) {
+        let mut temp = [0; SEED_LEN];
+        for chunk in temp.chunks_mut(BLOCK_LEN) {
+            self.increment_v();
+            chunk.copy_from_slice(&crypto::aes128(&self.key, &self.v));
+        }
+        for (temp, data) in temp.iter_mut().zip(provided_data.iter()) {
+            *temp ^= *data;
+        }
+        self.key.copy_from_slice(&temp[..BLOCK_LEN]);
+        self.v.copy_from_slice(&temp[BLOCK_LEN..]);
+    }
+
+    fn reseed(&mut self, entropy: &[u8; SEED_LEN]) {
+        self.update(entropy);
+        self.reseed_counter = 1;
+    }
+
+    fn generate_block(&mut self) -> [u8; BLOCK_LEN] {
+        self.increment_v();
+        crypto::aes128(&self.key, &self.v)
+    }
+
+    /// Ends a request, so its output cannot be recomputed.
+    fn finish_request(&mut self) {
+        self.update(&[0; SEED_LEN]);
+        self.reseed_counter = self.reseed_counter.saturating_add(1);
+    }
+}
+
+pub struct EntropyPool<'a> {
+    entropy: &'a dyn Entropy32<'a>,
+    users: List<'a, PoolRng<'a>>,
+    drbg: Cell<CtrDrbg>,
+    seeded: Cell<bool>,
+    /// The entropy collected for the next seed.
+    seed: Cell<[u8; SEED_LEN]>,
+    seed_len: Cell<usize>,
+    reseeding: Cell<bool>,
+    deferred_caller: &'a DynamicDeferredCall,
+    handle: OptionalCell<DeferredCallHandle>,
+}
+
+impl<'a> EntropyPool<'a> {
+    pub fn new(
+        entropy: &'a dyn Entropy32<'a>,
+        deferred_caller: &'a DynamicDeferredCall,
+    ) -> EntropyPool<'a> {
+        EntropyPool {
+            entropy: entropy,
+            users: List::new(),
+            drbg: Cell::new(CtrDrbg::new()),
+            seeded: Cell::new(false),
+            seed: Cell::new([0; SEED_LEN]),
+            seed_len: Cell::new(0),
+            reseeding: Cell::new(false),
+            deferred_caller: deferred_caller,
+            handle: OptionalCell::empty(),
+        }
+    }
+
+    pub fn initialize_callback_handle(&self, handle: DeferredCallHandle) {
+        self.handle.replace(handle);
+    }
+
+    fn start_reseed(&self) -> ReturnCode {
+        if self.reseeding.get() {
+            return ReturnCode::SUCCESS;
+        }
+        self.seed_len.set(0);
+        let rcode = self.entropy.get();
+        if rcode == ReturnCode::SUCCESS {
+            self.reseeding.set(true);
+        }
+        rcode
+    }
+
+    /// Serves the waiting users from a deferred call, or seeds the DRBG
+    /// first.
+    fn request(&self) -> ReturnCode {
+        if !self.seeded.get() {
+            return self.start_reseed();
+        }
+        self.handle.map_or(ReturnCode::FAIL, |handle| {
+            self.deferred_caller.set(*handle);
+            ReturnCode::SUCCESS
+        })
+    }
+}
+
+impl entropy::Client32 for EntropyPool<'_> {
+    fn entropy_available(
+        &self,
+        entropy: &mut dyn Iterator<Item = u32>,
+        error: ReturnCode,
+    ) -> entropy::Continue {
+        if error != ReturnCode::SUCCESS {
+            self.reseeding.set(false);
+            return entropy::Continue::Done;
+        }
+        let mut seed = self.seed.get();
+        let mut seed_len = self.seed_len.get();
+        while seed_len < SEED_LEN {
+            match entropy.next() {
+                Some(word) => {
+                    seed[seed_len..seed_len + 4].copy_from_slice(&word.to_le_bytes());
+                    seed_len += 4;
+                }
+                None => break,
+            }
+        }
+        if seed_len < SEED_LEN {
+            self.seed.set(seed);
+            self.seed_len.set(seed_len);
+            return entropy::Continue::More;
+        }
+
+        let mut drbg = self.drbg.get();
+        drbg.reseed(&seed);
+        self.drbg.set(drbg);
+        self.seed.set([0; SEED_LEN]);
+        self.seed_len.set(0);
+        self.reseeding.set(false);
+        if !self.seeded.get() {
+            self.seeded.set(true);
+            if self.users.iter().any(|user| user.requested.get()) {
+                self.request();
+            }
+        }
+        entropy::Continue::Done
+    }
+}
+
+impl DynamicDeferredCallClient for EntropyPool<'_> {
+    fn call(&self, _handle: DeferredCallHandle) {
+        let mut more = false;
+        for user in self.users.iter().filter(|user| user.requested.get()) {
+            user.requested.set(false);
+            let mut randomness = DrbgIter {
+                pool: self,
+                block: [0; BLOCK_LEN],
+                index: BLOCK_LEN,
+                remaining: CALLBACK_WORDS,
+            };
+            let result = user.client.map_or(rng::Continue::Done, |client| {
+                client.randomness_available(&mut randomness, ReturnCode::SUCCESS)
+            });
+            if result == rng::Continue::More {
+                user.requested.set(true);
+                more = true;
+            }
+        }
+
+        let mut drbg = self.drbg.get();
+        drbg.finish_request();
+        self.drbg.set(drbg);
+        if drbg.reseed_counter > RESEED_INTERVAL {
+            self.start_reseed();
+        }
+        if more {
+            self.request();
+        }
+    }
+}
+
+/// The output of the DRBG for one callback.
+struct DrbgIter<'a, 'b> {
+    pool: &'a EntropyPool<'b>,
+    block: [u8; BLOCK_LEN],
+    index: usize,
+    remaining: usize,
+}
+
+impl Iterator for DrbgIter<'_, '_> {
+    type Item = u32;
+
+    fn next(&mut self) -> Option<u32> {
+        if self.remaining == 0 {
+            return None;
+        }
+        if self.index == BLOCK_LEN {
+            let mut drbg = self.pool.drbg.get();
+            self.block = drbg.generate_block();
+            self.pool.drbg.set(drbg);
+            self.index = 0;
+        }
+        let mut word = [0; 4];
+        word.copy_from_slice(&self.block[self.index..self.index + 4]);
+        self.index += 4;
+        self.remaining -= 1;
+        Some(u32::from_le_bytes(word))
+    }
+}
+
+/// A user of the entropy pool.
+pub struct PoolRng<'a> {
+    pool: &'a EntropyPool<'a>,
+    client: OptionalCell<&'a dyn rng::Client>,
+    requested: Cell<bool>,
+    next: ListLink<'a, PoolRng<'a>>,
+}
+
+impl<'a> PoolRng<'a> {
+    pub const fn new(pool: &'a EntropyPool<'a>) -> PoolRng<'a> {
+        PoolRng {
+            pool: pool,
+            client: OptionalCell::empty(),
+            requested: Cell::new(false),
+            next: ListLink::empty(),
+        }
+    }
+}
+
+impl<'a> ListNode<'a, PoolRng<'a>> for PoolRng<'a> {
+    fn next(&'a self) -> &'a ListLink<'a, PoolRng<'a>> {
+        &self.next
+    }
+}
+
+impl<'a> rng::Rng<'a> for PoolRng<'a> {
+    fn get(&self) -> ReturnCode {
+        self.requested.set(true);
+        let rcode = self.pool.request();
+        if rcode != ReturnCode::SUCCESS {
+            self.requested.set(false);
+        }
+        rcode
+    }
+
+    fn cancel(&self) -> ReturnCode {
+        self.requested.set(false);
+        ReturnCode::SUCCESS
+    }
+
+    fn set_client(&'a self, client: &'a dyn rng::Client) {
+        self.pool.users.push_head(self);
+        self.client.set(client);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use super::*;
+    use kernel::common::dynamic_deferred_call::DynamicDeferredCallClientState;
+    use kernel::hil::entropy::Client32;
+    use kernel::hil::rng::Rng;
+    use std::boxed::Box;
+
+    // CTR_DRBG with AES-128, no derivation function, no nonce, personalization
+    // string or additional input, and 512 bits returned per request, in the
+    // layout of the SP 800-90A validation vectors. The expected outputs agree
+    // with the CTR-DRBG of OpenSSL for the same inputs.
+    const ENTROPY: [u8; SEED_LEN] = [
+        0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x0e,
+        0x0f, 0x10, 0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x17, 0x18, 0x19, 0x1a, 0x1b, 0x1c, 0x1d,
+        0x1e, 0x1f,
+    ];
+    const ENTROPY_RESEED: [u8; SEED_LEN] = [
+        0x80, 0x81, 0x82, 0x83, 0x84, 0x85, 0x86, 0x87, 0x88, 0x89, 0x8a, 0x8b, 0x8c, 0x8d, 0x8e,
+        0x8f, 0x90, 0x91, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9a, 0x9b, 0x9c, 0x9d,
+        0x9e, 0x9f,
+    ];
+    const FIRST: &str = "1686ffcf9f358be74452e647ba156aab05135797117fd1ab317d318c660e3d18\
+                         14810c15d85da5665c2518b4553fb155b85442c7900e7d827a11c60d18f424e5";
+    const SECOND: &str = "796037fe48c39bf610f8a85a98565d96094b2d53595ffe0fc61be739c21d9394\
+                          18c5b8c55816d23aeadeee4cef57b30e543d58712f7c891721a1233da10cd90b";
+    const AFTER_RESEED: &str = "9a3070e252cb4686c3be38d9db7e3160d660c811ba573e47948b90faf553071f\
+                                e7ac7957b834605234b4cda51921c8881e7af4318791ab713ae0eebb717dd7ae";
+
+    const RETURNED_LEN: usize = 64;
+
+    fn leak<T>(value: T) -> &'static T {
+        Box::leak(Box::new(value))
+    }
+
+    fn hex(s: &str) -> [u8; RETURNED_LEN] {
+        let mut bytes = [0; RETURNED_LEN];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&s[2 * i..2 * i + 2], 16).unwrap();
+        }
+        bytes
+    }
+
+    fn generate(drbg: &mut CtrDrbg) -> [u8; RETURNED_LEN] {
+        let mut output = [0; RETURNED_LEN];
+        for chunk in output.chunks_mut(BLOCK_LEN) {
+            chunk.copy_from_slice(&drbg.generate_block());
+        }
+        drbg.finish_request();
+        output
+    }
+
+    #[test]
+    fn ctr_drbg_instantiate_generate_reseed() {
+        let mut drbg = CtrDrbg::new();
+        drbg.reseed(&ENTROPY);
+        assert_eq!(drbg.reseed_counter, 1);
+        assert_eq!(&generate(&mut drbg)[..], &hex(FIRST)[..]);
+        assert_eq!(&generate(&mut drbg)[..], &hex(SECOND)[..]);
+        assert_eq!(drbg.reseed_counter, 3);
+
+        drbg.reseed(&ENTROPY_RESEED);
+        assert_eq!(drbg.reseed_counter, 1);
+        assert_eq!(&generate(&mut drbg)[..], &hex(AFTER_RESEED)[..]);
+    }
+
+    /// An entropy source whose requests are answered by the test.
+    struct TestEntropy {
+        requests: Cell<usize>,
+    }
+
+    impl<'a> Entropy32<'a> for TestEntropy {
+        fn get(&self) -> ReturnCode {
+            self.requests.set(self.requests.get() + 1);
+            ReturnCode::SUCCESS
+        }
+
+        fn cancel(&self) -> ReturnCode {
+            ReturnCode::SUCCESS
+        }
+
+        fn set_client(&'a self, _client: &'a dyn entropy::Client32) {}
+    }
+
+    /// Keeps the random words of one request.
+    struct Collector {
+        output: Cell<[u8; RETURNED_LEN]>,
+        calls: Cell<usize>,
+    }
+
+    impl rng::Client for Collector {
+        fn randomness_available(
+            &self,
+            randomness: &mut dyn Iterator<Item = u32>,
+            error: ReturnCode,
+        ) -> rng::Continue {
+            assert_eq!(error, ReturnCode::SUCCESS);
+            let mut output = [0; RETURNED_LEN];
+            for chunk in output.chunks_mut(4) {
+                chunk.copy_from_slice(&randomness.next().unwrap().to_le_bytes());
+            }
+            self.output.set(output);
+            self.calls.set(self.calls.get() + 1);
+            rng::Continue::Done
+        }
+    }
+
+    fn words(bytes: &[u8]) -> impl Iterator<Item = u32> + '_ {
+        bytes.chunks(4).map(|word| {
+            let mut le = [0; 4];
+            le.copy_from_slice(word);
+            u32::from_le_bytes(le)
+        })
+    }
+
+    #[test]
+    fn pool_serves_users_from_the_drbg() {
+        let entropy = leak(TestEntropy {
+            requests: Cell::new(0),
+        });
+        let states = Box::leak(Box::new([DynamicDeferredCallClientState::default()]));
+        let deferred_caller = leak(DynamicDeferredCall::new(states));
+        let pool = leak(EntropyPool::new(entropy, deferred_caller));
+        let handle = deferred_caller.register(pool).unwrap();
+        pool.initialize_callback_handle(handle);
+        let rng = leak(PoolRng::new(pool));
+        let client = leak(Collector {
+            output: Cell::new([0; RETURNED_LEN]),
+            calls: Cell::new(0),
+        });
+        rng.set_client(client);
+
+        // The first request waits for the seed, which comes in two parts.
+        assert_eq!(rng.get(), ReturnCode::SUCCESS);
+        assert_eq!(entropy.requests.get(), 1);
+        assert!(!deferred_caller.is_scheduled(handle));
+        let continued = pool.entropy_available(&mut words(&ENTROPY[..12]), ReturnCode::SUCCESS);
+        assert!(continued == entropy::Continue::More);
+        let continued = pool.entropy_available(&mut words(&ENTROPY[12..]), ReturnCode::SUCCESS);
+        assert!(continued == entropy::Continue::Done);
+
+        assert!(deferred_caller.cancel(handle));
+        pool.call(handle);
+        assert_eq!(client.calls.get(), 1);
+        assert_eq!(&client.output.get()[..], &hex(FIRST)[..]);
+
+        // Later requests do not wait for the entropy source.
+        assert_eq!(rng.get(), ReturnCode::SUCCESS);
+        assert!(deferred_caller.cancel(handle));
+        pool.call(handle);
+        assert_eq!(client.calls.get(), 2);
+        assert_eq!(&client.output.get()[..], &hex(SECOND)[..]);
+        assert_eq!(entropy.requests.get(), 1);
+    }
+}
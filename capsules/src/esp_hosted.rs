@@ -0,0 +1,579 @@
+//! Driver for ESP32 WiFi co-processors running Espressif's ESP-Hosted-FG
+//! firmware.
+//!
+//! The ESP32 is attached over SPI with two more pins: it raises the
+//! handshake pin when it is ready for a transaction and the data ready pin
+//! when it has something to send. Every transaction exchanges one fixed size
+//! buffer in each direction, starting with a header that names the
+//! interface the payload belongs to. Ethernet frames of the station
+//! interface are passed through `hil::ethernet::Mac`, so that
+//! `net::ethernet::EthernetLink` carries IPv6 over it, and joining or
+//! leaving an access point through `hil::wifi::Station` uses the firmware's
+//! protobuf control messages on its serial interface.
+//!
+//! The ESP32 passes up the frames for its own MAC address, which cannot be
+//! changed from here, and broadcast and multicast frames, which are
+//! filtered as set with `set_filter()`. Promiscuous reception is not
+//! supported.
+//!
+//! Socket offload modules such as the ATWINC1500 do not pass frames to the
+//! host, so they cannot be driven through this interface.
+//!
+//! ## Instantiation
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let wifi = static_init!(
+//!     capsules::esp_hosted::EspHosted<'static, VirtualSpiMasterDevice<'static, Spi>>,
+//!     capsules::esp_hosted::EspHosted::new(
+//!         wifi_spi,
+//!         &sam4l::gpio::PA[08],
+//!         &sam4l::gpio::PA[09],
+//!         static_init!([u8; 1600], [0; 1600]),
+//!         static_init!([u8; 1600], [0; 1600])));
+//! wifi_spi.set_client(wifi);
+//! sam4l::gpio::PA[08].set_client(wifi);
+//! sam4l::gpio::PA[09].set_client(wifi);
+//! hil::wifi::Station::set_client(wifi, wifi_client);
+//! wifi.initialize();
+//!
+//! let ethernet = static_init!(
+//!     capsules::net::ethernet::EthernetLink<'static>,
+//!     capsules::net::ethernet::EthernetLink::new(wifi, &mut ETHERNET_TX_BUF)
+//! );
+//! hil::ethernet::Mac::set_transmit_client(wifi, ethernet);
+//! hil::ethernet::Mac::set_receive_client(wifi, ethernet);
+//! ethernet.start();
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::ethernet::{self, Filter, ADDRESS_LEN, MAX_FRAME_LEN};
+use kernel::hil::gpio;
+use kernel::hil::spi;
+use kernel::hil::wifi::{self, MAX_PASSPHRASE_LEN, MAX_SSID_LEN};
+use kernel::ReturnCode;
+
+/// The length of every transaction.
+pub const BUFFER_LEN: usize = 1600;
+
+const HEADER_LEN: usize = 12;
+
+const IF_STA: u8 = 0;
+const IF_SERIAL: u8 = 2;
+const IF_PRIV: u8 = 4;
+
+const PRIV_PACKET_TYPE_EVENT: u8 = 0x33;
+const PRIV_EVENT_INIT: u8 = 0x22;
+
+/// Control messages are wrapped in an endpoint name and a data TLV.
+const TLV_EP_NAME: u8 = 0x01;
+const TLV_DATA: u8 = 0x02;
+const CTRL_EP_NAME: &[u8] = b"ctrlResp";
+
+const CTRL_MSG_REQUEST: u64 = 1;
+const CTRL_MSG_RESPONSE: u64 = 2;
+const CTRL_MSG_EVENT: u64 = 3;
+
+const REQ_GET_MAC_ADDRESS: u32 = 101;
+const REQ_CONNECT_AP: u32 = 107;
+const REQ_DISCONNECT_AP: u32 = 108;
+const RESP_GET_MAC_ADDRESS: u64 = 201;
+const RESP_CONNECT_AP: u64 = 207;
+const RESP_DISCONNECT_AP: u64 = 208;
+const EVENT_STATION_DISCONNECT_FROM_AP: u64 = 303;
+
+const WIFI_MODE_STA: u64 = 1;
+
+/// What the write buffer holds for the next transaction.
+#[derive(Copy, Clone, PartialEq)]
+enum Outgoing {
+    Nothing,
+    Frame,
+    Control,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Link {
+    Down,
+    Joining,
+    Up,
+    Leaving,
+}
+
+pub struct EspHosted<'a, S: spi::SpiMasterDevice> {
+    spi: &'a S,
+    handshake: &'a dyn gpio::InterruptPin<'a>,
+    data_ready: &'a dyn gpio::InterruptPin<'a>,
+    spi_write: TakeCell<'static, [u8]>,
+    spi_read: TakeCell<'static, [u8]>,
+    outgoing: Cell<Outgoing>,
+    /// The client's frame, returned once its transaction is done.
+    frame: TakeCell<'static, [u8]>,
+    link: Cell<Link>,
+    mac: OptionalCell<[u8; ADDRESS_LEN]>,
+    filter: Cell<Filter>,
+    seq: Cell<u16>,
+    client: OptionalCell<&'a dyn wifi::Client>,
+    tx_client: OptionalCell<&'a dyn ethernet::TxClient>,
+    rx_client: OptionalCell<&'a dyn ethernet::RxClient>,
+}
+
+impl<'a, S: spi::SpiMasterDevice> EspHosted<'a, S> {
+    /// `spi_write` and `spi_read` must hold `BUFFER_LEN` bytes.
+    pub fn new(
+        spi: &'a S,
+        handshake: &'a dyn gpio::InterruptPin<'a>,
+        data_ready: &'a dyn gpio::InterruptPin<'a>,
+        spi_write: &'static mut [u8],
+        spi_read: &'static mut [u8],
+    ) -> EspHosted<'a, S> {
+        EspHosted {
+            spi: spi,
+            handshake: handshake,
+            data_ready: data_ready,
+            spi_write: TakeCell::new(spi_write),
+            spi_read: TakeCell::new(spi_read),
+            outgoing: Cell::new(Outgoing::Nothing),
+            frame: TakeCell::empty(),
+            link: Cell::new(Link::Down),
+            mac: OptionalCell::empty(),
+            filter: Cell::new(Filter::DEFAULT),
+            seq: Cell::new(0),
+            client: OptionalCell::empty(),
+            tx_client: OptionalCell::empty(),
+            rx_client: OptionalCell::empty(),
+        }
+    }
+
+    /// Configure SPI and the pins. The ESP32 announces itself once it has
+    /// booted, and this driver then asks for its MAC address.
+    pub fn initialize(&self) {
+        self.spi.configure(
+            spi::ClockPolarity::IdleHigh,
+            spi::ClockPhase::SampleLeading,
+            10_000_000,
+        );
+        for pin in [self.handshake, self.data_ready].iter() {
+            pin.make_input();
+            pin.set_floating_state(gpio::FloatingState::PullDown);
+            pin.enable_interrupts(gpio::InterruptEdge::RisingEdge);
+        }
+        self.transfer();
+    }
+
+    /// Start a transaction if the ESP32 is ready and either side has
+    /// something to send.
+    fn transfer(&self) {
+        if !self.handshake.read() {
+            return;
+        }
+        if self.outgoing.get() == Outgoing::Nothing && !self.data_ready.read() {
+            return;
+        }
+        if self.spi_write.is_none() || self.spi_read.is_none() {
+            // A transaction is in progress.
+            return;
+        }
+        if let (Some(write), Some(read)) = (self.spi_write.take(), self.spi_read.take()) {
+            if self.outgoing.get() == Outgoing::Nothing {
+                for b in write[..HEADER_LEN].iter_mut() {
+                    *b = 0;
+                }
+            }
+            self.spi.read_write_bytes(write, Some(read), BUFFER_LEN);
+        }
+    }
+
+    /// Write the header for a payload of `len` bytes on interface `if_type`
+    /// and mark the write buffer as holding `outgoing`.
+    fn finish_packet(&self, buffer: &mut [u8], if_type: u8, len: usize, outgoing: Outgoing) {
+        let seq = self.seq.get();
+        self.seq.set(seq.wrapping_add(1));
+        buffer[0] = if_type;
+        buffer[1] = 0;
+        buffer[2..4].copy_from_slice(&(len as u16).to_le_bytes());
+        buffer[4..6].copy_from_slice(&(HEADER_LEN as u16).to_le_bytes());
+        buffer[6..8].copy_from_slice(&[0, 0]);
+        buffer[8..10].copy_from_slice(&seq.to_le_bytes());
+        buffer[10] = 0;
+        buffer[11] = 0;
+        let checksum = checksum(&buffer[..HEADER_LEN + len]);
+        buffer[6..8].copy_from_slice(&checksum.to_le_bytes());
+        self.outgoing.set(outgoing);
+    }
+
+    /// Send the control request `msg_id` with the encoded request `body`.
+    fn send_control<F: FnOnce(&mut [u8]) -> usize>(&self, msg_id: u32, body: F) -> ReturnCode {
+        if self.outgoing.get() != Outgoing::Nothing {
+            return ReturnCode::EBUSY;
+        }
+        self.spi_write.map_or(ReturnCode::EBUSY, |buffer| {
+            // The request goes in a field numbered after the message ID,
+            // after the message type and ID.
+            let mut message = [0; 128];
+            let mut off = pb_varint_field(&mut message, 0, 1, CTRL_MSG_REQUEST);
+            off = pb_varint_field(&mut message, off, 2, msg_id as u64);
+            let mut request = [0; 112];
+            let request_len = body(&mut request);
+            off = pb_bytes_field(&mut message, off, msg_id, &request[..request_len]);
+
+            let payload = &mut buffer[HEADER_LEN..];
+            payload[0] = TLV_EP_NAME;
+            payload[1..3].copy_from_slice(&(CTRL_EP_NAME.len() as u16).to_le_bytes());
+            payload[3..3 + CTRL_EP_NAME.len()].copy_from_slice(CTRL_EP_NAME);
+            let data = 3 + CTRL_EP_NAME.len();
+            payload[data] = TLV_DATA;
+            payload[data + 1..data + 3].copy_from_slice(&(off as u16).to_le_bytes());
+            payload[data + 3..data + 3 + off].copy_from_slice(&message[..off]);
+            self.finish_packet(buffer, IF_SERIAL, data + 3 + off, Outgoing::Control);
+            ReturnCode::SUCCESS
+        })
+    }
+
+    fn handle_packet(&self, buffer: &[u8]) {
+        let if_type = buffer[0] & 0x0f;
+        let len = u16::from_le_bytes([buffer[2], buffer[3]]) as usize;
+        let offset = u16::from_le_bytes([buffer[4], buffer[5]]) as usize;
+        if len == 0 || offset < HEADER_LEN || offset + len > buffer.len() {
+            return;
+        }
+        let payload = &buffer[offset..offset + len];
+        match if_type {
+            IF_STA => {
+                if self.link.get() == Link::Up && self.passes_filter(payload) {
+                    self.rx_client.map(|client| client.frame_received(payload));
+                }
+            }
+            IF_SERIAL => self.handle_control(payload),
+            IF_PRIV => {
+                if buffer[11] == PRIV_PACKET_TYPE_EVENT && payload[0] == PRIV_EVENT_INIT {
+                    self.send_control(REQ_GET_MAC_ADDRESS, |request| {
+                        pb_varint_field(request, 0, 1, WIFI_MODE_STA)
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Whether a received frame is one the filter lets through.
+    fn passes_filter(&self, frame: &[u8]) -> bool {
+        if frame.len() < ADDRESS_LEN {
+            return false;
+        }
+        let filter = self.filter.get();
+        if frame[..ADDRESS_LEN] == ethernet::BROADCAST {
+            filter.broadcast
+        } else if frame[0] & 0x01 != 0 {
+            filter.multicast
+        } else {
+            true
+        }
+    }
+
+    fn handle_control(&self, payload: &[u8]) {
+        // Skip the endpoint name to the data TLV.
+        let mut off = 0;
+        let mut message = None;
+        while off + 3 <= payload.len() {
+            let len = u16::from_le_bytes([payload[off + 1], payload[off + 2]]) as usize;
+            let end = cmp::min(off + 3 + len, payload.len());
+            if payload[off] == TLV_DATA {
+                message = Some(&payload[off + 3..end]);
+            }
+            off = end;
+        }
+        let message = match message {
+            Some(message) => message,
+            None => return,
+        };
+        let msg_type = pb_varint(message, 1).unwrap_or(0);
+        let msg_id = pb_varint(message, 2).unwrap_or(0);
+        let body = pb_bytes(message, msg_id as u32).unwrap_or(&[]);
+
+        match (msg_type, msg_id) {
+            (CTRL_MSG_RESPONSE, RESP_GET_MAC_ADDRESS) => {
+                if let Some(mac) = pb_bytes(body, 1).and_then(parse_mac) {
+                    self.mac.set(mac);
+                }
+            }
+            (CTRL_MSG_RESPONSE, RESP_CONNECT_AP) => {
+                if self.link.get() == Link::Joining {
+                    let result = if pb_varint(body, 1).unwrap_or(0) == 0 {
+                        self.link.set(Link::Up);
+                        ReturnCode::SUCCESS
+                    } else {
+                        self.link.set(Link::Down);
+                        ReturnCode::FAIL
+                    };
+                    self.client.map(|client| client.joined(result));
+                }
+            }
+            (CTRL_MSG_RESPONSE, RESP_DISCONNECT_AP)
+            | (CTRL_MSG_EVENT, EVENT_STATION_DISCONNECT_FROM_AP) => {
+                if self.link.get() == Link::Up || self.link.get() == Link::Leaving {
+                    self.link.set(Link::Down);
+                    self.client.map(|client| client.left());
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The sum of the bytes of the header and payload, with the checksum field
+/// zeroed.
+fn checksum(packet: &[u8]) -> u16 {
+    packet
+        .iter()
+        .fold(0u16, |sum, &b| sum.wrapping_add(b as u16))
+}
+
+/// The firmware reports MAC addresses as `aa:bb:cc:dd:ee:ff`.
+fn parse_mac(text: &[u8]) -> Option<[u8; ADDRESS_LEN]> {
+    if text.len() == ADDRESS_LEN {
+        let mut mac = [0; ADDRESS_LEN];
+        mac.copy_from_slice(text);
+        return Some(mac);
+    }
+    if text.len() != 17 {
+        return None;
+    }
+    let hex = |c: u8| match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        _ => None,
+    };
+    let mut mac = [0; ADDRESS_LEN];
+    for (i, byte) in mac.iter_mut().enumerate() {
+        *byte = hex(text[i * 3])? << 4 | hex(text[i * 3 + 1])?;
+    }
+    Some(mac)
+}
+
+fn pb_write_varint(buf: &mut [u8], mut off: usize, mut value: u64) -> usize {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            buf[off] = byte;
+            return off + 1;
+        }
+        buf[off] = byte | 0x80;
+        off += 1;
+    }
+}
+
+fn pb_varint_field(buf: &mut [u8], off: usize, field: u32, value: u64) -> usize {
+    let off = pb_write_varint(buf, off, (field as u64) << 3);
+    pb_write_varint(buf, off, value)
+}
+
+fn pb_bytes_field(buf: &mut [u8], off: usize, field: u32, value: &[u8]) -> usize {
+    let off = pb_write_varint(buf, off, (field as u64) << 3 | 2);
+    let off = pb_write_varint(buf, off, value.len() as u64);
+    buf[off..off + value.len()].copy_from_slice(value);
+    off + value.len()
+}
+
+fn pb_read_varint(buf: &[u8], off: &mut usize) -> Option<u64> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let byte = *buf.get(*off)?;
+        *off += 1;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// Find `field` in a protobuf message, returning its wire type and its
+/// value's offset.
+fn pb_find(buf: &[u8], field: u32) -> Option<(u64, usize)> {
+    let mut off = 0;
+    while off < buf.len() {
+        let key = pb_read_varint(buf, &mut off)?;
+        let wire_type = key & 0x7;
+        if key >> 3 == field as u64 {
+            return Some((wire_type, off));
+        }
+        match wire_type {
+            0 => {
+                pb_read_varint(buf, &mut off)?;
+            }
+            1 => off += 8,
+            2 => {
+                let len = pb_read_varint(buf, &mut off)? as usize;
+                off += len;
+            }
+            5 => off += 4,
+            _ => return None,
+        }
+    }
+    None
+}
+
+fn pb_varint(buf: &[u8], field: u32) -> Option<u64> {
+    match pb_find(buf, field)? {
+        (0, mut off) => pb_read_varint(buf, &mut off),
+        _ => None,
+    }
+}
+
+fn pb_bytes(buf: &[u8], field: u32) -> Option<&[u8]> {
+    match pb_find(buf, field)? {
+        (2, mut off) => {
+            let len = pb_read_varint(buf, &mut off)? as usize;
+            buf.get(off..off + len)
+        }
+        _ => None,
+    }
+}
+
+impl<'a, S: spi::SpiMasterDevice> wifi::Station<'a> for EspHosted<'a, S> {
+    fn set_client(&self, client: &'a dyn wifi::Client) {
+        self.client.set(client);
+    }
+
+    fn mac_address(&self) -> Option<[u8; ADDRESS_LEN]> {
+        self.mac.map(|mac| *mac)
+    }
+
+    fn join(&self, ssid: &[u8], passphrase: &[u8]) -> ReturnCode {
+        if ssid.is_empty() || ssid.len() > MAX_SSID_LEN || passphrase.len() > MAX_PASSPHRASE_LEN {
+            return ReturnCode::EINVAL;
+        }
+        if self.link.get() != Link::Down {
+            return ReturnCode::EALREADY;
+        }
+        let rcode = self.send_control(REQ_CONNECT_AP, |request| {
+            let off = pb_bytes_field(request, 0, 1, ssid);
+            pb_bytes_field(request, off, 2, passphrase)
+        });
+        if rcode == ReturnCode::SUCCESS {
+            self.link.set(Link::Joining);
+            self.transfer();
+        }
+        rcode
+    }
+
+    fn leave(&self) -> ReturnCode {
+        if self.link.get() != Link::Up {
+            return ReturnCode::EALREADY;
+        }
+        let rcode = self.send_control(REQ_DISCONNECT_AP, |_| 0);
+        if rcode == ReturnCode::SUCCESS {
+            self.link.set(Link::Leaving);
+            self.transfer();
+        }
+        rcode
+    }
+}
+
+impl<'a, S: spi::SpiMasterDevice> ethernet::Mac<'a> for EspHosted<'a, S> {
+    fn set_transmit_client(&self, client: &'a dyn ethernet::TxClient) {
+        self.tx_client.set(client);
+    }
+
+    fn set_receive_client(&self, client: &'a dyn ethernet::RxClient) {
+        self.rx_client.set(client);
+    }
+
+    /// All zeros until the ESP32 has reported its address.
+    fn mac_address(&self) -> [u8; ADDRESS_LEN] {
+        self.mac.map_or([0; ADDRESS_LEN], |mac| *mac)
+    }
+
+    /// The ESP32 keeps its own address, so this only succeeds for it.
+    fn set_mac_address(&self, address: [u8; ADDRESS_LEN]) -> ReturnCode {
+        if self.mac.contains(&address) {
+            ReturnCode::SUCCESS
+        } else {
+            ReturnCode::ENOSUPPORT
+        }
+    }
+
+    fn set_filter(&self, filter: Filter) -> ReturnCode {
+        if filter.promiscuous {
+            return ReturnCode::ENOSUPPORT;
+        }
+        self.filter.set(filter);
+        ReturnCode::SUCCESS
+    }
+
+    fn max_frame_len(&self) -> usize {
+        MAX_FRAME_LEN
+    }
+
+    /// Returns `EOFF` while the station has not joined an access point.
+    fn transmit(
+        &self,
+        frame: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        if self.link.get() != Link::Up {
+            return Err((ReturnCode::EOFF, frame));
+        }
+        if len > MAX_FRAME_LEN || len > frame.len() {
+            return Err((ReturnCode::ESIZE, frame));
+        }
+        if self.outgoing.get() != Outgoing::Nothing {
+            return Err((ReturnCode::EBUSY, frame));
+        }
+        let copied = self.spi_write.map_or(false, |buffer| {
+            buffer[HEADER_LEN..HEADER_LEN + len].copy_from_slice(&frame[..len]);
+            self.finish_packet(buffer, IF_STA, len, Outgoing::Frame);
+            true
+        });
+        if !copied {
+            return Err((ReturnCode::EBUSY, frame));
+        }
+        self.frame.replace(frame);
+        self.transfer();
+        Ok(())
+    }
+}
+
+impl<'a, S: spi::SpiMasterDevice> spi::SpiMasterClient for EspHosted<'a, S> {
+    fn read_write_done(
+        &self,
+        write_buffer: &'static mut [u8],
+        read_buffer: Option<&'static mut [u8]>,
+        _len: usize,
+    ) {
+        self.spi_write.replace(write_buffer);
+        let sent = self.outgoing.replace(Outgoing::Nothing);
+        if sent == Outgoing::Frame {
+            self.frame.take().map(|frame| {
+                self.tx_client
+                    .map(move |client| client.transmit_done(frame, ReturnCode::SUCCESS));
+            });
+        }
+
+        read_buffer.map(|buffer| {
+            let len = u16::from_le_bytes([buffer[2], buffer[3]]) as usize;
+            let expected = u16::from_le_bytes([buffer[6], buffer[7]]);
+            buffer[6] = 0;
+            buffer[7] = 0;
+            if HEADER_LEN + len <= buffer.len() && checksum(&buffer[..HEADER_LEN + len]) == expected
+            {
+                self.handle_packet(buffer);
+            }
+            self.spi_read.replace(buffer);
+        });
+        self.transfer();
+    }
+}
+
+impl<'a, S: spi::SpiMasterDevice> gpio::Client for EspHosted<'a, S> {
+    fn fired(&self) {
+        self.transfer();
+    }
+}
@@ -0,0 +1,1210 @@
+//! FAT16 and FAT32 file system on a `hil::block_storage::BlockStorage`.
+//!
+//! Mounts the first partition of a card with an MBR partition table, or a
+//! card formatted without one, so that files written here can be read on a
+//! PC and the other way around. Files are named by paths of 8.3 short names,
+//! such as `/LOGS/DATA.CSV`, which match without regard to case; the long
+//! names a PC adds are skipped. Files can be created, read, overwritten and
+//! appended to, and reading a directory gives its entries as
+//! `DIR_ENTRY_LEN` byte records: the name as `NAME.EXT`, padded with zeros
+//! to 12 bytes, the attribute byte, three reserved bytes and the size as a
+//! little endian `u32`.
+//!
+//! One operation runs at a time, through a single sector buffer. Each write
+//! ends by updating the directory entry of the file and writing every
+//! changed sector back, so the file system is intact if power is lost
+//! between writes. Deleting files and creating or growing directories are
+//! not supported, the free cluster count of FAT32 is left as it was, and
+//! files are stamped with a fixed date as there is no clock.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let fat = static_init!(
+//!     capsules::fat::Fat<'static>,
+//!     capsules::fat::Fat::new(sdcard, &mut capsules::fat::SECTOR_BUFFER, dynamic_deferred_caller)
+//! );
+//! fat.initialize_callback_handle(
+//!     dynamic_deferred_caller
+//!         .register(fat)
+//!         .expect("no deferred call slot available for the FAT file system"),
+//! );
+//! hil::block_storage::BlockStorage::set_client(sdcard, fat);
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::dynamic_deferred_call::{
+    DeferredCallHandle, DynamicDeferredCall, DynamicDeferredCallClient,
+};
+use kernel::hil::block_storage::{self, BlockStorage};
+use kernel::ReturnCode;
+
+/// The only sector size supported.
+pub const SECTOR_LEN: usize = 512;
+
+pub static mut SECTOR_BUFFER: [u8; SECTOR_LEN] = [0; SECTOR_LEN];
+
+/// The most files and directories open at once.
+pub const MAX_FILES: usize = 4;
+
+/// The longest path that can be opened.
+pub const MAX_PATH_LEN: usize = 64;
+
+/// The length of the records a directory is read as.
+pub const DIR_ENTRY_LEN: usize = 20;
+
+pub const ATTR_READ_ONLY: u8 = 0x01;
+pub const ATTR_HIDDEN: u8 = 0x02;
+pub const ATTR_SYSTEM: u8 = 0x04;
+pub const ATTR_DIRECTORY: u8 = 0x10;
+pub const ATTR_ARCHIVE: u8 = 0x20;
+
+/// Set in volume labels and, with the three attributes below it, in the
+/// entries of long names.
+const ATTR_VOLUME_ID: u8 = 0x08;
+
+const ENTRY_LEN: usize = 32;
+const ENTRY_END: u8 = 0x00;
+const ENTRY_FREE: u8 = 0xE5;
+
+/// Cluster 1 is never used, so it stands for the root directory of FAT16,
+/// which is not in a cluster chain.
+const ROOT_REGION: u32 = 1;
+
+/// 2020-01-01, the date files are stamped with.
+const DATE: u16 = (40 << 9) | (1 << 5) | 1;
+
+/// Callbacks from `Fat`.
+pub trait FatClient {
+    fn mount_done(&self, result: ReturnCode);
+
+    /// With the handle of the file on success.
+    fn open_done(&self, result: Result<usize, ReturnCode>);
+
+    /// `len` bytes were read into `buf`.
+    fn read_done(&self, buf: &'static mut [u8], len: usize, result: ReturnCode);
+
+    /// `len` bytes of `buf` were written.
+    fn write_done(&self, buf: &'static mut [u8], len: usize, result: ReturnCode);
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum FatType {
+    Fat16,
+    Fat32,
+}
+
+#[derive(Copy, Clone)]
+struct Volume {
+    fat_type: FatType,
+    /// The first sector of the first FAT.
+    fat_start: u32,
+    fat_sectors: u32,
+    num_fats: u32,
+    /// The root directory of FAT16.
+    root_start: u32,
+    root_sectors: u32,
+    /// The root directory of FAT32.
+    root_cluster: u32,
+    /// The first sector of cluster 2.
+    data_start: u32,
+    cluster_sectors: u32,
+    cluster_count: u32,
+}
+
+impl Volume {
+    fn is_cluster(&self, cluster: u32) -> bool {
+        cluster >= 2 && cluster - 2 < self.cluster_count
+    }
+
+    fn cluster_len(&self) -> u32 {
+        self.cluster_sectors * SECTOR_LEN as u32
+    }
+
+    fn cluster_lba(&self, cluster: u32) -> u32 {
+        self.data_start + (cluster - 2) * self.cluster_sectors
+    }
+
+    fn root(&self) -> u32 {
+        match self.fat_type {
+            FatType::Fat16 => ROOT_REGION,
+            FatType::Fat32 => self.root_cluster,
+        }
+    }
+
+    fn end_of_chain(&self) -> u32 {
+        match self.fat_type {
+            FatType::Fat16 => 0xFFFF,
+            FatType::Fat32 => 0x0FFF_FFFF,
+        }
+    }
+
+    /// The sector of the entry of `cluster` in FAT `copy`, and its offset.
+    fn fat_position(&self, cluster: u32, copy: u32) -> (u32, usize) {
+        let offset = match self.fat_type {
+            FatType::Fat16 => cluster * 2,
+            FatType::Fat32 => cluster * 4,
+        };
+        (
+            self.fat_start + copy * self.fat_sectors + offset / SECTOR_LEN as u32,
+            offset as usize % SECTOR_LEN,
+        )
+    }
+}
+
+/// A position in a cluster chain.
+#[derive(Copy, Clone)]
+struct Chain {
+    /// The first cluster, 0 for an empty file.
+    first: u32,
+    /// Cluster number `index` of the chain.
+    cluster: u32,
+    index: u32,
+}
+
+impl Chain {
+    fn new(first: u32) -> Chain {
+        Chain {
+            first: first,
+            cluster: first,
+            index: 0,
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+struct File {
+    /// The sector and offset of the directory entry, which the root
+    /// directory has none of.
+    entry: Option<(u32, usize)>,
+    dir: bool,
+    size: u32,
+    pos: u32,
+    chain: Chain,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum OpenStage {
+    Search,
+    /// Writing a new entry in the free one found.
+    Create,
+    Flush,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum WriteStage {
+    Data,
+    /// Looking for a free cluster, with `left` clusters yet to look at.
+    Allocate {
+        next: u32,
+        left: u32,
+    },
+    /// Marking the free cluster as the end of a chain.
+    Claim {
+        cluster: u32,
+    },
+    /// Linking the claimed cluster onto the end of the file.
+    Link {
+        cluster: u32,
+    },
+    Entry,
+    Flush,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Op {
+    Idle,
+    /// Reading sector 0, or the boot sector of the partition at `boot`.
+    Mount {
+        boot: Option<u32>,
+    },
+    Open {
+        handle: usize,
+        create: bool,
+        stage: OpenStage,
+    },
+    Read {
+        handle: usize,
+        len: usize,
+        done: usize,
+    },
+    Write {
+        handle: usize,
+        len: usize,
+        done: usize,
+        stage: WriteStage,
+    },
+}
+
+/// Why a step of an operation stopped before the end.
+enum Stop {
+    /// It waits for a sector, and goes on when it is read or written.
+    Pending,
+    Error(ReturnCode),
+}
+
+enum BootSector {
+    Volume(Volume),
+    /// A partition table, with the first partition at this sector.
+    Partition(u32),
+}
+
+fn u16_at(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([buf[offset], buf[offset + 1]])
+}
+
+fn u32_at(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        buf[offset],
+        buf[offset + 1],
+        buf[offset + 2],
+        buf[offset + 3],
+    ])
+}
+
+fn parse_boot_sector(sector: &[u8], lba: u32) -> Result<BootSector, ReturnCode> {
+    if sector[510] != 0x55 || sector[511] != 0xAA {
+        return Err(ReturnCode::FAIL);
+    }
+    let jump = sector[0] == 0xEB || sector[0] == 0xE9;
+    if !jump || u16_at(sector, 11) as usize != SECTOR_LEN {
+        // The partition type of the first entry, then its start.
+        return match sector[450] {
+            0 => Err(ReturnCode::FAIL),
+            _ => Ok(BootSector::Partition(u32_at(sector, 454))),
+        };
+    }
+
+    let cluster_sectors = sector[13] as u32;
+    let reserved = u16_at(sector, 14) as u32;
+    let num_fats = sector[16] as u32;
+    let root_entries = u16_at(sector, 17) as u32;
+    let total = match u16_at(sector, 19) {
+        0 => u32_at(sector, 32),
+        total => total as u32,
+    };
+    let fat_sectors = match u16_at(sector, 22) {
+        0 => u32_at(sector, 36),
+        fat_sectors => fat_sectors as u32,
+    };
+    if !cluster_sectors.is_power_of_two() || reserved == 0 || num_fats == 0 {
+        return Err(ReturnCode::FAIL);
+    }
+    let root_sectors =
+        (root_entries * ENTRY_LEN as u32 + SECTOR_LEN as u32 - 1) / SECTOR_LEN as u32;
+    let meta = reserved as u64 + num_fats as u64 * fat_sectors as u64 + root_sectors as u64;
+    if meta >= total as u64 {
+        return Err(ReturnCode::FAIL);
+    }
+    let cluster_count = (total - meta as u32) / cluster_sectors;
+    let fat_type = if cluster_count < 4085 {
+        // FAT12
+        return Err(ReturnCode::ENOSUPPORT);
+    } else if cluster_count < 65525 {
+        FatType::Fat16
+    } else {
+        FatType::Fat32
+    };
+
+    let volume = Volume {
+        fat_type: fat_type,
+        fat_start: lba + reserved,
+        fat_sectors: fat_sectors,
+        num_fats: num_fats,
+        root_start: lba + reserved + num_fats * fat_sectors,
+        root_sectors: root_sectors,
+        root_cluster: u32_at(sector, 44),
+        data_start: lba + meta as u32,
+        cluster_sectors: cluster_sectors,
+        cluster_count: cluster_count,
+    };
+    if fat_type == FatType::Fat32 && !volume.is_cluster(volume.root_cluster) {
+        return Err(ReturnCode::FAIL);
+    }
+    Ok(BootSector::Volume(volume))
+}
+
+/// The name of a directory entry for a path component, if it is a valid 8.3
+/// name.
+fn short_name(name: &[u8]) -> Option<[u8; 11]> {
+    let (base, ext) = match name.iter().position(|&b| b == b'.') {
+        Some(dot) => (&name[..dot], &name[dot + 1..]),
+        None => (name, &[][..]),
+    };
+    if base.is_empty() || base.len() > 8 || ext.len() > 3 {
+        return None;
+    }
+    let mut short = [b' '; 11];
+    let (short_base, short_ext) = short.split_at_mut(8);
+    for (c, &b) in short_base
+        .iter_mut()
+        .zip(base)
+        .chain(short_ext.iter_mut().zip(ext))
+    {
+        let b = b.to_ascii_uppercase();
+        if !b.is_ascii_alphanumeric() && !b"!#$%&'()-@^_`{}~".contains(&b) {
+            return None;
+        }
+        *c = b;
+    }
+    Some(short)
+}
+
+/// The record a directory entry is read as.
+fn dir_record(entry: &[u8]) -> [u8; DIR_ENTRY_LEN] {
+    let mut record = [0; DIR_ENTRY_LEN];
+    let mut len = 0;
+    for &b in entry[..8].iter().filter(|&&b| b != b' ') {
+        record[len] = b;
+        len += 1;
+    }
+    if entry[8] != b' ' {
+        record[len] = b'.';
+        len += 1;
+        for &b in entry[8..11].iter().filter(|&&b| b != b' ') {
+            record[len] = b;
+            len += 1;
+        }
+    }
+    record[12] = entry[11];
+    record[16..20].copy_from_slice(&entry[28..32]);
+    record
+}
+
+pub struct Fat<'a> {
+    storage: &'a dyn BlockStorage<'a>,
+    client: OptionalCell<&'a dyn FatClient>,
+    deferred_caller: &'a DynamicDeferredCall,
+    handle: OptionalCell<DeferredCallHandle>,
+
+    volume: OptionalCell<Volume>,
+    files: Cell<[Option<File>; MAX_FILES]>,
+    op: Cell<Op>,
+    /// The buffer of a read or write.
+    client_buf: TakeCell<'static, [u8]>,
+
+    /// The sector buffer, the sector in it and whether it has changed since
+    /// it was read.
+    sector: TakeCell<'static, [u8]>,
+    sector_lba: Cell<Option<u32>>,
+    sector_dirty: Cell<bool>,
+    /// The sector being read or written.
+    io_lba: Cell<u32>,
+
+    /// The FAT copies a cluster has been updated in so far.
+    fat_copy: Cell<u32>,
+    /// Where the search for a free cluster starts.
+    next_free: Cell<u32>,
+
+    /// The path being opened, and the start of the name looked for in it.
+    path: Cell<[u8; MAX_PATH_LEN]>,
+    path_len: Cell<usize>,
+    name_start: Cell<usize>,
+    /// The directory searched, the entry looked at and the first free entry.
+    dir: Cell<Chain>,
+    dir_entry: Cell<u32>,
+    free_entry: Cell<Option<(u32, usize)>>,
+}
+
+impl<'a> Fat<'a> {
+    /// `sector` must be at least `SECTOR_LEN` bytes long.
+    pub fn new(
+        storage: &'a dyn BlockStorage<'a>,
+        sector: &'static mut [u8],
+        deferred_caller: &'a DynamicDeferredCall,
+    ) -> Fat<'a> {
+        Fat {
+            storage: storage,
+            client: OptionalCell::empty(),
+            deferred_caller: deferred_caller,
+            handle: OptionalCell::empty(),
+            volume: OptionalCell::empty(),
+            files: Cell::new([None; MAX_FILES]),
+            op: Cell::new(Op::Idle),
+            client_buf: TakeCell::empty(),
+            sector: TakeCell::new(sector),
+            sector_lba: Cell::new(None),
+            sector_dirty: Cell::new(false),
+            io_lba: Cell::new(0),
+            fat_copy: Cell::new(0),
+            next_free: Cell::new(2),
+            path: Cell::new([0; MAX_PATH_LEN]),
+            path_len: Cell::new(0),
+            name_start: Cell::new(0),
+            dir: Cell::new(Chain::new(0)),
+            dir_entry: Cell::new(0),
+            free_entry: Cell::new(None),
+        }
+    }
+
+    pub fn initialize_callback_handle(&self, handle: DeferredCallHandle) {
+        self.handle.replace(handle);
+    }
+
+    pub fn set_client(&self, client: &'a dyn FatClient) {
+        self.client.set(client);
+    }
+
+    pub fn is_mounted(&self) -> bool {
+        self.volume.is_some()
+    }
+
+    /// Mounts the file system on the storage, which must be ready. Returns
+    /// `EALREADY` if it is mounted.
+    pub fn mount(&self) -> ReturnCode {
+        if self.volume.is_some() {
+            return ReturnCode::EALREADY;
+        }
+        if self.storage.block_size() != SECTOR_LEN {
+            return ReturnCode::ENOSUPPORT;
+        }
+        if self.storage.block_count() == 0 {
+            return ReturnCode::EOFF;
+        }
+        if self.op.get() != Op::Idle {
+            return ReturnCode::EBUSY;
+        }
+        self.sector_lba.set(None);
+        self.files.set([None; MAX_FILES]);
+        self.start(Op::Mount { boot: None })
+    }
+
+    /// Opens the file or directory at `path`, relative to the root
+    /// directory, or opens the root directory if `path` is empty. With
+    /// `create`, a file that does not exist is created empty in its
+    /// directory, or else `ENOSUPPORT` is returned in `open_done()`.
+    pub fn open(&self, path: &[u8], create: bool) -> ReturnCode {
+        let volume = match self.volume.map(|volume| *volume) {
+            Some(volume) => volume,
+            None => return ReturnCode::EOFF,
+        };
+        if self.op.get() != Op::Idle {
+            return ReturnCode::EBUSY;
+        }
+        let start = path.iter().position(|&b| b != b'/').unwrap_or(path.len());
+        let end = path
+            .iter()
+            .rposition(|&b| b != b'/')
+            .map_or(start, |i| i + 1);
+        let path = &path[start..end];
+        if path.len() > MAX_PATH_LEN {
+            return ReturnCode::ESIZE;
+        }
+        let handle = match self.files.get().iter().position(|file| file.is_none()) {
+            Some(handle) => handle,
+            None => return ReturnCode::ENOMEM,
+        };
+
+        let mut stored = [0; MAX_PATH_LEN];
+        stored[..path.len()].copy_from_slice(path);
+        self.path.set(stored);
+        self.path_len.set(path.len());
+        self.name_start.set(0);
+        self.dir.set(Chain::new(volume.root()));
+        self.dir_entry.set(0);
+        self.free_entry.set(None);
+        self.start(Op::Open {
+            handle: handle,
+            create: create,
+            stage: OpenStage::Search,
+        })
+    }
+
+    pub fn close(&self, handle: usize) -> ReturnCode {
+        self.update_file(handle, |_| Ok(None))
+    }
+
+    /// Reads up to `len` bytes from the position of a file, or the entries
+    /// that fit in `len` bytes from a directory.
+    pub fn read(
+        &self,
+        handle: usize,
+        buf: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        match self.file(handle) {
+            Some(_) if len > buf.len() => Err((ReturnCode::ESIZE, buf)),
+            Some(_) if self.op.get() != Op::Idle => Err((ReturnCode::EBUSY, buf)),
+            Some(_) => {
+                self.client_buf.replace(buf);
+                self.start(Op::Read {
+                    handle: handle,
+                    len: len,
+                    done: 0,
+                });
+                Ok(())
+            }
+            None => Err((ReturnCode::EINVAL, buf)),
+        }
+    }
+
+    /// Writes `len` bytes at the position of a file, overwriting the bytes
+    /// there and extending it past its end.
+    pub fn write(
+        &self,
+        handle: usize,
+        buf: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        match self.file(handle) {
+            Some(file) if file.dir => Err((ReturnCode::EINVAL, buf)),
+            Some(_) if len > buf.len() => Err((ReturnCode::ESIZE, buf)),
+            Some(_) if self.op.get() != Op::Idle => Err((ReturnCode::EBUSY, buf)),
+            Some(_) => {
+                self.client_buf.replace(buf);
+                self.start(Op::Write {
+                    handle: handle,
+                    len: len,
+                    done: 0,
+                    stage: WriteStage::Data,
+                });
+                Ok(())
+            }
+            None => Err((ReturnCode::EINVAL, buf)),
+        }
+    }
+
+    /// Writes `len` bytes at the end of a file.
+    pub fn append(
+        &self,
+        handle: usize,
+        buf: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        let size = self.file(handle).map_or(0, |file| file.size);
+        match self.seek(handle, size) {
+            ReturnCode::SUCCESS => self.write(handle, buf, len),
+            rcode => Err((rcode, buf)),
+        }
+    }
+
+    /// Moves the position of a file, to at most its end. Directories can
+    /// only be moved back to their start.
+    pub fn seek(&self, handle: usize, pos: u32) -> ReturnCode {
+        self.update_file(handle, |mut file| {
+            if pos > file.size && !(file.dir && pos == 0) {
+                return Err(ReturnCode::EINVAL);
+            }
+            file.pos = pos;
+            Ok(Some(file))
+        })
+    }
+
+    pub fn size(&self, handle: usize) -> Result<u32, ReturnCode> {
+        self.file(handle)
+            .map(|file| file.size)
+            .ok_or(ReturnCode::EINVAL)
+    }
+
+    pub fn is_dir(&self, handle: usize) -> Result<bool, ReturnCode> {
+        self.file(handle)
+            .map(|file| file.dir)
+            .ok_or(ReturnCode::EINVAL)
+    }
+
+    fn file(&self, handle: usize) -> Option<File> {
+        self.files.get().get(handle).and_then(|file| *file)
+    }
+
+    fn set_file(&self, handle: usize, file: Option<File>) {
+        let mut files = self.files.get();
+        files[handle] = file;
+        self.files.set(files);
+    }
+
+    /// Replaces an open file that no operation is using.
+    fn update_file<F>(&self, handle: usize, f: F) -> ReturnCode
+    where
+        F: FnOnce(File) -> Result<Option<File>, ReturnCode>,
+    {
+        let file = match self.file(handle) {
+            Some(file) => file,
+            None => return ReturnCode::EINVAL,
+        };
+        match self.op.get() {
+            Op::Read { handle: h, .. } | Op::Write { handle: h, .. } if h == handle => {
+                return ReturnCode::EBUSY;
+            }
+            _ => {}
+        }
+        match f(file) {
+            Ok(file) => {
+                self.set_file(handle, file);
+                ReturnCode::SUCCESS
+            }
+            Err(rcode) => rcode,
+        }
+    }
+
+    /// Starts `op` from a deferred call, so that its callback is never
+    /// called before the call that starts it returns.
+    fn start(&self, op: Op) -> ReturnCode {
+        self.handle.map_or(ReturnCode::FAIL, |handle| {
+            self.op.set(op);
+            self.fat_copy.set(0);
+            self.deferred_caller.set(*handle);
+            ReturnCode::SUCCESS
+        })
+    }
+
+    /// Runs the operation until it waits for a sector or ends.
+    fn run(&self) {
+        let result = match self.op.get() {
+            Op::Idle => return,
+            Op::Mount { boot } => self.step_mount(boot),
+            Op::Open {
+                handle,
+                create,
+                stage,
+            } => self.step_open(handle, create, stage),
+            Op::Read { handle, len, done } => self.step_read(handle, len, done),
+            Op::Write {
+                handle,
+                len,
+                done,
+                stage,
+            } => self.step_write(handle, len, done, stage),
+        };
+        match result {
+            Ok(()) => self.finish(ReturnCode::SUCCESS),
+            Err(Stop::Pending) => {}
+            Err(Stop::Error(rcode)) => self.finish(rcode),
+        }
+    }
+
+    fn finish(&self, result: ReturnCode) {
+        let op = self.op.replace(Op::Idle);
+        match op {
+            Op::Idle => {}
+            Op::Mount { .. } => {
+                if result != ReturnCode::SUCCESS {
+                    self.volume.clear();
+                }
+                self.client.map(|client| client.mount_done(result));
+            }
+            Op::Open { handle, .. } => {
+                if result != ReturnCode::SUCCESS {
+                    self.set_file(handle, None);
+                }
+                self.client.map(|client| {
+                    client.open_done(match result {
+                        ReturnCode::SUCCESS => Ok(handle),
+                        rcode => Err(rcode),
+                    })
+                });
+            }
+            Op::Read { done, .. } => {
+                self.client_buf.take().map(|buf| {
+                    self.client
+                        .map(move |client| client.read_done(buf, done, result));
+                });
+            }
+            Op::Write { done, .. } => {
+                self.client_buf.take().map(|buf| {
+                    self.client
+                        .map(move |client| client.write_done(buf, done, result));
+                });
+            }
+        }
+    }
+
+    fn volume(&self) -> Result<Volume, Stop> {
+        self.volume
+            .map(|volume| *volume)
+            .ok_or(Stop::Error(ReturnCode::EOFF))
+    }
+
+    fn open_file(&self, handle: usize) -> Result<File, Stop> {
+        self.file(handle).ok_or(Stop::Error(ReturnCode::EINVAL))
+    }
+
+    /// Brings sector `lba` into the sector buffer, writing back the one in
+    /// it first if it has changed. Without `read`, the sector is about to be
+    /// overwritten whole, so it is not read.
+    fn load(&self, lba: u32, read: bool) -> Result<(), Stop> {
+        if self.sector_lba.get() == Some(lba) {
+            return Ok(());
+        }
+        if self.sector_dirty.get() {
+            // The load is tried again once the sector is written.
+            return self.flush();
+        }
+        if read {
+            self.sector_lba.set(None);
+            self.start_io(lba, false)
+        } else {
+            self.sector_lba.set(Some(lba));
+            Ok(())
+        }
+    }
+
+    fn start_io(&self, lba: u32, write: bool) -> Result<(), Stop> {
+        let buf = self.sector.take().ok_or(Stop::Error(ReturnCode::EBUSY))?;
+        self.io_lba.set(lba);
+        let result = if write {
+            self.storage.write_blocks(buf, lba, 1)
+        } else {
+            self.storage.read_blocks(buf, lba, 1)
+        };
+        match result {
+            Ok(()) => Err(Stop::Pending),
+            Err((rcode, buf)) => {
+                self.sector.replace(buf);
+                Err(Stop::Error(rcode))
+            }
+        }
+    }
+
+    /// Writes back the sector in the sector buffer, if it has changed.
+    fn flush(&self) -> Result<(), Stop> {
+        match self.sector_lba.get() {
+            Some(lba) if self.sector_dirty.get() => self.start_io(lba, true),
+            _ => Ok(()),
+        }
+    }
+
+    fn read_sector<F, R>(&self, lba: u32, f: F) -> Result<R, Stop>
+    where
+        F: FnOnce(&[u8]) -> R,
+    {
+        self.load(lba, true)?;
+        self.sector
+            .map(|sector| f(&sector[..SECTOR_LEN]))
+            .ok_or(Stop::Error(ReturnCode::FAIL))
+    }
+
+    /// Changes sector `lba`, which `f` overwrites whole if `whole` is set.
+    fn modify_sector<F, R>(&self, lba: u32, whole: bool, f: F) -> Result<R, Stop>
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        self.load(lba, !whole)?;
+        self.sector_dirty.set(true);
+        self.sector
+            .map(|sector| f(&mut sector[..SECTOR_LEN]))
+            .ok_or(Stop::Error(ReturnCode::FAIL))
+    }
+
+    fn fat_entry(&self, volume: &Volume, cluster: u32) -> Result<u32, Stop> {
+        let (lba, offset) = volume.fat_position(cluster, 0);
+        self.read_sector(lba, |sector| match volume.fat_type {
+            FatType::Fat16 => u16_at(sector, offset) as u32,
+            FatType::Fat32 => u32_at(sector, offset) & 0x0FFF_FFFF,
+        })
+    }
+
+    /// Sets the entry of `cluster` in every FAT. Only one entry can be set
+    /// at a time, as the copies done so far are kept in `fat_copy`.
+    fn set_fat_entry(&self, volume: &Volume, cluster: u32, value: u32) -> Result<(), Stop> {
+        while self.fat_copy.get() < volume.num_fats {
+            let (lba, offset) = volume.fat_position(cluster, self.fat_copy.get());
+            self.modify_sector(lba, false, |sector| match volume.fat_type {
+                FatType::Fat16 => {
+                    sector[offset..offset + 2].copy_from_slice(&(value as u16).to_le_bytes());
+                }
+                FatType::Fat32 => {
+                    // The top four bits are reserved.
+                    let value = (u32_at(sector, offset) & 0xF000_0000) | value;
+                    sector[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+                }
+            })?;
+            self.fat_copy.set(self.fat_copy.get() + 1);
+        }
+        self.fat_copy.set(0);
+        Ok(())
+    }
+
+    /// The sector holding byte `pos` of a chain, or `None` past its end.
+    /// `chain` is left at the cluster of `pos`, or its last cluster.
+    fn locate(&self, volume: &Volume, chain: &mut Chain, pos: u32) -> Result<Option<u32>, Stop> {
+        if chain.first == ROOT_REGION {
+            let sector = pos / SECTOR_LEN as u32;
+            return Ok(if sector < volume.root_sectors {
+                Some(volume.root_start + sector)
+            } else {
+                None
+            });
+        }
+        if !volume.is_cluster(chain.first) {
+            return Ok(None);
+        }
+        let index = pos / volume.cluster_len();
+        if index < chain.index {
+            *chain = Chain::new(chain.first);
+        }
+        while chain.index < index {
+            let next = self.fat_entry(volume, chain.cluster)?;
+            if !volume.is_cluster(next) {
+                return Ok(None);
+            }
+            chain.cluster = next;
+            chain.index += 1;
+        }
+        Ok(Some(
+            volume.cluster_lba(chain.cluster) + (pos % volume.cluster_len()) / SECTOR_LEN as u32,
+        ))
+    }
+
+    fn step_mount(&self, boot: Option<u32>) -> Result<(), Stop> {
+        let lba = boot.unwrap_or(0);
+        let parsed = self.read_sector(lba, |sector| parse_boot_sector(sector, lba))?;
+        match parsed {
+            Ok(BootSector::Volume(volume)) => {
+                self.volume.set(volume);
+                self.next_free.set(2);
+                Ok(())
+            }
+            Ok(BootSector::Partition(start)) if boot.is_none() && start != 0 => {
+                self.op.set(Op::Mount { boot: Some(start) });
+                self.step_mount(Some(start))
+            }
+            Ok(BootSector::Partition(_)) => Err(Stop::Error(ReturnCode::FAIL)),
+            Err(rcode) => Err(Stop::Error(rcode)),
+        }
+    }
+
+    fn step_open(&self, handle: usize, create: bool, stage: OpenStage) -> Result<(), Stop> {
+        let volume = self.volume()?;
+        let path = self.path.get();
+        let path_len = self.path_len.get();
+        if path_len == 0 {
+            self.set_file(
+                handle,
+                Some(File {
+                    entry: None,
+                    dir: true,
+                    size: 0,
+                    pos: 0,
+                    chain: Chain::new(volume.root()),
+                }),
+            );
+            return Ok(());
+        }
+
+        let mut stage = stage;
+        loop {
+            self.op.set(Op::Open {
+                handle: handle,
+                create: create,
+                stage: stage,
+            });
+            let start = self.name_start.get();
+            let end = path[start..path_len]
+                .iter()
+                .position(|&b| b == b'/')
+                .map_or(path_len, |i| start + i);
+            let last = end == path_len;
+            let name = short_name(&path[start..end]).ok_or(Stop::Error(ReturnCode::EINVAL))?;
+
+            match stage {
+                OpenStage::Search => {
+                    let mut dir = self.dir.get();
+                    let pos = self.dir_entry.get() * ENTRY_LEN as u32;
+                    let located = self.locate(&volume, &mut dir, pos);
+                    self.dir.set(dir);
+                    let lba = match located? {
+                        Some(lba) => lba,
+                        None => {
+                            // The end of a full directory.
+                            if !last || !create || self.free_entry.get().is_none() {
+                                return Err(Stop::Error(if last && create {
+                                    ReturnCode::ENOMEM
+                                } else {
+                                    ReturnCode::ENOSUPPORT
+                                }));
+                            }
+                            stage = OpenStage::Create;
+                            continue;
+                        }
+                    };
+                    let offset = pos as usize % SECTOR_LEN;
+                    let mut entry = [0; ENTRY_LEN];
+                    self.read_sector(lba, |sector| {
+                        entry.copy_from_slice(&sector[offset..offset + ENTRY_LEN])
+                    })?;
+
+                    if entry[0] == ENTRY_END || entry[0] == ENTRY_FREE {
+                        if self.free_entry.get().is_none() {
+                            self.free_entry.set(Some((lba, offset)));
+                        }
+                        if entry[0] == ENTRY_FREE {
+                            self.dir_entry.set(self.dir_entry.get() + 1);
+                            continue;
+                        }
+                        // The entries after the end are all free.
+                        if !last || !create {
+                            return Err(Stop::Error(ReturnCode::ENOSUPPORT));
+                        }
+                        stage = OpenStage::Create;
+                        continue;
+                    }
+                    self.dir_entry.set(self.dir_entry.get() + 1);
+                    if entry[11] & ATTR_VOLUME_ID != 0 || entry[..11] != name {
+                        continue;
+                    }
+
+                    let mut first = u16_at(&entry, 26) as u32;
+                    if volume.fat_type == FatType::Fat32 {
+                        first |= (u16_at(&entry, 20) as u32) << 16;
+                    }
+                    let dir = entry[11] & ATTR_DIRECTORY != 0;
+                    // The `..` entries of directories in the root point to
+                    // cluster 0.
+                    if dir && first == 0 {
+                        first = volume.root();
+                    }
+                    if !last {
+                        if !dir {
+                            return Err(Stop::Error(ReturnCode::EINVAL));
+                        }
+                        self.name_start.set(end + 1);
+                        self.dir.set(Chain::new(first));
+                        self.dir_entry.set(0);
+                        self.free_entry.set(None);
+                        continue;
+                    }
+                    self.set_file(
+                        handle,
+                        Some(File {
+                            entry: Some((lba, offset)),
+                            dir: dir,
+                            size: if dir { 0 } else { u32_at(&entry, 28) },
+                            pos: 0,
+                            chain: Chain::new(first),
+                        }),
+                    );
+                    return Ok(());
+                }
+
+                OpenStage::Create => {
+                    let (lba, offset) =
+                        self.free_entry.get().ok_or(Stop::Error(ReturnCode::FAIL))?;
+                    self.modify_sector(lba, false, |sector| {
+                        let entry = &mut sector[offset..offset + ENTRY_LEN];
+                        for b in entry.iter_mut() {
+                            *b = 0;
+                        }
+                        entry[..11].copy_from_slice(&name);
+                        entry[11] = ATTR_ARCHIVE;
+                        // Creation, access and write dates.
+                        entry[16..18].copy_from_slice(&DATE.to_le_bytes());
+                        entry[18..20].copy_from_slice(&DATE.to_le_bytes());
+                        entry[24..26].copy_from_slice(&DATE.to_le_bytes());
+                    })?;
+                    self.set_file(
+                        handle,
+                        Some(File {
+                            entry: Some((lba, offset)),
+                            dir: false,
+                            size: 0,
+                            pos: 0,
+                            chain: Chain::new(0),
+                        }),
+                    );
+                    stage = OpenStage::Flush;
+                }
+
+                OpenStage::Flush => return self.flush(),
+            }
+        }
+    }
+
+    fn step_read(&self, handle: usize, len: usize, done: usize) -> Result<(), Stop> {
+        let volume = self.volume()?;
+        let mut file = self.open_file(handle)?;
+        let mut done = done;
+        loop {
+            self.op.set(Op::Read {
+                handle: handle,
+                len: len,
+                done: done,
+            });
+            if (file.dir && done + DIR_ENTRY_LEN > len)
+                || (!file.dir && (done == len || file.pos >= file.size))
+            {
+                return Ok(());
+            }
+            let located = self.locate(&volume, &mut file.chain, file.pos);
+            self.set_file(handle, Some(file));
+            let lba = match located? {
+                Some(lba) => lba,
+                // The end of a directory.
+                None if file.dir => return Ok(()),
+                // A file shorter than its size.
+                None => return Err(Stop::Error(ReturnCode::FAIL)),
+            };
+            let offset = file.pos as usize % SECTOR_LEN;
+
+            if file.dir {
+                let mut entry = [0; ENTRY_LEN];
+                self.read_sector(lba, |sector| {
+                    entry.copy_from_slice(&sector[offset..offset + ENTRY_LEN])
+                })?;
+                if entry[0] == ENTRY_END {
+                    return Ok(());
+                }
+                file.pos += ENTRY_LEN as u32;
+                if entry[0] != ENTRY_FREE && entry[11] & ATTR_VOLUME_ID == 0 {
+                    self.client_buf.map(|buf| {
+                        buf[done..done + DIR_ENTRY_LEN].copy_from_slice(&dir_record(&entry))
+                    });
+                    done += DIR_ENTRY_LEN;
+                }
+            } else {
+                let chunk = cmp::min(
+                    SECTOR_LEN - offset,
+                    cmp::min(len - done, (file.size - file.pos) as usize),
+                );
+                self.read_sector(lba, |sector| {
+                    self.client_buf.map(|buf| {
+                        buf[done..done + chunk].copy_from_slice(&sector[offset..offset + chunk])
+                    })
+                })?;
+                done += chunk;
+                file.pos += chunk as u32;
+            }
+            self.set_file(handle, Some(file));
+        }
+    }
+
+    fn step_write(
+        &self,
+        handle: usize,
+        len: usize,
+        done: usize,
+        stage: WriteStage,
+    ) -> Result<(), Stop> {
+        let volume = self.volume()?;
+        let mut file = self.open_file(handle)?;
+        let mut done = done;
+        let mut stage = stage;
+        loop {
+            self.op.set(Op::Write {
+                handle: handle,
+                len: len,
+                done: done,
+                stage: stage,
+            });
+            self.set_file(handle, Some(file));
+
+            match stage {
+                WriteStage::Data => {
+                    if done == len {
+                        stage = WriteStage::Entry;
+                        continue;
+                    }
+                    let located = self.locate(&volume, &mut file.chain, file.pos);
+                    self.set_file(handle, Some(file));
+                    let lba = match located? {
+                        Some(lba) => lba,
+                        None => {
+                            let next = self.next_free.get();
+                            stage = WriteStage::Allocate {
+                                next: if volume.is_cluster(next) { next } else { 2 },
+                                left: volume.cluster_count,
+                            };
+                            continue;
+                        }
+                    };
+                    let offset = file.pos as usize % SECTOR_LEN;
+                    let chunk = cmp::min(SECTOR_LEN - offset, len - done);
+                    self.modify_sector(lba, chunk == SECTOR_LEN, |sector| {
+                        self.client_buf.map(|buf| {
+                            sector[offset..offset + chunk].copy_from_slice(&buf[done..done + chunk])
+                        })
+                    })?;
+                    done += chunk;
+                    file.pos += chunk as u32;
+                    file.size = cmp::max(file.size, file.pos);
+                }
+
+                WriteStage::Allocate { next, left } => {
+                    if left == 0 {
+                        return Err(Stop::Error(ReturnCode::ENOMEM));
+                    }
+                    stage = if self.fat_entry(&volume, next)? == 0 {
+                        WriteStage::Claim { cluster: next }
+                    } else {
+                        WriteStage::Allocate {
+                            next: if volume.is_cluster(next + 1) {
+                                next + 1
+                            } else {
+                                2
+                            },
+                            left: left - 1,
+                        }
+                    };
+                }
+
+                WriteStage::Claim { cluster } => {
+                    self.set_fat_entry(&volume, cluster, volume.end_of_chain())?;
+                    self.next_free.set(cluster + 1);
+                    stage = WriteStage::Link { cluster: cluster };
+                }
+
+                WriteStage::Link { cluster } => {
+                    if volume.is_cluster(file.chain.first) {
+                        // `locate()` left the chain at its last cluster.
+                        self.set_fat_entry(&volume, file.chain.cluster, cluster)?;
+                    } else {
+                        file.chain = Chain::new(cluster);
+                    }
+                    stage = WriteStage::Data;
+                }
+
+                WriteStage::Entry => {
+                    if let Some((lba, offset)) = file.entry {
+                        let first = file.chain.first;
+                        self.modify_sector(lba, false, |sector| {
+                            let entry = &mut sector[offset..offset + ENTRY_LEN];
+                            entry[20..22].copy_from_slice(&((first >> 16) as u16).to_le_bytes());
+                            entry[26..28].copy_from_slice(&(first as u16).to_le_bytes());
+                            entry[28..32].copy_from_slice(&file.size.to_le_bytes());
+                            // Access and write dates.
+                            entry[18..20].copy_from_slice(&DATE.to_le_bytes());
+                            entry[24..26].copy_from_slice(&DATE.to_le_bytes());
+                            entry[11] |= ATTR_ARCHIVE;
+                        })?;
+                    }
+                    stage = WriteStage::Flush;
+                }
+
+                WriteStage::Flush => return self.flush(),
+            }
+        }
+    }
+}
+
+impl DynamicDeferredCallClient for Fat<'_> {
+    fn call(&self, _handle: DeferredCallHandle) {
+        self.run();
+    }
+}
+
+impl block_storage::Client for Fat<'_> {
+    fn read_done(&self, buf: &'static mut [u8], result: ReturnCode) {
+        self.sector.replace(buf);
+        if result == ReturnCode::SUCCESS {
+            self.sector_lba.set(Some(self.io_lba.get()));
+            self.run();
+        } else {
+            self.finish(result);
+        }
+    }
+
+    fn write_done(&self, buf: &'static mut [u8], result: ReturnCode) {
+        self.sector.replace(buf);
+        self.sector_dirty.set(false);
+        if result == ReturnCode::SUCCESS {
+            self.run();
+        } else {
+            self.sector_lba.set(None);
+            self.finish(result);
+        }
+    }
+}
@@ -0,0 +1,424 @@
+//! Provides userspace access to a FAT file system.
+//!
+//! Apps open files by path, for example to log data to an SD card that is
+//! then read on a PC, and each handle can only be used by the app that
+//! opened it. Data is copied through a kernel buffer, so a read or write
+//! moves at most its length at once; the callback gives the length moved.
+//! The file system runs one operation at a time, and the operations of other
+//! apps are queued until it is done.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let fat_driver = static_init!(
+//!     capsules::fat_driver::FatDriver<'static>,
+//!     capsules::fat_driver::FatDriver::new(
+//!         fat,
+//!         board_kernel.create_grant(&grant_cap),
+//!         &mut capsules::fat_driver::BUFFER,
+//!     )
+//! );
+//! fat.set_client(fat_driver);
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! ### Allow
+//!
+//! - `0`: The path to open, and the data to write.
+//! - `1`: The buffer data is read into. A directory is read as records of
+//!   `fat::DIR_ENTRY_LEN` bytes.
+//!
+//! ### Subscribe
+//!
+//! - `0`: Called when an operation completes, with its command number, its
+//!   result and the handle opened or the number of bytes moved.
+//!
+//! ### Command
+//!
+//! - `0`: Return SUCCESS if this driver is included on the platform.
+//! - `1`: Mount the file system.
+//! - `2`: Open the path of length `arg1` in the path buffer, creating the
+//!   file if bit 0 of `arg2` is set and moving to its end if bit 1 is.
+//! - `3`: Close handle `arg1`.
+//! - `4`: Read up to `arg2` bytes from handle `arg1`.
+//! - `5`: Write the first `arg2` bytes of the data buffer to handle `arg1`.
+//! - `6`: Append the first `arg2` bytes of the data buffer to handle `arg1`.
+//! - `7`: Move handle `arg1` to position `arg2`.
+//! - `8`: Return the size of handle `arg1`.
+
+use core::cell::Cell;
+use core::cmp;
+
+use crate::fat::{Fat, FatClient, MAX_FILES};
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::Fat as usize;
+
+pub static mut BUFFER: [u8; 512] = [0; 512];
+
+/// Set in the flags of an open to create the file.
+pub const OPEN_CREATE: usize = 1 << 0;
+/// Set in the flags of an open to move to the end of the file.
+pub const OPEN_APPEND: usize = 1 << 1;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Op {
+    Mount,
+    Open { path_len: usize, flags: usize },
+    Read { handle: usize, len: usize },
+    Write { handle: usize, len: usize },
+    Append { handle: usize, len: usize },
+}
+
+impl Op {
+    fn command_num(&self) -> usize {
+        match self {
+            Op::Mount => 1,
+            Op::Open { .. } => 2,
+            Op::Read { .. } => 4,
+            Op::Write { .. } => 5,
+            Op::Append { .. } => 6,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct App {
+    callback: Option<Callback>,
+    data: Option<AppSlice<Shared, u8>>,
+    read: Option<AppSlice<Shared, u8>>,
+    pending: Option<Op>,
+}
+
+pub struct FatDriver<'a> {
+    fat: &'a Fat<'a>,
+    apps: Grant<App>,
+    /// Buffer data is copied through.
+    buffer: TakeCell<'static, [u8]>,
+    /// The app whose operation is in progress, and the operation.
+    current: OptionalCell<(AppId, Op)>,
+    /// The app that opened each handle.
+    owners: Cell<[Option<AppId>; MAX_FILES]>,
+}
+
+impl<'a> FatDriver<'a> {
+    pub fn new(fat: &'a Fat<'a>, grant: Grant<App>, buffer: &'static mut [u8]) -> FatDriver<'a> {
+        FatDriver {
+            fat: fat,
+            apps: grant,
+            buffer: TakeCell::new(buffer),
+            current: OptionalCell::empty(),
+            owners: Cell::new([None; MAX_FILES]),
+        }
+    }
+
+    fn owns(&self, appid: AppId, handle: usize) -> bool {
+        self.owners
+            .get()
+            .get(handle)
+            .map_or(false, |owner| *owner == Some(appid))
+    }
+
+    /// Starts the operation of `appid`, and returns its error if it could
+    /// not be started.
+    fn start(&self, appid: AppId, op: Op) -> ReturnCode {
+        let rcode = self
+            .apps
+            .enter(appid, |app, _| match op {
+                Op::Mount => self.fat.mount(),
+                Op::Open { path_len, flags } => {
+                    let path = app.data.as_ref().map_or(&[][..], |data| data.as_ref());
+                    if path_len > path.len() {
+                        return ReturnCode::ESIZE;
+                    }
+                    self.fat.open(&path[..path_len], flags & OPEN_CREATE != 0)
+                }
+                Op::Read { handle, len } => {
+                    if !self.owns(appid, handle) {
+                        return ReturnCode::EINVAL;
+                    }
+                    let buffer = match self.buffer.take() {
+                        Some(buffer) => buffer,
+                        None => return ReturnCode::EBUSY,
+                    };
+                    let len = cmp::min(len, buffer.len());
+                    match self.fat.read(handle, buffer, len) {
+                        Ok(()) => ReturnCode::SUCCESS,
+                        Err((rcode, buffer)) => {
+                            self.buffer.replace(buffer);
+                            rcode
+                        }
+                    }
+                }
+                Op::Write { handle, len } | Op::Append { handle, len } => {
+                    if !self.owns(appid, handle) {
+                        return ReturnCode::EINVAL;
+                    }
+                    let buffer = match self.buffer.take() {
+                        Some(buffer) => buffer,
+                        None => return ReturnCode::EBUSY,
+                    };
+                    let data = app.data.as_ref().map_or(&[][..], |data| data.as_ref());
+                    let len = cmp::min(len, buffer.len());
+                    if len > data.len() {
+                        self.buffer.replace(buffer);
+                        return ReturnCode::ESIZE;
+                    }
+                    buffer[..len].copy_from_slice(&data[..len]);
+                    let result = match op {
+                        Op::Append { .. } => self.fat.append(handle, buffer, len),
+                        _ => self.fat.write(handle, buffer, len),
+                    };
+                    match result {
+                        Ok(()) => ReturnCode::SUCCESS,
+                        Err((rcode, buffer)) => {
+                            self.buffer.replace(buffer);
+                            rcode
+                        }
+                    }
+                }
+            })
+            .unwrap_or_else(|err| err.into());
+        if rcode == ReturnCode::SUCCESS {
+            self.current.set((appid, op));
+        }
+        rcode
+    }
+
+    /// Starts the operation of `appid` if none is in progress, or else
+    /// queues it.
+    fn enqueue(&self, appid: AppId, op: Op) -> ReturnCode {
+        if self.current.is_none() {
+            return self.start(appid, op);
+        }
+        self.apps
+            .enter(appid, |app, _| {
+                if app.pending.is_some() {
+                    ReturnCode::EBUSY
+                } else {
+                    app.pending = Some(op);
+                    ReturnCode::SUCCESS
+                }
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    /// Calls back `appid` with the result of its operation.
+    fn done(&self, appid: AppId, op: Op, result: ReturnCode, value: usize) {
+        let _ = self.apps.enter(appid, |app, _| {
+            app.callback
+                .map(|mut cb| cb.schedule(op.command_num(), usize::from(result), value));
+        });
+    }
+
+    /// Starts the queued operations in turn until one starts.
+    fn check_queue(&self) {
+        while self.current.is_none() {
+            let mut next = None;
+            for cntr in self.apps.iter() {
+                cntr.enter(|app, _| {
+                    if next.is_none() {
+                        next = app.pending.take().map(|op| (app.appid(), op));
+                    }
+                });
+                if next.is_some() {
+                    break;
+                }
+            }
+            match next {
+                Some((appid, op)) => {
+                    let rcode = self.start(appid, op);
+                    if rcode != ReturnCode::SUCCESS {
+                        self.done(appid, op, rcode, 0);
+                    }
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+impl<'a> FatClient for FatDriver<'a> {
+    fn mount_done(&self, result: ReturnCode) {
+        self.current
+            .take()
+            .map(|(appid, op)| self.done(appid, op, result, 0));
+        self.check_queue();
+    }
+
+    fn open_done(&self, result: Result<usize, ReturnCode>) {
+        self.current.take().map(|(appid, op)| match result {
+            Ok(handle) => {
+                let mut owners = self.owners.get();
+                owners[handle] = Some(appid);
+                self.owners.set(owners);
+                let mut rcode = ReturnCode::SUCCESS;
+                if let Op::Open { flags, .. } = op {
+                    if flags & OPEN_APPEND != 0 {
+                        let size = self.fat.size(handle).unwrap_or(0);
+                        rcode = self.fat.seek(handle, size);
+                    }
+                }
+                self.done(appid, op, rcode, handle);
+            }
+            Err(rcode) => self.done(appid, op, rcode, 0),
+        });
+        self.check_queue();
+    }
+
+    fn read_done(&self, buf: &'static mut [u8], len: usize, result: ReturnCode) {
+        self.current.take().map(|(appid, op)| {
+            let _ = self.apps.enter(appid, |app, _| {
+                app.read.as_mut().map(|read| {
+                    let len = cmp::min(len, read.len());
+                    read.as_mut()[..len].copy_from_slice(&buf[..len]);
+                });
+            });
+            self.done(appid, op, result, len);
+        });
+        self.buffer.replace(buf);
+        self.check_queue();
+    }
+
+    fn write_done(&self, buf: &'static mut [u8], len: usize, result: ReturnCode) {
+        self.buffer.replace(buf);
+        self.current
+            .take()
+            .map(|(appid, op)| self.done(appid, op, result, len));
+        self.check_queue();
+    }
+}
+
+impl<'a> Driver for FatDriver<'a> {
+    /// Setup shared buffers.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: The path or data to write.
+    /// - `1`: The buffer to read into.
+    fn allow(
+        &self,
+        appid: AppId,
+        allow_num: usize,
+        slice: Option<AppSlice<Shared, u8>>,
+    ) -> ReturnCode {
+        self.apps
+            .enter(appid, |app, _| {
+                match allow_num {
+                    0 => app.data = slice,
+                    1 => app.read = slice,
+                    _ => return ReturnCode::ENOSUPPORT,
+                }
+                ReturnCode::SUCCESS
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    /// Setup callbacks.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Operation done callback.
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        callback: Option<Callback>,
+        app_id: AppId,
+    ) -> ReturnCode {
+        self.apps
+            .enter(app_id, |app, _| {
+                match subscribe_num {
+                    0 => app.callback = callback,
+                    _ => return ReturnCode::ENOSUPPORT,
+                }
+                ReturnCode::SUCCESS
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    /// Command interface.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Return SUCCESS if this driver is included on the platform.
+    /// - `1`: Mount.
+    /// - `2`: Open a path.
+    /// - `3`: Close a handle.
+    /// - `4`: Read.
+    /// - `5`: Write.
+    /// - `6`: Append.
+    /// - `7`: Seek.
+    /// - `8`: Get the size of a file.
+    fn command(&self, command_num: usize, arg1: usize, arg2: usize, appid: AppId) -> ReturnCode {
+        match command_num {
+            0 => ReturnCode::SUCCESS,
+            1 => self.enqueue(appid, Op::Mount),
+            2 => self.enqueue(
+                appid,
+                Op::Open {
+                    path_len: arg1,
+                    flags: arg2,
+                },
+            ),
+            3 => {
+                if !self.owns(appid, arg1) {
+                    return ReturnCode::EINVAL;
+                }
+                let rcode = self.fat.close(arg1);
+                if rcode == ReturnCode::SUCCESS {
+                    let mut owners = self.owners.get();
+                    owners[arg1] = None;
+                    self.owners.set(owners);
+                }
+                rcode
+            }
+            4 => self.enqueue(
+                appid,
+                Op::Read {
+                    handle: arg1,
+                    len: arg2,
+                },
+            ),
+            5 => self.enqueue(
+                appid,
+                Op::Write {
+                    handle: arg1,
+                    len: arg2,
+                },
+            ),
+            6 => self.enqueue(
+                appid,
+                Op::Append {
+                    handle: arg1,
+                    len: arg2,
+                },
+            ),
+            7 => {
+                if !self.owns(appid, arg1) {
+                    return ReturnCode::EINVAL;
+                }
+                self.fat.seek(arg1, arg2 as u32)
+            }
+            8 => {
+                if !self.owns(appid, arg1) {
+                    return ReturnCode::EINVAL;
+                }
+                match self.fat.size(arg1) {
+                    Ok(size) => ReturnCode::SuccessWithValue {
+                        value: size as usize,
+                    },
+                    Err(rcode) => rcode,
+                }
+            }
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
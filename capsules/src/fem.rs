@@ -0,0 +1,115 @@
+//! Front-end modules controlled through GPIO pins.
+//!
+//! Modules like the nRF21540 or the SKY66112 sit between the radio and the
+//! antenna, with a power amplifier (PA) for range when sending and a low
+//! noise amplifier (LNA) for sensitivity when listening. They are switched
+//! with a TX enable and an RX enable line, usually with a power down line
+//! that turns the whole module off and an antenna select line for a second
+//! antenna. `GpioFem` drives those lines for a radio driver that implements
+//! `kernel::hil::radio::RadioFrontEnd`.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//! # use kernel::hil::gpio::ActivationMode;
+//! # use kernel::hil::radio::RadioFrontEnd;
+//! # use capsules::fem::GpioFem;
+//!
+//! let fem = static_init!(
+//!     GpioFem<'static>,
+//!     GpioFem::new(
+//!         &nrf52840::gpio::PORT[Pin::P0_17],
+//!         &nrf52840::gpio::PORT[Pin::P0_19],
+//!         Some(&nrf52840::gpio::PORT[Pin::P0_23]),
+//!         Some(&nrf52840::gpio::PORT[Pin::P0_20]),
+//!         ActivationMode::ActiveHigh,
+//!     )
+//! );
+//! fem.init();
+//! nrf52840::ieee802154_radio::RADIO.set_front_end(fem);
+//! ```
+
+use kernel::hil::gpio::{self, ActivationMode, ActivationState};
+use kernel::hil::radio;
+
+pub struct GpioFem<'a> {
+    tx_enable: &'a dyn gpio::Pin,
+    rx_enable: &'a dyn gpio::Pin,
+    /// Active while the module is powered.
+    power: Option<&'a dyn gpio::Pin>,
+    /// Inactive for antenna 0, active for antenna 1.
+    antenna_select: Option<&'a dyn gpio::Pin>,
+    mode: ActivationMode,
+}
+
+impl<'a> GpioFem<'a> {
+    /// All lines are active in `mode`.
+    pub fn new(
+        tx_enable: &'a dyn gpio::Pin,
+        rx_enable: &'a dyn gpio::Pin,
+        power: Option<&'a dyn gpio::Pin>,
+        antenna_select: Option<&'a dyn gpio::Pin>,
+        mode: ActivationMode,
+    ) -> GpioFem<'a> {
+        GpioFem {
+            tx_enable: tx_enable,
+            rx_enable: rx_enable,
+            power: power,
+            antenna_select: antenna_select,
+            mode: mode,
+        }
+    }
+
+    /// Make the lines outputs, with the module off on antenna 0.
+    pub fn init(&self) {
+        self.tx_enable.make_output();
+        self.rx_enable.make_output();
+        self.power.map(|pin| pin.make_output());
+        self.antenna_select.map(|pin| pin.make_output());
+        radio::FrontEndModule::disable(self);
+        radio::FrontEndModule::select_antenna(self, 0);
+    }
+
+    fn set(&self, pin: &dyn gpio::Pin, active: bool) {
+        let state = if active {
+            ActivationState::Active
+        } else {
+            ActivationState::Inactive
+        };
+        pin.write_activation(state, self.mode);
+    }
+}
+
+impl<'a> radio::FrontEndModule for GpioFem<'a> {
+    fn enable_pa(&self) {
+        self.power.map(|pin| self.set(pin, true));
+        self.set(self.rx_enable, false);
+        self.set(self.tx_enable, true);
+    }
+
+    fn enable_lna(&self) {
+        self.power.map(|pin| self.set(pin, true));
+        self.set(self.tx_enable, false);
+        self.set(self.rx_enable, true);
+    }
+
+    fn disable(&self) {
+        self.set(self.tx_enable, false);
+        self.set(self.rx_enable, false);
+        self.power.map(|pin| self.set(pin, false));
+    }
+
+    fn select_antenna(&self, antenna: u8) {
+        self.antenna_select.map(|pin| self.set(pin, antenna == 1));
+    }
+
+    fn antennas(&self) -> u8 {
+        if self.antenna_select.is_some() {
+            2
+        } else {
+            1
+        }
+    }
+}
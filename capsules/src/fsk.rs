@@ -0,0 +1,318 @@
+//! Provides userspace with access to FSK and OOK packet radios.
+//!
+//! Works with any `hil::fsk::Radio`, so apps talk to RFM69 or CC1101 class
+//! transceivers the same way. Once listening, the driver receives packets
+//! until told to stop, pausing only to transmit. The first app to use a
+//! command other than the driver check owns the driver; other apps get
+//! `ERESERVE`.
+//!
+//! ## Instantiation
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let fsk = static_init!(
+//!     capsules::fsk::FskDriver<'static>,
+//!     capsules::fsk::FskDriver::new(
+//!         rfm69,
+//!         static_init!([u8; 64], [0; 64]),
+//!         static_init!([u8; 64], [0; 64]),
+//!         board_kernel.create_grant(&grant_cap)));
+//! kernel::hil::fsk::Radio::set_transmit_client(rfm69, fsk);
+//! kernel::hil::fsk::Radio::set_receive_client(rfm69, fsk);
+//! ```
+//!
+//! ## Syscall Interface
+//!
+//! - allow `0`: the payload to transmit.
+//! - allow `1`: received payloads are copied here.
+//! - allow `2`: the sync word, 1 to 8 bytes, used by command `4`.
+//! - subscribe `0`: a transmission finished, `fn(ReturnCode, 0, 0)`.
+//! - subscribe `1`: a packet arrived, `fn(ReturnCode, len, rssi)`, with
+//!   `FAIL` if its CRC was wrong.
+//! - command `0`: driver check.
+//! - command `1`: use the frequency `data` in Hz.
+//! - command `2`: use bitrate `data` with FSK of deviation `data2` Hz, or OOK
+//!   if `data2` is 0.
+//! - command `3`: transmit at `data` dBm, as an `i8`.
+//! - command `4`: use the sync word in allow `2`.
+//! - command `5`: transmit the first `data` bytes of allow `0`.
+//! - command `6`: start listening.
+//! - command `7`: stop listening and put the radio to sleep.
+
+use core::cell::Cell;
+use core::cmp;
+
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::fsk::{self, Config, Modulation};
+use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
+
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::Fsk as usize;
+
+#[derive(Default)]
+pub struct App {
+    tx_callback: Option<Callback>,
+    rx_callback: Option<Callback>,
+    tx_payload: Option<AppSlice<Shared, u8>>,
+    rx_payload: Option<AppSlice<Shared, u8>>,
+    sync_word: Option<AppSlice<Shared, u8>>,
+}
+
+pub struct FskDriver<'a> {
+    radio: &'a dyn fsk::Radio<'a>,
+    config: Cell<Config>,
+    tx_buffer: TakeCell<'static, [u8]>,
+    rx_buffer: TakeCell<'static, [u8]>,
+    listening: Cell<bool>,
+    /// Length of a transmission waiting for reception to stop.
+    tx_pending: OptionalCell<usize>,
+    apps: Grant<App>,
+    owner: OptionalCell<AppId>,
+}
+
+impl<'a> FskDriver<'a> {
+    pub fn new(
+        radio: &'a dyn fsk::Radio<'a>,
+        tx_buffer: &'static mut [u8],
+        rx_buffer: &'static mut [u8],
+        apps: Grant<App>,
+    ) -> FskDriver<'a> {
+        FskDriver {
+            radio: radio,
+            config: Cell::new(Config {
+                frequency_hz: 868_000_000,
+                bitrate: 4800,
+                modulation: Modulation::Fsk(5000),
+                tx_power_dbm: 10,
+                sync_word: [0x2d, 0xd4, 0, 0, 0, 0, 0, 0],
+                sync_word_len: 2,
+            }),
+            tx_buffer: TakeCell::new(tx_buffer),
+            rx_buffer: TakeCell::new(rx_buffer),
+            listening: Cell::new(false),
+            tx_pending: OptionalCell::empty(),
+            apps: apps,
+            owner: OptionalCell::empty(),
+        }
+    }
+
+    fn configure<F: FnOnce(&mut Config)>(&self, change: F) -> ReturnCode {
+        let mut config = self.config.get();
+        change(&mut config);
+        let rcode = self.radio.configure(config);
+        if rcode == ReturnCode::SUCCESS {
+            self.config.set(config);
+        }
+        rcode
+    }
+
+    fn set_sync_word(&self, app: &mut App) -> ReturnCode {
+        let mut sync_word = [0; 8];
+        let len = match app.sync_word {
+            Some(ref slice) if slice.len() >= 1 && slice.len() <= 8 => {
+                sync_word[..slice.len()].copy_from_slice(slice.as_ref());
+                slice.len()
+            }
+            _ => return ReturnCode::EINVAL,
+        };
+        self.configure(|config| {
+            config.sync_word = sync_word;
+            config.sync_word_len = len as u8;
+        })
+    }
+
+    fn transmit(&self, app: &mut App, len: usize) -> ReturnCode {
+        if self.tx_pending.is_some() {
+            return ReturnCode::EBUSY;
+        }
+        if len > self.radio.max_payload_len() {
+            return ReturnCode::ESIZE;
+        }
+        let copied = match (app.tx_payload.as_ref(), self.tx_buffer.take()) {
+            (Some(payload), Some(buffer)) if payload.len() >= len && buffer.len() >= len => {
+                buffer[..len].copy_from_slice(&payload.as_ref()[..len]);
+                self.tx_buffer.replace(buffer);
+                true
+            }
+            (_, Some(buffer)) => {
+                self.tx_buffer.replace(buffer);
+                false
+            }
+            (_, None) => return ReturnCode::EBUSY,
+        };
+        if !copied {
+            return ReturnCode::EINVAL;
+        }
+        if self.rx_buffer.is_none() {
+            // Stop receiving first; the transmission starts once the
+            // reception was cancelled.
+            self.tx_pending.set(len);
+            return self.radio.sleep();
+        }
+        self.start_transmit(len)
+    }
+
+    fn start_transmit(&self, len: usize) -> ReturnCode {
+        self.tx_buffer.take().map_or(ReturnCode::EBUSY, |buffer| {
+            match self.radio.transmit(buffer, len) {
+                Ok(()) => ReturnCode::SUCCESS,
+                Err((rcode, buffer)) => {
+                    self.tx_buffer.replace(buffer);
+                    rcode
+                }
+            }
+        })
+    }
+
+    fn start_receive(&self) -> ReturnCode {
+        self.rx_buffer.take().map_or(ReturnCode::SUCCESS, |buffer| {
+            match self.radio.receive(buffer) {
+                Ok(()) => ReturnCode::SUCCESS,
+                Err((rcode, buffer)) => {
+                    self.rx_buffer.replace(buffer);
+                    rcode
+                }
+            }
+        })
+    }
+
+    fn listen(&self) -> ReturnCode {
+        self.listening.set(true);
+        // While transmitting, reception starts once the transmission is done.
+        if self.tx_buffer.is_none() {
+            ReturnCode::SUCCESS
+        } else {
+            self.start_receive()
+        }
+    }
+
+    fn stop(&self) -> ReturnCode {
+        self.listening.set(false);
+        self.radio.sleep()
+    }
+
+    fn with_owner<F: FnOnce(&mut App)>(&self, f: F) {
+        self.owner.map(|owner| {
+            let _ = self.apps.enter(*owner, |app, _| f(app));
+        });
+    }
+}
+
+impl<'a> fsk::TransmitClient for FskDriver<'a> {
+    fn transmitted(&self, buffer: &'static mut [u8], result: ReturnCode) {
+        self.tx_buffer.replace(buffer);
+        self.with_owner(|app| {
+            app.tx_callback
+                .map(|mut cb| cb.schedule(usize::from(result), 0, 0));
+        });
+        if self.listening.get() {
+            self.start_receive();
+        }
+    }
+}
+
+impl<'a> fsk::ReceiveClient for FskDriver<'a> {
+    fn received(&self, buffer: &'static mut [u8], len: usize, rssi_dbm: i16, result: ReturnCode) {
+        if result != ReturnCode::ECANCEL {
+            self.with_owner(|app| {
+                let copied = app.rx_payload.as_mut().map_or(0, |payload| {
+                    let copied = cmp::min(payload.len(), len);
+                    payload.as_mut()[..copied].copy_from_slice(&buffer[..copied]);
+                    copied
+                });
+                app.rx_callback
+                    .map(|mut cb| cb.schedule(usize::from(result), copied, rssi_dbm as usize));
+            });
+        }
+        self.rx_buffer.replace(buffer);
+
+        match self.tx_pending.take() {
+            Some(len) => {
+                let rcode = self.start_transmit(len);
+                if rcode != ReturnCode::SUCCESS {
+                    self.with_owner(|app| {
+                        app.tx_callback
+                            .map(|mut cb| cb.schedule(usize::from(rcode), 0, 0));
+                    });
+                }
+            }
+            None if self.listening.get() => {
+                self.start_receive();
+            }
+            None => {}
+        }
+    }
+}
+
+impl<'a> Driver for FskDriver<'a> {
+    fn allow(
+        &self,
+        appid: AppId,
+        allow_num: usize,
+        slice: Option<AppSlice<Shared, u8>>,
+    ) -> ReturnCode {
+        self.apps
+            .enter(appid, |app, _| {
+                match allow_num {
+                    0 => app.tx_payload = slice,
+                    1 => app.rx_payload = slice,
+                    2 => app.sync_word = slice,
+                    _ => return ReturnCode::ENOSUPPORT,
+                }
+                ReturnCode::SUCCESS
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        callback: Option<Callback>,
+        appid: AppId,
+    ) -> ReturnCode {
+        self.apps
+            .enter(appid, |app, _| {
+                match subscribe_num {
+                    0 => app.tx_callback = callback,
+                    1 => app.rx_callback = callback,
+                    _ => return ReturnCode::ENOSUPPORT,
+                }
+                ReturnCode::SUCCESS
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    fn command(&self, command_num: usize, data: usize, data2: usize, appid: AppId) -> ReturnCode {
+        if command_num == 0 {
+            return ReturnCode::SUCCESS;
+        }
+        if command_num > 7 {
+            return ReturnCode::ENOSUPPORT;
+        }
+        if self.owner.map_or(false, |owner| *owner != appid) {
+            return ReturnCode::ERESERVE;
+        }
+        self.owner.set(appid);
+
+        self.apps
+            .enter(appid, |app, _| match command_num {
+                1 => self.configure(|config| config.frequency_hz = data as u32),
+                2 => self.configure(|config| {
+                    config.bitrate = data as u32;
+                    config.modulation = if data2 == 0 {
+                        Modulation::Ook
+                    } else {
+                        Modulation::Fsk(data2 as u32)
+                    };
+                }),
+                3 => self.configure(|config| config.tx_power_dbm = data as i8),
+                4 => self.set_sync_word(app),
+                5 => self.transmit(app, data),
+                6 => self.listen(),
+                7 => self.stop(),
+                _ => ReturnCode::ENOSUPPORT,
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+}
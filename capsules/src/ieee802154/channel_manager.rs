@@ -0,0 +1,271 @@
+//! Channel selection for 802.15.4 networks.
+//!
+//! When a node forms a network it should settle on a channel that nobody
+//! else, such as a WiFi access point, is using. `ChannelManager` energy
+//! scans the candidate channels, picks the one with the lowest peak energy
+//! and configures the radio for it. The choice is kept in nonvolatile
+//! storage, so after a reboot the node comes back on the same channel
+//! without scanning, and the nodes that joined it can still find it.
+//!
+//! The channel is stored in two bytes, the channel number followed by its
+//! complement, so that erased or corrupt storage is not mistaken for one.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//! # use capsules::ieee802154::channel_manager::{ChannelManager, ALL_CHANNELS, REGION_LEN};
+//!
+//! let channel_manager = static_init!(
+//!     ChannelManager<'static, nrf52::ieee802154_radio::Radio>,
+//!     ChannelManager::new(
+//!         &nrf52::ieee802154_radio::RADIO,
+//!         nv_storage,
+//!         0x100,
+//!         static_init!([u8; REGION_LEN], [0; REGION_LEN]),
+//!     )
+//! );
+//! nrf52::ieee802154_radio::RADIO.set_energy_scan_client(channel_manager);
+//! hil::nonvolatile_storage::NonvolatileStorage::set_client(nv_storage, channel_manager);
+//! channel_manager.set_client(network_capsule);
+//! channel_manager.select_channel(ALL_CHANNELS);
+//! ```
+
+use core::cell::Cell;
+
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::nonvolatile_storage::{NonvolatileStorage, NonvolatileStorageClient};
+use kernel::hil::radio;
+use kernel::ReturnCode;
+
+/// Every 2.4 GHz channel, 11 to 26.
+pub const ALL_CHANNELS: u32 = 0x07ff_f800;
+
+/// Bytes of storage, and of the buffer, the channel takes.
+pub const REGION_LEN: usize = 2;
+
+/// How long each channel is measured for. Long enough to catch a WiFi
+/// beacon, which are usually sent every 102.4 ms, on busy channels most of
+/// the time.
+pub const DEFAULT_SCAN_DURATION_MS: u32 = 50;
+
+const FIRST_CHANNEL: u8 = 11;
+const LAST_CHANNEL: u8 = 26;
+
+pub trait ChannelClient {
+    /// The radio is now on `channel`, which was either read from storage or
+    /// found by a scan, or selection failed.
+    fn channel_selected(&self, channel: u8, result: ReturnCode);
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum State {
+    Idle,
+    /// Reading the stored channel.
+    Loading,
+    Scanning,
+    /// Writing the channel a scan found.
+    Storing,
+}
+
+pub struct ChannelManager<'a, R: radio::Radio + radio::RadioEnergyScan> {
+    radio: &'a R,
+    storage: &'a dyn NonvolatileStorage<'static>,
+    address: usize,
+    buffer: TakeCell<'static, [u8]>,
+    state: Cell<State>,
+    /// The channels the network may use.
+    candidates: Cell<u32>,
+    scan_duration_ms: Cell<u32>,
+    /// Peak energy of each channel in the last scan, from channel 11.
+    energy: Cell<[Option<i8>; 16]>,
+    client: OptionalCell<&'a dyn ChannelClient>,
+}
+
+impl<'a, R: radio::Radio + radio::RadioEnergyScan> ChannelManager<'a, R> {
+    /// Use `REGION_LEN` bytes of `storage` at `address`. `buffer` must be
+    /// `REGION_LEN` bytes long.
+    pub fn new(
+        radio: &'a R,
+        storage: &'a dyn NonvolatileStorage<'static>,
+        address: usize,
+        buffer: &'static mut [u8],
+    ) -> ChannelManager<'a, R> {
+        ChannelManager {
+            radio: radio,
+            storage: storage,
+            address: address,
+            buffer: TakeCell::new(buffer),
+            state: Cell::new(State::Idle),
+            candidates: Cell::new(0),
+            scan_duration_ms: Cell::new(DEFAULT_SCAN_DURATION_MS),
+            energy: Cell::new([None; 16]),
+            client: OptionalCell::empty(),
+        }
+    }
+
+    pub fn set_client(&self, client: &'a dyn ChannelClient) {
+        self.client.set(client);
+    }
+
+    pub fn set_scan_duration_ms(&self, ms: u32) {
+        self.scan_duration_ms.set(ms);
+    }
+
+    /// Put the radio on the stored channel if it is one of `channels`, a bit
+    /// mask with bit `n` set for channel `n`. Otherwise scan `channels` for
+    /// the quietest one and store it.
+    pub fn select_channel(&self, channels: u32) -> ReturnCode {
+        if self.state.get() != State::Idle {
+            return ReturnCode::EBUSY;
+        } else if channels & ALL_CHANNELS == 0 {
+            return ReturnCode::EINVAL;
+        }
+        self.candidates.set(channels & ALL_CHANNELS);
+        self.buffer.take().map_or(ReturnCode::EBUSY, |buffer| {
+            self.state.set(State::Loading);
+            let rcode = self.storage.read(buffer, self.address, REGION_LEN);
+            if rcode != ReturnCode::SUCCESS {
+                self.state.set(State::Idle);
+            }
+            rcode
+        })
+    }
+
+    /// Scan `channels` and store the quietest one regardless of any stored
+    /// channel, for example when forming a new network after the old one
+    /// suffered from interference.
+    pub fn rescan(&self, channels: u32) -> ReturnCode {
+        if self.state.get() != State::Idle {
+            return ReturnCode::EBUSY;
+        } else if channels & ALL_CHANNELS == 0 {
+            return ReturnCode::EINVAL;
+        }
+        self.candidates.set(channels & ALL_CHANNELS);
+        self.scan()
+    }
+
+    /// The peak energy on `channel` in the last scan, in dBm.
+    pub fn energy(&self, channel: u8) -> Option<i8> {
+        if channel < FIRST_CHANNEL || channel > LAST_CHANNEL {
+            return None;
+        }
+        self.energy.get()[(channel - FIRST_CHANNEL) as usize]
+    }
+
+    fn scan(&self) -> ReturnCode {
+        self.energy.set([None; 16]);
+        self.state.set(State::Scanning);
+        let rcode = self
+            .radio
+            .energy_scan(self.candidates.get(), self.scan_duration_ms.get());
+        if rcode != ReturnCode::SUCCESS {
+            self.state.set(State::Idle);
+        }
+        rcode
+    }
+
+    /// The candidate with the lowest peak energy. Ties go to the lowest
+    /// channel.
+    fn quietest(&self) -> Option<u8> {
+        let energy = self.energy.get();
+        (FIRST_CHANNEL..=LAST_CHANNEL)
+            .filter(|channel| self.candidates.get() & (1 << channel) != 0)
+            .filter_map(|channel| energy[(channel - FIRST_CHANNEL) as usize].map(|e| (e, channel)))
+            .min()
+            .map(|(_, channel)| channel)
+    }
+
+    fn use_channel(&self, channel: u8) {
+        self.state.set(State::Idle);
+        let rcode = self.radio.set_channel(channel);
+        if rcode == ReturnCode::SUCCESS {
+            self.radio.config_commit();
+        }
+        self.client
+            .map(|client| client.channel_selected(channel, rcode));
+    }
+
+    fn fail(&self, rcode: ReturnCode) {
+        self.state.set(State::Idle);
+        let channel = self.radio.get_channel();
+        self.client
+            .map(|client| client.channel_selected(channel, rcode));
+    }
+}
+
+impl<'a, R: radio::Radio + radio::RadioEnergyScan> radio::EnergyScanClient
+    for ChannelManager<'a, R>
+{
+    fn energy_measured(&self, channel: u8, energy: i8) {
+        if channel >= FIRST_CHANNEL && channel <= LAST_CHANNEL {
+            let mut levels = self.energy.get();
+            levels[(channel - FIRST_CHANNEL) as usize] = Some(energy);
+            self.energy.set(levels);
+        }
+    }
+
+    fn scan_done(&self, result: ReturnCode) {
+        if self.state.get() != State::Scanning {
+            return;
+        }
+        let channel = match self.quietest() {
+            Some(channel) if result == ReturnCode::SUCCESS => channel,
+            _ => {
+                self.fail(if result == ReturnCode::SUCCESS {
+                    ReturnCode::FAIL
+                } else {
+                    result
+                });
+                return;
+            }
+        };
+
+        match self.buffer.take() {
+            Some(buffer) => {
+                buffer[0] = channel;
+                buffer[1] = !channel;
+                self.state.set(State::Storing);
+                if self.storage.write(buffer, self.address, REGION_LEN) != ReturnCode::SUCCESS {
+                    // Use the channel anyway; the next selection scans again.
+                    self.use_channel(channel);
+                }
+            }
+            None => self.use_channel(channel),
+        }
+    }
+}
+
+impl<'a, R: radio::Radio + radio::RadioEnergyScan> NonvolatileStorageClient<'static>
+    for ChannelManager<'a, R>
+{
+    fn read_done(&self, buffer: &'static mut [u8], _length: usize) {
+        let (channel, check) = (buffer[0], buffer[1]);
+        self.buffer.replace(buffer);
+        if self.state.get() != State::Loading {
+            return;
+        }
+
+        let stored = check == !channel
+            && channel >= FIRST_CHANNEL
+            && channel <= LAST_CHANNEL
+            && self.candidates.get() & (1 << channel) != 0;
+        if stored {
+            self.use_channel(channel);
+        } else {
+            let rcode = self.scan();
+            if rcode != ReturnCode::SUCCESS {
+                self.fail(rcode);
+            }
+        }
+    }
+
+    fn write_done(&self, buffer: &'static mut [u8], _length: usize) {
+        let channel = buffer[0];
+        self.buffer.replace(buffer);
+        if self.state.get() == State::Storing {
+            self.use_channel(channel);
+        }
+    }
+}
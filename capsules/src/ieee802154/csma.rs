@@ -0,0 +1,500 @@
+//! Unslotted CSMA-CA MAC protocol layer for 802.15.4, with acknowledgements
+//! and retransmission done in software.
+//!
+//! For radios that leave channel access and acknowledgements to the MAC, such
+//! as the nRF52840. Before each attempt the layer waits a random number of
+//! backoff periods, up to `2^BE - 1`, and then assesses the channel. A busy
+//! channel doubles the backoff window, up to `2^max_be`, and once it was busy
+//! `max_csma_backoffs + 1` times in a row the frame fails with `EBUSY`, the
+//! channel access failure of IEEE 802.15.4-2015, 6.2.5.1.
+//!
+//! A frame that requests an acknowledgement is sent again, with a fresh
+//! backoff, if no acknowledgement with its sequence number arrives within
+//! `ACK_WAIT_US`. After `max_frame_retries` retransmissions it fails with
+//! `ENOACK`. Frames that request no acknowledgement, such as broadcasts, are
+//! sent once. The result reaches the 6LoWPAN and UDP layers above through
+//! `send_done`.
+//!
+//! In turn, received unicast frames that request an acknowledgement are
+//! acknowledged, and retransmissions of a frame already passed up are
+//! dropped.
+//!
+//! Additional notes:
+//!
+//!   * The radio must support `kernel::hil::radio::RadioChannelCheck`.
+//!   * Acknowledgements are sent from the receive callback, so they go out
+//!     later than the 192 us turnaround of the standard. Peers whose
+//!     hardware waits the standard 864 us for them may miss some, and retry.
+//!   * Radios that do CSMA-CA and retransmission themselves, like the RF233,
+//!     should use `AwakeMac` instead.
+//!
+//! Usage
+//! -----
+//! This capsule implements the `capsules::ieee802154::mac::Mac` interface and
+//! can replace `AwakeMac` as the backend of a `Framer`:
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! use capsules::ieee802154::csma::CsmaMac;
+//! use capsules::ieee802154::mac::Mac;
+//! type CsmaDevice = CsmaMac<'static, nrf52::ieee802154_radio::Radio, VirtualMuxAlarm<'static, Rtc>>;
+//!
+//! let csma_mac = static_init!(CsmaDevice, CsmaMac::new(&nrf52::ieee802154_radio::RADIO, mac_alarm));
+//! mac_alarm.set_alarm_client(csma_mac);
+//! nrf52::ieee802154_radio::RADIO.set_transmit_client(csma_mac);
+//! nrf52::ieee802154_radio::RADIO.set_receive_client(csma_mac, &mut RADIO_RX_BUF);
+//! nrf52::ieee802154_radio::RADIO.set_channel_check_client(csma_mac);
+//!
+//! csma_mac.set_max_frame_retries(5);
+//! csma_mac.initialize(&mut ACK_BUF);
+//! ```
+
+use crate::ieee802154::mac::Mac;
+use crate::net::ieee802154::{FrameType, Header, MacAddress};
+use core::cell::Cell;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::radio;
+use kernel::hil::time::{self, Alarm, Ticks};
+use kernel::ReturnCode;
+
+/// The defaults of macMinBe, macMaxBe, macMaxCsmaBackoffs and
+/// macMaxFrameRetries (IEEE 802.15.4-2015, table 8-94).
+pub const DEFAULT_MIN_BE: u8 = 3;
+pub const DEFAULT_MAX_BE: u8 = 5;
+pub const DEFAULT_MAX_CSMA_BACKOFFS: u8 = 4;
+pub const DEFAULT_MAX_FRAME_RETRIES: u8 = 3;
+
+/// The largest backoff exponent and number of retries the standard allows.
+const MAX_BE: u8 = 8;
+const MAX_CSMA_BACKOFFS: u8 = 5;
+const MAX_FRAME_RETRIES: u8 = 7;
+
+/// aUnitBackoffPeriod, 20 symbols of 16 us.
+const BACKOFF_PERIOD_US: u32 = 320;
+
+/// How long to wait for an acknowledgement after a frame was sent.
+/// macAckWaitDuration is 864 us, but a peer using this layer acknowledges
+/// from software, which takes longer.
+pub const ACK_WAIT_US: u32 = 2000;
+
+/// The length of an Imm-Ack frame without its FCS.
+const ACK_LEN: usize = 3;
+
+#[derive(Copy, Clone, PartialEq)]
+enum State {
+    Idle,
+    /// Waiting out a random backoff before assessing the channel.
+    Backoff,
+    /// Assessing the channel.
+    Checking,
+    /// The radio is sending the frame.
+    Transmitting,
+    /// The frame was sent, waiting for its acknowledgement.
+    WaitingAck,
+}
+
+pub struct CsmaMac<'a, R: radio::Radio + radio::RadioChannelCheck, A: Alarm<'a>> {
+    radio: &'a R,
+    alarm: &'a A,
+    tx_client: OptionalCell<&'static dyn radio::TxClient>,
+    rx_client: OptionalCell<&'static dyn radio::RxClient>,
+    state: Cell<State>,
+
+    min_be: Cell<u8>,
+    max_be: Cell<u8>,
+    max_csma_backoffs: Cell<u8>,
+    max_frame_retries: Cell<u8>,
+
+    tx_payload: TakeCell<'static, [u8]>,
+    tx_len: Cell<usize>,
+    /// The sequence number of the frame, if it requests an acknowledgement.
+    tx_ack_seq: Cell<Option<u8>>,
+    /// NB and BE of the CSMA-CA algorithm, for the current attempt.
+    backoffs: Cell<u8>,
+    be: Cell<u8>,
+    retries: Cell<u8>,
+
+    /// Holds the acknowledgements we send.
+    ack_buf: TakeCell<'static, [u8]>,
+    /// An acknowledgement is being sent.
+    acking: Cell<bool>,
+
+    /// Source and sequence number of the last frame passed up, to drop its
+    /// retransmissions.
+    last_rx: Cell<Option<(MacAddress, u8)>>,
+    /// State of the generator of backoffs.
+    random: Cell<u32>,
+}
+
+impl<'a, R: radio::Radio + radio::RadioChannelCheck, A: Alarm<'a>> CsmaMac<'a, R, A> {
+    pub fn new(radio: &'a R, alarm: &'a A) -> CsmaMac<'a, R, A> {
+        CsmaMac {
+            radio: radio,
+            alarm: alarm,
+            tx_client: OptionalCell::empty(),
+            rx_client: OptionalCell::empty(),
+            state: Cell::new(State::Idle),
+            min_be: Cell::new(DEFAULT_MIN_BE),
+            max_be: Cell::new(DEFAULT_MAX_BE),
+            max_csma_backoffs: Cell::new(DEFAULT_MAX_CSMA_BACKOFFS),
+            max_frame_retries: Cell::new(DEFAULT_MAX_FRAME_RETRIES),
+            tx_payload: TakeCell::empty(),
+            tx_len: Cell::new(0),
+            tx_ack_seq: Cell::new(None),
+            backoffs: Cell::new(0),
+            be: Cell::new(0),
+            retries: Cell::new(0),
+            ack_buf: TakeCell::empty(),
+            acking: Cell::new(false),
+            last_rx: Cell::new(None),
+            random: Cell::new(0),
+        }
+    }
+
+    /// Set the range of the backoff exponent. `min_be` is at most `max_be`,
+    /// which is from 3 to 8; a `min_be` of 0 skips the first backoff.
+    pub fn set_backoff_exponents(&self, min_be: u8, max_be: u8) -> ReturnCode {
+        if max_be < DEFAULT_MIN_BE || max_be > MAX_BE || min_be > max_be {
+            return ReturnCode::EINVAL;
+        }
+        self.min_be.set(min_be);
+        self.max_be.set(max_be);
+        ReturnCode::SUCCESS
+    }
+
+    /// Set how many more times the channel may be found busy before a frame
+    /// fails, from 0 to 5.
+    pub fn set_max_csma_backoffs(&self, backoffs: u8) -> ReturnCode {
+        if backoffs > MAX_CSMA_BACKOFFS {
+            return ReturnCode::EINVAL;
+        }
+        self.max_csma_backoffs.set(backoffs);
+        ReturnCode::SUCCESS
+    }
+
+    /// Set how many times an unacknowledged frame is sent again, from 0 to 7.
+    pub fn set_max_frame_retries(&self, retries: u8) -> ReturnCode {
+        if retries > MAX_FRAME_RETRIES {
+            return ReturnCode::EINVAL;
+        }
+        self.max_frame_retries.set(retries);
+        ReturnCode::SUCCESS
+    }
+
+    pub fn get_max_frame_retries(&self) -> u8 {
+        self.max_frame_retries.get()
+    }
+
+    /// The next number from a xorshift generator, seeded with our address so
+    /// that neighbors back off differently.
+    fn next_random(&self) -> u32 {
+        let mut x = self.random.get();
+        if x == 0 {
+            let address = self.radio.get_address_long();
+            x = (u32::from_le_bytes([address[4], address[5], address[6], address[7]])
+                ^ self.alarm.now().into_u32())
+                | 1;
+        }
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.random.set(x);
+        x
+    }
+
+    /// Start an attempt to send the frame.
+    fn start_attempt(&self) {
+        self.backoffs.set(0);
+        self.be.set(self.min_be.get());
+        self.backoff();
+    }
+
+    fn backoff(&self) {
+        self.state.set(State::Backoff);
+        let periods = self.next_random() & ((1 << self.be.get()) - 1);
+        self.alarm.set_alarm(
+            self.alarm.now(),
+            A::ticks_from_us(periods * BACKOFF_PERIOD_US),
+        );
+    }
+
+    fn check_channel(&self) {
+        self.state.set(State::Checking);
+        // The radio is busy sending an acknowledgement, so the channel is too.
+        if self.acking.get() || self.radio.channel_check() != ReturnCode::SUCCESS {
+            self.channel_busy();
+        }
+    }
+
+    fn channel_busy(&self) {
+        let backoffs = self.backoffs.get() + 1;
+        if backoffs > self.max_csma_backoffs.get() {
+            self.finish(false, ReturnCode::EBUSY);
+            return;
+        }
+        self.backoffs.set(backoffs);
+        self.be
+            .set(core::cmp::min(self.be.get() + 1, self.max_be.get()));
+        self.backoff();
+    }
+
+    fn send_frame(&self) {
+        self.tx_payload.take().map(|buf| {
+            self.state.set(State::Transmitting);
+            let (rcode, buf) = self.radio.transmit(buf, self.tx_len.get());
+            if rcode != ReturnCode::SUCCESS {
+                buf.map(|buf| self.tx_payload.replace(buf));
+                self.finish(false, rcode);
+            }
+        });
+    }
+
+    /// The frame was not acknowledged: send it again, or give up.
+    fn no_ack(&self) {
+        if self.retries.get() >= self.max_frame_retries.get() {
+            self.finish(false, ReturnCode::ENOACK);
+        } else {
+            self.retries.set(self.retries.get() + 1);
+            self.start_attempt();
+        }
+    }
+
+    fn finish(&self, acked: bool, result: ReturnCode) {
+        self.state.set(State::Idle);
+        self.tx_payload.take().map(|buf| {
+            self.tx_client.map(move |c| {
+                c.send_done(buf, acked, result);
+            });
+        });
+    }
+
+    /// Acknowledge the frame with sequence number `seq`, if the radio is
+    /// free.
+    fn send_ack(&self, seq: u8) {
+        match self.state.get() {
+            State::Checking | State::Transmitting => return,
+            _ => {}
+        }
+        if self.acking.get() {
+            return;
+        }
+        self.ack_buf.take().map(|buf| {
+            // IEEE 802.15.4-2015, 7.3.3: the frame control field of an
+            // Imm-Ack, least significant byte first, and the sequence number.
+            buf[radio::PSDU_OFFSET] = FrameType::Acknowledgement as u8;
+            buf[radio::PSDU_OFFSET + 1] = 0;
+            buf[radio::PSDU_OFFSET + 2] = seq;
+            match self.radio.transmit(buf, ACK_LEN) {
+                (ReturnCode::SUCCESS, _) => self.acking.set(true),
+                (_, buf) => {
+                    buf.map(|buf| self.ack_buf.replace(buf));
+                }
+            }
+        });
+    }
+}
+
+impl<'a, R: radio::Radio + radio::RadioChannelCheck, A: Alarm<'a>> Mac for CsmaMac<'a, R, A> {
+    /// `mac_buf` holds the acknowledgements this layer sends, and must be at
+    /// least `radio::PSDU_OFFSET + 3 + radio::MFR_SIZE` bytes long.
+    fn initialize(&self, mac_buf: &'static mut [u8]) -> ReturnCode {
+        if mac_buf.len() < radio::PSDU_OFFSET + ACK_LEN + radio::MFR_SIZE {
+            return ReturnCode::ESIZE;
+        }
+        self.ack_buf.replace(mac_buf);
+        ReturnCode::SUCCESS
+    }
+
+    fn is_on(&self) -> bool {
+        self.radio.is_on()
+    }
+
+    fn set_config_client(&self, client: &'static dyn radio::ConfigClient) {
+        self.radio.set_config_client(client)
+    }
+
+    fn set_address(&self, addr: u16) {
+        self.radio.set_address(addr)
+    }
+
+    fn set_address_long(&self, addr: [u8; 8]) {
+        self.radio.set_address_long(addr)
+    }
+
+    fn set_pan(&self, id: u16) {
+        self.radio.set_pan(id)
+    }
+
+    fn get_address(&self) -> u16 {
+        self.radio.get_address()
+    }
+
+    fn get_address_long(&self) -> [u8; 8] {
+        self.radio.get_address_long()
+    }
+
+    fn get_pan(&self) -> u16 {
+        self.radio.get_pan()
+    }
+
+    fn config_commit(&self) {
+        self.radio.config_commit()
+    }
+
+    fn set_transmit_client(&self, client: &'static dyn radio::TxClient) {
+        self.tx_client.set(client);
+    }
+
+    fn set_receive_client(&self, client: &'static dyn radio::RxClient) {
+        self.rx_client.set(client);
+    }
+
+    fn set_receive_buffer(&self, buffer: &'static mut [u8]) {
+        self.radio.set_receive_buffer(buffer);
+    }
+
+    fn transmit(
+        &self,
+        full_mac_frame: &'static mut [u8],
+        frame_len: usize,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        if self.state.get() != State::Idle {
+            return (ReturnCode::EBUSY, Some(full_mac_frame));
+        } else if radio::PSDU_OFFSET + frame_len >= full_mac_frame.len() {
+            return (ReturnCode::ESIZE, Some(full_mac_frame));
+        }
+
+        match Header::decode(&full_mac_frame[radio::PSDU_OFFSET..], false).done() {
+            Some((_, (header, _))) => self.tx_ack_seq.set(if header.ack_requested {
+                header.seq
+            } else {
+                None
+            }),
+            None => return (ReturnCode::FAIL, Some(full_mac_frame)),
+        }
+
+        self.tx_payload.replace(full_mac_frame);
+        self.tx_len.set(frame_len);
+        self.retries.set(0);
+        self.start_attempt();
+        (ReturnCode::SUCCESS, None)
+    }
+}
+
+impl<'a, R: radio::Radio + radio::RadioChannelCheck, A: Alarm<'a>> time::AlarmClient
+    for CsmaMac<'a, R, A>
+{
+    fn alarm(&self) {
+        match self.state.get() {
+            State::Backoff => self.check_channel(),
+            State::WaitingAck => self.no_ack(),
+            _ => {}
+        }
+    }
+}
+
+impl<'a, R: radio::Radio + radio::RadioChannelCheck, A: Alarm<'a>> radio::ChannelCheckClient
+    for CsmaMac<'a, R, A>
+{
+    fn channel_checked(&self, clear: bool) {
+        if self.state.get() != State::Checking {
+            return;
+        }
+        if clear {
+            self.send_frame();
+        } else {
+            self.channel_busy();
+        }
+    }
+}
+
+impl<'a, R: radio::Radio + radio::RadioChannelCheck, A: Alarm<'a>> radio::TxClient
+    for CsmaMac<'a, R, A>
+{
+    fn send_done(&self, buf: &'static mut [u8], acked: bool, result: ReturnCode) {
+        if self.acking.get() {
+            self.acking.set(false);
+            self.ack_buf.replace(buf);
+            return;
+        }
+        self.tx_payload.replace(buf);
+        if self.state.get() != State::Transmitting {
+            return;
+        }
+        if result != ReturnCode::SUCCESS || acked || self.tx_ack_seq.get().is_none() {
+            self.finish(acked, result);
+        } else {
+            self.state.set(State::WaitingAck);
+            self.alarm
+                .set_alarm(self.alarm.now(), A::ticks_from_us(ACK_WAIT_US));
+        }
+    }
+}
+
+impl<'a, R: radio::Radio + radio::RadioChannelCheck, A: Alarm<'a>> radio::RxClient
+    for CsmaMac<'a, R, A>
+{
+    fn receive(
+        &self,
+        buf: &'static mut [u8],
+        frame_len: usize,
+        crc_valid: bool,
+        timestamp: Option<u32>,
+        result: ReturnCode,
+    ) {
+        let header = Header::decode(&buf[radio::PSDU_OFFSET..], false)
+            .done()
+            .map(|(_, (header, _))| header);
+        let header = match header {
+            Some(header) if crc_valid => header,
+            _ => {
+                self.radio.set_receive_buffer(buf);
+                return;
+            }
+        };
+
+        if header.frame_type == FrameType::Acknowledgement {
+            if self.state.get() == State::WaitingAck
+                && header.seq.is_some()
+                && header.seq == self.tx_ack_seq.get()
+            {
+                self.alarm.disarm();
+                self.finish(true, ReturnCode::SUCCESS);
+            }
+            self.radio.set_receive_buffer(buf);
+            return;
+        }
+
+        // Filter packets by destination because radio is in promiscuous mode
+        let (addr_match, unicast) = match header.dst_addr {
+            Some(MacAddress::Short(0xffff)) => (true, false),
+            Some(MacAddress::Short(addr)) => (addr == self.radio.get_address(), true),
+            Some(MacAddress::Long(long_addr)) => (long_addr == self.radio.get_address_long(), true),
+            None => (false, false),
+        };
+        if !addr_match {
+            self.radio.set_receive_buffer(buf);
+            return;
+        }
+
+        let mut duplicate = false;
+        if let Some(seq) = header.seq {
+            if unicast && header.ack_requested {
+                self.send_ack(seq);
+            }
+            if let Some(src_addr) = header.src_addr {
+                duplicate = self.last_rx.get() == Some((src_addr, seq));
+                self.last_rx.set(Some((src_addr, seq)));
+            }
+        }
+        if duplicate {
+            self.radio.set_receive_buffer(buf);
+            return;
+        }
+
+        self.rx_client.map(move |c| {
+            c.receive(buf, frame_len, crc_valid, timestamp, result);
+        });
+    }
+}
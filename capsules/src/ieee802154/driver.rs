@@ -828,7 +828,14 @@ fn encode_address(addr: &Option<MacAddress>) -> usize {
 }
 
 impl device::RxClient for RadioDriver<'_> {
-    fn receive<'b>(&self, buf: &'b [u8], header: Header<'b>, data_offset: usize, data_len: usize) {
+    fn receive<'b>(
+        &self,
+        buf: &'b [u8],
+        header: Header<'b>,
+        data_offset: usize,
+        data_len: usize,
+        _timestamp: Option<u32>,
+    ) {
         self.apps.each(|app| {
             app.app_read.take().as_mut().map(|rbuf| {
                 let rbuf = rbuf.as_mut();
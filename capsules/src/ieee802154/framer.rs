@@ -1,6 +1,6 @@
 //! Implements IEEE 802.15.4 MAC device abstraction over a 802.15.4 MAC interface.
 //! Allows its users to prepare and send frames in plaintext, handling 802.15.4
-//! encoding and security procedures (in the future) transparently.
+//! encoding and security procedures transparently.
 //!
 //! However, certain IEEE 802.15.4 MAC device concepts are not implemented in
 //! this layer of abstraction and instead handled in hardware for performance
@@ -64,9 +64,14 @@
 //! mac_device.set_transmit_client(radio_capsule);
 //! mac_device.set_receive_client(radio_capsule);
 //! ```
+//!
+//! Secured frames use the keys and devices of the key and device procedures.
+//! Kernel users such as the IPv6 stack can use a `KeyTable` instead of the
+//! userspace driver. Each secured frame that is sent takes the next frame
+//! counter, which `set_frame_counter()` restores, for example after a
+//! reboot, so that its CCM* nonce is never reused.
 
 //
-// TODO: Encryption/decryption
 // TODO: Sending beacon frames
 // TODO: Channel scanning
 //
@@ -244,6 +249,15 @@ pub trait DeviceProcedure {
     /// address is already long, a long address should be returned only if the
     /// given address matches a known DeviceDescriptor.
     fn lookup_addr_long(&self, addr: MacAddress) -> Option<[u8; 8]>;
+
+    /// IEEE 802.15.4-2015, 9.2.3, steps g and h. Checks that the frame
+    /// counter of an authenticated frame from the device `addr` is higher
+    /// than that of the previous frame from it, and records it. Returns
+    /// whether the frame is accepted. By default every frame counter is, so
+    /// replayed frames are not detected.
+    fn update_frame_counter(&self, _addr: [u8; 8], _frame_counter: u32) -> bool {
+        true
+    }
 }
 
 /// This state enum describes the state of the transmission pipeline.
@@ -314,6 +328,14 @@ pub struct Framer<'a, M: Mac, A: AES128CCM<'a>> {
     /// `None`, except when transitioning between states.
     rx_state: MapCell<RxState>,
     rx_client: OptionalCell<&'a dyn RxClient>,
+    /// Radio timestamp of the frame in the reception pipeline.
+    rx_timestamp: Cell<Option<u32>>,
+    /// Source device and frame counter of the secured frame in the
+    /// reception pipeline.
+    rx_frame_counter: Cell<Option<([u8; 8], u32)>>,
+
+    /// Frame counter of the next secured frame that is sent.
+    tx_frame_counter: Cell<u32>,
 }
 
 impl<'a, M: Mac, A: AES128CCM<'a>> Framer<'a, M, A> {
@@ -328,9 +350,21 @@ impl<'a, M: Mac, A: AES128CCM<'a>> Framer<'a, M, A> {
             tx_client: OptionalCell::empty(),
             rx_state: MapCell::new(RxState::Idle),
             rx_client: OptionalCell::empty(),
+            rx_timestamp: Cell::new(None),
+            rx_frame_counter: Cell::new(None),
+            tx_frame_counter: Cell::new(0),
         }
     }
 
+    /// Sets the frame counter of the next secured frame that is sent.
+    pub fn set_frame_counter(&self, frame_counter: u32) {
+        self.tx_frame_counter.set(frame_counter);
+    }
+
+    pub fn get_frame_counter(&self) -> u32 {
+        self.tx_frame_counter.get()
+    }
+
     /// Sets the IEEE 802.15.4 key lookup procedure to be used.
     pub fn set_key_procedure(&self, key_procedure: &'a dyn KeyProcedure) {
         self.key_procedure.set(key_procedure);
@@ -434,7 +468,8 @@ impl<'a, M: Mac, A: AES128CCM<'a>> Framer<'a, M, A> {
                                     // Counter error
                                     return None;
                                 }
-                                // TODO: Check frame counter against source device
+                                // Checked against the source device once
+                                // the frame is authenticated
                                 frame_counter
                             }
                             // TSCH mode, where ASN is used instead, not supported
@@ -445,6 +480,8 @@ impl<'a, M: Mac, A: AES128CCM<'a>> Framer<'a, M, A> {
 
                         // Compute ccm nonce
                         let nonce = get_ccm_nonce(&device_addr, frame_counter, security.level);
+                        self.rx_frame_counter
+                            .set(Some((device_addr, frame_counter)));
 
                         Some(FrameInfo {
                             frame_type: header.frame_type,
@@ -458,7 +495,13 @@ impl<'a, M: Mac, A: AES128CCM<'a>> Framer<'a, M, A> {
                 } else {
                     // No security needed, can yield the frame immediately
                     self.rx_client.map(|client| {
-                        client.receive(&buf, header, radio::PSDU_OFFSET + data_offset, data_len);
+                        client.receive(
+                            &buf,
+                            header,
+                            radio::PSDU_OFFSET + data_offset,
+                            data_len,
+                            self.rx_timestamp.get(),
+                        );
                     });
                     None
                 }
@@ -630,6 +673,7 @@ impl<'a, M: Mac, A: AES128CCM<'a>> Framer<'a, M, A> {
                                 header,
                                 radio::PSDU_OFFSET + data_offset,
                                 frame_len - data_offset,
+                                self.rx_timestamp.get(),
                             );
                         });
                     }
@@ -705,9 +749,13 @@ impl<'a, M: Mac, A: AES128CCM<'a>> MacDevice<'a> for Framer<'a, M, A> {
         // specification.
         let src_addr_long = self.get_address_long();
         let security_desc = security_needed.and_then(|(level, key_id)| {
+            let frame_counter = self.tx_frame_counter.get();
+            if frame_counter == 0xffffffff {
+                // The counter is exhausted for the keys
+                return None;
+            }
             self.lookup_key(level, key_id).map(|key| {
-                // TODO: lookup frame counter for device
-                let frame_counter = 0;
+                self.tx_frame_counter.set(frame_counter + 1);
                 let nonce = get_ccm_nonce(&src_addr_long, frame_counter, level);
                 (
                     Security {
@@ -735,7 +783,7 @@ impl<'a, M: Mac, A: AES128CCM<'a>> MacDevice<'a> for Framer<'a, M, A> {
              * hardware so that ACKs set this flag to the right value. */
             frame_pending: false,
             // Unicast data frames request acknowledgement
-            ack_requested: true,
+            ack_requested: dst_addr != MacAddress::Short(0xffff),
             version: FrameVersion::V2006,
             seq: Some(self.data_sequence.get()),
             dst_pan: Some(dst_pan),
@@ -797,7 +845,14 @@ impl<'a, M: Mac, A: AES128CCM<'a>> radio::TxClient for Framer<'a, M, A> {
 }
 
 impl<'a, M: Mac, A: AES128CCM<'a>> radio::RxClient for Framer<'a, M, A> {
-    fn receive(&self, buf: &'static mut [u8], frame_len: usize, crc_valid: bool, _: ReturnCode) {
+    fn receive(
+        &self,
+        buf: &'static mut [u8],
+        frame_len: usize,
+        crc_valid: bool,
+        timestamp: Option<u32>,
+        _: ReturnCode,
+    ) {
         // Drop all frames with invalid CRC
         if !crc_valid {
             self.mac.set_receive_buffer(buf);
@@ -809,6 +864,7 @@ impl<'a, M: Mac, A: AES128CCM<'a>> radio::RxClient for Framer<'a, M, A> {
                 RxState::Idle => {
                     // We can start processing a new received frame only if
                     // the reception pipeline is free
+                    self.rx_timestamp.set(timestamp);
                     self.incoming_frame_security(buf, frame_len)
                 }
                 other_state => {
@@ -885,7 +941,15 @@ impl<'a, M: Mac, A: AES128CCM<'a>> CCMClient for Framer<'a, M, A> {
                 let buf = buf;
                 match state {
                     RxState::Decrypting(info) => {
-                        let next_state = if tag_is_valid {
+                        let fresh =
+                            self.rx_frame_counter
+                                .take()
+                                .map_or(false, |(addr, counter)| {
+                                    self.device_procedure.map_or(true, |device_procedure| {
+                                        device_procedure.update_frame_counter(addr, counter)
+                                    })
+                                });
+                        let next_state = if tag_is_valid && fresh {
                             RxState::ReadyToYield(info, buf)
                         } else {
                             RxState::ReadyToReturn(buf)
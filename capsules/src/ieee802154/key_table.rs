@@ -0,0 +1,199 @@
+//! A table of 802.15.4 keys and devices for kernel users of the MAC layer.
+//!
+//! `KeyTable` implements the key and device lookup procedures of the
+//! `Framer`, as the userspace radio driver does for applications, so that
+//! the kernel IPv6 stack can secure its frames. It keeps the frame counter
+//! of the last authenticated frame from each device and drops frames that
+//! do not count up, which rejects replayed frames. Adding or replacing a
+//! key starts the counters of all devices over, as a new key allows.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//! # use capsules::ieee802154::key_table::KeyTable;
+//!
+//! let key_table = static_init!(KeyTable, KeyTable::new());
+//! key_table.add_key(SecurityLevel::EncMic32, KeyId::Index(1), NETWORK_KEY);
+//! key_table.add_device(Some(0x0802), NEIGHBOR_LONG_ADDR);
+//! mac_device.set_key_procedure(key_table);
+//! mac_device.set_device_procedure(key_table);
+//!
+//! ip6_send.set_security(Some((SecurityLevel::EncMic32, KeyId::Index(1))));
+//! ```
+
+use crate::ieee802154::framer::{DeviceProcedure, KeyProcedure};
+use crate::net::ieee802154::{KeyId, MacAddress, SecurityLevel};
+use core::cell::Cell;
+use kernel::ReturnCode;
+
+pub const MAX_KEYS: usize = 4;
+pub const MAX_DEVICES: usize = 8;
+
+#[derive(Copy, Clone)]
+struct KeyDescriptor {
+    level: SecurityLevel,
+    key_id: KeyId,
+    key: [u8; 16],
+}
+
+#[derive(Copy, Clone)]
+struct DeviceDescriptor {
+    short_addr: Option<u16>,
+    long_addr: [u8; 8],
+    /// The frame counter the next frame from the device has to reach.
+    frame_counter: u32,
+}
+
+pub struct KeyTable {
+    keys: [Cell<Option<KeyDescriptor>>; MAX_KEYS],
+    devices: [Cell<Option<DeviceDescriptor>>; MAX_DEVICES],
+}
+
+impl KeyTable {
+    pub fn new() -> KeyTable {
+        KeyTable {
+            keys: [
+                Cell::new(None),
+                Cell::new(None),
+                Cell::new(None),
+                Cell::new(None),
+            ],
+            devices: [
+                Cell::new(None),
+                Cell::new(None),
+                Cell::new(None),
+                Cell::new(None),
+                Cell::new(None),
+                Cell::new(None),
+                Cell::new(None),
+                Cell::new(None),
+            ],
+        }
+    }
+
+    /// Adds the key for frames with `level` and `key_id`, replacing the
+    /// key they had. Returns `EINVAL` for `SecurityLevel::None` and `ENOMEM`
+    /// if the table is full.
+    pub fn add_key(&self, level: SecurityLevel, key_id: KeyId, key: [u8; 16]) -> ReturnCode {
+        if level == SecurityLevel::None {
+            return ReturnCode::EINVAL;
+        }
+        let slot = self
+            .keys
+            .iter()
+            .find(|slot| {
+                slot.get()
+                    .map_or(false, |desc| desc.level == level && desc.key_id == key_id)
+            })
+            .or_else(|| self.keys.iter().find(|slot| slot.get().is_none()));
+        match slot {
+            Some(slot) => {
+                slot.set(Some(KeyDescriptor {
+                    level: level,
+                    key_id: key_id,
+                    key: key,
+                }));
+                for device in self.devices.iter() {
+                    if let Some(mut desc) = device.get() {
+                        desc.frame_counter = 0;
+                        device.set(Some(desc));
+                    }
+                }
+                ReturnCode::SUCCESS
+            }
+            None => ReturnCode::ENOMEM,
+        }
+    }
+
+    /// Returns `EINVAL` if there is no key for `level` and `key_id`.
+    pub fn remove_key(&self, level: SecurityLevel, key_id: KeyId) -> ReturnCode {
+        match self.keys.iter().find(|slot| {
+            slot.get()
+                .map_or(false, |desc| desc.level == level && desc.key_id == key_id)
+        }) {
+            Some(slot) => {
+                slot.set(None);
+                ReturnCode::SUCCESS
+            }
+            None => ReturnCode::EINVAL,
+        }
+    }
+
+    /// Adds the device with the extended address `long_addr`, and
+    /// `short_addr` if it uses one, replacing an entry for `long_addr`.
+    /// Returns `ENOMEM` if the table is full.
+    pub fn add_device(&self, short_addr: Option<u16>, long_addr: [u8; 8]) -> ReturnCode {
+        let slot = self
+            .devices
+            .iter()
+            .find(|slot| slot.get().map_or(false, |desc| desc.long_addr == long_addr))
+            .or_else(|| self.devices.iter().find(|slot| slot.get().is_none()));
+        match slot {
+            Some(slot) => {
+                slot.set(Some(DeviceDescriptor {
+                    short_addr: short_addr,
+                    long_addr: long_addr,
+                    frame_counter: 0,
+                }));
+                ReturnCode::SUCCESS
+            }
+            None => ReturnCode::ENOMEM,
+        }
+    }
+
+    /// Returns `EINVAL` if there is no device with `long_addr`.
+    pub fn remove_device(&self, long_addr: [u8; 8]) -> ReturnCode {
+        match self
+            .devices
+            .iter()
+            .find(|slot| slot.get().map_or(false, |desc| desc.long_addr == long_addr))
+        {
+            Some(slot) => {
+                slot.set(None);
+                ReturnCode::SUCCESS
+            }
+            None => ReturnCode::EINVAL,
+        }
+    }
+}
+
+impl KeyProcedure for KeyTable {
+    fn lookup_key(&self, level: SecurityLevel, key_id: KeyId) -> Option<[u8; 16]> {
+        self.keys
+            .iter()
+            .filter_map(|slot| slot.get())
+            .find(|desc| desc.level == level && desc.key_id == key_id)
+            .map(|desc| desc.key)
+    }
+}
+
+impl DeviceProcedure for KeyTable {
+    fn lookup_addr_long(&self, addr: MacAddress) -> Option<[u8; 8]> {
+        self.devices
+            .iter()
+            .filter_map(|slot| slot.get())
+            .find(|desc| match addr {
+                MacAddress::Short(addr) => desc.short_addr == Some(addr),
+                MacAddress::Long(addr) => desc.long_addr == addr,
+            })
+            .map(|desc| desc.long_addr)
+    }
+
+    fn update_frame_counter(&self, addr: [u8; 8], frame_counter: u32) -> bool {
+        let slot = self
+            .devices
+            .iter()
+            .find(|slot| slot.get().map_or(false, |desc| desc.long_addr == addr));
+        match slot.and_then(|slot| slot.get().map(|desc| (slot, desc))) {
+            Some((slot, mut desc)) if frame_counter >= desc.frame_counter => {
+                // The framer drops frames with the counter 0xffffffff
+                desc.frame_counter = frame_counter + 1;
+                slot.set(Some(desc));
+                true
+            }
+            _ => false,
+        }
+    }
+}
@@ -0,0 +1,419 @@
+//! Low-power listening MAC protocol layer for 802.15.4, in the style of
+//! ContikiMAC and BoX-MAC-2.
+//!
+//! Nodes using this layer keep their radios off except for short, periodic
+//! channel checks. Every check interval the radio wakes and samples the
+//! channel with two clear channel assessments. If both are clear the radio
+//! goes straight back to sleep; otherwise it listens for a frame for one
+//! listen window. Once a frame for this node arrives, the radio sleeps again.
+//!
+//! Rather than sending preambles, a transmitter repeats the whole data frame
+//! back to back, so a receiver that wakes during the strobe picks up a full
+//! copy. Unicast frames are repeated until they are acknowledged, or for one
+//! check interval, after which `ENOACK` is returned. Frames that request no
+//! acknowledgement, such as broadcasts, are repeated for the whole check
+//! interval so that every neighbour wakes during the strobe. Receivers drop
+//! the extra copies.
+//!
+//! Additional notes:
+//!
+//!   * The radio must support `kernel::hil::radio::RadioChannelCheck`.
+//!   * Every node in the network must use this layer and the same check
+//!     interval, or frames may not be received.
+//!   * No phase lock is kept, so each unicast strobe lasts half a check
+//!     interval on average.
+//!
+//! Usage
+//! -----
+//! This capsule implements the `capsules::ieee802154::mac::Mac` interface and
+//! can replace `AwakeMac` or `XMac` as the backend of a `Framer`:
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! use capsules::ieee802154::lpl::LplMac;
+//! use capsules::ieee802154::mac::Mac;
+//! type LplDevice = LplMac<'static, nrf52::ieee802154_radio::Radio, VirtualMuxAlarm<'static, Rtc>>;
+//!
+//! let lpl_mac = static_init!(LplDevice, LplMac::new(&nrf52::ieee802154_radio::RADIO, mac_alarm));
+//! mac_alarm.set_alarm_client(lpl_mac);
+//! nrf52::ieee802154_radio::RADIO.set_transmit_client(lpl_mac);
+//! nrf52::ieee802154_radio::RADIO.set_receive_client(lpl_mac, &mut RADIO_RX_BUF);
+//! nrf52::ieee802154_radio::RADIO.set_power_client(lpl_mac);
+//! nrf52::ieee802154_radio::RADIO.set_channel_check_client(lpl_mac);
+//!
+//! lpl_mac.set_check_interval_ms(125);
+//! lpl_mac.initialize(&mut MAC_BUF);
+//! ```
+
+use crate::ieee802154::mac::Mac;
+use crate::net::ieee802154::{Header, MacAddress};
+use core::cell::Cell;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::radio;
+use kernel::hil::time::{self, Alarm};
+use kernel::ReturnCode;
+
+/// Check interval used until `set_check_interval_ms()` is called, giving the
+/// 8 Hz channel check rate of ContikiMAC.
+pub const DEFAULT_CHECK_INTERVAL_MS: u32 = 125;
+
+/// Number of clear channel assessments in a channel check. A second sample
+/// catches a strobe that was between two copies during the first.
+const CHANNEL_CHECKS: u8 = 2;
+
+/// How long the radio listens after a busy channel check. Longer than a
+/// maximum sized frame (4 ms) plus the gap between two copies of a strobe.
+const LISTEN_WINDOW_MS: u32 = 20;
+
+/// How long a strobe outlasts the check interval, so that a receiver that
+/// wakes at the very end of it still sees a full copy.
+const STROBE_GUARD_MS: u32 = 10;
+
+#[derive(Copy, Clone, PartialEq)]
+enum State {
+    /// Radio off, waiting for the next channel check.
+    Sleep,
+    /// Radio turning on, `PowerClient::changed()` moves to the next state.
+    Startup,
+    /// Sampling the channel.
+    Checking,
+    /// Channel was busy, waiting for a frame.
+    Listening,
+    /// Repeating a frame from the client.
+    Strobing,
+}
+
+pub struct LplMac<'a, R: radio::Radio + radio::RadioChannelCheck, A: Alarm<'a>> {
+    radio: &'a R,
+    alarm: &'a A,
+    tx_client: OptionalCell<&'static dyn radio::TxClient>,
+    rx_client: OptionalCell<&'static dyn radio::RxClient>,
+    state: Cell<State>,
+    check_interval_ms: Cell<u32>,
+    checks_left: Cell<u8>,
+
+    tx_payload: TakeCell<'static, [u8]>,
+    tx_len: Cell<usize>,
+    /// The frame requests an acknowledgement, so the strobe stops at the
+    /// first one.
+    tx_ack_requested: Cell<bool>,
+    /// At least one copy of the frame went out.
+    tx_sent: Cell<bool>,
+    /// The strobe has lasted a check interval; finish after this copy.
+    tx_expired: Cell<bool>,
+
+    /// Source and sequence number of the last frame passed up, to drop the
+    /// other copies of its strobe.
+    last_rx: Cell<Option<(MacAddress, u8)>>,
+}
+
+impl<'a, R: radio::Radio + radio::RadioChannelCheck, A: Alarm<'a>> LplMac<'a, R, A> {
+    pub fn new(radio: &'a R, alarm: &'a A) -> LplMac<'a, R, A> {
+        LplMac {
+            radio: radio,
+            alarm: alarm,
+            tx_client: OptionalCell::empty(),
+            rx_client: OptionalCell::empty(),
+            state: Cell::new(State::Sleep),
+            check_interval_ms: Cell::new(DEFAULT_CHECK_INTERVAL_MS),
+            checks_left: Cell::new(0),
+            tx_payload: TakeCell::empty(),
+            tx_len: Cell::new(0),
+            tx_ack_requested: Cell::new(false),
+            tx_sent: Cell::new(false),
+            tx_expired: Cell::new(false),
+            last_rx: Cell::new(None),
+        }
+    }
+
+    /// Set the time between channel checks. Takes effect from the next
+    /// check. Longer intervals save power on receivers but make every strobe
+    /// longer.
+    pub fn set_check_interval_ms(&self, ms: u32) {
+        self.check_interval_ms.set(ms);
+    }
+
+    pub fn get_check_interval_ms(&self) -> u32 {
+        self.check_interval_ms.get()
+    }
+
+    fn set_timer_ms(&self, ms: u32) {
+        self.alarm.set_alarm(self.alarm.now(), A::ticks_from_ms(ms));
+    }
+
+    fn wake(&self) {
+        self.state.set(State::Startup);
+        if self.radio.is_on() {
+            self.radio_ready();
+        } else {
+            self.radio.start();
+        }
+    }
+
+    /// The radio is on: send the client's frame first, otherwise check the
+    /// channel.
+    fn radio_ready(&self) {
+        if self.tx_payload.is_some() {
+            self.start_strobe();
+        } else {
+            self.checks_left.set(CHANNEL_CHECKS);
+            self.check_channel();
+        }
+    }
+
+    fn check_channel(&self) {
+        self.state.set(State::Checking);
+        self.checks_left.set(self.checks_left.get() - 1);
+        if self.radio.channel_check() != ReturnCode::SUCCESS {
+            self.sleep();
+        }
+    }
+
+    /// Send any frame waiting for the radio, and otherwise turn the radio off
+    /// until the next channel check.
+    fn sleep(&self) {
+        if self.tx_payload.is_some() {
+            self.start_strobe();
+            return;
+        }
+        self.radio.stop();
+        self.state.set(State::Sleep);
+        self.set_timer_ms(self.check_interval_ms.get());
+    }
+
+    fn start_strobe(&self) {
+        self.tx_payload.take().map(|buf| {
+            self.state.set(State::Strobing);
+            self.tx_sent.set(false);
+            self.tx_expired.set(false);
+            self.set_timer_ms(self.check_interval_ms.get() + STROBE_GUARD_MS);
+            self.transmit_copy(buf);
+        });
+    }
+
+    fn transmit_copy(&self, buf: &'static mut [u8]) {
+        let (rcode, buf) = self.radio.transmit(buf, self.tx_len.get());
+        if rcode != ReturnCode::SUCCESS {
+            buf.map(|buf| self.call_tx_client(buf, false, rcode));
+        }
+    }
+
+    fn call_tx_client(&self, buf: &'static mut [u8], acked: bool, result: ReturnCode) {
+        self.alarm.disarm();
+        self.sleep();
+        self.tx_client.map(move |c| {
+            c.send_done(buf, acked, result);
+        });
+    }
+}
+
+impl<'a, R: radio::Radio + radio::RadioChannelCheck, A: Alarm<'a>> Mac for LplMac<'a, R, A> {
+    // Starts duty cycling, with the first channel check one interval from
+    // now.
+    fn initialize(&self, _mac_buf: &'static mut [u8]) -> ReturnCode {
+        if self.state.get() == State::Sleep {
+            self.sleep();
+        }
+        ReturnCode::SUCCESS
+    }
+
+    // The radio is woken whenever there is something to send, so the layer
+    // is always able to send frames even while the radio sleeps.
+    fn is_on(&self) -> bool {
+        true
+    }
+
+    fn set_config_client(&self, client: &'static dyn radio::ConfigClient) {
+        self.radio.set_config_client(client)
+    }
+
+    fn set_address(&self, addr: u16) {
+        self.radio.set_address(addr)
+    }
+
+    fn set_address_long(&self, addr: [u8; 8]) {
+        self.radio.set_address_long(addr)
+    }
+
+    fn set_pan(&self, id: u16) {
+        self.radio.set_pan(id)
+    }
+
+    fn get_address(&self) -> u16 {
+        self.radio.get_address()
+    }
+
+    fn get_address_long(&self) -> [u8; 8] {
+        self.radio.get_address_long()
+    }
+
+    fn get_pan(&self) -> u16 {
+        self.radio.get_pan()
+    }
+
+    fn config_commit(&self) {
+        self.radio.config_commit()
+    }
+
+    fn set_transmit_client(&self, client: &'static dyn radio::TxClient) {
+        self.tx_client.set(client);
+    }
+
+    fn set_receive_client(&self, client: &'static dyn radio::RxClient) {
+        self.rx_client.set(client);
+    }
+
+    fn set_receive_buffer(&self, buffer: &'static mut [u8]) {
+        self.radio.set_receive_buffer(buffer);
+    }
+
+    fn transmit(
+        &self,
+        full_mac_frame: &'static mut [u8],
+        frame_len: usize,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        if self.tx_payload.is_some() || self.state.get() == State::Strobing {
+            return (ReturnCode::EBUSY, Some(full_mac_frame));
+        } else if radio::PSDU_OFFSET + frame_len >= full_mac_frame.len() {
+            return (ReturnCode::ESIZE, Some(full_mac_frame));
+        }
+
+        match Header::decode(&full_mac_frame[radio::PSDU_OFFSET..], false).done() {
+            Some((_, (header, _))) => self.tx_ack_requested.set(header.ack_requested),
+            None => return (ReturnCode::FAIL, Some(full_mac_frame)),
+        }
+
+        self.tx_payload.replace(full_mac_frame);
+        self.tx_len.set(frame_len);
+        // Otherwise the strobe starts once the channel check or listen window
+        // in progress finishes.
+        if self.state.get() == State::Sleep {
+            self.wake();
+        }
+        (ReturnCode::SUCCESS, None)
+    }
+}
+
+impl<'a, R: radio::Radio + radio::RadioChannelCheck, A: Alarm<'a>> time::AlarmClient
+    for LplMac<'a, R, A>
+{
+    fn alarm(&self) {
+        match self.state.get() {
+            State::Sleep => self.wake(),
+            // The channel was busy but no frame for us arrived.
+            State::Listening => self.sleep(),
+            // The copy being sent is the last one.
+            State::Strobing => self.tx_expired.set(true),
+            _ => {}
+        }
+    }
+}
+
+impl<'a, R: radio::Radio + radio::RadioChannelCheck, A: Alarm<'a>> radio::PowerClient
+    for LplMac<'a, R, A>
+{
+    fn changed(&self, on: bool) {
+        if on && self.state.get() == State::Startup {
+            self.radio_ready();
+        }
+    }
+}
+
+impl<'a, R: radio::Radio + radio::RadioChannelCheck, A: Alarm<'a>> radio::ChannelCheckClient
+    for LplMac<'a, R, A>
+{
+    fn channel_checked(&self, clear: bool) {
+        if self.state.get() != State::Checking {
+            return;
+        }
+        if !clear {
+            self.state.set(State::Listening);
+            self.set_timer_ms(LISTEN_WINDOW_MS);
+        } else if self.checks_left.get() > 0 {
+            self.check_channel();
+        } else {
+            self.sleep();
+        }
+    }
+}
+
+impl<'a, R: radio::Radio + radio::RadioChannelCheck, A: Alarm<'a>> radio::TxClient
+    for LplMac<'a, R, A>
+{
+    fn send_done(&self, buf: &'static mut [u8], acked: bool, result: ReturnCode) {
+        if self.state.get() != State::Strobing {
+            return;
+        }
+        if result == ReturnCode::SUCCESS {
+            self.tx_sent.set(true);
+        }
+
+        if acked {
+            self.call_tx_client(buf, true, result);
+        } else if self.tx_expired.get() {
+            let result = if !self.tx_sent.get() {
+                result
+            } else if self.tx_ack_requested.get() {
+                ReturnCode::ENOACK
+            } else {
+                ReturnCode::SUCCESS
+            };
+            self.call_tx_client(buf, false, result);
+        } else {
+            self.transmit_copy(buf);
+        }
+    }
+}
+
+impl<'a, R: radio::Radio + radio::RadioChannelCheck, A: Alarm<'a>> radio::RxClient
+    for LplMac<'a, R, A>
+{
+    fn receive(
+        &self,
+        buf: &'static mut [u8],
+        frame_len: usize,
+        crc_valid: bool,
+        timestamp: Option<u32>,
+        result: ReturnCode,
+    ) {
+        // The radio passes up frames with a bad CRC, whose header cannot be
+        // trusted.
+        if !crc_valid {
+            self.radio.set_receive_buffer(buf);
+            return;
+        }
+
+        // Filter packets by destination because radio is in promiscuous mode
+        let mut addr_match = false;
+        let mut duplicate = false;
+        if let Some((_, (header, _))) = Header::decode(&buf[radio::PSDU_OFFSET..], false).done() {
+            if let Some(dst_addr) = header.dst_addr {
+                addr_match = match dst_addr {
+                    MacAddress::Short(addr) => addr == self.radio.get_address() || addr == 0xffff,
+                    MacAddress::Long(long_addr) => long_addr == self.radio.get_address_long(),
+                };
+            }
+            if let (Some(src_addr), Some(seq)) = (header.src_addr, header.seq) {
+                duplicate = self.last_rx.get() == Some((src_addr, seq));
+                if addr_match {
+                    self.last_rx.set(Some((src_addr, seq)));
+                }
+            }
+        }
+
+        if !addr_match || duplicate {
+            self.radio.set_receive_buffer(buf);
+            return;
+        }
+
+        if self.state.get() == State::Listening || self.state.get() == State::Checking {
+            self.alarm.disarm();
+            self.sleep();
+        }
+
+        self.rx_client.map(move |c| {
+            c.receive(buf, frame_len, crc_valid, timestamp, result);
+        });
+    }
+}
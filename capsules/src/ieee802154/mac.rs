@@ -11,8 +11,8 @@
 
 use crate::net::ieee802154::{Header, MacAddress};
 use kernel::common::cells::OptionalCell;
-use kernel::debug;
 use kernel::hil::radio;
+use kernel::log_trace;
 use kernel::ReturnCode;
 
 pub trait Mac {
@@ -161,6 +161,7 @@ impl<R: radio::Radio> radio::RxClient for AwakeMac<'_, R> {
         buf: &'static mut [u8],
         frame_len: usize,
         crc_valid: bool,
+        timestamp: Option<u32>,
         result: ReturnCode,
     ) {
         // Filter packets by destination because radio is in promiscuous mode
@@ -174,14 +175,17 @@ impl<R: radio::Radio> radio::RxClient for AwakeMac<'_, R> {
             }
         }
 
-        if addr_match {
+        // Frames with a bad CRC are dropped, as their address cannot be trusted
+        if addr_match && crc_valid {
             //debug!("[AwakeMAC] Rcvd a 15.4 frame addressed to this device");
             self.rx_client.map(move |c| {
-                c.receive(buf, frame_len, crc_valid, result);
+                c.receive(buf, frame_len, crc_valid, timestamp, result);
             });
         } else {
-            debug!("[AwakeMAC] Received a packet, but not addressed to us");
-            debug!("radio addr is: {:?}", self.radio.get_address());
+            log_trace!(
+                "[AwakeMAC] Received a packet, but not addressed to us, radio addr is: {:?}",
+                self.radio.get_address()
+            );
             self.radio.set_receive_buffer(buf);
         }
     }
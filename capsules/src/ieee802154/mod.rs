@@ -1,8 +1,16 @@
 //! Support for IEEE 802.15.4.
 
+pub mod channel_manager;
+pub mod csma;
 pub mod device;
 pub mod framer;
+pub mod key_table;
+pub mod lpl;
 pub mod mac;
+pub mod pcap;
+pub mod radio_config;
+pub mod raw;
+pub mod sleepy;
 pub mod virtual_mac;
 pub mod xmac;
 
@@ -0,0 +1,422 @@
+//! Packet capture of the frames the 802.15.4 radio sends and receives.
+//!
+//! `RadioTap` sits between a radio and the MAC layer above it, forwards
+//! everything in both directions, and shows each frame to a `FrameTap` on
+//! the way. It taps below the MAC, so it sees frames to other addresses and
+//! frames with bad CRCs that the MAC drops, which is usually what is needed
+//! to tell why a socket receives nothing.
+//!
+//! `PcapWriter` is a `FrameTap` that streams the frames over a UART in the
+//! pcap format, with the link type for 802.15.4 frames without an FCS, so
+//! that Wireshark can decode them live. Capture starts turned off and is
+//! toggled with `set_enabled`; turning it on writes the pcap file header
+//! first. Anything else written to the UART corrupts the capture, so the
+//! writer needs a UART (or a virtual UART device) of its own rather than
+//! the console's. Frames are queued in a ring buffer while the UART is
+//! busy, and a frame whose record does not fit is dropped whole and counted
+//! in `dropped`. The classic pcap format has no direction field, so sent
+//! frames are told apart from received ones by their source address.
+//!
+//! On the host, `wireshark -k -i - < /dev/ttyACM1` shows the capture, once
+//! the serial port is set to the UART's baud rate and to raw mode.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//! # use capsules::ieee802154::pcap::{PcapWriter, RadioTap};
+//!
+//! let radio_tap = static_init!(
+//!     RadioTap<'static, nrf52840::ieee802154_radio::Radio>,
+//!     RadioTap::new(&nrf52840::ieee802154_radio::RADIO)
+//! );
+//! // Build the MAC layers over `radio_tap` in place of the radio, then:
+//! radio_tap.attach();
+//!
+//! let pcap_writer = static_init!(
+//!     PcapWriter<'static, nrf52840::rtc::Rtc>,
+//!     PcapWriter::new(
+//!         pcap_uart,
+//!         &nrf52840::rtc::RTC,
+//!         &mut capsules::ieee802154::pcap::PCAP_TX_BUF,
+//!         &mut capsules::ieee802154::pcap::PCAP_QUEUE_BUF,
+//!     )
+//! );
+//! pcap_uart.set_transmit_client(pcap_writer);
+//! radio_tap.set_tap(pcap_writer);
+//! pcap_writer.set_enabled(true);
+//! ```
+
+use core::cell::Cell;
+use kernel::common::cells::{MapCell, OptionalCell, TakeCell};
+use kernel::common::{Queue, RingBuffer};
+use kernel::hil::radio;
+use kernel::hil::time::{Frequency, Ticks, Time};
+use kernel::hil::uart;
+use kernel::ReturnCode;
+
+/// The link type of 802.15.4 frames without an FCS.
+pub const LINKTYPE_IEEE802_15_4_NOFCS: u32 = 230;
+
+pub const PCAP_HEADER_LEN: usize = 24;
+pub const PCAP_RECORD_HEADER_LEN: usize = 16;
+
+pub static mut PCAP_TX_BUF: [u8; 64] = [0; 64];
+/// Holds a few full-size frame records while the UART is busy.
+pub static mut PCAP_QUEUE_BUF: [u8; 1024] = [0; 1024];
+
+/// Sees the frames that pass a `RadioTap`.
+pub trait FrameTap {
+    /// `frame` is the MAC frame without its FCS. `transmitted` is true for
+    /// frames sent by this node and false for received ones.
+    fn frame(&self, frame: &[u8], transmitted: bool);
+}
+
+pub struct RadioTap<'a, R: radio::Radio + 'static> {
+    radio: &'static R,
+    tap: OptionalCell<&'a dyn FrameTap>,
+    tx_client: OptionalCell<&'static dyn radio::TxClient>,
+    rx_client: OptionalCell<&'static dyn radio::RxClient>,
+    /// The receive buffer of the client above, until `attach` hands it to
+    /// the radio.
+    rx_buf: TakeCell<'static, [u8]>,
+    attached: Cell<bool>,
+}
+
+impl<'a, R: radio::Radio + 'static> RadioTap<'a, R> {
+    pub fn new(radio: &'static R) -> RadioTap<'a, R> {
+        RadioTap {
+            radio: radio,
+            tap: OptionalCell::empty(),
+            tx_client: OptionalCell::empty(),
+            rx_client: OptionalCell::empty(),
+            rx_buf: TakeCell::empty(),
+            attached: Cell::new(false),
+        }
+    }
+
+    /// Registers the tap as the client of the radio. The radio's receive
+    /// client takes a buffer with it, so this has to be called after the
+    /// layer above has set its receive client on the tap.
+    pub fn attach(&'static self) {
+        self.radio.set_transmit_client(self);
+        if let Some(buf) = self.rx_buf.take() {
+            self.radio.set_receive_client(self, buf);
+        }
+        self.attached.set(true);
+    }
+
+    pub fn set_tap(&self, tap: &'a dyn FrameTap) {
+        self.tap.set(tap);
+    }
+
+    pub fn clear_tap(&self) {
+        self.tap.clear();
+    }
+}
+
+impl<R: radio::Radio + 'static> radio::Radio for RadioTap<'_, R> {}
+
+impl<R: radio::Radio + 'static> radio::RadioConfig for RadioTap<'_, R> {
+    fn initialize(
+        &self,
+        spi_buf: &'static mut [u8],
+        reg_write: &'static mut [u8],
+        reg_read: &'static mut [u8],
+    ) -> ReturnCode {
+        self.radio.initialize(spi_buf, reg_write, reg_read)
+    }
+
+    fn reset(&self) -> ReturnCode {
+        self.radio.reset()
+    }
+
+    fn start(&self) -> ReturnCode {
+        self.radio.start()
+    }
+
+    fn stop(&self) -> ReturnCode {
+        self.radio.stop()
+    }
+
+    fn is_on(&self) -> bool {
+        self.radio.is_on()
+    }
+
+    fn busy(&self) -> bool {
+        self.radio.busy()
+    }
+
+    fn set_power_client(&self, client: &'static dyn radio::PowerClient) {
+        self.radio.set_power_client(client);
+    }
+
+    fn config_commit(&self) {
+        self.radio.config_commit();
+    }
+
+    fn set_config_client(&self, client: &'static dyn radio::ConfigClient) {
+        self.radio.set_config_client(client);
+    }
+
+    fn get_address(&self) -> u16 {
+        self.radio.get_address()
+    }
+
+    fn get_address_long(&self) -> [u8; 8] {
+        self.radio.get_address_long()
+    }
+
+    fn get_pan(&self) -> u16 {
+        self.radio.get_pan()
+    }
+
+    fn get_tx_power(&self) -> i8 {
+        self.radio.get_tx_power()
+    }
+
+    fn get_channel(&self) -> u8 {
+        self.radio.get_channel()
+    }
+
+    fn set_address(&self, addr: u16) {
+        self.radio.set_address(addr);
+    }
+
+    fn set_address_long(&self, addr: [u8; 8]) {
+        self.radio.set_address_long(addr);
+    }
+
+    fn set_pan(&self, id: u16) {
+        self.radio.set_pan(id);
+    }
+
+    fn set_tx_power(&self, power: i8) -> ReturnCode {
+        self.radio.set_tx_power(power)
+    }
+
+    fn set_channel(&self, chan: u8) -> ReturnCode {
+        self.radio.set_channel(chan)
+    }
+}
+
+impl<R: radio::Radio + 'static> radio::RadioData for RadioTap<'_, R> {
+    fn set_transmit_client(&self, client: &'static dyn radio::TxClient) {
+        self.tx_client.set(client);
+    }
+
+    fn set_receive_client(
+        &self,
+        client: &'static dyn radio::RxClient,
+        receive_buffer: &'static mut [u8],
+    ) {
+        self.rx_client.set(client);
+        self.set_receive_buffer(receive_buffer);
+    }
+
+    fn set_receive_buffer(&self, receive_buffer: &'static mut [u8]) {
+        if self.attached.get() {
+            self.radio.set_receive_buffer(receive_buffer);
+        } else {
+            self.rx_buf.replace(receive_buffer);
+        }
+    }
+
+    fn transmit(
+        &self,
+        spi_buf: &'static mut [u8],
+        frame_len: usize,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        // The radio owns the buffer once it accepts the frame, so the tap
+        // sees a copy, and only frames the radio is going to send
+        let mut frame = [0; radio::MAX_FRAME_SIZE];
+        let len = frame_len.min(radio::MAX_FRAME_SIZE);
+        let end = spi_buf.len().min(radio::PSDU_OFFSET + len);
+        let len = end.saturating_sub(radio::PSDU_OFFSET);
+        frame[..len].copy_from_slice(&spi_buf[radio::PSDU_OFFSET..end]);
+
+        let (result, buf) = self.radio.transmit(spi_buf, frame_len);
+        if result == ReturnCode::SUCCESS {
+            self.tap.map(|tap| tap.frame(&frame[..len], true));
+        }
+        (result, buf)
+    }
+}
+
+impl<R: radio::Radio + 'static> radio::TxClient for RadioTap<'_, R> {
+    fn send_done(&self, buf: &'static mut [u8], acked: bool, result: ReturnCode) {
+        self.tx_client.map(move |client| {
+            client.send_done(buf, acked, result);
+        });
+    }
+}
+
+impl<R: radio::Radio + 'static> radio::RxClient for RadioTap<'_, R> {
+    fn receive(
+        &self,
+        buf: &'static mut [u8],
+        frame_len: usize,
+        crc_valid: bool,
+        timestamp: Option<u32>,
+        result: ReturnCode,
+    ) {
+        let end = radio::PSDU_OFFSET + frame_len;
+        if result == ReturnCode::SUCCESS && end <= buf.len() {
+            self.tap.map(|tap| {
+                tap.frame(&buf[radio::PSDU_OFFSET..end], false);
+            });
+        }
+        self.rx_client.map(move |client| {
+            client.receive(buf, frame_len, crc_valid, timestamp, result);
+        });
+    }
+}
+
+pub struct PcapWriter<'a, T: Time> {
+    uart: &'a dyn uart::Transmit<'a>,
+    time: &'a T,
+    tx_buf: TakeCell<'static, [u8]>,
+    queue: MapCell<RingBuffer<'static, u8>>,
+    enabled: Cell<bool>,
+    dropped: Cell<u32>,
+    /// The time of the last frame, and the ticks since capture started.
+    last_now: Cell<T::Ticks>,
+    elapsed: Cell<u64>,
+}
+
+impl<'a, T: Time> PcapWriter<'a, T> {
+    /// `queue_buf` must hold the file header and should hold a few frame
+    /// records of up to 143 bytes.
+    pub fn new(
+        uart: &'a dyn uart::Transmit<'a>,
+        time: &'a T,
+        tx_buf: &'static mut [u8],
+        queue_buf: &'static mut [u8],
+    ) -> PcapWriter<'a, T> {
+        PcapWriter {
+            uart: uart,
+            time: time,
+            tx_buf: TakeCell::new(tx_buf),
+            queue: MapCell::new(RingBuffer::new(queue_buf)),
+            enabled: Cell::new(false),
+            dropped: Cell::new(0),
+            last_now: Cell::new(T::Ticks::from(0)),
+            elapsed: Cell::new(0),
+        }
+    }
+
+    /// Starts or stops the capture. Starting it writes the pcap file
+    /// header, after which the host sees a new capture that starts at time
+    /// zero.
+    pub fn set_enabled(&self, enabled: bool) {
+        if enabled == self.enabled.get() {
+            return;
+        }
+        self.enabled.set(enabled);
+        if enabled {
+            self.last_now.set(self.time.now());
+            self.elapsed.set(0);
+            let mut header = [0; PCAP_HEADER_LEN];
+            header[0..4].copy_from_slice(&0xa1b2c3d4u32.to_le_bytes());
+            header[4..6].copy_from_slice(&2u16.to_le_bytes());
+            header[6..8].copy_from_slice(&4u16.to_le_bytes());
+            // The time zone offset and timestamp accuracy stay 0
+            header[16..20].copy_from_slice(&(radio::MAX_FRAME_SIZE as u32).to_le_bytes());
+            header[20..24].copy_from_slice(&LINKTYPE_IEEE802_15_4_NOFCS.to_le_bytes());
+            // A header that does not fit is dropped like a frame, and the
+            // host sees no capture until it is enabled again
+            if !self.enqueue(&[&header]) {
+                self.dropped.set(self.dropped.get() + 1);
+            }
+            self.publish();
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.get()
+    }
+
+    /// The number of frames dropped because the queue was full.
+    pub fn dropped(&self) -> u32 {
+        self.dropped.get()
+    }
+
+    /// The microseconds since capture started.
+    fn timestamp_us(&self) -> u64 {
+        let now = self.time.now();
+        let ticks = now.wrapping_sub(self.last_now.get()).into_u64();
+        self.last_now.set(now);
+        self.elapsed.set(self.elapsed.get() + ticks);
+        self.elapsed.get() * 1_000_000 / T::Frequency::frequency() as u64
+    }
+
+    /// Queues the concatenation of `parts`, or nothing if it does not fit.
+    fn enqueue(&self, parts: &[&[u8]]) -> bool {
+        let len: usize = parts.iter().map(|part| part.len()).sum();
+        self.queue.map_or(false, |queue| {
+            if queue.available_len() < len {
+                return false;
+            }
+            for part in parts.iter() {
+                for b in part.iter() {
+                    queue.enqueue(*b);
+                }
+            }
+            true
+        })
+    }
+
+    fn publish(&self) {
+        self.queue.map(|queue| {
+            if let Some(tx_buf) = self.tx_buf.take() {
+                let mut count = 0;
+                for dst in tx_buf.iter_mut() {
+                    match queue.dequeue() {
+                        Some(src) => {
+                            *dst = src;
+                            count += 1;
+                        }
+                        None => break,
+                    }
+                }
+                if count != 0 {
+                    let (_result, buf) = self.uart.transmit_buffer(tx_buf, count);
+                    self.tx_buf.put(buf);
+                } else {
+                    self.tx_buf.replace(tx_buf);
+                }
+            }
+        });
+    }
+}
+
+impl<T: Time> FrameTap for PcapWriter<'_, T> {
+    fn frame(&self, frame: &[u8], _transmitted: bool) {
+        if !self.enabled.get() {
+            return;
+        }
+        let us = self.timestamp_us();
+        let mut header = [0; PCAP_RECORD_HEADER_LEN];
+        header[0..4].copy_from_slice(&((us / 1_000_000) as u32).to_le_bytes());
+        header[4..8].copy_from_slice(&((us % 1_000_000) as u32).to_le_bytes());
+        header[8..12].copy_from_slice(&(frame.len() as u32).to_le_bytes());
+        header[12..16].copy_from_slice(&(frame.len() as u32).to_le_bytes());
+        if self.enqueue(&[&header, frame]) {
+            self.publish();
+        } else {
+            self.dropped.set(self.dropped.get() + 1);
+        }
+    }
+}
+
+impl<T: Time> uart::TransmitClient for PcapWriter<'_, T> {
+    fn transmitted_buffer(&self, tx_buffer: &'static mut [u8], _tx_len: usize, _rcode: ReturnCode) {
+        self.tx_buf.replace(tx_buffer);
+        if self.queue.map_or(false, |queue| queue.has_elements()) {
+            self.publish();
+        }
+    }
+
+    fn transmitted_word(&self, _rcode: ReturnCode) {}
+}
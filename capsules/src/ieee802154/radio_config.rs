@@ -0,0 +1,259 @@
+//! Runtime configuration of an 802.15.4 radio from userspace.
+//!
+//! Lets an app retune a deployed device: change the channel, transmit power,
+//! PAN ID and the short and long addresses the radio filters frames on.
+//! Changes are staged with the `set` commands and take effect together on
+//! `commit`, after which every app that subscribed, and every kernel listener
+//! added with `add_listener()`, is told so they can pick up the new settings.
+//!
+//! Any app that can reach this driver can move the whole node off its
+//! network, so boards should only give it to trusted apps, by listing it in
+//! the permissions of their TBF headers and not in those of other apps.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//! # use capsules::ieee802154::radio_config::RadioConfigDriver;
+//!
+//! let radio_config = static_init!(
+//!     RadioConfigDriver<'static>,
+//!     RadioConfigDriver::new(
+//!         &nrf52840::ieee802154_radio::RADIO,
+//!         board_kernel.create_grant(&memory_allocation_capability)
+//!     )
+//! );
+//! radio_config.add_listener(channel_monitor);
+//! ```
+
+use core::cell::Cell;
+
+use kernel::common::cells::OptionalCell;
+use kernel::hil::radio;
+use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
+
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::RadioConfig as usize;
+
+/// How many kernel listeners can be added.
+pub const MAX_LISTENERS: usize = 4;
+
+/// Transmit powers cross the syscall boundary offset by this, so that
+/// negative powers are positive return values.
+const TX_POWER_OFFSET: isize = 128;
+
+/// Implemented by capsules that depend on the radio configuration.
+pub trait RadioConfigListener {
+    /// New settings were committed to the radio.
+    fn radio_config_changed(&self);
+}
+
+#[derive(Default)]
+pub struct App {
+    callback: Option<Callback>,
+    /// Holds a long address: 8 bytes, most significant first.
+    address_long: Option<AppSlice<Shared, u8>>,
+}
+
+pub struct RadioConfigDriver<'a> {
+    radio: &'a dyn radio::RadioConfig,
+    apps: Grant<App>,
+    listeners: [OptionalCell<&'a dyn RadioConfigListener>; MAX_LISTENERS],
+    /// Settings staged since the last commit, if any.
+    pending: Cell<bool>,
+}
+
+impl<'a> RadioConfigDriver<'a> {
+    pub fn new(radio: &'a dyn radio::RadioConfig, grant: Grant<App>) -> RadioConfigDriver<'a> {
+        RadioConfigDriver {
+            radio: radio,
+            apps: grant,
+            listeners: [
+                OptionalCell::empty(),
+                OptionalCell::empty(),
+                OptionalCell::empty(),
+                OptionalCell::empty(),
+            ],
+            pending: Cell::new(false),
+        }
+    }
+
+    /// Tell `listener` about every commit. Returns `ENOMEM` once
+    /// `MAX_LISTENERS` were added.
+    pub fn add_listener(&self, listener: &'a dyn RadioConfigListener) -> ReturnCode {
+        self.listeners
+            .iter()
+            .find(|slot| slot.is_none())
+            .map_or(ReturnCode::ENOMEM, |slot| {
+                slot.set(listener);
+                ReturnCode::SUCCESS
+            })
+    }
+
+    /// Apply the staged settings and notify listeners and apps. Also for the
+    /// kernel, such as a console command, to call after changing the radio.
+    pub fn commit(&self) {
+        self.pending.set(false);
+        self.radio.config_commit();
+
+        for listener in self.listeners.iter() {
+            listener.map(|listener| listener.radio_config_changed());
+        }
+        let channel = self.radio.get_channel() as usize;
+        let tx_power = (self.radio.get_tx_power() as isize + TX_POWER_OFFSET) as usize;
+        let pan = self.radio.get_pan() as usize;
+        self.apps.each(|app| {
+            app.callback
+                .map(|mut callback| callback.schedule(channel, tx_power, pan));
+        });
+    }
+
+    fn staged(&self, rcode: ReturnCode) -> ReturnCode {
+        if rcode == ReturnCode::SUCCESS {
+            self.pending.set(true);
+        }
+        rcode
+    }
+}
+
+impl<'a> Driver for RadioConfigDriver<'a> {
+    /// ### `allow_num`
+    ///
+    /// - `0`: Buffer for a long address, 8 bytes, most significant first.
+    fn allow(
+        &self,
+        appid: AppId,
+        allow_num: usize,
+        slice: Option<AppSlice<Shared, u8>>,
+    ) -> ReturnCode {
+        match allow_num {
+            0 => self
+                .apps
+                .enter(appid, |app, _| {
+                    app.address_long = slice;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Settings were committed, by any app or the kernel. The callback
+    ///        gets the channel, the transmit power in dBm plus 128 and the
+    ///        PAN ID.
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        callback: Option<Callback>,
+        appid: AppId,
+    ) -> ReturnCode {
+        match subscribe_num {
+            0 => self
+                .apps
+                .enter(appid, |app, _| {
+                    app.callback = callback;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// Getters return the current, committed or not, value. Setters stage a
+    /// value until `commit`.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Get the channel.
+    /// - `2`: Set the channel. `EINVAL` if the radio does not have it.
+    /// - `3`: Get the transmit power, in dBm plus 128.
+    /// - `4`: Set the transmit power, in dBm plus 128. `EINVAL` if the radio
+    ///        does not support it.
+    /// - `5`: Get the PAN ID.
+    /// - `6`: Set the PAN ID.
+    /// - `7`: Get the short address.
+    /// - `8`: Set the short address.
+    /// - `9`: Copy the long address into the allowed buffer.
+    /// - `10`: Set the long address from the allowed buffer.
+    /// - `11`: Commit the staged settings. `EALREADY` if nothing was staged.
+    fn command(&self, command_num: usize, arg1: usize, _: usize, appid: AppId) -> ReturnCode {
+        match command_num {
+            0 => ReturnCode::SUCCESS,
+            1 => ReturnCode::SuccessWithValue {
+                value: self.radio.get_channel() as usize,
+            },
+            2 => match self.radio.set_channel(arg1 as u8) {
+                ReturnCode::ENOSUPPORT => ReturnCode::EINVAL,
+                rcode => self.staged(rcode),
+            },
+            3 => ReturnCode::SuccessWithValue {
+                value: (self.radio.get_tx_power() as isize + TX_POWER_OFFSET) as usize,
+            },
+            4 => {
+                if arg1 > u8::max_value() as usize {
+                    return ReturnCode::EINVAL;
+                }
+                let dbm = (arg1 as isize - TX_POWER_OFFSET) as i8;
+                match self.radio.set_tx_power(dbm) {
+                    ReturnCode::ENOSUPPORT => ReturnCode::EINVAL,
+                    rcode => self.staged(rcode),
+                }
+            }
+            5 => ReturnCode::SuccessWithValue {
+                value: self.radio.get_pan() as usize,
+            },
+            6 => {
+                self.radio.set_pan(arg1 as u16);
+                self.staged(ReturnCode::SUCCESS)
+            }
+            7 => ReturnCode::SuccessWithValue {
+                value: self.radio.get_address() as usize,
+            },
+            8 => {
+                self.radio.set_address(arg1 as u16);
+                self.staged(ReturnCode::SUCCESS)
+            }
+            9 => self
+                .apps
+                .enter(appid, |app, _| {
+                    app.address_long
+                        .as_mut()
+                        .filter(|slice| slice.len() >= 8)
+                        .map_or(ReturnCode::EINVAL, |slice| {
+                            slice.as_mut()[..8].copy_from_slice(&self.radio.get_address_long());
+                            ReturnCode::SUCCESS
+                        })
+                })
+                .unwrap_or_else(|err| err.into()),
+            10 => {
+                let rcode = self
+                    .apps
+                    .enter(appid, |app, _| {
+                        app.address_long
+                            .as_ref()
+                            .filter(|slice| slice.len() >= 8)
+                            .map_or(ReturnCode::EINVAL, |slice| {
+                                let mut address = [0; 8];
+                                address.copy_from_slice(&slice.as_ref()[..8]);
+                                self.radio.set_address_long(address);
+                                ReturnCode::SUCCESS
+                            })
+                    })
+                    .unwrap_or_else(|err| err.into());
+                self.staged(rcode)
+            }
+            11 => {
+                if !self.pending.get() {
+                    return ReturnCode::EALREADY;
+                }
+                self.commit();
+                ReturnCode::SUCCESS
+            }
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
@@ -0,0 +1,304 @@
+//! Provides userspace with raw access to an 802.15.4 radio.
+//!
+//! Unlike `RadioDriver`, which frames packets in the kernel, this driver
+//! sends and receives whole MAC frames as the app builds them, so MAC
+//! protocols can be prototyped in userspace on top of the radio driver alone.
+//! Frames are the MAC header and payload; the radio adds and checks the FCS.
+//! The driver takes the place of the MAC layer as the radio's only client.
+//!
+//! By default only frames addressed to the radio's PAN and address, or to the
+//! broadcast PAN or address, are passed up. In promiscuous mode every frame is
+//! passed up, including ones with a bad CRC. The first app to use a command
+//! other than the driver check owns the driver until it exits; other apps get
+//! `ERESERVE`.
+//!
+//! ## Instantiation
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let raw_radio = static_init!(
+//!     capsules::ieee802154::raw::RawDriver<'static, nrf52840::ieee802154_radio::Radio>,
+//!     capsules::ieee802154::raw::RawDriver::new(
+//!         &nrf52840::ieee802154_radio::RADIO,
+//!         static_init!([u8; radio::MAX_BUF_SIZE], [0; radio::MAX_BUF_SIZE]),
+//!         board_kernel.create_grant(&grant_cap)));
+//! nrf52840::ieee802154_radio::RADIO.set_transmit_client(raw_radio);
+//! nrf52840::ieee802154_radio::RADIO.set_receive_client(
+//!     raw_radio,
+//!     static_init!([u8; radio::MAX_BUF_SIZE], [0; radio::MAX_BUF_SIZE]));
+//! nrf52840::ieee802154_radio::RADIO.set_config_client(raw_radio);
+//! ```
+//!
+//! ## Syscall Interface
+//!
+//! - allow `0`: the frame to transmit.
+//! - allow `1`: received frames are copied here.
+//! - allow `2`: the 8 byte long address used by command `4`.
+//! - subscribe `0`: a transmission finished, `fn(ReturnCode, acked, 0)`.
+//! - subscribe `1`: a frame arrived, `fn(len, crc_valid, timestamp)`.
+//! - subscribe `2`: the configuration was committed, `fn(ReturnCode, 0, 0)`.
+//! - command `0`: driver check.
+//! - command `1`: transmit the first `data` bytes of allow `0`.
+//! - command `2`: use the PAN ID `data`.
+//! - command `3`: use the short address `data`.
+//! - command `4`: use the long address in allow `2`.
+//! - command `5`: use channel `data`.
+//! - command `6`: transmit at `data` dBm, as an `i8`.
+//! - command `7`: commit the configuration set with commands `2` to `6`.
+//! - command `8`: pass up every frame if `data` is 1, or only frames
+//!   addressed to this radio if it is 0.
+
+use core::cell::Cell;
+use core::cmp;
+
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::radio;
+use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
+
+use crate::driver;
+use crate::net::ieee802154::{Header, MacAddress};
+pub const DRIVER_NUM: usize = driver::NUM::Ieee802154Raw as usize;
+
+const BROADCAST: u16 = 0xffff;
+
+#[derive(Default)]
+pub struct App {
+    tx_callback: Option<Callback>,
+    rx_callback: Option<Callback>,
+    config_callback: Option<Callback>,
+    tx_frame: Option<AppSlice<Shared, u8>>,
+    rx_frame: Option<AppSlice<Shared, u8>>,
+    long_address: Option<AppSlice<Shared, u8>>,
+}
+
+pub struct RawDriver<'a, R: radio::Radio> {
+    radio: &'a R,
+    tx_buffer: TakeCell<'static, [u8]>,
+    promiscuous: Cell<bool>,
+    apps: Grant<App>,
+    owner: OptionalCell<AppId>,
+}
+
+impl<'a, R: radio::Radio> RawDriver<'a, R> {
+    pub fn new(radio: &'a R, tx_buffer: &'static mut [u8], apps: Grant<App>) -> RawDriver<'a, R> {
+        RawDriver {
+            radio: radio,
+            tx_buffer: TakeCell::new(tx_buffer),
+            promiscuous: Cell::new(false),
+            apps: apps,
+            owner: OptionalCell::empty(),
+        }
+    }
+
+    fn transmit(&self, app: &mut App, len: usize) -> ReturnCode {
+        if !self.radio.is_on() {
+            return ReturnCode::EOFF;
+        }
+        if len > radio::MAX_FRAME_SIZE - radio::MFR_SIZE {
+            return ReturnCode::ESIZE;
+        }
+        let buffer = match self.tx_buffer.take() {
+            Some(buffer) => buffer,
+            None => return ReturnCode::EBUSY,
+        };
+        let frame = &mut buffer[radio::PSDU_OFFSET..radio::PSDU_OFFSET + len];
+        match app.tx_frame {
+            Some(ref slice) if slice.len() >= len => {
+                frame.copy_from_slice(&slice.as_ref()[..len]);
+            }
+            _ => {
+                self.tx_buffer.replace(buffer);
+                return ReturnCode::EINVAL;
+            }
+        }
+        let (rcode, buffer) = self.radio.transmit(buffer, len);
+        if let Some(buffer) = buffer {
+            self.tx_buffer.replace(buffer);
+        }
+        rcode
+    }
+
+    fn set_long_address(&self, app: &mut App) -> ReturnCode {
+        match app.long_address {
+            Some(ref slice) if slice.len() == 8 => {
+                let mut address = [0; 8];
+                address.copy_from_slice(slice.as_ref());
+                self.radio.set_address_long(address);
+                ReturnCode::SUCCESS
+            }
+            _ => ReturnCode::EINVAL,
+        }
+    }
+
+    /// Whether a frame is for this radio, as the radio's own address
+    /// filtering would decide.
+    fn addressed_to_us(&self, frame: &[u8]) -> bool {
+        let header = match Header::decode(frame, false).done() {
+            Some((_, (header, _))) => header,
+            None => return false,
+        };
+        let pan_match = header
+            .dst_pan
+            .map_or(true, |pan| pan == BROADCAST || pan == self.radio.get_pan());
+        let addr_match = match header.dst_addr {
+            Some(MacAddress::Short(addr)) => addr == BROADCAST || addr == self.radio.get_address(),
+            Some(MacAddress::Long(addr)) => addr == self.radio.get_address_long(),
+            None => false,
+        };
+        pan_match && addr_match
+    }
+
+    /// Whether `appid` may use the driver, making it the owner if the driver
+    /// has none. The owner is dropped, and promiscuous mode turned off, once
+    /// its app no longer exists.
+    fn owned_by(&self, appid: AppId) -> bool {
+        let owner = self.owner.map_or(None, |owner| {
+            if *owner != appid && self.apps.enter(*owner, |_, _| ()).is_err() {
+                None
+            } else {
+                Some(*owner)
+            }
+        });
+        match owner {
+            Some(owner) => owner == appid,
+            None => {
+                self.promiscuous.set(false);
+                self.owner.set(appid);
+                true
+            }
+        }
+    }
+
+    fn with_owner<F: FnOnce(&mut App)>(&self, f: F) {
+        self.owner.map(|owner| {
+            let _ = self.apps.enter(*owner, |app, _| f(app));
+        });
+    }
+}
+
+impl<R: radio::Radio> radio::TxClient for RawDriver<'_, R> {
+    fn send_done(&self, buf: &'static mut [u8], acked: bool, result: ReturnCode) {
+        self.tx_buffer.replace(buf);
+        self.with_owner(|app| {
+            app.tx_callback
+                .map(|mut cb| cb.schedule(usize::from(result), acked as usize, 0));
+        });
+    }
+}
+
+impl<R: radio::Radio> radio::RxClient for RawDriver<'_, R> {
+    fn receive(
+        &self,
+        buf: &'static mut [u8],
+        frame_len: usize,
+        crc_valid: bool,
+        timestamp: Option<u32>,
+        result: ReturnCode,
+    ) {
+        let frame_len = cmp::min(frame_len, buf.len() - radio::PSDU_OFFSET);
+        let frame = &buf[radio::PSDU_OFFSET..radio::PSDU_OFFSET + frame_len];
+        let wanted = result == ReturnCode::SUCCESS
+            && (self.promiscuous.get() || (crc_valid && self.addressed_to_us(frame)));
+        if wanted {
+            self.with_owner(|app| {
+                let copied = app.rx_frame.as_mut().map_or(0, |slice| {
+                    let copied = cmp::min(slice.len(), frame_len);
+                    slice.as_mut()[..copied].copy_from_slice(&frame[..copied]);
+                    copied
+                });
+                app.rx_callback.map(|mut cb| {
+                    cb.schedule(copied, crc_valid as usize, timestamp.unwrap_or(0) as usize)
+                });
+            });
+        }
+        self.radio.set_receive_buffer(buf);
+    }
+}
+
+impl<R: radio::Radio> radio::ConfigClient for RawDriver<'_, R> {
+    fn config_done(&self, result: ReturnCode) {
+        self.with_owner(|app| {
+            app.config_callback
+                .map(|mut cb| cb.schedule(usize::from(result), 0, 0));
+        });
+    }
+}
+
+impl<R: radio::Radio> Driver for RawDriver<'_, R> {
+    fn allow(
+        &self,
+        appid: AppId,
+        allow_num: usize,
+        slice: Option<AppSlice<Shared, u8>>,
+    ) -> ReturnCode {
+        self.apps
+            .enter(appid, |app, _| {
+                match allow_num {
+                    0 => app.tx_frame = slice,
+                    1 => app.rx_frame = slice,
+                    2 => app.long_address = slice,
+                    _ => return ReturnCode::ENOSUPPORT,
+                }
+                ReturnCode::SUCCESS
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        callback: Option<Callback>,
+        appid: AppId,
+    ) -> ReturnCode {
+        self.apps
+            .enter(appid, |app, _| {
+                match subscribe_num {
+                    0 => app.tx_callback = callback,
+                    1 => app.rx_callback = callback,
+                    2 => app.config_callback = callback,
+                    _ => return ReturnCode::ENOSUPPORT,
+                }
+                ReturnCode::SUCCESS
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    fn command(&self, command_num: usize, data: usize, _: usize, appid: AppId) -> ReturnCode {
+        if command_num == 0 {
+            return ReturnCode::SUCCESS;
+        }
+        if command_num > 8 {
+            return ReturnCode::ENOSUPPORT;
+        }
+        if !self.owned_by(appid) {
+            return ReturnCode::ERESERVE;
+        }
+
+        self.apps
+            .enter(appid, |app, _| match command_num {
+                1 => self.transmit(app, data),
+                2 => {
+                    self.radio.set_pan(data as u16);
+                    ReturnCode::SUCCESS
+                }
+                3 => {
+                    self.radio.set_address(data as u16);
+                    ReturnCode::SUCCESS
+                }
+                4 => self.set_long_address(app),
+                5 => self.radio.set_channel(data as u8),
+                6 => self.radio.set_tx_power(data as i8),
+                7 => {
+                    self.radio.config_commit();
+                    ReturnCode::SUCCESS
+                }
+                8 => {
+                    self.promiscuous.set(data != 0);
+                    ReturnCode::SUCCESS
+                }
+                _ => ReturnCode::ENOSUPPORT,
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+}
@@ -0,0 +1,442 @@
+//! MAC protocol layer for sleepy 802.15.4 end devices.
+//!
+//! A sleepy end device keeps its radio off almost all of the time. Frames for
+//! it are held by its parent (indirect transmission) until the device asks for
+//! them. Every poll interval this layer wakes the radio and sends a MAC Data
+//! Request command to the parent. If the parent acknowledges the request, the
+//! radio stays on for a short window to receive the queued frame; otherwise
+//! the radio goes straight back to sleep. A received frame with the Frame
+//! Pending bit set means the parent holds more frames, so the device polls
+//! again immediately.
+//!
+//! The radio HIL does not report the Frame Pending bit of acknowledgements, so
+//! the receive window is opened after every acknowledged request rather than
+//! only when the parent says it has data.
+//!
+//! Frames from the client are sent directly to their destination, waking the
+//! radio if necessary. The radio is turned off once the transmission
+//! completes.
+//!
+//! Usage
+//! -----
+//! This capsule implements the `capsules::ieee802154::mac::Mac` interface and
+//! can replace `AwakeMac` or `XMac` as the backend of a `Framer`:
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! use capsules::ieee802154::mac::Mac;
+//! use capsules::ieee802154::sleepy::SleepyMac;
+//! use capsules::net::ieee802154::MacAddress;
+//! type SleepyDevice = SleepyMac<'static, RF233Device, VirtualMuxAlarm<'static, Ast>>;
+//!
+//! // Buffer used for the Data Request command frames.
+//! static mut MAC_BUF: [u8; radio::MAX_BUF_SIZE] = [0x00; radio::MAX_BUF_SIZE];
+//!
+//! let sleepy_mac = static_init!(SleepyDevice, SleepyMac::new(rf233, mac_alarm));
+//! mac_alarm.set_alarm_client(sleepy_mac);
+//! rf233.set_transmit_client(sleepy_mac);
+//! rf233.set_receive_client(sleepy_mac, &mut RF233_RX_BUF);
+//! rf233.set_power_client(sleepy_mac);
+//!
+//! sleepy_mac.initialize(&mut MAC_BUF);
+//! sleepy_mac.set_parent(MacAddress::Short(0x0001));
+//! sleepy_mac.set_poll_interval_ms(1000);
+//! sleepy_mac.start_polling();
+//! ```
+
+use crate::ieee802154::mac::Mac;
+use crate::net::ieee802154::{FrameType, FrameVersion, Header, MacAddress};
+use core::cell::Cell;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::radio;
+use kernel::hil::time::{self, Alarm};
+use kernel::ReturnCode;
+
+/// Poll interval used until `set_poll_interval_ms()` is called.
+pub const DEFAULT_POLL_INTERVAL_MS: u32 = 1000;
+
+/// How long the radio stays on after an acknowledged Data Request, waiting
+/// for the parent to send the queued frame. The standard's
+/// macMaxFrameTotalWaitTime is a little under 20 ms at 2.4 GHz.
+const POLL_RX_WINDOW_MS: u32 = 20;
+
+/// MAC command identifier of a Data Request.
+const DATA_REQUEST_COMMAND: u8 = 0x04;
+
+#[derive(Copy, Clone, PartialEq)]
+enum State {
+    /// Radio off, waiting for the next poll.
+    Sleep,
+    /// Radio turning on, `PowerClient::changed()` moves to the next state.
+    Startup,
+    /// Sending a Data Request to the parent.
+    Polling,
+    /// Request acknowledged, waiting for the indirect frame.
+    Listening,
+    /// Sending a frame from the client.
+    Transmitting,
+}
+
+pub struct SleepyMac<'a, R: radio::Radio, A: Alarm<'a>> {
+    radio: &'a R,
+    alarm: &'a A,
+    tx_client: OptionalCell<&'static dyn radio::TxClient>,
+    rx_client: OptionalCell<&'static dyn radio::RxClient>,
+    state: Cell<State>,
+
+    parent: Cell<Option<MacAddress>>,
+    poll_interval_ms: Cell<u32>,
+    polling: Cell<bool>,
+    /// Poll again as soon as the current exchange finishes.
+    poll_pending: Cell<bool>,
+    poll_seq: Cell<u8>,
+    poll_buf: TakeCell<'static, [u8]>,
+
+    tx_payload: TakeCell<'static, [u8]>,
+    tx_len: Cell<usize>,
+}
+
+impl<'a, R: radio::Radio, A: Alarm<'a>> SleepyMac<'a, R, A> {
+    pub fn new(radio: &'a R, alarm: &'a A) -> SleepyMac<'a, R, A> {
+        SleepyMac {
+            radio: radio,
+            alarm: alarm,
+            tx_client: OptionalCell::empty(),
+            rx_client: OptionalCell::empty(),
+            state: Cell::new(State::Sleep),
+            parent: Cell::new(None),
+            poll_interval_ms: Cell::new(DEFAULT_POLL_INTERVAL_MS),
+            polling: Cell::new(false),
+            poll_pending: Cell::new(false),
+            poll_seq: Cell::new(0),
+            poll_buf: TakeCell::empty(),
+            tx_payload: TakeCell::empty(),
+            tx_len: Cell::new(0),
+        }
+    }
+
+    /// Set the device that queues frames for this one.
+    pub fn set_parent(&self, parent: MacAddress) {
+        self.parent.set(Some(parent));
+    }
+
+    /// Set how often to poll the parent. Takes effect from the next poll.
+    pub fn set_poll_interval_ms(&self, ms: u32) {
+        self.poll_interval_ms.set(ms);
+    }
+
+    pub fn get_poll_interval_ms(&self) -> u32 {
+        self.poll_interval_ms.get()
+    }
+
+    /// Start polling the parent every poll interval. The first poll is sent
+    /// right away.
+    pub fn start_polling(&self) -> ReturnCode {
+        self.polling.set(true);
+        let rcode = self.poll_now();
+        if rcode != ReturnCode::SUCCESS {
+            self.polling.set(false);
+        }
+        rcode
+    }
+
+    /// Stop polling. The radio is turned off once any exchange in progress
+    /// finishes.
+    pub fn stop_polling(&self) {
+        self.polling.set(false);
+        self.poll_pending.set(false);
+        if self.state.get() == State::Sleep {
+            self.alarm.disarm();
+        }
+    }
+
+    /// Poll the parent now rather than waiting for the poll interval, for
+    /// example after sending a request that the parent will answer. Returns
+    /// `EOFF` if no parent is set or `initialize()` has not been called.
+    pub fn poll_now(&self) -> ReturnCode {
+        if self.parent.get().is_none()
+            || (self.poll_buf.is_none() && self.state.get() != State::Polling)
+        {
+            return ReturnCode::EOFF;
+        }
+        self.poll_pending.set(true);
+        if self.state.get() == State::Sleep {
+            self.wake();
+        }
+        ReturnCode::SUCCESS
+    }
+
+    fn wake(&self) {
+        self.state.set(State::Startup);
+        if self.radio.is_on() {
+            self.radio_ready();
+        } else {
+            self.radio.start();
+        }
+    }
+
+    /// The radio is on: send the client's frame first, then any poll.
+    fn radio_ready(&self) {
+        if self.tx_payload.is_some() {
+            self.transmit_payload();
+        } else if self.poll_pending.get() {
+            self.send_data_request();
+        } else {
+            self.sleep();
+        }
+    }
+
+    /// Finish the current exchange: start the next one if there is one, and
+    /// otherwise turn the radio off until the next poll.
+    fn sleep(&self) {
+        if self.tx_payload.is_some() || self.poll_pending.get() {
+            self.radio_ready();
+            return;
+        }
+        self.radio.stop();
+        self.state.set(State::Sleep);
+        if self.polling.get() {
+            self.alarm.set_alarm(
+                self.alarm.now(),
+                A::ticks_from_ms(self.poll_interval_ms.get()),
+            );
+        } else {
+            self.alarm.disarm();
+        }
+    }
+
+    fn transmit_payload(&self) {
+        self.tx_payload.take().map(|buf| {
+            self.state.set(State::Transmitting);
+            let (rcode, buf) = self.radio.transmit(buf, self.tx_len.get());
+            if rcode != ReturnCode::SUCCESS {
+                buf.map(|buf| self.call_tx_client(buf, false, rcode));
+            }
+        });
+    }
+
+    fn call_tx_client(&self, buf: &'static mut [u8], acked: bool, result: ReturnCode) {
+        self.sleep();
+        self.tx_client.map(move |c| {
+            c.send_done(buf, acked, result);
+        });
+    }
+
+    fn send_data_request(&self) {
+        self.poll_pending.set(false);
+        let buf = match self.poll_buf.take() {
+            Some(buf) => buf,
+            None => {
+                self.sleep();
+                return;
+            }
+        };
+        let pan = self.radio.get_pan();
+        let header = Header {
+            frame_type: FrameType::MACCommand,
+            frame_pending: false,
+            ack_requested: true,
+            version: FrameVersion::V2006,
+            seq: Some(self.poll_seq.get()),
+            dst_pan: Some(pan),
+            dst_addr: self.parent.get(),
+            src_pan: Some(pan),
+            src_addr: Some(MacAddress::Long(self.radio.get_address_long())),
+            security: None,
+            header_ies: Default::default(),
+            header_ies_len: 0,
+            payload_ies: Default::default(),
+            payload_ies_len: 0,
+        };
+        self.poll_seq.set(self.poll_seq.get().wrapping_add(1));
+
+        match header.encode(&mut buf[radio::PSDU_OFFSET..], true).done() {
+            Some((data_offset, _)) => {
+                buf[radio::PSDU_OFFSET + data_offset] = DATA_REQUEST_COMMAND;
+                self.state.set(State::Polling);
+                let (rcode, buf) = self.radio.transmit(buf, data_offset + 1);
+                if rcode != ReturnCode::SUCCESS {
+                    buf.map(|buf| self.poll_buf.replace(buf));
+                    self.sleep();
+                }
+            }
+            None => {
+                self.poll_buf.replace(buf);
+                self.sleep();
+            }
+        }
+    }
+}
+
+impl<'a, R: radio::Radio, A: Alarm<'a>> Mac for SleepyMac<'a, R, A> {
+    fn initialize(&self, mac_buf: &'static mut [u8]) -> ReturnCode {
+        self.poll_buf.replace(mac_buf);
+        ReturnCode::SUCCESS
+    }
+
+    // The radio is woken whenever there is something to send, so the layer
+    // is always able to send frames even while the radio sleeps.
+    fn is_on(&self) -> bool {
+        true
+    }
+
+    fn set_config_client(&self, client: &'static dyn radio::ConfigClient) {
+        self.radio.set_config_client(client)
+    }
+
+    fn set_address(&self, addr: u16) {
+        self.radio.set_address(addr)
+    }
+
+    fn set_address_long(&self, addr: [u8; 8]) {
+        self.radio.set_address_long(addr)
+    }
+
+    fn set_pan(&self, id: u16) {
+        self.radio.set_pan(id)
+    }
+
+    fn get_address(&self) -> u16 {
+        self.radio.get_address()
+    }
+
+    fn get_address_long(&self) -> [u8; 8] {
+        self.radio.get_address_long()
+    }
+
+    fn get_pan(&self) -> u16 {
+        self.radio.get_pan()
+    }
+
+    fn config_commit(&self) {
+        self.radio.config_commit()
+    }
+
+    fn set_transmit_client(&self, client: &'static dyn radio::TxClient) {
+        self.tx_client.set(client);
+    }
+
+    fn set_receive_client(&self, client: &'static dyn radio::RxClient) {
+        self.rx_client.set(client);
+    }
+
+    fn set_receive_buffer(&self, buffer: &'static mut [u8]) {
+        self.radio.set_receive_buffer(buffer);
+    }
+
+    fn transmit(
+        &self,
+        full_mac_frame: &'static mut [u8],
+        frame_len: usize,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        if self.tx_payload.is_some() || self.state.get() == State::Transmitting {
+            return (ReturnCode::EBUSY, Some(full_mac_frame));
+        } else if radio::PSDU_OFFSET + frame_len >= full_mac_frame.len() {
+            return (ReturnCode::ESIZE, Some(full_mac_frame));
+        }
+
+        self.tx_payload.replace(full_mac_frame);
+        self.tx_len.set(frame_len);
+        // Otherwise the frame is sent once the radio is on, the poll
+        // completes, or the receive window closes, as the parent may still
+        // send the frame we polled for.
+        if self.state.get() == State::Sleep {
+            self.wake();
+        }
+        (ReturnCode::SUCCESS, None)
+    }
+}
+
+impl<'a, R: radio::Radio, A: Alarm<'a>> time::AlarmClient for SleepyMac<'a, R, A> {
+    fn alarm(&self) {
+        match self.state.get() {
+            State::Sleep => {
+                if self.polling.get() {
+                    self.poll_now();
+                }
+            }
+            // No frame arrived in the receive window.
+            State::Listening => self.sleep(),
+            _ => {}
+        }
+    }
+}
+
+impl<'a, R: radio::Radio, A: Alarm<'a>> radio::PowerClient for SleepyMac<'a, R, A> {
+    fn changed(&self, on: bool) {
+        if on && self.state.get() == State::Startup {
+            self.radio_ready();
+        }
+    }
+}
+
+impl<'a, R: radio::Radio, A: Alarm<'a>> radio::TxClient for SleepyMac<'a, R, A> {
+    fn send_done(&self, buf: &'static mut [u8], acked: bool, result: ReturnCode) {
+        match self.state.get() {
+            State::Transmitting => self.call_tx_client(buf, acked, result),
+            State::Polling => {
+                self.poll_buf.replace(buf);
+                if acked && result == ReturnCode::SUCCESS {
+                    self.state.set(State::Listening);
+                    self.alarm
+                        .set_alarm(self.alarm.now(), A::ticks_from_ms(POLL_RX_WINDOW_MS));
+                } else {
+                    self.sleep();
+                }
+            }
+            _ => {
+                self.poll_buf.replace(buf);
+            }
+        }
+    }
+}
+
+impl<'a, R: radio::Radio, A: Alarm<'a>> radio::RxClient for SleepyMac<'a, R, A> {
+    fn receive(
+        &self,
+        buf: &'static mut [u8],
+        frame_len: usize,
+        crc_valid: bool,
+        timestamp: Option<u32>,
+        result: ReturnCode,
+    ) {
+        // The radio passes up frames with a bad CRC, whose header cannot be
+        // trusted.
+        if !crc_valid {
+            self.radio.set_receive_buffer(buf);
+            return;
+        }
+
+        // Filter packets by destination because radio is in promiscuous mode
+        let mut addr_match = false;
+        let mut frame_pending = false;
+        if let Some((_, (header, _))) = Header::decode(&buf[radio::PSDU_OFFSET..], false).done() {
+            if let Some(dst_addr) = header.dst_addr {
+                addr_match = match dst_addr {
+                    MacAddress::Short(addr) => addr == self.radio.get_address(),
+                    MacAddress::Long(long_addr) => long_addr == self.radio.get_address_long(),
+                };
+            }
+            frame_pending = header.frame_pending;
+        }
+
+        if !addr_match {
+            self.radio.set_receive_buffer(buf);
+            return;
+        }
+
+        if self.state.get() == State::Listening {
+            // The parent holds more frames for us: ask for the next one.
+            if frame_pending && self.polling.get() {
+                self.poll_pending.set(true);
+            }
+            self.alarm.disarm();
+            self.sleep();
+        }
+
+        self.rx_client.map(move |c| {
+            c.receive(buf, frame_len, crc_valid, timestamp, result);
+        });
+    }
+}
@@ -300,13 +300,14 @@ impl<'a, R: radio::Radio, A: Alarm<'a>> XMac<'a, R, A> {
         buf: &'static mut [u8],
         len: usize,
         crc_valid: bool,
+        timestamp: Option<u32>,
         result: ReturnCode,
     ) {
         self.delay_sleep.set(true);
         self.sleep();
 
         self.rx_client.map(move |c| {
-            c.receive(buf, len, crc_valid, result);
+            c.receive(buf, len, crc_valid, timestamp, result);
         });
     }
 }
@@ -562,8 +563,16 @@ impl<'a, R: radio::Radio, A: Alarm<'a>> radio::RxClient for XMac<'a, R, A> {
         buf: &'static mut [u8],
         frame_len: usize,
         crc_valid: bool,
+        timestamp: Option<u32>,
         result: ReturnCode,
     ) {
+        // The radio passes up frames with a bad CRC, whose header cannot be
+        // trusted to back off or wake up for.
+        if !crc_valid {
+            self.radio.set_receive_buffer(buf);
+            return;
+        }
+
         let mut data_received: bool = false;
         let mut continue_sleep: bool = true;
 
@@ -622,7 +631,7 @@ impl<'a, R: radio::Radio, A: Alarm<'a>> radio::RxClient for XMac<'a, R, A> {
 
         if data_received {
             self.rx_pending.set(false);
-            self.call_rx_client(buf, frame_len, crc_valid, result);
+            self.call_rx_client(buf, frame_len, crc_valid, timestamp, result);
         } else {
             self.radio.set_receive_buffer(buf);
         }
@@ -0,0 +1,270 @@
+//! Random delays and operation ordering for side-channel hardening.
+//!
+//! Simple timing and power analysis relies on an operation always taking the
+//! same time and touching data in the same order. `Jitter` lets crypto
+//! capsules break that up: `delay()` busy-waits for a random, bounded number
+//! of iterations, and `shuffle()` randomly permutes the order in which the
+//! caller processes independent items (for example the bytes of an AES state
+//! or the words of a key being compared).
+//!
+//! Both calls are synchronous so they can be used in the middle of an
+//! operation. They draw from a pool of `POOL_SIZE` random words that is
+//! refilled in the background from a CSPRNG, and reject words that would bias
+//! the result. If the pool runs dry, `delay()` waits for the maximum time and
+//! `shuffle()` leaves the order unchanged and returns `EBUSY`, so callers
+//! never quietly get predictable jitter. A full pool shuffles at most
+//! `MAX_SHUFFLE_LEN` items.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//! # use capsules::jitter::Jitter;
+//!
+//! let jitter_rng = static_init!(
+//!     capsules::entropy_pool::PoolRng<'static>,
+//!     capsules::entropy_pool::PoolRng::new(entropy_pool)
+//! );
+//! let jitter = static_init!(
+//!     Jitter<'static, capsules::entropy_pool::PoolRng<'static>>,
+//!     Jitter::new(jitter_rng, 256)
+//! );
+//! kernel::hil::rng::Rng::set_client(jitter_rng, jitter);
+//! jitter.start();
+//! mux_ccm.set_jitter(jitter);
+//! ```
+
+use core::cell::Cell;
+use core::sync::atomic::{self, Ordering};
+use kernel::hil::rng::{self, Continue, Rng};
+use kernel::ReturnCode;
+
+/// Number of random words kept in the pool.
+pub const POOL_SIZE: usize = 32;
+
+/// The longest slice `shuffle()` accepts, which takes `POOL_SIZE` words.
+pub const MAX_SHUFFLE_LEN: usize = POOL_SIZE + 1;
+
+/// Interface used by capsules that want to randomize their timing.
+pub trait RandomJitter {
+    /// Busy-wait for a random number of iterations, up to the maximum the
+    /// service was configured with.
+    fn delay(&self);
+
+    /// Randomly permute `order` in place. Returns `ESIZE` if `order` is
+    /// longer than `MAX_SHUFFLE_LEN`, and `EBUSY` if there is not enough
+    /// randomness available right now. `order` is unchanged on error.
+    fn shuffle(&self, order: &mut [usize]) -> ReturnCode;
+}
+
+pub struct Jitter<'a, R: Rng<'a>> {
+    rng: &'a R,
+    pool: Cell<[u32; POOL_SIZE]>,
+    /// Number of unused words at the start of `pool`.
+    available: Cell<usize>,
+    /// Upper bound on the iterations of a single `delay()`.
+    max_iterations: u32,
+    refilling: Cell<bool>,
+}
+
+impl<'a, R: Rng<'a>> Jitter<'a, R> {
+    pub fn new(rng: &'a R, max_iterations: u32) -> Jitter<'a, R> {
+        Jitter {
+            rng: rng,
+            pool: Cell::new([0; POOL_SIZE]),
+            available: Cell::new(0),
+            max_iterations: max_iterations,
+            refilling: Cell::new(false),
+        }
+    }
+
+    /// Fill the pool for the first time. Must be called once during board
+    /// setup.
+    pub fn start(&self) -> ReturnCode {
+        self.refill()
+    }
+
+    fn refill(&self) -> ReturnCode {
+        if self.refilling.get() {
+            return ReturnCode::SUCCESS;
+        }
+        let rcode = self.rng.get();
+        if rcode == ReturnCode::SUCCESS {
+            self.refilling.set(true);
+        }
+        rcode
+    }
+
+    fn take_word(&self) -> Option<u32> {
+        let available = self.available.get();
+        if available < POOL_SIZE / 2 {
+            self.refill();
+        }
+        if available == 0 {
+            return None;
+        }
+        let mut pool = self.pool.get();
+        let word = pool[available - 1];
+        // Do not let the same word be used twice.
+        pool[available - 1] = 0;
+        self.pool.set(pool);
+        self.available.set(available - 1);
+        Some(word)
+    }
+
+    /// A uniformly distributed number below `bound`, which must not be zero.
+    /// Words below `2^32 mod bound` are rejected, so that the words accepted
+    /// are a whole number of multiples of `bound`.
+    fn take_below(&self, bound: u32) -> Option<u32> {
+        let threshold = 0u32.wrapping_sub(bound) % bound;
+        loop {
+            let word = self.take_word()?;
+            if word >= threshold {
+                return Some(word % bound);
+            }
+        }
+    }
+}
+
+impl<'a, R: Rng<'a>> RandomJitter for Jitter<'a, R> {
+    fn delay(&self) {
+        let iterations = match self.max_iterations.checked_add(1) {
+            Some(bound) => self.take_below(bound),
+            None => self.take_word(),
+        }
+        .unwrap_or(self.max_iterations);
+        for _ in 0..iterations {
+            // Keep the compiler from removing the loop.
+            atomic::spin_loop_hint();
+            atomic::compiler_fence(Ordering::SeqCst);
+        }
+    }
+
+    fn shuffle(&self, order: &mut [usize]) -> ReturnCode {
+        if order.len() > MAX_SHUFFLE_LEN {
+            return ReturnCode::ESIZE;
+        }
+        if order.len() < 2 {
+            return ReturnCode::SUCCESS;
+        }
+        if self.available.get() < order.len() - 1 {
+            self.refill();
+            return ReturnCode::EBUSY;
+        }
+        // Fisher-Yates, remembering the swaps in case rejected words empty
+        // the pool before the end.
+        let mut swaps = [0; MAX_SHUFFLE_LEN];
+        for i in (1..order.len()).rev() {
+            match self.take_below(i as u32 + 1) {
+                Some(j) => {
+                    order.swap(i, j as usize);
+                    swaps[i] = j as usize;
+                }
+                None => {
+                    for k in i + 1..order.len() {
+                        order.swap(k, swaps[k]);
+                    }
+                    return ReturnCode::EBUSY;
+                }
+            }
+        }
+        ReturnCode::SUCCESS
+    }
+}
+
+impl<'a, R: Rng<'a>> rng::Client for Jitter<'a, R> {
+    fn randomness_available(
+        &self,
+        randomness: &mut dyn Iterator<Item = u32>,
+        error: ReturnCode,
+    ) -> Continue {
+        if error != ReturnCode::SUCCESS {
+            self.refilling.set(false);
+            return Continue::Done;
+        }
+        let mut pool = self.pool.get();
+        let mut available = self.available.get();
+        while available < POOL_SIZE {
+            match randomness.next() {
+                Some(word) => {
+                    pool[available] = word;
+                    available += 1;
+                }
+                None => break,
+            }
+        }
+        self.pool.set(pool);
+        self.available.set(available);
+
+        if available < POOL_SIZE {
+            Continue::More
+        } else {
+            self.refilling.set(false);
+            Continue::Done
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+    use super::*;
+    use kernel::hil::rng::Client;
+    use std::vec::Vec;
+
+    struct TestRng;
+
+    impl<'a> Rng<'a> for TestRng {
+        fn get(&self) -> ReturnCode {
+            ReturnCode::SUCCESS
+        }
+
+        fn cancel(&self) -> ReturnCode {
+            ReturnCode::SUCCESS
+        }
+
+        fn set_client(&'a self, _client: &'a dyn rng::Client) {}
+    }
+
+    fn fill(jitter: &Jitter<TestRng>, words: &[u32]) {
+        jitter.randomness_available(&mut words.iter().cloned(), ReturnCode::SUCCESS);
+    }
+
+    #[test]
+    fn rejects_biased_words() {
+        let jitter = Jitter::new(&TestRng, 10);
+        // 2^32 mod 3 is 1, so 0 is rejected.
+        fill(&jitter, &[5, 0]);
+        assert_eq!(jitter.take_below(3), Some(2));
+        assert_eq!(jitter.take_below(3), None);
+    }
+
+    #[test]
+    fn shuffles_up_to_a_full_pool() {
+        let jitter = Jitter::new(&TestRng, 10);
+        let words: Vec<u32> = (0..POOL_SIZE as u32)
+            .map(|i| i.wrapping_mul(0x9e37_79b9))
+            .collect();
+        fill(&jitter, &words);
+        let mut order: Vec<usize> = (0..MAX_SHUFFLE_LEN + 1).collect();
+        assert_eq!(jitter.shuffle(&mut order), ReturnCode::ESIZE);
+
+        order.pop();
+        assert_eq!(jitter.shuffle(&mut order), ReturnCode::SUCCESS);
+        let mut sorted = order.clone();
+        sorted.sort();
+        assert_eq!(sorted, (0..MAX_SHUFFLE_LEN).collect::<Vec<usize>>());
+        assert_ne!(sorted, order);
+    }
+
+    #[test]
+    fn restores_the_order_when_the_pool_runs_dry() {
+        let jitter = Jitter::new(&TestRng, 10);
+        // Three words for three swaps, but the second swap rejects the rest.
+        fill(&jitter, &[0, 0, 9]);
+        let mut order = [0, 1, 2, 3];
+        assert_eq!(jitter.shuffle(&mut order), ReturnCode::EBUSY);
+        assert_eq!(order, [0, 1, 2, 3]);
+    }
+}
@@ -0,0 +1,537 @@
+//! Implements a persistent key-value store in flash.
+//!
+//! The store is a log of entries in a storage volume. Setting a key appends
+//! an entry with its new value, and deleting a key appends an entry that
+//! marks it deleted, so the newest entry of a key holds its value. Each page
+//! of the volume starts with a header holding its sequence number, which
+//! orders the pages of the log. Entries are appended to the newest page
+//! until it is full, and then to the next page of the volume, so the pages
+//! are written in turn and wear evenly. One page is kept free: when only one
+//! is left, the entries of the oldest page that are still the newest of
+//! their key are copied to the free page, and the oldest page is erased to
+//! become the free page.
+//!
+//! An entry is a header of `ENTRY_HEADER_SIZE` bytes, with the length of the
+//! key, flags, the length of the value and the namespace, followed by the
+//! key and the value. An entry cannot be split between pages, so a value can
+//! take up at most a page, less the page and entry headers and the key.
+//!
+//! Each write rewrites the newest page, so that the store never needs to be
+//! synced. Reads are made directly from the storage volume, which must be
+//! memory-mapped and aligned to pages, so they complete at once. The store
+//! needs at least two pages.
+//!
+//! Note that while the store persists across reboots, it is erased upon
+//! flashing a new kernel.
+//!
+//! Usage
+//! -----
+//!
+//! ```
+//!     storage_volume!(KV_VOLUME, 4);
+//!     static mut PAGEBUFFER: sam4l::flashcalw::Sam4lPage = sam4l::flashcalw::Sam4lPage::new();
+//!
+//!     let kv_store = static_init!(
+//!         capsules::kv_store::KVStore<'static, sam4l::flashcalw::FLASHCALW>,
+//!         capsules::kv_store::KVStore::new(
+//!             &KV_VOLUME,
+//!             &mut sam4l::flashcalw::FLASH_CONTROLLER,
+//!             &mut PAGEBUFFER,
+//!         )
+//!     );
+//!     kernel::hil::flash::HasClient::set_client(&sam4l::flashcalw::FLASH_CONTROLLER, kv_store);
+//!     kv_store.set_client(kv_store_client);
+//! ```
+
+use core::cell::Cell;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::flash::{self, Flash};
+use kernel::hil::kv_store::{KVStoreClient, MAX_KEY_LEN};
+use kernel::ReturnCode;
+
+/// Size of the header of a page: its sequence number and the complement of
+/// it, which tells a valid header apart from erased flash.
+pub const PAGE_HEADER_SIZE: usize = 8;
+/// Size of the header of an entry.
+pub const ENTRY_HEADER_SIZE: usize = 8;
+
+/// Flag of an entry that marks its key deleted.
+const FLAG_DELETED: u8 = 0x01;
+
+/// Store state keeps track of any in-progress asynchronous operations.
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    Idle,
+    /// Writing the newest page with the new entry.
+    Write,
+    /// Writing the entries of the oldest page to the free page.
+    Compact,
+    /// Erasing the oldest page after compacting it.
+    Erase,
+}
+
+/// An entry in the storage volume.
+#[derive(Clone, Copy)]
+struct Entry {
+    /// Position of the header of the entry in the volume.
+    pos: usize,
+    namespace: u32,
+    key_len: usize,
+    value_len: usize,
+    deleted: bool,
+}
+
+impl Entry {
+    fn len(&self) -> usize {
+        ENTRY_HEADER_SIZE + self.key_len + self.value_len
+    }
+
+    fn key<'b>(&self, volume: &'b [u8]) -> &'b [u8] {
+        let start = self.pos + ENTRY_HEADER_SIZE;
+        &volume[start..start + self.key_len]
+    }
+
+    fn value<'b>(&self, volume: &'b [u8]) -> &'b [u8] {
+        let start = self.pos + ENTRY_HEADER_SIZE + self.key_len;
+        &volume[start..start + self.value_len]
+    }
+}
+
+pub struct KVStore<'a, F: Flash + 'static> {
+    /// Underlying storage volume.
+    volume: &'static [u8],
+    /// Flash interface.
+    driver: &'a F,
+    /// Buffer for a flash page, holding the newest page when idle.
+    pagebuffer: TakeCell<'static, F::Page>,
+    /// Size of a flash page.
+    page_size: usize,
+    /// Number of pages in the volume.
+    num_pages: usize,
+    client: OptionalCell<&'a dyn KVStoreClient>,
+
+    /// Current operation being executed, if asynchronous.
+    state: Cell<State>,
+    /// Index of the oldest page in use.
+    tail: Cell<usize>,
+    /// Index of the newest page, to which entries are appended.
+    head: Cell<usize>,
+    /// Sequence number of the newest page.
+    head_seq: Cell<u32>,
+    /// Bytes used in the newest page, including its header.
+    head_len: Cell<usize>,
+
+    // Note: for saving state across stack ripping.
+    /// Namespace and key of the entry to append.
+    namespace: Cell<u32>,
+    key: Cell<[u8; MAX_KEY_LEN]>,
+    key_len: Cell<usize>,
+    /// Client-provided value to append, unless deleting.
+    value: TakeCell<'static, [u8]>,
+    value_len: Cell<usize>,
+    deleting: Cell<bool>,
+    /// Pages compacted for the append so far.
+    compactions: Cell<usize>,
+}
+
+impl<'a, F: Flash + 'static> KVStore<'a, F> {
+    pub fn new(
+        volume: &'static [u8],
+        driver: &'a F,
+        pagebuffer: &'static mut F::Page,
+    ) -> KVStore<'a, F> {
+        let page_size = pagebuffer.as_mut().len();
+        let store = KVStore {
+            volume: volume,
+            driver: driver,
+            pagebuffer: TakeCell::new(pagebuffer),
+            page_size: page_size,
+            num_pages: volume.len() / page_size,
+            client: OptionalCell::empty(),
+            state: Cell::new(State::Idle),
+            tail: Cell::new(0),
+            head: Cell::new(0),
+            head_seq: Cell::new(0),
+            head_len: Cell::new(PAGE_HEADER_SIZE),
+            namespace: Cell::new(0),
+            key: Cell::new([0; MAX_KEY_LEN]),
+            key_len: Cell::new(0),
+            value: TakeCell::empty(),
+            value_len: Cell::new(0),
+            deleting: Cell::new(false),
+            compactions: Cell::new(0),
+        };
+        store.reconstruct();
+        store
+    }
+
+    /// Returns the sequence number of page `page`, if its header is valid.
+    fn page_seq(&self, page: usize) -> Option<u32> {
+        let pos = page * self.page_size;
+        let seq = read_u32(&self.volume[pos..]);
+        let check = read_u32(&self.volume[pos + 4..]);
+        if seq == !check {
+            Some(seq)
+        } else {
+            None
+        }
+    }
+
+    /// Calls `f` with each entry of page `page`, and returns the length of the
+    /// page used. The entries of a page end at a key length of 0 or above
+    /// `MAX_KEY_LEN`, such as that of erased flash, or at an entry that does
+    /// not fit.
+    fn page_entries<G: FnMut(Entry)>(&self, page: usize, mut f: G) -> usize {
+        let buffer = self.volume;
+        let start = page * self.page_size;
+        let end = start + self.page_size;
+        let mut pos = start + PAGE_HEADER_SIZE;
+        while pos + ENTRY_HEADER_SIZE <= end {
+            let key_len = buffer[pos] as usize;
+            if key_len == 0 || key_len > MAX_KEY_LEN {
+                break;
+            }
+            let entry = Entry {
+                pos: pos,
+                namespace: read_u32(&buffer[pos + 4..]),
+                key_len: key_len,
+                value_len: u16::from_le_bytes([buffer[pos + 2], buffer[pos + 3]]) as usize,
+                deleted: buffer[pos + 1] & FLAG_DELETED != 0,
+            };
+            if pos + entry.len() > end {
+                break;
+            }
+            f(entry);
+            pos += entry.len();
+        }
+        pos - start
+    }
+
+    /// Calls `f` with each entry in the volume, oldest first.
+    fn each_entry<G: FnMut(Entry)>(&self, mut f: G) {
+        let mut page = self.tail.get();
+        loop {
+            if self.page_seq(page).is_some() {
+                self.page_entries(page, &mut f);
+            }
+            if page == self.head.get() {
+                break;
+            }
+            page = (page + 1) % self.num_pages;
+        }
+    }
+
+    /// Returns the newest entry of `key` in `namespace`.
+    fn newest(&self, namespace: u32, key: &[u8]) -> Option<Entry> {
+        let mut newest = None;
+        self.each_entry(|entry| {
+            if entry.namespace == namespace && entry.key(self.volume) == key {
+                newest = Some(entry);
+            }
+        });
+        newest
+    }
+
+    /// Rebuilds the state of the store from the volume.
+    fn reconstruct(&self) {
+        let mut oldest: Option<(usize, u32)> = None;
+        let mut newest: Option<(usize, u32)> = None;
+        for page in 0..self.num_pages {
+            if let Some(seq) = self.page_seq(page) {
+                if oldest.map_or(true, |(_, oldest_seq)| seq < oldest_seq) {
+                    oldest = Some((page, seq));
+                }
+                if newest.map_or(true, |(_, newest_seq)| seq > newest_seq) {
+                    newest = Some((page, seq));
+                }
+            }
+        }
+        self.pagebuffer.map(|pagebuffer| {
+            let pagebuffer = pagebuffer.as_mut();
+            match (oldest, newest) {
+                (Some((tail, _)), Some((head, head_seq))) => {
+                    let start = head * self.page_size;
+                    let head_len = self.page_entries(head, |_| {});
+                    pagebuffer[..head_len].copy_from_slice(&self.volume[start..start + head_len]);
+                    for byte in pagebuffer[head_len..].iter_mut() {
+                        *byte = 0;
+                    }
+                    self.tail.set(tail);
+                    self.head.set(head);
+                    self.head_seq.set(head_seq);
+                    self.head_len.set(head_len);
+                }
+                _ => {
+                    // No valid pages, start an empty store at the first page.
+                    self.start_page(pagebuffer, 1);
+                    self.tail.set(0);
+                    self.head.set(0);
+                }
+            }
+        });
+    }
+
+    /// Clears `pagebuffer` for a page with sequence number `seq`, and makes
+    /// it the newest page.
+    fn start_page(&self, pagebuffer: &mut [u8], seq: u32) {
+        for byte in pagebuffer.iter_mut() {
+            *byte = 0;
+        }
+        pagebuffer[0..4].copy_from_slice(&seq.to_le_bytes());
+        pagebuffer[4..8].copy_from_slice(&(!seq).to_le_bytes());
+        self.head_seq.set(seq);
+        self.head_len.set(PAGE_HEADER_SIZE);
+    }
+
+    fn free_pages(&self) -> usize {
+        let used = (self.head.get() + self.num_pages - self.tail.get()) % self.num_pages + 1;
+        self.num_pages - used
+    }
+
+    fn page_number(&self, page: usize) -> usize {
+        self.volume.as_ptr() as usize / self.page_size + page
+    }
+
+    fn write_page(&self, page: usize, pagebuffer: &'static mut F::Page) -> ReturnCode {
+        match self.driver.write_page(self.page_number(page), pagebuffer) {
+            Ok(()) => ReturnCode::SUCCESS,
+            Err((rcode, pagebuffer)) => {
+                self.pagebuffer.replace(pagebuffer);
+                rcode
+            }
+        }
+    }
+
+    /// Appends the pending entry to the newest page, moving to the next page
+    /// or compacting the oldest page first if the entry does not fit.
+    fn advance(&self) -> ReturnCode {
+        let value_len = if self.deleting.get() {
+            0
+        } else {
+            self.value_len.get()
+        };
+        let entry_len = ENTRY_HEADER_SIZE + self.key_len.get() + value_len;
+        loop {
+            let pagebuffer = match self.pagebuffer.take() {
+                Some(pagebuffer) => pagebuffer,
+                None => return ReturnCode::ERESERVE,
+            };
+            let head_len = self.head_len.get();
+            if head_len + entry_len <= self.page_size {
+                self.write_entry(&mut pagebuffer.as_mut()[head_len..head_len + entry_len]);
+                self.head_len.set(head_len + entry_len);
+                self.state.set(State::Write);
+                return self.write_page(self.head.get(), pagebuffer);
+            } else if self.free_pages() >= 2 {
+                self.start_page(pagebuffer.as_mut(), self.head_seq.get().wrapping_add(1));
+                self.head.set((self.head.get() + 1) % self.num_pages);
+                self.pagebuffer.replace(pagebuffer);
+            } else if self.compactions.get() >= self.num_pages {
+                // Every page was compacted, so the store is full.
+                self.pagebuffer.replace(pagebuffer);
+                return ReturnCode::ENOMEM;
+            } else {
+                self.compactions.set(self.compactions.get() + 1);
+                self.compact(pagebuffer.as_mut());
+                self.head.set((self.head.get() + 1) % self.num_pages);
+                self.state.set(State::Compact);
+                return self.write_page(self.head.get(), pagebuffer);
+            }
+        }
+    }
+
+    /// Writes the pending entry into `buffer`.
+    fn write_entry(&self, buffer: &mut [u8]) {
+        let key_len = self.key_len.get();
+        buffer[0] = key_len as u8;
+        buffer[4..8].copy_from_slice(&self.namespace.get().to_le_bytes());
+        buffer[ENTRY_HEADER_SIZE..ENTRY_HEADER_SIZE + key_len]
+            .copy_from_slice(&self.key.get()[..key_len]);
+        if self.deleting.get() {
+            buffer[1] = FLAG_DELETED;
+            buffer[2..4].copy_from_slice(&0u16.to_le_bytes());
+        } else {
+            let value_len = self.value_len.get();
+            buffer[1] = 0;
+            buffer[2..4].copy_from_slice(&(value_len as u16).to_le_bytes());
+            self.value.map(|value| {
+                buffer[ENTRY_HEADER_SIZE + key_len..].copy_from_slice(&value[..value_len]);
+            });
+        }
+    }
+
+    /// Starts a new page in `pagebuffer` with the entries of the oldest page
+    /// that are the newest of their key, leaving out deleted keys.
+    fn compact(&self, pagebuffer: &mut [u8]) {
+        self.start_page(pagebuffer, self.head_seq.get().wrapping_add(1));
+        let mut len = PAGE_HEADER_SIZE;
+        if self.page_seq(self.tail.get()).is_some() {
+            self.page_entries(self.tail.get(), |entry| {
+                let newest = self.newest(entry.namespace, entry.key(self.volume));
+                if !entry.deleted && newest.map_or(false, |newest| newest.pos == entry.pos) {
+                    pagebuffer[len..len + entry.len()]
+                        .copy_from_slice(&self.volume[entry.pos..entry.pos + entry.len()]);
+                    len += entry.len();
+                }
+            });
+        }
+        self.head_len.set(len);
+    }
+
+    /// Checks the arguments of `set` and `delete`, and saves them for the
+    /// append.
+    fn start(&self, namespace: u32, key: &[u8], value_len: usize) -> ReturnCode {
+        if self.state.get() != State::Idle {
+            return ReturnCode::EBUSY;
+        }
+        if key.is_empty()
+            || key.len() > MAX_KEY_LEN
+            || value_len > u16::max_value() as usize
+            || PAGE_HEADER_SIZE + ENTRY_HEADER_SIZE + key.len() + value_len > self.page_size
+        {
+            return ReturnCode::ESIZE;
+        }
+        let mut saved_key = [0; MAX_KEY_LEN];
+        saved_key[..key.len()].copy_from_slice(key);
+        self.namespace.set(namespace);
+        self.key.set(saved_key);
+        self.key_len.set(key.len());
+        self.value_len.set(value_len);
+        self.compactions.set(0);
+        ReturnCode::SUCCESS
+    }
+
+    /// Ends the pending operation and calls back the client.
+    fn finish(&self, result: ReturnCode) {
+        self.state.set(State::Idle);
+        if self.deleting.get() {
+            self.client.map(|client| client.delete_done(result));
+        } else {
+            self.value.take().map(|value| {
+                self.client
+                    .map(move |client| client.set_done(value, result));
+            });
+        }
+    }
+
+    /// Ends the pending operation with `result` after a failed flash
+    /// operation, restoring the state of the store from the volume.
+    fn fail(&self, result: ReturnCode) {
+        self.reconstruct();
+        self.finish(result);
+    }
+}
+
+fn read_u32(buffer: &[u8]) -> u32 {
+    u32::from_le_bytes([buffer[0], buffer[1], buffer[2], buffer[3]])
+}
+
+impl<'a, F: Flash + 'static> kernel::hil::kv_store::KVStore<'a> for KVStore<'a, F> {
+    fn set_client(&'a self, client: &'a dyn KVStoreClient) {
+        self.client.set(client);
+    }
+
+    fn get(&self, namespace: u32, key: &[u8], value: &mut [u8]) -> Result<usize, ReturnCode> {
+        if self.state.get() != State::Idle {
+            return Err(ReturnCode::EBUSY);
+        }
+        if key.len() > MAX_KEY_LEN {
+            return Err(ReturnCode::ESIZE);
+        }
+        match self.newest(namespace, key) {
+            Some(entry) if !entry.deleted => {
+                let stored = entry.value(self.volume);
+                let len = stored.len().min(value.len());
+                value[..len].copy_from_slice(&stored[..len]);
+                Ok(stored.len())
+            }
+            _ => Err(ReturnCode::ENOSUPPORT),
+        }
+    }
+
+    fn set(
+        &'a self,
+        namespace: u32,
+        key: &[u8],
+        value: &'static mut [u8],
+        length: usize,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        if length > value.len() {
+            return Err((ReturnCode::EINVAL, value));
+        }
+        let rcode = self.start(namespace, key, length);
+        if rcode != ReturnCode::SUCCESS {
+            return Err((rcode, value));
+        }
+        self.deleting.set(false);
+        self.value.replace(value);
+        match self.advance() {
+            ReturnCode::SUCCESS => Ok(()),
+            rcode => {
+                self.reconstruct();
+                self.state.set(State::Idle);
+                Err((rcode, self.value.take().unwrap()))
+            }
+        }
+    }
+
+    fn delete(&'a self, namespace: u32, key: &[u8]) -> ReturnCode {
+        let rcode = self.start(namespace, key, 0);
+        if rcode != ReturnCode::SUCCESS {
+            return rcode;
+        }
+        if self
+            .newest(namespace, key)
+            .map_or(true, |entry| entry.deleted)
+        {
+            return ReturnCode::ENOSUPPORT;
+        }
+        self.deleting.set(true);
+        let rcode = self.advance();
+        if rcode != ReturnCode::SUCCESS {
+            self.reconstruct();
+            self.state.set(State::Idle);
+        }
+        rcode
+    }
+}
+
+impl<'a, F: Flash + 'static> flash::Client<F> for KVStore<'a, F> {
+    fn read_complete(&self, _read_buffer: &'static mut F::Page, _error: flash::Error) {
+        // Reads are made directly from the storage volume, not through the flash interface.
+        unreachable!();
+    }
+
+    /// Finishes the append, or erases the oldest page after compacting it.
+    fn write_complete(&self, pagebuffer: &'static mut F::Page, error: flash::Error) {
+        self.pagebuffer.replace(pagebuffer);
+        if error == flash::Error::FlashError {
+            self.fail(ReturnCode::FAIL);
+            return;
+        }
+        match self.state.get() {
+            State::Write => self.finish(ReturnCode::SUCCESS),
+            State::Compact => {
+                self.state.set(State::Erase);
+                let rcode = self.driver.erase_page(self.page_number(self.tail.get()));
+                if rcode != ReturnCode::SUCCESS {
+                    self.fail(rcode);
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    /// Continues the append once the oldest page is erased.
+    fn erase_complete(&self, error: flash::Error) {
+        if error == flash::Error::FlashError {
+            self.fail(ReturnCode::FAIL);
+            return;
+        }
+        self.tail.set((self.tail.get() + 1) % self.num_pages);
+        let rcode = self.advance();
+        if rcode != ReturnCode::SUCCESS {
+            self.fail(rcode);
+        }
+    }
+}
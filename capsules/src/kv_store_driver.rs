@@ -0,0 +1,346 @@
+//! Provides userspace access to a key-value store.
+//!
+//! Each app has a namespace of its own in the store, derived from its name,
+//! so apps cannot read or overwrite each other's keys, and an app finds its
+//! keys again after a reboot or an update as long as its name stays the
+//! same. Apps with the same name share a namespace. The namespaces of apps
+//! all have the top bit set, so they are apart from those of the kernel.
+//!
+//! Values are copied through a kernel buffer, whose length limits the length
+//! of a value. The driver writes for one app at a time, and queues the
+//! writes of the others.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let kv_store_driver = static_init!(
+//!     capsules::kv_store_driver::KVStoreDriver<'static>,
+//!     capsules::kv_store_driver::KVStoreDriver::new(
+//!         virtual_kv_store,
+//!         board_kernel.create_grant(&grant_cap),
+//!         &mut capsules::kv_store_driver::BUFFER,
+//!     )
+//! );
+//! hil::kv_store::KVStore::set_client(virtual_kv_store, kv_store_driver);
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! ### Allow
+//!
+//! - `0`: The key.
+//! - `1`: The value, read from by `set` and written to by `get`.
+//!
+//! ### Subscribe
+//!
+//! - `0`: Called when a write completes, with the command number of the
+//!   write and its result.
+//!
+//! ### Command
+//!
+//! - `0`: Return SUCCESS if this driver is included on the platform.
+//! - `1`: Get the value of the key of length `arg1`. Returns the length of
+//!   the value, of which as much as fits is copied to the value buffer, or
+//!   `ENOSUPPORT` if there is no such key.
+//! - `2`: Set the key of length `arg1` to the first `arg2` bytes of the value
+//!   buffer.
+//! - `3`: Delete the key of length `arg1`.
+
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::kv_store::{KVStore, KVStoreClient, MAX_KEY_LEN};
+use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::KVStore as usize;
+
+pub static mut BUFFER: [u8; 256] = [0; 256];
+
+/// The bit set in the namespaces of apps.
+const APP_NAMESPACE_BIT: u32 = 0x8000_0000;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Op {
+    Set { key_len: usize, value_len: usize },
+    Delete { key_len: usize },
+}
+
+impl Op {
+    fn command_num(&self) -> usize {
+        match self {
+            Op::Set { .. } => 2,
+            Op::Delete { .. } => 3,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct App {
+    callback: Option<Callback>,
+    key: Option<AppSlice<Shared, u8>>,
+    value: Option<AppSlice<Shared, u8>>,
+    pending: Option<Op>,
+}
+
+pub struct KVStoreDriver<'a> {
+    store: &'a dyn KVStore<'a>,
+    apps: Grant<App>,
+    /// Buffer the value of a set is copied into.
+    buffer: TakeCell<'static, [u8]>,
+    /// The app whose write is in progress.
+    current_app: OptionalCell<AppId>,
+}
+
+impl<'a> KVStoreDriver<'a> {
+    pub fn new(
+        store: &'a dyn KVStore<'a>,
+        grant: Grant<App>,
+        buffer: &'static mut [u8],
+    ) -> KVStoreDriver<'a> {
+        KVStoreDriver {
+            store: store,
+            apps: grant,
+            buffer: TakeCell::new(buffer),
+            current_app: OptionalCell::empty(),
+        }
+    }
+
+    /// The namespace of `appid`, an FNV-1a hash of its name.
+    fn namespace(appid: AppId) -> u32 {
+        let hash = appid
+            .get_process_name()
+            .bytes()
+            .fold(0x811c_9dc5u32, |hash, byte| {
+                (hash ^ byte as u32).wrapping_mul(0x0100_0193)
+            });
+        hash | APP_NAMESPACE_BIT
+    }
+
+    /// Copies the first `key_len` bytes of the key of `app` into `key`.
+    fn copy_key(
+        app: &App,
+        key_len: usize,
+        key: &mut [u8; MAX_KEY_LEN],
+    ) -> Result<usize, ReturnCode> {
+        let slice = app.key.as_ref().ok_or(ReturnCode::ERESERVE)?;
+        if key_len > slice.len() || key_len > MAX_KEY_LEN {
+            return Err(ReturnCode::ESIZE);
+        }
+        key[..key_len].copy_from_slice(&slice.as_ref()[..key_len]);
+        Ok(key_len)
+    }
+
+    /// Starts the write of `appid`, and returns its error if it could not be
+    /// started.
+    fn start(&self, appid: AppId, op: Op) -> ReturnCode {
+        let namespace = Self::namespace(appid);
+        let rcode = self
+            .apps
+            .enter(appid, |app, _| {
+                let mut key = [0; MAX_KEY_LEN];
+                match op {
+                    Op::Set { key_len, value_len } => {
+                        let key_len = match Self::copy_key(app, key_len, &mut key) {
+                            Ok(key_len) => key_len,
+                            Err(rcode) => return rcode,
+                        };
+                        let buffer = match self.buffer.take() {
+                            Some(buffer) => buffer,
+                            None => return ReturnCode::EBUSY,
+                        };
+                        let value = app.value.as_ref().map_or(&[][..], |value| value.as_ref());
+                        if value_len > value.len() || value_len > buffer.len() {
+                            self.buffer.replace(buffer);
+                            return ReturnCode::ESIZE;
+                        }
+                        buffer[..value_len].copy_from_slice(&value[..value_len]);
+                        match self
+                            .store
+                            .set(namespace, &key[..key_len], buffer, value_len)
+                        {
+                            Ok(()) => ReturnCode::SUCCESS,
+                            Err((rcode, buffer)) => {
+                                self.buffer.replace(buffer);
+                                rcode
+                            }
+                        }
+                    }
+                    Op::Delete { key_len } => match Self::copy_key(app, key_len, &mut key) {
+                        Ok(key_len) => self.store.delete(namespace, &key[..key_len]),
+                        Err(rcode) => rcode,
+                    },
+                }
+            })
+            .unwrap_or_else(|err| err.into());
+        if rcode == ReturnCode::SUCCESS {
+            self.current_app.set(appid);
+        }
+        rcode
+    }
+
+    /// Starts the write of `appid` if no write is in progress, or else queues
+    /// it.
+    fn enqueue(&self, appid: AppId, op: Op) -> ReturnCode {
+        if self.current_app.is_none() {
+            return self.start(appid, op);
+        }
+        self.apps
+            .enter(appid, |app, _| {
+                if app.pending.is_some() {
+                    ReturnCode::EBUSY
+                } else {
+                    app.pending = Some(op);
+                    ReturnCode::SUCCESS
+                }
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    /// Calls back `appid` with the result of its write, started with the
+    /// command `command_num`.
+    fn done(&self, appid: AppId, command_num: usize, result: ReturnCode) {
+        let _ = self.apps.enter(appid, |app, _| {
+            app.callback
+                .map(|mut cb| cb.schedule(command_num, usize::from(result), 0));
+        });
+    }
+
+    /// Starts the queued writes in turn until one starts.
+    fn check_queue(&self) {
+        while self.current_app.is_none() {
+            let mut next = None;
+            for cntr in self.apps.iter() {
+                cntr.enter(|app, _| {
+                    if next.is_none() {
+                        next = app.pending.take().map(|op| (app.appid(), op));
+                    }
+                });
+                if next.is_some() {
+                    break;
+                }
+            }
+            match next {
+                Some((appid, op)) => {
+                    let rcode = self.start(appid, op);
+                    if rcode != ReturnCode::SUCCESS {
+                        self.done(appid, op.command_num(), rcode);
+                    }
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+impl<'a> KVStoreClient for KVStoreDriver<'a> {
+    fn set_done(&self, value: &'static mut [u8], result: ReturnCode) {
+        self.buffer.replace(value);
+        self.current_app
+            .take()
+            .map(|appid| self.done(appid, 2, result));
+        self.check_queue();
+    }
+
+    fn delete_done(&self, result: ReturnCode) {
+        self.current_app
+            .take()
+            .map(|appid| self.done(appid, 3, result));
+        self.check_queue();
+    }
+}
+
+impl<'a> Driver for KVStoreDriver<'a> {
+    /// Setup shared buffers.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: The key.
+    /// - `1`: The value.
+    fn allow(
+        &self,
+        appid: AppId,
+        allow_num: usize,
+        slice: Option<AppSlice<Shared, u8>>,
+    ) -> ReturnCode {
+        self.apps
+            .enter(appid, |app, _| {
+                match allow_num {
+                    0 => app.key = slice,
+                    1 => app.value = slice,
+                    _ => return ReturnCode::ENOSUPPORT,
+                }
+                ReturnCode::SUCCESS
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    /// Setup callbacks.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Write done callback.
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        callback: Option<Callback>,
+        app_id: AppId,
+    ) -> ReturnCode {
+        self.apps
+            .enter(app_id, |app, _| {
+                match subscribe_num {
+                    0 => app.callback = callback,
+                    _ => return ReturnCode::ENOSUPPORT,
+                }
+                ReturnCode::SUCCESS
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    /// Command interface.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Return SUCCESS if this driver is included on the platform.
+    /// - `1`: Get the value of a key.
+    /// - `2`: Set a key.
+    /// - `3`: Delete a key.
+    fn command(&self, command_num: usize, arg1: usize, arg2: usize, appid: AppId) -> ReturnCode {
+        match command_num {
+            0 => ReturnCode::SUCCESS,
+            1 => {
+                let namespace = Self::namespace(appid);
+                self.apps
+                    .enter(appid, |app, _| {
+                        let mut key = [0; MAX_KEY_LEN];
+                        let key_len = match Self::copy_key(app, arg1, &mut key) {
+                            Ok(key_len) => key_len,
+                            Err(rcode) => return rcode,
+                        };
+                        let mut empty = [];
+                        let value = match app.value.as_mut() {
+                            Some(value) => value.as_mut(),
+                            None => &mut empty[..],
+                        };
+                        match self.store.get(namespace, &key[..key_len], value) {
+                            Ok(len) => ReturnCode::SuccessWithValue { value: len },
+                            Err(rcode) => rcode,
+                        }
+                    })
+                    .unwrap_or_else(|err| err.into())
+            }
+            2 => self.enqueue(
+                appid,
+                Op::Set {
+                    key_len: arg1,
+                    value_len: arg2,
+                },
+            ),
+            3 => self.enqueue(appid, Op::Delete { key_len: arg1 }),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
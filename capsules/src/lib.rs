@@ -8,22 +8,40 @@ pub mod test;
 pub mod net;
 
 pub mod adc;
+pub mod aes_aead;
 pub mod aes_ccm;
 pub mod alarm;
+pub mod alarm64;
 pub mod ambient_light;
 pub mod analog_comparator;
 pub mod analog_sensor;
+pub mod ant;
 pub mod apds9960;
 pub mod app_flash_driver;
+pub mod attestation;
+pub mod battery;
+pub mod bg96;
+pub mod ble;
 pub mod ble_advertising_driver;
 pub mod button;
 pub mod buzzer_driver;
+pub mod can;
+pub mod clock;
 pub mod console;
 pub mod crc;
 pub mod dac;
 pub mod debug_process_restart;
 pub mod driver;
+pub mod ecdsa_p256;
+pub mod enc28j60;
+pub mod energy;
+pub mod entropy_pool;
+pub mod esp_hosted;
+pub mod fat;
+pub mod fat_driver;
+pub mod fem;
 pub mod fm25cl;
+pub mod fsk;
 pub mod ft6x06;
 pub mod fxos8700cq;
 pub mod gpio;
@@ -35,17 +53,26 @@ pub mod i2c_master;
 pub mod i2c_master_slave_driver;
 pub mod ieee802154;
 pub mod isl29035;
+pub mod jitter;
+pub mod kv_store;
+pub mod kv_store_driver;
 pub mod l3gd20;
 pub mod led;
 pub mod log;
+pub mod log_driver;
+pub mod lorawan;
 pub mod low_level_debug;
 pub mod lps25hb;
 pub mod lsm303dlhc;
 pub mod ltc294x;
+pub mod max17048;
 pub mod max17205;
 pub mod mcp230xx;
 pub mod mlx90614;
+pub mod monotonic_counter;
 pub mod mx25r6435f;
+pub mod ndef;
+pub mod nfc_tag;
 pub mod ninedof;
 pub mod nonvolatile_storage_driver;
 pub mod nonvolatile_to_pages;
@@ -53,6 +80,7 @@ pub mod nrf51822_serialization;
 pub mod panic_button;
 pub mod pca9544a;
 pub mod process_console;
+pub mod process_loader;
 pub mod proximity;
 pub mod rf233;
 pub mod rf233_const;
@@ -61,20 +89,29 @@ pub mod screen;
 pub mod sdcard;
 pub mod segger_rtt;
 pub mod si7021;
+pub mod signed_process_loader;
+pub mod sleep_window;
+pub mod software_aes;
+pub mod software_sha256;
 pub mod spi_controller;
 pub mod spi_peripheral;
 pub mod st7735;
+pub mod stopwatch;
+pub mod sx127x;
 pub mod temperature;
 pub mod temperature_stm;
 pub mod touch;
 pub mod tsl2561;
 pub mod usb;
 pub mod virtual_adc;
+pub mod virtual_aes_ccm;
 pub mod virtual_alarm;
+pub mod virtual_alarm_wheel;
 pub mod virtual_digest;
 pub mod virtual_flash;
 pub mod virtual_hmac;
 pub mod virtual_i2c;
+pub mod virtual_kv_store;
 pub mod virtual_pwm;
 pub mod virtual_spi;
 pub mod virtual_timer;
@@ -33,6 +33,9 @@
 //!     * Erase:    Erase a log in its entirety, clearing the underlying flash volume.
 //! See the documentation for each individual function for more detail on how they operate.
 //!
+//! A log can also be set as the client of a `hil::brown_out::BrownOutDetector`, in which case it
+//! syncs itself when the supply voltage falls below the detector's threshold.
+//!
 //! Note that while logs persist across reboots, they will be erased upon flashing a new kernel.
 //!
 //! Usage
@@ -74,6 +77,7 @@ use kernel::common::cells::{OptionalCell, TakeCell};
 use kernel::common::dynamic_deferred_call::{
     DeferredCallHandle, DynamicDeferredCall, DynamicDeferredCallClient,
 };
+use kernel::hil::brown_out::BrownOutClient;
 use kernel::hil::flash::{self, Flash};
 use kernel::hil::log::{LogRead, LogReadClient, LogWrite, LogWriteClient};
 use kernel::ReturnCode;
@@ -766,12 +770,15 @@ impl<'a, F: Flash + 'static> LogWrite<'a> for Log<'a, F> {
     ///     * SUCCESS: append succeeded.
     ///     * FAIL: write failed due to flash error.
     fn sync(&self) -> ReturnCode {
-        if self.append_entry_id.get() % self.page_size == PAGE_HEADER_SIZE {
-            // Pagebuffer empty, don't need to flush.
-            return ReturnCode::SUCCESS;
-        } else if self.state.get() != State::Idle {
+        if self.state.get() != State::Idle {
             // Log busy, try appending again later.
             return ReturnCode::EBUSY;
+        } else if self.append_entry_id.get() % self.page_size == PAGE_HEADER_SIZE {
+            // Pagebuffer empty, don't need to flush, but still call back.
+            self.state.set(State::Sync);
+            self.error.set(ReturnCode::SUCCESS);
+            self.deferred_client_callback();
+            return ReturnCode::SUCCESS;
         }
 
         self.pagebuffer
@@ -804,6 +811,15 @@ impl<'a, F: Flash + 'static> LogWrite<'a> for Log<'a, F> {
     }
 }
 
+/// Flush the pagebuffer when the supply is about to fail, so that entries
+/// appended since the last sync are not lost. The append client receives the
+/// `sync_done` callback.
+impl<'a, F: Flash + 'static> BrownOutClient for Log<'a, F> {
+    fn supply_low(&self) {
+        self.sync();
+    }
+}
+
 impl<'a, F: Flash + 'static> flash::Client<F> for Log<'a, F> {
     fn read_complete(&self, _read_buffer: &'static mut F::Page, _error: flash::Error) {
         // Reads are made directly from the storage volume, not through the flash interface.
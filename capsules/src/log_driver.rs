@@ -0,0 +1,391 @@
+//! Provides userspace access to a persistent log.
+//!
+//! Apps append records of any length up to a page, for example network
+//! events or sensor readings, and replay them from the oldest remaining one
+//! after a reboot. The log is shared: every app sees the records of all of
+//! them, and keeps a read position of its own, which it can move back to the
+//! start, save as an entry ID and seek to later. A circular log overwrites
+//! its oldest records when full, and a read position that has been
+//! overwritten moves on to the oldest record left.
+//!
+//! Records are copied through a kernel buffer, whose length limits the
+//! length of a record. The log runs one operation at a time, and the
+//! operations of other apps are queued until it is done.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let log_driver = static_init!(
+//!     capsules::log_driver::LogDriver<'static, Log<'static, sam4l::flashcalw::FLASHCALW>>,
+//!     capsules::log_driver::LogDriver::new(
+//!         log,
+//!         board_kernel.create_grant(&grant_cap),
+//!         &mut capsules::log_driver::BUFFER,
+//!     )
+//! );
+//! log.set_read_client(log_driver);
+//! log.set_append_client(log_driver);
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! ### Allow
+//!
+//! - `0`: The buffer records are read into.
+//! - `1`: The record to append.
+//!
+//! ### Subscribe
+//!
+//! - `0`: Called when an operation completes, with its command number, its
+//!   result and, for a read, the length of the record or, for an append,
+//!   whether old records were overwritten.
+//!
+//! ### Command
+//!
+//! - `0`: Return SUCCESS if this driver is included on the platform.
+//! - `1`: Read the next record. Fails with `FAIL` at the end of the log.
+//! - `2`: Append the first `arg1` bytes of the record buffer.
+//! - `3`: Sync the log to flash, making the records appended persistent.
+//! - `4`: Erase the log.
+//! - `5`: Move the read position to the oldest record.
+//! - `6`: Move the read position to entry ID `arg1`.
+//! - `7`: Return the entry ID of the read position.
+//! - `8`: Return the entry ID the next record will be appended at.
+
+use core::cmp;
+
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::log::{LogRead, LogReadClient, LogWrite, LogWriteClient};
+use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::LogStorage as usize;
+
+pub static mut BUFFER: [u8; 256] = [0; 256];
+
+#[derive(Clone, Copy, PartialEq)]
+enum Op {
+    Read,
+    Append { len: usize },
+    Sync,
+    Erase,
+}
+
+impl Op {
+    fn command_num(&self) -> usize {
+        match self {
+            Op::Read => 1,
+            Op::Append { .. } => 2,
+            Op::Sync => 3,
+            Op::Erase => 4,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct App {
+    callback: Option<Callback>,
+    read_buffer: Option<AppSlice<Shared, u8>>,
+    record: Option<AppSlice<Shared, u8>>,
+    /// The entry ID of the next record to read, or the oldest if `None`.
+    read_pos: Option<usize>,
+    pending: Option<Op>,
+}
+
+pub struct LogDriver<'a, L: LogRead<'a, EntryID = usize> + LogWrite<'a>> {
+    log: &'a L,
+    apps: Grant<App>,
+    /// Buffer records are copied through.
+    buffer: TakeCell<'static, [u8]>,
+    /// The app whose operation is in progress, and the operation.
+    current: OptionalCell<(AppId, Op)>,
+}
+
+impl<'a, L: LogRead<'a, EntryID = usize> + LogWrite<'a>> LogDriver<'a, L> {
+    pub fn new(log: &'a L, grant: Grant<App>, buffer: &'static mut [u8]) -> LogDriver<'a, L> {
+        LogDriver {
+            log: log,
+            apps: grant,
+            buffer: TakeCell::new(buffer),
+            current: OptionalCell::empty(),
+        }
+    }
+
+    /// Where the app at `read_pos` reads from, moved to the oldest record if
+    /// its own has been overwritten or erased.
+    fn read_pos(&self, read_pos: Option<usize>) -> usize {
+        let start = self.log.log_start();
+        match read_pos {
+            Some(pos) if pos >= start && pos <= self.log.log_end() => pos,
+            _ => start,
+        }
+    }
+
+    /// Reads the record at the read position of the log.
+    fn read(&self) -> ReturnCode {
+        let buffer = match self.buffer.take() {
+            Some(buffer) => buffer,
+            None => return ReturnCode::EBUSY,
+        };
+        let len = buffer.len();
+        match self.log.read(buffer, len) {
+            Ok(()) => ReturnCode::SUCCESS,
+            Err((rcode, buffer)) => {
+                buffer.map(|buffer| self.buffer.replace(buffer));
+                rcode
+            }
+        }
+    }
+
+    /// Starts the operation of `appid`, and returns its error if it could
+    /// not be started.
+    fn start(&self, appid: AppId, op: Op) -> ReturnCode {
+        let rcode = self
+            .apps
+            .enter(appid, |app, _| match op {
+                Op::Read => {
+                    // The log has one read position, so it is moved to the
+                    // one of the app first, which calls `seek_done()`.
+                    let pos = self.read_pos(app.read_pos);
+                    if self.log.next_read_entry_id() != pos {
+                        self.log.seek(pos)
+                    } else {
+                        self.read()
+                    }
+                }
+                Op::Append { len } => {
+                    let buffer = match self.buffer.take() {
+                        Some(buffer) => buffer,
+                        None => return ReturnCode::EBUSY,
+                    };
+                    let record = app
+                        .record
+                        .as_ref()
+                        .map_or(&[][..], |record| record.as_ref());
+                    if len > record.len() || len > buffer.len() {
+                        self.buffer.replace(buffer);
+                        return ReturnCode::ESIZE;
+                    }
+                    buffer[..len].copy_from_slice(&record[..len]);
+                    match self.log.append(buffer, len) {
+                        Ok(()) => ReturnCode::SUCCESS,
+                        Err((rcode, buffer)) => {
+                            buffer.map(|buffer| self.buffer.replace(buffer));
+                            rcode
+                        }
+                    }
+                }
+                Op::Sync => self.log.sync(),
+                Op::Erase => self.log.erase(),
+            })
+            .unwrap_or_else(|err| err.into());
+        if rcode == ReturnCode::SUCCESS {
+            self.current.set((appid, op));
+        }
+        rcode
+    }
+
+    /// Starts the operation of `appid` if none is in progress, or else
+    /// queues it.
+    fn enqueue(&self, appid: AppId, op: Op) -> ReturnCode {
+        if self.current.is_none() {
+            return self.start(appid, op);
+        }
+        self.apps
+            .enter(appid, |app, _| {
+                if app.pending.is_some() {
+                    ReturnCode::EBUSY
+                } else {
+                    app.pending = Some(op);
+                    ReturnCode::SUCCESS
+                }
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    /// Calls back `appid` with the result of its operation.
+    fn done(&self, appid: AppId, op: Op, result: ReturnCode, value: usize) {
+        let _ = self.apps.enter(appid, |app, _| {
+            app.callback
+                .map(|mut cb| cb.schedule(op.command_num(), usize::from(result), value));
+        });
+    }
+
+    /// Calls back the app of the operation in progress and starts the next.
+    fn finish(&self, result: ReturnCode, value: usize) {
+        self.current
+            .take()
+            .map(|(appid, op)| self.done(appid, op, result, value));
+        self.check_queue();
+    }
+
+    /// Starts the queued operations in turn until one starts.
+    fn check_queue(&self) {
+        while self.current.is_none() {
+            let mut next = None;
+            for cntr in self.apps.iter() {
+                cntr.enter(|app, _| {
+                    if next.is_none() {
+                        next = app.pending.take().map(|op| (app.appid(), op));
+                    }
+                });
+                if next.is_some() {
+                    break;
+                }
+            }
+            match next {
+                Some((appid, op)) => {
+                    let rcode = self.start(appid, op);
+                    if rcode != ReturnCode::SUCCESS {
+                        self.done(appid, op, rcode, 0);
+                    }
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+impl<'a, L: LogRead<'a, EntryID = usize> + LogWrite<'a>> LogReadClient for LogDriver<'a, L> {
+    fn read_done(&self, buffer: &'static mut [u8], length: usize, error: ReturnCode) {
+        self.current.map(|(appid, _)| {
+            let _ = self.apps.enter(*appid, |app, _| {
+                app.read_pos = Some(self.log.next_read_entry_id());
+                app.read_buffer.as_mut().map(|read_buffer| {
+                    let len = cmp::min(length, read_buffer.len());
+                    read_buffer.as_mut()[..len].copy_from_slice(&buffer[..len]);
+                });
+            });
+        });
+        self.buffer.replace(buffer);
+        self.finish(error, length);
+    }
+
+    fn seek_done(&self, error: ReturnCode) {
+        let rcode = match error {
+            ReturnCode::SUCCESS => self.read(),
+            error => error,
+        };
+        if rcode != ReturnCode::SUCCESS {
+            self.finish(rcode, 0);
+        }
+    }
+}
+
+impl<'a, L: LogRead<'a, EntryID = usize> + LogWrite<'a>> LogWriteClient for LogDriver<'a, L> {
+    fn append_done(
+        &self,
+        buffer: &'static mut [u8],
+        _length: usize,
+        records_lost: bool,
+        error: ReturnCode,
+    ) {
+        self.buffer.replace(buffer);
+        self.finish(error, records_lost as usize);
+    }
+
+    fn sync_done(&self, error: ReturnCode) {
+        self.finish(error, 0);
+    }
+
+    fn erase_done(&self, error: ReturnCode) {
+        self.finish(error, 0);
+    }
+}
+
+impl<'a, L: LogRead<'a, EntryID = usize> + LogWrite<'a>> Driver for LogDriver<'a, L> {
+    /// Setup shared buffers.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: The buffer to read records into.
+    /// - `1`: The record to append.
+    fn allow(
+        &self,
+        appid: AppId,
+        allow_num: usize,
+        slice: Option<AppSlice<Shared, u8>>,
+    ) -> ReturnCode {
+        self.apps
+            .enter(appid, |app, _| {
+                match allow_num {
+                    0 => app.read_buffer = slice,
+                    1 => app.record = slice,
+                    _ => return ReturnCode::ENOSUPPORT,
+                }
+                ReturnCode::SUCCESS
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    /// Setup callbacks.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Operation done callback.
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        callback: Option<Callback>,
+        app_id: AppId,
+    ) -> ReturnCode {
+        self.apps
+            .enter(app_id, |app, _| {
+                match subscribe_num {
+                    0 => app.callback = callback,
+                    _ => return ReturnCode::ENOSUPPORT,
+                }
+                ReturnCode::SUCCESS
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    /// Command interface.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Return SUCCESS if this driver is included on the platform.
+    /// - `1`: Read the next record.
+    /// - `2`: Append a record.
+    /// - `3`: Sync.
+    /// - `4`: Erase.
+    /// - `5`: Rewind the read position.
+    /// - `6`: Seek the read position.
+    /// - `7`: Get the read position.
+    /// - `8`: Get the append position.
+    fn command(&self, command_num: usize, arg1: usize, _: usize, appid: AppId) -> ReturnCode {
+        match command_num {
+            0 => ReturnCode::SUCCESS,
+            1 => self.enqueue(appid, Op::Read),
+            2 => self.enqueue(appid, Op::Append { len: arg1 }),
+            3 => self.enqueue(appid, Op::Sync),
+            4 => self.enqueue(appid, Op::Erase),
+            5 | 6 => {
+                if command_num == 6 && (arg1 < self.log.log_start() || arg1 > self.log.log_end()) {
+                    return ReturnCode::EINVAL;
+                }
+                self.apps
+                    .enter(appid, |app, _| {
+                        app.read_pos = if command_num == 6 { Some(arg1) } else { None };
+                        ReturnCode::SUCCESS
+                    })
+                    .unwrap_or_else(|err| err.into())
+            }
+            7 => self
+                .apps
+                .enter(appid, |app, _| ReturnCode::SuccessWithValue {
+                    value: self.read_pos(app.read_pos),
+                })
+                .unwrap_or_else(|err| err.into()),
+            8 => ReturnCode::SuccessWithValue {
+                value: self.log.log_end(),
+            },
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
@@ -0,0 +1,751 @@
+//! LoRaWAN Class A end device
+//!
+//! A LoRaWAN 1.0 MAC for the EU868 region on top of `hil::lora::Radio`,
+//! with a system call interface. An app activates the device over the air
+//! (OTAA) with its DevEUI, JoinEUI and AppKey, then sends confirmed or
+//! unconfirmed uplinks. After each uplink the two receive windows are opened,
+//! one and two seconds later (five and six for a join), and a downlink
+//! received in either is passed to the app.
+//!
+//! The device starts on the three default channels and adds those of the
+//! join accept's channel list, choosing one at random for each uplink. The
+//! data rate is fixed by the app; ADR and other MAC commands are ignored,
+//! confirmed uplinks are not retransmitted, and duty cycle limits are left
+//! to the app. The first app to use a command other than the driver check
+//! owns the driver; other apps get `ERESERVE`.
+//!
+//! ## Instantiation
+//!
+//! ```rust
+//! # use kernel::static_init;
+//! # use capsules::virtual_alarm::VirtualMuxAlarm;
+//!
+//! let lorawan_alarm = static_init!(VirtualMuxAlarm<'static, Rtc>, VirtualMuxAlarm::new(mux_alarm));
+//! let lorawan = static_init!(
+//!     capsules::lorawan::LoRaWan<'static, VirtualMuxAlarm<'static, Rtc>>,
+//!     capsules::lorawan::LoRaWan::new(
+//!         sx127x,
+//!         lorawan_alarm,
+//!         rng,
+//!         static_init!([u8; 256], [0; 256]),
+//!         board_kernel.create_grant(&grant_cap)));
+//! kernel::hil::lora::Radio::set_transmit_client(sx127x, lorawan);
+//! kernel::hil::lora::Radio::set_receive_client(sx127x, lorawan);
+//! lorawan_alarm.set_client(lorawan);
+//! rng.set_client(lorawan);
+//! ```
+//!
+//! ## Syscall Interface
+//!
+//! - allow `0`: the DevEUI, JoinEUI and AppKey, 32 bytes, each most
+//!   significant byte first as network servers show them.
+//! - allow `1`: the payload of the next uplink.
+//! - allow `2`: downlink payloads are copied here.
+//! - subscribe `0`: a command finished, `fn(command, ReturnCode, 0)`. A join
+//!   fails with `FAIL` if no join accept arrived and a confirmed uplink with
+//!   `ENOACK` if it was not acknowledged.
+//! - subscribe `1`: a downlink arrived on port `port`, `fn(port, len, rssi)`.
+//! - command `0`: driver check.
+//! - command `1`: join with the credentials in allow `0`.
+//! - command `2`: send the first `data2` bytes of allow `1` on port `data`.
+//! - command `3`: like `2`, but ask the network to acknowledge the uplink.
+//! - command `4`: use data rate `data`, 0 (SF12) to 5 (SF7).
+
+use core::cell::Cell;
+use core::cmp;
+
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::lora::{self, Bandwidth, Config};
+use kernel::hil::rng::{self, Rng};
+use kernel::hil::time::{self, Alarm, Ticks};
+use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
+
+use crate::ble::crypto::{aes128, aes_cmac};
+
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::LoRaWan as usize;
+
+/// Message types, in the top bits of the MAC header.
+const JOIN_REQUEST: u8 = 0x00;
+const JOIN_ACCEPT: u8 = 0x20;
+const UNCONFIRMED_UP: u8 = 0x40;
+const UNCONFIRMED_DOWN: u8 = 0x60;
+const CONFIRMED_UP: u8 = 0x80;
+const CONFIRMED_DOWN: u8 = 0xa0;
+const MTYPE_MASK: u8 = 0xe0;
+
+/// Frame control bits.
+const FCTRL_ACK: u8 = 0x20;
+const FCTRL_FOPTS_LEN: u8 = 0x0f;
+
+const MIC_LEN: usize = 4;
+/// MAC header, address, frame control, counter and port.
+const UPLINK_HEADER_LEN: usize = 9;
+
+/// The default EU868 channels.
+const DEFAULT_CHANNELS: [u32; 3] = [868_100_000, 868_300_000, 868_500_000];
+const MAX_CHANNELS: usize = 8;
+const RX2_FREQUENCY: u32 = 869_525_000;
+
+const JOIN_ACCEPT_DELAY_MS: u32 = 5000;
+/// How early receive windows are opened, to allow for setting up the radio
+/// and for the network's timing error.
+const RX_MARGIN_MS: u32 = 20;
+/// Symbols of preamble that must be heard.
+const PREAMBLE_SYMBOLS: u32 = 8;
+
+/// The largest application payload at each data rate.
+const MAX_PAYLOAD_LEN: [usize; 6] = [51, 51, 51, 115, 222, 222];
+
+const TX_POWER_DBM: i8 = 14;
+const PUBLIC_SYNC_WORD: u8 = 0x34;
+
+#[derive(Copy, Clone, PartialEq)]
+enum State {
+    Idle,
+    /// Waiting for randomness for the join's DevNonce.
+    Random,
+    Transmitting,
+    WaitRx1,
+    Rx1,
+    WaitRx2,
+    Rx2,
+}
+
+#[derive(Copy, Clone, Default)]
+struct Credentials {
+    dev_eui: [u8; 8],
+    join_eui: [u8; 8],
+    app_key: [u8; 16],
+}
+
+#[derive(Copy, Clone)]
+struct Session {
+    dev_addr: u32,
+    nwk_s_key: [u8; 16],
+    app_s_key: [u8; 16],
+    fcnt_up: u32,
+    fcnt_down: u32,
+    rx1_dr_offset: u8,
+    rx2_data_rate: u8,
+    rx_delay_ms: u32,
+}
+
+#[derive(Default)]
+pub struct App {
+    done_callback: Option<Callback>,
+    downlink_callback: Option<Callback>,
+    credentials: Option<AppSlice<Shared, u8>>,
+    uplink: Option<AppSlice<Shared, u8>>,
+    downlink: Option<AppSlice<Shared, u8>>,
+}
+
+/// The data rate's radio configuration for an uplink on `frequency_hz`, or
+/// for a downlink with `downlink`.
+fn radio_config(frequency_hz: u32, data_rate: u8, downlink: bool) -> Config {
+    Config {
+        frequency_hz: frequency_hz,
+        spreading_factor: 12 - data_rate,
+        bandwidth: Bandwidth::Khz125,
+        coding_rate: 5,
+        tx_power_dbm: TX_POWER_DBM,
+        crc: !downlink,
+        invert_iq: downlink,
+        sync_word: PUBLIC_SYNC_WORD,
+    }
+}
+
+/// Encrypt or decrypt a frame payload in place.
+fn crypt_payload(key: &[u8; 16], downlink: bool, dev_addr: u32, fcnt: u32, payload: &mut [u8]) {
+    for (i, chunk) in payload.chunks_mut(16).enumerate() {
+        let mut a = [0; 16];
+        a[0] = 0x01;
+        a[5] = downlink as u8;
+        a[6..10].copy_from_slice(&dev_addr.to_le_bytes());
+        a[10..14].copy_from_slice(&fcnt.to_le_bytes());
+        a[15] = (i + 1) as u8;
+        let s = aes128(key, &a);
+        for (b, k) in chunk.iter_mut().zip(s.iter()) {
+            *b ^= *k;
+        }
+    }
+}
+
+/// The message integrity code of a data frame.
+fn data_mic(key: &[u8; 16], downlink: bool, dev_addr: u32, fcnt: u32, msg: &[u8]) -> [u8; 4] {
+    let mut b0 = [0; 16];
+    b0[0] = 0x49;
+    b0[5] = downlink as u8;
+    b0[6..10].copy_from_slice(&dev_addr.to_le_bytes());
+    b0[10..14].copy_from_slice(&fcnt.to_le_bytes());
+    b0[15] = msg.len() as u8;
+    let mac = aes_cmac(key, &[&b0, msg]);
+    [mac[0], mac[1], mac[2], mac[3]]
+}
+
+/// Copy `bytes`, sent least significant byte first, reversed.
+fn reversed8(bytes: &[u8]) -> [u8; 8] {
+    let mut out = [0; 8];
+    for (o, b) in out.iter_mut().zip(bytes.iter().rev()) {
+        *o = *b;
+    }
+    out
+}
+
+pub struct LoRaWan<'a, A: Alarm<'a>> {
+    radio: &'a dyn lora::Radio<'a>,
+    alarm: &'a A,
+    rng: &'a dyn Rng<'a>,
+    buffer: TakeCell<'static, [u8]>,
+    state: Cell<State>,
+    /// Whether the uplink in progress is a join request.
+    joining: Cell<bool>,
+    /// Whether the uplink in progress must be acknowledged.
+    confirmed: Cell<bool>,
+    acknowledged: Cell<bool>,
+    /// Whether the network sent a confirmed downlink we have to acknowledge
+    /// in the next uplink.
+    ack_pending: Cell<bool>,
+    credentials: Cell<Credentials>,
+    dev_nonce: Cell<u16>,
+    session: Cell<Option<Session>>,
+    channels: Cell<[u32; MAX_CHANNELS]>,
+    channel_count: Cell<usize>,
+    data_rate: Cell<u8>,
+    /// Frequency and end of the last uplink.
+    tx_frequency: Cell<u32>,
+    tx_end: Cell<u32>,
+    random: Cell<u32>,
+    apps: Grant<App>,
+    owner: OptionalCell<AppId>,
+}
+
+impl<'a, A: Alarm<'a>> LoRaWan<'a, A> {
+    /// `buffer` must hold `lora::MAX_PAYLOAD_LEN` bytes.
+    pub fn new(
+        radio: &'a dyn lora::Radio<'a>,
+        alarm: &'a A,
+        rng: &'a dyn Rng<'a>,
+        buffer: &'static mut [u8],
+        apps: Grant<App>,
+    ) -> LoRaWan<'a, A> {
+        let mut channels = [0; MAX_CHANNELS];
+        channels[..DEFAULT_CHANNELS.len()].copy_from_slice(&DEFAULT_CHANNELS);
+        LoRaWan {
+            radio: radio,
+            alarm: alarm,
+            rng: rng,
+            buffer: TakeCell::new(buffer),
+            state: Cell::new(State::Idle),
+            joining: Cell::new(false),
+            confirmed: Cell::new(false),
+            acknowledged: Cell::new(false),
+            ack_pending: Cell::new(false),
+            credentials: Cell::new(Credentials::default()),
+            dev_nonce: Cell::new(0),
+            session: Cell::new(None),
+            channels: Cell::new(channels),
+            channel_count: Cell::new(DEFAULT_CHANNELS.len()),
+            data_rate: Cell::new(0),
+            tx_frequency: Cell::new(DEFAULT_CHANNELS[0]),
+            tx_end: Cell::new(0),
+            random: Cell::new(1),
+            apps: apps,
+            owner: OptionalCell::empty(),
+        }
+    }
+
+    /// A random channel for the next uplink.
+    fn channel(&self) -> u32 {
+        let mut random = self.random.get();
+        random ^= random << 13;
+        random ^= random >> 17;
+        random ^= random << 5;
+        self.random.set(random);
+        self.channels.get()[random as usize % self.channel_count.get()]
+    }
+
+    fn join(&self, app: &mut App) -> ReturnCode {
+        let credentials = match app.credentials {
+            Some(ref slice) if slice.len() >= 32 => {
+                let bytes = slice.as_ref();
+                let mut app_key = [0; 16];
+                app_key.copy_from_slice(&bytes[16..32]);
+                Credentials {
+                    dev_eui: reversed8(&bytes[0..8]),
+                    join_eui: reversed8(&bytes[8..16]),
+                    app_key: app_key,
+                }
+            }
+            _ => return ReturnCode::EINVAL,
+        };
+        let rcode = self.rng.get();
+        if rcode == ReturnCode::SUCCESS {
+            self.credentials.set(credentials);
+            self.session.set(None);
+            self.joining.set(true);
+            self.state.set(State::Random);
+        }
+        rcode
+    }
+
+    fn send_join_request(&self) {
+        let credentials = self.credentials.get();
+        let rcode = self.buffer.take().map_or(ReturnCode::EBUSY, |buf| {
+            buf[0] = JOIN_REQUEST;
+            buf[1..9].copy_from_slice(&credentials.join_eui);
+            buf[9..17].copy_from_slice(&credentials.dev_eui);
+            buf[17..19].copy_from_slice(&self.dev_nonce.get().to_le_bytes());
+            let mac = aes_cmac(&credentials.app_key, &[&buf[..19]]);
+            buf[19..23].copy_from_slice(&mac[..MIC_LEN]);
+            self.transmit(buf, 23)
+        });
+        if rcode != ReturnCode::SUCCESS {
+            self.finish(rcode);
+        }
+    }
+
+    fn send(&self, app: &mut App, port: usize, len: usize, confirmed: bool) -> ReturnCode {
+        let session = match self.session.get() {
+            Some(session) => session,
+            None => return ReturnCode::EOFF,
+        };
+        if port == 0 || port > 223 {
+            return ReturnCode::EINVAL;
+        }
+        if self.state.get() != State::Idle {
+            return ReturnCode::EBUSY;
+        }
+        if len > MAX_PAYLOAD_LEN[self.data_rate.get() as usize] {
+            return ReturnCode::ESIZE;
+        }
+        let payload = match app.uplink {
+            Some(ref slice) if slice.len() >= len => slice,
+            _ => return ReturnCode::EINVAL,
+        };
+        self.buffer.take().map_or(ReturnCode::EBUSY, |buf| {
+            let fcnt = session.fcnt_up;
+            buf[0] = if confirmed {
+                CONFIRMED_UP
+            } else {
+                UNCONFIRMED_UP
+            };
+            buf[1..5].copy_from_slice(&session.dev_addr.to_le_bytes());
+            buf[5] = if self.ack_pending.replace(false) {
+                FCTRL_ACK
+            } else {
+                0
+            };
+            buf[6..8].copy_from_slice(&(fcnt as u16).to_le_bytes());
+            buf[8] = port as u8;
+            let end = UPLINK_HEADER_LEN + len;
+            buf[UPLINK_HEADER_LEN..end].copy_from_slice(&payload.as_ref()[..len]);
+            crypt_payload(
+                &session.app_s_key,
+                false,
+                session.dev_addr,
+                fcnt,
+                &mut buf[UPLINK_HEADER_LEN..end],
+            );
+            let mic = data_mic(
+                &session.nwk_s_key,
+                false,
+                session.dev_addr,
+                fcnt,
+                &buf[..end],
+            );
+            buf[end..end + MIC_LEN].copy_from_slice(&mic);
+            self.session.set(Some(Session {
+                fcnt_up: fcnt.wrapping_add(1),
+                ..session
+            }));
+            self.joining.set(false);
+            self.confirmed.set(confirmed);
+            self.acknowledged.set(false);
+            self.transmit(buf, end + MIC_LEN)
+        })
+    }
+
+    fn transmit(&self, buf: &'static mut [u8], len: usize) -> ReturnCode {
+        let frequency = self.channel();
+        self.tx_frequency.set(frequency);
+        self.radio
+            .configure(radio_config(frequency, self.data_rate.get(), false));
+        match self.radio.transmit(buf, len) {
+            Ok(()) => {
+                self.state.set(State::Transmitting);
+                ReturnCode::SUCCESS
+            }
+            Err((rcode, buf)) => {
+                self.buffer.replace(buf);
+                rcode
+            }
+        }
+    }
+
+    /// How long after an uplink the first receive window opens.
+    fn rx1_delay_ms(&self) -> u32 {
+        if self.joining.get() {
+            JOIN_ACCEPT_DELAY_MS
+        } else {
+            self.session
+                .get()
+                .map_or(1000, |session| session.rx_delay_ms)
+        }
+    }
+
+    /// Open a receive window.
+    fn open_window(&self, config: Config) {
+        self.radio.configure(config);
+        let timeout = (2 * RX_MARGIN_MS * 1000) / config.symbol_us() + PREAMBLE_SYMBOLS;
+        match self.buffer.take() {
+            Some(buf) => {
+                if let Err((_, buf)) = self.radio.receive(buf, timeout as u16) {
+                    self.buffer.replace(buf);
+                    self.window_closed();
+                }
+            }
+            None => self.window_closed(),
+        }
+    }
+
+    /// Nothing for us arrived in the current window.
+    fn window_closed(&self) {
+        match self.state.get() {
+            State::Rx1 => {
+                self.state.set(State::WaitRx2);
+                self.alarm.set_alarm(
+                    A::Ticks::from(self.tx_end.get()),
+                    A::ticks_from_ms(self.rx1_delay_ms() + 1000 - RX_MARGIN_MS),
+                );
+            }
+            _ => {
+                let result = if self.joining.get() {
+                    ReturnCode::FAIL
+                } else if self.confirmed.get() && !self.acknowledged.get() {
+                    ReturnCode::ENOACK
+                } else {
+                    ReturnCode::SUCCESS
+                };
+                self.finish(result);
+            }
+        }
+    }
+
+    fn finish(&self, result: ReturnCode) {
+        self.state.set(State::Idle);
+        self.radio.sleep();
+        let command = if self.joining.get() {
+            1
+        } else if self.confirmed.get() {
+            3
+        } else {
+            2
+        };
+        self.owner.map(|owner| {
+            let _ = self.apps.enter(*owner, |app, _| {
+                app.done_callback
+                    .map(|mut cb| cb.schedule(command, usize::from(result), 0));
+            });
+        });
+    }
+
+    /// Handle a frame received in a window. Returns whether it was for us.
+    fn frame_received(&self, frame: &mut [u8], rssi: i16) -> bool {
+        if frame.is_empty() {
+            return false;
+        }
+        match frame[0] & MTYPE_MASK {
+            JOIN_ACCEPT if self.joining.get() => self.join_accept(frame),
+            UNCONFIRMED_DOWN | CONFIRMED_DOWN if !self.joining.get() => self.data_down(frame, rssi),
+            _ => false,
+        }
+    }
+
+    fn join_accept(&self, frame: &mut [u8]) -> bool {
+        if frame.len() != 17 && frame.len() != 33 {
+            return false;
+        }
+        let credentials = self.credentials.get();
+        // The network encrypts join accepts with AES decryption, so they
+        // are decrypted with encryption.
+        for block in frame[1..].chunks_exact_mut(16) {
+            let mut input = [0; 16];
+            input.copy_from_slice(block);
+            block.copy_from_slice(&aes128(&credentials.app_key, &input));
+        }
+        let mic_at = frame.len() - MIC_LEN;
+        let mac = aes_cmac(&credentials.app_key, &[&frame[..mic_at]]);
+        if mac[..MIC_LEN] != frame[mic_at..] {
+            return false;
+        }
+
+        let body = &frame[1..mic_at];
+        let session_key = |kind: u8| {
+            let mut block = [0; 16];
+            block[0] = kind;
+            // JoinNonce and NetID.
+            block[1..7].copy_from_slice(&body[0..6]);
+            block[7..9].copy_from_slice(&self.dev_nonce.get().to_le_bytes());
+            aes128(&credentials.app_key, &block)
+        };
+        let dl_settings = body[10];
+        self.session.set(Some(Session {
+            dev_addr: u32::from_le_bytes([body[6], body[7], body[8], body[9]]),
+            nwk_s_key: session_key(0x01),
+            app_s_key: session_key(0x02),
+            fcnt_up: 0,
+            fcnt_down: 0,
+            rx1_dr_offset: (dl_settings >> 4) & 0x07,
+            rx2_data_rate: cmp::min(dl_settings & 0x0f, 5),
+            rx_delay_ms: cmp::max(1, body[11] & 0x0f) as u32 * 1000,
+        }));
+
+        let mut channels = [0; MAX_CHANNELS];
+        channels[..DEFAULT_CHANNELS.len()].copy_from_slice(&DEFAULT_CHANNELS);
+        let mut count = DEFAULT_CHANNELS.len();
+        if body.len() == 28 {
+            // The channel list gives five more frequencies, in units of
+            // 100 Hz.
+            for entry in body[12..27].chunks_exact(3) {
+                let frequency = u32::from_le_bytes([entry[0], entry[1], entry[2], 0]) * 100;
+                if frequency != 0 && count < MAX_CHANNELS {
+                    channels[count] = frequency;
+                    count += 1;
+                }
+            }
+        }
+        self.channels.set(channels);
+        self.channel_count.set(count);
+        self.ack_pending.set(false);
+        true
+    }
+
+    fn data_down(&self, frame: &mut [u8], rssi: i16) -> bool {
+        let session = match self.session.get() {
+            Some(session) => session,
+            None => return false,
+        };
+        if frame.len() < 8 + MIC_LEN {
+            return false;
+        }
+        let dev_addr = u32::from_le_bytes([frame[1], frame[2], frame[3], frame[4]]);
+        if dev_addr != session.dev_addr {
+            return false;
+        }
+        let fctrl = frame[5];
+        // The counter is sent truncated to 16 bits.
+        let low = u16::from_le_bytes([frame[6], frame[7]]) as u32;
+        let mut fcnt = session.fcnt_down & !0xffff | low;
+        if fcnt < session.fcnt_down {
+            fcnt = fcnt.wrapping_add(0x10000);
+        }
+        let mic_at = frame.len() - MIC_LEN;
+        let mic = data_mic(&session.nwk_s_key, true, dev_addr, fcnt, &frame[..mic_at]);
+        if mic[..] != frame[mic_at..] {
+            return false;
+        }
+        self.session.set(Some(Session {
+            fcnt_down: fcnt.wrapping_add(1),
+            ..session
+        }));
+        if fctrl & FCTRL_ACK != 0 {
+            self.acknowledged.set(true);
+        }
+        if frame[0] & MTYPE_MASK == CONFIRMED_DOWN {
+            self.ack_pending.set(true);
+        }
+
+        let port_at = 8 + (fctrl & FCTRL_FOPTS_LEN) as usize;
+        if port_at >= mic_at {
+            // No payload, such as a bare acknowledgement.
+            return true;
+        }
+        let port = frame[port_at];
+        let payload = &mut frame[port_at + 1..mic_at];
+        let key = if port == 0 {
+            &session.nwk_s_key
+        } else {
+            &session.app_s_key
+        };
+        crypt_payload(key, true, dev_addr, fcnt, payload);
+        // Port 0 carries only MAC commands, which are not supported.
+        if port != 0 {
+            self.owner.map(|owner| {
+                let _ = self.apps.enter(*owner, |app, _| {
+                    let len = app.downlink.as_mut().map_or(0, |downlink| {
+                        let len = cmp::min(downlink.len(), payload.len());
+                        downlink.as_mut()[..len].copy_from_slice(&payload[..len]);
+                        len
+                    });
+                    app.downlink_callback
+                        .map(|mut cb| cb.schedule(port as usize, len, rssi as usize));
+                });
+            });
+        }
+        true
+    }
+}
+
+impl<'a, A: Alarm<'a>> lora::TransmitClient for LoRaWan<'a, A> {
+    fn transmitted(&self, buffer: &'static mut [u8], result: ReturnCode) {
+        self.buffer.replace(buffer);
+        if self.state.get() != State::Transmitting {
+            return;
+        }
+        if result != ReturnCode::SUCCESS {
+            self.finish(result);
+            return;
+        }
+        let now = self.alarm.now();
+        self.tx_end.set(now.into_u32());
+        self.state.set(State::WaitRx1);
+        self.alarm
+            .set_alarm(now, A::ticks_from_ms(self.rx1_delay_ms() - RX_MARGIN_MS));
+    }
+}
+
+impl<'a, A: Alarm<'a>> lora::ReceiveClient for LoRaWan<'a, A> {
+    fn received(
+        &self,
+        buffer: &'static mut [u8],
+        len: usize,
+        rssi_dbm: i16,
+        _snr_db: i8,
+        result: ReturnCode,
+    ) {
+        let ours =
+            result == ReturnCode::SUCCESS && self.frame_received(&mut buffer[..len], rssi_dbm);
+        self.buffer.replace(buffer);
+        match self.state.get() {
+            State::Rx1 | State::Rx2 if ours => {
+                let result = if self.confirmed.get() && !self.acknowledged.get() {
+                    ReturnCode::ENOACK
+                } else {
+                    ReturnCode::SUCCESS
+                };
+                self.finish(result);
+            }
+            State::Rx1 | State::Rx2 => self.window_closed(),
+            _ => {}
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> time::AlarmClient for LoRaWan<'a, A> {
+    fn alarm(&self) {
+        let data_rate = self.data_rate.get();
+        match self.state.get() {
+            State::WaitRx1 => {
+                self.state.set(State::Rx1);
+                let offset = self.session.get().map_or(0, |s| s.rx1_dr_offset);
+                let rx1_data_rate = data_rate.saturating_sub(offset);
+                self.open_window(radio_config(self.tx_frequency.get(), rx1_data_rate, true));
+            }
+            State::WaitRx2 => {
+                self.state.set(State::Rx2);
+                let rx2_data_rate = self.session.get().map_or(0, |s| s.rx2_data_rate);
+                self.open_window(radio_config(RX2_FREQUENCY, rx2_data_rate, true));
+            }
+            _ => {}
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> rng::Client for LoRaWan<'a, A> {
+    fn randomness_available(
+        &self,
+        randomness: &mut dyn Iterator<Item = u32>,
+        error: ReturnCode,
+    ) -> rng::Continue {
+        if self.state.get() != State::Random {
+            return rng::Continue::Done;
+        }
+        if error != ReturnCode::SUCCESS {
+            self.finish(error);
+            return rng::Continue::Done;
+        }
+        match (randomness.next(), randomness.next()) {
+            (Some(nonce), Some(seed)) => {
+                self.dev_nonce.set(nonce as u16);
+                // Xorshift needs a non-zero seed.
+                self.random.set(seed | 1);
+                self.send_join_request();
+                rng::Continue::Done
+            }
+            _ => rng::Continue::More,
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> Driver for LoRaWan<'a, A> {
+    fn allow(
+        &self,
+        appid: AppId,
+        allow_num: usize,
+        slice: Option<AppSlice<Shared, u8>>,
+    ) -> ReturnCode {
+        self.apps
+            .enter(appid, |app, _| {
+                match allow_num {
+                    0 => app.credentials = slice,
+                    1 => app.uplink = slice,
+                    2 => app.downlink = slice,
+                    _ => return ReturnCode::ENOSUPPORT,
+                }
+                ReturnCode::SUCCESS
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        callback: Option<Callback>,
+        appid: AppId,
+    ) -> ReturnCode {
+        self.apps
+            .enter(appid, |app, _| {
+                match subscribe_num {
+                    0 => app.done_callback = callback,
+                    1 => app.downlink_callback = callback,
+                    _ => return ReturnCode::ENOSUPPORT,
+                }
+                ReturnCode::SUCCESS
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    fn command(&self, command_num: usize, data: usize, data2: usize, appid: AppId) -> ReturnCode {
+        if command_num == 0 {
+            return ReturnCode::SUCCESS;
+        }
+        if command_num > 4 {
+            return ReturnCode::ENOSUPPORT;
+        }
+        if self.owner.map_or(false, |owner| *owner != appid) {
+            return ReturnCode::ERESERVE;
+        }
+        self.owner.set(appid);
+
+        if command_num != 4 && self.state.get() != State::Idle {
+            return ReturnCode::EBUSY;
+        }
+        self.apps
+            .enter(appid, |app, _| match command_num {
+                1 => self.join(app),
+                2 => self.send(app, data, data2, false),
+                3 => self.send(app, data, data2, true),
+                4 => {
+                    if data < MAX_PAYLOAD_LEN.len() {
+                        self.data_rate.set(data as u8);
+                        ReturnCode::SUCCESS
+                    } else {
+                        ReturnCode::EINVAL
+                    }
+                }
+                _ => ReturnCode::ENOSUPPORT,
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+}
@@ -0,0 +1,272 @@
+//! Driver for the Maxim MAX17048 fuel gauge.
+//!
+//! <https://www.maximintegrated.com/en/products/power/battery-management/MAX17048.html>
+//!
+//! > The MAX17048/MAX17049 ICs are tiny, micropower current fuel gauges for
+//! > lithium-ion (Li+) batteries in handheld and portable equipment. The
+//! > MAX17048 operates with a single lithium cell and the MAX17049 with two
+//! > lithium cells in series.
+//!
+//! The driver implements `hil::battery::Battery`. The charging state is
+//! derived from the sign of the gauge's charge rate. If the gauge's ALRT pin
+//! is connected, the driver enables its interrupt once a low-battery threshold
+//! has been set, and clears the alert in the gauge when it fires.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let max17048_i2c = static_init!(
+//!     capsules::virtual_i2c::I2CDevice,
+//!     capsules::virtual_i2c::I2CDevice::new(i2c_bus, 0x36));
+//! let max17048 = static_init!(
+//!     capsules::max17048::MAX17048<'static>,
+//!     capsules::max17048::MAX17048::new(max17048_i2c, Some(&sam4l::gpio::PA[17]),
+//!                                       &mut capsules::max17048::BUFFER));
+//! max17048_i2c.set_client(max17048);
+//! sam4l::gpio::PA[17].set_client(max17048);
+//! ```
+
+use core::cell::Cell;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::battery::{Battery, BatteryClient, ChargingState};
+use kernel::hil::gpio;
+use kernel::hil::i2c;
+use kernel::ReturnCode;
+
+pub static mut BUFFER: [u8; 3] = [0; 3];
+
+/// All registers are 16 bits wide and big-endian.
+enum Registers {
+    VCell = 0x02, // Cell voltage, LSB = 78.125 uV
+    Soc = 0x04,   // State of charge, LSB = 1/256 %
+    Config = 0x0C,
+    CRate = 0x16, // Charge rate, signed, LSB = 0.208 %/hr
+}
+
+/// Alert flag in the low byte of CONFIG.
+const CONFIG_ALRT: u8 = 1 << 5;
+/// Alert threshold in the low byte of CONFIG, as 32 minus the percentage.
+const CONFIG_ATHD_MASK: u8 = 0x1F;
+
+#[derive(Clone, Copy, PartialEq)]
+enum ConfigUpdate {
+    Threshold(u8),
+    ClearAlert,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    Idle,
+    ReadSoc,
+    ReadVoltage,
+    ReadChargeRate,
+    /// Reading the state of charge, having read this charge rate.
+    ReadChargeSoc(i16),
+    ReadConfig(ConfigUpdate),
+    WriteConfig(ConfigUpdate),
+}
+
+pub struct MAX17048<'a> {
+    i2c: &'a dyn i2c::I2CDevice,
+    alert_pin: Option<&'a dyn gpio::InterruptPin<'a>>,
+    state: Cell<State>,
+    /// The alert pin fired while another operation was in progress.
+    alert_pending: Cell<bool>,
+    buffer: TakeCell<'static, [u8]>,
+    client: OptionalCell<&'a dyn BatteryClient>,
+}
+
+impl<'a> MAX17048<'a> {
+    pub fn new(
+        i2c: &'a dyn i2c::I2CDevice,
+        alert_pin: Option<&'a dyn gpio::InterruptPin<'a>>,
+        buffer: &'static mut [u8],
+    ) -> MAX17048<'a> {
+        MAX17048 {
+            i2c: i2c,
+            alert_pin: alert_pin,
+            state: Cell::new(State::Idle),
+            alert_pending: Cell::new(false),
+            buffer: TakeCell::new(buffer),
+            client: OptionalCell::empty(),
+        }
+    }
+
+    /// Start reading `register`, moving to `state` once the read is issued.
+    fn read_register(&self, register: Registers, state: State) -> ReturnCode {
+        if self.state.get() != State::Idle {
+            return ReturnCode::EBUSY;
+        }
+        self.buffer.take().map_or(ReturnCode::ENOMEM, |buffer| {
+            self.i2c.enable();
+            buffer[0] = register as u8;
+            self.i2c.write_read(buffer, 1, 2);
+            self.state.set(state);
+            ReturnCode::SUCCESS
+        })
+    }
+
+    fn finish(&self, buffer: &'static mut [u8]) {
+        self.buffer.replace(buffer);
+        self.i2c.disable();
+        self.state.set(State::Idle);
+    }
+
+    /// Handle an alert that arrived while the gauge was busy.
+    fn check_pending_alert(&self) {
+        if self.alert_pending.get() && self.state.get() == State::Idle {
+            self.alert_pending.set(false);
+            self.read_register(
+                Registers::Config,
+                State::ReadConfig(ConfigUpdate::ClearAlert),
+            );
+        }
+    }
+}
+
+impl i2c::I2CClient for MAX17048<'_> {
+    fn command_complete(&self, buffer: &'static mut [u8], error: i2c::Error) {
+        let result = if error == i2c::Error::CommandComplete {
+            ReturnCode::SUCCESS
+        } else {
+            ReturnCode::ENOACK
+        };
+        let value = u16::from_be_bytes([buffer[0], buffer[1]]);
+
+        match self.state.get() {
+            State::Idle => {
+                self.buffer.replace(buffer);
+            }
+            State::ReadSoc => {
+                self.finish(buffer);
+                let soc = ((value as u32 * 100) / 256).min(10000) as u16;
+                self.client.map(|client| {
+                    client.state_of_charge(if result == ReturnCode::SUCCESS {
+                        Ok(soc)
+                    } else {
+                        Err(result)
+                    })
+                });
+            }
+            State::ReadVoltage => {
+                self.finish(buffer);
+                let millivolts = (value as u32 * 78125) / 1_000_000;
+                self.client.map(|client| {
+                    client.voltage(if result == ReturnCode::SUCCESS {
+                        Ok(millivolts)
+                    } else {
+                        Err(result)
+                    })
+                });
+            }
+            State::ReadChargeRate => {
+                if result == ReturnCode::SUCCESS {
+                    buffer[0] = Registers::Soc as u8;
+                    self.i2c.write_read(buffer, 1, 2);
+                    self.state.set(State::ReadChargeSoc(value as i16));
+                } else {
+                    self.finish(buffer);
+                    self.client.map(|client| client.charging_state(Err(result)));
+                }
+            }
+            State::ReadChargeSoc(rate) => {
+                self.finish(buffer);
+                let state = if rate > 0 {
+                    ChargingState::Charging
+                } else if rate < 0 {
+                    ChargingState::Discharging
+                } else if value >= 100 * 256 {
+                    ChargingState::Full
+                } else {
+                    ChargingState::Unknown
+                };
+                self.client.map(|client| {
+                    client.charging_state(if result == ReturnCode::SUCCESS {
+                        Ok(state)
+                    } else {
+                        Err(result)
+                    })
+                });
+            }
+            State::ReadConfig(update) => {
+                let low = match update {
+                    ConfigUpdate::Threshold(percent) => {
+                        (buffer[1] & !(CONFIG_ALRT | CONFIG_ATHD_MASK)) | (32 - percent)
+                    }
+                    ConfigUpdate::ClearAlert => buffer[1] & !CONFIG_ALRT,
+                };
+                let alerted = buffer[1] & CONFIG_ALRT != 0;
+                if result != ReturnCode::SUCCESS || (update == ConfigUpdate::ClearAlert && !alerted)
+                {
+                    self.finish(buffer);
+                    if let ConfigUpdate::Threshold(_) = update {
+                        self.client.map(|client| client.threshold_set(result));
+                    }
+                } else {
+                    buffer[2] = low;
+                    buffer[1] = buffer[0];
+                    buffer[0] = Registers::Config as u8;
+                    self.i2c.write(buffer, 3);
+                    self.state.set(State::WriteConfig(update));
+                }
+            }
+            State::WriteConfig(update) => {
+                self.finish(buffer);
+                match update {
+                    ConfigUpdate::Threshold(_) => {
+                        if result == ReturnCode::SUCCESS {
+                            self.alert_pin.map(|pin| {
+                                pin.make_input();
+                                pin.enable_interrupts(gpio::InterruptEdge::FallingEdge);
+                            });
+                        }
+                        self.client.map(|client| client.threshold_set(result));
+                    }
+                    ConfigUpdate::ClearAlert => {
+                        self.client.map(|client| client.low_battery());
+                    }
+                }
+            }
+        }
+        self.check_pending_alert();
+    }
+}
+
+impl gpio::Client for MAX17048<'_> {
+    fn fired(&self) {
+        self.alert_pending.set(true);
+        self.check_pending_alert();
+    }
+}
+
+impl<'a> Battery<'a> for MAX17048<'a> {
+    fn set_client(&self, client: &'a dyn BatteryClient) {
+        self.client.set(client);
+    }
+
+    fn read_state_of_charge(&self) -> ReturnCode {
+        self.read_register(Registers::Soc, State::ReadSoc)
+    }
+
+    fn read_voltage(&self) -> ReturnCode {
+        self.read_register(Registers::VCell, State::ReadVoltage)
+    }
+
+    fn read_charging_state(&self) -> ReturnCode {
+        self.read_register(Registers::CRate, State::ReadChargeRate)
+    }
+
+    /// The MAX17048 supports thresholds from 1 to 32 percent.
+    fn set_low_battery_threshold(&self, percent: u8) -> ReturnCode {
+        if percent < 1 || percent > 32 {
+            return ReturnCode::EINVAL;
+        }
+        self.read_register(
+            Registers::Config,
+            State::ReadConfig(ConfigUpdate::Threshold(percent)),
+        )
+    }
+}
@@ -0,0 +1,355 @@
+//! Flash-backed monotonic counters.
+//!
+//! `MonotonicCounterStore` keeps a small table of 32-bit counters in a region
+//! of nonvolatile storage and caches it in RAM. Each `MonotonicCounterUser` is
+//! one slot of that table and implements `hil::monotonic_counter`, so each
+//! kernel client (for example the OTA updater's firmware version, or a
+//! capsule's replay counter) gets its own counter. Slots are chosen by the
+//! board, so they stay the same across reboots.
+//!
+//! Updates are written through to storage before the new value is reported
+//! or returned by `get()`. Only one write is outstanding at a time; updates
+//! from other users are queued. Counters stop at `MAX_VALUE`, so that they
+//! never hold the value of erased storage (all `0xFF`).
+//!
+//! The table is kept in two slots, and each update writes the whole table to
+//! the slot that does not hold the current one, so that power lost during a
+//! write leaves the previous table intact. A slot is `SLOT_LEN` bytes:
+//!
+//! ```text
+//! 0       4          8
+//! +-------+----------+---------------------------+
+//! | check | sequence | MAX_COUNTERS counters     |
+//! +-------+----------+---------------------------+
+//! ```
+//!
+//! `check` is the start of the SHA-256 hash of the bytes after it, so that a
+//! partly written slot is ignored, and at boot the valid slot with the later
+//! `sequence` is loaded. Storage where neither slot is valid reads as all
+//! counters zero. The two slots should be in different flash pages, so that
+//! erasing the page of one never loses the other.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//! # use capsules::monotonic_counter::{MonotonicCounterStore, MonotonicCounterUser};
+//!
+//! let counter_store = static_init!(
+//!     MonotonicCounterStore<'static>,
+//!     MonotonicCounterStore::new(
+//!         nv_to_page,
+//!         [0x3E000, 0x3F000],
+//!         &mut capsules::monotonic_counter::BUF
+//!     )
+//! );
+//! nv_to_page.set_client(counter_store);
+//!
+//! let firmware_version = static_init!(
+//!     MonotonicCounterUser<'static>,
+//!     MonotonicCounterUser::new(counter_store, 0)
+//! );
+//! firmware_version.setup();
+//! counter_store.init();
+//! ```
+
+use crate::net::dtls::sha256::Sha256;
+use core::cell::Cell;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::{List, ListLink, ListNode};
+use kernel::hil::monotonic_counter::{Client, MonotonicCounter};
+use kernel::hil::nonvolatile_storage::{NonvolatileStorage, NonvolatileStorageClient};
+use kernel::ReturnCode;
+
+/// Number of counters in the table.
+pub const MAX_COUNTERS: usize = 8;
+
+/// The highest value a counter can reach.
+pub const MAX_VALUE: u32 = 0xFFFF_FFFE;
+
+/// Size of a slot in storage, and of the buffer the store needs.
+pub const SLOT_LEN: usize = 8 + MAX_COUNTERS * 4;
+pub const BUF_LEN: usize = SLOT_LEN;
+
+pub static mut BUF: [u8; BUF_LEN] = [0; BUF_LEN];
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    Uninitialized,
+    /// Reading the slot with this index.
+    Loading(usize),
+    Idle,
+    Writing,
+}
+
+fn check(slot: &[u8]) -> u32 {
+    let mut hash = Sha256::new();
+    hash.update(&slot[4..SLOT_LEN]);
+    let hash = hash.finish();
+    u32::from_be_bytes([hash[0], hash[1], hash[2], hash[3]])
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+/// Returns the sequence number and counters in `slot`, if it is valid.
+fn decode(slot: &[u8]) -> Option<(u32, [u32; MAX_COUNTERS])> {
+    if slot.len() < SLOT_LEN || read_u32(&slot[0..4]) != check(slot) {
+        return None;
+    }
+    let mut values = [0; MAX_COUNTERS];
+    for (value, chunk) in values.iter_mut().zip(slot[8..SLOT_LEN].chunks(4)) {
+        *value = read_u32(chunk);
+    }
+    Some((read_u32(&slot[4..8]), values))
+}
+
+fn encode(slot: &mut [u8], sequence: u32, values: &[u32; MAX_COUNTERS]) {
+    slot[4..8].copy_from_slice(&sequence.to_le_bytes());
+    for (chunk, value) in slot[8..SLOT_LEN].chunks_mut(4).zip(values.iter()) {
+        chunk.copy_from_slice(&value.to_le_bytes());
+    }
+    let check = check(slot);
+    slot[0..4].copy_from_slice(&check.to_le_bytes());
+}
+
+pub struct MonotonicCounterStore<'a> {
+    storage: &'a dyn NonvolatileStorage<'a>,
+    /// Addresses of the two slots in storage.
+    addresses: [usize; 2],
+    buffer: TakeCell<'a, [u8]>,
+    /// Committed counter values, their sequence number and the slot that
+    /// holds them. `slot` is `None` while no slot is valid.
+    values: Cell<[u32; MAX_COUNTERS]>,
+    sequence: Cell<u32>,
+    slot: Cell<Option<usize>>,
+    state: Cell<State>,
+    users: List<'a, MonotonicCounterUser<'a>>,
+    /// The user whose update is being written, and the value it is writing.
+    inflight: OptionalCell<(&'a MonotonicCounterUser<'a>, u32)>,
+}
+
+impl<'a> MonotonicCounterStore<'a> {
+    pub fn new(
+        storage: &'a dyn NonvolatileStorage<'a>,
+        addresses: [usize; 2],
+        buffer: &'a mut [u8],
+    ) -> MonotonicCounterStore<'a> {
+        MonotonicCounterStore {
+            storage: storage,
+            addresses: addresses,
+            buffer: TakeCell::new(buffer),
+            values: Cell::new([0; MAX_COUNTERS]),
+            sequence: Cell::new(0),
+            slot: Cell::new(None),
+            state: Cell::new(State::Uninitialized),
+            users: List::new(),
+            inflight: OptionalCell::empty(),
+        }
+    }
+
+    /// Load the counters from storage. Must be called once during board
+    /// setup; counters report `EOFF` until loading completes.
+    pub fn init(&self) -> ReturnCode {
+        if self.state.get() != State::Uninitialized {
+            return ReturnCode::EALREADY;
+        }
+        self.buffer.take().map_or(ReturnCode::ENOMEM, |buffer| {
+            if buffer.len() < BUF_LEN {
+                self.buffer.replace(buffer);
+                return ReturnCode::ESIZE;
+            }
+            self.load(buffer, 0)
+        })
+    }
+
+    fn load(&self, buffer: &'a mut [u8], slot: usize) -> ReturnCode {
+        let rcode = self.storage.read(buffer, self.addresses[slot], SLOT_LEN);
+        if rcode == ReturnCode::SUCCESS {
+            self.state.set(State::Loading(slot));
+        }
+        rcode
+    }
+
+    fn value(&self, index: usize) -> u32 {
+        self.values.get()[index]
+    }
+
+    /// The slot the next table is written to.
+    fn next_slot(&self) -> usize {
+        self.slot.get().map_or(0, |slot| 1 - slot)
+    }
+
+    /// Write the next queued update, if any, to storage.
+    fn do_next_op(&self) {
+        if self.state.get() != State::Idle {
+            return;
+        }
+        let user = match self.users.iter().find(|u| u.pending.is_some()) {
+            Some(user) => user,
+            None => return,
+        };
+        let target = user.pending.take().unwrap_or(0);
+
+        self.buffer.take().map(|buffer| {
+            let mut values = self.values.get();
+            values[user.index] = target;
+            encode(buffer, self.sequence.get().wrapping_add(1), &values);
+
+            let address = self.addresses[self.next_slot()];
+            let rcode = self.storage.write(buffer, address, SLOT_LEN);
+            if rcode == ReturnCode::SUCCESS {
+                self.state.set(State::Writing);
+                self.inflight.set((user, target));
+            } else {
+                user.client
+                    .map(|client| client.counter_updated(rcode, self.value(user.index)));
+            }
+        });
+    }
+}
+
+impl<'a> NonvolatileStorageClient<'a> for MonotonicCounterStore<'a> {
+    fn read_done(&self, buffer: &'a mut [u8], length: usize) {
+        let slot = match self.state.get() {
+            State::Loading(slot) => slot,
+            _ => {
+                self.buffer.replace(buffer);
+                return;
+            }
+        };
+        if let Some((sequence, values)) = decode(&buffer[..length]) {
+            // Sequence numbers are compared as in serial number arithmetic,
+            // so that they may wrap around.
+            let later = self.slot.get().map_or(true, |_| {
+                (sequence.wrapping_sub(self.sequence.get()) as i32) > 0
+            });
+            if later {
+                self.values.set(values);
+                self.sequence.set(sequence);
+                self.slot.set(Some(slot));
+            }
+        }
+        if slot == 0 {
+            if self.load(buffer, 1) != ReturnCode::SUCCESS {
+                // The buffer is lost. Counters that may be stale are never
+                // reported, so they stay unloaded.
+                self.state.set(State::Uninitialized);
+            }
+            return;
+        }
+        self.buffer.replace(buffer);
+        self.state.set(State::Idle);
+        self.do_next_op();
+    }
+
+    fn write_done(&self, buffer: &'a mut [u8], length: usize) {
+        self.buffer.replace(buffer);
+        self.state.set(State::Idle);
+        self.inflight.take().map(|(user, target)| {
+            let result = if length == SLOT_LEN {
+                let mut values = self.values.get();
+                values[user.index] = target;
+                self.values.set(values);
+                self.sequence.set(self.sequence.get().wrapping_add(1));
+                self.slot.set(Some(self.next_slot()));
+                ReturnCode::SUCCESS
+            } else {
+                ReturnCode::FAIL
+            };
+            user.client
+                .map(|client| client.counter_updated(result, self.value(user.index)));
+        });
+        self.do_next_op();
+    }
+}
+
+/// A single counter of a `MonotonicCounterStore`.
+pub struct MonotonicCounterUser<'a> {
+    store: &'a MonotonicCounterStore<'a>,
+    next: ListLink<'a, MonotonicCounterUser<'a>>,
+    client: OptionalCell<&'a dyn Client>,
+    index: usize,
+    /// Value a queued update will set the counter to.
+    pending: OptionalCell<u32>,
+}
+
+impl<'a> ListNode<'a, MonotonicCounterUser<'a>> for MonotonicCounterUser<'a> {
+    fn next(&'a self) -> &'a ListLink<'a, MonotonicCounterUser<'a>> {
+        &self.next
+    }
+}
+
+impl<'a> MonotonicCounterUser<'a> {
+    /// Create a user of the counter in slot `index`, which must be less than
+    /// `MAX_COUNTERS` and not used by any other user.
+    pub fn new(store: &'a MonotonicCounterStore<'a>, index: usize) -> MonotonicCounterUser<'a> {
+        MonotonicCounterUser {
+            store: store,
+            next: ListLink::empty(),
+            client: OptionalCell::empty(),
+            index: index,
+            pending: OptionalCell::empty(),
+        }
+    }
+
+    /// Register this counter with the store. Must be called once during board
+    /// setup.
+    pub fn setup(&'a self) {
+        self.store.users.push_head(self);
+    }
+
+    fn busy(&self) -> bool {
+        self.pending.is_some()
+            || self
+                .store
+                .inflight
+                .map_or(false, |(user, _)| user.index == self.index)
+    }
+}
+
+impl<'a> MonotonicCounter<'a> for MonotonicCounterUser<'a> {
+    fn set_client(&self, client: &'a dyn Client) {
+        self.client.set(client);
+    }
+
+    fn get(&self) -> Result<u32, ReturnCode> {
+        if self.index >= MAX_COUNTERS {
+            return Err(ReturnCode::EINVAL);
+        }
+        match self.store.state.get() {
+            State::Uninitialized | State::Loading(_) => Err(ReturnCode::EOFF),
+            State::Idle | State::Writing => Ok(self.store.value(self.index)),
+        }
+    }
+
+    fn increment(&self) -> ReturnCode {
+        match self.get() {
+            Ok(value) if value >= MAX_VALUE => ReturnCode::ESIZE,
+            Ok(value) => self.advance_to(value + 1),
+            Err(rcode) => rcode,
+        }
+    }
+
+    fn advance_to(&self, value: u32) -> ReturnCode {
+        let current = match self.get() {
+            Ok(current) => current,
+            Err(rcode) => return rcode,
+        };
+        if self.busy() {
+            ReturnCode::EBUSY
+        } else if value < current {
+            ReturnCode::EINVAL
+        } else if value > MAX_VALUE {
+            ReturnCode::ESIZE
+        } else if value == current {
+            ReturnCode::EALREADY
+        } else {
+            self.pending.set(value);
+            self.store.do_next_op();
+            ReturnCode::SUCCESS
+        }
+    }
+}
@@ -0,0 +1,387 @@
+//! Building and parsing NFC Data Exchange Format (NDEF) messages.
+//!
+//! An NDEF message is a sequence of records, each with a type name format
+//! (TNF), a type, an optional ID and a payload. `MessageBuilder` appends
+//! records to a message in a buffer, keeping the message begin and message
+//! end flags right, with helpers for the records phones act on when they
+//! read a tag: URIs, text, MIME data and WiFi credentials. `records()`
+//! walks the records of a received message. Chunked records are passed up
+//! as they are, without being joined.
+//!
+//! ```rust
+//! let mut builder = ndef::MessageBuilder::new(buffer, 0).unwrap();
+//! builder.uri(b"https://www.tockos.org");
+//! builder.text(b"en", b"Tock");
+//! let len = builder.len();
+//! ```
+
+use kernel::ReturnCode;
+
+pub const TNF_EMPTY: u8 = 0x00;
+pub const TNF_WELL_KNOWN: u8 = 0x01;
+pub const TNF_MEDIA: u8 = 0x02;
+pub const TNF_ABSOLUTE_URI: u8 = 0x03;
+pub const TNF_EXTERNAL: u8 = 0x04;
+pub const TNF_UNKNOWN: u8 = 0x05;
+pub const TNF_UNCHANGED: u8 = 0x06;
+
+const FLAG_MB: u8 = 0x80;
+const FLAG_ME: u8 = 0x40;
+const FLAG_CF: u8 = 0x20;
+const FLAG_SR: u8 = 0x10;
+const FLAG_IL: u8 = 0x08;
+const TNF_MASK: u8 = 0x07;
+
+const TYPE_URI: &[u8] = b"U";
+const TYPE_TEXT: &[u8] = b"T";
+const TYPE_WIFI: &[u8] = b"application/vnd.wfa.wsc";
+
+/// The URI prefixes a URI record abbreviates, indexed by their code.
+pub const URI_PREFIXES: [&str; 36] = [
+    "",
+    "http://www.",
+    "https://www.",
+    "http://",
+    "https://",
+    "tel:",
+    "mailto:",
+    "ftp://anonymous:anonymous@",
+    "ftp://ftp.",
+    "ftps://",
+    "sftp://",
+    "smb://",
+    "nfs://",
+    "ftp://",
+    "dav://",
+    "news:",
+    "telnet://",
+    "imap:",
+    "rtsp://",
+    "urn:",
+    "pop:",
+    "sip:",
+    "sips:",
+    "tftp:",
+    "btspp://",
+    "btl2cap://",
+    "btgoep://",
+    "tcpobex://",
+    "irdaobex://",
+    "file://",
+    "urn:epc:id:",
+    "urn:epc:tag:",
+    "urn:epc:pat:",
+    "urn:epc:raw:",
+    "urn:epc:",
+    "urn:nfc:",
+];
+
+/// Attributes of the WiFi Simple Configuration credential.
+mod wsc {
+    pub const CREDENTIAL: u16 = 0x100e;
+    pub const NETWORK_INDEX: u16 = 0x1026;
+    pub const SSID: u16 = 0x1045;
+    pub const AUTH_TYPE: u16 = 0x1003;
+    pub const ENCRYPTION_TYPE: u16 = 0x100f;
+    pub const NETWORK_KEY: u16 = 0x1027;
+    pub const MAC_ADDRESS: u16 = 0x1020;
+
+    pub const AUTH_OPEN: u16 = 0x0001;
+    pub const AUTH_WPA2_PERSONAL: u16 = 0x0020;
+    pub const ENCRYPTION_NONE: u16 = 0x0001;
+    pub const ENCRYPTION_AES: u16 = 0x0008;
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Record<'b> {
+    pub tnf: u8,
+    /// Set for all but the last chunk of a chunked payload.
+    pub chunked: bool,
+    pub record_type: &'b [u8],
+    pub id: &'b [u8],
+    pub payload: &'b [u8],
+}
+
+impl Record<'_> {
+    /// The prefix and the rest of a URI record's URI.
+    pub fn uri(&self) -> Option<(&'static str, &[u8])> {
+        if self.tnf != TNF_WELL_KNOWN || self.record_type != TYPE_URI || self.payload.is_empty() {
+            return None;
+        }
+        URI_PREFIXES
+            .get(self.payload[0] as usize)
+            .map(|prefix| (*prefix, &self.payload[1..]))
+    }
+
+    /// The language code and the text of a text record.
+    pub fn text(&self) -> Option<(&[u8], &[u8])> {
+        if self.tnf != TNF_WELL_KNOWN || self.record_type != TYPE_TEXT || self.payload.is_empty() {
+            return None;
+        }
+        let lang_len = (self.payload[0] & 0x3f) as usize;
+        if 1 + lang_len > self.payload.len() {
+            return None;
+        }
+        Some((
+            &self.payload[1..1 + lang_len],
+            &self.payload[1 + lang_len..],
+        ))
+    }
+}
+
+/// Parse the record at `offset`, returning it with the offset of the next.
+fn parse_record(message: &[u8], offset: usize) -> Option<(Record, usize)> {
+    let header = *message.get(offset)?;
+    let type_len = *message.get(offset + 1)? as usize;
+    let mut off = offset + 2;
+    let payload_len = if header & FLAG_SR != 0 {
+        off += 1;
+        *message.get(off - 1)? as usize
+    } else {
+        off += 4;
+        let bytes = message.get(off - 4..off)?;
+        u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize
+    };
+    let id_len = if header & FLAG_IL != 0 {
+        off += 1;
+        *message.get(off - 1)? as usize
+    } else {
+        0
+    };
+    let record_type = message.get(off..off + type_len)?;
+    off += type_len;
+    let id = message.get(off..off + id_len)?;
+    off += id_len;
+    let payload = message.get(off..off.checked_add(payload_len)?)?;
+    off += payload_len;
+    Some((
+        Record {
+            tnf: header & TNF_MASK,
+            chunked: header & FLAG_CF != 0,
+            record_type: record_type,
+            id: id,
+            payload: payload,
+        },
+        off,
+    ))
+}
+
+/// An iterator over the records of a message. It stops at the end of the
+/// message or at a malformed record.
+pub struct Records<'b> {
+    message: &'b [u8],
+    offset: usize,
+    done: bool,
+}
+
+impl<'b> Iterator for Records<'b> {
+    type Item = Record<'b>;
+
+    fn next(&mut self) -> Option<Record<'b>> {
+        if self.done {
+            return None;
+        }
+        let last = self
+            .message
+            .get(self.offset)
+            .map_or(true, |h| h & FLAG_ME != 0);
+        match parse_record(self.message, self.offset) {
+            Some((record, next)) => {
+                self.offset = next;
+                self.done = last;
+                Some(record)
+            }
+            None => {
+                self.done = true;
+                None
+            }
+        }
+    }
+}
+
+pub fn records(message: &[u8]) -> Records {
+    Records {
+        message: message,
+        offset: 0,
+        done: false,
+    }
+}
+
+/// The number of records in `message`, or `None` if it is not a well
+/// formed message: the first record begins the message, the last ends it,
+/// and the last ends where the message does.
+pub fn count_records(message: &[u8]) -> Option<usize> {
+    let mut offset = 0;
+    let mut count = 0;
+    loop {
+        let header = *message.get(offset)?;
+        if (header & FLAG_MB != 0) != (offset == 0) {
+            return None;
+        }
+        let (_, next) = parse_record(message, offset)?;
+        count += 1;
+        offset = next;
+        if header & FLAG_ME != 0 {
+            return if offset == message.len() {
+                Some(count)
+            } else {
+                None
+            };
+        }
+    }
+}
+
+pub struct MessageBuilder<'b> {
+    buffer: &'b mut [u8],
+    len: usize,
+    /// The offset of the last record's header.
+    last: Option<usize>,
+}
+
+impl<'b> MessageBuilder<'b> {
+    /// Start a message in `buffer`, or go on with the `len` byte message
+    /// already at its start. Returns `None` if that message is malformed.
+    pub fn new(buffer: &'b mut [u8], len: usize) -> Option<MessageBuilder<'b>> {
+        let mut last = None;
+        let mut offset = 0;
+        while offset < len {
+            let (_, next) = parse_record(&buffer[..len], offset)?;
+            last = Some(offset);
+            offset = next;
+        }
+        Some(MessageBuilder {
+            buffer: buffer,
+            len: len,
+            last: last,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn message(&self) -> &[u8] {
+        &self.buffer[..self.len]
+    }
+
+    /// Append a record whose payload is the concatenation of `payload`.
+    /// Returns `ESIZE` if it does not fit.
+    pub fn record(&mut self, tnf: u8, record_type: &[u8], payload: &[&[u8]]) -> ReturnCode {
+        let payload_len: usize = payload.iter().map(|part| part.len()).sum();
+        let short = payload_len <= 0xff;
+        let header_len = if short { 3 } else { 6 };
+        if record_type.len() > 0xff
+            || self.len + header_len + record_type.len() + payload_len > self.buffer.len()
+        {
+            return ReturnCode::ESIZE;
+        }
+
+        let mut header = (tnf & TNF_MASK) | FLAG_ME;
+        match self.last {
+            Some(last) => self.buffer[last] &= !FLAG_ME,
+            None => header |= FLAG_MB,
+        }
+        let start = self.len;
+        let mut off = start;
+        self.buffer[off] = if short { header | FLAG_SR } else { header };
+        self.buffer[off + 1] = record_type.len() as u8;
+        off += 2;
+        if short {
+            self.buffer[off] = payload_len as u8;
+            off += 1;
+        } else {
+            self.buffer[off..off + 4].copy_from_slice(&(payload_len as u32).to_be_bytes());
+            off += 4;
+        }
+        self.buffer[off..off + record_type.len()].copy_from_slice(record_type);
+        off += record_type.len();
+        for part in payload {
+            self.buffer[off..off + part.len()].copy_from_slice(part);
+            off += part.len();
+        }
+        self.len = off;
+        self.last = Some(start);
+        ReturnCode::SUCCESS
+    }
+
+    /// Append a URI record, abbreviating the longest prefix it can.
+    pub fn uri(&mut self, uri: &[u8]) -> ReturnCode {
+        let (code, prefix) = URI_PREFIXES
+            .iter()
+            .enumerate()
+            .filter(|(_, prefix)| uri.starts_with(prefix.as_bytes()))
+            .max_by_key(|(_, prefix)| prefix.len())
+            .unwrap_or((0, &""));
+        self.record(
+            TNF_WELL_KNOWN,
+            TYPE_URI,
+            &[&[code as u8], &uri[prefix.len()..]],
+        )
+    }
+
+    /// Append a UTF-8 text record in the language `lang`, such as `b"en"`.
+    pub fn text(&mut self, lang: &[u8], text: &[u8]) -> ReturnCode {
+        if lang.len() > 0x3f {
+            return ReturnCode::EINVAL;
+        }
+        self.record(
+            TNF_WELL_KNOWN,
+            TYPE_TEXT,
+            &[&[lang.len() as u8], lang, text],
+        )
+    }
+
+    /// Append a record of MIME type `mime_type`.
+    pub fn mime(&mut self, mime_type: &[u8], data: &[u8]) -> ReturnCode {
+        self.record(TNF_MEDIA, mime_type, &[data])
+    }
+
+    /// Append a WiFi Simple Configuration credential that phones offer to
+    /// join: a WPA2 network with passphrase `key`, or an open network if
+    /// `key` is empty.
+    pub fn wifi(&mut self, ssid: &[u8], key: &[u8]) -> ReturnCode {
+        if ssid.len() > 32 || key.len() > 64 {
+            return ReturnCode::EINVAL;
+        }
+        let (auth, encryption) = if key.is_empty() {
+            (wsc::AUTH_OPEN, wsc::ENCRYPTION_NONE)
+        } else {
+            (wsc::AUTH_WPA2_PERSONAL, wsc::ENCRYPTION_AES)
+        };
+        let attribute = |id: u16, len: usize| {
+            let (id, len) = (id.to_be_bytes(), (len as u16).to_be_bytes());
+            [id[0], id[1], len[0], len[1]]
+        };
+        let index = attribute(wsc::NETWORK_INDEX, 1);
+        let ssid_header = attribute(wsc::SSID, ssid.len());
+        let auth_header = attribute(wsc::AUTH_TYPE, 2);
+        let encryption_header = attribute(wsc::ENCRYPTION_TYPE, 2);
+        let key_header = attribute(wsc::NETWORK_KEY, key.len());
+        let mac_header = attribute(wsc::MAC_ADDRESS, 6);
+        let credential_len = 5 + 4 + ssid.len() + 6 + 6 + 4 + key.len() + 10;
+        self.record(
+            TNF_MEDIA,
+            TYPE_WIFI,
+            &[
+                &attribute(wsc::CREDENTIAL, credential_len),
+                &index,
+                &[1],
+                &ssid_header,
+                ssid,
+                &auth_header,
+                &auth.to_be_bytes(),
+                &encryption_header,
+                &encryption.to_be_bytes(),
+                &key_header,
+                key,
+                &mac_header,
+                // Any access point with the SSID.
+                &[0xff; 6],
+            ],
+        )
+    }
+}
@@ -0,0 +1,384 @@
+//! This file contains an encoder and decoder for CBOR (RFC 8949), the
+//! compact binary data format that CoAP based protocols such as LwM2M use
+//! for their payloads. Like the other encoders of the stack, the functions
+//! here work in place on a buffer and return an `SResult`, so that writing a
+//! payload is a chain of `enc_consume!` calls and nothing is allocated.
+//!
+//! Each data item starts with a head holding its major type and an argument,
+//! which is the value of an integer, the length of a string or the number of
+//! items in an array or map. The items of an array or map follow their head,
+//! so an array is encoded by encoding its head with `encode_array` and then
+//! each of its items. `decode_item` likewise returns one head at a time, and
+//! `skip_item` skips an item along with all the items nested in it.
+//!
+//! Only definite lengths are supported, which is what the encoder always
+//! writes, and of the floating-point numbers only those of single and double
+//! precision are decoded.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use capsules::{enc_consume, stream_done};
+//! # use capsules::net::cbor;
+//! # use capsules::net::stream::SResult;
+//!
+//! // {"n": "temp", "v": 21}
+//! fn encode_reading(buf: &mut [u8], value: i64) -> SResult {
+//!     let off = enc_consume!(buf; cbor::encode_map, 2);
+//!     let off = enc_consume!(buf, off; cbor::encode_text, "n");
+//!     let off = enc_consume!(buf, off; cbor::encode_text, "temp");
+//!     let off = enc_consume!(buf, off; cbor::encode_text, "v");
+//!     let off = enc_consume!(buf, off; cbor::encode_int, value);
+//!     stream_done!(off);
+//! }
+//! ```
+
+use crate::net::stream::{self, SResult};
+
+/// The major types, from the top 3 bits of the head.
+mod major {
+    pub const UNSIGNED: u8 = 0;
+    pub const NEGATIVE: u8 = 1;
+    pub const BYTES: u8 = 2;
+    pub const TEXT: u8 = 3;
+    pub const ARRAY: u8 = 4;
+    pub const MAP: u8 = 5;
+    pub const TAG: u8 = 6;
+    pub const SIMPLE: u8 = 7;
+}
+
+/// The simple values of major type 7 with a meaning of their own.
+const SIMPLE_FALSE: u8 = 20;
+const SIMPLE_TRUE: u8 = 21;
+const SIMPLE_NULL: u8 = 22;
+const SIMPLE_UNDEFINED: u8 = 23;
+
+/// The additional information of a head whose argument follows in 1, 2, 4 or
+/// 8 bytes. A head with a smaller additional information holds the argument
+/// itself.
+const ARG_1: u8 = 24;
+const ARG_2: u8 = 25;
+const ARG_4: u8 = 26;
+const ARG_8: u8 = 27;
+
+/// A decoded data item. Strings borrow from the buffer they are decoded
+/// from, and arrays and maps hold the number of items that follow them, with
+/// a map holding the number of key and value pairs.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Item<'a> {
+    Unsigned(u64),
+    /// The integer `-1 - n`.
+    Negative(u64),
+    Bytes(&'a [u8]),
+    Text(&'a str),
+    Array(usize),
+    Map(usize),
+    /// A tag, which applies to the item that follows it.
+    Tag(u64),
+    Bool(bool),
+    Null,
+    Undefined,
+    Float(f64),
+}
+
+impl Item<'_> {
+    /// The value of an integer item, if it is one that fits in an `i64`.
+    pub fn as_i64(&self) -> Option<i64> {
+        match *self {
+            Item::Unsigned(value) if value <= i64::max_value() as u64 => Some(value as i64),
+            Item::Negative(value) if value <= i64::max_value() as u64 => Some(-1 - value as i64),
+            _ => None,
+        }
+    }
+}
+
+/// Encodes a head with the major type `major_type` and the argument `value`,
+/// in as few bytes as the value fits in.
+fn encode_head(buf: &mut [u8], major_type: u8, value: u64) -> SResult {
+    let first = major_type << 5;
+    if value < ARG_1 as u64 {
+        stream::encode_u8(buf, first | value as u8)
+    } else if value <= u8::max_value() as u64 {
+        stream_len_cond!(buf, 2);
+        buf[0] = first | ARG_1;
+        buf[1] = value as u8;
+        stream_done!(2);
+    } else if value <= u16::max_value() as u64 {
+        stream_len_cond!(buf, 3);
+        buf[0] = first | ARG_2;
+        buf[1..3].copy_from_slice(&(value as u16).to_be_bytes());
+        stream_done!(3);
+    } else if value <= u32::max_value() as u64 {
+        stream_len_cond!(buf, 5);
+        buf[0] = first | ARG_4;
+        buf[1..5].copy_from_slice(&(value as u32).to_be_bytes());
+        stream_done!(5);
+    } else {
+        stream_len_cond!(buf, 9);
+        buf[0] = first | ARG_8;
+        buf[1..9].copy_from_slice(&value.to_be_bytes());
+        stream_done!(9);
+    }
+}
+
+pub fn encode_uint(buf: &mut [u8], value: u64) -> SResult {
+    encode_head(buf, major::UNSIGNED, value)
+}
+
+pub fn encode_int(buf: &mut [u8], value: i64) -> SResult {
+    if value < 0 {
+        // -1 - value cannot overflow, unlike -value.
+        encode_head(buf, major::NEGATIVE, !value as u64)
+    } else {
+        encode_head(buf, major::UNSIGNED, value as u64)
+    }
+}
+
+/// Encodes a byte string.
+pub fn encode_bytes(buf: &mut [u8], bytes: &[u8]) -> SResult {
+    let off = enc_consume!(buf; encode_head, major::BYTES, bytes.len() as u64);
+    let off = enc_consume!(buf, off; stream::encode_bytes, bytes);
+    stream_done!(off);
+}
+
+/// Encodes a text string.
+pub fn encode_text(buf: &mut [u8], text: &str) -> SResult {
+    let off = enc_consume!(buf; encode_head, major::TEXT, text.len() as u64);
+    let off = enc_consume!(buf, off; stream::encode_bytes, text.as_bytes());
+    stream_done!(off);
+}
+
+/// Encodes the head of an array of `len` items, which must be encoded next.
+pub fn encode_array(buf: &mut [u8], len: usize) -> SResult {
+    encode_head(buf, major::ARRAY, len as u64)
+}
+
+/// Encodes the head of a map of `len` pairs, whose keys and values must be
+/// encoded next, each key followed by its value.
+pub fn encode_map(buf: &mut [u8], len: usize) -> SResult {
+    encode_head(buf, major::MAP, len as u64)
+}
+
+/// Encodes a tag, which applies to the item encoded next.
+pub fn encode_tag(buf: &mut [u8], tag: u64) -> SResult {
+    encode_head(buf, major::TAG, tag)
+}
+
+pub fn encode_bool(buf: &mut [u8], value: bool) -> SResult {
+    let simple = if value { SIMPLE_TRUE } else { SIMPLE_FALSE };
+    encode_head(buf, major::SIMPLE, simple as u64)
+}
+
+pub fn encode_null(buf: &mut [u8]) -> SResult {
+    encode_head(buf, major::SIMPLE, SIMPLE_NULL as u64)
+}
+
+/// Encodes a floating-point number in double precision.
+pub fn encode_f64(buf: &mut [u8], value: f64) -> SResult {
+    stream_len_cond!(buf, 9);
+    buf[0] = (major::SIMPLE << 5) | ARG_8;
+    buf[1..9].copy_from_slice(&value.to_bits().to_be_bytes());
+    stream_done!(9);
+}
+
+/// Decodes a head, returning its major type, its additional information and
+/// its argument. The argument of a head with an additional information of
+/// `ARG_1` or more is read from the bytes that follow.
+fn decode_head(buf: &[u8]) -> SResult<(u8, u8, u64)> {
+    stream_len_cond!(buf, 1);
+    let major_type = buf[0] >> 5;
+    let info = buf[0] & 0x1f;
+    let arg_len = match info {
+        0..=23 => stream_done!(1, (major_type, info, info as u64)),
+        ARG_1 => 1,
+        ARG_2 => 2,
+        ARG_4 => 4,
+        ARG_8 => 8,
+        // Reserved values and indefinite lengths.
+        _ => stream_err!(),
+    };
+    stream_len_cond!(buf, 1 + arg_len);
+    let value = buf[1..1 + arg_len]
+        .iter()
+        .fold(0u64, |value, &byte| value << 8 | byte as u64);
+    stream_done!(1 + arg_len, (major_type, info, value));
+}
+
+/// Decodes the item at the start of `buf`. For an array, a map or a tag,
+/// this is only the head, and the items in it follow.
+pub fn decode_item(buf: &[u8]) -> SResult<Item> {
+    let (off, (major_type, info, value)) = dec_try!(buf; decode_head);
+    let item = match major_type {
+        major::UNSIGNED => Item::Unsigned(value),
+        major::NEGATIVE => Item::Negative(value),
+        major::BYTES | major::TEXT => {
+            let len = value as usize;
+            stream_cond!(len as u64 == value);
+            let end = stream_from_option!(off.checked_add(len));
+            stream_len_cond!(buf, end);
+            let bytes = &buf[off..end];
+            let item = if major_type == major::BYTES {
+                Item::Bytes(bytes)
+            } else {
+                Item::Text(stream_from_option!(core::str::from_utf8(bytes).ok()))
+            };
+            stream_done!(end, item);
+        }
+        major::ARRAY | major::MAP => {
+            let len = value as usize;
+            stream_cond!(len as u64 == value);
+            if major_type == major::ARRAY {
+                Item::Array(len)
+            } else {
+                Item::Map(len)
+            }
+        }
+        major::TAG => Item::Tag(value),
+        _ => match info {
+            ARG_4 => Item::Float(f32::from_bits(value as u32) as f64),
+            ARG_8 => Item::Float(f64::from_bits(value)),
+            // Half-precision floats, and simple values without a meaning.
+            ARG_2 => stream_err!(),
+            _ => match value as u8 {
+                SIMPLE_FALSE => Item::Bool(false),
+                SIMPLE_TRUE => Item::Bool(true),
+                SIMPLE_NULL => Item::Null,
+                SIMPLE_UNDEFINED => Item::Undefined,
+                _ => stream_err!(),
+            },
+        },
+    };
+    stream_done!(off, item);
+}
+
+/// Skips the item at the start of `buf`, along with the items of an array or
+/// map and the item a tag applies to.
+pub fn skip_item(buf: &[u8]) -> SResult {
+    let mut off = 0;
+    // The number of items still to skip.
+    let mut remaining: usize = 1;
+    while remaining > 0 {
+        let (next, item) = dec_try!(buf, off; decode_item);
+        off = next;
+        remaining -= 1;
+        let nested = match item {
+            Item::Array(len) => Some(len),
+            Item::Map(len) => len.checked_mul(2),
+            Item::Tag(_) => Some(1),
+            _ => Some(0),
+        };
+        remaining = stream_from_option!(nested.and_then(|nested| remaining.checked_add(nested)));
+    }
+    stream_done!(off);
+}
+
+/// Decodes an integer that fits in an `i64`.
+pub fn decode_int(buf: &[u8]) -> SResult<i64> {
+    let (off, item) = dec_try!(buf; decode_item);
+    stream_done!(off, stream_from_option!(item.as_i64()));
+}
+
+/// Decodes a byte string.
+pub fn decode_bytes(buf: &[u8]) -> SResult<&[u8]> {
+    match dec_try!(buf; decode_item) {
+        (off, Item::Bytes(bytes)) => stream_done!(off, bytes),
+        _ => stream_err!(),
+    }
+}
+
+/// Decodes a text string.
+pub fn decode_text(buf: &[u8]) -> SResult<&str> {
+    match dec_try!(buf; decode_item) {
+        (off, Item::Text(text)) => stream_done!(off, text),
+        _ => stream_err!(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn encoded<F: Fn(&mut [u8]) -> SResult>(encode: F, expected: &[u8]) {
+        let mut buf = [0; 16];
+        let (len, _) = encode(&mut buf).done().unwrap();
+        assert_eq!(&buf[..len], expected);
+        // Each encoder needs the whole item to fit.
+        assert_eq!(encode(&mut buf[..len - 1]).needed(), Some(len));
+    }
+
+    #[test]
+    fn encodes_rfc_examples() {
+        encoded(|buf| encode_uint(buf, 0), &[0x00]);
+        encoded(|buf| encode_uint(buf, 23), &[0x17]);
+        encoded(|buf| encode_uint(buf, 24), &[0x18, 0x18]);
+        encoded(|buf| encode_uint(buf, 1000), &[0x19, 0x03, 0xe8]);
+        encoded(
+            |buf| encode_uint(buf, 1_000_000),
+            &[0x1a, 0x00, 0x0f, 0x42, 0x40],
+        );
+        encoded(
+            |buf| encode_uint(buf, 1_000_000_000_000),
+            &[0x1b, 0x00, 0x00, 0x00, 0xe8, 0xd4, 0xa5, 0x10, 0x00],
+        );
+        encoded(|buf| encode_int(buf, -1), &[0x20]);
+        encoded(|buf| encode_int(buf, -1000), &[0x39, 0x03, 0xe7]);
+        encoded(
+            |buf| encode_int(buf, i64::min_value()),
+            &[0x3b, 0x7f, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff],
+        );
+        encoded(|buf| encode_bytes(buf, &[1, 2, 3, 4]), &[0x44, 1, 2, 3, 4]);
+        encoded(
+            |buf| encode_text(buf, "IETF"),
+            &[0x64, 0x49, 0x45, 0x54, 0x46],
+        );
+        encoded(|buf| encode_bool(buf, true), &[0xf5]);
+        encoded(encode_null, &[0xf6]);
+        encoded(
+            |buf| encode_f64(buf, 1.1),
+            &[0xfb, 0x3f, 0xf1, 0x99, 0x99, 0x99, 0x99, 0x99, 0x9a],
+        );
+        encoded(|buf| encode_tag(buf, 1), &[0xc1]);
+    }
+
+    #[test]
+    fn decodes_nested_items() {
+        // {"a": 1, "b": [2, 3]}
+        let buf = [0xa2, 0x61, 0x61, 0x01, 0x61, 0x62, 0x82, 0x02, 0x03, 0xf4];
+        assert_eq!(decode_item(&buf).done(), Some((1, Item::Map(2))));
+        assert_eq!(decode_text(&buf[1..]).done(), Some((2, "a")));
+        assert_eq!(decode_int(&buf[3..]).done(), Some((1, 1)));
+        assert_eq!(decode_item(&buf[6..]).done(), Some((1, Item::Array(2))));
+        assert_eq!(skip_item(&buf).done(), Some((9, ())));
+        assert_eq!(decode_item(&buf[9..]).done(), Some((1, Item::Bool(false))));
+
+        // A map cut off in the middle of its last item.
+        assert_eq!(skip_item(&buf[..8]).needed(), Some(9));
+        // A map with more pairs than the buffer could ever hold.
+        assert!(skip_item(&[0xbb, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]).is_err());
+    }
+
+    #[test]
+    fn decodes_rfc_examples() {
+        assert_eq!(
+            decode_item(&[0x3b, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]).done(),
+            Some((9, Item::Negative(u64::max_value())))
+        );
+        assert_eq!(decode_int(&[0x39, 0x03, 0xe7]).done(), Some((3, -1000)));
+        assert_eq!(
+            decode_bytes(&[0x44, 1, 2, 3, 4]).done(),
+            Some((5, &[1u8, 2, 3, 4][..]))
+        );
+        assert_eq!(
+            decode_item(&[0xfa, 0x47, 0xc3, 0x50, 0x00]).done(),
+            Some((5, Item::Float(100000.0)))
+        );
+        assert_eq!(decode_item(&[0xf7]).done(), Some((1, Item::Undefined)));
+        // Indefinite lengths, half-precision floats and invalid UTF-8.
+        assert!(decode_item(&[0x5f, 0x41, 0x00, 0xff]).is_err());
+        assert!(decode_item(&[0xf9, 0x3c, 0x00]).is_err());
+        assert!(decode_text(&[0x62, 0xc3, 0x28]).is_err());
+        // A string longer than the buffer.
+        assert_eq!(decode_bytes(&[0x44, 1, 2]).needed(), Some(5));
+    }
+}
@@ -0,0 +1,461 @@
+//! This file contains the structs and methods associated with the CoAP
+//! message format (RFC 7252, 3): the fixed header and token, the options and
+//! the payload. Like the other headers of the stack, `CoAPHeader` has
+//! getters and setters and encode/decode functions; options are written with
+//! `encode_option` and read from a decoded `CoAPMessage` with an
+//! `OptionIterator`.
+
+use crate::net::stream::SResult;
+use crate::net::stream::{decode_u16, decode_u8};
+use crate::net::stream::{encode_bytes, encode_u16, encode_u8};
+
+pub const COAP_VERSION: u8 = 1;
+
+/// Size of the header without the token.
+pub const COAP_HDR_LEN: usize = 4;
+
+pub const MAX_TOKEN_LEN: usize = 8;
+
+/// Separates the options from the payload.
+const PAYLOAD_MARKER: u8 = 0xff;
+
+/// Message codes, with the class in the top 3 bits and the detail in the
+/// bottom 5, so that 2.05 is `(2 << 5) | 5`.
+pub mod coap_codes {
+    pub const EMPTY: u8 = 0x00;
+    pub const GET: u8 = 0x01;
+    pub const POST: u8 = 0x02;
+    pub const PUT: u8 = 0x03;
+    pub const DELETE: u8 = 0x04;
+    pub const CREATED: u8 = 0x41;
+    pub const DELETED: u8 = 0x42;
+    pub const VALID: u8 = 0x43;
+    pub const CHANGED: u8 = 0x44;
+    pub const CONTENT: u8 = 0x45;
+    pub const BAD_REQUEST: u8 = 0x80;
+    pub const UNAUTHORIZED: u8 = 0x81;
+    pub const NOT_FOUND: u8 = 0x84;
+    pub const METHOD_NOT_ALLOWED: u8 = 0x85;
+    pub const NOT_ACCEPTABLE: u8 = 0x86;
+    pub const UNSUPPORTED_CONTENT_FORMAT: u8 = 0x8f;
+    pub const INTERNAL_SERVER_ERROR: u8 = 0xa0;
+    pub const NOT_IMPLEMENTED: u8 = 0xa1;
+    pub const SERVICE_UNAVAILABLE: u8 = 0xa3;
+}
+
+/// Option numbers (RFC 7252, 5.10).
+pub mod coap_options {
+    pub const URI_HOST: u16 = 3;
+    /// Observe (RFC 7641, 2).
+    pub const OBSERVE: u16 = 6;
+    pub const URI_PORT: u16 = 7;
+    pub const LOCATION_PATH: u16 = 8;
+    pub const URI_PATH: u16 = 11;
+    pub const CONTENT_FORMAT: u16 = 12;
+    pub const URI_QUERY: u16 = 15;
+    pub const ACCEPT: u16 = 17;
+    /// Block2 and Block1 (RFC 7959, 2.1).
+    pub const BLOCK2: u16 = 23;
+    pub const BLOCK1: u16 = 27;
+    /// Size2 (RFC 7959, 4).
+    pub const SIZE2: u16 = 28;
+}
+
+/// Content-Format numbers (RFC 7252, 12.3) of the payloads the stack
+/// writes and reads.
+pub mod coap_content_formats {
+    pub const TEXT_PLAIN: u16 = 0;
+    pub const LINK_FORMAT: u16 = 40;
+    pub const CBOR: u16 = 60;
+    pub const SENML_CBOR: u16 = 112;
+}
+
+/// The value of a Block1 or Block2 option (RFC 7959, 2.2): the number of a
+/// block, whether more blocks follow it, and the size of the blocks, as
+/// `16 << szx` bytes.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct CoAPBlock {
+    pub num: u32,
+    pub more: bool,
+    pub szx: u8,
+}
+
+impl CoAPBlock {
+    /// The largest size exponent, for blocks of 1024 bytes.
+    pub const MAX_SZX: u8 = 6;
+
+    pub fn size(&self) -> usize {
+        16 << self.szx
+    }
+
+    /// The offset of the block in the whole body.
+    pub fn offset(&self) -> usize {
+        self.num as usize * self.size()
+    }
+
+    pub fn to_uint(&self) -> u32 {
+        self.num << 4 | (self.more as u32) << 3 | self.szx as u32
+    }
+
+    /// Returns `None` for the reserved size exponent 7.
+    pub fn from_uint(value: u32) -> Option<CoAPBlock> {
+        let szx = (value & 0x7) as u8;
+        if szx > CoAPBlock::MAX_SZX {
+            return None;
+        }
+        Some(CoAPBlock {
+            num: value >> 4,
+            more: value & 0x8 != 0,
+            szx: szx,
+        })
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CoAPType {
+    Confirmable = 0,
+    NonConfirmable = 1,
+    Acknowledgement = 2,
+    Reset = 3,
+}
+
+/// The fixed header of a CoAP message, with its token.
+#[derive(Copy, Clone, Debug)]
+pub struct CoAPHeader {
+    pub msg_type: CoAPType,
+    pub code: u8,
+    pub message_id: u16,
+    pub token: [u8; MAX_TOKEN_LEN],
+    pub token_len: u8,
+}
+
+impl CoAPHeader {
+    pub fn new(msg_type: CoAPType, code: u8, message_id: u16) -> CoAPHeader {
+        CoAPHeader {
+            msg_type: msg_type,
+            code: code,
+            message_id: message_id,
+            token: [0; MAX_TOKEN_LEN],
+            token_len: 0,
+        }
+    }
+
+    pub fn set_type(&mut self, msg_type: CoAPType) {
+        self.msg_type = msg_type;
+    }
+
+    pub fn set_code(&mut self, code: u8) {
+        self.code = code;
+    }
+
+    pub fn set_message_id(&mut self, message_id: u16) {
+        self.message_id = message_id;
+    }
+
+    /// Panics if `token` is longer than `MAX_TOKEN_LEN`.
+    pub fn set_token(&mut self, token: &[u8]) {
+        self.token[..token.len()].copy_from_slice(token);
+        self.token_len = token.len() as u8;
+    }
+
+    pub fn get_type(&self) -> CoAPType {
+        self.msg_type
+    }
+
+    pub fn get_code(&self) -> u8 {
+        self.code
+    }
+
+    pub fn get_message_id(&self) -> u16 {
+        self.message_id
+    }
+
+    pub fn get_token(&self) -> &[u8] {
+        &self.token[..self.token_len as usize]
+    }
+
+    /// Requests have a code of class 0, other than the empty message.
+    pub fn is_request(&self) -> bool {
+        self.code != coap_codes::EMPTY && self.code >> 5 == 0
+    }
+
+    pub fn is_response(&self) -> bool {
+        self.code >> 5 >= 2
+    }
+
+    pub fn get_hdr_size(&self) -> usize {
+        COAP_HDR_LEN + self.token_len as usize
+    }
+
+    /// This function serializes the `CoAPHeader` and its token into the
+    /// provided buffer.
+    ///
+    /// # Arguments
+    ///
+    /// `buf` - A mutable buffer to serialize the `CoAPHeader` into
+    /// `offset` - The current offset into the provided buffer
+    ///
+    /// # Return Value
+    ///
+    /// This function returns the new offset into the buffer wrapped in an
+    /// SResult.
+    pub fn encode(&self, buf: &mut [u8], offset: usize) -> SResult<usize> {
+        stream_len_cond!(buf, self.get_hdr_size() + offset);
+
+        let first = (COAP_VERSION << 6) | ((self.msg_type as u8) << 4) | self.token_len;
+        let mut off = offset;
+        off = enc_consume!(buf, off; encode_u8, first);
+        off = enc_consume!(buf, off; encode_u8, self.code);
+        off = enc_consume!(buf, off; encode_u16, self.message_id);
+        off = enc_consume!(buf, off; encode_bytes, self.get_token());
+        stream_done!(off, off);
+    }
+
+    /// This function deserializes the `CoAPHeader` and its token from the
+    /// provided buffer.
+    ///
+    /// # Return Value
+    ///
+    /// This function returns a `CoAPHeader` struct wrapped in an SResult,
+    /// with the offset of the options.
+    pub fn decode(buf: &[u8]) -> SResult<CoAPHeader> {
+        stream_len_cond!(buf, COAP_HDR_LEN);
+        let off = 0;
+        let (off, first) = dec_try!(buf, off; decode_u8);
+        let (off, code) = dec_try!(buf, off; decode_u8);
+        let (off, message_id) = dec_try!(buf, off; decode_u16);
+
+        let token_len = (first & 0x0f) as usize;
+        stream_cond!(first >> 6 == COAP_VERSION && token_len <= MAX_TOKEN_LEN);
+        stream_len_cond!(buf, off + token_len);
+        let msg_type = match (first >> 4) & 0x3 {
+            0 => CoAPType::Confirmable,
+            1 => CoAPType::NonConfirmable,
+            2 => CoAPType::Acknowledgement,
+            _ => CoAPType::Reset,
+        };
+        let mut header = CoAPHeader::new(msg_type, code, message_id);
+        header.set_token(&buf[off..off + token_len]);
+        stream_done!(off + token_len, header);
+    }
+}
+
+/// Writes the nibble of an option delta or length, and returns the
+/// extended bytes that follow it (RFC 7252, 3.1).
+fn option_nibble(value: u16) -> (u8, [u8; 2], usize) {
+    if value < 13 {
+        (value as u8, [0; 2], 0)
+    } else if value < 269 {
+        (13, [(value - 13) as u8, 0], 1)
+    } else {
+        let ext = (value - 269).to_be_bytes();
+        (14, ext, 2)
+    }
+}
+
+/// Serializes an option with number `number` into `buf` at `offset`.
+/// Options have to be encoded in order of their number, and `prev_number`
+/// is the number of the option before, or 0 for the first option.
+pub fn encode_option(
+    buf: &mut [u8],
+    offset: usize,
+    prev_number: u16,
+    number: u16,
+    value: &[u8],
+) -> SResult<usize> {
+    stream_cond!(number >= prev_number && value.len() <= u16::max_value() as usize);
+    let (delta, delta_ext, delta_ext_len) = option_nibble(number - prev_number);
+    let (len, len_ext, len_ext_len) = option_nibble(value.len() as u16);
+    stream_len_cond!(buf, offset + 1 + delta_ext_len + len_ext_len + value.len());
+
+    let mut off = offset;
+    off = enc_consume!(buf, off; encode_u8, (delta << 4) | len);
+    off = enc_consume!(buf, off; encode_bytes, &delta_ext[..delta_ext_len]);
+    off = enc_consume!(buf, off; encode_bytes, &len_ext[..len_ext_len]);
+    off = enc_consume!(buf, off; encode_bytes, value);
+    stream_done!(off, off);
+}
+
+/// Serializes an option whose value is an unsigned integer, in as few bytes
+/// as it fits in (RFC 7252, 3.2).
+pub fn encode_uint_option(
+    buf: &mut [u8],
+    offset: usize,
+    prev_number: u16,
+    number: u16,
+    value: u32,
+) -> SResult<usize> {
+    let bytes = value.to_be_bytes();
+    let zeros = (value.leading_zeros() / 8) as usize;
+    encode_option(buf, offset, prev_number, number, &bytes[zeros..])
+}
+
+/// Serializes the payload marker and `payload`, unless it is empty.
+pub fn encode_payload(buf: &mut [u8], offset: usize, payload: &[u8]) -> SResult<usize> {
+    if payload.is_empty() {
+        stream_done!(offset, offset);
+    }
+    stream_len_cond!(buf, offset + 1 + payload.len());
+    let mut off = offset;
+    off = enc_consume!(buf, off; encode_u8, PAYLOAD_MARKER);
+    off = enc_consume!(buf, off; encode_bytes, payload);
+    stream_done!(off, off);
+}
+
+/// A received message, split into its header, options and payload.
+pub struct CoAPMessage<'a> {
+    pub header: CoAPHeader,
+    options: &'a [u8],
+    pub payload: &'a [u8],
+}
+
+impl<'a> CoAPMessage<'a> {
+    /// Decodes a whole message. Returns `None` if it is malformed.
+    pub fn decode(buf: &'a [u8]) -> Option<CoAPMessage<'a>> {
+        let (off, header) = CoAPHeader::decode(buf).done()?;
+        let rest = &buf[off..];
+
+        // Walk the options to find where they end
+        let mut iter = OptionIterator {
+            buf: rest,
+            off: 0,
+            number: 0,
+        };
+        while let Some(result) = iter.next_option() {
+            result?;
+        }
+        let (options, payload) = rest.split_at(iter.off);
+        let payload = match payload.split_first() {
+            // A marker followed by no payload is an error
+            Some((_, payload)) if payload.is_empty() => return None,
+            Some((_, payload)) => payload,
+            None => payload,
+        };
+        Some(CoAPMessage {
+            header: header,
+            options: options,
+            payload: payload,
+        })
+    }
+
+    /// Iterates over the options, as pairs of their number and value.
+    pub fn options(&self) -> OptionIterator<'a> {
+        OptionIterator {
+            buf: self.options,
+            off: 0,
+            number: 0,
+        }
+    }
+
+    /// Returns the value of the first option numbered `number`.
+    pub fn option(&self, number: u16) -> Option<&'a [u8]> {
+        self.options()
+            .find(|&(option, _)| option == number)
+            .map(|(_, value)| value)
+    }
+
+    /// Returns the value of the first option numbered `number`, if it is an
+    /// unsigned integer.
+    pub fn uint_option(&self, number: u16) -> Option<u32> {
+        let value = self.option(number)?;
+        if value.len() > 4 {
+            return None;
+        }
+        Some(
+            value
+                .iter()
+                .fold(0, |uint, &byte| (uint << 8) | byte as u32),
+        )
+    }
+
+    /// Returns whether the Uri-Path options of the message are the segments
+    /// of `path`, such as "sensors/temp".
+    pub fn path_matches(&self, path: &str) -> bool {
+        let mut segments = path.split('/').filter(|s| !s.is_empty());
+        for (number, value) in self.options() {
+            if number != coap_options::URI_PATH {
+                continue;
+            }
+            match segments.next() {
+                Some(segment) if segment.as_bytes() == value => {}
+                _ => return false,
+            }
+        }
+        segments.next().is_none()
+    }
+
+    /// Returns whether the Uri-Path options of the message start with the
+    /// segments of `prefix`, so that "sensors" is a prefix of
+    /// "sensors/temp". Every path starts with "".
+    pub fn path_has_prefix(&self, prefix: &str) -> bool {
+        let mut segments = self.path_segments();
+        prefix
+            .split('/')
+            .filter(|s| !s.is_empty())
+            .all(|segment| segments.next() == Some(segment.as_bytes()))
+    }
+
+    /// Iterates over the Uri-Path options, such as "sensors" and "temp".
+    pub fn path_segments(&self) -> impl Iterator<Item = &'a [u8]> {
+        self.options()
+            .filter(|&(number, _)| number == coap_options::URI_PATH)
+            .map(|(_, value)| value)
+    }
+}
+
+pub struct OptionIterator<'a> {
+    buf: &'a [u8],
+    off: usize,
+    number: u16,
+}
+
+impl<'a> OptionIterator<'a> {
+    /// Reads the extended bytes of a nibble.
+    fn extended(&mut self, nibble: u8) -> Option<u16> {
+        match nibble {
+            13 => {
+                let ext = *self.buf.get(self.off)?;
+                self.off += 1;
+                Some(ext as u16 + 13)
+            }
+            14 => {
+                let ext = self.buf.get(self.off..self.off + 2)?;
+                self.off += 2;
+                (u16::from_be_bytes([ext[0], ext[1]])).checked_add(269)
+            }
+            15 => None,
+            _ => Some(nibble as u16),
+        }
+    }
+
+    /// `None` at the end of the options, or `Some(None)` if they are
+    /// malformed.
+    fn next_option(&mut self) -> Option<Option<(u16, &'a [u8])>> {
+        let first = *self.buf.get(self.off)?;
+        if first == PAYLOAD_MARKER {
+            return None;
+        }
+        self.off += 1;
+        let option = self.extended(first >> 4).and_then(|delta| {
+            let len = self.extended(first & 0x0f)? as usize;
+            let value = self.buf.get(self.off..self.off + len)?;
+            self.off += len;
+            self.number = self.number.checked_add(delta)?;
+            Some((self.number, value))
+        });
+        if option.is_none() {
+            // Stop at the malformed option
+            self.off = self.buf.len();
+        }
+        Some(option)
+    }
+}
+
+impl<'a> Iterator for OptionIterator<'a> {
+    type Item = (u16, &'a [u8]);
+
+    /// Stops at the end of the options, or at the first malformed one.
+    fn next(&mut self) -> Option<(u16, &'a [u8])> {
+        self.next_option().and_then(|option| option)
+    }
+}
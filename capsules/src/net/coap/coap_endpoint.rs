@@ -0,0 +1,858 @@
+//! A CoAP (RFC 7252) client and server over the kernel UDP stack.
+//!
+//! `CoAPEndpoint` binds a UDP port through the port table, then serves the
+//! `CoAPResource`s other capsules add with `add_resource()`, and sends the
+//! requests of its `CoAPClient`.
+//!
+//! - Requests are matched to resources by their Uri-Path options, or by
+//!   their first ones for a resource added with `CoAPResource::new_prefix()`.
+//!   A confirmable request is answered with a piggybacked response in its
+//!   acknowledgement, and the last response is kept so that a retransmitted
+//!   request gets it again instead of running the handler twice.
+//! - A handler that registers an observer of its resource (RFC 7641) answers
+//!   with an Observe option, and later sends the observer notifications with
+//!   `notify()`.
+//! - A client downloads a large resource a block at a time (RFC 7959) with
+//!   `request_block()`, reading the Block2 option of each response.
+//! - One request can be outstanding at a time, as with NSTART = 1. A
+//!   confirmable request is retransmitted after `ACK_TIMEOUT_MS`, doubling
+//!   each time, up to `MAX_RETRANSMIT` times. Responses are matched by their
+//!   token, and separate responses are acknowledged.
+//!
+//! The endpoint has no source of randomness, so the initial timeout is
+//! spread with the message ID instead of at random, and tokens are counted
+//! from the initial message ID given to `new()`, which boards should vary
+//! between boots, for example from a random number.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//! # use capsules::net::coap::coap_endpoint::{CoAPEndpoint, CoAPResource};
+//!
+//! let coap = static_init!(
+//!     CoAPEndpoint<'static, VirtualMuxAlarm<'static, sam4l::ast::Ast>>,
+//!     CoAPEndpoint::new(
+//!         udp_send,
+//!         udp_recv,
+//!         udp_port_table,
+//!         coap_alarm,
+//!         LeasableBuffer::new(&mut COAP_TX_BUF),
+//!         &mut COAP_REQUEST_BUF,
+//!         &mut COAP_RESPONSE_BUF,
+//!         initial_message_id,
+//!         net_cap,
+//!     )
+//! );
+//! udp_send.set_client(coap);
+//! udp_recv.set_client(coap);
+//! coap_alarm.set_alarm_client(coap);
+//! coap.bind(COAP_PORT);
+//!
+//! let temperature = static_init!(
+//!     CoAPResource<'static>,
+//!     CoAPResource::new("sensors/temp", temperature_handler)
+//! );
+//! coap.add_resource(temperature);
+//! ```
+
+use crate::net::coap::coap::{coap_codes, coap_options};
+use crate::net::coap::coap::{encode_option, encode_payload, encode_uint_option};
+use crate::net::coap::coap::{CoAPBlock, CoAPHeader, CoAPMessage, CoAPType, MAX_TOKEN_LEN};
+use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::network_capabilities::NetworkCapability;
+use crate::net::udp::udp_port_table::UdpPortManager;
+use crate::net::udp::udp_recv::{UDPReceiver, UDPRecvClient};
+use crate::net::udp::udp_send::{UDPSendClient, UDPSender};
+use core::cell::Cell;
+use kernel::common::cells::{MapCell, OptionalCell, TakeCell};
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::common::{List, ListLink, ListNode};
+use kernel::hil::time::{self, Alarm};
+use kernel::ReturnCode;
+
+/// The port CoAP servers listen on.
+pub const COAP_PORT: u16 = 5683;
+
+/// Timers are counted in ticks.
+pub const TICK_MS: u32 = 100;
+
+pub const ACK_TIMEOUT_MS: u32 = 2000;
+pub const MAX_RETRANSMIT: u8 = 4;
+
+/// How long a response to a non-confirmable or acknowledged request is
+/// waited for, about the MAX_TRANSMIT_WAIT of RFC 7252.
+pub const RESPONSE_TIMEOUT_MS: u32 = 93000;
+
+const TOKEN_LEN: usize = 4;
+
+/// The longest options of a response: an Observe option of up to 4 bytes
+/// and a Content-Format option of up to 3.
+const MAX_RESPONSE_OPTIONS_LEN: usize = 7;
+
+/// The response a `CoAPHandler` wrote the payload of.
+#[derive(Copy, Clone)]
+pub struct CoAPResponse {
+    pub code: u8,
+    /// The length of the payload.
+    pub len: usize,
+    pub content_format: Option<u16>,
+    /// The sequence number of the Observe option, for a response that
+    /// registers an observer.
+    pub observe: Option<u32>,
+}
+
+impl CoAPResponse {
+    pub fn new(code: u8, len: usize) -> CoAPResponse {
+        CoAPResponse {
+            code: code,
+            len: len,
+            content_format: None,
+            observe: None,
+        }
+    }
+}
+
+/// Implemented by capsules that serve a resource.
+pub trait CoAPHandler {
+    /// Handles a request with method `code`, such as `coap_codes::GET`.
+    /// Writes the payload of the response into `response`, and returns the
+    /// response code and the length of the payload. The default answers
+    /// every request with 4.05 Method Not Allowed.
+    fn handle_request(
+        &self,
+        _code: u8,
+        _request: &CoAPMessage,
+        _response: &mut [u8],
+    ) -> (u8, usize) {
+        (coap_codes::METHOD_NOT_ALLOWED, 0)
+    }
+
+    /// Handles a request from `src_addr`, for a handler that needs to know
+    /// who sent it or to set the options of the response. The default calls
+    /// `handle_request()`.
+    fn handle(
+        &self,
+        _src_addr: IPAddr,
+        _src_port: u16,
+        request: &CoAPMessage,
+        response: &mut [u8],
+    ) -> CoAPResponse {
+        let (code, len) = self.handle_request(request.header.get_code(), request, response);
+        CoAPResponse::new(code, len)
+    }
+}
+
+/// Implemented by the capsule that sends requests.
+pub trait CoAPClient {
+    /// The response to the outstanding request arrived. `result` is `FAIL`
+    /// if the request went unanswered or was reset, and `ECANCEL` if it was
+    /// cancelled; `code` and `payload` are then empty.
+    fn response(&self, result: ReturnCode, code: u8, payload: &[u8]);
+
+    /// The response to the outstanding request arrived, for a client that
+    /// reads its options, such as Location-Path. The default calls
+    /// `response()`.
+    fn response_message(&self, msg: &CoAPMessage) {
+        self.response(ReturnCode::SUCCESS, msg.header.get_code(), msg.payload);
+    }
+}
+
+/// A resource served by a `CoAPEndpoint`.
+pub struct CoAPResource<'a> {
+    /// The segments of the Uri-Path, such as "sensors/temp".
+    path: &'static str,
+    /// Whether the resource serves every path below `path` as well.
+    prefix: bool,
+    handler: &'a dyn CoAPHandler,
+    next: ListLink<'a, CoAPResource<'a>>,
+}
+
+impl<'a> ListNode<'a, CoAPResource<'a>> for CoAPResource<'a> {
+    fn next(&'a self) -> &'a ListLink<'a, CoAPResource<'a>> {
+        &self.next
+    }
+}
+
+impl<'a> CoAPResource<'a> {
+    pub fn new(path: &'static str, handler: &'a dyn CoAPHandler) -> CoAPResource<'a> {
+        CoAPResource {
+            path: path,
+            prefix: false,
+            handler: handler,
+            next: ListLink::empty(),
+        }
+    }
+
+    /// A resource that serves `path` and every path below it, so that
+    /// "sensors" serves "sensors/temp" as well, and "" serves every path.
+    /// Resources are matched in the order they were added.
+    pub fn new_prefix(path: &'static str, handler: &'a dyn CoAPHandler) -> CoAPResource<'a> {
+        CoAPResource {
+            path: path,
+            prefix: true,
+            handler: handler,
+            next: ListLink::empty(),
+        }
+    }
+
+    fn matches(&self, msg: &CoAPMessage) -> bool {
+        if self.prefix {
+            msg.path_has_prefix(self.path)
+        } else {
+            msg.path_matches(self.path)
+        }
+    }
+}
+
+/// The outstanding request.
+#[derive(Copy, Clone)]
+struct Exchange {
+    dest: IPAddr,
+    dst_port: u16,
+    /// Length of the encoded request, in `request_buf`.
+    len: usize,
+    message_id: u16,
+    token: [u8; TOKEN_LEN],
+    confirmable: bool,
+    /// Set once the request was acknowledged, or sent if it is not
+    /// confirmable, so that only a response is waited for.
+    acked: bool,
+    retransmits: u8,
+    /// Ticks until the next retransmission, or until the response is given
+    /// up on once `acked`.
+    timer: u32,
+    /// The retransmission timeout, doubled on each retransmission.
+    timeout: u32,
+    /// The request is to be sent once the transmit buffer is back.
+    send_pending: bool,
+}
+
+/// The request the kept response answers.
+#[derive(Copy, Clone)]
+struct Answered {
+    src_addr: IPAddr,
+    src_port: u16,
+    message_id: u16,
+    len: usize,
+}
+
+pub struct CoAPEndpoint<'a, A: Alarm<'a>> {
+    udp_sender: &'a dyn UDPSender<'a>,
+    udp_receiver: &'a UDPReceiver<'a>,
+    port_table: &'static UdpPortManager,
+    alarm: &'a A,
+    net_cap: &'static NetworkCapability,
+    resources: List<'a, CoAPResource<'a>>,
+    client: OptionalCell<&'a dyn CoAPClient>,
+    /// Given to the UDP sender for every datagram.
+    tx_buf: MapCell<LeasableBuffer<'static, u8>>,
+    /// Holds the outstanding request, for retransmissions.
+    request_buf: TakeCell<'static, [u8]>,
+    /// Holds the last response, for retransmitted requests.
+    response_buf: TakeCell<'static, [u8]>,
+    request: Cell<Option<Exchange>>,
+    answered: Cell<Option<Answered>>,
+    next_message_id: Cell<u16>,
+    next_token: Cell<u32>,
+}
+
+impl<'a, A: Alarm<'a>> CoAPEndpoint<'a, A> {
+    pub fn new(
+        udp_sender: &'a dyn UDPSender<'a>,
+        udp_receiver: &'a UDPReceiver<'a>,
+        port_table: &'static UdpPortManager,
+        alarm: &'a A,
+        tx_buf: LeasableBuffer<'static, u8>,
+        request_buf: &'static mut [u8],
+        response_buf: &'static mut [u8],
+        initial_message_id: u16,
+        net_cap: &'static NetworkCapability,
+    ) -> CoAPEndpoint<'a, A> {
+        CoAPEndpoint {
+            udp_sender: udp_sender,
+            udp_receiver: udp_receiver,
+            port_table: port_table,
+            alarm: alarm,
+            net_cap: net_cap,
+            resources: List::new(),
+            client: OptionalCell::empty(),
+            tx_buf: MapCell::new(tx_buf),
+            request_buf: TakeCell::new(request_buf),
+            response_buf: TakeCell::new(response_buf),
+            request: Cell::new(None),
+            answered: Cell::new(None),
+            next_message_id: Cell::new(initial_message_id),
+            next_token: Cell::new((initial_message_id as u32) << 16),
+        }
+    }
+
+    /// Binds the endpoint to `port`, usually `COAP_PORT` for a server. A
+    /// client only port can be any free port.
+    pub fn bind(&self, port: u16) -> ReturnCode {
+        if self.udp_sender.is_bound() {
+            return ReturnCode::EALREADY;
+        }
+        let socket = match self.port_table.create_socket() {
+            Ok(socket) => socket,
+            Err(rcode) => return rcode,
+        };
+        self.port_table.set_owner(&socket, "coap");
+        match self.port_table.bind(socket, port, self.net_cap) {
+            Ok((send_binding, recv_binding)) => {
+                self.udp_sender.set_binding(send_binding);
+                self.udp_receiver.set_binding(recv_binding);
+                ReturnCode::SUCCESS
+            }
+            // Dropping the socket frees it
+            Err(_socket) => ReturnCode::EBUSY,
+        }
+    }
+
+    pub fn add_resource(&self, resource: &'a CoAPResource<'a>) {
+        self.resources.push_tail(resource);
+    }
+
+    pub fn set_client(&self, client: &'a dyn CoAPClient) {
+        self.client.set(client);
+    }
+
+    /// Sends a request with method `code` for the resource at `path` on
+    /// `dest`, such as "sensors/temp". Returns `EBUSY` while another request
+    /// is outstanding and `ESIZE` if the request does not fit the buffer.
+    pub fn request(
+        &self,
+        dest: IPAddr,
+        dst_port: u16,
+        confirmable: bool,
+        code: u8,
+        path: &str,
+        payload: &[u8],
+    ) -> ReturnCode {
+        self.request_with_query(dest, dst_port, confirmable, code, path, "", None, payload)
+    }
+
+    /// Sends a request like `request()`, with the Uri-Query options of
+    /// `query`, such as "ep=node&lt=300", and the Content-Format option of
+    /// the payload.
+    pub fn request_with_query(
+        &self,
+        dest: IPAddr,
+        dst_port: u16,
+        confirmable: bool,
+        code: u8,
+        path: &str,
+        query: &str,
+        content_format: Option<u16>,
+        payload: &[u8],
+    ) -> ReturnCode {
+        self.start_request(
+            dest,
+            dst_port,
+            confirmable,
+            code,
+            path,
+            query,
+            content_format,
+            None,
+            payload,
+        )
+    }
+
+    /// Sends a confirmable GET for the block `block.num` of the resource at
+    /// `path` on `dest`, in blocks of `block.size()` bytes. The server may
+    /// answer with smaller blocks, so clients should read the Block2 option
+    /// of the response for where its payload goes.
+    pub fn request_block(
+        &self,
+        dest: IPAddr,
+        dst_port: u16,
+        path: &str,
+        block: CoAPBlock,
+    ) -> ReturnCode {
+        let block = CoAPBlock {
+            more: false,
+            ..block
+        };
+        self.start_request(
+            dest,
+            dst_port,
+            true,
+            coap_codes::GET,
+            path,
+            "",
+            None,
+            Some(block),
+            &[],
+        )
+    }
+
+    fn start_request(
+        &self,
+        dest: IPAddr,
+        dst_port: u16,
+        confirmable: bool,
+        code: u8,
+        path: &str,
+        query: &str,
+        content_format: Option<u16>,
+        block2: Option<CoAPBlock>,
+        payload: &[u8],
+    ) -> ReturnCode {
+        if self.request.get().is_some() {
+            return ReturnCode::EBUSY;
+        }
+        let message_id = self.message_id();
+        let token = self.next_token.get().to_be_bytes();
+        self.next_token.set(self.next_token.get().wrapping_add(1));
+
+        let msg_type = if confirmable {
+            CoAPType::Confirmable
+        } else {
+            CoAPType::NonConfirmable
+        };
+        let mut header = CoAPHeader::new(msg_type, code, message_id);
+        header.set_token(&token);
+        let len = match self
+            .request_buf
+            .map(|buf| encode_message(buf, &header, path, query, content_format, block2, payload))
+        {
+            Some(Some(len)) => len,
+            _ => return ReturnCode::ESIZE,
+        };
+
+        // Spread the initial timeout over 1 to 1.5 times ACK_TIMEOUT, as
+        // RFC 7252 does at random
+        let base = ACK_TIMEOUT_MS / TICK_MS;
+        let timeout = base + (message_id as u32 % (base / 2 + 1));
+        self.request.set(Some(Exchange {
+            dest: dest,
+            dst_port: dst_port,
+            len: len,
+            message_id: message_id,
+            token: token,
+            confirmable: confirmable,
+            acked: !confirmable,
+            retransmits: 0,
+            timer: if confirmable {
+                timeout
+            } else {
+                RESPONSE_TIMEOUT_MS / TICK_MS
+            },
+            timeout: timeout,
+            send_pending: true,
+        }));
+        self.start_timer();
+        self.send_request();
+        ReturnCode::SUCCESS
+    }
+
+    /// Stops waiting for the response to the outstanding request.
+    pub fn cancel(&self) -> ReturnCode {
+        match self.request.take() {
+            Some(_) => {
+                self.client
+                    .map(|client| client.response(ReturnCode::ECANCEL, coap_codes::EMPTY, &[]));
+                ReturnCode::SUCCESS
+            }
+            None => ReturnCode::EALREADY,
+        }
+    }
+
+    fn message_id(&self) -> u16 {
+        let message_id = self.next_message_id.get();
+        self.next_message_id.set(message_id.wrapping_add(1));
+        message_id
+    }
+
+    fn start_timer(&self) {
+        if !self.alarm.is_armed() {
+            self.alarm
+                .set_alarm(self.alarm.now(), A::ticks_from_ms(TICK_MS));
+        }
+    }
+
+    /// Sends `len` bytes of `buf`. Returns `EBUSY` while another datagram is
+    /// in the UDP stack.
+    fn send(&self, dest: IPAddr, dst_port: u16, buf: &[u8]) -> ReturnCode {
+        let mut dgram = match self.tx_buf.take() {
+            Some(dgram) => dgram,
+            None => return ReturnCode::EBUSY,
+        };
+        if buf.len() > dgram.len() {
+            self.tx_buf.replace(dgram);
+            return ReturnCode::ESIZE;
+        }
+        dgram[..buf.len()].copy_from_slice(buf);
+        dgram.slice(..buf.len());
+        match self.udp_sender.send_to(dest, dst_port, dgram, self.net_cap) {
+            Ok(()) => ReturnCode::SUCCESS,
+            Err(mut dgram) => {
+                dgram.reset();
+                self.tx_buf.replace(dgram);
+                ReturnCode::FAIL
+            }
+        }
+    }
+
+    /// Sends the outstanding request if it is pending.
+    fn send_request(&self) {
+        if let Some(mut exchange) = self.request.get() {
+            if !exchange.send_pending || self.tx_buf.is_none() {
+                return;
+            }
+            exchange.send_pending = false;
+            self.request.set(Some(exchange));
+            // A failed transmission is retransmitted like a lost one
+            self.request_buf
+                .map(|buf| self.send(exchange.dest, exchange.dst_port, &buf[..exchange.len]));
+        }
+    }
+
+    /// Sends a notification to an observer (RFC 7641, 4.2), as a
+    /// non-confirmable response with the token of the request that
+    /// registered it and the sequence number `observe`, which must grow with
+    /// each notification. Returns `EBUSY` while another datagram is in the
+    /// UDP stack, so the notification has to be sent again later.
+    pub fn notify(
+        &self,
+        dest: IPAddr,
+        dst_port: u16,
+        token: &[u8],
+        observe: u32,
+        code: u8,
+        content_format: Option<u16>,
+        payload: &[u8],
+    ) -> ReturnCode {
+        if token.len() > MAX_TOKEN_LEN {
+            return ReturnCode::EINVAL;
+        }
+        let mut dgram = match self.tx_buf.take() {
+            Some(dgram) => dgram,
+            None => return ReturnCode::EBUSY,
+        };
+        let mut header = CoAPHeader::new(CoAPType::NonConfirmable, code, self.message_id());
+        header.set_token(token);
+        let len = encode_response_header(&mut dgram[..], &header, Some(observe), content_format)
+            .and_then(|off| encode_payload(&mut dgram[..], off, payload).done())
+            .map(|(off, _)| off);
+        let len = match len {
+            Some(len) => len,
+            None => {
+                self.tx_buf.replace(dgram);
+                return ReturnCode::ESIZE;
+            }
+        };
+        dgram.slice(..len);
+        match self.udp_sender.send_to(dest, dst_port, dgram, self.net_cap) {
+            Ok(()) => ReturnCode::SUCCESS,
+            Err(mut dgram) => {
+                dgram.reset();
+                self.tx_buf.replace(dgram);
+                ReturnCode::FAIL
+            }
+        }
+    }
+
+    /// Sends an empty acknowledgement or reset.
+    fn send_empty(&self, dest: IPAddr, dst_port: u16, msg_type: CoAPType, message_id: u16) {
+        let header = CoAPHeader::new(msg_type, coap_codes::EMPTY, message_id);
+        let mut buf = [0; 4];
+        if header.encode(&mut buf, 0).done().is_some() {
+            // Lost like any other datagram if the sender is busy
+            let _ = self.send(dest, dst_port, &buf);
+        }
+    }
+
+    fn receive_request(&self, src_addr: IPAddr, src_port: u16, msg: &CoAPMessage) {
+        let confirmable = msg.header.get_type() == CoAPType::Confirmable;
+        let message_id = msg.header.get_message_id();
+        if confirmable {
+            if let Some(answered) = self.answered.get() {
+                if answered.src_addr == src_addr
+                    && answered.src_port == src_port
+                    && answered.message_id == message_id
+                {
+                    self.response_buf
+                        .map(|buf| self.send(src_addr, src_port, &buf[..answered.len]));
+                    return;
+                }
+            }
+        }
+
+        let (msg_type, response_id) = if confirmable {
+            (CoAPType::Acknowledgement, message_id)
+        } else {
+            (CoAPType::NonConfirmable, self.message_id())
+        };
+        let resource = self.resources.iter().find(|resource| resource.matches(msg));
+
+        let len = self.response_buf.map(|buf| {
+            // The payload is written after the longest header, then moved
+            // next to the header
+            let max_hdr_len = msg.header.get_hdr_size() + MAX_RESPONSE_OPTIONS_LEN + 1;
+            if buf.len() < max_hdr_len {
+                return None;
+            }
+            let response = match resource {
+                Some(resource) => {
+                    let mut response =
+                        resource
+                            .handler
+                            .handle(src_addr, src_port, msg, &mut buf[max_hdr_len..]);
+                    response.len = core::cmp::min(response.len, buf.len() - max_hdr_len);
+                    response
+                }
+                None => CoAPResponse::new(coap_codes::NOT_FOUND, 0),
+            };
+            let payload_len = response.len;
+            let mut header = CoAPHeader::new(msg_type, response.code, response_id);
+            header.set_token(msg.header.get_token());
+            let hdr_len =
+                encode_response_header(buf, &header, response.observe, response.content_format)?;
+            if payload_len > 0 {
+                buf[hdr_len] = 0xff;
+                buf.copy_within(max_hdr_len..max_hdr_len + payload_len, hdr_len + 1);
+                Some(hdr_len + 1 + payload_len)
+            } else {
+                Some(hdr_len)
+            }
+        });
+        if let Some(Some(len)) = len {
+            if confirmable {
+                self.answered.set(Some(Answered {
+                    src_addr: src_addr,
+                    src_port: src_port,
+                    message_id: message_id,
+                    len: len,
+                }));
+            }
+            self.response_buf
+                .map(|buf| self.send(src_addr, src_port, &buf[..len]));
+        }
+    }
+
+    fn receive_response(&self, src_addr: IPAddr, src_port: u16, msg: &CoAPMessage) {
+        let msg_type = msg.header.get_type();
+        let mut exchange = match self.request.get() {
+            Some(exchange) => exchange,
+            None => {
+                if msg_type == CoAPType::Confirmable {
+                    self.send_empty(
+                        src_addr,
+                        src_port,
+                        CoAPType::Reset,
+                        msg.header.get_message_id(),
+                    );
+                }
+                return;
+            }
+        };
+        let same_message = exchange.confirmable
+            && msg.header.get_message_id() == exchange.message_id
+            && (msg_type == CoAPType::Acknowledgement || msg_type == CoAPType::Reset);
+
+        if same_message && msg_type == CoAPType::Reset {
+            self.request.set(None);
+            self.client
+                .map(|client| client.response(ReturnCode::FAIL, coap_codes::EMPTY, &[]));
+            return;
+        }
+        if same_message && msg.header.get_code() == coap_codes::EMPTY {
+            // The response will be sent separately
+            exchange.acked = true;
+            exchange.timer = RESPONSE_TIMEOUT_MS / TICK_MS;
+            self.request.set(Some(exchange));
+            return;
+        }
+        if !msg.header.is_response() || msg.header.get_token() != &exchange.token[..] {
+            if msg_type == CoAPType::Confirmable {
+                self.send_empty(
+                    src_addr,
+                    src_port,
+                    CoAPType::Reset,
+                    msg.header.get_message_id(),
+                );
+            }
+            return;
+        }
+        if msg_type == CoAPType::Confirmable {
+            self.send_empty(
+                src_addr,
+                src_port,
+                CoAPType::Acknowledgement,
+                msg.header.get_message_id(),
+            );
+        }
+        self.request.set(None);
+        self.client.map(|client| client.response_message(msg));
+    }
+}
+
+/// Encodes a message with the Uri-Path options of `path`, the Uri-Query
+/// options of `query`, the Content-Format of the payload and a Block2 option
+/// into `buf`, and returns its length.
+fn encode_message(
+    buf: &mut [u8],
+    header: &CoAPHeader,
+    path: &str,
+    query: &str,
+    content_format: Option<u16>,
+    block2: Option<CoAPBlock>,
+    payload: &[u8],
+) -> Option<usize> {
+    let mut off = header.encode(buf, 0).done()?.0;
+    let mut prev_number = 0;
+    for segment in path.split('/').filter(|s| !s.is_empty()) {
+        off = encode_option(
+            buf,
+            off,
+            prev_number,
+            coap_options::URI_PATH,
+            segment.as_bytes(),
+        )
+        .done()?
+        .0;
+        prev_number = coap_options::URI_PATH;
+    }
+    if let Some(content_format) = content_format {
+        off = encode_uint_option(
+            buf,
+            off,
+            prev_number,
+            coap_options::CONTENT_FORMAT,
+            content_format as u32,
+        )
+        .done()?
+        .0;
+        prev_number = coap_options::CONTENT_FORMAT;
+    }
+    for argument in query.split('&').filter(|s| !s.is_empty()) {
+        off = encode_option(
+            buf,
+            off,
+            prev_number,
+            coap_options::URI_QUERY,
+            argument.as_bytes(),
+        )
+        .done()?
+        .0;
+        prev_number = coap_options::URI_QUERY;
+    }
+    if let Some(block2) = block2 {
+        off = encode_uint_option(
+            buf,
+            off,
+            prev_number,
+            coap_options::BLOCK2,
+            block2.to_uint(),
+        )
+        .done()?
+        .0;
+    }
+    encode_payload(buf, off, payload).done().map(|(off, _)| off)
+}
+
+/// Encodes the header of a response and its Observe and Content-Format
+/// options into `buf`, and returns their length.
+fn encode_response_header(
+    buf: &mut [u8],
+    header: &CoAPHeader,
+    observe: Option<u32>,
+    content_format: Option<u16>,
+) -> Option<usize> {
+    let mut off = header.encode(buf, 0).done()?.0;
+    let mut prev_number = 0;
+    if let Some(observe) = observe {
+        // Sequence numbers are 24 bits long
+        off = encode_uint_option(
+            buf,
+            off,
+            prev_number,
+            coap_options::OBSERVE,
+            observe & 0xff_ffff,
+        )
+        .done()?
+        .0;
+        prev_number = coap_options::OBSERVE;
+    }
+    if let Some(content_format) = content_format {
+        off = encode_uint_option(
+            buf,
+            off,
+            prev_number,
+            coap_options::CONTENT_FORMAT,
+            content_format as u32,
+        )
+        .done()?
+        .0;
+    }
+    Some(off)
+}
+
+impl<'a, A: Alarm<'a>> time::AlarmClient for CoAPEndpoint<'a, A> {
+    fn alarm(&self) {
+        let mut exchange = match self.request.get() {
+            Some(exchange) => exchange,
+            None => return,
+        };
+        exchange.timer = exchange.timer.saturating_sub(1);
+        if exchange.timer == 0 {
+            if exchange.acked || exchange.retransmits >= MAX_RETRANSMIT {
+                self.request.set(None);
+                self.client
+                    .map(|client| client.response(ReturnCode::FAIL, coap_codes::EMPTY, &[]));
+                return;
+            }
+            exchange.retransmits += 1;
+            exchange.timeout *= 2;
+            exchange.timer = exchange.timeout;
+            exchange.send_pending = true;
+        }
+        self.request.set(Some(exchange));
+        self.start_timer();
+        self.send_request();
+    }
+}
+
+impl<'a, A: Alarm<'a>> UDPSendClient for CoAPEndpoint<'a, A> {
+    fn send_done(&self, _result: ReturnCode, mut dgram: LeasableBuffer<'static, u8>) {
+        dgram.reset();
+        self.tx_buf.replace(dgram);
+        self.send_request();
+    }
+}
+
+impl<'a, A: Alarm<'a>> UDPRecvClient for CoAPEndpoint<'a, A> {
+    fn receive(
+        &self,
+        src_addr: IPAddr,
+        _dst_addr: IPAddr,
+        src_port: u16,
+        _dst_port: u16,
+        payload: &[u8],
+        _timestamp: Option<u32>,
+    ) {
+        let msg = match CoAPMessage::decode(payload) {
+            Some(msg) => msg,
+            None => return,
+        };
+        let msg_type = msg.header.get_type();
+        if msg.header.is_request() {
+            if msg_type == CoAPType::Confirmable || msg_type == CoAPType::NonConfirmable {
+                self.receive_request(src_addr, src_port, &msg);
+            }
+        } else if msg.header.get_code() == coap_codes::EMPTY && msg_type == CoAPType::Confirmable {
+            // A ping (RFC 7252, 4.3)
+            self.send_empty(
+                src_addr,
+                src_port,
+                CoAPType::Reset,
+                msg.header.get_message_id(),
+            );
+        } else {
+            self.receive_response(src_addr, src_port, &msg);
+        }
+    }
+}
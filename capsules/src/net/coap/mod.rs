@@ -0,0 +1,2 @@
+pub mod coap;
+pub mod coap_endpoint;
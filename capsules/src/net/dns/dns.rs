@@ -0,0 +1,269 @@
+//! This file contains the structs and functions associated with the DNS
+//! message format (RFC 1035, 4.1), which mDNS (RFC 6762) shares: the fixed
+//! header, questions and resource records. Names are written uncompressed,
+//! and names that are read may use compression pointers.
+
+use crate::net::stream::SResult;
+use crate::net::stream::{decode_u16, decode_u32};
+use crate::net::stream::{encode_bytes, encode_u16, encode_u32, encode_u8};
+
+pub const DNS_PORT: u16 = 53;
+pub const MDNS_PORT: u16 = 5353;
+
+pub const DNS_HDR_LEN: usize = 12;
+
+/// Longest name that is encoded, in its dotted form.
+pub const MAX_NAME_LEN: usize = 64;
+
+/// Compression pointers followed before a name is taken to loop.
+const MAX_POINTERS: usize = 8;
+
+pub mod dns_types {
+    pub const A: u16 = 1;
+    pub const AAAA: u16 = 28;
+    pub const ANY: u16 = 255;
+}
+
+pub const CLASS_IN: u16 = 1;
+
+/// Set in the class of an mDNS question to ask for a unicast response, and
+/// in the class of an mDNS record that replaces cached records.
+pub const MDNS_CLASS_FLAG: u16 = 0x8000;
+
+pub mod dns_flags {
+    /// Set in responses.
+    pub const QR: u16 = 0x8000;
+    pub const AA: u16 = 0x0400;
+    pub const TC: u16 = 0x0200;
+    /// Recursion desired.
+    pub const RD: u16 = 0x0100;
+    pub const RCODE_MASK: u16 = 0x000f;
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct DNSHeader {
+    pub id: u16,
+    pub flags: u16,
+    pub qdcount: u16,
+    pub ancount: u16,
+    pub nscount: u16,
+    pub arcount: u16,
+}
+
+impl DNSHeader {
+    pub fn new(id: u16, flags: u16) -> DNSHeader {
+        DNSHeader {
+            id: id,
+            flags: flags,
+            qdcount: 0,
+            ancount: 0,
+            nscount: 0,
+            arcount: 0,
+        }
+    }
+
+    pub fn is_response(&self) -> bool {
+        self.flags & dns_flags::QR != 0
+    }
+
+    pub fn get_rcode(&self) -> u16 {
+        self.flags & dns_flags::RCODE_MASK
+    }
+
+    pub fn encode(&self, buf: &mut [u8], offset: usize) -> SResult<usize> {
+        stream_len_cond!(buf, offset + DNS_HDR_LEN);
+
+        let mut off = offset;
+        off = enc_consume!(buf, off; encode_u16, self.id);
+        off = enc_consume!(buf, off; encode_u16, self.flags);
+        off = enc_consume!(buf, off; encode_u16, self.qdcount);
+        off = enc_consume!(buf, off; encode_u16, self.ancount);
+        off = enc_consume!(buf, off; encode_u16, self.nscount);
+        off = enc_consume!(buf, off; encode_u16, self.arcount);
+        stream_done!(off, off);
+    }
+
+    pub fn decode(buf: &[u8]) -> SResult<DNSHeader> {
+        stream_len_cond!(buf, DNS_HDR_LEN);
+
+        let off = 0;
+        let (off, id) = dec_try!(buf, off; decode_u16);
+        let (off, flags) = dec_try!(buf, off; decode_u16);
+        let (off, qdcount) = dec_try!(buf, off; decode_u16);
+        let (off, ancount) = dec_try!(buf, off; decode_u16);
+        let (off, nscount) = dec_try!(buf, off; decode_u16);
+        let (off, arcount) = dec_try!(buf, off; decode_u16);
+        stream_done!(
+            off,
+            DNSHeader {
+                id: id,
+                flags: flags,
+                qdcount: qdcount,
+                ancount: ancount,
+                nscount: nscount,
+                arcount: arcount,
+            }
+        );
+    }
+}
+
+/// Serializes a dotted name such as "tock.example.com" as labels.
+pub fn encode_name(buf: &mut [u8], offset: usize, name: &str) -> SResult<usize> {
+    stream_cond!(name.len() <= MAX_NAME_LEN);
+    let mut off = offset;
+    for label in name.split('.').filter(|l| !l.is_empty()) {
+        stream_cond!(label.len() < 64);
+        stream_len_cond!(buf, off + 1 + label.len());
+        off = enc_consume!(buf, off; encode_u8, label.len() as u8);
+        off = enc_consume!(buf, off; encode_bytes, label.as_bytes());
+    }
+    stream_len_cond!(buf, off + 1);
+    off = enc_consume!(buf, off; encode_u8, 0);
+    stream_done!(off, off);
+}
+
+/// Serializes a question for the records of type `qtype` of `name`.
+pub fn encode_question(
+    buf: &mut [u8],
+    offset: usize,
+    name: &str,
+    qtype: u16,
+    qclass: u16,
+) -> SResult<usize> {
+    let (mut off, _) = enc_try!(encode_name(buf, offset, name));
+    stream_len_cond!(buf, off + 4);
+    off = enc_consume!(buf, off; encode_u16, qtype);
+    off = enc_consume!(buf, off; encode_u16, qclass);
+    stream_done!(off, off);
+}
+
+/// Serializes a record of `name` with data `rdata`.
+pub fn encode_record(
+    buf: &mut [u8],
+    offset: usize,
+    name: &str,
+    rtype: u16,
+    class: u16,
+    ttl: u32,
+    rdata: &[u8],
+) -> SResult<usize> {
+    let (mut off, _) = enc_try!(encode_name(buf, offset, name));
+    stream_len_cond!(buf, off + 10 + rdata.len());
+    off = enc_consume!(buf, off; encode_u16, rtype);
+    off = enc_consume!(buf, off; encode_u16, class);
+    off = enc_consume!(buf, off; encode_u32, ttl);
+    off = enc_consume!(buf, off; encode_u16, rdata.len() as u16);
+    off = enc_consume!(buf, off; encode_bytes, rdata);
+    stream_done!(off, off);
+}
+
+/// Returns the offset after the name at `offset` of the message `buf`.
+pub fn skip_name(buf: &[u8], offset: usize) -> Option<usize> {
+    let mut off = offset;
+    loop {
+        let len = *buf.get(off)? as usize;
+        if len == 0 {
+            return Some(off + 1);
+        } else if len & 0xc0 == 0xc0 {
+            return if off + 2 <= buf.len() {
+                Some(off + 2)
+            } else {
+                None
+            };
+        } else if len & 0xc0 != 0 {
+            return None;
+        }
+        off += 1 + len;
+    }
+}
+
+/// Returns whether the name at `offset` of the message `buf` is the dotted
+/// name `name`, ignoring case, or `None` if it is malformed.
+pub fn name_matches(buf: &[u8], offset: usize, name: &str) -> Option<bool> {
+    let mut labels = name.split('.').filter(|l| !l.is_empty());
+    let mut off = offset;
+    let mut pointers = 0;
+    loop {
+        let len = *buf.get(off)? as usize;
+        if len == 0 {
+            return Some(labels.next().is_none());
+        } else if len & 0xc0 == 0xc0 {
+            pointers += 1;
+            if pointers > MAX_POINTERS {
+                return None;
+            }
+            off = ((len & 0x3f) << 8) | *buf.get(off + 1)? as usize;
+            continue;
+        } else if len & 0xc0 != 0 {
+            return None;
+        }
+        let label = buf.get(off + 1..off + 1 + len)?;
+        match labels.next() {
+            Some(l) if l.as_bytes().eq_ignore_ascii_case(label) => {}
+            _ => return Some(false),
+        }
+        off += 1 + len;
+    }
+}
+
+/// A question of a received message.
+#[derive(Copy, Clone, Debug)]
+pub struct DNSQuestion {
+    /// Offset of the name in the message.
+    pub name_offset: usize,
+    pub qtype: u16,
+    pub qclass: u16,
+}
+
+impl DNSQuestion {
+    /// Decodes the question at `offset` of the message `buf`.
+    pub fn decode(buf: &[u8], offset: usize) -> SResult<DNSQuestion> {
+        let off = stream_from_option!(skip_name(buf, offset));
+        stream_len_cond!(buf, off + 4);
+        let (off, qtype) = dec_try!(buf, off; decode_u16);
+        let (off, qclass) = dec_try!(buf, off; decode_u16);
+        stream_done!(
+            off,
+            DNSQuestion {
+                name_offset: offset,
+                qtype: qtype,
+                qclass: qclass,
+            }
+        );
+    }
+}
+
+/// A resource record of a received message.
+#[derive(Copy, Clone, Debug)]
+pub struct DNSRecord<'a> {
+    /// Offset of the name in the message.
+    pub name_offset: usize,
+    pub rtype: u16,
+    pub class: u16,
+    pub ttl: u32,
+    pub rdata: &'a [u8],
+}
+
+impl<'a> DNSRecord<'a> {
+    /// Decodes the record at `offset` of the message `buf`.
+    pub fn decode(buf: &'a [u8], offset: usize) -> SResult<DNSRecord<'a>> {
+        let off = stream_from_option!(skip_name(buf, offset));
+        stream_len_cond!(buf, off + 10);
+        let (off, rtype) = dec_try!(buf, off; decode_u16);
+        let (off, class) = dec_try!(buf, off; decode_u16);
+        let (off, ttl) = dec_try!(buf, off; decode_u32);
+        let (off, rdlen) = dec_try!(buf, off; decode_u16);
+        let end = off + rdlen as usize;
+        stream_len_cond!(buf, end);
+        stream_done!(
+            end,
+            DNSRecord {
+                name_offset: offset,
+                rtype: rtype,
+                class: class,
+                ttl: ttl,
+                rdata: &buf[off..end],
+            }
+        );
+    }
+}
@@ -0,0 +1,476 @@
+//! A DNS resolver for the kernel UDP stack.
+//!
+//! `DNSResolver` looks up the IPv6 (AAAA) address of a hostname. Names in
+//! `.local` are asked of the link with an mDNS query (RFC 6762, 5.1), and
+//! other names of the server set with `set_server()`. Queries are
+//! retransmitted `MAX_RETRIES` times before the client is told that the
+//! name could not be resolved.
+//!
+//! Answers are cached for their TTL, up to `MAX_TTL_S`, in a cache of
+//! `CACHE_SIZE` names that replaces the entry closest to expiring. A
+//! cached name is still answered through the client, from the alarm.
+//!
+//! One name is resolved at a time; `resolve()` returns `EBUSY` while
+//! another is outstanding.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//! # use capsules::net::dns::dns_resolver::DNSResolver;
+//!
+//! let dns = static_init!(
+//!     DNSResolver<'static, VirtualMuxAlarm<'static, sam4l::ast::Ast>>,
+//!     DNSResolver::new(
+//!         udp_send,
+//!         udp_recv,
+//!         udp_port_table,
+//!         dns_alarm,
+//!         LeasableBuffer::new(&mut DNS_BUF),
+//!         initial_id,
+//!         net_cap,
+//!     )
+//! );
+//! udp_send.set_client(dns);
+//! udp_recv.set_client(dns);
+//! dns_alarm.set_alarm_client(dns);
+//! dns.bind(DNS_CLIENT_PORT);
+//! dns.set_server(dns_server_addr);
+//! ```
+
+use crate::net::dns::dns::{dns_flags, dns_types, CLASS_IN, DNS_HDR_LEN, DNS_PORT};
+use crate::net::dns::dns::{encode_question, DNSHeader, DNSQuestion, DNSRecord};
+use crate::net::dns::dns::{MAX_NAME_LEN, MDNS_CLASS_FLAG, MDNS_PORT};
+use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::network_capabilities::NetworkCapability;
+use crate::net::udp::udp_port_table::UdpPortManager;
+use crate::net::udp::udp_recv::{UDPReceiver, UDPRecvClient};
+use crate::net::udp::udp_send::{UDPSendClient, UDPSender};
+use core::cell::Cell;
+use kernel::common::cells::{MapCell, OptionalCell};
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::hil::time::{self, Alarm, Ticks};
+use kernel::ReturnCode;
+
+pub const CACHE_SIZE: usize = 4;
+
+/// The TTL of cached names is counted, and queries are retransmitted, in
+/// ticks.
+pub const TICK_MS: u32 = 1000;
+
+pub const MAX_TTL_S: u32 = 86400;
+pub const QUERY_TIMEOUT_TICKS: u8 = 2;
+pub const MAX_RETRIES: u8 = 2;
+
+/// The mDNS group all mDNS responders listen on, ff02::fb.
+pub const MDNS_GROUP: IPAddr = IPAddr([0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xfb]);
+
+/// Implemented by capsules that resolve names.
+pub trait DNSClient {
+    /// The name passed to `resolve()` resolved to an address, or could not
+    /// be resolved (`FAIL`).
+    fn resolved(&self, hostname: &str, result: Result<IPAddr, ReturnCode>);
+}
+
+#[derive(Copy, Clone)]
+struct Name {
+    bytes: [u8; MAX_NAME_LEN],
+    len: usize,
+}
+
+impl Name {
+    fn new(name: &str) -> Name {
+        let mut bytes = [0; MAX_NAME_LEN];
+        bytes[..name.len()].copy_from_slice(name.as_bytes());
+        Name {
+            bytes: bytes,
+            len: name.len(),
+        }
+    }
+
+    fn as_str(&self) -> &str {
+        // Only copied from a str
+        core::str::from_utf8(&self.bytes[..self.len]).unwrap_or("")
+    }
+
+    fn matches(&self, name: &str) -> bool {
+        self.bytes[..self.len].eq_ignore_ascii_case(name.as_bytes())
+    }
+}
+
+#[derive(Copy, Clone)]
+struct CacheEntry {
+    name: Name,
+    addr: IPAddr,
+    /// Remaining lifetime, in ticks.
+    ttl: u32,
+}
+
+#[derive(Copy, Clone)]
+struct Query {
+    name: Name,
+    id: u16,
+    /// Set for cached names, which are answered from the alarm.
+    answer: Option<IPAddr>,
+    retries: u8,
+    timer: u8,
+    send_pending: bool,
+}
+
+pub struct DNSResolver<'a, A: Alarm<'a>> {
+    udp_sender: &'a dyn UDPSender<'a>,
+    udp_receiver: &'a UDPReceiver<'a>,
+    port_table: &'static UdpPortManager,
+    alarm: &'a A,
+    net_cap: &'static NetworkCapability,
+    server: Cell<Option<IPAddr>>,
+    tx_buf: MapCell<LeasableBuffer<'static, u8>>,
+    cache: [Cell<Option<CacheEntry>>; CACHE_SIZE],
+    query: Cell<Option<Query>>,
+    client: OptionalCell<&'a dyn DNSClient>,
+    next_id: Cell<u16>,
+    /// When the current tick started.
+    tick_start: Cell<A::Ticks>,
+}
+
+impl<'a, A: Alarm<'a>> DNSResolver<'a, A> {
+    /// `initial_id` starts the query IDs, which boards should vary between
+    /// boots, for example from a random number.
+    pub fn new(
+        udp_sender: &'a dyn UDPSender<'a>,
+        udp_receiver: &'a UDPReceiver<'a>,
+        port_table: &'static UdpPortManager,
+        alarm: &'a A,
+        tx_buf: LeasableBuffer<'static, u8>,
+        initial_id: u16,
+        net_cap: &'static NetworkCapability,
+    ) -> DNSResolver<'a, A> {
+        DNSResolver {
+            udp_sender: udp_sender,
+            udp_receiver: udp_receiver,
+            port_table: port_table,
+            alarm: alarm,
+            net_cap: net_cap,
+            server: Cell::new(None),
+            tx_buf: MapCell::new(tx_buf),
+            cache: [
+                Cell::new(None),
+                Cell::new(None),
+                Cell::new(None),
+                Cell::new(None),
+            ],
+            query: Cell::new(None),
+            client: OptionalCell::empty(),
+            next_id: Cell::new(initial_id),
+            tick_start: Cell::new(A::Ticks::from(0)),
+        }
+    }
+
+    /// Binds the resolver to `port`, which queries are sent from. This has
+    /// to be some port other than `MDNS_PORT`, so that responders answer
+    /// mDNS queries directly.
+    pub fn bind(&self, port: u16) -> ReturnCode {
+        if port == MDNS_PORT {
+            return ReturnCode::EINVAL;
+        }
+        if self.udp_sender.is_bound() {
+            return ReturnCode::EALREADY;
+        }
+        let socket = match self.port_table.create_socket() {
+            Ok(socket) => socket,
+            Err(rcode) => return rcode,
+        };
+        self.port_table.set_owner(&socket, "dns");
+        match self.port_table.bind(socket, port, self.net_cap) {
+            Ok((send_binding, recv_binding)) => {
+                self.udp_sender.set_binding(send_binding);
+                self.udp_receiver.set_binding(recv_binding);
+                ReturnCode::SUCCESS
+            }
+            Err(_socket) => ReturnCode::EBUSY,
+        }
+    }
+
+    /// Sets the server that names outside `.local` are resolved by.
+    pub fn set_server(&self, server: IPAddr) {
+        self.server.set(Some(server));
+    }
+
+    /// Resolves `hostname`, such as "tock.example.com", telling `client`
+    /// about the address. Returns `EBUSY` while another name is being
+    /// resolved, `ESIZE` if the name is longer than `MAX_NAME_LEN` and
+    /// `EINVAL` if there is no server for it.
+    pub fn resolve(&self, hostname: &str, client: &'a dyn DNSClient) -> ReturnCode {
+        if self.query.get().is_some() {
+            return ReturnCode::EBUSY;
+        }
+        if hostname.is_empty() || hostname.len() > MAX_NAME_LEN {
+            return ReturnCode::ESIZE;
+        }
+        if !is_local(hostname) && self.server.get().is_none() {
+            return ReturnCode::EINVAL;
+        }
+        self.client.set(client);
+
+        let id = self.next_id.get();
+        self.next_id.set(id.wrapping_add(1));
+        let answer = self.lookup(hostname);
+        self.query.set(Some(Query {
+            name: Name::new(hostname),
+            id: id,
+            answer: answer,
+            retries: 0,
+            timer: QUERY_TIMEOUT_TICKS,
+            send_pending: true,
+        }));
+        match answer {
+            Some(_) => {
+                // Answer as soon as the alarm fires
+                if !self.alarm.is_armed() {
+                    self.tick_start.set(self.alarm.now());
+                }
+                self.alarm.set_alarm(self.alarm.now(), A::Ticks::from(0));
+            }
+            None => {
+                self.start_timer();
+                self.send_query();
+            }
+        }
+        ReturnCode::SUCCESS
+    }
+
+    /// Returns the cached address of `hostname`.
+    pub fn lookup(&self, hostname: &str) -> Option<IPAddr> {
+        self.cache
+            .iter()
+            .filter_map(|entry| entry.get())
+            .find(|entry| entry.name.matches(hostname))
+            .map(|entry| entry.addr)
+    }
+
+    /// Removes every cached name.
+    pub fn flush(&self) {
+        for entry in self.cache.iter() {
+            entry.set(None);
+        }
+    }
+
+    fn start_timer(&self) {
+        if !self.alarm.is_armed() {
+            self.tick_start.set(self.alarm.now());
+            self.alarm
+                .set_alarm(self.alarm.now(), A::ticks_from_ms(TICK_MS));
+        }
+    }
+
+    fn cache_insert(&self, name: Name, addr: IPAddr, ttl: u32) {
+        let ticks = core::cmp::min(ttl, MAX_TTL_S) * 1000 / TICK_MS;
+        if ticks == 0 {
+            return;
+        }
+        let entry = CacheEntry {
+            name: name,
+            addr: addr,
+            ttl: ticks,
+        };
+        let slot = self
+            .cache
+            .iter()
+            .find(|slot| {
+                slot.get()
+                    .map_or(false, |cached| cached.name.matches(name.as_str()))
+            })
+            .or_else(|| self.cache.iter().find(|slot| slot.get().is_none()))
+            .or_else(|| {
+                self.cache
+                    .iter()
+                    .min_by_key(|slot| slot.get().map_or(0, |cached| cached.ttl))
+            });
+        if let Some(slot) = slot {
+            slot.set(Some(entry));
+            self.start_timer();
+        }
+    }
+
+    /// Sends the query if it is pending and the buffer is free.
+    fn send_query(&self) {
+        let mut query = match self.query.get() {
+            Some(query) => query,
+            None => return,
+        };
+        if !query.send_pending || query.answer.is_some() {
+            return;
+        }
+        let mut dgram = match self.tx_buf.take() {
+            Some(dgram) => dgram,
+            None => return,
+        };
+        query.send_pending = false;
+        self.query.set(Some(query));
+
+        let name = query.name.as_str();
+        let (dest, dst_port, flags, qclass) = if is_local(name) {
+            (MDNS_GROUP, MDNS_PORT, 0, CLASS_IN | MDNS_CLASS_FLAG)
+        } else {
+            // resolve() checked for a server
+            let server = self.server.get().unwrap_or_else(IPAddr::new);
+            (server, DNS_PORT, dns_flags::RD, CLASS_IN)
+        };
+        let mut header = DNSHeader::new(query.id, flags);
+        header.qdcount = 1;
+        let len = header
+            .encode(&mut dgram[..], 0)
+            .done()
+            .and_then(|(off, _)| {
+                encode_question(&mut dgram[..], off, name, dns_types::AAAA, qclass)
+                    .done()
+                    .map(|(off, _)| off)
+            });
+        match len {
+            Some(len) => {
+                dgram.slice(..len);
+                if let Err(mut dgram) = self.udp_sender.send_to(dest, dst_port, dgram, self.net_cap)
+                {
+                    // Retransmitted like a lost query
+                    dgram.reset();
+                    self.tx_buf.replace(dgram);
+                }
+            }
+            None => {
+                self.tx_buf.replace(dgram);
+                self.finish(Err(ReturnCode::ESIZE));
+            }
+        }
+    }
+
+    fn finish(&self, result: Result<IPAddr, ReturnCode>) {
+        if let Some(query) = self.query.take() {
+            self.client
+                .map(|client| client.resolved(query.name.as_str(), result));
+        }
+    }
+
+    /// Ages the cache and the query by a tick.
+    fn tick(&self) {
+        for slot in self.cache.iter() {
+            if let Some(mut entry) = slot.get() {
+                entry.ttl -= 1;
+                slot.set(if entry.ttl == 0 { None } else { Some(entry) });
+            }
+        }
+        if let Some(mut query) = self.query.get() {
+            if query.answer.is_some() {
+                return;
+            }
+            query.timer -= 1;
+            if query.timer == 0 {
+                if query.retries >= MAX_RETRIES {
+                    self.finish(Err(ReturnCode::FAIL));
+                    return;
+                }
+                query.retries += 1;
+                query.timer = QUERY_TIMEOUT_TICKS;
+                query.send_pending = true;
+            }
+            self.query.set(Some(query));
+        }
+    }
+}
+
+/// Returns whether `name` is in the `.local` domain of mDNS.
+fn is_local(name: &str) -> bool {
+    name.trim_end_matches('.')
+        .rsplit('.')
+        .next()
+        .map_or(false, |label| label.eq_ignore_ascii_case("local"))
+}
+
+impl<'a, A: Alarm<'a>> time::AlarmClient for DNSResolver<'a, A> {
+    fn alarm(&self) {
+        let tick = A::ticks_from_ms(TICK_MS);
+        let now = self.alarm.now();
+        while now.wrapping_sub(self.tick_start.get()) >= tick {
+            self.tick_start
+                .set(self.tick_start.get().wrapping_add(tick));
+            self.tick();
+        }
+        if let Some(query) = self.query.get() {
+            if let Some(addr) = query.answer {
+                self.finish(Ok(addr));
+            }
+        }
+        self.send_query();
+
+        let active = self.query.get().is_some() || self.cache.iter().any(|e| e.get().is_some());
+        if active {
+            self.alarm.set_alarm(self.tick_start.get(), tick);
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> UDPSendClient for DNSResolver<'a, A> {
+    fn send_done(&self, _result: ReturnCode, mut dgram: LeasableBuffer<'static, u8>) {
+        dgram.reset();
+        self.tx_buf.replace(dgram);
+        self.send_query();
+    }
+}
+
+impl<'a, A: Alarm<'a>> UDPRecvClient for DNSResolver<'a, A> {
+    fn receive(
+        &self,
+        _src_addr: IPAddr,
+        _dst_addr: IPAddr,
+        _src_port: u16,
+        _dst_port: u16,
+        payload: &[u8],
+        _timestamp: Option<u32>,
+    ) {
+        let query = match self.query.get() {
+            Some(query) if query.answer.is_none() => query,
+            _ => return,
+        };
+        let header = match DNSHeader::decode(payload).done() {
+            Some((_, header)) => header,
+            None => return,
+        };
+        if !header.is_response() || header.id != query.id {
+            return;
+        }
+        if header.get_rcode() != 0 {
+            self.finish(Err(ReturnCode::FAIL));
+            return;
+        }
+
+        let mut off = DNS_HDR_LEN;
+        for _ in 0..header.qdcount {
+            off = match DNSQuestion::decode(payload, off).done() {
+                Some((off, _)) => off,
+                None => return,
+            };
+        }
+        // The first address answers the query, even if it is reached
+        // through an alias
+        for _ in 0..header.ancount {
+            let record = match DNSRecord::decode(payload, off).done() {
+                Some((next, record)) => {
+                    off = next;
+                    record
+                }
+                None => break,
+            };
+            if record.rtype == dns_types::AAAA
+                && record.class & !MDNS_CLASS_FLAG == CLASS_IN
+                && record.rdata.len() == 16
+            {
+                let mut addr = IPAddr::new();
+                addr.0.copy_from_slice(record.rdata);
+                self.cache_insert(query.name, addr, record.ttl);
+                self.finish(Ok(addr));
+                return;
+            }
+        }
+        self.finish(Err(ReturnCode::FAIL));
+    }
+}
@@ -0,0 +1,228 @@
+//! An mDNS (RFC 6762) responder, which makes the node reachable by a
+//! `.local` hostname on the link.
+//!
+//! `MDNSResponder` listens on `MDNS_PORT` and answers the AAAA and ANY
+//! questions for its hostname with the addresses of the interface. Answers
+//! are sent to the mDNS group unless the question asked for a unicast
+//! response. Queries from ports other than `MDNS_PORT`, as the
+//! `DNSResolver` sends, are answered directly like a DNS server would
+//! (RFC 6762, 6.7). `announce()` sends the addresses unasked, for example
+//! once they are configured.
+//!
+//! Probing for a unique hostname is not done, so boards have to pick one
+//! that is unique on the link, for example from the MAC address.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//! # use capsules::net::dns::mdns::MDNSResponder;
+//!
+//! let mdns = static_init!(
+//!     MDNSResponder<'static>,
+//!     MDNSResponder::new(
+//!         udp_send,
+//!         udp_recv,
+//!         udp_port_table,
+//!         "tock.local",
+//!         &LOCAL_IP_IFACES,
+//!         LeasableBuffer::new(&mut MDNS_BUF),
+//!         net_cap,
+//!     )
+//! );
+//! udp_send.set_client(mdns);
+//! udp_recv.set_client(mdns);
+//! mdns.bind();
+//! mdns.announce();
+//! ```
+
+use crate::net::dns::dns::{dns_flags, dns_types, CLASS_IN, DNS_HDR_LEN};
+use crate::net::dns::dns::{encode_question, encode_record, name_matches};
+use crate::net::dns::dns::{DNSHeader, DNSQuestion, MDNS_CLASS_FLAG, MDNS_PORT};
+use crate::net::dns::dns_resolver::MDNS_GROUP;
+use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::network_capabilities::NetworkCapability;
+use crate::net::stream::SResult;
+use crate::net::udp::udp_port_table::UdpPortManager;
+use crate::net::udp::udp_recv::{UDPReceiver, UDPRecvClient};
+use crate::net::udp::udp_send::{UDPSendClient, UDPSender};
+use kernel::common::cells::MapCell;
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::ReturnCode;
+
+/// TTL of the answers, which RFC 6762 recommends for address records.
+pub const MDNS_TTL_S: u32 = 120;
+
+/// Highest TTL of answers to queries from other ports (RFC 6762, 6.7).
+pub const LEGACY_TTL_S: u32 = 10;
+
+/// Questions looked at in a query.
+const MAX_QUESTIONS: u16 = 8;
+
+pub struct MDNSResponder<'a> {
+    udp_sender: &'a dyn UDPSender<'a>,
+    udp_receiver: &'a UDPReceiver<'a>,
+    port_table: &'static UdpPortManager,
+    hostname: &'static str,
+    interface_list: &'static [IPAddr],
+    net_cap: &'static NetworkCapability,
+    tx_buf: MapCell<LeasableBuffer<'static, u8>>,
+}
+
+impl<'a> MDNSResponder<'a> {
+    pub fn new(
+        udp_sender: &'a dyn UDPSender<'a>,
+        udp_receiver: &'a UDPReceiver<'a>,
+        port_table: &'static UdpPortManager,
+        hostname: &'static str,
+        interface_list: &'static [IPAddr],
+        tx_buf: LeasableBuffer<'static, u8>,
+        net_cap: &'static NetworkCapability,
+    ) -> MDNSResponder<'a> {
+        MDNSResponder {
+            udp_sender: udp_sender,
+            udp_receiver: udp_receiver,
+            port_table: port_table,
+            hostname: hostname,
+            interface_list: interface_list,
+            net_cap: net_cap,
+            tx_buf: MapCell::new(tx_buf),
+        }
+    }
+
+    /// Binds the responder to `MDNS_PORT`, which it shares with other mDNS
+    /// capsules, and joins the mDNS group.
+    pub fn bind(&self) -> ReturnCode {
+        if self.udp_sender.is_bound() {
+            return ReturnCode::EALREADY;
+        }
+        let socket = match self.port_table.create_socket() {
+            Ok(socket) => socket,
+            Err(rcode) => return rcode,
+        };
+        self.port_table.set_owner(&socket, "mdns");
+        match self.port_table.bind_shared(socket, MDNS_PORT, self.net_cap) {
+            Ok((send_binding, recv_binding)) => {
+                self.udp_sender.set_binding(send_binding);
+                self.udp_receiver.set_binding(recv_binding);
+                self.udp_receiver.join_group(self.port_table, MDNS_GROUP)
+            }
+            Err(_socket) => ReturnCode::EBUSY,
+        }
+    }
+
+    /// Sends the addresses of the interface to the mDNS group. Returns
+    /// `EBUSY` while another message is being sent.
+    pub fn announce(&self) -> ReturnCode {
+        self.respond(MDNS_GROUP, MDNS_PORT, 0, None)
+    }
+
+    /// Sends a response with the addresses of the interface to `dest`. A
+    /// legacy response carries the ID and the question of the query.
+    fn respond(&self, dest: IPAddr, dst_port: u16, id: u16, question: Option<u16>) -> ReturnCode {
+        let mut dgram = match self.tx_buf.take() {
+            Some(dgram) => dgram,
+            None => return ReturnCode::EBUSY,
+        };
+        let len = self.encode_response(&mut dgram[..], id, question).done();
+        let len = match len {
+            Some((len, _)) => len,
+            None => {
+                self.tx_buf.replace(dgram);
+                return ReturnCode::ESIZE;
+            }
+        };
+        dgram.slice(..len);
+        match self.udp_sender.send_to(dest, dst_port, dgram, self.net_cap) {
+            Ok(()) => ReturnCode::SUCCESS,
+            Err(mut dgram) => {
+                dgram.reset();
+                self.tx_buf.replace(dgram);
+                ReturnCode::FAIL
+            }
+        }
+    }
+
+    fn encode_response(&self, buf: &mut [u8], id: u16, question: Option<u16>) -> SResult<usize> {
+        let addrs = self.interface_list.iter().filter(|a| !a.is_unspecified());
+        let mut header = DNSHeader::new(id, dns_flags::QR | dns_flags::AA);
+        header.qdcount = if question.is_some() { 1 } else { 0 };
+        header.ancount = addrs.clone().count() as u16;
+        let (mut off, _) = enc_try!(header.encode(buf, 0));
+
+        let (class, ttl) = match question {
+            Some(qtype) => {
+                off = enc_try!(encode_question(buf, off, self.hostname, qtype, CLASS_IN)).0;
+                (CLASS_IN, LEGACY_TTL_S)
+            }
+            // The addresses replace those cached for the name
+            None => (CLASS_IN | MDNS_CLASS_FLAG, MDNS_TTL_S),
+        };
+        for addr in addrs {
+            off = enc_try!(encode_record(
+                buf,
+                off,
+                self.hostname,
+                dns_types::AAAA,
+                class,
+                ttl,
+                &addr.0
+            ))
+            .0;
+        }
+        stream_done!(off, off);
+    }
+}
+
+impl<'a> UDPSendClient for MDNSResponder<'a> {
+    fn send_done(&self, _result: ReturnCode, mut dgram: LeasableBuffer<'static, u8>) {
+        dgram.reset();
+        self.tx_buf.replace(dgram);
+    }
+}
+
+impl<'a> UDPRecvClient for MDNSResponder<'a> {
+    fn receive(
+        &self,
+        src_addr: IPAddr,
+        _dst_addr: IPAddr,
+        src_port: u16,
+        _dst_port: u16,
+        payload: &[u8],
+        _timestamp: Option<u32>,
+    ) {
+        let header = match DNSHeader::decode(payload).done() {
+            Some((_, header)) => header,
+            None => return,
+        };
+        if header.is_response() {
+            return;
+        }
+        let mut off = DNS_HDR_LEN;
+        for _ in 0..core::cmp::min(header.qdcount, MAX_QUESTIONS) {
+            let question = match DNSQuestion::decode(payload, off).done() {
+                Some((next, question)) => {
+                    off = next;
+                    question
+                }
+                None => return,
+            };
+            if (question.qtype != dns_types::AAAA && question.qtype != dns_types::ANY)
+                || question.qclass & !MDNS_CLASS_FLAG != CLASS_IN
+                || name_matches(payload, question.name_offset, self.hostname) != Some(true)
+            {
+                continue;
+            }
+            // A lost response is asked for again
+            let _ = if src_port != MDNS_PORT {
+                self.respond(src_addr, src_port, header.id, Some(question.qtype))
+            } else if question.qclass & MDNS_CLASS_FLAG != 0 {
+                self.respond(src_addr, MDNS_PORT, 0, None)
+            } else {
+                self.respond(MDNS_GROUP, MDNS_PORT, 0, None)
+            };
+            return;
+        }
+    }
+}
@@ -0,0 +1,3 @@
+pub mod dns;
+pub mod dns_resolver;
+pub mod mdns;
@@ -0,0 +1,389 @@
+//! This file contains the structs and functions associated with the
+//! format of DTLS 1.2 records and handshake messages (RFC 6347), and the
+//! key derivation of the TLS 1.2 PRF (RFC 5246) for the
+//! TLS_PSK_WITH_AES_128_CBC_SHA256 cipher suite (RFC 5487).
+//!
+//! A datagram carries one or more records, each with a 13-byte header:
+//!
+//! ```txt
+//! +------+---------+-------+-----------------+--------+----------+
+//! | type | version | epoch | sequence number | length | fragment |
+//! |  1   |    2    |   2   |        6        |   2    |  length  |
+//! +------+---------+-------+-----------------+--------+----------+
+//! ```
+//!
+//! Records of epoch 0 are in plaintext. After a ChangeCipherSpec, a record
+//! of epoch 1 carries an unpredictable 16-byte IV followed by the AES-CBC
+//! encryption of the plaintext, its HMAC-SHA256 and the CBC padding.
+
+use crate::net::dtls::sha256::{hmac_sha256, SHA256_LEN};
+use crate::net::stream::SResult;
+use crate::net::stream::{decode_bytes, decode_u16, decode_u8};
+use crate::net::stream::{encode_bytes, encode_u16, encode_u32, encode_u8};
+use kernel::common::constant_time;
+use kernel::hil::symmetric_encryption::{AES128_BLOCK_SIZE, AES128_KEY_SIZE};
+
+/// The version field of DTLS 1.2 records and handshake messages.
+pub const DTLS_1_2: u16 = 0xfefd;
+
+pub const RECORD_HDR_LEN: usize = 13;
+pub const HANDSHAKE_HDR_LEN: usize = 12;
+
+pub const TLS_PSK_WITH_AES_128_CBC_SHA256: u16 = 0x00ae;
+
+/// The null compression method, the only one offered.
+pub const COMPRESSION_NULL: u8 = 0;
+
+pub const RANDOM_LEN: usize = 32;
+pub const MASTER_SECRET_LEN: usize = 48;
+pub const MAC_LEN: usize = SHA256_LEN;
+pub const VERIFY_DATA_LEN: usize = 12;
+pub const MAX_COOKIE_LEN: usize = 64;
+pub const MAX_PSK_LEN: usize = 32;
+
+/// The most a record of epoch 1 adds to its plaintext: the IV, the MAC
+/// and up to a block of padding.
+pub const MAX_RECORD_OVERHEAD: usize =
+    RECORD_HDR_LEN + AES128_BLOCK_SIZE + MAC_LEN + AES128_BLOCK_SIZE;
+
+pub mod content_types {
+    pub const CHANGE_CIPHER_SPEC: u8 = 20;
+    pub const ALERT: u8 = 21;
+    pub const HANDSHAKE: u8 = 22;
+    pub const APPLICATION_DATA: u8 = 23;
+}
+
+pub mod handshake_types {
+    pub const CLIENT_HELLO: u8 = 1;
+    pub const SERVER_HELLO: u8 = 2;
+    pub const HELLO_VERIFY_REQUEST: u8 = 3;
+    pub const SERVER_KEY_EXCHANGE: u8 = 12;
+    pub const SERVER_HELLO_DONE: u8 = 14;
+    pub const CLIENT_KEY_EXCHANGE: u8 = 16;
+    pub const FINISHED: u8 = 20;
+}
+
+pub mod alerts {
+    pub const LEVEL_WARNING: u8 = 1;
+    pub const LEVEL_FATAL: u8 = 2;
+
+    pub const CLOSE_NOTIFY: u8 = 0;
+    pub const HANDSHAKE_FAILURE: u8 = 40;
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct RecordHeader {
+    pub content_type: u8,
+    pub epoch: u16,
+    /// The 48-bit sequence number of the record in its epoch.
+    pub seq: u64,
+    pub length: u16,
+}
+
+impl RecordHeader {
+    pub fn new(content_type: u8, epoch: u16, seq: u64, length: u16) -> RecordHeader {
+        RecordHeader {
+            content_type: content_type,
+            epoch: epoch,
+            seq: seq,
+            length: length,
+        }
+    }
+
+    pub fn encode(&self, buf: &mut [u8]) -> SResult {
+        stream_len_cond!(buf, RECORD_HDR_LEN);
+
+        let mut off = enc_consume!(buf, 0; encode_u8, self.content_type);
+        off = enc_consume!(buf, off; encode_u16, DTLS_1_2);
+        off = enc_consume!(buf, off; encode_u16, self.epoch);
+        off = enc_consume!(buf, off; encode_u16, (self.seq >> 32) as u16);
+        off = enc_consume!(buf, off; encode_u32, self.seq as u32);
+        off = enc_consume!(buf, off; encode_u16, self.length);
+        stream_done!(off);
+    }
+
+    /// Decodes the header of any DTLS version, leaving the fragment to
+    /// the caller. The record has to hold `length` bytes after it.
+    pub fn decode(buf: &[u8]) -> SResult<RecordHeader> {
+        stream_len_cond!(buf, RECORD_HDR_LEN);
+
+        let (off, content_type) = dec_try!(buf, 0; decode_u8);
+        let (off, version) = dec_try!(buf, off; decode_u16);
+        stream_cond!(version >> 8 == DTLS_1_2 >> 8);
+        let (off, epoch) = dec_try!(buf, off; decode_u16);
+        let (off, seq) = dec_try!(buf, off; decode_u48);
+        let (off, length) = dec_try!(buf, off; decode_u16);
+        stream_len_cond!(buf, off + length as usize);
+        stream_done!(
+            off,
+            RecordHeader {
+                content_type: content_type,
+                epoch: epoch,
+                seq: seq,
+                length: length,
+            }
+        );
+    }
+}
+
+/// The header of an unfragmented handshake message.
+#[derive(Copy, Clone, Debug)]
+pub struct HandshakeHeader {
+    pub msg_type: u8,
+    pub length: u32,
+    pub message_seq: u16,
+}
+
+impl HandshakeHeader {
+    pub fn new(msg_type: u8, length: u32, message_seq: u16) -> HandshakeHeader {
+        HandshakeHeader {
+            msg_type: msg_type,
+            length: length,
+            message_seq: message_seq,
+        }
+    }
+
+    pub fn encode(&self, buf: &mut [u8]) -> SResult {
+        stream_len_cond!(buf, HANDSHAKE_HDR_LEN);
+
+        let mut off = enc_consume!(buf, 0; encode_u8, self.msg_type);
+        off = enc_consume!(buf, off; encode_u24, self.length);
+        off = enc_consume!(buf, off; encode_u16, self.message_seq);
+        // The whole message is a single fragment
+        off = enc_consume!(buf, off; encode_u24, 0);
+        off = enc_consume!(buf, off; encode_u24, self.length);
+        stream_done!(off);
+    }
+
+    /// Decodes the header of a whole message, which `buf` has to hold.
+    /// Fragments of messages are errors.
+    pub fn decode(buf: &[u8]) -> SResult<HandshakeHeader> {
+        stream_len_cond!(buf, HANDSHAKE_HDR_LEN);
+
+        let (off, msg_type) = dec_try!(buf, 0; decode_u8);
+        let (off, length) = dec_try!(buf, off; decode_u24);
+        let (off, message_seq) = dec_try!(buf, off; decode_u16);
+        let (off, fragment_offset) = dec_try!(buf, off; decode_u24);
+        let (off, fragment_length) = dec_try!(buf, off; decode_u24);
+        stream_cond!(fragment_offset == 0 && fragment_length == length);
+        stream_len_cond!(buf, off + length as usize);
+        stream_done!(
+            off,
+            HandshakeHeader {
+                msg_type: msg_type,
+                length: length,
+                message_seq: message_seq,
+            }
+        );
+    }
+}
+
+/// Encodes the body of a ClientHello that offers only
+/// `TLS_PSK_WITH_AES_128_CBC_SHA256`, without a session ID or extensions.
+pub fn encode_client_hello(buf: &mut [u8], random: &[u8; RANDOM_LEN], cookie: &[u8]) -> SResult {
+    stream_cond!(cookie.len() <= MAX_COOKIE_LEN);
+
+    let mut off = enc_consume!(buf, 0; encode_u16, DTLS_1_2);
+    off = enc_consume!(buf, off; encode_bytes, random);
+    // No session ID
+    off = enc_consume!(buf, off; encode_u8, 0);
+    off = enc_consume!(buf, off; encode_u8, cookie.len() as u8);
+    off = enc_consume!(buf, off; encode_bytes, cookie);
+    off = enc_consume!(buf, off; encode_u16, 2);
+    off = enc_consume!(buf, off; encode_u16, TLS_PSK_WITH_AES_128_CBC_SHA256);
+    off = enc_consume!(buf, off; encode_u8, 1);
+    off = enc_consume!(buf, off; encode_u8, COMPRESSION_NULL);
+    stream_done!(off);
+}
+
+/// Decodes the body of a HelloVerifyRequest, copying its cookie to
+/// `cookie` and returning its length.
+pub fn decode_hello_verify_request(
+    buf: &[u8],
+    cookie: &mut [u8; MAX_COOKIE_LEN],
+) -> SResult<usize> {
+    let (off, _server_version) = dec_try!(buf, 0; decode_u16);
+    let (off, cookie_len) = dec_try!(buf, off; decode_u8);
+    let cookie_len = cookie_len as usize;
+    stream_cond!(cookie_len <= MAX_COOKIE_LEN);
+    let off = dec_consume!(buf, off; decode_bytes, &mut cookie[..cookie_len]);
+    stream_done!(off, cookie_len);
+}
+
+/// Decodes the body of a ServerHello, which has to choose DTLS 1.2 and
+/// the offered cipher suite, returning the server random. As none were
+/// offered, extensions are ignored.
+pub fn decode_server_hello(buf: &[u8]) -> SResult<[u8; RANDOM_LEN]> {
+    let (off, version) = dec_try!(buf, 0; decode_u16);
+    stream_cond!(version == DTLS_1_2);
+    let mut random = [0; RANDOM_LEN];
+    let off = dec_consume!(buf, off; decode_bytes, &mut random);
+    let (off, session_id_len) = dec_try!(buf, off; decode_u8);
+    let off = off + session_id_len as usize;
+    stream_len_cond!(buf, off);
+    let (off, cipher_suite) = dec_try!(buf, off; decode_u16);
+    stream_cond!(cipher_suite == TLS_PSK_WITH_AES_128_CBC_SHA256);
+    let (off, compression) = dec_try!(buf, off; decode_u8);
+    stream_cond!(compression == COMPRESSION_NULL);
+    stream_done!(off, random);
+}
+
+fn encode_u24(buf: &mut [u8], n: u32) -> SResult {
+    stream_len_cond!(buf, 3);
+    buf[..3].copy_from_slice(&n.to_be_bytes()[1..]);
+    stream_done!(3);
+}
+
+fn decode_u24(buf: &[u8]) -> SResult<u32> {
+    stream_len_cond!(buf, 3);
+    stream_done!(
+        3,
+        (buf[0] as u32) << 16 | (buf[1] as u32) << 8 | buf[2] as u32
+    );
+}
+
+fn decode_u48(buf: &[u8]) -> SResult<u64> {
+    stream_len_cond!(buf, 6);
+    let mut seq = [0; 8];
+    seq[2..].copy_from_slice(&buf[..6]);
+    stream_done!(6, u64::from_be_bytes(seq));
+}
+
+/// Fills `out` with the TLS 1.2 PRF of `secret`, `label` and the seed
+/// `seed_1` followed by `seed_2`.
+pub fn prf(secret: &[u8], label: &[u8], seed_1: &[u8], seed_2: &[u8], out: &mut [u8]) {
+    let mut a = hmac_sha256(secret, &[label, seed_1, seed_2]);
+    for chunk in out.chunks_mut(SHA256_LEN) {
+        let block = hmac_sha256(secret, &[&a, label, seed_1, seed_2]);
+        chunk.copy_from_slice(&block[..chunk.len()]);
+        a = hmac_sha256(secret, &[&a]);
+    }
+}
+
+/// The keys of a session, derived from its PSK and the randoms of its
+/// handshake.
+#[derive(Copy, Clone)]
+pub struct SessionKeys {
+    pub master_secret: [u8; MASTER_SECRET_LEN],
+    pub client_mac_key: [u8; MAC_LEN],
+    pub server_mac_key: [u8; MAC_LEN],
+    pub client_key: [u8; AES128_KEY_SIZE],
+    pub server_key: [u8; AES128_KEY_SIZE],
+}
+
+impl SessionKeys {
+    /// Derives the keys from `psk`, which is at most `MAX_PSK_LEN` bytes
+    /// long.
+    pub fn derive(
+        psk: &[u8],
+        client_random: &[u8; RANDOM_LEN],
+        server_random: &[u8; RANDOM_LEN],
+    ) -> SessionKeys {
+        // The premaster secret of a PSK is its length, as many zeros, its
+        // length again and the PSK itself
+        let psk_len = (psk.len() as u16).to_be_bytes();
+        let mut premaster = [0; 4 + 2 * MAX_PSK_LEN];
+        let pms_len = 4 + 2 * psk.len();
+        premaster[..2].copy_from_slice(&psk_len);
+        premaster[2 + psk.len()..4 + psk.len()].copy_from_slice(&psk_len);
+        premaster[4 + psk.len()..pms_len].copy_from_slice(psk);
+
+        let mut keys = SessionKeys {
+            master_secret: [0; MASTER_SECRET_LEN],
+            client_mac_key: [0; MAC_LEN],
+            server_mac_key: [0; MAC_LEN],
+            client_key: [0; AES128_KEY_SIZE],
+            server_key: [0; AES128_KEY_SIZE],
+        };
+        prf(
+            &premaster[..pms_len],
+            b"master secret",
+            client_random,
+            server_random,
+            &mut keys.master_secret,
+        );
+        constant_time::wipe(&mut premaster);
+
+        let mut key_block = [0; 2 * MAC_LEN + 2 * AES128_KEY_SIZE];
+        prf(
+            &keys.master_secret,
+            b"key expansion",
+            server_random,
+            client_random,
+            &mut key_block,
+        );
+        let (mac_keys, enc_keys) = key_block.split_at(2 * MAC_LEN);
+        keys.client_mac_key.copy_from_slice(&mac_keys[..MAC_LEN]);
+        keys.server_mac_key.copy_from_slice(&mac_keys[MAC_LEN..]);
+        keys.client_key
+            .copy_from_slice(&enc_keys[..AES128_KEY_SIZE]);
+        keys.server_key
+            .copy_from_slice(&enc_keys[AES128_KEY_SIZE..]);
+        constant_time::wipe(&mut key_block);
+        keys
+    }
+
+    /// The verify data of the Finished message with `label`, "client
+    /// finished" or "server finished", after the handshake messages with
+    /// `handshake_hash`.
+    pub fn verify_data(
+        &self,
+        label: &[u8],
+        handshake_hash: &[u8; SHA256_LEN],
+    ) -> [u8; VERIFY_DATA_LEN] {
+        let mut verify_data = [0; VERIFY_DATA_LEN];
+        prf(
+            &self.master_secret,
+            label,
+            handshake_hash,
+            &[],
+            &mut verify_data,
+        );
+        verify_data
+    }
+
+    pub fn wipe(&mut self) {
+        constant_time::wipe(&mut self.master_secret);
+        constant_time::wipe(&mut self.client_mac_key);
+        constant_time::wipe(&mut self.server_mac_key);
+        constant_time::wipe(&mut self.client_key);
+        constant_time::wipe(&mut self.server_key);
+    }
+}
+
+/// The MAC of the record with `header`, whose length is that of the
+/// `plaintext`, under `mac_key`.
+pub fn record_mac(mac_key: &[u8], header: &RecordHeader, plaintext: &[u8]) -> [u8; MAC_LEN] {
+    let mut seq = header.seq.to_be_bytes();
+    seq[..2].copy_from_slice(&header.epoch.to_be_bytes());
+    let version = DTLS_1_2.to_be_bytes();
+    let length = (plaintext.len() as u16).to_be_bytes();
+    hmac_sha256(
+        mac_key,
+        &[
+            &seq,
+            &[
+                header.content_type,
+                version[0],
+                version[1],
+                length[0],
+                length[1],
+            ],
+            plaintext,
+        ],
+    )
+}
+
+/// The IV of the record with `epoch` and `seq`. It has to be unpredictable
+/// to an attacker, which is the case for the HMAC of the record position
+/// under the secret `mac_key`, and it is never used twice.
+pub fn record_iv(mac_key: &[u8], epoch: u16, seq: u64) -> [u8; AES128_BLOCK_SIZE] {
+    let mut iv = [0; AES128_BLOCK_SIZE];
+    iv.copy_from_slice(
+        &hmac_sha256(
+            mac_key,
+            &[b"record iv", &epoch.to_be_bytes(), &seq.to_be_bytes()],
+        )[..AES128_BLOCK_SIZE],
+    );
+    iv
+}
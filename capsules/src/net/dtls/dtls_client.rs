@@ -0,0 +1,1223 @@
+//! A DTLS 1.2 client (RFC 6347) that secures the datagrams of a UDP socket
+//! to one server with a pre-shared key.
+//!
+//! `DTLSClient` runs over a `UDPSender` and `UDPReceiver` bound to a port
+//! of its own and negotiates TLS_PSK_WITH_AES_128_CBC_SHA256:
+//!
+//! 1. `open()` sends a ClientHello. A server that answers with a
+//!    HelloVerifyRequest gets the ClientHello again with its cookie.
+//! 2. The server sends its ServerHello, maybe a ServerKeyExchange, whose
+//!    PSK identity hint is not used, and its ServerHelloDone.
+//! 3. The client derives the keys from the PSK and sends its
+//!    ClientKeyExchange with the PSK identity, a ChangeCipherSpec and its
+//!    encrypted Finished in one datagram.
+//! 4. The session is open once the ChangeCipherSpec and Finished of the
+//!    server are verified.
+//!
+//! A flight of the client is sent again when the server does not answer
+//! it within `INITIAL_TIMEOUT_MS`, doubling the timeout each time, and the
+//! handshake fails after `MAX_RETRANSMITS`.
+//!
+//! The session is used in one of two ways. As a `SecureSession`, for
+//! example by the `SecureSessionDriver`, it is opened and closed
+//! explicitly. It is also a `UDPSender`, whose `send_to()` opens the
+//! session to the destination when it is closed, and passes the plaintext
+//! it receives to its receive client as a `UDPReceiver` would. Kernel UDP
+//! users such as CoAP are secured by sending through the session instead
+//! of their own `UDPSendStruct`.
+//!
+//! The client does not resume sessions or renegotiate, takes handshake
+//! messages only whole, and decrypts one record of epoch 1 per datagram.
+//! As for `AES128CCM`, the AES engine has to be used by the session alone.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//! # use capsules::net::dtls::dtls_client::DTLSClient;
+//! # use capsules::net::secure_session::SecureSession;
+//!
+//! let dtls = static_init!(
+//!     DTLSClient<
+//!         'static,
+//!         VirtualMuxAlarm<'static, sam4l::ast::Ast>,
+//!         sam4l::aes::Aes<'static>,
+//!     >,
+//!     DTLSClient::new(
+//!         udp_send,
+//!         udp_recv,
+//!         udp_port_table,
+//!         dtls_alarm,
+//!         &sam4l::aes::AES,
+//!         rng,
+//!         &mut DTLS_CRYPT_BUF,
+//!         &mut DTLS_RX_BUF,
+//!         LeasableBuffer::new(&mut DTLS_TX_BUF),
+//!         net_cap,
+//!     )
+//! );
+//! udp_send.set_client(dtls);
+//! udp_recv.set_client(dtls);
+//! dtls_alarm.set_alarm_client(dtls);
+//! sam4l::aes::AES.set_client(dtls);
+//! rng.set_client(dtls);
+//! dtls.set_psk(b"tock", &DTLS_PSK);
+//! dtls.bind(DTLS_CLIENT_PORT);
+//! SecureSession::set_client(dtls, secure_session_driver);
+//! ```
+
+use crate::net::dtls::dtls::{alerts, content_types, handshake_types};
+use crate::net::dtls::dtls::{decode_hello_verify_request, decode_server_hello};
+use crate::net::dtls::dtls::{encode_client_hello, record_iv, record_mac};
+use crate::net::dtls::dtls::{HandshakeHeader, RecordHeader, SessionKeys};
+use crate::net::dtls::dtls::{HANDSHAKE_HDR_LEN, MAC_LEN, MAX_COOKIE_LEN, MAX_PSK_LEN};
+use crate::net::dtls::dtls::{RANDOM_LEN, RECORD_HDR_LEN, VERIFY_DATA_LEN};
+use crate::net::dtls::sha256::Sha256;
+use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::network_capabilities::NetworkCapability;
+use crate::net::secure_session::{SecureSession, SecureSessionClient};
+use crate::net::stream::SResult;
+use crate::net::stream::{encode_bytes, encode_u16, encode_u8};
+use crate::net::udp::udp::UDPHeader;
+use crate::net::udp::udp_port_table::{UdpPortBindingTx, UdpPortManager};
+use crate::net::udp::udp_recv::{UDPReceiver, UDPRecvClient};
+use crate::net::udp::udp_send::{UDPSendClient, UDPSender};
+use core::cell::Cell;
+use kernel::capabilities::UdpDriverCapability;
+use kernel::common::cells::{MapCell, OptionalCell, TakeCell};
+use kernel::common::constant_time;
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::hil::rng;
+use kernel::hil::symmetric_encryption::{self, AES128, AES128CBC, AES128_BLOCK_SIZE};
+use kernel::hil::time::{self, Alarm};
+use kernel::ReturnCode;
+
+pub const INITIAL_TIMEOUT_MS: u32 = 1000;
+pub const MAX_RETRANSMITS: u8 = 5;
+
+pub const MAX_IDENTITY_LEN: usize = 32;
+
+/// The length of a ClientHello with the longest cookie.
+const CLIENT_HELLO_MAX_LEN: usize = HANDSHAKE_HDR_LEN + 42 + MAX_COOKIE_LEN;
+
+/// Offset of the plaintext of an encrypted record, after its IV.
+const PLAINTEXT_OFF: usize = RECORD_HDR_LEN + AES128_BLOCK_SIZE;
+
+/// The number of records before the latest that the replay window covers.
+const REPLAY_WINDOW: u64 = 32;
+
+#[derive(Copy, Clone, PartialEq)]
+enum State {
+    Closed,
+    /// Waiting for the client random.
+    Random,
+    /// Waiting for a HelloVerifyRequest or the ServerHello.
+    ClientHello,
+    /// Waiting for the rest of the flight of the ServerHello.
+    ServerHello,
+    /// Waiting for the ChangeCipherSpec of the server, then for its
+    /// Finished once `ccs`.
+    Finished {
+        ccs: bool,
+    },
+    Open,
+}
+
+#[derive(Copy, Clone)]
+enum CryptOp {
+    /// The first `len` bytes of the crypt buffer are sent to `dst` once the
+    /// last record is encrypted. `data` if the datagram carries the pending
+    /// message.
+    Encrypt {
+        dst: (IPAddr, u16),
+        len: usize,
+        data: bool,
+    },
+    /// The first `len` bytes of the crypt buffer are the record with
+    /// `header`, which was sent to `dst_addr`:`dst_port`.
+    Decrypt {
+        header: RecordHeader,
+        len: usize,
+        dst_addr: IPAddr,
+        dst_port: u16,
+    },
+}
+
+/// A message of a user, waiting for the handshake or being sent.
+enum Pending {
+    Message(&'static mut [u8], usize),
+    Datagram(LeasableBuffer<'static, u8>),
+}
+
+impl Pending {
+    fn data(&self) -> &[u8] {
+        match self {
+            Pending::Message(buf, len) => &buf[..*len],
+            Pending::Datagram(dgram) => &dgram[..],
+        }
+    }
+}
+
+pub struct DTLSClient<'a, A: Alarm<'a>, C: AES128<'a> + AES128CBC> {
+    udp_sender: &'a dyn UDPSender<'a>,
+    udp_receiver: &'a UDPReceiver<'a>,
+    port_table: &'static UdpPortManager,
+    alarm: &'a A,
+    aes: &'a C,
+    rng: &'a dyn rng::Rng<'a>,
+    net_cap: &'static NetworkCapability,
+    crypt_buf: TakeCell<'a, [u8]>,
+    rx_buf: TakeCell<'static, [u8]>,
+    tx_buf: MapCell<LeasableBuffer<'static, u8>>,
+    crypt_op: OptionalCell<CryptOp>,
+    session_client: OptionalCell<&'a dyn SecureSessionClient>,
+    send_client: OptionalCell<&'a dyn UDPSendClient>,
+    recv_client: OptionalCell<&'a dyn UDPRecvClient>,
+
+    state: Cell<State>,
+    peer: Cell<Option<(IPAddr, u16)>>,
+    /// The PSK identity and the PSK.
+    psk: Cell<Option<(&'static [u8], &'static [u8])>>,
+    client_random: Cell<[u8; RANDOM_LEN]>,
+    /// How much of the client random the RNG has provided.
+    random_len: Cell<usize>,
+    server_random: Cell<[u8; RANDOM_LEN]>,
+    cookie: Cell<([u8; MAX_COOKIE_LEN], usize)>,
+    /// The hash of the handshake messages since the ServerHello.
+    transcript: MapCell<Sha256>,
+    keys: MapCell<SessionKeys>,
+    client_verify: Cell<[u8; VERIFY_DATA_LEN]>,
+    /// The sequence numbers of the next records of epoch 0 and 1.
+    epoch_0_seq: Cell<u64>,
+    epoch_1_seq: Cell<u64>,
+    /// The message sequence number of the last ClientHello.
+    message_seq: Cell<u16>,
+    /// The message sequence number of the next message of the server.
+    rx_message_seq: Cell<u16>,
+    /// The latest record of epoch 1 received and a bitmap of the records
+    /// before it that were.
+    replay: Cell<Option<(u64, u32)>>,
+    timeout_ms: Cell<u32>,
+    retransmits: Cell<u8>,
+    pending: MapCell<Pending>,
+    /// Whether the datagram being sent carries the pending message.
+    sending_data: Cell<bool>,
+}
+
+impl<'a, A: Alarm<'a>, C: AES128<'a> + AES128CBC> DTLSClient<'a, A, C> {
+    /// `crypt_buf` and `tx_buf` hold a datagram as sent. Besides the
+    /// handshake, for which 200 bytes are enough, they have to be
+    /// `MAX_RECORD_OVERHEAD` bytes longer than the longest message sent.
+    /// `crypt_buf` also holds the records received, and `rx_buf` their
+    /// plaintext.
+    pub fn new(
+        udp_sender: &'a dyn UDPSender<'a>,
+        udp_receiver: &'a UDPReceiver<'a>,
+        port_table: &'static UdpPortManager,
+        alarm: &'a A,
+        aes: &'a C,
+        rng: &'a dyn rng::Rng<'a>,
+        crypt_buf: &'static mut [u8],
+        rx_buf: &'static mut [u8],
+        tx_buf: LeasableBuffer<'static, u8>,
+        net_cap: &'static NetworkCapability,
+    ) -> DTLSClient<'a, A, C> {
+        DTLSClient {
+            udp_sender: udp_sender,
+            udp_receiver: udp_receiver,
+            port_table: port_table,
+            alarm: alarm,
+            aes: aes,
+            rng: rng,
+            net_cap: net_cap,
+            crypt_buf: TakeCell::new(crypt_buf),
+            rx_buf: TakeCell::new(rx_buf),
+            tx_buf: MapCell::new(tx_buf),
+            crypt_op: OptionalCell::empty(),
+            session_client: OptionalCell::empty(),
+            send_client: OptionalCell::empty(),
+            recv_client: OptionalCell::empty(),
+            state: Cell::new(State::Closed),
+            peer: Cell::new(None),
+            psk: Cell::new(None),
+            client_random: Cell::new([0; RANDOM_LEN]),
+            random_len: Cell::new(0),
+            server_random: Cell::new([0; RANDOM_LEN]),
+            cookie: Cell::new(([0; MAX_COOKIE_LEN], 0)),
+            transcript: MapCell::empty(),
+            keys: MapCell::empty(),
+            client_verify: Cell::new([0; VERIFY_DATA_LEN]),
+            epoch_0_seq: Cell::new(0),
+            epoch_1_seq: Cell::new(0),
+            message_seq: Cell::new(0),
+            rx_message_seq: Cell::new(0),
+            replay: Cell::new(None),
+            timeout_ms: Cell::new(INITIAL_TIMEOUT_MS),
+            retransmits: Cell::new(0),
+            pending: MapCell::empty(),
+            sending_data: Cell::new(false),
+        }
+    }
+
+    /// Binds the session to the local `port`.
+    pub fn bind(&self, port: u16) -> ReturnCode {
+        if self.udp_sender.is_bound() {
+            return ReturnCode::EALREADY;
+        }
+        let socket = match self.port_table.create_socket() {
+            Ok(socket) => socket,
+            Err(rcode) => return rcode,
+        };
+        self.port_table.set_owner(&socket, "dtls_client");
+        match self.port_table.bind(socket, port, self.net_cap) {
+            Ok((send_binding, recv_binding)) => {
+                self.udp_sender.set_binding(send_binding);
+                self.udp_receiver.set_binding(recv_binding);
+                ReturnCode::SUCCESS
+            }
+            Err(_socket) => ReturnCode::EBUSY,
+        }
+    }
+
+    /// Sets the PSK the server knows by `identity`, for the handshakes
+    /// that follow. Returns `EINVAL` if `identity` is longer than
+    /// `MAX_IDENTITY_LEN` or `psk` is empty or longer than `MAX_PSK_LEN`.
+    pub fn set_psk(&self, identity: &'static [u8], psk: &'static [u8]) -> ReturnCode {
+        if identity.len() > MAX_IDENTITY_LEN || psk.is_empty() || psk.len() > MAX_PSK_LEN {
+            return ReturnCode::EINVAL;
+        }
+        self.psk.set(Some((identity, psk)));
+        ReturnCode::SUCCESS
+    }
+
+    /// Sets the client the plaintext of received datagrams is passed to.
+    pub fn set_receive_client(&self, client: &'a dyn UDPRecvClient) {
+        self.recv_client.set(client);
+    }
+
+    /// The server of the session, until it is closed.
+    pub fn get_peer(&self) -> Option<(IPAddr, u16)> {
+        self.peer.get()
+    }
+
+    /// Starts a handshake with `dest`:`dst_port`. Returns `EINVAL` without
+    /// a PSK and `EOFF` while the session is not bound.
+    fn start(&self, dest: IPAddr, dst_port: u16) -> ReturnCode {
+        if self.psk.get().is_none() {
+            return ReturnCode::EINVAL;
+        }
+        if !self.udp_sender.is_bound() {
+            return ReturnCode::EOFF;
+        }
+        if self.state.get() != State::Closed {
+            return ReturnCode::EBUSY;
+        }
+        self.peer.set(Some((dest, dst_port)));
+        self.random_len.set(0);
+        self.state.set(State::Random);
+        let result = self.rng.get();
+        if result != ReturnCode::SUCCESS {
+            self.state.set(State::Closed);
+            self.peer.set(None);
+        }
+        result
+    }
+
+    fn reset(&self) {
+        self.state.set(State::Closed);
+        self.peer.set(None);
+        self.transcript.take();
+        if let Some(mut keys) = self.keys.take() {
+            keys.wipe();
+        }
+        self.replay.set(None);
+        let _ = self.alarm.disarm();
+    }
+
+    /// Ends a handshake that failed or was cancelled with `result`.
+    fn fail(&self, result: ReturnCode) {
+        self.reset();
+        self.return_pending(result);
+        self.session_client.map(|client| client.opened(result));
+    }
+
+    /// Ends an open session, with `result` if the server closed it.
+    fn end(&self, result: ReturnCode) {
+        self.reset();
+        self.return_pending(ReturnCode::ECANCEL);
+        self.session_client.map(|client| client.closed(result));
+    }
+
+    fn return_pending(&self, result: ReturnCode) {
+        match self.pending.take() {
+            Some(Pending::Message(buf, _)) => {
+                self.session_client
+                    .map(move |client| client.send_done(result, buf));
+            }
+            Some(Pending::Datagram(dgram)) => {
+                self.send_client
+                    .map(move |client| client.send_done(result, dgram));
+            }
+            None => {}
+        }
+    }
+
+    fn set_timer(&self, ms: u32) {
+        self.alarm.set_alarm(self.alarm.now(), A::ticks_from_ms(ms));
+    }
+
+    /// Starts the retransmission timer of a new flight.
+    fn start_timer(&self) {
+        self.timeout_ms.set(INITIAL_TIMEOUT_MS);
+        self.retransmits.set(0);
+        self.set_timer(INITIAL_TIMEOUT_MS);
+    }
+
+    /// Waits twice as long for the answer to a flight sent again.
+    fn back_off(&self) {
+        let timeout_ms = self.timeout_ms.get() * 2;
+        self.timeout_ms.set(timeout_ms);
+        self.retransmits.set(self.retransmits.get() + 1);
+        self.set_timer(timeout_ms);
+    }
+
+    /// Encodes the header of a record of epoch 0 whose `len` bytes follow
+    /// it.
+    fn encode_plain_header(&self, buf: &mut [u8], content_type: u8, len: usize) -> SResult {
+        let seq = self.epoch_0_seq.get();
+        self.epoch_0_seq.set(seq + 1);
+        RecordHeader::new(content_type, 0, seq, len as u16).encode(buf)
+    }
+
+    /// Encodes the last ClientHello, with its handshake header.
+    fn encode_hello(&self, buf: &mut [u8]) -> SResult {
+        let (cookie, cookie_len) = self.cookie.get();
+        let random = self.client_random.get();
+        let cookie = &cookie[..cookie_len];
+        let off = enc_consume!(buf, HANDSHAKE_HDR_LEN; encode_client_hello, &random, cookie);
+        let header = HandshakeHeader::new(
+            handshake_types::CLIENT_HELLO,
+            (off - HANDSHAKE_HDR_LEN) as u32,
+            self.message_seq.get(),
+        );
+        enc_consume!(buf, 0; header; encode);
+        stream_done!(off);
+    }
+
+    fn encode_client_key_exchange(&self, buf: &mut [u8]) -> SResult {
+        let (identity, _) = stream_from_option!(self.psk.get());
+        let header = HandshakeHeader::new(
+            handshake_types::CLIENT_KEY_EXCHANGE,
+            2 + identity.len() as u32,
+            self.message_seq.get() + 1,
+        );
+        let mut off = enc_consume!(buf, 0; header; encode);
+        off = enc_consume!(buf, off; encode_u16, identity.len() as u16);
+        off = enc_consume!(buf, off; encode_bytes, identity);
+        stream_done!(off);
+    }
+
+    fn encode_finished(&self, buf: &mut [u8]) -> SResult {
+        let header = HandshakeHeader::new(
+            handshake_types::FINISHED,
+            VERIFY_DATA_LEN as u32,
+            self.message_seq.get() + 2,
+        );
+        let mut off = enc_consume!(buf, 0; header; encode);
+        off = enc_consume!(buf, off; encode_bytes, &self.client_verify.get());
+        stream_done!(off);
+    }
+
+    /// Encodes the ClientKeyExchange and ChangeCipherSpec records and the
+    /// Finished record in plaintext, returning the offset of the latter.
+    fn encode_flight_5(&self, buf: &mut [u8]) -> SResult<usize> {
+        let ccs = enc_consume!(buf, RECORD_HDR_LEN; self; encode_client_key_exchange);
+        let cke_len = ccs - RECORD_HDR_LEN;
+        enc_consume!(buf, 0; self; encode_plain_header, content_types::HANDSHAKE, cke_len);
+        let off = enc_consume!(buf, ccs + RECORD_HDR_LEN; encode_u8, 1);
+        let ccs_type = content_types::CHANGE_CIPHER_SPEC;
+        enc_consume!(buf, ccs; self; encode_plain_header, ccs_type, 1);
+        let finished = off;
+        let off = enc_consume!(buf, finished + PLAINTEXT_OFF; self; encode_finished);
+        stream_done!(off, finished);
+    }
+
+    fn send_dgram(&self, dst: (IPAddr, u16), dgram: LeasableBuffer<'static, u8>) -> ReturnCode {
+        match self.udp_sender.send_to(dst.0, dst.1, dgram, self.net_cap) {
+            Ok(()) => ReturnCode::SUCCESS,
+            Err(mut dgram) => {
+                dgram.reset();
+                self.tx_buf.replace(dgram);
+                ReturnCode::FAIL
+            }
+        }
+    }
+
+    /// Sends the ClientHello. If the datagram cannot be sent, the timer
+    /// sends it again.
+    fn send_client_hello(&self) {
+        let dst = match self.peer.get() {
+            Some(dst) => dst,
+            None => return,
+        };
+        self.tx_buf.take().map(|mut dgram| {
+            let len = self
+                .encode_hello(&mut dgram[RECORD_HDR_LEN..])
+                .done()
+                .and_then(|(len, _)| {
+                    self.encode_plain_header(&mut dgram[..], content_types::HANDSHAKE, len)
+                        .done()
+                        .map(|(hdr_len, _)| hdr_len + len)
+                });
+            match len {
+                Some(len) => {
+                    dgram.slice(..len);
+                    self.sending_data.set(false);
+                    let _ = self.send_dgram(dst, dgram);
+                }
+                None => {
+                    self.tx_buf.replace(dgram);
+                }
+            }
+        });
+    }
+
+    /// Sends the second flight of the client, which is sent once the
+    /// Finished is encrypted. If it cannot be, the timer sends it again.
+    fn send_flight_5(&self) {
+        let dst = match self.peer.get() {
+            Some(dst) => dst,
+            None => return,
+        };
+        let buf = match self.crypt_buf.take() {
+            Some(buf) => buf,
+            None => return,
+        };
+        match self.encode_flight_5(buf).done() {
+            Some((_, finished)) => {
+                let _ = self.encrypt_record(
+                    buf,
+                    finished,
+                    content_types::HANDSHAKE,
+                    HANDSHAKE_HDR_LEN + VERIFY_DATA_LEN,
+                    dst,
+                    false,
+                );
+            }
+            None => {
+                self.crypt_buf.replace(buf);
+            }
+        }
+    }
+
+    /// Encrypts the pending message, which is sent once it is.
+    fn send_data(&self) -> ReturnCode {
+        let dst = match self.peer.get() {
+            Some(dst) => dst,
+            None => return ReturnCode::EOFF,
+        };
+        let buf = match self.crypt_buf.take() {
+            Some(buf) => buf,
+            None => return ReturnCode::EBUSY,
+        };
+        let len = self.pending.map_or(None, |pending| {
+            let data = pending.data();
+            if PLAINTEXT_OFF + data.len() + MAC_LEN + AES128_BLOCK_SIZE > buf.len() {
+                None
+            } else {
+                buf[PLAINTEXT_OFF..PLAINTEXT_OFF + data.len()].copy_from_slice(data);
+                Some(data.len())
+            }
+        });
+        match len {
+            Some(len) => {
+                self.encrypt_record(buf, 0, content_types::APPLICATION_DATA, len, dst, true)
+            }
+            None => {
+                self.crypt_buf.replace(buf);
+                ReturnCode::ESIZE
+            }
+        }
+    }
+
+    /// Sends a close_notify alert, if the crypt buffer is free.
+    fn send_close_notify(&self) {
+        let dst = match self.peer.get() {
+            Some(dst) => dst,
+            None => return,
+        };
+        self.crypt_buf.take().map(|buf| {
+            buf[PLAINTEXT_OFF] = alerts::LEVEL_WARNING;
+            buf[PLAINTEXT_OFF + 1] = alerts::CLOSE_NOTIFY;
+            let _ = self.encrypt_record(buf, 0, content_types::ALERT, 2, dst, false);
+        });
+    }
+
+    /// Encrypts the record at `off` in `buf`, whose `len` bytes of plaintext
+    /// are `PLAINTEXT_OFF` after it, then sends `buf` up to the end of the
+    /// record to `dst`. The crypt buffer is given back if it fails.
+    fn encrypt_record(
+        &self,
+        buf: &'a mut [u8],
+        off: usize,
+        content_type: u8,
+        len: usize,
+        dst: (IPAddr, u16),
+        data: bool,
+    ) -> ReturnCode {
+        let mut keys = match self.keys.map(|keys| *keys) {
+            Some(keys) => keys,
+            None => {
+                self.crypt_buf.replace(buf);
+                return ReturnCode::EOFF;
+            }
+        };
+        let padding = AES128_BLOCK_SIZE - (len + MAC_LEN) % AES128_BLOCK_SIZE;
+        let start = off + PLAINTEXT_OFF;
+        let end = start + len + MAC_LEN + padding;
+        if end > buf.len() {
+            keys.wipe();
+            self.crypt_buf.replace(buf);
+            return ReturnCode::ESIZE;
+        }
+
+        let seq = self.epoch_1_seq.get();
+        self.epoch_1_seq.set(seq + 1);
+        let header = RecordHeader::new(content_type, 1, seq, (end - off - RECORD_HDR_LEN) as u16);
+        let mac = record_mac(&keys.client_mac_key, &header, &buf[start..start + len]);
+        buf[start + len..start + len + MAC_LEN].copy_from_slice(&mac);
+        for b in buf[start + len + MAC_LEN..end].iter_mut() {
+            *b = (padding - 1) as u8;
+        }
+        let _ = header.encode(&mut buf[off..]);
+        let iv = record_iv(&keys.client_mac_key, 1, seq);
+        buf[off + RECORD_HDR_LEN..start].copy_from_slice(&iv);
+
+        self.aes.set_mode_aes128cbc(true);
+        self.aes.set_key(&keys.client_key);
+        self.aes.set_iv(&iv);
+        self.aes.start_message();
+        keys.wipe();
+        self.crypt_op.set(CryptOp::Encrypt {
+            dst: dst,
+            len: end,
+            data: data,
+        });
+        match self.aes.crypt(None, buf, start, end) {
+            None => ReturnCode::SUCCESS,
+            Some((result, _, buf)) => {
+                self.crypt_op.clear();
+                self.crypt_buf.replace(buf);
+                result
+            }
+        }
+    }
+
+    fn receive_plain(&self, content_type: u8, fragment: &[u8]) {
+        match (content_type, self.state.get()) {
+            (content_types::HANDSHAKE, State::ClientHello)
+            | (content_types::HANDSHAKE, State::ServerHello) => {
+                let mut off = 0;
+                while off < fragment.len() {
+                    let header = match HandshakeHeader::decode(&fragment[off..]).done() {
+                        Some((_, header)) => header,
+                        None => return,
+                    };
+                    let end = off + HANDSHAKE_HDR_LEN + header.length as usize;
+                    self.receive_handshake(header, &fragment[off..end]);
+                    off = end;
+                }
+            }
+            (content_types::CHANGE_CIPHER_SPEC, State::Finished { .. }) => {
+                self.state.set(State::Finished { ccs: true });
+            }
+            // Alerts in plaintext are not authenticated, so they can only
+            // end a handshake
+            (content_types::ALERT, State::Open) => {}
+            (content_types::ALERT, _) => {
+                if fragment.len() == 2 && fragment[0] == alerts::LEVEL_FATAL {
+                    self.fail(ReturnCode::FAIL);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Handles the handshake message `msg`, with `header`, from the flight
+    /// of the ServerHello.
+    fn receive_handshake(&self, header: HandshakeHeader, msg: &[u8]) {
+        let state = self.state.get();
+        if state == State::ClientHello {
+            // The flights the ClientHello is answered with can start with
+            // any sequence number
+            self.rx_message_seq.set(header.message_seq);
+        }
+        if header.message_seq != self.rx_message_seq.get() {
+            // A message sent again, or one after a message that was lost
+            return;
+        }
+        self.rx_message_seq.set(header.message_seq.wrapping_add(1));
+
+        let body = &msg[HANDSHAKE_HDR_LEN..];
+        match (header.msg_type, state) {
+            (handshake_types::HELLO_VERIFY_REQUEST, State::ClientHello) => {
+                let mut cookie = [0; MAX_COOKIE_LEN];
+                match decode_hello_verify_request(body, &mut cookie).done() {
+                    Some((_, cookie_len)) => self.cookie.set((cookie, cookie_len)),
+                    None => {
+                        self.fail(ReturnCode::FAIL);
+                        return;
+                    }
+                }
+                // The answer to this ClientHello is waited for as long as
+                // for the first, which limits how often it is sent
+                self.message_seq.set(self.message_seq.get() + 1);
+                self.send_client_hello();
+                self.set_timer(self.timeout_ms.get());
+            }
+            (handshake_types::SERVER_HELLO, State::ClientHello) => {
+                match decode_server_hello(body).done() {
+                    Some((_, server_random)) => self.server_random.set(server_random),
+                    None => {
+                        self.fail(ReturnCode::FAIL);
+                        return;
+                    }
+                }
+                // The handshake hash starts with the ClientHello this
+                // answers
+                let mut hello = [0; CLIENT_HELLO_MAX_LEN];
+                let mut transcript = Sha256::new();
+                if let Some((len, _)) = self.encode_hello(&mut hello).done() {
+                    transcript.update(&hello[..len]);
+                }
+                transcript.update(msg);
+                self.transcript.replace(transcript);
+                self.state.set(State::ServerHello);
+            }
+            (handshake_types::SERVER_KEY_EXCHANGE, State::ServerHello) => {
+                self.transcript.map(|transcript| transcript.update(msg));
+            }
+            (handshake_types::SERVER_HELLO_DONE, State::ServerHello) => {
+                self.transcript.map(|transcript| transcript.update(msg));
+                if self.derive_keys() {
+                    self.state.set(State::Finished { ccs: false });
+                    self.send_flight_5();
+                    self.start_timer();
+                } else {
+                    self.fail(ReturnCode::FAIL);
+                }
+            }
+            // Requests for certificates, which PSK suites do not use
+            _ => self.fail(ReturnCode::FAIL),
+        }
+    }
+
+    /// Derives the keys of the session, then hashes the ClientKeyExchange
+    /// and the Finished of the client, whose verify data are computed in
+    /// between.
+    fn derive_keys(&self) -> bool {
+        let psk = match self.psk.get() {
+            Some((_, psk)) => psk,
+            None => return false,
+        };
+        let mut keys =
+            SessionKeys::derive(psk, &self.client_random.get(), &self.server_random.get());
+
+        let mut msg = [0; HANDSHAKE_HDR_LEN + 2 + MAX_IDENTITY_LEN];
+        let hash = self
+            .encode_client_key_exchange(&mut msg)
+            .done()
+            .and_then(|(len, _)| {
+                self.transcript.map(|transcript| {
+                    transcript.update(&msg[..len]);
+                    transcript.finish()
+                })
+            });
+        match hash {
+            Some(hash) => self
+                .client_verify
+                .set(keys.verify_data(b"client finished", &hash)),
+            None => {
+                keys.wipe();
+                return false;
+            }
+        }
+        let mut msg = [0; HANDSHAKE_HDR_LEN + VERIFY_DATA_LEN];
+        if let Some((len, _)) = self.encode_finished(&mut msg).done() {
+            self.transcript
+                .map(|transcript| transcript.update(&msg[..len]));
+        }
+
+        self.keys.replace(keys);
+        keys.wipe();
+        self.epoch_1_seq.set(0);
+        true
+    }
+
+    fn replayed(&self, seq: u64) -> bool {
+        match self.replay.get() {
+            Some((latest, _)) if seq + REPLAY_WINDOW <= latest => true,
+            Some((latest, window)) if seq <= latest => window & 1 << (latest - seq) != 0,
+            _ => false,
+        }
+    }
+
+    fn mark_received(&self, seq: u64) {
+        let replay = match self.replay.get() {
+            Some((latest, window)) if seq + REPLAY_WINDOW <= latest => (latest, window),
+            Some((latest, window)) if seq <= latest => (latest, window | 1 << (latest - seq)),
+            Some((latest, window)) if seq - latest < REPLAY_WINDOW => {
+                (seq, window << (seq - latest) | 1)
+            }
+            _ => (seq, 1),
+        };
+        self.replay.set(Some(replay));
+    }
+
+    /// Decrypts the record of epoch 1 with `header` and `fragment`. It is
+    /// dropped if the crypt buffer is in use, and the server sends it again
+    /// if it has to.
+    fn decrypt_record(
+        &self,
+        header: RecordHeader,
+        fragment: &[u8],
+        dst_addr: IPAddr,
+        dst_port: u16,
+    ) {
+        match self.state.get() {
+            State::Finished { ccs: true } | State::Open => {}
+            _ => return,
+        }
+        let len = fragment.len();
+        if len < AES128_BLOCK_SIZE + MAC_LEN + AES128_BLOCK_SIZE
+            || len % AES128_BLOCK_SIZE != 0
+            || self.replayed(header.seq)
+        {
+            return;
+        }
+        let mut server_key = match self.keys.map(|keys| keys.server_key) {
+            Some(server_key) => server_key,
+            None => return,
+        };
+        let buf = match self.crypt_buf.take() {
+            Some(buf) => buf,
+            None => return,
+        };
+        if len > buf.len() {
+            self.crypt_buf.replace(buf);
+            return;
+        }
+        buf[..len].copy_from_slice(fragment);
+
+        self.aes.set_mode_aes128cbc(false);
+        self.aes.set_key(&server_key);
+        self.aes.set_iv(&fragment[..AES128_BLOCK_SIZE]);
+        self.aes.start_message();
+        constant_time::wipe(&mut server_key);
+        self.crypt_op.set(CryptOp::Decrypt {
+            header: header,
+            len: len,
+            dst_addr: dst_addr,
+            dst_port: dst_port,
+        });
+        if let Some((_, _, buf)) = self.aes.crypt(None, buf, AES128_BLOCK_SIZE, len) {
+            self.crypt_op.clear();
+            self.crypt_buf.replace(buf);
+        }
+    }
+
+    /// Checks the padding and MAC of the decrypted `record` with `header`,
+    /// after its IV, returning the length of its plaintext.
+    fn check_record(&self, header: &RecordHeader, record: &[u8]) -> Option<usize> {
+        let padding = record[record.len() - 1] as usize;
+        let padding_ok = padding + 1 + MAC_LEN <= record.len()
+            && record[record.len() - 1 - padding..]
+                .iter()
+                .all(|b| *b as usize == padding);
+        // The MAC is checked whether or not the padding is right, so that
+        // the time taken does not tell which it was
+        let len = if padding_ok {
+            record.len() - 1 - padding - MAC_LEN
+        } else {
+            record.len() - MAC_LEN
+        };
+        let mac = self
+            .keys
+            .map(|keys| record_mac(&keys.server_mac_key, header, &record[..len]))?;
+        if constant_time::eq(&mac, &record[len..len + MAC_LEN]) && padding_ok {
+            Some(len)
+        } else {
+            None
+        }
+    }
+
+    /// Handles the authenticated `plaintext` of a record of epoch 1.
+    fn receive_secured(&self, content_type: u8, plaintext: &[u8], dst_addr: IPAddr, dst_port: u16) {
+        match (content_type, self.state.get()) {
+            (content_types::HANDSHAKE, State::Finished { .. }) => {
+                match HandshakeHeader::decode(plaintext).done() {
+                    Some((_, header))
+                        if header.msg_type == handshake_types::FINISHED
+                            && header.message_seq == self.rx_message_seq.get()
+                            && header.length as usize == VERIFY_DATA_LEN => {}
+                    _ => {
+                        self.fail(ReturnCode::FAIL);
+                        return;
+                    }
+                }
+                let expected = self
+                    .transcript
+                    .map(|transcript| transcript.finish())
+                    .and_then(|hash| {
+                        self.keys
+                            .map(|keys| keys.verify_data(b"server finished", &hash))
+                    });
+                let verify_data = &plaintext[HANDSHAKE_HDR_LEN..];
+                match expected {
+                    Some(expected) if constant_time::eq(&expected, verify_data) => {
+                        self.state.set(State::Open);
+                        let _ = self.alarm.disarm();
+                        self.session_client
+                            .map(|client| client.opened(ReturnCode::SUCCESS));
+                        if self.pending.is_some() {
+                            let result = self.send_data();
+                            if result != ReturnCode::SUCCESS {
+                                self.return_pending(result);
+                            }
+                        }
+                    }
+                    _ => self.fail(ReturnCode::FAIL),
+                }
+            }
+            (content_types::APPLICATION_DATA, State::Open) => {
+                if let Some((addr, port)) = self.peer.get() {
+                    self.recv_client.map(|client| {
+                        client.receive(addr, dst_addr, port, dst_port, plaintext, None)
+                    });
+                }
+                self.session_client.map(|client| client.received(plaintext));
+            }
+            (content_types::ALERT, state) if plaintext.len() == 2 => {
+                let result = if plaintext[1] == alerts::CLOSE_NOTIFY {
+                    ReturnCode::SUCCESS
+                } else if plaintext[0] == alerts::LEVEL_FATAL {
+                    ReturnCode::FAIL
+                } else {
+                    return;
+                };
+                if state == State::Open {
+                    self.end(result);
+                } else {
+                    self.fail(ReturnCode::FAIL);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>, C: AES128<'a> + AES128CBC> SecureSession<'a> for DTLSClient<'a, A, C> {
+    fn set_client(&self, client: &'a dyn SecureSessionClient) {
+        self.session_client.set(client);
+    }
+
+    /// Returns `EINVAL` without a PSK, `EOFF` while the session is not
+    /// bound and `EBUSY` unless it is closed.
+    fn open(&self, dest: IPAddr, dst_port: u16) -> ReturnCode {
+        self.start(dest, dst_port)
+    }
+
+    fn send(
+        &self,
+        buf: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        if self.state.get() != State::Open {
+            return Err((ReturnCode::EOFF, buf));
+        }
+        if self.pending.is_some() {
+            return Err((ReturnCode::EBUSY, buf));
+        }
+        if len > buf.len() {
+            return Err((ReturnCode::EINVAL, buf));
+        }
+        self.pending.replace(Pending::Message(buf, len));
+        let result = self.send_data();
+        if result != ReturnCode::SUCCESS {
+            if let Some(Pending::Message(buf, _)) = self.pending.take() {
+                return Err((result, buf));
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends a close_notify alert to the server of an open session. A
+    /// handshake in progress fails with `ECANCEL`.
+    fn close(&self) -> ReturnCode {
+        match self.state.get() {
+            State::Closed => ReturnCode::EALREADY,
+            State::Open => {
+                self.send_close_notify();
+                self.end(ReturnCode::SUCCESS);
+                ReturnCode::SUCCESS
+            }
+            _ => {
+                self.fail(ReturnCode::ECANCEL);
+                ReturnCode::SUCCESS
+            }
+        }
+    }
+
+    fn is_open(&self) -> bool {
+        self.state.get() == State::Open
+    }
+}
+
+/// UDP users send datagrams through the session as through their own
+/// `UDPSendStruct`, from the port the session is bound to.
+impl<'a, A: Alarm<'a>, C: AES128<'a> + AES128CBC> UDPSender<'a> for DTLSClient<'a, A, C> {
+    fn set_client(&self, client: &'a dyn UDPSendClient) {
+        self.send_client.set(client);
+    }
+
+    /// Sends `buf` over the session, opening it to `dest`:`dst_port` first
+    /// if it is closed. The datagram waits for the handshake, whose failure
+    /// is reported in `send_done`. The session sends with its own network
+    /// capability, so `net_cap` is not used; datagrams to a destination
+    /// other than the server are refused.
+    fn send_to(
+        &'a self,
+        dest: IPAddr,
+        dst_port: u16,
+        buf: LeasableBuffer<'static, u8>,
+        _net_cap: &'static NetworkCapability,
+    ) -> Result<(), LeasableBuffer<'static, u8>> {
+        if self.pending.is_some() {
+            return Err(buf);
+        }
+        match (self.state.get(), self.peer.get()) {
+            (State::Closed, _) => {
+                if self.start(dest, dst_port) != ReturnCode::SUCCESS {
+                    return Err(buf);
+                }
+                self.pending.replace(Pending::Datagram(buf));
+            }
+            (state, Some((addr, port))) if addr == dest && port == dst_port => {
+                self.pending.replace(Pending::Datagram(buf));
+                if state == State::Open && self.send_data() != ReturnCode::SUCCESS {
+                    if let Some(Pending::Datagram(buf)) = self.pending.take() {
+                        return Err(buf);
+                    }
+                }
+            }
+            _ => return Err(buf),
+        }
+        Ok(())
+    }
+
+    /// The userspace UDP driver does not send through the session.
+    fn driver_send_to(
+        &'a self,
+        _dest: IPAddr,
+        _dst_port: u16,
+        _src_port: u16,
+        buf: LeasableBuffer<'static, u8>,
+        _driver_send_cap: &dyn UdpDriverCapability,
+        _net_cap: &'static NetworkCapability,
+    ) -> Result<(), LeasableBuffer<'static, u8>> {
+        Err(buf)
+    }
+
+    /// The UDP header of the datagrams of the session is its own.
+    fn send(
+        &'a self,
+        _dest: IPAddr,
+        _udp_header: UDPHeader,
+        buf: LeasableBuffer<'static, u8>,
+        _net_cap: &'static NetworkCapability,
+    ) -> Result<(), LeasableBuffer<'static, u8>> {
+        Err(buf)
+    }
+
+    fn get_binding(&self) -> Option<UdpPortBindingTx> {
+        self.udp_sender.get_binding()
+    }
+
+    fn is_bound(&self) -> bool {
+        self.udp_sender.is_bound()
+    }
+
+    fn set_binding(&self, binding: UdpPortBindingTx) -> Option<UdpPortBindingTx> {
+        self.udp_sender.set_binding(binding)
+    }
+}
+
+impl<'a, A: Alarm<'a>, C: AES128<'a> + AES128CBC> time::AlarmClient for DTLSClient<'a, A, C> {
+    fn alarm(&self) {
+        match self.state.get() {
+            State::ClientHello | State::ServerHello | State::Finished { .. }
+                if self.retransmits.get() >= MAX_RETRANSMITS =>
+            {
+                self.fail(ReturnCode::FAIL)
+            }
+            State::ClientHello | State::ServerHello => {
+                self.send_client_hello();
+                self.back_off();
+            }
+            State::Finished { .. } => {
+                self.send_flight_5();
+                self.back_off();
+            }
+            State::Closed | State::Random | State::Open => {}
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>, C: AES128<'a> + AES128CBC> rng::Client for DTLSClient<'a, A, C> {
+    fn randomness_available(
+        &self,
+        randomness: &mut dyn Iterator<Item = u32>,
+        error: ReturnCode,
+    ) -> rng::Continue {
+        if self.state.get() != State::Random {
+            return rng::Continue::Done;
+        }
+        if error != ReturnCode::SUCCESS {
+            self.fail(error);
+            return rng::Continue::Done;
+        }
+        let mut random = self.client_random.get();
+        let mut len = self.random_len.get();
+        while len < RANDOM_LEN {
+            match randomness.next() {
+                Some(r) => {
+                    random[len..len + 4].copy_from_slice(&r.to_be_bytes());
+                    len += 4;
+                }
+                None => break,
+            }
+        }
+        self.client_random.set(random);
+        self.random_len.set(len);
+        if len < RANDOM_LEN {
+            return rng::Continue::More;
+        }
+
+        self.epoch_0_seq.set(0);
+        self.message_seq.set(0);
+        self.rx_message_seq.set(0);
+        self.cookie.set(([0; MAX_COOKIE_LEN], 0));
+        self.state.set(State::ClientHello);
+        self.send_client_hello();
+        self.start_timer();
+        rng::Continue::Done
+    }
+}
+
+impl<'a, A: Alarm<'a>, C: AES128<'a> + AES128CBC> symmetric_encryption::Client<'a>
+    for DTLSClient<'a, A, C>
+{
+    fn crypt_done(&'a self, _source: Option<&'a mut [u8]>, buf: &'a mut [u8]) {
+        match self.crypt_op.take() {
+            Some(CryptOp::Encrypt { dst, len, data }) => {
+                let result = self.tx_buf.take().map_or(ReturnCode::EBUSY, |mut dgram| {
+                    if len > dgram.len() {
+                        self.tx_buf.replace(dgram);
+                        return ReturnCode::ESIZE;
+                    }
+                    dgram[..len].copy_from_slice(&buf[..len]);
+                    dgram.slice(..len);
+                    self.sending_data.set(data);
+                    self.send_dgram(dst, dgram)
+                });
+                self.crypt_buf.replace(buf);
+                if data && result != ReturnCode::SUCCESS {
+                    self.return_pending(result);
+                }
+            }
+            Some(CryptOp::Decrypt {
+                header,
+                len,
+                dst_addr,
+                dst_port,
+            }) => {
+                let plaintext = self
+                    .check_record(&header, &buf[AES128_BLOCK_SIZE..len])
+                    .and_then(|plaintext_len| {
+                        self.rx_buf.take().and_then(|rx_buf| {
+                            if plaintext_len > rx_buf.len() {
+                                self.rx_buf.replace(rx_buf);
+                                return None;
+                            }
+                            rx_buf[..plaintext_len].copy_from_slice(
+                                &buf[AES128_BLOCK_SIZE..AES128_BLOCK_SIZE + plaintext_len],
+                            );
+                            Some((rx_buf, plaintext_len))
+                        })
+                    });
+                // The buffer is free again before the plaintext is passed on,
+                // so that clients can answer it
+                self.crypt_buf.replace(buf);
+                if let Some((rx_buf, plaintext_len)) = plaintext {
+                    self.mark_received(header.seq);
+                    self.receive_secured(
+                        header.content_type,
+                        &rx_buf[..plaintext_len],
+                        dst_addr,
+                        dst_port,
+                    );
+                    self.rx_buf.replace(rx_buf);
+                }
+            }
+            None => {
+                self.crypt_buf.replace(buf);
+            }
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>, C: AES128<'a> + AES128CBC> UDPSendClient for DTLSClient<'a, A, C> {
+    fn send_done(&self, result: ReturnCode, mut dgram: LeasableBuffer<'static, u8>) {
+        dgram.reset();
+        self.tx_buf.replace(dgram);
+        if self.sending_data.get() {
+            self.sending_data.set(false);
+            self.return_pending(result);
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>, C: AES128<'a> + AES128CBC> UDPRecvClient for DTLSClient<'a, A, C> {
+    fn receive(
+        &self,
+        src_addr: IPAddr,
+        dst_addr: IPAddr,
+        src_port: u16,
+        dst_port: u16,
+        payload: &[u8],
+        _timestamp: Option<u32>,
+    ) {
+        match self.peer.get() {
+            Some((addr, port)) if addr == src_addr && port == src_port => {}
+            _ => return,
+        }
+        let mut off = 0;
+        while off < payload.len() {
+            let header = match RecordHeader::decode(&payload[off..]).done() {
+                Some((_, header)) => header,
+                None => return,
+            };
+            let start = off + RECORD_HDR_LEN;
+            off = start + header.length as usize;
+            match header.epoch {
+                0 => self.receive_plain(header.content_type, &payload[start..off]),
+                1 => {
+                    self.decrypt_record(header, &payload[start..off], dst_addr, dst_port);
+                    return;
+                }
+                _ => return,
+            }
+        }
+    }
+}
@@ -0,0 +1,3 @@
+pub mod dtls;
+pub mod dtls_client;
+pub mod sha256;
@@ -0,0 +1,235 @@
+//! IPv6 over Ethernet (RFC 2464), an `IpLink` over a `hil::ethernet::Mac`.
+//!
+//! Each packet is sent in one Ethernet II frame with the IPv6 EtherType.
+//! Multicast destinations map to the `33:33` MAC addresses. The MAC addresses
+//! of unicast next hops are found in a small neighbor table, which is filled
+//! with `add_neighbor()` and learned from the source addresses of received
+//! packets; packets to other next hops are sent to the gateway, if one is
+//! set. The link does not take part in Neighbor Discovery, so the neighbors
+//! that send to it first need a static entry for it:
+//!
+//! ```txt
+//! ip -6 neigh add fe80::ff:fe12:3456 lladdr 02:00:00:12:34:56 dev eth0
+//! ```
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//! # use capsules::net::ethernet::EthernetLink;
+//!
+//! let ethernet = static_init!(
+//!     EthernetLink<'static>,
+//!     EthernetLink::new(enc28j60, &mut ETHERNET_TX_BUF)
+//! );
+//! hil::ethernet::Mac::set_transmit_client(enc28j60, ethernet);
+//! hil::ethernet::Mac::set_receive_client(enc28j60, ethernet);
+//! ethernet.start();
+//! ethernet.set_gateway([0x02, 0x00, 0x00, 0x00, 0x00, 0x01]);
+//! ```
+
+use crate::net::ipv6::ip_link::{IpLink, IpLinkRxClient, IpLinkTxClient};
+use crate::net::ipv6::ip_utils::IPAddr;
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::ethernet::{self, Filter, ADDRESS_LEN, HEADER_LEN};
+use kernel::ReturnCode;
+
+pub const ETHERTYPE_IPV6: u16 = 0x86dd;
+
+/// The largest IPv6 packet in an Ethernet frame.
+pub const MTU: usize = 1500;
+
+/// The number of neighbors whose MAC addresses are kept.
+pub const NUM_NEIGHBORS: usize = 8;
+
+const IP6_HEADER_LEN: usize = 40;
+
+#[derive(Copy, Clone)]
+struct Neighbor {
+    ip_addr: IPAddr,
+    mac_addr: [u8; ADDRESS_LEN],
+}
+
+pub struct EthernetLink<'a> {
+    mac: &'a dyn ethernet::Mac<'a>,
+    /// The frame being sent.
+    tx_buf: TakeCell<'static, [u8]>,
+    tx_buf_len: usize,
+    /// The packet of the frame being sent, returned in `transmit_done`.
+    tx_packet: TakeCell<'static, [u8]>,
+    neighbors: Cell<[Option<Neighbor>; NUM_NEIGHBORS]>,
+    /// The entry replaced when a new neighbor is learned.
+    next_neighbor: Cell<usize>,
+    gateway: OptionalCell<[u8; ADDRESS_LEN]>,
+    tx_client: OptionalCell<&'a dyn IpLinkTxClient>,
+    rx_client: OptionalCell<&'a dyn IpLinkRxClient>,
+}
+
+impl<'a> EthernetLink<'a> {
+    /// The MTU of the link is at most `tx_buf` less the Ethernet header.
+    pub fn new(mac: &'a dyn ethernet::Mac<'a>, tx_buf: &'static mut [u8]) -> EthernetLink<'a> {
+        EthernetLink {
+            mac: mac,
+            tx_buf_len: tx_buf.len(),
+            tx_buf: TakeCell::new(tx_buf),
+            tx_packet: TakeCell::empty(),
+            neighbors: Cell::new([None; NUM_NEIGHBORS]),
+            next_neighbor: Cell::new(0),
+            gateway: OptionalCell::empty(),
+            tx_client: OptionalCell::empty(),
+            rx_client: OptionalCell::empty(),
+        }
+    }
+
+    /// Starts receiving the multicast frames that IPv6 needs.
+    pub fn start(&self) -> ReturnCode {
+        self.mac.set_filter(Filter {
+            broadcast: true,
+            multicast: true,
+            promiscuous: false,
+        })
+    }
+
+    /// Sets the MAC address that packets to unknown next hops are sent to.
+    pub fn set_gateway(&self, mac_addr: [u8; ADDRESS_LEN]) {
+        self.gateway.set(mac_addr);
+    }
+
+    /// Adds or updates the MAC address of a neighbor. Returns `ENOMEM` if
+    /// the table is full of other neighbors; learned entries are replaced
+    /// as needed instead.
+    pub fn add_neighbor(&self, ip_addr: IPAddr, mac_addr: [u8; ADDRESS_LEN]) -> ReturnCode {
+        let mut neighbors = self.neighbors.get();
+        let slot = neighbors
+            .iter()
+            .position(|entry| entry.map_or(false, |entry| entry.ip_addr == ip_addr))
+            .or_else(|| neighbors.iter().position(|entry| entry.is_none()));
+        match slot {
+            Some(slot) => {
+                neighbors[slot] = Some(Neighbor { ip_addr, mac_addr });
+                self.neighbors.set(neighbors);
+                ReturnCode::SUCCESS
+            }
+            None => ReturnCode::ENOMEM,
+        }
+    }
+
+    /// Records the MAC address a packet from `ip_addr` came from.
+    fn learn(&self, ip_addr: IPAddr, mac_addr: [u8; ADDRESS_LEN]) {
+        if self.add_neighbor(ip_addr, mac_addr) == ReturnCode::ENOMEM {
+            let mut neighbors = self.neighbors.get();
+            let slot = self.next_neighbor.get();
+            neighbors[slot] = Some(Neighbor { ip_addr, mac_addr });
+            self.neighbors.set(neighbors);
+            self.next_neighbor.set((slot + 1) % NUM_NEIGHBORS);
+        }
+    }
+
+    /// The MAC address frames to `next_hop` are sent to.
+    fn mac_addr_of(&self, next_hop: IPAddr) -> Option<[u8; ADDRESS_LEN]> {
+        if next_hop.is_multicast() {
+            let ip = next_hop.0;
+            return Some([0x33, 0x33, ip[12], ip[13], ip[14], ip[15]]);
+        }
+        self.neighbors
+            .get()
+            .iter()
+            .filter_map(|entry| *entry)
+            .find(|entry| entry.ip_addr == next_hop)
+            .map(|entry| entry.mac_addr)
+            .or_else(|| self.gateway.map(|gateway| *gateway))
+    }
+}
+
+impl<'a> IpLink<'a> for EthernetLink<'a> {
+    fn set_transmit_client(&self, client: &'a dyn IpLinkTxClient) {
+        self.tx_client.set(client);
+    }
+
+    fn set_receive_client(&self, client: &'a dyn IpLinkRxClient) {
+        self.rx_client.set(client);
+    }
+
+    fn get_mtu(&self) -> usize {
+        cmp::min(
+            MTU,
+            cmp::min(self.mac.max_frame_len(), self.tx_buf_len).saturating_sub(HEADER_LEN),
+        )
+    }
+
+    /// Returns `FAIL` if the MAC address of `next_hop` is not known and
+    /// there is no gateway.
+    fn transmit(
+        &self,
+        next_hop: IPAddr,
+        buf: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        if len > buf.len() || len > self.get_mtu() {
+            return Err((ReturnCode::ESIZE, buf));
+        }
+        let dst = match self.mac_addr_of(next_hop) {
+            Some(dst) => dst,
+            None => return Err((ReturnCode::FAIL, buf)),
+        };
+        let frame = match self.tx_buf.take() {
+            Some(frame) => frame,
+            None => return Err((ReturnCode::EBUSY, buf)),
+        };
+        frame[..ADDRESS_LEN].copy_from_slice(&dst);
+        frame[ADDRESS_LEN..2 * ADDRESS_LEN].copy_from_slice(&self.mac.mac_address());
+        frame[2 * ADDRESS_LEN..HEADER_LEN].copy_from_slice(&ETHERTYPE_IPV6.to_be_bytes());
+        frame[HEADER_LEN..HEADER_LEN + len].copy_from_slice(&buf[..len]);
+
+        match self.mac.transmit(frame, HEADER_LEN + len) {
+            Ok(()) => {
+                self.tx_packet.replace(buf);
+                Ok(())
+            }
+            Err((rcode, frame)) => {
+                self.tx_buf.replace(frame);
+                Err((rcode, buf))
+            }
+        }
+    }
+}
+
+impl<'a> ethernet::TxClient for EthernetLink<'a> {
+    fn transmit_done(&self, frame: &'static mut [u8], result: ReturnCode) {
+        self.tx_buf.replace(frame);
+        if let Some(packet) = self.tx_packet.take() {
+            self.tx_client
+                .map(move |client| client.transmit_done(packet, result));
+        }
+    }
+}
+
+impl<'a> ethernet::RxClient for EthernetLink<'a> {
+    fn frame_received(&self, frame: &[u8]) {
+        if frame.len() < HEADER_LEN + IP6_HEADER_LEN
+            || frame[2 * ADDRESS_LEN..HEADER_LEN] != ETHERTYPE_IPV6.to_be_bytes()
+        {
+            return;
+        }
+        let packet = &frame[HEADER_LEN..];
+        // Short frames are padded, so take the length from the IPv6 header.
+        let len = IP6_HEADER_LEN + ((packet[4] as usize) << 8 | packet[5] as usize);
+        if len > packet.len() {
+            return;
+        }
+        let packet = &packet[..len];
+
+        let mut src_ip = IPAddr::new();
+        src_ip.0.copy_from_slice(&packet[8..24]);
+        let mut src_mac = [0; ADDRESS_LEN];
+        src_mac.copy_from_slice(&frame[ADDRESS_LEN..2 * ADDRESS_LEN]);
+        // The group bit is never set in the address of a sender.
+        if !src_ip.is_multicast() && !src_ip.is_unspecified() && src_mac[0] & 0x01 == 0 {
+            self.learn(src_ip, src_mac);
+        }
+        self.rx_client.map(|client| client.receive(packet, None));
+    }
+}
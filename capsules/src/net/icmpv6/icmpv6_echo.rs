@@ -0,0 +1,141 @@
+//! ICMPv6 echo (ping), RFC 4443 section 4.
+//!
+//! `ICMP6Echo` answers every echo request addressed to one of the addresses
+//! of the interface with an echo reply carrying the same identifier,
+//! sequence number and data. Capsules can also send echo requests with
+//! `ping()`, and are told about the replies through `ICMP6EchoClient`.
+//!
+//! Only one message is sent at a time: a request that arrives while another
+//! message is being sent is dropped, and `ping()` returns `EBUSY`, which a
+//! pinger handles like a lost reply.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//! # use capsules::net::icmpv6::icmpv6_echo::ICMP6Echo;
+//!
+//! let icmp_echo = static_init!(
+//!     ICMP6Echo<'static>,
+//!     ICMP6Echo::new(icmp_send_struct, &mut ICMP_ECHO_BUF, &LOCAL_IP_IFACES, net_cap)
+//! );
+//! icmp_send_struct.set_client(icmp_echo);
+//! icmp_recv_struct.set_client(icmp_echo);
+//! icmp_echo.set_client(pinger);
+//! ```
+
+use crate::net::icmpv6::icmpv6::{ICMP6Header, ICMP6HeaderOptions, ICMP6Type};
+use crate::net::icmpv6::icmpv6_recv::ICMP6RecvClient;
+use crate::net::icmpv6::icmpv6_send::{ICMP6SendClient, ICMP6Sender};
+use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::ipv6::ipv6::IP6Header;
+use crate::net::network_capabilities::NetworkCapability;
+use core::cell::Cell;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::ReturnCode;
+
+/// Implemented by capsules that send echo requests.
+pub trait ICMP6EchoClient {
+    /// An echo reply from `src_addr` was received.
+    fn echo_reply(&self, src_addr: IPAddr, id: u16, seqno: u16, payload: &[u8]);
+}
+
+pub struct ICMP6Echo<'a> {
+    icmp_sender: &'a dyn ICMP6Sender<'a>,
+    /// Holds the data of the message being sent; it is copied by the IP
+    /// layer, but kept until `send_done` so messages are not interleaved.
+    buf: TakeCell<'static, [u8]>,
+    interface_list: &'static [IPAddr],
+    net_cap: &'static NetworkCapability,
+    client: OptionalCell<&'a dyn ICMP6EchoClient>,
+    busy: Cell<bool>,
+}
+
+impl<'a> ICMP6Echo<'a> {
+    /// `buf` bounds the data of pings sent and of requests answered.
+    pub fn new(
+        icmp_sender: &'a dyn ICMP6Sender<'a>,
+        buf: &'static mut [u8],
+        interface_list: &'static [IPAddr],
+        net_cap: &'static NetworkCapability,
+    ) -> ICMP6Echo<'a> {
+        ICMP6Echo {
+            icmp_sender: icmp_sender,
+            buf: TakeCell::new(buf),
+            interface_list: interface_list,
+            net_cap: net_cap,
+            client: OptionalCell::empty(),
+            busy: Cell::new(false),
+        }
+    }
+
+    pub fn set_client(&self, client: &'a dyn ICMP6EchoClient) {
+        self.client.set(client);
+    }
+
+    /// Send an echo request to `dest`. Returns `EBUSY` while a message is
+    /// being sent and `ESIZE` if `payload` does not fit the buffer.
+    pub fn ping(&self, dest: IPAddr, id: u16, seqno: u16, payload: &[u8]) -> ReturnCode {
+        let mut icmp_header = ICMP6Header::new(ICMP6Type::Type128);
+        icmp_header.set_options(ICMP6HeaderOptions::Type128 { id, seqno });
+        self.send(dest, icmp_header, payload)
+    }
+
+    fn send(&self, dest: IPAddr, icmp_header: ICMP6Header, payload: &[u8]) -> ReturnCode {
+        if self.busy.get() {
+            return ReturnCode::EBUSY;
+        }
+        let buf = match self.buf.take() {
+            Some(buf) => buf,
+            None => return ReturnCode::EBUSY,
+        };
+        if payload.len() > buf.len() {
+            self.buf.replace(buf);
+            return ReturnCode::ESIZE;
+        }
+        buf[..payload.len()].copy_from_slice(payload);
+        let mut lease = LeasableBuffer::new(buf);
+        lease.slice(..payload.len());
+
+        // Set before sending, as send_done may be called synchronously
+        self.busy.set(true);
+        let result = self
+            .icmp_sender
+            .send(dest, icmp_header, &lease, self.net_cap);
+        self.buf.replace(lease.take());
+        if result != ReturnCode::SUCCESS {
+            self.busy.set(false);
+        }
+        result
+    }
+}
+
+impl<'a> ICMP6RecvClient for ICMP6Echo<'a> {
+    fn receive(&self, ip_header: IP6Header, icmp_header: ICMP6Header, payload: &[u8]) {
+        match icmp_header.get_options() {
+            ICMP6HeaderOptions::Type128 { id, seqno } => {
+                let dst_addr = ip_header.get_dst_addr();
+                if !self.interface_list.iter().any(|addr| *addr == dst_addr) {
+                    return;
+                }
+                let mut reply = ICMP6Header::new(ICMP6Type::Type129);
+                reply.set_options(ICMP6HeaderOptions::Type129 { id, seqno });
+                // The sender retries if the reply is lost
+                let _ = self.send(ip_header.get_src_addr(), reply, payload);
+            }
+            ICMP6HeaderOptions::Type129 { id, seqno } => {
+                self.client
+                    .map(|client| client.echo_reply(ip_header.get_src_addr(), id, seqno, payload));
+            }
+            _ => {}
+        }
+    }
+}
+
+impl<'a> ICMP6SendClient for ICMP6Echo<'a> {
+    fn send_done(&self, _result: ReturnCode) {
+        self.busy.set(false);
+    }
+}
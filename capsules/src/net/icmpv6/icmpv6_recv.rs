@@ -0,0 +1,65 @@
+//! This file contains the definition and implementation of a simple ICMPv6
+//! receiving interface. The [ICMP6Receiver](trait.ICMP6Receiver.html) trait
+//! lets an upper layer set the client that is told about received ICMPv6
+//! messages, and the [ICMP6RecvClient](trait.ICMP6RecvClient.html) trait is
+//! implemented by that upper layer.
+//!
+//! `ICMP6RecvStruct` is the `IP6RecvClient` of an IP receiver. It drops
+//! packets of other transports and ICMPv6 messages of types it does not
+//! decode; the checksum has already been checked by the IP layer.
+
+use crate::net::icmpv6::icmpv6::ICMP6Header;
+use crate::net::ipv6::ip_utils::ip6_nh;
+use crate::net::ipv6::ipv6::IP6Header;
+use crate::net::ipv6::ipv6_recv::IP6RecvClient;
+use kernel::common::cells::OptionalCell;
+
+/// A trait for a client of an `ICMP6Receiver`.
+pub trait ICMP6RecvClient {
+    /// Called for every ICMPv6 message received, with the IPv6 header of
+    /// its packet and the message body after the ICMPv6 header.
+    fn receive(&self, ip_header: IP6Header, icmp_header: ICMP6Header, payload: &[u8]);
+}
+
+/// A trait that defines an interface for receiving ICMPv6 messages.
+pub trait ICMP6Receiver<'a> {
+    /// Sets the client for the `ICMP6Receiver` instance.
+    ///
+    /// # Arguments
+    ///
+    /// `client` - The `ICMP6RecvClient` instance to be set as the client
+    /// of the `ICMP6Receiver` instance
+    fn set_client(&self, client: &'a dyn ICMP6RecvClient);
+}
+
+/// A struct that implements the `ICMP6Receiver` trait.
+pub struct ICMP6RecvStruct<'a> {
+    client: OptionalCell<&'a dyn ICMP6RecvClient>,
+}
+
+impl<'a> ICMP6RecvStruct<'a> {
+    pub fn new() -> ICMP6RecvStruct<'a> {
+        ICMP6RecvStruct {
+            client: OptionalCell::empty(),
+        }
+    }
+}
+
+impl<'a> ICMP6Receiver<'a> for ICMP6RecvStruct<'a> {
+    fn set_client(&self, client: &'a dyn ICMP6RecvClient) {
+        self.client.set(client);
+    }
+}
+
+impl<'a> IP6RecvClient for ICMP6RecvStruct<'a> {
+    fn receive(&self, ip_header: IP6Header, payload: &[u8], _timestamp: Option<u32>) {
+        if ip_header.get_next_header() != ip6_nh::ICMP {
+            return;
+        }
+        if let Some((offset, mut icmp_header)) = ICMP6Header::decode(payload).done() {
+            icmp_header.set_len(payload.len() as u16);
+            self.client
+                .map(|client| client.receive(ip_header, icmp_header, &payload[offset..]));
+        }
+    }
+}
@@ -40,7 +40,8 @@ pub trait ICMP6Sender<'a> {
     ///
     /// `dest` - The destination IP address
     /// `icmp_header` - The ICMPv6 header to be sent
-    /// `buf` - The byte array containing the ICMPv6 payload
+    /// `buf` - The ICMPv6 payload, which is copied before this returns so
+    /// that the caller keeps the buffer
     ///
     /// # Return Value
     ///
@@ -51,7 +52,7 @@ pub trait ICMP6Sender<'a> {
         &self,
         dest: IPAddr,
         icmp_header: ICMP6Header,
-        buf: &'static mut [u8],
+        buf: &LeasableBuffer<'static, u8>,
         net_cap: &'static NetworkCapability,
     ) -> ReturnCode;
 }
@@ -80,14 +81,14 @@ impl<'a, T: IP6Sender<'a>> ICMP6Sender<'a> for ICMP6SendStruct<'a, T> {
         &self,
         dest: IPAddr,
         mut icmp_header: ICMP6Header,
-        buf: &'static mut [u8],
+        buf: &LeasableBuffer<'static, u8>,
         net_cap: &'static NetworkCapability,
     ) -> ReturnCode {
         let total_len = buf.len() + icmp_header.get_hdr_size();
         icmp_header.set_len(total_len as u16);
         let transport_header = TransportHeader::ICMP(icmp_header);
         self.ip_send_struct
-            .send_to(dest, transport_header, &LeasableBuffer::new(buf), net_cap)
+            .send_to(dest, transport_header, buf, net_cap)
     }
 }
 
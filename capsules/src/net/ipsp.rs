@@ -0,0 +1,273 @@
+//! IPv6 over BLE (RFC 7668), an `IpLink` over an L2CAP connection-oriented
+//! channel.
+//!
+//! The Internet Protocol Support Profile carries IPv6 packets in the SDUs of
+//! a credit based channel to `SPSM_IPSP`, one packet per SDU, so the link
+//! needs no fragmentation of its own. The IPv6 header is compressed with
+//! IPHC (RFC 6282), the interface identifiers coming from the BLE device
+//! addresses of the two ends, and next headers are sent uncompressed.
+//! Received packets may also use UDP next header compression, or come
+//! uncompressed behind the IPv6 dispatch.
+//!
+//! The link is a point to point link to the peer of the channel, so every
+//! packet goes to it whatever its next hop. We take the IPSP Node role,
+//! accepting the channel when a router opens it, or open it ourselves with
+//! `connect()`. A Linux host acts as the router with:
+//!
+//! ```txt
+//! modprobe bluetooth_6lowpan
+//! echo 1 > /sys/kernel/debug/bluetooth/6lowpan_enable
+//! echo "connect c0:11:22:33:44:55 2" > /sys/kernel/debug/bluetooth/6lowpan_control
+//! ```
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//! # use capsules::ble::coc::CocChannel;
+//! # use capsules::net::ipsp::IpspLink;
+//!
+//! let ipsp_coc = static_init!(
+//!     CocChannel<'static>,
+//!     CocChannel::new(signaling, ipsp_l2cap, &mut IPSP_RX_SDU, &mut IPSP_FRAME)
+//! );
+//! ipsp_l2cap.set_client(ipsp_coc);
+//! ipsp_coc.setup();
+//! let ipsp = static_init!(
+//!     IpspLink<'static>,
+//!     IpspLink::new(
+//!         ipsp_coc,
+//!         &nrf52::ble_radio::RADIO,
+//!         link_local_context,
+//!         &mut IPSP_TX_BUF,
+//!         &mut IPSP_RX_BUF
+//!     )
+//! );
+//! ipsp_coc.set_client(ipsp);
+//! ipsp.start();
+//! ipsp.set_receive_client(ip_receive);
+//! ipsp.set_transmit_client(ipsp_sender);
+//! ipsp_sender.set_addr(ipsp.link_local_address());
+//! ```
+
+use crate::ble::coc::{CocChannel, CocClient};
+use crate::net::ieee802154::MacAddress;
+use crate::net::ipv6::ip_link::{IpLink, IpLinkRxClient, IpLinkTxClient};
+use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::ipv6::ipv6::{IP6Header, IP6Packet, IPPayload, RawHeader, TransportHeader};
+use crate::net::sixlowpan::sixlowpan_compression::{self, ContextStore};
+use core::cmp;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::ble_connection::{BleConnection, ConnectionHandle, DeviceAddress};
+use kernel::ReturnCode;
+
+/// The SPSM of the Internet Protocol Support Service.
+pub const SPSM_IPSP: u16 = 0x0023;
+
+/// The MTU of the link, which the channel MTUs of both ends must be at least
+/// as large as.
+pub const MTU: usize = 1280;
+
+/// The dispatch of an uncompressed IPv6 packet (RFC 4944).
+const DISPATCH_IPV6: u8 = 0x41;
+
+const IP6_HEADER_LEN: usize = 40;
+
+/// The EUI-64 formed from a device address (RFC 7668, 3.2.2), from which
+/// IPHC derives the interface identifier.
+fn mac_address(address: DeviceAddress) -> MacAddress {
+    let a = address.address;
+    MacAddress::Long([a[5], a[4], a[3], 0xff, 0xfe, a[2], a[1], a[0]])
+}
+
+pub struct IpspLink<'a> {
+    coc: &'a CocChannel<'a>,
+    ble: &'a dyn BleConnection<'a>,
+    ctx_store: &'a dyn ContextStore,
+    /// The compressed packet being sent.
+    tx_buf: TakeCell<'static, [u8]>,
+    tx_buf_len: usize,
+    /// The packet being sent, returned in `transmit_done`.
+    tx_packet: TakeCell<'static, [u8]>,
+    /// Received packets are decompressed into this buffer.
+    rx_buf: TakeCell<'static, [u8]>,
+    tx_client: OptionalCell<&'a dyn IpLinkTxClient>,
+    rx_client: OptionalCell<&'a dyn IpLinkRxClient>,
+}
+
+impl<'a> IpspLink<'a> {
+    /// `ctx_store` holds the compression contexts; a context 0 for the
+    /// link-local prefix is enough for a single link. `rx_buf` should hold
+    /// `MTU` bytes; packets longer than it once decompressed are dropped.
+    /// The MTU of the link is at most the length of `tx_buf`.
+    pub fn new(
+        coc: &'a CocChannel<'a>,
+        ble: &'a dyn BleConnection<'a>,
+        ctx_store: &'a dyn ContextStore,
+        tx_buf: &'static mut [u8],
+        rx_buf: &'static mut [u8],
+    ) -> IpspLink<'a> {
+        IpspLink {
+            coc: coc,
+            ble: ble,
+            ctx_store: ctx_store,
+            tx_buf_len: tx_buf.len(),
+            tx_buf: TakeCell::new(tx_buf),
+            tx_packet: TakeCell::empty(),
+            rx_buf: TakeCell::new(rx_buf),
+            tx_client: OptionalCell::empty(),
+            rx_client: OptionalCell::empty(),
+        }
+    }
+
+    /// Accept the channel whenever a router opens it.
+    pub fn start(&self) {
+        self.coc.listen(SPSM_IPSP);
+    }
+
+    /// Open the channel to the peer of connection `handle`, if it is a
+    /// router.
+    pub fn connect(&self, handle: ConnectionHandle) -> ReturnCode {
+        self.coc.connect(handle, SPSM_IPSP)
+    }
+
+    pub fn is_up(&self) -> bool {
+        self.coc.is_open()
+    }
+
+    /// Our link-local address, formed from our device address.
+    pub fn link_local_address(&self) -> IPAddr {
+        IPAddr::generate_from_mac(mac_address(self.ble.local_address()))
+    }
+
+    /// Compress the packet in the first `len` bytes of `packet` into `frame`,
+    /// returning the length of the SDU.
+    fn compress(
+        &self,
+        packet: &[u8],
+        len: usize,
+        peer: MacAddress,
+        frame: &mut [u8],
+    ) -> Option<usize> {
+        let header = IP6Header::decode(&packet[..len]).done()?.1;
+        let mut ip6_packet = IP6Packet::new(IPPayload::new(
+            TransportHeader::Raw(RawHeader::new(header.get_next_header())),
+            &mut [],
+        ));
+        ip6_packet.header = header;
+        let local = mac_address(self.ble.local_address());
+        let (consumed, written) =
+            sixlowpan_compression::compress(self.ctx_store, &ip6_packet, local, peer, frame)
+                .ok()?;
+        let sdu_len = written + len - consumed;
+        if sdu_len > frame.len() || sdu_len > self.coc.peer_mtu() {
+            return None;
+        }
+        frame[written..sdu_len].copy_from_slice(&packet[consumed..len]);
+        Some(sdu_len)
+    }
+}
+
+impl<'a> IpLink<'a> for IpspLink<'a> {
+    fn set_transmit_client(&self, client: &'a dyn IpLinkTxClient) {
+        self.tx_client.set(client);
+    }
+
+    fn set_receive_client(&self, client: &'a dyn IpLinkRxClient) {
+        self.rx_client.set(client);
+    }
+
+    fn get_mtu(&self) -> usize {
+        cmp::min(MTU, self.tx_buf_len)
+    }
+
+    /// Returns `EOFF` while the channel is closed.
+    fn transmit(
+        &self,
+        _next_hop: IPAddr,
+        buf: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        if len > buf.len() || len > self.get_mtu() || len < IP6_HEADER_LEN {
+            return Err((ReturnCode::ESIZE, buf));
+        }
+        let peer = match self.coc.peer_address() {
+            Some(peer) => mac_address(peer),
+            None => return Err((ReturnCode::EOFF, buf)),
+        };
+        let frame = match self.tx_buf.take() {
+            Some(frame) => frame,
+            None => return Err((ReturnCode::EBUSY, buf)),
+        };
+        let sdu_len = match self.compress(buf, len, peer, frame) {
+            Some(sdu_len) => sdu_len,
+            None => {
+                self.tx_buf.replace(frame);
+                return Err((ReturnCode::ESIZE, buf));
+            }
+        };
+        match self.coc.send(frame, sdu_len) {
+            Ok(()) => {
+                self.tx_packet.replace(buf);
+                Ok(())
+            }
+            Err((rcode, frame)) => {
+                self.tx_buf.replace(frame);
+                Err((rcode, buf))
+            }
+        }
+    }
+}
+
+impl<'a> CocClient for IpspLink<'a> {
+    fn opened(&self, _handle: ConnectionHandle, _result: ReturnCode) {}
+
+    fn closed(&self) {}
+
+    fn received(&self, sdu: &[u8]) {
+        if sdu.is_empty() {
+            return;
+        }
+        if sdu[0] == DISPATCH_IPV6 {
+            self.rx_client.map(|client| client.receive(&sdu[1..], None));
+            return;
+        }
+        if sdu.len() < 2 || !sixlowpan_compression::is_lowpan(sdu) {
+            return;
+        }
+        let peer = match self.coc.peer_address() {
+            Some(peer) => mac_address(peer),
+            None => return,
+        };
+        let local = mac_address(self.ble.local_address());
+        self.rx_buf.take().map(|packet| {
+            let decompressed = sixlowpan_compression::decompress(
+                self.ctx_store,
+                sdu,
+                peer,
+                local,
+                packet,
+                0,
+                false,
+            );
+            if let Ok((consumed, written)) = decompressed {
+                let len = written + sdu.len() - consumed;
+                if len <= packet.len() {
+                    packet[written..len].copy_from_slice(&sdu[consumed..]);
+                    self.rx_client
+                        .map(|client| client.receive(&packet[..len], None));
+                }
+            }
+            self.rx_buf.replace(packet);
+        });
+    }
+
+    fn sent(&self, buffer: &'static mut [u8], result: ReturnCode) {
+        self.tx_buf.replace(buffer);
+        if let Some(packet) = self.tx_packet.take() {
+            self.tx_client
+                .map(move |client| client.transmit_done(packet, result));
+        }
+    }
+}
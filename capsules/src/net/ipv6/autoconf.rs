@@ -0,0 +1,487 @@
+//! IPv6 stateless address autoconfiguration (RFC 4862).
+//!
+//! `AddressAutoconf` forms a link-local address from the 802.15.4 address of
+//! the node, and an address for each prefix that routers advertise for
+//! autonomous configuration. Each address is tentative until duplicate
+//! address detection, a Neighbor Solicitation for it sent from the
+//! unspecified address, went unanswered for a tick. Clients added with
+//! `add_client()` are told whenever an address is assigned, deprecated,
+//! found to be a duplicate or expires, so they can update their IPv6 senders
+//! and the addresses they accept.
+//!
+//! It sends and receives ICMPv6 through its own MAC user, and the IPv6 sender
+//! of that stack has to be left with the unspecified source address, which
+//! duplicate address detection and the Router Solicitations need.
+//!
+//! Limitations
+//! -----------
+//! - Only prefixes of 64 bits are used, with the interface identifier of
+//!   the link-local address.
+//! - The valid lifetime of an address is taken from every advertisement of
+//!   its prefix, without the two hour rule of RFC 4862, 5.5.3.
+//! - DHCPv6 is not supported.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//! # use capsules::net::ipv6::autoconf::AddressAutoconf;
+//!
+//! let autoconf = static_init!(
+//!     AddressAutoconf<'static, VirtualMuxAlarm<'static, sam4l::ast::Ast>>,
+//!     AddressAutoconf::new(icmp_send, autoconf_alarm, &mut AUTOCONF_BUF, src_mac_addr, net_cap)
+//! );
+//! autoconf_alarm.set_alarm_client(autoconf);
+//! icmp_send.set_client(autoconf);
+//! icmp_recv.set_client(autoconf);
+//! autoconf.add_client(ndp);
+//! autoconf.start();
+//! ```
+
+use crate::net::icmpv6::icmpv6::{ICMP6Header, ICMP6HeaderOptions, ICMP6Type};
+use crate::net::icmpv6::icmpv6_recv::ICMP6RecvClient;
+use crate::net::icmpv6::icmpv6_send::{ICMP6SendClient, ICMP6Sender};
+use crate::net::ieee802154::MacAddress;
+use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::ipv6::ipv6::IP6Header;
+use crate::net::network_capabilities::NetworkCapability;
+use core::cell::Cell;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::hil::time::{self, Alarm};
+use kernel::ReturnCode;
+
+/// The link-local address and one address for each of two prefixes.
+pub const MAX_ADDRESSES: usize = 3;
+
+/// How many clients can be added.
+pub const MAX_CLIENTS: usize = 4;
+
+/// The size of the buffer for the messages sent, which hold a target
+/// address.
+pub const AUTOCONF_BUF_LEN: usize = 16;
+
+/// Lifetimes are counted, and messages retransmitted, in ticks.
+pub const TICK_MS: u32 = 1000;
+
+/// Solicitations sent for a tentative address.
+const DUP_ADDR_DETECT_TRANSMITS: u16 = 1;
+
+const MAX_RTR_SOLICITATIONS: u16 = 3;
+const RTR_SOLICITATION_INTERVAL_TICKS: u16 = 4;
+
+/// A lifetime that never runs out.
+const INFINITE_LIFETIME: u32 = 0xffff_ffff;
+
+const ALL_ROUTERS: IPAddr = IPAddr([0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x02]);
+
+mod nd_options {
+    pub const PREFIX_INFORMATION: u8 = 3;
+    pub const PREFIX_INFORMATION_LEN: usize = 32;
+    pub const PREFIX_AUTONOMOUS: u8 = 0x40;
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum AddressState {
+    /// Duplicate address detection is under way.
+    Tentative,
+    /// Assigned to the interface.
+    Preferred,
+    /// Still assigned, but its preferred lifetime ran out, so it should not
+    /// be used for new connections.
+    Deprecated,
+    /// Another node has the address, so it is not used.
+    Duplicate,
+    /// Its valid lifetime ran out, and it was removed.
+    Invalid,
+}
+
+/// Implemented by capsules that depend on the addresses of the interface.
+pub trait AutoconfClient {
+    /// `addr` is now in `state`. This is not called for `Tentative`.
+    fn address_changed(&self, addr: IPAddr, state: AddressState);
+}
+
+#[derive(Copy, Clone)]
+struct Address {
+    addr: IPAddr,
+    state: AddressState,
+    /// Ticks since duplicate address detection started.
+    dad_ticks: u16,
+    /// A solicitation for the address is to be sent once the sender is free.
+    dad_pending: bool,
+    /// Remaining lifetimes, in ticks.
+    valid: u32,
+    preferred: u32,
+}
+
+pub struct AddressAutoconf<'a, A: Alarm<'a>> {
+    icmp_sender: &'a dyn ICMP6Sender<'a>,
+    alarm: &'a A,
+    buf: TakeCell<'static, [u8]>,
+    mac_addr: Cell<MacAddress>,
+    net_cap: &'static NetworkCapability,
+    addresses: [Cell<Option<Address>>; MAX_ADDRESSES],
+    clients: [OptionalCell<&'a dyn AutoconfClient>; MAX_CLIENTS],
+    /// Router Solicitations sent; `MAX_RTR_SOLICITATIONS` once a router
+    /// answered.
+    rs_count: Cell<u16>,
+    rs_ticks: Cell<u16>,
+    rs_pending: Cell<bool>,
+    busy: Cell<bool>,
+}
+
+impl<'a, A: Alarm<'a>> AddressAutoconf<'a, A> {
+    /// `buf` must hold `AUTOCONF_BUF_LEN` bytes. The interface identifier
+    /// of the addresses is formed from `mac_addr`.
+    pub fn new(
+        icmp_sender: &'a dyn ICMP6Sender<'a>,
+        alarm: &'a A,
+        buf: &'static mut [u8],
+        mac_addr: MacAddress,
+        net_cap: &'static NetworkCapability,
+    ) -> AddressAutoconf<'a, A> {
+        AddressAutoconf {
+            icmp_sender: icmp_sender,
+            alarm: alarm,
+            buf: TakeCell::new(buf),
+            mac_addr: Cell::new(mac_addr),
+            net_cap: net_cap,
+            addresses: [Cell::new(None), Cell::new(None), Cell::new(None)],
+            clients: [
+                OptionalCell::empty(),
+                OptionalCell::empty(),
+                OptionalCell::empty(),
+                OptionalCell::empty(),
+            ],
+            rs_count: Cell::new(0),
+            rs_ticks: Cell::new(0),
+            rs_pending: Cell::new(false),
+            busy: Cell::new(false),
+        }
+    }
+
+    /// Tell `client` about address changes. Returns `ENOMEM` once
+    /// `MAX_CLIENTS` were added.
+    pub fn add_client(&self, client: &'a dyn AutoconfClient) -> ReturnCode {
+        self.clients
+            .iter()
+            .find(|slot| slot.is_none())
+            .map_or(ReturnCode::ENOMEM, |slot| {
+                slot.set(client);
+                ReturnCode::SUCCESS
+            })
+    }
+
+    /// Forgets every address and configures the interface again, for
+    /// example after `mac_addr` changed.
+    pub fn start(&self) {
+        for address in self.addresses.iter() {
+            if let Some(address) = address.take() {
+                if address.state != AddressState::Tentative {
+                    self.notify(address.addr, AddressState::Invalid);
+                }
+            }
+        }
+        self.add_address(
+            IPAddr::generate_from_mac(self.mac_addr.get()),
+            INFINITE_LIFETIME,
+            INFINITE_LIFETIME,
+        );
+        self.rs_count.set(0);
+        self.rs_ticks.set(0);
+        self.rs_pending.set(true);
+        self.start_timer();
+        self.send_pending();
+    }
+
+    /// Sets the link-layer address the interface identifier is formed from.
+    /// Takes effect on the next `start()`.
+    pub fn set_mac_addr(&self, mac_addr: MacAddress) {
+        self.mac_addr.set(mac_addr);
+    }
+
+    /// Calls `f` with each address and its state.
+    pub fn each_address<F: FnMut(IPAddr, AddressState)>(&self, mut f: F) {
+        for address in self.addresses.iter() {
+            if let Some(address) = address.get() {
+                f(address.addr, address.state);
+            }
+        }
+    }
+
+    fn notify(&self, addr: IPAddr, state: AddressState) {
+        for client in self.clients.iter() {
+            client.map(|client| client.address_changed(addr, state));
+        }
+    }
+
+    fn add_address(&self, addr: IPAddr, valid: u32, preferred: u32) {
+        if let Some(slot) = self.addresses.iter().find(|a| a.get().is_none()) {
+            slot.set(Some(Address {
+                addr: addr,
+                state: AddressState::Tentative,
+                dad_ticks: 0,
+                dad_pending: true,
+                valid: valid,
+                preferred: preferred,
+            }));
+        }
+    }
+
+    fn start_timer(&self) {
+        if !self.alarm.is_armed() {
+            self.alarm
+                .set_alarm(self.alarm.now(), A::ticks_from_ms(TICK_MS));
+        }
+    }
+
+    /// Sends the pending solicitations, until the sender is busy.
+    fn send_pending(&self) {
+        for address in self.addresses.iter() {
+            if self.busy.get() {
+                return;
+            }
+            if let Some(mut entry) = address.get() {
+                if !entry.dad_pending {
+                    continue;
+                }
+                entry.dad_pending = false;
+                address.set(Some(entry));
+                let icmp_header = ICMP6Header::new(ICMP6Type::Type135);
+                // Duplicates still answer the next solicitation
+                let _ =
+                    self.send_message(entry.addr.solicited_node(), icmp_header, Some(entry.addr));
+            }
+        }
+        if !self.busy.get() && self.rs_pending.get() {
+            self.rs_pending.set(false);
+            self.rs_count.set(self.rs_count.get() + 1);
+            let icmp_header = ICMP6Header::new(ICMP6Type::Type133);
+            let _ = self.send_message(ALL_ROUTERS, icmp_header, None);
+        }
+    }
+
+    /// Sends a message with `target` as its body, if any.
+    fn send_message(
+        &self,
+        dest: IPAddr,
+        icmp_header: ICMP6Header,
+        target: Option<IPAddr>,
+    ) -> ReturnCode {
+        if self.busy.get() {
+            return ReturnCode::EBUSY;
+        }
+        let buf = match self.buf.take() {
+            Some(buf) => buf,
+            None => return ReturnCode::EBUSY,
+        };
+        if buf.len() < AUTOCONF_BUF_LEN {
+            self.buf.replace(buf);
+            return ReturnCode::ESIZE;
+        }
+        let len = match target {
+            Some(target) => {
+                buf[..16].copy_from_slice(&target.0);
+                16
+            }
+            None => 0,
+        };
+        let mut lease = LeasableBuffer::new(buf);
+        lease.slice(..len);
+
+        // Set before sending, as send_done may be called synchronously
+        self.busy.set(true);
+        let result = self
+            .icmp_sender
+            .send(dest, icmp_header, &lease, self.net_cap);
+        self.buf.replace(lease.take());
+        if result != ReturnCode::SUCCESS {
+            self.busy.set(false);
+        }
+        result
+    }
+
+    /// Marks a tentative `target` as a duplicate.
+    fn conflict(&self, target: IPAddr) {
+        for address in self.addresses.iter() {
+            if let Some(mut entry) = address.get() {
+                if entry.addr == target && entry.state == AddressState::Tentative {
+                    entry.state = AddressState::Duplicate;
+                    address.set(Some(entry));
+                    self.notify(target, AddressState::Duplicate);
+                }
+            }
+        }
+    }
+
+    fn receive_advertisement(&self, options: &[u8]) {
+        // A router answered, so no more solicitations are sent
+        self.rs_count.set(MAX_RTR_SOLICITATIONS);
+        self.rs_pending.set(false);
+
+        let mut off = 0;
+        while off + 2 <= options.len() {
+            let len = options[off + 1] as usize * 8;
+            if len == 0 || off + len > options.len() {
+                return;
+            }
+            if options[off] == nd_options::PREFIX_INFORMATION
+                && len == nd_options::PREFIX_INFORMATION_LEN
+            {
+                self.receive_prefix(&options[off..off + len]);
+            }
+            off += len;
+        }
+    }
+
+    fn receive_prefix(&self, option: &[u8]) {
+        let prefix_len = option[2];
+        let flags = option[3];
+        let valid = u32::from_be_bytes([option[4], option[5], option[6], option[7]]);
+        let preferred = u32::from_be_bytes([option[8], option[9], option[10], option[11]]);
+        let prefix = &option[16..32];
+        // RFC 4862, 5.5.3: link-local prefixes and invalid lifetimes are
+        // ignored
+        if flags & nd_options::PREFIX_AUTONOMOUS == 0
+            || prefix_len != 64
+            || (prefix[0] == 0xfe && prefix[1] & 0xc0 == 0x80)
+            || preferred > valid
+        {
+            return;
+        }
+
+        let mut addr = IPAddr::generate_from_mac(self.mac_addr.get());
+        addr.set_prefix(prefix, prefix_len);
+        for address in self.addresses.iter() {
+            if let Some(mut entry) = address.get() {
+                if entry.addr == addr {
+                    entry.valid = valid;
+                    entry.preferred = preferred;
+                    if entry.state == AddressState::Deprecated && preferred > 0 {
+                        entry.state = AddressState::Preferred;
+                        self.notify(addr, AddressState::Preferred);
+                    }
+                    address.set(Some(entry));
+                    return;
+                }
+            }
+        }
+        if valid > 0 {
+            self.add_address(addr, valid, preferred);
+            self.start_timer();
+            self.send_pending();
+        }
+    }
+
+    /// Ages `entry` by a tick, and returns the address with its new state, if
+    /// it changed.
+    fn age(entry: &mut Address) -> Option<AddressState> {
+        if entry.valid != INFINITE_LIFETIME {
+            entry.valid = entry.valid.saturating_sub(1);
+        }
+        if entry.preferred != INFINITE_LIFETIME {
+            entry.preferred = entry.preferred.saturating_sub(1);
+        }
+        if entry.valid == 0 {
+            Some(AddressState::Invalid)
+        } else if entry.preferred == 0 && entry.state == AddressState::Preferred {
+            entry.state = AddressState::Deprecated;
+            Some(AddressState::Deprecated)
+        } else {
+            None
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> time::AlarmClient for AddressAutoconf<'a, A> {
+    fn alarm(&self) {
+        let mut active = false;
+        for address in self.addresses.iter() {
+            if let Some(mut entry) = address.get() {
+                let change = match entry.state {
+                    AddressState::Tentative => {
+                        entry.dad_ticks += 1;
+                        if entry.dad_ticks > DUP_ADDR_DETECT_TRANSMITS {
+                            entry.state = AddressState::Preferred;
+                            Some(AddressState::Preferred)
+                        } else {
+                            entry.dad_pending = entry.dad_ticks < DUP_ADDR_DETECT_TRANSMITS;
+                            None
+                        }
+                    }
+                    AddressState::Preferred | AddressState::Deprecated => Self::age(&mut entry),
+                    _ => None,
+                };
+                if change == Some(AddressState::Invalid) {
+                    address.set(None);
+                } else {
+                    address.set(Some(entry));
+                    active |= entry.state == AddressState::Tentative
+                        || (entry.state != AddressState::Duplicate
+                            && entry.valid != INFINITE_LIFETIME);
+                }
+                if let Some(state) = change {
+                    self.notify(entry.addr, state);
+                }
+            }
+        }
+
+        if self.rs_count.get() < MAX_RTR_SOLICITATIONS {
+            let ticks = self.rs_ticks.get() + 1;
+            if ticks >= RTR_SOLICITATION_INTERVAL_TICKS {
+                self.rs_ticks.set(0);
+                self.rs_pending.set(true);
+            } else {
+                self.rs_ticks.set(ticks);
+            }
+            active = true;
+        }
+
+        if active {
+            self.start_timer();
+        }
+        self.send_pending();
+    }
+}
+
+impl<'a, A: Alarm<'a>> ICMP6RecvClient for AddressAutoconf<'a, A> {
+    fn receive(&self, ip_header: IP6Header, icmp_header: ICMP6Header, payload: &[u8]) {
+        // Routers decrement the hop limit, so this drops messages from off
+        // the link (RFC 4861, 6.1 and 7.1)
+        if ip_header.get_hop_limit() != 255 || icmp_header.get_code() != 0 {
+            return;
+        }
+        match icmp_header.get_options() {
+            ICMP6HeaderOptions::Type134 { .. } if payload.len() >= 8 => {
+                // After the reachable time and retransmission timer
+                self.receive_advertisement(&payload[8..]);
+            }
+            // A node answered for a tentative address, or is checking for
+            // the same address (RFC 4862, 5.4.3 and 5.4.4)
+            ICMP6HeaderOptions::Type135 { .. } | ICMP6HeaderOptions::Type136 { .. }
+                if payload.len() >= 16 =>
+            {
+                let is_dad = ip_header.get_src_addr().is_unspecified();
+                let is_advertisement = match icmp_header.get_options() {
+                    ICMP6HeaderOptions::Type136 { .. } => true,
+                    _ => false,
+                };
+                if is_dad || is_advertisement {
+                    let mut target = IPAddr::new();
+                    target.0.copy_from_slice(&payload[..16]);
+                    self.conflict(target);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> ICMP6SendClient for AddressAutoconf<'a, A> {
+    fn send_done(&self, _result: ReturnCode) {
+        self.busy.set(false);
+        self.send_pending();
+    }
+}
@@ -0,0 +1,515 @@
+//! A 6LoWPAN border router (RFC 6775), bridging the 802.15.4 network to a
+//! host over another link, such as SLIP over a UART.
+//!
+//! `BorderRouter` receives the packets of both links, the 6LoWPAN network as
+//! the `SixlowpanRxClient` of its MAC user and the host link as its
+//! `IpLinkRxClient`. Packets from the host to the prefix of the 6LoWPAN
+//! network are forwarded into it, and packets from the network to other
+//! destinations are forwarded to the host, each with its hop limit
+//! decremented. Packets for the addresses of the router, link-local and
+//! multicast packets are passed to the local IPv6 stack instead, if one was
+//! set with `set_local_client`. Each link is sent to through a
+//! `BorderPort`, which forwards one packet at a time and drops packets that
+//! arrive while it is busy.
+//!
+//! The router answers Router Solicitations from the 6LoWPAN network with a
+//! Router Advertisement carrying the prefix, a 6LoWPAN Context Option for
+//! each context of its `ContextTable`, and an Authoritative Border Router
+//! Option. `contexts_changed` should be called after the table changes: it
+//! increments the version of the advertised contexts and multicasts a new
+//! advertisement, so that the nodes compress with the same contexts as the
+//! router.
+//!
+//! The router remembers the nodes of the network it has heard from, and
+//! answers Neighbor Solicitations for them from the host, so that the host
+//! can reach them as if the prefix were on its link.
+//!
+//! Limitations
+//! -----------
+//! - Nodes of the network are taken to be one hop away, with addresses
+//!   formed from their MAC addresses, as `IP6Forwarder` for
+//!   `IP6SendStruct` expects. Address registrations (RFC 6775, 5.5) are not
+//!   supported.
+//! - Packets whose hop limit runs out are dropped without an ICMPv6 Time
+//!   Exceeded message.
+//! - Proxied nodes are forgotten only when `MAX_PROXIED` others replace
+//!   them.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//! # use capsules::net::ipv6::border_router::{BorderPort, BorderRouter};
+//!
+//! let lowpan_port = static_init!(BorderPort<'static>, BorderPort::new(lowpan_ip_send));
+//! lowpan_ip_send.set_client(lowpan_port);
+//! let slip_port = static_init!(BorderPort<'static>, BorderPort::new(slip_sender));
+//! slip_sender.set_client(slip_port);
+//! let border_router = static_init!(
+//!     BorderRouter<'static>,
+//!     BorderRouter::new(
+//!         lowpan_port,
+//!         slip_port,
+//!         context_table,
+//!         &ROUTER_ADDRS,
+//!         LOWPAN_PREFIX,
+//!         64,
+//!         &mut BORDER_ROUTER_BUF,
+//!         net_cap,
+//!     )
+//! );
+//! br_sixlowpan_state.set_rx_client(border_router);
+//! slip.set_receive_client(border_router);
+//! border_router.set_local_client(ip_receive);
+//! ```
+
+use crate::net::ipv6::ip_link::IpLinkRxClient;
+use crate::net::ipv6::ip_utils::{compute_ipv6_ph_sum, compute_sum, ip6_nh, IPAddr};
+use crate::net::ipv6::ipv6::IP6Header;
+use crate::net::ipv6::ipv6_send::{IP6Forwarder, IP6SendClient};
+use crate::net::network_capabilities::NetworkCapability;
+use crate::net::sixlowpan::context_table::{self, ContextTable};
+use crate::net::sixlowpan::sixlowpan_state::SixlowpanRxClient;
+use core::cell::Cell;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::ReturnCode;
+
+/// The number of nodes of the network that are proxied.
+pub const MAX_PROXIED: usize = 8;
+
+/// The length of a Router Advertisement with every option, which the buffer
+/// has to hold besides the largest packet forwarded.
+pub const RA_LEN: usize = 16
+    + nd_options::PREFIX_INFORMATION_LEN
+    + context_table::MAX_CONTEXTS * nd_options::SIXLOWPAN_CONTEXT_MAX_LEN
+    + nd_options::ABRO_LEN;
+
+/// How long nodes use the router as their default router, in seconds.
+const ROUTER_LIFETIME_S: u16 = 1800;
+
+/// How long nodes use an advertised context, in minutes.
+const CONTEXT_LIFETIME_MIN: u16 = 0xffff;
+
+const INFINITE_LIFETIME: u32 = 0xffff_ffff;
+
+const ALL_NODES: IPAddr = IPAddr([0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x01]);
+
+mod icmp_types {
+    pub const ROUTER_SOLICITATION: u8 = 133;
+    pub const ROUTER_ADVERTISEMENT: u8 = 134;
+    pub const NEIGHBOR_SOLICITATION: u8 = 135;
+    pub const NEIGHBOR_ADVERTISEMENT: u8 = 136;
+}
+
+mod nd_options {
+    pub const PREFIX_INFORMATION: u8 = 3;
+    pub const PREFIX_INFORMATION_LEN: usize = 32;
+    pub const PREFIX_AUTONOMOUS: u8 = 0x40;
+    pub const SIXLOWPAN_CONTEXT: u8 = 34;
+    pub const SIXLOWPAN_CONTEXT_MAX_LEN: usize = 24;
+    pub const SIXLOWPAN_CONTEXT_COMPRESS: u8 = 0x10;
+    pub const ABRO: u8 = 35;
+    pub const ABRO_LEN: usize = 24;
+}
+
+/// The flags of a Neighbor Advertisement.
+const NA_ROUTER: u8 = 0x80;
+const NA_SOLICITED: u8 = 0x40;
+const NA_OVERRIDE: u8 = 0x20;
+
+/// A link the border router forwards packets to.
+pub struct BorderPort<'a> {
+    forwarder: &'a dyn IP6Forwarder<'a>,
+    busy: Cell<bool>,
+    forwarded: Cell<u32>,
+    dropped: Cell<u32>,
+}
+
+impl<'a> BorderPort<'a> {
+    pub fn new(forwarder: &'a dyn IP6Forwarder<'a>) -> BorderPort<'a> {
+        BorderPort {
+            forwarder: forwarder,
+            busy: Cell::new(false),
+            forwarded: Cell::new(0),
+            dropped: Cell::new(0),
+        }
+    }
+
+    /// The packets sent to the link, forwarded or from the router.
+    pub fn get_forwarded(&self) -> u32 {
+        self.forwarded.get()
+    }
+
+    /// The packets for the link that were dropped, because it was busy or
+    /// refused them.
+    pub fn get_dropped(&self) -> u32 {
+        self.dropped.get()
+    }
+
+    fn send(
+        &self,
+        header: IP6Header,
+        payload: &LeasableBuffer<'static, u8>,
+        net_cap: &'static NetworkCapability,
+    ) -> ReturnCode {
+        if self.busy.get() {
+            self.dropped.set(self.dropped.get().wrapping_add(1));
+            return ReturnCode::EBUSY;
+        }
+        // Set before sending, as send_done may be called synchronously
+        self.busy.set(true);
+        let result = self.forwarder.forward(header, payload, net_cap);
+        if result == ReturnCode::SUCCESS {
+            self.forwarded.set(self.forwarded.get().wrapping_add(1));
+        } else {
+            self.busy.set(false);
+            self.dropped.set(self.dropped.get().wrapping_add(1));
+        }
+        result
+    }
+}
+
+impl<'a> IP6SendClient for BorderPort<'a> {
+    fn send_done(&self, _result: ReturnCode) {
+        self.busy.set(false);
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Side {
+    Lowpan,
+    Host,
+}
+
+pub struct BorderRouter<'a> {
+    lowpan: &'a BorderPort<'a>,
+    host: &'a BorderPort<'a>,
+    contexts: &'a ContextTable,
+    addrs: &'static [IPAddr],
+    prefix: IPAddr,
+    prefix_len: u8,
+    /// Forwarded payloads and the messages of the router are built here.
+    buf: TakeCell<'static, [u8]>,
+    net_cap: &'static NetworkCapability,
+    local: OptionalCell<&'a dyn IpLinkRxClient>,
+    proxied: [Cell<Option<IPAddr>>; MAX_PROXIED],
+    next_proxied: Cell<usize>,
+    /// The version of the Authoritative Border Router Option.
+    version: Cell<u32>,
+}
+
+impl<'a> BorderRouter<'a> {
+    /// `addrs` are the addresses of the router. Its advertisements are sent
+    /// from the first link-local one, and name the first other one as the
+    /// border router. The network has the first `prefix_len` bits of
+    /// `prefix`. `buf` has to hold `RA_LEN` bytes and the payload of the
+    /// largest packet that is forwarded.
+    pub fn new(
+        lowpan: &'a BorderPort<'a>,
+        host: &'a BorderPort<'a>,
+        contexts: &'a ContextTable,
+        addrs: &'static [IPAddr],
+        prefix: IPAddr,
+        prefix_len: u8,
+        buf: &'static mut [u8],
+        net_cap: &'static NetworkCapability,
+    ) -> BorderRouter<'a> {
+        BorderRouter {
+            lowpan: lowpan,
+            host: host,
+            contexts: contexts,
+            addrs: addrs,
+            prefix: prefix,
+            prefix_len: prefix_len,
+            buf: TakeCell::new(buf),
+            net_cap: net_cap,
+            local: OptionalCell::empty(),
+            proxied: [
+                Cell::new(None),
+                Cell::new(None),
+                Cell::new(None),
+                Cell::new(None),
+                Cell::new(None),
+                Cell::new(None),
+                Cell::new(None),
+                Cell::new(None),
+            ],
+            next_proxied: Cell::new(0),
+            version: Cell::new(0),
+        }
+    }
+
+    /// Passes the packets for the router itself to `local`, usually an
+    /// `IP6RecvStruct`.
+    pub fn set_local_client(&self, local: &'a dyn IpLinkRxClient) {
+        self.local.set(local);
+    }
+
+    /// Advertises the contexts of the table again, under a new version.
+    pub fn contexts_changed(&self) {
+        self.version.set(self.version.get().wrapping_add(1));
+        self.send_advertisement(ALL_NODES);
+    }
+
+    /// Calls `f` with each node of the network that is proxied.
+    pub fn each_proxied<F: FnMut(IPAddr)>(&self, mut f: F) {
+        for addr in self.proxied.iter().filter_map(|slot| slot.get()) {
+            f(addr);
+        }
+    }
+
+    fn is_local(&self, addr: IPAddr) -> bool {
+        self.addrs.iter().any(|a| *a == addr)
+    }
+
+    fn is_proxied(&self, addr: IPAddr) -> bool {
+        self.proxied.iter().any(|slot| slot.get() == Some(addr))
+    }
+
+    fn link_local_addr(&self) -> IPAddr {
+        self.addrs
+            .iter()
+            .find(|addr| addr.is_unicast_link_local())
+            .or_else(|| self.addrs.first())
+            .map_or(IPAddr::new(), |addr| *addr)
+    }
+
+    fn router_addr(&self) -> IPAddr {
+        self.addrs
+            .iter()
+            .find(|addr| !addr.is_unicast_link_local())
+            .map_or(self.link_local_addr(), |addr| *addr)
+    }
+
+    /// Remembers a node of the network, replacing the oldest if the table is
+    /// full.
+    fn learn(&self, addr: IPAddr) {
+        if self.is_proxied(addr) {
+            return;
+        }
+        let index = self.next_proxied.get();
+        self.proxied[index].set(Some(addr));
+        self.next_proxied.set((index + 1) % MAX_PROXIED);
+    }
+
+    /// Sends a packet with `header`, whose payload `fill` writes to the
+    /// buffer, returning its length.
+    fn send<F>(&self, port: &BorderPort<'a>, mut header: IP6Header, fill: F)
+    where
+        F: FnOnce(&mut [u8]) -> Option<usize>,
+    {
+        if let Some(buf) = self.buf.take() {
+            match fill(buf) {
+                Some(len) => {
+                    header.set_payload_len(len as u16);
+                    let mut lease = LeasableBuffer::new(buf);
+                    lease.slice(..len);
+                    let _ = port.send(header, &lease, self.net_cap);
+                    self.buf.replace(lease.take());
+                }
+                None => {
+                    self.buf.replace(buf);
+                }
+            }
+        }
+    }
+
+    /// Sends an ICMPv6 message from the router, computing its checksum.
+    fn send_icmp<F>(&self, port: &BorderPort<'a>, dst: IPAddr, fill: F)
+    where
+        F: FnOnce(&mut [u8]) -> Option<usize>,
+    {
+        let mut header = IP6Header::default();
+        header.src_addr = self.link_local_addr();
+        header.dst_addr = dst;
+        header.set_next_header(ip6_nh::ICMP);
+        let ph_header = header;
+        self.send(port, header, |buf| {
+            let len = fill(buf)?;
+            let mut ph_header = ph_header;
+            ph_header.set_payload_len(len as u16);
+            buf[2] = 0;
+            buf[3] = 0;
+            let mut sum = compute_ipv6_ph_sum(&ph_header) + compute_sum(buf, len as u16);
+            while sum > 0xffff {
+                sum = (sum >> 16) + (sum & 0xffff);
+            }
+            let cksum = !(sum as u16);
+            buf[2..4].copy_from_slice(&cksum.to_be_bytes());
+            Some(len)
+        });
+    }
+
+    fn send_advertisement(&self, dst: IPAddr) {
+        let prefix = self.prefix;
+        let prefix_len = self.prefix_len;
+        let version = self.version.get();
+        let router_addr = self.router_addr();
+        self.send_icmp(self.lowpan, dst, |buf| {
+            if buf.len() < RA_LEN {
+                return None;
+            }
+            buf[..16].copy_from_slice(&[0; 16]);
+            buf[0] = icmp_types::ROUTER_ADVERTISEMENT;
+            buf[4] = 64;
+            buf[6..8].copy_from_slice(&ROUTER_LIFETIME_S.to_be_bytes());
+            let mut off = 16;
+
+            let option = &mut buf[off..off + nd_options::PREFIX_INFORMATION_LEN];
+            option.copy_from_slice(&[0; nd_options::PREFIX_INFORMATION_LEN]);
+            option[0] = nd_options::PREFIX_INFORMATION;
+            option[1] = (nd_options::PREFIX_INFORMATION_LEN / 8) as u8;
+            option[2] = prefix_len;
+            // Not on-link, as the nodes only reach each other through routers
+            option[3] = nd_options::PREFIX_AUTONOMOUS;
+            option[4..8].copy_from_slice(&INFINITE_LIFETIME.to_be_bytes());
+            option[8..12].copy_from_slice(&INFINITE_LIFETIME.to_be_bytes());
+            option[16..32].copy_from_slice(&prefix.0);
+            off += nd_options::PREFIX_INFORMATION_LEN;
+
+            self.contexts.each_context(|ctx| {
+                let len = if ctx.prefix_len > 64 { 24 } else { 16 };
+                let option = &mut buf[off..off + len];
+                option[0] = nd_options::SIXLOWPAN_CONTEXT;
+                option[1] = (len / 8) as u8;
+                option[2] = ctx.prefix_len;
+                option[3] = ctx.id
+                    | if ctx.compress {
+                        nd_options::SIXLOWPAN_CONTEXT_COMPRESS
+                    } else {
+                        0
+                    };
+                option[4] = 0;
+                option[5] = 0;
+                option[6..8].copy_from_slice(&CONTEXT_LIFETIME_MIN.to_be_bytes());
+                option[8..].copy_from_slice(&ctx.prefix[..len - 8]);
+                off += len;
+            });
+
+            let option = &mut buf[off..off + nd_options::ABRO_LEN];
+            option[0] = nd_options::ABRO;
+            option[1] = (nd_options::ABRO_LEN / 8) as u8;
+            option[2..4].copy_from_slice(&(version as u16).to_be_bytes());
+            option[4..6].copy_from_slice(&((version >> 16) as u16).to_be_bytes());
+            // The default lifetime, 10000 minutes
+            option[6] = 0;
+            option[7] = 0;
+            option[8..24].copy_from_slice(&router_addr.0);
+            off += nd_options::ABRO_LEN;
+            Some(off)
+        });
+    }
+
+    /// Answers a Neighbor Solicitation from `src` for a proxied `target`.
+    fn send_proxy_advertisement(&self, src: IPAddr, target: IPAddr) {
+        // Solicitations for duplicate address detection are answered to
+        // all nodes, and unsolicited (RFC 4861, 7.2.4)
+        let (dst, flags) = if src.is_unspecified() {
+            (ALL_NODES, NA_ROUTER | NA_OVERRIDE)
+        } else {
+            (src, NA_ROUTER | NA_SOLICITED | NA_OVERRIDE)
+        };
+        self.send_icmp(self.host, dst, |buf| {
+            if buf.len() < 24 {
+                return None;
+            }
+            buf[..8].copy_from_slice(&[
+                icmp_types::NEIGHBOR_ADVERTISEMENT,
+                0,
+                0,
+                0,
+                flags,
+                0,
+                0,
+                0,
+            ]);
+            buf[8..24].copy_from_slice(&target.0);
+            Some(24)
+        });
+    }
+
+    fn receive_packet(&self, packet: &[u8], timestamp: Option<u32>, from: Side) {
+        let (offset, header) = match IP6Header::decode(packet).done() {
+            Some(decoded) => decoded,
+            None => return,
+        };
+        let payload_len = header.get_payload_len() as usize;
+        if offset + payload_len > packet.len() {
+            return;
+        }
+        let payload = &packet[offset..offset + payload_len];
+        let src = header.get_src_addr();
+        let dst = header.get_dst_addr();
+        let in_network = |addr: IPAddr| addr.has_prefix(&self.prefix, self.prefix_len);
+
+        if from == Side::Lowpan && in_network(src) {
+            self.learn(src);
+        }
+
+        // Neighbor discovery messages are only valid from the link (RFC
+        // 4861, 6.1)
+        if header.get_next_header() == ip6_nh::ICMP
+            && header.get_hop_limit() == 255
+            && !payload.is_empty()
+        {
+            match (from, payload[0]) {
+                (Side::Lowpan, icmp_types::ROUTER_SOLICITATION) => {
+                    let dst = if src.is_unspecified() { ALL_NODES } else { src };
+                    self.send_advertisement(dst);
+                    return;
+                }
+                (Side::Host, icmp_types::NEIGHBOR_SOLICITATION) if payload.len() >= 24 => {
+                    let mut target = IPAddr::new();
+                    target.0.copy_from_slice(&payload[8..24]);
+                    if self.is_proxied(target) {
+                        self.send_proxy_advertisement(src, target);
+                        return;
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        if dst.is_multicast() || dst.is_unicast_link_local() || self.is_local(dst) {
+            self.local.map(|local| local.receive(packet, timestamp));
+            return;
+        }
+
+        let port = match from {
+            Side::Host if in_network(dst) => self.lowpan,
+            Side::Lowpan if !in_network(dst) => self.host,
+            _ => return,
+        };
+        let hop_limit = header.get_hop_limit();
+        if hop_limit <= 1 {
+            return;
+        }
+        let mut header = header;
+        header.set_hop_limit(hop_limit - 1);
+        self.send(port, header, |buf| {
+            if payload.len() > buf.len() {
+                port.dropped.set(port.dropped.get().wrapping_add(1));
+                return None;
+            }
+            buf[..payload.len()].copy_from_slice(payload);
+            Some(payload.len())
+        });
+    }
+}
+
+impl<'a> SixlowpanRxClient for BorderRouter<'a> {
+    fn receive(&self, buf: &[u8], len: usize, timestamp: Option<u32>, result: ReturnCode) {
+        if len > buf.len() || result != ReturnCode::SUCCESS {
+            return;
+        }
+        self.receive_packet(&buf[..len], timestamp, Side::Lowpan);
+    }
+}
+
+impl<'a> IpLinkRxClient for BorderRouter<'a> {
+    fn receive(&self, packet: &[u8], timestamp: Option<u32>) {
+        self.receive_packet(packet, timestamp, Side::Host);
+    }
+}
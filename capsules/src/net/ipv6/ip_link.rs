@@ -0,0 +1,209 @@
+//! Links other than 802.15.4 that the IPv6 stack can run over.
+//!
+//! An `IpLink` carries whole, uncompressed IPv6 packets, as an Ethernet MAC,
+//! SLIP over a UART or a BLE IPSP channel would. `IP6LinkSender` implements
+//! `IP6Sender` over any `IpLink`, and `IP6RecvStruct` is an
+//! `IpLinkRxClient`, so the transport layers above are the same as over
+//! 6LoWPAN. An `IP6Router` chooses between the links of several interfaces.
+//!
+//! ```txt
+//!   +-------------+          +-------------+
+//!   |IP6LinkSender|          |IP6RecvStruct|
+//!   +-------------+          +-------------+
+//!     |         ^                   ^
+//! transmit(..)  transmit_done(..)   receive(..)
+//!     v         |                   |
+//!   +---------------------------------------+
+//!   |        IpLink (SLIP, Ethernet)        |
+//!   +---------------------------------------+
+//! ```
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//! # use capsules::net::ipv6::ip_link::IP6LinkSender;
+//!
+//! let slip_sender = static_init!(
+//!     IP6LinkSender<'static>,
+//!     IP6LinkSender::new(slip, slip_ip6_packet, &mut SLIP_TX_BUF, ip_vis)
+//! );
+//! slip.set_transmit_client(slip_sender);
+//! slip.set_receive_client(ip_receive);
+//! slip_sender.set_addr(slip_addr);
+//! ```
+
+use crate::net::ieee802154::MacAddress;
+use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::ipv6::ipv6::{IP6Header, IP6Packet, RawHeader, TransportHeader};
+use crate::net::ipv6::ipv6_send::{IP6Forwarder, IP6SendClient, IP6Sender};
+use crate::net::network_capabilities::{IpVisibilityCapability, NetworkCapability};
+use core::cell::Cell;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::ReturnCode;
+
+/// A link that carries whole IPv6 packets.
+pub trait IpLink<'a> {
+    fn set_transmit_client(&self, client: &'a dyn IpLinkTxClient);
+
+    fn set_receive_client(&self, client: &'a dyn IpLinkRxClient);
+
+    /// The largest IPv6 packet the link carries.
+    fn get_mtu(&self) -> usize;
+
+    /// Sends the first `len` bytes of `buf`, an IPv6 packet, towards
+    /// `next_hop`. Links without addresses, such as SLIP, ignore it. On
+    /// success, the buffer is returned in `transmit_done`.
+    fn transmit(
+        &self,
+        next_hop: IPAddr,
+        buf: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])>;
+}
+
+pub trait IpLinkTxClient {
+    fn transmit_done(&self, buf: &'static mut [u8], result: ReturnCode);
+}
+
+pub trait IpLinkRxClient {
+    /// An IPv6 packet was received. `timestamp` is as for `IP6RecvClient`.
+    fn receive(&self, packet: &[u8], timestamp: Option<u32>);
+}
+
+/// Sends IPv6 packets over an `IpLink`.
+pub struct IP6LinkSender<'a> {
+    link: &'a dyn IpLink<'a>,
+    ip6_packet: TakeCell<'static, IP6Packet<'static>>,
+    /// The encoded packet is passed to the link in this buffer.
+    tx_buf: TakeCell<'static, [u8]>,
+    src_addr: Cell<IPAddr>,
+    client: OptionalCell<&'a dyn IP6SendClient>,
+    ip_vis: &'static IpVisibilityCapability,
+}
+
+impl<'a> IP6LinkSender<'a> {
+    /// `tx_buf` has to hold the largest packet that is sent; packets that do
+    /// not fit it or the MTU of the link are refused with `ESIZE`.
+    pub fn new(
+        link: &'a dyn IpLink<'a>,
+        ip6_packet: &'static mut IP6Packet<'static>,
+        tx_buf: &'static mut [u8],
+        ip_vis: &'static IpVisibilityCapability,
+    ) -> IP6LinkSender<'a> {
+        IP6LinkSender {
+            link: link,
+            ip6_packet: TakeCell::new(ip6_packet),
+            tx_buf: TakeCell::new(tx_buf),
+            src_addr: Cell::new(IPAddr::new()),
+            client: OptionalCell::empty(),
+            ip_vis: ip_vis,
+        }
+    }
+}
+
+impl<'a> IP6Sender<'a> for IP6LinkSender<'a> {
+    fn set_client(&self, client: &'a dyn IP6SendClient) {
+        self.client.set(client);
+    }
+
+    fn set_addr(&self, src_addr: IPAddr) {
+        self.src_addr.set(src_addr);
+    }
+
+    /// Links of this kind have no gateway MAC address.
+    fn set_gateway(&self, _gateway: MacAddress) {}
+
+    fn set_header(&mut self, ip6_header: IP6Header) {
+        self.ip6_packet
+            .map(|ip6_packet| ip6_packet.header = ip6_header);
+    }
+
+    fn send_to(
+        &self,
+        dst: IPAddr,
+        transport_header: TransportHeader,
+        payload: &LeasableBuffer<'static, u8>,
+        net_cap: &'static NetworkCapability,
+    ) -> ReturnCode {
+        if !net_cap.remote_addr_valid(dst, self.ip_vis) {
+            return ReturnCode::FAIL;
+        }
+        self.transmit_packet(dst, payload.len(), |ip6_packet| {
+            ip6_packet.header = IP6Header::default();
+            ip6_packet.header.src_addr = self.src_addr.get();
+            ip6_packet.header.dst_addr = dst;
+            ip6_packet.set_payload(transport_header, payload);
+            ip6_packet.set_transport_checksum();
+        })
+    }
+}
+
+impl<'a> IP6LinkSender<'a> {
+    /// Encodes the packet `fill` sets up, and passes it to the link.
+    fn transmit_packet<F>(&self, dst: IPAddr, payload_len: usize, fill: F) -> ReturnCode
+    where
+        F: FnOnce(&mut IP6Packet<'static>),
+    {
+        let buf = match self.tx_buf.take() {
+            Some(buf) => buf,
+            None => return ReturnCode::EBUSY,
+        };
+        let len = self.ip6_packet.map_or(None, |ip6_packet| {
+            if payload_len > ip6_packet.get_payload_capacity() {
+                return None;
+            }
+            fill(ip6_packet);
+
+            let len = ip6_packet.get_total_len() as usize;
+            if len > buf.len() || len > self.link.get_mtu() {
+                return None;
+            }
+            ip6_packet.encode(buf).done().map(|(len, _)| len)
+        });
+        let len = match len {
+            Some(len) => len,
+            None => {
+                self.tx_buf.replace(buf);
+                return ReturnCode::ESIZE;
+            }
+        };
+        match self.link.transmit(dst, buf, len) {
+            Ok(()) => ReturnCode::SUCCESS,
+            Err((result, buf)) => {
+                self.tx_buf.replace(buf);
+                result
+            }
+        }
+    }
+}
+
+impl<'a> IP6Forwarder<'a> for IP6LinkSender<'a> {
+    fn forward(
+        &self,
+        header: IP6Header,
+        payload: &LeasableBuffer<'static, u8>,
+        net_cap: &'static NetworkCapability,
+    ) -> ReturnCode {
+        let dst = header.get_dst_addr();
+        if !net_cap.remote_addr_valid(dst, self.ip_vis) {
+            return ReturnCode::FAIL;
+        }
+        self.transmit_packet(dst, payload.len(), |ip6_packet| {
+            ip6_packet.header = header;
+            ip6_packet.set_payload(
+                TransportHeader::Raw(RawHeader::new(header.get_next_header())),
+                payload,
+            );
+        })
+    }
+}
+
+impl<'a> IpLinkTxClient for IP6LinkSender<'a> {
+    fn transmit_done(&self, buf: &'static mut [u8], result: ReturnCode) {
+        self.tx_buf.replace(buf);
+        self.client.map(|client| client.send_done(result));
+    }
+}
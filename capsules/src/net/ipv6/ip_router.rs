@@ -0,0 +1,180 @@
+//! Sends IPv6 packets over one of several network interfaces.
+//!
+//! Each `NetInterface` wraps the `IP6Sender` of one link, such as the
+//! 6LoWPAN `IP6SendStruct` or an `IP6LinkSender`, with the addresses of the
+//! interface and the prefix it routes. `IP6Router` is itself an
+//! `IP6Sender`: it sends each packet over the interface whose prefix is the
+//! longest match for the destination, from the first address of that
+//! interface. Interfaces with a prefix length of 0 are default routes, and
+//! among equal matches the interface added first is chosen.
+//!
+//! Transport layers send through the router as through a single
+//! `IP6Sender`. They send one packet at a time, so `send_done` is passed on
+//! from whichever interface sent the packet.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//! # use capsules::net::ipv6::ip_router::{IP6Router, NetInterface};
+//!
+//! let lowpan_iface = static_init!(
+//!     NetInterface<'static>,
+//!     NetInterface::new(lowpan_sender, &LOWPAN_ADDRS, IPAddr::new(), 0)
+//! );
+//! let slip_iface = static_init!(
+//!     NetInterface<'static>,
+//!     NetInterface::new(slip_sender, &SLIP_ADDRS, SLIP_PREFIX, 64)
+//! );
+//! let router = static_init!(IP6Router<'static>, IP6Router::new());
+//! router.add_interface(lowpan_iface);
+//! router.add_interface(slip_iface);
+//! let udp_send_mux = static_init!(
+//!     MuxUdpSender<'static, IP6Router<'static>>,
+//!     MuxUdpSender::new(router)
+//! );
+//! router.set_client(udp_send_mux);
+//! ```
+
+use crate::net::ieee802154::MacAddress;
+use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::ipv6::ipv6::{IP6Header, TransportHeader};
+use crate::net::ipv6::ipv6_send::{IP6SendClient, IP6Sender};
+use crate::net::network_capabilities::NetworkCapability;
+use kernel::common::cells::OptionalCell;
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::common::{List, ListLink, ListNode};
+use kernel::ReturnCode;
+
+pub struct NetInterface<'a> {
+    sender: &'a dyn IP6Sender<'a>,
+    addrs: &'static [IPAddr],
+    prefix: IPAddr,
+    prefix_len: u8,
+    next: ListLink<'a, NetInterface<'a>>,
+}
+
+impl<'a> ListNode<'a, NetInterface<'a>> for NetInterface<'a> {
+    fn next(&'a self) -> &'a ListLink<'a, NetInterface<'a>> {
+        &self.next
+    }
+}
+
+impl<'a> NetInterface<'a> {
+    /// `addrs` are the addresses of the interface, the first of which is
+    /// the source address of the packets it sends. Destinations with the
+    /// first `prefix_len` bits of `prefix` are routed to the interface.
+    pub fn new(
+        sender: &'a dyn IP6Sender<'a>,
+        addrs: &'static [IPAddr],
+        prefix: IPAddr,
+        prefix_len: u8,
+    ) -> NetInterface<'a> {
+        NetInterface {
+            sender: sender,
+            addrs: addrs,
+            prefix: prefix,
+            prefix_len: prefix_len,
+            next: ListLink::empty(),
+        }
+    }
+
+    pub fn get_addrs(&self) -> &'static [IPAddr] {
+        self.addrs
+    }
+
+    pub fn get_sender(&self) -> &'a dyn IP6Sender<'a> {
+        self.sender
+    }
+}
+
+pub struct IP6Router<'a> {
+    interfaces: List<'a, NetInterface<'a>>,
+    client: OptionalCell<&'a dyn IP6SendClient>,
+}
+
+impl<'a> IP6Router<'a> {
+    pub fn new() -> IP6Router<'a> {
+        IP6Router {
+            interfaces: List::new(),
+            client: OptionalCell::empty(),
+        }
+    }
+
+    /// Adds an interface, setting its source address and becoming the
+    /// client of its sender.
+    pub fn add_interface(&'a self, interface: &'a NetInterface<'a>) {
+        if let Some(addr) = interface.addrs.first() {
+            interface.sender.set_addr(*addr);
+        }
+        interface.sender.set_client(self);
+        self.interfaces.push_tail(interface);
+    }
+
+    /// The interface that packets to `dst` are sent over.
+    pub fn route(&self, dst: IPAddr) -> Option<&'a NetInterface<'a>> {
+        self.interfaces
+            .iter()
+            .filter(|interface| dst.has_prefix(&interface.prefix, interface.prefix_len))
+            .fold(
+                None,
+                |best: Option<&'a NetInterface<'a>>, interface| match best {
+                    Some(best) if best.prefix_len >= interface.prefix_len => Some(best),
+                    _ => Some(interface),
+                },
+            )
+    }
+
+    /// Checks whether `addr` is an address of one of the interfaces.
+    pub fn is_local(&self, addr: IPAddr) -> bool {
+        self.interfaces
+            .iter()
+            .any(|interface| interface.addrs.iter().any(|a| *a == addr))
+    }
+}
+
+impl<'a> IP6Sender<'a> for IP6Router<'a> {
+    fn set_client(&self, client: &'a dyn IP6SendClient) {
+        self.client.set(client);
+    }
+
+    /// Sets the source address of every interface. Interfaces normally send
+    /// from their own first address, which `add_interface` sets.
+    fn set_addr(&self, src_addr: IPAddr) {
+        for interface in self.interfaces.iter() {
+            interface.sender.set_addr(src_addr);
+        }
+    }
+
+    fn set_gateway(&self, gateway: MacAddress) {
+        for interface in self.interfaces.iter() {
+            interface.sender.set_gateway(gateway);
+        }
+    }
+
+    /// The header of each interface is set through its own sender.
+    fn set_header(&mut self, _ip6_header: IP6Header) {}
+
+    /// Returns `FAIL` if no interface routes `dst`.
+    fn send_to(
+        &self,
+        dst: IPAddr,
+        transport_header: TransportHeader,
+        payload: &LeasableBuffer<'static, u8>,
+        net_cap: &'static NetworkCapability,
+    ) -> ReturnCode {
+        match self.route(dst) {
+            Some(interface) => interface
+                .sender
+                .send_to(dst, transport_header, payload, net_cap),
+            None => ReturnCode::FAIL,
+        }
+    }
+}
+
+impl<'a> IP6SendClient for IP6Router<'a> {
+    fn send_done(&self, result: ReturnCode) {
+        self.client.map(|client| client.send_done(result));
+    }
+}
@@ -74,6 +74,20 @@ impl IPAddr {
         ip_addr
     }
 
+    /// The MAC address the interface identifier of the address was formed
+    /// from by `generate_from_mac`.
+    pub fn mac_from_iid(&self) -> MacAddress {
+        let iid = &self.0[8..16];
+        if iid[..6] == [0, 0, 0, 0xff, 0xfe, 0] {
+            MacAddress::Short(u16::from_be_bytes([iid[6], iid[7]]))
+        } else {
+            let mut long_addr = [0; 8];
+            long_addr.copy_from_slice(iid);
+            long_addr[0] ^= 0b00000010;
+            MacAddress::Long(long_addr)
+        }
+    }
+
     pub fn is_unspecified(&self) -> bool {
         self.0.iter().all(|&b| b == 0)
     }
@@ -108,9 +122,33 @@ impl IPAddr {
         }
     }
 
+    /// Checks whether the first `prefix_len` bits of this address are those
+    /// of `prefix`.
+    pub fn has_prefix(&self, prefix: &IPAddr, prefix_len: u8) -> bool {
+        let prefix_len = core::cmp::min(prefix_len, 128);
+        let full_bytes = (prefix_len / 8) as usize;
+        let remaining = prefix_len & 0x7;
+        if self.0[..full_bytes] != prefix.0[..full_bytes] {
+            return false;
+        }
+        if remaining == 0 {
+            return true;
+        }
+        let mask = (0xff as u8) << (8 - remaining);
+        self.0[full_bytes] & mask == prefix.0[full_bytes] & mask
+    }
+
     pub fn is_multicast(&self) -> bool {
         self.0[0] == 0xff
     }
+
+    /// The solicited-node multicast address of this address, to which
+    /// neighbor solicitations for it are sent (RFC 4291, 2.7.1).
+    pub fn solicited_node(&self) -> IPAddr {
+        let mut addr = IPAddr([0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x01, 0xff, 0, 0, 0]);
+        addr.0[13..].copy_from_slice(&self.0[13..]);
+        addr
+    }
 }
 
 pub fn compute_udp_checksum(
@@ -197,10 +235,23 @@ pub fn compute_icmp_checksum(
 
     // add options
     match icmp_header.get_options() {
-        ICMP6HeaderOptions::Type1 { unused } | ICMP6HeaderOptions::Type3 { unused } => {
+        ICMP6HeaderOptions::Type1 { unused }
+        | ICMP6HeaderOptions::Type3 { unused }
+        | ICMP6HeaderOptions::Type133 { reserved: unused }
+        | ICMP6HeaderOptions::Type135 { reserved: unused }
+        | ICMP6HeaderOptions::Type136 { flags: unused }
+        | ICMP6HeaderOptions::Type155 { base: unused } => {
             sum += unused >> 16; // upper 16 bits
             sum += unused & 0xffff; // lower 16 bits
         }
+        ICMP6HeaderOptions::Type134 {
+            cur_hop_limit,
+            flags,
+            router_lifetime,
+        } => {
+            sum += ((cur_hop_limit as u32) << 8) + flags as u32;
+            sum += router_lifetime as u32;
+        }
         ICMP6HeaderOptions::Type128 { id, seqno } | ICMP6HeaderOptions::Type129 { id, seqno } => {
             sum += id as u32;
             sum += seqno as u32;
@@ -215,7 +266,45 @@ pub fn compute_icmp_checksum(
     while sum > 0xffff {
         let sum_upper = sum >> 16;
         let sum_lower = sum & 0xffff;
-        sum += sum_upper + sum_lower;
+        sum = sum_upper + sum_lower;
+    }
+
+    sum = !sum;
+    sum = sum & 0xffff;
+
+    sum as u16
+}
+
+/// Computes the TCP checksum of a segment, over the pseudo-header of
+/// `ip6_header`, the encoded TCP header `tcp_header` and `payload`. For a
+/// received segment, passing the whole segment as `tcp_header` and an empty
+/// `payload` gives 0 if its checksum is correct.
+pub fn compute_tcp_checksum(ip6_header: &IP6Header, tcp_header: &[u8], payload: &[u8]) -> u16 {
+    let mut sum: u32 = 0;
+
+    // add ipv6 pseudo-header, with the 32 bit TCP length
+    let mut i = 0;
+    while i < 16 {
+        sum += (ip6_header.src_addr.0[i] as u32) << 8 | ip6_header.src_addr.0[i + 1] as u32;
+        sum += (ip6_header.dst_addr.0[i] as u32) << 8 | ip6_header.dst_addr.0[i + 1] as u32;
+        i += 2;
+    }
+    let tcp_len = (tcp_header.len() + payload.len()) as u32;
+    sum += tcp_len >> 16;
+    sum += tcp_len & 0xffff;
+    sum += ip6_nh::TCP as u32;
+
+    // add header and payload, with an odd last byte padded with zero. The
+    // header length is a multiple of 4, so the payload stays aligned.
+    for word in tcp_header.chunks(2).chain(payload.chunks(2)) {
+        let msb = (word[0] as u32) << 8;
+        let lsb = word.get(1).map_or(0, |b| *b as u32);
+        sum += msb + lsb;
+    }
+
+    // carry overflow
+    while sum > 0xffff {
+        sum = (sum >> 16) + (sum & 0xffff);
     }
 
     sum = !sum;
@@ -241,7 +330,7 @@ pub fn compute_ipv6_ph_sum(ip6_header: &IP6Header) -> u32 {
         i += 2;
     }
 
-    sum += ip6_header.payload_len as u32;
+    sum += ip6_header.get_payload_len() as u32;
     sum += ip6_header.next_header as u32;
 
     sum
@@ -253,7 +342,12 @@ pub fn compute_sum(buf: &[u8], len: u16) -> u32 {
     let mut i: usize = 0;
     while i < (len as usize) {
         let msb = (buf[i] as u32) << 8;
-        let lsb = buf[i + 1] as u32;
+        // an odd last byte is padded with zero
+        let lsb = if i + 1 < len as usize {
+            buf[i + 1] as u32
+        } else {
+            0
+        };
         sum += msb + lsb;
         i += 2;
     }
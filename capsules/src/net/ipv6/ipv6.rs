@@ -67,11 +67,14 @@
 // (as required by 6LoWPAN) difficult.
 
 use crate::net::icmpv6::icmpv6::ICMP6Header;
-use crate::net::ipv6::ip_utils::{compute_icmp_checksum, compute_udp_checksum, ip6_nh, IPAddr};
+use crate::net::ipv6::ip_utils::{
+    compute_icmp_checksum, compute_ipv6_ph_sum, compute_sum, compute_tcp_checksum,
+    compute_udp_checksum, ip6_nh, IPAddr,
+};
 use crate::net::stream::SResult;
 use crate::net::stream::{decode_bytes, decode_u16, decode_u8};
 use crate::net::stream::{encode_bytes, encode_u16, encode_u8};
-use crate::net::tcp::TCPHeader;
+use crate::net::tcp::tcp::{TCPHeader, TCP_MAX_HDR_LEN};
 use crate::net::udp::udp::UDPHeader;
 use kernel::common::leasable_buffer::LeasableBuffer;
 use kernel::ReturnCode;
@@ -281,17 +284,28 @@ impl IP6Header {
                 ReturnCode::SUCCESS
             }
             ip6_nh::ICMP => {
-                // Untested (10/5/18)
+                if buf.len() < ICMP_HDR_LEN {
+                    return ReturnCode::FAIL;
+                }
+                // The checksum is computed without the checksum field, so
+                // it has to match the received one.
                 let mut icmp_header: [u8; ICMP_HDR_LEN] = [0; ICMP_HDR_LEN];
                 icmp_header.copy_from_slice(&buf[..ICMP_HDR_LEN]);
-                let checksum = match ICMP6Header::decode(&icmp_header).done() {
+                let valid = match ICMP6Header::decode(&icmp_header).done() {
                     Some((_offset, mut hdr)) => {
                         hdr.set_len(buf.len() as u16);
-                        u16::from_be(compute_icmp_checksum(&self, &hdr, &buf[ICMP_HDR_LEN..]))
+                        compute_icmp_checksum(&self, &hdr, &buf[ICMP_HDR_LEN..]) == hdr.get_cksum()
                     }
-                    None => 0xffff, //Will be dropped, as ones comp -0 checksum is invalid
+                    None => false, //Unsupported type, dropped
                 };
-                if checksum != 0 {
+                if !valid {
+                    return ReturnCode::FAIL; //Incorrect cksum
+                }
+                ReturnCode::SUCCESS
+            }
+            ip6_nh::TCP => {
+                // Summing a segment with its checksum gives 0 when it is right
+                if compute_tcp_checksum(&self, buf, &[]) != 0 {
                     return ReturnCode::FAIL; //Incorrect cksum
                 }
                 ReturnCode::SUCCESS
@@ -304,7 +318,8 @@ impl IP6Header {
 /// This defines the currently supported `TransportHeader` types. The contents
 /// of each header is encapsulated by the enum type. Note that this definition
 /// of `TransportHeader`s means that recursive headers are not supported.
-/// As of now, there is no support for sending raw IP packets without a transport header.
+/// Raw IP packets, whose transport header is carried in the payload, are sent
+/// with a `RawHeader`.
 /// Currently we accept the overhead of copying these structs in/out of an OptionalCell
 /// in `udp_send.rs`.
 #[derive(Copy, Clone)]
@@ -312,6 +327,42 @@ pub enum TransportHeader {
     UDP(UDPHeader),
     TCP(TCPHeader),
     ICMP(ICMP6Header),
+    Raw(RawHeader),
+}
+
+/// The next header value of a raw packet, whose payload holds the transport
+/// header, if there is one, as it is sent. The only field the stack fills in
+/// is the checksum of ICMPv6 messages, which raw sockets do not compute
+/// themselves (RFC 3542, 3.1).
+#[derive(Copy, Clone)]
+pub struct RawHeader {
+    next_header: u8,
+    len: u16,
+}
+
+impl RawHeader {
+    pub fn new(next_header: u8) -> RawHeader {
+        RawHeader {
+            next_header: next_header,
+            len: 0,
+        }
+    }
+
+    pub fn get_next_header(&self) -> u8 {
+        self.next_header
+    }
+
+    pub fn get_len(&self) -> u16 {
+        self.len
+    }
+
+    pub fn set_len(&mut self, len: u16) {
+        self.len = len;
+    }
+
+    pub fn get_hdr_size(&self) -> usize {
+        0
+    }
 }
 
 /// The `IPPayload` struct contains a `TransportHeader` and a mutable buffer
@@ -366,10 +417,21 @@ impl<'a> IPPayload<'a> {
             TransportHeader::ICMP(mut icmp_header) => {
                 let length = (payload.len() + icmp_header.get_hdr_size()) as u16;
                 icmp_header.set_len(length);
-                self.header = transport_header;
+                self.header = TransportHeader::ICMP(icmp_header);
                 (ip6_nh::ICMP, length)
             }
-            _ => (ip6_nh::NO_NEXT, payload.len() as u16),
+            TransportHeader::TCP(mut tcp_header) => {
+                let length = (payload.len() + tcp_header.get_hdr_size()) as u16;
+                tcp_header.set_len(length);
+                self.header = TransportHeader::TCP(tcp_header);
+                (ip6_nh::TCP, length)
+            }
+            TransportHeader::Raw(mut raw_header) => {
+                let length = payload.len() as u16;
+                raw_header.set_len(length);
+                self.header = TransportHeader::Raw(raw_header);
+                (raw_header.get_next_header(), length)
+            }
         }
     }
 
@@ -388,9 +450,8 @@ impl<'a> IPPayload<'a> {
         let (offset, _) = match self.header {
             TransportHeader::UDP(udp_header) => udp_header.encode(buf, offset).done().unwrap(),
             TransportHeader::ICMP(icmp_header) => icmp_header.encode(buf, offset).done().unwrap(),
-            _ => {
-                unimplemented!();
-            }
+            TransportHeader::TCP(tcp_header) => tcp_header.encode(buf, offset).done().unwrap(),
+            TransportHeader::Raw(_) => (offset, offset),
         };
         let payload_length = self.get_payload_length();
         let offset = enc_consume!(buf, offset; encode_bytes, &self.payload[..payload_length]);
@@ -405,9 +466,10 @@ impl<'a> IPPayload<'a> {
             TransportHeader::ICMP(icmp_header) => {
                 icmp_header.get_len() as usize - icmp_header.get_hdr_size()
             }
-            _ => {
-                unimplemented!();
+            TransportHeader::TCP(tcp_header) => {
+                tcp_header.get_len() as usize - tcp_header.get_hdr_size()
             }
+            TransportHeader::Raw(raw_header) => raw_header.get_len() as usize,
         }
     }
 }
@@ -450,11 +512,17 @@ impl<'a> IP6Packet<'a> {
         self.payload.payload
     }
 
+    /// The largest transport payload the packet can hold.
+    pub fn get_payload_capacity(&self) -> usize {
+        self.payload.payload.len()
+    }
+
     pub fn get_total_hdr_size(&self) -> usize {
         let transport_hdr_size = match self.payload.header {
             TransportHeader::UDP(udp_hdr) => udp_hdr.get_hdr_size(),
             TransportHeader::ICMP(icmp_header) => icmp_header.get_hdr_size(),
-            _ => unimplemented!(),
+            TransportHeader::TCP(tcp_header) => tcp_header.get_hdr_size(),
+            TransportHeader::Raw(raw_header) => raw_header.get_hdr_size(),
         };
         40 + transport_hdr_size
     }
@@ -480,8 +548,34 @@ impl<'a> IP6Packet<'a> {
                 let cksum = compute_icmp_checksum(&self.header, &icmp_header, self.payload.payload);
                 icmp_header.set_cksum(cksum);
             }
-            _ => {
-                unimplemented!();
+            TransportHeader::TCP(ref mut tcp_header) => {
+                // The checksum covers the options, so sum the encoded header
+                let mut header = [0; TCP_MAX_HDR_LEN];
+                tcp_header.set_cksum(0);
+                let header_len = tcp_header
+                    .encode(&mut header, 0)
+                    .done()
+                    .map_or(0, |(offset, _)| offset);
+                let payload_len = tcp_header.get_len() as usize - tcp_header.get_hdr_size();
+                let cksum = compute_tcp_checksum(
+                    &self.header,
+                    &header[..header_len],
+                    &self.payload.payload[..payload_len],
+                );
+                tcp_header.set_cksum(cksum);
+            }
+            TransportHeader::Raw(raw_header) => {
+                let len = raw_header.get_len() as usize;
+                if raw_header.get_next_header() == ip6_nh::ICMP && len >= 4 {
+                    let message = &mut self.payload.payload[..len];
+                    message[2..4].copy_from_slice(&[0, 0]);
+                    let mut sum =
+                        compute_ipv6_ph_sum(&self.header) + compute_sum(message, len as u16);
+                    while sum > 0xffff {
+                        sum = (sum >> 16) + (sum & 0xffff);
+                    }
+                    message[2..4].copy_from_slice(&(!sum as u16).to_be_bytes());
+                }
             }
         }
     }
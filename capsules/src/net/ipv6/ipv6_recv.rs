@@ -1,7 +1,8 @@
+use crate::net::ipv6::ip_link::IpLinkRxClient;
 use crate::net::ipv6::ipv6::IP6Header;
 use crate::net::sixlowpan::sixlowpan_state::SixlowpanRxClient;
 use kernel::common::cells::OptionalCell;
-use kernel::debug;
+use kernel::log_trace;
 use kernel::ReturnCode;
 
 // To provide some context for the entire rx chain:
@@ -27,8 +28,14 @@ use kernel::ReturnCode;
   packets up to userland.
 */
 
+/// `timestamp` is the radio timestamp of the packet, if the radio
+/// timestamps received frames.
 pub trait IP6RecvClient {
-    fn receive(&self, header: IP6Header, payload: &[u8]);
+    fn receive(&self, header: IP6Header, payload: &[u8], timestamp: Option<u32>);
+
+    /// A packet was dropped because its transport checksum was wrong, for
+    /// clients that count dropped packets.
+    fn checksum_failed(&self, _header: IP6Header) {}
 }
 
 /// Currently only one implementation of this trait should exist,
@@ -59,26 +66,41 @@ impl<'a> IP6RecvStruct<'a> {
 }
 
 impl<'a> SixlowpanRxClient for IP6RecvStruct<'a> {
-    fn receive(&self, buf: &[u8], len: usize, result: ReturnCode) {
+    fn receive(&self, buf: &[u8], len: usize, timestamp: Option<u32>, result: ReturnCode) {
         // TODO: Drop here?
         if len > buf.len() || result != ReturnCode::SUCCESS {
             return;
         }
+        self.receive_packet(&buf[..len], timestamp);
+    }
+}
+
+/// Links other than 6LoWPAN pass up whole packets.
+impl<'a> IpLinkRxClient for IP6RecvStruct<'a> {
+    fn receive(&self, packet: &[u8], timestamp: Option<u32>) {
+        self.receive_packet(packet, timestamp);
+    }
+}
+
+impl<'a> IP6RecvStruct<'a> {
+    fn receive_packet(&self, buf: &[u8], timestamp: Option<u32>) {
+        let len = buf.len();
         match IP6Header::decode(buf).done() {
             Some((offset, ip6_header)) => {
                 let checksum_result = ip6_header.check_transport_checksum(&buf[offset..len]);
                 if checksum_result == ReturnCode::FAIL {
-                    debug!("cksum fail!: {:?}", checksum_result);
+                    log_trace!("cksum fail!: {:?}", checksum_result);
+                    self.client.map(|client| client.checksum_failed(ip6_header));
                     return; //Dropped.
                 }
-                // Note: Protocols for which checksum verification is not implemented (TCP, etc.)
+                // Note: Protocols for which checksum verification is not implemented
                 // are automatically assumed as fine, rather than dropped
 
                 self.client
-                    .map(|client| client.receive(ip6_header, &buf[offset..len]));
+                    .map(|client| client.receive(ip6_header, &buf[offset..len], timestamp));
             }
             None => {
-                debug!("failed to decode ipv6 header");
+                log_trace!("failed to decode ipv6 header");
                 // TODO: Report the error somewhere...
             }
         }
@@ -5,7 +5,9 @@
 //! when a transmission has completed.
 //!
 //! This file also includes an implementation of the `IP6Sender` trait, which
-//! sends an IPv6 packet using 6LoWPAN.
+//! sends an IPv6 packet using 6LoWPAN. Multicast packets are broadcast, and
+//! unicast packets are sent to the gateway, unless a `NeighborResolver` was
+//! set, in which case link-local destinations are resolved with it.
 
 // Additional Work and Known Problems
 // ----------------------------------
@@ -17,17 +19,28 @@
 // interface.
 
 use crate::ieee802154::device::{MacDevice, TxClient};
-use crate::net::ieee802154::MacAddress;
+use crate::net::ieee802154::{KeyId, MacAddress, SecurityLevel};
 use crate::net::ipv6::ip_utils::IPAddr;
-use crate::net::ipv6::ipv6::{IP6Header, IP6Packet, TransportHeader};
+use crate::net::ipv6::ipv6::{IP6Header, IP6Packet, RawHeader, TransportHeader};
+use crate::net::ipv6::ndp::{self, NeighborResolver};
 use crate::net::network_capabilities::{IpVisibilityCapability, NetworkCapability};
 use crate::net::sixlowpan::sixlowpan_state::TxState;
 use core::cell::Cell;
 use kernel::common::cells::{OptionalCell, TakeCell};
 use kernel::common::leasable_buffer::LeasableBuffer;
-use kernel::debug;
 use kernel::hil::time;
 use kernel::ReturnCode;
+use kernel::{log_error, log_info, log_warn};
+
+/// How often the cache is checked while the next hop of a packet is being
+/// resolved.
+const RESOLVE_POLL_MS: u32 = 100;
+
+/// Checks of the cache before a packet whose next hop was not resolved is
+/// dropped; longer than the solicitations are retransmitted for.
+const RESOLVE_POLLS: u32 = ndp::MAX_MULTICAST_SOLICIT as u32 * ndp::TICK_MS / RESOLVE_POLL_MS + 5;
+
+const BROADCAST_MAC_ADDR: MacAddress = MacAddress::Short(0xffff);
 
 /// This trait must be implemented by upper layers in order to receive
 /// the `send_done` callback when a transmission has completed. The upper
@@ -79,6 +92,9 @@ pub trait IP6Sender<'a> {
     /// `dst` - IPv6 address to send the packet to
     /// `transport_header` - The `TransportHeader` for the packet being sent
     /// `payload` - The transport payload for the packet being sent
+    ///
+    /// # Return Value
+    /// `ESIZE` if the payload does not fit the packet buffer of the sender
     fn send_to(
         &self,
         dst: IPAddr,
@@ -88,6 +104,22 @@ pub trait IP6Sender<'a> {
     ) -> ReturnCode;
 }
 
+/// Sends packets whose IPv6 header was written elsewhere, for capsules that
+/// route packets between links. The header and payload are sent as they
+/// are, so the caller decrements the hop limit, and `send_done` is called on
+/// the `IP6SendClient` of the sender.
+pub trait IP6Forwarder<'a>: IP6Sender<'a> {
+    /// `payload` is everything after the IPv6 header, extension and
+    /// transport headers included. Returns `ESIZE` if it does not fit the
+    /// packet buffer of the sender.
+    fn forward(
+        &self,
+        header: IP6Header,
+        payload: &LeasableBuffer<'static, u8>,
+        net_cap: &'static NetworkCapability,
+    ) -> ReturnCode;
+}
+
 /// This struct is a specific implementation of the `IP6Sender` trait. This
 /// struct sends the packet using 6LoWPAN over a generic `MacDevice` object.
 pub struct IP6SendStruct<'a, A: time::Alarm<'a>> {
@@ -101,10 +133,15 @@ pub struct IP6SendStruct<'a, A: time::Alarm<'a>> {
     tx_buf: TakeCell<'static, [u8]>,
     sixlowpan: TxState<'a>,
     radio: &'a dyn MacDevice<'a>,
-    dst_mac_addr: MacAddress,
     src_mac_addr: MacAddress,
     client: OptionalCell<&'a dyn IP6SendClient>,
     ip_vis: &'static IpVisibilityCapability,
+    resolver: OptionalCell<&'a dyn NeighborResolver>,
+    // The next hop of the packet waiting for address resolution, and how
+    // many times the cache was checked for it
+    next_hop: Cell<Option<IPAddr>>,
+    resolve_polls: Cell<u32>,
+    security: Cell<Option<(SecurityLevel, KeyId)>>,
 }
 
 impl<'a, A: time::Alarm<'a>> IP6Sender<'a> for IP6SendStruct<'a, A> {
@@ -135,15 +172,63 @@ impl<'a, A: time::Alarm<'a>> IP6Sender<'a> for IP6SendStruct<'a, A> {
         if !net_cap.remote_addr_valid(dst, self.ip_vis) {
             return ReturnCode::FAIL;
         }
-        self.sixlowpan.init(
-            self.src_mac_addr,
-            self.dst_mac_addr,
-            self.radio.get_pan(),
-            None,
-        );
+        // Larger payloads are fragmented by 6LoWPAN, but have to fit the
+        // packet buffer first
+        let capacity = self
+            .ip6_packet
+            .map_or(0, |ip6_packet| ip6_packet.get_payload_capacity());
+        if payload.len() > capacity {
+            return ReturnCode::ESIZE;
+        }
         self.init_packet(dst, transport_header, payload);
-        let ret = self.send_next_fragment();
-        ret
+        match self.next_hop_mac_addr(dst) {
+            Some(dst_mac_addr) => self.send_to_mac(dst_mac_addr),
+            None => {
+                // The payload was copied, so the packet waits for the
+                // advertisement in ip6_packet
+                self.next_hop.set(Some(dst));
+                self.resolve_polls.set(0);
+                self.alarm
+                    .set_alarm(self.alarm.now(), A::ticks_from_ms(RESOLVE_POLL_MS));
+                ReturnCode::SUCCESS
+            }
+        }
+    }
+}
+
+/// Forwarded packets are sent to the MAC address their destination was
+/// formed from, as the nodes of a 6LoWPAN network a border router serves
+/// are one hop away and configure their addresses from their MAC addresses.
+impl<'a, A: time::Alarm<'a>> IP6Forwarder<'a> for IP6SendStruct<'a, A> {
+    fn forward(
+        &self,
+        header: IP6Header,
+        payload: &LeasableBuffer<'static, u8>,
+        net_cap: &'static NetworkCapability,
+    ) -> ReturnCode {
+        let dst = header.get_dst_addr();
+        if !net_cap.remote_addr_valid(dst, self.ip_vis) {
+            return ReturnCode::FAIL;
+        }
+        let fits = self.ip6_packet.map_or(false, |ip6_packet| {
+            if payload.len() > ip6_packet.get_payload_capacity() {
+                return false;
+            }
+            ip6_packet.header = header;
+            ip6_packet.set_payload(
+                TransportHeader::Raw(RawHeader::new(header.get_next_header())),
+                payload,
+            );
+            true
+        });
+        if !fits {
+            return ReturnCode::ESIZE;
+        }
+        if dst.is_multicast() {
+            self.send_to_mac(BROADCAST_MAC_ADDR)
+        } else {
+            self.send_to_mac(dst.mac_from_iid())
+        }
     }
 }
 
@@ -166,10 +251,75 @@ impl<'a, A: time::Alarm<'a>> IP6SendStruct<'a, A> {
             tx_buf: TakeCell::new(tx_buf),
             sixlowpan: sixlowpan,
             radio: radio,
-            dst_mac_addr: dst_mac_addr,
             src_mac_addr: src_mac_addr,
             client: OptionalCell::empty(),
             ip_vis: ip_vis,
+            resolver: OptionalCell::empty(),
+            next_hop: Cell::new(None),
+            resolve_polls: Cell::new(0),
+            security: Cell::new(None),
+        }
+    }
+
+    /// Sets the link-layer security of the frames of the packets that are
+    /// sent next, or `None` to send them in the clear. The key has to be in
+    /// the key procedure of the MAC device, or the packets fail to send.
+    pub fn set_security(&self, security: Option<(SecurityLevel, KeyId)>) {
+        self.security.set(security);
+    }
+
+    /// Resolves the MAC addresses of link-local destinations with
+    /// `resolver`, such as a `NeighborDiscovery`, instead of sending every
+    /// unicast packet to the gateway.
+    pub fn set_neighbor_resolver(&self, resolver: &'a dyn NeighborResolver) {
+        self.resolver.set(resolver);
+    }
+
+    /// Returns `None` if the destination is being resolved.
+    fn next_hop_mac_addr(&self, dst: IPAddr) -> Option<MacAddress> {
+        if dst.is_multicast() {
+            return Some(BROADCAST_MAC_ADDR);
+        }
+        if !dst.is_unicast_link_local() {
+            return Some(self.gateway.get());
+        }
+        self.resolver
+            .map_or(Some(self.gateway.get()), |resolver| resolver.resolve(dst))
+    }
+
+    fn send_to_mac(&self, dst_mac_addr: MacAddress) -> ReturnCode {
+        self.sixlowpan.init(
+            self.src_mac_addr,
+            dst_mac_addr,
+            self.radio.get_pan(),
+            self.security.get(),
+        );
+        self.send_next_fragment()
+    }
+
+    fn poll_next_hop(&self, dst: IPAddr) {
+        let mac_addr = self.resolver.and_then(|resolver| resolver.lookup(dst));
+        let result = match mac_addr {
+            Some(dst_mac_addr) => {
+                self.next_hop.set(None);
+                self.send_to_mac(dst_mac_addr)
+            }
+            None => {
+                let polls = self.resolve_polls.get() + 1;
+                self.resolve_polls.set(polls);
+                if polls >= RESOLVE_POLLS {
+                    log_warn!("Neighbor not resolved");
+                    self.next_hop.set(None);
+                    ReturnCode::FAIL
+                } else {
+                    self.alarm
+                        .set_alarm(self.alarm.now(), A::ticks_from_ms(RESOLVE_POLL_MS));
+                    ReturnCode::SUCCESS
+                }
+            }
+        };
+        if result != ReturnCode::SUCCESS {
+            self.send_completed(result);
         }
     }
 
@@ -181,7 +331,7 @@ impl<'a, A: time::Alarm<'a>> IP6SendStruct<'a, A> {
     ) {
         self.ip6_packet.map_or_else(
             || {
-                debug!("init packet failed.");
+                log_warn!("init packet failed.");
             },
             |ip6_packet| {
                 ip6_packet.header = IP6Header::default();
@@ -226,7 +376,7 @@ impl<'a, A: time::Alarm<'a>> IP6SendStruct<'a, A> {
                     }
                 }
                 None => {
-                    debug!("Missing tx_buf");
+                    log_error!("Missing tx_buf");
                     (ReturnCode::EBUSY, false)
                 }
             })
@@ -247,6 +397,10 @@ impl<'a, A: time::Alarm<'a>> IP6SendStruct<'a, A> {
 
 impl<'a, A: time::Alarm<'a>> time::AlarmClient for IP6SendStruct<'a, A> {
     fn alarm(&self) {
+        if let Some(dst) = self.next_hop.get() {
+            self.poll_next_hop(dst);
+            return;
+        }
         let result = self.send_next_fragment();
         if result != ReturnCode::SUCCESS {
             self.send_completed(result);
@@ -258,7 +412,7 @@ impl<'a, A: time::Alarm<'a>> TxClient for IP6SendStruct<'a, A> {
     fn send_done(&self, tx_buf: &'static mut [u8], acked: bool, result: ReturnCode) {
         self.tx_buf.replace(tx_buf);
         if result != ReturnCode::SUCCESS {
-            debug!("Send Failed: {:?}, acked: {}", result, acked);
+            log_info!("Send Failed: {:?}, acked: {}", result, acked);
             self.client.map(move |client| {
                 client.send_done(result);
             });
@@ -1,4 +1,11 @@
+pub mod autoconf;
+pub mod border_router;
+pub mod ip_link;
+pub mod ip_router;
 pub mod ip_utils;
 pub mod ipv6;
 pub mod ipv6_recv;
 pub mod ipv6_send;
+pub mod ndp;
+pub mod raw_driver;
+pub mod rpl;
@@ -0,0 +1,545 @@
+//! IPv6 Neighbor Discovery (RFC 4861), for address resolution.
+//!
+//! `NeighborDiscovery` keeps a neighbor cache that maps the IPv6 addresses of
+//! neighbors to their link-layer addresses. An `IP6SendStruct` given it with
+//! `set_neighbor_resolver()` resolves the next hop of each link-local
+//! destination through it: on a miss, a Neighbor Solicitation is multicast to
+//! the solicited-node address of the destination, and the packet is held
+//! until the Neighbor Advertisement arrives. As in 6LoWPAN networks (RFC
+//! 6775), other unicast destinations are taken to be off-link and are sent to
+//! the gateway.
+//!
+//! It also answers solicitations for the addresses of the interface, and
+//! learns the link-layer addresses of the nodes that send them. As an
+//! `AutoconfClient`, it also answers for the addresses that are
+//! autoconfigured. Capsules can
+//! look up, add, remove and flush entries of the cache.
+//!
+//! Like the transports, it has its own MAC user, and sends and receives
+//! through an `ICMP6SendStruct` and an `ICMP6RecvStruct`.
+//!
+//! Limitations
+//! -----------
+//! - Entries are used until they expire, `NEIGHBOR_LIFETIME_TICKS` after they
+//!   were learned; there are no STALE or PROBE states.
+//! - Router Solicitations, Router Advertisements and Redirects are ignored.
+//! - Duplicate address detection is not performed, though solicitations sent
+//!   for it by other nodes are answered.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//! # use capsules::net::ipv6::ndp::NeighborDiscovery;
+//!
+//! let ndp = static_init!(
+//!     NeighborDiscovery<'static, VirtualMuxAlarm<'static, sam4l::ast::Ast>>,
+//!     NeighborDiscovery::new(
+//!         icmp_send,
+//!         ndp_alarm,
+//!         &mut NDP_BUF,
+//!         local_ip_ifaces,
+//!         src_mac_addr,
+//!         net_cap
+//!     )
+//! );
+//! ndp_alarm.set_alarm_client(ndp);
+//! icmp_send.set_client(ndp);
+//! icmp_recv.set_client(ndp);
+//! udp_ip_send.set_neighbor_resolver(ndp);
+//! ```
+
+use crate::net::icmpv6::icmpv6::{ICMP6Header, ICMP6HeaderOptions, ICMP6Type};
+use crate::net::icmpv6::icmpv6_recv::ICMP6RecvClient;
+use crate::net::icmpv6::icmpv6_send::{ICMP6SendClient, ICMP6Sender};
+use crate::net::ieee802154::MacAddress;
+use crate::net::ipv6::autoconf::{self, AddressState, AutoconfClient};
+use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::ipv6::ipv6::IP6Header;
+use crate::net::network_capabilities::NetworkCapability;
+use core::cell::Cell;
+use kernel::common::cells::TakeCell;
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::hil::time::{self, Alarm};
+use kernel::ReturnCode;
+
+/// The number of neighbors that can be cached.
+pub const NEIGHBOR_CACHE_SIZE: usize = 8;
+
+/// The size of the buffer for the messages sent: a target address and a
+/// link-layer address option.
+pub const NDP_BUF_LEN: usize = 32;
+
+/// Entries are aged, and solicitations retransmitted, every tick.
+pub const TICK_MS: u32 = 1000;
+
+/// Solicitations sent for an address before giving up on it.
+pub const MAX_MULTICAST_SOLICIT: u16 = 3;
+
+/// Learned entries are dropped after this many ticks without an
+/// advertisement or solicitation from the neighbor.
+pub const NEIGHBOR_LIFETIME_TICKS: u16 = 600;
+
+pub mod na_flags {
+    pub const ROUTER: u32 = 1 << 31;
+    pub const SOLICITED: u32 = 1 << 30;
+    pub const OVERRIDE: u32 = 1 << 29;
+}
+
+mod nd_options {
+    pub const SOURCE_LL_ADDR: u8 = 1;
+    pub const TARGET_LL_ADDR: u8 = 2;
+}
+
+const ALL_NODES: IPAddr = IPAddr([0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x01]);
+
+/// Resolves the IPv6 addresses of neighbors to link-layer addresses.
+pub trait NeighborResolver {
+    /// Returns the link-layer address of `ip_addr` if it is known, and
+    /// otherwise starts resolving it, unless that is already under way.
+    fn resolve(&self, ip_addr: IPAddr) -> Option<MacAddress>;
+
+    /// Returns the link-layer address of `ip_addr` if it is known.
+    fn lookup(&self, ip_addr: IPAddr) -> Option<MacAddress>;
+}
+
+#[derive(Copy, Clone)]
+struct Neighbor {
+    ip_addr: IPAddr,
+    /// `None` while the address is being resolved.
+    mac_addr: Option<MacAddress>,
+    /// Added by a capsule, so it does not expire.
+    is_static: bool,
+    /// Ticks since the entry was learned, or solicitations sent while it is
+    /// being resolved.
+    count: u16,
+    /// A solicitation is to be sent once the sender is free.
+    solicit: bool,
+}
+
+pub struct NeighborDiscovery<'a, A: Alarm<'a>> {
+    icmp_sender: &'a dyn ICMP6Sender<'a>,
+    alarm: &'a A,
+    buf: TakeCell<'static, [u8]>,
+    interface_list: &'static [IPAddr],
+    mac_addr: Cell<MacAddress>,
+    net_cap: &'static NetworkCapability,
+    neighbors: [Cell<Option<Neighbor>>; NEIGHBOR_CACHE_SIZE],
+    autoconf_addrs: [Cell<Option<IPAddr>>; autoconf::MAX_ADDRESSES],
+    busy: Cell<bool>,
+}
+
+impl<'a, A: Alarm<'a>> NeighborDiscovery<'a, A> {
+    /// `buf` must hold `NDP_BUF_LEN` bytes. `mac_addr` is the link-layer
+    /// address advertised for the addresses in `interface_list`.
+    pub fn new(
+        icmp_sender: &'a dyn ICMP6Sender<'a>,
+        alarm: &'a A,
+        buf: &'static mut [u8],
+        interface_list: &'static [IPAddr],
+        mac_addr: MacAddress,
+        net_cap: &'static NetworkCapability,
+    ) -> NeighborDiscovery<'a, A> {
+        NeighborDiscovery {
+            icmp_sender: icmp_sender,
+            alarm: alarm,
+            buf: TakeCell::new(buf),
+            interface_list: interface_list,
+            mac_addr: Cell::new(mac_addr),
+            net_cap: net_cap,
+            neighbors: [
+                Cell::new(None),
+                Cell::new(None),
+                Cell::new(None),
+                Cell::new(None),
+                Cell::new(None),
+                Cell::new(None),
+                Cell::new(None),
+                Cell::new(None),
+            ],
+            autoconf_addrs: [Cell::new(None), Cell::new(None), Cell::new(None)],
+            busy: Cell::new(false),
+        }
+    }
+
+    /// Sets the link-layer address advertised, after the radio address was
+    /// changed.
+    pub fn set_mac_addr(&self, mac_addr: MacAddress) {
+        self.mac_addr.set(mac_addr);
+    }
+
+    /// Adds an entry that does not expire, replacing any entry for
+    /// `ip_addr`. Returns `ENOMEM` if every entry is static.
+    pub fn add_static(&self, ip_addr: IPAddr, mac_addr: MacAddress) -> ReturnCode {
+        match self.find(ip_addr).or_else(|| self.free_entry()) {
+            Some(i) => {
+                self.neighbors[i].set(Some(Neighbor {
+                    ip_addr: ip_addr,
+                    mac_addr: Some(mac_addr),
+                    is_static: true,
+                    count: 0,
+                    solicit: false,
+                }));
+                ReturnCode::SUCCESS
+            }
+            None => ReturnCode::ENOMEM,
+        }
+    }
+
+    /// Removes the entry for `ip_addr`. Returns `EINVAL` if there is none.
+    pub fn remove(&self, ip_addr: IPAddr) -> ReturnCode {
+        match self.find(ip_addr) {
+            Some(i) => {
+                self.neighbors[i].set(None);
+                ReturnCode::SUCCESS
+            }
+            None => ReturnCode::EINVAL,
+        }
+    }
+
+    /// Removes every entry, static ones included.
+    pub fn flush(&self) {
+        for neighbor in self.neighbors.iter() {
+            neighbor.set(None);
+        }
+    }
+
+    /// Calls `f` with the addresses of each resolved entry.
+    pub fn each<F: FnMut(IPAddr, MacAddress)>(&self, mut f: F) {
+        for neighbor in self.neighbors.iter() {
+            if let Some(Neighbor {
+                ip_addr,
+                mac_addr: Some(mac_addr),
+                ..
+            }) = neighbor.get()
+            {
+                f(ip_addr, mac_addr);
+            }
+        }
+    }
+
+    fn find(&self, ip_addr: IPAddr) -> Option<usize> {
+        self.neighbors.iter().position(|neighbor| {
+            neighbor
+                .get()
+                .map_or(false, |neighbor| neighbor.ip_addr == ip_addr)
+        })
+    }
+
+    /// Returns a free entry, or else the oldest resolved entry that is not
+    /// static.
+    fn free_entry(&self) -> Option<usize> {
+        if let Some(i) = self.neighbors.iter().position(|n| n.get().is_none()) {
+            return Some(i);
+        }
+        let mut oldest: Option<(usize, u16)> = None;
+        for (i, neighbor) in self.neighbors.iter().enumerate() {
+            if let Some(neighbor) = neighbor.get() {
+                if neighbor.is_static || neighbor.mac_addr.is_none() {
+                    continue;
+                }
+                if oldest.map_or(true, |(_, count)| neighbor.count > count) {
+                    oldest = Some((i, neighbor.count));
+                }
+            }
+        }
+        oldest.map(|(i, _)| i)
+    }
+
+    /// Records that `ip_addr` is at `mac_addr`. Static entries are kept, and
+    /// an entry is only added if `create`.
+    fn learn(&self, ip_addr: IPAddr, mac_addr: MacAddress, create: bool) {
+        let index = match self.find(ip_addr) {
+            Some(i) => i,
+            None if create => match self.free_entry() {
+                Some(i) => i,
+                None => return,
+            },
+            None => return,
+        };
+        if self.neighbors[index]
+            .get()
+            .map_or(false, |neighbor| neighbor.is_static)
+        {
+            return;
+        }
+        self.neighbors[index].set(Some(Neighbor {
+            ip_addr: ip_addr,
+            mac_addr: Some(mac_addr),
+            is_static: false,
+            count: 0,
+            solicit: false,
+        }));
+        self.start_timer();
+    }
+
+    fn start_timer(&self) {
+        if !self.alarm.is_armed() {
+            self.alarm
+                .set_alarm(self.alarm.now(), A::ticks_from_ms(TICK_MS));
+        }
+    }
+
+    /// Sends the pending solicitations, until the sender is busy.
+    fn send_solicits(&self) {
+        for neighbor in self.neighbors.iter() {
+            if self.busy.get() {
+                return;
+            }
+            if let Some(mut entry) = neighbor.get() {
+                if !entry.solicit {
+                    continue;
+                }
+                entry.solicit = false;
+                entry.count += 1;
+                neighbor.set(Some(entry));
+
+                let icmp_header = ICMP6Header::new(ICMP6Type::Type135);
+                // Retransmitted on the next tick if this fails
+                let _ = self.send_message(
+                    entry.ip_addr.solicited_node(),
+                    icmp_header,
+                    entry.ip_addr,
+                    nd_options::SOURCE_LL_ADDR,
+                );
+            }
+        }
+    }
+
+    /// Sends a solicitation or advertisement about `target`, with our
+    /// link-layer address in an option of type `option_type`.
+    fn send_message(
+        &self,
+        dest: IPAddr,
+        icmp_header: ICMP6Header,
+        target: IPAddr,
+        option_type: u8,
+    ) -> ReturnCode {
+        if self.busy.get() {
+            return ReturnCode::EBUSY;
+        }
+        let buf = match self.buf.take() {
+            Some(buf) => buf,
+            None => return ReturnCode::EBUSY,
+        };
+        if buf.len() < NDP_BUF_LEN {
+            self.buf.replace(buf);
+            return ReturnCode::ESIZE;
+        }
+        buf[..16].copy_from_slice(&target.0);
+        let len = 16 + self.encode_ll_option(&mut buf[16..], option_type);
+        let mut lease = LeasableBuffer::new(buf);
+        lease.slice(..len);
+
+        // Set before sending, as send_done may be called synchronously
+        self.busy.set(true);
+        let result = self
+            .icmp_sender
+            .send(dest, icmp_header, &lease, self.net_cap);
+        self.buf.replace(lease.take());
+        if result != ReturnCode::SUCCESS {
+            self.busy.set(false);
+        }
+        result
+    }
+
+    /// Writes a link-layer address option with our address, padded to a
+    /// multiple of 8 bytes (RFC 4944, 8), and returns its length.
+    fn encode_ll_option(&self, buf: &mut [u8], option_type: u8) -> usize {
+        let (addr_len, len) = match self.mac_addr.get() {
+            MacAddress::Short(addr) => {
+                buf[2..4].copy_from_slice(&addr.to_be_bytes());
+                (2, 8)
+            }
+            MacAddress::Long(addr) => {
+                buf[2..10].copy_from_slice(&addr);
+                (8, 16)
+            }
+        };
+        buf[0] = option_type;
+        buf[1] = (len / 8) as u8;
+        for byte in buf[2 + addr_len..len].iter_mut() {
+            *byte = 0;
+        }
+        len
+    }
+
+    fn receive_solicitation(&self, src_addr: IPAddr, target: IPAddr, options: &[u8]) {
+        if !self.interface_list.iter().any(|addr| *addr == target)
+            && !self
+                .autoconf_addrs
+                .iter()
+                .any(|addr| addr.get() == Some(target))
+        {
+            return;
+        }
+        let mut flags = na_flags::OVERRIDE;
+        let dest = if src_addr.is_unspecified() {
+            // Sent for duplicate address detection, so answered to all nodes
+            ALL_NODES
+        } else {
+            if let Some(mac_addr) = decode_ll_option(options, nd_options::SOURCE_LL_ADDR) {
+                self.learn(src_addr, mac_addr, true);
+            }
+            flags |= na_flags::SOLICITED;
+            src_addr
+        };
+        let mut icmp_header = ICMP6Header::new(ICMP6Type::Type136);
+        icmp_header.set_options(ICMP6HeaderOptions::Type136 { flags });
+        // The solicitor retransmits if the advertisement is lost
+        let _ = self.send_message(dest, icmp_header, target, nd_options::TARGET_LL_ADDR);
+    }
+
+    fn receive_advertisement(&self, target: IPAddr, flags: u32, options: &[u8]) {
+        let resolving = match self.find(target).and_then(|i| self.neighbors[i].get()) {
+            Some(neighbor) => neighbor.mac_addr.is_none(),
+            // Advertisements that were not asked for are not cached
+            None => return,
+        };
+        if resolving || flags & na_flags::OVERRIDE != 0 {
+            if let Some(mac_addr) = decode_ll_option(options, nd_options::TARGET_LL_ADDR) {
+                self.learn(target, mac_addr, false);
+            }
+        }
+    }
+}
+
+/// Finds the link-layer address option of type `option_type`.
+fn decode_ll_option(options: &[u8], option_type: u8) -> Option<MacAddress> {
+    let mut off = 0;
+    while off + 2 <= options.len() {
+        let len = options[off + 1] as usize * 8;
+        if len == 0 || off + len > options.len() {
+            return None;
+        }
+        if options[off] == option_type {
+            let option = &options[off..off + len];
+            return match len {
+                8 => Some(MacAddress::Short(u16::from_be_bytes([
+                    option[2], option[3],
+                ]))),
+                _ => {
+                    let mut addr = [0; 8];
+                    addr.copy_from_slice(&option[2..10]);
+                    Some(MacAddress::Long(addr))
+                }
+            };
+        }
+        off += len;
+    }
+    None
+}
+
+impl<'a, A: Alarm<'a>> NeighborResolver for NeighborDiscovery<'a, A> {
+    fn resolve(&self, ip_addr: IPAddr) -> Option<MacAddress> {
+        if let Some(i) = self.find(ip_addr) {
+            return self.neighbors[i]
+                .get()
+                .and_then(|neighbor| neighbor.mac_addr);
+        }
+        if let Some(i) = self.free_entry() {
+            self.neighbors[i].set(Some(Neighbor {
+                ip_addr: ip_addr,
+                mac_addr: None,
+                is_static: false,
+                count: 0,
+                solicit: true,
+            }));
+            self.start_timer();
+            self.send_solicits();
+        }
+        None
+    }
+
+    fn lookup(&self, ip_addr: IPAddr) -> Option<MacAddress> {
+        self.find(ip_addr)
+            .and_then(|i| self.neighbors[i].get())
+            .and_then(|neighbor| neighbor.mac_addr)
+    }
+}
+
+impl<'a, A: Alarm<'a>> time::AlarmClient for NeighborDiscovery<'a, A> {
+    fn alarm(&self) {
+        let mut active = false;
+        for neighbor in self.neighbors.iter() {
+            if let Some(mut entry) = neighbor.get() {
+                if entry.is_static {
+                    continue;
+                }
+                let expired = match entry.mac_addr {
+                    Some(_) => {
+                        entry.count += 1;
+                        entry.count >= NEIGHBOR_LIFETIME_TICKS
+                    }
+                    None => {
+                        entry.solicit = true;
+                        entry.count >= MAX_MULTICAST_SOLICIT
+                    }
+                };
+                if expired {
+                    neighbor.set(None);
+                } else {
+                    neighbor.set(Some(entry));
+                    active = true;
+                }
+            }
+        }
+        if active {
+            self.start_timer();
+        }
+        self.send_solicits();
+    }
+}
+
+impl<'a, A: Alarm<'a>> ICMP6RecvClient for NeighborDiscovery<'a, A> {
+    fn receive(&self, ip_header: IP6Header, icmp_header: ICMP6Header, payload: &[u8]) {
+        // Routers decrement the hop limit, so this drops messages from off
+        // the link (RFC 4861, 7.1)
+        if ip_header.get_hop_limit() != 255 || icmp_header.get_code() != 0 || payload.len() < 16 {
+            return;
+        }
+        let mut target = IPAddr::new();
+        target.0.copy_from_slice(&payload[..16]);
+        let options = &payload[16..];
+        match icmp_header.get_options() {
+            ICMP6HeaderOptions::Type135 { .. } => {
+                self.receive_solicitation(ip_header.get_src_addr(), target, options)
+            }
+            ICMP6HeaderOptions::Type136 { flags } => {
+                self.receive_advertisement(target, flags, options)
+            }
+            _ => {}
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> AutoconfClient for NeighborDiscovery<'a, A> {
+    fn address_changed(&self, addr: IPAddr, state: AddressState) {
+        let assigned = match state {
+            AddressState::Preferred | AddressState::Deprecated => true,
+            _ => false,
+        };
+        let slot = self
+            .autoconf_addrs
+            .iter()
+            .find(|slot| slot.get() == Some(addr));
+        match (slot, assigned) {
+            (None, true) => {
+                self.autoconf_addrs
+                    .iter()
+                    .find(|slot| slot.get().is_none())
+                    .map(|slot| slot.set(Some(addr)));
+            }
+            (Some(slot), false) => slot.set(None),
+            _ => {}
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> ICMP6SendClient for NeighborDiscovery<'a, A> {
+    fn send_done(&self, _result: ReturnCode) {
+        self.busy.set(false);
+        self.send_solicits();
+    }
+}
@@ -0,0 +1,345 @@
+//! Raw IPv6 sockets for userspace.
+//!
+//! `RawIP6Driver` lets processes send IPv6 packets with any next header
+//! value, their transport header written by the process, and receive the
+//! packets with the next header values each process asked for. This is
+//! enough to implement RPL, ICMPv6 tools such as ping, or experimental
+//! transports in userspace. The stack fills in the source address, and the
+//! checksum of ICMPv6 messages; everything else is sent as written.
+//!
+//! Unlike the UDP driver, raw sockets can impersonate any protocol and see
+//! every packet of the protocols they filter on, so boards should only let
+//! trusted processes reach this driver, by listing it in the permissions of
+//! their TBF headers or with `Platform::filter_syscall`.
+//!
+//! The driver is an `IP6RecvClient` that passes every packet on to the next
+//! client, usually a `MuxUdpReceiver`, after copying it to the processes
+//! that match. It needs an `IP6Sender` of its own, as an IP sender has one
+//! client.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//! # use capsules::net::ipv6::raw_driver::RawIP6Driver;
+//!
+//! let raw_ip6 = static_init!(
+//!     RawIP6Driver<'static>,
+//!     RawIP6Driver::new(
+//!         raw_ip_send,
+//!         board_kernel.create_grant(&grant_cap),
+//!         LeasableBuffer::new(&mut RAW_IP6_BUF),
+//!         net_cap,
+//!     )
+//! );
+//! raw_ip_send.set_client(raw_ip6);
+//! ip_receive.set_client(raw_ip6);
+//! raw_ip6.set_next_client(udp_recv_mux);
+//! ```
+
+use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::ipv6::ipv6::{IP6Header, RawHeader, TransportHeader};
+use crate::net::ipv6::ipv6_recv::IP6RecvClient;
+use crate::net::ipv6::ipv6_send::{IP6SendClient, IP6Sender};
+use crate::net::network_capabilities::NetworkCapability;
+use core::cell::Cell;
+use kernel::common::cells::{MapCell, OptionalCell};
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
+
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::Ip6Raw as usize;
+
+/// The length of the IPv6 header before the payload of received packets.
+pub const IP6_HEADER_LEN: usize = 40;
+
+#[derive(Default)]
+pub struct App {
+    rx_callback: Option<Callback>,
+    tx_callback: Option<Callback>,
+    rx_buf: Option<AppSlice<Shared, u8>>,
+    tx_buf: Option<AppSlice<Shared, u8>>,
+    tx_cfg: Option<AppSlice<Shared, u8>>,
+    /// One bit for each next header value the process receives.
+    filter: [u32; 8],
+    /// The destination, next header and payload length of the packet to
+    /// send.
+    pending_tx: Option<(IPAddr, u8, usize)>,
+}
+
+impl App {
+    fn filters(&self, next_header: u8) -> bool {
+        self.filter[next_header as usize / 32] & (1 << (next_header % 32)) != 0
+    }
+
+    fn set_filter(&mut self, next_header: u8, on: bool) {
+        let bit = 1 << (next_header % 32);
+        if on {
+            self.filter[next_header as usize / 32] |= bit;
+        } else {
+            self.filter[next_header as usize / 32] &= !bit;
+        }
+    }
+}
+
+pub struct RawIP6Driver<'a> {
+    sender: &'a dyn IP6Sender<'a>,
+    apps: Grant<App>,
+    /// The process whose packet is being sent.
+    current_app: Cell<Option<AppId>>,
+    kernel_buffer: MapCell<LeasableBuffer<'static, u8>>,
+    next_client: OptionalCell<&'a dyn IP6RecvClient>,
+    net_cap: &'static NetworkCapability,
+}
+
+impl<'a> RawIP6Driver<'a> {
+    pub fn new(
+        sender: &'a dyn IP6Sender<'a>,
+        grant: Grant<App>,
+        kernel_buffer: LeasableBuffer<'static, u8>,
+        net_cap: &'static NetworkCapability,
+    ) -> RawIP6Driver<'a> {
+        RawIP6Driver {
+            sender: sender,
+            apps: grant,
+            current_app: Cell::new(None),
+            kernel_buffer: MapCell::new(kernel_buffer),
+            next_client: OptionalCell::empty(),
+            net_cap: net_cap,
+        }
+    }
+
+    /// Sets the client every received packet is passed on to.
+    pub fn set_next_client(&self, client: &'a dyn IP6RecvClient) {
+        self.next_client.set(client);
+    }
+
+    fn do_with_app<F>(&self, appid: AppId, closure: F) -> ReturnCode
+    where
+        F: FnOnce(&mut App) -> ReturnCode,
+    {
+        self.apps
+            .enter(appid, |app, _| closure(app))
+            .unwrap_or_else(|err| err.into())
+    }
+
+    /// Sends the packet of the next process that has one, if no packet is
+    /// being sent. Returns the result of sending the packet of `appid`, and
+    /// tells other processes about failures with their callbacks.
+    fn send_next(&self, appid: Option<AppId>) -> ReturnCode {
+        while self.current_app.get().is_none() {
+            let mut next = None;
+            for app in self.apps.iter() {
+                app.enter(|app, _| {
+                    if next.is_none() && app.pending_tx.is_some() {
+                        next = Some(app.appid());
+                    }
+                });
+            }
+            let next = match next {
+                Some(next) => next,
+                None => break,
+            };
+            let result = self.send(next);
+            if result == ReturnCode::SUCCESS {
+                self.current_app.set(Some(next));
+            } else if Some(next) == appid {
+                return result;
+            } else {
+                let _ = self.apps.enter(next, |app, _| {
+                    app.tx_callback
+                        .map(|mut cb| cb.schedule(result.into(), 0, 0));
+                });
+            }
+        }
+        ReturnCode::SUCCESS
+    }
+
+    fn send(&self, appid: AppId) -> ReturnCode {
+        self.do_with_app(appid, |app| {
+            let (dst, next_header, len) = match app.pending_tx.take() {
+                Some(pending_tx) => pending_tx,
+                None => return ReturnCode::SUCCESS,
+            };
+            let tx_buf = match app.tx_buf.as_ref() {
+                Some(tx_buf) if tx_buf.len() >= len => tx_buf,
+                _ => return ReturnCode::EINVAL,
+            };
+            self.kernel_buffer
+                .take()
+                .map_or(ReturnCode::ENOMEM, |mut kernel_buffer| {
+                    if len > kernel_buffer.len() {
+                        self.kernel_buffer.replace(kernel_buffer);
+                        return ReturnCode::ESIZE;
+                    }
+                    kernel_buffer[..len].copy_from_slice(&tx_buf.as_ref()[..len]);
+                    kernel_buffer.slice(0..len);
+                    let result = self.sender.send_to(
+                        dst,
+                        TransportHeader::Raw(RawHeader::new(next_header)),
+                        &kernel_buffer,
+                        self.net_cap,
+                    );
+                    kernel_buffer.reset();
+                    self.kernel_buffer.replace(kernel_buffer);
+                    result
+                })
+        })
+    }
+}
+
+impl<'a> Driver for RawIP6Driver<'a> {
+    /// ### `allow_num`
+    ///
+    /// - `0`: Receive buffer. Each received packet is written to it, its
+    ///        IPv6 header followed by the payload.
+    /// - `1`: Transmit buffer, with the payload of the packet to send.
+    /// - `2`: Transmit config buffer, with the 16 byte destination address.
+    fn allow(
+        &self,
+        appid: AppId,
+        allow_num: usize,
+        slice: Option<AppSlice<Shared, u8>>,
+    ) -> ReturnCode {
+        match allow_num {
+            0 => self.do_with_app(appid, |app| {
+                app.rx_buf = slice;
+                ReturnCode::SUCCESS
+            }),
+            1 => self.do_with_app(appid, |app| {
+                app.tx_buf = slice;
+                ReturnCode::SUCCESS
+            }),
+            2 => self.do_with_app(appid, |app| {
+                app.tx_cfg = slice;
+                ReturnCode::SUCCESS
+            }),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: A packet was received. The callback gets the length written
+    ///        to the receive buffer, the next header value, and the length
+    ///        of the packet, which is larger if it did not fit.
+    /// - `1`: A packet was sent. The callback gets the result.
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        callback: Option<Callback>,
+        app_id: AppId,
+    ) -> ReturnCode {
+        match subscribe_num {
+            0 => self.do_with_app(app_id, |app| {
+                app.rx_callback = callback;
+                ReturnCode::SUCCESS
+            }),
+            1 => self.do_with_app(app_id, |app| {
+                app.tx_callback = callback;
+                ReturnCode::SUCCESS
+            }),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Receive the packets with the next header value `arg1`.
+    /// - `2`: Stop receiving the packets with the next header value `arg1`.
+    /// - `3`: Send the first `arg2` bytes of the transmit buffer to the
+    ///        address in the transmit config buffer, with the next header
+    ///        value `arg1`. Returns `EBUSY` if the process is already
+    ///        sending a packet, and `EINVAL` if a buffer is missing or too
+    ///        short.
+    /// - `4`: Returns the longest payload the driver sends.
+    fn command(&self, command_num: usize, arg1: usize, arg2: usize, appid: AppId) -> ReturnCode {
+        match command_num {
+            0 => ReturnCode::SUCCESS,
+            1 | 2 => {
+                if arg1 > 0xff {
+                    return ReturnCode::EINVAL;
+                }
+                self.do_with_app(appid, |app| {
+                    app.set_filter(arg1 as u8, command_num == 1);
+                    ReturnCode::SUCCESS
+                })
+            }
+            3 => {
+                if arg1 > 0xff {
+                    return ReturnCode::EINVAL;
+                }
+                let result = self.do_with_app(appid, |app| {
+                    if app.pending_tx.is_some() || self.current_app.get() == Some(appid) {
+                        return ReturnCode::EBUSY;
+                    }
+                    let dst = match app.tx_cfg.as_ref() {
+                        Some(cfg) if cfg.len() >= 16 => {
+                            let mut dst = IPAddr::new();
+                            dst.0.copy_from_slice(&cfg.as_ref()[..16]);
+                            dst
+                        }
+                        _ => return ReturnCode::EINVAL,
+                    };
+                    if app.tx_buf.as_ref().map_or(true, |buf| buf.len() < arg2) {
+                        return ReturnCode::EINVAL;
+                    }
+                    app.pending_tx = Some((dst, arg1 as u8, arg2));
+                    ReturnCode::SUCCESS
+                });
+                if result != ReturnCode::SUCCESS {
+                    return result;
+                }
+                self.send_next(Some(appid))
+            }
+            4 => ReturnCode::SuccessWithValue {
+                value: self.kernel_buffer.map_or(0, |buf| buf.len()),
+            },
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
+
+impl<'a> IP6SendClient for RawIP6Driver<'a> {
+    fn send_done(&self, result: ReturnCode) {
+        if let Some(appid) = self.current_app.take() {
+            let _ = self.apps.enter(appid, |app, _| {
+                app.tx_callback
+                    .map(|mut cb| cb.schedule(result.into(), 0, 0));
+            });
+        }
+        self.send_next(None);
+    }
+}
+
+impl<'a> IP6RecvClient for RawIP6Driver<'a> {
+    fn receive(&self, header: IP6Header, payload: &[u8], timestamp: Option<u32>) {
+        let next_header = header.get_next_header();
+        self.apps.each(|app| {
+            if !app.filters(next_header) {
+                return;
+            }
+            let packet_len = IP6_HEADER_LEN + payload.len();
+            let mut written = 0;
+            if let Some(rx_buf) = app.rx_buf.as_mut() {
+                let rx_buf = rx_buf.as_mut();
+                if let Some((offset, _)) = header.encode(rx_buf).done() {
+                    let len = payload.len().min(rx_buf.len() - offset);
+                    rx_buf[offset..offset + len].copy_from_slice(&payload[..len]);
+                    written = offset + len;
+                }
+            }
+            app.rx_callback
+                .map(|mut cb| cb.schedule(written, next_header as usize, packet_len));
+        });
+        self.next_client
+            .map(|client| client.receive(header, payload, timestamp));
+    }
+
+    fn checksum_failed(&self, header: IP6Header) {
+        self.next_client
+            .map(|client| client.checksum_failed(header));
+    }
+}
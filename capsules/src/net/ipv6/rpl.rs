@@ -0,0 +1,672 @@
+//! A leaf node of the RPL routing protocol (RFC 6550), in storing mode.
+//!
+//! `RplLeaf` joins the first DODAG whose DODAG Information Objects (DIOs) it
+//! hears, soliciting them with DODAG Information Solicitations (DISs) until
+//! it has a parent. Of the neighbors that advertise the DODAG, the one with
+//! the lowest rank is the preferred parent, and it is only replaced by a
+//! neighbor at least `MinHopRankIncrease` better, so that the route does not
+//! flap. The parent becomes the default route: it is set as the gateway of
+//! every IPv6 sender added with `add_route_sender`, such as the
+//! `IP6SendStruct` of the UDP stack, so packets to off-link destinations
+//! reach the DODAG root over several hops.
+//!
+//! In the storing modes of operation, the addresses added with
+//! `add_target`, or assigned by an `AddressAutoconf` the leaf is a client
+//! of, are advertised to the parent in Destination Advertisement Objects
+//! (DAOs), so packets to them are routed down the DODAG. DAOs are sent again
+//! when the parent asks for it by incrementing its DTSN, and each time half
+//! of the route lifetime of the DODAG has passed. A parent that rejects a
+//! DAO, or does not acknowledge it after `MAX_DAO_RETRIES` retransmissions,
+//! is dropped.
+//!
+//! Like neighbor discovery, it has its own MAC user, and sends and receives
+//! through an `ICMP6SendStruct` and an `ICMP6RecvStruct`.
+//!
+//! Limitations
+//! -----------
+//! - As a leaf, it never sends DIOs or forwards packets, and DISs from other
+//!   nodes are ignored.
+//! - Ranks are compared as in the Objective Function Zero (RFC 6552),
+//!   whatever the Objective Code Point of the DODAG; metric containers are
+//!   ignored.
+//! - Data packets are sent without the RPL Option (RFC 6553).
+//! - DODAG versions are compared with serial number arithmetic, not the
+//!   lollipop counters of RFC 6550, 7.2.
+//! - No-Path DAOs are not sent for removed targets, whose routes expire
+//!   with their lifetime.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//! # use capsules::net::ipv6::rpl::RplLeaf;
+//!
+//! let rpl = static_init!(
+//!     RplLeaf<'static, VirtualMuxAlarm<'static, sam4l::ast::Ast>>,
+//!     RplLeaf::new(icmp_send, rpl_alarm, &mut RPL_BUF, net_cap)
+//! );
+//! rpl_alarm.set_alarm_client(rpl);
+//! icmp_send.set_client(rpl);
+//! icmp_recv.set_client(rpl);
+//! rpl.add_route_sender(udp_ip_send);
+//! autoconf.add_client(rpl);
+//! rpl.start();
+//! ```
+
+use crate::net::icmpv6::icmpv6::{ICMP6Header, ICMP6HeaderOptions, ICMP6Type};
+use crate::net::icmpv6::icmpv6_recv::ICMP6RecvClient;
+use crate::net::icmpv6::icmpv6_send::{ICMP6SendClient, ICMP6Sender};
+use crate::net::ipv6::autoconf::{AddressState, AutoconfClient};
+use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::ipv6::ipv6::IP6Header;
+use crate::net::ipv6::ipv6_send::IP6Sender;
+use crate::net::network_capabilities::NetworkCapability;
+use core::cell::Cell;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::hil::time::{self, Alarm};
+use kernel::ReturnCode;
+
+/// The number of candidate parents that are kept.
+pub const MAX_PARENTS: usize = 3;
+
+/// The number of addresses that are advertised.
+pub const MAX_TARGETS: usize = 2;
+
+/// How many senders can be added.
+pub const MAX_ROUTE_SENDERS: usize = 4;
+
+/// The size of the buffer for the messages sent: a DAO with a Target option
+/// for each target and a Transit Information option.
+pub const RPL_BUF_LEN: usize = MAX_TARGETS * options::TARGET_LEN + options::TRANSIT_LEN;
+
+/// Messages are retransmitted, and lifetimes counted, every tick.
+pub const TICK_MS: u32 = 1000;
+
+/// The rank that nodes advertise when they have no route to the root.
+pub const INFINITE_RANK: u16 = 0xffff;
+
+/// Ticks between DISs until a parent is found.
+const DIS_INTERVAL_TICKS: u32 = 10;
+
+/// Ticks before a DAO that was not acknowledged is sent again.
+const DAO_ACK_TIMEOUT_TICKS: u32 = 4;
+
+/// Retransmissions of a DAO before its parent is dropped.
+pub const MAX_DAO_RETRIES: u8 = 3;
+
+/// The defaults of a DODAG Configuration option (RFC 6550, 17).
+const DEFAULT_MIN_HOP_RANK_INCREASE: u16 = 256;
+const DEFAULT_LIFETIME: u8 = 0xff;
+const DEFAULT_LIFETIME_UNIT: u16 = 0xffff;
+
+const ALL_RPL_NODES: IPAddr = IPAddr([0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x1a]);
+
+/// The codes of the RPL control messages, ICMPv6 type 155.
+mod codes {
+    pub const DIS: u8 = 0x00;
+    pub const DIO: u8 = 0x01;
+    pub const DAO: u8 = 0x02;
+    pub const DAO_ACK: u8 = 0x03;
+}
+
+mod options {
+    pub const PAD1: u8 = 0;
+    pub const DODAG_CONFIG: u8 = 4;
+    pub const DODAG_CONFIG_LEN: usize = 16;
+    pub const TARGET: u8 = 5;
+    pub const TARGET_LEN: usize = 20;
+    pub const TRANSIT: u8 = 6;
+    pub const TRANSIT_LEN: usize = 6;
+}
+
+/// The modes of operation with downward routes kept by the parents.
+const MOP_STORING: u8 = 2;
+const MOP_STORING_MULTICAST: u8 = 3;
+
+/// A DAO flag, asking for a DAO-ACK.
+const DAO_ACK_REQUESTED: u8 = 0x80;
+
+/// The DIS base object, its flags and reserved byte, padded to four bytes
+/// with an empty PadN option.
+const DIS_BASE: u32 = 0x0000_0100;
+
+/// The length of the DIO base object after its first four bytes.
+const DIO_BASE_LEN: usize = 20;
+
+#[derive(Copy, Clone)]
+struct Dodag {
+    instance_id: u8,
+    dodag_id: IPAddr,
+    version: u8,
+    mop: u8,
+    min_hop_rank_increase: u16,
+    default_lifetime: u8,
+    lifetime_unit: u16,
+}
+
+impl Dodag {
+    fn has_downward_routes(&self) -> bool {
+        self.mop == MOP_STORING || self.mop == MOP_STORING_MULTICAST
+    }
+
+    /// Ticks between DAOs, half of the route lifetime, or `None` if routes
+    /// never expire.
+    fn dao_refresh_ticks(&self) -> Option<u32> {
+        if self.default_lifetime == 0xff {
+            return None;
+        }
+        let lifetime_s = self.default_lifetime as u32 * self.lifetime_unit as u32;
+        Some(((lifetime_s / 2).saturating_mul(1000) / TICK_MS).max(1))
+    }
+}
+
+#[derive(Copy, Clone)]
+struct Parent {
+    addr: IPAddr,
+    rank: u16,
+    dtsn: u8,
+}
+
+pub struct RplLeaf<'a, A: Alarm<'a>> {
+    icmp_sender: &'a dyn ICMP6Sender<'a>,
+    alarm: &'a A,
+    buf: TakeCell<'static, [u8]>,
+    net_cap: &'static NetworkCapability,
+    dodag: Cell<Option<Dodag>>,
+    parents: [Cell<Option<Parent>>; MAX_PARENTS],
+    preferred: Cell<Option<IPAddr>>,
+    targets: [Cell<Option<IPAddr>>; MAX_TARGETS],
+    route_senders: [OptionalCell<&'a dyn IP6Sender<'a>>; MAX_ROUTE_SENDERS],
+    dao_seq: Cell<u8>,
+    /// Ticks until a DAO is retransmitted, if it was not acknowledged, or
+    /// refreshed; 0 if neither is due.
+    dao_ticks: Cell<u32>,
+    dao_retries: Cell<u8>,
+    dao_awaiting_ack: Cell<bool>,
+    dao_pending: Cell<bool>,
+    dis_ticks: Cell<u32>,
+    dis_pending: Cell<bool>,
+    busy: Cell<bool>,
+}
+
+impl<'a, A: Alarm<'a>> RplLeaf<'a, A> {
+    /// `buf` must hold `RPL_BUF_LEN` bytes.
+    pub fn new(
+        icmp_sender: &'a dyn ICMP6Sender<'a>,
+        alarm: &'a A,
+        buf: &'static mut [u8],
+        net_cap: &'static NetworkCapability,
+    ) -> RplLeaf<'a, A> {
+        RplLeaf {
+            icmp_sender: icmp_sender,
+            alarm: alarm,
+            buf: TakeCell::new(buf),
+            net_cap: net_cap,
+            dodag: Cell::new(None),
+            parents: [Cell::new(None), Cell::new(None), Cell::new(None)],
+            preferred: Cell::new(None),
+            targets: [Cell::new(None), Cell::new(None)],
+            route_senders: [
+                OptionalCell::empty(),
+                OptionalCell::empty(),
+                OptionalCell::empty(),
+                OptionalCell::empty(),
+            ],
+            dao_seq: Cell::new(0),
+            dao_ticks: Cell::new(0),
+            dao_retries: Cell::new(0),
+            dao_awaiting_ack: Cell::new(false),
+            dao_pending: Cell::new(false),
+            dis_ticks: Cell::new(0),
+            dis_pending: Cell::new(false),
+            busy: Cell::new(false),
+        }
+    }
+
+    /// Sets the gateway of `sender` to the preferred parent whenever it
+    /// changes. Returns `ENOMEM` once `MAX_ROUTE_SENDERS` were added.
+    pub fn add_route_sender(&self, sender: &'a dyn IP6Sender<'a>) -> ReturnCode {
+        self.route_senders
+            .iter()
+            .find(|slot| slot.is_none())
+            .map_or(ReturnCode::ENOMEM, |slot| {
+                slot.set(sender);
+                ReturnCode::SUCCESS
+            })
+    }
+
+    /// Advertises `addr` to the parents. Returns `ENOMEM` if `MAX_TARGETS`
+    /// addresses are advertised.
+    pub fn add_target(&self, addr: IPAddr) -> ReturnCode {
+        if self.targets.iter().any(|target| target.get() == Some(addr)) {
+            return ReturnCode::SUCCESS;
+        }
+        match self.targets.iter().find(|target| target.get().is_none()) {
+            Some(slot) => {
+                slot.set(Some(addr));
+                self.schedule_dao();
+                ReturnCode::SUCCESS
+            }
+            None => ReturnCode::ENOMEM,
+        }
+    }
+
+    /// Stops advertising `addr`.
+    pub fn remove_target(&self, addr: IPAddr) {
+        for target in self.targets.iter() {
+            if target.get() == Some(addr) {
+                target.set(None);
+            }
+        }
+    }
+
+    /// Forgets the DODAG and its parents, and looks for a DODAG again.
+    pub fn start(&self) {
+        self.dodag.set(None);
+        for parent in self.parents.iter() {
+            parent.set(None);
+        }
+        self.preferred.set(None);
+        self.dao_ticks.set(0);
+        self.dao_awaiting_ack.set(false);
+        self.dao_pending.set(false);
+        self.dis_ticks.set(0);
+        self.dis_pending.set(true);
+        self.start_timer();
+        self.send_pending();
+    }
+
+    /// The preferred parent, the default route.
+    pub fn get_parent(&self) -> Option<IPAddr> {
+        self.preferred.get()
+    }
+
+    /// The root of the DODAG that was joined.
+    pub fn get_dodag_id(&self) -> Option<IPAddr> {
+        self.dodag.get().map(|dodag| dodag.dodag_id)
+    }
+
+    fn start_timer(&self) {
+        if !self.alarm.is_armed() {
+            self.alarm
+                .set_alarm(self.alarm.now(), A::ticks_from_ms(TICK_MS));
+        }
+    }
+
+    /// Sends a new DAO to the preferred parent, if routes are stored and
+    /// there is something to advertise.
+    fn schedule_dao(&self) {
+        let stores_routes = self
+            .dodag
+            .get()
+            .map_or(false, |dodag| dodag.has_downward_routes());
+        let has_targets = self.targets.iter().any(|target| target.get().is_some());
+        if !stores_routes || !has_targets || self.preferred.get().is_none() {
+            return;
+        }
+        self.dao_seq.set(self.dao_seq.get().wrapping_add(1));
+        self.dao_retries.set(0);
+        self.dao_awaiting_ack.set(false);
+        self.dao_pending.set(true);
+        self.send_pending();
+    }
+
+    /// Sends the pending messages, until the sender is busy.
+    fn send_pending(&self) {
+        if self.busy.get() {
+            return;
+        }
+        if self.dao_pending.get() {
+            self.dao_pending.set(false);
+            if let (Some(dodag), Some(parent)) = (self.dodag.get(), self.preferred.get()) {
+                self.send_dao(dodag, parent);
+            }
+        }
+        if !self.busy.get() && self.dis_pending.get() && self.preferred.get().is_none() {
+            self.dis_pending.set(false);
+            let _ = self.send_message(ALL_RPL_NODES, codes::DIS, DIS_BASE, |_| 0);
+        }
+    }
+
+    fn send_dao(&self, dodag: Dodag, parent: IPAddr) {
+        let base = (dodag.instance_id as u32) << 24
+            | (DAO_ACK_REQUESTED as u32) << 16
+            | self.dao_seq.get() as u32;
+        let path_seq = self.dao_seq.get();
+        let _ = self.send_message(parent, codes::DAO, base, |buf| {
+            let mut off = 0;
+            for target in self.targets.iter().filter_map(|target| target.get()) {
+                buf[off] = options::TARGET;
+                buf[off + 1] = (options::TARGET_LEN - 2) as u8;
+                buf[off + 2] = 0;
+                buf[off + 3] = 128;
+                buf[off + 4..off + options::TARGET_LEN].copy_from_slice(&target.0);
+                off += options::TARGET_LEN;
+            }
+            buf[off..off + options::TRANSIT_LEN].copy_from_slice(&[
+                options::TRANSIT,
+                (options::TRANSIT_LEN - 2) as u8,
+                0,
+                0,
+                path_seq,
+                dodag.default_lifetime,
+            ]);
+            off + options::TRANSIT_LEN
+        });
+        // A DAO that failed to send is retransmitted like a lost one
+        self.dao_awaiting_ack.set(true);
+        self.dao_ticks.set(DAO_ACK_TIMEOUT_TICKS);
+        self.start_timer();
+    }
+
+    /// Sends a message whose body `fill` writes to the buffer, returning
+    /// its length.
+    fn send_message<F>(&self, dest: IPAddr, code: u8, base: u32, fill: F) -> ReturnCode
+    where
+        F: FnOnce(&mut [u8]) -> usize,
+    {
+        if self.busy.get() {
+            return ReturnCode::EBUSY;
+        }
+        let buf = match self.buf.take() {
+            Some(buf) => buf,
+            None => return ReturnCode::EBUSY,
+        };
+        if buf.len() < RPL_BUF_LEN {
+            self.buf.replace(buf);
+            return ReturnCode::ESIZE;
+        }
+        let len = fill(buf);
+        let mut lease = LeasableBuffer::new(buf);
+        lease.slice(..len);
+
+        let mut icmp_header = ICMP6Header::new(ICMP6Type::Type155);
+        icmp_header.set_code(code);
+        icmp_header.set_options(ICMP6HeaderOptions::Type155 { base: base });
+
+        // Set before sending, as send_done may be called synchronously
+        self.busy.set(true);
+        let result = self
+            .icmp_sender
+            .send(dest, icmp_header, &lease, self.net_cap);
+        self.buf.replace(lease.take());
+        if result != ReturnCode::SUCCESS {
+            self.busy.set(false);
+        }
+        result
+    }
+
+    /// Sets the gateway of the route senders to `parent`, whose link-layer
+    /// address is its interface identifier.
+    fn install_route(&self, parent: IPAddr) {
+        let mac_addr = parent.mac_from_iid();
+        for sender in self.route_senders.iter() {
+            sender.map(|sender| sender.set_gateway(mac_addr));
+        }
+    }
+
+    /// Chooses the preferred parent among the candidates.
+    fn select_parent(&self) {
+        let min_hop_rank_increase = self
+            .dodag
+            .get()
+            .map_or(DEFAULT_MIN_HOP_RANK_INCREASE, |dodag| {
+                dodag.min_hop_rank_increase
+            });
+        let best = self
+            .parents
+            .iter()
+            .filter_map(|parent| parent.get())
+            .min_by_key(|parent| parent.rank);
+        let current = self.preferred.get().and_then(|addr| {
+            self.parents
+                .iter()
+                .filter_map(|parent| parent.get())
+                .find(|parent| parent.addr == addr)
+        });
+        let selected = match (current, best) {
+            (Some(current), Some(best))
+                if best.rank.saturating_add(min_hop_rank_increase) > current.rank =>
+            {
+                Some(current)
+            }
+            (_, best) => best,
+        };
+        let selected = selected.map(|parent| parent.addr);
+        if selected == self.preferred.get() {
+            return;
+        }
+        self.preferred.set(selected);
+        match selected {
+            Some(parent) => {
+                self.install_route(parent);
+                self.schedule_dao();
+            }
+            None => {
+                self.dao_ticks.set(0);
+                self.dao_awaiting_ack.set(false);
+                self.dao_pending.set(false);
+                self.dis_ticks.set(0);
+                self.dis_pending.set(true);
+                self.start_timer();
+                self.send_pending();
+            }
+        }
+    }
+
+    fn remove_parent(&self, addr: IPAddr) {
+        for parent in self.parents.iter() {
+            if parent.get().map_or(false, |parent| parent.addr == addr) {
+                parent.set(None);
+            }
+        }
+    }
+
+    /// Reads the DODAG Configuration option from the options of a DIO.
+    fn parse_config(dodag: &mut Dodag, mut options: &[u8]) {
+        while !options.is_empty() {
+            if options[0] == options::PAD1 {
+                options = &options[1..];
+                continue;
+            }
+            if options.len() < 2 || options.len() < 2 + options[1] as usize {
+                return;
+            }
+            let len = 2 + options[1] as usize;
+            if options[0] == options::DODAG_CONFIG && len == options::DODAG_CONFIG_LEN {
+                dodag.min_hop_rank_increase = u16::from_be_bytes([options[8], options[9]]);
+                dodag.default_lifetime = options[13];
+                dodag.lifetime_unit = u16::from_be_bytes([options[14], options[15]]);
+            }
+            options = &options[len..];
+        }
+    }
+
+    fn receive_dio(&self, src: IPAddr, base: u32, body: &[u8]) {
+        if body.len() < DIO_BASE_LEN || !src.is_unicast_link_local() {
+            return;
+        }
+        let instance_id = (base >> 24) as u8;
+        let version = (base >> 16) as u8;
+        let rank = base as u16;
+        let mut dodag_id = IPAddr::new();
+        dodag_id.0.copy_from_slice(&body[4..20]);
+        let mut dio_dodag = Dodag {
+            instance_id: instance_id,
+            dodag_id: dodag_id,
+            version: version,
+            mop: (body[0] >> 3) & 0x07,
+            min_hop_rank_increase: DEFAULT_MIN_HOP_RANK_INCREASE,
+            default_lifetime: DEFAULT_LIFETIME,
+            lifetime_unit: DEFAULT_LIFETIME_UNIT,
+        };
+        Self::parse_config(&mut dio_dodag, &body[DIO_BASE_LEN..]);
+
+        let joined = match self.dodag.get() {
+            Some(dodag) if dodag.instance_id == instance_id && dodag.dodag_id == dodag_id => {
+                let newer = (version.wrapping_sub(dodag.version) as i8) > 0;
+                if newer {
+                    // A global repair: the parents of the old version are
+                    // stale, and the routes have to be advertised again
+                    for parent in self.parents.iter() {
+                        parent.set(None);
+                    }
+                    self.preferred.set(None);
+                } else if version != dodag.version {
+                    return;
+                }
+                true
+            }
+            // A node that lost its parents joins any DODAG
+            Some(_) if self.preferred.get().is_some() => return,
+            _ => false,
+        };
+        if rank == INFINITE_RANK {
+            if joined {
+                self.remove_parent(src);
+                self.select_parent();
+            }
+            return;
+        }
+        self.dodag.set(Some(dio_dodag));
+        if !joined {
+            for parent in self.parents.iter() {
+                parent.set(None);
+            }
+        }
+
+        let dtsn = body[1];
+        let candidate = Parent {
+            addr: src,
+            rank: rank,
+            dtsn: dtsn,
+        };
+        let existing = self
+            .parents
+            .iter()
+            .find(|parent| parent.get().map_or(false, |parent| parent.addr == src));
+        match existing {
+            Some(slot) => {
+                let old_dtsn = slot.get().map_or(dtsn, |parent| parent.dtsn);
+                slot.set(Some(candidate));
+                if old_dtsn != dtsn && self.preferred.get() == Some(src) {
+                    self.schedule_dao();
+                }
+            }
+            None => {
+                let slot = self
+                    .parents
+                    .iter()
+                    .find(|parent| parent.get().is_none())
+                    .or_else(|| {
+                        self.parents
+                            .iter()
+                            .filter(|parent| parent.get().map(|p| p.addr) != self.preferred.get())
+                            .max_by_key(|parent| parent.get().map_or(0, |parent| parent.rank))
+                    });
+                if let Some(slot) = slot {
+                    if slot.get().map_or(true, |parent| parent.rank > rank) {
+                        slot.set(Some(candidate));
+                    }
+                }
+            }
+        }
+        self.select_parent();
+    }
+
+    fn receive_dao_ack(&self, base: u32) {
+        let instance_id = (base >> 24) as u8;
+        let seq = (base >> 8) as u8;
+        let status = base as u8;
+        let matches = self
+            .dodag
+            .get()
+            .map_or(false, |dodag| dodag.instance_id == instance_id);
+        if !matches || !self.dao_awaiting_ack.get() || seq != self.dao_seq.get() {
+            return;
+        }
+        self.dao_awaiting_ack.set(false);
+        // Statuses of 128 and above are rejections
+        if status < 128 {
+            let refresh = self.dodag.get().and_then(|dodag| dodag.dao_refresh_ticks());
+            self.dao_ticks.set(refresh.unwrap_or(0));
+        } else if let Some(parent) = self.preferred.get() {
+            self.dao_ticks.set(0);
+            self.remove_parent(parent);
+            self.select_parent();
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> time::AlarmClient for RplLeaf<'a, A> {
+    fn alarm(&self) {
+        if self.preferred.get().is_none() {
+            let ticks = self.dis_ticks.get() + 1;
+            if ticks >= DIS_INTERVAL_TICKS {
+                self.dis_ticks.set(0);
+                self.dis_pending.set(true);
+            } else {
+                self.dis_ticks.set(ticks);
+            }
+        }
+
+        let dao_ticks = self.dao_ticks.get();
+        if dao_ticks == 1 {
+            self.dao_ticks.set(0);
+            if !self.dao_awaiting_ack.get() {
+                self.schedule_dao();
+            } else if self.dao_retries.get() < MAX_DAO_RETRIES {
+                self.dao_retries.set(self.dao_retries.get() + 1);
+                self.dao_pending.set(true);
+            } else if let Some(parent) = self.preferred.get() {
+                self.dao_awaiting_ack.set(false);
+                self.remove_parent(parent);
+                self.select_parent();
+            }
+        } else if dao_ticks > 1 {
+            self.dao_ticks.set(dao_ticks - 1);
+        }
+
+        if self.preferred.get().is_none() || self.dao_ticks.get() > 0 || self.dao_pending.get() {
+            self.start_timer();
+        }
+        self.send_pending();
+    }
+}
+
+impl<'a, A: Alarm<'a>> ICMP6RecvClient for RplLeaf<'a, A> {
+    fn receive(&self, ip_header: IP6Header, icmp_header: ICMP6Header, payload: &[u8]) {
+        if let ICMP6HeaderOptions::Type155 { base } = icmp_header.get_options() {
+            match icmp_header.get_code() {
+                codes::DIO => self.receive_dio(ip_header.get_src_addr(), base, payload),
+                codes::DAO_ACK => self.receive_dao_ack(base),
+                _ => {}
+            }
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> ICMP6SendClient for RplLeaf<'a, A> {
+    fn send_done(&self, _result: ReturnCode) {
+        self.busy.set(false);
+        self.send_pending();
+    }
+}
+
+impl<'a, A: Alarm<'a>> AutoconfClient for RplLeaf<'a, A> {
+    /// Advertises the addresses that are not link-local.
+    fn address_changed(&self, addr: IPAddr, state: AddressState) {
+        if addr.is_unicast_link_local() {
+            return;
+        }
+        match state {
+            AddressState::Preferred => {
+                let _ = self.add_target(addr);
+            }
+            AddressState::Duplicate | AddressState::Invalid => self.remove_target(addr),
+            _ => {}
+        }
+    }
+}
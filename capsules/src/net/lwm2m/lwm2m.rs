@@ -0,0 +1,463 @@
+//! The object model of LwM2M (OMA Lightweight M2M 1.1) and the encoding of
+//! its values.
+//!
+//! A device exposes objects, such as the IPSO Temperature object 3303, whose
+//! instances each hold resources, such as the Sensor Value 5700 of a
+//! temperature. A resource is addressed by the path of these IDs, as in
+//! "/3303/0/5700". Capsules expose an object to the `LwM2MClient` by
+//! implementing `LwM2MObject`, and tell the client when the value of a
+//! resource changes through `LwM2MObjectClient`, so that it can notify the
+//! servers observing the resource.
+//!
+//! Objects, instances and resources are read as SenML CBOR packs, with a
+//! record for each resource, and a single resource can also be read as plain
+//! text or as a CBOR item. Values are written in any of these formats.
+//! Resources with multiple instances are not supported.
+
+use crate::net::cbor::{self, Item};
+use crate::net::coap::coap::coap_content_formats;
+use core::fmt::{self, Write};
+use kernel::ReturnCode;
+
+/// IDs of the objects the stack knows of.
+pub mod object_ids {
+    pub const SECURITY: u16 = 0;
+    pub const SERVER: u16 = 1;
+    pub const DEVICE: u16 = 3;
+    pub const TEMPERATURE: u16 = 3303;
+}
+
+/// The keys of the SenML fields (RFC 8428, 6) that LwM2M uses.
+mod senml_keys {
+    pub const BASE_NAME: i64 = -2;
+    pub const NAME: i64 = 0;
+    pub const VALUE: i64 = 2;
+    pub const STRING_VALUE: i64 = 3;
+    pub const BOOLEAN_VALUE: i64 = 4;
+    pub const DATA_VALUE: i64 = 8;
+}
+
+/// The value of a resource.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Value<'a> {
+    Integer(i64),
+    Float(f64),
+    Boolean(bool),
+    String(&'a str),
+    Opaque(&'a [u8]),
+}
+
+impl Value<'_> {
+    /// The value as an integer, parsing a string that was written as plain
+    /// text.
+    pub fn as_integer(&self) -> Option<i64> {
+        match *self {
+            Value::Integer(value) => Some(value),
+            Value::String(text) => text.parse().ok(),
+            _ => None,
+        }
+    }
+
+    /// The value as a float, parsing a string that was written as plain
+    /// text.
+    pub fn as_float(&self) -> Option<f64> {
+        match *self {
+            Value::Float(value) => Some(value),
+            Value::Integer(value) => Some(value as f64),
+            Value::String(text) => text.parse().ok(),
+            _ => None,
+        }
+    }
+
+    /// The value as a boolean, which is written as "0" or "1" in plain text.
+    pub fn as_bool(&self) -> Option<bool> {
+        match *self {
+            Value::Boolean(value) => Some(value),
+            Value::String("0") => Some(false),
+            Value::String("1") => Some(true),
+            _ => None,
+        }
+    }
+}
+
+/// The path of an object, an object instance or a resource, or the root
+/// path, which holds every object.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Path {
+    ids: [u16; 3],
+    depth: usize,
+}
+
+impl Path {
+    pub const ROOT: Path = Path {
+        ids: [0; 3],
+        depth: 0,
+    };
+
+    pub fn object(object: u16) -> Path {
+        Path {
+            ids: [object, 0, 0],
+            depth: 1,
+        }
+    }
+
+    pub fn instance(object: u16, instance: u16) -> Path {
+        Path {
+            ids: [object, instance, 0],
+            depth: 2,
+        }
+    }
+
+    pub fn resource(object: u16, instance: u16, resource: u16) -> Path {
+        Path {
+            ids: [object, instance, resource],
+            depth: 3,
+        }
+    }
+
+    /// Parses the segments of a path, such as the Uri-Path options of a
+    /// request. Returns `None` if a segment is not an ID, or there are more
+    /// than three.
+    pub fn from_segments<'b, I: Iterator<Item = &'b [u8]>>(segments: I) -> Option<Path> {
+        let mut path = Path::ROOT;
+        for segment in segments {
+            if path.depth == path.ids.len() {
+                return None;
+            }
+            path.ids[path.depth] = core::str::from_utf8(segment).ok()?.parse().ok()?;
+            path.depth += 1;
+        }
+        Some(path)
+    }
+
+    /// Parses a path written as text, such as "/3303/0/5700".
+    pub fn parse(text: &str) -> Option<Path> {
+        Path::from_segments(text.split('/').filter(|s| !s.is_empty()).map(str::as_bytes))
+    }
+
+    pub fn object_id(&self) -> Option<u16> {
+        self.id(0)
+    }
+
+    pub fn instance_id(&self) -> Option<u16> {
+        self.id(1)
+    }
+
+    pub fn resource_id(&self) -> Option<u16> {
+        self.id(2)
+    }
+
+    fn id(&self, level: usize) -> Option<u16> {
+        if level < self.depth {
+            Some(self.ids[level])
+        } else {
+            None
+        }
+    }
+
+    pub fn is_resource(&self) -> bool {
+        self.depth == 3
+    }
+
+    /// Returns whether `other` is this path or below it.
+    pub fn contains(&self, other: &Path) -> bool {
+        self.depth <= other.depth && self.ids[..self.depth] == other.ids[..self.depth]
+    }
+}
+
+impl fmt::Display for Path {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.depth == 0 {
+            return f.write_str("/");
+        }
+        for id in &self.ids[..self.depth] {
+            write!(f, "/{}", id)?;
+        }
+        Ok(())
+    }
+}
+
+/// Implemented by capsules that expose an object.
+///
+/// For errors, `ENODEVICE` means there is no such instance or resource,
+/// `ENOSUPPORT` that the resource does not allow the operation, `EINVAL`
+/// that the value is not valid for the resource, and `EBUSY` that the value
+/// is not available yet.
+pub trait LwM2MObject {
+    /// The ID of the object, such as 3303 for a temperature.
+    fn object_id(&self) -> u16;
+
+    /// Calls `f` with the ID of each instance.
+    fn instances(&self, f: &mut dyn FnMut(u16));
+
+    /// Calls `f` with the ID of each resource of `instance`.
+    fn resources(&self, instance: u16, f: &mut dyn FnMut(u16));
+
+    /// Returns the value of a resource.
+    fn read(&self, instance: u16, resource: u16) -> Result<Value<'static>, ReturnCode>;
+
+    /// Sets the value of a resource. The default allows no writes.
+    fn write(&self, _instance: u16, _resource: u16, _value: Value) -> ReturnCode {
+        ReturnCode::ENOSUPPORT
+    }
+
+    /// Executes a resource with the arguments `arguments`. The default
+    /// allows no resource to be executed.
+    fn execute(&self, _instance: u16, _resource: u16, _arguments: &[u8]) -> ReturnCode {
+        ReturnCode::ENOSUPPORT
+    }
+}
+
+/// Implemented by the client that objects are exposed to.
+pub trait LwM2MObjectClient {
+    /// The value of a resource changed.
+    fn value_changed(&self, object_id: u16, instance: u16, resource: u16);
+}
+
+/// Calls `f` with the instance and resource IDs of each resource of `object`
+/// under `path`.
+pub fn each_resource(object: &dyn LwM2MObject, path: &Path, f: &mut dyn FnMut(u16, u16)) {
+    object.instances(&mut |instance| {
+        if path.instance_id().map_or(true, |id| id == instance) {
+            object.resources(instance, &mut |resource| {
+                if path.resource_id().map_or(true, |id| id == resource) {
+                    f(instance, resource);
+                }
+            });
+        }
+    });
+}
+
+/// Returns whether `object` has the instance or resource of `path`.
+pub fn exists(object: &dyn LwM2MObject, path: &Path) -> bool {
+    let mut found = false;
+    if path.instance_id().is_none() {
+        return true;
+    }
+    object.instances(&mut |instance| {
+        if Some(instance) == path.instance_id() {
+            match path.resource_id() {
+                Some(id) => object.resources(instance, &mut |resource| found |= resource == id),
+                None => found = true,
+            }
+        }
+    });
+    found
+}
+
+/// Lets `write!` format text into a buffer.
+pub struct WriteAdapter<'b> {
+    buffer: &'b mut [u8],
+    used: usize,
+}
+
+impl<'b> WriteAdapter<'b> {
+    pub fn new(buffer: &'b mut [u8]) -> WriteAdapter<'b> {
+        WriteAdapter {
+            buffer: buffer,
+            used: 0,
+        }
+    }
+
+    /// The length of the text written so far.
+    pub fn used(&self) -> usize {
+        self.used
+    }
+}
+
+impl Write for WriteAdapter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.used + s.len();
+        if end > self.buffer.len() {
+            return Err(fmt::Error);
+        }
+        self.buffer[self.used..end].copy_from_slice(s.as_bytes());
+        self.used = end;
+        Ok(())
+    }
+}
+
+/// Encodes `value` into `buf` as plain text or as a CBOR item, and returns
+/// its length. Returns `None` if it does not fit, or is opaque and asked for
+/// in plain text.
+pub fn encode_value(buf: &mut [u8], format: u16, value: &Value) -> Option<usize> {
+    if format == coap_content_formats::TEXT_PLAIN {
+        let mut writer = WriteAdapter::new(buf);
+        match *value {
+            Value::Integer(value) => write!(writer, "{}", value).ok()?,
+            Value::Float(value) => write!(writer, "{}", value).ok()?,
+            Value::Boolean(value) => writer.write_str(if value { "1" } else { "0" }).ok()?,
+            Value::String(text) => writer.write_str(text).ok()?,
+            Value::Opaque(_) => return None,
+        }
+        Some(writer.used())
+    } else {
+        encode_cbor_value(buf, value).done().map(|(len, _)| len)
+    }
+}
+
+fn encode_cbor_value(buf: &mut [u8], value: &Value) -> crate::net::stream::SResult {
+    match *value {
+        Value::Integer(value) => cbor::encode_int(buf, value),
+        Value::Float(value) => cbor::encode_f64(buf, value),
+        Value::Boolean(value) => cbor::encode_bool(buf, value),
+        Value::String(text) => cbor::encode_text(buf, text),
+        Value::Opaque(bytes) => cbor::encode_bytes(buf, bytes),
+    }
+}
+
+/// Encodes the resources of `object` under `path` into `buf` as a SenML
+/// CBOR pack, with a record named by the path of each resource that can be
+/// read, and returns its length.
+pub fn encode_senml(buf: &mut [u8], object: &dyn LwM2MObject, path: &Path) -> Option<usize> {
+    let mut records = 0;
+    each_resource(object, path, &mut |instance, resource| {
+        if object.read(instance, resource).is_ok() {
+            records += 1;
+        }
+    });
+    let mut off = cbor::encode_array(buf, records).done()?.0;
+    let mut written = 0;
+    let mut fits = true;
+    each_resource(object, path, &mut |instance, resource| {
+        let value = match object.read(instance, resource) {
+            Ok(value) => value,
+            Err(_) => return,
+        };
+        match encode_record(
+            &mut buf[off..],
+            &Path::resource(object.object_id(), instance, resource),
+            &value,
+        ) {
+            Some(len) if fits => {
+                off += len;
+                written += 1;
+            }
+            _ => fits = false,
+        }
+    });
+    // A value that could no longer be read leaves the pack short.
+    if fits && written == records {
+        Some(off)
+    } else {
+        None
+    }
+}
+
+/// Encodes a SenML record with the name `path` and `value`.
+fn encode_record(buf: &mut [u8], path: &Path, value: &Value) -> Option<usize> {
+    let mut name = [0; 20];
+    let mut writer = WriteAdapter::new(&mut name);
+    write!(writer, "{}", path).ok()?;
+    let name_len = writer.used();
+    let name = core::str::from_utf8(&name[..name_len]).ok()?;
+
+    let value_key = match *value {
+        Value::Integer(_) | Value::Float(_) => senml_keys::VALUE,
+        Value::String(_) => senml_keys::STRING_VALUE,
+        Value::Boolean(_) => senml_keys::BOOLEAN_VALUE,
+        Value::Opaque(_) => senml_keys::DATA_VALUE,
+    };
+    let mut off = cbor::encode_map(buf, 2).done()?.0;
+    off += cbor::encode_int(&mut buf[off..], senml_keys::NAME)
+        .done()?
+        .0;
+    off += cbor::encode_text(&mut buf[off..], name).done()?.0;
+    off += cbor::encode_int(&mut buf[off..], value_key).done()?.0;
+    off += encode_cbor_value(&mut buf[off..], value).done()?.0;
+    Some(off)
+}
+
+/// Decodes the value of a single resource written as plain text or as a
+/// CBOR item. A value in plain text is decoded as a string, which the
+/// `as_` methods of `Value` parse.
+pub fn decode_value(payload: &[u8], format: u16) -> Option<Value> {
+    match format {
+        coap_content_formats::TEXT_PLAIN => core::str::from_utf8(payload).ok().map(Value::String),
+        coap_content_formats::CBOR => cbor_value(cbor::decode_item(payload).done()?.1),
+        _ => None,
+    }
+}
+
+fn cbor_value(item: Item) -> Option<Value> {
+    match item {
+        Item::Unsigned(_) | Item::Negative(_) => item.as_i64().map(Value::Integer),
+        Item::Float(value) => Some(Value::Float(value)),
+        Item::Bool(value) => Some(Value::Boolean(value)),
+        Item::Text(text) => Some(Value::String(text)),
+        Item::Bytes(bytes) => Some(Value::Opaque(bytes)),
+        _ => None,
+    }
+}
+
+/// Calls `f` with the path and value of each record of the SenML CBOR pack
+/// `payload`, until it returns an error. Returns the error, or `EINVAL` if
+/// the pack is malformed.
+pub fn decode_senml<F: FnMut(Path, Value) -> ReturnCode>(payload: &[u8], mut f: F) -> ReturnCode {
+    let (mut off, records) = match cbor::decode_item(payload).done() {
+        Some((off, Item::Array(records))) => (off, records),
+        _ => return ReturnCode::EINVAL,
+    };
+    let mut base_name = "";
+    for _ in 0..records {
+        let (len, path, value) = match decode_record(&payload[off..], &mut base_name) {
+            Some(record) => record,
+            None => return ReturnCode::EINVAL,
+        };
+        off += len;
+        let rcode = f(path, value);
+        if rcode != ReturnCode::SUCCESS {
+            return rcode;
+        }
+    }
+    ReturnCode::SUCCESS
+}
+
+/// Decodes a SenML record, whose name is appended to the base name of the
+/// records before it unless it sets a base name of its own.
+fn decode_record<'b>(buf: &'b [u8], base_name: &mut &'b str) -> Option<(usize, Path, Value<'b>)> {
+    let (mut off, fields) = match cbor::decode_item(buf).done()? {
+        (off, Item::Map(fields)) => (off, fields),
+        _ => return None,
+    };
+    let mut name = "";
+    let mut value = None;
+    for _ in 0..fields {
+        let (len, key) = cbor::decode_int(&buf[off..]).done()?;
+        off += len;
+        let field = match key {
+            senml_keys::BASE_NAME
+            | senml_keys::NAME
+            | senml_keys::VALUE
+            | senml_keys::STRING_VALUE
+            | senml_keys::BOOLEAN_VALUE
+            | senml_keys::DATA_VALUE => {
+                let (len, item) = cbor::decode_item(&buf[off..]).done()?;
+                off += len;
+                item
+            }
+            _ => {
+                // Such as a time or a unit, which are not needed.
+                off += cbor::skip_item(&buf[off..]).done()?.0;
+                continue;
+            }
+        };
+        match (key, field) {
+            (senml_keys::BASE_NAME, Item::Text(text)) => *base_name = text,
+            (senml_keys::NAME, Item::Text(text)) => name = text,
+            (senml_keys::VALUE, Item::Unsigned(_))
+            | (senml_keys::VALUE, Item::Negative(_))
+            | (senml_keys::VALUE, Item::Float(_))
+            | (senml_keys::STRING_VALUE, Item::Text(_))
+            | (senml_keys::BOOLEAN_VALUE, Item::Bool(_))
+            | (senml_keys::DATA_VALUE, Item::Bytes(_)) => value = cbor_value(field),
+            _ => return None,
+        }
+    }
+    let segments = base_name
+        .split('/')
+        .chain(name.split('/'))
+        .filter(|s| !s.is_empty())
+        .map(str::as_bytes);
+    Some((off, Path::from_segments(segments)?, value?))
+}
@@ -0,0 +1,1081 @@
+//! An LwM2M (OMA Lightweight M2M 1.1) client over CoAP and UDP.
+//!
+//! `LwM2MClient` registers the device with an LwM2M server, and serves the
+//! server's requests to read, write, observe and execute the resources of
+//! the objects other capsules add with `add_object()`. It serves requests as
+//! the handler of a `CoAPResource` for every path of a `CoAPEndpoint`, and
+//! sends its own requests as the client of the endpoint.
+//!
+//! - The server is either set by the board with `set_server()`, or by a
+//!   bootstrap server set with `set_bootstrap_server()`. The client asks the
+//!   bootstrap server for its configuration, which the bootstrap server
+//!   writes to the Security (0) and Server (1) objects before finishing
+//!   bootstrap with a POST to "/bs".
+//! - The client registers at "/rd" with its endpoint name, its lifetime and
+//!   links to its object instances, and updates its registration when three
+//!   quarters of its lifetime have passed. A registration that fails, or an
+//!   update the server no longer knows, is retried after `RETRY_DELAY_S`.
+//! - A server observes a resource, an instance or an object with a read that
+//!   has an Observe option. Once an object tells the client that the value
+//!   of a resource changed, the client sends the value under the path
+//!   observed to each of its observers.
+//!
+//! Only one server is supported, over plain UDP in the NoSec security mode,
+//! and the attributes of observations cannot be written.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//! # use capsules::net::coap::coap_endpoint::CoAPResource;
+//! # use capsules::net::lwm2m::lwm2m_client::{LwM2MClient, ObjectNode};
+//!
+//! let lwm2m = static_init!(
+//!     LwM2MClient<'static, VirtualMuxAlarm<'static, sam4l::ast::Ast>>,
+//!     LwM2MClient::new(coap, lwm2m_alarm, "imix", &mut LWM2M_BUF)
+//! );
+//! coap.set_client(lwm2m);
+//! lwm2m_alarm.set_alarm_client(lwm2m);
+//! let resource = static_init!(CoAPResource<'static>, CoAPResource::new_prefix("", lwm2m));
+//! coap.add_resource(resource);
+//!
+//! let temperature_node = static_init!(ObjectNode<'static>, ObjectNode::new(temperature));
+//! lwm2m.add_object(temperature_node);
+//! temperature.set_client(lwm2m);
+//!
+//! lwm2m.set_bootstrap_server(bootstrap_addr, COAP_PORT);
+//! lwm2m.start();
+//! ```
+
+use crate::net::coap::coap::{coap_codes, coap_content_formats, coap_options};
+use crate::net::coap::coap::{CoAPMessage, MAX_TOKEN_LEN};
+use crate::net::coap::coap_endpoint::COAP_PORT;
+use crate::net::coap::coap_endpoint::{CoAPClient, CoAPEndpoint, CoAPHandler, CoAPResponse};
+use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::lwm2m::lwm2m::{self, object_ids, Path, Value, WriteAdapter};
+use crate::net::lwm2m::lwm2m::{LwM2MObject, LwM2MObjectClient};
+use core::cell::Cell;
+use core::fmt::Write;
+use kernel::common::cells::TakeCell;
+use kernel::common::{List, ListLink, ListNode};
+use kernel::hil::time::{self, Alarm};
+use kernel::ReturnCode;
+
+/// The lifetime of a registration, unless the server sets another.
+pub const DEFAULT_LIFETIME_S: u32 = 86400;
+
+/// How long to wait before retrying a bootstrap or registration that failed.
+pub const RETRY_DELAY_S: u32 = 30;
+
+/// How long the bootstrap server has to finish bootstrapping.
+pub const BOOTSTRAP_TIMEOUT_S: u32 = 120;
+
+pub const MAX_OBSERVATIONS: usize = 4;
+
+/// The longest location the server can give a registration, such as
+/// "rd/5a3f".
+const MAX_LOCATION_LEN: usize = 32;
+
+/// The short ID of the server set by the board.
+const SHORT_SERVER_ID: u16 = 1;
+
+/// Timers are counted in seconds.
+const TICK_MS: u32 = 1000;
+
+/// An object exposed by an `LwM2MClient`.
+pub struct ObjectNode<'a> {
+    object: &'a dyn LwM2MObject,
+    next: ListLink<'a, ObjectNode<'a>>,
+}
+
+impl<'a> ListNode<'a, ObjectNode<'a>> for ObjectNode<'a> {
+    fn next(&'a self) -> &'a ListLink<'a, ObjectNode<'a>> {
+        &self.next
+    }
+}
+
+impl<'a> ObjectNode<'a> {
+    pub fn new(object: &'a dyn LwM2MObject) -> ObjectNode<'a> {
+        ObjectNode {
+            object: object,
+            next: ListLink::empty(),
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum State {
+    /// Not started, or deregistered.
+    Idle,
+    /// The bootstrap request is to be sent once the timer expires.
+    BootstrapPending,
+    /// The bootstrap request was sent.
+    BootstrapRequested,
+    /// The bootstrap server is writing the configuration, until the timer
+    /// expires.
+    Bootstrapping,
+    /// The registration is to be sent once the timer expires.
+    RegisterPending,
+    Registering,
+    /// The registration is updated once the timer expires.
+    Registered,
+    Updating,
+    Deregistering,
+}
+
+/// A server observing a path.
+#[derive(Copy, Clone)]
+struct Observation {
+    path: Path,
+    addr: IPAddr,
+    port: u16,
+    token: [u8; MAX_TOKEN_LEN],
+    token_len: usize,
+    /// The content format of the notifications.
+    format: u16,
+    /// The sequence number of the last notification.
+    seq: u32,
+    /// The value changed since the last notification.
+    pending: bool,
+}
+
+/// An instance of the Security object.
+#[derive(Copy, Clone)]
+struct SecurityInstance {
+    id: u16,
+    /// The address and port of the LwM2M Server URI.
+    server: Option<(IPAddr, u16)>,
+    bootstrap: bool,
+    short_server_id: u16,
+}
+
+/// The Security object (0), which holds the URIs of the bootstrap server and
+/// of the server. Only the bootstrap server can write it, and no server can
+/// read it.
+struct SecurityObject {
+    instances: [Cell<Option<SecurityInstance>>; 2],
+}
+
+impl SecurityObject {
+    fn set(&self, instance: SecurityInstance) {
+        let slot = self
+            .instances
+            .iter()
+            .find(|slot| slot.get().map_or(false, |slot| slot.id == instance.id))
+            .or_else(|| self.instances.iter().find(|slot| slot.get().is_none()));
+        if let Some(slot) = slot {
+            slot.set(Some(instance));
+        }
+    }
+
+    /// The address of the server, or of the bootstrap server.
+    fn server(&self, bootstrap: bool) -> Option<(IPAddr, u16)> {
+        self.instances
+            .iter()
+            .filter_map(|slot| slot.get())
+            .find(|instance| instance.bootstrap == bootstrap)
+            .and_then(|instance| instance.server)
+    }
+
+    /// Deletes the instances of the server, keeping that of the bootstrap
+    /// server.
+    fn delete_servers(&self) {
+        for slot in self.instances.iter() {
+            if slot.get().map_or(false, |instance| !instance.bootstrap) {
+                slot.set(None);
+            }
+        }
+    }
+}
+
+impl LwM2MObject for SecurityObject {
+    fn object_id(&self) -> u16 {
+        object_ids::SECURITY
+    }
+
+    fn instances(&self, f: &mut dyn FnMut(u16)) {
+        for instance in self.instances.iter().filter_map(|slot| slot.get()) {
+            f(instance.id);
+        }
+    }
+
+    fn resources(&self, _instance: u16, f: &mut dyn FnMut(u16)) {
+        for &resource in [0, 1, 2, 10].iter() {
+            f(resource);
+        }
+    }
+
+    fn read(&self, _instance: u16, _resource: u16) -> Result<Value<'static>, ReturnCode> {
+        Err(ReturnCode::ENOSUPPORT)
+    }
+
+    /// Writing an instance that does not exist creates it.
+    fn write(&self, instance: u16, resource: u16, value: Value) -> ReturnCode {
+        let existing = self
+            .instances
+            .iter()
+            .filter_map(|slot| slot.get())
+            .find(|security| security.id == instance);
+        if existing.is_none() && self.instances.iter().all(|slot| slot.get().is_some()) {
+            return ReturnCode::ENOMEM;
+        }
+        let mut security = existing.unwrap_or(SecurityInstance {
+            id: instance,
+            server: None,
+            bootstrap: false,
+            short_server_id: 0,
+        });
+        let valid = match resource {
+            // LwM2M Server URI
+            0 => match value {
+                Value::String(uri) => {
+                    parse_coap_uri(uri).map(|server| security.server = Some(server))
+                }
+                _ => None,
+            },
+            // Bootstrap-Server
+            1 => value
+                .as_bool()
+                .map(|bootstrap| security.bootstrap = bootstrap),
+            // Security Mode, of which only NoSec is supported
+            2 => value.as_integer().filter(|&mode| mode == 3).map(|_| ()),
+            // Short Server ID
+            10 => value
+                .as_integer()
+                .filter(|&id| id > 0 && id < 0xffff)
+                .map(|id| security.short_server_id = id as u16),
+            // The keys of the other security modes
+            _ => Some(()),
+        };
+        if valid.is_none() {
+            return ReturnCode::EINVAL;
+        }
+        self.set(security);
+        ReturnCode::SUCCESS
+    }
+}
+
+/// The Server object (1), which holds the lifetime of the registration.
+struct ServerObject {
+    instance: Cell<Option<u16>>,
+    short_server_id: Cell<u16>,
+    lifetime: Cell<u32>,
+}
+
+impl LwM2MObject for ServerObject {
+    fn object_id(&self) -> u16 {
+        object_ids::SERVER
+    }
+
+    fn instances(&self, f: &mut dyn FnMut(u16)) {
+        if let Some(instance) = self.instance.get() {
+            f(instance);
+        }
+    }
+
+    fn resources(&self, _instance: u16, f: &mut dyn FnMut(u16)) {
+        for &resource in [0, 1, 7, 8].iter() {
+            f(resource);
+        }
+    }
+
+    fn read(&self, _instance: u16, resource: u16) -> Result<Value<'static>, ReturnCode> {
+        match resource {
+            0 => Ok(Value::Integer(self.short_server_id.get() as i64)),
+            1 => Ok(Value::Integer(self.lifetime.get() as i64)),
+            7 => Ok(Value::String("U")),
+            8 => Err(ReturnCode::ENOSUPPORT),
+            _ => Err(ReturnCode::ENODEVICE),
+        }
+    }
+
+    /// Writing an instance that does not exist replaces the instance.
+    fn write(&self, instance: u16, resource: u16, value: Value) -> ReturnCode {
+        let valid = match resource {
+            0 => value
+                .as_integer()
+                .filter(|&id| id > 0 && id < 0xffff)
+                .map(|id| self.short_server_id.set(id as u16)),
+            1 => value
+                .as_integer()
+                .filter(|&lifetime| lifetime > 0 && lifetime <= u32::max_value() as i64)
+                .map(|lifetime| self.lifetime.set(lifetime as u32)),
+            7 => match value {
+                Value::String("U") => Some(()),
+                _ => None,
+            },
+            // Default Minimum and Maximum Period, Notification Storing and
+            // the other resources the client does not use
+            2 | 3 | 5 | 6 => Some(()),
+            _ => return ReturnCode::ENOSUPPORT,
+        };
+        match valid {
+            Some(()) => {
+                self.instance.set(Some(instance));
+                ReturnCode::SUCCESS
+            }
+            None => ReturnCode::EINVAL,
+        }
+    }
+}
+
+pub struct LwM2MClient<'a, A: Alarm<'a>> {
+    coap: &'a CoAPEndpoint<'a, A>,
+    alarm: &'a A,
+    /// The endpoint name the device registers with.
+    name: &'static str,
+    objects: List<'a, ObjectNode<'a>>,
+    security: SecurityObject,
+    server: ServerObject,
+    state: Cell<State>,
+    /// Seconds until the timer of the state expires.
+    timer: Cell<u32>,
+    location: Cell<[u8; MAX_LOCATION_LEN]>,
+    location_len: Cell<usize>,
+    observations: [Cell<Option<Observation>>; MAX_OBSERVATIONS],
+    /// Holds the payload of requests and notifications.
+    buffer: TakeCell<'static, [u8]>,
+}
+
+impl<'a, A: Alarm<'a>> LwM2MClient<'a, A> {
+    pub fn new(
+        coap: &'a CoAPEndpoint<'a, A>,
+        alarm: &'a A,
+        name: &'static str,
+        buffer: &'static mut [u8],
+    ) -> LwM2MClient<'a, A> {
+        LwM2MClient {
+            coap: coap,
+            alarm: alarm,
+            name: name,
+            objects: List::new(),
+            security: SecurityObject {
+                instances: [Cell::new(None), Cell::new(None)],
+            },
+            server: ServerObject {
+                instance: Cell::new(None),
+                short_server_id: Cell::new(SHORT_SERVER_ID),
+                lifetime: Cell::new(DEFAULT_LIFETIME_S),
+            },
+            state: Cell::new(State::Idle),
+            timer: Cell::new(0),
+            location: Cell::new([0; MAX_LOCATION_LEN]),
+            location_len: Cell::new(0),
+            observations: [
+                Cell::new(None),
+                Cell::new(None),
+                Cell::new(None),
+                Cell::new(None),
+            ],
+            buffer: TakeCell::new(buffer),
+        }
+    }
+
+    pub fn add_object(&self, node: &'a ObjectNode<'a>) {
+        self.objects.push_tail(node);
+    }
+
+    /// Sets the server to register with, instead of asking a bootstrap
+    /// server for it.
+    pub fn set_server(&self, addr: IPAddr, port: u16, lifetime: u32) {
+        self.security.set(SecurityInstance {
+            id: 1,
+            server: Some((addr, port)),
+            bootstrap: false,
+            short_server_id: SHORT_SERVER_ID,
+        });
+        self.server.instance.set(Some(0));
+        self.server.short_server_id.set(SHORT_SERVER_ID);
+        self.server.lifetime.set(lifetime);
+    }
+
+    /// Sets the bootstrap server, which the client asks for the server to
+    /// register with if none is set.
+    pub fn set_bootstrap_server(&self, addr: IPAddr, port: u16) {
+        self.security.set(SecurityInstance {
+            id: 0,
+            server: Some((addr, port)),
+            bootstrap: true,
+            short_server_id: 0,
+        });
+    }
+
+    /// Registers with the server, after bootstrapping if no server is set.
+    /// Returns `FAIL` if neither a server nor a bootstrap server is set.
+    pub fn start(&self) -> ReturnCode {
+        if self.state.get() != State::Idle {
+            return ReturnCode::EALREADY;
+        }
+        if self.security.server(false).is_some() {
+            self.set_state(State::RegisterPending, 0);
+        } else if self.security.server(true).is_some() {
+            self.set_state(State::BootstrapPending, 0);
+        } else {
+            return ReturnCode::FAIL;
+        }
+        self.start_timer();
+        self.send_request();
+        ReturnCode::SUCCESS
+    }
+
+    /// Deregisters from the server.
+    pub fn stop(&self) -> ReturnCode {
+        match self.state.get() {
+            State::Idle | State::Deregistering => ReturnCode::EALREADY,
+            State::Registered => {
+                self.clear_observations();
+                self.set_state(State::Deregistering, 0);
+                let rcode = self.request_location(coap_codes::DELETE, "");
+                if rcode != ReturnCode::SUCCESS {
+                    self.set_state(State::Idle, 0);
+                }
+                ReturnCode::SUCCESS
+            }
+            State::BootstrapRequested | State::Registering | State::Updating => {
+                let _ = self.coap.cancel();
+                self.set_state(State::Idle, 0);
+                ReturnCode::SUCCESS
+            }
+            _ => {
+                self.set_state(State::Idle, 0);
+                ReturnCode::SUCCESS
+            }
+        }
+    }
+
+    /// Updates the registration at once, such as after objects are added.
+    pub fn update(&self) -> ReturnCode {
+        if self.state.get() != State::Registered {
+            return ReturnCode::EOFF;
+        }
+        self.timer.set(0);
+        self.send_request();
+        ReturnCode::SUCCESS
+    }
+
+    fn set_state(&self, state: State, timer: u32) {
+        self.state.set(state);
+        self.timer.set(timer);
+    }
+
+    fn start_timer(&self) {
+        if !self.alarm.is_armed() {
+            self.alarm
+                .set_alarm(self.alarm.now(), A::ticks_from_ms(TICK_MS));
+        }
+    }
+
+    /// The object with the ID `object_id`.
+    fn object(&self, object_id: u16) -> Option<&dyn LwM2MObject> {
+        match object_id {
+            object_ids::SECURITY => Some(&self.security),
+            object_ids::SERVER => Some(&self.server),
+            _ => self
+                .objects
+                .iter()
+                .find(|node| node.object.object_id() == object_id)
+                .map(|node| node.object),
+        }
+    }
+
+    /// Calls `f` with each object, the Server object first. The Security
+    /// object is left out, as it is not registered.
+    fn each_object<F: FnMut(&dyn LwM2MObject)>(&self, mut f: F) {
+        f(&self.server);
+        for node in self.objects.iter() {
+            f(node.object);
+        }
+    }
+
+    /// Sends the request of the state once its timer has expired.
+    fn send_request(&self) {
+        if self.timer.get() > 0 {
+            return;
+        }
+        let (rcode, next) = match self.state.get() {
+            State::BootstrapPending => (self.request_bootstrap(), State::BootstrapRequested),
+            State::RegisterPending => (self.request_registration(), State::Registering),
+            State::Registered => {
+                let mut query = [0; 16];
+                let mut writer = WriteAdapter::new(&mut query);
+                let _ = write!(writer, "lt={}", self.server.lifetime.get());
+                let len = writer.used();
+                let query = core::str::from_utf8(&query[..len]).unwrap_or("");
+                (
+                    self.request_location(coap_codes::POST, query),
+                    State::Updating,
+                )
+            }
+            State::Bootstrapping => {
+                // The bootstrap server never finished.
+                self.set_state(State::BootstrapPending, 0);
+                return self.send_request();
+            }
+            _ => return,
+        };
+        match rcode {
+            ReturnCode::SUCCESS => self.state.set(next),
+            // The endpoint is busy with another request.
+            ReturnCode::EBUSY => self.timer.set(1),
+            _ => self.timer.set(RETRY_DELAY_S),
+        }
+    }
+
+    fn request_bootstrap(&self) -> ReturnCode {
+        let (addr, port) = match self.security.server(true) {
+            Some(server) => server,
+            None => return ReturnCode::FAIL,
+        };
+        let mut query = [0; 64];
+        let mut writer = WriteAdapter::new(&mut query);
+        if write!(writer, "ep={}", self.name).is_err() {
+            return ReturnCode::ESIZE;
+        }
+        let len = writer.used();
+        let query = core::str::from_utf8(&query[..len]).unwrap_or("");
+        self.coap
+            .request_with_query(addr, port, true, coap_codes::POST, "bs", query, None, &[])
+    }
+
+    fn request_registration(&self) -> ReturnCode {
+        let (addr, port) = match self.security.server(false) {
+            Some(server) => server,
+            None => return ReturnCode::FAIL,
+        };
+        let mut query = [0; 96];
+        let mut writer = WriteAdapter::new(&mut query);
+        if write!(
+            writer,
+            "ep={}&lt={}&lwm2m=1.1&b=U",
+            self.name,
+            self.server.lifetime.get()
+        )
+        .is_err()
+        {
+            return ReturnCode::ESIZE;
+        }
+        let query_len = writer.used();
+        let query = core::str::from_utf8(&query[..query_len]).unwrap_or("");
+        self.buffer.map_or(ReturnCode::ENOMEM, |buffer| {
+            // Links to the instances of each object, such as
+            // "</1/0>,</3303/0>"
+            let mut writer = WriteAdapter::new(buffer);
+            let mut result = Ok(());
+            self.each_object(|object| {
+                object.instances(&mut |instance| {
+                    let separator = if writer.used() == 0 { "" } else { "," };
+                    result = result.and_then(|()| {
+                        write!(
+                            writer,
+                            "{}<{}>",
+                            separator,
+                            Path::instance(object.object_id(), instance)
+                        )
+                    });
+                });
+            });
+            if result.is_err() {
+                return ReturnCode::ESIZE;
+            }
+            let len = writer.used();
+            self.coap.request_with_query(
+                addr,
+                port,
+                true,
+                coap_codes::POST,
+                "rd",
+                query,
+                Some(coap_content_formats::LINK_FORMAT),
+                &buffer[..len],
+            )
+        })
+    }
+
+    /// Sends a request to the location of the registration.
+    fn request_location(&self, code: u8, query: &str) -> ReturnCode {
+        let (addr, port) = match self.security.server(false) {
+            Some(server) => server,
+            None => return ReturnCode::FAIL,
+        };
+        let location = self.location.get();
+        let location = core::str::from_utf8(&location[..self.location_len.get()]).unwrap_or("");
+        self.coap
+            .request_with_query(addr, port, true, code, location, query, None, &[])
+    }
+
+    /// Saves the Location-Path options of a registration, such as "rd" and
+    /// "5a3f", as "rd/5a3f".
+    fn save_location(&self, msg: &CoAPMessage) -> bool {
+        let mut location = [0; MAX_LOCATION_LEN];
+        let mut len = 0;
+        for (number, segment) in msg.options() {
+            if number != coap_options::LOCATION_PATH {
+                continue;
+            }
+            let separator = if len == 0 { 0 } else { 1 };
+            if len + separator + segment.len() > MAX_LOCATION_LEN
+                || core::str::from_utf8(segment).is_err()
+            {
+                return false;
+            }
+            if separator == 1 {
+                location[len] = b'/';
+            }
+            location[len + separator..len + separator + segment.len()].copy_from_slice(segment);
+            len += separator + segment.len();
+        }
+        self.location.set(location);
+        self.location_len.set(len);
+        len > 0
+    }
+
+    /// Handles the response to a request of the client, which is `None` if
+    /// the request failed.
+    fn request_done(&self, msg: Option<&CoAPMessage>) {
+        let code = msg.map_or(coap_codes::EMPTY, |msg| msg.header.get_code());
+        match self.state.get() {
+            State::BootstrapRequested => {
+                if code == coap_codes::CHANGED {
+                    self.set_state(State::Bootstrapping, BOOTSTRAP_TIMEOUT_S);
+                } else {
+                    self.set_state(State::BootstrapPending, RETRY_DELAY_S);
+                }
+            }
+            State::Registering => match msg {
+                Some(msg) if code == coap_codes::CREATED && self.save_location(msg) => {
+                    // Observations end with the registration they were made
+                    // in.
+                    self.clear_observations();
+                    self.set_state(State::Registered, self.update_delay());
+                }
+                _ => self.set_state(State::RegisterPending, RETRY_DELAY_S),
+            },
+            State::Updating => match code {
+                coap_codes::CHANGED => self.set_state(State::Registered, self.update_delay()),
+                // The server no longer knows the registration.
+                coap_codes::NOT_FOUND => self.set_state(State::RegisterPending, 0),
+                _ => self.set_state(State::RegisterPending, RETRY_DELAY_S),
+            },
+            State::Deregistering => self.set_state(State::Idle, 0),
+            _ => {}
+        }
+        self.send_request();
+    }
+
+    /// Seconds until the registration is updated.
+    fn update_delay(&self) -> u32 {
+        let lifetime = self.server.lifetime.get();
+        core::cmp::max(lifetime - lifetime / 4, 1)
+    }
+
+    fn clear_observations(&self) {
+        for observation in self.observations.iter() {
+            observation.set(None);
+        }
+    }
+
+    /// Encodes the value of `path` in the content format `format`, and
+    /// returns its length, or the response code of the error.
+    fn encode_read(&self, buf: &mut [u8], path: &Path, format: u16) -> Result<usize, u8> {
+        let object = path
+            .object_id()
+            .and_then(|id| self.object(id))
+            .ok_or(coap_codes::NOT_FOUND)?;
+        if !lwm2m::exists(object, path) {
+            return Err(coap_codes::NOT_FOUND);
+        }
+        match format {
+            coap_content_formats::TEXT_PLAIN | coap_content_formats::CBOR => {
+                let (instance, resource) = match (path.instance_id(), path.resource_id()) {
+                    (Some(instance), Some(resource)) => (instance, resource),
+                    _ => return Err(coap_codes::NOT_ACCEPTABLE),
+                };
+                let value = object.read(instance, resource).map_err(error_code)?;
+                lwm2m::encode_value(buf, format, &value).ok_or(coap_codes::NOT_ACCEPTABLE)
+            }
+            coap_content_formats::SENML_CBOR => {
+                lwm2m::encode_senml(buf, object, path).ok_or(coap_codes::INTERNAL_SERVER_ERROR)
+            }
+            coap_content_formats::LINK_FORMAT => {
+                // Discover, with a link to each resource
+                let mut writer = WriteAdapter::new(buf);
+                let mut result = Ok(());
+                lwm2m::each_resource(object, path, &mut |instance, resource| {
+                    let separator = if writer.used() == 0 { "" } else { "," };
+                    result = result.and_then(|()| {
+                        write!(
+                            writer,
+                            "{}<{}>",
+                            separator,
+                            Path::resource(object.object_id(), instance, resource)
+                        )
+                    });
+                });
+                result.map_err(|_| coap_codes::INTERNAL_SERVER_ERROR)?;
+                Ok(writer.used())
+            }
+            _ => Err(coap_codes::NOT_ACCEPTABLE),
+        }
+    }
+
+    fn read(
+        &self,
+        src_addr: IPAddr,
+        src_port: u16,
+        path: &Path,
+        request: &CoAPMessage,
+        response: &mut [u8],
+    ) -> CoAPResponse {
+        if path.object_id() == Some(object_ids::SECURITY) {
+            return CoAPResponse::new(coap_codes::UNAUTHORIZED, 0);
+        }
+        let format = request
+            .uint_option(coap_options::ACCEPT)
+            .map_or(coap_content_formats::SENML_CBOR, |format| format as u16);
+        let len = match self.encode_read(response, path, format) {
+            Ok(len) => len,
+            Err(code) => return CoAPResponse::new(code, 0),
+        };
+        let mut result = CoAPResponse::new(coap_codes::CONTENT, len);
+        result.content_format = Some(format);
+        let token = request.header.get_token();
+        match request.uint_option(coap_options::OBSERVE) {
+            Some(0) => {
+                // An observer the table has no room for is answered as if it
+                // had not asked to observe
+                let slot = self
+                    .observations
+                    .iter()
+                    .find(|slot| {
+                        slot.get().map_or(false, |observation| {
+                            observation.addr == src_addr
+                                && &observation.token[..observation.token_len] == token
+                        })
+                    })
+                    .or_else(|| self.observations.iter().find(|slot| slot.get().is_none()));
+                if let Some(slot) = slot {
+                    let mut saved_token = [0; MAX_TOKEN_LEN];
+                    saved_token[..token.len()].copy_from_slice(token);
+                    slot.set(Some(Observation {
+                        path: *path,
+                        addr: src_addr,
+                        port: src_port,
+                        token: saved_token,
+                        token_len: token.len(),
+                        format: format,
+                        seq: 0,
+                        pending: false,
+                    }));
+                    result.observe = Some(0);
+                }
+            }
+            Some(1) => self.cancel_observation(src_addr, token),
+            _ => {}
+        }
+        result
+    }
+
+    fn cancel_observation(&self, addr: IPAddr, token: &[u8]) {
+        for slot in self.observations.iter() {
+            if slot.get().map_or(false, |observation| {
+                observation.addr == addr && &observation.token[..observation.token_len] == token
+            }) {
+                slot.set(None);
+            }
+        }
+    }
+
+    fn write(&self, path: &Path, bootstrap: bool, request: &CoAPMessage) -> CoAPResponse {
+        // A write without a payload writes the attributes of observations.
+        if request.payload.is_empty() {
+            return CoAPResponse::new(coap_codes::NOT_IMPLEMENTED, 0);
+        }
+        let format = request
+            .uint_option(coap_options::CONTENT_FORMAT)
+            .map_or(coap_content_formats::TEXT_PLAIN, |format| format as u16);
+        let write_one = |target: Path, value: Value| -> ReturnCode {
+            if !path.contains(&target) {
+                return ReturnCode::EINVAL;
+            }
+            let object = match target.object_id().and_then(|id| self.object(id)) {
+                Some(object) => object,
+                None => return ReturnCode::ENODEVICE,
+            };
+            let (instance, resource) = match (target.instance_id(), target.resource_id()) {
+                (Some(instance), Some(resource)) => (instance, resource),
+                _ => return ReturnCode::EINVAL,
+            };
+            // Only the bootstrap server configures the servers, apart from
+            // the lifetime, which the server can change.
+            let allowed = bootstrap
+                || match target.object_id() {
+                    Some(object_ids::SECURITY) => false,
+                    Some(object_ids::SERVER) => resource == 1,
+                    _ => true,
+                };
+            if !allowed {
+                return ReturnCode::ERESERVE;
+            }
+            object.write(instance, resource, value)
+        };
+        let rcode = match format {
+            coap_content_formats::TEXT_PLAIN | coap_content_formats::CBOR => {
+                match lwm2m::decode_value(request.payload, format) {
+                    Some(value) => write_one(*path, value),
+                    None => ReturnCode::EINVAL,
+                }
+            }
+            coap_content_formats::SENML_CBOR => lwm2m::decode_senml(request.payload, write_one),
+            _ => return CoAPResponse::new(coap_codes::UNSUPPORTED_CONTENT_FORMAT, 0),
+        };
+        if rcode != ReturnCode::SUCCESS {
+            return CoAPResponse::new(error_code(rcode), 0);
+        }
+        if !bootstrap && path.object_id() == Some(object_ids::SERVER) {
+            // Tell the server the new lifetime.
+            self.timer.set(1);
+        }
+        CoAPResponse::new(coap_codes::CHANGED, 0)
+    }
+
+    fn execute(&self, path: &Path, request: &CoAPMessage) -> CoAPResponse {
+        let (object_id, instance, resource) =
+            match (path.object_id(), path.instance_id(), path.resource_id()) {
+                (Some(object), Some(instance), Some(resource)) => (object, instance, resource),
+                _ => return CoAPResponse::new(coap_codes::METHOD_NOT_ALLOWED, 0),
+            };
+        let object = match self.object(object_id) {
+            Some(object) if lwm2m::exists(object, path) => object,
+            _ => return CoAPResponse::new(coap_codes::NOT_FOUND, 0),
+        };
+        let rcode = if object_id == object_ids::SERVER && resource == 8 {
+            // Registration Update Trigger
+            self.timer.set(1);
+            ReturnCode::SUCCESS
+        } else {
+            object.execute(instance, resource, request.payload)
+        };
+        match rcode {
+            ReturnCode::SUCCESS => CoAPResponse::new(coap_codes::CHANGED, 0),
+            rcode => CoAPResponse::new(error_code(rcode), 0),
+        }
+    }
+
+    /// Bootstrap-Finish, after which the client registers with the server
+    /// the bootstrap server wrote.
+    fn finish_bootstrap(&self) -> CoAPResponse {
+        if self.security.server(false).is_none() || self.server.instance.get().is_none() {
+            return CoAPResponse::new(coap_codes::NOT_ACCEPTABLE, 0);
+        }
+        // The response is sent before the registration, which would take
+        // the transmit buffer of the endpoint.
+        self.set_state(State::RegisterPending, 1);
+        CoAPResponse::new(coap_codes::CHANGED, 0)
+    }
+
+    /// Bootstrap-Delete, of the configuration of the server.
+    fn delete(&self, path: &Path) -> CoAPResponse {
+        match path.object_id() {
+            None | Some(object_ids::SECURITY) | Some(object_ids::SERVER) => {
+                self.security.delete_servers();
+                self.server.instance.set(None);
+                CoAPResponse::new(coap_codes::DELETED, 0)
+            }
+            _ => CoAPResponse::new(coap_codes::METHOD_NOT_ALLOWED, 0),
+        }
+    }
+
+    /// Sends the observers of changed values their notifications, until the
+    /// endpoint is busy.
+    fn send_notifications(&self) {
+        for slot in self.observations.iter() {
+            let mut observation = match slot.get() {
+                Some(observation) if observation.pending => observation,
+                _ => continue,
+            };
+            let rcode = self.buffer.map_or(ReturnCode::ENOMEM, |buffer| {
+                match self.encode_read(buffer, &observation.path, observation.format) {
+                    Ok(len) => self.coap.notify(
+                        observation.addr,
+                        observation.port,
+                        &observation.token[..observation.token_len],
+                        observation.seq + 1,
+                        coap_codes::CONTENT,
+                        Some(observation.format),
+                        &buffer[..len],
+                    ),
+                    Err(_) => ReturnCode::FAIL,
+                }
+            });
+            match rcode {
+                ReturnCode::SUCCESS => {
+                    observation.seq += 1;
+                    observation.pending = false;
+                    slot.set(Some(observation));
+                }
+                // Sent again on the next tick
+                ReturnCode::EBUSY => return,
+                // The path can no longer be read
+                _ => slot.set(None),
+            }
+        }
+    }
+}
+
+/// Maps the error of an object to a response code.
+fn error_code(rcode: ReturnCode) -> u8 {
+    match rcode {
+        ReturnCode::ENODEVICE => coap_codes::NOT_FOUND,
+        ReturnCode::ENOSUPPORT => coap_codes::METHOD_NOT_ALLOWED,
+        ReturnCode::ERESERVE => coap_codes::UNAUTHORIZED,
+        ReturnCode::EINVAL | ReturnCode::ESIZE => coap_codes::BAD_REQUEST,
+        ReturnCode::EBUSY => coap_codes::SERVICE_UNAVAILABLE,
+        _ => coap_codes::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// Parses a CoAP URI with an IPv6 address, such as "coap://[fd00::1]:5683".
+fn parse_coap_uri(uri: &str) -> Option<(IPAddr, u16)> {
+    const SCHEME: &str = "coap://[";
+    if !uri.starts_with(SCHEME) {
+        return None;
+    }
+    let rest = &uri[SCHEME.len()..];
+    let end = rest.find(']')?;
+    let addr = parse_ipv6(&rest[..end])?;
+    let rest = rest[end + 1..].trim_end_matches('/');
+    let port = if rest.is_empty() {
+        COAP_PORT
+    } else if rest.starts_with(':') {
+        rest[1..].parse().ok()?
+    } else {
+        return None;
+    };
+    Some((addr, port))
+}
+
+/// Parses an IPv6 address, such as "fd00::1".
+fn parse_ipv6(text: &str) -> Option<IPAddr> {
+    let mut groups = [0u16; 8];
+    match text.find("::") {
+        Some(split) => {
+            let head = parse_groups(&text[..split], &mut groups)?;
+            let mut tail = [0u16; 8];
+            let tail_len = parse_groups(&text[split + 2..], &mut tail)?;
+            if head + tail_len > 7 {
+                return None;
+            }
+            groups[8 - tail_len..].copy_from_slice(&tail[..tail_len]);
+        }
+        None => {
+            if parse_groups(text, &mut groups)? != 8 {
+                return None;
+            }
+        }
+    }
+    let mut addr = IPAddr::new();
+    for (bytes, group) in addr.0.chunks_mut(2).zip(groups.iter()) {
+        bytes.copy_from_slice(&group.to_be_bytes());
+    }
+    Some(addr)
+}
+
+/// Parses the groups of hexadecimal digits of an address separated by
+/// colons, and returns how many there are.
+fn parse_groups(text: &str, groups: &mut [u16; 8]) -> Option<usize> {
+    if text.is_empty() {
+        return Some(0);
+    }
+    let mut len = 0;
+    for group in text.split(':') {
+        if len == groups.len() || group.is_empty() || group.len() > 4 {
+            return None;
+        }
+        groups[len] = u16::from_str_radix(group, 16).ok()?;
+        len += 1;
+    }
+    Some(len)
+}
+
+impl<'a, A: Alarm<'a>> CoAPHandler for LwM2MClient<'a, A> {
+    fn handle(
+        &self,
+        src_addr: IPAddr,
+        src_port: u16,
+        request: &CoAPMessage,
+        response: &mut [u8],
+    ) -> CoAPResponse {
+        // Requests are served from the bootstrap server while bootstrapping,
+        // and from the server while registered.
+        let bootstrap = match self.state.get() {
+            State::BootstrapRequested | State::Bootstrapping => true,
+            State::Registering | State::Registered | State::Updating => false,
+            _ => return CoAPResponse::new(coap_codes::UNAUTHORIZED, 0),
+        };
+        let trusted = self
+            .security
+            .server(bootstrap)
+            .map_or(false, |(addr, _)| addr == src_addr);
+        if !trusted {
+            return CoAPResponse::new(coap_codes::UNAUTHORIZED, 0);
+        }
+        let code = request.header.get_code();
+        if request.path_matches("bs") {
+            return if bootstrap && code == coap_codes::POST {
+                self.finish_bootstrap()
+            } else {
+                CoAPResponse::new(coap_codes::METHOD_NOT_ALLOWED, 0)
+            };
+        }
+        let path = match Path::from_segments(request.path_segments()) {
+            Some(path) => path,
+            None => return CoAPResponse::new(coap_codes::NOT_FOUND, 0),
+        };
+        match code {
+            coap_codes::GET => self.read(src_addr, src_port, &path, request, response),
+            coap_codes::PUT => self.write(&path, bootstrap, request),
+            coap_codes::POST if path.is_resource() => self.execute(&path, request),
+            coap_codes::POST => self.write(&path, bootstrap, request),
+            coap_codes::DELETE if bootstrap => self.delete(&path),
+            _ => CoAPResponse::new(coap_codes::METHOD_NOT_ALLOWED, 0),
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> CoAPClient for LwM2MClient<'a, A> {
+    fn response(&self, _result: ReturnCode, _code: u8, _payload: &[u8]) {
+        self.request_done(None);
+    }
+
+    fn response_message(&self, msg: &CoAPMessage) {
+        self.request_done(Some(msg));
+    }
+}
+
+impl<'a, A: Alarm<'a>> LwM2MObjectClient for LwM2MClient<'a, A> {
+    fn value_changed(&self, object_id: u16, instance: u16, resource: u16) {
+        let changed = Path::resource(object_id, instance, resource);
+        for slot in self.observations.iter() {
+            if let Some(mut observation) = slot.get() {
+                if observation.path.contains(&changed) {
+                    observation.pending = true;
+                    slot.set(Some(observation));
+                }
+            }
+        }
+        self.send_notifications();
+    }
+}
+
+impl<'a, A: Alarm<'a>> time::AlarmClient for LwM2MClient<'a, A> {
+    fn alarm(&self) {
+        if self.state.get() == State::Idle {
+            return;
+        }
+        self.timer.set(self.timer.get().saturating_sub(1));
+        self.start_timer();
+        self.send_request();
+        self.send_notifications();
+    }
+}
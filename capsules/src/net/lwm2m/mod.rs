@@ -0,0 +1,3 @@
+pub mod lwm2m;
+pub mod lwm2m_client;
+pub mod temperature;
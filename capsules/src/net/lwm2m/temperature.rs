@@ -0,0 +1,173 @@
+//! The IPSO Temperature object (3303) of a temperature sensor, exposed to an
+//! LwM2M client.
+//!
+//! The object samples the sensor every `period_ms` milliseconds, and tells
+//! its client when the temperature or the lowest or highest temperature
+//! measured changes. It has one instance, with the resources:
+//!
+//! - `5700`: Sensor Value, the last temperature, in degrees Celsius.
+//! - `5701`: Sensor Units, "Cel".
+//! - `5601`: Min Measured Value.
+//! - `5602`: Max Measured Value.
+//! - `5605`: Reset Min and Max Measured Values, to the last temperature.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//! # use capsules::net::lwm2m::temperature::TemperatureObject;
+//!
+//! let temperature = static_init!(
+//!     TemperatureObject<'static, VirtualMuxAlarm<'static, sam4l::ast::Ast>>,
+//!     TemperatureObject::new(si7021, temperature_alarm, 10000)
+//! );
+//! kernel::hil::sensors::TemperatureDriver::set_client(si7021, temperature);
+//! temperature_alarm.set_alarm_client(temperature);
+//! temperature.set_client(lwm2m);
+//! temperature.start();
+//! ```
+
+use crate::net::lwm2m::lwm2m::{object_ids, LwM2MObject, LwM2MObjectClient, Value};
+use core::cell::Cell;
+use kernel::common::cells::OptionalCell;
+use kernel::hil::sensors::{TemperatureClient, TemperatureDriver};
+use kernel::hil::time::{self, Alarm};
+use kernel::ReturnCode;
+
+pub const SENSOR_VALUE: u16 = 5700;
+pub const SENSOR_UNITS: u16 = 5701;
+pub const MIN_MEASURED_VALUE: u16 = 5601;
+pub const MAX_MEASURED_VALUE: u16 = 5602;
+pub const RESET_MIN_MAX: u16 = 5605;
+
+const RESOURCES: [u16; 5] = [
+    SENSOR_VALUE,
+    SENSOR_UNITS,
+    MIN_MEASURED_VALUE,
+    MAX_MEASURED_VALUE,
+    RESET_MIN_MAX,
+];
+
+pub struct TemperatureObject<'a, A: Alarm<'a>> {
+    sensor: &'a dyn TemperatureDriver<'a>,
+    alarm: &'a A,
+    period_ms: u32,
+    client: OptionalCell<&'a dyn LwM2MObjectClient>,
+    /// The last temperature, and the lowest and highest measured, in
+    /// hundredths of degrees.
+    value: Cell<Option<i32>>,
+    min: Cell<Option<i32>>,
+    max: Cell<Option<i32>>,
+}
+
+impl<'a, A: Alarm<'a>> TemperatureObject<'a, A> {
+    pub fn new(
+        sensor: &'a dyn TemperatureDriver<'a>,
+        alarm: &'a A,
+        period_ms: u32,
+    ) -> TemperatureObject<'a, A> {
+        TemperatureObject {
+            sensor: sensor,
+            alarm: alarm,
+            period_ms: period_ms,
+            client: OptionalCell::empty(),
+            value: Cell::new(None),
+            min: Cell::new(None),
+            max: Cell::new(None),
+        }
+    }
+
+    pub fn set_client(&self, client: &'a dyn LwM2MObjectClient) {
+        self.client.set(client);
+    }
+
+    /// Starts sampling the sensor.
+    pub fn start(&self) -> ReturnCode {
+        self.alarm
+            .set_alarm(self.alarm.now(), A::ticks_from_ms(self.period_ms));
+        self.sensor.read_temperature()
+    }
+
+    /// Sets `cell` to `value`, telling the client if it changed.
+    fn update(&self, cell: &Cell<Option<i32>>, resource: u16, value: i32) {
+        if cell.replace(Some(value)) != Some(value) {
+            self.client.map(|client| {
+                client.value_changed(object_ids::TEMPERATURE, 0, resource);
+            });
+        }
+    }
+}
+
+fn celsius(hundredths: Option<i32>) -> Result<Value<'static>, ReturnCode> {
+    hundredths
+        .map(|hundredths| Value::Float(hundredths as f64 / 100.0))
+        .ok_or(ReturnCode::EBUSY)
+}
+
+impl<'a, A: Alarm<'a>> LwM2MObject for TemperatureObject<'a, A> {
+    fn object_id(&self) -> u16 {
+        object_ids::TEMPERATURE
+    }
+
+    fn instances(&self, f: &mut dyn FnMut(u16)) {
+        f(0);
+    }
+
+    fn resources(&self, _instance: u16, f: &mut dyn FnMut(u16)) {
+        for &resource in RESOURCES.iter() {
+            f(resource);
+        }
+    }
+
+    fn read(&self, instance: u16, resource: u16) -> Result<Value<'static>, ReturnCode> {
+        if instance != 0 {
+            return Err(ReturnCode::ENODEVICE);
+        }
+        match resource {
+            SENSOR_VALUE => celsius(self.value.get()),
+            SENSOR_UNITS => Ok(Value::String("Cel")),
+            MIN_MEASURED_VALUE => celsius(self.min.get()),
+            MAX_MEASURED_VALUE => celsius(self.max.get()),
+            RESET_MIN_MAX => Err(ReturnCode::ENOSUPPORT),
+            _ => Err(ReturnCode::ENODEVICE),
+        }
+    }
+
+    fn execute(&self, instance: u16, resource: u16, _arguments: &[u8]) -> ReturnCode {
+        match (instance, resource) {
+            (0, RESET_MIN_MAX) => {
+                if let Some(value) = self.value.get() {
+                    self.update(&self.min, MIN_MEASURED_VALUE, value);
+                    self.update(&self.max, MAX_MEASURED_VALUE, value);
+                }
+                ReturnCode::SUCCESS
+            }
+            (0, _) => ReturnCode::ENOSUPPORT,
+            _ => ReturnCode::ENODEVICE,
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> TemperatureClient for TemperatureObject<'a, A> {
+    fn callback(&self, value: usize) {
+        // Temperatures below zero wrap around
+        let value = value as i32;
+        self.update(&self.value, SENSOR_VALUE, value);
+        if self.min.get().map_or(true, |min| value < min) {
+            self.update(&self.min, MIN_MEASURED_VALUE, value);
+        }
+        if self.max.get().map_or(true, |max| value > max) {
+            self.update(&self.max, MAX_MEASURED_VALUE, value);
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> time::AlarmClient for TemperatureObject<'a, A> {
+    fn alarm(&self) {
+        self.alarm
+            .set_alarm(self.alarm.now(), A::ticks_from_ms(self.period_ms));
+        // A failed sample leaves the last temperature until the next one
+        let _ = self.sensor.read_temperature();
+    }
+}
@@ -5,10 +5,22 @@ pub mod sixlowpan;
 pub mod util;
 #[macro_use]
 pub mod stream;
+pub mod cbor;
+pub mod coap;
+pub mod dns;
+pub mod dtls;
+pub mod ethernet;
 pub mod icmpv6;
 pub mod ieee802154;
+pub mod ipsp;
 pub mod ipv6;
+pub mod lwm2m;
+pub mod mqttsn;
 pub mod network_capabilities;
+pub mod ota;
+pub mod secure_session;
+pub mod slip;
+pub mod sntp;
 pub mod tcp;
 pub mod thread;
 pub mod udp;
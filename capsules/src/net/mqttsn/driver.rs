@@ -0,0 +1,297 @@
+//! Userspace driver for an MQTT-SN session.
+//!
+//! [MqttSnDriver](struct.MqttSnDriver.html) lets an app publish, for
+//! example sensor readings, through an
+//! [MqttSnSession](../mqttsn_session/struct.MqttSnSession.html) to an MQTT-SN
+//! gateway, and receive the messages of the topics it subscribes to. The
+//! first app to connect owns the session until it disconnects (or the app
+//! dies), and other apps receive `EBUSY`.
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! - allow `0`: receive buffer, filled with the payload of received
+//!   messages.
+//! - allow `1`: transmit buffer, holding the payload to publish.
+//! - allow `2`: config buffer, a 16 byte IPv6 address followed by a 2 byte
+//!   port in host byte order and the client ID, used by `connect`.
+//! - allow `3`: topic buffer, holding the topic name to publish or
+//!   subscribe to.
+//! - subscribe `0`: session events, `fn(event: usize, arg1: usize, arg2:
+//!   usize)` where `event` is 0 for connected, 1 for published, 2 for
+//!   subscribed, 3 for received and 4 for disconnected. `arg1` is the
+//!   result, except for received messages, where it is the topic ID and
+//!   `arg2` the length of the payload. `arg2` of subscriptions is the ID of
+//!   their topic.
+//! - command `0`: driver check.
+//! - command `1`: connect to the gateway in the config buffer, with a
+//!   keepalive of `data` seconds.
+//! - command `2`: publish the first `data` bytes of the transmit buffer to
+//!   the topic in the topic buffer, with the quality of service in bits 0-1
+//!   of `data2` and the retain flag in bit 2.
+//! - command `3`: subscribe to the topic in the topic buffer, with the
+//!   quality of service `data`.
+//! - command `4`: disconnect from the gateway.
+
+use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::mqttsn::mqttsn_session::{MqttSnClient, MqttSnSession};
+use crate::net::util::host_slice_to_u16;
+use core::cmp;
+use kernel::common::cells::OptionalCell;
+use kernel::hil::time::Alarm;
+use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
+
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::MqttSn as usize;
+
+/// Events reported in the first argument of the userspace callback.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MqttSnEvent {
+    Connected = 0,
+    Published = 1,
+    Subscribed = 2,
+    Received = 3,
+    Disconnected = 4,
+}
+
+#[derive(Default)]
+pub struct App {
+    callback: Option<Callback>,
+    app_read: Option<AppSlice<Shared, u8>>,
+    app_write: Option<AppSlice<Shared, u8>>,
+    app_cfg: Option<AppSlice<Shared, u8>>,
+    app_topic: Option<AppSlice<Shared, u8>>,
+}
+
+pub struct MqttSnDriver<'a, A: Alarm<'a>> {
+    session: &'a MqttSnSession<'a, A>,
+    apps: Grant<App>,
+    /// App that currently owns the session.
+    owner: OptionalCell<AppId>,
+}
+
+impl<'a, A: Alarm<'a>> MqttSnDriver<'a, A> {
+    pub fn new(session: &'a MqttSnSession<'a, A>, grant: Grant<App>) -> MqttSnDriver<'a, A> {
+        MqttSnDriver {
+            session: session,
+            apps: grant,
+            owner: OptionalCell::empty(),
+        }
+    }
+
+    #[inline]
+    fn do_with_app<F>(&self, appid: AppId, closure: F) -> ReturnCode
+    where
+        F: FnOnce(&mut App) -> ReturnCode,
+    {
+        self.apps
+            .enter(appid, |app, _| closure(app))
+            .unwrap_or_else(|err| err.into())
+    }
+
+    /// Checks whether `appid` may use the session, releasing it first if the
+    /// previous owner no longer exists.
+    fn owned_by(&self, appid: AppId) -> bool {
+        let owner = self.owner.map_or(None, |owner| {
+            if *owner != appid && self.apps.enter(*owner, |_, _| ()).is_err() {
+                None
+            } else {
+                Some(*owner)
+            }
+        });
+        match owner {
+            Some(owner) => owner == appid,
+            None => {
+                if self.owner.is_some() && self.session.is_connected() {
+                    self.session.disconnect();
+                }
+                self.owner.clear();
+                false
+            }
+        }
+    }
+
+    fn notify(&self, event: MqttSnEvent, arg1: usize, arg2: usize) {
+        self.owner.map(|owner| {
+            let _ = self.apps.enter(*owner, |app, _| {
+                app.callback
+                    .map(|mut cb| cb.schedule(event as usize, arg1, arg2));
+            });
+        });
+    }
+
+    fn connect(&self, appid: AppId, keepalive_s: usize) -> ReturnCode {
+        if self.owner.is_some() && !self.owned_by(appid) {
+            return ReturnCode::EBUSY;
+        }
+        if keepalive_s > u16::max_value() as usize {
+            return ReturnCode::EINVAL;
+        }
+        let result = self.do_with_app(appid, |app| {
+            let cfg = match app.app_cfg.as_ref() {
+                Some(cfg) => cfg.as_ref(),
+                None => return ReturnCode::EINVAL,
+            };
+            let addr_len = IPAddr::new().0.len();
+            if cfg.len() < addr_len + 2 {
+                return ReturnCode::EINVAL;
+            }
+            let (a, rest) = cfg.split_at(addr_len);
+            let mut addr = IPAddr::new();
+            addr.0.copy_from_slice(a);
+            let port = host_slice_to_u16(rest);
+            match core::str::from_utf8(&rest[2..]) {
+                Ok(client_id) => self
+                    .session
+                    .connect(addr, port, client_id, keepalive_s as u16),
+                Err(_) => ReturnCode::EINVAL,
+            }
+        });
+        if result == ReturnCode::SUCCESS {
+            self.owner.set(appid);
+        }
+        result
+    }
+
+    fn publish(&self, appid: AppId, len: usize, flags: usize) -> ReturnCode {
+        if !self.owned_by(appid) {
+            return ReturnCode::ERESERVE;
+        }
+        self.do_with_app(appid, |app| {
+            let (topic, payload) = match (app.app_topic.as_ref(), app.app_write.as_ref()) {
+                (Some(topic), Some(payload)) if len <= payload.len() => {
+                    (topic.as_ref(), &payload.as_ref()[..len])
+                }
+                _ => return ReturnCode::EINVAL,
+            };
+            match core::str::from_utf8(topic) {
+                Ok(topic) => {
+                    self.session
+                        .publish(topic, payload, (flags & 0x3) as u8, flags & 0x4 != 0)
+                }
+                Err(_) => ReturnCode::EINVAL,
+            }
+        })
+    }
+
+    fn subscribe_topic(&self, appid: AppId, qos: usize) -> ReturnCode {
+        if !self.owned_by(appid) {
+            return ReturnCode::ERESERVE;
+        }
+        if qos > 1 {
+            return ReturnCode::EINVAL;
+        }
+        self.do_with_app(appid, |app| {
+            let topic = match app.app_topic.as_ref() {
+                Some(topic) => topic.as_ref(),
+                None => return ReturnCode::EINVAL,
+            };
+            match core::str::from_utf8(topic) {
+                Ok(topic) => self.session.subscribe(topic, qos as u8),
+                Err(_) => ReturnCode::EINVAL,
+            }
+        })
+    }
+}
+
+impl<'a, A: Alarm<'a>> MqttSnClient for MqttSnDriver<'a, A> {
+    fn connected(&self, result: ReturnCode) {
+        self.notify(MqttSnEvent::Connected, usize::from(result), 0);
+        if result != ReturnCode::SUCCESS {
+            self.owner.clear();
+        }
+    }
+
+    fn published(&self, result: ReturnCode) {
+        self.notify(MqttSnEvent::Published, usize::from(result), 0);
+    }
+
+    fn subscribed(&self, result: ReturnCode, topic_id: u16) {
+        self.notify(
+            MqttSnEvent::Subscribed,
+            usize::from(result),
+            topic_id as usize,
+        );
+    }
+
+    fn received(&self, topic_id: u16, _topic: Option<&str>, payload: &[u8]) {
+        self.owner.map(|owner| {
+            let _ = self.apps.enter(*owner, |app, _| {
+                let len = app.app_read.as_mut().map_or(0, |rbuf| {
+                    let len = cmp::min(rbuf.len(), payload.len());
+                    rbuf.as_mut()[..len].copy_from_slice(&payload[..len]);
+                    len
+                });
+                app.callback.map(|mut cb| {
+                    cb.schedule(MqttSnEvent::Received as usize, topic_id as usize, len)
+                });
+            });
+        });
+    }
+
+    fn disconnected(&self, result: ReturnCode) {
+        self.notify(MqttSnEvent::Disconnected, usize::from(result), 0);
+        self.owner.clear();
+    }
+}
+
+impl<'a, A: Alarm<'a>> Driver for MqttSnDriver<'a, A> {
+    fn allow(
+        &self,
+        appid: AppId,
+        allow_num: usize,
+        slice: Option<AppSlice<Shared, u8>>,
+    ) -> ReturnCode {
+        match allow_num {
+            0 => self.do_with_app(appid, |app| {
+                app.app_read = slice;
+                ReturnCode::SUCCESS
+            }),
+            1 => self.do_with_app(appid, |app| {
+                app.app_write = slice;
+                ReturnCode::SUCCESS
+            }),
+            2 => self.do_with_app(appid, |app| {
+                app.app_cfg = slice;
+                ReturnCode::SUCCESS
+            }),
+            3 => self.do_with_app(appid, |app| {
+                app.app_topic = slice;
+                ReturnCode::SUCCESS
+            }),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        callback: Option<Callback>,
+        app_id: AppId,
+    ) -> ReturnCode {
+        match subscribe_num {
+            0 => self.do_with_app(app_id, |app| {
+                app.callback = callback;
+                ReturnCode::SUCCESS
+            }),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    fn command(&self, command_num: usize, arg1: usize, arg2: usize, appid: AppId) -> ReturnCode {
+        match command_num {
+            0 => ReturnCode::SUCCESS,
+            1 => self.connect(appid, arg1),
+            2 => self.publish(appid, arg1, arg2),
+            3 => self.subscribe_topic(appid, arg1),
+            4 => {
+                if self.owned_by(appid) {
+                    self.session.disconnect()
+                } else {
+                    ReturnCode::ERESERVE
+                }
+            }
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
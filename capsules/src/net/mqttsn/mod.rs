@@ -0,0 +1,3 @@
+pub mod driver;
+pub mod mqttsn;
+pub mod mqttsn_session;
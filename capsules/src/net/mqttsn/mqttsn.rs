@@ -0,0 +1,234 @@
+//! This file contains the message format of MQTT-SN 1.2, the variant of MQTT
+//! for sensor networks that runs over UDP. Each message starts with its
+//! length and type, which `encode_header` writes and `Message::decode`
+//! reads, and the topics a message refers to are mostly given by the 2 byte
+//! IDs the gateway assigns to their names.
+
+use crate::net::stream::SResult;
+use crate::net::stream::{decode_u16, decode_u8};
+use crate::net::stream::{encode_bytes, encode_u16, encode_u8};
+
+/// The port gateways commonly listen on.
+pub const MQTTSN_PORT: u16 = 1883;
+
+pub const PROTOCOL_ID: u8 = 0x01;
+
+/// The message types of the messages a client sends or receives.
+pub mod msg_types {
+    pub const CONNECT: u8 = 0x04;
+    pub const CONNACK: u8 = 0x05;
+    pub const REGISTER: u8 = 0x0a;
+    pub const REGACK: u8 = 0x0b;
+    pub const PUBLISH: u8 = 0x0c;
+    pub const PUBACK: u8 = 0x0d;
+    pub const SUBSCRIBE: u8 = 0x12;
+    pub const SUBACK: u8 = 0x13;
+    pub const PINGREQ: u8 = 0x16;
+    pub const PINGRESP: u8 = 0x17;
+    pub const DISCONNECT: u8 = 0x18;
+}
+
+/// The bits of the flags field.
+pub mod flags {
+    pub const DUP: u8 = 0x80;
+    pub const QOS_SHIFT: u8 = 5;
+    pub const QOS_MASK: u8 = 0x60;
+    pub const RETAIN: u8 = 0x10;
+    pub const CLEAN_SESSION: u8 = 0x04;
+    pub const TOPIC_TYPE_MASK: u8 = 0x03;
+    /// The topic ID was assigned by the gateway to a topic name.
+    pub const TOPIC_NORMAL: u8 = 0x00;
+    pub const TOPIC_PREDEFINED: u8 = 0x01;
+    /// The topic ID holds a topic name of two characters.
+    pub const TOPIC_SHORT: u8 = 0x02;
+}
+
+pub mod return_codes {
+    pub const ACCEPTED: u8 = 0x00;
+    pub const CONGESTION: u8 = 0x01;
+    pub const INVALID_TOPIC_ID: u8 = 0x02;
+    pub const NOT_SUPPORTED: u8 = 0x03;
+}
+
+/// Messages longer than this have a 3 byte length field.
+const MAX_SHORT_LEN: usize = 255;
+
+/// Encodes the length and type of a message with `body_len` bytes after its
+/// type.
+pub fn encode_header(buf: &mut [u8], msg_type: u8, body_len: usize) -> SResult {
+    let len = body_len + 2;
+    if len <= MAX_SHORT_LEN {
+        let off = enc_consume!(buf; encode_u8, len as u8);
+        let off = enc_consume!(buf, off; encode_u8, msg_type);
+        stream_done!(off);
+    }
+    // The 3 byte length field counts its 2 extra bytes as well
+    stream_cond!(len + 2 <= u16::max_value() as usize);
+    let off = enc_consume!(buf; encode_u8, 0x01);
+    let off = enc_consume!(buf, off; encode_u16, (len + 2) as u16);
+    let off = enc_consume!(buf, off; encode_u8, msg_type);
+    stream_done!(off);
+}
+
+pub fn encode_connect(buf: &mut [u8], duration: u16, client_id: &str) -> SResult {
+    let off = enc_consume!(buf; encode_header, msg_types::CONNECT, 4 + client_id.len());
+    let off = enc_consume!(buf, off; encode_u8, flags::CLEAN_SESSION);
+    let off = enc_consume!(buf, off; encode_u8, PROTOCOL_ID);
+    let off = enc_consume!(buf, off; encode_u16, duration);
+    let off = enc_consume!(buf, off; encode_bytes, client_id.as_bytes());
+    stream_done!(off);
+}
+
+pub fn encode_register(buf: &mut [u8], msg_id: u16, topic: &[u8]) -> SResult {
+    let off = enc_consume!(buf; encode_header, msg_types::REGISTER, 4 + topic.len());
+    let off = enc_consume!(buf, off; encode_u16, 0);
+    let off = enc_consume!(buf, off; encode_u16, msg_id);
+    let off = enc_consume!(buf, off; encode_bytes, topic);
+    stream_done!(off);
+}
+
+/// Encodes a REGACK or a PUBACK, which have the same fields.
+pub fn encode_ack(
+    buf: &mut [u8],
+    msg_type: u8,
+    topic_id: u16,
+    msg_id: u16,
+    return_code: u8,
+) -> SResult {
+    let off = enc_consume!(buf; encode_header, msg_type, 5);
+    let off = enc_consume!(buf, off; encode_u16, topic_id);
+    let off = enc_consume!(buf, off; encode_u16, msg_id);
+    let off = enc_consume!(buf, off; encode_u8, return_code);
+    stream_done!(off);
+}
+
+/// Encodes a PUBLISH, and returns the offset of its topic ID, which is
+/// filled in once the topic is registered.
+pub fn encode_publish(
+    buf: &mut [u8],
+    flags: u8,
+    topic_id: u16,
+    msg_id: u16,
+    data: &[u8],
+) -> SResult<usize> {
+    let off = enc_consume!(buf; encode_header, msg_types::PUBLISH, 5 + data.len());
+    let off = enc_consume!(buf, off; encode_u8, flags);
+    let topic_off = off;
+    let off = enc_consume!(buf, off; encode_u16, topic_id);
+    let off = enc_consume!(buf, off; encode_u16, msg_id);
+    let off = enc_consume!(buf, off; encode_bytes, data);
+    stream_done!(off, topic_off);
+}
+
+/// Encodes a SUBSCRIBE to a topic name, or to the topic ID in the two bytes
+/// of `topic` with `flags::TOPIC_SHORT`.
+pub fn encode_subscribe(buf: &mut [u8], flags: u8, msg_id: u16, topic: &[u8]) -> SResult {
+    let off = enc_consume!(buf; encode_header, msg_types::SUBSCRIBE, 3 + topic.len());
+    let off = enc_consume!(buf, off; encode_u8, flags);
+    let off = enc_consume!(buf, off; encode_u16, msg_id);
+    let off = enc_consume!(buf, off; encode_bytes, topic);
+    stream_done!(off);
+}
+
+/// Encodes a message with no fields, such as a PINGREQ or a DISCONNECT.
+pub fn encode_empty(buf: &mut [u8], msg_type: u8) -> SResult {
+    encode_header(buf, msg_type, 0)
+}
+
+/// A message received from the gateway.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Message<'a> {
+    ConnAck {
+        return_code: u8,
+    },
+    /// The gateway registered a topic, such as one that matches a wildcard
+    /// subscription.
+    Register {
+        topic_id: u16,
+        msg_id: u16,
+        topic: &'a [u8],
+    },
+    RegAck {
+        topic_id: u16,
+        msg_id: u16,
+        return_code: u8,
+    },
+    Publish {
+        flags: u8,
+        topic_id: u16,
+        msg_id: u16,
+        data: &'a [u8],
+    },
+    PubAck {
+        topic_id: u16,
+        msg_id: u16,
+        return_code: u8,
+    },
+    SubAck {
+        flags: u8,
+        topic_id: u16,
+        msg_id: u16,
+        return_code: u8,
+    },
+    PingReq,
+    PingResp,
+    Disconnect,
+}
+
+impl<'a> Message<'a> {
+    /// Decodes a message. Returns `None` if it is malformed, or of a type a
+    /// client does not receive.
+    pub fn decode(buf: &'a [u8]) -> Option<Message<'a>> {
+        let (off, len) = decode_u8(buf).done()?;
+        let (off, len) = if len == 0x01 {
+            let (ext, len) = decode_u16(&buf[off..]).done()?;
+            (off + ext, len as usize)
+        } else {
+            (off, len as usize)
+        };
+        if len < off + 1 || len > buf.len() {
+            return None;
+        }
+        let msg_type = buf[off];
+        let body = &buf[off + 1..len];
+        let u16_at = |at: usize| decode_u16(body.get(at..)?).done().map(|(_, v)| v);
+        let u8_at = |at: usize| body.get(at).copied();
+        let message = match msg_type {
+            msg_types::CONNACK => Message::ConnAck {
+                return_code: u8_at(0)?,
+            },
+            msg_types::REGISTER => Message::Register {
+                topic_id: u16_at(0)?,
+                msg_id: u16_at(2)?,
+                topic: body.get(4..)?,
+            },
+            msg_types::REGACK => Message::RegAck {
+                topic_id: u16_at(0)?,
+                msg_id: u16_at(2)?,
+                return_code: u8_at(4)?,
+            },
+            msg_types::PUBLISH => Message::Publish {
+                flags: u8_at(0)?,
+                topic_id: u16_at(1)?,
+                msg_id: u16_at(3)?,
+                data: body.get(5..)?,
+            },
+            msg_types::PUBACK => Message::PubAck {
+                topic_id: u16_at(0)?,
+                msg_id: u16_at(2)?,
+                return_code: u8_at(4)?,
+            },
+            msg_types::SUBACK => Message::SubAck {
+                flags: u8_at(0)?,
+                topic_id: u16_at(1)?,
+                msg_id: u16_at(3)?,
+                return_code: u8_at(5)?,
+            },
+            msg_types::PINGREQ => Message::PingReq,
+            msg_types::PINGRESP => Message::PingResp,
+            msg_types::DISCONNECT => Message::Disconnect,
+            _ => return None,
+        };
+        Some(message)
+    }
+}
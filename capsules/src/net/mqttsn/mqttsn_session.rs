@@ -0,0 +1,947 @@
+//! An MQTT-SN client for the kernel UDP stack.
+//!
+//! `MqttSnSession` connects to an MQTT-SN gateway, which forwards the
+//! messages the session publishes to an MQTT broker, and the messages of
+//! the topics it subscribes to back to it. PUBLISH and SUBSCRIBE messages
+//! are sent with quality of service 0 or 1.
+//!
+//! Topic names are registered with the gateway before the first PUBLISH to
+//! them, and the IDs the gateway assigns are cached in a cache of
+//! `TOPIC_CACHE_SIZE` topics, cleared on each connection, which replaces
+//! the oldest topic. Names of two characters are sent as short topic names
+//! instead, which need no registration.
+//!
+//! One request is outstanding at a time; requests return `EBUSY` while
+//! another one is. Requests are retransmitted every `RETRY_TICKS` ticks,
+//! `MAX_RETRIES` times, before the client is told that they failed. While
+//! connected, the session sends a PINGREQ after `keepalive_s` seconds
+//! without sending anything, and tells the client that it was disconnected
+//! if the gateway does not answer.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//! # use capsules::net::mqttsn::mqttsn_session::MqttSnSession;
+//!
+//! let mqttsn = static_init!(
+//!     MqttSnSession<'static, VirtualMuxAlarm<'static, sam4l::ast::Ast>>,
+//!     MqttSnSession::new(
+//!         udp_send,
+//!         udp_recv,
+//!         udp_port_table,
+//!         mqttsn_alarm,
+//!         LeasableBuffer::new(&mut MQTTSN_TX_BUF),
+//!         &mut MQTTSN_REQUEST_BUF,
+//!         net_cap,
+//!     )
+//! );
+//! udp_send.set_client(mqttsn);
+//! udp_recv.set_client(mqttsn);
+//! mqttsn_alarm.set_alarm_client(mqttsn);
+//! mqttsn.bind(MQTTSN_CLIENT_PORT);
+//! mqttsn.set_client(mqttsn_client);
+//! mqttsn.connect(gateway_addr, MQTTSN_PORT, "tock", 60);
+//! ```
+
+use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::mqttsn::mqttsn::{encode_ack, encode_connect, encode_empty, encode_publish};
+use crate::net::mqttsn::mqttsn::{encode_register, encode_subscribe, Message};
+use crate::net::mqttsn::mqttsn::{flags, msg_types, return_codes};
+use crate::net::network_capabilities::NetworkCapability;
+use crate::net::udp::udp_port_table::UdpPortManager;
+use crate::net::udp::udp_recv::{UDPReceiver, UDPRecvClient};
+use crate::net::udp::udp_send::{UDPSendClient, UDPSender};
+use core::cell::Cell;
+use kernel::common::cells::{MapCell, OptionalCell, TakeCell};
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::hil::time::{self, Alarm, Ticks};
+use kernel::ReturnCode;
+
+pub const TOPIC_CACHE_SIZE: usize = 8;
+pub const MAX_TOPIC_LEN: usize = 32;
+pub const MAX_CLIENT_ID_LEN: usize = 23;
+
+/// Requests are retransmitted, and the keepalive is counted, in ticks.
+pub const TICK_MS: u32 = 1000;
+
+pub const RETRY_TICKS: u8 = 10;
+pub const MAX_RETRIES: u8 = 3;
+
+/// Implemented by capsules that use an MQTT-SN session.
+pub trait MqttSnClient {
+    /// The session connected to the gateway, or could not connect.
+    fn connected(&self, result: ReturnCode);
+
+    /// The message passed to `publish()` was acknowledged by the gateway,
+    /// or sent if its quality of service is 0.
+    fn published(&self, result: ReturnCode);
+
+    /// The gateway accepted the subscription passed to `subscribe()`, with
+    /// the ID of its topic, which is 0 for topics with wildcards.
+    fn subscribed(&self, result: ReturnCode, topic_id: u16);
+
+    /// A message was received on a subscribed topic. `topic` is the name of
+    /// the topic, if it is known.
+    fn received(&self, topic_id: u16, topic: Option<&str>, payload: &[u8]);
+
+    /// The session disconnected, because `disconnect()` was called
+    /// (`SUCCESS`) or because the gateway disconnected it or stopped
+    /// answering (`FAIL`).
+    fn disconnected(&self, result: ReturnCode);
+}
+
+#[derive(Copy, Clone)]
+struct Topic {
+    bytes: [u8; MAX_TOPIC_LEN],
+    len: usize,
+}
+
+impl Topic {
+    fn new(name: &[u8]) -> Topic {
+        let mut bytes = [0; MAX_TOPIC_LEN];
+        bytes[..name.len()].copy_from_slice(name);
+        Topic {
+            bytes: bytes,
+            len: name.len(),
+        }
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+
+    fn as_str(&self) -> &str {
+        core::str::from_utf8(self.as_bytes()).unwrap_or("")
+    }
+
+    fn has_wildcard(&self) -> bool {
+        self.as_bytes().iter().any(|&b| b == b'+' || b == b'#')
+    }
+}
+
+#[derive(Copy, Clone)]
+struct CachedTopic {
+    topic: Topic,
+    id: u16,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum State {
+    Disconnected,
+    Connecting,
+    Connected,
+    Disconnecting,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum RequestKind {
+    Connect,
+    Publish,
+    Subscribe,
+    Disconnect,
+}
+
+/// The request being sent, whose message is in `request_buf`.
+#[derive(Copy, Clone)]
+struct Request {
+    kind: RequestKind,
+    /// The ID the gateway acknowledges, which is 0 for PUBLISHes with
+    /// quality of service 0, as they are not acknowledged.
+    msg_id: u16,
+    len: usize,
+    /// The offset of the topic ID of a PUBLISH.
+    topic_off: usize,
+    /// Set while the topic of a PUBLISH is being registered, to the ID of
+    /// the REGISTER.
+    register: Option<u16>,
+    retries: u8,
+    timer: u8,
+    send_pending: bool,
+}
+
+/// A reply to a message of the gateway.
+#[derive(Copy, Clone)]
+enum Reply {
+    RegAck { topic_id: u16, msg_id: u16 },
+    PubAck { topic_id: u16, msg_id: u16 },
+    PingResp,
+}
+
+#[derive(Copy, Clone)]
+struct Ping {
+    retries: u8,
+    timer: u8,
+    send_pending: bool,
+}
+
+pub struct MqttSnSession<'a, A: Alarm<'a>> {
+    udp_sender: &'a dyn UDPSender<'a>,
+    udp_receiver: &'a UDPReceiver<'a>,
+    port_table: &'static UdpPortManager,
+    alarm: &'a A,
+    net_cap: &'static NetworkCapability,
+    tx_buf: MapCell<LeasableBuffer<'static, u8>>,
+    /// The longest message `tx_buf` holds.
+    max_len: usize,
+    request_buf: TakeCell<'static, [u8]>,
+    client: OptionalCell<&'a dyn MqttSnClient>,
+    state: Cell<State>,
+    gateway: Cell<Option<(IPAddr, u16)>>,
+    keepalive_s: Cell<u16>,
+    /// Seconds since the last message was sent.
+    idle: Cell<u32>,
+    request: Cell<Option<Request>>,
+    /// Whether the datagram being sent is the message of the request.
+    sending_request: Cell<bool>,
+    /// The topic of the request.
+    topic: Cell<Topic>,
+    reply: Cell<Option<Reply>>,
+    ping: Cell<Option<Ping>>,
+    cache: [Cell<Option<CachedTopic>>; TOPIC_CACHE_SIZE],
+    /// The entry replaced when the cache is full.
+    next_victim: Cell<usize>,
+    next_msg_id: Cell<u16>,
+    /// When the current tick started.
+    tick_start: Cell<A::Ticks>,
+}
+
+impl<'a, A: Alarm<'a>> MqttSnSession<'a, A> {
+    pub fn new(
+        udp_sender: &'a dyn UDPSender<'a>,
+        udp_receiver: &'a UDPReceiver<'a>,
+        port_table: &'static UdpPortManager,
+        alarm: &'a A,
+        tx_buf: LeasableBuffer<'static, u8>,
+        request_buf: &'static mut [u8],
+        net_cap: &'static NetworkCapability,
+    ) -> MqttSnSession<'a, A> {
+        MqttSnSession {
+            udp_sender: udp_sender,
+            udp_receiver: udp_receiver,
+            port_table: port_table,
+            alarm: alarm,
+            net_cap: net_cap,
+            max_len: tx_buf.len(),
+            tx_buf: MapCell::new(tx_buf),
+            request_buf: TakeCell::new(request_buf),
+            client: OptionalCell::empty(),
+            state: Cell::new(State::Disconnected),
+            gateway: Cell::new(None),
+            keepalive_s: Cell::new(0),
+            idle: Cell::new(0),
+            request: Cell::new(None),
+            sending_request: Cell::new(false),
+            topic: Cell::new(Topic::new(&[])),
+            reply: Cell::new(None),
+            ping: Cell::new(None),
+            cache: [
+                Cell::new(None),
+                Cell::new(None),
+                Cell::new(None),
+                Cell::new(None),
+                Cell::new(None),
+                Cell::new(None),
+                Cell::new(None),
+                Cell::new(None),
+            ],
+            next_victim: Cell::new(0),
+            next_msg_id: Cell::new(1),
+            tick_start: Cell::new(A::Ticks::from(0)),
+        }
+    }
+
+    pub fn set_client(&self, client: &'a dyn MqttSnClient) {
+        self.client.set(client);
+    }
+
+    /// Binds the session to `port`, which messages are sent from.
+    pub fn bind(&self, port: u16) -> ReturnCode {
+        if self.udp_sender.is_bound() {
+            return ReturnCode::EALREADY;
+        }
+        let socket = match self.port_table.create_socket() {
+            Ok(socket) => socket,
+            Err(rcode) => return rcode,
+        };
+        self.port_table.set_owner(&socket, "mqttsn");
+        match self.port_table.bind(socket, port, self.net_cap) {
+            Ok((send_binding, recv_binding)) => {
+                self.udp_sender.set_binding(send_binding);
+                self.udp_receiver.set_binding(recv_binding);
+                ReturnCode::SUCCESS
+            }
+            Err(_socket) => ReturnCode::EBUSY,
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.state.get() == State::Connected
+    }
+
+    /// Connects to the gateway at `gateway` and `port` as `client_id`,
+    /// asking it to disconnect the session after `keepalive_s` seconds
+    /// without a message, or never if 0. Returns `EALREADY` if the session
+    /// is not disconnected, and `ESIZE` if the client ID is empty or longer
+    /// than `MAX_CLIENT_ID_LEN`.
+    pub fn connect(
+        &self,
+        gateway: IPAddr,
+        port: u16,
+        client_id: &str,
+        keepalive_s: u16,
+    ) -> ReturnCode {
+        if self.state.get() != State::Disconnected {
+            return ReturnCode::EALREADY;
+        }
+        if client_id.is_empty() || client_id.len() > MAX_CLIENT_ID_LEN {
+            return ReturnCode::ESIZE;
+        }
+        let len = self.encode_request(|buf| {
+            encode_connect(buf, keepalive_s, client_id)
+                .done()
+                .map(|(len, _)| (len, 0))
+        });
+        let (len, _) = match len {
+            Some(len) => len,
+            None => return ReturnCode::ESIZE,
+        };
+
+        // Sessions are clean, so the gateway forgets the registered topics
+        self.flush();
+        self.reply.set(None);
+        self.ping.set(None);
+        self.gateway.set(Some((gateway, port)));
+        self.keepalive_s.set(keepalive_s);
+        self.state.set(State::Connecting);
+        self.start_request(RequestKind::Connect, 0, len, 0, None);
+        ReturnCode::SUCCESS
+    }
+
+    /// Publishes `payload` to `topic` with quality of service `qos`, 0 or
+    /// 1, registering the topic first if its ID is not cached. Returns
+    /// `EOFF` if the session is not connected, `EBUSY` while another
+    /// request is outstanding and `ESIZE` if the topic is empty or longer
+    /// than `MAX_TOPIC_LEN`, or the message does not fit in a datagram.
+    pub fn publish(&self, topic: &str, payload: &[u8], qos: u8, retain: bool) -> ReturnCode {
+        let check = self.check_request(topic, qos);
+        if check != ReturnCode::SUCCESS {
+            return check;
+        }
+        let (topic_type, topic_id) = if topic.len() == 2 {
+            (flags::TOPIC_SHORT, short_topic_id(topic.as_bytes()))
+        } else {
+            (
+                flags::TOPIC_NORMAL,
+                self.lookup(topic.as_bytes()).unwrap_or(0),
+            )
+        };
+        let mut msg_flags = qos << flags::QOS_SHIFT | topic_type;
+        if retain {
+            msg_flags |= flags::RETAIN;
+        }
+        let msg_id = if qos == 0 { 0 } else { self.next_msg_id() };
+        let len = self
+            .encode_request(|buf| encode_publish(buf, msg_flags, topic_id, msg_id, payload).done());
+        let (len, topic_off) = match len {
+            Some(len) => len,
+            None => return ReturnCode::ESIZE,
+        };
+
+        let register = if topic_type == flags::TOPIC_NORMAL && topic_id == 0 {
+            Some(self.next_msg_id())
+        } else {
+            None
+        };
+        self.topic.set(Topic::new(topic.as_bytes()));
+        self.start_request(RequestKind::Publish, msg_id, len, topic_off, register);
+        ReturnCode::SUCCESS
+    }
+
+    /// Subscribes to `topic`, which may have wildcards, with the highest
+    /// quality of service `qos` the gateway should forward its messages
+    /// with. Returns the same errors as `publish()`.
+    pub fn subscribe(&self, topic: &str, qos: u8) -> ReturnCode {
+        let check = self.check_request(topic, qos);
+        if check != ReturnCode::SUCCESS {
+            return check;
+        }
+        let topic_type = if topic.len() == 2 {
+            flags::TOPIC_SHORT
+        } else {
+            flags::TOPIC_NORMAL
+        };
+        let msg_id = self.next_msg_id();
+        let len = self.encode_request(|buf| {
+            encode_subscribe(
+                buf,
+                qos << flags::QOS_SHIFT | topic_type,
+                msg_id,
+                topic.as_bytes(),
+            )
+            .done()
+            .map(|(len, _)| (len, 0))
+        });
+        let (len, _) = match len {
+            Some(len) => len,
+            None => return ReturnCode::ESIZE,
+        };
+
+        self.topic.set(Topic::new(topic.as_bytes()));
+        self.start_request(RequestKind::Subscribe, msg_id, len, 0, None);
+        ReturnCode::SUCCESS
+    }
+
+    /// Disconnects from the gateway. Returns `EOFF` if the session is not
+    /// connected, and `EBUSY` while another request is outstanding.
+    pub fn disconnect(&self) -> ReturnCode {
+        if self.state.get() != State::Connected {
+            return ReturnCode::EOFF;
+        }
+        if self.request.get().is_some() {
+            return ReturnCode::EBUSY;
+        }
+        let len = self.encode_request(|buf| {
+            encode_empty(buf, msg_types::DISCONNECT)
+                .done()
+                .map(|(len, _)| (len, 0))
+        });
+        let (len, _) = match len {
+            Some(len) => len,
+            None => return ReturnCode::ESIZE,
+        };
+        self.ping.set(None);
+        self.state.set(State::Disconnecting);
+        self.start_request(RequestKind::Disconnect, 0, len, 0, None);
+        ReturnCode::SUCCESS
+    }
+
+    /// Returns the cached ID of `topic`.
+    fn lookup(&self, topic: &[u8]) -> Option<u16> {
+        self.cache
+            .iter()
+            .filter_map(|entry| entry.get())
+            .find(|entry| entry.topic.as_bytes() == topic)
+            .map(|entry| entry.id)
+    }
+
+    /// Returns the cached topic with ID `topic_id`.
+    fn lookup_id(&self, topic_id: u16) -> Option<Topic> {
+        self.cache
+            .iter()
+            .filter_map(|entry| entry.get())
+            .find(|entry| entry.id == topic_id)
+            .map(|entry| entry.topic)
+    }
+
+    fn cache_insert(&self, topic: Topic, id: u16) {
+        let entry = CachedTopic {
+            topic: topic,
+            id: id,
+        };
+        let slot = self
+            .cache
+            .iter()
+            .find(|slot| {
+                slot.get().map_or(false, |cached| {
+                    cached.topic.as_bytes() == topic.as_bytes() || cached.id == id
+                })
+            })
+            .or_else(|| self.cache.iter().find(|slot| slot.get().is_none()));
+        match slot {
+            Some(slot) => slot.set(Some(entry)),
+            None => {
+                let victim = self.next_victim.get();
+                self.cache[victim].set(Some(entry));
+                self.next_victim.set((victim + 1) % TOPIC_CACHE_SIZE);
+            }
+        }
+    }
+
+    fn cache_remove(&self, id: u16) {
+        for slot in self.cache.iter() {
+            if slot.get().map_or(false, |cached| cached.id == id) {
+                slot.set(None);
+            }
+        }
+    }
+
+    /// Removes every cached topic.
+    fn flush(&self) {
+        for slot in self.cache.iter() {
+            slot.set(None);
+        }
+        self.next_victim.set(0);
+    }
+
+    fn next_msg_id(&self) -> u16 {
+        // Message IDs are never 0
+        let id = self.next_msg_id.get();
+        self.next_msg_id
+            .set(if id == u16::max_value() { 1 } else { id + 1 });
+        id
+    }
+
+    fn check_request(&self, topic: &str, qos: u8) -> ReturnCode {
+        if self.state.get() != State::Connected {
+            ReturnCode::EOFF
+        } else if self.request.get().is_some() {
+            ReturnCode::EBUSY
+        } else if qos > 1 {
+            ReturnCode::EINVAL
+        } else if topic.is_empty() || topic.len() > MAX_TOPIC_LEN {
+            ReturnCode::ESIZE
+        } else {
+            ReturnCode::SUCCESS
+        }
+    }
+
+    /// Encodes the message of a request with `encode`, which returns its
+    /// length and the offset of its topic ID, if it fits in a datagram.
+    fn encode_request<F>(&self, encode: F) -> Option<(usize, usize)>
+    where
+        F: FnOnce(&mut [u8]) -> Option<(usize, usize)>,
+    {
+        self.request_buf
+            .map_or(None, |buf| encode(buf))
+            .filter(|&(len, _)| len <= self.max_len)
+    }
+
+    fn start_request(
+        &self,
+        kind: RequestKind,
+        msg_id: u16,
+        len: usize,
+        topic_off: usize,
+        register: Option<u16>,
+    ) {
+        self.request.set(Some(Request {
+            kind: kind,
+            msg_id: msg_id,
+            len: len,
+            topic_off: topic_off,
+            register: register,
+            retries: 0,
+            timer: RETRY_TICKS,
+            send_pending: true,
+        }));
+        self.start_timer();
+        self.send_pending();
+    }
+
+    fn start_timer(&self) {
+        if !self.alarm.is_armed() {
+            self.tick_start.set(self.alarm.now());
+            self.alarm
+                .set_alarm(self.alarm.now(), A::ticks_from_ms(TICK_MS));
+        }
+    }
+
+    /// Sends the next pending message, replies first, if the buffer is free.
+    fn send_pending(&self) {
+        let (dest, dst_port) = match self.gateway.get() {
+            Some(gateway) => gateway,
+            None => return,
+        };
+        let mut dgram = match self.tx_buf.take() {
+            Some(dgram) => dgram,
+            None => return,
+        };
+
+        let len = if let Some(reply) = self.reply.take() {
+            match reply {
+                Reply::RegAck { topic_id, msg_id } => encode_ack(
+                    &mut dgram[..],
+                    msg_types::REGACK,
+                    topic_id,
+                    msg_id,
+                    return_codes::ACCEPTED,
+                ),
+                Reply::PubAck { topic_id, msg_id } => encode_ack(
+                    &mut dgram[..],
+                    msg_types::PUBACK,
+                    topic_id,
+                    msg_id,
+                    return_codes::ACCEPTED,
+                ),
+                Reply::PingResp => encode_empty(&mut dgram[..], msg_types::PINGRESP),
+            }
+            .done()
+            .map(|(len, _)| len)
+        } else if let Some(ping) = self.ping.get().filter(|ping| ping.send_pending) {
+            self.ping.set(Some(Ping {
+                send_pending: false,
+                ..ping
+            }));
+            encode_empty(&mut dgram[..], msg_types::PINGREQ)
+                .done()
+                .map(|(len, _)| len)
+        } else if let Some(request) = self.request.get().filter(|request| request.send_pending) {
+            self.request.set(Some(Request {
+                send_pending: false,
+                ..request
+            }));
+            match request.register {
+                Some(msg_id) => {
+                    encode_register(&mut dgram[..], msg_id, self.topic.get().as_bytes())
+                        .done()
+                        .map(|(len, _)| len)
+                }
+                None => self.request_buf.map_or(None, |buf| {
+                    // The request was encoded to fit
+                    dgram[..request.len].copy_from_slice(&buf[..request.len]);
+                    self.sending_request.set(true);
+                    Some(request.len)
+                }),
+            }
+        } else {
+            self.tx_buf.replace(dgram);
+            return;
+        };
+
+        match len {
+            Some(len) => {
+                dgram.slice(..len);
+                self.idle.set(0);
+                if let Err(mut dgram) = self.udp_sender.send_to(dest, dst_port, dgram, self.net_cap)
+                {
+                    // Retransmitted like a lost message
+                    self.sending_request.set(false);
+                    dgram.reset();
+                    self.tx_buf.replace(dgram);
+                }
+            }
+            None => {
+                self.tx_buf.replace(dgram);
+            }
+        }
+    }
+
+    /// Ends the request, telling the client about `result`.
+    fn finish(&self, result: ReturnCode, topic_id: u16) {
+        let request = match self.request.take() {
+            Some(request) => request,
+            None => return,
+        };
+        match request.kind {
+            RequestKind::Connect => {
+                if result != ReturnCode::SUCCESS {
+                    self.state.set(State::Disconnected);
+                }
+                self.client.map(|client| client.connected(result));
+            }
+            RequestKind::Publish => {
+                self.client.map(|client| client.published(result));
+            }
+            RequestKind::Subscribe => {
+                self.client
+                    .map(|client| client.subscribed(result, topic_id));
+            }
+            RequestKind::Disconnect => {
+                self.state.set(State::Disconnected);
+                self.client.map(|client| client.disconnected(result));
+            }
+        }
+    }
+
+    /// Ends the session after the gateway disconnected it or stopped
+    /// answering.
+    fn lost(&self) {
+        self.reply.set(None);
+        self.ping.set(None);
+        if self.state.get() == State::Connecting {
+            self.finish(ReturnCode::FAIL, 0);
+            return;
+        }
+        if self.request.get().map_or(false, |request| {
+            request.kind == RequestKind::Publish || request.kind == RequestKind::Subscribe
+        }) {
+            self.finish(ReturnCode::FAIL, 0);
+        }
+        self.request.set(None);
+        self.state.set(State::Disconnected);
+        self.client
+            .map(|client| client.disconnected(ReturnCode::FAIL));
+    }
+
+    /// Counts down the keepalive and the request by a tick.
+    fn tick(&self) {
+        if self.state.get() == State::Connected && self.keepalive_s.get() != 0 {
+            self.idle.set(self.idle.get() + 1);
+            match self.ping.get() {
+                Some(mut ping) => {
+                    ping.timer -= 1;
+                    if ping.timer == 0 {
+                        if ping.retries >= MAX_RETRIES {
+                            self.lost();
+                            return;
+                        }
+                        ping.retries += 1;
+                        ping.timer = RETRY_TICKS;
+                        ping.send_pending = true;
+                    }
+                    self.ping.set(Some(ping));
+                }
+                None => {
+                    if self.idle.get() >= self.keepalive_s.get() as u32 {
+                        self.ping.set(Some(Ping {
+                            retries: 0,
+                            timer: RETRY_TICKS,
+                            send_pending: true,
+                        }));
+                    }
+                }
+            }
+        }
+
+        if let Some(mut request) = self.request.get() {
+            request.timer -= 1;
+            if request.timer == 0 {
+                if request.retries >= MAX_RETRIES {
+                    // The session is disconnected even if the gateway does
+                    // not answer the DISCONNECT
+                    self.finish(
+                        if request.kind == RequestKind::Disconnect {
+                            ReturnCode::SUCCESS
+                        } else {
+                            ReturnCode::FAIL
+                        },
+                        0,
+                    );
+                    return;
+                }
+                request.retries += 1;
+                request.timer = RETRY_TICKS;
+                request.send_pending = true;
+                if request.kind == RequestKind::Publish
+                    && request.msg_id != 0
+                    && request.register.is_none()
+                {
+                    self.request_buf.map(|buf| {
+                        buf[request.topic_off - 1] |= flags::DUP;
+                    });
+                }
+            }
+            self.request.set(Some(request));
+        }
+    }
+
+    fn receive_message(&self, message: Message) {
+        let request = self.request.get();
+        let awaiting = |kind: RequestKind, msg_id: u16| {
+            request.map_or(false, |request| {
+                request.kind == kind && request.msg_id == msg_id && request.register.is_none()
+            })
+        };
+        match message {
+            Message::ConnAck { return_code } => {
+                if awaiting(RequestKind::Connect, 0) {
+                    if return_code == return_codes::ACCEPTED {
+                        self.state.set(State::Connected);
+                        self.idle.set(0);
+                    }
+                    self.finish(result_from_return_code(return_code), 0);
+                }
+            }
+            Message::Register {
+                topic_id,
+                msg_id,
+                topic,
+            } => {
+                if topic.len() <= MAX_TOPIC_LEN {
+                    self.cache_insert(Topic::new(topic), topic_id);
+                    self.reply.set(Some(Reply::RegAck {
+                        topic_id: topic_id,
+                        msg_id: msg_id,
+                    }));
+                }
+            }
+            Message::RegAck {
+                topic_id,
+                msg_id,
+                return_code,
+            } => {
+                let mut request = match request {
+                    Some(request) if request.register == Some(msg_id) => request,
+                    _ => return,
+                };
+                if return_code != return_codes::ACCEPTED {
+                    self.finish(result_from_return_code(return_code), 0);
+                    return;
+                }
+                self.cache_insert(self.topic.get(), topic_id);
+                self.request_buf.map(|buf| {
+                    buf[request.topic_off] = (topic_id >> 8) as u8;
+                    buf[request.topic_off + 1] = topic_id as u8;
+                });
+                request.register = None;
+                request.timer = RETRY_TICKS;
+                request.send_pending = true;
+                self.request.set(Some(request));
+            }
+            Message::Publish {
+                flags: msg_flags,
+                topic_id,
+                msg_id,
+                data,
+            } => {
+                let qos = (msg_flags & flags::QOS_MASK) >> flags::QOS_SHIFT;
+                if qos > 1 {
+                    return;
+                }
+                let topic = match msg_flags & flags::TOPIC_TYPE_MASK {
+                    flags::TOPIC_NORMAL => self.lookup_id(topic_id),
+                    flags::TOPIC_SHORT => {
+                        Some(Topic::new(&[(topic_id >> 8) as u8, topic_id as u8]))
+                    }
+                    _ => None,
+                };
+                self.client.map(|client| {
+                    client.received(topic_id, topic.as_ref().map(Topic::as_str), data)
+                });
+                if qos == 1 {
+                    self.reply.set(Some(Reply::PubAck {
+                        topic_id: topic_id,
+                        msg_id: msg_id,
+                    }));
+                }
+            }
+            Message::PubAck {
+                topic_id,
+                msg_id,
+                return_code,
+            } => {
+                if !awaiting(RequestKind::Publish, msg_id) || msg_id == 0 {
+                    return;
+                }
+                let mut request = match request {
+                    Some(request) => request,
+                    None => return,
+                };
+                let registered = self.topic.get().len != 2;
+                if return_code == return_codes::INVALID_TOPIC_ID
+                    && registered
+                    && request.retries < MAX_RETRIES
+                {
+                    // The gateway forgot the topic, so it is registered
+                    // again
+                    self.cache_remove(topic_id);
+                    request.retries += 1;
+                    request.register = Some(self.next_msg_id());
+                    request.timer = RETRY_TICKS;
+                    request.send_pending = true;
+                    self.request.set(Some(request));
+                    return;
+                }
+                self.finish(result_from_return_code(return_code), 0);
+            }
+            Message::SubAck {
+                topic_id,
+                msg_id,
+                return_code,
+                ..
+            } => {
+                if !awaiting(RequestKind::Subscribe, msg_id) {
+                    return;
+                }
+                let topic = self.topic.get();
+                if return_code != return_codes::ACCEPTED {
+                    self.finish(result_from_return_code(return_code), 0);
+                    return;
+                }
+                let topic_id = if topic.len == 2 {
+                    short_topic_id(topic.as_bytes())
+                } else {
+                    if topic_id != 0 && !topic.has_wildcard() {
+                        self.cache_insert(topic, topic_id);
+                    }
+                    topic_id
+                };
+                self.finish(ReturnCode::SUCCESS, topic_id);
+            }
+            Message::PingReq => self.reply.set(Some(Reply::PingResp)),
+            Message::PingResp => self.ping.set(None),
+            Message::Disconnect => {
+                if awaiting(RequestKind::Disconnect, 0) {
+                    self.finish(ReturnCode::SUCCESS, 0);
+                } else {
+                    self.lost();
+                }
+            }
+        }
+    }
+}
+
+/// Returns the topic ID of a short topic name, which is the name itself.
+fn short_topic_id(topic: &[u8]) -> u16 {
+    (topic[0] as u16) << 8 | topic[1] as u16
+}
+
+fn result_from_return_code(return_code: u8) -> ReturnCode {
+    match return_code {
+        return_codes::ACCEPTED => ReturnCode::SUCCESS,
+        return_codes::CONGESTION => ReturnCode::EBUSY,
+        return_codes::INVALID_TOPIC_ID => ReturnCode::EINVAL,
+        return_codes::NOT_SUPPORTED => ReturnCode::ENOSUPPORT,
+        _ => ReturnCode::FAIL,
+    }
+}
+
+impl<'a, A: Alarm<'a>> time::AlarmClient for MqttSnSession<'a, A> {
+    fn alarm(&self) {
+        let tick = A::ticks_from_ms(TICK_MS);
+        let now = self.alarm.now();
+        while now.wrapping_sub(self.tick_start.get()) >= tick {
+            self.tick_start
+                .set(self.tick_start.get().wrapping_add(tick));
+            self.tick();
+        }
+        self.send_pending();
+
+        if self.state.get() != State::Disconnected {
+            self.alarm.set_alarm(self.tick_start.get(), tick);
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> UDPSendClient for MqttSnSession<'a, A> {
+    fn send_done(&self, result: ReturnCode, mut dgram: LeasableBuffer<'static, u8>) {
+        dgram.reset();
+        self.tx_buf.replace(dgram);
+        if self.sending_request.take() {
+            // PUBLISHes with quality of service 0 are done once sent
+            let unacknowledged = self.request.get().map_or(false, |request| {
+                request.kind == RequestKind::Publish
+                    && request.msg_id == 0
+                    && request.register.is_none()
+            });
+            if unacknowledged && result == ReturnCode::SUCCESS {
+                self.finish(result, 0);
+            }
+        }
+        self.send_pending();
+    }
+}
+
+impl<'a, A: Alarm<'a>> UDPRecvClient for MqttSnSession<'a, A> {
+    fn receive(
+        &self,
+        src_addr: IPAddr,
+        _dst_addr: IPAddr,
+        src_port: u16,
+        _dst_port: u16,
+        payload: &[u8],
+        _timestamp: Option<u32>,
+    ) {
+        if self.state.get() == State::Disconnected
+            || self.gateway.get() != Some((src_addr, src_port))
+        {
+            return;
+        }
+        if let Some(message) = Message::decode(payload) {
+            self.receive_message(message);
+            self.send_pending();
+        }
+    }
+}
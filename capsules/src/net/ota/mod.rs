@@ -0,0 +1,2 @@
+pub mod ota;
+pub mod ota_updater;
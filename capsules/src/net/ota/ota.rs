@@ -0,0 +1,164 @@
+//! This file contains the formats of over-the-air updates: the signature
+//! that follows the image in an update, and the progress record that an
+//! `OtaUpdater` keeps in nonvolatile storage so that it can resume a
+//! download after a reset.
+//!
+//! An update is a process image, followed by its version as a big-endian
+//! `u32` of `VERSION_LEN` bytes and by a signature of
+//! `ImageVerifier::signature_len()` bytes over the SHA-256 hash of the image
+//! and version. The updater refuses versions lower than its version counter,
+//! so that an old image with known flaws cannot be installed again. The
+//! progress record is `PROGRESS_LEN` bytes long:
+//!
+//! ```text
+//! 0       4       8        12     14    15         16       32       64
+//! +-------+-------+--------+------+-----+----------+--------+--------+
+//! | magic | check | offset | port | szx | path_len | server | path   |
+//! +-------+-------+--------+------+-----+----------+--------+--------+
+//! ```
+//!
+//! `check` is the start of the SHA-256 hash of the bytes after it, so that a
+//! record that was only partly written when power was lost is ignored.
+
+use crate::ecdsa_p256;
+use crate::net::dtls::sha256::{hmac_sha256, Sha256, SHA256_LEN};
+use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::stream::SResult;
+use crate::net::stream::{decode_bytes, decode_u16, decode_u32, decode_u8};
+use crate::net::stream::{encode_bytes, encode_u16, encode_u32, encode_u8};
+use kernel::common::constant_time;
+
+pub const VERSION_LEN: usize = 4;
+pub const PROGRESS_LEN: usize = 64;
+pub const MAX_PATH_LEN: usize = 32;
+
+const PROGRESS_MAGIC: u32 = 0x4f54_4131;
+
+/// Checks the signature of a downloaded image.
+pub trait ImageVerifier {
+    /// The length of the signature that follows the image in an update.
+    fn signature_len(&self) -> usize;
+
+    /// Returns whether `signature` is valid for an image with the SHA-256
+    /// hash `digest`.
+    fn verify(&self, digest: &[u8; SHA256_LEN], signature: &[u8]) -> bool;
+}
+
+/// Verifies updates signed with an HMAC-SHA256 of the hash of the image,
+/// under a key the device shares with the update server.
+pub struct HmacImageVerifier {
+    key: [u8; 32],
+}
+
+impl HmacImageVerifier {
+    pub fn new(key: [u8; 32]) -> HmacImageVerifier {
+        HmacImageVerifier { key: key }
+    }
+}
+
+impl ImageVerifier for HmacImageVerifier {
+    fn signature_len(&self) -> usize {
+        SHA256_LEN
+    }
+
+    fn verify(&self, digest: &[u8; SHA256_LEN], signature: &[u8]) -> bool {
+        constant_time::eq(&hmac_sha256(&self.key, &[digest]), signature)
+    }
+}
+
+/// Verifies updates signed with ECDSA over NIST P-256 of the hash of the
+/// image, with the software verifier.
+pub struct EcdsaImageVerifier {
+    public_key: [u8; ecdsa_p256::PUBLIC_KEY_LEN],
+}
+
+impl EcdsaImageVerifier {
+    pub fn new(public_key: [u8; ecdsa_p256::PUBLIC_KEY_LEN]) -> EcdsaImageVerifier {
+        EcdsaImageVerifier {
+            public_key: public_key,
+        }
+    }
+}
+
+impl ImageVerifier for EcdsaImageVerifier {
+    fn signature_len(&self) -> usize {
+        ecdsa_p256::SIGNATURE_LEN
+    }
+
+    fn verify(&self, digest: &[u8; SHA256_LEN], signature: &[u8]) -> bool {
+        let mut sig = [0; ecdsa_p256::SIGNATURE_LEN];
+        if signature.len() != sig.len() {
+            return false;
+        }
+        sig.copy_from_slice(signature);
+        ecdsa_p256::verify_signature(&self.public_key, digest, &sig)
+    }
+}
+
+/// How far a download got, and where it comes from.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Progress {
+    pub server: IPAddr,
+    pub port: u16,
+    pub path: [u8; MAX_PATH_LEN],
+    pub path_len: usize,
+    /// The size exponent of the blocks, as in `CoAPBlock`.
+    pub szx: u8,
+    /// The length of the update written so far.
+    pub offset: usize,
+}
+
+impl Progress {
+    fn check(buf: &[u8]) -> u32 {
+        let mut hash = Sha256::new();
+        hash.update(&buf[8..PROGRESS_LEN]);
+        let hash = hash.finish();
+        u32::from_be_bytes([hash[0], hash[1], hash[2], hash[3]])
+    }
+
+    pub fn encode(&self, buf: &mut [u8]) -> SResult {
+        stream_len_cond!(buf, PROGRESS_LEN);
+        for byte in buf[..PROGRESS_LEN].iter_mut() {
+            *byte = 0;
+        }
+        enc_consume!(buf, 8; encode_u32, self.offset as u32);
+        enc_consume!(buf, 12; encode_u16, self.port);
+        enc_consume!(buf, 14; encode_u8, self.szx);
+        enc_consume!(buf, 15; encode_u8, self.path_len as u8);
+        enc_consume!(buf, 16; encode_bytes, &self.server.0);
+        enc_consume!(buf, 32; encode_bytes, &self.path[..self.path_len]);
+        let check = Progress::check(buf);
+        enc_consume!(buf; encode_u32, PROGRESS_MAGIC);
+        enc_consume!(buf, 4; encode_u32, check);
+        stream_done!(PROGRESS_LEN);
+    }
+
+    /// Decodes a record. Returns an error for a record that is missing,
+    /// damaged or cleared.
+    pub fn decode(buf: &[u8]) -> SResult<Progress> {
+        stream_len_cond!(buf, PROGRESS_LEN);
+        let (_, magic) = dec_try!(buf; decode_u32);
+        let (_, check) = dec_try!(buf, 4; decode_u32);
+        stream_cond!(magic == PROGRESS_MAGIC && check == Progress::check(buf), ());
+        let (_, offset) = dec_try!(buf, 8; decode_u32);
+        let (_, port) = dec_try!(buf, 12; decode_u16);
+        let (_, szx) = dec_try!(buf, 14; decode_u8);
+        let (_, path_len) = dec_try!(buf, 15; decode_u8);
+        stream_cond!(path_len as usize <= MAX_PATH_LEN, ());
+        let mut server = IPAddr([0; 16]);
+        dec_consume!(buf, 16; decode_bytes, &mut server.0);
+        let mut path = [0; MAX_PATH_LEN];
+        dec_consume!(buf, 32; decode_bytes, &mut path);
+        stream_done!(
+            PROGRESS_LEN,
+            Progress {
+                server: server,
+                port: port,
+                path: path,
+                path_len: path_len as usize,
+                szx: szx,
+                offset: offset as usize,
+            }
+        );
+    }
+}
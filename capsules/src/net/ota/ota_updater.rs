@@ -0,0 +1,592 @@
+//! Over-the-air updates of apps over CoAP.
+//!
+//! `OtaUpdater` downloads an update from a CoAP server a block at a time
+//! (RFC 7959), as the client of a `CoAPEndpoint`, and writes it to a staging
+//! region of a `NonvolatileStorage`. Each block is only requested once the
+//! one before it is written, so the download goes at the pace of the flash.
+//! Once the last block is written, the updater reads the image back from the
+//! staging region and checks the signature that follows it (see `ota.rs`)
+//! with an `ImageVerifier`. A verified image is then loaded with the
+//! `ProcessLoader` set with `set_loader()`, whose flash region should hold
+//! the staging region. Boards that boot updates with a bootloader instead
+//! leave the loader unset, and arrange the boot once the `OtaUpdateClient`
+//! is told that the update is done.
+//!
+//! With a version counter set with `set_version_counter()`, the version of a
+//! verified image must be at least the value of the counter, which is
+//! advanced to it before the image is loaded. A downgrade to an older,
+//! validly signed image is then refused even after a reset.
+//!
+//! Every `SAVE_INTERVAL` blocks, the updater saves how far the download got
+//! in a progress record at `progress_address`. After a reset, `resume()`
+//! continues an interrupted download from the last saved block. Downloads
+//! that fail because the server stops answering keep their record, so they
+//! can be resumed later, while finished, rejected and cancelled downloads
+//! clear it.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//! # use capsules::net::ota::ota::HmacImageVerifier;
+//! # use capsules::net::ota::ota_updater::OtaUpdater;
+//!
+//! let verifier = static_init!(HmacImageVerifier, HmacImageVerifier::new(OTA_KEY));
+//! let ota = static_init!(
+//!     OtaUpdater<'static, VirtualMuxAlarm<'static, nrf52840::rtc::Rtc>>,
+//!     OtaUpdater::new(
+//!         ota_coap,
+//!         nv_to_page,
+//!         0x80000, // Start of the staging region
+//!         0x3f000, // Length of the staging region
+//!         0xbf000, // Address of the progress record
+//!         verifier,
+//!         &mut OTA_BUF,
+//!     )
+//! );
+//! ota_coap.set_client(ota);
+//! nv_to_page.set_client(ota);
+//! ota.set_loader(process_loader, 0);
+//! ota.set_version_counter(firmware_version);
+//! firmware_version.set_client(ota);
+//! if ota.resume() != ReturnCode::SUCCESS {
+//!     ota.start(update_server, COAP_PORT, "fw/app", 6);
+//! }
+//! ```
+
+use crate::net::coap::coap::{coap_codes, coap_options, CoAPBlock, CoAPMessage};
+use crate::net::coap::coap_endpoint::{CoAPClient, CoAPEndpoint};
+use crate::net::dtls::sha256::Sha256;
+use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::ota::ota::{ImageVerifier, Progress, MAX_PATH_LEN, PROGRESS_LEN, VERSION_LEN};
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::monotonic_counter::{self, MonotonicCounter};
+use kernel::hil::nonvolatile_storage::{NonvolatileStorage, NonvolatileStorageClient};
+use kernel::hil::time::Alarm;
+use kernel::procs::ProcessLoader;
+use kernel::{log_info, log_warn, ReturnCode};
+
+/// Times a block is requested again after the server did not answer.
+pub const MAX_RETRIES: u8 = 3;
+
+/// The progress record is saved every this many blocks.
+pub const SAVE_INTERVAL: usize = 8;
+
+/// Told how an update is going.
+pub trait OtaUpdateClient {
+    /// `received` bytes of the update have been written to the staging
+    /// region.
+    fn progress(&self, received: usize);
+
+    /// The update ended. `result` is
+    /// - `SUCCESS` if the image was verified, and loaded if there is a
+    ///   loader. `length` is then the length of the image.
+    /// - `EINVAL` if the signature is invalid, the version is lower than
+    ///   the version counter or the server sent something other than the
+    ///   update, `ESIZE` if it does not fit the staging region, and
+    ///   `ECANCEL` if it was cancelled.
+    /// - `FAIL` if the server stopped answering, the version counter could
+    ///   not be read or advanced, or the image could not be loaded. The
+    ///   download can be resumed in the first case.
+    /// - `EOFF` if `resume()` found no download to resume.
+    ///
+    /// Otherwise `length` is the number of bytes received.
+    fn update_done(&self, result: ReturnCode, length: usize);
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum State {
+    Idle,
+    /// Reading the progress record for `resume()`.
+    Resuming,
+    /// Saving the progress record, before requesting the next block.
+    Saving,
+    /// The request for the next block is outstanding.
+    Downloading,
+    /// Writing a block of `len` bytes, which `more` blocks follow or not.
+    Writing {
+        len: usize,
+        more: bool,
+    },
+    /// Reading back the image to hash it.
+    Hashing,
+    /// Reading back the version and the signature.
+    ReadingSignature,
+    /// Advancing the version counter to the version of the image.
+    Advancing,
+    /// Clearing the progress record, before the client is told `result`.
+    Clearing(ReturnCode),
+}
+
+pub struct OtaUpdater<'a, A: Alarm<'a>> {
+    coap: &'a CoAPEndpoint<'a, A>,
+    storage: &'a dyn NonvolatileStorage<'static>,
+    /// Address and length of the staging region in `storage`.
+    base: usize,
+    length: usize,
+    progress_address: usize,
+    verifier: &'a dyn ImageVerifier,
+    /// The loader, and the offset of the staging region in its flash.
+    loader: OptionalCell<(&'a dyn ProcessLoader, usize)>,
+    version_counter: OptionalCell<&'a dyn MonotonicCounter<'a>>,
+    client: OptionalCell<&'a dyn OtaUpdateClient>,
+    /// Holds a block, the progress record or what is read back.
+    buffer: TakeCell<'static, [u8]>,
+    state: Cell<State>,
+    progress: Cell<Progress>,
+    retries: Cell<u8>,
+    /// The hash of the image read back so far, and its length.
+    hash: Cell<Sha256>,
+    hashed: Cell<usize>,
+}
+
+impl<'a, A: Alarm<'a>> OtaUpdater<'a, A> {
+    /// `buffer` must hold a block of the size downloads are started with,
+    /// and a signature.
+    pub fn new(
+        coap: &'a CoAPEndpoint<'a, A>,
+        storage: &'a dyn NonvolatileStorage<'static>,
+        base: usize,
+        length: usize,
+        progress_address: usize,
+        verifier: &'a dyn ImageVerifier,
+        buffer: &'static mut [u8],
+    ) -> OtaUpdater<'a, A> {
+        OtaUpdater {
+            coap: coap,
+            storage: storage,
+            base: base,
+            length: length,
+            progress_address: progress_address,
+            verifier: verifier,
+            loader: OptionalCell::empty(),
+            version_counter: OptionalCell::empty(),
+            client: OptionalCell::empty(),
+            buffer: TakeCell::new(buffer),
+            state: Cell::new(State::Idle),
+            progress: Cell::new(Progress {
+                server: IPAddr([0; 16]),
+                port: 0,
+                path: [0; MAX_PATH_LEN],
+                path_len: 0,
+                szx: 0,
+                offset: 0,
+            }),
+            retries: Cell::new(0),
+            hash: Cell::new(Sha256::new()),
+            hashed: Cell::new(0),
+        }
+    }
+
+    pub fn set_client(&self, client: &'a dyn OtaUpdateClient) {
+        self.client.set(client);
+    }
+
+    /// Loads verified images with `loader`, from `offset` in its flash
+    /// region, where the staging region starts.
+    pub fn set_loader(&self, loader: &'a dyn ProcessLoader, offset: usize) {
+        self.loader.set((loader, offset));
+    }
+
+    /// Refuses images with a version lower than `counter`, and advances it
+    /// to the version of each image before the image is loaded.
+    pub fn set_version_counter(&self, counter: &'a dyn MonotonicCounter<'a>) {
+        self.version_counter.set(counter);
+    }
+
+    /// The length of the version and signature that follow the image.
+    fn trailer_len(&self) -> usize {
+        VERSION_LEN + self.verifier.signature_len()
+    }
+
+    /// Whether the buffer holds blocks of `16 << szx` bytes.
+    fn fits_blocks(&self, szx: u8) -> bool {
+        let needed = cmp::max(cmp::max(16 << szx, PROGRESS_LEN), self.trailer_len());
+        szx <= CoAPBlock::MAX_SZX && self.buffer.map_or(false, |buffer| buffer.len() >= needed)
+    }
+
+    /// Downloads the update at `path` on `server`, in blocks of `16 << szx`
+    /// bytes. Returns `EBUSY` during another update, and `ESIZE` if the path
+    /// is longer than `MAX_PATH_LEN` or the blocks do not fit the buffer.
+    pub fn start(&self, server: IPAddr, port: u16, path: &str, szx: u8) -> ReturnCode {
+        if self.state.get() != State::Idle {
+            return ReturnCode::EBUSY;
+        }
+        if path.len() > MAX_PATH_LEN || !self.fits_blocks(szx) {
+            return ReturnCode::ESIZE;
+        }
+        let mut progress = Progress {
+            server: server,
+            port: port,
+            path: [0; MAX_PATH_LEN],
+            path_len: path.len(),
+            szx: szx,
+            offset: 0,
+        };
+        progress.path[..path.len()].copy_from_slice(path.as_bytes());
+        self.progress.set(progress);
+        self.retries.set(0);
+        self.save_progress()
+    }
+
+    /// Continues the download that was interrupted by a reset, if there is
+    /// one. The client is told `EOFF` if there is not.
+    pub fn resume(&self) -> ReturnCode {
+        if self.state.get() != State::Idle {
+            return ReturnCode::EBUSY;
+        }
+        match self.buffer.take() {
+            Some(buffer) => {
+                self.state.set(State::Resuming);
+                let rcode = self
+                    .storage
+                    .read(buffer, self.progress_address, PROGRESS_LEN);
+                if rcode != ReturnCode::SUCCESS {
+                    self.state.set(State::Idle);
+                }
+                rcode
+            }
+            None => ReturnCode::ENOMEM,
+        }
+    }
+
+    /// Stops the download, and forgets its progress.
+    pub fn cancel(&self) -> ReturnCode {
+        match self.state.get() {
+            // The endpoint tells us the request was cancelled
+            State::Downloading => self.coap.cancel(),
+            State::Idle => ReturnCode::EALREADY,
+            _ => ReturnCode::EBUSY,
+        }
+    }
+
+    fn save_progress(&self) -> ReturnCode {
+        match self.buffer.take() {
+            Some(buffer) => {
+                let _ = self.progress.get().encode(buffer);
+                self.state.set(State::Saving);
+                // The buffer is lost if the write fails
+                let rcode = self
+                    .storage
+                    .write(buffer, self.progress_address, PROGRESS_LEN);
+                if rcode != ReturnCode::SUCCESS {
+                    self.state.set(State::Idle);
+                }
+                rcode
+            }
+            None => ReturnCode::ENOMEM,
+        }
+    }
+
+    fn request_next(&self) {
+        let progress = self.progress.get();
+        let block = CoAPBlock {
+            num: (progress.offset >> (progress.szx + 4)) as u32,
+            more: false,
+            szx: progress.szx,
+        };
+        let path = core::str::from_utf8(&progress.path[..progress.path_len]).unwrap_or("");
+        self.state.set(State::Downloading);
+        let rcode = self
+            .coap
+            .request_block(progress.server, progress.port, path, block);
+        if rcode != ReturnCode::SUCCESS {
+            self.done(rcode);
+        }
+    }
+
+    fn block_written(&self, len: usize, more: bool) {
+        let mut progress = self.progress.get();
+        progress.offset += len;
+        self.progress.set(progress);
+        self.client.map(|client| client.progress(progress.offset));
+
+        if !more {
+            self.start_hashing();
+        } else if (progress.offset >> (progress.szx + 4)) % SAVE_INTERVAL == 0 {
+            let rcode = self.save_progress();
+            if rcode != ReturnCode::SUCCESS {
+                self.done(rcode);
+            }
+        } else {
+            self.request_next();
+        }
+    }
+
+    fn start_hashing(&self) {
+        let received = self.progress.get().offset;
+        if received <= self.trailer_len() {
+            self.clear_and_finish(ReturnCode::EINVAL);
+            return;
+        }
+        log_info!("OTA update of {} bytes received", received);
+        self.hash.set(Sha256::new());
+        self.hashed.set(0);
+        self.read_next();
+    }
+
+    /// Reads back the next part of the image and version, which the
+    /// signature is over, or the version and signature at their end.
+    fn read_next(&self) {
+        let signed_len = self.progress.get().offset - self.verifier.signature_len();
+        let hashed = self.hashed.get();
+        self.buffer.take().map(|buffer| {
+            let (state, address, len) = if hashed < signed_len {
+                (
+                    State::Hashing,
+                    hashed,
+                    cmp::min(buffer.len(), signed_len - hashed),
+                )
+            } else {
+                (
+                    State::ReadingSignature,
+                    signed_len - VERSION_LEN,
+                    self.trailer_len(),
+                )
+            };
+            self.state.set(state);
+            if self.storage.read(buffer, self.base + address, len) != ReturnCode::SUCCESS {
+                self.done(ReturnCode::FAIL);
+            }
+        });
+    }
+
+    /// Checks the version of the image if its signature is `valid`, and
+    /// then loads it.
+    fn verified(&self, valid: bool, version: u32) {
+        if !valid {
+            log_warn!("OTA update has an invalid signature");
+            self.clear_and_finish(ReturnCode::EINVAL);
+            return;
+        }
+        let counter = match self.version_counter.map(|counter| *counter) {
+            Some(counter) => counter,
+            None => return self.load(),
+        };
+        match counter.get() {
+            Ok(current) if version < current => {
+                log_warn!("OTA update version {} is older than {}", version, current);
+                self.clear_and_finish(ReturnCode::EINVAL);
+            }
+            Ok(_) => match counter.advance_to(version) {
+                ReturnCode::SUCCESS => self.state.set(State::Advancing),
+                ReturnCode::EALREADY => self.load(),
+                _ => self.clear_and_finish(ReturnCode::FAIL),
+            },
+            Err(_) => self.clear_and_finish(ReturnCode::FAIL),
+        }
+    }
+
+    /// Loads a verified image, if there is a loader.
+    fn load(&self) {
+        let result = self.loader.map_or(ReturnCode::SUCCESS, |(loader, offset)| {
+            match loader.load(*offset) {
+                Ok(_) => ReturnCode::SUCCESS,
+                Err(error) => {
+                    log_warn!("OTA update could not be loaded: {:?}", error);
+                    ReturnCode::FAIL
+                }
+            }
+        });
+        self.clear_and_finish(result);
+    }
+
+    /// Clears the progress record, and then ends the update with `result`.
+    fn clear_and_finish(&self, result: ReturnCode) {
+        match self.buffer.take() {
+            Some(buffer) => {
+                for byte in buffer[..PROGRESS_LEN].iter_mut() {
+                    *byte = 0;
+                }
+                self.state.set(State::Clearing(result));
+                if self
+                    .storage
+                    .write(buffer, self.progress_address, PROGRESS_LEN)
+                    != ReturnCode::SUCCESS
+                {
+                    self.done(result);
+                }
+            }
+            None => self.done(result),
+        }
+    }
+
+    fn done(&self, result: ReturnCode) {
+        self.state.set(State::Idle);
+        let received = self.progress.get().offset;
+        let length = if result == ReturnCode::SUCCESS {
+            received - self.trailer_len()
+        } else {
+            received
+        };
+        self.client.map(|client| client.update_done(result, length));
+    }
+}
+
+impl<'a, A: Alarm<'a>> CoAPClient for OtaUpdater<'a, A> {
+    fn response(&self, result: ReturnCode, _code: u8, _payload: &[u8]) {
+        if self.state.get() != State::Downloading {
+            return;
+        }
+        if result == ReturnCode::ECANCEL {
+            self.clear_and_finish(ReturnCode::ECANCEL);
+        } else if self.retries.get() < MAX_RETRIES {
+            self.retries.set(self.retries.get() + 1);
+            self.request_next();
+        } else {
+            log_warn!("OTA server did not answer");
+            self.done(ReturnCode::FAIL);
+        }
+    }
+
+    fn response_message(&self, msg: &CoAPMessage) {
+        if self.state.get() != State::Downloading {
+            return;
+        }
+        let code = msg.header.get_code();
+        if code != coap_codes::CONTENT {
+            log_warn!("OTA server answered {}.{:02}", code >> 5, code & 0x1f);
+            if code >> 5 == 5 {
+                // The server may answer once it is back
+                self.done(ReturnCode::FAIL);
+            } else {
+                self.clear_and_finish(ReturnCode::EINVAL);
+            }
+            return;
+        }
+
+        let mut progress = self.progress.get();
+        let block = match msg.uint_option(coap_options::BLOCK2) {
+            Some(value) => CoAPBlock::from_uint(value),
+            // The whole update fits in one response
+            None => Some(CoAPBlock {
+                num: 0,
+                more: false,
+                szx: progress.szx,
+            }),
+        };
+        let payload = msg.payload;
+        let block = match block {
+            // Servers may send smaller blocks than were asked for
+            Some(block)
+                if block.szx <= progress.szx
+                    && block.offset() == progress.offset
+                    && (!block.more || payload.len() == block.size()) =>
+            {
+                block
+            }
+            _ => {
+                self.clear_and_finish(ReturnCode::EINVAL);
+                return;
+            }
+        };
+        if progress.offset + payload.len() > self.length {
+            self.clear_and_finish(ReturnCode::ESIZE);
+            return;
+        }
+        progress.szx = block.szx;
+        self.progress.set(progress);
+        self.retries.set(0);
+
+        if payload.is_empty() {
+            self.block_written(0, block.more);
+            return;
+        }
+        match self.buffer.take() {
+            Some(buffer) if buffer.len() >= payload.len() => {
+                buffer[..payload.len()].copy_from_slice(payload);
+                self.state.set(State::Writing {
+                    len: payload.len(),
+                    more: block.more,
+                });
+                if self
+                    .storage
+                    .write(buffer, self.base + progress.offset, payload.len())
+                    != ReturnCode::SUCCESS
+                {
+                    self.done(ReturnCode::FAIL);
+                }
+            }
+            Some(buffer) => {
+                self.buffer.replace(buffer);
+                self.clear_and_finish(ReturnCode::ESIZE);
+            }
+            None => self.done(ReturnCode::ENOMEM),
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> NonvolatileStorageClient<'static> for OtaUpdater<'a, A> {
+    fn read_done(&self, buffer: &'static mut [u8], length: usize) {
+        match self.state.get() {
+            State::Resuming => {
+                let progress = Progress::decode(&buffer[..length]).done();
+                self.buffer.replace(buffer);
+                match progress {
+                    Some((_, progress)) if self.fits_blocks(progress.szx) => {
+                        log_info!("OTA update resumed after {} bytes", progress.offset);
+                        self.progress.set(progress);
+                        self.retries.set(0);
+                        self.client.map(|client| client.progress(progress.offset));
+                        self.request_next();
+                    }
+                    _ => self.done(ReturnCode::EOFF),
+                }
+            }
+            State::Hashing => {
+                let mut hash = self.hash.get();
+                hash.update(&buffer[..length]);
+                self.hash.set(hash);
+                self.hashed.set(self.hashed.get() + length);
+                self.buffer.replace(buffer);
+                if length == 0 {
+                    self.done(ReturnCode::FAIL);
+                } else {
+                    self.read_next();
+                }
+            }
+            State::ReadingSignature => {
+                let digest = self.hash.get().finish();
+                let (valid, version) = if length == self.trailer_len() {
+                    let version = u32::from_be_bytes([buffer[0], buffer[1], buffer[2], buffer[3]]);
+                    let valid = self.verifier.verify(&digest, &buffer[VERSION_LEN..length]);
+                    (valid, version)
+                } else {
+                    (false, 0)
+                };
+                self.buffer.replace(buffer);
+                self.verified(valid, version);
+            }
+            _ => {
+                self.buffer.replace(buffer);
+            }
+        }
+    }
+
+    fn write_done(&self, buffer: &'static mut [u8], _length: usize) {
+        self.buffer.replace(buffer);
+        match self.state.get() {
+            State::Saving => self.request_next(),
+            State::Writing { len, more } => self.block_written(len, more),
+            State::Clearing(result) => self.done(result),
+            _ => {}
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> monotonic_counter::Client for OtaUpdater<'a, A> {
+    fn counter_updated(&self, result: ReturnCode, _value: u32) {
+        if self.state.get() != State::Advancing {
+            return;
+        }
+        if result == ReturnCode::SUCCESS {
+            self.load();
+        } else {
+            log_warn!("OTA version counter could not be advanced");
+            self.clear_and_finish(ReturnCode::FAIL);
+        }
+    }
+}
@@ -0,0 +1,324 @@
+//! Opaque secure channel interface and its userspace driver.
+//!
+//! The [SecureSession](trait.SecureSession.html) trait is implemented by
+//! kernel capsules that provide an authenticated, encrypted datagram channel
+//! to a remote peer, such as the DTLS capsule. Handshakes, retransmissions,
+//! and all key material stay inside the implementation; users only open a
+//! channel to an address and port, send and receive plaintext, and close it.
+//!
+//! [SecureSessionDriver](struct.SecureSessionDriver.html) exposes one such
+//! channel to userspace. The first app to open the channel owns it until it
+//! closes the channel (or the app dies), and other apps receive `EBUSY`.
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! - allow `0`: receive buffer, filled with the plaintext of received
+//!   messages.
+//! - allow `1`: transmit buffer, holding the plaintext to send.
+//! - allow `2`: config buffer, a 16 byte IPv6 address followed by a 2 byte
+//!   port in host byte order, used by `open`.
+//! - subscribe `0`: session events, `fn(event: usize, result: usize, len:
+//!   usize)` where `event` is 0 for opened, 1 for sent, 2 for received, and 3
+//!   for closed.
+//! - command `0`: driver check.
+//! - command `1`: open a session to the peer in the config buffer.
+//! - command `2`: send the first `data` bytes of the transmit buffer.
+//! - command `3`: close the session.
+
+use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::util::host_slice_to_u16;
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
+
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::SecureSession as usize;
+
+/// Events reported in the first argument of the userspace callback.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SessionEvent {
+    Opened = 0,
+    Sent = 1,
+    Received = 2,
+    Closed = 3,
+}
+
+/// Callbacks from a `SecureSession`.
+pub trait SecureSessionClient {
+    /// The handshake with the peer finished. `result` is `SUCCESS` if the
+    /// session is now established.
+    fn opened(&self, result: ReturnCode);
+
+    /// A message passed to `send()` has been transmitted (or failed to be).
+    fn send_done(&self, result: ReturnCode, buf: &'static mut [u8]);
+
+    /// An authenticated message was received and decrypted.
+    fn received(&self, payload: &[u8]);
+
+    /// The session was closed, either locally or by the peer.
+    fn closed(&self, result: ReturnCode);
+}
+
+/// An encrypted, authenticated channel to a single remote peer.
+pub trait SecureSession<'a> {
+    fn set_client(&self, client: &'a dyn SecureSessionClient);
+
+    /// Start a handshake with `dest`:`dst_port`. Completion is signalled with
+    /// `SecureSessionClient::opened`.
+    fn open(&self, dest: IPAddr, dst_port: u16) -> ReturnCode;
+
+    /// Encrypt and send the first `len` bytes of `buf` to the peer.
+    fn send(
+        &self,
+        buf: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])>;
+
+    /// Close the session and erase its keys.
+    fn close(&self) -> ReturnCode;
+
+    fn is_open(&self) -> bool;
+}
+
+#[derive(Default)]
+pub struct App {
+    callback: Option<Callback>,
+    app_read: Option<AppSlice<Shared, u8>>,
+    app_write: Option<AppSlice<Shared, u8>>,
+    app_cfg: Option<AppSlice<Shared, u8>>,
+}
+
+pub struct SecureSessionDriver<'a> {
+    session: &'a dyn SecureSession<'a>,
+    apps: Grant<App>,
+    /// App that currently owns the session.
+    owner: OptionalCell<AppId>,
+    tx_in_progress: Cell<bool>,
+    kernel_buffer: TakeCell<'static, [u8]>,
+}
+
+impl<'a> SecureSessionDriver<'a> {
+    pub fn new(
+        session: &'a dyn SecureSession<'a>,
+        grant: Grant<App>,
+        kernel_buffer: &'static mut [u8],
+    ) -> SecureSessionDriver<'a> {
+        SecureSessionDriver {
+            session: session,
+            apps: grant,
+            owner: OptionalCell::empty(),
+            tx_in_progress: Cell::new(false),
+            kernel_buffer: TakeCell::new(kernel_buffer),
+        }
+    }
+
+    #[inline]
+    fn do_with_app<F>(&self, appid: AppId, closure: F) -> ReturnCode
+    where
+        F: FnOnce(&mut App) -> ReturnCode,
+    {
+        self.apps
+            .enter(appid, |app, _| closure(app))
+            .unwrap_or_else(|err| err.into())
+    }
+
+    /// Checks whether `appid` may use the session, releasing it first if the
+    /// previous owner no longer exists.
+    fn owned_by(&self, appid: AppId) -> bool {
+        let owner = self.owner.map_or(None, |owner| {
+            if *owner != appid && self.apps.enter(*owner, |_, _| ()).is_err() {
+                None
+            } else {
+                Some(*owner)
+            }
+        });
+        match owner {
+            Some(owner) => owner == appid,
+            None => {
+                if self.session.is_open() {
+                    self.session.close();
+                }
+                self.owner.clear();
+                false
+            }
+        }
+    }
+
+    fn notify(&self, event: SessionEvent, result: ReturnCode, len: usize) {
+        self.owner.map(|owner| {
+            let _ = self.apps.enter(*owner, |app, _| {
+                app.callback
+                    .map(|mut cb| cb.schedule(event as usize, usize::from(result), len));
+            });
+        });
+    }
+
+    fn open(&self, appid: AppId) -> ReturnCode {
+        if self.owner.is_some() && !self.owned_by(appid) {
+            return ReturnCode::EBUSY;
+        }
+        let peer = self
+            .apps
+            .enter(appid, |app, _| {
+                app.app_cfg.as_ref().and_then(|cfg| {
+                    let addr_len = IPAddr::new().0.len();
+                    if cfg.len() != addr_len + 2 {
+                        return None;
+                    }
+                    let (a, p) = cfg.as_ref().split_at(addr_len);
+                    let mut addr = IPAddr::new();
+                    addr.0.copy_from_slice(a);
+                    Some((addr, host_slice_to_u16(p)))
+                })
+            })
+            .unwrap_or(None);
+        match peer {
+            Some((addr, port)) => {
+                let ret = self.session.open(addr, port);
+                if ret == ReturnCode::SUCCESS {
+                    self.owner.set(appid);
+                }
+                ret
+            }
+            None => ReturnCode::EINVAL,
+        }
+    }
+
+    fn send(&self, appid: AppId, len: usize) -> ReturnCode {
+        if !self.owned_by(appid) || !self.session.is_open() {
+            return ReturnCode::ERESERVE;
+        }
+        if self.tx_in_progress.get() {
+            return ReturnCode::EBUSY;
+        }
+        self.kernel_buffer.take().map_or(ReturnCode::EBUSY, |kbuf| {
+            let copied = self
+                .apps
+                .enter(appid, |app, _| {
+                    app.app_write.as_ref().map_or(None, |src| {
+                        if len > src.len() || len > kbuf.len() {
+                            None
+                        } else {
+                            kbuf[..len].copy_from_slice(&src.as_ref()[..len]);
+                            Some(len)
+                        }
+                    })
+                })
+                .unwrap_or(None);
+            match copied {
+                Some(len) => match self.session.send(kbuf, len) {
+                    Ok(()) => {
+                        self.tx_in_progress.set(true);
+                        ReturnCode::SUCCESS
+                    }
+                    Err((rcode, kbuf)) => {
+                        self.kernel_buffer.replace(kbuf);
+                        rcode
+                    }
+                },
+                None => {
+                    self.kernel_buffer.replace(kbuf);
+                    ReturnCode::ESIZE
+                }
+            }
+        })
+    }
+}
+
+impl<'a> SecureSessionClient for SecureSessionDriver<'a> {
+    fn opened(&self, result: ReturnCode) {
+        self.notify(SessionEvent::Opened, result, 0);
+        if result != ReturnCode::SUCCESS {
+            self.owner.clear();
+        }
+    }
+
+    fn send_done(&self, result: ReturnCode, buf: &'static mut [u8]) {
+        self.tx_in_progress.set(false);
+        self.kernel_buffer.replace(buf);
+        self.notify(SessionEvent::Sent, result, 0);
+    }
+
+    fn received(&self, payload: &[u8]) {
+        self.owner.map(|owner| {
+            let _ = self.apps.enter(*owner, |app, _| {
+                let len = app.app_read.as_mut().map_or(0, |rbuf| {
+                    let len = cmp::min(rbuf.len(), payload.len());
+                    rbuf.as_mut()[..len].copy_from_slice(&payload[..len]);
+                    len
+                });
+                let result = if len < payload.len() {
+                    ReturnCode::ESIZE
+                } else {
+                    ReturnCode::SUCCESS
+                };
+                app.callback.map(|mut cb| {
+                    cb.schedule(SessionEvent::Received as usize, usize::from(result), len)
+                });
+            });
+        });
+    }
+
+    fn closed(&self, result: ReturnCode) {
+        self.notify(SessionEvent::Closed, result, 0);
+        self.owner.clear();
+    }
+}
+
+impl<'a> Driver for SecureSessionDriver<'a> {
+    fn allow(
+        &self,
+        appid: AppId,
+        allow_num: usize,
+        slice: Option<AppSlice<Shared, u8>>,
+    ) -> ReturnCode {
+        match allow_num {
+            0 => self.do_with_app(appid, |app| {
+                app.app_read = slice;
+                ReturnCode::SUCCESS
+            }),
+            1 => self.do_with_app(appid, |app| {
+                app.app_write = slice;
+                ReturnCode::SUCCESS
+            }),
+            2 => self.do_with_app(appid, |app| {
+                app.app_cfg = slice;
+                ReturnCode::SUCCESS
+            }),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        callback: Option<Callback>,
+        app_id: AppId,
+    ) -> ReturnCode {
+        match subscribe_num {
+            0 => self.do_with_app(app_id, |app| {
+                app.callback = callback;
+                ReturnCode::SUCCESS
+            }),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    fn command(&self, command_num: usize, arg1: usize, _: usize, appid: AppId) -> ReturnCode {
+        match command_num {
+            0 => ReturnCode::SUCCESS,
+            1 => self.open(appid),
+            2 => self.send(appid, arg1),
+            3 => {
+                if self.owned_by(appid) {
+                    self.session.close()
+                } else {
+                    ReturnCode::ERESERVE
+                }
+            }
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
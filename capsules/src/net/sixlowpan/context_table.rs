@@ -0,0 +1,158 @@
+//! A table of the 6LoWPAN compression contexts of a network.
+//!
+//! A `Context` alone only knows context 0, the mesh-local prefix.
+//! `ContextTable` holds up to `MAX_CONTEXTS` contexts with identifiers 0 to
+//! 15 (RFC 6282), so that addresses under other prefixes, such as the
+//! global prefix a border router advertises, are compressed as well. A
+//! border router capsule installs and removes contexts at runtime, as the
+//! 6LoWPAN Context Options of its Router Advertisements change (RFC 6775).
+//! A context that is installed with `compress` false is still used to
+//! decompress packets, but not to compress them, as RFC 6775 requires for
+//! a context that is about to be removed.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//! # use capsules::net::sixlowpan::context_table::ContextTable;
+//!
+//! let context_table = static_init!(
+//!     ContextTable,
+//!     ContextTable::new(Context {
+//!         prefix: MESH_LOCAL_PREFIX,
+//!         prefix_len: 64,
+//!         id: 0,
+//!         compress: true,
+//!     })
+//! );
+//! let sixlowpan = static_init!(
+//!     Sixlowpan<'static, sam4l::ast::Ast<'static>, &'static ContextTable>,
+//!     Sixlowpan::new(context_table, &sam4l::ast::AST)
+//! );
+//!
+//! context_table.add_context(Context {
+//!     prefix: GLOBAL_PREFIX,
+//!     prefix_len: 64,
+//!     id: 1,
+//!     compress: true,
+//! });
+//! ```
+
+use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::sixlowpan::sixlowpan_compression::{Context, ContextStore};
+use crate::net::util;
+use core::cell::Cell;
+use kernel::ReturnCode;
+
+pub const MAX_CONTEXTS: usize = 4;
+
+/// The largest context identifier the Context Identifier Extension carries.
+pub const MAX_CONTEXT_ID: u8 = 15;
+
+pub struct ContextTable {
+    contexts: [Cell<Option<Context>>; MAX_CONTEXTS],
+}
+
+impl ContextTable {
+    /// `context_0` must have the identifier 0. Packets are decompressed
+    /// with it when they carry no context identifier.
+    pub fn new(context_0: Context) -> ContextTable {
+        ContextTable {
+            contexts: [
+                Cell::new(Some(Context { id: 0, ..context_0 })),
+                Cell::new(None),
+                Cell::new(None),
+                Cell::new(None),
+            ],
+        }
+    }
+
+    /// Installs `context`, replacing the context with its identifier.
+    /// Returns `EINVAL` if the identifier is above `MAX_CONTEXT_ID` or the
+    /// prefix is longer than 128 bits, and `ENOMEM` if the table is full.
+    pub fn add_context(&self, context: Context) -> ReturnCode {
+        if context.id > MAX_CONTEXT_ID || context.prefix_len > 128 {
+            return ReturnCode::EINVAL;
+        }
+        let slot = self
+            .slot_of(context.id)
+            .or_else(|| self.contexts.iter().find(|slot| slot.get().is_none()));
+        match slot {
+            Some(slot) => {
+                slot.set(Some(context));
+                ReturnCode::SUCCESS
+            }
+            None => ReturnCode::ENOMEM,
+        }
+    }
+
+    /// Removes the context with the identifier `id`. Context 0 can only be
+    /// replaced, so removing it returns `EINVAL`, as does an identifier
+    /// that is not installed.
+    pub fn remove_context(&self, id: u8) -> ReturnCode {
+        if id == 0 {
+            return ReturnCode::EINVAL;
+        }
+        match self.slot_of(id) {
+            Some(slot) => {
+                slot.set(None);
+                ReturnCode::SUCCESS
+            }
+            None => ReturnCode::EINVAL,
+        }
+    }
+
+    /// Sets whether the context with the identifier `id` is used to
+    /// compress packets.
+    pub fn set_compress(&self, id: u8, compress: bool) -> ReturnCode {
+        match self.slot_of(id) {
+            Some(slot) => {
+                slot.set(slot.get().map(|ctx| Context {
+                    compress: compress,
+                    ..ctx
+                }));
+                ReturnCode::SUCCESS
+            }
+            None => ReturnCode::EINVAL,
+        }
+    }
+
+    /// Calls `f` with each installed context.
+    pub fn each_context<F: FnMut(Context)>(&self, mut f: F) {
+        for ctx in self.contexts.iter().filter_map(|slot| slot.get()) {
+            f(ctx);
+        }
+    }
+
+    fn slot_of(&self, id: u8) -> Option<&Cell<Option<Context>>> {
+        self.contexts
+            .iter()
+            .find(|slot| slot.get().map_or(false, |ctx| ctx.id == id))
+    }
+}
+
+impl ContextStore for ContextTable {
+    /// Returns the context with the longest prefix of `ip_addr`.
+    fn get_context_from_addr(&self, ip_addr: IPAddr) -> Option<Context> {
+        self.contexts
+            .iter()
+            .filter_map(|slot| slot.get())
+            .filter(|ctx| util::matches_prefix(&ip_addr.0, &ctx.prefix, ctx.prefix_len))
+            .max_by_key(|ctx| ctx.prefix_len)
+    }
+
+    fn get_context_from_id(&self, ctx_id: u8) -> Option<Context> {
+        self.slot_of(ctx_id).and_then(|slot| slot.get())
+    }
+
+    fn get_context_from_prefix(&self, prefix: &[u8], prefix_len: u8) -> Option<Context> {
+        self.contexts
+            .iter()
+            .filter_map(|slot| slot.get())
+            .find(|ctx| {
+                ctx.prefix_len == prefix_len
+                    && util::matches_prefix(prefix, &ctx.prefix, prefix_len)
+            })
+    }
+}
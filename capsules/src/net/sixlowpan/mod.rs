@@ -1,2 +1,3 @@
+pub mod context_table;
 pub mod sixlowpan_compression;
 pub mod sixlowpan_state;
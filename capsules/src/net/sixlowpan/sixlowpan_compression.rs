@@ -152,6 +152,22 @@ impl ContextStore for Context {
     }
 }
 
+/// Lets a `Sixlowpan` use a store, such as a `ContextTable`, that other
+/// capsules also hold to change its contexts at runtime.
+impl<C: ContextStore> ContextStore for &C {
+    fn get_context_from_addr(&self, ip_addr: IPAddr) -> Option<Context> {
+        (*self).get_context_from_addr(ip_addr)
+    }
+
+    fn get_context_from_id(&self, ctx_id: u8) -> Option<Context> {
+        (*self).get_context_from_id(ctx_id)
+    }
+
+    fn get_context_from_prefix(&self, prefix: &[u8], prefix_len: u8) -> Option<Context> {
+        (*self).get_context_from_prefix(prefix, prefix_len)
+    }
+}
+
 pub fn is_lowpan(packet: &[u8]) -> bool {
     (packet[0] & iphc::DISPATCH[0]) == iphc::DISPATCH[0]
 }
@@ -230,7 +246,11 @@ pub fn compress<'a>(
     // Next Header
 
     //let (mut is_nhc, mut nh_len): (bool, u8) = is_ip6_nh_compressible(ip6_packet)?;
-    let is_nhc = ip6_header.next_header == ip6_nh::UDP;
+    // Raw packets carry their UDP header in the payload, and send it inline
+    let is_nhc = match ip6_packet.payload.header {
+        TransportHeader::UDP(_) => ip6_header.next_header == ip6_nh::UDP,
+        _ => false,
+    };
     compress_nh(&ip6_header, is_nhc, &mut buf, &mut written);
 
     // Hop Limit
@@ -510,18 +530,16 @@ fn compress_multicast(
 }
 
 fn compress_udp_ports(udp_header: &UDPHeader, buf: &mut [u8], written: &mut usize) -> u8 {
-    // Need to deal with fields in network byte order when writing directly to buf
-    let src_port = udp_header.get_src_port().to_be();
-    let dst_port = udp_header.get_dst_port().to_be();
+    let src_port = udp_header.get_src_port();
+    let dst_port = udp_header.get_dst_port();
 
     let mut udp_port_nhc = 0;
     if (src_port & nhc::UDP_4BIT_PORT_MASK) == nhc::UDP_4BIT_PORT
         && (dst_port & nhc::UDP_4BIT_PORT_MASK) == nhc::UDP_4BIT_PORT
     {
-        // Both can be compressed to 4 bits
+        // Both can be compressed to 4 bits, carried in a single byte with
+        // the source port before the destination port
         udp_port_nhc |= nhc::UDP_SRC_PORT_FLAG | nhc::UDP_DST_PORT_FLAG;
-        // This should compress the ports to a single 8-bit value,
-        // with the source port before the destination port
         buf[*written] = (((src_port & !nhc::UDP_4BIT_PORT_MASK) << 4)
             | (dst_port & !nhc::UDP_4BIT_PORT_MASK)) as u8;
         *written += 1;
@@ -529,19 +547,17 @@ fn compress_udp_ports(udp_header: &UDPHeader, buf: &mut [u8], written: &mut usiz
         // Source port compressed to 8 bits, destination port uncompressed
         udp_port_nhc |= nhc::UDP_SRC_PORT_FLAG;
         buf[*written] = (src_port & !nhc::UDP_8BIT_PORT_MASK) as u8;
-        u16_to_network_slice(dst_port.to_be(), &mut buf[*written + 1..*written + 3]);
+        u16_to_network_slice(dst_port, &mut buf[*written + 1..*written + 3]);
         *written += 3;
     } else if (dst_port & nhc::UDP_8BIT_PORT_MASK) == nhc::UDP_8BIT_PORT {
+        // Source port uncompressed, destination port compressed to 8 bits
         udp_port_nhc |= nhc::UDP_DST_PORT_FLAG;
-        u16_to_network_slice(src_port.to_be(), &mut buf[*written..*written + 2]);
-        buf[*written + 3] = (dst_port & !nhc::UDP_8BIT_PORT_MASK) as u8;
+        u16_to_network_slice(src_port, &mut buf[*written..*written + 2]);
+        buf[*written + 2] = (dst_port & !nhc::UDP_8BIT_PORT_MASK) as u8;
         *written += 3;
     } else {
-        buf[*written] = src_port as u8;
-        buf[*written + 1] = (src_port >> 8) as u8;
-        buf[*written + 2] = dst_port as u8;
-        buf[*written + 3] = (dst_port >> 8) as u8;
-        //buf[*written..*written + 4].copy_from_slice(&udp_header[0..4]);
+        u16_to_network_slice(src_port, &mut buf[*written..*written + 2]);
+        u16_to_network_slice(dst_port, &mut buf[*written + 2..*written + 4]);
         *written += 4;
     }
     udp_port_nhc
@@ -720,12 +736,8 @@ pub fn decompress(
                 }
 
                 // Fill in uncompressed UDP header
-                // TODO: The current implementation works, but I don't understand the calls to
-                // to_be(), because src_port.to_be() returns the src_port in little endian..
-                // Accordingly, the udp_length must also be written in little endian for this
-                // to work.
-                u16_to_network_slice(src_port.to_be(), &mut next_headers[0..2]);
-                u16_to_network_slice(dst_port.to_be(), &mut next_headers[2..4]);
+                u16_to_network_slice(src_port, &mut next_headers[0..2]);
+                u16_to_network_slice(dst_port, &mut next_headers[2..4]);
                 u16_to_network_slice(udp_length, &mut next_headers[4..6]);
                 // Need to fill in header values before computing the checksum
                 let udp_checksum = decompress_udp_checksum(
@@ -1136,18 +1148,18 @@ fn decompress_udp_ports(udp_nhc: u8, buf: &[u8], consumed: &mut usize) -> (u16,
         // Source port is compressed to 8 bits
         src_port = nhc::UDP_8BIT_PORT | (buf[*consumed] as u16);
         // Destination port is uncompressed
-        dst_port = u16::from_be(network_slice_to_u16(&buf[*consumed + 1..*consumed + 3]));
+        dst_port = network_slice_to_u16(&buf[*consumed + 1..*consumed + 3]);
         *consumed += 3;
     } else if dst_compressed {
         // Source port is uncompressed
-        src_port = u16::from_be(network_slice_to_u16(&buf[*consumed..*consumed + 2]));
+        src_port = network_slice_to_u16(&buf[*consumed..*consumed + 2]);
         // Destination port is compressed to 8 bits
         dst_port = nhc::UDP_8BIT_PORT | (buf[*consumed + 2] as u16);
         *consumed += 3;
     } else {
         // Both ports are uncompressed
-        src_port = u16::from_be(network_slice_to_u16(&buf[*consumed..*consumed + 2]));
-        dst_port = u16::from_be(network_slice_to_u16(&buf[*consumed + 2..*consumed + 4]));
+        src_port = network_slice_to_u16(&buf[*consumed..*consumed + 2]);
+        dst_port = network_slice_to_u16(&buf[*consumed + 2..*consumed + 4]);
         *consumed += 4;
     }
     (src_port, dst_port)
@@ -1186,3 +1198,52 @@ fn decompress_udp_checksum(
         checksum
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Compresses the ports, checks the NHC flags and inline bytes against
+    /// RFC 6282, section 4.3.3, and decompresses them again.
+    fn round_trip(src_port: u16, dst_port: u16, flags: u8, inline: &[u8]) {
+        let mut udp_header = UDPHeader::new();
+        udp_header.set_src_port(src_port);
+        udp_header.set_dst_port(dst_port);
+
+        let mut buf = [0; 8];
+        let mut written = 0;
+        assert_eq!(
+            compress_udp_ports(&udp_header, &mut buf, &mut written),
+            flags
+        );
+        assert_eq!(&buf[..written], inline);
+
+        let mut consumed = 0;
+        assert_eq!(
+            decompress_udp_ports(nhc::DISPATCH_UDP | flags, &buf, &mut consumed),
+            (src_port, dst_port)
+        );
+        assert_eq!(consumed, written);
+    }
+
+    #[test]
+    fn udp_ports_4bit() {
+        let both = nhc::UDP_SRC_PORT_FLAG | nhc::UDP_DST_PORT_FLAG;
+        round_trip(0xf0b3, 0xf0b5, both, &[0x35]);
+        round_trip(0xf0b0, 0xf0bf, both, &[0x0f]);
+    }
+
+    #[test]
+    fn udp_ports_8bit() {
+        round_trip(0xf012, 0x1634, nhc::UDP_SRC_PORT_FLAG, &[0x12, 0x16, 0x34]);
+        round_trip(0x1634, 0xf0ab, nhc::UDP_DST_PORT_FLAG, &[0x16, 0x34, 0xab]);
+        // Only one of the ports fits in 4 bits, so the source takes 8
+        round_trip(0xf0b1, 0xf0c2, nhc::UDP_SRC_PORT_FLAG, &[0xb1, 0xf0, 0xc2]);
+    }
+
+    #[test]
+    fn udp_ports_inline() {
+        round_trip(0x1234, 0x5678, 0, &[0x12, 0x34, 0x56, 0x78]);
+        round_trip(0xe0f0, 0x0001, 0, &[0xe0, 0xf0, 0x00, 0x01]);
+    }
+}
@@ -37,6 +37,14 @@
 //! [SixlowpanRxClient](trait.SixlowpanRxClient.html) trait, which is called
 //! after a packet is fully received.
 //!
+//! Each packet is reassembled in one of the pool of
+//! [RxState](struct.RxState.html)s added with `add_rx_state`, so the number
+//! of `RxState`s bounds how many packets are reassembled at once, and the
+//! size of their buffers how large a packet can be received. A partially
+//! reassembled packet is dropped once its `RxState` is needed after the
+//! reassembly timeout, `DEFAULT_REASSEMBLY_TIMEOUT` seconds unless set with
+//! `set_reassembly_timeout`.
+//!
 //! At a high level, clients interact with this module as shown in the diagrams
 //! below:
 //!
@@ -241,14 +249,20 @@ use kernel::hil::time;
 use kernel::hil::time::{Frequency, Ticks};
 use kernel::ReturnCode;
 
-// Reassembly timeout in seconds
-const FRAG_TIMEOUT: u32 = 60;
+/// Seconds after which a partially reassembled packet is dropped, unless set
+/// with `Sixlowpan::set_reassembly_timeout`. RFC 4944 allows up to 60.
+pub const DEFAULT_REASSEMBLY_TIMEOUT: u32 = 60;
+
+/// The largest datagram a fragment header can describe (RFC 4944, 5.3).
+pub const MAX_DGRAM_SIZE: u16 = 0x7ff;
 
 /// Objects that implement this trait can set themselves to be the client
 /// for the [Sixlowpan](struct.Sixlowpan.html) struct, and will then receive
-/// a callback once an IPv6 packet has been fully reassembled.
+/// a callback once an IPv6 packet has been fully reassembled. `timestamp` is
+/// the radio timestamp of the frame that completed the packet, if the radio
+/// timestamps frames.
 pub trait SixlowpanRxClient {
-    fn receive<'a>(&self, buf: &'a [u8], len: usize, result: ReturnCode);
+    fn receive<'a>(&self, buf: &'a [u8], len: usize, timestamp: Option<u32>, result: ReturnCode);
 }
 
 pub mod lowpan_frag {
@@ -458,6 +472,9 @@ impl<'a> TxState<'a> {
         frame: Frame,
         ctx_store: &dyn ContextStore,
     ) -> Result<Frame, (ReturnCode, &'static mut [u8])> {
+        if ip6_packet.get_total_len() > MAX_DGRAM_SIZE {
+            return Err((ReturnCode::ESIZE, frame.into_buf()));
+        }
         self.busy.set(true);
         self.dgram_size.set(ip6_packet.get_total_len());
         self.dgram_tag.set(self.sixlowpan.next_dgram_tag());
@@ -633,8 +650,9 @@ pub struct RxState<'a> {
     // Marks if this instance is being used for a packet reassembly or if it is
     // free to use for a new packet.
     busy: Cell<bool>,
-    // The time when packet reassembly started for the current packet.
-    start_time: Cell<u32>,
+    // The time when packet reassembly started for the current packet, in the
+    // ticks of the `Sixlowpan`'s clock.
+    start_time: Cell<u64>,
 
     next: ListLink<'a, RxState<'a>>,
 }
@@ -682,21 +700,26 @@ impl<'a> RxState<'a> {
 
     // Checks if a given RxState is free or expired (and thus, can be freed).
     // This function implements the reassembly timeout for 6LoWPAN lazily.
-    fn is_busy(&self, frequency: u32, current_time: u32) -> bool {
-        let expired = current_time >= (self.start_time.get() + FRAG_TIMEOUT * frequency);
-        if expired {
-            self.end_receive(None, ReturnCode::FAIL);
+    // `expired` tells whether a reassembly started at a given time timed out.
+    fn is_busy<F: FnOnce(u64) -> bool>(&self, expired: F) -> bool {
+        if self.busy.get() && expired(self.start_time.get()) {
+            self.end_receive(None, None, ReturnCode::FAIL);
         }
         self.busy.get()
     }
 
+    /// The largest datagram this `RxState` can reassemble.
+    fn capacity(&self) -> usize {
+        self.packet.map_or(0, |packet| packet.len())
+    }
+
     fn start_receive(
         &self,
         src_mac_addr: MacAddress,
         dst_mac_addr: MacAddress,
         dgram_size: u16,
         dgram_tag: u16,
-        current_tics: u32,
+        current_tics: u64,
     ) {
         self.dst_mac_addr.set(dst_mac_addr);
         self.src_mac_addr.set(src_mac_addr);
@@ -718,28 +741,39 @@ impl<'a> RxState<'a> {
         dgram_offset: usize,
         ctx_store: &dyn ContextStore,
     ) -> Result<bool, ReturnCode> {
-        let mut packet = self.packet.take().ok_or(ReturnCode::ENOMEM)?;
+        let packet = self.packet.take().ok_or(ReturnCode::ENOMEM)?;
+        // Fragments must stay within the datagram, which start_receive
+        // checked fits the packet buffer
+        let end = min(dgram_size as usize, packet.len());
         let uncompressed_len = if dgram_offset == 0 {
-            let (consumed, written) = sixlowpan_compression::decompress(
+            sixlowpan_compression::decompress(
                 ctx_store,
                 &payload[0..payload_len as usize],
                 self.src_mac_addr.get(),
                 self.dst_mac_addr.get(),
-                &mut packet,
+                &mut packet[..end],
                 dgram_size,
                 true,
             )
-            .map_err(|_| ReturnCode::FAIL)?;
-            let remaining = payload_len - consumed;
-            packet[written..written + remaining]
-                .copy_from_slice(&payload[consumed..consumed + remaining]);
-            written + remaining
-        } else {
+            .ok()
+            .and_then(|(consumed, written)| {
+                let remaining = payload_len - consumed;
+                if written + remaining > end {
+                    return None;
+                }
+                packet[written..written + remaining]
+                    .copy_from_slice(&payload[consumed..consumed + remaining]);
+                Some(written + remaining)
+            })
+        } else if dgram_offset + payload_len <= end {
             packet[dgram_offset..dgram_offset + payload_len]
                 .copy_from_slice(&payload[0..payload_len]);
-            payload_len
+            Some(payload_len)
+        } else {
+            None
         };
         self.packet.replace(packet);
+        let uncompressed_len = uncompressed_len.ok_or(ReturnCode::FAIL)?;
         if !self.bitmap.map_or(false, |bitmap| {
             bitmap.set_bits(dgram_offset / 8, (dgram_offset + uncompressed_len) / 8)
         }) {
@@ -753,7 +787,12 @@ impl<'a> RxState<'a> {
         }
     }
 
-    fn end_receive(&self, client: Option<&'a dyn SixlowpanRxClient>, result: ReturnCode) {
+    fn end_receive(
+        &self,
+        client: Option<&'a dyn SixlowpanRxClient>,
+        timestamp: Option<u32>,
+        result: ReturnCode,
+    ) {
         self.busy.set(false);
         self.bitmap.map(|bitmap| bitmap.clear());
         self.start_time.set(0);
@@ -764,7 +803,7 @@ impl<'a> RxState<'a> {
             // and thus the packet should always be here.
             self.packet
                 .map(|packet| {
-                    client.receive(&packet, self.dgram_size.get() as usize, result);
+                    client.receive(&packet, self.dgram_size.get() as usize, timestamp, result);
                 })
                 .expect("Error: `packet` is None in call to end_receive.");
         });
@@ -788,6 +827,8 @@ pub struct Sixlowpan<'a, A: time::Alarm<'a>, C: ContextStore> {
     clock: &'a A,
     tx_dgram_tag: Cell<u16>,
     rx_client: Cell<Option<&'a dyn SixlowpanRxClient>>,
+    // Reassembly timeout in seconds
+    reassembly_timeout: Cell<u32>,
 
     // Receive state
     rx_states: List<'a, RxState<'a>>,
@@ -795,7 +836,14 @@ pub struct Sixlowpan<'a, A: time::Alarm<'a>, C: ContextStore> {
 
 // This function is called after receiving a frame
 impl<'a, A: time::Alarm<'a>, C: ContextStore> RxClient for Sixlowpan<'a, A, C> {
-    fn receive<'b>(&self, buf: &'b [u8], header: Header<'b>, data_offset: usize, data_len: usize) {
+    fn receive<'b>(
+        &self,
+        buf: &'b [u8],
+        header: Header<'b>,
+        data_offset: usize,
+        data_len: usize,
+        timestamp: Option<u32>,
+    ) {
         // We return if retcode is not valid, as it does not make sense to issue
         // a callback for an invalid frame reception
         // TODO: Handle the case where the addresses are None/elided - they
@@ -811,7 +859,7 @@ impl<'a, A: time::Alarm<'a>, C: ContextStore> RxClient for Sixlowpan<'a, A, C> {
         );
         // Reception completed if rx_state is not None. Note that this can
         // also occur for some fail states (e.g. dropping an invalid packet)
-        rx_state.map(|state| state.end_receive(self.rx_client.get(), returncode));
+        rx_state.map(|state| state.end_receive(self.rx_client.get(), timestamp, returncode));
     }
 }
 
@@ -866,11 +914,38 @@ impl<'a, A: time::Alarm<'a>, C: ContextStore> Sixlowpan<'a, A, C> {
             clock: clock,
             tx_dgram_tag: Cell::new(0),
             rx_client: Cell::new(None),
+            reassembly_timeout: Cell::new(DEFAULT_REASSEMBLY_TIMEOUT),
 
             rx_states: List::new(),
         }
     }
 
+    /// Sets how many seconds a partially reassembled packet is kept before
+    /// its `RxState` is freed for another packet. The timeout must be shorter
+    /// than the time the clock takes to wrap around.
+    pub fn set_reassembly_timeout(&self, seconds: u32) {
+        self.reassembly_timeout.set(seconds);
+    }
+
+    // Finds a free RxState that can hold `dgram_size` bytes. RxStates whose
+    // reassembly timed out are freed first.
+    fn free_rx_state(&self, dgram_size: usize) -> Option<&RxState<'a>> {
+        let now = self.clock.now();
+        self.rx_states.iter().find(|state| {
+            !state.is_busy(|start| self.reassembly_expired(now, start))
+                && dgram_size <= state.capacity()
+        })
+    }
+
+    // Whether a reassembly that started at `start` ticks has timed out. The
+    // difference wraps at the width of the clock, so the timeout must be
+    // shorter than the clock's period.
+    fn reassembly_expired(&self, now: A::Ticks, start: u64) -> bool {
+        let elapsed = now.wrapping_sub(A::Ticks::from_u64_wrapping(start));
+        elapsed.into_u64()
+            >= self.reassembly_timeout.get() as u64 * A::Frequency::frequency() as u64
+    }
+
     fn receive_frame(
         &self,
         packet: &[u8],
@@ -906,17 +981,14 @@ impl<'a, A: time::Alarm<'a>, C: ContextStore> Sixlowpan<'a, A, C> {
         src_mac_addr: MacAddress,
         dst_mac_addr: MacAddress,
     ) -> (Option<&RxState<'a>>, ReturnCode) {
-        let rx_state = self
-            .rx_states
-            .iter()
-            .find(|state| !state.is_busy(self.clock.now().into_u32(), A::Frequency::frequency()));
+        let rx_state = self.free_rx_state(0);
         rx_state.map_or((None, ReturnCode::ENOMEM), |state| {
             state.start_receive(
                 src_mac_addr,
                 dst_mac_addr,
                 payload_len as u16,
                 0,
-                self.clock.now().into_u32(),
+                self.clock.now().into_u64(),
             );
             // The packet buffer should *always* be there; in particular,
             // since this state is not busy, it must have the packet buffer.
@@ -976,9 +1048,11 @@ impl<'a, A: time::Alarm<'a>, C: ContextStore> Sixlowpan<'a, A, C> {
 
         // Else find a free state
         if rx_state.is_none() {
-            rx_state = self.rx_states.iter().find(|state| {
-                !state.is_busy(self.clock.now().into_u32(), A::Frequency::frequency())
-            });
+            // Datagrams larger than the reassembly buffers are dropped
+            if dgram_size > MAX_DGRAM_SIZE {
+                return (None, ReturnCode::ESIZE);
+            }
+            rx_state = self.free_rx_state(dgram_size as usize);
             // Initialize new state
             rx_state.map(|state| {
                 state.start_receive(
@@ -986,7 +1060,7 @@ impl<'a, A: time::Alarm<'a>, C: ContextStore> Sixlowpan<'a, A, C> {
                     dst_mac_addr,
                     dgram_size,
                     dgram_tag,
-                    self.clock.now().into_u32(),
+                    self.clock.now().into_u64(),
                 )
             });
             if rx_state.is_none() {
@@ -1024,7 +1098,7 @@ impl<'a, A: time::Alarm<'a>, C: ContextStore> Sixlowpan<'a, A, C> {
     // to expire all pending state.
     fn discard_all_state(&self) {
         for rx_state in self.rx_states.iter() {
-            rx_state.end_receive(None, ReturnCode::FAIL);
+            rx_state.end_receive(None, None, ReturnCode::FAIL);
         }
         unimplemented!();
         // TODO: Need to get buffer back from Mac layer on disassociation
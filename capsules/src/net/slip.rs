@@ -0,0 +1,235 @@
+//! SLIP (RFC 1055), an `IpLink` that carries IPv6 packets over a UART.
+//!
+//! Each packet is sent as one frame ended by an `END` byte, with the `END`
+//! and `ESC` bytes in it escaped. A frame is also started with an `END`, as
+//! RFC 1055 suggests, so that line noise before it makes a separate frame
+//! that is dropped. Received frames are passed up whole; those longer than
+//! the receive buffer are dropped.
+//!
+//! On a Linux host, the other end of the link is set up with `slattach`:
+//!
+//! ```txt
+//! slattach -L -s 115200 -p slip /dev/ttyUSB0 &
+//! ip addr add fd00::1/64 dev sl0
+//! ip link set sl0 up mtu 1280
+//! ```
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//! # use capsules::net::slip::SlipLink;
+//!
+//! let slip = static_init!(
+//!     SlipLink<'static>,
+//!     SlipLink::new(uart, &mut SLIP_TX_BUF, &mut SLIP_RX_BYTE, &mut SLIP_RX_BUF)
+//! );
+//! hil::uart::Transmit::set_transmit_client(uart, slip);
+//! hil::uart::Receive::set_receive_client(uart, slip);
+//! slip.start_receive();
+//! ```
+
+use crate::net::ipv6::ip_link::{IpLink, IpLinkRxClient, IpLinkTxClient};
+use crate::net::ipv6::ip_utils::IPAddr;
+use core::cell::Cell;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::uart;
+use kernel::ReturnCode;
+
+pub const END: u8 = 0xc0;
+pub const ESC: u8 = 0xdb;
+pub const ESC_END: u8 = 0xdc;
+pub const ESC_ESC: u8 = 0xdd;
+
+/// The size of the frame buffer that a packet of `mtu` bytes needs, if
+/// every byte is escaped.
+pub const fn frame_buf_len(mtu: usize) -> usize {
+    2 * mtu + 2
+}
+
+pub struct SlipLink<'a> {
+    uart: &'a dyn uart::UartData<'a>,
+    /// The frame being sent.
+    tx_buf: TakeCell<'static, [u8]>,
+    /// The packet of the frame being sent, returned in `transmit_done`.
+    tx_packet: TakeCell<'static, [u8]>,
+    /// Bytes are received one at a time, into this buffer.
+    rx_byte: TakeCell<'static, [u8]>,
+    rx_buf: TakeCell<'static, [u8]>,
+    rx_len: Cell<usize>,
+    rx_escaped: Cell<bool>,
+    /// The frame being received did not fit `rx_buf`.
+    rx_overflow: Cell<bool>,
+    tx_client: OptionalCell<&'a dyn IpLinkTxClient>,
+    rx_client: OptionalCell<&'a dyn IpLinkRxClient>,
+}
+
+impl<'a> SlipLink<'a> {
+    /// `rx_byte` must hold at least a byte. The MTU of the link is the
+    /// length of `rx_buf`, and `tx_buf` must hold `frame_buf_len` of it.
+    pub fn new(
+        uart: &'a dyn uart::UartData<'a>,
+        tx_buf: &'static mut [u8],
+        rx_byte: &'static mut [u8],
+        rx_buf: &'static mut [u8],
+    ) -> SlipLink<'a> {
+        SlipLink {
+            uart: uart,
+            tx_buf: TakeCell::new(tx_buf),
+            tx_packet: TakeCell::empty(),
+            rx_byte: TakeCell::new(rx_byte),
+            rx_buf: TakeCell::new(rx_buf),
+            rx_len: Cell::new(0),
+            rx_escaped: Cell::new(false),
+            rx_overflow: Cell::new(false),
+            tx_client: OptionalCell::empty(),
+            rx_client: OptionalCell::empty(),
+        }
+    }
+
+    /// Starts receiving frames.
+    pub fn start_receive(&self) {
+        self.rx_byte.take().map(|rx_byte| {
+            let (rcode, rx_byte) = self.uart.receive_buffer(rx_byte, 1);
+            if rcode != ReturnCode::SUCCESS {
+                rx_byte.map(|rx_byte| self.rx_byte.replace(rx_byte));
+            }
+        });
+    }
+
+    /// Adds a received byte to the frame, passing the frame up once it
+    /// ends.
+    fn receive_byte(&self, byte: u8) {
+        if byte == END {
+            let len = self.rx_len.get();
+            let overflow = self.rx_overflow.get();
+            self.rx_len.set(0);
+            self.rx_escaped.set(false);
+            self.rx_overflow.set(false);
+            if len > 0 && !overflow {
+                self.rx_buf.map(|rx_buf| {
+                    self.rx_client
+                        .map(|client| client.receive(&rx_buf[..len], None));
+                });
+            }
+            return;
+        }
+        let byte = if self.rx_escaped.get() {
+            self.rx_escaped.set(false);
+            match byte {
+                ESC_END => END,
+                ESC_ESC => ESC,
+                // A protocol violation, which RFC 1055 leaves the byte for
+                _ => byte,
+            }
+        } else if byte == ESC {
+            self.rx_escaped.set(true);
+            return;
+        } else {
+            byte
+        };
+        let len = self.rx_len.get();
+        self.rx_buf.map(|rx_buf| {
+            if len < rx_buf.len() {
+                rx_buf[len] = byte;
+                self.rx_len.set(len + 1);
+            } else {
+                self.rx_overflow.set(true);
+            }
+        });
+    }
+}
+
+impl<'a> IpLink<'a> for SlipLink<'a> {
+    fn set_transmit_client(&self, client: &'a dyn IpLinkTxClient) {
+        self.tx_client.set(client);
+    }
+
+    fn set_receive_client(&self, client: &'a dyn IpLinkRxClient) {
+        self.rx_client.set(client);
+    }
+
+    fn get_mtu(&self) -> usize {
+        self.rx_buf.map_or(0, |rx_buf| rx_buf.len())
+    }
+
+    /// A point-to-point link, so `next_hop` is ignored.
+    fn transmit(
+        &self,
+        _next_hop: IPAddr,
+        buf: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        let tx_buf = match self.tx_buf.take() {
+            Some(tx_buf) => tx_buf,
+            None => return Err((ReturnCode::EBUSY, buf)),
+        };
+        if len > buf.len() || frame_buf_len(len) > tx_buf.len() {
+            self.tx_buf.replace(tx_buf);
+            return Err((ReturnCode::ESIZE, buf));
+        }
+        let mut off = 0;
+        tx_buf[off] = END;
+        off += 1;
+        for byte in buf[..len].iter() {
+            match *byte {
+                END => {
+                    tx_buf[off] = ESC;
+                    tx_buf[off + 1] = ESC_END;
+                    off += 2;
+                }
+                ESC => {
+                    tx_buf[off] = ESC;
+                    tx_buf[off + 1] = ESC_ESC;
+                    off += 2;
+                }
+                byte => {
+                    tx_buf[off] = byte;
+                    off += 1;
+                }
+            }
+        }
+        tx_buf[off] = END;
+        off += 1;
+
+        let (rcode, tx_buf) = self.uart.transmit_buffer(tx_buf, off);
+        if rcode != ReturnCode::SUCCESS {
+            tx_buf.map(|tx_buf| self.tx_buf.replace(tx_buf));
+            return Err((rcode, buf));
+        }
+        self.tx_packet.replace(buf);
+        Ok(())
+    }
+}
+
+impl<'a> uart::TransmitClient for SlipLink<'a> {
+    fn transmitted_buffer(&self, tx_buffer: &'static mut [u8], _tx_len: usize, rval: ReturnCode) {
+        self.tx_buf.replace(tx_buffer);
+        if let Some(packet) = self.tx_packet.take() {
+            self.tx_client
+                .map(move |client| client.transmit_done(packet, rval));
+        }
+    }
+
+    fn transmitted_word(&self, _rval: ReturnCode) {}
+}
+
+impl<'a> uart::ReceiveClient for SlipLink<'a> {
+    fn received_buffer(
+        &self,
+        rx_buffer: &'static mut [u8],
+        rx_len: usize,
+        rval: ReturnCode,
+        _error: uart::Error,
+    ) {
+        if rval == ReturnCode::SUCCESS && rx_len > 0 {
+            self.receive_byte(rx_buffer[0]);
+        } else {
+            // A lost byte spoils the frame
+            self.rx_overflow.set(true);
+        }
+        self.rx_byte.replace(rx_buffer);
+        self.start_receive();
+    }
+}
@@ -0,0 +1,2 @@
+pub mod sntp;
+pub mod sntp_client;
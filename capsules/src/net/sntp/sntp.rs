@@ -0,0 +1,143 @@
+//! This file contains the packet format of SNTP (RFC 4330), the simple
+//! subset of NTP that clients use to learn the time from a server. A packet
+//! is a fixed 48 byte header with the timestamps of a request and its
+//! answer, so this only encodes and decodes that header.
+
+use crate::net::stream::SResult;
+use crate::net::stream::{decode_u32, decode_u8, encode_u32, encode_u8};
+use core::time::Duration;
+
+pub const NTP_PORT: u16 = 123;
+pub const NTP_PACKET_LEN: usize = 48;
+pub const NTP_VERSION: u8 = 4;
+
+/// Seconds from the NTP epoch, 1900-01-01, to the Unix epoch.
+pub const UNIX_EPOCH_OFFSET: u64 = 2_208_988_800;
+
+pub mod modes {
+    pub const CLIENT: u8 = 3;
+    pub const SERVER: u8 = 4;
+    pub const BROADCAST: u8 = 5;
+}
+
+/// The leap indicator of a server whose clock is not synchronized.
+pub const LEAP_ALARM: u8 = 3;
+
+/// A timestamp, in seconds and fractions of a second since the NTP epoch,
+/// which wrap every 136 years.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct NtpTimestamp {
+    pub seconds: u32,
+    pub fraction: u32,
+}
+
+impl NtpTimestamp {
+    pub const ZERO: NtpTimestamp = NtpTimestamp {
+        seconds: 0,
+        fraction: 0,
+    };
+
+    /// The timestamp of `time` since the Unix epoch.
+    pub fn from_unix(time: Duration) -> NtpTimestamp {
+        NtpTimestamp {
+            seconds: (time.as_secs() + UNIX_EPOCH_OFFSET) as u32,
+            fraction: (((time.subsec_nanos() as u64) << 32) / 1_000_000_000) as u32,
+        }
+    }
+
+    /// The time since the Unix epoch of the timestamp. Timestamps with the
+    /// top bit clear are after the seconds wrapped in 2036 (RFC 4330, 3).
+    pub fn to_unix(&self) -> Duration {
+        let seconds = if self.seconds & 0x8000_0000 != 0 {
+            self.seconds as u64 - UNIX_EPOCH_OFFSET
+        } else {
+            self.seconds as u64 + (1 << 32) - UNIX_EPOCH_OFFSET
+        };
+        let nanos = ((self.fraction as u64) * 1_000_000_000) >> 32;
+        Duration::new(seconds, nanos as u32)
+    }
+
+    pub fn encode(&self, buf: &mut [u8]) -> SResult {
+        let off = enc_consume!(buf; encode_u32, self.seconds);
+        let off = enc_consume!(buf, off; encode_u32, self.fraction);
+        stream_done!(off);
+    }
+
+    pub fn decode(buf: &[u8]) -> SResult<NtpTimestamp> {
+        let (off, seconds) = dec_try!(buf; decode_u32);
+        let (off, fraction) = dec_try!(buf, off; decode_u32);
+        stream_done!(
+            off,
+            NtpTimestamp {
+                seconds: seconds,
+                fraction: fraction,
+            }
+        );
+    }
+}
+
+/// The fields of a packet that a client uses. The fields it does not use,
+/// such as the root delay and dispersion, are zero in requests.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct NtpPacket {
+    pub leap: u8,
+    pub version: u8,
+    pub mode: u8,
+    pub stratum: u8,
+    /// The transmit timestamp of the request this answers.
+    pub originate: NtpTimestamp,
+    /// When the server received the request.
+    pub receive: NtpTimestamp,
+    /// When the packet was sent.
+    pub transmit: NtpTimestamp,
+}
+
+impl NtpPacket {
+    /// A request sent at `transmit`, which the answer repeats in its
+    /// originate timestamp.
+    pub fn request(transmit: NtpTimestamp) -> NtpPacket {
+        NtpPacket {
+            leap: 0,
+            version: NTP_VERSION,
+            mode: modes::CLIENT,
+            stratum: 0,
+            originate: NtpTimestamp::ZERO,
+            receive: NtpTimestamp::ZERO,
+            transmit: transmit,
+        }
+    }
+
+    pub fn encode(&self, buf: &mut [u8]) -> SResult {
+        stream_len_cond!(buf, NTP_PACKET_LEN);
+        for byte in buf[..NTP_PACKET_LEN].iter_mut() {
+            *byte = 0;
+        }
+        enc_consume!(buf; encode_u8, self.leap << 6 | self.version << 3 | self.mode);
+        enc_consume!(buf, 1; encode_u8, self.stratum);
+        let off = enc_consume!(buf, 24; self.originate; encode);
+        let off = enc_consume!(buf, off; self.receive; encode);
+        let off = enc_consume!(buf, off; self.transmit; encode);
+        stream_done!(off);
+    }
+
+    pub fn decode(buf: &[u8]) -> SResult<NtpPacket> {
+        stream_len_cond!(buf, NTP_PACKET_LEN);
+        let (_, first) = dec_try!(buf; decode_u8);
+        let (_, stratum) = dec_try!(buf, 1; decode_u8);
+        let (off, originate) = dec_try!(buf, 24; NtpTimestamp::decode);
+        let (off, receive) = dec_try!(buf, off; NtpTimestamp::decode);
+        let (off, transmit) = dec_try!(buf, off; NtpTimestamp::decode);
+        stream_done!(
+            off,
+            NtpPacket {
+                leap: first >> 6,
+                version: (first >> 3) & 0x7,
+                mode: first & 0x7,
+                stratum: stratum,
+                originate: originate,
+                receive: receive,
+                transmit: transmit,
+            }
+        );
+    }
+}
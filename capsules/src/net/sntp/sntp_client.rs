@@ -0,0 +1,367 @@
+//! An SNTP client for the kernel UDP stack.
+//!
+//! `SntpClient` keeps the clock of a `clock::ClockDriver` synchronized with
+//! the server set with `set_server()`: after `start()`, it asks the server
+//! for the time at once and then every `interval_s` seconds, and adjusts
+//! the clock to the time the server answered with, plus half the round trip
+//! of the request, which also tells subscribed apps that the clock changed.
+//! The round trip is measured with the alarm of the client, so the clock
+//! does not need to be set for the first request.
+//!
+//! Requests are retransmitted `MAX_RETRIES` times. If the server does not
+//! answer them, or answers that it is not synchronized, the client asks
+//! again after `FAILED_RETRY_S` seconds, or after the interval if it is
+//! shorter. Answers are only accepted from the server, if they repeat the
+//! transmit timestamp of the request, which is a counter started at
+//! `initial_nonce` in the fraction of a second rather than the time.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//! # use capsules::net::sntp::sntp_client::SntpClient;
+//!
+//! let sntp = static_init!(
+//!     SntpClient<'static, VirtualMuxAlarm<'static, sam4l::ast::Ast>, Rtc>,
+//!     SntpClient::new(
+//!         udp_send,
+//!         udp_recv,
+//!         udp_port_table,
+//!         sntp_alarm,
+//!         clock,
+//!         LeasableBuffer::new(&mut SNTP_BUF),
+//!         initial_nonce,
+//!         net_cap,
+//!     )
+//! );
+//! udp_send.set_client(sntp);
+//! udp_recv.set_client(sntp);
+//! sntp_alarm.set_alarm_client(sntp);
+//! sntp.bind(SNTP_CLIENT_PORT);
+//! sntp.set_server(ntp_server_addr);
+//! sntp.start(3600);
+//! ```
+
+use crate::clock::ClockDriver;
+use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::network_capabilities::NetworkCapability;
+use crate::net::sntp::sntp::{modes, NtpPacket, NtpTimestamp, LEAP_ALARM, NTP_PORT};
+use crate::net::udp::udp_port_table::UdpPortManager;
+use crate::net::udp::udp_recv::{UDPReceiver, UDPRecvClient};
+use crate::net::udp::udp_send::{UDPSendClient, UDPSender};
+use core::cell::Cell;
+use core::time::Duration;
+use kernel::common::cells::MapCell;
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::hil::date_time::{DateTime, DateTimeValues};
+use kernel::hil::time::{self, Alarm, Ticks};
+use kernel::{log_info, log_warn, ReturnCode};
+
+/// Requests are retransmitted, and the interval is counted, in ticks.
+pub const TICK_MS: u32 = 1000;
+
+pub const REQUEST_TIMEOUT_TICKS: u8 = 2;
+pub const MAX_RETRIES: u8 = 3;
+pub const FAILED_RETRY_S: u32 = 60;
+
+#[derive(Copy, Clone)]
+struct Request {
+    transmit: NtpTimestamp,
+    retries: u8,
+    timer: u8,
+    send_pending: bool,
+}
+
+pub struct SntpClient<'a, A: Alarm<'a>, R: DateTime<'a>> {
+    udp_sender: &'a dyn UDPSender<'a>,
+    udp_receiver: &'a UDPReceiver<'a>,
+    port_table: &'static UdpPortManager,
+    alarm: &'a A,
+    clock: &'a ClockDriver<'a, R>,
+    net_cap: &'static NetworkCapability,
+    server: Cell<Option<IPAddr>>,
+    tx_buf: MapCell<LeasableBuffer<'static, u8>>,
+    /// The seconds between requests, or 0 if the client is stopped.
+    interval_s: Cell<u32>,
+    /// The seconds until the next request.
+    next_request: Cell<u32>,
+    request: Cell<Option<Request>>,
+    /// When the request was last sent.
+    sent_at: Cell<A::Ticks>,
+    next_nonce: Cell<u32>,
+    /// When the current tick started.
+    tick_start: Cell<A::Ticks>,
+}
+
+impl<'a, A: Alarm<'a>, R: DateTime<'a>> SntpClient<'a, A, R> {
+    /// `initial_nonce` starts the nonces of the requests, which boards
+    /// should vary between boots, for example from a random number.
+    pub fn new(
+        udp_sender: &'a dyn UDPSender<'a>,
+        udp_receiver: &'a UDPReceiver<'a>,
+        port_table: &'static UdpPortManager,
+        alarm: &'a A,
+        clock: &'a ClockDriver<'a, R>,
+        tx_buf: LeasableBuffer<'static, u8>,
+        initial_nonce: u32,
+        net_cap: &'static NetworkCapability,
+    ) -> SntpClient<'a, A, R> {
+        SntpClient {
+            udp_sender: udp_sender,
+            udp_receiver: udp_receiver,
+            port_table: port_table,
+            alarm: alarm,
+            clock: clock,
+            net_cap: net_cap,
+            server: Cell::new(None),
+            tx_buf: MapCell::new(tx_buf),
+            interval_s: Cell::new(0),
+            next_request: Cell::new(0),
+            request: Cell::new(None),
+            sent_at: Cell::new(A::Ticks::from(0)),
+            next_nonce: Cell::new(initial_nonce),
+            tick_start: Cell::new(A::Ticks::from(0)),
+        }
+    }
+
+    /// Binds the client to `port`, which requests are sent from.
+    pub fn bind(&self, port: u16) -> ReturnCode {
+        if self.udp_sender.is_bound() {
+            return ReturnCode::EALREADY;
+        }
+        let socket = match self.port_table.create_socket() {
+            Ok(socket) => socket,
+            Err(rcode) => return rcode,
+        };
+        self.port_table.set_owner(&socket, "sntp");
+        match self.port_table.bind(socket, port, self.net_cap) {
+            Ok((send_binding, recv_binding)) => {
+                self.udp_sender.set_binding(send_binding);
+                self.udp_receiver.set_binding(recv_binding);
+                ReturnCode::SUCCESS
+            }
+            Err(_socket) => ReturnCode::EBUSY,
+        }
+    }
+
+    /// Sets the server the time is asked of.
+    pub fn set_server(&self, server: IPAddr) {
+        self.server.set(Some(server));
+    }
+
+    /// Asks the server for the time now, and then every `interval_s`
+    /// seconds. Returns `EINVAL` if there is no server or the interval is
+    /// 0.
+    pub fn start(&self, interval_s: u32) -> ReturnCode {
+        if self.server.get().is_none() || interval_s == 0 {
+            return ReturnCode::EINVAL;
+        }
+        self.interval_s.set(interval_s);
+        self.sync()
+    }
+
+    /// Stops asking the server for the time.
+    pub fn stop(&self) {
+        self.interval_s.set(0);
+        self.request.set(None);
+    }
+
+    /// Asks the server for the time, outside of the interval. Returns
+    /// `EBUSY` while a request is outstanding.
+    pub fn sync(&self) -> ReturnCode {
+        if self.server.get().is_none() {
+            return ReturnCode::EINVAL;
+        }
+        if self.request.get().is_some() {
+            return ReturnCode::EBUSY;
+        }
+        let nonce = self.next_nonce.get();
+        self.next_nonce.set(nonce.wrapping_add(1));
+        self.request.set(Some(Request {
+            transmit: NtpTimestamp {
+                seconds: 0,
+                fraction: nonce,
+            },
+            retries: 0,
+            timer: REQUEST_TIMEOUT_TICKS,
+            send_pending: true,
+        }));
+        self.start_timer();
+        self.send_request();
+        ReturnCode::SUCCESS
+    }
+
+    fn start_timer(&self) {
+        if !self.alarm.is_armed() {
+            self.tick_start.set(self.alarm.now());
+            self.alarm
+                .set_alarm(self.alarm.now(), A::ticks_from_ms(TICK_MS));
+        }
+    }
+
+    /// Sends the request if it is pending and the buffer is free.
+    fn send_request(&self) {
+        let mut request = match self.request.get() {
+            Some(request) if request.send_pending => request,
+            _ => return,
+        };
+        let server = match self.server.get() {
+            Some(server) => server,
+            None => return,
+        };
+        let mut dgram = match self.tx_buf.take() {
+            Some(dgram) => dgram,
+            None => return,
+        };
+        request.send_pending = false;
+        self.request.set(Some(request));
+        self.sent_at.set(self.alarm.now());
+
+        match NtpPacket::request(request.transmit)
+            .encode(&mut dgram[..])
+            .done()
+        {
+            Some((len, _)) => {
+                dgram.slice(..len);
+                if let Err(mut dgram) =
+                    self.udp_sender
+                        .send_to(server, NTP_PORT, dgram, self.net_cap)
+                {
+                    // Retransmitted like a lost request
+                    dgram.reset();
+                    self.tx_buf.replace(dgram);
+                }
+            }
+            None => {
+                self.tx_buf.replace(dgram);
+                self.finish(false);
+            }
+        }
+    }
+
+    /// Ends the request, and schedules the next one.
+    fn finish(&self, synchronized: bool) {
+        self.request.set(None);
+        let interval_s = self.interval_s.get();
+        self.next_request.set(if synchronized {
+            interval_s
+        } else {
+            core::cmp::min(interval_s, FAILED_RETRY_S)
+        });
+    }
+
+    /// Counts down the request and the interval by a tick.
+    fn tick(&self) {
+        match self.request.get() {
+            Some(mut request) => {
+                request.timer -= 1;
+                if request.timer == 0 {
+                    if request.retries >= MAX_RETRIES {
+                        log_warn!("SNTP server did not answer");
+                        self.finish(false);
+                        return;
+                    }
+                    request.retries += 1;
+                    request.timer = REQUEST_TIMEOUT_TICKS;
+                    request.send_pending = true;
+                }
+                self.request.set(Some(request));
+            }
+            None => {
+                if self.interval_s.get() == 0 {
+                    return;
+                }
+                let next_request = self.next_request.get().saturating_sub(1);
+                self.next_request.set(next_request);
+                if next_request == 0 {
+                    self.sync();
+                }
+            }
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>, R: DateTime<'a>> time::AlarmClient for SntpClient<'a, A, R> {
+    fn alarm(&self) {
+        let tick = A::ticks_from_ms(TICK_MS);
+        let now = self.alarm.now();
+        while now.wrapping_sub(self.tick_start.get()) >= tick {
+            self.tick_start
+                .set(self.tick_start.get().wrapping_add(tick));
+            self.tick();
+        }
+        self.send_request();
+
+        if self.request.get().is_some() || self.interval_s.get() != 0 {
+            self.alarm.set_alarm(self.tick_start.get(), tick);
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>, R: DateTime<'a>> UDPSendClient for SntpClient<'a, A, R> {
+    fn send_done(&self, _result: ReturnCode, mut dgram: LeasableBuffer<'static, u8>) {
+        dgram.reset();
+        self.tx_buf.replace(dgram);
+        self.send_request();
+    }
+}
+
+impl<'a, A: Alarm<'a>, R: DateTime<'a>> UDPRecvClient for SntpClient<'a, A, R> {
+    fn receive(
+        &self,
+        src_addr: IPAddr,
+        _dst_addr: IPAddr,
+        src_port: u16,
+        _dst_port: u16,
+        payload: &[u8],
+        _timestamp: Option<u32>,
+    ) {
+        let received_at = self.alarm.now();
+        let request = match self.request.get() {
+            Some(request) => request,
+            None => return,
+        };
+        if self.server.get() != Some(src_addr) || src_port != NTP_PORT {
+            return;
+        }
+        let packet = match NtpPacket::decode(payload).done() {
+            Some((_, packet)) => packet,
+            None => return,
+        };
+        if packet.mode != modes::SERVER || packet.originate != request.transmit {
+            return;
+        }
+        // A stratum of 0 is a kiss-o'-death, asking clients to go away
+        if packet.stratum == 0 || packet.leap == LEAP_ALARM || packet.transmit == NtpTimestamp::ZERO
+        {
+            log_warn!("SNTP server is not synchronized");
+            self.finish(false);
+            return;
+        }
+
+        // The time the request spent in the network, without the time the
+        // server held it
+        let round_trip =
+            Duration::from_micros(A::ticks_to_us(received_at.wrapping_sub(self.sent_at.get())));
+        let held = packet
+            .transmit
+            .to_unix()
+            .checked_sub(packet.receive.to_unix())
+            .unwrap_or_else(|| Duration::from_secs(0));
+        let delay = round_trip
+            .checked_sub(held)
+            .unwrap_or_else(|| Duration::from_secs(0));
+        let time = packet.transmit.to_unix() + delay / 2;
+        // The clock keeps whole seconds, so round to the nearest one
+        let date_time = DateTimeValues::from_unix(time + Duration::from_millis(500));
+        if self.clock.adjust(date_time) == ReturnCode::SUCCESS {
+            log_info!(
+                "SNTP set the time to {}.{:06}",
+                time.as_secs(),
+                time.subsec_micros()
+            );
+        }
+        self.finish(true);
+    }
+}
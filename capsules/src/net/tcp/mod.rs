@@ -0,0 +1,4 @@
+pub mod tcp;
+pub mod tcp_recv;
+pub mod tcp_send;
+pub mod tcp_socket;
@@ -0,0 +1,283 @@
+//! This file contains the structs and methods associated with the TCP header.
+//! This includes getters and setters for the various header fields, as well
+//! as the standard encode/decode functionality required for serializing
+//! the struct for transmission. It also defines the connection states and
+//! the modular comparisons used for sequence numbers.
+
+use crate::net::stream::SResult;
+use crate::net::stream::{decode_u16, decode_u32, decode_u8};
+use crate::net::stream::{encode_u16, encode_u32, encode_u8};
+
+// Note: Unlike the UDP header, TCP header fields are stored in host byte
+// order, and converted when the header is encoded or decoded.
+
+/// Size of a TCP header without options.
+pub const TCP_HDR_LEN: usize = 20;
+
+/// Size of the largest header we send, which carries the MSS option.
+pub const TCP_MAX_HDR_LEN: usize = 24;
+
+/// The MSS a peer is assumed to accept if it does not send the option: the
+/// IPv6 minimum MTU without the IPv6 and TCP headers (RFC 8200).
+pub const DEFAULT_MSS: u16 = 1220;
+
+pub mod tcp_flags {
+    pub const FIN: u8 = 0x01;
+    pub const SYN: u8 = 0x02;
+    pub const RST: u8 = 0x04;
+    pub const PSH: u8 = 0x08;
+    pub const ACK: u8 = 0x10;
+    pub const URG: u8 = 0x20;
+}
+
+mod tcp_options {
+    pub const END: u8 = 0;
+    pub const NOP: u8 = 1;
+    pub const MSS: u8 = 2;
+    pub const MSS_LEN: u8 = 4;
+}
+
+/// The connection states of RFC 793.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TCPState {
+    Closed,
+    Listen,
+    SynSent,
+    SynReceived,
+    Established,
+    FinWait1,
+    FinWait2,
+    CloseWait,
+    Closing,
+    LastAck,
+    TimeWait,
+}
+
+/// Sequence numbers wrap, so they are compared modulo 2^32 (RFC 793, 3.3).
+pub fn seq_lt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+pub fn seq_leq(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) <= 0
+}
+
+/// The `TCPHeader` struct follows the layout for the TCP segment header.
+/// The only option supported is the maximum segment size, which is sent on
+/// SYN segments; other options of received segments are skipped.
+#[derive(Copy, Clone, Debug)]
+pub struct TCPHeader {
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub seq_num: u32,
+    pub ack_num: u32,
+    pub offset_and_control: u16,
+    pub window: u16,
+    pub cksum: u16,
+    pub urg_ptr: u16,
+    pub mss: Option<u16>,
+    pub len: u16, // Not a real TCP field, the length of header and payload
+}
+
+impl Default for TCPHeader {
+    fn default() -> TCPHeader {
+        TCPHeader {
+            src_port: 0,
+            dst_port: 0,
+            seq_num: 0,
+            ack_num: 0,
+            offset_and_control: ((TCP_HDR_LEN / 4) as u16) << 12,
+            window: 0,
+            cksum: 0,
+            urg_ptr: 0,
+            mss: None,
+            len: TCP_HDR_LEN as u16,
+        }
+    }
+}
+
+impl TCPHeader {
+    pub fn new() -> TCPHeader {
+        TCPHeader::default()
+    }
+
+    pub fn set_src_port(&mut self, port: u16) {
+        self.src_port = port;
+    }
+
+    pub fn set_dst_port(&mut self, port: u16) {
+        self.dst_port = port;
+    }
+
+    pub fn set_seq_num(&mut self, seq_num: u32) {
+        self.seq_num = seq_num;
+    }
+
+    pub fn set_ack_num(&mut self, ack_num: u32) {
+        self.ack_num = ack_num;
+    }
+
+    /// Sets the control bits, a combination of `tcp_flags`.
+    pub fn set_flags(&mut self, flags: u8) {
+        self.offset_and_control = (self.offset_and_control & 0xf000) | flags as u16;
+    }
+
+    pub fn set_window(&mut self, window: u16) {
+        self.window = window;
+    }
+
+    pub fn set_cksum(&mut self, cksum: u16) {
+        self.cksum = cksum;
+    }
+
+    pub fn set_len(&mut self, len: u16) {
+        self.len = len;
+    }
+
+    /// Sets the maximum segment size option, which changes the header size.
+    pub fn set_mss(&mut self, mss: Option<u16>) {
+        self.mss = mss;
+        let words = if mss.is_some() {
+            TCP_MAX_HDR_LEN / 4
+        } else {
+            TCP_HDR_LEN / 4
+        };
+        self.offset_and_control = (self.offset_and_control & 0x0fff) | (words as u16) << 12;
+    }
+
+    pub fn get_src_port(&self) -> u16 {
+        self.src_port
+    }
+
+    pub fn get_dst_port(&self) -> u16 {
+        self.dst_port
+    }
+
+    pub fn get_seq_num(&self) -> u32 {
+        self.seq_num
+    }
+
+    pub fn get_ack_num(&self) -> u32 {
+        self.ack_num
+    }
+
+    pub fn get_flags(&self) -> u8 {
+        self.offset_and_control as u8
+    }
+
+    pub fn has_flag(&self, flag: u8) -> bool {
+        self.get_flags() & flag != 0
+    }
+
+    pub fn get_window(&self) -> u16 {
+        self.window
+    }
+
+    pub fn get_cksum(&self) -> u16 {
+        self.cksum
+    }
+
+    pub fn get_len(&self) -> u16 {
+        self.len
+    }
+
+    pub fn get_mss(&self) -> Option<u16> {
+        self.mss
+    }
+
+    /// The header size given by the data offset field, options included.
+    pub fn get_hdr_size(&self) -> usize {
+        (self.offset_and_control >> 12) as usize * 4
+    }
+
+    /// This function serializes the `TCPHeader` into the provided buffer.
+    ///
+    /// # Arguments
+    ///
+    /// `buf` - A mutable buffer to serialize the `TCPHeader` into
+    /// `offset` - The current offset into the provided buffer
+    ///
+    /// # Return Value
+    ///
+    /// This function returns the new offset into the buffer wrapped in an
+    /// SResult.
+    pub fn encode(&self, buf: &mut [u8], offset: usize) -> SResult<usize> {
+        stream_len_cond!(buf, self.get_hdr_size() + offset);
+
+        let mut off = offset;
+        off = enc_consume!(buf, off; encode_u16, self.src_port);
+        off = enc_consume!(buf, off; encode_u16, self.dst_port);
+        off = enc_consume!(buf, off; encode_u32, self.seq_num);
+        off = enc_consume!(buf, off; encode_u32, self.ack_num);
+        off = enc_consume!(buf, off; encode_u16, self.offset_and_control);
+        off = enc_consume!(buf, off; encode_u16, self.window);
+        off = enc_consume!(buf, off; encode_u16, self.cksum);
+        off = enc_consume!(buf, off; encode_u16, self.urg_ptr);
+        if let Some(mss) = self.mss {
+            off = enc_consume!(buf, off; encode_u8, tcp_options::MSS);
+            off = enc_consume!(buf, off; encode_u8, tcp_options::MSS_LEN);
+            off = enc_consume!(buf, off; encode_u16, mss);
+        }
+        // Pad to the data offset, in case it was set by hand
+        while off < offset + self.get_hdr_size() {
+            off = enc_consume!(buf, off; encode_u8, tcp_options::END);
+        }
+        stream_done!(off, off);
+    }
+
+    /// This function deserializes the `TCPHeader` from the provided buffer.
+    /// `len` is set to the length of the buffer, which should hold the whole
+    /// segment.
+    ///
+    /// # Arguments
+    ///
+    /// `buf` - The byte array corresponding to a serialized `TCPHeader`
+    ///
+    /// # Return Value
+    ///
+    /// This function returns a `TCPHeader` struct wrapped in an SResult, with
+    /// the offset of the segment payload.
+    pub fn decode(buf: &[u8]) -> SResult<TCPHeader> {
+        stream_len_cond!(buf, TCP_HDR_LEN);
+        let mut tcp_header = Self::new();
+        let off = 0;
+        let (off, src_port) = dec_try!(buf, off; decode_u16);
+        tcp_header.src_port = src_port;
+        let (off, dst_port) = dec_try!(buf, off; decode_u16);
+        tcp_header.dst_port = dst_port;
+        let (off, seq_num) = dec_try!(buf, off; decode_u32);
+        tcp_header.seq_num = seq_num;
+        let (off, ack_num) = dec_try!(buf, off; decode_u32);
+        tcp_header.ack_num = ack_num;
+        let (off, offset_and_control) = dec_try!(buf, off; decode_u16);
+        tcp_header.offset_and_control = offset_and_control;
+        let (off, window) = dec_try!(buf, off; decode_u16);
+        tcp_header.window = window;
+        let (off, cksum) = dec_try!(buf, off; decode_u16);
+        tcp_header.cksum = cksum;
+        let (mut off, urg_ptr) = dec_try!(buf, off; decode_u16);
+        tcp_header.urg_ptr = urg_ptr;
+        tcp_header.len = buf.len() as u16;
+
+        let hdr_size = tcp_header.get_hdr_size();
+        stream_cond!(hdr_size >= TCP_HDR_LEN);
+        stream_len_cond!(buf, hdr_size);
+        while off < hdr_size {
+            let (next, kind) = dec_try!(buf, off; decode_u8);
+            match kind {
+                tcp_options::END => break,
+                tcp_options::NOP => off = next,
+                _ => {
+                    let (_, len) = dec_try!(buf, next; decode_u8);
+                    stream_cond!(len >= 2 && off + len as usize <= hdr_size);
+                    if kind == tcp_options::MSS && len == tcp_options::MSS_LEN {
+                        let (_, mss) = dec_try!(buf, next + 1; decode_u16);
+                        tcp_header.mss = Some(mss);
+                    }
+                    off += len as usize;
+                }
+            }
+        }
+        stream_done!(hdr_size, tcp_header);
+    }
+}
@@ -0,0 +1,49 @@
+//! This file contains the interface for receiving on a TCP connection. It
+//! follows the model of `tcp_send.rs`: the [TCPReceiver](trait.TCPReceiver.html)
+//! trait is implemented by connections, and lets a kernel capsule wait for
+//! connections on a port, while the [TCPRecvClient](trait.TCPRecvClient.html)
+//! trait is implemented by the capsule to be told about the bytes and
+//! connection events of the stream.
+//! Received bytes are passed to the client in order as soon as they arrive,
+//! and are not buffered, so the receive window is always fully open.
+
+use crate::net::network_capabilities::NetworkCapability;
+use kernel::ReturnCode;
+
+/// Kernel capsules implement this trait to be told about the events of a
+/// connection they opened or are listening for.
+pub trait TCPRecvClient {
+    /// The connection is established, or could not be with `FAIL`. For a
+    /// listening socket this is called once a peer connected.
+    fn connected(&self, result: ReturnCode);
+
+    /// The next bytes of the stream arrived.
+    fn receive(&self, payload: &[u8]);
+
+    /// The peer closed its sending half, so no more bytes will arrive.
+    /// Sending is still possible until `TCPSender::close` is called.
+    fn remote_closed(&self);
+
+    /// The connection is closed, and can be reused. `SUCCESS` if both sides
+    /// closed it, `FAIL` if it was reset or the peer stopped answering.
+    fn closed(&self, result: ReturnCode);
+}
+
+/// This trait represents the receiving half of a TCP connection.
+pub trait TCPReceiver<'a> {
+    /// This function sets the client for the `TCPReceiver` instance
+    fn set_client(&self, client: &'a dyn TCPRecvClient);
+
+    /// This function passively opens a connection, waiting for a peer to
+    /// connect to `src_port`. Like a UDP binding, only a single connection
+    /// is accepted; once it is closed, `listen` must be called again.
+    ///
+    /// # Arguments
+    /// `src_port` - Local port to accept a connection on
+    /// `net_cap` - Capability the peer address is checked against when
+    /// replying
+    ///
+    /// # Return Value
+    /// `EBUSY` if the connection is not closed.
+    fn listen(&'a self, src_port: u16, net_cap: &'static NetworkCapability) -> ReturnCode;
+}
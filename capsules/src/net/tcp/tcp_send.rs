@@ -0,0 +1,81 @@
+//! This file contains the interface for sending on a TCP connection. The
+//! [TCPSender](trait.TCPSender.html) trait provides an interface for kernel
+//! capsules to open a connection and send a stream of bytes over it, and the
+//! [TCPSendClient](trait.TCPSendClient.html) trait is implemented by upper
+//! layer clients to receive `send_done` callbacks once the peer has
+//! acknowledged the bytes passed to `send`.
+//! As with UDP, each sender may have a single outstanding buffer at a time.
+//! The buffer is kept by the connection until every byte in it has been
+//! acknowledged, as it may need to be retransmitted.
+//! The only implementation of these traits is
+//! [TCPSocket](../tcp_socket/struct.TCPSocket.html).
+
+use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::network_capabilities::NetworkCapability;
+use crate::net::tcp::tcp::TCPState;
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::ReturnCode;
+
+/// The `send_done` function in this trait is invoked once the peer has
+/// acknowledged all of the bytes of the buffer passed to `TCPSender::send`,
+/// or with an error if the connection was closed before it did. Note that
+/// the `TCPSender::set_client` method must be called to set the client.
+pub trait TCPSendClient {
+    fn send_done(&self, result: ReturnCode, buf: LeasableBuffer<'static, u8>);
+}
+
+/// This trait represents the sending half of a TCP connection. Opening a
+/// connection with `connect` is reported through the
+/// `TCPRecvClient::connected` callback, like a connection accepted by a
+/// listening socket.
+pub trait TCPSender<'a> {
+    /// This function sets the client for the `TCPSender` instance
+    ///
+    /// # Arguments
+    /// `client` - Implementation of `TCPSendClient` to be set as the client
+    /// for the `TCPSender` instance
+    fn set_client(&self, client: &'a dyn TCPSendClient);
+
+    /// This function actively opens a connection to the provided address and
+    /// port, from `src_port`.
+    ///
+    /// # Arguments
+    /// `dest` - IPv6 address to connect to
+    /// `dst_port` - Destination port to connect to
+    /// `src_port` - Local port of the connection
+    /// `net_cap` - Capability the destination is checked against
+    ///
+    /// # Return Value
+    /// `EBUSY` if the connection is not closed. Otherwise the result is
+    /// delivered via the `TCPRecvClient::connected` callback.
+    fn connect(
+        &'a self,
+        dest: IPAddr,
+        dst_port: u16,
+        src_port: u16,
+        net_cap: &'static NetworkCapability,
+    ) -> ReturnCode;
+
+    /// This function queues `buf` to be sent over the connection. It is
+    /// returned through `send_done`.
+    ///
+    /// # Return Value
+    /// Returns the buffer if it is empty, if the connection is not open for
+    /// sending or if a previous buffer has not been acknowledged yet.
+    fn send(&'a self, buf: LeasableBuffer<'static, u8>) -> Result<(), LeasableBuffer<'static, u8>>;
+
+    /// This function closes the sending half of the connection, once the
+    /// queued bytes are sent. The connection is closed when both sides are
+    /// done, which is reported through `TCPRecvClient::closed`. A connection
+    /// that is not established yet is closed right away, without callbacks.
+    ///
+    /// # Return Value
+    /// `EALREADY` if this side of the connection is already closed.
+    fn close(&'a self) -> ReturnCode;
+
+    /// This function resets the connection. An unacknowledged buffer is
+    /// returned through `send_done` with `FAIL`; no other callbacks follow.
+    fn abort(&'a self);
+
+    fn get_state(&self) -> TCPState;
+}
@@ -0,0 +1,770 @@
+//! This file contains the TCP connection state machine, with the
+//! [TCPSocket](struct.TCPSocket.html) struct implementing both the
+//! `TCPSender` and `TCPReceiver` traits for a single connection, and the
+//! [MuxTcp](struct.MuxTcp.html) struct that virtualizes the IPv6 layer
+//! between all of the sockets.
+//!
+//! The MuxTcp demultiplexes received segments to the socket of their
+//! connection, or to a socket listening on their destination port, and acts
+//! as a FIFO queue for sockets with segments to send, building each segment
+//! only once the IP layer is free. It also runs a single tick timer, every
+//! `TICK_MS`, that drives the retransmission and TIME-WAIT timers of all
+//! sockets. Segments that match no socket are dropped.
+//!
+//! The implementation is deliberately small:
+//!
+//! - Unacknowledged bytes are retransmitted go-back-N from the oldest one,
+//!   with an exponential backoff from the one second RTO of RFC 6298. After
+//!   `MAX_RETRANSMITS` timeouts in a row the connection is reset.
+//! - The send window is the window the peer advertised, and the segment size
+//!   the smaller of its MSS option and the MuxTcp segment buffer. When the
+//!   peer window is closed the retransmission timer probes it with a byte.
+//! - Received bytes are delivered in order and right away, so the receive
+//!   window is constant. Segments that arrive out of order are dropped and
+//!   answered with a duplicate ACK, so the peer resends them.
+//! - Every segment is acknowledged immediately; there are no delayed ACKs.
+//! - TIME-WAIT lasts `TIME_WAIT_TICKS`, far shorter than the four minutes of
+//!   RFC 793, since sockets are few and statically allocated.
+//!
+//! Usage
+//! -----
+//!
+//! The sockets need their own IPv6 sender and receiver, with the MuxTcp as
+//! the client of both:
+//!
+//! ```rust
+//! # use kernel::static_init;
+//! # use capsules::net::tcp::tcp_socket::{MuxTcp, TCPSocket};
+//! # use capsules::net::tcp::tcp_recv::TCPReceiver;
+//! # use capsules::net::tcp::tcp_send::TCPSender;
+//!
+//! let tcp_mux = static_init!(
+//!     MuxTcp<'static, VirtualMuxAlarm<'static, sam4l::ast::Ast>>,
+//!     MuxTcp::new(ip_send, tcp_alarm, &mut TCP_SEGMENT_BUF)
+//! );
+//! ip_send.set_client(tcp_mux);
+//! ip_receive.set_client(tcp_mux);
+//! tcp_alarm.set_alarm_client(tcp_mux);
+//!
+//! let socket = static_init!(
+//!     TCPSocket<'static, VirtualMuxAlarm<'static, sam4l::ast::Ast>>,
+//!     TCPSocket::new(tcp_mux)
+//! );
+//! tcp_mux.add_socket(socket);
+//! TCPSender::set_client(socket, capsule);
+//! TCPReceiver::set_client(socket, capsule);
+//! socket.connect(border_router, 4000, 49152, net_cap);
+//! ```
+
+use crate::net::ipv6::ip_utils::{ip6_nh, IPAddr};
+use crate::net::ipv6::ipv6::{IP6Header, TransportHeader};
+use crate::net::ipv6::ipv6_recv::IP6RecvClient;
+use crate::net::ipv6::ipv6_send::{IP6SendClient, IP6Sender};
+use crate::net::network_capabilities::NetworkCapability;
+use crate::net::tcp::tcp::{seq_leq, seq_lt, tcp_flags, TCPHeader, TCPState, DEFAULT_MSS};
+use crate::net::tcp::tcp_recv::{TCPReceiver, TCPRecvClient};
+use crate::net::tcp::tcp_send::{TCPSendClient, TCPSender};
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::cells::{MapCell, OptionalCell, TakeCell};
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::common::{List, ListLink, ListNode};
+use kernel::hil::time::{self, Alarm, Ticks};
+use kernel::ReturnCode;
+
+/// Period of the timer that drives retransmissions and TIME-WAIT.
+pub const TICK_MS: u32 = 100;
+
+/// Initial retransmission timeout, in ticks.
+const RTO_TICKS: u16 = 10;
+
+/// The retransmission timeout doubles on every timeout up to this, in ticks.
+const MAX_RTO_TICKS: u16 = 600;
+
+/// How many timeouts in a row reset the connection.
+const MAX_RETRANSMITS: u8 = 6;
+
+const TIME_WAIT_TICKS: u16 = 20;
+
+/// The window advertised on every segment.
+pub const RCV_WND: u16 = 1024;
+
+/// Step between the initial sequence numbers of successive connections, on
+/// top of the clock, as in BSD.
+const ISS_STEP: u32 = 64000;
+
+pub struct MuxTcp<'a, A: Alarm<'a>> {
+    sockets: List<'a, TCPSocket<'a, A>>,
+    ip_sender: &'a dyn IP6Sender<'a>,
+    alarm: &'a A,
+    /// Holds the payload of the segment being passed to the IP layer.
+    tx_buf: TakeCell<'static, [u8]>,
+    mss: u16,
+    sending: Cell<bool>,
+    iss_offset: Cell<u32>,
+}
+
+impl<'a, A: Alarm<'a>> MuxTcp<'a, A> {
+    /// `tx_buf` bounds the segments sent; the IP layer packet buffer must
+    /// hold it plus `TCP_MAX_HDR_LEN`.
+    pub fn new(
+        ip_sender: &'a dyn IP6Sender<'a>,
+        alarm: &'a A,
+        tx_buf: &'static mut [u8],
+    ) -> MuxTcp<'a, A> {
+        let mss = cmp::min(tx_buf.len(), DEFAULT_MSS as usize) as u16;
+        MuxTcp {
+            sockets: List::new(),
+            ip_sender: ip_sender,
+            alarm: alarm,
+            tx_buf: TakeCell::new(tx_buf),
+            mss: mss,
+            sending: Cell::new(false),
+            iss_offset: Cell::new(0),
+        }
+    }
+
+    pub fn add_socket(&self, socket: &'a TCPSocket<'a, A>) {
+        self.sockets.push_tail(socket);
+    }
+
+    fn new_iss(&self) -> u32 {
+        let offset = self.iss_offset.get().wrapping_add(ISS_STEP);
+        self.iss_offset.set(offset);
+        self.alarm.now().into_u32().wrapping_add(offset)
+    }
+
+    fn start_ticking(&self) {
+        if !self.alarm.is_armed() {
+            self.alarm
+                .set_alarm(self.alarm.now(), A::ticks_from_ms(TICK_MS));
+        }
+    }
+
+    /// Queue `socket` to build its next segment once the IP layer is free.
+    fn transmit(&self, socket: &TCPSocket<'a, A>) {
+        socket.tx_pending.set(true);
+        self.send_next();
+    }
+
+    fn send_next(&self) {
+        // The IP layer may report a failed send before `send_to` returns,
+        // in which case the nested call finds the buffer missing and the
+        // loop sends the next segment.
+        while !self.sending.get() {
+            let socket = match self.sockets.iter().find(|socket| socket.tx_pending.get()) {
+                Some(socket) => socket,
+                None => return,
+            };
+            let buf = match self.tx_buf.take() {
+                Some(buf) => buf,
+                None => return,
+            };
+            socket.tx_pending.set(false);
+            let segment = socket.next_segment(buf);
+            let mut payload = LeasableBuffer::new(buf);
+            if let Some((tcp_header, len)) = segment {
+                payload.slice(..len);
+                socket.net_cap.map(|net_cap| {
+                    self.sending.set(true);
+                    let ret = self.ip_sender.send_to(
+                        socket.remote_addr.get(),
+                        TransportHeader::TCP(tcp_header),
+                        &payload,
+                        net_cap,
+                    );
+                    if ret != ReturnCode::SUCCESS {
+                        self.sending.set(false);
+                    }
+                });
+            }
+            self.tx_buf.replace(payload.take());
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> IP6SendClient for MuxTcp<'a, A> {
+    /// Failed sends are not reported to the socket, whose retransmission
+    /// timer covers them.
+    fn send_done(&self, _result: ReturnCode) {
+        self.sending.set(false);
+        self.send_next();
+    }
+}
+
+impl<'a, A: Alarm<'a>> IP6RecvClient for MuxTcp<'a, A> {
+    fn receive(&self, ip_header: IP6Header, payload: &[u8], _timestamp: Option<u32>) {
+        if ip_header.get_next_header() != ip6_nh::TCP {
+            return;
+        }
+        if let Some((offset, tcp_header)) = TCPHeader::decode(payload).done() {
+            let src_addr = ip_header.get_src_addr();
+            self.sockets
+                .iter()
+                .find(|socket| socket.is_connection_of(src_addr, &tcp_header))
+                .or_else(|| {
+                    self.sockets
+                        .iter()
+                        .find(|socket| socket.is_listening_on(tcp_header.get_dst_port()))
+                })
+                .map(|socket| socket.receive_segment(src_addr, &tcp_header, &payload[offset..]));
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> time::AlarmClient for MuxTcp<'a, A> {
+    fn alarm(&self) {
+        let mut armed = false;
+        for socket in self.sockets.iter() {
+            armed |= socket.tick();
+        }
+        if armed {
+            self.start_ticking();
+        }
+    }
+}
+
+/// A single TCP connection. Sequence numbers follow the names of RFC 793,
+/// with `snd_max` the highest `snd_nxt` sent, as `snd_nxt` goes back to
+/// `snd_una` on a retransmission.
+pub struct TCPSocket<'a, A: Alarm<'a>> {
+    mux: &'a MuxTcp<'a, A>,
+    next: ListLink<'a, TCPSocket<'a, A>>,
+    send_client: OptionalCell<&'a dyn TCPSendClient>,
+    recv_client: OptionalCell<&'a dyn TCPRecvClient>,
+    net_cap: OptionalCell<&'static NetworkCapability>,
+    state: Cell<TCPState>,
+    /// Opened by `listen`, so a failed handshake goes back to listening.
+    passive: Cell<bool>,
+    local_port: Cell<u16>,
+    remote_addr: Cell<IPAddr>,
+    remote_port: Cell<u16>,
+
+    iss: Cell<u32>,
+    snd_una: Cell<u32>,
+    snd_nxt: Cell<u32>,
+    snd_max: Cell<u32>,
+    snd_wnd: Cell<u16>,
+    snd_mss: Cell<u16>,
+    /// Sequence number of the first byte of `tx_buffer`.
+    tx_start: Cell<u32>,
+    /// Sequence number after the last byte queued, which is that of the FIN.
+    data_end: Cell<u32>,
+    tx_buffer: MapCell<LeasableBuffer<'static, u8>>,
+
+    rcv_nxt: Cell<u32>,
+
+    /// Ticks until the retransmission or TIME-WAIT timer fires, 0 if off.
+    timer: Cell<u16>,
+    retries: Cell<u8>,
+
+    tx_pending: Cell<bool>,
+    ack_pending: Cell<bool>,
+    rst_pending: Cell<bool>,
+    /// Send a byte even if the peer window is closed.
+    probe: Cell<bool>,
+}
+
+impl<'a, A: Alarm<'a>> ListNode<'a, TCPSocket<'a, A>> for TCPSocket<'a, A> {
+    fn next(&'a self) -> &'a ListLink<'a, TCPSocket<'a, A>> {
+        &self.next
+    }
+}
+
+impl<'a, A: Alarm<'a>> TCPSocket<'a, A> {
+    pub fn new(mux: &'a MuxTcp<'a, A>) -> TCPSocket<'a, A> {
+        TCPSocket {
+            mux: mux,
+            next: ListLink::empty(),
+            send_client: OptionalCell::empty(),
+            recv_client: OptionalCell::empty(),
+            net_cap: OptionalCell::empty(),
+            state: Cell::new(TCPState::Closed),
+            passive: Cell::new(false),
+            local_port: Cell::new(0),
+            remote_addr: Cell::new(IPAddr::new()),
+            remote_port: Cell::new(0),
+            iss: Cell::new(0),
+            snd_una: Cell::new(0),
+            snd_nxt: Cell::new(0),
+            snd_max: Cell::new(0),
+            snd_wnd: Cell::new(0),
+            snd_mss: Cell::new(mux.mss),
+            tx_start: Cell::new(0),
+            data_end: Cell::new(0),
+            tx_buffer: MapCell::empty(),
+            rcv_nxt: Cell::new(0),
+            timer: Cell::new(0),
+            retries: Cell::new(0),
+            tx_pending: Cell::new(false),
+            ack_pending: Cell::new(false),
+            rst_pending: Cell::new(false),
+            probe: Cell::new(false),
+        }
+    }
+
+    fn is_connection_of(&self, src_addr: IPAddr, tcp_header: &TCPHeader) -> bool {
+        match self.state.get() {
+            TCPState::Closed | TCPState::Listen => false,
+            _ => {
+                self.local_port.get() == tcp_header.get_dst_port()
+                    && self.remote_port.get() == tcp_header.get_src_port()
+                    && self.remote_addr.get() == src_addr
+            }
+        }
+    }
+
+    fn is_listening_on(&self, port: u16) -> bool {
+        self.state.get() == TCPState::Listen && self.local_port.get() == port
+    }
+
+    /// Start a handshake in `state`, from a new initial sequence number.
+    fn open(&self, state: TCPState) {
+        let iss = self.mux.new_iss();
+        self.iss.set(iss);
+        self.snd_una.set(iss);
+        self.snd_nxt.set(iss);
+        self.snd_max.set(iss);
+        self.data_end.set(iss.wrapping_add(1));
+        self.snd_mss.set(self.mux.mss);
+        self.timer.set(0);
+        self.retries.set(0);
+        self.ack_pending.set(false);
+        self.probe.set(false);
+        self.state.set(state);
+        self.mux.transmit(self);
+    }
+
+    fn set_peer_options(&self, tcp_header: &TCPHeader) {
+        let mss = tcp_header.get_mss().unwrap_or(DEFAULT_MSS);
+        self.snd_mss.set(cmp::min(mss, self.mux.mss));
+        self.snd_wnd.set(tcp_header.get_window());
+    }
+
+    fn rto(&self) -> u16 {
+        cmp::min(RTO_TICKS << cmp::min(self.retries.get(), 8), MAX_RTO_TICKS)
+    }
+
+    fn start_timer(&self, ticks: u16) {
+        self.timer.set(ticks);
+        self.mux.start_ticking();
+    }
+
+    /// Go to CLOSED, returning an unacknowledged buffer with `FAIL`.
+    fn stop(&self) {
+        self.state.set(TCPState::Closed);
+        self.timer.set(0);
+        self.tx_buffer.take().map(|buf| {
+            self.send_client
+                .map(move |client| client.send_done(ReturnCode::FAIL, buf));
+        });
+    }
+
+    /// The connection was reset, or the peer stopped answering.
+    fn fail(&self) {
+        let state = self.state.get();
+        self.stop();
+        match state {
+            TCPState::SynReceived if self.passive.get() => self.state.set(TCPState::Listen),
+            TCPState::SynSent | TCPState::SynReceived => {
+                self.recv_client
+                    .map(|client| client.connected(ReturnCode::FAIL));
+            }
+            _ => {
+                self.recv_client
+                    .map(|client| client.closed(ReturnCode::FAIL));
+            }
+        }
+    }
+
+    fn enter_time_wait(&self) {
+        self.state.set(TCPState::TimeWait);
+        self.start_timer(TIME_WAIT_TICKS);
+    }
+
+    /// Advances the timer by a tick, and returns whether it is still running.
+    fn tick(&self) -> bool {
+        let ticks = self.timer.get();
+        if ticks == 0 {
+            return false;
+        } else if ticks > 1 {
+            self.timer.set(ticks - 1);
+            return true;
+        }
+        self.timer.set(0);
+
+        if self.state.get() == TCPState::TimeWait {
+            self.state.set(TCPState::Closed);
+            self.recv_client
+                .map(|client| client.closed(ReturnCode::SUCCESS));
+            return false;
+        }
+        let retries = self.retries.get() + 1;
+        if retries > MAX_RETRANSMITS {
+            self.rst_pending.set(true);
+            self.mux.transmit(self);
+            self.fail();
+            return false;
+        }
+        self.retries.set(retries);
+        self.snd_nxt.set(self.snd_una.get());
+        self.probe.set(true);
+        self.timer.set(self.rto());
+        self.mux.transmit(self);
+        true
+    }
+
+    /// Builds the next segment to send, if any, with its payload copied into
+    /// `buf`, and returns its header and payload length.
+    fn next_segment(&self, buf: &mut [u8]) -> Option<(TCPHeader, usize)> {
+        let mut tcp_header = TCPHeader::new();
+        tcp_header.set_src_port(self.local_port.get());
+        tcp_header.set_dst_port(self.remote_port.get());
+        tcp_header.set_window(RCV_WND);
+
+        if self.rst_pending.replace(false) {
+            tcp_header.set_seq_num(self.snd_nxt.get());
+            tcp_header.set_flags(tcp_flags::RST);
+            return Some((tcp_header, 0));
+        }
+
+        let state = self.state.get();
+        let seq = self.snd_nxt.get();
+        let data_end = self.data_end.get();
+        let mut flags = 0;
+        let mut len = 0;
+        match state {
+            TCPState::Closed | TCPState::Listen => return None,
+            TCPState::SynSent | TCPState::SynReceived => {
+                if seq == self.iss.get() {
+                    flags |= tcp_flags::SYN;
+                    tcp_header.set_mss(Some(self.mux.mss));
+                }
+            }
+            _ => {
+                if seq_lt(seq, data_end) {
+                    let queued = data_end.wrapping_sub(seq) as usize;
+                    let in_flight = seq.wrapping_sub(self.snd_una.get()) as usize;
+                    let window = if self.probe.get() {
+                        cmp::max(self.snd_wnd.get(), 1)
+                    } else {
+                        self.snd_wnd.get()
+                    };
+                    let usable = (window as usize).saturating_sub(in_flight);
+                    len = cmp::min(
+                        cmp::min(queued, usable),
+                        cmp::min(self.snd_mss.get() as usize, buf.len()),
+                    );
+                    if len > 0 {
+                        let start = seq.wrapping_sub(self.tx_start.get()) as usize;
+                        self.tx_buffer.map(|tx_buffer| {
+                            buf[..len].copy_from_slice(&tx_buffer[start..start + len])
+                        });
+                        if len == queued {
+                            flags |= tcp_flags::PSH;
+                        }
+                    }
+                }
+                let fin_state = match state {
+                    TCPState::FinWait1 | TCPState::Closing | TCPState::LastAck => true,
+                    _ => false,
+                };
+                if fin_state && seq.wrapping_add(len as u32) == data_end {
+                    flags |= tcp_flags::FIN;
+                }
+            }
+        }
+        if state != TCPState::SynSent {
+            flags |= tcp_flags::ACK;
+            tcp_header.set_ack_num(self.rcv_nxt.get());
+        }
+        if flags & (tcp_flags::SYN | tcp_flags::FIN) == 0 && len == 0 && !self.ack_pending.get() {
+            return None;
+        }
+        tcp_header.set_seq_num(seq);
+        tcp_header.set_flags(flags);
+        self.ack_pending.set(false);
+        self.probe.set(false);
+
+        let mut consumed = len as u32;
+        if flags & tcp_flags::SYN != 0 {
+            consumed += 1;
+        }
+        if flags & tcp_flags::FIN != 0 {
+            consumed += 1;
+        }
+        let nxt = seq.wrapping_add(consumed);
+        self.snd_nxt.set(nxt);
+        if seq_lt(self.snd_max.get(), nxt) {
+            self.snd_max.set(nxt);
+        }
+        if consumed > 0 && self.timer.get() == 0 {
+            self.start_timer(self.rto());
+        }
+        Some((tcp_header, len))
+    }
+
+    fn acknowledge(&self, ack: u32) {
+        self.snd_una.set(ack);
+        if seq_lt(self.snd_nxt.get(), ack) {
+            self.snd_nxt.set(ack);
+        }
+        self.retries.set(0);
+        if ack == self.snd_max.get() {
+            self.timer.set(0);
+        } else {
+            self.start_timer(self.rto());
+        }
+        if seq_leq(self.data_end.get(), ack) {
+            self.tx_buffer.take().map(|buf| {
+                self.send_client
+                    .map(move |client| client.send_done(ReturnCode::SUCCESS, buf));
+            });
+        }
+    }
+
+    fn receive_segment(&self, src_addr: IPAddr, tcp_header: &TCPHeader, payload: &[u8]) {
+        match self.state.get() {
+            TCPState::Closed => {}
+            TCPState::Listen => {
+                if !tcp_header.has_flag(tcp_flags::SYN)
+                    || tcp_header.has_flag(tcp_flags::RST | tcp_flags::ACK)
+                {
+                    return;
+                }
+                self.remote_addr.set(src_addr);
+                self.remote_port.set(tcp_header.get_src_port());
+                self.rcv_nxt.set(tcp_header.get_seq_num().wrapping_add(1));
+                self.open(TCPState::SynReceived);
+                self.set_peer_options(tcp_header);
+            }
+            TCPState::SynSent => {
+                let ack = tcp_header.get_ack_num();
+                let has_ack = tcp_header.has_flag(tcp_flags::ACK);
+                if has_ack && ack != self.iss.get().wrapping_add(1) {
+                    return;
+                }
+                if tcp_header.has_flag(tcp_flags::RST) {
+                    if has_ack {
+                        self.fail();
+                    }
+                    return;
+                }
+                if !tcp_header.has_flag(tcp_flags::SYN) {
+                    return;
+                }
+                self.rcv_nxt.set(tcp_header.get_seq_num().wrapping_add(1));
+                self.set_peer_options(tcp_header);
+                if has_ack {
+                    self.acknowledge(ack);
+                    self.state.set(TCPState::Established);
+                    self.ack_pending.set(true);
+                    self.mux.transmit(self);
+                    self.recv_client
+                        .map(|client| client.connected(ReturnCode::SUCCESS));
+                } else {
+                    // Simultaneous open: our SYN is sent again with an ACK
+                    self.state.set(TCPState::SynReceived);
+                    self.snd_nxt.set(self.iss.get());
+                    self.mux.transmit(self);
+                }
+            }
+            _ => self.receive_synchronized(tcp_header, payload),
+        }
+    }
+
+    fn receive_synchronized(&self, tcp_header: &TCPHeader, payload: &[u8]) {
+        let rcv_nxt = self.rcv_nxt.get();
+        let mut seq = tcp_header.get_seq_num();
+        if tcp_header.has_flag(tcp_flags::RST) {
+            // Only an exact match, against blind resets (RFC 5961)
+            if seq == rcv_nxt {
+                self.fail();
+            }
+            return;
+        }
+
+        let mut data = payload;
+        let mut fin = tcp_header.has_flag(tcp_flags::FIN);
+        let syn = tcp_header.has_flag(tcp_flags::SYN);
+        if syn {
+            seq = seq.wrapping_add(1);
+        }
+        if seq_lt(seq, rcv_nxt) {
+            let old = rcv_nxt.wrapping_sub(seq) as usize;
+            if old > data.len() {
+                // A retransmission of what we already have: acknowledge it
+                // again, in case our ACK was lost.
+                if syn || fin || !data.is_empty() {
+                    if self.state.get() == TCPState::TimeWait {
+                        self.start_timer(TIME_WAIT_TICKS);
+                    }
+                    self.ack_pending.set(true);
+                    self.mux.transmit(self);
+                }
+                return;
+            }
+            data = &data[old..];
+        } else if seq != rcv_nxt || syn {
+            // Out of order, or a SYN inside the connection (RFC 5961)
+            self.ack_pending.set(true);
+            self.mux.transmit(self);
+            return;
+        }
+        if !tcp_header.has_flag(tcp_flags::ACK) {
+            return;
+        }
+
+        let ack = tcp_header.get_ack_num();
+        if seq_lt(self.snd_max.get(), ack) {
+            self.ack_pending.set(true);
+            self.mux.transmit(self);
+            return;
+        }
+        if self.state.get() == TCPState::SynReceived {
+            if !seq_lt(self.snd_una.get(), ack) {
+                return;
+            }
+            self.state.set(TCPState::Established);
+            self.recv_client
+                .map(|client| client.connected(ReturnCode::SUCCESS));
+        }
+        if seq_lt(self.snd_una.get(), ack) {
+            self.acknowledge(ack);
+        }
+        self.snd_wnd.set(tcp_header.get_window());
+
+        let fin_acked = self.snd_una.get() == self.data_end.get().wrapping_add(1);
+        match self.state.get() {
+            TCPState::FinWait1 if fin_acked => self.state.set(TCPState::FinWait2),
+            TCPState::Closing if fin_acked => self.enter_time_wait(),
+            TCPState::LastAck if fin_acked => {
+                self.stop();
+                self.recv_client
+                    .map(|client| client.closed(ReturnCode::SUCCESS));
+                return;
+            }
+            _ => {}
+        }
+
+        match self.state.get() {
+            TCPState::Established | TCPState::FinWait1 | TCPState::FinWait2 => {}
+            _ => {
+                // The peer already closed its side
+                data = &[];
+                fin = false;
+            }
+        }
+        if !data.is_empty() {
+            self.rcv_nxt
+                .set(self.rcv_nxt.get().wrapping_add(data.len() as u32));
+            self.ack_pending.set(true);
+            self.recv_client.map(|client| client.receive(data));
+        }
+        if fin {
+            self.rcv_nxt.set(self.rcv_nxt.get().wrapping_add(1));
+            self.ack_pending.set(true);
+            match self.state.get() {
+                TCPState::Established => self.state.set(TCPState::CloseWait),
+                TCPState::FinWait1 => self.state.set(TCPState::Closing),
+                _ => self.enter_time_wait(),
+            }
+            self.recv_client.map(|client| client.remote_closed());
+        }
+        self.mux.transmit(self);
+    }
+}
+
+impl<'a, A: Alarm<'a>> TCPSender<'a> for TCPSocket<'a, A> {
+    fn set_client(&self, client: &'a dyn TCPSendClient) {
+        self.send_client.set(client);
+    }
+
+    fn connect(
+        &'a self,
+        dest: IPAddr,
+        dst_port: u16,
+        src_port: u16,
+        net_cap: &'static NetworkCapability,
+    ) -> ReturnCode {
+        if self.state.get() != TCPState::Closed {
+            return ReturnCode::EBUSY;
+        }
+        self.net_cap.set(net_cap);
+        self.passive.set(false);
+        self.local_port.set(src_port);
+        self.remote_addr.set(dest);
+        self.remote_port.set(dst_port);
+        self.snd_wnd.set(0);
+        self.open(TCPState::SynSent);
+        ReturnCode::SUCCESS
+    }
+
+    fn send(&'a self, buf: LeasableBuffer<'static, u8>) -> Result<(), LeasableBuffer<'static, u8>> {
+        match self.state.get() {
+            TCPState::Established | TCPState::CloseWait => {}
+            _ => return Err(buf),
+        }
+        if self.tx_buffer.is_some() || buf.len() == 0 {
+            return Err(buf);
+        }
+        let start = self.data_end.get();
+        self.tx_start.set(start);
+        self.data_end.set(start.wrapping_add(buf.len() as u32));
+        self.tx_buffer.replace(buf);
+        self.mux.transmit(self);
+        Ok(())
+    }
+
+    fn close(&'a self) -> ReturnCode {
+        match self.state.get() {
+            TCPState::Listen | TCPState::SynSent => {
+                self.state.set(TCPState::Closed);
+                self.timer.set(0);
+            }
+            TCPState::SynReceived => self.abort(),
+            TCPState::Established => {
+                self.state.set(TCPState::FinWait1);
+                self.mux.transmit(self);
+            }
+            TCPState::CloseWait => {
+                self.state.set(TCPState::LastAck);
+                self.mux.transmit(self);
+            }
+            _ => return ReturnCode::EALREADY,
+        }
+        ReturnCode::SUCCESS
+    }
+
+    fn abort(&'a self) {
+        match self.state.get() {
+            TCPState::Closed | TCPState::Listen | TCPState::SynSent | TCPState::TimeWait => {}
+            _ => {
+                self.rst_pending.set(true);
+                self.mux.transmit(self);
+            }
+        }
+        self.stop();
+    }
+
+    fn get_state(&self) -> TCPState {
+        self.state.get()
+    }
+}
+
+impl<'a, A: Alarm<'a>> TCPReceiver<'a> for TCPSocket<'a, A> {
+    fn set_client(&self, client: &'a dyn TCPRecvClient) {
+        self.recv_client.set(client);
+    }
+
+    fn listen(&'a self, src_port: u16, net_cap: &'static NetworkCapability) -> ReturnCode {
+        if self.state.get() != TCPState::Closed {
+            return ReturnCode::EBUSY;
+        }
+        self.net_cap.set(net_cap);
+        self.passive.set(true);
+        self.local_port.set(src_port);
+        self.state.set(TCPState::Listen);
+        ReturnCode::SUCCESS
+    }
+}
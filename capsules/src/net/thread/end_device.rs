@@ -0,0 +1,863 @@
+//! A minimal Thread End Device (MTD), which attaches to a parent router of
+//! a Thread network with MLE and keeps the link to it alive.
+//!
+//! `ThreadEndDevice` runs over the UDP stack on `MLE_PORT`, with every
+//! message secured with the MLE key of the network:
+//!
+//! - `start()` multicasts a Parent Request to the routers of the link, and
+//!   after `PARENT_REQUEST_ROUTER_MS` to the REEDs as well. The Parent
+//!   Responses that answer its challenge are compared by link quality,
+//!   parent priority and number of good links, then a Child ID Request is
+//!   sent to the best parent.
+//! - The Child ID Response gives the RLOC16 of the device, which the
+//!   client gets in `attached()`, for example to set the short address of
+//!   the MAC.
+//! - Once attached, a Child Update Request is sent every half timeout.
+//!   If the parent answers none of `CHILD_UPDATE_ATTEMPTS` in a row, the
+//!   device is detached and attaches again.
+//!
+//! The device is a rx-on-when-idle child: it takes no part in routing, so
+//! mesh forwarding is left to its parent. The board provides the MLE key of
+//! the current key sequence, derived from the network master key as in
+//! Section 7.1.4 of the specification, and the interface has to send from
+//! the link-local address of the extended address given to `new()`, which
+//! `link_local_addr()` returns. Messages with another key sequence are
+//! dropped. Messages are secured with AES-CCM* through an `AES128Aead`, such
+//! as `AesAead`, which the device has to use alone.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//! # use capsules::net::thread::end_device::ThreadEndDevice;
+//!
+//! let thread = static_init!(
+//!     ThreadEndDevice<'static, VirtualMuxAlarm<'static, sam4l::ast::Ast>>,
+//!     ThreadEndDevice::new(
+//!         udp_send,
+//!         udp_recv,
+//!         udp_port_table,
+//!         thread_alarm,
+//!         mle_aead,
+//!         rng,
+//!         ext_addr,
+//!         &mut MLE_CRYPT_BUF,
+//!         LeasableBuffer::new(&mut MLE_TX_BUF),
+//!         net_cap,
+//!     )
+//! );
+//! udp_send.set_client(thread);
+//! udp_recv.set_client(thread);
+//! thread_alarm.set_alarm_client(thread);
+//! mle_aead.set_client(thread);
+//! rng.set_client(thread);
+//! thread.set_mle_key(0, &MLE_KEY);
+//! thread.bind();
+//! thread.start();
+//! ```
+
+use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::network_capabilities::NetworkCapability;
+use crate::net::stream::SResult;
+use crate::net::thread::mle::THREAD_VERSION;
+use crate::net::thread::mle::{ext_addr_of, link_local_addr, AuxSecurityHeader};
+use crate::net::thread::mle::{find_tlv, mle_commands, nonce, security_suites};
+use crate::net::thread::mle::{AUX_HDR_LEN, LINK_LOCAL_ALL_ROUTERS, MIC_LEN, MLE_PORT};
+use crate::net::thread::tlv::{LinkMode, MulticastResponder, Tlv, TlvType};
+use crate::net::udp::udp_port_table::UdpPortManager;
+use crate::net::udp::udp_recv::{UDPReceiver, UDPRecvClient};
+use crate::net::udp::udp_send::{UDPSendClient, UDPSender};
+use core::cell::Cell;
+use kernel::common::cells::{MapCell, OptionalCell, TakeCell};
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::hil::rng;
+use kernel::hil::symmetric_encryption::{AES128Aead, AeadClient, AeadMode, AES128_KEY_SIZE};
+use kernel::hil::time::{self, Alarm};
+use kernel::ReturnCode;
+
+/// How long Parent Responses from routers are waited for.
+pub const PARENT_REQUEST_ROUTER_MS: u32 = 750;
+
+/// How long Parent Responses from routers and REEDs are waited for.
+pub const PARENT_REQUEST_REED_MS: u32 = 1250;
+
+pub const CHILD_ID_RESPONSE_TIMEOUT_MS: u32 = 1250;
+
+/// How long an attach that found no parent waits before the next one.
+pub const ATTACH_BACKOFF_MS: u32 = 10000;
+
+pub const CHILD_UPDATE_TIMEOUT_MS: u32 = 1000;
+pub const CHILD_UPDATE_ATTEMPTS: u8 = 3;
+
+/// The timeout the parent keeps a silent child for.
+pub const DEFAULT_CHILD_TIMEOUT_S: u32 = 240;
+
+/// The length of the authenticated data: the source and destination
+/// addresses, and the auxiliary security header.
+const AAD_LEN: usize = 32 + AUX_HDR_LEN;
+
+/// The mode of a rx-on-when-idle child that does not store network data.
+const MODE: u8 = LinkMode::ReceiverOnWhenIdle as u8 | LinkMode::SecureDataRequests as u8;
+
+/// The TLVs a Child ID Request asks its parent for.
+const CHILD_ID_TLV_REQUEST: [u8; 2] = [TlvType::Address16 as u8, TlvType::NetworkData as u8];
+
+pub trait ThreadClient {
+    fn attached(&self, rloc16: u16);
+
+    /// The parent stopped answering. The device is attaching again.
+    fn detached(&self);
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum State {
+    Disabled,
+    /// Waiting for the random challenge of the Parent Requests.
+    Challenge,
+    /// Collecting Parent Responses, from routers only until `reeds`.
+    ParentRequest {
+        reeds: bool,
+    },
+    ChildIdRequest,
+    Attached,
+    /// `attempts` Child Update Requests are unanswered.
+    ChildUpdate {
+        attempts: u8,
+    },
+    /// Waiting to attach again.
+    Backoff,
+}
+
+#[derive(Copy, Clone)]
+enum CryptOp {
+    Encrypt {
+        dst: IPAddr,
+        len: usize,
+    },
+    Decrypt {
+        src: IPAddr,
+        frame_counter: u32,
+        len: usize,
+    },
+}
+
+#[derive(Copy, Clone)]
+struct LeaderData {
+    partition_id: u32,
+    weighting: u8,
+    data_version: u8,
+    stable_data_version: u8,
+    leader_router_id: u8,
+}
+
+#[derive(Copy, Clone)]
+struct Parent {
+    addr: IPAddr,
+    rloc16: u16,
+    /// The challenge of its Parent Response, which the Child ID Request
+    /// answers.
+    challenge: [u8; 8],
+    /// The frame counter its next message has to reach.
+    frame_counter: u32,
+    link_quality: u8,
+    priority: i8,
+    link_quality_3: u8,
+    leader_data: LeaderData,
+}
+
+impl Parent {
+    fn is_better_than(&self, other: &Parent) -> bool {
+        (self.link_quality, self.priority, self.link_quality_3)
+            > (other.link_quality, other.priority, other.link_quality_3)
+    }
+}
+
+pub struct ThreadEndDevice<'a, A: Alarm<'a>> {
+    udp_sender: &'a dyn UDPSender<'a>,
+    udp_receiver: &'a UDPReceiver<'a>,
+    port_table: &'static UdpPortManager,
+    alarm: &'a A,
+    aead: &'a dyn AES128Aead<'a>,
+    rng: &'a dyn rng::Rng<'a>,
+    ext_addr: [u8; 8],
+    net_cap: &'static NetworkCapability,
+    /// The authenticated data, message and MIC being secured.
+    aad_buf: TakeCell<'static, [u8]>,
+    msg_buf: TakeCell<'static, [u8]>,
+    mic_buf: TakeCell<'static, [u8]>,
+    tx_buf: MapCell<LeasableBuffer<'static, u8>>,
+    crypt_op: OptionalCell<CryptOp>,
+    client: OptionalCell<&'a dyn ThreadClient>,
+
+    state: Cell<State>,
+    mle_key: Cell<Option<(u32, [u8; AES128_KEY_SIZE])>>,
+    frame_counter: Cell<u32>,
+    timeout_s: Cell<u32>,
+    challenge: Cell<[u8; 8]>,
+    parent: Cell<Option<Parent>>,
+    rloc16: Cell<Option<u16>>,
+    /// The challenge of a Child Update Request from the parent, which the
+    /// next Child Update Response answers.
+    parent_challenge: Cell<Option<[u8; 8]>>,
+}
+
+impl<'a, A: Alarm<'a>> ThreadEndDevice<'a, A> {
+    /// `crypt_buf` is split between a message, its authenticated data and
+    /// its MIC, and `tx_buf` holds a message as sent, so both have to be
+    /// larger than the longest message received, such as a Child ID
+    /// Response with the network data. 200 bytes is enough for small
+    /// networks.
+    pub fn new(
+        udp_sender: &'a dyn UDPSender<'a>,
+        udp_receiver: &'a UDPReceiver<'a>,
+        port_table: &'static UdpPortManager,
+        alarm: &'a A,
+        aead: &'a dyn AES128Aead<'a>,
+        rng: &'a dyn rng::Rng<'a>,
+        ext_addr: [u8; 8],
+        crypt_buf: &'static mut [u8],
+        tx_buf: LeasableBuffer<'static, u8>,
+        net_cap: &'static NetworkCapability,
+    ) -> ThreadEndDevice<'a, A> {
+        let (aad_buf, rest) = crypt_buf.split_at_mut(AAD_LEN);
+        let (mic_buf, msg_buf) = rest.split_at_mut(MIC_LEN);
+        ThreadEndDevice {
+            udp_sender: udp_sender,
+            udp_receiver: udp_receiver,
+            port_table: port_table,
+            alarm: alarm,
+            aead: aead,
+            rng: rng,
+            ext_addr: ext_addr,
+            net_cap: net_cap,
+            aad_buf: TakeCell::new(aad_buf),
+            msg_buf: TakeCell::new(msg_buf),
+            mic_buf: TakeCell::new(mic_buf),
+            tx_buf: MapCell::new(tx_buf),
+            crypt_op: OptionalCell::empty(),
+            client: OptionalCell::empty(),
+            state: Cell::new(State::Disabled),
+            mle_key: Cell::new(None),
+            frame_counter: Cell::new(0),
+            timeout_s: Cell::new(DEFAULT_CHILD_TIMEOUT_S),
+            challenge: Cell::new([0; 8]),
+            parent: Cell::new(None),
+            rloc16: Cell::new(None),
+            parent_challenge: Cell::new(None),
+        }
+    }
+
+    /// Binds the device to `MLE_PORT`.
+    pub fn bind(&self) -> ReturnCode {
+        if self.udp_sender.is_bound() {
+            return ReturnCode::EALREADY;
+        }
+        let socket = match self.port_table.create_socket() {
+            Ok(socket) => socket,
+            Err(rcode) => return rcode,
+        };
+        self.port_table.set_owner(&socket, "thread");
+        match self.port_table.bind(socket, MLE_PORT, self.net_cap) {
+            Ok((send_binding, recv_binding)) => {
+                self.udp_sender.set_binding(send_binding);
+                self.udp_receiver.set_binding(recv_binding);
+                ReturnCode::SUCCESS
+            }
+            Err(_socket) => ReturnCode::EBUSY,
+        }
+    }
+
+    pub fn set_client(&self, client: &'a dyn ThreadClient) {
+        self.client.set(client);
+    }
+
+    /// Sets the MLE key of `key_sequence`. Returns `EINVAL` if `key` is not
+    /// `AES128_KEY_SIZE` bytes long.
+    pub fn set_mle_key(&self, key_sequence: u32, key: &[u8]) -> ReturnCode {
+        if key.len() != AES128_KEY_SIZE {
+            return ReturnCode::EINVAL;
+        }
+        let mut mle_key = [0; AES128_KEY_SIZE];
+        mle_key.copy_from_slice(key);
+        self.mle_key.set(Some((key_sequence, mle_key)));
+        ReturnCode::SUCCESS
+    }
+
+    /// Sets the timeout that later attaches ask the parent for, in
+    /// seconds. It has to fit the alarm, which limits it to a few minutes
+    /// with 24-bit timers.
+    pub fn set_timeout(&self, timeout_s: u32) {
+        self.timeout_s.set(timeout_s);
+    }
+
+    /// The address the interface has to send from.
+    pub fn link_local_addr(&self) -> IPAddr {
+        link_local_addr(&self.ext_addr)
+    }
+
+    pub fn get_rloc16(&self) -> Option<u16> {
+        self.rloc16.get()
+    }
+
+    /// The link-local address of the parent, once attached.
+    pub fn get_parent(&self) -> Option<IPAddr> {
+        self.rloc16
+            .get()
+            .and(self.parent.get())
+            .map(|parent| parent.addr)
+    }
+
+    /// Starts attaching to a parent. Returns `EOFF` while the device is not
+    /// bound, and `EINVAL` without an MLE key.
+    pub fn start(&self) -> ReturnCode {
+        if !self.udp_sender.is_bound() {
+            return ReturnCode::EOFF;
+        }
+        if self.mle_key.get().is_none() {
+            return ReturnCode::EINVAL;
+        }
+        if self.state.get() != State::Disabled {
+            return ReturnCode::EALREADY;
+        }
+        self.attach()
+    }
+
+    /// Stops attaching or keeping the parent. The parent drops the device
+    /// after its timeout.
+    pub fn stop(&self) {
+        self.state.set(State::Disabled);
+        self.parent.set(None);
+        self.rloc16.set(None);
+        let _ = self.alarm.disarm();
+    }
+
+    fn attach(&self) -> ReturnCode {
+        self.parent.set(None);
+        self.rloc16.set(None);
+        self.state.set(State::Challenge);
+        let result = self.rng.get();
+        if result != ReturnCode::SUCCESS {
+            self.state.set(State::Disabled);
+        }
+        result
+    }
+
+    fn set_timer(&self, ms: u32) {
+        self.alarm.set_alarm(self.alarm.now(), A::ticks_from_ms(ms));
+    }
+
+    /// Encodes the command and TLVs of a message, returning their length.
+    fn encode_message(&self, buf: &mut [u8], command: u8) -> SResult<usize> {
+        let off = enc_consume!(buf, 0; encode_command, command);
+        let parent = self.parent.get();
+        let tlvs = &mut buf[off..];
+        let len = match command {
+            mle_commands::PARENT_REQUEST => {
+                let mut scan_mask = MulticastResponder::Router as u8;
+                if let State::ParentRequest { reeds: true } = self.state.get() {
+                    scan_mask |= MulticastResponder::EndDevice as u8;
+                }
+                let mut off = enc_consume!(tlvs, 0; Tlv::Mode(MODE); encode);
+                off = enc_consume!(tlvs, off; Tlv::Challenge(self.challenge.get()); encode);
+                off = enc_consume!(tlvs, off; Tlv::ScanMask(scan_mask); encode);
+                off = enc_consume!(tlvs, off; Tlv::Version(THREAD_VERSION); encode);
+                off
+            }
+            mle_commands::CHILD_ID_REQUEST => {
+                let parent = stream_from_option!(parent);
+                let frame_counter = self.frame_counter.get();
+                let mut off = enc_consume!(tlvs, 0; Tlv::Response(parent.challenge); encode);
+                // Frames of the link layer are not secured
+                off = enc_consume!(tlvs, off; Tlv::LinkLayerFrameCounter(0); encode);
+                off = enc_consume!(tlvs, off; Tlv::MleFrameCounter(frame_counter); encode);
+                off = enc_consume!(tlvs, off; Tlv::Mode(MODE); encode);
+                off = enc_consume!(tlvs, off; Tlv::Timeout(self.timeout_s.get()); encode);
+                off = enc_consume!(tlvs, off; Tlv::Version(THREAD_VERSION); encode);
+                off = enc_consume!(tlvs, off; Tlv::TlvRequest(&CHILD_ID_TLV_REQUEST); encode);
+                off
+            }
+            mle_commands::CHILD_UPDATE_REQUEST | mle_commands::CHILD_UPDATE_RESPONSE => {
+                let parent = stream_from_option!(parent);
+                let rloc16 = stream_from_option!(self.rloc16.get());
+                let leader = parent.leader_data;
+                let mut off = enc_consume!(tlvs, 0; Tlv::SourceAddress(rloc16); encode);
+                off = enc_consume!(tlvs, off; Tlv::Mode(MODE); encode);
+                off = enc_consume!(tlvs, off; Tlv::Timeout(self.timeout_s.get()); encode);
+                let leader_data = Tlv::LeaderData {
+                    partition_id: leader.partition_id,
+                    weighting: leader.weighting,
+                    data_version: leader.data_version,
+                    stable_data_version: leader.stable_data_version,
+                    leader_router_id: leader.leader_router_id,
+                };
+                off = enc_consume!(tlvs, off; leader_data; encode);
+                if command == mle_commands::CHILD_UPDATE_RESPONSE {
+                    if let Some(challenge) = self.parent_challenge.take() {
+                        off = enc_consume!(tlvs, off; Tlv::Response(challenge); encode);
+                    }
+                }
+                off
+            }
+            _ => stream_err!(),
+        };
+        stream_done!(off + len, off + len);
+    }
+
+    /// Secures the message `command` for `dst`, which is sent once it is
+    /// encrypted. Returns `EBUSY` while another message is being secured or
+    /// sent.
+    fn send_message(&self, dst: IPAddr, command: u8) -> ReturnCode {
+        let (key_sequence, key) = match self.mle_key.get() {
+            Some(mle_key) => mle_key,
+            None => return ReturnCode::EINVAL,
+        };
+        if self.tx_buf.is_none() {
+            return ReturnCode::EBUSY;
+        }
+        let (aad, msg, mic) = match self.take_crypt_bufs() {
+            Some(bufs) => bufs,
+            None => return ReturnCode::EBUSY,
+        };
+        let frame_counter = self.frame_counter.get();
+        let header = AuxSecurityHeader::new(frame_counter, key_sequence);
+        let len = match self.encode_message(msg, command).done() {
+            Some((len, _)) => len,
+            None => {
+                self.replace_crypt_bufs(aad, msg, mic);
+                return ReturnCode::ESIZE;
+            }
+        };
+        aad[..16].copy_from_slice(&self.link_local_addr().0);
+        aad[16..32].copy_from_slice(&dst.0);
+        let _ = header.encode(aad, 32);
+        self.frame_counter.set(frame_counter.wrapping_add(1));
+
+        self.crypt_op.set(CryptOp::Encrypt { dst: dst, len: len });
+        let nonce = nonce(&self.ext_addr, frame_counter);
+        let result = self.crypt(&key, &nonce, aad, msg, len, mic, true);
+        if result != ReturnCode::SUCCESS {
+            self.crypt_op.clear();
+        }
+        result
+    }
+
+    fn take_crypt_bufs(&self) -> Option<(&'static mut [u8], &'static mut [u8], &'static mut [u8])> {
+        match (
+            self.aad_buf.take(),
+            self.msg_buf.take(),
+            self.mic_buf.take(),
+        ) {
+            (Some(aad), Some(msg), Some(mic)) => Some((aad, msg, mic)),
+            (aad, msg, mic) => {
+                aad.map(|aad| self.aad_buf.replace(aad));
+                msg.map(|msg| self.msg_buf.replace(msg));
+                mic.map(|mic| self.mic_buf.replace(mic));
+                None
+            }
+        }
+    }
+
+    fn replace_crypt_bufs(
+        &self,
+        aad: &'static mut [u8],
+        msg: &'static mut [u8],
+        mic: &'static mut [u8],
+    ) {
+        self.aad_buf.replace(aad);
+        self.msg_buf.replace(msg);
+        self.mic_buf.replace(mic);
+    }
+
+    /// Starts securing, or checking and decrypting, the first `len` bytes of
+    /// `msg` with CCM*. The buffers are kept if it cannot start.
+    fn crypt(
+        &self,
+        key: &[u8],
+        nonce: &[u8],
+        aad: &'static mut [u8],
+        msg: &'static mut [u8],
+        len: usize,
+        mic: &'static mut [u8],
+        encrypting: bool,
+    ) -> ReturnCode {
+        let result = self.aead.set_mode(AeadMode::Ccm);
+        if result != ReturnCode::SUCCESS {
+            self.replace_crypt_bufs(aad, msg, mic);
+            return result;
+        }
+        self.aead.set_key(key);
+        self.aead.set_nonce(nonce);
+        let mut payload = LeasableBuffer::new(msg);
+        payload.slice(..len);
+        match self.aead.crypt(
+            LeasableBuffer::new(aad),
+            payload,
+            LeasableBuffer::new(mic),
+            encrypting,
+        ) {
+            Ok(()) => ReturnCode::SUCCESS,
+            Err((result, aad, msg, mic)) => {
+                self.replace_crypt_bufs(aad, msg, mic);
+                result
+            }
+        }
+    }
+
+    fn send_parent_request(&self) {
+        let _ = self.send_message(LINK_LOCAL_ALL_ROUTERS, mle_commands::PARENT_REQUEST);
+        let reeds = self.state.get() == State::ParentRequest { reeds: true };
+        self.set_timer(if reeds {
+            PARENT_REQUEST_REED_MS
+        } else {
+            PARENT_REQUEST_ROUTER_MS
+        });
+    }
+
+    fn send_child_update_request(&self, attempts: u8) {
+        self.state.set(State::ChildUpdate { attempts: attempts });
+        if let Some(parent) = self.parent.get() {
+            let _ = self.send_message(parent.addr, mle_commands::CHILD_UPDATE_REQUEST);
+        }
+        self.set_timer(CHILD_UPDATE_TIMEOUT_MS);
+    }
+
+    /// Waits half the timeout for the next Child Update Request.
+    fn keep_alive(&self) {
+        self.state.set(State::Attached);
+        self.set_timer(self.timeout_s.get() * 1000 / 2);
+    }
+
+    /// Handles the decrypted message `msg` from `src`, returning the command
+    /// to answer it with.
+    fn receive_message(&self, src: IPAddr, frame_counter: u32, msg: &[u8]) -> Option<u8> {
+        let (command, tlvs) = msg.split_first()?;
+        match (*command, self.state.get()) {
+            (mle_commands::PARENT_RESPONSE, State::ParentRequest { .. }) => {
+                self.receive_parent_response(src, frame_counter, tlvs);
+                None
+            }
+            (mle_commands::CHILD_ID_RESPONSE, State::ChildIdRequest) => {
+                let mut parent = self.parent_message(src, frame_counter)?;
+                match find_tlv(tlvs, TlvType::SourceAddress) {
+                    Some(Tlv::SourceAddress(rloc16)) if rloc16 == parent.rloc16 => {}
+                    _ => return None,
+                }
+                let rloc16 = match find_tlv(tlvs, TlvType::Address16) {
+                    Some(Tlv::Address16(rloc16)) => rloc16,
+                    _ => return None,
+                };
+                if let Some(leader_data) = decode_leader_data(tlvs) {
+                    parent.leader_data = leader_data;
+                }
+                self.parent.set(Some(parent));
+                self.rloc16.set(Some(rloc16));
+                self.keep_alive();
+                self.client.map(|client| client.attached(rloc16));
+                None
+            }
+            (mle_commands::CHILD_UPDATE_RESPONSE, State::ChildUpdate { .. }) => {
+                let mut parent = self.parent_message(src, frame_counter)?;
+                if let Some(Tlv::Status(_)) = find_tlv(tlvs, TlvType::Status) {
+                    // The parent no longer has the device as its child
+                    self.detach();
+                    return None;
+                }
+                if let Some(leader_data) = decode_leader_data(tlvs) {
+                    parent.leader_data = leader_data;
+                }
+                self.parent.set(Some(parent));
+                self.keep_alive();
+                None
+            }
+            (mle_commands::CHILD_UPDATE_REQUEST, State::Attached)
+            | (mle_commands::CHILD_UPDATE_REQUEST, State::ChildUpdate { .. }) => {
+                let parent = self.parent_message(src, frame_counter)?;
+                self.parent.set(Some(parent));
+                if let Some(Tlv::Challenge(challenge)) = find_tlv(tlvs, TlvType::Challenge) {
+                    self.parent_challenge.set(Some(challenge));
+                }
+                Some(mle_commands::CHILD_UPDATE_RESPONSE)
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns the parent with the frame counter of its new message, or
+    /// `None` if `src` is not the parent or the message is replayed.
+    fn parent_message(&self, src: IPAddr, frame_counter: u32) -> Option<Parent> {
+        let mut parent = self.parent.get()?;
+        if parent.addr != src || frame_counter < parent.frame_counter {
+            return None;
+        }
+        parent.frame_counter = frame_counter.wrapping_add(1);
+        Some(parent)
+    }
+
+    fn receive_parent_response(&self, src: IPAddr, frame_counter: u32, tlvs: &[u8]) {
+        match find_tlv(tlvs, TlvType::Response) {
+            Some(Tlv::Response(response)) if response == self.challenge.get() => {}
+            _ => return,
+        }
+        let rloc16 = match find_tlv(tlvs, TlvType::SourceAddress) {
+            Some(Tlv::SourceAddress(rloc16)) => rloc16,
+            _ => return,
+        };
+        let challenge = match find_tlv(tlvs, TlvType::Challenge) {
+            Some(Tlv::Challenge(challenge)) => challenge,
+            _ => return,
+        };
+        let link_margin = match find_tlv(tlvs, TlvType::LinkMargin) {
+            Some(Tlv::LinkMargin(link_margin)) => link_margin,
+            _ => return,
+        };
+        let (priority, link_quality_3) = match find_tlv(tlvs, TlvType::Connectivity) {
+            Some(Tlv::Connectivity {
+                parent_priority,
+                link_quality_3,
+                ..
+            }) => (parent_priority as i8 >> 6, link_quality_3),
+            _ => return,
+        };
+        let leader_data = match decode_leader_data(tlvs) {
+            Some(leader_data) => leader_data,
+            None => return,
+        };
+        let candidate = Parent {
+            addr: src,
+            rloc16: rloc16,
+            challenge: challenge,
+            frame_counter: frame_counter.wrapping_add(1),
+            link_quality: link_quality(link_margin),
+            priority: priority,
+            link_quality_3: link_quality_3,
+            leader_data: leader_data,
+        };
+        match self.parent.get() {
+            Some(best) if !candidate.is_better_than(&best) => {}
+            _ => self.parent.set(Some(candidate)),
+        }
+    }
+
+    fn detach(&self) {
+        self.rloc16.set(None);
+        self.client.map(|client| client.detached());
+        let _ = self.attach();
+    }
+}
+
+fn encode_command(buf: &mut [u8], command: u8) -> SResult {
+    stream_len_cond!(buf, 1);
+    buf[0] = command;
+    stream_done!(1);
+}
+
+fn decode_leader_data(tlvs: &[u8]) -> Option<LeaderData> {
+    match find_tlv(tlvs, TlvType::LeaderData)? {
+        Tlv::LeaderData {
+            partition_id,
+            weighting,
+            data_version,
+            stable_data_version,
+            leader_router_id,
+        } => Some(LeaderData {
+            partition_id: partition_id,
+            weighting: weighting,
+            data_version: data_version,
+            stable_data_version: stable_data_version,
+            leader_router_id: leader_router_id,
+        }),
+        _ => None,
+    }
+}
+
+/// The link quality of a link with `link_margin` dB (Section 4.4.1.1.1).
+fn link_quality(link_margin: u8) -> u8 {
+    match link_margin {
+        0..=2 => 0,
+        3..=10 => 1,
+        11..=20 => 2,
+        _ => 3,
+    }
+}
+
+impl<'a, A: Alarm<'a>> time::AlarmClient for ThreadEndDevice<'a, A> {
+    fn alarm(&self) {
+        match self.state.get() {
+            State::ParentRequest { reeds } => match self.parent.get() {
+                Some(parent) => {
+                    self.state.set(State::ChildIdRequest);
+                    let _ = self.send_message(parent.addr, mle_commands::CHILD_ID_REQUEST);
+                    self.set_timer(CHILD_ID_RESPONSE_TIMEOUT_MS);
+                }
+                None if !reeds => {
+                    self.state.set(State::ParentRequest { reeds: true });
+                    self.send_parent_request();
+                }
+                None => {
+                    self.state.set(State::Backoff);
+                    self.set_timer(ATTACH_BACKOFF_MS);
+                }
+            },
+            State::ChildIdRequest | State::Backoff => {
+                let _ = self.attach();
+            }
+            State::Attached => self.send_child_update_request(1),
+            State::ChildUpdate { attempts } if attempts < CHILD_UPDATE_ATTEMPTS => {
+                self.send_child_update_request(attempts + 1)
+            }
+            State::ChildUpdate { .. } => self.detach(),
+            State::Disabled | State::Challenge => {}
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> rng::Client for ThreadEndDevice<'a, A> {
+    fn randomness_available(
+        &self,
+        randomness: &mut dyn Iterator<Item = u32>,
+        error: ReturnCode,
+    ) -> rng::Continue {
+        if self.state.get() != State::Challenge {
+            return rng::Continue::Done;
+        }
+        if error != ReturnCode::SUCCESS {
+            self.state.set(State::Disabled);
+            return rng::Continue::Done;
+        }
+        let (high, low) = match (randomness.next(), randomness.next()) {
+            (Some(high), Some(low)) => (high, low),
+            _ => return rng::Continue::More,
+        };
+        let mut challenge = [0; 8];
+        challenge[..4].copy_from_slice(&high.to_be_bytes());
+        challenge[4..].copy_from_slice(&low.to_be_bytes());
+        self.challenge.set(challenge);
+        self.state.set(State::ParentRequest { reeds: false });
+        self.send_parent_request();
+        rng::Continue::Done
+    }
+}
+
+impl<'a, A: Alarm<'a>> AeadClient for ThreadEndDevice<'a, A> {
+    fn crypt_done(
+        &self,
+        aad: &'static mut [u8],
+        msg: &'static mut [u8],
+        mic: &'static mut [u8],
+        res: ReturnCode,
+        tag_is_valid: bool,
+    ) {
+        match self.crypt_op.take() {
+            Some(CryptOp::Encrypt { dst, len }) => {
+                // The auxiliary security header, the encrypted message and
+                // its MIC
+                let secured_len = AUX_HDR_LEN + len + MIC_LEN;
+                if res == ReturnCode::SUCCESS {
+                    self.tx_buf.take().map(|mut dgram| {
+                        if 1 + secured_len > dgram.len() {
+                            self.tx_buf.replace(dgram);
+                            return;
+                        }
+                        dgram[0] = security_suites::SECURED;
+                        dgram[1..1 + AUX_HDR_LEN].copy_from_slice(&aad[32..AAD_LEN]);
+                        dgram[1 + AUX_HDR_LEN..1 + AUX_HDR_LEN + len].copy_from_slice(&msg[..len]);
+                        dgram[1 + AUX_HDR_LEN + len..1 + secured_len].copy_from_slice(mic);
+                        dgram.slice(..1 + secured_len);
+                        if let Err(mut dgram) =
+                            self.udp_sender.send_to(dst, MLE_PORT, dgram, self.net_cap)
+                        {
+                            dgram.reset();
+                            self.tx_buf.replace(dgram);
+                        }
+                    });
+                }
+                self.replace_crypt_bufs(aad, msg, mic);
+            }
+            Some(CryptOp::Decrypt {
+                src,
+                frame_counter,
+                len,
+            }) => {
+                let reply = if res == ReturnCode::SUCCESS && tag_is_valid {
+                    self.receive_message(src, frame_counter, &msg[..len])
+                } else {
+                    None
+                };
+                self.replace_crypt_bufs(aad, msg, mic);
+                if let (Some(command), Some(parent)) = (reply, self.parent.get()) {
+                    let _ = self.send_message(parent.addr, command);
+                }
+            }
+            None => {
+                self.replace_crypt_bufs(aad, msg, mic);
+            }
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> UDPSendClient for ThreadEndDevice<'a, A> {
+    fn send_done(&self, _result: ReturnCode, mut dgram: LeasableBuffer<'static, u8>) {
+        dgram.reset();
+        self.tx_buf.replace(dgram);
+    }
+}
+
+impl<'a, A: Alarm<'a>> UDPRecvClient for ThreadEndDevice<'a, A> {
+    fn receive(
+        &self,
+        src_addr: IPAddr,
+        dst_addr: IPAddr,
+        src_port: u16,
+        _dst_port: u16,
+        payload: &[u8],
+        _timestamp: Option<u32>,
+    ) {
+        let (key_sequence, key) = match self.mle_key.get() {
+            Some(mle_key) => mle_key,
+            None => return,
+        };
+        if src_port != MLE_PORT
+            || self.state.get() == State::Disabled
+            || payload.len() < 1 + AUX_HDR_LEN + 1 + MIC_LEN
+            || payload[0] != security_suites::SECURED
+        {
+            return;
+        }
+        let header = match AuxSecurityHeader::decode(&payload[1..]).done() {
+            Some((_, header)) if header.key_sequence == key_sequence => header,
+            _ => return,
+        };
+        let sender = match ext_addr_of(&src_addr) {
+            Some(sender) => sender,
+            None => return,
+        };
+        // A message that arrives while another is being secured is
+        // dropped, and MLE sends it again
+        let (aad, msg, mic) = match self.take_crypt_bufs() {
+            Some(bufs) => bufs,
+            None => return,
+        };
+        let secured = &payload[1..];
+        let len = secured.len() - AUX_HDR_LEN - MIC_LEN;
+        if len > msg.len() {
+            self.replace_crypt_bufs(aad, msg, mic);
+            return;
+        }
+        aad[..16].copy_from_slice(&src_addr.0);
+        aad[16..32].copy_from_slice(&dst_addr.0);
+        aad[32..AAD_LEN].copy_from_slice(&secured[..AUX_HDR_LEN]);
+        msg[..len].copy_from_slice(&secured[AUX_HDR_LEN..AUX_HDR_LEN + len]);
+        mic.copy_from_slice(&secured[AUX_HDR_LEN + len..]);
+
+        self.crypt_op.set(CryptOp::Decrypt {
+            src: src_addr,
+            frame_counter: header.frame_counter,
+            len: len,
+        });
+        let nonce = nonce(&sender, header.frame_counter);
+        if self.crypt(&key, &nonce, aad, msg, len, mic, false) != ReturnCode::SUCCESS {
+            self.crypt_op.clear();
+        }
+    }
+}
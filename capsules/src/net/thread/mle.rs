@@ -0,0 +1,173 @@
+//! This file contains the structs and functions associated with the
+//! format of Mesh Link Establishment (MLE) messages, as outlined in
+//! Chapter 4 of the Thread 1.1.1 Specification. The TLVs the messages
+//! carry are in `tlv.rs`.
+//!
+//! MLE messages are sent over UDP on `MLE_PORT`. The first byte of the
+//! payload is the security suite. A secured message follows it with an
+//! 802.15.4 auxiliary security header, then the command type and its TLVs
+//! encrypted with AES-CCM under the MLE key, then a 4-byte MIC. The
+//! authenticated data are the IPv6 source and destination addresses
+//! followed by the auxiliary security header.
+//!
+//! MLE for network attaching comprises a four-step handshake that works
+//! as follows:
+//!
+//! 1. A child device multicasts a Parent Request MLE command.
+//! 2. Each potential parent device on the network unicasts a Parent
+//!    Response MLE command.
+//! 3. The child device selects a parent based on a hierarchy of
+//!    connectivity metrics and unicasts a Child ID Request MLE
+//!    command.
+//! 4. The selected parent unicasts a Child ID Response MLE command.
+
+use crate::net::ieee802154::MacAddress;
+use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::stream::SResult;
+use crate::net::stream::{decode_u32, decode_u8};
+use crate::net::stream::{encode_u32, encode_u8};
+use crate::net::thread::tlv::{Tlv, TlvType};
+use kernel::hil::symmetric_encryption::CCM_NONCE_LENGTH;
+
+pub const MLE_PORT: u16 = 19788;
+
+/// Length of the auxiliary security header of a secured message.
+pub const AUX_HDR_LEN: usize = 10;
+
+/// Length of the MIC of a secured message.
+pub const MIC_LEN: usize = 4;
+
+/// The ENC-MIC-32 security level that MLE messages are secured with.
+pub const SECURITY_LEVEL: u8 = 5;
+
+/// Security control of a secured message: `SECURITY_LEVEL` and key
+/// identifier mode 2, which carries the key sequence.
+pub const SECURITY_CONTROL: u8 = SECURITY_LEVEL | 0b10 << 3;
+
+/// The multicast group of the routers on the link, ff02::2.
+pub const LINK_LOCAL_ALL_ROUTERS: IPAddr =
+    IPAddr([0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x02]);
+
+/// The Version TLV value of Thread 1.1.
+pub const THREAD_VERSION: u16 = 2;
+
+pub mod security_suites {
+    pub const SECURED: u8 = 0;
+    pub const NONE: u8 = 255;
+}
+
+pub mod mle_commands {
+    pub const LINK_REQUEST: u8 = 0;
+    pub const LINK_ACCEPT: u8 = 1;
+    pub const LINK_ACCEPT_AND_REQUEST: u8 = 2;
+    pub const LINK_REJECT: u8 = 3;
+    pub const ADVERTISEMENT: u8 = 4;
+    pub const DATA_REQUEST: u8 = 7;
+    pub const DATA_RESPONSE: u8 = 8;
+    pub const PARENT_REQUEST: u8 = 9;
+    pub const PARENT_RESPONSE: u8 = 10;
+    pub const CHILD_ID_REQUEST: u8 = 11;
+    pub const CHILD_ID_RESPONSE: u8 = 12;
+    pub const CHILD_UPDATE_REQUEST: u8 = 13;
+    pub const CHILD_UPDATE_RESPONSE: u8 = 14;
+    pub const ANNOUNCE: u8 = 15;
+    pub const DISCOVERY_REQUEST: u8 = 16;
+    pub const DISCOVERY_RESPONSE: u8 = 17;
+}
+
+/// The auxiliary security header of a secured MLE message.
+#[derive(Copy, Clone, Debug)]
+pub struct AuxSecurityHeader {
+    pub frame_counter: u32,
+    pub key_sequence: u32,
+}
+
+impl AuxSecurityHeader {
+    pub fn new(frame_counter: u32, key_sequence: u32) -> AuxSecurityHeader {
+        AuxSecurityHeader {
+            frame_counter: frame_counter,
+            key_sequence: key_sequence,
+        }
+    }
+
+    /// The key index that goes with the key sequence.
+    pub fn key_index(&self) -> u8 {
+        (self.key_sequence & 0x7f) as u8 + 1
+    }
+
+    pub fn encode(&self, buf: &mut [u8], offset: usize) -> SResult<usize> {
+        stream_len_cond!(buf, offset + AUX_HDR_LEN);
+
+        let mut off = offset;
+        off = enc_consume!(buf, off; encode_u8, SECURITY_CONTROL);
+        // As in 802.15.4, the frame counter is little-endian
+        off = enc_consume!(buf, off; encode_u32, self.frame_counter.swap_bytes());
+        off = enc_consume!(buf, off; encode_u32, self.key_sequence);
+        off = enc_consume!(buf, off; encode_u8, self.key_index());
+        stream_done!(off, off);
+    }
+
+    /// Decodes the header, which has to have `SECURITY_CONTROL`.
+    pub fn decode(buf: &[u8]) -> SResult<AuxSecurityHeader> {
+        stream_len_cond!(buf, AUX_HDR_LEN);
+
+        let off = 0;
+        let (off, security_control) = dec_try!(buf, off; decode_u8);
+        stream_cond!(security_control == SECURITY_CONTROL);
+        let (off, frame_counter) = dec_try!(buf, off; decode_u32);
+        let (off, key_sequence) = dec_try!(buf, off; decode_u32);
+        let (off, _key_index) = dec_try!(buf, off; decode_u8);
+        stream_done!(
+            off,
+            AuxSecurityHeader {
+                frame_counter: frame_counter.swap_bytes(),
+                key_sequence: key_sequence,
+            }
+        );
+    }
+}
+
+/// The CCM nonce of a message with `frame_counter` from the device with
+/// the extended address `ext_addr`.
+pub fn nonce(ext_addr: &[u8; 8], frame_counter: u32) -> [u8; CCM_NONCE_LENGTH] {
+    let mut nonce = [0; CCM_NONCE_LENGTH];
+    nonce[..8].copy_from_slice(ext_addr);
+    nonce[8..12].copy_from_slice(&frame_counter.to_be_bytes());
+    nonce[12] = SECURITY_LEVEL;
+    nonce
+}
+
+/// The link-local address MLE messages from `ext_addr` are sent from.
+pub fn link_local_addr(ext_addr: &[u8; 8]) -> IPAddr {
+    IPAddr::generate_from_mac(MacAddress::Long(*ext_addr))
+}
+
+/// The extended address of the device that sent a message from the
+/// link-local address `addr`.
+pub fn ext_addr_of(addr: &IPAddr) -> Option<[u8; 8]> {
+    if !addr.is_unicast_link_local() {
+        return None;
+    }
+    let mut ext_addr = [0; 8];
+    ext_addr.copy_from_slice(&addr.0[8..]);
+    ext_addr[0] ^= 0b0000_0010;
+    Some(ext_addr)
+}
+
+/// Returns the first TLV of type `tlv_type` in `tlvs`, the TLVs of a
+/// message, or `None` if there is none or it is malformed.
+pub fn find_tlv(tlvs: &[u8], tlv_type: TlvType) -> Option<Tlv> {
+    let tlv_type = tlv_type as u8;
+    let mut off = 0;
+    while off + 2 <= tlvs.len() {
+        let end = off + 2 + tlvs[off + 1] as usize;
+        if end > tlvs.len() {
+            return None;
+        }
+        if tlvs[off] == tlv_type {
+            return Tlv::decode(&tlvs[off..end]).done().map(|(_, tlv)| tlv);
+        }
+        off = end;
+    }
+    None
+}
@@ -1 +1,3 @@
+pub mod end_device;
+pub mod mle;
 pub mod tlv;
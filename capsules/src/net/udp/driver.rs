@@ -20,7 +20,7 @@ use core::{cmp, mem};
 use kernel::capabilities::UdpDriverCapability;
 use kernel::common::cells::MapCell;
 use kernel::common::leasable_buffer::LeasableBuffer;
-use kernel::{debug, AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
+use kernel::{log_warn, AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
 
 use crate::driver;
 pub const DRIVER_NUM: usize = driver::NUM::Udp as usize;
@@ -328,7 +328,7 @@ impl<'a> UDPDriver<'a> {
     #[inline]
     fn parse_ip_port_pair(&self, buf: &[u8]) -> Option<UDPEndpoint> {
         if buf.len() != mem::size_of::<UDPEndpoint>() {
-            debug!(
+            log_warn!(
                 "[parse] len is {:?}, not {:?} as expected",
                 buf.len(),
                 mem::size_of::<UDPEndpoint>()
@@ -401,11 +401,14 @@ impl<'a> Driver for UDPDriver<'a> {
     ///
     /// - `0`: Setup callback for when packet is received. If no port has
     ///        been bound, return ERESERVE to indicate that port binding is
-    ///        is a prerequisite to reception.
+    ///        is a prerequisite to reception. The callback receives the
+    ///        payload length, the arrival timestamp, and whether the
+    ///        timestamp is valid.
     /// - `1`: Setup callback for when packet is transmitted. Notably,
     ///        this callback receives the result of the send_done callback
-    ///        from udp_send.rs, which does not currently pass information
-    ///        regarding whether packets were acked at the link layer.
+    ///        from udp_send.rs. Over a MAC layer that retransmits, such as
+    ///        `CsmaMac`, it is `ENOACK` if a frame of the packet was never
+    ///        acknowledged and `EBUSY` if the channel stayed busy.
     fn subscribe(
         &self,
         subscribe_num: usize,
@@ -469,9 +472,10 @@ impl<'a> Driver for UDPDriver<'a> {
     ///        containing the bound port to None and set the rx callback to None. Notably,
     ///        the current implementation of this only allows for each app to bind to a single
     ///        port at a time, as such an implementation conserves memory (and is similar
-    ///        to the approach applied by TinyOS and Riot). Further, there is
-    ///        currently no mechanism for anything in the kernel to bind to ports, and there
-    ///        is no distinction between ephemeral ports and reserved ports.
+    ///        to the approach applied by TinyOS and Riot). A port can only be bound by
+    ///        one app at a time, on any interface, and not if a kernel capsule bound it
+    ///        through the port table. There is no distinction between ephemeral ports and
+    ///        reserved ports.
     /// - `4`: Returns the maximum payload that can be transmitted by apps using this driver.
     ///        This represents the size of the payload buffer in the kernel. Apps can use this
     ///        syscall to ensure they do not attempt to send too-large messages.
@@ -534,7 +538,7 @@ impl<'a> Driver for UDPDriver<'a> {
             3 => {
                 self.do_with_app(appid, |app| {
                     // Move UDPEndpoint into udp.rs?
-                    let mut requested_addr_opt = app.app_rx_cfg.as_ref().and_then(|cfg| {
+                    let requested_addr_opt = app.app_rx_cfg.as_ref().and_then(|cfg| {
                         if cfg.len() != 2 * mem::size_of::<UDPEndpoint>() {
                             None
                         } else if let Some(local_iface) =
@@ -563,44 +567,29 @@ impl<'a> Driver for UDPDriver<'a> {
                                 requested_is_local = true;
                             }
                         }
-                        if !requested_is_local {
+                        if !requested_is_local || requested_addr.port == 0 {
                             return ReturnCode::EINVAL;
                         }
-                        let mut addr_already_bound = false;
-                        // This checks the bound ports in the other grants.
-                        // This code needs to be replicated in the bound port
-                        // table when checking the userspace apps.
-                        for app in self.apps.iter() {
-                            app.enter(|other_app, _| {
-                                if other_app.bound_port.is_some() {
-                                    let other_addr_opt = other_app.bound_port.clone();
-                                    let other_addr =
-                                        other_addr_opt.expect("Missing other address.");
-                                    if other_addr.port == requested_addr.port {
-                                        if other_addr.addr == requested_addr.addr {
-                                            addr_already_bound = true;
-                                        }
-                                    }
-                                }
-                            });
-                        }
-                        // Check bound ports in the kernel.
+                        // A port is reserved by one app at a time, on any
+                        // interface, and never by both an app and a capsule.
+                        // The port table checks both, so drop our own binding
+                        // while asking it, to let an app bind again to its
+                        // port, and restore it if the request fails.
+                        let previous = app.bound_port.take();
                         match self.port_table.is_bound(requested_addr.port) {
-                            Ok(bound) => {
-                                addr_already_bound = bound;
+                            Ok(false) => {
+                                // If this point is reached, the requested addr is free and valid
+                                app.bound_port = Some(requested_addr);
+                                ReturnCode::SUCCESS
+                            }
+                            Ok(true) => {
+                                app.bound_port = previous;
+                                ReturnCode::EBUSY
                             }
                             Err(_) => {
-                                return ReturnCode::FAIL;
-                            } //error in port table
-                        }
-                        // Also check the bound port table here.
-                        if addr_already_bound {
-                            ReturnCode::EBUSY
-                        } else {
-                            requested_addr_opt = Some(requested_addr);
-                            // If this point is reached, the requested addr is free and valid
-                            app.bound_port = requested_addr_opt;
-                            ReturnCode::SUCCESS
+                                app.bound_port = previous;
+                                ReturnCode::FAIL //error in port table
+                            }
                         }
                     } else {
                         ReturnCode::EINVAL
@@ -639,6 +628,7 @@ impl<'a> UDPRecvClient for UDPDriver<'a> {
         src_port: u16,
         dst_port: u16,
         payload: &[u8],
+        timestamp: Option<u32>,
     ) {
         self.apps.each(|app| {
             if app.bound_port.is_some() {
@@ -669,7 +659,13 @@ impl<'a> UDPRecvClient for UDPDriver<'a> {
                                     sender_addr.encode(cfg, 0);
                                     ReturnCode::SUCCESS
                                 });
-                                app.rx_callback.map(|mut cb| cb.schedule(len, 0, 0));
+                                app.rx_callback.map(|mut cb| {
+                                    cb.schedule(
+                                        len,
+                                        timestamp.unwrap_or(0) as usize,
+                                        timestamp.is_some() as usize,
+                                    )
+                                });
                             }
                         });
                         app.app_read = app_read;
@@ -698,4 +694,31 @@ impl<'a> PortQuery for UDPDriver<'a> {
         }
         port_bound
     }
+
+    fn each_bound_port(&self, f: &mut dyn FnMut(u16, AppId)) {
+        for app in self.apps.iter() {
+            app.enter(|app, _| {
+                if let Some(endpoint) = app.bound_port.as_ref() {
+                    f(endpoint.port, app.appid());
+                }
+            });
+        }
+    }
+
+    fn force_unbind(&self, port: u16) -> bool {
+        let mut unbound = false;
+        for app in self.apps.iter() {
+            app.enter(|app, _| {
+                if app
+                    .bound_port
+                    .as_ref()
+                    .map_or(false, |endpoint| endpoint.port == port)
+                {
+                    app.bound_port = None;
+                    unbound = true;
+                }
+            });
+        }
+        unbound
+    }
 }
@@ -31,12 +31,34 @@
 //! such that removing an app automatically unbinds it. This file is able to query the
 //! userspace UDP driver to check which ports are bound, and vice-versa, such that
 //! exclusive access to ports between userspace apps and capsules is still enforced.
+//!
+//! Receive bindings can also join IPv6 multicast groups with `join_group`, and
+//! the table keeps up to `MAX_NUM_MEMBERSHIPS` memberships in total. The UDP
+//! receive mux delivers packets sent to a group to every receiver that joined
+//! it on the destination port. Several capsules can receive on a port this way
+//! by binding it with `bind_shared`, which, unlike `bind`, does not exclude
+//! other shared bindings.
+//!
+//! The table also counts the datagrams each binding sent and received, and
+//! in its totals the datagrams the UDP muxes dropped, as `UdpStats`. The
+//! counters of a binding start over when its socket is bound again.
+//!
+//! For debugging, a capsule can label its socket with `set_owner`, and
+//! `each_binding` lists the bindings with their labels. `force_unbind` takes
+//! a port away from whoever holds it, capsule or app. A capsule whose binding
+//! was forced off the port keeps the binding structures, but the UDP muxes no
+//! longer send or deliver packets with them, and it can still `unbind` them
+//! to get its socket back.
 
+use crate::net::ipv6::ip_utils::IPAddr;
 use crate::net::network_capabilities::{NetworkCapability, UdpVisibilityCapability};
+use core::cell::Cell;
 use core::fmt;
-use kernel::capabilities::{CreatePortTableCapability, UdpDriverCapability};
-use kernel::common::cells::{OptionalCell, TakeCell};
-use kernel::ReturnCode;
+use kernel::capabilities::{
+    CreatePortTableCapability, ProcessManagementCapability, UdpDriverCapability,
+};
+use kernel::common::cells::{MapCell, OptionalCell, TakeCell};
+use kernel::{AppId, ReturnCode};
 
 // Sets the maximum number of UDP ports that can be bound by capsules. Reducing this number
 // can save a small amount of memory, and slightly reduces the overhead of iterating through the
@@ -46,12 +68,39 @@ use kernel::ReturnCode;
 // is.
 pub const MAX_NUM_BOUND_PORTS: usize = 16;
 
+/// Sets the maximum number of multicast group memberships of all bindings.
+pub const MAX_NUM_MEMBERSHIPS: usize = 8;
+
+/// The all-nodes group, ff02::1, which every binding is a member of.
+pub const ALL_NODES_GROUP: IPAddr =
+    IPAddr([0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x01]);
+
+/// Counters of the datagrams of one binding, or of all datagrams for the
+/// totals of the table. The counters wrap around.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct UdpStats {
+    /// Datagrams the IP layer finished sending.
+    pub sent: u32,
+    /// Datagrams that were not sent, most often because the IP layer had no
+    /// free buffer.
+    pub send_failures: u32,
+    pub received: u32,
+    /// Datagrams dropped because nothing was bound to their port. Always 0
+    /// for a binding.
+    pub no_receiver: u32,
+    /// Datagrams dropped because of a wrong checksum. Always 0 for a
+    /// binding.
+    pub checksum_failures: u32,
+}
+
 /// The SocketBindingEntry struct is stored in the PORT_TABLE and conveys what port is bound
 /// at the given index if one is bound. If no port is bound, the value stored
 /// at that location in the table is Unbound.
 #[derive(Clone, Copy, PartialEq)]
 pub enum SocketBindingEntry {
     Port(u16),
+    /// Bound with `bind_shared`, so other sockets can share the port.
+    SharedPort(u16),
     Unbound,
 }
 
@@ -59,6 +108,14 @@ pub enum SocketBindingEntry {
 /// ports in the UDP driver. The UDP driver struct implements this trait.
 pub trait PortQuery {
     fn is_bound(&self, port: u16) -> bool;
+
+    /// Calls `f` with each port bound by an app, and the app.
+    fn each_bound_port(&self, _f: &mut dyn FnMut(u16, AppId)) {}
+
+    /// Unbinds every app bound to `port`. Returns whether one was.
+    fn force_unbind(&self, _port: u16) -> bool {
+        false
+    }
 }
 
 /// A UdpSocket provides a handle into the bound port table. When binding to
@@ -77,6 +134,12 @@ pub struct UdpPortManager {
     port_array: TakeCell<'static, [Option<SocketBindingEntry>]>,
     user_ports: OptionalCell<&'static dyn PortQuery>,
     udp_vis: &'static UdpVisibilityCapability,
+    // The table index of each binding that joined a group, with the group
+    memberships: [Cell<Option<(usize, IPAddr)>>; MAX_NUM_MEMBERSHIPS],
+    stats: MapCell<[UdpStats; MAX_NUM_BOUND_PORTS]>,
+    totals: Cell<UdpStats>,
+    // The label each socket was given with `set_owner`
+    owners: [Cell<Option<&'static str>>; MAX_NUM_BOUND_PORTS],
 }
 
 impl fmt::Debug for UdpPortManager {
@@ -155,6 +218,19 @@ impl UdpPortManager {
             port_array: TakeCell::new(used_kernel_ports),
             user_ports: OptionalCell::empty(),
             udp_vis: udp_vis,
+            memberships: [
+                Cell::new(None),
+                Cell::new(None),
+                Cell::new(None),
+                Cell::new(None),
+                Cell::new(None),
+                Cell::new(None),
+                Cell::new(None),
+                Cell::new(None),
+            ],
+            stats: MapCell::new([UdpStats::default(); MAX_NUM_BOUND_PORTS]),
+            totals: Cell::new(UdpStats::default()),
+            owners: <[Cell<Option<&'static str>>; MAX_NUM_BOUND_PORTS]>::default(),
         }
     }
 
@@ -198,12 +274,85 @@ impl UdpPortManager {
             Some(entry) => {
                 if entry == SocketBindingEntry::Unbound {
                     table[socket.idx] = None;
+                    self.owners[socket.idx].set(None);
                 }
             }
             _ => {}
         });
     }
 
+    /// Labels `socket` and the bindings made with it as belonging to
+    /// `owner`, usually the name of the capsule, for `each_binding`.
+    pub fn set_owner(&self, socket: &UdpSocket, owner: &'static str) {
+        self.owners[socket.idx].set(Some(owner));
+    }
+
+    /// Calls `f` with the port of each binding of a capsule, whether it was
+    /// bound with `bind_shared`, and the label of its socket.
+    pub fn each_binding<F: FnMut(u16, bool, Option<&'static str>)>(&self, mut f: F) {
+        self.port_array.map(|table| {
+            for (entry, owner) in table.iter().zip(self.owners.iter()) {
+                match entry {
+                    Some(SocketBindingEntry::Port(port)) => f(*port, false, owner.get()),
+                    Some(SocketBindingEntry::SharedPort(port)) => f(*port, true, owner.get()),
+                    _ => {}
+                }
+            }
+        });
+    }
+
+    /// Calls `f` with each port bound by an app, and the app.
+    pub fn each_user_binding<F: FnMut(u16, AppId)>(&self, mut f: F) {
+        self.user_ports
+            .map(|port_query| port_query.each_bound_port(&mut f));
+    }
+
+    /// Unbinds every capsule and app bound to `port`, so that it can be
+    /// bound again. Returns the number of bindings removed, counting all
+    /// apps as one.
+    pub fn force_unbind(&self, port: u16, _cap: &dyn ProcessManagementCapability) -> usize {
+        let mut removed = 0;
+        self.port_array.map(|table| {
+            for (idx, entry) in table.iter_mut().enumerate() {
+                match entry {
+                    Some(SocketBindingEntry::Port(p)) | Some(SocketBindingEntry::SharedPort(p))
+                        if *p == port =>
+                    {
+                        *entry = Some(SocketBindingEntry::Unbound);
+                        self.leave_all_groups(idx);
+                        removed += 1;
+                    }
+                    _ => {}
+                }
+            }
+        });
+        let user_removed = self
+            .user_ports
+            .map_or(false, |port_query| port_query.force_unbind(port));
+        removed + user_removed as usize
+    }
+
+    /// Whether `binding` still holds its port, that is, it was not forced
+    /// off it with `force_unbind`.
+    pub fn holds_port_tx(&self, binding: &UdpPortBindingTx) -> bool {
+        self.holds_port(binding.idx, binding.port)
+    }
+
+    /// Whether `binding` still holds its port, that is, it was not forced
+    /// off it with `force_unbind`.
+    pub fn holds_port_rx(&self, binding: &UdpPortBindingRx) -> bool {
+        self.holds_port(binding.idx, binding.port)
+    }
+
+    fn holds_port(&self, idx: usize, port: u16) -> bool {
+        self.port_array.map_or(false, |table| match table[idx] {
+            Some(SocketBindingEntry::Port(p)) | Some(SocketBindingEntry::SharedPort(p)) => {
+                p == port
+            }
+            _ => false,
+        })
+    }
+
     /// Check if a given port is already bound, by either an app or capsule.
     pub fn is_bound(&self, port: u16) -> Result<bool, ()> {
         // First, check the user bindings.
@@ -223,7 +372,8 @@ impl UdpPortManager {
                 let mut port_exists = false;
                 for i in 0..MAX_NUM_BOUND_PORTS {
                     match table[i] {
-                        Some(SocketBindingEntry::Port(p)) => {
+                        Some(SocketBindingEntry::Port(p))
+                        | Some(SocketBindingEntry::SharedPort(p)) => {
                             if p == port {
                                 port_exists = true;
                                 break;
@@ -257,6 +407,7 @@ impl UdpPortManager {
                         self.port_array
                             .map(|table| {
                                 table[socket.idx] = Some(SocketBindingEntry::Port(port));
+                                self.reset_stats(socket.idx);
                                 let binding_pair = (
                                     UdpPortBindingTx::new(socket.idx, port),
                                     UdpPortBindingRx::new(socket.idx, port),
@@ -274,6 +425,166 @@ impl UdpPortManager {
         }
     }
 
+    /// Like `bind`, but succeeds if `port` is only bound by other calls to
+    /// `bind_shared`, so that several capsules can receive the packets sent
+    /// to multicast groups on the port.
+    pub fn bind_shared(
+        &self,
+        socket: UdpSocket,
+        port: u16,
+        net_cap: &'static NetworkCapability,
+    ) -> Result<(UdpPortBindingTx, UdpPortBindingRx), UdpSocket> {
+        if !net_cap.local_port_valid(port, self.udp_vis) {
+            return Err(socket);
+        }
+        let user_bound = self
+            .user_ports
+            .map_or(true, |port_query| port_query.is_bound(port));
+        let exclusive = self.port_array.map_or(true, |table| {
+            table
+                .iter()
+                .any(|entry| *entry == Some(SocketBindingEntry::Port(port)))
+        });
+        if user_bound || exclusive {
+            return Err(socket);
+        }
+        self.port_array
+            .map(|table| {
+                table[socket.idx] = Some(SocketBindingEntry::SharedPort(port));
+                self.reset_stats(socket.idx);
+                Ok((
+                    UdpPortBindingTx::new(socket.idx, port),
+                    UdpPortBindingRx::new(socket.idx, port),
+                ))
+            })
+            .unwrap_or(Err(socket))
+    }
+
+    /// Joins `binding` to the multicast group `group`, so the binding
+    /// receives the packets sent to the group on its port. Returns `EINVAL`
+    /// if `group` is not a multicast address, `EALREADY` if the binding is
+    /// a member, and `ENOMEM` if `MAX_NUM_MEMBERSHIPS` memberships exist.
+    pub fn join_group(&self, binding: &UdpPortBindingRx, group: IPAddr) -> ReturnCode {
+        if !group.is_multicast() {
+            return ReturnCode::EINVAL;
+        }
+        if self.is_member(binding, group) {
+            return ReturnCode::EALREADY;
+        }
+        match self.memberships.iter().find(|slot| slot.get().is_none()) {
+            Some(slot) => {
+                slot.set(Some((binding.idx, group)));
+                ReturnCode::SUCCESS
+            }
+            None => ReturnCode::ENOMEM,
+        }
+    }
+
+    /// Removes `binding` from the multicast group `group`.
+    pub fn leave_group(&self, binding: &UdpPortBindingRx, group: IPAddr) -> ReturnCode {
+        match self
+            .memberships
+            .iter()
+            .find(|slot| slot.get() == Some((binding.idx, group)))
+        {
+            Some(slot) => {
+                slot.set(None);
+                ReturnCode::SUCCESS
+            }
+            None => ReturnCode::EINVAL,
+        }
+    }
+
+    /// Checks if `binding` is a member of the multicast group `group`.
+    pub fn is_member(&self, binding: &UdpPortBindingRx, group: IPAddr) -> bool {
+        group == ALL_NODES_GROUP
+            || self
+                .memberships
+                .iter()
+                .any(|slot| slot.get() == Some((binding.idx, group)))
+    }
+
+    /// The counters of the socket of `binding`.
+    pub fn binding_stats(&self, binding: &UdpPortBindingRx) -> UdpStats {
+        self.stats
+            .map_or(UdpStats::default(), |stats| stats[binding.idx])
+    }
+
+    /// The counters of all datagrams, including those of userspace apps.
+    pub fn total_stats(&self) -> UdpStats {
+        self.totals.get()
+    }
+
+    /// Calls `f` with the port and counters of each bound socket.
+    pub fn each_binding_stats<F: FnMut(u16, UdpStats)>(&self, mut f: F) {
+        self.port_array.map(|table| {
+            self.stats.map(|stats| {
+                for (entry, stats) in table.iter().zip(stats.iter()) {
+                    match entry {
+                        Some(SocketBindingEntry::Port(port))
+                        | Some(SocketBindingEntry::SharedPort(port)) => f(*port, *stats),
+                        _ => {}
+                    }
+                }
+            });
+        });
+    }
+
+    /// Counts a datagram sent with `binding`, or by the userspace driver if
+    /// it is `None`, that finished with `result`.
+    pub fn record_send(&self, binding: Option<&UdpPortBindingTx>, result: ReturnCode) {
+        self.count(binding.map(|binding| binding.idx), |stats| {
+            if result == ReturnCode::SUCCESS {
+                stats.sent = stats.sent.wrapping_add(1);
+            } else {
+                stats.send_failures = stats.send_failures.wrapping_add(1);
+            }
+        });
+    }
+
+    /// Counts a datagram passed to `binding`, or to the userspace driver if
+    /// it is `None`.
+    pub fn record_receive(&self, binding: Option<&UdpPortBindingRx>) {
+        self.count(binding.map(|binding| binding.idx), |stats| {
+            stats.received = stats.received.wrapping_add(1);
+        });
+    }
+
+    pub fn record_no_receiver(&self) {
+        self.count(None, |stats| {
+            stats.no_receiver = stats.no_receiver.wrapping_add(1);
+        });
+    }
+
+    pub fn record_checksum_failure(&self) {
+        self.count(None, |stats| {
+            stats.checksum_failures = stats.checksum_failures.wrapping_add(1);
+        });
+    }
+
+    /// Applies `f` to the totals, and to the counters of the socket at
+    /// `idx` if there is one.
+    fn count<F: Fn(&mut UdpStats)>(&self, idx: Option<usize>, f: F) {
+        let mut totals = self.totals.get();
+        f(&mut totals);
+        self.totals.set(totals);
+        if let Some(idx) = idx {
+            self.stats.map(|stats| f(&mut stats[idx]));
+        }
+    }
+
+    fn leave_all_groups(&self, idx: usize) {
+        for slot in self.memberships.iter() {
+            if slot.get().map_or(false, |(member, _)| member == idx) {
+                slot.set(None);
+            }
+        }
+    }
+
+    fn reset_stats(&self, idx: usize) {
+        self.stats.map(|stats| stats[idx] = UdpStats::default());
+    }
+
     /// Disassociate the port from the given binding. Return the socket associated
     /// with the passed bindings. On Err, return the passed bindings.
     pub fn unbind(
@@ -289,6 +600,8 @@ impl UdpPortManager {
         self.port_array.map(|table| {
             table[idx] = Some(SocketBindingEntry::Unbound);
         });
+        // The binding leaves its groups
+        self.leave_all_groups(idx);
         // Search the list and return the appropriate socket
         Ok(UdpSocket::new(idx, &self))
     }
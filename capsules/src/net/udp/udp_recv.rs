@@ -4,20 +4,27 @@
 //! appropriate capsule / app. Once again, port binding for userspace apps is managed seperately
 //! by the UDP userspace driver, which must correctly check bindings of kernel apps to ensure
 //! correctness when dispatching received packets to the appropriate client.
+//! A packet sent to a multicast group is instead dispatched to every receiver
+//! bound to its port that joined the group with `UDPReceiver::join_group`, and
+//! then to the driver.
+//! Given the port table, the mux counts the datagrams it passes to each
+//! receiver, and those it drops, in it.
 
-use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::ipv6::ip_utils::{ip6_nh, IPAddr};
 use crate::net::ipv6::ipv6::IP6Header;
 use crate::net::ipv6::ipv6_recv::IP6RecvClient;
 use crate::net::udp::driver::UDPDriver;
 use crate::net::udp::udp::UDPHeader;
-use crate::net::udp::udp_port_table::{PortQuery, UdpPortBindingRx};
+use crate::net::udp::udp_port_table::{PortQuery, UdpPortBindingRx, UdpPortManager};
 use kernel::common::cells::{MapCell, OptionalCell};
 use kernel::common::{List, ListLink, ListNode};
-use kernel::debug;
+use kernel::log_trace;
+use kernel::ReturnCode;
 
 pub struct MuxUdpReceiver<'a> {
     rcvr_list: List<'a, UDPReceiver<'a>>,
     driver: OptionalCell<&'static UDPDriver<'static>>,
+    port_table: OptionalCell<&'static UdpPortManager>,
 }
 
 impl<'a> MuxUdpReceiver<'a> {
@@ -25,6 +32,7 @@ impl<'a> MuxUdpReceiver<'a> {
         MuxUdpReceiver {
             rcvr_list: List::new(),
             driver: OptionalCell::empty(),
+            port_table: OptionalCell::empty(),
         }
     }
 
@@ -35,22 +43,42 @@ impl<'a> MuxUdpReceiver<'a> {
     pub fn set_driver(&self, driver_ref: &'static UDPDriver) {
         self.driver.replace(driver_ref);
     }
+
+    /// Sets the port table that tracks the multicast groups receivers
+    /// joined and counts datagrams. Without it, multicast packets are only
+    /// passed to the driver.
+    pub fn set_port_table(&self, port_table: &'static UdpPortManager) {
+        self.port_table.replace(port_table);
+    }
 }
 
 impl<'a> IP6RecvClient for MuxUdpReceiver<'a> {
-    fn receive(&self, ip_header: IP6Header, payload: &[u8]) {
+    fn receive(&self, ip_header: IP6Header, payload: &[u8], timestamp: Option<u32>) {
+        // The IP receiver passes up packets of every transport, such as TCP
+        if ip_header.get_next_header() != ip6_nh::UDP {
+            return;
+        }
         match UDPHeader::decode(payload).done() {
             Some((offset, udp_header)) => {
                 let len = udp_header.get_len() as usize;
                 let dst_port = udp_header.get_dst_port();
                 if len > payload.len() {
-                    debug!("[UDP_RECV] Error: Received UDP length too long");
+                    log_trace!("[UDP_RECV] Error: Received UDP length too long");
                     return;
                 }
+                let dst_addr = ip_header.get_dst_addr();
+                if dst_addr.is_multicast() {
+                    self.receive_multicast(ip_header, udp_header, &payload[offset..], timestamp);
+                    return;
+                }
+                let mut delivered = false;
                 for rcvr in self.rcvr_list.iter() {
                     match rcvr.binding.take() {
                         Some(binding) => {
-                            if binding.get_port() == dst_port {
+                            let holds_port = self
+                                .port_table
+                                .map_or(true, |port_table| port_table.holds_port_rx(&binding));
+                            if binding.get_port() == dst_port && holds_port {
                                 rcvr.client.map(|client| {
                                     client.receive(
                                         ip_header.get_src_addr(),
@@ -58,9 +86,13 @@ impl<'a> IP6RecvClient for MuxUdpReceiver<'a> {
                                         udp_header.get_src_port(),
                                         udp_header.get_dst_port(),
                                         &payload[offset..],
+                                        timestamp,
                                     );
                                 });
+                                self.port_table
+                                    .map(|port_table| port_table.record_receive(Some(&binding)));
                                 rcvr.binding.replace(binding);
+                                delivered = true;
                                 break;
                             }
                             rcvr.binding.replace(binding);
@@ -75,8 +107,12 @@ impl<'a> IP6RecvClient for MuxUdpReceiver<'a> {
                                         udp_header.get_src_port(),
                                         udp_header.get_dst_port(),
                                         &payload[offset..],
+                                        timestamp,
                                     );
                                     self.driver.replace(driver);
+                                    self.port_table
+                                        .map(|port_table| port_table.record_receive(None));
+                                    delivered = true;
                                     break;
                                 }
                                 self.driver.replace(driver);
@@ -85,17 +121,87 @@ impl<'a> IP6RecvClient for MuxUdpReceiver<'a> {
                         },
                     }
                 }
+                if !delivered {
+                    self.port_table
+                        .map(|port_table| port_table.record_no_receiver());
+                }
             }
             None => {}
         }
     }
+
+    fn checksum_failed(&self, ip_header: IP6Header) {
+        if ip_header.get_next_header() == ip6_nh::UDP {
+            self.port_table
+                .map(|port_table| port_table.record_checksum_failure());
+        }
+    }
+}
+
+impl<'a> MuxUdpReceiver<'a> {
+    fn receive_multicast(
+        &self,
+        ip_header: IP6Header,
+        udp_header: UDPHeader,
+        payload: &[u8],
+        timestamp: Option<u32>,
+    ) {
+        let dst_addr = ip_header.get_dst_addr();
+        let dst_port = udp_header.get_dst_port();
+        let mut delivered = false;
+        self.port_table.map(|port_table| {
+            for rcvr in self.rcvr_list.iter() {
+                let member = rcvr.binding.map_or(false, |binding| {
+                    binding.get_port() == dst_port
+                        && port_table.holds_port_rx(binding)
+                        && port_table.is_member(binding, dst_addr)
+                });
+                if member {
+                    rcvr.binding
+                        .map(|binding| port_table.record_receive(Some(binding)));
+                    delivered = true;
+                    rcvr.client.map(|client| {
+                        client.receive(
+                            ip_header.get_src_addr(),
+                            dst_addr,
+                            udp_header.get_src_port(),
+                            dst_port,
+                            payload,
+                            timestamp,
+                        );
+                    });
+                }
+            }
+        });
+        self.driver.map(|driver| {
+            if driver.is_bound(dst_port) {
+                driver.receive(
+                    ip_header.get_src_addr(),
+                    dst_addr,
+                    udp_header.get_src_port(),
+                    dst_port,
+                    payload,
+                    timestamp,
+                );
+                self.port_table
+                    .map(|port_table| port_table.record_receive(None));
+                delivered = true;
+            }
+        });
+        if !delivered {
+            self.port_table
+                .map(|port_table| port_table.record_no_receiver());
+        }
+    }
 }
 
 /// The UDP driver implements this client interface trait to receive
 /// packets passed up the network stack to the UDPReceiver, and then
 /// distributes them to userland applications from there.
 /// Kernel apps can also instantiate structs that implement this trait
-/// in order to receive UDP packets
+/// in order to receive UDP packets. `timestamp` is the time the packet
+/// arrived, in ticks of the radio's timestamp source, or `None` if the radio
+/// does not timestamp received frames.
 pub trait UDPRecvClient {
     fn receive(
         &self,
@@ -104,6 +210,7 @@ pub trait UDPRecvClient {
         src_port: u16,
         dst_port: u16,
         payload: &[u8],
+        timestamp: Option<u32>,
     );
 }
 
@@ -146,4 +253,19 @@ impl<'a> UDPReceiver<'a> {
     pub fn set_binding(&self, binding: UdpPortBindingRx) -> Option<UdpPortBindingRx> {
         self.binding.replace(binding)
     }
+
+    /// Joins the multicast group `group` in `port_table`, to receive the
+    /// packets sent to it on the bound port. Returns `EOFF` if the receiver
+    /// is not bound.
+    pub fn join_group(&self, port_table: &UdpPortManager, group: IPAddr) -> ReturnCode {
+        self.binding.map_or(ReturnCode::EOFF, |binding| {
+            port_table.join_group(binding, group)
+        })
+    }
+
+    pub fn leave_group(&self, port_table: &UdpPortManager, group: IPAddr) -> ReturnCode {
+        self.binding.map_or(ReturnCode::EOFF, |binding| {
+            port_table.leave_group(binding, group)
+        })
+    }
 }
@@ -11,27 +11,64 @@
 //! on the port binding logic in the driver being correct.
 //! The MuxUdpSender acts as a FIFO queue for transmitted packets, with each capsule being allowed
 //! a single outstanding / unsent packet at a time.
+//! A capsule can instead queue several packets by giving its `UDPSendStruct` a
+//! queue with `set_queue`. Sends that find the capsule's packet outstanding then
+//! wait in the queue, and fail only once it is full. The MuxUdpSender sends one
+//! packet of each capsule in turn, so a capsule with a long queue does not hold
+//! back the others.
 //! Because the userspace driver is viewed by the MuxUdpSender as being a single capsule,
 //! the userspace driver must queue app packets on its own, as it can only pass a single
 //! packet to the MuxUdpSender queue at a time.
+//! Given the port table with `set_port_table`, the MuxUdpSender counts the
+//! datagrams each binding sent and failed to send in it.
+//!
+//! Usage
+//! -----
+//!
+//! Each kernel client gets its own `UDPSendStruct` on the mux, which holds the
+//! client's buffer while its packet is in flight and returns it in the
+//! client's own `send_done`:
+//!
+//! ```rust,ignore
+//! # use kernel::static_init;
+//! # use capsules::net::udp::udp_send::{MuxUdpSender, UDPSendStruct};
+//!
+//! let udp_send_mux = static_init!(
+//!     MuxUdpSender<'static, IP6SendStruct<'static, VirtualMuxAlarm<'static, Ast>>>,
+//!     MuxUdpSender::new(ip_send)
+//! );
+//! ip_send.set_client(udp_send_mux);
+//!
+//! let coap_send = static_init!(
+//!     UDPSendStruct<'static, IP6SendStruct<'static, VirtualMuxAlarm<'static, Ast>>>,
+//!     UDPSendStruct::new(udp_send_mux, udp_vis)
+//! );
+//! coap_send.set_client(coap);
+//! let dns_send = static_init!(
+//!     UDPSendStruct<'static, IP6SendStruct<'static, VirtualMuxAlarm<'static, Ast>>>,
+//!     UDPSendStruct::new(udp_send_mux, udp_vis)
+//! );
+//! dns_send.set_client(dns);
+//! ```
 
 use crate::net::ipv6::ip_utils::IPAddr;
 use crate::net::ipv6::ipv6::TransportHeader;
 use crate::net::ipv6::ipv6_send::{IP6SendClient, IP6Sender};
 use crate::net::network_capabilities::{NetworkCapability, UdpVisibilityCapability};
 use crate::net::udp::udp::UDPHeader;
-use crate::net::udp::udp_port_table::UdpPortBindingTx;
+use crate::net::udp::udp_port_table::{UdpPortBindingTx, UdpPortManager};
 use core::cell::Cell;
 use kernel::capabilities::UdpDriverCapability;
-use kernel::common::cells::{MapCell, OptionalCell};
+use kernel::common::cells::{MapCell, OptionalCell, TakeCell};
 use kernel::common::leasable_buffer::LeasableBuffer;
 use kernel::common::{List, ListLink, ListNode};
-use kernel::debug;
 use kernel::ReturnCode;
+use kernel::{log_error, log_warn};
 
 pub struct MuxUdpSender<'a, T: IP6Sender<'a>> {
     sender_list: List<'a, UDPSendStruct<'a, T>>,
     ip_sender: &'a dyn IP6Sender<'a>,
+    port_table: OptionalCell<&'a UdpPortManager>,
 }
 
 impl<'a, T: IP6Sender<'a>> MuxUdpSender<'a, T> {
@@ -40,9 +77,14 @@ impl<'a, T: IP6Sender<'a>> MuxUdpSender<'a, T> {
         MuxUdpSender {
             sender_list: List::new(),
             ip_sender: ip6_sender,
+            port_table: OptionalCell::empty(),
         }
     }
 
+    pub fn set_port_table(&self, port_table: &'a UdpPortManager) {
+        self.port_table.set(port_table);
+    }
+
     fn send_to(
         &self,
         dest: IPAddr,
@@ -53,6 +95,7 @@ impl<'a, T: IP6Sender<'a>> MuxUdpSender<'a, T> {
         // Add this sender to the tail of the sender_list
         let list_empty = self.sender_list.head().is_none();
         self.add_client(caller);
+        caller.pending.set(true);
         let mut ret = ReturnCode::SUCCESS;
         // If list empty, initiate send immediately, and return result.
         // Otherwise, packet is queued.
@@ -63,10 +106,18 @@ impl<'a, T: IP6Sender<'a>> MuxUdpSender<'a, T> {
                         .ip_sender
                         .send_to(dest, transport_header, &buf, net_cap);
                     caller.tx_buffer.replace(buf); //Replace buffer as soon as sent.
+                    if ret != ReturnCode::SUCCESS {
+                        // Nothing was sent, so no send_done will remove the caller
+                        self.sender_list.pop_head();
+                        caller.pending.set(false);
+                        self.record_send(caller, ret);
+                    }
                     ret
                 }
                 None => {
-                    debug!("No buffer available to take.");
+                    log_warn!("No buffer available to take.");
+                    self.sender_list.pop_head();
+                    caller.pending.set(false);
                     ReturnCode::FAIL
                 }
             }
@@ -76,9 +127,28 @@ impl<'a, T: IP6Sender<'a>> MuxUdpSender<'a, T> {
         ret
     }
 
+    // Whether `binding` was not forced off its port in the port table
+    fn holds_port(&self, binding: &UdpPortBindingTx) -> bool {
+        self.port_table
+            .map_or(true, |port_table| port_table.holds_port_tx(binding))
+    }
+
     fn add_client(&self, sender: &'a UDPSendStruct<'a, T>) {
         self.sender_list.push_tail(sender);
     }
+
+    fn record_send(&self, sender: &UDPSendStruct<'a, T>, result: ReturnCode) {
+        self.port_table.map(|port_table| {
+            // The sender of the userspace driver has no binding
+            if sender
+                .binding
+                .map(|binding| port_table.record_send(Some(binding), result))
+                .is_none()
+            {
+                port_table.record_send(None, result);
+            }
+        });
+    }
 }
 
 /// This function implements the `IP6SendClient` trait for the `UDPSendStruct`,
@@ -87,20 +157,30 @@ impl<'a, T: IP6Sender<'a>> MuxUdpSender<'a, T> {
 impl<'a, T: IP6Sender<'a>> IP6SendClient for MuxUdpSender<'a, T> {
     fn send_done(&self, result: ReturnCode) {
         let last_sender = self.sender_list.pop_head();
-        let next_sender_option = self.sender_list.head(); // must check here, because udp driver
-                                                          // could queue addl. sends in response to
-                                                          // send_done.
+        // must check here, because udp driver could queue addl. sends in
+        // response to send_done.
+        let mut next_sender_option = self.sender_list.head();
         last_sender.map(|last_sender| {
-            last_sender
-                .client
-                .map(|client| match last_sender.tx_buffer.take() {
-                    Some(buf) => {
-                        client.send_done(result, buf);
-                    }
-                    None => {
-                        debug!("ERROR: Missing buffer in send done.");
-                    }
-                })
+            last_sender.pending.set(false);
+            self.record_send(last_sender, result);
+            let buf = last_sender.tx_buffer.take();
+            // A sender with more packets queued goes to the back of the
+            // line, before its client can send more
+            if last_sender.dequeue() {
+                last_sender.pending.set(true);
+                self.add_client(last_sender);
+                if next_sender_option.is_none() {
+                    next_sender_option = Some(last_sender);
+                }
+            }
+            last_sender.client.map(|client| match buf {
+                Some(buf) => {
+                    client.send_done(result, buf);
+                }
+                None => {
+                    log_error!("Missing buffer in send done.");
+                }
+            })
         });
 
         let success = match next_sender_option {
@@ -118,19 +198,19 @@ impl<'a, T: IP6Sender<'a>> IP6SendClient for MuxUdpSender<'a, T> {
                                 );
                                 next_sender.tx_buffer.replace(buf);
                                 if ret != ReturnCode::SUCCESS {
-                                    debug!("IP send_to failed: {:?}", ret);
+                                    log_warn!("IP send_to failed: {:?}", ret);
                                 }
                                 ret
                             }
                             None => ReturnCode::FAIL,
                         },
                         None => {
-                            debug!("Missing transport header.");
+                            log_error!("Missing transport header.");
                             ReturnCode::FAIL
                         }
                     },
                     None => {
-                        debug!("No buffer available to take.");
+                        log_warn!("No buffer available to take.");
                         ReturnCode::FAIL
                     }
                 }
@@ -138,7 +218,10 @@ impl<'a, T: IP6Sender<'a>> IP6SendClient for MuxUdpSender<'a, T> {
             None => ReturnCode::SUCCESS, //No more packets queued.
         };
         if success != ReturnCode::SUCCESS {
-            debug!("Error in udp_send send_done() callback.");
+            log_error!("Error in udp_send send_done() callback.");
+            // The next packet will not complete either, so its sender is
+            // told now and the packet after it is sent
+            self.send_done(success);
         }
     }
 }
@@ -234,6 +317,14 @@ pub trait UDPSender<'a> {
     fn set_binding(&self, binding: UdpPortBindingTx) -> Option<UdpPortBindingTx>;
 }
 
+/// A packet waiting in the queue of a `UDPSendStruct`.
+pub struct QueuedSend {
+    dest: IPAddr,
+    transport_header: TransportHeader,
+    buf: LeasableBuffer<'static, u8>,
+    net_cap: &'static NetworkCapability,
+}
+
 /// This is a specific instantiation of the `UDPSender` trait. Note
 /// that this struct contains a reference to an `IP6Sender` which it
 /// forwards packets to (and receives callbacks from).
@@ -247,6 +338,10 @@ pub struct UDPSendStruct<'a, T: IP6Sender<'a>> {
     binding: MapCell<UdpPortBindingTx>,
     udp_vis: &'static UdpVisibilityCapability,
     net_cap: OptionalCell<&'static NetworkCapability>,
+    // Whether the sender is in the list of the mux
+    pending: Cell<bool>,
+    // Packets waiting behind the one in `tx_buffer`, oldest first
+    queue: TakeCell<'static, [Option<QueuedSend>]>,
 }
 
 impl<'a, T: IP6Sender<'a>> ListNode<'a, UDPSendStruct<'a, T>> for UDPSendStruct<'a, T> {
@@ -278,7 +373,7 @@ impl<'a, T: IP6Sender<'a>> UDPSender<'a> for UDPSendStruct<'a, T> {
                 {
                     self.binding.replace(binding);
                     Err(buf)
-                } else if binding.get_port() == 0 {
+                } else if binding.get_port() == 0 || !self.udp_mux_sender.holds_port(&binding) {
                     self.binding.replace(binding);
                     Err(buf)
                 } else {
@@ -316,6 +411,14 @@ impl<'a, T: IP6Sender<'a>> UDPSender<'a> for UDPSendStruct<'a, T> {
     ) -> Result<(), LeasableBuffer<'static, u8>> {
         udp_header.set_len((buf.len() + udp_header.get_hdr_size()) as u16);
         let transport_header = TransportHeader::UDP(udp_header);
+        if self.pending.get() {
+            return self.enqueue(QueuedSend {
+                dest: dest,
+                transport_header: transport_header,
+                buf: buf,
+                net_cap: net_cap,
+            });
+        }
         self.tx_buffer.replace(buf);
         self.next_dest.replace(dest);
         self.next_th.replace(transport_header); // th = transport header
@@ -356,6 +459,48 @@ impl<'a, T: IP6Sender<'a>> UDPSendStruct<'a, T> {
             binding: MapCell::empty(),
             udp_vis: udp_vis,
             net_cap: OptionalCell::empty(),
+            pending: Cell::new(false),
+            queue: TakeCell::empty(),
+        }
+    }
+
+    /// Lets the sender queue up to `queue.len()` packets behind the one
+    /// being sent. The slots of `queue` start out `None`.
+    pub fn set_queue(&self, queue: &'static mut [Option<QueuedSend>]) {
+        self.queue.replace(queue);
+    }
+
+    fn enqueue(&self, send: QueuedSend) -> Result<(), LeasableBuffer<'static, u8>> {
+        let mut send = Some(send);
+        self.queue.map(|queue| {
+            if let Some(slot) = queue.iter_mut().find(|slot| slot.is_none()) {
+                *slot = send.take();
+            }
+        });
+        match send {
+            Some(send) => Err(send.buf),
+            None => Ok(()),
+        }
+    }
+
+    /// Moves the oldest queued packet to be sent next, if there is one.
+    fn dequeue(&self) -> bool {
+        let next = self.queue.map_or(None, |queue| {
+            let next = queue.get_mut(0).and_then(|slot| slot.take());
+            if next.is_some() {
+                queue.rotate_left(1);
+            }
+            next
+        });
+        match next {
+            Some(send) => {
+                self.tx_buffer.replace(send.buf);
+                self.next_dest.set(send.dest);
+                self.next_th.replace(send.transport_header);
+                self.net_cap.replace(send.net_cap);
+                true
+            }
+            None => false,
         }
     }
 }
@@ -0,0 +1,422 @@
+//! Provides userspace with an NFC Forum Type 2 tag holding an NDEF message.
+//!
+//! An app builds an NDEF message from records, such as a URL with the
+//! device's ID or the credentials of a WiFi network, and starts the tag;
+//! phones then read the message when tapped against the antenna. The tag
+//! answers READ commands from a memory image laid out as a Type 2 tag:
+//! the ID and lock bytes, the capability container, and the message in an
+//! NDEF TLV. If the app made the tag writable, a reader can write a new
+//! message, which is checked and passed to the app.
+//!
+//! Records can only be added while the tag is stopped. The first app to use
+//! a command other than the driver check owns the driver; other apps get
+//! `ERESERVE`.
+//!
+//! ## Instantiation
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let tag = static_init!(
+//!     capsules::nfc_tag::Type2Tag<'static>,
+//!     capsules::nfc_tag::Type2Tag::new(
+//!         &nrf52::nfct::NFCT,
+//!         [0x5f, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06],
+//!         &mut capsules::nfc_tag::MEMORY,
+//!         &mut capsules::nfc_tag::MESSAGE_BUF,
+//!         &mut capsules::nfc_tag::FRAME_BUF,
+//!         board_kernel.create_grant(&grant_cap)));
+//! kernel::hil::nfc::NfcTag::set_client(&nrf52::nfct::NFCT, tag);
+//! ```
+//!
+//! ## Syscall Interface
+//!
+//! - allow `0`: the URI, the text, the MIME type or the SSID of the record
+//!   added by commands `2` to `5`.
+//! - allow `1`: the MIME data or the WiFi passphrase for commands `4` and
+//!   `5`.
+//! - allow `2`: a message written by a reader is copied here.
+//! - subscribe `0`: a reader event, `fn(event, 0, 0)`, where the event is
+//!   `0` for a field detected, `1` for the tag selected and `2` for the
+//!   field lost.
+//! - subscribe `1`: a reader wrote a message, `fn(len, records, 0)`.
+//! - command `0`: driver check.
+//! - command `1`: clear the message.
+//! - command `2`: add a URI record.
+//! - command `3`: add a text record, in the language whose two letters are
+//!   the low two bytes of `data`, or English if `data` is 0.
+//! - command `4`: add a MIME record.
+//! - command `5`: add a WiFi credential record, for an open network if allow
+//!   `1` is empty.
+//! - command `6`: start the tag, writable by readers if bit 0 of `data` is
+//!   set.
+//! - command `7`: stop the tag.
+
+use core::cell::Cell;
+use core::cmp;
+
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::nfc::{self, TagType};
+use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
+
+use crate::driver;
+use crate::ndef::{self, MessageBuilder};
+pub const DRIVER_NUM: usize = driver::NUM::NfcTag as usize;
+
+const BLOCK_LEN: usize = 4;
+/// Blocks 0 to 2 hold the ID and lock bytes, and block 3 the capability
+/// container.
+const DATA_START: usize = 4 * BLOCK_LEN;
+const DATA_LEN: usize = 256;
+const MEMORY_LEN: usize = DATA_START + DATA_LEN;
+/// The longest message, leaving room for a short TLV header and the
+/// terminator.
+pub const MAX_MESSAGE_LEN: usize = DATA_LEN - 3;
+
+/// A READ answers with four blocks.
+const READ_LEN: usize = 4 * BLOCK_LEN;
+
+const CMD_READ: u8 = 0x30;
+const CMD_WRITE: u8 = 0xa2;
+
+const ACK: u8 = 0xa;
+const NAK: u8 = 0x0;
+
+const TLV_NULL: u8 = 0x00;
+const TLV_NDEF: u8 = 0x03;
+const TLV_TERMINATOR: u8 = 0xfe;
+
+const EVENT_FIELD_DETECTED: usize = 0;
+const EVENT_SELECTED: usize = 1;
+const EVENT_FIELD_LOST: usize = 2;
+
+pub static mut MEMORY: [u8; MEMORY_LEN] = [0; MEMORY_LEN];
+pub static mut MESSAGE_BUF: [u8; MAX_MESSAGE_LEN] = [0; MAX_MESSAGE_LEN];
+pub static mut FRAME_BUF: [u8; READ_LEN + 2] = [0; READ_LEN + 2];
+
+#[derive(Default)]
+pub struct App {
+    event_callback: Option<Callback>,
+    written_callback: Option<Callback>,
+    first: Option<AppSlice<Shared, u8>>,
+    second: Option<AppSlice<Shared, u8>>,
+    written: Option<AppSlice<Shared, u8>>,
+}
+
+pub struct Type2Tag<'a> {
+    tag: &'a dyn nfc::NfcTag<'a>,
+    id: [u8; 7],
+    memory: TakeCell<'static, [u8]>,
+    message: TakeCell<'static, [u8]>,
+    message_len: Cell<usize>,
+    frame: TakeCell<'static, [u8]>,
+    running: Cell<bool>,
+    writable: Cell<bool>,
+    apps: Grant<App>,
+    owner: OptionalCell<AppId>,
+}
+
+impl<'a> Type2Tag<'a> {
+    pub fn new(
+        tag: &'a dyn nfc::NfcTag<'a>,
+        id: [u8; 7],
+        memory: &'static mut [u8],
+        message: &'static mut [u8],
+        frame: &'static mut [u8],
+        apps: Grant<App>,
+    ) -> Type2Tag<'a> {
+        Type2Tag {
+            tag: tag,
+            id: id,
+            memory: TakeCell::new(memory),
+            message: TakeCell::new(message),
+            message_len: Cell::new(0),
+            frame: TakeCell::new(frame),
+            running: Cell::new(false),
+            writable: Cell::new(false),
+            apps: apps,
+            owner: OptionalCell::empty(),
+        }
+    }
+
+    fn add_record<F>(&self, f: F) -> ReturnCode
+    where
+        F: FnOnce(&mut MessageBuilder) -> ReturnCode,
+    {
+        if self.running.get() {
+            return ReturnCode::EBUSY;
+        }
+        self.message.map_or(ReturnCode::ENOMEM, |message| {
+            match MessageBuilder::new(message, self.message_len.get()) {
+                Some(mut builder) => {
+                    let rcode = f(&mut builder);
+                    self.message_len.set(builder.len());
+                    rcode
+                }
+                None => ReturnCode::FAIL,
+            }
+        })
+    }
+
+    /// Lay out the tag's memory with the message and start answering
+    /// readers.
+    fn start(&self, writable: bool) -> ReturnCode {
+        if self.running.get() {
+            return ReturnCode::EALREADY;
+        }
+        let id = self.id;
+        let len = self.message_len.get();
+        self.memory.map(|memory| {
+            for b in memory.iter_mut() {
+                *b = 0;
+            }
+            // The ID, each part followed by its check byte, as NFC-A
+            // cascade levels give it.
+            memory[..3].copy_from_slice(&id[..3]);
+            memory[3] = 0x88 ^ id[0] ^ id[1] ^ id[2];
+            memory[4..8].copy_from_slice(&id[3..]);
+            memory[8] = id[3] ^ id[4] ^ id[5] ^ id[6];
+            // The capability container: version 1.0, the data area size in
+            // units of 8 bytes, and whether readers may write.
+            memory[12] = 0xe1;
+            memory[13] = 0x10;
+            memory[14] = (DATA_LEN / 8) as u8;
+            memory[15] = if writable { 0x00 } else { 0x0f };
+
+            let data = &mut memory[DATA_START..];
+            data[0] = TLV_NDEF;
+            data[1] = len as u8;
+            self.message.map(|message| {
+                data[2..2 + len].copy_from_slice(&message[..len]);
+            });
+            data[2 + len] = TLV_TERMINATOR;
+        });
+        let rcode = self.tag.enable(&self.id, TagType::Type2);
+        if rcode == ReturnCode::SUCCESS {
+            self.running.set(true);
+            self.writable.set(writable);
+        }
+        rcode
+    }
+
+    fn stop(&self) -> ReturnCode {
+        if !self.running.get() {
+            return ReturnCode::EALREADY;
+        }
+        self.running.set(false);
+        self.tag.disable()
+    }
+
+    fn with_owner<F: FnOnce(&mut App)>(&self, f: F) {
+        self.owner.map(|owner| {
+            let _ = self.apps.enter(*owner, |app, _| f(app));
+        });
+    }
+
+    fn event(&self, event: usize) {
+        self.with_owner(|app| {
+            app.event_callback.map(|mut cb| cb.schedule(event, 0, 0));
+        });
+    }
+
+    /// Answer a command from the reader, filling `frame` with a full answer
+    /// and returning its length, or returning `Err(nibble)` for a 4 bit
+    /// answer.
+    fn handle_command(&self, frame: &mut [u8], len: usize) -> Result<usize, u8> {
+        let blocks = MEMORY_LEN / BLOCK_LEN;
+        match frame[0] {
+            CMD_READ if len >= 2 && (frame[1] as usize) < blocks => {
+                let start = frame[1] as usize * BLOCK_LEN;
+                self.memory.map(|memory| {
+                    // Reads past the end wrap around to block 0.
+                    for i in 0..READ_LEN {
+                        frame[i] = memory[(start + i) % MEMORY_LEN];
+                    }
+                });
+                Ok(READ_LEN)
+            }
+            CMD_WRITE
+                if len >= 2 + BLOCK_LEN
+                    && self.writable.get()
+                    && frame[1] as usize >= DATA_START / BLOCK_LEN
+                    && (frame[1] as usize) < blocks =>
+            {
+                let start = frame[1] as usize * BLOCK_LEN;
+                self.memory.map(|memory| {
+                    memory[start..start + BLOCK_LEN].copy_from_slice(&frame[2..2 + BLOCK_LEN]);
+                });
+                self.check_written();
+                Err(ACK)
+            }
+            _ => Err(NAK),
+        }
+    }
+
+    /// Pass the message in the tag's memory to the app if it is complete.
+    /// Readers clear the NDEF TLV's length while writing a message and set
+    /// it last, so a well formed message is a finished one.
+    fn check_written(&self) {
+        self.memory.map(|memory| {
+            let data = &memory[DATA_START..];
+            let mut off = 0;
+            while off < data.len() && data[off] == TLV_NULL {
+                off += 1;
+            }
+            if data.get(off) != Some(&TLV_NDEF) {
+                return;
+            }
+            let (start, len) = match data.get(off + 1) {
+                Some(&0xff) if off + 4 <= data.len() => (
+                    off + 4,
+                    u16::from_be_bytes([data[off + 2], data[off + 3]]) as usize,
+                ),
+                Some(&len) => (off + 2, len as usize),
+                None => return,
+            };
+            if len == 0 || start + len > data.len() {
+                return;
+            }
+            let message = &data[start..start + len];
+            ndef::count_records(message).map(|records| {
+                self.with_owner(|app| {
+                    let copied = app.written.as_mut().map_or(0, |slice| {
+                        let copied = cmp::min(slice.len(), len);
+                        slice.as_mut()[..copied].copy_from_slice(&message[..copied]);
+                        copied
+                    });
+                    app.written_callback
+                        .map(|mut cb| cb.schedule(copied, records, 0));
+                });
+            });
+        });
+    }
+
+    fn receive(&self, frame: &'static mut [u8]) {
+        if let Err((_, frame)) = self.tag.receive(frame) {
+            self.frame.replace(frame);
+        }
+    }
+}
+
+impl nfc::Client for Type2Tag<'_> {
+    fn field_detected(&self) {
+        self.event(EVENT_FIELD_DETECTED);
+    }
+
+    fn field_lost(&self) {
+        self.event(EVENT_FIELD_LOST);
+    }
+
+    fn selected(&self) {
+        self.frame.take().map(|frame| self.receive(frame));
+        self.event(EVENT_SELECTED);
+    }
+
+    fn frame_received(&self, frame: &'static mut [u8], len: usize, result: ReturnCode) {
+        if result != ReturnCode::SUCCESS || len == 0 {
+            if result == ReturnCode::ECANCEL {
+                self.frame.replace(frame);
+            } else {
+                self.receive(frame);
+            }
+            return;
+        }
+        let sent = match self.handle_command(frame, len) {
+            Ok(answer_len) => self.tag.transmit(frame, answer_len),
+            Err(nibble) => {
+                frame[0] = nibble;
+                self.tag.transmit_bits(frame, 4)
+            }
+        };
+        if let Err((_, frame)) = sent {
+            self.receive(frame);
+        }
+    }
+
+    fn frame_transmitted(&self, frame: &'static mut [u8], result: ReturnCode) {
+        if result == ReturnCode::ECANCEL {
+            self.frame.replace(frame);
+        } else {
+            self.receive(frame);
+        }
+    }
+}
+
+impl Driver for Type2Tag<'_> {
+    fn allow(
+        &self,
+        appid: AppId,
+        allow_num: usize,
+        slice: Option<AppSlice<Shared, u8>>,
+    ) -> ReturnCode {
+        self.apps
+            .enter(appid, |app, _| {
+                match allow_num {
+                    0 => app.first = slice,
+                    1 => app.second = slice,
+                    2 => app.written = slice,
+                    _ => return ReturnCode::ENOSUPPORT,
+                }
+                ReturnCode::SUCCESS
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        callback: Option<Callback>,
+        appid: AppId,
+    ) -> ReturnCode {
+        self.apps
+            .enter(appid, |app, _| {
+                match subscribe_num {
+                    0 => app.event_callback = callback,
+                    1 => app.written_callback = callback,
+                    _ => return ReturnCode::ENOSUPPORT,
+                }
+                ReturnCode::SUCCESS
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    fn command(&self, command_num: usize, data: usize, _: usize, appid: AppId) -> ReturnCode {
+        if command_num == 0 {
+            return ReturnCode::SUCCESS;
+        }
+        if self.owner.map_or(false, |owner| *owner != appid) {
+            return ReturnCode::ERESERVE;
+        }
+        self.owner.set(appid);
+
+        self.apps
+            .enter(appid, |app, _| {
+                let first = app.first.as_ref().map_or(&[][..], |slice| slice.as_ref());
+                let second = app.second.as_ref().map_or(&[][..], |slice| slice.as_ref());
+                match command_num {
+                    1 => {
+                        if self.running.get() {
+                            ReturnCode::EBUSY
+                        } else {
+                            self.message_len.set(0);
+                            ReturnCode::SUCCESS
+                        }
+                    }
+                    2 => self.add_record(|builder| builder.uri(first)),
+                    3 => {
+                        let lang = match data {
+                            0 => [b'e', b'n'],
+                            _ => [data as u8, (data >> 8) as u8],
+                        };
+                        self.add_record(|builder| builder.text(&lang, first))
+                    }
+                    4 => self.add_record(|builder| builder.mime(first, second)),
+                    5 => self.add_record(|builder| builder.wifi(first, second)),
+                    6 => self.start(data & 1 != 0),
+                    7 => self.stop(),
+                    _ => ReturnCode::ENOSUPPORT,
+                }
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+}
@@ -5,13 +5,32 @@
 //! --------
 //!
 //! This module provides a simple text-based console to inspect and control
-//! which processes are running. The console has five commands:
+//! which processes are running. The console has twelve commands:
 //!  - 'help' prints the available commands and arguments
 //!  - 'status' prints the current system status
 //!  - 'list' lists the current processes with their IDs and running state
 //!  - 'stop n' stops the process with name n
 //!  - 'start n' starts the stopped process with name n
 //!  - 'fault n' forces the process with name n into a fault state
+//!  - 'power' prints the deepest sleep state the chip may enter and the
+//!    drivers that keep it out of deep sleep
+//!  - 'energy' prints the estimated energy each process has consumed
+//!  - 'dfu' switches the USB device into DFU mode for a firmware update, if
+//!    the board set one with `set_dfu()`
+//!  - 'udp' prints the datagram counters of the UDP stack and of each bound
+//!    port, if the board set the port table with `set_udp_port_table()`
+//!  - 'net' inspects the network stack with its subcommands:
+//!    - 'net ports' lists the UDP ports bound by capsules, with the label
+//!      each capsule gave its socket, and by processes
+//!    - 'net unbind p' unbinds every capsule and process bound to port p
+//!    - 'net addrs' prints the addresses of the interfaces, if the board set
+//!      them with `set_interface_addrs()`
+//!
+//!    The port commands need the port table set with `set_udp_port_table()`.
+//!  - 'log' prints the messages kept in the kernel log, if the board set one
+//!    with `kernel::log::set_log()`; 'log clear' drops them, and 'log level l'
+//!    keeps the messages up to level l (error, warn, info or trace) from then
+//!    on
 //!
 //! ### `list` Command Fields:
 //!
@@ -102,6 +121,7 @@
 //! Total processes: 2
 //! Active processes: 2
 //! Timeslice expirations: 0
+//! Denied syscalls: 0
 //! ```
 //!
 //! and you can control processes with the `start` and `stop` commands:
@@ -110,18 +130,32 @@
 //! stop blink
 //! Process blink stopped
 //! ```
+//!
+//! When a capsule fails to bind a port, `net ports` shows who holds it:
+//!
+//! ```text
+//! net ports
+//!  Port  Owner
+//!  5683  coap
+//! 16123  udp app (process 00 udp_rx)
+//! ```
 
 use core::cell::Cell;
 use core::cmp;
 use core::str;
 use kernel::capabilities::ProcessManagementCapability;
-use kernel::common::cells::TakeCell;
+use kernel::common::cells::{OptionalCell, TakeCell};
 use kernel::debug;
 use kernel::hil::uart;
 use kernel::introspection::KernelInfo;
+use kernel::log::{self, Level};
 use kernel::Kernel;
 use kernel::ReturnCode;
 
+use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::udp::udp_port_table::UdpPortManager;
+use crate::usb::dfu::DfuMode;
+
 // Since writes are character echoes, we do not need more than 4 bytes:
 // the longest write is 3 bytes for a backspace (backspace, space, backspace).
 pub static mut WRITE_BUF: [u8; 4] = [0; 4];
@@ -150,6 +184,15 @@ pub struct ProcessConsole<'a, C: ProcessManagementCapability> {
     execute: Cell<bool>,
     kernel: &'static Kernel,
     capability: C,
+
+    /// Entered by the `dfu` command.
+    dfu: OptionalCell<&'a dyn DfuMode<'a>>,
+
+    /// Read by the `udp` and `net` commands.
+    udp_port_table: OptionalCell<&'a UdpPortManager>,
+
+    /// Printed by the `net addrs` command.
+    interface_addrs: OptionalCell<&'a [IPAddr]>,
 }
 
 impl<'a, C: ProcessManagementCapability> ProcessConsole<'a, C> {
@@ -173,6 +216,100 @@ impl<'a, C: ProcessManagementCapability> ProcessConsole<'a, C> {
             execute: Cell::new(false),
             kernel: kernel,
             capability: capability,
+            dfu: OptionalCell::empty(),
+            udp_port_table: OptionalCell::empty(),
+            interface_addrs: OptionalCell::empty(),
+        }
+    }
+
+    pub fn set_dfu(&self, dfu: &'a dyn DfuMode<'a>) {
+        self.dfu.set(dfu);
+    }
+
+    pub fn set_udp_port_table(&self, port_table: &'a UdpPortManager) {
+        self.udp_port_table.set(port_table);
+    }
+
+    pub fn set_interface_addrs(&self, addrs: &'a [IPAddr]) {
+        self.interface_addrs.set(addrs);
+    }
+
+    fn log_command<'b, I: Iterator<Item = &'b str>>(&self, mut args: I) {
+        let log = match log::get_log() {
+            Some(log) => log,
+            None => {
+                debug!("No kernel log");
+                return;
+            }
+        };
+        match (args.next(), args.next()) {
+            (None, _) | (Some("dump"), _) => log.dump(),
+            (Some("clear"), _) => log.clear(),
+            (Some("level"), None) => debug!("Log level: {}", log.level().name()),
+            (Some("level"), Some(name)) => match Level::from_name(name) {
+                Some(level) => log.set_level(level),
+                None => debug!("Valid levels are: error warn info trace"),
+            },
+            _ => debug!("Valid log commands are: dump, clear, level <level>"),
+        }
+    }
+
+    fn net_command<'b, I: Iterator<Item = &'b str>>(&self, mut args: I) {
+        match (args.next(), args.next()) {
+            (Some("ports"), _) => self.udp_port_table.map_or_else(
+                || debug!("No UDP port table"),
+                |port_table| {
+                    debug!(" Port  Owner");
+                    port_table.each_binding(|port, shared, owner| {
+                        debug!(
+                            "{:5}  {}{}",
+                            port,
+                            owner.unwrap_or("unlabeled capsule"),
+                            if shared { " (shared)" } else { "" }
+                        );
+                    });
+                    port_table.each_user_binding(|port, appid| {
+                        let name = Cell::new("");
+                        self.kernel
+                            .process_each_capability(&self.capability, |proc| {
+                                if proc.appid() == appid {
+                                    name.set(proc.get_process_name());
+                                }
+                            });
+                        debug!("{:5}  udp app (process {:?} {})", port, appid, name.get());
+                    });
+                },
+            ),
+            (Some("unbind"), Some(port)) => match port.parse::<u16>() {
+                Ok(port) => self.udp_port_table.map_or_else(
+                    || debug!("No UDP port table"),
+                    |port_table| {
+                        let removed = port_table.force_unbind(port, &self.capability);
+                        debug!("Removed {} bindings of port {}", removed, port);
+                    },
+                ),
+                Err(_) => debug!("Invalid port: {}", port),
+            },
+            (Some("addrs"), _) => self.interface_addrs.map_or_else(
+                || debug!("No interface addresses"),
+                |addrs| {
+                    for addr in addrs.iter() {
+                        let a = &addr.0;
+                        debug!(
+                            "  {:x}:{:x}:{:x}:{:x}:{:x}:{:x}:{:x}:{:x}",
+                            u16::from_be_bytes([a[0], a[1]]),
+                            u16::from_be_bytes([a[2], a[3]]),
+                            u16::from_be_bytes([a[4], a[5]]),
+                            u16::from_be_bytes([a[6], a[7]]),
+                            u16::from_be_bytes([a[8], a[9]]),
+                            u16::from_be_bytes([a[10], a[11]]),
+                            u16::from_be_bytes([a[12], a[13]]),
+                            u16::from_be_bytes([a[14], a[15]])
+                        );
+                    }
+                },
+            ),
+            _ => debug!("Valid net commands are: ports, unbind <port>, addrs"),
         }
     }
 
@@ -210,7 +347,7 @@ impl<'a, C: ProcessManagementCapability> ProcessConsole<'a, C> {
                         let clean_str = s.trim();
                         if clean_str.starts_with("help") {
                             debug!("Welcome to the process console.");
-                            debug!("Valid commands are: help status list stop start fault");
+                            debug!("Valid commands are: help status list stop start fault power energy dfu udp net log");
                         } else if clean_str.starts_with("start") {
                             let argument = clean_str.split_whitespace().nth(1);
                             argument.map(|name| {
@@ -290,8 +427,82 @@ impl<'a, C: ProcessManagementCapability> ProcessConsole<'a, C> {
                                 "Timeslice expirations: {}",
                                 info.timeslice_expirations(&self.capability)
                             );
+                            debug!(
+                                "Denied syscalls: {}",
+                                info.denied_syscalls(&self.capability)
+                            );
+                        } else if clean_str.starts_with("power") {
+                            match self.kernel.power_manager() {
+                                Some(power_manager) => {
+                                    debug!(
+                                        "Deepest sleep state: {}",
+                                        power_manager.deepest_sleep_state()
+                                    );
+                                    for constraint in power_manager.blocking() {
+                                        debug!(
+                                            "  {} limits sleep to {}",
+                                            constraint.name(),
+                                            constraint.deepest()
+                                        );
+                                    }
+                                }
+                                None => debug!("No power manager"),
+                            }
+                        } else if clean_str.starts_with("energy") {
+                            debug!(" PID    Name                Energy (uJ)");
+                            self.kernel
+                                .process_each_capability(&self.capability, |proc| {
+                                    let info: KernelInfo = KernelInfo::new(self.kernel);
+                                    let appid = proc.appid();
+                                    debug!(
+                                        "  {:?}\t{:<20}{:11}",
+                                        appid,
+                                        proc.get_process_name(),
+                                        info.app_energy_consumed(appid, &self.capability) / 1000
+                                    );
+                                });
+                        } else if clean_str.starts_with("dfu") {
+                            self.dfu.map_or_else(
+                                || debug!("No DFU device"),
+                                |dfu| {
+                                    debug!("Entering DFU mode");
+                                    dfu.enter_dfu_mode();
+                                },
+                            );
+                        } else if clean_str.starts_with("udp") {
+                            self.udp_port_table.map_or_else(
+                                || debug!("No UDP port table"),
+                                |port_table| {
+                                    let totals = port_table.total_stats();
+                                    debug!(
+                                        "Sent: {} Send failures: {} Received: {}",
+                                        totals.sent,
+                                        totals.send_failures,
+                                        totals.received
+                                    );
+                                    debug!(
+                                        "No receiver: {} Checksum failures: {}",
+                                        totals.no_receiver,
+                                        totals.checksum_failures
+                                    );
+                                    debug!(" Port      Sent  Failures  Received");
+                                    port_table.each_binding_stats(|port, stats| {
+                                        debug!(
+                                            " {:5}{:10}{:10}{:10}",
+                                            port,
+                                            stats.sent,
+                                            stats.send_failures,
+                                            stats.received
+                                        );
+                                    });
+                                },
+                            );
+                        } else if clean_str.starts_with("log") {
+                            self.log_command(clean_str.split_whitespace().skip(1));
+                        } else if clean_str.starts_with("net") {
+                            self.net_command(clean_str.split_whitespace().skip(1));
                         } else {
-                            debug!("Valid commands are: help status list stop start fault");
+                            debug!("Valid commands are: help status list stop start fault power energy dfu udp net log");
                         }
                     }
                     Err(_e) => debug!("Invalid command: {:?}", command),
@@ -0,0 +1,105 @@
+//! Lets an app load, unload and restart processes at runtime.
+//!
+//! `ProcessLoaderDriver` gives an app, usually one that receives app updates
+//! and writes them to flash, use of a `kernel::procs::ProcessLoader`. Images
+//! are referred to by their offset in the flash region of the loader, and
+//! processes by their identifier, which is one less than the identifier
+//! apps see in IPC. Boards should only permit this driver for the app that
+//! manages the others, as it can stop any process.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let process_loader_driver = static_init!(
+//!     capsules::process_loader::ProcessLoaderDriver<'static>,
+//!     capsules::process_loader::ProcessLoaderDriver::new(process_loader)
+//! );
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! - command `0`: driver check.
+//! - command `1`: check the image at offset `data`. Returns the length of
+//!   the image, so that the next image can be checked after it.
+//! - command `2`: load the image at offset `data`, replacing any process
+//!   with the same package name. Returns the identifier of the new process.
+//! - command `3`: unload the process with identifier `data`.
+//! - command `4`: restart the process with identifier `data`.
+//!
+//! Images that are padding, disabled or have an invalid header are
+//! `EINVAL`, and images that do not fit in the region are `ESIZE`. A process
+//! cannot unload or restart itself, or load an image with its own name.
+
+use kernel::procs::{ProcessLoadError, ProcessLoader};
+use kernel::{AppId, Driver, ReturnCode};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::ProcessLoader as usize;
+
+/// The return code of a failed load, as given to apps.
+pub fn load_error(error: ProcessLoadError) -> ReturnCode {
+    match error {
+        ProcessLoadError::TbfHeaderParseFailure(_) | ProcessLoadError::NotAnApp => {
+            ReturnCode::EINVAL
+        }
+        ProcessLoadError::NotEnoughFlash => ReturnCode::ESIZE,
+        ProcessLoadError::NotEnoughMemory => ReturnCode::ENOMEM,
+        ProcessLoadError::NoProcessSlot => ReturnCode::EBUSY,
+        _ => ReturnCode::FAIL,
+    }
+}
+
+pub struct ProcessLoaderDriver<'a> {
+    loader: &'a dyn ProcessLoader,
+}
+
+impl<'a> ProcessLoaderDriver<'a> {
+    pub fn new(loader: &'a dyn ProcessLoader) -> ProcessLoaderDriver<'a> {
+        ProcessLoaderDriver { loader: loader }
+    }
+
+    /// Returns the process with `identifier`, if it is not the caller.
+    fn other_app(&self, identifier: usize, appid: AppId) -> Option<AppId> {
+        self.loader
+            .lookup_app(identifier)
+            .filter(|app| *app != appid)
+    }
+}
+
+impl<'a> Driver for ProcessLoaderDriver<'a> {
+    fn command(&self, command_num: usize, data: usize, _: usize, appid: AppId) -> ReturnCode {
+        match command_num {
+            0 => ReturnCode::SUCCESS,
+            1 => match self.loader.image(data) {
+                Ok(image) => ReturnCode::SuccessWithValue { value: image.len },
+                Err(error) => load_error(error),
+            },
+            2 => {
+                let image = match self.loader.image(data) {
+                    Ok(image) => image,
+                    Err(error) => return load_error(error),
+                };
+                // Replacing the caller would free it during its own call
+                if image.name == Some(appid.get_process_name()) {
+                    return ReturnCode::EINVAL;
+                }
+                match self.loader.load(data) {
+                    Ok(app) => ReturnCode::SuccessWithValue { value: app.id() },
+                    Err(error) => load_error(error),
+                }
+            }
+            3 => self
+                .other_app(data, appid)
+                .map_or(ReturnCode::EINVAL, |app| self.loader.unload(app)),
+            4 => self
+                .other_app(data, appid)
+                .map_or(ReturnCode::EINVAL, |app| self.loader.restart(app)),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
@@ -217,6 +217,9 @@ pub struct RF233<'a, S: spi::SpiMasterDevice> {
     spi_rx: TakeCell<'static, [u8]>,
     spi_tx: TakeCell<'static, [u8]>,
     spi_buf: TakeCell<'static, [u8]>,
+    timestamp_source: OptionalCell<&'a dyn radio::TimestampSource>,
+    /// Time of the interrupt signalling the frame being read.
+    rx_timestamp: Cell<Option<u32>>,
 }
 
 fn setting_to_power(setting: u8) -> i8 {
@@ -909,7 +912,13 @@ impl<'a, S: spi::SpiMasterDevice> spi::SpiMasterClient for RF233<'a, S> {
                 self.rx_client.map(|client| {
                     let rbuf = self.rx_buf.take().unwrap();
                     let frame_len = rbuf[1] as usize - radio::MFR_SIZE;
-                    client.receive(rbuf, frame_len, self.crc_valid.get(), ReturnCode::SUCCESS);
+                    client.receive(
+                        rbuf,
+                        frame_len,
+                        self.crc_valid.get(),
+                        self.rx_timestamp.get(),
+                        ReturnCode::SUCCESS,
+                    );
                 });
             }
 
@@ -1019,6 +1028,12 @@ impl<'a, S: spi::SpiMasterDevice> spi::SpiMasterClient for RF233<'a, S> {
 
 impl<S: spi::SpiMasterDevice> gpio::Client for RF233<'_, S> {
     fn fired(&self) {
+        // In RX the interrupt signals the end of a received frame, so this is
+        // as close to its arrival time as the driver can get.
+        if self.state.get() == InternalState::RX {
+            self.rx_timestamp
+                .set(self.timestamp_source.map(|source| source.timestamp()));
+        }
         self.handle_interrupt();
     }
 }
@@ -1063,9 +1078,16 @@ impl<'a, S: spi::SpiMasterDevice> RF233<'a, S> {
             spi_rx: TakeCell::empty(),
             spi_tx: TakeCell::empty(),
             spi_buf: TakeCell::empty(),
+            timestamp_source: OptionalCell::empty(),
+            rx_timestamp: Cell::new(None),
         }
     }
 
+    /// Timestamp received frames with `source`.
+    pub fn set_timestamp_source(&self, source: &'a dyn radio::TimestampSource) {
+        self.timestamp_source.set(source);
+    }
+
     fn handle_interrupt(&self) {
         // In most cases, the first thing the driver does on handling an interrupt is
         // read the IRQ status; this pushes most logic to the SPI handler.
@@ -33,6 +33,17 @@
 //!     capsules::sdcard::SDCardDriver::new(sdcard, &mut capsules::sdcard::KERNEL_BUFFER));
 //! sdcard.set_client(sdcard_driver);
 //! ```
+//!
+//! Other capsules, such as file systems, can use the card through
+//! `hil::block_storage::BlockStorage` instead, once it has been initialized:
+//!
+//! ```rust
+//! # use kernel::hil::block_storage::BlockStorage;
+//!
+//! sdcard.set_client(sdcard_driver);
+//! BlockStorage::set_client(sdcard, fat);
+//! sdcard.initialize();
+//! ```
 
 // Resources for SD Card API:
 //  * elm-chan.org/docs/mmc/mmc_e.html
@@ -44,6 +55,7 @@ use core::cell::Cell;
 use core::cmp;
 use kernel::common::cells::{MapCell, OptionalCell, TakeCell};
 use kernel::hil;
+use kernel::hil::block_storage;
 use kernel::{AppId, AppSlice, Callback, Driver, ReturnCode, Shared};
 
 /// Syscall driver number.
@@ -79,6 +91,10 @@ pub struct SDCard<'a, A: hil::time::Alarm<'a>> {
     client: OptionalCell<&'static dyn SDCardClient>,
     client_buffer: TakeCell<'static, [u8]>,
     client_offset: Cell<usize>,
+
+    block_client: OptionalCell<&'a dyn block_storage::Client>,
+    block_op: Cell<Option<BlockOp>>,
+    block_count: Cell<u32>,
 }
 
 /// SD card command codes
@@ -138,6 +154,10 @@ enum SpiState {
     WriteBlockResponse,
     WriteBlockBusy,
     WaitWriteBlockBusy,
+    WriteBlocksResponse { count: u32 },
+    WriteBlocksBusy { count: u32 },
+    WaitWriteBlocksBusy { count: u32 },
+    WriteBlocksStop,
 }
 
 /// Alarm states
@@ -155,6 +175,14 @@ enum AlarmState {
     WaitForDataBlocks { count: u32 },
 
     WaitForWriteBusy,
+    WaitForWriteBlocksBusy { count: u32 },
+}
+
+/// Block storage operations, whose buffer goes back to the block client
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum BlockOp {
+    Read,
+    Write,
 }
 
 /// Error codes returned if an SD card transaction fails
@@ -181,6 +209,9 @@ enum SDCardType {
 const SUCCESS_STATUS: u8 = 0x00;
 const INITIALIZING_STATUS: u8 = 0x01;
 const DATA_TOKEN: u8 = 0xFE;
+const WRITE_MULTIPLE_TOKEN: u8 = 0xFC;
+const STOP_TRAN_TOKEN: u8 = 0xFD;
+const BLOCK_SIZE: usize = 512;
 
 /// Callback functions from SDCard
 pub trait SDCardClient {
@@ -240,6 +271,9 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
             client: OptionalCell::empty(),
             client_buffer: TakeCell::empty(),
             client_offset: Cell::new(0),
+            block_client: OptionalCell::empty(),
+            block_op: Cell::new(None),
+            block_count: Cell::new(0),
         }
     }
 
@@ -404,6 +438,100 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
         (r1, r2, r3)
     }
 
+    /// fill in a data packet with the next block of the client buffer and
+    /// write it
+    fn write_data_packet(
+        &self,
+        write_buffer: &'static mut [u8],
+        read_buffer: &'static mut [u8],
+        token: u8,
+    ) {
+        let offset = self.client_offset.get();
+        let bytes_written = self.client_buffer.map_or(0, |buffer| {
+            // copy over data from client buffer
+            // Limit to minimum length between write_buffer, buffer, and 512
+            // (block size)
+            for (write_byte, &client_byte) in write_buffer
+                .iter_mut()
+                .skip(1)
+                .zip(buffer.iter().skip(offset))
+                .take(512)
+            {
+                *write_byte = client_byte;
+            }
+
+            // calculate number of bytes written
+            cmp::min(
+                write_buffer.len() - 1,
+                cmp::min(buffer.len().saturating_sub(offset), 512),
+            )
+        });
+        self.client_offset.set(offset + bytes_written);
+
+        // set a known value for remaining bytes
+        for write_byte in write_buffer
+            .iter_mut()
+            .skip(1)
+            .skip(bytes_written)
+            .take(512 - bytes_written)
+        {
+            *write_byte = 0xFF;
+        }
+
+        // set up remainder of data packet
+        write_buffer[0] = token; // Data token
+        write_buffer[513] = 0xFF; // dummy CRC
+        write_buffer[514] = 0xFF; // dummy CRC
+
+        self.write_bytes(write_buffer, read_buffer, 515);
+    }
+
+    /// hand a finished read to whoever started it
+    fn read_complete(&self, buffer: &'static mut [u8], len: usize) {
+        if self.block_op.take().is_some() {
+            self.block_client.map(move |client| {
+                client.read_done(buffer, ReturnCode::SUCCESS);
+            });
+        } else {
+            self.client.map(move |client| {
+                client.read_done(buffer, len);
+            });
+        }
+    }
+
+    /// hand a finished write to whoever started it
+    fn write_complete(&self, buffer: &'static mut [u8]) {
+        if self.block_op.take().is_some() {
+            self.block_client.map(move |client| {
+                client.write_done(buffer, ReturnCode::SUCCESS);
+            });
+        } else {
+            self.client.map(move |client| {
+                client.write_done(buffer);
+            });
+        }
+    }
+
+    /// report a failed transaction. A block storage operation instead gets
+    /// its buffer back with `FAIL`
+    fn report_error(&self, error: ErrorCode) {
+        match self.block_op.take() {
+            Some(op) => {
+                self.client_buffer.take().map(|buffer| {
+                    self.block_client.map(move |client| match op {
+                        BlockOp::Read => client.read_done(buffer, ReturnCode::FAIL),
+                        BlockOp::Write => client.write_done(buffer, ReturnCode::FAIL),
+                    });
+                });
+            }
+            None => {
+                self.client.map(move |client| {
+                    client.error(error as u32);
+                });
+            }
+        }
+    }
+
     /// updates SD card state on SPI transaction returns
     fn process_spi_states(
         &self,
@@ -447,9 +575,7 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
                     self.state.set(SpiState::Idle);
                     self.alarm_state.set(AlarmState::Idle);
                     self.alarm_count.set(0);
-                    self.client.map(move |client| {
-                        client.error(ErrorCode::InitializationFailure as u32);
-                    });
+                    self.report_error(ErrorCode::InitializationFailure);
                 }
             }
 
@@ -517,9 +643,7 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
                     self.state.set(SpiState::Idle);
                     self.alarm_state.set(AlarmState::Idle);
                     self.alarm_count.set(0);
-                    self.client.map(move |client| {
-                        client.error(ErrorCode::InitializationFailure as u32);
-                    });
+                    self.report_error(ErrorCode::InitializationFailure);
                 }
             }
 
@@ -546,9 +670,7 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
                     self.state.set(SpiState::Idle);
                     self.alarm_state.set(AlarmState::Idle);
                     self.alarm_count.set(0);
-                    self.client.map(move |client| {
-                        client.error(ErrorCode::InitializationFailure as u32);
-                    });
+                    self.report_error(ErrorCode::InitializationFailure);
                 }
             }
 
@@ -613,9 +735,7 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
                     self.state.set(SpiState::Idle);
                     self.alarm_state.set(AlarmState::Idle);
                     self.alarm_count.set(0);
-                    self.client.map(move |client| {
-                        client.error(ErrorCode::InitializationFailure as u32);
-                    });
+                    self.report_error(ErrorCode::InitializationFailure);
                 }
             }
 
@@ -651,9 +771,7 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
                     self.state.set(SpiState::Idle);
                     self.alarm_state.set(AlarmState::Idle);
                     self.alarm_count.set(0);
-                    self.client.map(move |client| {
-                        client.error(ErrorCode::InitializationFailure as u32);
-                    });
+                    self.report_error(ErrorCode::InitializationFailure);
                 }
             }
 
@@ -674,9 +792,7 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
                     self.state.set(SpiState::Idle);
                     self.alarm_state.set(AlarmState::Idle);
                     self.alarm_count.set(0);
-                    self.client.map(move |client| {
-                        client.error(ErrorCode::InitializationFailure as u32);
-                    });
+                    self.report_error(ErrorCode::InitializationFailure);
                 }
             }
 
@@ -724,6 +840,8 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
                     // initialization complete
                     self.state.set(SpiState::Idle);
                     self.is_initialized.set(true);
+                    self.block_count
+                        .set((total_size / BLOCK_SIZE as u64) as u32);
 
                     // perform callback
                     self.client.map(move |client| {
@@ -736,9 +854,7 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
                     self.state.set(SpiState::Idle);
                     self.alarm_state.set(AlarmState::Idle);
                     self.alarm_count.set(0);
-                    self.client.map(move |client| {
-                        client.error(ErrorCode::InitializationFailure as u32);
-                    });
+                    self.report_error(ErrorCode::InitializationFailure);
                 }
             }
 
@@ -763,9 +879,7 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
                     self.state.set(SpiState::Idle);
                     self.alarm_state.set(AlarmState::Idle);
                     self.alarm_count.set(0);
-                    self.client.map(move |client| {
-                        client.error(ErrorCode::ReadFailure as u32);
-                    });
+                    self.report_error(ErrorCode::ReadFailure);
                 }
             }
 
@@ -793,9 +907,7 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
                     self.state.set(SpiState::Idle);
                     self.alarm_state.set(AlarmState::Idle);
                     self.alarm_count.set(0);
-                    self.client.map(move |client| {
-                        client.error(ErrorCode::ReadFailure as u32);
-                    });
+                    self.report_error(ErrorCode::ReadFailure);
                 }
             }
 
@@ -819,9 +931,7 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
 
                         // callback
                         let read_len = cmp::min(read_buffer.len(), cmp::min(buffer.len(), 512));
-                        self.client.map(move |client| {
-                            client.read_done(buffer, read_len);
-                        });
+                        self.read_complete(buffer, read_len);
                     });
                 });
             }
@@ -851,9 +961,7 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
                     self.state.set(SpiState::Idle);
                     self.alarm_state.set(AlarmState::Idle);
                     self.alarm_count.set(0);
-                    self.client.map(move |client| {
-                        client.error(ErrorCode::ReadFailure as u32);
-                    });
+                    self.report_error(ErrorCode::ReadFailure);
                 }
             }
 
@@ -902,9 +1010,7 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
 
                     // read finished, perform callback
                     self.client_buffer.take().map(move |buffer| {
-                        self.client.map(move |client| {
-                            client.read_done(buffer, self.client_offset.get());
-                        });
+                        self.read_complete(buffer, self.client_offset.get());
                     });
                 } else {
                     // error, send callback and quit
@@ -913,9 +1019,7 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
                     self.state.set(SpiState::Idle);
                     self.alarm_state.set(AlarmState::Idle);
                     self.alarm_count.set(0);
-                    self.client.map(move |client| {
-                        client.error(ErrorCode::ReadFailure as u32);
-                    });
+                    self.report_error(ErrorCode::ReadFailure);
                 }
             }
 
@@ -925,50 +1029,14 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
 
                 if r1 == SUCCESS_STATUS {
                     if count <= 1 {
-                        let bytes_written = self.client_buffer.map_or(0, |buffer| {
-                            // copy over data from client buffer
-                            // Limit to minimum length between write_buffer,
-                            // buffer, and 512 (block size)
-                            for (write_byte, &client_byte) in
-                                write_buffer.iter_mut().skip(1).zip(buffer.iter()).take(512)
-                            {
-                                *write_byte = client_byte;
-                            }
-
-                            // calculate number of bytes written
-                            cmp::min(write_buffer.len(), cmp::min(buffer.len(), 512))
-                        });
-
-                        // set a known value for remaining bytes
-                        for write_byte in write_buffer
-                            .iter_mut()
-                            .skip(1)
-                            .skip(bytes_written)
-                            .take(512)
-                        {
-                            *write_byte = 0xFF;
-                        }
-
-                        // set up remainder of data packet
-                        write_buffer[0] = DATA_TOKEN; // Data token
-                        write_buffer[513] = 0xFF; // dummy CRC
-                        write_buffer[514] = 0xFF; // dummy CRC
-
                         // write data packet
                         self.state.set(SpiState::WriteBlockResponse);
-                        self.write_bytes(write_buffer, read_buffer, 515);
+                        self.write_data_packet(write_buffer, read_buffer, DATA_TOKEN);
                     } else {
-                        // multi-block SD card writes are unimplemented
-                        // This should have returned an error already, but if
-                        // we got here somehow, return an error and quit now
-                        self.txbuffer.replace(write_buffer);
-                        self.rxbuffer.replace(read_buffer);
-                        self.state.set(SpiState::Idle);
-                        self.alarm_state.set(AlarmState::Idle);
-                        self.alarm_count.set(0);
-                        self.client.map(move |client| {
-                            client.error(ErrorCode::WriteFailure as u32);
-                        });
+                        // write the first of the data packets
+                        self.state
+                            .set(SpiState::WriteBlocksResponse { count: count });
+                        self.write_data_packet(write_buffer, read_buffer, WRITE_MULTIPLE_TOKEN);
                     }
                 } else {
                     // error, send callback and quit
@@ -977,9 +1045,7 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
                     self.state.set(SpiState::Idle);
                     self.alarm_state.set(AlarmState::Idle);
                     self.alarm_count.set(0);
-                    self.client.map(move |client| {
-                        client.error(ErrorCode::WriteFailure as u32);
-                    });
+                    self.report_error(ErrorCode::WriteFailure);
                 }
             }
 
@@ -1001,9 +1067,7 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
                     self.state.set(SpiState::Idle);
                     self.alarm_state.set(AlarmState::Idle);
                     self.alarm_count.set(0);
-                    self.client.map(move |client| {
-                        client.error(ErrorCode::WriteFailure as u32);
-                    });
+                    self.report_error(ErrorCode::WriteFailure);
                 }
             }
 
@@ -1018,9 +1082,7 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
                     self.state.set(SpiState::Idle);
                     self.alarm_count.set(0);
                     self.client_buffer.take().map(move |buffer| {
-                        self.client.map(move |client| {
-                            client.write_done(buffer);
-                        });
+                        self.write_complete(buffer);
                     });
                 } else {
                     // replace buffers
@@ -1034,6 +1096,65 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
                 }
             }
 
+            SpiState::WriteBlocksResponse { count } => {
+                // Get data packet
+                self.state.set(SpiState::WriteBlocksBusy { count: count });
+                self.read_bytes(write_buffer, read_buffer, 1);
+            }
+
+            SpiState::WriteBlocksBusy { count } => {
+                if (read_buffer[0] & 0x1F) == 0x05 {
+                    // check if sd card is busy
+                    self.state
+                        .set(SpiState::WaitWriteBlocksBusy { count: count });
+                    self.read_bytes(write_buffer, read_buffer, 1);
+                } else {
+                    // error, send callback and quit
+                    self.txbuffer.replace(write_buffer);
+                    self.rxbuffer.replace(read_buffer);
+                    self.state.set(SpiState::Idle);
+                    self.alarm_state.set(AlarmState::Idle);
+                    self.alarm_count.set(0);
+                    self.report_error(ErrorCode::WriteFailure);
+                }
+            }
+
+            SpiState::WaitWriteBlocksBusy { count } => {
+                // check if line is still held low (busy state)
+                if read_buffer[0] != 0x00 {
+                    self.alarm_count.set(0);
+                    if count > 1 {
+                        // write the next data packet
+                        self.state
+                            .set(SpiState::WriteBlocksResponse { count: count - 1 });
+                        self.write_data_packet(write_buffer, read_buffer, WRITE_MULTIPLE_TOKEN);
+                    } else {
+                        // all blocks written. Terminate multiple write, the
+                        //  card holds the line busy one byte later
+                        write_buffer[0] = STOP_TRAN_TOKEN;
+                        write_buffer[1] = 0xFF;
+                        self.state.set(SpiState::WriteBlocksStop);
+                        self.write_bytes(write_buffer, read_buffer, 2);
+                    }
+                } else {
+                    // replace buffers
+                    self.txbuffer.replace(write_buffer);
+                    self.rxbuffer.replace(read_buffer);
+
+                    // try again after 1 ms
+                    self.alarm_state
+                        .set(AlarmState::WaitForWriteBlocksBusy { count: count });
+                    let delay = A::ticks_from_ms(1);
+                    self.alarm.set_alarm(self.alarm.now(), delay);
+                }
+            }
+
+            SpiState::WriteBlocksStop => {
+                // wait for the card to finish programming, then done
+                self.state.set(SpiState::WaitWriteBlockBusy);
+                self.read_bytes(write_buffer, read_buffer, 1);
+            }
+
             SpiState::Idle => {
                 // receiving an event from Idle means something was killed
 
@@ -1053,9 +1174,7 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
             self.state.set(SpiState::Idle);
             self.alarm_state.set(AlarmState::Idle);
             self.alarm_count.set(0);
-            self.client.map(move |client| {
-                client.error(ErrorCode::TimeoutFailure as u32);
-            });
+            self.report_error(ErrorCode::TimeoutFailure);
         } else {
             self.alarm_count.set(repeats + 1);
         }
@@ -1171,6 +1290,19 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
                 self.alarm_state.set(AlarmState::Idle);
             }
 
+            AlarmState::WaitForWriteBlocksBusy { count } => {
+                self.txbuffer.take().map(|write_buffer| {
+                    self.rxbuffer.take().map(move |read_buffer| {
+                        // check if sd card is busy
+                        self.state
+                            .set(SpiState::WaitWriteBlocksBusy { count: count });
+                        self.read_bytes(write_buffer, read_buffer, 1);
+                    });
+                });
+
+                self.alarm_state.set(AlarmState::Idle);
+            }
+
             AlarmState::Idle => {
                 // receiving an event from Idle means something was killed
                 // do nothing
@@ -1226,103 +1358,141 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
     }
 
     pub fn read_blocks(&self, buffer: &'static mut [u8], sector: u32, count: u32) -> ReturnCode {
-        // only if initialized and installed
-        if self.is_installed() {
-            if self.is_initialized() {
-                self.txbuffer.take().map_or(ReturnCode::ENOMEM, |txbuffer| {
-                    self.rxbuffer
-                        .take()
-                        .map_or(ReturnCode::ENOMEM, move |rxbuffer| {
-                            // save the user buffer for later
-                            self.client_buffer.replace(buffer);
-                            self.client_offset.set(0);
-
-                            // convert block address to byte address for non-block
-                            //  access cards
-                            let mut address = sector;
-                            if self.card_type.get() != SDCardType::SDv2BlockAddressable {
-                                address *= 512;
-                            }
+        match self.start_transfer(buffer, sector, count, false) {
+            Ok(()) => ReturnCode::SUCCESS,
+            Err((rcode, _)) => rcode,
+        }
+    }
 
-                            self.state.set(SpiState::StartReadBlocks { count: count });
-                            if count == 1 {
-                                self.send_command(
-                                    SDCmd::CMD17_ReadSingle,
-                                    address,
-                                    txbuffer,
-                                    rxbuffer,
-                                    10,
-                                );
-                            } else {
-                                self.send_command(
-                                    SDCmd::CMD18_ReadMultiple,
-                                    address,
-                                    txbuffer,
-                                    rxbuffer,
-                                    10,
-                                );
-                            }
+    pub fn write_blocks(&self, buffer: &'static mut [u8], sector: u32, count: u32) -> ReturnCode {
+        match self.start_transfer(buffer, sector, count, true) {
+            Ok(()) => ReturnCode::SUCCESS,
+            Err((rcode, _)) => rcode,
+        }
+    }
 
-                            // command started successfully
-                            ReturnCode::SUCCESS
-                        })
-                })
-            } else {
-                // sd card not initialized
-                ReturnCode::ERESERVE
-            }
-        } else {
+    /// start reading or writing `count` blocks at `sector`, returning the
+    /// buffer if that is not possible
+    fn start_transfer(
+        &self,
+        buffer: &'static mut [u8],
+        sector: u32,
+        count: u32,
+        write: bool,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        // only if initialized and installed
+        if !self.is_installed() {
             // sd card not installed
-            ReturnCode::EUNINSTALLED
+            return Err((ReturnCode::EUNINSTALLED, buffer));
         }
+        if !self.is_initialized() {
+            // sd card not initialized
+            return Err((ReturnCode::ERESERVE, buffer));
+        }
+        if self.txbuffer.is_none() || self.rxbuffer.is_none() {
+            return Err((ReturnCode::ENOMEM, buffer));
+        }
+
+        self.txbuffer.take().map(|txbuffer| {
+            self.rxbuffer.take().map(move |rxbuffer| {
+                // save the user buffer for later
+                self.client_buffer.replace(buffer);
+                self.client_offset.set(0);
+
+                // convert block address to byte address for non-block
+                //  access cards
+                let mut address = sector;
+                if self.card_type.get() != SDCardType::SDv2BlockAddressable {
+                    address *= 512;
+                }
+
+                let cmd = if write {
+                    self.state.set(SpiState::StartWriteBlocks { count: count });
+                    if count == 1 {
+                        SDCmd::CMD24_WriteSingle
+                    } else {
+                        SDCmd::CMD25_WriteMultiple
+                    }
+                } else {
+                    self.state.set(SpiState::StartReadBlocks { count: count });
+                    if count == 1 {
+                        SDCmd::CMD17_ReadSingle
+                    } else {
+                        SDCmd::CMD18_ReadMultiple
+                    }
+                };
+                self.send_command(cmd, address, txbuffer, rxbuffer, 10);
+            });
+        });
+
+        // command started successfully
+        Ok(())
     }
 
-    pub fn write_blocks(&self, buffer: &'static mut [u8], sector: u32, count: u32) -> ReturnCode {
-        // only if initialized and installed
-        if self.is_installed() {
-            if self.is_initialized() {
-                self.txbuffer.take().map_or(ReturnCode::ENOMEM, |txbuffer| {
-                    self.rxbuffer
-                        .take()
-                        .map_or(ReturnCode::ENOMEM, move |rxbuffer| {
-                            // save the user buffer for later
-                            self.client_buffer.replace(buffer);
-                            self.client_offset.set(0);
-
-                            // convert block address to byte address for non-block
-                            //  access cards
-                            let mut address = sector;
-                            if self.card_type.get() != SDCardType::SDv2BlockAddressable {
-                                address *= 512;
-                            }
+    /// checks and starts a block storage operation
+    fn start_block_op(
+        &self,
+        op: BlockOp,
+        buffer: &'static mut [u8],
+        block: u32,
+        count: u32,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        if !self.is_installed() || !self.is_initialized() {
+            return Err((ReturnCode::EOFF, buffer));
+        }
+        if self.state.get() != SpiState::Idle || self.alarm_state.get() != AlarmState::Idle {
+            return Err((ReturnCode::EBUSY, buffer));
+        }
+        let end = block.checked_add(count).unwrap_or(u32::MAX);
+        if count == 0 || end > self.block_count.get() {
+            return Err((ReturnCode::EINVAL, buffer));
+        }
+        if buffer.len() < count as usize * BLOCK_SIZE {
+            return Err((ReturnCode::ESIZE, buffer));
+        }
 
-                            self.state.set(SpiState::StartWriteBlocks { count: count });
-                            if count == 1 {
-                                self.send_command(
-                                    SDCmd::CMD24_WriteSingle,
-                                    address,
-                                    txbuffer,
-                                    rxbuffer,
-                                    10,
-                                );
-
-                                // command started successfully
-                                ReturnCode::SUCCESS
-                            } else {
-                                // can't write multiple blocks yet
-                                ReturnCode::ENOSUPPORT
-                            }
-                        })
-                })
-            } else {
-                // sd card not initialized
-                ReturnCode::ERESERVE
-            }
+        self.start_transfer(buffer, block, count, op == BlockOp::Write)
+            .map_err(|(_, buffer)| (ReturnCode::EBUSY, buffer))?;
+        self.block_op.set(Some(op));
+        Ok(())
+    }
+}
+
+/// Block storage access for other capsules, such as file systems
+impl<'a, A: hil::time::Alarm<'a>> block_storage::BlockStorage<'a> for SDCard<'a, A> {
+    fn set_client(&self, client: &'a dyn block_storage::Client) {
+        self.block_client.set(client);
+    }
+
+    fn block_size(&self) -> usize {
+        BLOCK_SIZE
+    }
+
+    fn block_count(&self) -> u32 {
+        if self.is_initialized() {
+            self.block_count.get()
         } else {
-            // sd card not installed
-            ReturnCode::EUNINSTALLED
+            0
         }
     }
+
+    fn read_blocks(
+        &self,
+        buf: &'static mut [u8],
+        block: u32,
+        count: u32,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        self.start_block_op(BlockOp::Read, buf, block, count)
+    }
+
+    fn write_blocks(
+        &self,
+        buf: &'static mut [u8],
+        block: u32,
+        count: u32,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        self.start_block_op(BlockOp::Write, buf, block, count)
+    }
 }
 
 /// Handle callbacks from the SPI peripheral
@@ -1356,9 +1526,7 @@ impl<'a, A: hil::time::Alarm<'a>> hil::gpio::Client for SDCard<'a, A> {
             //  send an error callback
             self.state.set(SpiState::Idle);
             self.alarm_state.set(AlarmState::Idle);
-            self.client.map(move |client| {
-                client.error(ErrorCode::CardStateChanged as u32);
-            });
+            self.report_error(ErrorCode::CardStateChanged);
         }
 
         // either the card is new or gone, in either case it isn't initialized
@@ -0,0 +1,185 @@
+//! Loads processes only from signed images.
+//!
+//! `SignedProcessLoader` checks the signature of an image in the flash
+//! region of a `kernel::procs::ProcessLoader` with a
+//! `hil::public_key_crypto::SignatureVerify`, and loads the image only if
+//! the signature is valid. Boards that require signed apps put them in the
+//! region of a `DynamicProcessLoader` rather than loading them with
+//! `load_processes()`, call `load_all()` once the kernel is set up, and do
+//! not give the loader itself to capsules that load images without checking
+//! them, such as `ProcessLoaderDriver`.
+//!
+//! The signature of a signed image is its last `signature_len()` bytes,
+//! inside the TBF entry: the signing tool appends it to the image, adds its
+//! length to the total size in the TBF header, and signs the SHA-256 hash of
+//! the entry up to the signature. The region must not be written to while an
+//! image is being loaded.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let signed_loader = static_init!(
+//!     capsules::signed_process_loader::SignedProcessLoader<'static>,
+//!     capsules::signed_process_loader::SignedProcessLoader::new(
+//!         process_loader,
+//!         ecdsa,
+//!         &mut APP_HASH_BUF,
+//!         &mut APP_SIGNATURE_BUF,
+//!     )
+//! );
+//! ecdsa.set_client(signed_loader);
+//! signed_loader.load_all();
+//! ```
+
+use crate::net::dtls::sha256::{Sha256, SHA256_LEN};
+use crate::process_loader::load_error;
+use core::cell::Cell;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::public_key_crypto::{ClientVerify, SignatureVerify};
+use kernel::procs::ProcessLoader;
+use kernel::{log_warn, AppId, ReturnCode};
+
+pub trait SignedProcessLoaderClient {
+    /// The image at `offset` was loaded, or `result` is `EINVAL` if its
+    /// signature is invalid.
+    fn load_done(&self, offset: usize, result: Result<AppId, ReturnCode>);
+}
+
+pub struct SignedProcessLoader<'a> {
+    loader: &'a dyn ProcessLoader,
+    verifier: &'a dyn SignatureVerify<'a>,
+    client: OptionalCell<&'a dyn SignedProcessLoaderClient>,
+    hash: TakeCell<'static, [u8]>,
+    signature: TakeCell<'static, [u8]>,
+    /// The offset of the image being verified.
+    offset: OptionalCell<usize>,
+    /// Whether to go on to the next image once this one is loaded.
+    loading_all: Cell<bool>,
+}
+
+impl<'a> SignedProcessLoader<'a> {
+    /// `hash` and `signature` hold the hash of an image and its signature
+    /// while it is verified.
+    pub fn new(
+        loader: &'a dyn ProcessLoader,
+        verifier: &'a dyn SignatureVerify<'a>,
+        hash: &'static mut [u8],
+        signature: &'static mut [u8],
+    ) -> SignedProcessLoader<'a> {
+        SignedProcessLoader {
+            loader: loader,
+            verifier: verifier,
+            client: OptionalCell::empty(),
+            hash: TakeCell::new(hash),
+            signature: TakeCell::new(signature),
+            offset: OptionalCell::empty(),
+            loading_all: Cell::new(false),
+        }
+    }
+
+    pub fn set_client(&self, client: &'a dyn SignedProcessLoaderClient) {
+        self.client.set(client);
+    }
+
+    /// Loads the image at `offset` if its signature is valid. Errors in its
+    /// header are returned at once, as from `ProcessLoaderDriver`.
+    pub fn load(&self, offset: usize) -> ReturnCode {
+        if self.offset.is_some() {
+            return ReturnCode::EBUSY;
+        }
+        self.loading_all.set(false);
+        self.verify(offset)
+    }
+
+    /// Loads each image in the region with a valid signature, from its
+    /// start up to the first offset without a valid TBF header.
+    pub fn load_all(&self) -> ReturnCode {
+        if self.offset.is_some() {
+            return ReturnCode::EBUSY;
+        }
+        self.loading_all.set(true);
+        self.verify(0)
+    }
+
+    fn verify(&self, offset: usize) -> ReturnCode {
+        let image = match self.loader.image(offset) {
+            Ok(image) => image,
+            Err(error) => return load_error(error),
+        };
+        let signature_len = self.verifier.signature_len();
+        if self.verifier.hash_len() != SHA256_LEN {
+            return ReturnCode::ENOSUPPORT;
+        }
+        if image.flash.len() <= signature_len {
+            return ReturnCode::EINVAL;
+        }
+        let (signed, signature) = image.flash.split_at(image.flash.len() - signature_len);
+
+        let (hash_buf, signature_buf) = match (self.hash.take(), self.signature.take()) {
+            (Some(hash_buf), Some(signature_buf))
+                if hash_buf.len() >= SHA256_LEN && signature_buf.len() >= signature_len =>
+            {
+                (hash_buf, signature_buf)
+            }
+            (hash_buf, signature_buf) => {
+                hash_buf.map(|buf| self.hash.replace(buf));
+                signature_buf.map(|buf| self.signature.replace(buf));
+                return ReturnCode::ESIZE;
+            }
+        };
+        let mut sha = Sha256::new();
+        sha.update(signed);
+        hash_buf[..SHA256_LEN].copy_from_slice(&sha.finish());
+        signature_buf[..signature_len].copy_from_slice(signature);
+
+        match self.verifier.verify(hash_buf, signature_buf) {
+            Ok(()) => {
+                self.offset.set(offset);
+                ReturnCode::SUCCESS
+            }
+            Err((rcode, hash_buf, signature_buf)) => {
+                self.hash.replace(hash_buf);
+                self.signature.replace(signature_buf);
+                rcode
+            }
+        }
+    }
+}
+
+impl<'a> ClientVerify for SignedProcessLoader<'a> {
+    fn verification_done(
+        &self,
+        result: Result<bool, ReturnCode>,
+        hash: &'static mut [u8],
+        signature: &'static mut [u8],
+    ) {
+        self.hash.replace(hash);
+        self.signature.replace(signature);
+        let offset = match self.offset.take() {
+            Some(offset) => offset,
+            None => return,
+        };
+        // The length is read before loading, which replaces older versions
+        let next = self.loader.image(offset).map(|image| offset + image.len);
+
+        let result = match result {
+            Ok(true) => self.loader.load(offset).map_err(load_error),
+            Ok(false) => {
+                log_warn!("Process image at {:#x} has an invalid signature", offset);
+                Err(ReturnCode::EINVAL)
+            }
+            Err(rcode) => Err(rcode),
+        };
+        self.client.map(|client| client.load_done(offset, result));
+
+        if self.loading_all.get() {
+            let rcode = next.map_or(ReturnCode::EINVAL, |next| self.verify(next));
+            if rcode != ReturnCode::SUCCESS {
+                self.loading_all.set(false);
+            }
+        }
+    }
+}
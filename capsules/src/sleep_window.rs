@@ -0,0 +1,229 @@
+//! Lets apps declare that they need nothing from the kernel for a while.
+//!
+//! An app that samples a sensor every few seconds spends almost all its time
+//! waiting, yet callbacks from timers or other drivers can still wake the
+//! chip. With this driver the app opens a sleep window of N milliseconds:
+//! until the window ends, every callback for the app is held in its queue
+//! rather than delivered, and the app does not count as work for the kernel.
+//! If no other process or driver needs the CPU, the kernel can then stay in
+//! the deepest sleep state the power manager allows for the whole window.
+//!
+//! When a window ends the app's held callbacks are delivered together,
+//! followed by the window-ended callback. Windows of other apps that end
+//! shortly after are closed at the same time, so that apps with similar
+//! periods wake the chip once rather than once each. Callbacks beyond the
+//! length of the app's callback queue are dropped while the window is open.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let sleep_window_alarm = static_init!(
+//!     VirtualMuxAlarm<'static, sam4l::ast::Ast>,
+//!     VirtualMuxAlarm::new(mux_alarm)
+//! );
+//! let sleep_window = static_init!(
+//!     capsules::sleep_window::SleepWindow<'static, VirtualMuxAlarm<'static, sam4l::ast::Ast>, Capability>,
+//!     capsules::sleep_window::SleepWindow::new(
+//!         sleep_window_alarm,
+//!         board_kernel,
+//!         Capability,
+//!         board_kernel.create_grant(&grant_cap)
+//!     )
+//! );
+//! sleep_window_alarm.set_alarm_client(sleep_window);
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! - subscribe `0`: window ended, `fn(0, 0, 0)`.
+//! - command `0`: driver check.
+//! - command `1`: open a window of `data` milliseconds. Opening a window while
+//!   one is open replaces it.
+//! - command `2`: end the window now.
+
+use kernel::capabilities::ProcessManagementCapability;
+use kernel::hil::time::{self, Alarm, Ticks, Ticks32};
+use kernel::{AppId, Callback, Driver, Grant, Kernel, ReturnCode};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::SleepWindow as usize;
+
+/// Windows that end within this long of the one being closed are closed with
+/// it.
+const BATCH_MS: u32 = 50;
+
+#[derive(Copy, Clone)]
+struct Window {
+    reference: Ticks32,
+    dt: Ticks32,
+}
+
+impl Window {
+    /// Ticks from `now` until the window ends, or zero if it has.
+    fn remaining(&self, now: Ticks32) -> Ticks32 {
+        let end = self.reference.wrapping_add(self.dt);
+        if now.within_range(self.reference, end) {
+            end.wrapping_sub(now)
+        } else {
+            Ticks32::from(0)
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct App {
+    callback: Option<Callback>,
+    window: Option<Window>,
+}
+
+pub struct SleepWindow<'a, A: Alarm<'a>, C: ProcessManagementCapability> {
+    alarm: &'a A,
+    kernel: &'static Kernel,
+    capability: C,
+    apps: Grant<App>,
+}
+
+impl<'a, A: Alarm<'a>, C: ProcessManagementCapability> SleepWindow<'a, A, C> {
+    pub fn new(
+        alarm: &'a A,
+        kernel: &'static Kernel,
+        capability: C,
+        grant: Grant<App>,
+    ) -> SleepWindow<'a, A, C> {
+        SleepWindow {
+            alarm: alarm,
+            kernel: kernel,
+            capability: capability,
+            apps: grant,
+        }
+    }
+
+    fn now(&self) -> Ticks32 {
+        Ticks32::from(self.alarm.now().into_u32())
+    }
+
+    fn set_tasks_held(&self, appid: AppId, held: bool) {
+        self.kernel
+            .process_each_capability(&self.capability, |process| {
+                if process.appid() == appid {
+                    if held {
+                        process.hold_tasks();
+                    } else {
+                        process.release_tasks();
+                    }
+                }
+            });
+    }
+
+    fn open(&self, appid: AppId, ms: usize) -> ReturnCode {
+        let dt = A::ticks_from_ms(ms as u32).into_u32();
+        let rcode = self
+            .apps
+            .enter(appid, |app, _| {
+                app.window = Some(Window {
+                    reference: self.now(),
+                    dt: Ticks32::from(dt),
+                });
+                ReturnCode::SUCCESS
+            })
+            .unwrap_or_else(|err| err.into());
+        if rcode == ReturnCode::SUCCESS {
+            self.set_tasks_held(appid, true);
+            self.rearm();
+        }
+        rcode
+    }
+
+    fn close(&self, appid: AppId) -> ReturnCode {
+        self.apps
+            .enter(appid, |app, _| {
+                if app.window.is_some() {
+                    self.end(app, appid);
+                }
+            })
+            .unwrap_or(());
+        self.rearm();
+        ReturnCode::SUCCESS
+    }
+
+    /// Close `app`'s window: deliver its held callbacks, then tell it the
+    /// window ended.
+    fn end(&self, app: &mut App, appid: AppId) {
+        app.window = None;
+        self.set_tasks_held(appid, false);
+        app.callback.map(|mut cb| cb.schedule(0, 0, 0));
+    }
+
+    /// Set the alarm for the window that ends first.
+    fn rearm(&self) {
+        let now = self.now();
+        let mut earliest: Option<Ticks32> = None;
+        for cntr in self.apps.iter() {
+            if let Some(window) = cntr.enter(|app, _| app.window) {
+                let remaining = window.remaining(now);
+                if earliest.map_or(true, |earliest| remaining < earliest) {
+                    earliest = Some(remaining);
+                }
+            }
+        }
+        match earliest {
+            Some(remaining) => self
+                .alarm
+                .set_alarm(self.alarm.now(), A::Ticks::from(remaining.into_u32())),
+            None => {
+                self.alarm.disarm();
+            }
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>, C: ProcessManagementCapability> time::AlarmClient for SleepWindow<'a, A, C> {
+    fn alarm(&self) {
+        let now = self.now();
+        let batch = Ticks32::from(A::ticks_from_ms(BATCH_MS).into_u32());
+        for cntr in self.apps.iter() {
+            cntr.enter(|app, _| match app.window {
+                Some(window) if window.remaining(now) <= batch => {
+                    let appid = app.appid();
+                    self.end(app, appid);
+                }
+                _ => {}
+            });
+        }
+        self.rearm();
+    }
+}
+
+impl<'a, A: Alarm<'a>, C: ProcessManagementCapability> Driver for SleepWindow<'a, A, C> {
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        callback: Option<Callback>,
+        app_id: AppId,
+    ) -> ReturnCode {
+        match subscribe_num {
+            0 => self
+                .apps
+                .enter(app_id, |app, _| {
+                    app.callback = callback;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    fn command(&self, command_num: usize, data: usize, _: usize, appid: AppId) -> ReturnCode {
+        match command_num {
+            0 => ReturnCode::SUCCESS,
+            1 => self.open(appid, data),
+            2 => self.close(appid),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
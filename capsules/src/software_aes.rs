@@ -0,0 +1,245 @@
+//! AES-128 in software, for chips without an AES engine.
+//!
+//! `SoftwareAes128` implements the `symmetric_encryption` HIL like an AES
+//! peripheral would, so the capsules built on it, such as `AesAead` and
+//! `AES128CCM`, also run on chips without one. Each `crypt()` is computed at
+//! once, and the client is called back from a deferred call.
+//!
+//! Only the AES encryption function is implemented, with
+//! `ble::crypto::aes128`. That is all CTR mode in both directions, and CBC
+//! and ECB encryption, need; CBC and ECB decryption return `ENOSUPPORT`.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let aes = static_init!(
+//!     capsules::software_aes::SoftwareAes128<'static>,
+//!     capsules::software_aes::SoftwareAes128::new(dynamic_deferred_caller)
+//! );
+//! aes.initialize_callback_handle(
+//!     dynamic_deferred_caller
+//!         .register(aes)
+//!         .expect("no deferred call slot available for AES"),
+//! );
+//! ```
+
+use crate::ble::crypto;
+use core::cell::Cell;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::dynamic_deferred_call::{
+    DeferredCallHandle, DynamicDeferredCall, DynamicDeferredCallClient,
+};
+use kernel::hil::symmetric_encryption;
+use kernel::hil::symmetric_encryption::{
+    AES128Ctr, AES128, AES128CBC, AES128ECB, AES128_BLOCK_SIZE, AES128_KEY_SIZE,
+};
+use kernel::ReturnCode;
+
+type Block = [u8; AES128_BLOCK_SIZE];
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum Mode {
+    Ctr,
+    Cbc,
+    Ecb,
+}
+
+pub struct SoftwareAes128<'a> {
+    client: OptionalCell<&'a dyn symmetric_encryption::Client<'a>>,
+    key: Cell<[u8; AES128_KEY_SIZE]>,
+    iv: Cell<Block>,
+    /// The counter of CTR mode, or the last ciphertext block of CBC mode.
+    chain: Cell<Block>,
+    new_message: Cell<bool>,
+    mode: Cell<Mode>,
+    encrypting: Cell<bool>,
+    /// The buffers of the `crypt()` whose callback is pending.
+    source: TakeCell<'a, [u8]>,
+    dest: TakeCell<'a, [u8]>,
+    busy: Cell<bool>,
+    deferred_caller: &'a DynamicDeferredCall,
+    handle: OptionalCell<DeferredCallHandle>,
+}
+
+impl<'a> SoftwareAes128<'a> {
+    pub fn new(deferred_caller: &'a DynamicDeferredCall) -> SoftwareAes128<'a> {
+        SoftwareAes128 {
+            client: OptionalCell::empty(),
+            key: Cell::new(Default::default()),
+            iv: Cell::new(Default::default()),
+            chain: Cell::new(Default::default()),
+            new_message: Cell::new(true),
+            mode: Cell::new(Mode::Ctr),
+            encrypting: Cell::new(true),
+            source: TakeCell::empty(),
+            dest: TakeCell::empty(),
+            busy: Cell::new(false),
+            deferred_caller: deferred_caller,
+            handle: OptionalCell::empty(),
+        }
+    }
+
+    pub fn initialize_callback_handle(&self, handle: DeferredCallHandle) {
+        self.handle.replace(handle);
+    }
+
+    /// Returns the output for the next input block, and updates the chain.
+    fn crypt_block(&self, input: &Block) -> Block {
+        let key = self.key.get();
+        let chain = self.chain.get();
+        match self.mode.get() {
+            Mode::Ctr => {
+                let mut output = crypto::aes128(&key, &chain);
+                for (output, input) in output.iter_mut().zip(input.iter()) {
+                    *output ^= *input;
+                }
+                let mut counter = chain;
+                for byte in counter.iter_mut().rev() {
+                    *byte = byte.wrapping_add(1);
+                    if *byte != 0 {
+                        break;
+                    }
+                }
+                self.chain.set(counter);
+                output
+            }
+            Mode::Cbc => {
+                let mut block = chain;
+                for (block, input) in block.iter_mut().zip(input.iter()) {
+                    *block ^= *input;
+                }
+                let output = crypto::aes128(&key, &block);
+                self.chain.set(output);
+                output
+            }
+            Mode::Ecb => crypto::aes128(&key, input),
+        }
+    }
+}
+
+impl<'a> AES128<'a> for SoftwareAes128<'a> {
+    fn enable(&self) {}
+
+    fn disable(&self) {}
+
+    fn set_client(&'a self, client: &'a dyn symmetric_encryption::Client<'a>) {
+        self.client.set(client);
+    }
+
+    fn set_key(&self, key: &[u8]) -> ReturnCode {
+        if key.len() != AES128_KEY_SIZE {
+            return ReturnCode::EINVAL;
+        }
+        let mut new_key = [0; AES128_KEY_SIZE];
+        new_key.copy_from_slice(key);
+        self.key.set(new_key);
+        ReturnCode::SUCCESS
+    }
+
+    fn set_iv(&self, iv: &[u8]) -> ReturnCode {
+        if iv.len() != AES128_BLOCK_SIZE {
+            return ReturnCode::EINVAL;
+        }
+        let mut new_iv = [0; AES128_BLOCK_SIZE];
+        new_iv.copy_from_slice(iv);
+        self.iv.set(new_iv);
+        ReturnCode::SUCCESS
+    }
+
+    fn start_message(&self) {
+        if !self.busy.get() {
+            self.new_message.set(true);
+        }
+    }
+
+    fn crypt(
+        &'a self,
+        source: Option<&'a mut [u8]>,
+        dest: &'a mut [u8],
+        start_index: usize,
+        stop_index: usize,
+    ) -> Option<(ReturnCode, Option<&'a mut [u8]>, &'a mut [u8])> {
+        if self.busy.get() {
+            return Some((ReturnCode::EBUSY, source, dest));
+        }
+        if start_index > stop_index
+            || stop_index > dest.len()
+            || (stop_index - start_index) % AES128_BLOCK_SIZE != 0
+            || source
+                .as_ref()
+                .map_or(false, |source| source.len() != stop_index - start_index)
+        {
+            return Some((ReturnCode::EINVAL, source, dest));
+        }
+        if !self.encrypting.get() && self.mode.get() != Mode::Ctr {
+            return Some((ReturnCode::ENOSUPPORT, source, dest));
+        }
+        let handle = match self.handle.map(|handle| *handle) {
+            Some(handle) => handle,
+            None => return Some((ReturnCode::FAIL, source, dest)),
+        };
+
+        if self.new_message.get() {
+            self.chain.set(self.iv.get());
+            self.new_message.set(false);
+        }
+        for offset in (0..stop_index - start_index).step_by(AES128_BLOCK_SIZE) {
+            let mut input = [0; AES128_BLOCK_SIZE];
+            match source {
+                Some(ref source) => {
+                    input.copy_from_slice(&source[offset..offset + AES128_BLOCK_SIZE])
+                }
+                None => input.copy_from_slice(
+                    &dest[start_index + offset..start_index + offset + AES128_BLOCK_SIZE],
+                ),
+            }
+            let output = self.crypt_block(&input);
+            dest[start_index + offset..start_index + offset + AES128_BLOCK_SIZE]
+                .copy_from_slice(&output);
+        }
+
+        source.map(|source| self.source.replace(source));
+        self.dest.replace(dest);
+        self.busy.set(true);
+        self.deferred_caller.set(handle);
+        None
+    }
+}
+
+impl AES128Ctr for SoftwareAes128<'_> {
+    fn set_mode_aes128ctr(&self, encrypting: bool) {
+        self.mode.set(Mode::Ctr);
+        self.encrypting.set(encrypting);
+    }
+}
+
+impl AES128CBC for SoftwareAes128<'_> {
+    fn set_mode_aes128cbc(&self, encrypting: bool) {
+        self.mode.set(Mode::Cbc);
+        self.encrypting.set(encrypting);
+    }
+}
+
+impl AES128ECB for SoftwareAes128<'_> {
+    fn set_mode_aes128ecb(&self, encrypting: bool) {
+        self.mode.set(Mode::Ecb);
+        self.encrypting.set(encrypting);
+    }
+}
+
+impl<'a> DynamicDeferredCallClient for SoftwareAes128<'a> {
+    fn call(&self, _handle: DeferredCallHandle) {
+        if !self.busy.get() {
+            return;
+        }
+        self.busy.set(false);
+        let source = self.source.take();
+        if let Some(dest) = self.dest.take() {
+            self.client
+                .map(move |client| client.crypt_done(source, dest));
+        }
+    }
+}
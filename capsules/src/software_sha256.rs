@@ -0,0 +1,383 @@
+//! SHA-256 and HMAC-SHA256 in software, for chips without a digest engine.
+//!
+//! `SoftwareSha256` implements `hil::digest` with the SHA-256 of
+//! `net::dtls::sha256`, so capsules that hash through the HIL, and the
+//! digest virtualizer, also run on chips without a hash peripheral. The data
+//! passed to `add_data()` is hashed at once, and the client is called back
+//! from a deferred call.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let sha = static_init!(
+//!     capsules::software_sha256::SoftwareSha256<'static>,
+//!     capsules::software_sha256::SoftwareSha256::new(dynamic_deferred_caller)
+//! );
+//! sha.initialize_callback_handle(
+//!     dynamic_deferred_caller
+//!         .register(sha)
+//!         .expect("no deferred call slot available for SHA-256"),
+//! );
+//! ```
+
+use crate::net::dtls::sha256::{Sha256, SHA256_LEN};
+use core::cell::Cell;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::dynamic_deferred_call::{
+    DeferredCallHandle, DynamicDeferredCall, DynamicDeferredCallClient,
+};
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::hil::digest;
+use kernel::ReturnCode;
+
+const BLOCK_LEN: usize = 64;
+
+pub struct SoftwareSha256<'a> {
+    client: OptionalCell<&'a dyn digest::Client<'a, [u8; SHA256_LEN]>>,
+    /// The hash so far, which for HMAC starts with the inner padded key.
+    hash: Cell<Sha256>,
+    hmac_key: OptionalCell<[u8; 32]>,
+    /// The buffers whose callbacks are pending.
+    data: TakeCell<'static, [u8]>,
+    digest: TakeCell<'static, [u8; SHA256_LEN]>,
+    deferred_caller: &'a DynamicDeferredCall,
+    handle: OptionalCell<DeferredCallHandle>,
+}
+
+impl<'a> SoftwareSha256<'a> {
+    pub fn new(deferred_caller: &'a DynamicDeferredCall) -> SoftwareSha256<'a> {
+        SoftwareSha256 {
+            client: OptionalCell::empty(),
+            hash: Cell::new(Sha256::new()),
+            hmac_key: OptionalCell::empty(),
+            data: TakeCell::empty(),
+            digest: TakeCell::empty(),
+            deferred_caller: deferred_caller,
+            handle: OptionalCell::empty(),
+        }
+    }
+
+    pub fn initialize_callback_handle(&self, handle: DeferredCallHandle) {
+        self.handle.replace(handle);
+    }
+
+    /// Returns `key` padded to a block and XORed with `pad`.
+    fn padded_key(key: &[u8; 32], pad: u8) -> [u8; BLOCK_LEN] {
+        let mut block = [pad; BLOCK_LEN];
+        for (byte, key) in block.iter_mut().zip(key.iter()) {
+            *byte ^= *key;
+        }
+        block
+    }
+
+    /// Starts a new hash in the current mode.
+    fn restart(&self) {
+        let mut hash = Sha256::new();
+        self.hmac_key
+            .map(|key| hash.update(&Self::padded_key(key, 0x36)));
+        self.hash.set(hash);
+    }
+
+    fn busy(&self) -> bool {
+        self.data.is_some() || self.digest.is_some()
+    }
+
+    fn schedule_callback(&self) -> bool {
+        self.handle
+            .map(|handle| self.deferred_caller.set(*handle))
+            .is_some()
+    }
+}
+
+impl<'a> digest::Digest<'a, [u8; SHA256_LEN]> for SoftwareSha256<'a> {
+    fn set_client(&'a self, client: &'a dyn digest::Client<'a, [u8; SHA256_LEN]>) {
+        self.client.set(client);
+    }
+
+    fn add_data(
+        &self,
+        data: LeasableBuffer<'static, u8>,
+    ) -> Result<usize, (ReturnCode, &'static mut [u8])> {
+        if self.data.is_some() {
+            return Err((ReturnCode::EBUSY, data.take()));
+        }
+        if !self.schedule_callback() {
+            return Err((ReturnCode::FAIL, data.take()));
+        }
+        let mut hash = self.hash.get();
+        hash.update(&data[..]);
+        self.hash.set(hash);
+        let len = data.len();
+        self.data.replace(data.take());
+        Ok(len)
+    }
+
+    fn run(
+        &'a self,
+        digest: &'static mut [u8; SHA256_LEN],
+    ) -> Result<(), (ReturnCode, &'static mut [u8; SHA256_LEN])> {
+        if self.digest.is_some() {
+            return Err((ReturnCode::EBUSY, digest));
+        }
+        if !self.schedule_callback() {
+            return Err((ReturnCode::FAIL, digest));
+        }
+        let inner = self.hash.get().finish();
+        *digest = match self.hmac_key.map(|key| *key) {
+            Some(key) => {
+                let mut outer = Sha256::new();
+                outer.update(&Self::padded_key(&key, 0x5c));
+                outer.update(&inner);
+                outer.finish()
+            }
+            None => inner,
+        };
+        self.restart();
+        self.digest.replace(digest);
+        Ok(())
+    }
+
+    fn clear_data(&self) {
+        self.hmac_key.clear();
+        self.hash.set(Sha256::new());
+    }
+}
+
+impl digest::Sha256 for SoftwareSha256<'_> {
+    fn set_mode_sha256(&self) -> Result<(), ReturnCode> {
+        if self.busy() {
+            return Err(ReturnCode::EBUSY);
+        }
+        self.hmac_key.clear();
+        self.restart();
+        Ok(())
+    }
+}
+
+impl digest::HMACSha256 for SoftwareSha256<'_> {
+    fn set_mode_hmacsha256(&self, key: &[u8; 32]) -> Result<(), ReturnCode> {
+        if self.busy() {
+            return Err(ReturnCode::EBUSY);
+        }
+        self.hmac_key.set(*key);
+        self.restart();
+        Ok(())
+    }
+}
+
+impl<'a> DynamicDeferredCallClient for SoftwareSha256<'a> {
+    fn call(&self, _handle: DeferredCallHandle) {
+        if let Some(data) = self.data.take() {
+            self.client
+                .map(move |client| client.add_data_done(Ok(()), data));
+        }
+        if let Some(digest) = self.digest.take() {
+            self.client
+                .map(move |client| client.hash_done(Ok(()), digest));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use super::*;
+    use kernel::common::dynamic_deferred_call::DynamicDeferredCallClientState;
+    use kernel::hil::digest::{Digest, HMACSha256, Sha256 as _};
+    use std::boxed::Box;
+    use std::vec::Vec;
+
+    fn leak<T>(value: T) -> &'static T {
+        Box::leak(Box::new(value))
+    }
+
+    fn hex(s: &str) -> [u8; SHA256_LEN] {
+        let mut bytes = [0; SHA256_LEN];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&s[2 * i..2 * i + 2], 16).unwrap();
+        }
+        bytes
+    }
+
+    /// Keeps what the engine gives back.
+    struct Collector {
+        data: TakeCell<'static, [u8]>,
+        digest: Cell<Option<[u8; SHA256_LEN]>>,
+    }
+
+    impl digest::Client<'static, [u8; SHA256_LEN]> for Collector {
+        fn add_data_done(&self, result: Result<(), ReturnCode>, data: &'static mut [u8]) {
+            assert_eq!(result, Ok(()));
+            self.data.replace(data);
+        }
+
+        fn hash_done(&self, result: Result<(), ReturnCode>, digest: &'static mut [u8; SHA256_LEN]) {
+            assert_eq!(result, Ok(()));
+            self.digest.set(Some(*digest));
+        }
+    }
+
+    struct Engine {
+        sha: &'static SoftwareSha256<'static>,
+        collector: &'static Collector,
+        handle: DeferredCallHandle,
+    }
+
+    impl Engine {
+        fn new() -> Engine {
+            let states = Box::leak(Box::new([DynamicDeferredCallClientState::default()]));
+            let deferred_caller = leak(DynamicDeferredCall::new(states));
+            let sha = leak(SoftwareSha256::new(deferred_caller));
+            let handle = deferred_caller.register(sha).unwrap();
+            sha.initialize_callback_handle(handle);
+            let collector = leak(Collector {
+                data: TakeCell::empty(),
+                digest: Cell::new(None),
+            });
+            sha.set_client(collector);
+            Engine {
+                sha: sha,
+                collector: collector,
+                handle: handle,
+            }
+        }
+
+        /// Hashes `parts` one `add_data()` each, and returns the digest.
+        fn hash(&self, parts: &[&[u8]]) -> [u8; SHA256_LEN] {
+            for part in parts {
+                let buf = Box::leak(part.to_vec().into_boxed_slice());
+                assert_eq!(self.sha.add_data(LeasableBuffer::new(buf)), Ok(part.len()));
+                self.sha.call(self.handle);
+                assert!(self.collector.data.take().is_some());
+            }
+            let digest = Box::leak(Box::new([0; SHA256_LEN]));
+            assert!(self.sha.run(digest).is_ok());
+            self.sha.call(self.handle);
+            self.collector.digest.take().unwrap()
+        }
+    }
+
+    #[test]
+    fn sha256_fips_180_4() {
+        let engine = Engine::new();
+        assert_eq!(
+            engine.hash(&[b"abc"]),
+            hex("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad")
+        );
+        assert_eq!(
+            engine.hash(&[]),
+            hex("e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855")
+        );
+        // Two blocks once padded.
+        assert_eq!(
+            engine.hash(&[b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"]),
+            hex("248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1")
+        );
+        assert_eq!(
+            engine.hash(
+                &[b"abcdefghbcdefghicdefghijdefghijkefghijklfghijklmghijklmn\
+                            hijklmnoijklmnopjklmnopqklmnopqrlmnopqrsmnopqrstnopqrstu"]
+            ),
+            hex("cf5b16a778af8380036ce59e7b0492370b249b11e8f07a51afac45037afee9d1")
+        );
+        let thousand = [b'a'; 1000];
+        let million: Vec<&[u8]> = (0..1000).map(|_| &thousand[..]).collect();
+        assert_eq!(
+            engine.hash(&million),
+            hex("cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0")
+        );
+    }
+
+    #[test]
+    fn sha256_block_boundaries() {
+        let engine = Engine::new();
+        let message: Vec<u8> = (0..128).collect();
+        for &(len, expected) in [
+            (
+                55,
+                "463eb28e72f82e0a96c0a4cc53690c571281131f672aa229e0d45ae59b598b59",
+            ),
+            (
+                56,
+                "da2ae4d6b36748f2a318f23e7ab1dfdf45acdc9d049bd80e59de82a60895f562",
+            ),
+            (
+                63,
+                "29af2686fd53374a36b0846694cc342177e428d1647515f078784d69cdb9e488",
+            ),
+            (
+                64,
+                "fdeab9acf3710362bd2658cdc9a29e8f9c757fcf9811603a8c447cd1d9151108",
+            ),
+            (
+                65,
+                "4bfd2c8b6f1eec7a2afeb48b934ee4b2694182027e6d0fc075074f2fabb31781",
+            ),
+            (
+                128,
+                "471fb943aa23c511f6f72f8d1652d9c880cfa392ad80503120547703e56a2be5",
+            ),
+        ]
+        .iter()
+        {
+            let expected = hex(expected);
+            assert_eq!(engine.hash(&[&message[..len]]), expected);
+            // The same message split across and at block boundaries.
+            for split in [1, 63, 64].iter().filter(|&&split| split < len) {
+                assert_eq!(
+                    engine.hash(&[&message[..*split], &message[*split..len]]),
+                    expected
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn hmac_sha256_rfc_4231() {
+        let engine = Engine::new();
+        // Keys shorter than 32 bytes are padded with zeros, as HMAC does.
+        let cases: [(&[u8], &[u8], &str); 4] = [
+            (
+                &[0x0b; 20],
+                b"Hi There",
+                "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7",
+            ),
+            (
+                b"Jefe",
+                b"what do ya want for nothing?",
+                "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843",
+            ),
+            (
+                &[0xaa; 20],
+                &[0xdd; 50],
+                "773ea91e36800e46854db8ebd09181a72959098b3ef8c122d9635514ced565fe",
+            ),
+            (
+                &[
+                    0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d,
+                    0x0e, 0x0f, 0x10, 0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x17, 0x18, 0x19,
+                ],
+                &[0xcd; 50],
+                "82558a389a443c0ea4cc819899f2083a85f0faa3e578f8077a2e3ff46729665b",
+            ),
+        ];
+        for (key, data, expected) in cases.iter() {
+            let mut padded = [0; 32];
+            padded[..key.len()].copy_from_slice(key);
+            assert_eq!(engine.sha.set_mode_hmacsha256(&padded), Ok(()));
+            assert_eq!(engine.hash(&[data]), hex(expected));
+            // The key is kept for the next hash.
+            assert_eq!(engine.hash(&[&data[..7], &data[7..]]), hex(expected));
+        }
+
+        assert_eq!(engine.sha.set_mode_sha256(), Ok(()));
+        assert_eq!(
+            engine.hash(&[b"abc"]),
+            hex("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad")
+        );
+    }
+}
@@ -0,0 +1,137 @@
+//! Microsecond stopwatch for benchmarking userspace code.
+//!
+//! Measuring a stretch of code with the alarm driver takes a syscall to read
+//! the time before and after, plus the app's own arithmetic to convert ticks
+//! and handle wrap-around. This driver keeps a stopwatch for each app instead:
+//! the app starts it, takes lap times and stops it, and each command returns
+//! the elapsed time directly in microseconds.
+//!
+//! The stopwatch runs on any `hil::time::Time`, so its resolution is that of
+//! the timer it is given: a timer running at 1 MHz or faster gives
+//! microsecond resolution, while the nRF5x `TimerAlarm` below, which ticks at
+//! 16 kHz, resolves about 62 us. Elapsed times wrap around with the counter
+//! of the timer, for example after 2^24 ticks of a 24-bit RTC, and times
+//! too long for an `isize` of microseconds are returned as `isize::MAX`.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let stopwatch = static_init!(
+//!     capsules::stopwatch::Stopwatch<'static, nrf5x::timer::TimerAlarm<'static>>,
+//!     capsules::stopwatch::Stopwatch::new(&nrf5x::timer::TIMER0, board_kernel.create_grant(&grant_cap))
+//! );
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! - command `0`: driver check.
+//! - command `1`: start (or restart) the stopwatch.
+//! - command `2`: stop the stopwatch, returning the time since it was started.
+//! - command `3`: lap, returning the time since the previous lap or the start.
+//! - command `4`: read the time since the stopwatch was started without
+//!   stopping it. Once stopped, this returns the time it ran for.
+//!
+//! Commands 2 and 4 return `EOFF` if the stopwatch has not been started, and
+//! command 3 returns `EOFF` unless it is running.
+
+use core::cmp;
+use kernel::hil::time::{Ticks, Time};
+use kernel::{AppId, Driver, Grant, ReturnCode};
+
+/// Syscall driver number.
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::Stopwatch as usize;
+
+/// Times are kept in the ticks of the timer, so that they wrap around at
+/// its width.
+#[derive(Copy, Clone)]
+enum State<K: Ticks> {
+    Idle,
+    Running { start: K, lap: K },
+    Stopped { elapsed: K },
+}
+
+pub struct App<K: Ticks> {
+    state: State<K>,
+}
+
+impl<K: Ticks> Default for App<K> {
+    fn default() -> App<K> {
+        App { state: State::Idle }
+    }
+}
+
+pub struct Stopwatch<'a, T: Time> {
+    time: &'a T,
+    apps: Grant<App<T::Ticks>>,
+}
+
+impl<'a, T: Time> Stopwatch<'a, T> {
+    pub fn new(time: &'a T, grant: Grant<App<T::Ticks>>) -> Stopwatch<'a, T> {
+        Stopwatch {
+            time: time,
+            apps: grant,
+        }
+    }
+
+    /// Larger values would reach the app as negative error codes.
+    fn microseconds(ticks: T::Ticks) -> ReturnCode {
+        ReturnCode::SuccessWithValue {
+            value: cmp::min(T::ticks_to_us(ticks), isize::MAX as u64) as usize,
+        }
+    }
+
+    fn update<F>(&self, appid: AppId, fun: F) -> ReturnCode
+    where
+        F: FnOnce(&mut State<T::Ticks>, T::Ticks) -> ReturnCode,
+    {
+        let now = self.time.now();
+        self.apps
+            .enter(appid, |app, _| fun(&mut app.state, now))
+            .unwrap_or_else(|err| err.into())
+    }
+}
+
+impl<'a, T: Time> Driver for Stopwatch<'a, T> {
+    fn command(&self, command_num: usize, _: usize, _: usize, appid: AppId) -> ReturnCode {
+        match command_num {
+            0 => ReturnCode::SUCCESS,
+            1 => self.update(appid, |state, now| {
+                *state = State::Running {
+                    start: now,
+                    lap: now,
+                };
+                ReturnCode::SUCCESS
+            }),
+            2 => self.update(appid, |state, now| match *state {
+                State::Running { start, .. } => {
+                    let elapsed = now.wrapping_sub(start);
+                    *state = State::Stopped { elapsed: elapsed };
+                    Self::microseconds(elapsed)
+                }
+                State::Stopped { elapsed } => Self::microseconds(elapsed),
+                State::Idle => ReturnCode::EOFF,
+            }),
+            3 => self.update(appid, |state, now| match *state {
+                State::Running { start, lap } => {
+                    *state = State::Running {
+                        start: start,
+                        lap: now,
+                    };
+                    Self::microseconds(now.wrapping_sub(lap))
+                }
+                _ => ReturnCode::EOFF,
+            }),
+            4 => self.update(appid, |state, now| match *state {
+                State::Running { start, .. } => Self::microseconds(now.wrapping_sub(start)),
+                State::Stopped { elapsed } => Self::microseconds(elapsed),
+                State::Idle => ReturnCode::EOFF,
+            }),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
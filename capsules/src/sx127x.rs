@@ -0,0 +1,523 @@
+//! Driver for Semtech SX1276/77/78/79 LoRa radios.
+//!
+//! Implements `hil::lora::Radio` over SPI, for modules such as the HopeRF
+//! RFM95. The radio is used in LoRa mode only, with its high frequency port
+//! and the `PA_BOOST` output, so transmit power is 2 to 17 dBm. `DIO0` must
+//! be wired to signal TX and RX done and `DIO1` to signal RX timeouts; both
+//! pins share this driver as their client.
+//!
+//! Every `transmit()` or `receive()` writes the full configuration before
+//! starting, so the radio can be reconfigured between packets without
+//! tracking what changed. The board is expected to have released the
+//! radio's reset line before `initialize()`.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//! # use capsules::virtual_spi::VirtualSpiMasterDevice;
+//!
+//! let lora_spi = static_init!(
+//!     VirtualSpiMasterDevice<'static, sam4l::spi::SpiHw>,
+//!     VirtualSpiMasterDevice::new(mux_spi, 3)
+//! );
+//! let sx127x = static_init!(
+//!     capsules::sx127x::Sx127x<'static, VirtualSpiMasterDevice<'static, sam4l::spi::SpiHw>>,
+//!     capsules::sx127x::Sx127x::new(
+//!         lora_spi,
+//!         &sam4l::gpio::PA[08],
+//!         &sam4l::gpio::PA[09],
+//!         static_init!([u8; 256], [0; 256]),
+//!         static_init!([u8; 256], [0; 256]),
+//!     )
+//! );
+//! lora_spi.set_client(sx127x);
+//! sam4l::gpio::PA[08].set_client(sx127x);
+//! sam4l::gpio::PA[09].set_client(sx127x);
+//! sx127x.initialize();
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::gpio;
+use kernel::hil::lora::{self, Bandwidth, Config};
+use kernel::hil::spi;
+use kernel::ReturnCode;
+
+// Registers.
+const REG_FIFO: u8 = 0x00;
+const REG_OP_MODE: u8 = 0x01;
+const REG_FRF_MSB: u8 = 0x06;
+const REG_FRF_MID: u8 = 0x07;
+const REG_FRF_LSB: u8 = 0x08;
+const REG_PA_CONFIG: u8 = 0x09;
+const REG_LNA: u8 = 0x0c;
+const REG_FIFO_ADDR_PTR: u8 = 0x0d;
+const REG_FIFO_TX_BASE_ADDR: u8 = 0x0e;
+const REG_FIFO_RX_BASE_ADDR: u8 = 0x0f;
+const REG_FIFO_RX_CURRENT_ADDR: u8 = 0x10;
+const REG_IRQ_FLAGS: u8 = 0x12;
+const REG_MODEM_CONFIG1: u8 = 0x1d;
+const REG_MODEM_CONFIG2: u8 = 0x1e;
+const REG_SYMB_TIMEOUT_LSB: u8 = 0x1f;
+const REG_PAYLOAD_LENGTH: u8 = 0x22;
+const REG_MODEM_CONFIG3: u8 = 0x26;
+const REG_INVERT_IQ: u8 = 0x33;
+const REG_SYNC_WORD: u8 = 0x39;
+const REG_INVERT_IQ2: u8 = 0x3b;
+const REG_DIO_MAPPING1: u8 = 0x40;
+
+const WRITE: u8 = 0x80;
+
+// Operating modes.
+const MODE_LONG_RANGE: u8 = 0x80;
+const MODE_SLEEP: u8 = 0x00;
+const MODE_STANDBY: u8 = 0x01;
+const MODE_TX: u8 = 0x03;
+const MODE_RX_SINGLE: u8 = 0x06;
+
+// Interrupt flags.
+const IRQ_RX_TIMEOUT: u8 = 0x80;
+const IRQ_RX_DONE: u8 = 0x40;
+const IRQ_PAYLOAD_CRC_ERROR: u8 = 0x20;
+const IRQ_TX_DONE: u8 = 0x08;
+
+/// `DIO0` signals TX done while transmitting and RX done otherwise; `DIO1`
+/// always signals RX timeout.
+const DIO_MAPPING_TX: u8 = 0x40;
+const DIO_MAPPING_RX: u8 = 0x00;
+
+/// Offset of the packet RSSI on the high frequency port.
+const RSSI_OFFSET: i16 = -157;
+
+/// The status registers read after an interrupt, from
+/// `REG_FIFO_RX_CURRENT_ADDR` to `RegPktRssiValue`.
+const STATUS_LEN: usize = 11;
+
+/// The most register writes before an operation.
+const MAX_WRITES: usize = 18;
+
+#[derive(Copy, Clone, PartialEq)]
+enum Next {
+    /// Nothing; the radio is idle.
+    Idle,
+    /// Write the payload to the FIFO, then start transmitting.
+    WriteFifo,
+    /// Wait for TX done.
+    Transmitting,
+    /// Wait for RX done or timeout.
+    Receiving,
+    /// Read the packet of this length, RSSI and SNR from the FIFO.
+    ReadFifo(usize, i16, i8),
+    /// Tell the transmit client we are done.
+    Transmitted(ReturnCode),
+    /// Tell the receive client nothing was received.
+    ReceiveFailed(ReturnCode),
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum State {
+    /// Waiting for the SPI transaction of a register write.
+    Writing(Next),
+    WritingFifo,
+    ReadingStatus,
+    ReadingFifo(usize, i16, i8),
+    /// Not using SPI.
+    Waiting(Next),
+}
+
+pub struct Sx127x<'a, S: spi::SpiMasterDevice> {
+    spi: &'a S,
+    dio0: &'a dyn gpio::InterruptPin<'a>,
+    dio1: &'a dyn gpio::InterruptPin<'a>,
+    config: Cell<Config>,
+    state: Cell<State>,
+    /// Register writes still to do, and how many of them were done.
+    writes: Cell<[(u8, u8); MAX_WRITES]>,
+    write_count: Cell<usize>,
+    write_index: Cell<usize>,
+    spi_write: TakeCell<'static, [u8]>,
+    spi_read: TakeCell<'static, [u8]>,
+    /// The buffer being transmitted from or received into.
+    buffer: TakeCell<'static, [u8]>,
+    tx_len: Cell<usize>,
+    tx_client: OptionalCell<&'a dyn lora::TransmitClient>,
+    rx_client: OptionalCell<&'a dyn lora::ReceiveClient>,
+}
+
+impl<'a, S: spi::SpiMasterDevice> Sx127x<'a, S> {
+    /// `spi_write` and `spi_read` must hold 256 bytes.
+    pub fn new(
+        spi: &'a S,
+        dio0: &'a dyn gpio::InterruptPin<'a>,
+        dio1: &'a dyn gpio::InterruptPin<'a>,
+        spi_write: &'static mut [u8],
+        spi_read: &'static mut [u8],
+    ) -> Sx127x<'a, S> {
+        Sx127x {
+            spi: spi,
+            dio0: dio0,
+            dio1: dio1,
+            config: Cell::new(Config {
+                frequency_hz: 868_100_000,
+                spreading_factor: 7,
+                bandwidth: Bandwidth::Khz125,
+                coding_rate: 5,
+                tx_power_dbm: 14,
+                crc: true,
+                invert_iq: false,
+                sync_word: 0x12,
+            }),
+            state: Cell::new(State::Waiting(Next::Idle)),
+            writes: Cell::new([(0, 0); MAX_WRITES]),
+            write_count: Cell::new(0),
+            write_index: Cell::new(0),
+            spi_write: TakeCell::new(spi_write),
+            spi_read: TakeCell::new(spi_read),
+            buffer: TakeCell::empty(),
+            tx_len: Cell::new(0),
+            tx_client: OptionalCell::empty(),
+            rx_client: OptionalCell::empty(),
+        }
+    }
+
+    /// Configure SPI and the pins and switch the radio to LoRa mode.
+    pub fn initialize(&self) -> ReturnCode {
+        self.spi.configure(
+            spi::ClockPolarity::IdleLow,
+            spi::ClockPhase::SampleLeading,
+            1_000_000,
+        );
+        for pin in [self.dio0, self.dio1].iter() {
+            pin.make_input();
+            pin.set_floating_state(gpio::FloatingState::PullNone);
+            pin.enable_interrupts(gpio::InterruptEdge::RisingEdge);
+        }
+        // The mode can only be changed to LoRa while sleeping.
+        self.start_writes(
+            &[
+                (REG_OP_MODE, MODE_SLEEP),
+                (REG_OP_MODE, MODE_LONG_RANGE | MODE_SLEEP),
+                (REG_FIFO_TX_BASE_ADDR, 0),
+                (REG_FIFO_RX_BASE_ADDR, 0),
+                // Maximum gain with the LNA boost.
+                (REG_LNA, 0x23),
+                (REG_OP_MODE, MODE_LONG_RANGE | MODE_STANDBY),
+            ],
+            Next::Idle,
+        )
+    }
+
+    fn busy(&self) -> bool {
+        self.state.get() != State::Waiting(Next::Idle)
+    }
+
+    /// The register writes that apply the configuration, for a transmission
+    /// if `transmit` and otherwise for a reception.
+    fn configuration(&self, transmit: bool, timeout_symbols: u16) -> [(u8, u8); 14] {
+        let config = self.config.get();
+        let frf = ((config.frequency_hz as u64) << 19) / 32_000_000;
+        let bandwidth = match config.bandwidth {
+            Bandwidth::Khz125 => 0x7,
+            Bandwidth::Khz250 => 0x8,
+            Bandwidth::Khz500 => 0x9,
+        };
+        // Low data rate optimization is required for symbols over 16 ms.
+        let low_data_rate = if config.symbol_us() > 16_000 { 0x08 } else { 0 };
+        let power = cmp::max(2, cmp::min(17, config.tx_power_dbm)) as u8 - 2;
+        let (invert_iq, invert_iq2) = match (config.invert_iq, transmit) {
+            (true, true) => (0x26, 0x19),
+            (true, false) => (0x67, 0x19),
+            (false, _) => (0x27, 0x1d),
+        };
+        [
+            (REG_OP_MODE, MODE_LONG_RANGE | MODE_STANDBY),
+            (REG_FRF_MSB, (frf >> 16) as u8),
+            (REG_FRF_MID, (frf >> 8) as u8),
+            (REG_FRF_LSB, frf as u8),
+            (
+                REG_MODEM_CONFIG1,
+                bandwidth << 4 | (config.coding_rate - 4) << 1,
+            ),
+            (
+                REG_MODEM_CONFIG2,
+                config.spreading_factor << 4
+                    | (config.crc as u8) << 2
+                    | (timeout_symbols >> 8) as u8 & 0x03,
+            ),
+            // AGC on.
+            (REG_MODEM_CONFIG3, low_data_rate | 0x04),
+            (REG_SYMB_TIMEOUT_LSB, timeout_symbols as u8),
+            (REG_PA_CONFIG, 0x80 | power),
+            (REG_SYNC_WORD, config.sync_word),
+            (REG_INVERT_IQ, invert_iq),
+            (REG_INVERT_IQ2, invert_iq2),
+            (REG_FIFO_ADDR_PTR, 0),
+            (
+                REG_DIO_MAPPING1,
+                if transmit {
+                    DIO_MAPPING_TX
+                } else {
+                    DIO_MAPPING_RX
+                },
+            ),
+        ]
+    }
+
+    /// Write `writes` to the registers, one by one, and then continue with
+    /// `next`.
+    fn start_writes(&self, writes: &[(u8, u8)], next: Next) -> ReturnCode {
+        let mut all = [(0, 0); MAX_WRITES];
+        all[..writes.len()].copy_from_slice(writes);
+        self.writes.set(all);
+        self.write_count.set(writes.len());
+        self.write_index.set(0);
+        self.state.set(State::Writing(next));
+        self.next_write()
+    }
+
+    fn next_write(&self) -> ReturnCode {
+        let index = self.write_index.get();
+        if index == self.write_count.get() {
+            if let State::Writing(next) = self.state.get() {
+                self.continue_with(next);
+            }
+            return ReturnCode::SUCCESS;
+        }
+        self.write_index.set(index + 1);
+        let (register, value) = self.writes.get()[index];
+        self.spi_write.take().map_or(ReturnCode::EBUSY, |buf| {
+            buf[0] = WRITE | register;
+            buf[1] = value;
+            self.spi.read_write_bytes(buf, None, 2)
+        })
+    }
+
+    fn continue_with(&self, next: Next) {
+        match next {
+            Next::WriteFifo => {
+                let len = self.tx_len.get();
+                let rcode = self.spi_write.take().map_or(ReturnCode::FAIL, |buf| {
+                    buf[0] = WRITE | REG_FIFO;
+                    self.buffer
+                        .map(|payload| buf[1..1 + len].copy_from_slice(&payload[..len]));
+                    self.state.set(State::WritingFifo);
+                    self.spi.read_write_bytes(buf, None, 1 + len)
+                });
+                if rcode != ReturnCode::SUCCESS {
+                    self.continue_with(Next::Transmitted(rcode));
+                }
+            }
+            Next::ReadFifo(len, rssi, snr) => match (self.spi_write.take(), self.spi_read.take()) {
+                (Some(wbuf), Some(rbuf)) => {
+                    wbuf[0] = REG_FIFO;
+                    self.state.set(State::ReadingFifo(len, rssi, snr));
+                    self.spi.read_write_bytes(wbuf, Some(rbuf), 1 + len);
+                }
+                (wbuf, rbuf) => {
+                    wbuf.map(|buf| self.spi_write.replace(buf));
+                    rbuf.map(|buf| self.spi_read.replace(buf));
+                    self.continue_with(Next::ReceiveFailed(ReturnCode::FAIL));
+                }
+            },
+            Next::Transmitted(rcode) => {
+                self.state.set(State::Waiting(Next::Idle));
+                self.buffer.take().map(|buf| {
+                    self.tx_client
+                        .map(move |client| client.transmitted(buf, rcode));
+                });
+            }
+            Next::ReceiveFailed(rcode) => {
+                self.state.set(State::Waiting(Next::Idle));
+                self.buffer.take().map(|buf| {
+                    self.rx_client
+                        .map(move |client| client.received(buf, 0, 0, 0, rcode));
+                });
+            }
+            Next::Idle | Next::Transmitting | Next::Receiving => {
+                self.state.set(State::Waiting(next));
+            }
+        }
+    }
+
+    /// Read the interrupt flags and packet status.
+    fn read_status(&self) {
+        match (self.spi_write.take(), self.spi_read.take()) {
+            (Some(wbuf), Some(rbuf)) => {
+                wbuf[0] = REG_FIFO_RX_CURRENT_ADDR;
+                self.state.set(State::ReadingStatus);
+                self.spi.read_write_bytes(wbuf, Some(rbuf), 1 + STATUS_LEN);
+            }
+            (wbuf, rbuf) => {
+                wbuf.map(|buf| self.spi_write.replace(buf));
+                rbuf.map(|buf| self.spi_read.replace(buf));
+            }
+        }
+    }
+
+    /// Decide what to do about the interrupt whose status is in `status`.
+    fn status_read(&self, status: &[u8]) {
+        let current_addr = status[0];
+        let flags = status[2];
+        let len = status[3] as usize;
+        let snr = (status[9] as i8) / 4;
+        let rssi = RSSI_OFFSET + status[10] as i16;
+        let clear = (REG_IRQ_FLAGS, 0xff);
+        if flags & IRQ_TX_DONE != 0 {
+            self.start_writes(&[clear], Next::Transmitted(ReturnCode::SUCCESS));
+        } else if flags & IRQ_RX_TIMEOUT != 0 {
+            self.start_writes(&[clear], Next::ReceiveFailed(ReturnCode::ECANCEL));
+        } else if flags & IRQ_RX_DONE != 0 {
+            if flags & IRQ_PAYLOAD_CRC_ERROR != 0 {
+                self.start_writes(&[clear], Next::ReceiveFailed(ReturnCode::FAIL));
+            } else {
+                self.start_writes(
+                    &[clear, (REG_FIFO_ADDR_PTR, current_addr)],
+                    Next::ReadFifo(len, rssi, snr),
+                );
+            }
+        } else if self.tx_len.get() != 0 {
+            // Spurious; keep waiting.
+            self.state.set(State::Waiting(Next::Transmitting));
+        } else {
+            self.state.set(State::Waiting(Next::Receiving));
+        }
+    }
+}
+
+impl<'a, S: spi::SpiMasterDevice> lora::Radio<'a> for Sx127x<'a, S> {
+    fn set_transmit_client(&self, client: &'a dyn lora::TransmitClient) {
+        self.tx_client.set(client);
+    }
+
+    fn set_receive_client(&self, client: &'a dyn lora::ReceiveClient) {
+        self.rx_client.set(client);
+    }
+
+    fn configure(&self, config: Config) -> ReturnCode {
+        if config.spreading_factor < 7
+            || config.spreading_factor > 12
+            || config.coding_rate < 5
+            || config.coding_rate > 8
+        {
+            return ReturnCode::EINVAL;
+        }
+        self.config.set(config);
+        ReturnCode::SUCCESS
+    }
+
+    fn transmit(
+        &self,
+        buffer: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        if self.busy() {
+            return Err((ReturnCode::EBUSY, buffer));
+        }
+        if len == 0 || len > cmp::min(buffer.len(), lora::MAX_PAYLOAD_LEN) {
+            return Err((ReturnCode::ESIZE, buffer));
+        }
+        let mut writes = [(0, 0); MAX_WRITES];
+        writes[..14].copy_from_slice(&self.configuration(true, 0));
+        writes[14] = (REG_PAYLOAD_LENGTH, len as u8);
+        writes[15] = (REG_IRQ_FLAGS, 0xff);
+        self.buffer.replace(buffer);
+        self.tx_len.set(len);
+        self.start_writes(&writes[..16], Next::WriteFifo);
+        Ok(())
+    }
+
+    fn receive(
+        &self,
+        buffer: &'static mut [u8],
+        timeout_symbols: u16,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        if self.busy() {
+            return Err((ReturnCode::EBUSY, buffer));
+        }
+        let timeout = cmp::max(4, cmp::min(1023, timeout_symbols));
+        let mut writes = [(0, 0); MAX_WRITES];
+        writes[..14].copy_from_slice(&self.configuration(false, timeout));
+        writes[14] = (REG_IRQ_FLAGS, 0xff);
+        writes[15] = (REG_OP_MODE, MODE_LONG_RANGE | MODE_RX_SINGLE);
+        self.buffer.replace(buffer);
+        self.tx_len.set(0);
+        self.start_writes(&writes[..16], Next::Receiving);
+        Ok(())
+    }
+
+    fn sleep(&self) -> ReturnCode {
+        let next = match self.state.get() {
+            State::Waiting(Next::Idle) => Next::Idle,
+            State::Waiting(Next::Transmitting) => Next::Transmitted(ReturnCode::ECANCEL),
+            State::Waiting(Next::Receiving) => Next::ReceiveFailed(ReturnCode::ECANCEL),
+            _ => return ReturnCode::EBUSY,
+        };
+        self.start_writes(&[(REG_OP_MODE, MODE_LONG_RANGE | MODE_SLEEP)], next)
+    }
+}
+
+impl<'a, S: spi::SpiMasterDevice> spi::SpiMasterClient for Sx127x<'a, S> {
+    fn read_write_done(
+        &self,
+        write: &'static mut [u8],
+        read: Option<&'static mut [u8]>,
+        len: usize,
+    ) {
+        self.spi_write.replace(write);
+        match self.state.get() {
+            State::Writing(_) => {
+                read.map(|buf| self.spi_read.replace(buf));
+                self.next_write();
+            }
+            State::WritingFifo => {
+                read.map(|buf| self.spi_read.replace(buf));
+                self.start_writes(
+                    &[(REG_OP_MODE, MODE_LONG_RANGE | MODE_TX)],
+                    Next::Transmitting,
+                );
+            }
+            State::ReadingStatus => {
+                if let Some(buf) = read {
+                    let mut status = [0; STATUS_LEN];
+                    status.copy_from_slice(&buf[1..1 + STATUS_LEN]);
+                    self.spi_read.replace(buf);
+                    self.status_read(&status);
+                }
+            }
+            State::ReadingFifo(packet_len, rssi, snr) => {
+                let received = self.buffer.map_or(0, |buffer| {
+                    read.as_ref().map_or(0, |rbuf| {
+                        let copied = cmp::min(cmp::min(packet_len, len - 1), buffer.len());
+                        buffer[..copied].copy_from_slice(&rbuf[1..1 + copied]);
+                        copied
+                    })
+                });
+                read.map(|buf| self.spi_read.replace(buf));
+                self.state.set(State::Waiting(Next::Idle));
+                self.buffer.take().map(|buf| {
+                    self.rx_client.map(move |client| {
+                        client.received(buf, received, rssi, snr, ReturnCode::SUCCESS)
+                    });
+                });
+            }
+            State::Waiting(_) => {
+                read.map(|buf| self.spi_read.replace(buf));
+            }
+        }
+    }
+}
+
+impl<'a, S: spi::SpiMasterDevice> gpio::Client for Sx127x<'a, S> {
+    fn fired(&self) {
+        match self.state.get() {
+            State::Waiting(Next::Transmitting) | State::Waiting(Next::Receiving) => {
+                self.read_status()
+            }
+            _ => {}
+        }
+    }
+}
@@ -13,7 +13,7 @@ use crate::net::udp::udp_send::{UDPSendClient, UDPSender};
 use core::cell::Cell;
 use kernel::common::cells::MapCell;
 use kernel::common::leasable_buffer::LeasableBuffer;
-use kernel::hil::time::{self, Alarm, Frequency};
+use kernel::hil::time::{self, PeriodicAlarm};
 use kernel::{debug, ReturnCode};
 
 pub const DST_ADDR: IPAddr = IPAddr([
@@ -25,7 +25,7 @@ pub const DST_ADDR: IPAddr = IPAddr([
 pub const PAYLOAD_LEN: usize = 192;
 pub const SEND_INTERVAL_SECONDS: u32 = 5;
 
-pub struct MockUdp<'a, A: Alarm<'a>> {
+pub struct MockUdp<'a, A: PeriodicAlarm<'a>> {
     id: u16,
     pub alarm: &'a A,
     udp_sender: &'a dyn UDPSender<'a>,
@@ -38,7 +38,7 @@ pub struct MockUdp<'a, A: Alarm<'a>> {
     net_cap: Cell<&'static NetworkCapability>,
 }
 
-impl<'a, A: Alarm<'a>> MockUdp<'a, A> {
+impl<'a, A: PeriodicAlarm<'a>> MockUdp<'a, A> {
     pub fn new(
         id: u16,
         alarm: &'a A,
@@ -67,9 +67,10 @@ impl<'a, A: Alarm<'a>> MockUdp<'a, A> {
     pub fn start_sending(&self) {
         // Set alarm bc if you try to send immediately there are initialization issues
         self.send_loop.set(true);
-        let delay = <A::Frequency>::frequency() * SEND_INTERVAL_SECONDS;
-        self.alarm
-            .set_alarm(self.alarm.now(), A::Ticks::from(delay));
+        let period = A::ticks_from_seconds(SEND_INTERVAL_SECONDS);
+        if self.alarm.set_periodic_alarm(self.alarm.now(), period) != ReturnCode::SUCCESS {
+            debug!("Mock UDP: invalid send interval");
+        }
     }
 
     pub fn update_capability(&self, new_cap: &'static NetworkCapability) {
@@ -123,6 +124,7 @@ impl<'a, A: Alarm<'a>> MockUdp<'a, A> {
                 let socket = self.port_table.create_socket();
                 match socket {
                     Ok(sock) => {
+                        self.port_table.set_owner(&sock, "mock_udp");
                         match self
                             .port_table
                             .bind(sock, self.src_port.get(), self.net_cap.get())
@@ -178,7 +180,7 @@ impl<'a, A: Alarm<'a>> MockUdp<'a, A> {
     }
 }
 
-impl<'a, A: Alarm<'a>> time::AlarmClient for MockUdp<'a, A> {
+impl<'a, A: PeriodicAlarm<'a>> time::AlarmClient for MockUdp<'a, A> {
     fn alarm(&self) {
         if self.send_loop.get() {
             self.send(self.id);
@@ -186,19 +188,16 @@ impl<'a, A: Alarm<'a>> time::AlarmClient for MockUdp<'a, A> {
     }
 }
 
-impl<'a, A: Alarm<'a>> UDPSendClient for MockUdp<'a, A> {
+impl<'a, A: PeriodicAlarm<'a>> UDPSendClient for MockUdp<'a, A> {
     fn send_done(&self, result: ReturnCode, mut dgram: LeasableBuffer<'static, u8>) {
         debug!("Mock UDP done sending. Result: {:?}", result);
         dgram.reset();
         self.udp_dgram.replace(dgram);
         debug!("");
-        let delay = <A::Frequency>::frequency() * SEND_INTERVAL_SECONDS;
-        self.alarm
-            .set_alarm(self.alarm.now(), A::Ticks::from(delay));
     }
 }
 
-impl<'a, A: Alarm<'a>> UDPRecvClient for MockUdp<'a, A> {
+impl<'a, A: PeriodicAlarm<'a>> UDPRecvClient for MockUdp<'a, A> {
     fn receive(
         &self,
         src_addr: IPAddr,
@@ -206,10 +205,11 @@ impl<'a, A: Alarm<'a>> UDPRecvClient for MockUdp<'a, A> {
         src_port: u16,
         _dst_port: u16,
         payload: &[u8],
+        timestamp: Option<u32>,
     ) {
         debug!(
-            "[MOCK_UDP {:?}] Received packet from {:?}:{:?}, contents: {:?}\n",
-            self.id, src_addr, src_port, payload
+            "[MOCK_UDP {:?}] Received packet from {:?}:{:?} at {:?}, contents: {:?}\n",
+            self.id, src_addr, src_port, timestamp, payload
         );
     }
 }
@@ -1,6 +1,13 @@
 //! Communications Class Device for USB
 //!
 //! This capsule allows Tock to support a serial port over USB.
+//!
+//! `CdcAcm` implements `hil::uart::Uart`, so it can replace a UART under
+//! `MuxUart` for the console and debug output. A host is connected once it
+//! sets DTR, or sets the line coding to 115200 baud, and disconnected when it
+//! clears DTR or sends a break. Transmissions wait for a connected host.
+//! Aborted transmissions and receptions are called back from a deferred
+//! call with `ECANCEL`, with the bytes sent or received so far.
 
 use core::cell::Cell;
 use core::cmp;
@@ -17,6 +24,9 @@ use super::usbc_client_ctrl::ClientCtrl;
 use kernel::common::cells::OptionalCell;
 use kernel::common::cells::TakeCell;
 use kernel::common::cells::VolatileCell;
+use kernel::common::dynamic_deferred_call::{
+    DeferredCallHandle, DynamicDeferredCall, DynamicDeferredCallClient,
+};
 use kernel::hil;
 use kernel::hil::uart;
 use kernel::hil::usb::TransferType;
@@ -127,6 +137,13 @@ pub struct CdcAcm<'a, U: 'a> {
     rx_offset: Cell<usize>,
     /// The RX client to use when RX data is received.
     rx_client: OptionalCell<&'a dyn uart::ReceiveClient>,
+
+    /// Whether a transmission or reception has been aborted and waits for
+    /// its callback.
+    tx_aborted: Cell<bool>,
+    rx_aborted: Cell<bool>,
+    deferred_caller: &'a DynamicDeferredCall,
+    handle: OptionalCell<DeferredCallHandle>,
 }
 
 impl<'a, U: hil::usb::UsbController<'a>> CdcAcm<'a, U> {
@@ -136,6 +153,7 @@ impl<'a, U: hil::usb::UsbController<'a>> CdcAcm<'a, U> {
         vendor_id: u16,
         product_id: u16,
         strings: &'static [&'static str; 3],
+        deferred_caller: &'a DynamicDeferredCall,
     ) -> Self {
         let interfaces: &mut [InterfaceDescriptor] = &mut [
             InterfaceDescriptor {
@@ -225,6 +243,7 @@ impl<'a, U: hil::usb::UsbController<'a>> CdcAcm<'a, U> {
                 endpoints,
                 None, // No HID descriptor
                 Some(cdc_descriptors),
+                None, // No DFU descriptor
             );
 
         CdcAcm {
@@ -252,9 +271,17 @@ impl<'a, U: hil::usb::UsbController<'a>> CdcAcm<'a, U> {
             rx_len: Cell::new(0),
             rx_offset: Cell::new(0),
             rx_client: OptionalCell::empty(),
+            tx_aborted: Cell::new(false),
+            rx_aborted: Cell::new(false),
+            deferred_caller: deferred_caller,
+            handle: OptionalCell::empty(),
         }
     }
 
+    pub fn initialize_callback_handle(&self, handle: DeferredCallHandle) {
+        self.handle.replace(handle);
+    }
+
     #[inline]
     fn controller(&self) -> &'a U {
         self.client_ctrl.controller()
@@ -315,7 +342,14 @@ impl<'a, U: hil::usb::UsbController<'a>> hil::usb::Client<'a> for CdcAcm<'a, U>
                     // D1: Carrier control for half duplex modems.
                     //     - 0 -> Deactivate carrier
                     //     - 1 -> Activate carrier
-                    // Currently we don't care about the value
+                    // Terminals set DTR when they open the port, whatever
+                    // its baud rate, and clear it when they close it.
+                    let dtr = setup_data.value & 0x1 != 0;
+                    match self.state.get() {
+                        State::Enumerated if dtr => self.state.set(State::Connecting),
+                        State::Connected if !dtr => self.state.set(State::Enumerated),
+                        _ => {}
+                    }
                 }
                 CDCCntrlMessage::SendBreak => {
                     // On Mac, we seem to get the SEND_BREAK to signal that a
@@ -421,6 +455,7 @@ impl<'a, U: hil::usb::UsbController<'a>> hil::usb::Client<'a> for CdcAcm<'a, U>
                             // ok to signal the callback.
 
                             // Signal the callback and pass back the TX buffer.
+                            self.tx_aborted.set(false);
                             self.tx_client.map(move |tx_client| {
                                 tx_client.transmitted_buffer(
                                     tx_buf,
@@ -475,6 +510,7 @@ impl<'a, U: hil::usb::UsbController<'a>> hil::usb::Client<'a> for CdcAcm<'a, U>
                     // Check if we have received at least as many bytes as the
                     // client asked for.
                     if total_received_bytes >= self.rx_len.get() {
+                        self.rx_aborted.set(false);
                         self.rx_client.map(move |client| {
                             client.received_buffer(
                                 rx_buf,
@@ -513,6 +549,7 @@ impl<'a, U: hil::usb::UsbController<'a>> hil::usb::Client<'a> for CdcAcm<'a, U>
                 // ok to signal the callback.
 
                 // Signal the callback and pass back the TX buffer.
+                self.tx_aborted.set(false);
                 self.tx_client.map(move |tx_client| {
                     tx_client.transmitted_buffer(tx_buf, self.tx_len.get(), ReturnCode::SUCCESS)
                 });
@@ -564,7 +601,14 @@ impl<'a, U: hil::usb::UsbController<'a>> uart::Transmit<'a> for CdcAcm<'a, U> {
     }
 
     fn transmit_abort(&self) -> ReturnCode {
-        ReturnCode::FAIL
+        if self.tx_buffer.is_none() {
+            return ReturnCode::SUCCESS;
+        }
+        if !self.tx_aborted.get() {
+            self.tx_aborted.set(true);
+            self.handle.map(|handle| self.deferred_caller.set(*handle));
+        }
+        ReturnCode::EBUSY
     }
 
     fn transmit_word(&self, _word: u32) -> ReturnCode {
@@ -596,7 +640,14 @@ impl<'a, U: hil::usb::UsbController<'a>> uart::Receive<'a> for CdcAcm<'a, U> {
     }
 
     fn receive_abort(&self) -> ReturnCode {
-        ReturnCode::FAIL
+        if self.rx_buffer.is_none() {
+            return ReturnCode::SUCCESS;
+        }
+        if !self.rx_aborted.get() {
+            self.rx_aborted.set(true);
+            self.handle.map(|handle| self.deferred_caller.set(*handle));
+        }
+        ReturnCode::EBUSY
     }
 
     fn receive_word(&self) -> ReturnCode {
@@ -606,3 +657,31 @@ impl<'a, U: hil::usb::UsbController<'a>> uart::Receive<'a> for CdcAcm<'a, U> {
 
 impl<'a, U: hil::usb::UsbController<'a>> uart::Uart<'a> for CdcAcm<'a, U> {}
 impl<'a, U: hil::usb::UsbController<'a>> uart::UartData<'a> for CdcAcm<'a, U> {}
+
+impl<'a, U: hil::usb::UsbController<'a>> DynamicDeferredCallClient for CdcAcm<'a, U> {
+    fn call(&self, _handle: DeferredCallHandle) {
+        if self.tx_aborted.get() {
+            self.tx_aborted.set(false);
+            // A packet already given to the controller is still sent, and
+            // counted as sent.
+            self.tx_buffer.take().map(|tx_buf| {
+                self.tx_client.map(move |client| {
+                    client.transmitted_buffer(tx_buf, self.tx_offset.get(), ReturnCode::ECANCEL)
+                });
+            });
+        }
+        if self.rx_aborted.get() {
+            self.rx_aborted.set(false);
+            self.rx_buffer.take().map(|rx_buf| {
+                self.rx_client.map(move |client| {
+                    client.received_buffer(
+                        rx_buf,
+                        self.rx_offset.get(),
+                        ReturnCode::ECANCEL,
+                        uart::Error::Aborted,
+                    )
+                });
+            });
+        }
+    }
+}
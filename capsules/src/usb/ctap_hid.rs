@@ -0,0 +1,547 @@
+//! CTAPHID transport for FIDO authenticators
+//!
+//! CTAP requests reach an authenticator as a series of 64 byte HID packets
+//! (CTAP 2.0, section 8.1). This capsule reassembles them into messages,
+//! answers the transport-level commands (`INIT`, `PING`, `WINK`) itself, and
+//! hands `MSG` (U2F) and `CBOR` (CTAP2) requests to a `CtapHandler`. The
+//! handler answers with `respond()`, and the response is split back into
+//! packets.
+//!
+//! One transaction is handled at a time. Requests on other channels are
+//! answered with `ERR_CHANNEL_BUSY` until the handler has responded. A
+//! request whose packets stop arriving for `TRANSACTION_TIMEOUT_MS` is
+//! dropped with `ERR_MSG_TIMEOUT`, and while the handler works on a request
+//! the host gets a `KEEPALIVE` every `KEEPALIVE_MS`. Single packet replies,
+//! such as those to `INIT` and errors, wait for the response packet being
+//! sent rather than being dropped.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let ctap_tx = static_init!([u8; 64], [0; 64]);
+//! let ctap_rx = static_init!([u8; 64], [0; 64]);
+//! let ctap_message = static_init!([u8; 1024], [0; 1024]);
+//! let ctap_alarm = static_init!(
+//!     capsules::virtual_alarm::VirtualMuxAlarm<'static, nrf52840::rtc::Rtc>,
+//!     capsules::virtual_alarm::VirtualMuxAlarm::new(mux_alarm)
+//! );
+//! let ctap_hid = static_init!(
+//!     capsules::usb::ctap_hid::CtapHid<
+//!         'static,
+//!         capsules::usb::ctap::UsbCtap<'static, nrf52840::usbd::Usbd<'static>>,
+//!         capsules::virtual_alarm::VirtualMuxAlarm<'static, nrf52840::rtc::Rtc>,
+//!     >,
+//!     capsules::usb::ctap_hid::CtapHid::new(ctap, ctap_alarm, ctap_tx, ctap_rx, ctap_message)
+//! );
+//! ctap.set_client(ctap_hid);
+//! ctap_alarm.set_client(ctap_hid);
+//! ctap_hid.set_handler(ctap_driver);
+//! ctap_hid.start();
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::time::{self, Alarm};
+use kernel::hil::usb_hid;
+use kernel::ReturnCode;
+
+/// CTAPHID commands.
+pub const CMD_PING: u8 = 0x81;
+pub const CMD_MSG: u8 = 0x83;
+pub const CMD_LOCK: u8 = 0x84;
+pub const CMD_INIT: u8 = 0x86;
+pub const CMD_WINK: u8 = 0x88;
+pub const CMD_CBOR: u8 = 0x90;
+pub const CMD_CANCEL: u8 = 0x91;
+pub const CMD_KEEPALIVE: u8 = 0xbb;
+pub const CMD_ERROR: u8 = 0xbf;
+
+/// CTAPHID error codes.
+const ERR_INVALID_CMD: u8 = 0x01;
+const ERR_INVALID_LEN: u8 = 0x03;
+const ERR_INVALID_SEQ: u8 = 0x04;
+const ERR_MSG_TIMEOUT: u8 = 0x05;
+const ERR_CHANNEL_BUSY: u8 = 0x06;
+const ERR_INVALID_CHANNEL: u8 = 0x0b;
+
+const BROADCAST_CID: u32 = 0xffff_ffff;
+const PACKET_LEN: usize = 64;
+/// Payload bytes in an initialization packet.
+const INIT_DATA_LEN: usize = PACKET_LEN - 7;
+/// Payload bytes in a continuation packet.
+const CONT_DATA_LEN: usize = PACKET_LEN - 5;
+
+/// Version numbers reported in the `INIT` response.
+const PROTOCOL_VERSION: u8 = 2;
+const DEVICE_VERSION: [u8; 3] = [1, 0, 0];
+/// `INIT` capability flags: WINK and CBOR.
+const CAPABILITIES: u8 = 0x01 | 0x04;
+/// The length of the `INIT` response, the longest single packet reply.
+const INIT_RESPONSE_LEN: usize = 17;
+
+/// `KEEPALIVE` status while the handler works on a request.
+const STATUS_PROCESSING: u8 = 1;
+
+/// How long the host has between the packets of a request.
+pub const TRANSACTION_TIMEOUT_MS: u32 = 500;
+/// How often the host is told that a request is still being worked on.
+pub const KEEPALIVE_MS: u32 = 100;
+
+/// How many single packet replies can wait for the transmit buffer.
+const REPLY_QUEUE_LEN: usize = 4;
+
+/// Handles the CTAP requests the transport does not answer itself.
+pub trait CtapHandler {
+    /// A `CMD_MSG` or `CMD_CBOR` request of `len` bytes is in `message`. The
+    /// handler must pass the buffer back with `CtapHid::respond()`.
+    fn message_received(&self, command: u8, message: &'static mut [u8], len: usize);
+
+    /// Called every `KEEPALIVE_MS` while the handler has a request, so that
+    /// it can respond to one it can no longer finish.
+    fn check_request(&self);
+}
+
+/// A single packet reply waiting for the transmit buffer.
+#[derive(Copy, Clone)]
+struct Reply {
+    cid: u32,
+    cmd: u8,
+    data: [u8; INIT_RESPONSE_LEN],
+    len: usize,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum State {
+    Idle,
+    /// Reassembling a request into the message buffer.
+    Receiving {
+        cid: u32,
+        cmd: u8,
+        len: usize,
+        received: usize,
+        seq: u8,
+    },
+    /// The handler has the message buffer.
+    Processing {
+        cid: u32,
+        cmd: u8,
+    },
+    /// Sending the response in the message buffer.
+    Responding {
+        cid: u32,
+        cmd: u8,
+        len: usize,
+        sent: usize,
+        packets: u8,
+    },
+}
+
+pub struct CtapHid<'a, H: usb_hid::UsbHid<'a, [u8; 64]>, A: Alarm<'a>> {
+    hid: &'a H,
+    alarm: &'a A,
+    tx_packet: TakeCell<'static, [u8; 64]>,
+    rx_packet: TakeCell<'static, [u8; 64]>,
+    message: TakeCell<'static, [u8]>,
+    state: Cell<State>,
+    next_cid: Cell<u32>,
+    /// Replies waiting for the transmit buffer, oldest first.
+    replies: Cell<[Option<Reply>; REPLY_QUEUE_LEN]>,
+    handler: OptionalCell<&'a dyn CtapHandler>,
+}
+
+impl<'a, H: usb_hid::UsbHid<'a, [u8; 64]>, A: Alarm<'a>> CtapHid<'a, H, A> {
+    pub fn new(
+        hid: &'a H,
+        alarm: &'a A,
+        tx_packet: &'static mut [u8; 64],
+        rx_packet: &'static mut [u8; 64],
+        message: &'static mut [u8],
+    ) -> CtapHid<'a, H, A> {
+        CtapHid {
+            hid: hid,
+            alarm: alarm,
+            tx_packet: TakeCell::new(tx_packet),
+            rx_packet: TakeCell::new(rx_packet),
+            message: TakeCell::new(message),
+            state: Cell::new(State::Idle),
+            next_cid: Cell::new(1),
+            replies: Cell::new([None; REPLY_QUEUE_LEN]),
+            handler: OptionalCell::empty(),
+        }
+    }
+
+    pub fn set_handler(&self, handler: &'a dyn CtapHandler) {
+        self.handler.set(handler);
+    }
+
+    /// Start listening for packets from the host.
+    pub fn start(&'a self) {
+        self.receive();
+    }
+
+    /// Send the handler's response to the request it was given, and take
+    /// back the message buffer. Returns `EINVAL` if no request is waiting
+    /// for a response and `ESIZE` if `len` is longer than `message`.
+    pub fn respond(&'a self, message: &'static mut [u8], len: usize) -> ReturnCode {
+        match self.state.get() {
+            State::Processing { cid, cmd } => {
+                if len > message.len() || len > u16::max_value() as usize {
+                    self.message.replace(message);
+                    self.state.set(State::Idle);
+                    self.send_error(cid, ERR_INVALID_LEN);
+                    return ReturnCode::ESIZE;
+                }
+                self.message.replace(message);
+                self.state.set(State::Responding {
+                    cid: cid,
+                    cmd: cmd,
+                    len: len,
+                    sent: 0,
+                    packets: 0,
+                });
+                self.send_next();
+                ReturnCode::SUCCESS
+            }
+            _ => {
+                self.message.replace(message);
+                ReturnCode::EINVAL
+            }
+        }
+    }
+
+    fn receive(&'a self) {
+        self.rx_packet.take().map(|rx| {
+            if let Err((_, rx)) = self.hid.receive_buffer(rx) {
+                self.rx_packet.replace(rx);
+            }
+        });
+    }
+
+    fn send_packet<F>(&self, fill: F) -> bool
+    where
+        F: FnOnce(&mut [u8; 64]),
+    {
+        self.tx_packet.take().map_or(false, |tx| {
+            *tx = [0; PACKET_LEN];
+            fill(tx);
+            match self.hid.send_buffer(tx) {
+                Ok(_) => true,
+                Err((_, tx)) => {
+                    self.tx_packet.replace(tx);
+                    false
+                }
+            }
+        })
+    }
+
+    fn set_timeout(&self, ms: u32) {
+        self.alarm.set_alarm(self.alarm.now(), A::ticks_from_ms(ms));
+    }
+
+    /// Send a single packet response of `data` on channel `cid`, or queue it
+    /// while another packet is being sent. Replies that find the queue full
+    /// are dropped, and the host retries.
+    fn send_reply(&self, cid: u32, cmd: u8, data: &[u8]) {
+        let mut reply = Reply {
+            cid: cid,
+            cmd: cmd,
+            data: [0; INIT_RESPONSE_LEN],
+            len: data.len(),
+        };
+        reply.data[..data.len()].copy_from_slice(data);
+        if self.tx_packet.is_some() {
+            self.send_single(&reply);
+        } else {
+            let mut replies = self.replies.get();
+            if let Some(slot) = replies.iter_mut().find(|slot| slot.is_none()) {
+                *slot = Some(reply);
+            }
+            self.replies.set(replies);
+        }
+    }
+
+    fn send_single(&self, reply: &Reply) -> bool {
+        self.send_packet(|packet| {
+            packet[0..4].copy_from_slice(&reply.cid.to_be_bytes());
+            packet[4] = reply.cmd;
+            packet[5..7].copy_from_slice(&(reply.len as u16).to_be_bytes());
+            packet[7..7 + reply.len].copy_from_slice(&reply.data[..reply.len]);
+        })
+    }
+
+    /// Send the oldest queued reply. Returns whether a packet is on its way.
+    fn send_queued_reply(&self) -> bool {
+        let mut replies = self.replies.get();
+        let reply = replies[0].take();
+        replies.rotate_left(1);
+        self.replies.set(replies);
+        reply.map_or(false, |reply| self.send_single(&reply))
+    }
+
+    fn send_error(&self, cid: u32, error: u8) {
+        self.send_reply(cid, CMD_ERROR, &[error]);
+    }
+
+    /// Send the next packet of the response, if the last one has gone.
+    fn send_next(&'a self) {
+        if let State::Responding {
+            cid,
+            cmd,
+            len,
+            sent,
+            packets,
+        } = self.state.get()
+        {
+            if packets > 0 && sent >= len {
+                self.state.set(State::Idle);
+                return;
+            }
+            let message = match self.message.take() {
+                Some(message) => message,
+                None => return,
+            };
+            let mut chunk = 0;
+            let queued = self.send_packet(|packet| {
+                packet[0..4].copy_from_slice(&cid.to_be_bytes());
+                let start = if packets == 0 {
+                    packet[4] = cmd;
+                    packet[5..7].copy_from_slice(&(len as u16).to_be_bytes());
+                    7
+                } else {
+                    packet[4] = packets - 1;
+                    5
+                };
+                chunk = cmp::min(PACKET_LEN - start, len - sent);
+                packet[start..start + chunk].copy_from_slice(&message[sent..sent + chunk]);
+            });
+            self.message.replace(message);
+            if queued {
+                self.state.set(State::Responding {
+                    cid: cid,
+                    cmd: cmd,
+                    len: len,
+                    sent: sent + chunk,
+                    packets: packets + 1,
+                });
+            }
+        }
+    }
+
+    /// A whole request is in the message buffer.
+    fn dispatch(&'a self, cid: u32, cmd: u8, len: usize) {
+        match cmd {
+            CMD_PING => {
+                // Echo the request back.
+                self.state.set(State::Responding {
+                    cid: cid,
+                    cmd: cmd,
+                    len: len,
+                    sent: 0,
+                    packets: 0,
+                });
+                self.send_next();
+            }
+            _ => match (self.handler.map(|handler| *handler), self.message.take()) {
+                (Some(handler), Some(message)) => {
+                    self.state.set(State::Processing { cid: cid, cmd: cmd });
+                    self.set_timeout(KEEPALIVE_MS);
+                    handler.message_received(cmd, message, len);
+                }
+                (_, message) => {
+                    message.map(|message| self.message.replace(message));
+                    self.state.set(State::Idle);
+                    self.send_error(cid, ERR_INVALID_CMD);
+                }
+            },
+        }
+    }
+
+    /// Copy payload from `data` into the message buffer at `offset`.
+    fn store(&self, offset: usize, data: &[u8]) {
+        self.message.map(|message| {
+            message[offset..offset + data.len()].copy_from_slice(data);
+        });
+    }
+
+    fn handle_init(&'a self, cid: u32, packet: &[u8; 64]) {
+        let len = u16::from_be_bytes([packet[5], packet[6]]) as usize;
+        if len != 8 {
+            self.send_error(cid, ERR_INVALID_LEN);
+            return;
+        }
+        // INIT on a channel resynchronises it, abandoning any partial request.
+        let new_cid = if cid == BROADCAST_CID {
+            let new_cid = self.next_cid.get();
+            self.next_cid.set(match new_cid.wrapping_add(1) {
+                0 | BROADCAST_CID => 1,
+                next => next,
+            });
+            new_cid
+        } else {
+            if let State::Receiving { cid: current, .. } = self.state.get() {
+                if current == cid {
+                    self.state.set(State::Idle);
+                }
+            }
+            cid
+        };
+        let mut response = [0; INIT_RESPONSE_LEN];
+        response[0..8].copy_from_slice(&packet[7..15]);
+        response[8..12].copy_from_slice(&new_cid.to_be_bytes());
+        response[12] = PROTOCOL_VERSION;
+        response[13..16].copy_from_slice(&DEVICE_VERSION);
+        response[16] = CAPABILITIES;
+        self.send_reply(cid, CMD_INIT, &response);
+    }
+
+    fn handle_packet(&'a self, packet: &[u8; 64]) {
+        let cid = u32::from_be_bytes([packet[0], packet[1], packet[2], packet[3]]);
+        let state = self.state.get();
+
+        if packet[4] & 0x80 == 0 {
+            // Continuation packet.
+            if let State::Receiving {
+                cid: current,
+                cmd,
+                len,
+                received,
+                seq,
+            } = state
+            {
+                if cid != current {
+                    return;
+                }
+                if packet[4] != seq {
+                    self.state.set(State::Idle);
+                    self.send_error(cid, ERR_INVALID_SEQ);
+                    return;
+                }
+                let chunk = cmp::min(CONT_DATA_LEN, len - received);
+                self.store(received, &packet[5..5 + chunk]);
+                if received + chunk == len {
+                    self.dispatch(cid, cmd, len);
+                } else {
+                    self.state.set(State::Receiving {
+                        cid: cid,
+                        cmd: cmd,
+                        len: len,
+                        received: received + chunk,
+                        seq: seq + 1,
+                    });
+                    self.set_timeout(TRANSACTION_TIMEOUT_MS);
+                }
+            }
+            return;
+        }
+
+        let cmd = packet[4];
+        if cmd == CMD_INIT {
+            self.handle_init(cid, packet);
+            return;
+        }
+        if cid == 0 || cid == BROADCAST_CID {
+            self.send_error(cid, ERR_INVALID_CHANNEL);
+            return;
+        }
+        match state {
+            State::Idle => {}
+            State::Receiving { cid: current, .. } if current == cid => {
+                // A new request before the last one was complete.
+                self.state.set(State::Idle);
+                self.send_error(cid, ERR_INVALID_SEQ);
+                return;
+            }
+            State::Processing { cid: current, .. } if current == cid && cmd == CMD_CANCEL => {
+                // The handler finishes the request regardless.
+                return;
+            }
+            _ => {
+                self.send_error(cid, ERR_CHANNEL_BUSY);
+                return;
+            }
+        }
+
+        match cmd {
+            CMD_PING | CMD_MSG | CMD_CBOR => {
+                let len = u16::from_be_bytes([packet[5], packet[6]]) as usize;
+                if len > self.message.map_or(0, |message| message.len()) {
+                    self.send_error(cid, ERR_INVALID_LEN);
+                    return;
+                }
+                let chunk = cmp::min(INIT_DATA_LEN, len);
+                self.store(0, &packet[7..7 + chunk]);
+                if chunk == len {
+                    self.dispatch(cid, cmd, len);
+                } else {
+                    self.state.set(State::Receiving {
+                        cid: cid,
+                        cmd: cmd,
+                        len: len,
+                        received: chunk,
+                        seq: 0,
+                    });
+                    self.set_timeout(TRANSACTION_TIMEOUT_MS);
+                }
+            }
+            CMD_WINK => self.send_reply(cid, CMD_WINK, &[]),
+            CMD_CANCEL => {}
+            _ => self.send_error(cid, ERR_INVALID_CMD),
+        }
+    }
+}
+
+impl<'a, H: usb_hid::UsbHid<'a, [u8; 64]>, A: Alarm<'a>> time::AlarmClient for CtapHid<'a, H, A> {
+    fn alarm(&self) {
+        match self.state.get() {
+            State::Receiving { cid, .. } => {
+                self.state.set(State::Idle);
+                self.send_error(cid, ERR_MSG_TIMEOUT);
+            }
+            State::Processing { cid, .. } => {
+                // Skipped while another packet is being sent: the host only
+                // needs one now and then.
+                if self.tx_packet.is_some() {
+                    self.send_single(&Reply {
+                        cid: cid,
+                        cmd: CMD_KEEPALIVE,
+                        data: [STATUS_PROCESSING; INIT_RESPONSE_LEN],
+                        len: 1,
+                    });
+                }
+                self.handler.map(|handler| handler.check_request());
+                if let State::Processing { .. } = self.state.get() {
+                    self.set_timeout(KEEPALIVE_MS);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl<'a, H: usb_hid::UsbHid<'a, [u8; 64]>, A: Alarm<'a>> usb_hid::Client<'a, [u8; 64]>
+    for CtapHid<'a, H, A>
+{
+    fn packet_received(&'a self, result: ReturnCode, buffer: &'static mut [u8; 64], _: usize) {
+        if result == ReturnCode::SUCCESS {
+            self.handle_packet(buffer);
+        }
+        self.rx_packet.replace(buffer);
+        self.receive();
+    }
+
+    fn packet_transmitted(&'a self, _: ReturnCode, buffer: &'static mut [u8; 64], _: usize) {
+        self.tx_packet.replace(buffer);
+        if !self.send_queued_reply() {
+            self.send_next();
+        }
+    }
+
+    fn can_receive(&'a self) -> bool {
+        self.rx_packet.is_none()
+    }
+}
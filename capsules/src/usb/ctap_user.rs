@@ -0,0 +1,208 @@
+//! System call interface for a FIDO authenticator
+//!
+//! Hands the CTAP requests `ctap_hid` reassembles to an app, which holds the
+//! credentials and produces the responses. Tock has no key store or
+//! signature capsule to do this in the kernel, so an app such as OpenSK
+//! provides the CTAP2 logic and the cryptography.
+//!
+//! ## Instantiation
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let ctap_driver = static_init!(
+//!     capsules::usb::ctap_user::CtapSyscallDriver<
+//!         'static,
+//!         capsules::usb::ctap::UsbCtap<'static, nrf52840::usbd::Usbd<'static>>,
+//!         capsules::virtual_alarm::VirtualMuxAlarm<'static, nrf52840::rtc::Rtc>,
+//!     >,
+//!     capsules::usb::ctap_user::CtapSyscallDriver::new(
+//!         ctap_hid, board_kernel.create_grant(&grant_cap)));
+//! ctap_hid.set_handler(ctap_driver);
+//! ```
+//!
+//! ## Syscall Interface
+//!
+//! - allow `0`: buffer requests are copied into.
+//! - allow `1`: buffer the response is read from.
+//! - subscribe `0`: a request arrived, `fn(command, len, 0)`, where `command`
+//!   is `0x83` for a U2F message or `0x90` for a CTAP2 CBOR message.
+//! - command `0`: driver check.
+//! - command `1`: send the first `data` bytes of the response buffer as the
+//!   response to the current request.
+//!
+//! Requests go to the first app with both a callback and a large enough
+//! request buffer. If there is none, or the app serving a request exits
+//! before it responds, the request is answered with an error.
+
+use core::cell::Cell;
+
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::time::Alarm;
+use kernel::hil::usb_hid;
+use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
+
+use super::ctap_hid::{CtapHandler, CtapHid, CMD_CBOR};
+
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::Ctap as usize;
+
+/// CTAP2 status for an unsupported command.
+const CTAP1_ERR_INVALID_COMMAND: u8 = 0x01;
+/// U2F status word for an unsupported instruction.
+const SW_INS_NOT_SUPPORTED: [u8; 2] = [0x6d, 0x00];
+
+#[derive(Default)]
+pub struct App {
+    callback: Option<Callback>,
+    request: Option<AppSlice<Shared, u8>>,
+    response: Option<AppSlice<Shared, u8>>,
+}
+
+pub struct CtapSyscallDriver<'a, H: usb_hid::UsbHid<'a, [u8; 64]>, A: Alarm<'a>> {
+    ctap: &'a CtapHid<'a, H, A>,
+    apps: Grant<App>,
+    /// The message buffer, while an app works on the request in it.
+    message: TakeCell<'static, [u8]>,
+    /// The command of the request.
+    command: Cell<u8>,
+    serving_app: OptionalCell<AppId>,
+}
+
+impl<'a, H: usb_hid::UsbHid<'a, [u8; 64]>, A: Alarm<'a>> CtapSyscallDriver<'a, H, A> {
+    pub fn new(ctap: &'a CtapHid<'a, H, A>, apps: Grant<App>) -> Self {
+        CtapSyscallDriver {
+            ctap: ctap,
+            apps: apps,
+            message: TakeCell::empty(),
+            command: Cell::new(0),
+            serving_app: OptionalCell::empty(),
+        }
+    }
+
+    /// Answer a request no app can serve with an error in the protocol of
+    /// `command`.
+    fn respond_with_error(&self, command: u8, message: &'static mut [u8]) {
+        let error: &[u8] = if command == CMD_CBOR {
+            &[CTAP1_ERR_INVALID_COMMAND]
+        } else {
+            &SW_INS_NOT_SUPPORTED
+        };
+        message[..error.len()].copy_from_slice(error);
+        self.ctap.respond(message, error.len());
+    }
+
+    fn respond(&self, appid: AppId, len: usize) -> ReturnCode {
+        if !self.serving_app.contains(&appid) {
+            return ReturnCode::EINVAL;
+        }
+        self.apps
+            .enter(appid, |app, _| {
+                let response = match app.response {
+                    Some(ref response) if response.len() >= len => response,
+                    _ => return ReturnCode::ESIZE,
+                };
+                self.message.take().map_or(ReturnCode::FAIL, |message| {
+                    if len > message.len() {
+                        self.message.replace(message);
+                        return ReturnCode::ESIZE;
+                    }
+                    message[..len].copy_from_slice(&response.as_ref()[..len]);
+                    self.serving_app.clear();
+                    self.ctap.respond(message, len)
+                })
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+}
+
+impl<'a, H: usb_hid::UsbHid<'a, [u8; 64]>, A: Alarm<'a>> CtapHandler
+    for CtapSyscallDriver<'a, H, A>
+{
+    fn message_received(&self, command: u8, message: &'static mut [u8], len: usize) {
+        let mut served = None;
+        for cntr in self.apps.iter() {
+            if served.is_some() {
+                break;
+            }
+            cntr.enter(|app, _| match (app.callback, app.request.as_mut()) {
+                (Some(mut callback), Some(request)) if request.len() >= len => {
+                    request.as_mut()[..len].copy_from_slice(&message[..len]);
+                    callback.schedule(command as usize, len, 0);
+                    served = Some(app.appid());
+                }
+                _ => {}
+            });
+        }
+
+        match served {
+            Some(appid) => {
+                self.serving_app.set(appid);
+                self.command.set(command);
+                self.message.replace(message);
+            }
+            None => self.respond_with_error(command, message),
+        }
+    }
+
+    fn check_request(&self) {
+        let exited = self
+            .serving_app
+            .map_or(false, |appid| self.apps.enter(*appid, |_, _| ()).is_err());
+        if exited {
+            self.serving_app.clear();
+            if let Some(message) = self.message.take() {
+                self.respond_with_error(self.command.get(), message);
+            }
+        }
+    }
+}
+
+impl<'a, H: usb_hid::UsbHid<'a, [u8; 64]>, A: Alarm<'a>> Driver for CtapSyscallDriver<'a, H, A> {
+    fn allow(
+        &self,
+        appid: AppId,
+        allow_num: usize,
+        slice: Option<AppSlice<Shared, u8>>,
+    ) -> ReturnCode {
+        self.apps
+            .enter(appid, |app, _| match allow_num {
+                0 => {
+                    app.request = slice;
+                    ReturnCode::SUCCESS
+                }
+                1 => {
+                    app.response = slice;
+                    ReturnCode::SUCCESS
+                }
+                _ => ReturnCode::ENOSUPPORT,
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        callback: Option<Callback>,
+        appid: AppId,
+    ) -> ReturnCode {
+        match subscribe_num {
+            0 => self
+                .apps
+                .enter(appid, |app, _| {
+                    app.callback = callback;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    fn command(&self, command_num: usize, data: usize, _: usize, appid: AppId) -> ReturnCode {
+        match command_num {
+            0 => ReturnCode::SUCCESS,
+            1 => self.respond(appid, data),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
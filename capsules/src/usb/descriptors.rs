@@ -384,6 +384,34 @@ pub struct DeviceBuffer {
 }
 
 impl DeviceBuffer {
+    fn empty() -> Self {
+        // Cell doesn't implement Copy, so here we are.
+        DeviceBuffer {
+            buf: [
+                Cell::default(),
+                Cell::default(),
+                Cell::default(),
+                Cell::default(),
+                Cell::default(),
+                Cell::default(),
+                Cell::default(),
+                Cell::default(),
+                Cell::default(),
+                Cell::default(),
+                Cell::default(),
+                Cell::default(),
+                Cell::default(),
+                Cell::default(),
+                Cell::default(),
+                Cell::default(),
+                Cell::default(),
+                Cell::default(),
+                Cell::default(),
+            ],
+            len: 0,
+        }
+    }
+
     pub fn write_to(&self, buf: &[Cell<u8>]) -> usize {
         for i in 0..self.len {
             buf[i].set(self.buf[i].get());
@@ -400,6 +428,44 @@ pub struct DescriptorBuffer {
 }
 
 impl DescriptorBuffer {
+    fn empty() -> Self {
+        // For the moment, the Default trait is not implemented for arrays
+        // of length > 32, and the Cell type is not Copy, so we have to
+        // initialize each element manually.
+        DescriptorBuffer {
+            #[rustfmt::skip]
+            buf: [
+                Cell::default(), Cell::default(), Cell::default(), Cell::default(), Cell::default(),
+                Cell::default(), Cell::default(), Cell::default(), Cell::default(), Cell::default(),
+                Cell::default(), Cell::default(), Cell::default(), Cell::default(), Cell::default(),
+                Cell::default(), Cell::default(), Cell::default(), Cell::default(), Cell::default(),
+                Cell::default(), Cell::default(), Cell::default(), Cell::default(), Cell::default(),
+                Cell::default(), Cell::default(), Cell::default(), Cell::default(), Cell::default(),
+                Cell::default(), Cell::default(), Cell::default(), Cell::default(), Cell::default(),
+                Cell::default(), Cell::default(), Cell::default(), Cell::default(), Cell::default(),
+                Cell::default(), Cell::default(), Cell::default(), Cell::default(), Cell::default(),
+                Cell::default(), Cell::default(), Cell::default(), Cell::default(), Cell::default(),
+                Cell::default(), Cell::default(), Cell::default(), Cell::default(), Cell::default(),
+                Cell::default(), Cell::default(), Cell::default(), Cell::default(), Cell::default(),
+                Cell::default(), Cell::default(), Cell::default(), Cell::default(), Cell::default(),
+                Cell::default(), Cell::default(), Cell::default(), Cell::default(), Cell::default(),
+                Cell::default(), Cell::default(), Cell::default(), Cell::default(), Cell::default(),
+                Cell::default(), Cell::default(), Cell::default(), Cell::default(), Cell::default(),
+                Cell::default(), Cell::default(), Cell::default(), Cell::default(), Cell::default(),
+                Cell::default(), Cell::default(), Cell::default(), Cell::default(), Cell::default(),
+                Cell::default(), Cell::default(), Cell::default(), Cell::default(), Cell::default(),
+                Cell::default(), Cell::default(), Cell::default(), Cell::default(), Cell::default(),
+                Cell::default(), Cell::default(), Cell::default(), Cell::default(), Cell::default(),
+                Cell::default(), Cell::default(), Cell::default(), Cell::default(), Cell::default(),
+                Cell::default(), Cell::default(), Cell::default(), Cell::default(), Cell::default(),
+                Cell::default(), Cell::default(), Cell::default(), Cell::default(), Cell::default(),
+                Cell::default(), Cell::default(), Cell::default(), Cell::default(), Cell::default(),
+                Cell::default(), Cell::default(), Cell::default(),
+            ],
+            len: 0,
+        }
+    }
+
     pub fn write_to(&self, buf: &[Cell<u8>]) -> usize {
         for i in 0..self.len {
             buf[i].set(self.buf[i].get());
@@ -422,71 +488,14 @@ pub fn create_descriptor_buffers(
     endpoint_descriptors: &[&[EndpointDescriptor]],
     hid_descriptor: Option<&HIDDescriptor>,
     cdc_descriptor: Option<&[CdcInterfaceDescriptor]>,
+    dfu_descriptor: Option<&DfuFunctionalDescriptor>,
 ) -> (DeviceBuffer, DescriptorBuffer) {
     // Create device descriptor buffer and fill.
-    // Cell doesn't implement Copy, so here we are.
-    let mut dev_buf = DeviceBuffer {
-        buf: [
-            Cell::default(),
-            Cell::default(),
-            Cell::default(),
-            Cell::default(),
-            Cell::default(),
-            Cell::default(),
-            Cell::default(),
-            Cell::default(),
-            Cell::default(),
-            Cell::default(),
-            Cell::default(),
-            Cell::default(),
-            Cell::default(),
-            Cell::default(),
-            Cell::default(),
-            Cell::default(),
-            Cell::default(),
-            Cell::default(),
-            Cell::default(),
-        ],
-        len: 0,
-    };
+    let mut dev_buf = DeviceBuffer::empty();
     dev_buf.len = device_descriptor.write_to(&dev_buf.buf);
 
     // Create other descriptors buffer.
-    // For the moment, the Default trait is not implemented for arrays
-    // of length > 32, and the Cell type is not Copy, so we have to
-    // initialize each element manually.
-    let mut other_buf = DescriptorBuffer {
-        #[rustfmt::skip]
-        buf: [
-            Cell::default(), Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-            Cell::default(), Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-            Cell::default(), Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-            Cell::default(), Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-            Cell::default(), Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-            Cell::default(), Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-            Cell::default(), Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-            Cell::default(), Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-            Cell::default(), Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-            Cell::default(), Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-            Cell::default(), Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-            Cell::default(), Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-            Cell::default(), Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-            Cell::default(), Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-            Cell::default(), Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-            Cell::default(), Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-            Cell::default(), Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-            Cell::default(), Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-            Cell::default(), Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-            Cell::default(), Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-            Cell::default(), Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-            Cell::default(), Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-            Cell::default(), Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-            Cell::default(), Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-            Cell::default(), Cell::default(), Cell::default(), Cell::default(), Cell::default(),
-            Cell::default(), Cell::default(), Cell::default(),
-        ],
-        len: 0,
-    };
+    let mut other_buf = DescriptorBuffer::empty();
 
     // Setup certain descriptor fields since now we know the tree of
     // descriptors.
@@ -505,7 +514,8 @@ pub fn create_descriptor_buffers(
                 .map(|descs| descs.iter().map(|d| d.size()).sum::<usize>())
                 .sum::<usize>()
             + hid_descriptor.map_or(0, |d| d.size())
-            + cdc_descriptor.map_or(0, |ds| ds.iter().map(|d| d.size()).sum::<usize>());
+            + cdc_descriptor.map_or(0, |ds| ds.iter().map(|d| d.size()).sum::<usize>())
+            + dfu_descriptor.map_or(0, |d| d.size());
 
     // Set the number of endpoints for each interface descriptor.
     for (i, d) in interface_descriptor.iter_mut().enumerate() {
@@ -541,6 +551,15 @@ pub fn create_descriptor_buffers(
             }
         }
 
+        // If there is a DFU functional descriptor, we include
+        // it with the first interface descriptor.
+        if i == 0 {
+            // DFU descriptor, if any.
+            if let Some(ddfu) = dfu_descriptor {
+                len += ddfu.write_to(&other_buf.buf[len..]);
+            }
+        }
+
         // Endpoints for each interface.
         for de in endpoint_descriptors[i] {
             len += de.write_to(&other_buf.buf[len..]);
@@ -552,6 +571,28 @@ pub fn create_descriptor_buffers(
     (dev_buf, other_buf)
 }
 
+/// Create descriptor buffers for a configuration given as raw bytes, for
+/// classes such as USB MIDI that interleave class-specific descriptors with
+/// the interfaces and endpoints in a way `create_descriptor_buffers()` cannot
+/// express. `configuration` starts with the configuration descriptor and
+/// holds all descriptors returned with it, so it must carry the right total
+/// length and number of interfaces itself.
+pub fn create_raw_descriptor_buffers(
+    device_descriptor: DeviceDescriptor,
+    configuration: &[u8],
+) -> (DeviceBuffer, DescriptorBuffer) {
+    let mut dev_buf = DeviceBuffer::empty();
+    dev_buf.len = device_descriptor.write_to(&dev_buf.buf);
+
+    let mut other_buf = DescriptorBuffer::empty();
+    for (cell, byte) in other_buf.buf.iter().zip(configuration.iter()) {
+        cell.set(*byte);
+    }
+    other_buf.len = min(configuration.len(), other_buf.buf.len());
+
+    (dev_buf, other_buf)
+}
+
 pub struct ConfigurationDescriptor {
     pub num_interfaces: u8,
     pub configuration_value: u8,
@@ -884,6 +925,41 @@ impl CdcAcmSetLineCodingData {
     }
 }
 
+//
+// For DFU
+//
+
+/// Descriptor type of the DFU functional descriptor. It shares its value with
+/// the HID descriptor, which is fine since they belong to different classes.
+const DFU_FUNCTIONAL_DESCRIPTOR_TYPE: u8 = 0x21;
+
+pub struct DfuFunctionalDescriptor {
+    /// `bmAttributes`: bit 0 can download, bit 1 can upload, bit 2
+    /// manifestation tolerant, bit 3 will detach.
+    pub attributes: u8,
+    /// Time in ms the device waits for a bus reset after a `DFU_DETACH`.
+    pub detach_timeout: u16,
+    /// Maximum number of bytes in a single download or upload request.
+    pub transfer_size: u16,
+    pub dfu_version: u16,
+}
+
+impl Descriptor for DfuFunctionalDescriptor {
+    fn size(&self) -> usize {
+        9
+    }
+
+    fn write_to_unchecked(&self, buf: &[Cell<u8>]) -> usize {
+        buf[0].set(9); // Size of descriptor
+        buf[1].set(DFU_FUNCTIONAL_DESCRIPTOR_TYPE);
+        buf[2].set(self.attributes);
+        put_u16(&buf[3..5], self.detach_timeout);
+        put_u16(&buf[5..7], self.transfer_size);
+        put_u16(&buf[7..9], self.dfu_version);
+        9
+    }
+}
+
 pub struct LanguagesDescriptor<'a> {
     pub langs: &'a [u16],
 }
@@ -0,0 +1,500 @@
+//! Device Firmware Upgrade class for USB
+//!
+//! This capsule lets a host download a firmware image with a standard tool
+//! such as `dfu-util`. The image is written to a staging region of a
+//! `NonvolatileStorage` device, typically the inactive bank of an A/B flash
+//! layout, and the `DfuClient` is told once the download is complete so that
+//! it can check the image and arrange to boot it.
+//!
+//! The board usually runs another USB class, such as the CDC-ACM console,
+//! and only switches to DFU when asked to. `enter_dfu_mode()` detaches that
+//! device from the bus, takes over the USB controller and enumerates as a
+//! device in DFU mode. The `dfu` command of the process console (see
+//! `ProcessConsole::set_dfu()`) and a `DfuButton` both call it. A board that
+//! has no other use for USB can call it at boot.
+//!
+//! Only downloads are supported. The device is manifestation tolerant, so it
+//! stays in DFU mode after an image was downloaded, and a bus reset does not
+//! return it to the runtime device.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let dfu_buffer = static_init!([u8; 1024], [0; 1024]);
+//! let dfu = static_init!(
+//!     capsules::usb::dfu::UsbDfu<'static, nrf52840::usbd::Usbd<'static>>,
+//!     capsules::usb::dfu::UsbDfu::new(
+//!         &nrf52840::usbd::USBD,
+//!         capsules::usb::dfu::MAX_CTRL_PACKET_SIZE_NRF52840,
+//!         0x1915,
+//!         0x521f,
+//!         strings,
+//!         nv_to_page,
+//!         0x80000, // Start of the staging region
+//!         0x40000, // Length of the staging region
+//!         dfu_buffer,
+//!     )
+//! );
+//! nv_to_page.set_client(dfu);
+//! process_console.set_dfu(dfu);
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+
+use super::descriptors;
+use super::descriptors::DfuFunctionalDescriptor;
+use super::descriptors::InterfaceDescriptor;
+use super::descriptors::RequestType;
+use super::descriptors::SetupData;
+use super::usbc_client_ctrl::ClientCtrl;
+
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil;
+use kernel::hil::nonvolatile_storage::{NonvolatileStorage, NonvolatileStorageClient};
+use kernel::hil::usb::TransferType;
+use kernel::ReturnCode;
+
+static LANGUAGES: &'static [u16; 1] = &[
+    0x0409, // English (United States)
+];
+/// Platform-specific packet length for the `SAM4L` USB hardware.
+pub const MAX_CTRL_PACKET_SIZE_SAM4L: u8 = 8;
+/// Platform-specific packet length for the `nRF52` USB hardware.
+pub const MAX_CTRL_PACKET_SIZE_NRF52840: u8 = 64;
+/// Platform-specific packet length for the `earlgrey` USB hardware.
+pub const MAX_CTRL_PACKET_SIZE_EARLGREY: u8 = 64;
+
+/// How long the host should wait before asking again while a block is being
+/// written, in ms.
+const POLL_TIMEOUT_MS: u32 = 10;
+
+/// DFU class requests.
+const REQUEST_DETACH: u8 = 0;
+const REQUEST_DNLOAD: u8 = 1;
+const REQUEST_GETSTATUS: u8 = 3;
+const REQUEST_CLRSTATUS: u8 = 4;
+const REQUEST_GETSTATE: u8 = 5;
+const REQUEST_ABORT: u8 = 6;
+
+/// Device states, as reported by `DFU_GETSTATUS` and `DFU_GETSTATE`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum State {
+    Idle = 2,
+    /// A block was received and is being written, or has been.
+    DnloadSync = 3,
+    /// A block is being written, and the host was told to wait.
+    DnBusy = 4,
+    /// Waiting for the next block.
+    DnloadIdle = 5,
+    /// The host signalled the end of the image.
+    ManifestSync = 6,
+    Error = 10,
+}
+
+/// Status codes, as reported by `DFU_GETSTATUS`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Status {
+    Ok = 0x00,
+    ErrWrite = 0x03,
+    ErrAddress = 0x08,
+    ErrStalledPkt = 0x0f,
+}
+
+/// What the control endpoint is doing for a DFU request.
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum CtrlState {
+    /// Not handling a DFU request.
+    Idle,
+    /// Receiving a block of the given length.
+    Download(usize),
+    /// Sending the given number of bytes of `response`.
+    Respond(usize),
+}
+
+/// Told when the host has downloaded an image.
+pub trait DfuClient {
+    /// The staging region holds a complete image of `length` bytes.
+    fn image_received(&self, length: usize);
+}
+
+/// Switches the USB device into DFU mode.
+pub trait DfuMode<'a> {
+    fn enter_dfu_mode(&'a self);
+}
+
+pub struct UsbDfu<'a, U: 'a> {
+    /// Helper USB client library for handling many USB operations.
+    client_ctrl: ClientCtrl<'a, 'static, U>,
+
+    storage: &'a dyn NonvolatileStorage<'static>,
+    /// Address and length of the staging region in `storage`.
+    base: usize,
+    length: usize,
+
+    client: OptionalCell<&'a dyn DfuClient>,
+
+    /// Whether we have taken over the USB controller.
+    active: Cell<bool>,
+    state: Cell<State>,
+    status: Cell<Status>,
+    ctrl_state: Cell<CtrlState>,
+    response: Cell<[u8; 6]>,
+
+    /// Holds the block being received. It is empty while the block is being
+    /// written.
+    buffer: TakeCell<'static, [u8]>,
+    /// Bytes of the current block received so far.
+    received: Cell<usize>,
+    /// Bytes of the image written so far.
+    offset: Cell<usize>,
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> UsbDfu<'a, U> {
+    pub fn new(
+        controller: &'a U,
+        max_ctrl_packet_size: u8,
+        vendor_id: u16,
+        product_id: u16,
+        strings: &'static [&'static str; 3],
+        storage: &'a dyn NonvolatileStorage<'static>,
+        base: usize,
+        length: usize,
+        buffer: &'static mut [u8],
+    ) -> Self {
+        let interfaces: &mut [InterfaceDescriptor] = &mut [InterfaceDescriptor {
+            interface_number: 0,
+            interface_class: 0xfe,    // Application specific
+            interface_subclass: 0x01, // Device firmware upgrade
+            interface_protocol: 0x02, // DFU mode
+            ..InterfaceDescriptor::default()
+        }];
+
+        let dfu_descriptor = DfuFunctionalDescriptor {
+            attributes: 0b0101, // Can download, manifestation tolerant
+            detach_timeout: 0,
+            transfer_size: cmp::min(buffer.len(), u16::MAX as usize) as u16,
+            dfu_version: 0x0110,
+        };
+
+        let (device_descriptor_buffer, other_descriptor_buffer) =
+            descriptors::create_descriptor_buffers(
+                descriptors::DeviceDescriptor {
+                    vendor_id: vendor_id,
+                    product_id: product_id,
+                    manufacturer_string: 1,
+                    product_string: 2,
+                    serial_number_string: 3,
+                    class: 0x0, // Class given by the interface
+                    max_packet_size_ep0: max_ctrl_packet_size,
+                    ..descriptors::DeviceDescriptor::default()
+                },
+                descriptors::ConfigurationDescriptor {
+                    ..descriptors::ConfigurationDescriptor::default()
+                },
+                interfaces,
+                &[&[]], // Only the control endpoint
+                None,   // No HID descriptor
+                None,   // No CDC descriptor array
+                Some(&dfu_descriptor),
+            );
+
+        UsbDfu {
+            client_ctrl: ClientCtrl::new(
+                controller,
+                device_descriptor_buffer,
+                other_descriptor_buffer,
+                None, // No HID descriptor
+                None, // No report descriptor
+                LANGUAGES,
+                strings,
+            ),
+            storage: storage,
+            base: base,
+            length: length,
+            client: OptionalCell::empty(),
+            active: Cell::new(false),
+            state: Cell::new(State::Idle),
+            status: Cell::new(Status::Ok),
+            ctrl_state: Cell::new(CtrlState::Idle),
+            response: Cell::new([0; 6]),
+            buffer: TakeCell::new(buffer),
+            received: Cell::new(0),
+            offset: Cell::new(0),
+        }
+    }
+
+    pub fn set_client(&self, client: &'a dyn DfuClient) {
+        self.client.set(client);
+    }
+
+    #[inline]
+    fn controller(&self) -> &'a U {
+        self.client_ctrl.controller()
+    }
+
+    /// Go to the error state. The request that failed is stalled.
+    fn fail(&self, status: Status) -> hil::usb::CtrlSetupResult {
+        self.state.set(State::Error);
+        self.status.set(status);
+        hil::usb::CtrlSetupResult::ErrGeneric
+    }
+
+    /// Arrange for `data` to be sent in the data stage of this request.
+    fn respond(&self, data: &[u8]) -> hil::usb::CtrlSetupResult {
+        let mut response = [0; 6];
+        response[..data.len()].copy_from_slice(data);
+        self.response.set(response);
+        self.ctrl_state.set(CtrlState::Respond(data.len()));
+        hil::usb::CtrlSetupResult::Ok
+    }
+
+    fn handle_request(&self, setup_data: SetupData) -> hil::usb::CtrlSetupResult {
+        let state = self.state.get();
+        match setup_data.request_code {
+            REQUEST_DNLOAD => {
+                let len = setup_data.length as usize;
+                match state {
+                    State::DnloadIdle if len == 0 => {
+                        self.state.set(State::ManifestSync);
+                        hil::usb::CtrlSetupResult::Ok
+                    }
+                    State::Idle | State::DnloadIdle
+                        if len > 0 && self.buffer.map_or(false, |buffer| len <= buffer.len()) =>
+                    {
+                        if state == State::Idle {
+                            self.offset.set(0);
+                        }
+                        self.received.set(0);
+                        self.ctrl_state.set(CtrlState::Download(len));
+                        hil::usb::CtrlSetupResult::Ok
+                    }
+                    _ => self.fail(Status::ErrStalledPkt),
+                }
+            }
+            REQUEST_GETSTATUS => {
+                let mut poll_timeout = 0;
+                match state {
+                    State::DnloadSync | State::DnBusy => {
+                        if self.buffer.is_none() {
+                            self.state.set(State::DnBusy);
+                            poll_timeout = POLL_TIMEOUT_MS;
+                        } else {
+                            self.state.set(State::DnloadIdle);
+                        }
+                    }
+                    State::ManifestSync => {
+                        self.state.set(State::Idle);
+                        let length = self.offset.get();
+                        self.client.map(|client| client.image_received(length));
+                    }
+                    State::Idle | State::DnloadIdle | State::Error => {}
+                }
+                let timeout = poll_timeout.to_le_bytes();
+                self.respond(&[
+                    self.status.get() as u8,
+                    timeout[0],
+                    timeout[1],
+                    timeout[2],
+                    self.state.get() as u8,
+                    0, // No status description string
+                ])
+            }
+            REQUEST_GETSTATE => self.respond(&[state as u8]),
+            REQUEST_CLRSTATUS if state == State::Error => {
+                self.state.set(State::Idle);
+                self.status.set(Status::Ok);
+                hil::usb::CtrlSetupResult::Ok
+            }
+            REQUEST_ABORT if state == State::Idle || state == State::DnloadIdle => {
+                self.state.set(State::Idle);
+                hil::usb::CtrlSetupResult::Ok
+            }
+            REQUEST_DETACH => {
+                // We already are in DFU mode.
+                hil::usb::CtrlSetupResult::Ok
+            }
+            _ => self.fail(Status::ErrStalledPkt),
+        }
+    }
+
+    /// Write the block that was just received to the staging region.
+    fn write_block(&self) {
+        let len = self.received.get();
+        let offset = self.offset.get();
+        if len > self.length.saturating_sub(offset) {
+            self.fail(Status::ErrAddress);
+            return;
+        }
+        self.buffer.take().map(|buffer| {
+            self.state.set(State::DnloadSync);
+            if self.storage.write(buffer, self.base + offset, len) != ReturnCode::SUCCESS {
+                // The buffer is lost, so no further blocks can be received.
+                self.fail(Status::ErrWrite);
+            }
+        });
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> DfuMode<'a> for UsbDfu<'a, U> {
+    fn enter_dfu_mode(&'a self) {
+        if self.active.replace(true) {
+            return;
+        }
+        // Disconnect whatever device the board was running, so that the host
+        // enumerates us instead.
+        self.controller().detach();
+        self.controller().set_client(self);
+        self.client_ctrl.enable();
+        self.client_ctrl.attach();
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> hil::usb::Client<'a> for UsbDfu<'a, U> {
+    fn enable(&'a self) {
+        // Set up the default control endpoint, which is all DFU uses.
+        self.client_ctrl.enable();
+    }
+
+    fn attach(&'a self) {
+        self.client_ctrl.attach();
+    }
+
+    fn bus_reset(&'a self) {
+        self.ctrl_state.set(CtrlState::Idle);
+        if self.buffer.is_some() {
+            self.state.set(State::Idle);
+            self.status.set(Status::Ok);
+        }
+    }
+
+    /// Handle a Control Setup transaction.
+    ///
+    /// DFU class requests are handled here, everything else by `ClientCtrl`.
+    fn ctrl_setup(&'a self, endpoint: usize) -> hil::usb::CtrlSetupResult {
+        let class_request =
+            SetupData::get(&self.client_ctrl.ctrl_buffer.buf).filter(|setup_data| match setup_data
+                .request_type
+                .request_type()
+            {
+                RequestType::Class => true,
+                _ => false,
+            });
+        match class_request {
+            Some(setup_data) => self.handle_request(setup_data),
+            None => self.client_ctrl.ctrl_setup(endpoint),
+        }
+    }
+
+    /// Handle a Control In transaction
+    fn ctrl_in(&'a self, endpoint: usize) -> hil::usb::CtrlInResult {
+        match self.ctrl_state.get() {
+            CtrlState::Respond(len) => {
+                let response = self.response.get();
+                for (i, b) in response[..len].iter().enumerate() {
+                    self.client_ctrl.ctrl_buffer.buf[i].set(*b);
+                }
+                hil::usb::CtrlInResult::Packet(len, true)
+            }
+            CtrlState::Idle | CtrlState::Download(_) => self.client_ctrl.ctrl_in(endpoint),
+        }
+    }
+
+    /// Handle a Control Out transaction
+    fn ctrl_out(&'a self, endpoint: usize, packet_bytes: u32) -> hil::usb::CtrlOutResult {
+        match self.ctrl_state.get() {
+            CtrlState::Download(expected) => {
+                self.buffer
+                    .map_or(hil::usb::CtrlOutResult::Halted, |buffer| {
+                        let received = self.received.get();
+                        let to_copy = cmp::min(packet_bytes as usize, expected - received);
+                        for i in 0..to_copy {
+                            buffer[received + i] = self.client_ctrl.ctrl_buffer.buf[i].get();
+                        }
+                        self.received.set(received + to_copy);
+                        hil::usb::CtrlOutResult::Ok
+                    })
+            }
+            CtrlState::Idle | CtrlState::Respond(_) => {
+                self.client_ctrl.ctrl_out(endpoint, packet_bytes)
+            }
+        }
+    }
+
+    fn ctrl_status(&'a self, endpoint: usize) {
+        self.client_ctrl.ctrl_status(endpoint)
+    }
+
+    /// Handle the completion of a Control transfer
+    fn ctrl_status_complete(&'a self, endpoint: usize) {
+        if let CtrlState::Download(expected) = self.ctrl_state.get() {
+            if self.received.get() == expected {
+                self.write_block();
+            } else {
+                self.fail(Status::ErrStalledPkt);
+            }
+        }
+        self.ctrl_state.set(CtrlState::Idle);
+        self.client_ctrl.ctrl_status_complete(endpoint)
+    }
+
+    fn packet_in(&'a self, _transfer_type: TransferType, _endpoint: usize) -> hil::usb::InResult {
+        // DFU only uses the control endpoint.
+        hil::usb::InResult::Error
+    }
+
+    fn packet_out(
+        &'a self,
+        _transfer_type: TransferType,
+        _endpoint: usize,
+        _packet_bytes: u32,
+    ) -> hil::usb::OutResult {
+        // DFU only uses the control endpoint.
+        hil::usb::OutResult::Error
+    }
+
+    fn packet_transmitted(&'a self, _endpoint: usize) {}
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> NonvolatileStorageClient<'static> for UsbDfu<'a, U> {
+    fn read_done(&self, buffer: &'static mut [u8], _length: usize) {
+        // We never read.
+        self.buffer.replace(buffer);
+    }
+
+    fn write_done(&self, buffer: &'static mut [u8], length: usize) {
+        self.buffer.replace(buffer);
+        match self.state.get() {
+            State::DnloadSync | State::DnBusy => {
+                self.offset.set(self.offset.get() + length);
+                // The next `DFU_GETSTATUS` moves us to `DnloadIdle`.
+            }
+            _ => {
+                // Reset or aborted while the write was in progress.
+            }
+        }
+    }
+}
+
+/// Enters DFU mode when a button is pressed.
+///
+/// The board configures the pin as an input with an interrupt and sets this
+/// as its client.
+pub struct DfuButton<'a> {
+    dfu: &'a dyn DfuMode<'a>,
+}
+
+impl<'a> DfuButton<'a> {
+    pub fn new(dfu: &'a dyn DfuMode<'a>) -> Self {
+        DfuButton { dfu: dfu }
+    }
+}
+
+impl<'a> hil::gpio::Client for DfuButton<'a> {
+    fn fired(&self) {
+        self.dfu.enter_dfu_mode();
+    }
+}
@@ -0,0 +1,396 @@
+//! Generic Human Interface Device for USB
+//!
+//! Presents a HID interface with a vendor defined usage page, which hosts
+//! open without a driver (for example with hidapi or WebHID), and moves raw
+//! 64 byte reports to and from it through `hil::usb_hid::UsbHid`. It suits
+//! apps that talk to a host program over their own protocol; see `hid` for a
+//! keyboard or mouse.
+//!
+//! Reports from the host arrive on interrupt OUT endpoint 2 and reports to
+//! the host are sent on interrupt IN endpoint 1. There are no report IDs, so
+//! each report is exactly 64 bytes.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let generic_hid = static_init!(
+//!     capsules::usb::generic_hid::UsbGenericHid<'static, nrf52840::usbd::Usbd<'static>>,
+//!     capsules::usb::generic_hid::UsbGenericHid::new(
+//!         &nrf52840::usbd::USBD,
+//!         capsules::usb::generic_hid::MAX_CTRL_PACKET_SIZE_NRF52840,
+//!         0x1915,
+//!         0x503e,
+//!         strings,
+//!     )
+//! );
+//! nrf52840::usbd::USBD.set_client(generic_hid);
+//! generic_hid.enable();
+//! generic_hid.attach();
+//! ```
+
+use core::cell::Cell;
+
+use super::descriptors;
+use super::descriptors::Buffer64;
+use super::descriptors::DescriptorType;
+use super::descriptors::EndpointAddress;
+use super::descriptors::EndpointDescriptor;
+use super::descriptors::HIDCountryCode;
+use super::descriptors::HIDDescriptor;
+use super::descriptors::HIDSubordinateDescriptor;
+use super::descriptors::InterfaceDescriptor;
+use super::descriptors::ReportDescriptor;
+use super::descriptors::TransferDirection;
+use super::usbc_client_ctrl::ClientCtrl;
+
+use kernel::common::cells::OptionalCell;
+use kernel::common::cells::TakeCell;
+use kernel::common::cells::VolatileCell;
+use kernel::hil;
+use kernel::hil::usb::TransferType;
+use kernel::hil::usb_hid;
+use kernel::ReturnCode;
+
+/// Identifying number for the endpoint when transferring data from us to the
+/// host.
+const ENDPOINT_IN_NUM: usize = 1;
+/// Identifying number for the endpoint when transferring data from the host to
+/// us.
+const ENDPOINT_OUT_NUM: usize = 2;
+
+static LANGUAGES: &'static [u16; 1] = &[
+    0x0409, // English (United States)
+];
+/// Platform-specific packet length for the `SAM4L` USB hardware.
+pub const MAX_CTRL_PACKET_SIZE_SAM4L: u8 = 8;
+/// Platform-specific packet length for the `nRF52` USB hardware.
+pub const MAX_CTRL_PACKET_SIZE_NRF52840: u8 = 64;
+/// Platform-specific packet length for the `earlgrey` USB hardware.
+pub const MAX_CTRL_PACKET_SIZE_EARLGREY: u8 = 64;
+
+const N_ENDPOINTS: usize = 2;
+
+/// Report descriptor for one 64 byte input and one 64 byte output report.
+static GENERIC_REPORT_DESCRIPTOR: &'static [u8] = &[
+    0x06, 0x00, 0xff, // Usage Page (Vendor Defined 0xFF00)
+    0x09, 0x01, //       Usage (Vendor Usage 1)
+    0xa1, 0x01, //       Collection (Application)
+    0x09, 0x02, //         Usage (Vendor Usage 2)
+    0x15, 0x00, //         Logical Minimum (0)
+    0x26, 0xff, 0x00, //   Logical Maximum (255)
+    0x75, 0x08, //         Report Size (8)
+    0x95, 0x40, //         Report Count (64)
+    0x81, 0x02, //         Input (Data, Variable, Absolute)
+    0x09, 0x03, //         Usage (Vendor Usage 3)
+    0x15, 0x00, //         Logical Minimum (0)
+    0x26, 0xff, 0x00, //   Logical Maximum (255)
+    0x75, 0x08, //         Report Size (8)
+    0x95, 0x40, //         Report Count (64)
+    0x91, 0x02, //         Output (Data, Variable, Absolute)
+    0xc0, //             End Collection
+];
+
+static GENERIC_REPORT: ReportDescriptor<'static> = ReportDescriptor {
+    desc: GENERIC_REPORT_DESCRIPTOR,
+};
+
+static GENERIC_HID: HIDDescriptor<'static> = HIDDescriptor {
+    hid_class: 0x0111,
+    country_code: HIDCountryCode::NotSupported,
+    sub_descriptors: &[HIDSubordinateDescriptor {
+        typ: DescriptorType::Report,
+        len: 34,
+    }],
+};
+
+pub struct UsbGenericHid<'a, U: 'a> {
+    /// Helper USB client library for handling many USB operations.
+    client_ctrl: ClientCtrl<'a, 'static, U>,
+
+    /// 64 byte buffers for each endpoint.
+    buffers: [Buffer64; N_ENDPOINTS],
+
+    /// Whether `enable()` has been called.
+    enabled: Cell<bool>,
+
+    /// The report passed to `send_buffer()`, held until the host has read it.
+    tx_buffer: TakeCell<'static, [u8; 64]>,
+    /// Whether the report in the IN endpoint buffer has been handed to the
+    /// controller.
+    in_flight: Cell<bool>,
+
+    /// The buffer passed to `receive_buffer()`.
+    rx_buffer: TakeCell<'static, [u8; 64]>,
+    /// Whether we returned `Delay` for an OUT packet because there was no
+    /// receive buffer.
+    delayed_out: Cell<bool>,
+
+    client: OptionalCell<&'a dyn usb_hid::Client<'a, [u8; 64]>>,
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> UsbGenericHid<'a, U> {
+    pub fn new(
+        controller: &'a U,
+        max_ctrl_packet_size: u8,
+        vendor_id: u16,
+        product_id: u16,
+        strings: &'static [&'static str; 3],
+    ) -> Self {
+        let interfaces: &mut [InterfaceDescriptor] = &mut [InterfaceDescriptor {
+            interface_number: 0,
+            interface_class: 0x03,    // HID
+            interface_subclass: 0x00, // no subclass
+            interface_protocol: 0x00, // no protocol
+            ..InterfaceDescriptor::default()
+        }];
+
+        let endpoints: &[&[EndpointDescriptor]] = &[&[
+            EndpointDescriptor {
+                endpoint_address: EndpointAddress::new_const(
+                    ENDPOINT_IN_NUM,
+                    TransferDirection::DeviceToHost,
+                ),
+                transfer_type: TransferType::Interrupt,
+                max_packet_size: 64,
+                interval: 5,
+            },
+            EndpointDescriptor {
+                endpoint_address: EndpointAddress::new_const(
+                    ENDPOINT_OUT_NUM,
+                    TransferDirection::HostToDevice,
+                ),
+                transfer_type: TransferType::Interrupt,
+                max_packet_size: 64,
+                interval: 5,
+            },
+        ]];
+
+        let (device_descriptor_buffer, other_descriptor_buffer) =
+            descriptors::create_descriptor_buffers(
+                descriptors::DeviceDescriptor {
+                    vendor_id: vendor_id,
+                    product_id: product_id,
+                    manufacturer_string: 1,
+                    product_string: 2,
+                    serial_number_string: 3,
+                    class: 0x0, // Class given by the interface
+                    max_packet_size_ep0: max_ctrl_packet_size,
+                    ..descriptors::DeviceDescriptor::default()
+                },
+                descriptors::ConfigurationDescriptor {
+                    ..descriptors::ConfigurationDescriptor::default()
+                },
+                interfaces,
+                endpoints,
+                Some(&GENERIC_HID),
+                None, // No CDC descriptor array
+                None, // No DFU descriptor
+            );
+
+        UsbGenericHid {
+            client_ctrl: ClientCtrl::new(
+                controller,
+                device_descriptor_buffer,
+                other_descriptor_buffer,
+                Some(&GENERIC_HID),
+                Some(&GENERIC_REPORT),
+                LANGUAGES,
+                strings,
+            ),
+            buffers: [Buffer64::default(), Buffer64::default()],
+            enabled: Cell::new(false),
+            tx_buffer: TakeCell::empty(),
+            in_flight: Cell::new(false),
+            rx_buffer: TakeCell::empty(),
+            delayed_out: Cell::new(false),
+            client: OptionalCell::empty(),
+        }
+    }
+
+    pub fn set_client(&self, client: &'a dyn usb_hid::Client<'a, [u8; 64]>) {
+        self.client.set(client);
+    }
+
+    #[inline]
+    fn controller(&self) -> &'a U {
+        self.client_ctrl.controller()
+    }
+
+    #[inline]
+    fn buffer(&'a self, i: usize) -> &'a [VolatileCell<u8>; 64] {
+        &self.buffers[i - 1].buf
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> hil::usb::Client<'a> for UsbGenericHid<'a, U> {
+    fn enable(&'a self) {
+        // Set up the default control endpoint
+        self.client_ctrl.enable();
+
+        // Setup buffers for IN and OUT data transfer.
+        self.controller()
+            .endpoint_set_in_buffer(ENDPOINT_IN_NUM, self.buffer(ENDPOINT_IN_NUM));
+        self.controller()
+            .endpoint_in_enable(TransferType::Interrupt, ENDPOINT_IN_NUM);
+
+        self.controller()
+            .endpoint_set_out_buffer(ENDPOINT_OUT_NUM, self.buffer(ENDPOINT_OUT_NUM));
+        self.controller()
+            .endpoint_out_enable(TransferType::Interrupt, ENDPOINT_OUT_NUM);
+
+        self.enabled.set(true);
+    }
+
+    fn attach(&'a self) {
+        self.client_ctrl.attach();
+    }
+
+    fn bus_reset(&'a self) {
+        // A report in flight was lost with the reset, so offer it again.
+        self.in_flight.set(false);
+        if self.tx_buffer.is_some() {
+            self.controller().endpoint_resume_in(ENDPOINT_IN_NUM);
+        }
+    }
+
+    /// Handle a Control Setup transaction.
+    fn ctrl_setup(&'a self, endpoint: usize) -> hil::usb::CtrlSetupResult {
+        self.client_ctrl.ctrl_setup(endpoint)
+    }
+
+    /// Handle a Control In transaction
+    fn ctrl_in(&'a self, endpoint: usize) -> hil::usb::CtrlInResult {
+        self.client_ctrl.ctrl_in(endpoint)
+    }
+
+    /// Handle a Control Out transaction
+    fn ctrl_out(&'a self, endpoint: usize, packet_bytes: u32) -> hil::usb::CtrlOutResult {
+        self.client_ctrl.ctrl_out(endpoint, packet_bytes)
+    }
+
+    fn ctrl_status(&'a self, endpoint: usize) {
+        self.client_ctrl.ctrl_status(endpoint)
+    }
+
+    /// Handle the completion of a Control transfer
+    fn ctrl_status_complete(&'a self, endpoint: usize) {
+        self.client_ctrl.ctrl_status_complete(endpoint)
+    }
+
+    /// Handle an Interrupt IN transaction.
+    fn packet_in(&'a self, transfer_type: TransferType, _endpoint: usize) -> hil::usb::InResult {
+        match transfer_type {
+            TransferType::Interrupt => {
+                if self.tx_buffer.is_some() && !self.in_flight.get() {
+                    self.in_flight.set(true);
+                    hil::usb::InResult::Packet(64)
+                } else {
+                    hil::usb::InResult::Delay
+                }
+            }
+            TransferType::Bulk | TransferType::Control | TransferType::Isochronous => {
+                // Nothing to do for HID.
+                hil::usb::InResult::Delay
+            }
+        }
+    }
+
+    /// Handle an Interrupt OUT transaction.
+    fn packet_out(
+        &'a self,
+        transfer_type: TransferType,
+        endpoint: usize,
+        packet_bytes: u32,
+    ) -> hil::usb::OutResult {
+        match transfer_type {
+            TransferType::Interrupt => self.rx_buffer.take().map_or_else(
+                || {
+                    // Hold the report until there is somewhere to put it.
+                    self.delayed_out.set(true);
+                    hil::usb::OutResult::Delay
+                },
+                |rx_buf| {
+                    let packet = self.buffer(endpoint);
+                    for (byte, p) in rx_buf.iter_mut().zip(packet.iter()) {
+                        *byte = p.get();
+                    }
+                    let result = if packet_bytes as usize == rx_buf.len() {
+                        ReturnCode::SUCCESS
+                    } else {
+                        ReturnCode::ESIZE
+                    };
+                    self.client
+                        .map(move |client| client.packet_received(result, rx_buf, endpoint));
+                    hil::usb::OutResult::Ok
+                },
+            ),
+            TransferType::Bulk | TransferType::Control | TransferType::Isochronous => {
+                // Nothing to do for HID.
+                hil::usb::OutResult::Ok
+            }
+        }
+    }
+
+    fn packet_transmitted(&'a self, endpoint: usize) {
+        if self.in_flight.get() {
+            self.in_flight.set(false);
+            self.tx_buffer.take().map(|buf| {
+                self.client.map(move |client| {
+                    client.packet_transmitted(ReturnCode::SUCCESS, buf, endpoint)
+                });
+            });
+        }
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> usb_hid::UsbHid<'a, [u8; 64]> for UsbGenericHid<'a, U> {
+    /// Returns `EOFF` before `enable()` and `EBUSY` while the previous report
+    /// is still waiting.
+    fn send_buffer(
+        &'a self,
+        send: &'static mut [u8; 64],
+    ) -> Result<usize, (ReturnCode, &'static mut [u8; 64])> {
+        if !self.enabled.get() {
+            Err((ReturnCode::EOFF, send))
+        } else if self.tx_buffer.is_some() {
+            Err((ReturnCode::EBUSY, send))
+        } else {
+            for (p, byte) in self.buffer(ENDPOINT_IN_NUM).iter().zip(send.iter()) {
+                p.set(*byte);
+            }
+            self.tx_buffer.replace(send);
+            self.controller().endpoint_resume_in(ENDPOINT_IN_NUM);
+            Ok(64)
+        }
+    }
+
+    fn send_cancel(&'a self) -> Result<&'static mut [u8; 64], ReturnCode> {
+        if self.in_flight.get() {
+            Err(ReturnCode::EBUSY)
+        } else {
+            self.tx_buffer.take().ok_or(ReturnCode::EINVAL)
+        }
+    }
+
+    fn receive_buffer(
+        &'a self,
+        recv: &'static mut [u8; 64],
+    ) -> Result<(), (ReturnCode, &'static mut [u8; 64])> {
+        if self.rx_buffer.is_some() {
+            Err((ReturnCode::EBUSY, recv))
+        } else {
+            self.rx_buffer.replace(recv);
+            if self.delayed_out.take() {
+                self.controller().endpoint_resume_out(ENDPOINT_OUT_NUM);
+            }
+            Ok(())
+        }
+    }
+
+    fn receive_cancel(&'a self) -> Result<&'static mut [u8; 64], ReturnCode> {
+        self.rx_buffer.take().ok_or(ReturnCode::EINVAL)
+    }
+}
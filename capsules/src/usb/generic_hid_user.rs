@@ -0,0 +1,242 @@
+//! System call interface to a generic USB HID device
+//!
+//! Lets apps exchange raw 64 byte reports with a host program through a
+//! `UsbGenericHid`, or any other `hil::usb_hid::UsbHid` device with 64 byte
+//! reports.
+//!
+//! ## Instantiation
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let generic_hid_driver = static_init!(
+//!     capsules::usb::generic_hid_user::GenericHidSyscallDriver<'static, capsules::usb::generic_hid::UsbGenericHid<'static, nrf52840::usbd::Usbd<'static>>>,
+//!     capsules::usb::generic_hid_user::GenericHidSyscallDriver::new(
+//!         generic_hid,
+//!         &mut GENERIC_HID_SEND_BUFFER,
+//!         &mut GENERIC_HID_RECEIVE_BUFFER,
+//!         board_kernel.create_grant(&grant_cap)));
+//! generic_hid.set_client(generic_hid_driver);
+//! ```
+//!
+//! ## Syscall Interface
+//!
+//! - allow `0`: the report for command `1`, of at most 64 bytes. Shorter
+//!   reports are padded with zeros.
+//! - allow `1`: buffer received reports are copied into.
+//! - subscribe `0`: the report was read by the host, `fn(ReturnCode, 0, 0)`.
+//! - subscribe `1`: a report was received, `fn(ReturnCode, len, 0)`.
+//! - command `0`: driver check.
+//! - command `1`: send the allowed report.
+//! - command `2`: receive the next report from the host.
+//! - command `3`: stop waiting for a report.
+//!
+//! Only one app sends and one app receives at a time; the others get
+//! `EBUSY`.
+
+use core::cmp;
+
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::usb_hid;
+use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
+
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::UsbGenericHid as usize;
+
+const REPORT_LEN: usize = 64;
+
+#[derive(Default)]
+pub struct App {
+    sent_callback: Option<Callback>,
+    received_callback: Option<Callback>,
+    send: Option<AppSlice<Shared, u8>>,
+    receive: Option<AppSlice<Shared, u8>>,
+}
+
+pub struct GenericHidSyscallDriver<'a, H: usb_hid::UsbHid<'a, [u8; REPORT_LEN]>> {
+    hid: &'a H,
+    send_buffer: TakeCell<'static, [u8; REPORT_LEN]>,
+    receive_buffer: TakeCell<'static, [u8; REPORT_LEN]>,
+    apps: Grant<App>,
+    sending_app: OptionalCell<AppId>,
+    receiving_app: OptionalCell<AppId>,
+}
+
+impl<'a, H: usb_hid::UsbHid<'a, [u8; REPORT_LEN]>> GenericHidSyscallDriver<'a, H> {
+    pub fn new(
+        hid: &'a H,
+        send_buffer: &'static mut [u8; REPORT_LEN],
+        receive_buffer: &'static mut [u8; REPORT_LEN],
+        apps: Grant<App>,
+    ) -> Self {
+        GenericHidSyscallDriver {
+            hid: hid,
+            send_buffer: TakeCell::new(send_buffer),
+            receive_buffer: TakeCell::new(receive_buffer),
+            apps: apps,
+            sending_app: OptionalCell::empty(),
+            receiving_app: OptionalCell::empty(),
+        }
+    }
+
+    fn send(&self, appid: AppId) -> ReturnCode {
+        self.apps
+            .enter(appid, |app, _| {
+                let report = match app.send {
+                    Some(ref report) if report.len() <= REPORT_LEN => report,
+                    Some(_) => return ReturnCode::ESIZE,
+                    None => return ReturnCode::ENOMEM,
+                };
+                self.send_buffer.take().map_or(ReturnCode::EBUSY, |buffer| {
+                    *buffer = [0; REPORT_LEN];
+                    buffer[..report.len()].copy_from_slice(report.as_ref());
+                    match self.hid.send_buffer(buffer) {
+                        Ok(_) => {
+                            self.sending_app.set(appid);
+                            ReturnCode::SUCCESS
+                        }
+                        Err((rcode, buffer)) => {
+                            self.send_buffer.replace(buffer);
+                            rcode
+                        }
+                    }
+                })
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    fn receive(&self, appid: AppId) -> ReturnCode {
+        if self.receiving_app.is_some() {
+            return if self.receiving_app.contains(&appid) {
+                ReturnCode::EALREADY
+            } else {
+                ReturnCode::EBUSY
+            };
+        }
+        self.receive_buffer
+            .take()
+            .map_or(ReturnCode::EBUSY, |buffer| {
+                match self.hid.receive_buffer(buffer) {
+                    Ok(()) => {
+                        self.receiving_app.set(appid);
+                        ReturnCode::SUCCESS
+                    }
+                    Err((rcode, buffer)) => {
+                        self.receive_buffer.replace(buffer);
+                        rcode
+                    }
+                }
+            })
+    }
+
+    fn cancel_receive(&self, appid: AppId) -> ReturnCode {
+        if !self.receiving_app.contains(&appid) {
+            return ReturnCode::EINVAL;
+        }
+        match self.hid.receive_cancel() {
+            Ok(buffer) => {
+                self.receive_buffer.replace(buffer);
+                self.receiving_app.clear();
+                ReturnCode::SUCCESS
+            }
+            Err(rcode) => rcode,
+        }
+    }
+}
+
+impl<'a, H: usb_hid::UsbHid<'a, [u8; REPORT_LEN]>> usb_hid::Client<'a, [u8; REPORT_LEN]>
+    for GenericHidSyscallDriver<'a, H>
+{
+    fn packet_received(
+        &'a self,
+        result: ReturnCode,
+        buffer: &'static mut [u8; REPORT_LEN],
+        _endpoint: usize,
+    ) {
+        self.receiving_app.take().map(|appid| {
+            let _ = self.apps.enter(appid, |app, _| {
+                let len = app.receive.as_mut().map_or(0, |receive| {
+                    let len = cmp::min(receive.len(), REPORT_LEN);
+                    receive.as_mut()[..len].copy_from_slice(&buffer[..len]);
+                    len
+                });
+                app.received_callback
+                    .map(|mut cb| cb.schedule(From::from(result), len, 0));
+            });
+        });
+        self.receive_buffer.replace(buffer);
+    }
+
+    fn packet_transmitted(
+        &'a self,
+        result: ReturnCode,
+        buffer: &'static mut [u8; REPORT_LEN],
+        _endpoint: usize,
+    ) {
+        self.send_buffer.replace(buffer);
+        self.sending_app.take().map(|appid| {
+            let _ = self.apps.enter(appid, |app, _| {
+                app.sent_callback
+                    .map(|mut cb| cb.schedule(From::from(result), 0, 0));
+            });
+        });
+    }
+
+    fn can_receive(&'a self) -> bool {
+        self.receiving_app.is_some()
+    }
+}
+
+impl<'a, H: usb_hid::UsbHid<'a, [u8; REPORT_LEN]>> Driver for GenericHidSyscallDriver<'a, H> {
+    fn allow(
+        &self,
+        appid: AppId,
+        allow_num: usize,
+        slice: Option<AppSlice<Shared, u8>>,
+    ) -> ReturnCode {
+        self.apps
+            .enter(appid, |app, _| match allow_num {
+                0 => {
+                    app.send = slice;
+                    ReturnCode::SUCCESS
+                }
+                1 => {
+                    app.receive = slice;
+                    ReturnCode::SUCCESS
+                }
+                _ => ReturnCode::ENOSUPPORT,
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        callback: Option<Callback>,
+        appid: AppId,
+    ) -> ReturnCode {
+        self.apps
+            .enter(appid, |app, _| match subscribe_num {
+                0 => {
+                    app.sent_callback = callback;
+                    ReturnCode::SUCCESS
+                }
+                1 => {
+                    app.received_callback = callback;
+                    ReturnCode::SUCCESS
+                }
+                _ => ReturnCode::ENOSUPPORT,
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    fn command(&self, command_num: usize, _: usize, _: usize, appid: AppId) -> ReturnCode {
+        match command_num {
+            0 => ReturnCode::SUCCESS,
+            1 => self.send(appid),
+            2 => self.receive(appid),
+            3 => self.cancel_receive(appid),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
@@ -0,0 +1,444 @@
+//! Human Interface Device class for USB
+//!
+//! This capsule lets Tock present itself to a host as a USB keyboard or
+//! mouse. Both use the boot protocol report formats from appendix B of the
+//! HID specification, so they work with BIOS setup screens and other hosts
+//! that do not parse report descriptors:
+//!
+//! - keyboard: 8 bytes, `[modifiers, reserved, key1, ..., key6]`, where each
+//!   key is a usage ID from the keyboard usage page.
+//! - mouse: 3 bytes, `[buttons, dx, dy]`, where `dx` and `dy` are signed.
+//!
+//! Reports are sent through `hil::usb_hid::UsbHid` on interrupt IN endpoint
+//! 1, using the first `Protocol::report_len()` bytes of each buffer. Only one
+//! report can be outstanding at a time. Boot protocol devices have no OUT
+//! endpoint, so receiving is not supported.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let hid = static_init!(
+//!     capsules::usb::hid::UsbHid<'static, nrf52840::usbd::Usbd<'static>>,
+//!     capsules::usb::hid::UsbHid::new(
+//!         &nrf52840::usbd::USBD,
+//!         capsules::usb::hid::MAX_CTRL_PACKET_SIZE_NRF52840,
+//!         0x1915,
+//!         0x503b,
+//!         strings,
+//!         capsules::usb::hid::Protocol::Keyboard,
+//!     )
+//! );
+//! nrf52840::usbd::USBD.set_client(hid);
+//! hid.enable();
+//! hid.attach();
+//! ```
+
+use core::cell::Cell;
+
+use super::descriptors;
+use super::descriptors::Buffer8;
+use super::descriptors::DescriptorType;
+use super::descriptors::EndpointAddress;
+use super::descriptors::EndpointDescriptor;
+use super::descriptors::HIDCountryCode;
+use super::descriptors::HIDDescriptor;
+use super::descriptors::HIDSubordinateDescriptor;
+use super::descriptors::InterfaceDescriptor;
+use super::descriptors::ReportDescriptor;
+use super::descriptors::TransferDirection;
+use super::usbc_client_ctrl::ClientCtrl;
+
+use kernel::common::cells::OptionalCell;
+use kernel::common::cells::TakeCell;
+use kernel::common::cells::VolatileCell;
+use kernel::hil;
+use kernel::hil::usb::TransferType;
+use kernel::hil::usb_hid;
+use kernel::ReturnCode;
+
+/// Identifying number for the endpoint reports are sent on.
+const ENDPOINT_IN_NUM: usize = 1;
+
+static LANGUAGES: &'static [u16; 1] = &[
+    0x0409, // English (United States)
+];
+/// Platform-specific packet length for the `SAM4L` USB hardware.
+pub const MAX_CTRL_PACKET_SIZE_SAM4L: u8 = 8;
+/// Platform-specific packet length for the `nRF52` USB hardware.
+pub const MAX_CTRL_PACKET_SIZE_NRF52840: u8 = 64;
+/// Platform-specific packet length for the `earlgrey` USB hardware.
+pub const MAX_CTRL_PACKET_SIZE_EARLGREY: u8 = 64;
+
+/// Boot protocol keyboard report descriptor (HID 1.11, appendix B.1).
+static KEYBOARD_REPORT_DESCRIPTOR: &'static [u8] = &[
+    0x05, 0x01, // Usage Page (Generic Desktop)
+    0x09, 0x06, // Usage (Keyboard)
+    0xa1, 0x01, // Collection (Application)
+    0x05, 0x07, //   Usage Page (Key Codes)
+    0x19, 0xe0, //   Usage Minimum (224)
+    0x29, 0xe7, //   Usage Maximum (231)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x25, 0x01, //   Logical Maximum (1)
+    0x75, 0x01, //   Report Size (1)
+    0x95, 0x08, //   Report Count (8)
+    0x81, 0x02, //   Input (Data, Variable, Absolute): modifiers
+    0x95, 0x01, //   Report Count (1)
+    0x75, 0x08, //   Report Size (8)
+    0x81, 0x01, //   Input (Constant): reserved byte
+    0x95, 0x05, //   Report Count (5)
+    0x75, 0x01, //   Report Size (1)
+    0x05, 0x08, //   Usage Page (LEDs)
+    0x19, 0x01, //   Usage Minimum (1)
+    0x29, 0x05, //   Usage Maximum (5)
+    0x91, 0x02, //   Output (Data, Variable, Absolute): LEDs
+    0x95, 0x01, //   Report Count (1)
+    0x75, 0x03, //   Report Size (3)
+    0x91, 0x01, //   Output (Constant): LED padding
+    0x95, 0x06, //   Report Count (6)
+    0x75, 0x08, //   Report Size (8)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x25, 0x65, //   Logical Maximum (101)
+    0x05, 0x07, //   Usage Page (Key Codes)
+    0x19, 0x00, //   Usage Minimum (0)
+    0x29, 0x65, //   Usage Maximum (101)
+    0x81, 0x00, //   Input (Data, Array): keys
+    0xc0, //       End Collection
+];
+
+/// Boot protocol mouse report descriptor (HID 1.11, appendix B.2).
+static MOUSE_REPORT_DESCRIPTOR: &'static [u8] = &[
+    0x05, 0x01, // Usage Page (Generic Desktop)
+    0x09, 0x02, // Usage (Mouse)
+    0xa1, 0x01, // Collection (Application)
+    0x09, 0x01, //   Usage (Pointer)
+    0xa1, 0x00, //   Collection (Physical)
+    0x05, 0x09, //     Usage Page (Buttons)
+    0x19, 0x01, //     Usage Minimum (1)
+    0x29, 0x03, //     Usage Maximum (3)
+    0x15, 0x00, //     Logical Minimum (0)
+    0x25, 0x01, //     Logical Maximum (1)
+    0x95, 0x03, //     Report Count (3)
+    0x75, 0x01, //     Report Size (1)
+    0x81, 0x02, //     Input (Data, Variable, Absolute): buttons
+    0x95, 0x01, //     Report Count (1)
+    0x75, 0x05, //     Report Size (5)
+    0x81, 0x01, //     Input (Constant): padding
+    0x05, 0x01, //     Usage Page (Generic Desktop)
+    0x09, 0x30, //     Usage (X)
+    0x09, 0x31, //     Usage (Y)
+    0x15, 0x81, //     Logical Minimum (-127)
+    0x25, 0x7f, //     Logical Maximum (127)
+    0x75, 0x08, //     Report Size (8)
+    0x95, 0x02, //     Report Count (2)
+    0x81, 0x06, //     Input (Data, Variable, Relative): dx, dy
+    0xc0, //         End Collection
+    0xc0, //       End Collection
+];
+
+static KEYBOARD_REPORT: ReportDescriptor<'static> = ReportDescriptor {
+    desc: KEYBOARD_REPORT_DESCRIPTOR,
+};
+
+static MOUSE_REPORT: ReportDescriptor<'static> = ReportDescriptor {
+    desc: MOUSE_REPORT_DESCRIPTOR,
+};
+
+static KEYBOARD_HID: HIDDescriptor<'static> = HIDDescriptor {
+    hid_class: 0x0111,
+    country_code: HIDCountryCode::NotSupported,
+    sub_descriptors: &[HIDSubordinateDescriptor {
+        typ: DescriptorType::Report,
+        len: 63,
+    }],
+};
+
+static MOUSE_HID: HIDDescriptor<'static> = HIDDescriptor {
+    hid_class: 0x0111,
+    country_code: HIDCountryCode::NotSupported,
+    sub_descriptors: &[HIDSubordinateDescriptor {
+        typ: DescriptorType::Report,
+        len: 50,
+    }],
+};
+
+/// Which boot protocol device to present to the host.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Protocol {
+    Keyboard = 1,
+    Mouse = 2,
+}
+
+impl Protocol {
+    /// Length in bytes of the boot protocol input report.
+    pub fn report_len(self) -> usize {
+        match self {
+            Protocol::Keyboard => 8,
+            Protocol::Mouse => 3,
+        }
+    }
+
+    fn hid_descriptor(self) -> &'static HIDDescriptor<'static> {
+        match self {
+            Protocol::Keyboard => &KEYBOARD_HID,
+            Protocol::Mouse => &MOUSE_HID,
+        }
+    }
+
+    fn report_descriptor(self) -> &'static ReportDescriptor<'static> {
+        match self {
+            Protocol::Keyboard => &KEYBOARD_REPORT,
+            Protocol::Mouse => &MOUSE_REPORT,
+        }
+    }
+}
+
+pub struct UsbHid<'a, U: 'a> {
+    /// Helper USB client library for handling many USB operations.
+    client_ctrl: ClientCtrl<'a, 'static, U>,
+
+    /// Buffer for the interrupt IN endpoint.
+    buffer: Buffer8,
+
+    protocol: Protocol,
+
+    /// Whether `enable()` has been called.
+    enabled: Cell<bool>,
+
+    /// The report passed to `send_buffer()`, held until the host has read it.
+    tx_buffer: TakeCell<'static, [u8; 8]>,
+
+    /// Whether the report in `buffer` has been handed to the controller.
+    in_flight: Cell<bool>,
+
+    client: OptionalCell<&'a dyn usb_hid::Client<'a, [u8; 8]>>,
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> UsbHid<'a, U> {
+    pub fn new(
+        controller: &'a U,
+        max_ctrl_packet_size: u8,
+        vendor_id: u16,
+        product_id: u16,
+        strings: &'static [&'static str; 3],
+        protocol: Protocol,
+    ) -> Self {
+        let interfaces: &mut [InterfaceDescriptor] = &mut [InterfaceDescriptor {
+            interface_number: 0,
+            interface_class: 0x03,    // HID
+            interface_subclass: 0x01, // boot interface
+            interface_protocol: protocol as u8,
+            ..InterfaceDescriptor::default()
+        }];
+
+        let endpoints: &[&[EndpointDescriptor]] = &[&[EndpointDescriptor {
+            endpoint_address: EndpointAddress::new_const(
+                ENDPOINT_IN_NUM,
+                TransferDirection::DeviceToHost,
+            ),
+            transfer_type: TransferType::Interrupt,
+            max_packet_size: 8,
+            interval: 10,
+        }]];
+
+        let (device_descriptor_buffer, other_descriptor_buffer) =
+            descriptors::create_descriptor_buffers(
+                descriptors::DeviceDescriptor {
+                    vendor_id: vendor_id,
+                    product_id: product_id,
+                    manufacturer_string: 1,
+                    product_string: 2,
+                    serial_number_string: 3,
+                    class: 0x0, // Class given by the interface
+                    max_packet_size_ep0: max_ctrl_packet_size,
+                    ..descriptors::DeviceDescriptor::default()
+                },
+                descriptors::ConfigurationDescriptor {
+                    ..descriptors::ConfigurationDescriptor::default()
+                },
+                interfaces,
+                endpoints,
+                Some(protocol.hid_descriptor()),
+                None, // No CDC descriptor array
+                None, // No DFU descriptor
+            );
+
+        UsbHid {
+            client_ctrl: ClientCtrl::new(
+                controller,
+                device_descriptor_buffer,
+                other_descriptor_buffer,
+                Some(protocol.hid_descriptor()),
+                Some(protocol.report_descriptor()),
+                LANGUAGES,
+                strings,
+            ),
+            buffer: Buffer8::default(),
+            protocol: protocol,
+            enabled: Cell::new(false),
+            tx_buffer: TakeCell::empty(),
+            in_flight: Cell::new(false),
+            client: OptionalCell::empty(),
+        }
+    }
+
+    pub fn set_client(&self, client: &'a dyn usb_hid::Client<'a, [u8; 8]>) {
+        self.client.set(client);
+    }
+
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    #[inline]
+    fn controller(&self) -> &'a U {
+        self.client_ctrl.controller()
+    }
+
+    #[inline]
+    fn buffer(&'a self) -> &'a [VolatileCell<u8>; 8] {
+        &self.buffer.buf
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> hil::usb::Client<'a> for UsbHid<'a, U> {
+    fn enable(&'a self) {
+        // Set up the default control endpoint
+        self.client_ctrl.enable();
+
+        // Setup the buffer for the interrupt IN endpoint.
+        self.controller()
+            .endpoint_set_in_buffer(ENDPOINT_IN_NUM, self.buffer());
+        self.controller()
+            .endpoint_in_enable(TransferType::Interrupt, ENDPOINT_IN_NUM);
+
+        self.enabled.set(true);
+    }
+
+    fn attach(&'a self) {
+        self.client_ctrl.attach();
+    }
+
+    fn bus_reset(&'a self) {
+        // A report in flight was lost with the reset, so offer it again.
+        self.in_flight.set(false);
+        if self.tx_buffer.is_some() {
+            self.controller().endpoint_resume_in(ENDPOINT_IN_NUM);
+        }
+    }
+
+    /// Handle a Control Setup transaction.
+    ///
+    /// The class requests HID defines (`SET_IDLE`, `SET_PROTOCOL`, ...) are
+    /// accepted by `ClientCtrl` without effect, which is all the boot
+    /// protocol needs.
+    fn ctrl_setup(&'a self, endpoint: usize) -> hil::usb::CtrlSetupResult {
+        self.client_ctrl.ctrl_setup(endpoint)
+    }
+
+    /// Handle a Control In transaction
+    fn ctrl_in(&'a self, endpoint: usize) -> hil::usb::CtrlInResult {
+        self.client_ctrl.ctrl_in(endpoint)
+    }
+
+    /// Handle a Control Out transaction
+    fn ctrl_out(&'a self, endpoint: usize, packet_bytes: u32) -> hil::usb::CtrlOutResult {
+        self.client_ctrl.ctrl_out(endpoint, packet_bytes)
+    }
+
+    fn ctrl_status(&'a self, endpoint: usize) {
+        self.client_ctrl.ctrl_status(endpoint)
+    }
+
+    /// Handle the completion of a Control transfer
+    fn ctrl_status_complete(&'a self, endpoint: usize) {
+        self.client_ctrl.ctrl_status_complete(endpoint)
+    }
+
+    /// Handle an Interrupt IN transaction.
+    ///
+    /// The report was copied into the endpoint buffer by `send_buffer()`, so
+    /// all there is to do is tell the controller how long it is.
+    fn packet_in(&'a self, transfer_type: TransferType, _endpoint: usize) -> hil::usb::InResult {
+        match transfer_type {
+            TransferType::Interrupt => {
+                if self.tx_buffer.is_some() && !self.in_flight.get() {
+                    self.in_flight.set(true);
+                    hil::usb::InResult::Packet(self.protocol.report_len())
+                } else {
+                    hil::usb::InResult::Delay
+                }
+            }
+            TransferType::Bulk | TransferType::Control | TransferType::Isochronous => {
+                // Nothing to do for HID.
+                hil::usb::InResult::Delay
+            }
+        }
+    }
+
+    /// Handle a Bulk/Interrupt OUT transaction
+    fn packet_out(
+        &'a self,
+        _transfer_type: TransferType,
+        _endpoint: usize,
+        _packet_bytes: u32,
+    ) -> hil::usb::OutResult {
+        // Boot protocol devices have no OUT endpoint.
+        hil::usb::OutResult::Ok
+    }
+
+    fn packet_transmitted(&'a self, endpoint: usize) {
+        if self.in_flight.get() {
+            self.in_flight.set(false);
+            self.tx_buffer.take().map(|buf| {
+                self.client.map(move |client| {
+                    client.packet_transmitted(ReturnCode::SUCCESS, buf, endpoint)
+                });
+            });
+        }
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> usb_hid::UsbHid<'a, [u8; 8]> for UsbHid<'a, U> {
+    /// Returns `EOFF` before `enable()` and `EBUSY` while the previous report
+    /// is still waiting.
+    fn send_buffer(
+        &'a self,
+        send: &'static mut [u8; 8],
+    ) -> Result<usize, (ReturnCode, &'static mut [u8; 8])> {
+        if !self.enabled.get() {
+            Err((ReturnCode::EOFF, send))
+        } else if self.tx_buffer.is_some() {
+            Err((ReturnCode::EBUSY, send))
+        } else {
+            let len = self.protocol.report_len();
+            for (packet, byte) in self.buffer.buf.iter().zip(send[..len].iter()) {
+                packet.set(*byte);
+            }
+            self.tx_buffer.replace(send);
+            self.controller().endpoint_resume_in(ENDPOINT_IN_NUM);
+            Ok(len)
+        }
+    }
+
+    fn send_cancel(&'a self) -> Result<&'static mut [u8; 8], ReturnCode> {
+        if self.in_flight.get() {
+            Err(ReturnCode::EBUSY)
+        } else {
+            self.tx_buffer.take().ok_or(ReturnCode::EINVAL)
+        }
+    }
+
+    fn receive_buffer(
+        &'a self,
+        recv: &'static mut [u8; 8],
+    ) -> Result<(), (ReturnCode, &'static mut [u8; 8])> {
+        Err((ReturnCode::ENOSUPPORT, recv))
+    }
+
+    fn receive_cancel(&'a self) -> Result<&'static mut [u8; 8], ReturnCode> {
+        Err(ReturnCode::EINVAL)
+    }
+}
@@ -0,0 +1,222 @@
+//! System call interface to the USB HID class
+//!
+//! Lets apps inject keypresses or mouse movement through a `UsbHid` device,
+//! for example to build macro pads or accessibility switches.
+//!
+//! ## Instantiation
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let hid_buffer = static_init!([u8; 8], [0; 8]);
+//! let hid_driver = static_init!(
+//!     capsules::usb::hid_user::HidSyscallDriver<'static, nrf52840::usbd::Usbd<'static>>,
+//!     capsules::usb::hid_user::HidSyscallDriver::new(
+//!         hid, hid_buffer, board_kernel.create_grant(&grant_cap)));
+//! hid.set_client(hid_driver);
+//! ```
+//!
+//! ## Syscall Interface
+//!
+//! - allow `0`: a raw report for command `1`.
+//! - subscribe `0`: the requested report(s) were read by the host,
+//!   `fn(ReturnCode, 0, 0)`.
+//! - command `0`: driver check.
+//! - command `1`: send the allowed report as is. Its length must match the
+//!   device's boot protocol report (8 bytes for a keyboard, 3 for a mouse).
+//! - command `2`: keyboard only. Press and release the key with usage ID
+//!   `data`, holding the modifier bits in `data2` while it is down.
+//! - command `3`: mouse only. Set the buttons to `data` and move by `data2`,
+//!   with x in bits 0-7 and y in bits 8-15 as signed bytes.
+//! - command `4`: the device type: `1` for a keyboard, `2` for a mouse.
+//!
+//! Only one app's request is handled at a time; the others get `EBUSY`.
+
+use core::cell::Cell;
+use core::cmp;
+
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil;
+use kernel::hil::usb_hid::{self, UsbHid as UsbHidTrait};
+use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
+
+use super::hid::{Protocol, UsbHid};
+
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::UsbHid as usize;
+
+#[derive(Default)]
+pub struct App {
+    callback: Option<Callback>,
+    report: Option<AppSlice<Shared, u8>>,
+}
+
+pub struct HidSyscallDriver<'a, U: hil::usb::UsbController<'a>> {
+    hid: &'a UsbHid<'a, U>,
+    buffer: TakeCell<'static, [u8; 8]>,
+    apps: Grant<App>,
+    serving_app: OptionalCell<AppId>,
+    /// Whether a key release report still has to follow the press that was
+    /// just sent.
+    release_pending: Cell<bool>,
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> HidSyscallDriver<'a, U> {
+    pub fn new(hid: &'a UsbHid<'a, U>, buffer: &'static mut [u8; 8], apps: Grant<App>) -> Self {
+        HidSyscallDriver {
+            hid: hid,
+            buffer: TakeCell::new(buffer),
+            apps: apps,
+            serving_app: OptionalCell::empty(),
+            release_pending: Cell::new(false),
+        }
+    }
+
+    /// Fill the report buffer with `fill` and send it for `appid`, unless
+    /// another app's report is still outstanding.
+    fn send<F>(&self, appid: AppId, fill: F) -> ReturnCode
+    where
+        F: FnOnce(&mut [u8; 8]),
+    {
+        self.buffer.take().map_or(ReturnCode::EBUSY, |buffer| {
+            *buffer = [0; 8];
+            fill(buffer);
+            match self.hid.send_buffer(buffer) {
+                Ok(_) => {
+                    self.serving_app.set(appid);
+                    ReturnCode::SUCCESS
+                }
+                Err((rcode, buffer)) => {
+                    self.buffer.replace(buffer);
+                    rcode
+                }
+            }
+        })
+    }
+
+    fn finish(&self, appid: AppId, result: ReturnCode) {
+        self.serving_app.clear();
+        let _ = self.apps.enter(appid, |app, _| {
+            app.callback
+                .map(|mut cb| cb.schedule(From::from(result), 0, 0));
+        });
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> usb_hid::Client<'a, [u8; 8]> for HidSyscallDriver<'a, U> {
+    fn packet_received(&'a self, _result: ReturnCode, buffer: &'static mut [u8; 8], _: usize) {
+        // We never ask to receive.
+        self.buffer.replace(buffer);
+    }
+
+    fn packet_transmitted(&'a self, result: ReturnCode, buffer: &'static mut [u8; 8], _: usize) {
+        self.buffer.replace(buffer);
+        self.serving_app.take().map(|appid| {
+            let release = self.release_pending.replace(false);
+            if result == ReturnCode::SUCCESS && release {
+                // An all-zero keyboard report releases every key.
+                let rcode = self.send(appid, |_| {});
+                if rcode != ReturnCode::SUCCESS {
+                    self.finish(appid, rcode);
+                }
+            } else {
+                self.finish(appid, result);
+            }
+        });
+    }
+
+    fn can_receive(&'a self) -> bool {
+        false
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> Driver for HidSyscallDriver<'a, U> {
+    fn allow(
+        &self,
+        appid: AppId,
+        allow_num: usize,
+        slice: Option<AppSlice<Shared, u8>>,
+    ) -> ReturnCode {
+        match allow_num {
+            0 => self
+                .apps
+                .enter(appid, |app, _| {
+                    app.report = slice;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        callback: Option<Callback>,
+        appid: AppId,
+    ) -> ReturnCode {
+        match subscribe_num {
+            0 => self
+                .apps
+                .enter(appid, |app, _| {
+                    app.callback = callback;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    fn command(&self, command_num: usize, data: usize, data2: usize, appid: AppId) -> ReturnCode {
+        match command_num {
+            0 => ReturnCode::SUCCESS,
+
+            1 => self
+                .apps
+                .enter(appid, |app, _| {
+                    app.report.as_ref().map_or(ReturnCode::ENOMEM, |report| {
+                        if report.len() != self.hid.protocol().report_len() {
+                            return ReturnCode::ESIZE;
+                        }
+                        self.send(appid, |buffer| {
+                            let len = cmp::min(buffer.len(), report.len());
+                            buffer[..len].copy_from_slice(&report.as_ref()[..len]);
+                        })
+                    })
+                })
+                .unwrap_or_else(|err| err.into()),
+
+            2 => {
+                if self.hid.protocol() != Protocol::Keyboard {
+                    return ReturnCode::ENOSUPPORT;
+                }
+                let rcode = self.send(appid, |buffer| {
+                    buffer[0] = data2 as u8;
+                    buffer[2] = data as u8;
+                });
+                self.release_pending.set(rcode == ReturnCode::SUCCESS);
+                rcode
+            }
+
+            3 => {
+                if self.hid.protocol() != Protocol::Mouse {
+                    return ReturnCode::ENOSUPPORT;
+                }
+                self.send(appid, |buffer| {
+                    buffer[0] = data as u8;
+                    buffer[1] = data2 as u8;
+                    buffer[2] = (data2 >> 8) as u8;
+                })
+            }
+
+            4 => ReturnCode::SuccessWithValue {
+                value: match self.hid.protocol() {
+                    Protocol::Keyboard => 1,
+                    Protocol::Mouse => 2,
+                },
+            },
+
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
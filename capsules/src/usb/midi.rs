@@ -0,0 +1,362 @@
+//! MIDI streaming device for USB
+//!
+//! Presents a USB-MIDI 1.0 device with one MIDI IN and one MIDI OUT port, so
+//! that a board shows up as a MIDI controller or instrument without drivers
+//! on any major host OS.
+//!
+//! Data moves as USB-MIDI event packets: four bytes each, the first holding
+//! the cable number in the high nibble and the code index number in the low
+//! nibble, followed by up to three bytes of MIDI message. A note on for
+//! middle C at full velocity on channel 1 is `[0x09, 0x90, 0x3c, 0x7f]`. Up
+//! to 16 events fit in one 64 byte packet. Events from the host arrive on
+//! bulk OUT endpoint 2 and events to the host are sent on bulk IN endpoint 1.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let midi = static_init!(
+//!     capsules::usb::midi::UsbMidi<'static, nrf52840::usbd::Usbd<'static>>,
+//!     capsules::usb::midi::UsbMidi::new(
+//!         &nrf52840::usbd::USBD,
+//!         capsules::usb::midi::MAX_CTRL_PACKET_SIZE_NRF52840,
+//!         0x1915,
+//!         0x503e,
+//!         strings,
+//!     )
+//! );
+//! nrf52840::usbd::USBD.set_client(midi);
+//! midi.enable();
+//! midi.attach();
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+
+use super::descriptors;
+use super::descriptors::Buffer64;
+use super::usbc_client_ctrl::ClientCtrl;
+
+use kernel::common::cells::OptionalCell;
+use kernel::common::cells::TakeCell;
+use kernel::common::cells::VolatileCell;
+use kernel::hil;
+use kernel::hil::usb::TransferType;
+use kernel::ReturnCode;
+
+/// Identifying number for the endpoint when transferring data from us to the
+/// host.
+const ENDPOINT_IN_NUM: usize = 1;
+/// Identifying number for the endpoint when transferring data from the host to
+/// us.
+const ENDPOINT_OUT_NUM: usize = 2;
+
+static LANGUAGES: &'static [u16; 1] = &[
+    0x0409, // English (United States)
+];
+/// Platform-specific packet length for the `SAM4L` USB hardware.
+pub const MAX_CTRL_PACKET_SIZE_SAM4L: u8 = 8;
+/// Platform-specific packet length for the `nRF52` USB hardware.
+pub const MAX_CTRL_PACKET_SIZE_NRF52840: u8 = 64;
+/// Platform-specific packet length for the `earlgrey` USB hardware.
+pub const MAX_CTRL_PACKET_SIZE_EARLGREY: u8 = 64;
+
+/// Size of a USB-MIDI event packet.
+pub const EVENT_LEN: usize = 4;
+/// Most bytes of events that can be sent or received at once.
+pub const MAX_EVENTS_LEN: usize = 64;
+
+/// The configuration descriptor and everything returned with it.
+///
+/// An Audio Control interface (0) that only points at the MIDI Streaming
+/// interface (1). That one has an embedded MIDI IN jack (1) fed by the OUT
+/// endpoint and an embedded MIDI OUT jack (3) feeding the IN endpoint, each
+/// connected to an external jack (2 and 4).
+#[rustfmt::skip]
+static CONFIGURATION: [u8; 101] = [
+    // Configuration
+    0x09, 0x02, 101, 0x00, // Total length 101
+    0x02, 0x01, 0x00, 0xc0, 0x00,
+    // Standard Audio Control interface
+    0x09, 0x04, 0x00, 0x00, 0x00, 0x01, 0x01, 0x00, 0x00,
+    // Class-specific Audio Control header
+    0x09, 0x24, 0x01, 0x00, 0x01, // ADC 1.0
+    0x09, 0x00,                   // Total length 9
+    0x01, 0x01,                   // One streaming interface, number 1
+    // Standard MIDI Streaming interface, with two endpoints
+    0x09, 0x04, 0x01, 0x00, 0x02, 0x01, 0x03, 0x00, 0x00,
+    // Class-specific MIDI Streaming header
+    0x07, 0x24, 0x01, 0x00, 0x01, // MSC 1.0
+    0x41, 0x00,                   // Total length 65
+    // MIDI IN jacks: embedded 1, external 2
+    0x06, 0x24, 0x02, 0x01, 0x01, 0x00,
+    0x06, 0x24, 0x02, 0x02, 0x02, 0x00,
+    // MIDI OUT jacks: embedded 3 fed by jack 2, external 4 fed by jack 1
+    0x09, 0x24, 0x03, 0x01, 0x03, 0x01, 0x02, 0x01, 0x00,
+    0x09, 0x24, 0x03, 0x02, 0x04, 0x01, 0x01, 0x01, 0x00,
+    // Bulk OUT endpoint 2, 64 bytes, into embedded MIDI IN jack 1
+    0x09, 0x05, 0x02, 0x02, 0x40, 0x00, 0x00, 0x00, 0x00,
+    0x05, 0x25, 0x01, 0x01, 0x01,
+    // Bulk IN endpoint 1, 64 bytes, from embedded MIDI OUT jack 3
+    0x09, 0x05, 0x81, 0x02, 0x40, 0x00, 0x00, 0x00, 0x00,
+    0x05, 0x25, 0x01, 0x01, 0x03,
+];
+
+/// Told when events were sent or received.
+pub trait MidiClient {
+    /// The events passed to `send()` were read by the host.
+    fn events_sent(&self, buffer: &'static mut [u8], result: ReturnCode);
+
+    /// `len` bytes of events from the host are in `buffer`, the buffer passed
+    /// to `receive()`.
+    fn events_received(&self, buffer: &'static mut [u8], len: usize);
+}
+
+pub struct UsbMidi<'a, U: 'a> {
+    /// Helper USB client library for handling many USB operations.
+    client_ctrl: ClientCtrl<'a, 'static, U>,
+
+    /// 64 byte buffers for each endpoint.
+    buffers: [Buffer64; 2],
+
+    enabled: Cell<bool>,
+
+    /// Events waiting to be read by the host, and how many bytes of them.
+    tx_buffer: TakeCell<'static, [u8]>,
+    tx_len: Cell<usize>,
+    /// Whether the IN endpoint holds `tx_buffer`'s events.
+    in_flight: Cell<bool>,
+
+    /// Buffer to receive the next events into.
+    rx_buffer: TakeCell<'static, [u8]>,
+    /// Whether we returned `Delay` for an OUT packet because there was no
+    /// `rx_buffer`.
+    delayed_out: Cell<bool>,
+
+    client: OptionalCell<&'a dyn MidiClient>,
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> UsbMidi<'a, U> {
+    pub fn new(
+        controller: &'a U,
+        max_ctrl_packet_size: u8,
+        vendor_id: u16,
+        product_id: u16,
+        strings: &'static [&'static str; 3],
+    ) -> Self {
+        let (device_descriptor_buffer, other_descriptor_buffer) =
+            descriptors::create_raw_descriptor_buffers(
+                descriptors::DeviceDescriptor {
+                    vendor_id: vendor_id,
+                    product_id: product_id,
+                    manufacturer_string: 1,
+                    product_string: 2,
+                    serial_number_string: 3,
+                    class: 0x0, // Class given by the interface
+                    max_packet_size_ep0: max_ctrl_packet_size,
+                    ..descriptors::DeviceDescriptor::default()
+                },
+                &CONFIGURATION,
+            );
+
+        UsbMidi {
+            client_ctrl: ClientCtrl::new(
+                controller,
+                device_descriptor_buffer,
+                other_descriptor_buffer,
+                None, // No HID descriptor
+                None, // No report descriptor
+                LANGUAGES,
+                strings,
+            ),
+            buffers: [Buffer64::default(), Buffer64::default()],
+            enabled: Cell::new(false),
+            tx_buffer: TakeCell::empty(),
+            tx_len: Cell::new(0),
+            in_flight: Cell::new(false),
+            rx_buffer: TakeCell::empty(),
+            delayed_out: Cell::new(false),
+            client: OptionalCell::empty(),
+        }
+    }
+
+    pub fn set_client(&self, client: &'a dyn MidiClient) {
+        self.client.set(client);
+    }
+
+    #[inline]
+    fn controller(&self) -> &'a U {
+        self.client_ctrl.controller()
+    }
+
+    #[inline]
+    fn buffer(&'a self, i: usize) -> &'a [VolatileCell<u8>; 64] {
+        &self.buffers[i - 1].buf
+    }
+
+    /// Send the first `len` bytes of `buffer`, a whole number of event
+    /// packets and at most `MAX_EVENTS_LEN` bytes.
+    ///
+    /// Returns `EOFF` before `enable()` and `EBUSY` while previous events are
+    /// still waiting.
+    pub fn send(
+        &'a self,
+        buffer: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        if !self.enabled.get() {
+            Err((ReturnCode::EOFF, buffer))
+        } else if self.tx_buffer.is_some() {
+            Err((ReturnCode::EBUSY, buffer))
+        } else if len == 0 || len > cmp::min(buffer.len(), MAX_EVENTS_LEN) || len % EVENT_LEN != 0 {
+            Err((ReturnCode::ESIZE, buffer))
+        } else {
+            for (p, byte) in self
+                .buffer(ENDPOINT_IN_NUM)
+                .iter()
+                .zip(buffer[..len].iter())
+            {
+                p.set(*byte);
+            }
+            self.tx_buffer.replace(buffer);
+            self.tx_len.set(len);
+            self.controller().endpoint_resume_in(ENDPOINT_IN_NUM);
+            Ok(())
+        }
+    }
+
+    /// Receive the next events from the host into `buffer`, which should
+    /// hold `MAX_EVENTS_LEN` bytes; longer packets are truncated.
+    pub fn receive(
+        &'a self,
+        buffer: &'static mut [u8],
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        if self.rx_buffer.is_some() {
+            Err((ReturnCode::EBUSY, buffer))
+        } else {
+            self.rx_buffer.replace(buffer);
+            if self.delayed_out.take() {
+                self.controller().endpoint_resume_out(ENDPOINT_OUT_NUM);
+            }
+            Ok(())
+        }
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> hil::usb::Client<'a> for UsbMidi<'a, U> {
+    fn enable(&'a self) {
+        // Set up the default control endpoint
+        self.client_ctrl.enable();
+
+        // Setup buffers for IN and OUT data transfer.
+        self.controller()
+            .endpoint_set_in_buffer(ENDPOINT_IN_NUM, self.buffer(ENDPOINT_IN_NUM));
+        self.controller()
+            .endpoint_in_enable(TransferType::Bulk, ENDPOINT_IN_NUM);
+
+        self.controller()
+            .endpoint_set_out_buffer(ENDPOINT_OUT_NUM, self.buffer(ENDPOINT_OUT_NUM));
+        self.controller()
+            .endpoint_out_enable(TransferType::Bulk, ENDPOINT_OUT_NUM);
+
+        self.enabled.set(true);
+    }
+
+    fn attach(&'a self) {
+        self.client_ctrl.attach();
+    }
+
+    fn bus_reset(&'a self) {
+        // Events in flight were lost with the reset, so offer them again.
+        self.in_flight.set(false);
+        if self.tx_buffer.is_some() {
+            self.controller().endpoint_resume_in(ENDPOINT_IN_NUM);
+        }
+    }
+
+    /// Handle a Control Setup transaction.
+    fn ctrl_setup(&'a self, endpoint: usize) -> hil::usb::CtrlSetupResult {
+        self.client_ctrl.ctrl_setup(endpoint)
+    }
+
+    /// Handle a Control In transaction
+    fn ctrl_in(&'a self, endpoint: usize) -> hil::usb::CtrlInResult {
+        self.client_ctrl.ctrl_in(endpoint)
+    }
+
+    /// Handle a Control Out transaction
+    fn ctrl_out(&'a self, endpoint: usize, packet_bytes: u32) -> hil::usb::CtrlOutResult {
+        self.client_ctrl.ctrl_out(endpoint, packet_bytes)
+    }
+
+    fn ctrl_status(&'a self, endpoint: usize) {
+        self.client_ctrl.ctrl_status(endpoint)
+    }
+
+    /// Handle the completion of a Control transfer
+    fn ctrl_status_complete(&'a self, endpoint: usize) {
+        self.client_ctrl.ctrl_status_complete(endpoint)
+    }
+
+    /// Handle a Bulk IN transaction.
+    fn packet_in(&'a self, transfer_type: TransferType, _endpoint: usize) -> hil::usb::InResult {
+        match transfer_type {
+            TransferType::Bulk => {
+                if self.tx_buffer.is_some() && !self.in_flight.get() {
+                    self.in_flight.set(true);
+                    hil::usb::InResult::Packet(self.tx_len.get())
+                } else {
+                    hil::usb::InResult::Delay
+                }
+            }
+            TransferType::Interrupt | TransferType::Control | TransferType::Isochronous => {
+                // Nothing to do for MIDI.
+                hil::usb::InResult::Delay
+            }
+        }
+    }
+
+    /// Handle a Bulk OUT transaction.
+    fn packet_out(
+        &'a self,
+        transfer_type: TransferType,
+        endpoint: usize,
+        packet_bytes: u32,
+    ) -> hil::usb::OutResult {
+        match transfer_type {
+            TransferType::Bulk => self.rx_buffer.take().map_or_else(
+                || {
+                    // Hold the events until there is somewhere to put them.
+                    self.delayed_out.set(true);
+                    hil::usb::OutResult::Delay
+                },
+                |rx_buf| {
+                    let packet = self.buffer(endpoint);
+                    let len = cmp::min(packet_bytes as usize, rx_buf.len());
+                    for (byte, p) in rx_buf[..len].iter_mut().zip(packet.iter()) {
+                        *byte = p.get();
+                    }
+                    self.client
+                        .map(move |client| client.events_received(rx_buf, len));
+                    hil::usb::OutResult::Ok
+                },
+            ),
+            TransferType::Interrupt | TransferType::Control | TransferType::Isochronous => {
+                // Nothing to do for MIDI.
+                hil::usb::OutResult::Ok
+            }
+        }
+    }
+
+    fn packet_transmitted(&'a self, _endpoint: usize) {
+        if self.in_flight.get() {
+            self.in_flight.set(false);
+            self.tx_buffer.take().map(|buf| {
+                self.client
+                    .map(move |client| client.events_sent(buf, ReturnCode::SUCCESS));
+            });
+        }
+    }
+}
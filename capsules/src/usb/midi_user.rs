@@ -0,0 +1,207 @@
+//! System call interface to the USB MIDI class
+//!
+//! Lets apps send and receive USB-MIDI event packets through a `UsbMidi`
+//! device, for example to build MIDI controllers. See `midi` for the event
+//! format.
+//!
+//! ## Instantiation
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let midi_tx = static_init!([u8; 64], [0; 64]);
+//! let midi_rx = static_init!([u8; 64], [0; 64]);
+//! let midi_driver = static_init!(
+//!     capsules::usb::midi_user::MidiSyscallDriver<'static, nrf52840::usbd::Usbd<'static>>,
+//!     capsules::usb::midi_user::MidiSyscallDriver::new(
+//!         midi, midi_tx, midi_rx, board_kernel.create_grant(&grant_cap)));
+//! midi.set_client(midi_driver);
+//! ```
+//!
+//! ## Syscall Interface
+//!
+//! - allow `0`: events to send.
+//! - allow `1`: buffer received events are copied into.
+//! - subscribe `0`: the events were read by the host, `fn(ReturnCode, 0, 0)`.
+//! - subscribe `1`: events arrived, `fn(len, 0, 0)` with `len` the number of
+//!   bytes copied.
+//! - command `0`: driver check.
+//! - command `1`: send the first `data` bytes of the events buffer. This must
+//!   be a whole number of 4 byte events, at most 64 bytes.
+//!
+//! Received events are copied to every app that subscribed to them. Only
+//! one app's events are sent at a time; the others get `EBUSY`.
+
+use core::cmp;
+
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil;
+use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
+
+use super::midi::{MidiClient, UsbMidi, MAX_EVENTS_LEN};
+
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::UsbMidi as usize;
+
+#[derive(Default)]
+pub struct App {
+    sent_callback: Option<Callback>,
+    received_callback: Option<Callback>,
+    tx: Option<AppSlice<Shared, u8>>,
+    rx: Option<AppSlice<Shared, u8>>,
+}
+
+pub struct MidiSyscallDriver<'a, U: hil::usb::UsbController<'a>> {
+    midi: &'a UsbMidi<'a, U>,
+    tx_buffer: TakeCell<'static, [u8]>,
+    /// Held here while no app is listening for events.
+    rx_buffer: TakeCell<'static, [u8]>,
+    apps: Grant<App>,
+    serving_app: OptionalCell<AppId>,
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> MidiSyscallDriver<'a, U> {
+    pub fn new(
+        midi: &'a UsbMidi<'a, U>,
+        tx_buffer: &'static mut [u8],
+        rx_buffer: &'static mut [u8],
+        apps: Grant<App>,
+    ) -> Self {
+        MidiSyscallDriver {
+            midi: midi,
+            tx_buffer: TakeCell::new(tx_buffer),
+            rx_buffer: TakeCell::new(rx_buffer),
+            apps: apps,
+            serving_app: OptionalCell::empty(),
+        }
+    }
+
+    fn send(&self, appid: AppId, len: usize) -> ReturnCode {
+        self.apps
+            .enter(appid, |app, _| {
+                let events = match app.tx {
+                    Some(ref events) if events.len() >= len => events,
+                    _ => return ReturnCode::ESIZE,
+                };
+                self.tx_buffer.take().map_or(ReturnCode::EBUSY, |buffer| {
+                    let copy = cmp::min(len, cmp::min(buffer.len(), MAX_EVENTS_LEN));
+                    buffer[..copy].copy_from_slice(&events.as_ref()[..copy]);
+                    match self.midi.send(buffer, len) {
+                        Ok(()) => {
+                            self.serving_app.set(appid);
+                            ReturnCode::SUCCESS
+                        }
+                        Err((rcode, buffer)) => {
+                            self.tx_buffer.replace(buffer);
+                            rcode
+                        }
+                    }
+                })
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    /// Hand the receive buffer back to `midi`.
+    fn receive(&self) {
+        self.rx_buffer.take().map(|buffer| {
+            if let Err((_, buffer)) = self.midi.receive(buffer) {
+                self.rx_buffer.replace(buffer);
+            }
+        });
+    }
+
+    fn anyone_listening(&self) -> bool {
+        let mut listening = false;
+        for cntr in self.apps.iter() {
+            cntr.enter(|app, _| {
+                listening = listening || app.received_callback.is_some();
+            });
+        }
+        listening
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> MidiClient for MidiSyscallDriver<'a, U> {
+    fn events_sent(&self, buffer: &'static mut [u8], result: ReturnCode) {
+        self.tx_buffer.replace(buffer);
+        self.serving_app.take().map(|appid| {
+            let _ = self.apps.enter(appid, |app, _| {
+                app.sent_callback
+                    .map(|mut cb| cb.schedule(From::from(result), 0, 0));
+            });
+        });
+    }
+
+    fn events_received(&self, buffer: &'static mut [u8], len: usize) {
+        for cntr in self.apps.iter() {
+            cntr.enter(|app, _| {
+                if let (Some(mut callback), Some(rx)) = (app.received_callback, app.rx.as_mut()) {
+                    let copy = cmp::min(len, rx.len());
+                    rx.as_mut()[..copy].copy_from_slice(&buffer[..copy]);
+                    callback.schedule(copy, 0, 0);
+                }
+            });
+        }
+        self.rx_buffer.replace(buffer);
+        if self.anyone_listening() {
+            self.receive();
+        }
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> Driver for MidiSyscallDriver<'a, U> {
+    fn allow(
+        &self,
+        appid: AppId,
+        allow_num: usize,
+        slice: Option<AppSlice<Shared, u8>>,
+    ) -> ReturnCode {
+        self.apps
+            .enter(appid, |app, _| match allow_num {
+                0 => {
+                    app.tx = slice;
+                    ReturnCode::SUCCESS
+                }
+                1 => {
+                    app.rx = slice;
+                    ReturnCode::SUCCESS
+                }
+                _ => ReturnCode::ENOSUPPORT,
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        callback: Option<Callback>,
+        appid: AppId,
+    ) -> ReturnCode {
+        let rcode = self
+            .apps
+            .enter(appid, |app, _| match subscribe_num {
+                0 => {
+                    app.sent_callback = callback;
+                    ReturnCode::SUCCESS
+                }
+                1 => {
+                    app.received_callback = callback;
+                    ReturnCode::SUCCESS
+                }
+                _ => ReturnCode::ENOSUPPORT,
+            })
+            .unwrap_or_else(|err| err.into());
+        if rcode == ReturnCode::SUCCESS && subscribe_num == 1 && callback.is_some() {
+            self.receive();
+        }
+        rcode
+    }
+
+    fn command(&self, command_num: usize, data: usize, _: usize, appid: AppId) -> ReturnCode {
+        match command_num {
+            0 => ReturnCode::SUCCESS,
+            1 => self.send(appid, data),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
@@ -1,5 +1,16 @@
 pub mod cdc;
+pub mod ctap;
+pub mod ctap_hid;
+pub mod ctap_user;
 pub mod descriptors;
+pub mod dfu;
+pub mod generic_hid;
+pub mod generic_hid_user;
+pub mod hid;
+pub mod hid_user;
+pub mod midi;
+pub mod midi_user;
+pub mod msc;
 pub mod usb_user;
 pub mod usbc_client;
 pub mod usbc_client_ctrl;
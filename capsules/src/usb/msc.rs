@@ -0,0 +1,689 @@
+//! Mass Storage Class device for USB
+//!
+//! This capsule exposes a region of a `NonvolatileStorage` device, such as
+//! internal flash behind `nonvolatile_to_pages` or an external flash chip, to
+//! a host as a removable drive. Plugging the board into a PC then lets the
+//! user copy logged data off with the file manager, provided the region holds
+//! a filesystem the host understands.
+//!
+//! It implements the Bulk-Only Transport with the SCSI transparent command
+//! set, which is what every major host OS expects from a USB stick. Only the
+//! commands hosts need to mount a drive are supported: `TEST UNIT READY`,
+//! `REQUEST SENSE`, `INQUIRY`, `MODE SENSE(6)`, `PREVENT ALLOW MEDIUM
+//! REMOVAL`, `READ CAPACITY(10)`, `READ(10)` and `WRITE(10)`. The drive has
+//! a single LUN and 512 byte blocks.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let msc_buffer = static_init!([u8; 512], [0; 512]);
+//! let msc = static_init!(
+//!     capsules::usb::msc::UsbMsc<'static, nrf52840::usbd::Usbd<'static>>,
+//!     capsules::usb::msc::UsbMsc::new(
+//!         &nrf52840::usbd::USBD,
+//!         capsules::usb::msc::MAX_CTRL_PACKET_SIZE_NRF52840,
+//!         0x1915,
+//!         0x503c,
+//!         strings,
+//!         nv_to_page,
+//!         0x60000, // Start of the exported region
+//!         256,     // Number of blocks (128 KiB)
+//!         msc_buffer,
+//!     )
+//! );
+//! nv_to_page.set_client(msc);
+//! nrf52840::usbd::USBD.set_client(msc);
+//! msc.enable();
+//! msc.attach();
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+
+use super::descriptors;
+use super::descriptors::Buffer64;
+use super::descriptors::EndpointAddress;
+use super::descriptors::EndpointDescriptor;
+use super::descriptors::InterfaceDescriptor;
+use super::descriptors::RequestType;
+use super::descriptors::TransferDirection;
+use super::usbc_client_ctrl::ClientCtrl;
+
+use kernel::common::cells::TakeCell;
+use kernel::common::cells::VolatileCell;
+use kernel::hil;
+use kernel::hil::nonvolatile_storage::{NonvolatileStorage, NonvolatileStorageClient};
+use kernel::hil::usb::TransferType;
+use kernel::ReturnCode;
+
+/// Identifying number for the endpoint when transferring data from us to the
+/// host.
+const ENDPOINT_IN_NUM: usize = 2;
+/// Identifying number for the endpoint when transferring data from the host to
+/// us.
+const ENDPOINT_OUT_NUM: usize = 3;
+
+static LANGUAGES: &'static [u16; 1] = &[
+    0x0409, // English (United States)
+];
+/// Platform-specific packet length for the `SAM4L` USB hardware.
+pub const MAX_CTRL_PACKET_SIZE_SAM4L: u8 = 8;
+/// Platform-specific packet length for the `nRF52` USB hardware.
+pub const MAX_CTRL_PACKET_SIZE_NRF52840: u8 = 64;
+/// Platform-specific packet length for the `earlgrey` USB hardware.
+pub const MAX_CTRL_PACKET_SIZE_EARLGREY: u8 = 64;
+
+/// Size of the blocks the drive is made of. The buffer passed to `new()`
+/// must be at least this long.
+pub const BLOCK_SIZE: usize = 512;
+
+const N_ENDPOINTS: usize = 3;
+
+const CBW_SIGNATURE: u32 = 0x43425355;
+const CSW_SIGNATURE: u32 = 0x53425355;
+const CBW_LEN: usize = 31;
+const CSW_LEN: usize = 13;
+
+/// Bulk-Only Mass Storage Reset class request.
+const REQUEST_RESET: u8 = 0xff;
+/// Get Max LUN class request.
+const REQUEST_GET_MAX_LUN: u8 = 0xfe;
+
+const INQUIRY_DATA: [u8; 36] = [
+    0x00, // Direct access block device
+    0x80, // Removable
+    0x04, // SPC-2
+    0x02, // Response data format
+    31,   // Additional length
+    0x00, 0x00, 0x00, //
+    b'T', b'o', b'c', b'k', b' ', b' ', b' ', b' ', // Vendor
+    b'M', b'a', b's', b's', b' ', b'S', b't', b'o', // Product
+    b'r', b'a', b'g', b'e', b' ', b' ', b' ', b' ', //
+    b'1', b'.', b'0', b' ', // Revision
+];
+
+/// SCSI operation codes.
+#[derive(PartialEq)]
+enum ScsiCommand {
+    TestUnitReady = 0x00,
+    RequestSense = 0x03,
+    Inquiry = 0x12,
+    ModeSense6 = 0x1a,
+    PreventAllowMediumRemoval = 0x1e,
+    ReadCapacity10 = 0x25,
+    Read10 = 0x28,
+    Write10 = 0x2a,
+    NotSupported,
+}
+
+impl From<u8> for ScsiCommand {
+    fn from(num: u8) -> Self {
+        match num {
+            0x00 => ScsiCommand::TestUnitReady,
+            0x03 => ScsiCommand::RequestSense,
+            0x12 => ScsiCommand::Inquiry,
+            0x1a => ScsiCommand::ModeSense6,
+            0x1e => ScsiCommand::PreventAllowMediumRemoval,
+            0x25 => ScsiCommand::ReadCapacity10,
+            0x28 => ScsiCommand::Read10,
+            0x2a => ScsiCommand::Write10,
+            _ => ScsiCommand::NotSupported,
+        }
+    }
+}
+
+/// SCSI sense data, reported by `REQUEST SENSE` after a command fails.
+#[derive(Copy, Clone)]
+struct Sense {
+    key: u8,
+    code: u8,
+}
+
+const SENSE_NONE: Sense = Sense {
+    key: 0x00,
+    code: 0x00,
+};
+const SENSE_INVALID_COMMAND: Sense = Sense {
+    key: 0x05,
+    code: 0x20,
+};
+const SENSE_LBA_OUT_OF_RANGE: Sense = Sense {
+    key: 0x05,
+    code: 0x21,
+};
+const SENSE_READ_ERROR: Sense = Sense {
+    key: 0x03,
+    code: 0x11,
+};
+const SENSE_WRITE_ERROR: Sense = Sense {
+    key: 0x03,
+    code: 0x0c,
+};
+
+/// States of the Bulk-Only Transport.
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum State {
+    /// Waiting for a Command Block Wrapper from the host.
+    Command,
+    /// Sending the data in `buffer` to the host, reading further blocks from
+    /// storage as it runs out.
+    DataIn,
+    /// Receiving blocks from the host and writing them to storage.
+    DataOut,
+    /// The Command Status Wrapper is ready to be sent.
+    Status,
+    /// The Command Status Wrapper has been handed to the controller.
+    SendingStatus,
+}
+
+pub struct UsbMsc<'a, U: 'a> {
+    /// Helper USB client library for handling many USB operations.
+    client_ctrl: ClientCtrl<'a, 'static, U>,
+
+    /// 64 byte buffers for each endpoint.
+    buffers: [Buffer64; N_ENDPOINTS],
+
+    storage: &'a dyn NonvolatileStorage<'static>,
+    /// Address of the first block in `storage`.
+    base: usize,
+    num_blocks: u32,
+
+    state: Cell<State>,
+
+    /// Holds one block, or the response to a command other than `READ(10)`.
+    /// It is empty while a storage operation is in progress.
+    buffer: TakeCell<'static, [u8]>,
+    /// Number of valid bytes in `buffer` when sending, or the number received
+    /// so far when receiving.
+    buffer_len: Cell<usize>,
+    /// How much of `buffer` has been sent.
+    buffer_offset: Cell<usize>,
+
+    /// Next block to read or write, and how many are left.
+    lba: Cell<u32>,
+    blocks_left: Cell<u32>,
+
+    /// From the current Command Block Wrapper.
+    tag: Cell<u32>,
+    /// Bytes of the transfer length the host asked for that have not been
+    /// transferred yet.
+    residue: Cell<u32>,
+    failed: Cell<bool>,
+    sense: Cell<Sense>,
+
+    /// Whether we returned `Delay` for an OUT packet while storage was busy.
+    delayed_out: Cell<bool>,
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> UsbMsc<'a, U> {
+    pub fn new(
+        controller: &'a U,
+        max_ctrl_packet_size: u8,
+        vendor_id: u16,
+        product_id: u16,
+        strings: &'static [&'static str; 3],
+        storage: &'a dyn NonvolatileStorage<'static>,
+        base: usize,
+        num_blocks: u32,
+        buffer: &'static mut [u8],
+    ) -> Self {
+        let interfaces: &mut [InterfaceDescriptor] = &mut [InterfaceDescriptor {
+            interface_number: 0,
+            interface_class: 0x08,    // Mass storage
+            interface_subclass: 0x06, // SCSI transparent command set
+            interface_protocol: 0x50, // Bulk-only transport
+            ..InterfaceDescriptor::default()
+        }];
+
+        let endpoints: &[&[EndpointDescriptor]] = &[&[
+            EndpointDescriptor {
+                endpoint_address: EndpointAddress::new_const(
+                    ENDPOINT_IN_NUM,
+                    TransferDirection::DeviceToHost,
+                ),
+                transfer_type: TransferType::Bulk,
+                max_packet_size: 64,
+                interval: 0,
+            },
+            EndpointDescriptor {
+                endpoint_address: EndpointAddress::new_const(
+                    ENDPOINT_OUT_NUM,
+                    TransferDirection::HostToDevice,
+                ),
+                transfer_type: TransferType::Bulk,
+                max_packet_size: 64,
+                interval: 0,
+            },
+        ]];
+
+        let (device_descriptor_buffer, other_descriptor_buffer) =
+            descriptors::create_descriptor_buffers(
+                descriptors::DeviceDescriptor {
+                    vendor_id: vendor_id,
+                    product_id: product_id,
+                    manufacturer_string: 1,
+                    product_string: 2,
+                    serial_number_string: 3,
+                    class: 0x0, // Class given by the interface
+                    max_packet_size_ep0: max_ctrl_packet_size,
+                    ..descriptors::DeviceDescriptor::default()
+                },
+                descriptors::ConfigurationDescriptor {
+                    ..descriptors::ConfigurationDescriptor::default()
+                },
+                interfaces,
+                endpoints,
+                None, // No HID descriptor
+                None, // No CDC descriptor array
+                None, // No DFU descriptor
+            );
+
+        UsbMsc {
+            client_ctrl: ClientCtrl::new(
+                controller,
+                device_descriptor_buffer,
+                other_descriptor_buffer,
+                None, // No HID descriptor
+                None, // No report descriptor
+                LANGUAGES,
+                strings,
+            ),
+            buffers: [
+                Buffer64::default(),
+                Buffer64::default(),
+                Buffer64::default(),
+            ],
+            storage: storage,
+            base: base,
+            num_blocks: num_blocks,
+            state: Cell::new(State::Command),
+            buffer: TakeCell::new(buffer),
+            buffer_len: Cell::new(0),
+            buffer_offset: Cell::new(0),
+            lba: Cell::new(0),
+            blocks_left: Cell::new(0),
+            tag: Cell::new(0),
+            residue: Cell::new(0),
+            failed: Cell::new(false),
+            sense: Cell::new(SENSE_NONE),
+            delayed_out: Cell::new(false),
+        }
+    }
+
+    #[inline]
+    fn controller(&self) -> &'a U {
+        self.client_ctrl.controller()
+    }
+
+    #[inline]
+    fn buffer(&'a self, i: usize) -> &'a [VolatileCell<u8>; 64] {
+        &self.buffers[i - 1].buf
+    }
+
+    /// Parse a Command Block Wrapper and start the command it carries.
+    fn command(&'a self, packet: &[VolatileCell<u8>], packet_bytes: usize) {
+        let get_u32 = |i: usize| {
+            u32::from_le_bytes([
+                packet[i].get(),
+                packet[i + 1].get(),
+                packet[i + 2].get(),
+                packet[i + 3].get(),
+            ])
+        };
+        if packet_bytes != CBW_LEN || get_u32(0) != CBW_SIGNATURE {
+            // Not a valid CBW, so there is nothing we can answer.
+            return;
+        }
+        self.tag.set(get_u32(4));
+        self.residue.set(get_u32(8));
+        self.failed.set(false);
+
+        let cdb = &packet[15..31];
+        let cdb_u32 = |i: usize| {
+            u32::from_be_bytes([
+                cdb[i].get(),
+                cdb[i + 1].get(),
+                cdb[i + 2].get(),
+                cdb[i + 3].get(),
+            ])
+        };
+        let cdb_u16 = |i: usize| u16::from_be_bytes([cdb[i].get(), cdb[i + 1].get()]);
+
+        match ScsiCommand::from(cdb[0].get()) {
+            ScsiCommand::TestUnitReady | ScsiCommand::PreventAllowMediumRemoval => {
+                self.status();
+            }
+            ScsiCommand::RequestSense => {
+                let sense = self.sense.replace(SENSE_NONE);
+                let mut data = [0; 18];
+                data[0] = 0x70; // Current error, fixed format
+                data[2] = sense.key;
+                data[7] = 10; // Additional length
+                data[12] = sense.code;
+                self.respond(&data);
+            }
+            ScsiCommand::Inquiry => {
+                self.respond(&INQUIRY_DATA);
+            }
+            ScsiCommand::ModeSense6 => {
+                // No mode pages, not write protected.
+                self.respond(&[3, 0, 0, 0]);
+            }
+            ScsiCommand::ReadCapacity10 => {
+                let mut data = [0; 8];
+                data[0..4].copy_from_slice(&(self.num_blocks - 1).to_be_bytes());
+                data[4..8].copy_from_slice(&(BLOCK_SIZE as u32).to_be_bytes());
+                self.respond(&data);
+            }
+            ScsiCommand::Read10 | ScsiCommand::Write10 => {
+                let lba = cdb_u32(2);
+                let count = cdb_u16(7) as u32;
+                if lba >= self.num_blocks || count > self.num_blocks - lba {
+                    self.fail(SENSE_LBA_OUT_OF_RANGE);
+                } else if count == 0 {
+                    self.status();
+                } else {
+                    self.lba.set(lba);
+                    self.blocks_left.set(count);
+                    self.buffer_len.set(0);
+                    self.buffer_offset.set(0);
+                    if cdb[0].get() == ScsiCommand::Read10 as u8 {
+                        self.state.set(State::DataIn);
+                        self.read_block();
+                    } else {
+                        self.state.set(State::DataOut);
+                    }
+                }
+            }
+            ScsiCommand::NotSupported => {
+                self.fail(SENSE_INVALID_COMMAND);
+            }
+        }
+    }
+
+    /// Send `data`, truncated to what the host asked for, then the status.
+    fn respond(&'a self, data: &[u8]) {
+        let len = cmp::min(data.len(), self.residue.get() as usize);
+        let copied = self.buffer.map_or(false, |buffer| {
+            buffer[..len].copy_from_slice(&data[..len]);
+            true
+        });
+        if !copied {
+            // The buffer was lost to a failed storage operation.
+            self.fail(SENSE_READ_ERROR);
+        } else if len == 0 {
+            self.status();
+        } else {
+            self.buffer_len.set(len);
+            self.buffer_offset.set(0);
+            self.blocks_left.set(0);
+            self.state.set(State::DataIn);
+            self.controller().endpoint_resume_in(ENDPOINT_IN_NUM);
+        }
+    }
+
+    fn fail(&'a self, sense: Sense) {
+        self.failed.set(true);
+        self.sense.set(sense);
+        self.status();
+    }
+
+    fn status(&'a self) {
+        self.state.set(State::Status);
+        self.controller().endpoint_resume_in(ENDPOINT_IN_NUM);
+    }
+
+    fn block_address(&self) -> usize {
+        self.base + self.lba.get() as usize * BLOCK_SIZE
+    }
+
+    fn read_block(&'a self) {
+        self.buffer.take().map(|buffer| {
+            let rcode = self.storage.read(buffer, self.block_address(), BLOCK_SIZE);
+            if rcode != ReturnCode::SUCCESS {
+                self.fail(SENSE_READ_ERROR);
+            }
+        });
+    }
+
+    fn write_block(&'a self) {
+        self.buffer.take().map(|buffer| {
+            let rcode = self.storage.write(buffer, self.block_address(), BLOCK_SIZE);
+            if rcode != ReturnCode::SUCCESS {
+                self.fail(SENSE_WRITE_ERROR);
+            }
+        });
+    }
+
+    /// Write the Command Status Wrapper into `packet`.
+    fn write_status(&self, packet: &[VolatileCell<u8>]) {
+        let mut csw = [0; CSW_LEN];
+        csw[0..4].copy_from_slice(&CSW_SIGNATURE.to_le_bytes());
+        csw[4..8].copy_from_slice(&self.tag.get().to_le_bytes());
+        csw[8..12].copy_from_slice(&self.residue.get().to_le_bytes());
+        csw[12] = self.failed.get() as u8;
+        for (p, byte) in packet.iter().zip(csw.iter()) {
+            p.set(*byte);
+        }
+    }
+
+    fn reset(&self) {
+        self.state.set(State::Command);
+        self.blocks_left.set(0);
+        self.delayed_out.set(false);
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> hil::usb::Client<'a> for UsbMsc<'a, U> {
+    fn enable(&'a self) {
+        // Set up the default control endpoint
+        self.client_ctrl.enable();
+
+        // Setup buffers for IN and OUT data transfer.
+        self.controller()
+            .endpoint_set_in_buffer(ENDPOINT_IN_NUM, self.buffer(ENDPOINT_IN_NUM));
+        self.controller()
+            .endpoint_in_enable(TransferType::Bulk, ENDPOINT_IN_NUM);
+
+        self.controller()
+            .endpoint_set_out_buffer(ENDPOINT_OUT_NUM, self.buffer(ENDPOINT_OUT_NUM));
+        self.controller()
+            .endpoint_out_enable(TransferType::Bulk, ENDPOINT_OUT_NUM);
+    }
+
+    fn attach(&'a self) {
+        self.client_ctrl.attach();
+    }
+
+    fn bus_reset(&'a self) {
+        self.reset();
+    }
+
+    /// Handle a Control Setup transaction.
+    ///
+    /// The drive has a single LUN, so Get Max LUN is stalled, which hosts
+    /// take to mean LUN 0 only, instead of being answered with the
+    /// placeholder data `ClientCtrl` returns for unknown requests.
+    fn ctrl_setup(&'a self, endpoint: usize) -> hil::usb::CtrlSetupResult {
+        let class_request = descriptors::SetupData::get(&self.client_ctrl.ctrl_buffer.buf)
+            .filter(|setup_data| match setup_data.request_type.request_type() {
+                RequestType::Class => true,
+                _ => false,
+            })
+            .map(|setup_data| setup_data.request_code);
+        match class_request {
+            Some(REQUEST_GET_MAX_LUN) => hil::usb::CtrlSetupResult::ErrGeneric,
+            Some(REQUEST_RESET) => {
+                self.reset();
+                self.client_ctrl.ctrl_setup(endpoint)
+            }
+            _ => self.client_ctrl.ctrl_setup(endpoint),
+        }
+    }
+
+    /// Handle a Control In transaction
+    fn ctrl_in(&'a self, endpoint: usize) -> hil::usb::CtrlInResult {
+        self.client_ctrl.ctrl_in(endpoint)
+    }
+
+    /// Handle a Control Out transaction
+    fn ctrl_out(&'a self, endpoint: usize, packet_bytes: u32) -> hil::usb::CtrlOutResult {
+        self.client_ctrl.ctrl_out(endpoint, packet_bytes)
+    }
+
+    fn ctrl_status(&'a self, endpoint: usize) {
+        self.client_ctrl.ctrl_status(endpoint)
+    }
+
+    /// Handle the completion of a Control transfer
+    fn ctrl_status_complete(&'a self, endpoint: usize) {
+        self.client_ctrl.ctrl_status_complete(endpoint)
+    }
+
+    /// Handle a Bulk IN transaction.
+    ///
+    /// Sends the next packet of data from `buffer`, or the status once all
+    /// data has been sent.
+    fn packet_in(&'a self, transfer_type: TransferType, endpoint: usize) -> hil::usb::InResult {
+        match transfer_type {
+            TransferType::Bulk => {
+                let packet = self.buffer(endpoint);
+                match self.state.get() {
+                    State::DataIn => self.buffer.map_or(hil::usb::InResult::Delay, |buffer| {
+                        let offset = self.buffer_offset.get();
+                        let remaining = self.buffer_len.get() - offset;
+                        if remaining == 0 {
+                            // Waiting for the next block to be read.
+                            return hil::usb::InResult::Delay;
+                        }
+                        let to_send = cmp::min(packet.len(), remaining);
+                        for i in 0..to_send {
+                            packet[i].set(buffer[offset + i]);
+                        }
+                        self.buffer_offset.set(offset + to_send);
+                        self.residue
+                            .set(self.residue.get().saturating_sub(to_send as u32));
+                        hil::usb::InResult::Packet(to_send)
+                    }),
+                    State::Status => {
+                        self.write_status(packet);
+                        self.state.set(State::SendingStatus);
+                        hil::usb::InResult::Packet(CSW_LEN)
+                    }
+                    State::Command | State::DataOut | State::SendingStatus => {
+                        hil::usb::InResult::Delay
+                    }
+                }
+            }
+            TransferType::Control | TransferType::Isochronous | TransferType::Interrupt => {
+                // Nothing to do for mass storage.
+                hil::usb::InResult::Delay
+            }
+        }
+    }
+
+    /// Handle a Bulk OUT transaction
+    fn packet_out(
+        &'a self,
+        transfer_type: TransferType,
+        endpoint: usize,
+        packet_bytes: u32,
+    ) -> hil::usb::OutResult {
+        match transfer_type {
+            TransferType::Bulk => {
+                let packet = self.buffer(endpoint);
+                match self.state.get() {
+                    State::Command => {
+                        self.command(packet, packet_bytes as usize);
+                        hil::usb::OutResult::Ok
+                    }
+                    State::DataOut => {
+                        if self.buffer.is_none() {
+                            // The previous block is still being written.
+                            self.delayed_out.set(true);
+                            return hil::usb::OutResult::Delay;
+                        }
+                        let full = self.buffer.map_or(false, |buffer| {
+                            let received = self.buffer_len.get();
+                            let to_copy = cmp::min(packet_bytes as usize, BLOCK_SIZE - received);
+                            for i in 0..to_copy {
+                                buffer[received + i] = packet[i].get();
+                            }
+                            self.buffer_len.set(received + to_copy);
+                            self.residue
+                                .set(self.residue.get().saturating_sub(to_copy as u32));
+                            received + to_copy == BLOCK_SIZE
+                        });
+                        if full {
+                            self.write_block();
+                        }
+                        hil::usb::OutResult::Ok
+                    }
+                    State::DataIn | State::Status | State::SendingStatus => {
+                        // The host should not send anything until it has the
+                        // status.
+                        hil::usb::OutResult::Ok
+                    }
+                }
+            }
+            TransferType::Control | TransferType::Isochronous | TransferType::Interrupt => {
+                // Nothing to do for mass storage.
+                hil::usb::OutResult::Ok
+            }
+        }
+    }
+
+    fn packet_transmitted(&'a self, _endpoint: usize) {
+        match self.state.get() {
+            State::SendingStatus => {
+                self.state.set(State::Command);
+            }
+            State::DataIn => {
+                if self.buffer_offset.get() < self.buffer_len.get() {
+                    self.controller().endpoint_resume_in(ENDPOINT_IN_NUM);
+                } else if self.blocks_left.get() > 0 {
+                    self.read_block();
+                } else {
+                    self.status();
+                }
+            }
+            State::Command | State::DataOut | State::Status => {}
+        }
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> NonvolatileStorageClient<'static> for UsbMsc<'a, U> {
+    fn read_done(&self, buffer: &'static mut [u8], length: usize) {
+        self.buffer.replace(buffer);
+        if self.state.get() != State::DataIn {
+            // Reset while the read was in progress.
+            return;
+        }
+        self.lba.set(self.lba.get() + 1);
+        self.blocks_left.set(self.blocks_left.get() - 1);
+        self.buffer_len.set(length);
+        self.buffer_offset.set(0);
+        self.controller().endpoint_resume_in(ENDPOINT_IN_NUM);
+    }
+
+    fn write_done(&self, buffer: &'static mut [u8], _length: usize) {
+        self.buffer.replace(buffer);
+        if self.state.get() != State::DataOut {
+            // Reset while the write was in progress.
+            return;
+        }
+        self.lba.set(self.lba.get() + 1);
+        self.blocks_left.set(self.blocks_left.get() - 1);
+        self.buffer_len.set(0);
+        if self.blocks_left.get() == 0 {
+            self.state.set(State::Status);
+            self.controller().endpoint_resume_in(ENDPOINT_IN_NUM);
+        }
+        if self.delayed_out.take() {
+            self.controller().endpoint_resume_out(ENDPOINT_OUT_NUM);
+        }
+    }
+}
@@ -0,0 +1,264 @@
+//! Virtualize the AES-CCM interface to enable multiple users of a single
+//! AES-CCM engine.
+//!
+//! Each `VirtualAES128CCM` keeps its own key and nonce, so clients such as
+//! 802.15.4 link-layer security, DTLS, and a userspace crypto driver never
+//! observe or depend on each other's key material. The key and nonce are only
+//! loaded into the shared engine immediately before that client's operation
+//! runs.
+//!
+//! Each client may have one operation outstanding. Operations issued while the
+//! engine is busy are queued, and queued operations are serviced round-robin
+//! across clients so that a busy client cannot starve the others.
+//!
+//! Given a `RandomJitter` with `set_jitter()`, the mux waits a random time
+//! before starting each operation, so that the start of the engine's work
+//! cannot be lined up across power traces.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let mux_ccm = static_init!(
+//!     MuxAES128CCM<'static, capsules::aes_ccm::AES128CCM<'static, sam4l::aes::Aes>>,
+//!     MuxAES128CCM::new(aes_ccm)
+//! );
+//! aes_ccm.set_client(mux_ccm);
+//!
+//! let framer_ccm = static_init!(
+//!     VirtualAES128CCM<'static, capsules::aes_ccm::AES128CCM<'static, sam4l::aes::Aes>>,
+//!     VirtualAES128CCM::new(mux_ccm)
+//! );
+//! framer_ccm.setup();
+//! ```
+
+use crate::jitter::RandomJitter;
+use core::cell::Cell;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::{List, ListLink, ListNode};
+use kernel::hil::symmetric_encryption::{CCMClient, AES128CCM, AES128_KEY_SIZE, CCM_NONCE_LENGTH};
+use kernel::ReturnCode;
+
+/// Parameters of a queued `crypt()` call.
+#[derive(Copy, Clone)]
+struct CryptOp {
+    a_off: usize,
+    m_off: usize,
+    m_len: usize,
+    mic_len: usize,
+    confidential: bool,
+    encrypting: bool,
+}
+
+pub struct MuxAES128CCM<'a, A: AES128CCM<'a>> {
+    ccm: &'a A,
+    clients: List<'a, VirtualAES128CCM<'a, A>>,
+    inflight: OptionalCell<&'a VirtualAES128CCM<'a, A>>,
+    /// Id of the client that most recently got the engine, used to pick the
+    /// next queued client in round-robin order.
+    last_served: Cell<u32>,
+    next_id: Cell<u32>,
+    jitter: OptionalCell<&'a dyn RandomJitter>,
+}
+
+impl<'a, A: AES128CCM<'a>> MuxAES128CCM<'a, A> {
+    pub const fn new(ccm: &'a A) -> MuxAES128CCM<'a, A> {
+        MuxAES128CCM {
+            ccm: ccm,
+            clients: List::new(),
+            inflight: OptionalCell::empty(),
+            last_served: Cell::new(0),
+            next_id: Cell::new(0),
+            jitter: OptionalCell::empty(),
+        }
+    }
+
+    /// Delay the start of each operation by a random time from `jitter`.
+    pub fn set_jitter(&self, jitter: &'a dyn RandomJitter) {
+        self.jitter.set(jitter);
+    }
+
+    /// Load `client`'s key and nonce into the engine and start its operation.
+    fn start(
+        &self,
+        client: &'a VirtualAES128CCM<'a, A>,
+        buf: &'static mut [u8],
+        op: CryptOp,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        let key = client.key.get();
+        let nonce = client.nonce.get();
+        let rcode = self.ccm.set_key(&key);
+        if rcode != ReturnCode::SUCCESS {
+            return (rcode, Some(buf));
+        }
+        let rcode = self.ccm.set_nonce(&nonce);
+        if rcode != ReturnCode::SUCCESS {
+            return (rcode, Some(buf));
+        }
+
+        self.inflight.set(client);
+        self.last_served.set(client.id);
+        self.jitter.map(|jitter| jitter.delay());
+        let (rcode, buf) = self.ccm.crypt(
+            buf,
+            op.a_off,
+            op.m_off,
+            op.m_len,
+            op.mic_len,
+            op.confidential,
+            op.encrypting,
+        );
+        if rcode != ReturnCode::SUCCESS {
+            self.inflight.clear();
+        }
+        (rcode, buf)
+    }
+
+    /// Start the next queued operation, if any. Clients with an id greater
+    /// than the last served client are preferred, wrapping around to the
+    /// lowest id.
+    fn do_next_op(&self) {
+        while self.inflight.is_none() {
+            let last = self.last_served.get();
+            let next = self
+                .clients
+                .iter()
+                .filter(|c| c.pending.is_some())
+                .min_by_key(|c| (c.id <= last, c.id));
+            let client = match next {
+                Some(client) => client,
+                None => return,
+            };
+            let op = client.pending.take().unwrap();
+            if let Some(buf) = client.buf.take() {
+                let (rcode, buf) = self.start(client, buf, op);
+                if rcode != ReturnCode::SUCCESS {
+                    // Report the failure to the client and try the next one.
+                    buf.map(|buf| {
+                        client.client.map(move |c| c.crypt_done(buf, rcode, false));
+                    });
+                }
+            }
+        }
+    }
+}
+
+impl<'a, A: AES128CCM<'a>> CCMClient for MuxAES128CCM<'a, A> {
+    fn crypt_done(&self, buf: &'static mut [u8], res: ReturnCode, tag_is_valid: bool) {
+        let done = self.inflight.take();
+        // Start the next queued operation before the callback, so that a
+        // client calling `crypt()` again from it queues behind the others.
+        self.do_next_op();
+        done.map(move |client| {
+            client
+                .client
+                .map(move |c| c.crypt_done(buf, res, tag_is_valid));
+        });
+    }
+}
+
+pub struct VirtualAES128CCM<'a, A: AES128CCM<'a>> {
+    mux: &'a MuxAES128CCM<'a, A>,
+    next: ListLink<'a, VirtualAES128CCM<'a, A>>,
+    client: OptionalCell<&'a dyn CCMClient>,
+    id: u32,
+    key: Cell<[u8; AES128_KEY_SIZE]>,
+    nonce: Cell<[u8; CCM_NONCE_LENGTH]>,
+    buf: TakeCell<'static, [u8]>,
+    pending: OptionalCell<CryptOp>,
+}
+
+impl<'a, A: AES128CCM<'a>> ListNode<'a, VirtualAES128CCM<'a, A>> for VirtualAES128CCM<'a, A> {
+    fn next(&'a self) -> &'a ListLink<'a, VirtualAES128CCM<'a, A>> {
+        &self.next
+    }
+}
+
+impl<'a, A: AES128CCM<'a>> VirtualAES128CCM<'a, A> {
+    pub fn new(mux: &'a MuxAES128CCM<'a, A>) -> VirtualAES128CCM<'a, A> {
+        let id = mux.next_id.get();
+        mux.next_id.set(id + 1);
+
+        VirtualAES128CCM {
+            mux: mux,
+            next: ListLink::empty(),
+            client: OptionalCell::empty(),
+            id: id,
+            key: Cell::new(Default::default()),
+            nonce: Cell::new(Default::default()),
+            buf: TakeCell::empty(),
+            pending: OptionalCell::empty(),
+        }
+    }
+
+    /// Register this client with the mux. Must be called once during board
+    /// setup.
+    pub fn setup(&'a self) {
+        self.mux.clients.push_head(self);
+    }
+}
+
+impl<'a, A: AES128CCM<'a>> AES128CCM<'a> for VirtualAES128CCM<'a, A> {
+    fn set_client(&'a self, client: &'a dyn CCMClient) {
+        self.client.set(client);
+    }
+
+    fn set_key(&self, key: &[u8]) -> ReturnCode {
+        if key.len() != AES128_KEY_SIZE {
+            return ReturnCode::EINVAL;
+        }
+        let mut new_key = [0; AES128_KEY_SIZE];
+        new_key.copy_from_slice(key);
+        self.key.set(new_key);
+        ReturnCode::SUCCESS
+    }
+
+    fn set_nonce(&self, nonce: &[u8]) -> ReturnCode {
+        if nonce.len() != CCM_NONCE_LENGTH {
+            return ReturnCode::EINVAL;
+        }
+        let mut new_nonce = [0; CCM_NONCE_LENGTH];
+        new_nonce.copy_from_slice(nonce);
+        self.nonce.set(new_nonce);
+        ReturnCode::SUCCESS
+    }
+
+    fn crypt(
+        &self,
+        buf: &'static mut [u8],
+        a_off: usize,
+        m_off: usize,
+        m_len: usize,
+        mic_len: usize,
+        confidential: bool,
+        encrypting: bool,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        if self.pending.is_some() || self.mux.inflight.map_or(false, |c| c.id == self.id) {
+            return (ReturnCode::EBUSY, Some(buf));
+        }
+
+        let op = CryptOp {
+            a_off,
+            m_off,
+            m_len,
+            mic_len,
+            confidential,
+            encrypting,
+        };
+
+        if self.mux.inflight.is_none() {
+            // The engine is idle; look ourselves up in the client list to get
+            // a reference with the mux lifetime.
+            match self.mux.clients.iter().find(|c| c.id == self.id) {
+                Some(me) => self.mux.start(me, buf, op),
+                None => (ReturnCode::EOFF, Some(buf)),
+            }
+        } else {
+            self.buf.replace(buf);
+            self.pending.set(op);
+            (ReturnCode::SUCCESS, None)
+        }
+    }
+}
@@ -1,17 +1,49 @@
 //! Virtualize the Alarm interface to enable multiple users of an underlying
 //! alarm hardware peripheral.
+//!
+//! Armed virtual alarms are kept in a list sorted by expiration, so the mux
+//! only ever needs to look at the head of the list: the underlying alarm is
+//! always set to the head, and when it fires the expired alarms are exactly a
+//! prefix of the list. Firing and reprogramming the hardware therefore take
+//! time proportional to the number of alarms that expire rather than to the
+//! number of clients. Arming an alarm walks the list to find its position,
+//! which takes time linear in the number of armed alarms and often happens in
+//! interrupt context, when a client re-arms from its callback. Few boards have
+//! more than a handful of alarms, and the list keeps each of them exact and
+//! small, so it stays the default. Boards with many alarms can use
+//! `virtual_alarm_wheel::MuxAlarmWheel` instead, which arms in constant time.
+//!
+//! The list stays sorted even though tick values wrap around: for alarms that
+//! have not expired, the time remaining until each of them decreases at the
+//! same rate, so their relative order never changes. Alarms that have expired
+//! but not yet fired are always ahead of those that have not.
+//!
+//! Virtual alarms also implement `PeriodicAlarm`. A periodic alarm is put back
+//! into the list at its next expiration just before its client is called, so
+//! the client can still disarm or re-arm it from the callback.
+//!
+//! A client that does not need precise timing can give its alarm some slack
+//! with `set_slack()`: the alarm may then fire up to that many ticks late. The
+//! mux sets the underlying alarm to the earliest time by which some alarm must
+//! fire, rather than to the earliest expiration, so that alarms expiring
+//! close together are fired from a single wakeup.
 
 use core::cell::Cell;
+use core::ptr;
 use kernel::common::cells::OptionalCell;
-use kernel::common::{List, ListLink, ListNode};
-use kernel::hil::time::{self, Alarm, Ticks, Time};
+use kernel::hil::time::{self, Alarm, PeriodicAlarm, Ticks, Time};
 use kernel::ReturnCode;
 
-/// An object to multiplex multiple "virtual" alarms over a single underlying alarm. A
-/// `VirtualMuxAlarm` is a node in a linked list of alarms that share the same underlying alarm.
+/// An object to multiplex multiple "virtual" alarms over a single underlying
+/// alarm. While armed, a `VirtualMuxAlarm` is a node in the mux's sorted list
+/// of pending alarms.
 pub struct VirtualMuxAlarm<'a, A: Alarm<'a>> {
     /// Underlying alarm which multiplexes all these virtual alarm.
     mux: &'a MuxAlarm<'a, A>,
+    /// Reference to this alarm with the lifetime of the mux, set when the
+    /// client is registered, so that `set_alarm(&self)` can link it into the
+    /// armed list.
+    this: OptionalCell<&'a VirtualMuxAlarm<'a, A>>,
     /// Reference time point when this alarm was setup.
     reference: Cell<A::Ticks>,
     /// Duration of this alarm w.r.t. the reference time point. In other words, this alarm should
@@ -20,30 +52,104 @@ pub struct VirtualMuxAlarm<'a, A: Alarm<'a>> {
     /// Whether this alarm is currently armed, i.e. whether it should fire when the time has
     /// elapsed.
     armed: Cell<bool>,
-    /// Next alarm in the list.
-    next: ListLink<'a, VirtualMuxAlarm<'a, A>>,
+    /// Period of a periodic alarm, or `None` for a one-shot alarm.
+    period: Cell<Option<A::Ticks>>,
+    /// How late this alarm may fire.
+    slack: Cell<A::Ticks>,
+    /// Next alarm in the sorted list of armed alarms.
+    next: Cell<Option<&'a VirtualMuxAlarm<'a, A>>>,
     /// Alarm client for this node in the list.
     client: OptionalCell<&'a dyn time::AlarmClient>,
 }
 
-impl<'a, A: Alarm<'a>> ListNode<'a, VirtualMuxAlarm<'a, A>> for VirtualMuxAlarm<'a, A> {
-    fn next(&self) -> &'a ListLink<VirtualMuxAlarm<'a, A>> {
-        &self.next
-    }
-}
-
 impl<'a, A: Alarm<'a>> VirtualMuxAlarm<'a, A> {
     pub fn new(mux_alarm: &'a MuxAlarm<'a, A>) -> VirtualMuxAlarm<'a, A> {
         let zero = A::ticks_from_seconds(0);
         VirtualMuxAlarm {
             mux: mux_alarm,
+            this: OptionalCell::empty(),
             reference: Cell::new(zero),
             dt: Cell::new(zero),
             armed: Cell::new(false),
-            next: ListLink::empty(),
+            period: Cell::new(None),
+            slack: Cell::new(zero),
+            next: Cell::new(None),
             client: OptionalCell::empty(),
         }
     }
+
+    /// Whether this alarm's time has passed at `now`.
+    fn expired(&self, now: A::Ticks) -> bool {
+        !now.within_range(
+            self.reference.get(),
+            self.reference.get().wrapping_add(self.dt.get()),
+        )
+    }
+
+    /// Ticks from `now` until this alarm expires, or zero if it already has.
+    fn remaining(&self, now: A::Ticks) -> A::Ticks {
+        if self.expired(now) {
+            A::Ticks::from(0 as u32)
+        } else {
+            self.reference
+                .get()
+                .wrapping_add(self.dt.get())
+                .wrapping_sub(now)
+        }
+    }
+
+    /// Let this alarm fire up to `slack` ticks after it expires, so that it
+    /// can share a wakeup with other alarms. The slack applies to every
+    /// following expiration until it is changed.
+    pub fn set_slack(&self, slack: A::Ticks) {
+        self.slack.set(slack);
+        if self.armed.get() {
+            self.mux.update_underlying();
+        }
+    }
+
+    pub fn get_slack(&self) -> A::Ticks {
+        self.slack.get()
+    }
+
+    /// The latest time this alarm may fire, as a reference and offset. The
+    /// offset saturates, so that a large slack cannot wrap the deadline back
+    /// before the expiration.
+    fn deadline(&self) -> (A::Ticks, A::Ticks) {
+        let dt = self.dt.get();
+        let late = dt.wrapping_add(self.slack.get());
+        let late = if late < dt {
+            A::Ticks::max_value()
+        } else {
+            late
+        };
+        (self.reference.get(), late)
+    }
+
+    /// Ticks from `now` until the latest time this alarm may fire, or zero if
+    /// that has passed.
+    fn deadline_remaining(&self, now: A::Ticks) -> A::Ticks {
+        let (reference, dt) = self.deadline();
+        let end = reference.wrapping_add(dt);
+        if now.within_range(reference, end) {
+            end.wrapping_sub(now)
+        } else {
+            A::Ticks::from(0 as u32)
+        }
+    }
+
+    fn arm(&self, reference: A::Ticks, dt: A::Ticks) {
+        self.this.map(|this| {
+            // Re-arming moves the alarm to its new position.
+            if self.armed.get() {
+                self.mux.remove(this);
+            }
+            self.reference.set(reference);
+            self.dt.set(dt);
+            self.armed.set(true);
+            self.mux.insert(this);
+        });
+    }
 }
 
 impl<'a, A: Alarm<'a>> Time for VirtualMuxAlarm<'a, A> {
@@ -57,31 +163,24 @@ impl<'a, A: Alarm<'a>> Time for VirtualMuxAlarm<'a, A> {
 
 impl<'a, A: Alarm<'a>> Alarm<'a> for VirtualMuxAlarm<'a, A> {
     fn set_alarm_client(&'a self, client: &'a dyn time::AlarmClient) {
-        self.mux.virtual_alarms.push_head(self);
+        self.this.set(self);
         // Reset the alarm state: should it do this? Does not seem
         // to be semantically correct. What if you just wanted to
         // change the callback. Keeping it but skeptical. -pal
+        self.disarm();
         self.reference.set(A::Ticks::from(0 as u32));
         self.dt.set(A::Ticks::from(0 as u32));
-        self.armed.set(false);
         self.client.set(client);
     }
 
     fn disarm(&self) -> ReturnCode {
+        self.period.set(None);
         if !self.armed.get() {
             return ReturnCode::SUCCESS;
         }
 
         self.armed.set(false);
-
-        let enabled = self.mux.enabled.get() - 1;
-        self.mux.enabled.set(enabled);
-
-        // If there are not more enabled alarms, disable the underlying alarm
-        // completely.
-        if enabled == 0 {
-            self.mux.alarm.disarm();
-        }
+        self.this.map(|this| self.mux.remove(this));
         ReturnCode::SUCCESS
     }
 
@@ -90,47 +189,8 @@ impl<'a, A: Alarm<'a>> Alarm<'a> for VirtualMuxAlarm<'a, A> {
     }
 
     fn set_alarm(&self, reference: Self::Ticks, dt: Self::Ticks) {
-        let enabled = self.mux.enabled.get();
-        self.reference.set(reference);
-        self.dt.set(dt);
-
-        if !self.armed.get() {
-            self.mux.enabled.set(enabled + 1);
-            self.armed.set(true);
-        }
-
-        // First alarm, so set it
-        if enabled == 0 {
-            //debug!("virtual_alarm: first alarm: set it.");
-            self.mux.set_alarm(self.reference.get(), self.dt.get());
-        } else if self.mux.firing.get() == false {
-            // If firing is true, the mux will scan all the alarms after
-            // firing and pick the soonest one so do not need to modify the
-            // mux. Otherwise, this is an alarm
-            // started in a separate code path (e.g., another event).
-            // This new alarm fires sooner if two things are both true:
-            //    1. The current earliest alarm expiration doesn't fall
-            //    in the range of [reference, reference+dt): this means
-            //    it is either in the past (before reference) or the future
-            //    (reference + dt), AND
-            //    2. now falls in the [reference, reference+dt)
-            //    window of the current earliest alarm. This means the
-            //    current earliest alarm hasn't fired yet (it is in the future).
-            // -pal
-            let cur_alarm = self.mux.alarm.get_alarm();
-            let now = self.mux.alarm.now();
-            let expiration = reference.wrapping_add(dt);
-            if !cur_alarm.within_range(reference, expiration) {
-                let next = self.mux.next_tick_vals.get();
-                if next.map_or(true, |(next_reference, next_dt)| {
-                    now.within_range(next_reference, next_reference.wrapping_add(next_dt))
-                }) {
-                    self.mux.set_alarm(reference, dt);
-                }
-            } else {
-                // current alarm will fire earlier, keep it
-            }
-        }
+        self.period.set(None);
+        self.arm(reference, dt);
     }
 
     fn get_alarm(&self) -> Self::Ticks {
@@ -142,6 +202,26 @@ impl<'a, A: Alarm<'a>> Alarm<'a> for VirtualMuxAlarm<'a, A> {
     }
 }
 
+impl<'a, A: Alarm<'a>> PeriodicAlarm<'a> for VirtualMuxAlarm<'a, A> {
+    fn set_periodic_alarm(&self, reference: Self::Ticks, period: Self::Ticks) -> ReturnCode {
+        // A zero period would expire again every time it was re-armed.
+        if period == Self::Ticks::from(0) {
+            return ReturnCode::EINVAL;
+        }
+        self.period.set(Some(period));
+        self.arm(reference, period);
+        ReturnCode::SUCCESS
+    }
+
+    fn period(&self) -> Option<Self::Ticks> {
+        if self.armed.get() {
+            self.period.get()
+        } else {
+            None
+        }
+    }
+}
+
 impl<'a, A: Alarm<'a>> time::AlarmClient for VirtualMuxAlarm<'a, A> {
     fn alarm(&self) {
         self.client.map(|client| client.alarm());
@@ -150,37 +230,105 @@ impl<'a, A: Alarm<'a>> time::AlarmClient for VirtualMuxAlarm<'a, A> {
 
 /// Structure to control a set of virtual alarms multiplexed together on top of a single alarm.
 pub struct MuxAlarm<'a, A: Alarm<'a>> {
-    /// Head of the linked list of virtual alarms multiplexed together.
-    virtual_alarms: List<'a, VirtualMuxAlarm<'a, A>>,
-    /// Number of virtual alarms that are currently enabled.
-    enabled: Cell<usize>,
+    /// Head of the list of armed virtual alarms, sorted by expiration.
+    armed: Cell<Option<&'a VirtualMuxAlarm<'a, A>>>,
     /// Underlying alarm, over which the virtual alarms are multiplexed.
     alarm: &'a A,
-    /// Whether we are firing; used to delay restarted alarms
+    /// Whether we are firing; the underlying alarm is reprogrammed once all
+    /// expired alarms have fired.
     firing: Cell<bool>,
-    /// Reference to next alarm
-    next_tick_vals: Cell<Option<(A::Ticks, A::Ticks)>>,
 }
 
 impl<'a, A: Alarm<'a>> MuxAlarm<'a, A> {
     pub const fn new(alarm: &'a A) -> MuxAlarm<'a, A> {
         MuxAlarm {
-            virtual_alarms: List::new(),
-            enabled: Cell::new(0),
+            armed: Cell::new(None),
             alarm: alarm,
             firing: Cell::new(false),
-            next_tick_vals: Cell::new(None),
         }
     }
 
-    pub fn set_alarm(&self, reference: A::Ticks, dt: A::Ticks) {
-        self.next_tick_vals.set(Some((reference, dt)));
-        self.alarm.set_alarm(reference, dt);
+    /// Link `node` into the armed list after every alarm that expires no
+    /// later than it does.
+    fn insert(&self, node: &'a VirtualMuxAlarm<'a, A>) {
+        let now = self.alarm.now();
+        let remaining = node.remaining(now);
+
+        let mut prev: Option<&'a VirtualMuxAlarm<'a, A>> = None;
+        let mut cur = self.armed.get();
+        while let Some(c) = cur {
+            if !c.expired(now) && c.remaining(now) > remaining {
+                break;
+            }
+            prev = cur;
+            cur = c.next.get();
+        }
+
+        node.next.set(cur);
+        match prev {
+            Some(p) => p.next.set(Some(node)),
+            None => {
+                self.armed.set(Some(node));
+                self.update_underlying();
+            }
+        }
+    }
+
+    /// Unlink `node` from the armed list.
+    fn remove(&self, node: &'a VirtualMuxAlarm<'a, A>) {
+        let mut prev: Option<&'a VirtualMuxAlarm<'a, A>> = None;
+        let mut cur = self.armed.get();
+        while let Some(c) = cur {
+            if ptr::eq(c, node) {
+                let next = c.next.get();
+                c.next.set(None);
+                match prev {
+                    Some(p) => p.next.set(next),
+                    None => {
+                        self.armed.set(next);
+                        self.update_underlying();
+                    }
+                }
+                return;
+            }
+            prev = cur;
+            cur = c.next.get();
+        }
     }
 
-    pub fn disarm(&self) {
-        self.next_tick_vals.set(None);
-        self.alarm.disarm();
+    /// Set the underlying alarm to the earliest deadline of the armed
+    /// alarms, or disarm it if there are none. Without slack, this is the
+    /// head of the list.
+    fn update_underlying(&self) {
+        if self.firing.get() {
+            return;
+        }
+        match self.armed.get() {
+            Some(head) => {
+                // Alarms after one that expires past the earliest deadline
+                // found so far cannot have an earlier deadline.
+                let now = self.alarm.now();
+                let mut earliest = head;
+                let mut earliest_remaining = head.deadline_remaining(now);
+                let mut cur = head.next.get();
+                while let Some(c) = cur {
+                    if c.remaining(now) >= earliest_remaining {
+                        break;
+                    }
+                    let remaining = c.deadline_remaining(now);
+                    if remaining < earliest_remaining {
+                        earliest = c;
+                        earliest_remaining = remaining;
+                    }
+                    cur = c.next.get();
+                }
+                let (reference, dt) = earliest.deadline();
+                self.alarm.set_alarm(reference, dt);
+            }
+            None => {
+                self.alarm.disarm();
+            }
+        }
     }
 }
 
@@ -188,45 +336,82 @@ impl<'a, A: Alarm<'a>> time::AlarmClient for MuxAlarm<'a, A> {
     /// When the underlying alarm has fired, we have to multiplex this event back to the virtual
     /// alarms that should now fire.
     fn alarm(&self) {
+        // At this level, alarms are one-shot, so a repeating client will set
+        // it again in the alarm() callback. To make sure such a client cannot
+        // keep the mux firing forever, only fire the alarms that have expired
+        // by now; alarms that are re-armed and expire again while firing are
+        // handled by the next underlying alarm.
         let now = self.alarm.now();
-        // Check whether to fire each alarm. At this level, alarms are one-shot,
-        // so a repeating client will set it again in the alarm() callback.
+        let mut expired = 0;
+        let mut cur = self.armed.get();
+        while let Some(c) = cur {
+            if !c.expired(now) {
+                break;
+            }
+            expired += 1;
+            cur = c.next.get();
+        }
+
         self.firing.set(true);
-        self.virtual_alarms
-            .iter()
-            .filter(|cur| {
-                cur.armed.get()
-                    && !now.within_range(
-                        cur.reference.get(),
-                        cur.reference.get().wrapping_add(cur.dt.get()),
-                    )
-            })
-            .for_each(|cur| {
-                cur.armed.set(false);
-                self.enabled.set(self.enabled.get() - 1);
-                //debug!("  Virtualizer: {:?} outside {:?}-{:?}, fire!", now, cur.reference.get(), cur.reference.get().wrapping_add(cur.dt.get()));
-                cur.alarm();
-            });
-        self.firing.set(false);
-        // Find the soonest alarm client (if any) and set the "next" underlying
-        // alarm based on it.  This needs to happen after firing all expired
-        // alarms since those may have reset new alarms.
-        let next = self
-            .virtual_alarms
-            .iter()
-            .filter(|cur| cur.armed.get())
-            .min_by_key(|cur| {
-                cur.reference
-                    .get()
-                    .wrapping_add(cur.dt.get())
-                    .wrapping_sub(now)
-            });
-
-        // Set the alarm.
-        if let Some(valrm) = next {
-            self.set_alarm(valrm.reference.get(), valrm.dt.get());
-        } else {
-            self.disarm();
+        for _ in 0..expired {
+            match self.armed.get() {
+                Some(head) if head.expired(self.alarm.now()) => {
+                    self.armed.set(head.next.get());
+                    head.next.set(None);
+                    match head.period.get() {
+                        Some(period) => {
+                            // Count the next period from this expiration,
+                            // not from now, so that the alarm does not drift.
+                            head.reference
+                                .set(head.reference.get().wrapping_add(head.dt.get()));
+                            head.dt.set(period);
+                            self.insert(head);
+                        }
+                        None => head.armed.set(false),
+                    }
+                    head.alarm();
+                }
+                _ => break,
+            }
         }
+        self.firing.set(false);
+
+        // This needs to happen after firing all expired alarms since those
+        // may have set new alarms.
+        self.update_underlying();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use kernel::hil::time::{MockAlarm, Ticks32};
+
+    struct Client;
+
+    impl time::AlarmClient for Client {
+        fn alarm(&self) {}
+    }
+
+    #[test]
+    fn saturates_the_deadline_of_a_large_slack() {
+        let alarm = MockAlarm::new();
+        let mux = MuxAlarm::new(&alarm);
+        alarm.set_alarm_client(&mux);
+        let virtual_alarm = VirtualMuxAlarm::new(&mux);
+        virtual_alarm.set_alarm_client(&Client);
+
+        alarm.set_now(Ticks32::from(100));
+        virtual_alarm.set_slack(Ticks32::from(u32::max_value() - 10));
+        virtual_alarm.set_alarm(Ticks32::from(100), Ticks32::from(1000));
+        assert_eq!(
+            virtual_alarm.deadline(),
+            (Ticks32::from(100), Ticks32::max_value())
+        );
+        // Expired, but well before the deadline.
+        assert_ne!(
+            virtual_alarm.deadline_remaining(Ticks32::from(1200)),
+            Ticks32::from(0)
+        );
     }
 }
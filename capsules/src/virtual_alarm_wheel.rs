@@ -0,0 +1,677 @@
+//! Virtualize the Alarm interface with a hierarchical timing wheel, for boards
+//! with many virtual alarms.
+//!
+//! `MuxAlarm` keeps armed alarms in a sorted list, so each alarm that is armed
+//! walks the list to find its position. With dozens of clients re-arming their
+//! alarms every period, that walk is paid over and over. `MuxAlarmWheel`
+//! instead hashes each armed alarm into a slot by its expiration, in one of
+//! `LEVELS` wheels of `SLOTS` slots each (Varghese and Lauck, "Hashed and
+//! Hierarchical Timing Wheels"). A slot of the first wheel spans one wheel
+//! tick, `2^resolution` ticks of the underlying alarm, and a slot of each
+//! following wheel spans a full turn of the wheel before it. Arming or
+//! disarming an alarm links it into or unlinks it from its slot, in constant
+//! time.
+//!
+//! When the wheels reach a slot of a wheel after the first, the alarms in it
+//! are cascaded: each is hashed again, into a lower wheel since it is now
+//! closer, so an alarm is moved at most `LEVELS - 1` times. The mux finds the
+//! next slot that holds alarms from a bitmap of the occupied slots of each
+//! wheel, and the underlying alarm only fires for such slots. A single
+//! interrupt therefore does work proportional to the alarms in the slots it
+//! reaches, however long the wheels were idle. The alarms in a slot of the
+//! first wheel are not ordered; the underlying alarm is set to the earliest of
+//! them, so alarms still fire at their exact time.
+//!
+//! A larger resolution means fewer cascades, and so fewer wakeups that fire no
+//! alarm, but more alarms sharing each slot of the first wheel. The wheels
+//! reach `2^(resolution + 25)` ticks ahead; alarms further away wait in the
+//! highest wheel and are hashed again each time their slot is cascaded.
+//!
+//! `VirtualWheelAlarm` implements `Alarm` and `PeriodicAlarm` as
+//! `VirtualMuxAlarm` does, so a board chooses the wheel by creating its
+//! virtual alarms from a `MuxAlarmWheel` rather than a `MuxAlarm`; the
+//! capsules using them are unchanged. As with `VirtualMuxAlarm`, a periodic
+//! alarm is re-armed at its next expiration just before its client is called.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//! # use capsules::virtual_alarm_wheel::{MuxAlarmWheel, VirtualWheelAlarm};
+//!
+//! let mux_alarm = static_init!(
+//!     MuxAlarmWheel<'static, sam4l::ast::Ast>,
+//!     MuxAlarmWheel::new(&sam4l::ast::AST, 4)
+//! );
+//! sam4l::ast::AST.set_alarm_client(mux_alarm);
+//!
+//! let virtual_alarm = static_init!(
+//!     VirtualWheelAlarm<'static, sam4l::ast::Ast>,
+//!     VirtualWheelAlarm::new(mux_alarm)
+//! );
+//! ```
+
+use core::cell::Cell;
+use kernel::common::cells::OptionalCell;
+use kernel::hil::time::{self, Alarm, PeriodicAlarm, Ticks, Time};
+use kernel::ReturnCode;
+
+/// The number of wheels.
+pub const LEVELS: usize = 5;
+
+/// log2 of the number of slots of each wheel.
+pub const SLOT_BITS: usize = 5;
+
+/// The number of slots of each wheel, one bit of its occupied bitmap each.
+pub const SLOTS: usize = 1 << SLOT_BITS;
+
+const SLOT_MASK: usize = SLOTS - 1;
+
+/// A virtual alarm of a `MuxAlarmWheel`. While armed, it is a node in the list
+/// of the slot its expiration hashes to.
+pub struct VirtualWheelAlarm<'a, A: Alarm<'a>> {
+    mux: &'a MuxAlarmWheel<'a, A>,
+    /// Reference to this alarm with the lifetime of the mux, set when the
+    /// client is registered, so that `set_alarm(&self)` can link it into a
+    /// slot.
+    this: OptionalCell<&'a VirtualWheelAlarm<'a, A>>,
+    /// Reference time point when this alarm was setup.
+    reference: Cell<A::Ticks>,
+    /// The alarm fires at `reference + dt`.
+    dt: Cell<A::Ticks>,
+    armed: Cell<bool>,
+    /// Period of a periodic alarm, or `None` for a one-shot alarm.
+    period: Cell<Option<A::Ticks>>,
+    /// The wheel and slot this alarm is linked into.
+    slot: Cell<Option<(usize, usize)>>,
+    prev: Cell<Option<&'a VirtualWheelAlarm<'a, A>>>,
+    next: Cell<Option<&'a VirtualWheelAlarm<'a, A>>>,
+    client: OptionalCell<&'a dyn time::AlarmClient>,
+}
+
+impl<'a, A: Alarm<'a>> VirtualWheelAlarm<'a, A> {
+    pub fn new(mux: &'a MuxAlarmWheel<'a, A>) -> VirtualWheelAlarm<'a, A> {
+        let zero = A::ticks_from_seconds(0);
+        VirtualWheelAlarm {
+            mux: mux,
+            this: OptionalCell::empty(),
+            reference: Cell::new(zero),
+            dt: Cell::new(zero),
+            armed: Cell::new(false),
+            period: Cell::new(None),
+            slot: Cell::new(None),
+            prev: Cell::new(None),
+            next: Cell::new(None),
+            client: OptionalCell::empty(),
+        }
+    }
+
+    /// Whether this alarm's time has passed at `now`.
+    fn expired(&self, now: A::Ticks) -> bool {
+        !now.within_range(
+            self.reference.get(),
+            self.reference.get().wrapping_add(self.dt.get()),
+        )
+    }
+
+    /// Ticks from `now` until this alarm expires, or zero if it already has.
+    fn remaining(&self, now: A::Ticks) -> A::Ticks {
+        if self.expired(now) {
+            A::Ticks::from(0 as u32)
+        } else {
+            self.reference
+                .get()
+                .wrapping_add(self.dt.get())
+                .wrapping_sub(now)
+        }
+    }
+
+    fn arm(&self, reference: A::Ticks, dt: A::Ticks) {
+        self.this.map(|this| {
+            // Re-arming moves the alarm to its new slot.
+            if self.armed.get() {
+                self.mux.remove(this);
+            }
+            self.reference.set(reference);
+            self.dt.set(dt);
+            self.armed.set(true);
+            self.mux.insert(this);
+        });
+    }
+}
+
+impl<'a, A: Alarm<'a>> Time for VirtualWheelAlarm<'a, A> {
+    type Frequency = A::Frequency;
+    type Ticks = A::Ticks;
+
+    fn now(&self) -> Self::Ticks {
+        self.mux.alarm.now()
+    }
+}
+
+impl<'a, A: Alarm<'a>> Alarm<'a> for VirtualWheelAlarm<'a, A> {
+    fn set_alarm_client(&'a self, client: &'a dyn time::AlarmClient) {
+        self.this.set(self);
+        self.disarm();
+        self.reference.set(A::Ticks::from(0 as u32));
+        self.dt.set(A::Ticks::from(0 as u32));
+        self.client.set(client);
+    }
+
+    fn disarm(&self) -> ReturnCode {
+        self.period.set(None);
+        if !self.armed.get() {
+            return ReturnCode::SUCCESS;
+        }
+
+        self.armed.set(false);
+        self.this.map(|this| self.mux.remove(this));
+        ReturnCode::SUCCESS
+    }
+
+    fn is_armed(&self) -> bool {
+        self.armed.get()
+    }
+
+    fn set_alarm(&self, reference: Self::Ticks, dt: Self::Ticks) {
+        self.period.set(None);
+        self.arm(reference, dt);
+    }
+
+    fn get_alarm(&self) -> Self::Ticks {
+        self.reference.get().wrapping_add(self.dt.get())
+    }
+
+    fn minimum_dt(&self) -> Self::Ticks {
+        self.mux.alarm.minimum_dt()
+    }
+}
+
+impl<'a, A: Alarm<'a>> PeriodicAlarm<'a> for VirtualWheelAlarm<'a, A> {
+    fn set_periodic_alarm(&self, reference: Self::Ticks, period: Self::Ticks) -> ReturnCode {
+        // A zero period would expire again every time it was re-armed.
+        if period == Self::Ticks::from(0) {
+            return ReturnCode::EINVAL;
+        }
+        self.period.set(Some(period));
+        self.arm(reference, period);
+        ReturnCode::SUCCESS
+    }
+
+    fn period(&self) -> Option<Self::Ticks> {
+        if self.armed.get() {
+            self.period.get()
+        } else {
+            None
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> time::AlarmClient for VirtualWheelAlarm<'a, A> {
+    fn alarm(&self) {
+        self.client.map(|client| client.alarm());
+    }
+}
+
+/// Multiplexes virtual alarms over a single underlying alarm with a
+/// hierarchical timing wheel.
+pub struct MuxAlarmWheel<'a, A: Alarm<'a>> {
+    alarm: &'a A,
+    /// log2 of the ticks of the underlying alarm in a wheel tick.
+    resolution: u32,
+    /// The wheel tick the wheels stand at. Its slot in the first wheel is the
+    /// current slot.
+    current: Cell<u32>,
+    /// The time of the underlying alarm at which the current wheel tick
+    /// started.
+    current_start: Cell<A::Ticks>,
+    /// The head of the list of alarms of each slot of each wheel.
+    slots: [[Cell<Option<&'a VirtualWheelAlarm<'a, A>>>; SLOTS]; LEVELS],
+    /// A bit for each slot of each wheel, set while the slot holds alarms.
+    occupied: [Cell<u32>; LEVELS],
+    /// The number of armed alarms.
+    armed: Cell<usize>,
+    /// Whether we are firing; the underlying alarm is reprogrammed once all
+    /// expired alarms have fired.
+    firing: Cell<bool>,
+}
+
+impl<'a, A: Alarm<'a>> MuxAlarmWheel<'a, A> {
+    /// A wheel tick is `2^resolution` ticks of `alarm`.
+    pub fn new(alarm: &'a A, resolution: u32) -> MuxAlarmWheel<'a, A> {
+        MuxAlarmWheel {
+            alarm: alarm,
+            resolution: resolution,
+            current: Cell::new(0),
+            current_start: Cell::new(A::Ticks::from(0 as u32)),
+            slots: <[[Cell<Option<&'a VirtualWheelAlarm<'a, A>>>; SLOTS]; LEVELS]>::default(),
+            occupied: <[Cell<u32>; LEVELS]>::default(),
+            armed: Cell::new(0),
+            firing: Cell::new(false),
+        }
+    }
+
+    /// Wheel ticks from the start of the current one to `now`.
+    fn elapsed(&self, now: A::Ticks) -> u64 {
+        now.wrapping_sub(self.current_start.get()).into_u64() >> self.resolution
+    }
+
+    /// Moves the wheels `ticks` wheel ticks ahead, without cascading.
+    fn advance(&self, ticks: u64) {
+        self.current
+            .set(self.current.get().wrapping_add(ticks as u32));
+        self.current_start.set(
+            self.current_start
+                .get()
+                .wrapping_add(A::Ticks::from_u64_wrapping(ticks << self.resolution)),
+        );
+    }
+
+    fn insert(&self, node: &'a VirtualWheelAlarm<'a, A>) {
+        if self.armed.get() == 0 {
+            // With no alarms, the current wheel tick may lie arbitrarily far
+            // in the past; start it now so that elapsed ticks do not wrap.
+            self.current_start.set(self.alarm.now());
+        }
+        self.armed.set(self.armed.get() + 1);
+        self.place(node);
+        self.update_underlying();
+    }
+
+    fn remove(&self, node: &'a VirtualWheelAlarm<'a, A>) {
+        self.unlink(node);
+        self.armed.set(self.armed.get() - 1);
+        self.update_underlying();
+    }
+
+    /// Links `node` into the slot its expiration hashes to.
+    fn place(&self, node: &'a VirtualWheelAlarm<'a, A>) {
+        let now = self.alarm.now();
+        let current = self.current.get();
+        let ahead = (now.wrapping_sub(self.current_start.get()).into_u64()
+            + node.remaining(now).into_u64())
+            >> self.resolution;
+
+        let mut level = 0;
+        while level < LEVELS - 1 && ahead >> (SLOT_BITS * (level + 1)) != 0 {
+            level += 1;
+        }
+        let expiration = if ahead >> (SLOT_BITS * LEVELS) != 0 {
+            // Beyond the reach of the wheels: the current slot of the highest
+            // wheel is cascaded last.
+            current
+        } else {
+            current.wrapping_add(ahead as u32)
+        };
+        let index = (expiration >> (SLOT_BITS * level)) as usize & SLOT_MASK;
+
+        let head = self.slots[level][index].get();
+        node.prev.set(None);
+        node.next.set(head);
+        if let Some(head) = head {
+            head.prev.set(Some(node));
+        }
+        self.slots[level][index].set(Some(node));
+        node.slot.set(Some((level, index)));
+        self.occupied[level].set(self.occupied[level].get() | 1 << index);
+    }
+
+    /// Unlinks `node` from its slot.
+    fn unlink(&self, node: &'a VirtualWheelAlarm<'a, A>) {
+        if let Some((level, index)) = node.slot.take() {
+            let prev = node.prev.take();
+            let next = node.next.take();
+            match prev {
+                Some(prev) => prev.next.set(next),
+                None => self.slots[level][index].set(next),
+            }
+            if let Some(next) = next {
+                next.prev.set(prev);
+            }
+            if self.slots[level][index].get().is_none() {
+                self.occupied[level].set(self.occupied[level].get() & !(1 << index));
+            }
+        }
+    }
+
+    /// The wheel ticks from the current one until the next slot that holds
+    /// alarms is reached, and the wheel of that slot. A slot of the first
+    /// wheel is reached at its wheel tick, and a slot of a higher wheel when
+    /// it is cascaded. On a tie the higher wheel is returned, as its alarms
+    /// may expire as early as those of the first wheel.
+    fn next_slot(&self) -> Option<(u64, usize)> {
+        let current = self.current.get();
+        let mut next: Option<(u64, usize)> = None;
+        for level in 0..LEVELS {
+            let occupied = self.occupied[level].get();
+            if occupied == 0 {
+                continue;
+            }
+            let shift = SLOT_BITS * level;
+            let index = (current >> shift) as usize & SLOT_MASK;
+            let ticks = if level == 0 {
+                u64::from(occupied.rotate_right(index as u32).trailing_zeros())
+            } else {
+                // The current slot of a higher wheel was cascaded when the
+                // wheels reached it, so it is only reached again a full turn
+                // later.
+                let turns = u64::from(
+                    occupied
+                        .rotate_right(((index + 1) & SLOT_MASK) as u32)
+                        .trailing_zeros(),
+                ) + 1;
+                (turns << shift) - (u64::from(current) & ((1 << shift) - 1))
+            };
+            if next.map_or(true, |(next_ticks, _)| ticks <= next_ticks) {
+                next = Some((ticks, level));
+            }
+        }
+        next
+    }
+
+    /// Hashes the alarms of the slots of the higher wheels that the current
+    /// wheel tick reaches again, highest wheel first.
+    fn cascade(&self) {
+        let current = self.current.get();
+        for level in (1..LEVELS).rev() {
+            let shift = SLOT_BITS * level;
+            if current & ((1 << shift) - 1) != 0 {
+                continue;
+            }
+            let index = (current >> shift) as usize & SLOT_MASK;
+            let mut cur = self.slots[level][index].take();
+            self.occupied[level].set(self.occupied[level].get() & !(1 << index));
+            while let Some(node) = cur {
+                cur = node.next.get();
+                node.slot.set(None);
+                self.place(node);
+            }
+        }
+    }
+
+    /// Fires the alarms of the current slot that have expired at `now`, and
+    /// returns whether there were any. Only the alarms that had expired when
+    /// it was called are fired, so a periodic alarm that expires again as it
+    /// is re-armed waits for the next underlying alarm.
+    fn fire_slot(&self, now: A::Ticks) -> bool {
+        let index = self.current.get() as usize & SLOT_MASK;
+        let mut expired = 0;
+        let mut cur = self.slots[0][index].get();
+        while let Some(c) = cur {
+            if c.expired(now) {
+                expired += 1;
+            }
+            cur = c.next.get();
+        }
+
+        for _ in 0..expired {
+            let mut cur = self.slots[0][index].get();
+            while let Some(c) = cur {
+                if c.expired(now) {
+                    break;
+                }
+                cur = c.next.get();
+            }
+            let node = match cur {
+                Some(node) => node,
+                None => break,
+            };
+            self.unlink(node);
+            match node.period.get() {
+                Some(period) => {
+                    // Count the next period from this expiration, not from
+                    // now, so that the alarm does not drift.
+                    node.reference
+                        .set(node.reference.get().wrapping_add(node.dt.get()));
+                    node.dt.set(period);
+                    self.place(node);
+                }
+                None => {
+                    node.armed.set(false);
+                    self.armed.set(self.armed.get() - 1);
+                }
+            }
+            node.client.map(|client| client.alarm());
+        }
+        expired > 0
+    }
+
+    /// Sets the underlying alarm to the earliest alarm of the next slot of the
+    /// first wheel, or to the cascade of the next slot of a higher wheel, or
+    /// disarms it if no alarm is armed.
+    fn update_underlying(&self) {
+        if self.firing.get() {
+            return;
+        }
+        match self.next_slot() {
+            Some((ticks, 0)) => {
+                let now = self.alarm.now();
+                let index = self.current.get().wrapping_add(ticks as u32) as usize & SLOT_MASK;
+                let mut earliest: Option<&'a VirtualWheelAlarm<'a, A>> = None;
+                let mut cur = self.slots[0][index].get();
+                while let Some(c) = cur {
+                    if earliest.map_or(true, |e| c.remaining(now) < e.remaining(now)) {
+                        earliest = Some(c);
+                    }
+                    cur = c.next.get();
+                }
+                if let Some(earliest) = earliest {
+                    self.alarm
+                        .set_alarm(earliest.reference.get(), earliest.dt.get());
+                }
+            }
+            Some((ticks, _)) => {
+                // Waking up early is harmless, so a cascade further away than
+                // the underlying alarm reaches is brought closer.
+                let dt = (ticks << self.resolution).min(A::Ticks::max_value().into_u64() / 2);
+                self.alarm
+                    .set_alarm(self.current_start.get(), A::Ticks::from_u64_wrapping(dt));
+            }
+            None => {
+                self.alarm.disarm();
+            }
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> time::AlarmClient for MuxAlarmWheel<'a, A> {
+    /// Turns the wheels to now, firing and cascading the slots on the way.
+    fn alarm(&self) {
+        self.firing.set(true);
+        loop {
+            let now = self.alarm.now();
+            let elapsed = self.elapsed(now);
+            match self.next_slot() {
+                Some((ticks, level)) if ticks <= elapsed => {
+                    self.advance(ticks);
+                    if level > 0 {
+                        self.cascade();
+                    } else if !self.fire_slot(now) || ticks == elapsed {
+                        // Alarms in the current slot that have not expired
+                        // are left for the next underlying alarm.
+                        break;
+                    }
+                }
+                _ => {
+                    self.advance(elapsed);
+                    break;
+                }
+            }
+        }
+        self.firing.set(false);
+
+        // This needs to happen after firing all expired alarms since those
+        // may have set new alarms.
+        self.update_underlying();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use core::cmp;
+    use kernel::hil::time::{MockAlarm, Ticks32};
+
+    /// Records the times its alarm fires at.
+    struct Recorder<'a> {
+        alarm: &'a VirtualWheelAlarm<'a, MockAlarm<'a>>,
+        fired: Cell<[u32; 16]>,
+        count: Cell<usize>,
+    }
+
+    impl<'a> Recorder<'a> {
+        fn new(alarm: &'a VirtualWheelAlarm<'a, MockAlarm<'a>>) -> Recorder<'a> {
+            Recorder {
+                alarm: alarm,
+                fired: Cell::new([0; 16]),
+                count: Cell::new(0),
+            }
+        }
+
+        /// Checks the times of the first firings.
+        fn assert_fired(&self, expected: &[u32]) {
+            let count = cmp::min(self.count.get(), 16);
+            assert_eq!(&self.fired.get()[..count], expected);
+        }
+    }
+
+    impl time::AlarmClient for Recorder<'_> {
+        fn alarm(&self) {
+            let count = self.count.get();
+            if count < 16 {
+                let mut fired = self.fired.get();
+                fired[count] = self.alarm.now().into_u32();
+                self.fired.set(fired);
+            }
+            self.count.set(count + 1);
+        }
+    }
+
+    #[test]
+    fn fires_at_exact_times_across_wheels() {
+        let alarm = MockAlarm::new();
+        let mux = MuxAlarmWheel::new(&alarm, 0);
+        alarm.set_alarm_client(&mux);
+        let alarms = [
+            VirtualWheelAlarm::new(&mux),
+            VirtualWheelAlarm::new(&mux),
+            VirtualWheelAlarm::new(&mux),
+            VirtualWheelAlarm::new(&mux),
+        ];
+        let recorders = [
+            Recorder::new(&alarms[0]),
+            Recorder::new(&alarms[1]),
+            Recorder::new(&alarms[2]),
+            Recorder::new(&alarms[3]),
+        ];
+        for (alarm, recorder) in alarms.iter().zip(recorders.iter()) {
+            alarm.set_alarm_client(recorder);
+        }
+
+        // One alarm in each of the first four wheels, armed out of order.
+        alarms[2].set_alarm(Ticks32::from(0), Ticks32::from(1000));
+        alarms[0].set_alarm(Ticks32::from(0), Ticks32::from(3));
+        alarms[3].set_alarm(Ticks32::from(0), Ticks32::from(33_000));
+        alarms[1].set_alarm(Ticks32::from(0), Ticks32::from(40));
+
+        alarm.advance_ms(999);
+        recorders[0].assert_fired(&[3]);
+        recorders[1].assert_fired(&[40]);
+        recorders[2].assert_fired(&[]);
+
+        alarm.advance_ms(40_000);
+        recorders[2].assert_fired(&[1000]);
+        recorders[3].assert_fired(&[33_000]);
+        assert!(!alarm.is_armed());
+    }
+
+    #[test]
+    fn cascades_with_a_coarse_resolution() {
+        let alarm = MockAlarm::new();
+        let mux = MuxAlarmWheel::new(&alarm, 4);
+        alarm.set_alarm_client(&mux);
+        let virtual_alarm = VirtualWheelAlarm::new(&mux);
+        let recorder = Recorder::new(&virtual_alarm);
+        virtual_alarm.set_alarm_client(&recorder);
+
+        // Not at a slot boundary, and armed partway through a wheel tick.
+        alarm.set_now(Ticks32::from(7));
+        virtual_alarm.set_alarm(Ticks32::from(7), Ticks32::from(12_345));
+        alarm.advance_ms(20_000);
+        recorder.assert_fired(&[12_352]);
+    }
+
+    #[test]
+    fn fires_beyond_the_reach_of_the_wheels() {
+        let alarm = MockAlarm::new();
+        let mux = MuxAlarmWheel::new(&alarm, 0);
+        alarm.set_alarm_client(&mux);
+        let virtual_alarm = VirtualWheelAlarm::new(&mux);
+        let recorder = Recorder::new(&virtual_alarm);
+        virtual_alarm.set_alarm_client(&recorder);
+
+        // The wheels reach 2^25 ticks ahead.
+        let dt = (1 << 26) + 7;
+        virtual_alarm.set_alarm(Ticks32::from(0), Ticks32::from(dt));
+        alarm.advance_ms(dt - 1);
+        recorder.assert_fired(&[]);
+        alarm.advance_ms(1);
+        recorder.assert_fired(&[dt]);
+    }
+
+    #[test]
+    fn fires_across_counter_wraparound() {
+        let alarm = MockAlarm::new();
+        let mux = MuxAlarmWheel::new(&alarm, 2);
+        alarm.set_alarm_client(&mux);
+        let alarms = [VirtualWheelAlarm::new(&mux), VirtualWheelAlarm::new(&mux)];
+        let recorders = [Recorder::new(&alarms[0]), Recorder::new(&alarms[1])];
+        alarms[0].set_alarm_client(&recorders[0]);
+        alarms[1].set_alarm_client(&recorders[1]);
+
+        let start = 0xffff_f000;
+        alarm.set_now(Ticks32::from(start));
+        alarms[0].set_alarm(Ticks32::from(start), Ticks32::from(0x1100));
+        alarms[1].set_alarm(Ticks32::from(start), Ticks32::from(0x20_0000));
+        alarm.advance_ms(0x30_0000);
+        recorders[0].assert_fired(&[0x100]);
+        recorders[1].assert_fired(&[0x1f_f000]);
+    }
+
+    #[test]
+    fn rearms_periodic_alarms() {
+        let alarm = MockAlarm::new();
+        let mux = MuxAlarmWheel::new(&alarm, 0);
+        alarm.set_alarm_client(&mux);
+        let alarms = [VirtualWheelAlarm::new(&mux), VirtualWheelAlarm::new(&mux)];
+        let recorders = [Recorder::new(&alarms[0]), Recorder::new(&alarms[1])];
+        alarms[0].set_alarm_client(&recorders[0]);
+        alarms[1].set_alarm_client(&recorders[1]);
+
+        assert_eq!(
+            alarms[0].set_periodic_alarm(Ticks32::from(0), Ticks32::from(0)),
+            ReturnCode::EINVAL
+        );
+        assert!(!alarms[0].is_armed());
+
+        // A short period that stays in the first wheel, and a long one that
+        // is cascaded every time.
+        alarms[0].set_periodic_alarm(Ticks32::from(0), Ticks32::from(10));
+        alarms[1].set_periodic_alarm(Ticks32::from(0), Ticks32::from(1500));
+        alarm.advance_ms(3005);
+        recorders[0].assert_fired(&[
+            10, 20, 30, 40, 50, 60, 70, 80, 90, 100, 110, 120, 130, 140, 150, 160,
+        ]);
+        assert_eq!(recorders[0].count.get(), 300);
+        recorders[1].assert_fired(&[1500, 3000]);
+        assert_eq!(alarms[1].period(), Some(Ticks32::from(1500)));
+
+        alarms[0].disarm();
+        alarms[1].disarm();
+        assert_eq!(alarms[1].period(), None);
+        alarm.advance_ms(10_000);
+        recorders[1].assert_fired(&[1500, 3000]);
+        assert!(!alarm.is_armed());
+    }
+}
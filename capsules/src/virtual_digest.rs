@@ -1,23 +1,57 @@
 //! Virtualize the Digest interface to enable multiple users of an underlying
 //! Digest hardware peripheral.
+//!
+//! A computation holds the digest engine from the first `set_mode*()`,
+//! `add_data()` or `run()` of a `VirtualMuxDigest` until its `hash_done()`
+//! callback or its `clear_data()`, so the data of different users is never
+//! mixed in one hash. Meanwhile the calls of other users are queued, and
+//! issued when the engine is released: they return `Ok`, a queued
+//! `add_data()` with the length of the whole buffer, and an error in a queued
+//! `set_mode*()` is returned in the callbacks of the operations queued after
+//! it. Each user can queue one `add_data()` and one `run()`.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let mux_digest = static_init!(
+//!     capsules::virtual_digest::MuxDigest<'static, lowrisc::hmac::Hmac, [u8; 32]>,
+//!     capsules::virtual_digest::MuxDigest::new(&earlgrey::hmac::HMAC)
+//! );
+//! digest::Digest::set_client(&earlgrey::hmac::HMAC, mux_digest);
+//!
+//! let digest_user = static_init!(
+//!     capsules::virtual_digest::VirtualMuxDigest<'static, lowrisc::hmac::Hmac, [u8; 32]>,
+//!     capsules::virtual_digest::VirtualMuxDigest::new(mux_digest)
+//! );
+//! digest::Digest::set_client(digest_user, ota_verifier);
+//! ```
 
 use core::cell::Cell;
-use core::marker::PhantomData;
-use kernel::common::cells::OptionalCell;
+use kernel::common::cells::{MapCell, OptionalCell, TakeCell};
 use kernel::common::leasable_buffer::LeasableBuffer;
-use kernel::common::{ListLink, ListNode};
+use kernel::common::{List, ListLink, ListNode};
 use kernel::hil::digest;
-use kernel::hil::digest::DigestType;
+use kernel::hil::digest::{Client, DigestType};
 use kernel::ReturnCode;
 
-pub struct VirtualMuxDigest<'a, A: digest::Digest<'a, T>, T: DigestType> {
+/// A `set_mode*()` call of the engine, with its key if it takes one.
+type SetMode<A> = (fn(&A, &[u8; 32]) -> Result<(), ReturnCode>, [u8; 32]);
+
+pub struct VirtualMuxDigest<'a, A: digest::Digest<'a, T>, T: 'static + DigestType> {
     mux: &'a MuxDigest<'a, A, T>,
     next: ListLink<'a, VirtualMuxDigest<'a, A, T>>,
     client: OptionalCell<&'a dyn digest::Client<'a, T>>,
     id: u32,
+    /// The calls queued until this user gets the engine.
+    mode: Cell<Option<SetMode<A>>>,
+    data: MapCell<LeasableBuffer<'static, u8>>,
+    digest: TakeCell<'static, T>,
 }
 
-impl<'a, A: digest::Digest<'a, T>, T: DigestType> ListNode<'a, VirtualMuxDigest<'a, A, T>>
+impl<'a, A: digest::Digest<'a, T>, T: 'static + DigestType> ListNode<'a, VirtualMuxDigest<'a, A, T>>
     for VirtualMuxDigest<'a, A, T>
 {
     fn next(&self) -> &'a ListLink<VirtualMuxDigest<'a, A, T>> {
@@ -25,7 +59,7 @@ impl<'a, A: digest::Digest<'a, T>, T: DigestType> ListNode<'a, VirtualMuxDigest<
     }
 }
 
-impl<'a, A: digest::Digest<'a, T>, T: DigestType> VirtualMuxDigest<'a, A, T> {
+impl<'a, A: digest::Digest<'a, T>, T: 'static + DigestType> VirtualMuxDigest<'a, A, T> {
     pub fn new(mux_digest: &'a MuxDigest<'a, A, T>) -> VirtualMuxDigest<'a, A, T> {
         let id = mux_digest.next_id.get();
         mux_digest.next_id.set(id + 1);
@@ -35,65 +69,119 @@ impl<'a, A: digest::Digest<'a, T>, T: DigestType> VirtualMuxDigest<'a, A, T> {
             next: ListLink::empty(),
             client: OptionalCell::empty(),
             id: id,
+            mode: Cell::new(None),
+            data: MapCell::empty(),
+            digest: TakeCell::empty(),
+        }
+    }
+
+    fn has_pending(&self) -> bool {
+        self.mode.get().is_some() || self.data.is_some() || self.digest.is_some()
+    }
+
+    fn set_mode(
+        &self,
+        set_mode: fn(&A, &[u8; 32]) -> Result<(), ReturnCode>,
+        key: &[u8; 32],
+    ) -> Result<(), ReturnCode> {
+        if self.data.is_some() || self.digest.is_some() {
+            return Err(ReturnCode::EBUSY);
+        }
+        if !self.mux.can_start(self) {
+            self.mode.set(Some((set_mode, *key)));
+            return Ok(());
+        }
+        let started = self.mux.start(self);
+        let result = set_mode(self.mux.digest, key);
+        if result.is_err() && started {
+            self.mux.running.clear();
+        }
+        result
+    }
+
+    /// Fails the queued operations after an error in a queued `set_mode*()`.
+    fn fail(&'a self, rcode: ReturnCode) {
+        if let Some(data) = self.data.take() {
+            self.add_data_done(Err(rcode), data.take());
+        }
+        if let Some(digest) = self.digest.take() {
+            self.hash_done(Err(rcode), digest);
         }
     }
 }
 
-impl<'a, A: digest::Digest<'a, T>, T: DigestType> digest::Digest<'a, T>
+impl<'a, A: digest::Digest<'a, T>, T: 'static + DigestType> digest::Digest<'a, T>
     for VirtualMuxDigest<'a, A, T>
 {
     /// Set the client instance which will receive `add_data_done()` and
     /// `hash_done()` callbacks
     fn set_client(&'a self, client: &'a dyn digest::Client<'a, T>) {
+        self.mux.users.push_head(self);
         self.client.set(client);
     }
 
     /// Add data to the digest IP.
-    /// All data passed in is fed to the Digest hardware block.
+    /// All data passed in is fed to the Digest hardware block, at once if
+    /// this user has the engine and otherwise when it gets it.
     /// Returns the number of bytes written on success
     fn add_data(
         &self,
         data: LeasableBuffer<'static, u8>,
     ) -> Result<usize, (ReturnCode, &'static mut [u8])> {
-        // Check if any mux is enabled. If it isn't we enable it for us.
-        if self.mux.running.get() == false {
-            self.mux.running.set(true);
-            self.mux.running_id.set(self.id);
-            self.mux.digest.add_data(data)
-        } else if self.mux.running_id.get() == self.id {
-            self.mux.digest.add_data(data)
-        } else {
-            Err((ReturnCode::EBUSY, data.take()))
+        if self.data.is_some() || self.digest.is_some() {
+            return Err((ReturnCode::EBUSY, data.take()));
         }
+        if !self.mux.can_start(self) {
+            let len = data.len();
+            self.data.replace(data);
+            return Ok(len);
+        }
+        let started = self.mux.start(self);
+        let result = self.mux.digest.add_data(data);
+        match result {
+            Ok(_) => self.mux.inflight.set(self.id),
+            Err(_) if started => self.mux.running.clear(),
+            Err(_) => {}
+        }
+        result
     }
 
-    /// Request the hardware block to generate a Digest
+    /// Request the hardware block to generate a Digest, after the data
+    /// queued by this user.
     /// This doesn't return anything, instead the client needs to have
     /// set a `hash_done` handler.
     fn run(&'a self, digest: &'static mut T) -> Result<(), (ReturnCode, &'static mut T)> {
-        // Check if any mux is enabled. If it isn't we enable it for us.
-        if self.mux.running.get() == false {
-            self.mux.running.set(true);
-            self.mux.running_id.set(self.id);
-            self.mux.digest.run(digest)
-        } else if self.mux.running_id.get() == self.id {
-            self.mux.digest.run(digest)
-        } else {
-            Err((ReturnCode::EBUSY, digest))
+        if self.digest.is_some() {
+            return Err((ReturnCode::EBUSY, digest));
+        }
+        if !self.mux.can_start(self) {
+            self.digest.replace(digest);
+            return Ok(());
+        }
+        let started = self.mux.start(self);
+        let result = self.mux.digest.run(digest);
+        match result {
+            Ok(()) => self.mux.inflight.set(self.id),
+            Err(_) if started => self.mux.running.clear(),
+            Err(_) => {}
         }
+        result
     }
 
     /// Disable the Digest hardware and clear the keys and any other sensitive
-    /// data
+    /// data, and release it to the other users. Queued `add_data()` and
+    /// `run()` calls are kept, and start a new computation.
     fn clear_data(&self) {
-        if self.mux.running_id.get() == self.id {
-            self.mux.running.set(false);
-            self.mux.digest.clear_data()
+        self.mode.set(None);
+        if self.mux.running.contains(&self.id) {
+            self.mux.running.clear();
+            self.mux.digest.clear_data();
+            self.mux.do_next_op();
         }
     }
 }
 
-impl<'a, A: digest::Digest<'a, T>, T: DigestType> digest::Client<'a, T>
+impl<'a, A: digest::Digest<'a, T>, T: 'static + DigestType> digest::Client<'a, T>
     for VirtualMuxDigest<'a, A, T>
 {
     fn add_data_done(&'a self, result: Result<(), ReturnCode>, data: &'static mut [u8]) {
@@ -107,43 +195,233 @@ impl<'a, A: digest::Digest<'a, T>, T: DigestType> digest::Client<'a, T>
     }
 }
 
-impl<'a, A: digest::Digest<'a, T> + digest::HMACSha256, T: DigestType> digest::HMACSha256
+impl<'a, A: digest::Digest<'a, T> + digest::HMACSha256, T: 'static + DigestType> digest::HMACSha256
     for VirtualMuxDigest<'a, A, T>
 {
     fn set_mode_hmacsha256(&self, key: &[u8; 32]) -> Result<(), ReturnCode> {
-        // Check if any mux is enabled. If it isn't we enable it for us.
-        if self.mux.running.get() == false {
-            self.mux.running.set(true);
-            self.mux.running_id.set(self.id);
-            self.mux.digest.set_mode_hmacsha256(key)
-        } else if self.mux.running_id.get() == self.id {
-            self.mux.digest.set_mode_hmacsha256(key)
-        } else {
-            Err(ReturnCode::EBUSY)
-        }
+        self.set_mode(|digest, key| digest.set_mode_hmacsha256(key), key)
     }
 }
 
-/// Calling a 'set_mode*()' function from a `VirtualMuxDigest` will mark that
-/// `VirtualMuxDigest` as the one that has been enabled and running. Until that
-/// Mux calls `clear_data()` it will be the only `VirtualMuxDigest` that can
-/// interact with the underlying device.
-pub struct MuxDigest<'a, A: digest::Digest<'a, T>, T: DigestType> {
+impl<'a, A: digest::Digest<'a, T> + digest::Sha256, T: 'static + DigestType> digest::Sha256
+    for VirtualMuxDigest<'a, A, T>
+{
+    fn set_mode_sha256(&self) -> Result<(), ReturnCode> {
+        self.set_mode(|digest, _key| digest.set_mode_sha256(), &[0; 32])
+    }
+}
+
+/// Shares a digest engine between the `VirtualMuxDigest`s that have set a
+/// client, one computation at a time. The mux must be the client of the
+/// engine.
+pub struct MuxDigest<'a, A: digest::Digest<'a, T>, T: 'static + DigestType> {
     digest: &'a A,
-    running: Cell<bool>,
-    running_id: Cell<u32>,
+    users: List<'a, VirtualMuxDigest<'a, A, T>>,
+    /// The user whose computation holds the engine.
+    running: OptionalCell<u32>,
+    /// The user whose `add_data()` or `run()` the engine is processing.
+    inflight: OptionalCell<u32>,
     next_id: Cell<u32>,
-    phantom: PhantomData<&'a T>,
 }
 
-impl<'a, A: digest::Digest<'a, T>, T: DigestType> MuxDigest<'a, A, T> {
+impl<'a, A: digest::Digest<'a, T>, T: 'static + DigestType> MuxDigest<'a, A, T> {
     pub const fn new(digest: &'a A) -> MuxDigest<'a, A, T> {
         MuxDigest {
             digest: digest,
-            running: Cell::new(false),
-            running_id: Cell::new(0),
+            users: List::new(),
+            running: OptionalCell::empty(),
+            inflight: OptionalCell::empty(),
             next_id: Cell::new(0),
-            phantom: PhantomData,
         }
     }
+
+    fn user(&self, id: u32) -> Option<&'a VirtualMuxDigest<'a, A, T>> {
+        self.users.iter().find(|user| user.id == id)
+    }
+
+    /// Whether a call of `user` can be issued to the engine now.
+    fn can_start(&self, user: &VirtualMuxDigest<'a, A, T>) -> bool {
+        self.inflight.is_none()
+            && self.running.map_or(true, |id| *id == user.id)
+            && !user.has_pending()
+    }
+
+    /// Gives the engine to `user`, and returns whether this starts its
+    /// computation.
+    fn start(&self, user: &VirtualMuxDigest<'a, A, T>) -> bool {
+        self.running.replace(user.id).is_none()
+    }
+
+    /// Issues the next queued call of the user holding the engine, or
+    /// starts the computation of the first user with queued calls.
+    fn do_next_op(&self) {
+        if self.inflight.is_some() {
+            return;
+        }
+        let user = match self.running.map(|id| *id) {
+            Some(id) => self.user(id),
+            None => self.users.iter().find(|user| user.has_pending()),
+        };
+        let user = match user {
+            Some(user) => user,
+            None => return,
+        };
+        self.running.set(user.id);
+
+        if let Some((set_mode, key)) = user.mode.take() {
+            if let Err(rcode) = set_mode(self.digest, &key) {
+                self.running.clear();
+                user.fail(rcode);
+                self.do_next_op();
+                return;
+            }
+        }
+        if let Some(data) = user.data.take() {
+            match self.digest.add_data(data) {
+                Ok(_) => self.inflight.set(user.id),
+                Err((rcode, data)) => {
+                    user.add_data_done(Err(rcode), data);
+                    self.do_next_op();
+                }
+            }
+        } else if let Some(digest) = user.digest.take() {
+            match self.digest.run(digest) {
+                Ok(()) => self.inflight.set(user.id),
+                Err((rcode, digest)) => {
+                    self.running.clear();
+                    user.hash_done(Err(rcode), digest);
+                    self.do_next_op();
+                }
+            }
+        }
+    }
+}
+
+impl<'a, A: digest::Digest<'a, T>, T: 'static + DigestType> digest::Client<'a, T>
+    for MuxDigest<'a, A, T>
+{
+    fn add_data_done(&'a self, result: Result<(), ReturnCode>, data: &'static mut [u8]) {
+        let user = self.inflight.take().and_then(|id| self.user(id));
+        user.map(move |user| user.add_data_done(result, data));
+        self.do_next_op();
+    }
+
+    fn hash_done(&'a self, result: Result<(), ReturnCode>, digest: &'static mut T) {
+        let user = self.inflight.take().and_then(|id| self.user(id));
+        self.running.clear();
+        user.map(move |user| user.hash_done(result, digest));
+        self.do_next_op();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use super::*;
+    use crate::software_sha256::SoftwareSha256;
+    use kernel::common::dynamic_deferred_call::{
+        DynamicDeferredCall, DynamicDeferredCallClient, DynamicDeferredCallClientState,
+    };
+    use kernel::hil::digest::{Digest, HMACSha256, Sha256};
+    use std::boxed::Box;
+
+    type User = VirtualMuxDigest<'static, SoftwareSha256<'static>, [u8; 32]>;
+
+    fn leak<T>(value: T) -> &'static T {
+        Box::leak(Box::new(value))
+    }
+
+    fn buffer(data: &[u8]) -> LeasableBuffer<'static, u8> {
+        LeasableBuffer::new(Box::leak(data.to_vec().into_boxed_slice()))
+    }
+
+    struct Collector {
+        added: Cell<usize>,
+        digest: Cell<Option<[u8; 32]>>,
+    }
+
+    impl digest::Client<'static, [u8; 32]> for Collector {
+        fn add_data_done(&self, result: Result<(), ReturnCode>, _data: &'static mut [u8]) {
+            assert_eq!(result, Ok(()));
+            self.added.set(self.added.get() + 1);
+        }
+
+        fn hash_done(&self, result: Result<(), ReturnCode>, digest: &'static mut [u8; 32]) {
+            assert_eq!(result, Ok(()));
+            self.digest.set(Some(*digest));
+        }
+    }
+
+    fn collector() -> &'static Collector {
+        leak(Collector {
+            added: Cell::new(0),
+            digest: Cell::new(None),
+        })
+    }
+
+    #[test]
+    fn interleaved_users_get_their_own_digests() {
+        let states = Box::leak(Box::new([DynamicDeferredCallClientState::default()]));
+        let deferred_caller = leak(DynamicDeferredCall::new(states));
+        let sha = leak(SoftwareSha256::new(deferred_caller));
+        let handle = deferred_caller.register(sha).unwrap();
+        sha.initialize_callback_handle(handle);
+        let mux = leak(MuxDigest::new(sha));
+        sha.set_client(mux);
+        let hmac_user: &'static User = leak(VirtualMuxDigest::new(mux));
+        let sha_user: &'static User = leak(VirtualMuxDigest::new(mux));
+        let hmac_client = collector();
+        let sha_client = collector();
+        hmac_user.set_client(hmac_client);
+        sha_user.set_client(sha_client);
+        let run = || {
+            while deferred_caller.cancel(handle) {
+                sha.call(handle);
+            }
+        };
+
+        // RFC 4231 test case 2, with the key padded with zeros.
+        let mut key = [0; 32];
+        key[..4].copy_from_slice(b"Jefe");
+        assert_eq!(hmac_user.set_mode_hmacsha256(&key), Ok(()));
+        assert_eq!(hmac_user.add_data(buffer(b"what do ya ")), Ok(11));
+
+        // Queued behind the HMAC, which holds the engine.
+        assert_eq!(sha_user.set_mode_sha256(), Ok(()));
+        assert_eq!(sha_user.add_data(buffer(b"ab")), Ok(2));
+        assert_eq!(sha_user.run(Box::leak(Box::new([0; 32]))), Ok(()));
+        assert_eq!(
+            sha_user.add_data(buffer(b"c")).map_err(|e| e.0),
+            Err(ReturnCode::EBUSY)
+        );
+
+        run();
+        assert_eq!(hmac_client.added.get(), 1);
+        assert_eq!(sha_client.added.get(), 0);
+        assert_eq!(hmac_user.add_data(buffer(b"want for nothing?")), Ok(17));
+        run();
+        assert_eq!(hmac_user.run(Box::leak(Box::new([0; 32]))), Ok(()));
+        run();
+        assert_eq!(
+            hmac_client.digest.take().unwrap(),
+            [
+                0x5b, 0xdc, 0xc1, 0x46, 0xbf, 0x60, 0x75, 0x4e, 0x6a, 0x04, 0x24, 0x26, 0x08, 0x95,
+                0x75, 0xc7, 0x5a, 0x00, 0x3f, 0x08, 0x9d, 0x27, 0x39, 0x83, 0x9d, 0xec, 0x58, 0xb9,
+                0x64, 0xec, 0x38, 0x43,
+            ]
+        );
+
+        // SHA-256 of "ab", now that the HMAC released the engine.
+        run();
+        assert_eq!(sha_client.added.get(), 1);
+        assert_eq!(
+            sha_client.digest.take().unwrap(),
+            [
+                0xfb, 0x8e, 0x20, 0xfc, 0x2e, 0x4c, 0x3f, 0x24, 0x8c, 0x60, 0xc3, 0x9b, 0xd6, 0x52,
+                0xf3, 0xc1, 0x34, 0x72, 0x98, 0xbb, 0x97, 0x7b, 0x8b, 0x4d, 0x59, 0x03, 0xb8, 0x50,
+                0x55, 0x62, 0x06, 0x03,
+            ]
+        );
+    }
 }
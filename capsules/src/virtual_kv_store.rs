@@ -0,0 +1,227 @@
+//! Virtualize a key-value store.
+//!
+//! `MuxKVStore` provides shared access to a `hil::kv_store::KVStore` from
+//! multiple clients in the kernel, such as a capsule storing network keys and
+//! the syscall driver of the store. A store performs one write at a time, so
+//! each client uses a `VirtualKVStore`, which queues its write until the
+//! store is free. Reads are passed through to the store, and fail with
+//! `EBUSY` while a write is in progress.
+//!
+//! Usage
+//! -----
+//!
+//! ```
+//! # use kernel::{hil, static_init};
+//!
+//! let mux_kv_store = static_init!(
+//!     capsules::virtual_kv_store::MuxKVStore<'static>,
+//!     capsules::virtual_kv_store::MuxKVStore::new(kv_store)
+//! );
+//! hil::kv_store::KVStore::set_client(kv_store, mux_kv_store);
+//!
+//! let thread_kv_store = static_init!(
+//!     capsules::virtual_kv_store::VirtualKVStore<'static>,
+//!     capsules::virtual_kv_store::VirtualKVStore::new(mux_kv_store)
+//! );
+//! hil::kv_store::KVStore::set_client(thread_kv_store, thread_key_client);
+//! ```
+
+use core::cell::Cell;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::{List, ListLink, ListNode};
+use kernel::hil::kv_store::{self, KVStoreClient, MAX_KEY_LEN};
+use kernel::ReturnCode;
+
+/// Keeps the list of users of the store and issues their writes in turn.
+pub struct MuxKVStore<'a> {
+    store: &'a dyn kv_store::KVStore<'a>,
+    users: List<'a, VirtualKVStore<'a>>,
+    inflight: OptionalCell<&'a VirtualKVStore<'a>>,
+}
+
+impl<'a> MuxKVStore<'a> {
+    pub const fn new(store: &'a dyn kv_store::KVStore<'a>) -> MuxKVStore<'a> {
+        MuxKVStore {
+            store: store,
+            users: List::new(),
+            inflight: OptionalCell::empty(),
+        }
+    }
+
+    /// Issues the queued write of `user` to the store, and makes it the
+    /// write in flight if the store accepts it.
+    fn issue(&self, user: &'a VirtualKVStore<'a>) -> ReturnCode {
+        let key = user.key.get();
+        let key = &key[..user.key_len.get()];
+        let rcode = match user.operation.get() {
+            Op::Set(length) => match user.value.take() {
+                Some(value) => match self.store.set(user.namespace.get(), key, value, length) {
+                    Ok(()) => ReturnCode::SUCCESS,
+                    Err((rcode, value)) => {
+                        user.value.replace(value);
+                        rcode
+                    }
+                },
+                None => ReturnCode::ERESERVE,
+            },
+            Op::Delete => self.store.delete(user.namespace.get(), key),
+            Op::Idle => ReturnCode::FAIL,
+        };
+        if rcode == ReturnCode::SUCCESS {
+            self.inflight.set(user);
+        } else {
+            user.operation.set(Op::Idle);
+        }
+        rcode
+    }
+
+    /// Issues the next queued write, calling back the users whose writes the
+    /// store rejects.
+    fn do_next_op(&self) {
+        while self.inflight.is_none() {
+            let next = self
+                .users
+                .iter()
+                .find(|user| user.operation.get() != Op::Idle);
+            match next {
+                Some(user) => {
+                    let operation = user.operation.get();
+                    let rcode = self.issue(user);
+                    if rcode != ReturnCode::SUCCESS {
+                        user.done(operation, rcode);
+                    }
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+impl KVStoreClient for MuxKVStore<'_> {
+    fn set_done(&self, value: &'static mut [u8], result: ReturnCode) {
+        self.inflight.take().map(move |user| {
+            user.operation.set(Op::Idle);
+            user.client
+                .map(move |client| client.set_done(value, result));
+        });
+        self.do_next_op();
+    }
+
+    fn delete_done(&self, result: ReturnCode) {
+        self.inflight.take().map(|user| {
+            user.operation.set(Op::Idle);
+            user.client.map(|client| client.delete_done(result));
+        });
+        self.do_next_op();
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Op {
+    Idle,
+    Set(usize),
+    Delete,
+}
+
+/// Keep state for each user of the store.
+pub struct VirtualKVStore<'a> {
+    mux: &'a MuxKVStore<'a>,
+    operation: Cell<Op>,
+    namespace: Cell<u32>,
+    key: Cell<[u8; MAX_KEY_LEN]>,
+    key_len: Cell<usize>,
+    value: TakeCell<'static, [u8]>,
+    next: ListLink<'a, VirtualKVStore<'a>>,
+    client: OptionalCell<&'a dyn KVStoreClient>,
+}
+
+impl<'a> VirtualKVStore<'a> {
+    pub const fn new(mux: &'a MuxKVStore<'a>) -> VirtualKVStore<'a> {
+        VirtualKVStore {
+            mux: mux,
+            operation: Cell::new(Op::Idle),
+            namespace: Cell::new(0),
+            key: Cell::new([0; MAX_KEY_LEN]),
+            key_len: Cell::new(0),
+            value: TakeCell::empty(),
+            next: ListLink::empty(),
+            client: OptionalCell::empty(),
+        }
+    }
+
+    /// Saves a write, and issues it at once if the store is free. Returns
+    /// the error if the store rejects it, leaving the user idle.
+    fn start(&'a self, operation: Op, namespace: u32, key: &[u8]) -> ReturnCode {
+        if self.operation.get() != Op::Idle {
+            return ReturnCode::EBUSY;
+        }
+        if key.len() > MAX_KEY_LEN {
+            return ReturnCode::ESIZE;
+        }
+        let mut saved_key = [0; MAX_KEY_LEN];
+        saved_key[..key.len()].copy_from_slice(key);
+        self.key.set(saved_key);
+        self.key_len.set(key.len());
+        self.namespace.set(namespace);
+        self.operation.set(operation);
+        if self.mux.inflight.is_none() {
+            self.mux.issue(self)
+        } else {
+            ReturnCode::SUCCESS
+        }
+    }
+
+    /// Calls back the client of a queued write the store rejected.
+    fn done(&self, operation: Op, result: ReturnCode) {
+        match operation {
+            Op::Set(_) => {
+                self.value.take().map(|value| {
+                    self.client
+                        .map(move |client| client.set_done(value, result));
+                });
+            }
+            Op::Delete => {
+                self.client.map(|client| client.delete_done(result));
+            }
+            Op::Idle => {}
+        }
+    }
+}
+
+impl<'a> ListNode<'a, VirtualKVStore<'a>> for VirtualKVStore<'a> {
+    fn next(&'a self) -> &'a ListLink<'a, VirtualKVStore<'a>> {
+        &self.next
+    }
+}
+
+impl<'a> kv_store::KVStore<'a> for VirtualKVStore<'a> {
+    fn set_client(&'a self, client: &'a dyn KVStoreClient) {
+        self.mux.users.push_head(self);
+        self.client.set(client);
+    }
+
+    fn get(&self, namespace: u32, key: &[u8], value: &mut [u8]) -> Result<usize, ReturnCode> {
+        self.mux.store.get(namespace, key, value)
+    }
+
+    fn set(
+        &'a self,
+        namespace: u32,
+        key: &[u8],
+        value: &'static mut [u8],
+        length: usize,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        if self.operation.get() != Op::Idle {
+            return Err((ReturnCode::EBUSY, value));
+        }
+        self.value.replace(value);
+        match self.start(Op::Set(length), namespace, key) {
+            ReturnCode::SUCCESS => Ok(()),
+            rcode => Err((rcode, self.value.take().unwrap())),
+        }
+    }
+
+    fn delete(&'a self, namespace: u32, key: &[u8]) -> ReturnCode {
+        self.start(Op::Delete, namespace, key)
+    }
+}
@@ -1,9 +1,14 @@
 //! Virtualize a SPI master bus to enable multiple users of the SPI bus.
+//!
+//! Given a `PowerConstraint` with `set_power_constraint()`, the mux keeps the
+//! chip out of deep sleep while a transfer is in progress, so the SPI clock
+//! keeps running.
 
 use core::cell::Cell;
 use kernel::common::cells::{OptionalCell, TakeCell};
 use kernel::common::{List, ListLink, ListNode};
 use kernel::hil;
+use kernel::power::{PowerConstraint, SleepState};
 use kernel::ReturnCode;
 
 /// The Mux struct manages multiple Spi clients. Each client may have
@@ -12,6 +17,7 @@ pub struct MuxSpiMaster<'a, Spi: hil::spi::SpiMaster> {
     spi: &'a Spi,
     devices: List<'a, VirtualSpiMasterDevice<'a, Spi>>,
     inflight: OptionalCell<&'a VirtualSpiMasterDevice<'a, Spi>>,
+    constraint: OptionalCell<&'a PowerConstraint<'a>>,
 }
 
 impl<Spi: hil::spi::SpiMaster> hil::spi::SpiMasterClient for MuxSpiMaster<'_, Spi> {
@@ -23,6 +29,9 @@ impl<Spi: hil::spi::SpiMaster> hil::spi::SpiMasterClient for MuxSpiMaster<'_, Sp
     ) {
         self.inflight.take().map(move |device| {
             self.do_next_op();
+            if self.inflight.is_none() {
+                self.constraint.map(|constraint| constraint.release());
+            }
             device.read_write_done(write_buffer, read_buffer, len);
         });
     }
@@ -34,9 +43,15 @@ impl<'a, Spi: hil::spi::SpiMaster> MuxSpiMaster<'a, Spi> {
             spi: spi,
             devices: List::new(),
             inflight: OptionalCell::empty(),
+            constraint: OptionalCell::empty(),
         }
     }
 
+    /// Limit sleep with `constraint` while a transfer is in progress.
+    pub fn set_power_constraint(&self, constraint: &'a PowerConstraint<'a>) {
+        self.constraint.set(constraint);
+    }
+
     fn do_next_op(&self) {
         if self.inflight.is_none() {
             let mnode = self
@@ -60,6 +75,8 @@ impl<'a, Spi: hil::spi::SpiMaster> MuxSpiMaster<'a, Spi> {
                         // Only async operations want to block by setting
                         // the devices as inflight.
                         self.inflight.set(node);
+                        self.constraint
+                            .map(|constraint| constraint.require(SleepState::Sleep));
                         node.txbuffer.take().map(|txbuffer| {
                             let rxbuffer = node.rxbuffer.take();
                             self.spi.read_write_bytes(txbuffer, rxbuffer, len);
@@ -10,6 +10,11 @@
 //! `MuxUart` provides shared access to a single UART bus for multiple users.
 //! `UartDevice` provides access for a single client.
 //!
+//! Given a `LivenessSource` with `set_liveness()`, the mux checks in each time
+//! it starts a transmission, and suspends the source while it has nothing to
+//! send, so a UART that never finishes a transmission stops the watchdog from
+//! being tickled.
+//!
 //! Usage
 //! -----
 //!
@@ -50,6 +55,7 @@ use kernel::common::dynamic_deferred_call::{
 };
 use kernel::common::{List, ListLink, ListNode};
 use kernel::hil::uart;
+use kernel::liveness::LivenessSource;
 use kernel::ReturnCode;
 
 const RX_BUF_LEN: usize = 64;
@@ -64,6 +70,7 @@ pub struct MuxUart<'a> {
     completing_read: Cell<bool>,
     deferred_caller: &'a DynamicDeferredCall,
     handle: OptionalCell<DeferredCallHandle>,
+    liveness: OptionalCell<&'a LivenessSource<'a>>,
 }
 
 impl<'a> uart::TransmitClient for MuxUart<'a> {
@@ -200,6 +207,7 @@ impl<'a> MuxUart<'a> {
             completing_read: Cell::new(false),
             deferred_caller: deferred_caller,
             handle: OptionalCell::empty(),
+            liveness: OptionalCell::empty(),
         }
     }
 
@@ -217,12 +225,24 @@ impl<'a> MuxUart<'a> {
         self.handle.replace(handle);
     }
 
+    /// Check in with `liveness` whenever a transmission starts. The source is
+    /// suspended until then.
+    pub fn set_liveness(&self, liveness: &'a LivenessSource<'a>) {
+        if self.inflight.is_none() {
+            liveness.suspend();
+        }
+        self.liveness.set(liveness);
+    }
+
     fn do_next_op(&self) {
         if self.inflight.is_none() {
             let mnode = self.devices.iter().find(|node| node.operation.is_some());
+            if mnode.is_none() {
+                self.liveness.map(|liveness| liveness.suspend());
+            }
             mnode.map(|node| {
-                node.tx_buffer.take().map(|buf| {
-                    node.operation.map(move |op| match op {
+                let started = node.tx_buffer.take().map_or(false, |buf| {
+                    node.operation.map_or(false, move |op| match op {
                         Operation::Transmit { len } => {
                             let (rcode, rbuf) = self.uart.transmit_buffer(buf, *len);
                             if rcode != ReturnCode::SUCCESS {
@@ -231,6 +251,7 @@ impl<'a> MuxUart<'a> {
                                     client.transmitted_buffer(rbuf.unwrap(), 0, rcode);
                                 });
                             }
+                            rcode == ReturnCode::SUCCESS
                         }
                         Operation::TransmitWord { word } => {
                             let rcode = self.uart.transmit_word(*word);
@@ -240,11 +261,19 @@ impl<'a> MuxUart<'a> {
                                     client.transmitted_word(rcode);
                                 });
                             }
+                            rcode == ReturnCode::SUCCESS
                         }
-                    });
+                    })
                 });
                 node.operation.clear();
-                self.inflight.set(node);
+                if started {
+                    self.inflight.set(node);
+                    self.liveness.map(|liveness| liveness.resume());
+                } else {
+                    // No completion will come for this one, so move on to the
+                    // next device.
+                    self.do_next_op_async();
+                }
             });
         }
     }
@@ -32,18 +32,61 @@
 //! * Payload - 2 to 255 bytes
 //!
 //! * CRC - 3 bytes
+//!
+//! ### Extended Advertising
+//!
+//! Besides the 1 Mb/s PHY, the radio sends and receives on the 2 Mb/s PHY
+//! and, on the nRF52840, on the coded PHY. The delayed transmissions of
+//! extended advertising run on `TIMER0`, so a board that uses them must make
+//! this driver its alarm client:
+//!
+//! ```rust
+//! nrf52::timer::TIMER0.set_alarm_client(&nrf52::ble_radio::RADIO);
+//! ```
+//!
+//! ### Connections
+//!
+//! The radio also implements `hil::ble_connection::BleConnection` as a
+//! peripheral. It advertises with `ADV_IND` on the three advertising
+//! channels, answers scan requests with an empty scan response and accepts
+//! `CONNECT_IND`. It then follows the connection events on the data channels
+//! chosen by channel selection algorithm #1. Each event carries one packet
+//! from the master and one answer, so one LL data PDU of at most 27 bytes
+//! moves each way per connection interval. The link layer answers the
+//! control procedures a slave has to, follows connection updates and channel
+//! map changes, and rejects encryption.
+//!
+//! Answers go out `T_IFS` after the packet they answer, and the radio learns
+//! what to send when its interrupt is serviced, so the kernel has to service
+//! the radio interrupt within about 100 us. The advertising driver cannot use
+//! the radio while it advertises connectably or is connected. Connection
+//! events are timed with `TIMER0` too, which needs the high frequency crystal
+//! oscillator for the accuracy the window widening assumes:
+//!
+//! ```rust
+//! nrf52::clock::CLOCK.high_start();
+//! nrf52::timer::TIMER0.set_alarm_client(&nrf52::ble_radio::RADIO);
+//! kernel::hil::ble_connection::BleConnection::set_client(&nrf52::ble_radio::RADIO, l2cap);
+//! ```
 
 use core::cell::Cell;
+use core::cmp;
 use core::convert::TryFrom;
 use kernel::common::cells::OptionalCell;
 use kernel::common::cells::TakeCell;
 use kernel::common::registers::{register_bitfields, ReadOnly, ReadWrite, WriteOnly};
 use kernel::common::StaticRef;
 use kernel::hil::ble_advertising;
-use kernel::hil::ble_advertising::RadioChannel;
+use kernel::hil::ble_advertising::{Phy, RadioChannel};
+use kernel::hil::ble_connection::{
+    BleConnection, ConnectionClient, ConnectionHandle, DeviceAddress,
+};
+use kernel::hil::time::{Alarm, AlarmClient, Ticks, Ticks32, Time};
 use kernel::ReturnCode;
 use nrf5x::constants::TxPower;
 
+use crate::ficr;
+
 const RADIO_BASE: StaticRef<RadioRegisters> =
     unsafe { StaticRef::new(0x40001000 as *const RadioRegisters) };
 
@@ -349,7 +392,10 @@ register_bitfields! [u32,
             NRF_1MBIT = 0,
             NRF_2MBIT = 1,
             NRF_250KBIT = 2,
-            BLE_1MBIT = 3
+            BLE_1MBIT = 3,
+            BLE_2MBIT = 4,
+            BLE_LR125KBIT = 5,
+            BLE_LR500KBIT = 6
         ]
     ],
     /// Packet configuration register 0
@@ -365,11 +411,17 @@ register_bitfields! [u32,
             AUTOMATIC = 0,
             INCLUDE = 1
         ],
+        /// Length of code indicator, long range
+        CILEN OFFSET(22) NUMBITS(2) [],
         /// Length of preamble on air. Decision point: TASKS_START task
-        PLEN OFFSET(24) NUMBITS(1) [
+        PLEN OFFSET(24) NUMBITS(2) [
             EIGHT = 0,
-            SIXTEEN = 1
-        ]
+            SIXTEEN = 1,
+            THIRTYTWOZEROS = 2,
+            LONGRANGE = 3
+        ],
+        /// Length of TERM field in long range operation
+        TERMLEN OFFSET(29) NUMBITS(2) []
     ],
     /// Packet configuration register 1
     PacketConfiguration1 [
@@ -526,15 +578,300 @@ register_bitfields! [u32,
     ]
 ];
 
-static mut PAYLOAD: [u8; nrf5x::constants::RADIO_PAYLOAD_LENGTH] =
-    [0x00; nrf5x::constants::RADIO_PAYLOAD_LENGTH];
+/// S0, length and the longest payload.
+const PDU_LENGTH: usize = 2 + nrf5x::constants::RADIO_PAYLOAD_LENGTH;
+
+static mut PAYLOAD: [u8; PDU_LENGTH] = [0x00; PDU_LENGTH];
+
+/// `TIMER0` ticks at 16 kHz.
+const TIMER_HZ: u32 = 16000;
+
+/// From TXEN to the start of the preamble, with the default ramp-up.
+const TX_RAMP_UP_US: u32 = 140;
+
+/// From RXEN until the radio receives, with the default ramp-up.
+const RX_RAMP_UP_US: u32 = 140;
+
+// BLUETOOTH SPECIFICATION Version 5.0 [Vol 6, Part B], section 4.1.1
+const T_IFS_US: u32 = 150;
+
+/// From the start of a packet until its access address was received.
+const ADDRESS_US: u32 = 40;
+
+/// How long the longest packet the radio receives takes on air.
+const MAX_PACKET_US: u32 = 8 + 32 + (2 + 255) * 8 + 24;
+
+/// How long to listen for a scan or connect request after each `ADV_IND`.
+const ADV_LISTEN_US: u32 = 1000;
+
+/// Added to the window widening for the 62.5 us resolution of `TIMER0` and
+/// for interrupt latency.
+const WINDOW_MARGIN_US: u32 = 250;
+
+/// The accuracy of `TIMER0` on the crystal oscillator.
+const LOCAL_SCA_PPM: u32 = 50;
+
+/// The master's sleep clock accuracy for each value of the SCA field.
+const SCA_PPM: [u32; 8] = [500, 250, 150, 100, 75, 50, 30, 20];
+
+/// Connection intervals and window offsets count 1.25 ms units.
+const TICKS_PER_UNIT: u32 = 20;
+
+/// Supervision timeouts count 10 ms units.
+const TICKS_PER_TIMEOUT_UNIT: u32 = 160;
+
+/// The longest advertising data of an `ADV_IND`.
+const MAX_ADV_DATA_LEN: usize = 31;
+
+/// The longest LL data PDU payload, without the data length extension.
+const MAX_DATA_LEN: usize = 27;
+
+/// Length of the basic L2CAP header, which holds the length of the PDU.
+const L2CAP_HEADER_LEN: usize = 4;
+
+/// The longest L2CAP PDU, header included, sent or received on a
+/// connection.
+const MAX_L2CAP_PDU_LEN: usize = 251;
+
+/// Header and payload of an `ADV_IND` with the longest advertising data.
+const LINK_PDU_LENGTH: usize = 2 + 6 + MAX_ADV_DATA_LEN;
+
+/// The packet the link layer sends, while `PAYLOAD` receives.
+static mut LINK_PAYLOAD: [u8; LINK_PDU_LENGTH] = [0x00; LINK_PDU_LENGTH];
+
+/// The L2CAP PDU being reassembled.
+static mut L2CAP_PDU: [u8; MAX_L2CAP_PDU_LEN] = [0x00; MAX_L2CAP_PDU_LEN];
+
+// BLUETOOTH SPECIFICATION Version 5.0 [Vol 6, Part B], section 2.3
+const ADV_IND: u8 = 0x0;
+const SCAN_REQ: u8 = 0x3;
+const SCAN_RSP: u8 = 0x4;
+const CONNECT_IND: u8 = 0x5;
+const PDU_TYPE: u8 = 0x0f;
+const TX_ADD: u8 = 0x40;
+const RX_ADD: u8 = 0x80;
+
+// BLUETOOTH SPECIFICATION Version 5.0 [Vol 6, Part B], section 2.4
+const LLID: u8 = 0x03;
+const LLID_CONTINUATION: u8 = 0x1;
+const LLID_START: u8 = 0x2;
+const LLID_CONTROL: u8 = 0x3;
+const NESN: u8 = 0x04;
+const SN: u8 = 0x08;
+
+// BLUETOOTH SPECIFICATION Version 5.0 [Vol 6, Part B], section 2.4.2
+const LL_CONNECTION_UPDATE_IND: u8 = 0x00;
+const LL_CHANNEL_MAP_IND: u8 = 0x01;
+const LL_TERMINATE_IND: u8 = 0x02;
+const LL_ENC_REQ: u8 = 0x03;
+const LL_UNKNOWN_RSP: u8 = 0x07;
+const LL_FEATURE_REQ: u8 = 0x08;
+const LL_FEATURE_RSP: u8 = 0x09;
+const LL_VERSION_IND: u8 = 0x0c;
+const LL_REJECT_IND: u8 = 0x0d;
+const LL_REJECT_EXT_IND: u8 = 0x11;
+const LL_PING_REQ: u8 = 0x12;
+const LL_PING_RSP: u8 = 0x13;
+const LL_LENGTH_REQ: u8 = 0x14;
+const LL_LENGTH_RSP: u8 = 0x15;
+
+/// Bluetooth 5.0, in `LL_VERSION_IND`.
+const LL_VERSION: u8 = 0x09;
+/// The company identifier of devices that have none.
+const COMPANY_ID: u16 = 0xffff;
+
+// HCI error codes, BLUETOOTH SPECIFICATION Version 5.0 [Vol 2, Part D]
+const CONNECTION_TIMEOUT: u8 = 0x08;
+const LOCAL_HOST_TERMINATED: u8 = 0x16;
+const UNSUPPORTED_REMOTE_FEATURE: u8 = 0x1a;
+const INSTANT_PASSED: u8 = 0x28;
+const FAILED_TO_ESTABLISH: u8 = 0x3e;
+
+fn us_to_ticks(us: u32) -> u32 {
+    (us * (TIMER_HZ / 1000) + 999) / 1000
+}
+
+/// The used channels of a channel map in ascending order, and how many there
+/// are.
+fn used_channels(map: &[u8; 5]) -> ([u8; 37], usize) {
+    let mut used = [0; 37];
+    let mut count = 0;
+    for channel in 0..37 {
+        if map[channel / 8] & (1 << (channel % 8)) != 0 {
+            used[count] = channel as u8;
+            count += 1;
+        }
+    }
+    (used, count)
+}
+
+/// What the link layer does with the radio.
+#[derive(Copy, Clone, PartialEq)]
+enum LinkState {
+    /// Neither advertising nor connected.
+    Off,
+    /// Waiting for the next advertising event.
+    AdvSleep,
+    /// Sending `ADV_IND` on the advertising channel with this index.
+    AdvTransmit(u32),
+    /// Listening for a scan or connect request after it.
+    AdvListen(u32),
+    /// Sending the scan response.
+    AdvScanResponse(u32),
+    /// Stopping the radio before the next advertising channel.
+    AdvStop(u32),
+    /// Connected, waiting for the next connection event.
+    ConnSleep,
+    /// Listening for the master's packet.
+    ConnListen,
+    /// Sending the answer.
+    ConnTransmit,
+    /// Stopping the radio to end the connection event.
+    ConnStop,
+}
+
+/// What `LINK_PAYLOAD` holds until the master acknowledges it.
+#[derive(Copy, Clone, PartialEq)]
+enum Sent {
+    Empty,
+    Control,
+    Terminate,
+    /// A fragment of the L2CAP PDU, this many bytes long.
+    Data(usize),
+}
+
+// BLUETOOTH SPECIFICATION Version 5.0 [Vol 6, Part B], section 5.1.1
+#[derive(Copy, Clone)]
+struct Update {
+    win_size: u8,
+    win_offset: u16,
+    interval: u16,
+    timeout: u16,
+    instant: u16,
+}
+
+/// The link layer state of `BleConnection`.
+struct Link<'a> {
+    state: Cell<LinkState>,
+    client: OptionalCell<&'a dyn ConnectionClient>,
+
+    advertising: Cell<bool>,
+    adv_data: Cell<[u8; MAX_ADV_DATA_LEN]>,
+    adv_data_len: Cell<usize>,
+    /// Ticks between advertising events, before the random delay.
+    adv_interval: Cell<u32>,
+    /// State of the generator of the random delay.
+    prng: Cell<u16>,
+
+    connected: Cell<bool>,
+    handle: Cell<ConnectionHandle>,
+    access_address: Cell<u32>,
+    crc_init: Cell<u32>,
+    /// Connection interval and supervision timeout in ticks.
+    interval: Cell<u32>,
+    timeout: Cell<u32>,
+    channel_map: Cell<[u8; 5]>,
+    hop: Cell<u32>,
+    unmapped_channel: Cell<u32>,
+    /// Counter of the current, or while sleeping the next, connection event.
+    event_counter: Cell<u16>,
+    master_sca_ppm: Cell<u32>,
+    /// Whether the master sent a packet yet.
+    established: Cell<bool>,
+    /// Connection events since the last packet with a good CRC.
+    missed: Cell<u32>,
+    /// Transmit window of the next connection event, in ticks.
+    window: Cell<u32>,
+    /// Ticks from waking up for this event until `TIMER0` was last reset.
+    since_wake: Cell<u32>,
+    /// Ticks from that reset until listening stops.
+    listen: Cell<u32>,
+    /// A packet was still coming in when listening should have stopped.
+    receiving: Cell<bool>,
+    /// It was time for the next connection event before this one ended.
+    late: Cell<bool>,
+    update: Cell<Option<Update>>,
+    map_update: Cell<Option<([u8; 5], u16)>>,
+    sn: Cell<bool>,
+    nesn: Cell<bool>,
+    sent: Cell<Sent>,
+    /// A control PDU to send and its length.
+    control: Cell<Option<([u8; 9], usize)>>,
+    version_sent: Cell<bool>,
+    /// `disconnect()` asked to send `LL_TERMINATE_IND` with this reason.
+    terminate: Cell<Option<u8>>,
+    /// Close the connection after this event, for this reason.
+    closing: Cell<Option<u8>>,
+
+    tx_buffer: TakeCell<'static, [u8]>,
+    tx_len: Cell<usize>,
+    tx_offset: Cell<usize>,
+    /// Length of the L2CAP PDU being received, 0 if none is, and how much of
+    /// it arrived.
+    rx_len: Cell<usize>,
+    rx_offset: Cell<usize>,
+}
+
+impl<'a> Link<'a> {
+    const fn new() -> Link<'a> {
+        Link {
+            state: Cell::new(LinkState::Off),
+            client: OptionalCell::empty(),
+            advertising: Cell::new(false),
+            adv_data: Cell::new([0; MAX_ADV_DATA_LEN]),
+            adv_data_len: Cell::new(0),
+            adv_interval: Cell::new(0),
+            prng: Cell::new(0xace1),
+            connected: Cell::new(false),
+            handle: Cell::new(0),
+            access_address: Cell::new(0),
+            crc_init: Cell::new(0),
+            interval: Cell::new(0),
+            timeout: Cell::new(0),
+            channel_map: Cell::new([0; 5]),
+            hop: Cell::new(0),
+            unmapped_channel: Cell::new(0),
+            event_counter: Cell::new(0),
+            master_sca_ppm: Cell::new(0),
+            established: Cell::new(false),
+            missed: Cell::new(0),
+            window: Cell::new(0),
+            since_wake: Cell::new(0),
+            listen: Cell::new(0),
+            receiving: Cell::new(false),
+            late: Cell::new(false),
+            update: Cell::new(None),
+            map_update: Cell::new(None),
+            sn: Cell::new(false),
+            nesn: Cell::new(false),
+            sent: Cell::new(Sent::Empty),
+            control: Cell::new(None),
+            version_sent: Cell::new(false),
+            terminate: Cell::new(None),
+            closing: Cell::new(None),
+            tx_buffer: TakeCell::empty(),
+            tx_len: Cell::new(0),
+            tx_offset: Cell::new(0),
+            rx_len: Cell::new(0),
+            rx_offset: Cell::new(0),
+        }
+    }
+}
 
 pub struct Radio<'a> {
     registers: StaticRef<RadioRegisters>,
     tx_power: Cell<TxPower>,
+    phy: Cell<Phy>,
+    access_address: Cell<u32>,
+    crc_init: Cell<u32>,
+    /// How long the packet being sent takes on air.
+    airtime_us: Cell<u32>,
+    /// A transmission waits for `TIMER0`.
+    delayed: Cell<bool>,
     rx_client: OptionalCell<&'a dyn ble_advertising::RxClient>,
     tx_client: OptionalCell<&'a dyn ble_advertising::TxClient>,
     buffer: TakeCell<'static, [u8]>,
+    link: Link<'a>,
 }
 
 pub static mut RADIO: Radio = Radio::new();
@@ -544,17 +881,30 @@ impl<'a> Radio<'a> {
         Radio {
             registers: RADIO_BASE,
             tx_power: Cell::new(TxPower::ZerodBm),
+            phy: Cell::new(Phy::Le1M),
+            access_address: Cell::new(ble_advertising::ADVERTISING_ACCESS_ADDRESS),
+            crc_init: Cell::new(ble_advertising::ADVERTISING_CRC_INIT),
+            airtime_us: Cell::new(0),
+            delayed: Cell::new(false),
             rx_client: OptionalCell::empty(),
             tx_client: OptionalCell::empty(),
             buffer: TakeCell::empty(),
+            link: Link::new(),
         }
     }
 
     pub fn is_enabled(&self) -> bool {
-        self.registers.mode.matches_all(Mode::MODE::BLE_1MBIT)
+        match self.registers.mode.read_as_enum(Mode::MODE) {
+            Some(Mode::MODE::Value::BLE_1MBIT)
+            | Some(Mode::MODE::Value::BLE_2MBIT)
+            | Some(Mode::MODE::Value::BLE_LR125KBIT)
+            | Some(Mode::MODE::Value::BLE_LR500KBIT) => true,
+            _ => false,
+        }
     }
 
     fn tx(&self) {
+        self.airtime_us.set(self.airtime_us());
         self.registers.event_ready.write(Event::READY::CLEAR);
         self.registers.task_txen.write(Task::ENABLE::SET);
     }
@@ -598,6 +948,10 @@ impl<'a> Radio<'a> {
 
     #[inline(never)]
     pub fn handle_interrupt(&self) {
+        if self.link.state.get() != LinkState::Off {
+            self.handle_link_interrupt();
+            return;
+        }
         self.disable_all_interrupts();
 
         if self.registers.event_ready.is_set(Event::READY) {
@@ -677,7 +1031,7 @@ impl<'a> Radio<'a> {
 
     fn replace_radio_buffer(&self, buf: &'static mut [u8]) -> &'static mut [u8] {
         // set payload
-        for (i, c) in buf.as_ref().iter().enumerate() {
+        for (i, c) in buf.as_ref().iter().take(PDU_LENGTH).enumerate() {
             unsafe {
                 PAYLOAD[i] = *c;
             }
@@ -685,12 +1039,29 @@ impl<'a> Radio<'a> {
         buf
     }
 
+    // BLUETOOTH SPECIFICATION Version 5.0 [Vol 6, Part B], section 2.1 and 2.2
+    //
+    // How long the packet in `PAYLOAD` takes on air, from the start of the
+    // preamble to the end of the CRC.
+    fn airtime_us(&self) -> u32 {
+        let pdu_bits = unsafe { (2 + PAYLOAD[1] as u32) * 8 };
+        match self.phy.get() {
+            // preamble, access address, PDU and CRC
+            Phy::Le1M => 8 + 32 + pdu_bits + 24,
+            Phy::Le2M => (16 + 32 + pdu_bits + 24) / 2,
+            // an 80 us preamble, then the access address, coding indicator
+            // and TERM1 at S = 8, then the PDU, CRC and TERM2 at S = 8 or 2
+            Phy::LeCodedS8 => 80 + (32 + 2 + 3) * 8 + (pdu_bits + 24 + 3) * 8,
+            Phy::LeCodedS2 => 80 + (32 + 2 + 3) * 8 + (pdu_bits + 24 + 3) * 2,
+        }
+    }
+
     fn ble_initialize(&self, channel: RadioChannel) {
         self.radio_on();
 
         self.ble_set_tx_power();
 
-        self.ble_set_channel_rate();
+        self.ble_set_channel_rate(self.phy.get());
 
         self.ble_set_channel_freq(channel);
         self.ble_set_data_whitening(channel);
@@ -698,8 +1069,8 @@ impl<'a> Radio<'a> {
         self.set_tx_address();
         self.set_rx_address();
 
-        self.ble_set_packet_config();
-        self.ble_set_advertising_access_address();
+        self.ble_set_packet_config(self.phy.get());
+        self.ble_set_access_address();
 
         self.ble_set_crc_config();
 
@@ -711,19 +1082,18 @@ impl<'a> Radio<'a> {
         self.registers
             .crccnf
             .write(CrcConfiguration::LEN::THREE + CrcConfiguration::SKIPADDR::EXCLUDE);
-        self.registers
-            .crcinit
-            .set(nrf5x::constants::RADIO_CRCINIT_BLE);
+        self.registers.crcinit.set(self.crc_init.get());
         self.registers
             .crcpoly
             .set(nrf5x::constants::RADIO_CRCPOLY_BLE);
     }
 
     // BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 2.1.2 Access Address
-    // Set access address to 0x8E89BED6
-    fn ble_set_advertising_access_address(&self) {
-        self.registers.prefix0.set(0x0000008e);
-        self.registers.base0.set(0x89bed600);
+    // The advertising access address 0x8E89BED6 unless a periodic advertising train set another
+    fn ble_set_access_address(&self) {
+        let access_address = self.access_address.get();
+        self.registers.prefix0.set(access_address >> 24);
+        self.registers.base0.set(access_address << 8);
     }
 
     // Packet configuration
@@ -735,7 +1105,19 @@ impl<'a> Radio<'a> {
     // | (1 byte) |   | (4 bytes)      |   | (2-255 bytes) |   | (3 bytes)  |
     // +----------+   +----------------+   +---------------+   +------------+
     //
-    fn ble_set_packet_config(&self) {
+    //
+    // The 2 Mb/s PHY has a 2 byte preamble and the coded PHY an 80 us one followed by a coding
+    // indicator and a TERM field after the access address.
+    fn ble_set_packet_config(&self, phy: Phy) {
+        let preamble = match phy {
+            Phy::Le1M => PacketConfiguration0::PLEN::EIGHT,
+            Phy::Le2M => PacketConfiguration0::PLEN::SIXTEEN,
+            Phy::LeCodedS8 | Phy::LeCodedS2 => {
+                PacketConfiguration0::PLEN::LONGRANGE
+                    + PacketConfiguration0::CILEN.val(2)
+                    + PacketConfiguration0::TERMLEN.val(3)
+            }
+        };
         // sets the header of PDU TYPE to 1 byte
         // sets the header length to 1 byte
         self.registers.pcnf0.write(
@@ -743,7 +1125,7 @@ impl<'a> Radio<'a> {
                 + PacketConfiguration0::S0LEN.val(1)
                 + PacketConfiguration0::S1LEN::CLEAR
                 + PacketConfiguration0::S1INCL::CLEAR
-                + PacketConfiguration0::PLEN::EIGHT,
+                + preamble,
         );
 
         self.registers.pcnf1.write(
@@ -756,9 +1138,14 @@ impl<'a> Radio<'a> {
     }
 
     // BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part A], 4.6 REFERENCE SIGNAL DEFINITION
-    // Bit Rate = 1 Mb/s ±1 ppm
-    fn ble_set_channel_rate(&self) {
-        self.registers.mode.write(Mode::MODE::BLE_1MBIT);
+    // Bit Rate = 1 Mb/s ±1 ppm, or 2 Mb/s, 500 kb/s and 125 kb/s for the Bluetooth 5 PHYs
+    fn ble_set_channel_rate(&self, phy: Phy) {
+        self.registers.mode.write(match phy {
+            Phy::Le1M => Mode::MODE::BLE_1MBIT,
+            Phy::Le2M => Mode::MODE::BLE_2MBIT,
+            Phy::LeCodedS8 => Mode::MODE::BLE_LR125KBIT,
+            Phy::LeCodedS2 => Mode::MODE::BLE_LR500KBIT,
+        });
     }
 
     // BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 3.2 Data Whitening
@@ -788,6 +1175,643 @@ impl<'a> Radio<'a> {
     }
 }
 
+// The link layer behind `BleConnection`. Every radio operation ends with
+// `END_DISABLE`, and the DISABLED interrupt sets up what follows while the
+// shortcuts already ramp the radio up for it.
+impl<'a> Radio<'a> {
+    fn link_now(&self) -> Ticks32 {
+        unsafe { nrf5x::timer::TIMER0.now() }
+    }
+
+    fn link_set_alarm(&self, reference: Ticks32, ticks: u32) {
+        unsafe {
+            nrf5x::timer::TIMER0.set_alarm(reference, Ticks32::from(ticks));
+        }
+    }
+
+    fn link_disarm(&self) {
+        unsafe {
+            nrf5x::timer::TIMER0.disarm();
+        }
+    }
+
+    fn link_address(&self) -> DeviceAddress {
+        let ficr = unsafe { &ficr::FICR_INSTANCE };
+        let mut address = ficr.address();
+        let random = match ficr.address_type() {
+            ficr::AddressType::Random => {
+                // A random static address has the two top bits set.
+                address[5] |= 0xc0;
+                true
+            }
+            ficr::AddressType::Public => false,
+        };
+        DeviceAddress {
+            address: address,
+            random: random,
+        }
+    }
+
+    /// Set the radio up on the channel with `index`, for packets with
+    /// `access_address` and `crc_init`.
+    fn link_initialize(&self, index: u32, access_address: u32, crc_init: u32) {
+        self.ble_initialize(
+            RadioChannel::from_channel_index(index).unwrap_or(RadioChannel::AdvertisingChannel37),
+        );
+        // Connections only use the 1 Mb/s PHY.
+        self.ble_set_channel_rate(Phy::Le1M);
+        self.ble_set_packet_config(Phy::Le1M);
+        self.registers.prefix0.set(access_address >> 24);
+        self.registers.base0.set(access_address << 8);
+        self.registers.crcinit.set(crc_init);
+        self.registers
+            .tifs
+            .write(InterFrameSpacing::TIFS.val(T_IFS_US));
+        self.disable_all_interrupts();
+        self.registers.intenset.write(Interrupt::DISABLED::SET);
+    }
+
+    /// Stop the radio, and the answer it may be ramping up for.
+    fn link_stop(&self, next: LinkState) {
+        self.registers.shorts.set(0);
+        self.link.state.set(next);
+        self.registers.task_disable.write(Task::ENABLE::SET);
+    }
+
+    fn handle_link_interrupt(&self) {
+        if !self.registers.event_disabled.is_set(Event::READY) {
+            return;
+        }
+        self.registers.event_disabled.write(Event::READY::CLEAR);
+        self.registers.event_ready.write(Event::READY::CLEAR);
+        self.registers.event_address.write(Event::READY::CLEAR);
+        self.registers.event_payload.write(Event::READY::CLEAR);
+        self.registers.event_end.write(Event::READY::CLEAR);
+
+        match self.link.state.get() {
+            LinkState::AdvTransmit(index) => self.adv_listen(index),
+            LinkState::AdvListen(index) => self.adv_request_received(index),
+            LinkState::AdvScanResponse(index) | LinkState::AdvStop(index) => self.adv_next(index),
+            LinkState::ConnListen => self.conn_packet_received(),
+            LinkState::ConnTransmit | LinkState::ConnStop => self.conn_event_done(),
+            LinkState::Off | LinkState::AdvSleep | LinkState::ConnSleep => {}
+        }
+    }
+
+    fn link_alarm(&self) {
+        match self.link.state.get() {
+            LinkState::AdvSleep => self.advertise(37),
+            LinkState::AdvListen(index) => self.link_stop(LinkState::AdvStop(index)),
+            LinkState::ConnSleep => self.conn_event(),
+            LinkState::ConnListen => self.conn_listen_timeout(),
+            LinkState::ConnTransmit | LinkState::ConnStop => self.link.late.set(true),
+            _ => {}
+        }
+    }
+
+    /// Send `ADV_IND` on the advertising channel with `index`.
+    fn advertise(&self, index: u32) {
+        self.link_initialize(
+            index,
+            ble_advertising::ADVERTISING_ACCESS_ADDRESS,
+            ble_advertising::ADVERTISING_CRC_INIT,
+        );
+        let address = self.link_address();
+        let len = self.link.adv_data_len.get();
+        unsafe {
+            LINK_PAYLOAD[0] = ADV_IND | if address.random { TX_ADD } else { 0 };
+            LINK_PAYLOAD[1] = (6 + len) as u8;
+            LINK_PAYLOAD[2..8].copy_from_slice(&address.address);
+            LINK_PAYLOAD[8..8 + len].copy_from_slice(&self.link.adv_data.get()[..len]);
+            self.registers.packetptr.set(LINK_PAYLOAD.as_ptr() as u32);
+        }
+        self.registers.shorts.write(
+            Shortcut::READY_START::SET + Shortcut::END_DISABLE::SET + Shortcut::DISABLED_RXEN::SET,
+        );
+        self.link.state.set(LinkState::AdvTransmit(index));
+        self.registers.task_txen.write(Task::ENABLE::SET);
+    }
+
+    /// The `ADV_IND` was sent and the radio ramps up to receive.
+    fn adv_listen(&self, index: u32) {
+        let address = self.link_address();
+        unsafe {
+            self.registers.packetptr.set(PAYLOAD.as_ptr() as u32);
+            // The scan response is empty, so only the header differs from
+            // the `ADV_IND`.
+            LINK_PAYLOAD[0] = SCAN_RSP | if address.random { TX_ADD } else { 0 };
+            LINK_PAYLOAD[1] = 6;
+        }
+        self.registers.shorts.write(
+            Shortcut::READY_START::SET + Shortcut::END_DISABLE::SET + Shortcut::DISABLED_TXEN::SET,
+        );
+        self.link.state.set(LinkState::AdvListen(index));
+        self.link_set_alarm(self.link_now(), us_to_ticks(ADV_LISTEN_US));
+    }
+
+    /// A packet arrived after the `ADV_IND`, and the radio ramps up to send
+    /// the scan response.
+    fn adv_request_received(&self, index: u32) {
+        self.link_disarm();
+        let address = self.link_address();
+        let (header, len) = unsafe { (PAYLOAD[0], PAYLOAD[1]) };
+        let for_us = unsafe { PAYLOAD[8..14] == address.address }
+            && (header & RX_ADD != 0) == address.random;
+        if self.registers.crcstatus.is_set(Event::READY) && for_us {
+            if header & PDU_TYPE == SCAN_REQ && len == 12 {
+                unsafe {
+                    self.registers.packetptr.set(LINK_PAYLOAD.as_ptr() as u32);
+                }
+                self.registers
+                    .shorts
+                    .write(Shortcut::READY_START::SET + Shortcut::END_DISABLE::SET);
+                self.link.state.set(LinkState::AdvScanResponse(index));
+                return;
+            }
+            if header & PDU_TYPE == CONNECT_IND && len == 34 {
+                if let Some(peer) = self.connect(header) {
+                    self.link_stop(LinkState::ConnStop);
+                    let handle = self.link.handle.get();
+                    self.link
+                        .client
+                        .map(|client| client.connected(handle, peer));
+                    return;
+                }
+            }
+        }
+        self.link_stop(LinkState::AdvStop(index));
+    }
+
+    /// Go on to the next advertising channel, or end the advertising event.
+    fn adv_next(&self, index: u32) {
+        if index < 39 && self.link.advertising.get() {
+            self.advertise(index + 1);
+            return;
+        }
+        self.radio_off();
+        if self.link.advertising.get() {
+            // BLUETOOTH SPECIFICATION Version 5.0 [Vol 6, Part B], section 4.4.2.2
+            // Advertising events are a pseudo-random 0 to 10 ms further apart
+            // than the interval.
+            let mut x = self.link.prng.get();
+            x ^= x << 7;
+            x ^= x >> 9;
+            x ^= x << 8;
+            self.link.prng.set(x);
+            let delay = x as u32 % us_to_ticks(10_000);
+            self.link.state.set(LinkState::AdvSleep);
+            self.link_set_alarm(self.link_now(), self.link.adv_interval.get() + delay);
+        } else {
+            self.link.state.set(LinkState::Off);
+        }
+    }
+
+    /// Set up the connection the `CONNECT_IND` in `PAYLOAD` asks for, and
+    /// return the master's address, unless we cannot follow it.
+    fn connect(&self, header: u8) -> Option<DeviceAddress> {
+        // BLUETOOTH SPECIFICATION Version 5.0 [Vol 6, Part B], section 2.3.3.1
+        let pdu = unsafe { &PAYLOAD[2..36] };
+        let ll = &pdu[12..];
+        let win_size = ll[7] as u32;
+        let win_offset = u16::from_le_bytes([ll[8], ll[9]]) as u32;
+        let interval = u16::from_le_bytes([ll[10], ll[11]]) as u32;
+        let timeout = u16::from_le_bytes([ll[14], ll[15]]) as u32;
+        let mut channel_map = [0; 5];
+        channel_map.copy_from_slice(&ll[16..21]);
+        channel_map[4] &= 0x1f;
+        let hop = (ll[21] & 0x1f) as u32;
+        if interval < 6 || interval > 3200 || hop < 5 || hop > 16 {
+            return None;
+        }
+        if used_channels(&channel_map).1 < 2 {
+            return None;
+        }
+        let mut peer = [0; 6];
+        peer.copy_from_slice(&pdu[..6]);
+
+        let link = &self.link;
+        link.access_address
+            .set(u32::from_le_bytes([ll[0], ll[1], ll[2], ll[3]]));
+        link.crc_init
+            .set(u32::from_le_bytes([ll[4], ll[5], ll[6], 0]));
+        link.interval.set(interval * TICKS_PER_UNIT);
+        link.timeout.set(timeout * TICKS_PER_TIMEOUT_UNIT);
+        link.channel_map.set(channel_map);
+        link.hop.set(hop);
+        link.unmapped_channel.set(0);
+        link.event_counter.set(0);
+        link.master_sca_ppm.set(SCA_PPM[(ll[21] >> 5) as usize]);
+        link.established.set(false);
+        link.missed.set(0);
+        link.window.set(win_size * TICKS_PER_UNIT);
+        link.late.set(false);
+        link.update.set(None);
+        link.map_update.set(None);
+        link.sn.set(false);
+        link.nesn.set(false);
+        link.sent.set(Sent::Empty);
+        link.control.set(None);
+        link.version_sent.set(false);
+        link.terminate.set(None);
+        link.closing.set(None);
+        link.tx_offset.set(0);
+        link.rx_len.set(0);
+        link.handle.set(link.handle.get().wrapping_add(1));
+        link.connected.set(true);
+        unsafe {
+            LINK_PAYLOAD[0] = LLID_CONTINUATION;
+            LINK_PAYLOAD[1] = 0;
+        }
+
+        // BLUETOOTH SPECIFICATION Version 5.0 [Vol 6, Part B], section 4.5.3
+        // The transmit window starts 1.25 ms plus the window offset after the
+        // end of the `CONNECT_IND`, which just arrived.
+        let start = (1 + win_offset) * TICKS_PER_UNIT;
+        self.link_set_alarm(self.link_now(), start.saturating_sub(self.lead()));
+        Some(DeviceAddress {
+            address: peer,
+            random: header & TX_ADD != 0,
+        })
+    }
+
+    // BLUETOOTH SPECIFICATION Version 5.0 [Vol 6, Part B], section 4.5.7
+    //
+    // Window widening in ticks, for the time since the last anchor point we
+    // heard.
+    fn widening(&self) -> u32 {
+        let elapsed = (self.link.missed.get() + 1) * self.link.interval.get();
+        let ppm = self.link.master_sca_ppm.get() + LOCAL_SCA_PPM;
+        let drift = (elapsed as u64 * ppm as u64 + 999_999) / 1_000_000;
+        drift as u32 + us_to_ticks(WINDOW_MARGIN_US)
+    }
+
+    /// How long before an anchor point to wake up.
+    fn lead(&self) -> u32 {
+        self.widening() + us_to_ticks(RX_RAMP_UP_US)
+    }
+
+    // BLUETOOTH SPECIFICATION Version 5.0 [Vol 6, Part B], section 4.5.8.2
+    // Channel Selection Algorithm #1
+    fn next_data_channel(&self) -> u32 {
+        let unmapped = (self.link.unmapped_channel.get() + self.link.hop.get()) % 37;
+        self.link.unmapped_channel.set(unmapped);
+        let map = self.link.channel_map.get();
+        if map[unmapped as usize / 8] & (1 << (unmapped % 8)) != 0 {
+            unmapped
+        } else {
+            let (used, count) = used_channels(&map);
+            used[unmapped as usize % count] as u32
+        }
+    }
+
+    /// Whether `instant` is not after the current connection event.
+    fn instant_passed(&self, instant: u16) -> bool {
+        let ahead = instant.wrapping_sub(self.link.event_counter.get());
+        ahead == 0 || ahead >= 32767
+    }
+
+    /// Wake up for a connection event and listen for the master.
+    fn conn_event(&self) {
+        let link = &self.link;
+        let counter = link.event_counter.get();
+        if let Some(update) = link.update.get() {
+            if update.instant == counter {
+                link.interval.set(update.interval as u32 * TICKS_PER_UNIT);
+                link.timeout
+                    .set(update.timeout as u32 * TICKS_PER_TIMEOUT_UNIT);
+                link.update.set(None);
+            }
+        }
+        if let Some((map, instant)) = link.map_update.get() {
+            if instant == counter {
+                link.channel_map.set(map);
+                link.map_update.set(None);
+            }
+        }
+
+        let channel = self.next_data_channel();
+        self.link_initialize(channel, link.access_address.get(), link.crc_init.get());
+        unsafe {
+            self.registers.packetptr.set(PAYLOAD.as_ptr() as u32);
+        }
+        self.registers.shorts.write(
+            Shortcut::READY_START::SET + Shortcut::END_DISABLE::SET + Shortcut::DISABLED_TXEN::SET,
+        );
+        link.state.set(LinkState::ConnListen);
+        self.registers.task_rxen.write(Task::ENABLE::SET);
+
+        // The master starts sending within the widened window around the
+        // anchor point, or anywhere in the transmit window after it.
+        link.since_wake.set(0);
+        link.receiving.set(false);
+        link.listen
+            .set(self.lead() + self.widening() + link.window.get() + us_to_ticks(ADDRESS_US));
+        self.link_set_alarm(Ticks32::from(0), link.listen.get());
+    }
+
+    /// Nothing arrived while listening for the master.
+    fn conn_listen_timeout(&self) {
+        let link = &self.link;
+        link.since_wake
+            .set(link.since_wake.get() + link.listen.get());
+        if self.registers.event_address.is_set(Event::READY) && !link.receiving.get() {
+            // A packet is coming in, give it time to end.
+            link.receiving.set(true);
+            link.listen.set(us_to_ticks(MAX_PACKET_US));
+            self.link_set_alarm(Ticks32::from(0), link.listen.get());
+            return;
+        }
+        let expected_anchor = self.lead();
+        link.missed.set(link.missed.get() + 1);
+        self.link_stop(LinkState::ConnStop);
+        self.conn_schedule(expected_anchor);
+    }
+
+    /// The master's packet arrived, and the radio ramps up to answer it.
+    fn conn_packet_received(&self) {
+        let link = &self.link;
+        let (header, len) = unsafe { (PAYLOAD[0], PAYLOAD[1] as u32) };
+        if !self.registers.crcstatus.is_set(Event::READY) {
+            // End the event without answering.
+            let expected_anchor = self.lead();
+            link.missed.set(link.missed.get() + 1);
+            self.link_stop(LinkState::ConnStop);
+            self.conn_schedule(expected_anchor);
+            return;
+        }
+        let end = self.link_now().into_u32() + link.since_wake.get();
+        let anchor = end.saturating_sub(us_to_ticks(8 + 32 + (2 + len) * 8 + 24));
+        link.missed.set(0);
+        link.established.set(true);
+
+        unsafe {
+            self.registers.packetptr.set(LINK_PAYLOAD.as_ptr() as u32);
+        }
+        self.registers
+            .shorts
+            .write(Shortcut::READY_START::SET + Shortcut::END_DISABLE::SET);
+        link.state.set(LinkState::ConnTransmit);
+
+        // BLUETOOTH SPECIFICATION Version 5.0 [Vol 6, Part B], section 4.5.9
+        let mut sent_buffer = None;
+        if (header & NESN != 0) != link.sn.get() {
+            link.sn.set(!link.sn.get());
+            sent_buffer = self.conn_acknowledged();
+            self.conn_load_next();
+        }
+        // A control PDU that cannot be answered yet is not acknowledged, so
+        // the master sends it again.
+        let llid = header & LLID;
+        let accepted = (header & SN != 0) == link.nesn.get()
+            && (llid != LLID_CONTROL || link.control.get().is_none());
+        if accepted {
+            link.nesn.set(!link.nesn.get());
+        }
+        unsafe {
+            LINK_PAYLOAD[0] = (LINK_PAYLOAD[0] & LLID)
+                | if link.nesn.get() { NESN } else { 0 }
+                | if link.sn.get() { SN } else { 0 };
+        }
+
+        let received = if accepted {
+            self.conn_receive(llid, cmp::min(len as usize, MAX_DATA_LEN))
+        } else {
+            None
+        };
+        self.conn_schedule(anchor);
+
+        let handle = link.handle.get();
+        if let Some(len) = received {
+            link.client
+                .map(|client| unsafe { client.pdu_received(handle, &L2CAP_PDU[..len]) });
+        }
+        if let Some(buffer) = sent_buffer {
+            link.client
+                .map(move |client| client.pdu_sent(handle, buffer, ReturnCode::SUCCESS));
+        }
+    }
+
+    /// The master acknowledged `LINK_PAYLOAD`. Returns the buffer of the
+    /// L2CAP PDU if that was its last fragment.
+    fn conn_acknowledged(&self) -> Option<&'static mut [u8]> {
+        let link = &self.link;
+        match link.sent.get() {
+            Sent::Data(len) => {
+                let offset = link.tx_offset.get() + len;
+                link.tx_offset.set(offset);
+                if offset >= link.tx_len.get() {
+                    return link.tx_buffer.take();
+                }
+            }
+            Sent::Terminate => {
+                if link.closing.get().is_none() {
+                    link.closing.set(Some(LOCAL_HOST_TERMINATED));
+                }
+            }
+            Sent::Empty | Sent::Control => {}
+        }
+        None
+    }
+
+    /// Put the next packet to send into `LINK_PAYLOAD`, less the header bits
+    /// for acknowledgements.
+    fn conn_load_next(&self) {
+        let link = &self.link;
+        let payload = unsafe { &mut LINK_PAYLOAD };
+        if let Some((pdu, len)) = link.control.take() {
+            payload[0] = LLID_CONTROL;
+            payload[1] = len as u8;
+            payload[2..2 + len].copy_from_slice(&pdu[..len]);
+            link.sent.set(Sent::Control);
+        } else if let Some(reason) = link.terminate.take() {
+            payload[0] = LLID_CONTROL;
+            payload[1] = 2;
+            payload[2] = LL_TERMINATE_IND;
+            payload[3] = reason;
+            link.sent.set(Sent::Terminate);
+        } else {
+            let offset = link.tx_offset.get();
+            let len = cmp::min(link.tx_len.get() - offset, MAX_DATA_LEN);
+            let fragment = link.tx_buffer.map(|buffer| {
+                payload[2..2 + len].copy_from_slice(&buffer[offset..offset + len]);
+            });
+            if fragment.is_some() {
+                payload[0] = if offset == 0 {
+                    LLID_START
+                } else {
+                    LLID_CONTINUATION
+                };
+                payload[1] = len as u8;
+                link.sent.set(Sent::Data(len));
+            } else {
+                payload[0] = LLID_CONTINUATION;
+                payload[1] = 0;
+                link.sent.set(Sent::Empty);
+            }
+        }
+    }
+
+    /// Handle the `len` byte payload of a new packet in `PAYLOAD`. Returns
+    /// the length of the L2CAP PDU in `L2CAP_PDU` if it completed one.
+    fn conn_receive(&self, llid: u8, len: usize) -> Option<usize> {
+        let payload = unsafe { &PAYLOAD[2..2 + len] };
+        let link = &self.link;
+        match llid {
+            LLID_CONTROL if len > 0 => {
+                self.conn_control(payload);
+                None
+            }
+            LLID_START if len >= 2 => {
+                let pdu_len = u16::from_le_bytes([payload[0], payload[1]]) as usize;
+                link.rx_len.set(L2CAP_HEADER_LEN + pdu_len);
+                link.rx_offset.set(0);
+                self.conn_reassemble(payload)
+            }
+            LLID_CONTINUATION if len > 0 && link.rx_len.get() > 0 => self.conn_reassemble(payload),
+            _ => None,
+        }
+    }
+
+    fn conn_reassemble(&self, fragment: &[u8]) -> Option<usize> {
+        let link = &self.link;
+        let total = link.rx_len.get();
+        let offset = link.rx_offset.get() + fragment.len();
+        if offset > total || total > MAX_L2CAP_PDU_LEN {
+            // Longer than announced or than we can hold, drop it.
+            link.rx_len.set(0);
+            return None;
+        }
+        unsafe {
+            L2CAP_PDU[offset - fragment.len()..offset].copy_from_slice(fragment);
+        }
+        link.rx_offset.set(offset);
+        if offset == total {
+            link.rx_len.set(0);
+            Some(total)
+        } else {
+            None
+        }
+    }
+
+    // BLUETOOTH SPECIFICATION Version 5.0 [Vol 6, Part B], section 5
+    fn conn_control(&self, pdu: &[u8]) {
+        let link = &self.link;
+        match pdu[0] {
+            LL_CONNECTION_UPDATE_IND if pdu.len() == 12 => {
+                let update = Update {
+                    win_size: pdu[1],
+                    win_offset: u16::from_le_bytes([pdu[2], pdu[3]]),
+                    interval: u16::from_le_bytes([pdu[4], pdu[5]]),
+                    timeout: u16::from_le_bytes([pdu[8], pdu[9]]),
+                    instant: u16::from_le_bytes([pdu[10], pdu[11]]),
+                };
+                if self.instant_passed(update.instant) {
+                    link.closing.set(Some(INSTANT_PASSED));
+                } else {
+                    link.update.set(Some(update));
+                }
+            }
+            LL_CHANNEL_MAP_IND if pdu.len() == 8 => {
+                let mut map = [0; 5];
+                map.copy_from_slice(&pdu[1..6]);
+                map[4] &= 0x1f;
+                let instant = u16::from_le_bytes([pdu[6], pdu[7]]);
+                if self.instant_passed(instant) {
+                    link.closing.set(Some(INSTANT_PASSED));
+                } else if used_channels(&map).1 >= 2 {
+                    link.map_update.set(Some((map, instant)));
+                }
+            }
+            LL_TERMINATE_IND if pdu.len() == 2 => link.closing.set(Some(pdu[1])),
+            LL_ENC_REQ => self.conn_respond(&[LL_REJECT_IND, UNSUPPORTED_REMOTE_FEATURE]),
+            // No optional features.
+            LL_FEATURE_REQ => self.conn_respond(&[LL_FEATURE_RSP, 0, 0, 0, 0, 0, 0, 0, 0]),
+            LL_VERSION_IND => {
+                if !link.version_sent.replace(true) {
+                    let company = COMPANY_ID.to_le_bytes();
+                    self.conn_respond(&[LL_VERSION_IND, LL_VERSION, company[0], company[1], 0, 0]);
+                }
+            }
+            LL_PING_REQ => self.conn_respond(&[LL_PING_RSP]),
+            // Only the default 27 bytes and 328 us each way.
+            LL_LENGTH_REQ => {
+                self.conn_respond(&[LL_LENGTH_RSP, 27, 0, 0x48, 0x01, 27, 0, 0x48, 0x01])
+            }
+            LL_UNKNOWN_RSP | LL_FEATURE_RSP | LL_REJECT_IND | LL_REJECT_EXT_IND | LL_PING_RSP
+            | LL_LENGTH_RSP => {}
+            opcode => self.conn_respond(&[LL_UNKNOWN_RSP, opcode]),
+        }
+    }
+
+    fn conn_respond(&self, pdu: &[u8]) {
+        let mut control = [0; 9];
+        control[..pdu.len()].copy_from_slice(pdu);
+        self.link.control.set(Some((control, pdu.len())));
+    }
+
+    /// Arm `TIMER0` for the next connection event, given the anchor point of
+    /// this one in ticks after waking up for it.
+    fn conn_schedule(&self, anchor: u32) {
+        let link = &self.link;
+        let counter = link.event_counter.get().wrapping_add(1);
+        link.event_counter.set(counter);
+        let mut delay = link.interval.get();
+        link.window.set(0);
+        if let Some(update) = link.update.get() {
+            if update.instant == counter {
+                // BLUETOOTH SPECIFICATION Version 5.0 [Vol 6, Part B], section 5.1.1
+                // The transmit window of the new parameters starts the old
+                // interval plus the window offset after the last old anchor.
+                delay += update.win_offset as u32 * TICKS_PER_UNIT;
+                link.window.set(update.win_size as u32 * TICKS_PER_UNIT);
+            }
+        }
+
+        // BLUETOOTH SPECIFICATION Version 5.0 [Vol 6, Part B], section 4.5.2
+        let missed = link.missed.get();
+        if link.closing.get().is_none() {
+            if missed * link.interval.get() >= link.timeout.get() {
+                link.closing.set(Some(CONNECTION_TIMEOUT));
+            } else if !link.established.get() && missed >= 6 {
+                link.closing.set(Some(FAILED_TO_ESTABLISH));
+            }
+        }
+
+        let wake = (anchor + delay).saturating_sub(self.lead() + link.since_wake.get());
+        self.link_set_alarm(Ticks32::from(0), wake);
+    }
+
+    /// The radio stopped after the connection event.
+    fn conn_event_done(&self) {
+        self.radio_off();
+        if let Some(reason) = self.link.closing.take() {
+            self.conn_close(reason);
+        } else if self.link.late.take() {
+            self.conn_event();
+        } else {
+            self.link.state.set(LinkState::ConnSleep);
+        }
+    }
+
+    fn conn_close(&self, reason: u8) {
+        let link = &self.link;
+        self.link_disarm();
+        self.radio_off();
+        link.connected.set(false);
+        link.state.set(LinkState::Off);
+        let handle = link.handle.get();
+        link.tx_buffer.take().map(|buffer| {
+            link.client
+                .map(move |client| client.pdu_sent(handle, buffer, ReturnCode::FAIL))
+        });
+        link.client
+            .map(|client| client.disconnected(handle, reason));
+        if link.advertising.get() && link.state.get() == LinkState::Off {
+            self.advertise(37);
+        }
+    }
+}
+
 impl<'a> ble_advertising::BleAdvertisementDriver<'a> for Radio<'a> {
     fn transmit_advertisement(&self, buf: &'static mut [u8], _len: usize, channel: RadioChannel) {
         let res = self.replace_radio_buffer(buf);
@@ -810,6 +1834,44 @@ impl<'a> ble_advertising::BleAdvertisementDriver<'a> for Radio<'a> {
     fn set_transmit_client(&self, client: &'a dyn ble_advertising::TxClient) {
         self.tx_client.set(client);
     }
+
+    fn transmit_advertisement_delayed(
+        &self,
+        buf: &'static mut [u8],
+        _len: usize,
+        channel: RadioChannel,
+        delay_us: u32,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        if self.delayed.get() {
+            return Err((ReturnCode::EBUSY, buf));
+        }
+        // This is called right after the previous packet ended, so wait out
+        // the rest of the delay less the ramp-up.
+        let wait_us = delay_us.saturating_sub(self.airtime_us.get() + TX_RAMP_UP_US);
+        let ticks = (wait_us * (TIMER_HZ / 1000) + 500) / 1000;
+
+        let res = self.replace_radio_buffer(buf);
+        self.buffer.replace(res);
+        self.ble_initialize(channel);
+        self.delayed.set(true);
+        unsafe {
+            let timer = &nrf5x::timer::TIMER0;
+            timer.set_alarm(timer.now(), Ticks32::from(ticks));
+        }
+        Ok(())
+    }
+}
+
+impl AlarmClient for Radio<'_> {
+    fn alarm(&self) {
+        if self.delayed.get() {
+            self.delayed.set(false);
+            self.tx();
+            self.enable_interrupts();
+        } else {
+            self.link_alarm();
+        }
+    }
 }
 
 impl ble_advertising::BleConfig for Radio<'_> {
@@ -827,4 +1889,98 @@ impl ble_advertising::BleConfig for Radio<'_> {
             }
         }
     }
+
+    // The coded PHY needs an nRF52840, which is the only nRF52 with the modes for it
+    fn set_phy(&self, phy: Phy) -> kernel::ReturnCode {
+        self.phy.set(phy);
+        kernel::ReturnCode::SUCCESS
+    }
+
+    fn set_access_address(&self, access_address: u32, crc_init: u32) -> kernel::ReturnCode {
+        self.access_address.set(access_address);
+        self.crc_init.set(crc_init & 0xff_ffff);
+        kernel::ReturnCode::SUCCESS
+    }
+}
+
+impl<'a> BleConnection<'a> for Radio<'a> {
+    fn set_client(&self, client: &'a dyn ConnectionClient) {
+        self.link.client.set(client);
+    }
+
+    fn start_advertising(&self, adv_data: &[u8], interval_ms: u32) -> ReturnCode {
+        if adv_data.len() > MAX_ADV_DATA_LEN {
+            return ReturnCode::ESIZE;
+        }
+        // BLUETOOTH SPECIFICATION Version 5.0 [Vol 6, Part B], section 4.4.2.2
+        if interval_ms < 20 || interval_ms > 10_240 {
+            return ReturnCode::EINVAL;
+        }
+        let mut data = [0; MAX_ADV_DATA_LEN];
+        data[..adv_data.len()].copy_from_slice(adv_data);
+        self.link.adv_data.set(data);
+        self.link.adv_data_len.set(adv_data.len());
+        self.link.adv_interval.set(interval_ms * (TIMER_HZ / 1000));
+        self.link.advertising.set(true);
+        if self.link.state.get() == LinkState::Off {
+            self.advertise(37);
+        }
+        ReturnCode::SUCCESS
+    }
+
+    fn stop_advertising(&self) -> ReturnCode {
+        if !self.link.advertising.replace(false) {
+            return ReturnCode::EALREADY;
+        }
+        // Otherwise the advertising event ends after the current channel.
+        if self.link.state.get() == LinkState::AdvSleep {
+            self.link_disarm();
+            self.link.state.set(LinkState::Off);
+        }
+        ReturnCode::SUCCESS
+    }
+
+    fn send(
+        &self,
+        handle: ConnectionHandle,
+        buffer: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        if !self.link.connected.get() || handle != self.link.handle.get() {
+            return Err((ReturnCode::EINVAL, buffer));
+        }
+        if len > buffer.len() || len > MAX_L2CAP_PDU_LEN {
+            return Err((ReturnCode::ESIZE, buffer));
+        }
+        if self.link.tx_buffer.is_some() {
+            return Err((ReturnCode::EBUSY, buffer));
+        }
+        self.link.tx_len.set(len);
+        self.link.tx_offset.set(0);
+        self.link.tx_buffer.replace(buffer);
+        Ok(())
+    }
+
+    fn disconnect(&self, handle: ConnectionHandle, reason: u8) -> ReturnCode {
+        let link = &self.link;
+        if !link.connected.get() || handle != link.handle.get() {
+            return ReturnCode::EINVAL;
+        }
+        if link.terminate.get().is_some()
+            || link.sent.get() == Sent::Terminate
+            || link.closing.get().is_some()
+        {
+            return ReturnCode::EALREADY;
+        }
+        link.terminate.set(Some(reason));
+        ReturnCode::SUCCESS
+    }
+
+    fn max_pdu_len(&self) -> usize {
+        MAX_L2CAP_PDU_LEN
+    }
+
+    fn local_address(&self) -> DeviceAddress {
+        self.link_address()
+    }
 }
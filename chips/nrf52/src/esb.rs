@@ -0,0 +1,806 @@
+//! Radio driver, Enhanced ShockBurst, NRF52
+//!
+//! Implements `hil::esb` with dynamic payload lengths, so the radio talks to
+//! nRF24L01+ style devices and to nRF5x chips running Nordic's ESB library.
+//! Only one of the BLE, 802.15.4 and ESB drivers can use the radio at a time.
+//!
+//! The acknowledgement timeout runs on `TIMER0`, so the board must make this
+//! driver its alarm client:
+//!
+//! ```rust
+//! nrf52::timer::TIMER0.set_alarm_client(&nrf52::esb::ESB);
+//! kernel::hil::esb::Esb::set_client(&nrf52::esb::ESB, client);
+//! ```
+//!
+//! ### Packet Configuration
+//! ```txt
+//! +----------+---------+--------+-----+--------+---------+-----+
+//! | Preamble | Address | Length | PID | ACK    | Payload | CRC |
+//! +----------+---------+--------+-----+--------+---------+-----+
+//! ```
+//!
+//! * Preamble - 1 byte
+//! * Address - a 4 byte base followed by a 1 byte prefix
+//! * Length - 6 bits, the payload length
+//! * PID - 2 bits, incremented for each new packet so that receivers can
+//!   drop retransmitted duplicates
+//! * ACK - 1 bit, set when an acknowledgement is wanted, as Nordic's library
+//!   does
+//! * Payload - 0 to 32 bytes
+//! * CRC - 2 bytes
+
+use core::cell::Cell;
+use core::cmp;
+use core::convert::TryFrom;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::registers::{register_bitfields, ReadOnly, ReadWrite, WriteOnly};
+use kernel::common::StaticRef;
+use kernel::hil::esb::{self, Config, DataRate, MAX_PAYLOAD_LEN, PIPES};
+use kernel::hil::time::{Alarm, AlarmClient, Ticks32, Time};
+use kernel::ReturnCode;
+use nrf5x::constants::TxPower;
+
+const RADIO_BASE: StaticRef<RadioRegisters> =
+    unsafe { StaticRef::new(0x40001000 as *const RadioRegisters) };
+
+#[repr(C)]
+struct RadioRegisters {
+    /// Enable Radio in TX mode
+    /// - Address: 0x000 - 0x004
+    task_txen: WriteOnly<u32, Task::Register>,
+    /// Enable Radio in RX mode
+    /// - Address: 0x004 - 0x008
+    task_rxen: WriteOnly<u32, Task::Register>,
+    /// Start Radio
+    /// - Address: 0x008 - 0x00c
+    task_start: WriteOnly<u32, Task::Register>,
+    /// Reserved
+    _reserved1: [u32; 1],
+    /// Disable Radio
+    /// - Address: 0x010 - 0x014
+    task_disable: WriteOnly<u32, Task::Register>,
+    /// Reserved
+    _reserved2: [u32; 59],
+    /// Radio has ramped up and is ready to be started
+    /// - Address: 0x100 - 0x104
+    event_ready: ReadWrite<u32, Event::Register>,
+    /// Address sent or received
+    /// - Address: 0x104 - 0x108
+    event_address: ReadWrite<u32, Event::Register>,
+    /// Packet payload sent or received
+    /// - Address: 0x108 - 0x10c
+    event_payload: ReadWrite<u32, Event::Register>,
+    /// Packet sent or received
+    /// - Address: 0x10c - 0x110
+    event_end: ReadWrite<u32, Event::Register>,
+    /// Radio has been disabled
+    /// - Address: 0x110 - 0x114
+    event_disabled: ReadWrite<u32, Event::Register>,
+    /// Reserved
+    _reserved3: [u32; 59],
+    /// Shortcut register
+    /// - Address: 0x200 - 0x204
+    shorts: ReadWrite<u32, Shortcut::Register>,
+    /// Reserved
+    _reserved4: [u32; 64],
+    /// Enable interrupt
+    /// - Address: 0x304 - 0x308
+    intenset: ReadWrite<u32, Interrupt::Register>,
+    /// Disable interrupt
+    /// - Address: 0x308 - 0x30c
+    intenclr: ReadWrite<u32, Interrupt::Register>,
+    /// Reserved
+    _reserved5: [u32; 61],
+    /// CRC status
+    /// - Address: 0x400 - 0x404
+    crcstatus: ReadOnly<u32, Event::Register>,
+    /// Reserved
+    _reserved6: [u32; 1],
+    /// Received address
+    /// - Address: 0x408 - 0x40c
+    rxmatch: ReadOnly<u32, ReceiveMatch::Register>,
+    /// CRC field of previously received packet
+    /// - Address: 0x40c - 0x410
+    rxcrc: ReadOnly<u32>,
+    /// Reserved
+    _reserved7: [u32; 61],
+    /// Packet pointer
+    /// - Address: 0x504 - 0x508
+    packetptr: ReadWrite<u32>,
+    /// Frequency
+    /// - Address: 0x508 - 0x50c
+    frequency: ReadWrite<u32, Frequency::Register>,
+    /// Output power
+    /// - Address: 0x50c - 0x510
+    txpower: ReadWrite<u32>,
+    /// Data rate and modulation
+    /// - Address: 0x510 - 0x514
+    mode: ReadWrite<u32, Mode::Register>,
+    /// Packet configuration register 0
+    /// - Address 0x514 - 0x518
+    pcnf0: ReadWrite<u32, PacketConfiguration0::Register>,
+    /// Packet configuration register 1
+    /// - Address: 0x518 - 0x51c
+    pcnf1: ReadWrite<u32, PacketConfiguration1::Register>,
+    /// Base address 0
+    /// - Address: 0x51c - 0x520
+    base0: ReadWrite<u32>,
+    /// Base address 1
+    /// - Address: 0x520 - 0x524
+    base1: ReadWrite<u32>,
+    /// Prefix bytes for logical addresses 0-3
+    /// - Address: 0x524 - 0x528
+    prefix0: ReadWrite<u32>,
+    /// Prefix bytes for logical addresses 4-7
+    /// - Address: 0x528 - 0x52c
+    prefix1: ReadWrite<u32>,
+    /// Transmit address select
+    /// - Address: 0x52c - 0x530
+    txaddress: ReadWrite<u32>,
+    /// Receive address select
+    /// - Address: 0x530 - 0x534
+    rxaddresses: ReadWrite<u32>,
+    /// CRC configuration
+    /// - Address: 0x534 - 0x538
+    crccnf: ReadWrite<u32, CrcConfiguration::Register>,
+    /// CRC polynomial
+    /// - Address: 0x538 - 0x53c
+    crcpoly: ReadWrite<u32>,
+    /// CRC initial value
+    /// - Address: 0x53c - 0x540
+    crcinit: ReadWrite<u32>,
+    /// Reserved
+    _reserved8: [u32; 2],
+    /// RSSI sample
+    /// - Address: 0x548 - 0x54c
+    rssisample: ReadOnly<u32>,
+    /// Reserved
+    _reserved9: [u32; 65],
+    /// Radio mode configuration register
+    /// - Address: 0x650 - 0x654
+    modecnf0: ReadWrite<u32, RadioModeConfig::Register>,
+    /// Reserved
+    _reserved10: [u32; 618],
+    /// Peripheral power control
+    /// - Address: 0xFFC - 0x1000
+    power: ReadWrite<u32, Task::Register>,
+}
+
+register_bitfields! [u32,
+    /// Task register
+    Task [
+        /// Enable task
+        ENABLE OFFSET(0) NUMBITS(1)
+    ],
+    /// Event register
+    Event [
+        /// Ready event
+        READY OFFSET(0) NUMBITS(1)
+    ],
+    /// Shortcut register
+    Shortcut [
+        /// Shortcut between READY event and START task
+        READY_START OFFSET(0) NUMBITS(1),
+        /// Shortcut between END event and DISABLE task
+        END_DISABLE OFFSET(1) NUMBITS(1),
+        /// Shortcut between DISABLED event and RXEN task
+        DISABLED_RXEN OFFSET(3) NUMBITS(1),
+        /// Shortcut between ADDRESS event and RSSISTART task
+        ADDRESS_RSSISTART OFFSET(4) NUMBITS(1)
+    ],
+    /// Interrupt register
+    Interrupt [
+        /// DISABLED event
+        DISABLED OFFSET(4) NUMBITS(1)
+    ],
+    /// Receive match register
+    ReceiveMatch [
+        /// Logical address of which previous packet was received
+        MATCH OFFSET(0) NUMBITS(3)
+    ],
+    /// Frequency register
+    Frequency [
+        /// Radio channel frequency
+        /// Frequency = 2400 + FREQUENCY (MHz)
+        FREQUENCY OFFSET(0) NUMBITS(7) []
+    ],
+    /// Data rate and modulation register
+    Mode [
+        MODE OFFSET(0) NUMBITS(4) [
+            NRF_1MBIT = 0,
+            NRF_2MBIT = 1
+        ]
+    ],
+    /// Packet configuration register 0
+    PacketConfiguration0 [
+        /// Length on air of LENGTH field in number of bits
+        LFLEN OFFSET(0) NUMBITS(4) [],
+        /// Length on air of S0 field in number of bytes
+        S0LEN OFFSET(8) NUMBITS(1) [],
+        /// Length on air of S1 field in number of bits.
+        S1LEN OFFSET(16) NUMBITS(4) []
+    ],
+    /// Packet configuration register 1
+    PacketConfiguration1 [
+        /// Maximum length of packet payload
+        MAXLEN OFFSET(0) NUMBITS(8) [],
+        /// Static length in number of bytes
+        STATLEN OFFSET(8) NUMBITS(8) [],
+        /// Base address length in number of bytes
+        BALEN OFFSET(16) NUMBITS(3) [],
+        /// On air endianness
+        ENDIAN OFFSET(24) NUMBITS(1) [
+            LITTLE = 0,
+            BIG = 1
+        ],
+        /// Enable or disable packet whitening
+        WHITEEN OFFSET(25) NUMBITS(1) [
+            DISABLED = 0,
+            ENABLED = 1
+        ]
+    ],
+    /// CRC configuration register
+    CrcConfiguration [
+        /// CRC length in bytes
+        LEN OFFSET(0) NUMBITS(2) [
+            DISABLED = 0,
+            ONE = 1,
+            TWO = 2,
+            THREE = 3
+        ],
+        /// Include or exclude packet field from CRC calculation
+        SKIPADDR OFFSET(8) NUMBITS(1) [
+            INCLUDE = 0,
+            EXCLUDE = 1
+        ]
+    ],
+    /// Radio mode configuration register
+    RadioModeConfig [
+        /// Radio ramp-up time
+        RU OFFSET(0) NUMBITS(1) [
+            DEFAULT = 0,
+            FAST = 1
+        ]
+    ]
+];
+
+/// Length byte, S1 byte and payload.
+const PACKET_LEN: usize = 2 + MAX_PAYLOAD_LEN;
+
+/// How many payloads can wait to be sent, as on nRF24 radios.
+const QUEUE_LEN: usize = 3;
+
+const CRC_INIT: u32 = 0xffff;
+const CRC_POLY: u32 = 0x11021;
+
+/// `TIMER0` ticks at 16 kHz.
+const TIMER_HZ: u32 = 16000;
+
+static mut TX_PACKET: [u8; PACKET_LEN] = [0; PACKET_LEN];
+static mut RX_PACKET: [u8; PACKET_LEN] = [0; PACKET_LEN];
+
+#[derive(Copy, Clone, PartialEq)]
+enum State {
+    Off,
+    /// PTX with nothing to send.
+    Idle,
+    /// PTX sending the head of the queue.
+    Transmit,
+    /// PTX waiting for the acknowledgement of the head of the queue.
+    WaitAck,
+    /// PTX gave up waiting and is disabling the receiver.
+    AckTimeout,
+    /// PRX listening.
+    Listen,
+    /// PRX sending an acknowledgement, with the payload in the given queue
+    /// slot if any.
+    SendAck(Option<usize>),
+}
+
+#[derive(Copy, Clone)]
+struct Queued {
+    pipe: u8,
+    len: u8,
+    ack: bool,
+}
+
+pub struct Esb<'a> {
+    registers: StaticRef<RadioRegisters>,
+    client: OptionalCell<&'a dyn esb::Client>,
+    config: Cell<Config>,
+    tx_power: Cell<TxPower>,
+    base: Cell<[[u8; 4]; 2]>,
+    prefixes: Cell<[u8; PIPES]>,
+    enabled_pipes: Cell<u8>,
+    state: Cell<State>,
+    stop_requested: Cell<bool>,
+    queue: [TakeCell<'static, [u8]>; QUEUE_LEN],
+    queued: Cell<[Queued; QUEUE_LEN]>,
+    queue_len: Cell<usize>,
+    pid: Cell<u8>,
+    attempts: Cell<u8>,
+    /// The PID and CRC of the last packet received on each pipe.
+    last_pid: Cell<[u8; PIPES]>,
+    last_crc: Cell<[u32; PIPES]>,
+}
+
+pub static mut ESB: Esb = Esb::new();
+
+impl<'a> Esb<'a> {
+    pub const fn new() -> Esb<'a> {
+        Esb {
+            registers: RADIO_BASE,
+            client: OptionalCell::empty(),
+            config: Cell::new(Config {
+                mode: esb::Mode::Ptx,
+                channel: 2,
+                data_rate: DataRate::Mbps1,
+                tx_power_dbm: 0,
+                retransmits: 3,
+                retransmit_delay_us: 250,
+            }),
+            tx_power: Cell::new(TxPower::ZerodBm),
+            base: Cell::new([[0xe7; 4], [0xc2; 4]]),
+            prefixes: Cell::new([0xe7, 0xc2, 0xc3, 0xc4, 0xc5, 0xc6, 0xc7, 0xc8]),
+            enabled_pipes: Cell::new(0x01),
+            state: Cell::new(State::Off),
+            stop_requested: Cell::new(false),
+            queue: [TakeCell::empty(), TakeCell::empty(), TakeCell::empty()],
+            queued: Cell::new(
+                [Queued {
+                    pipe: 0,
+                    len: 0,
+                    ack: false,
+                }; QUEUE_LEN],
+            ),
+            queue_len: Cell::new(0),
+            pid: Cell::new(0),
+            attempts: Cell::new(0),
+            last_pid: Cell::new([0xff; PIPES]),
+            last_crc: Cell::new([0; PIPES]),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.state.get() != State::Off
+    }
+
+    fn radio_on(&self) {
+        // reset and enable power
+        self.registers.power.write(Task::ENABLE::CLEAR);
+        self.registers.power.write(Task::ENABLE::SET);
+    }
+
+    fn radio_off(&self) {
+        self.registers.intenclr.set(0xffffffff);
+        self.registers.power.write(Task::ENABLE::CLEAR);
+        self.state.set(State::Off);
+        self.stop_requested.set(false);
+    }
+
+    fn radio_initialize(&self) {
+        self.radio_on();
+        let config = self.config.get();
+
+        self.registers.mode.write(match config.data_rate {
+            DataRate::Mbps2 => Mode::MODE::NRF_2MBIT,
+            _ => Mode::MODE::NRF_1MBIT,
+        });
+        self.registers
+            .frequency
+            .write(Frequency::FREQUENCY.val(config.channel as u32));
+        self.registers.txpower.set(self.tx_power.get() as u32);
+        self.registers.modecnf0.write(RadioModeConfig::RU::DEFAULT);
+
+        self.registers.pcnf0.write(
+            PacketConfiguration0::LFLEN.val(6)
+                + PacketConfiguration0::S0LEN.val(0)
+                + PacketConfiguration0::S1LEN.val(3),
+        );
+        self.registers.pcnf1.write(
+            PacketConfiguration1::WHITEEN::DISABLED
+                + PacketConfiguration1::ENDIAN::BIG
+                + PacketConfiguration1::BALEN.val(4)
+                + PacketConfiguration1::STATLEN.val(0)
+                + PacketConfiguration1::MAXLEN.val(MAX_PAYLOAD_LEN as u32),
+        );
+
+        self.registers
+            .crccnf
+            .write(CrcConfiguration::LEN::TWO + CrcConfiguration::SKIPADDR::INCLUDE);
+        self.registers.crcinit.set(CRC_INIT);
+        self.registers.crcpoly.set(CRC_POLY);
+
+        // nRF24 radios send each address byte most significant bit first.
+        let base = self.base.get();
+        self.registers.base0.set(address_word(base[0]));
+        self.registers.base1.set(address_word(base[1]));
+        let p = self.prefixes.get();
+        self.registers
+            .prefix0
+            .set(address_word([p[3], p[2], p[1], p[0]]));
+        self.registers
+            .prefix1
+            .set(address_word([p[7], p[6], p[5], p[4]]));
+
+        self.registers.intenset.write(Interrupt::DISABLED::SET);
+    }
+
+    fn set_ack_timer(&self) {
+        let delay_us = self.config.get().retransmit_delay_us as u32;
+        let ticks = cmp::max(2, (delay_us * TIMER_HZ + 999_999) / 1_000_000);
+        unsafe {
+            let timer = &nrf5x::timer::TIMER0;
+            timer.set_alarm(timer.now(), Ticks32::from(ticks));
+        }
+    }
+
+    fn cancel_ack_timer(&self) {
+        unsafe {
+            nrf5x::timer::TIMER0.disarm();
+        }
+    }
+
+    fn received_rssi(&self) -> i16 {
+        -(self.registers.rssisample.get() as i16)
+    }
+
+    /// Take the payload in `slot` out of the queue.
+    fn dequeue(&self, slot: usize) -> Option<&'static mut [u8]> {
+        let len = self.queue_len.get();
+        let buffer = self.queue[slot].take();
+        let mut queued = self.queued.get();
+        for i in slot..len - 1 {
+            if let Some(next) = self.queue[i + 1].take() {
+                self.queue[i].replace(next);
+            }
+            queued[i] = queued[i + 1];
+        }
+        self.queued.set(queued);
+        self.queue_len.set(len - 1);
+        buffer
+    }
+
+    /// Copy the payload in `slot` into the transmit packet, or send an empty
+    /// one if there is no slot.
+    fn load_packet(&self, slot: Option<usize>, s1: u8) {
+        let queued = self.queued.get();
+        let len = slot.map_or(0, |slot| queued[slot].len as usize);
+        unsafe {
+            TX_PACKET[0] = len as u8;
+            TX_PACKET[1] = s1;
+            if let Some(slot) = slot {
+                self.queue[slot].map(|buffer| {
+                    TX_PACKET[2..2 + len].copy_from_slice(&buffer[..len]);
+                });
+            }
+            self.registers.packetptr.set(TX_PACKET.as_ptr() as u32);
+        }
+    }
+
+    fn send_head(&self) {
+        let head = self.queued.get()[0];
+        self.attempts.set(0);
+        self.load_packet(Some(0), self.pid.get() << 1 | head.ack as u8);
+        self.send_packet();
+    }
+
+    fn send_packet(&self) {
+        let head = self.queued.get()[0];
+        self.registers.txaddress.set(head.pipe as u32);
+        self.registers.shorts.write(if head.ack {
+            Shortcut::READY_START::SET + Shortcut::END_DISABLE::SET + Shortcut::DISABLED_RXEN::SET
+        } else {
+            Shortcut::READY_START::SET + Shortcut::END_DISABLE::SET
+        });
+        self.state.set(State::Transmit);
+        self.registers.task_txen.write(Task::ENABLE::SET);
+    }
+
+    fn listen(&self) {
+        unsafe {
+            self.registers.packetptr.set(RX_PACKET.as_ptr() as u32);
+        }
+        self.registers
+            .rxaddresses
+            .set(self.enabled_pipes.get() as u32);
+        self.registers.shorts.write(
+            Shortcut::READY_START::SET
+                + Shortcut::END_DISABLE::SET
+                + Shortcut::ADDRESS_RSSISTART::SET,
+        );
+        self.state.set(State::Listen);
+        self.registers.task_rxen.write(Task::ENABLE::SET);
+    }
+
+    /// The head of the queue was sent, acknowledged, or given up on.
+    fn complete(&self, result: ReturnCode) {
+        self.pid.set((self.pid.get() + 1) & 0x3);
+        let buffer = self.dequeue(0);
+        if self.stop_requested.get() {
+            self.radio_off();
+        } else {
+            self.state.set(State::Idle);
+        }
+        buffer.map(|buffer| {
+            self.client
+                .map(move |client| client.transmitted(buffer, result))
+        });
+        if self.state.get() == State::Idle && self.queue_len.get() > 0 {
+            self.send_head();
+        }
+    }
+
+    fn transmit_done(&self) {
+        if !self.queued.get()[0].ack {
+            self.complete(ReturnCode::SUCCESS);
+            return;
+        }
+        // The radio is already ramping up to receive the acknowledgement.
+        unsafe {
+            self.registers.packetptr.set(RX_PACKET.as_ptr() as u32);
+        }
+        self.registers
+            .rxaddresses
+            .set(1 << self.queued.get()[0].pipe);
+        self.registers.shorts.write(
+            Shortcut::READY_START::SET
+                + Shortcut::END_DISABLE::SET
+                + Shortcut::ADDRESS_RSSISTART::SET,
+        );
+        self.state.set(State::WaitAck);
+        self.set_ack_timer();
+    }
+
+    fn ack_received(&self) {
+        if !self.registers.crcstatus.is_set(Event::READY) {
+            self.cancel_ack_timer();
+            self.retransmit();
+            return;
+        }
+        self.cancel_ack_timer();
+        let pipe = self.queued.get()[0].pipe as usize;
+        let len = unsafe { cmp::min(RX_PACKET[0] as usize, MAX_PAYLOAD_LEN) };
+        if len > 0 {
+            let rssi = self.received_rssi();
+            self.client.map(|client| unsafe {
+                client.received(pipe, &RX_PACKET[2..2 + len], rssi);
+            });
+        }
+        self.complete(ReturnCode::SUCCESS);
+    }
+
+    fn retransmit(&self) {
+        if self.attempts.get() >= self.config.get().retransmits {
+            self.complete(ReturnCode::ENOACK);
+        } else {
+            self.attempts.set(self.attempts.get() + 1);
+            self.send_packet();
+        }
+    }
+
+    fn packet_received(&self) {
+        if !self.registers.crcstatus.is_set(Event::READY) {
+            self.listen();
+            return;
+        }
+        let pipe = self.registers.rxmatch.read(ReceiveMatch::MATCH) as usize;
+        let (len, s1) = unsafe {
+            (
+                cmp::min(RX_PACKET[0] as usize, MAX_PAYLOAD_LEN),
+                RX_PACKET[1],
+            )
+        };
+        let pid = (s1 >> 1) & 0x3;
+        let crc = self.registers.rxcrc.get();
+        let rssi = self.received_rssi();
+
+        let mut last_pid = self.last_pid.get();
+        let mut last_crc = self.last_crc.get();
+        let duplicate = last_pid[pipe] == pid && last_crc[pipe] == crc;
+        last_pid[pipe] = pid;
+        last_crc[pipe] = crc;
+        self.last_pid.set(last_pid);
+        self.last_crc.set(last_crc);
+
+        if s1 & 0x1 != 0 {
+            // Acknowledge right away; the PTX only listens briefly. A
+            // payload already sent in an earlier acknowledgement is not sent
+            // again for a duplicate.
+            let queued = self.queued.get();
+            let slot = if duplicate {
+                None
+            } else {
+                (0..self.queue_len.get()).find(|&slot| queued[slot].pipe as usize == pipe)
+            };
+            self.load_packet(slot, pid << 1);
+            self.registers.txaddress.set(pipe as u32);
+            self.registers
+                .shorts
+                .write(Shortcut::READY_START::SET + Shortcut::END_DISABLE::SET);
+            self.state.set(State::SendAck(slot));
+            self.registers.task_txen.write(Task::ENABLE::SET);
+        }
+
+        if !duplicate {
+            self.client.map(|client| unsafe {
+                client.received(pipe, &RX_PACKET[2..2 + len], rssi);
+            });
+        }
+        if self.state.get() == State::Listen {
+            if self.stop_requested.get() {
+                self.radio_off();
+            } else {
+                self.listen();
+            }
+        }
+    }
+
+    fn ack_sent(&self, slot: Option<usize>) {
+        if self.stop_requested.get() {
+            self.radio_off();
+        } else {
+            self.listen();
+        }
+        slot.and_then(|slot| self.dequeue(slot)).map(|buffer| {
+            self.client
+                .map(move |client| client.transmitted(buffer, ReturnCode::SUCCESS))
+        });
+    }
+
+    #[inline(never)]
+    pub fn handle_interrupt(&self) {
+        if !self.registers.event_disabled.is_set(Event::READY) {
+            return;
+        }
+        self.registers.event_disabled.write(Event::READY::CLEAR);
+        self.registers.event_ready.write(Event::READY::CLEAR);
+        self.registers.event_address.write(Event::READY::CLEAR);
+        self.registers.event_payload.write(Event::READY::CLEAR);
+        self.registers.event_end.write(Event::READY::CLEAR);
+
+        match self.state.get() {
+            State::Transmit => self.transmit_done(),
+            State::WaitAck => self.ack_received(),
+            State::AckTimeout => self.retransmit(),
+            State::Listen => self.packet_received(),
+            State::SendAck(slot) => self.ack_sent(slot),
+            State::Off | State::Idle => {}
+        }
+    }
+}
+
+/// The register value for four address bytes. The radio sends each byte
+/// in the opposite bit order to nRF24 radios, so the bytes are bit reversed,
+/// as Nordic's library does.
+fn address_word(bytes: [u8; 4]) -> u32 {
+    u32::from_be_bytes([
+        bytes[0].reverse_bits(),
+        bytes[1].reverse_bits(),
+        bytes[2].reverse_bits(),
+        bytes[3].reverse_bits(),
+    ])
+}
+
+impl<'a> esb::Esb<'a> for Esb<'a> {
+    fn set_client(&self, client: &'a dyn esb::Client) {
+        self.client.set(client);
+    }
+
+    fn configure(&self, config: Config) -> ReturnCode {
+        if self.state.get() != State::Off {
+            return ReturnCode::EBUSY;
+        }
+        // The nRF52840 has no 250 kbit/s mode.
+        if config.channel > 100 || config.retransmits > 15 || config.data_rate == DataRate::Kbps250
+        {
+            return ReturnCode::EINVAL;
+        }
+        match TxPower::try_from(config.tx_power_dbm as u8) {
+            Ok(tx_power) => {
+                self.tx_power.set(tx_power);
+                self.config.set(config);
+                ReturnCode::SUCCESS
+            }
+            Err(_) => ReturnCode::EINVAL,
+        }
+    }
+
+    fn set_address(&self, pipe: usize, base: [u8; 4], prefix: u8) -> ReturnCode {
+        if pipe >= PIPES {
+            return ReturnCode::EINVAL;
+        }
+        if self.state.get() != State::Off {
+            return ReturnCode::EBUSY;
+        }
+        let mut bases = self.base.get();
+        bases[cmp::min(pipe, 1)] = base;
+        self.base.set(bases);
+        let mut prefixes = self.prefixes.get();
+        prefixes[pipe] = prefix;
+        self.prefixes.set(prefixes);
+        ReturnCode::SUCCESS
+    }
+
+    fn enable_pipes(&self, pipes: u8) -> ReturnCode {
+        self.enabled_pipes.set(pipes);
+        if self.state.get() == State::Listen {
+            // Takes effect with the next packet.
+            self.registers.rxaddresses.set(pipes as u32);
+        }
+        ReturnCode::SUCCESS
+    }
+
+    fn write(
+        &self,
+        pipe: usize,
+        buffer: &'static mut [u8],
+        len: usize,
+        ack: bool,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        if pipe >= PIPES || len > buffer.len() {
+            return Err((ReturnCode::EINVAL, buffer));
+        }
+        if len > MAX_PAYLOAD_LEN {
+            return Err((ReturnCode::ESIZE, buffer));
+        }
+        let slot = self.queue_len.get();
+        if slot == QUEUE_LEN {
+            return Err((ReturnCode::EBUSY, buffer));
+        }
+        self.queue[slot].replace(buffer);
+        let mut queued = self.queued.get();
+        queued[slot] = Queued {
+            pipe: pipe as u8,
+            len: len as u8,
+            ack: ack,
+        };
+        self.queued.set(queued);
+        self.queue_len.set(slot + 1);
+
+        if self.state.get() == State::Idle {
+            self.send_head();
+        }
+        Ok(())
+    }
+
+    fn start(&self) -> ReturnCode {
+        if self.state.get() != State::Off {
+            return ReturnCode::EALREADY;
+        }
+        self.radio_initialize();
+        match self.config.get().mode {
+            esb::Mode::Ptx => {
+                self.state.set(State::Idle);
+                if self.queue_len.get() > 0 {
+                    self.send_head();
+                }
+            }
+            esb::Mode::Prx => self.listen(),
+        }
+        ReturnCode::SUCCESS
+    }
+
+    fn stop(&self) -> ReturnCode {
+        match self.state.get() {
+            State::Off => ReturnCode::EALREADY,
+            State::Idle | State::Listen => {
+                self.radio_off();
+                ReturnCode::SUCCESS
+            }
+            _ => {
+                self.stop_requested.set(true);
+                ReturnCode::SUCCESS
+            }
+        }
+    }
+}
+
+impl AlarmClient for Esb<'_> {
+    fn alarm(&self) {
+        if self.state.get() == State::WaitAck {
+            self.state.set(State::AckTimeout);
+            self.registers.task_disable.write(Task::ENABLE::SET);
+        }
+    }
+}
@@ -1,6 +1,7 @@
 //! IEEE 802.15.4 radio driver for nRF52
 
 use core::cell::Cell;
+use core::cmp;
 use core::convert::TryFrom;
 use kernel;
 use kernel::common::cells::{OptionalCell, TakeCell};
@@ -29,6 +30,8 @@ pub const IEEE802154_ACK_TIME: usize = 512; //microseconds = 32 symbols
 pub const IEEE802154_MAX_POLLING_ATTEMPTS: u8 = 4;
 pub const IEEE802154_MIN_BE: u8 = 3;
 pub const IEEE802154_MAX_BE: u8 = 5;
+/// Energy detect levels are in dB above this, in dBm.
+const ED_RSSIOFFS: i16 = -94;
 pub const RAM_LEN_BITS: usize = 8;
 pub const RAM_S1_BITS: usize = 0;
 pub const PREBUF_LEN_BYTES: usize = 2;
@@ -143,8 +146,12 @@ struct RadioRegisters {
     /// Stop the bit counter
     /// - Address: 0x020 - 0x024
     task_bcstop: WriteOnly<u32, Task::Register>,
-    /// Reserved
-    _reserved1: [u32; 2],
+    /// Start the energy detect measurement used in IEEE 802.15.4 mode
+    /// - Address: 0x024 - 0x028
+    task_edstart: WriteOnly<u32, Task::Register>,
+    /// Stop the energy detect measurement
+    /// - Address: 0x028 - 0x02c
+    task_edstop: WriteOnly<u32, Task::Register>,
     /// Stop the bit counter
     /// - Address: 0x02c - 0x030
     task_ccastart: WriteOnly<u32, Task::Register>,
@@ -193,8 +200,12 @@ struct RadioRegisters {
     /// IEEE 802.15.4 length field received
     /// - Address: 0x138 - 0x13c
     event_framestart: ReadWrite<u32, Event::Register>,
-    /// Reserved
-    _reserved5: [u32; 2],
+    /// Sampling of energy detection complete
+    /// - Address: 0x13c - 0x140
+    event_edend: ReadWrite<u32, Event::Register>,
+    /// The sampling of energy detection has stopped
+    /// - Address: 0x140 - 0x144
+    event_edstopped: ReadWrite<u32, Event::Register>,
     /// Wireless medium in idle - clear to send
     /// - Address: 0x144-0x148
     event_ccaidle: ReadWrite<u32, Event::Register>,
@@ -321,7 +332,13 @@ struct RadioRegisters {
     /// - Address: 0x650 - 0x654
     modecnf0: ReadWrite<u32, RadioModeConfig::Register>,
     /// Reserved
-    _reserved16: [u32; 6],
+    _reserved16: [u32; 4],
+    /// IEEE 802.15.4 energy detect loop count
+    /// - Address: 0x664 - 0x668
+    edcnt: ReadWrite<u32, EdCount::Register>,
+    /// IEEE 802.15.4 energy detect level
+    /// - Address: 0x668 - 0x66c
+    edsample: ReadOnly<u32, EdSample::Register>,
     /// Clear Channel Assesment (CCA) control register
     /// - Address: 0x66C - 0x670
     ccactrl: ReadWrite<u32, CCAControl::Register>,
@@ -388,6 +405,10 @@ register_bitfields! [u32,
         CRCERROR OFFSET(13) NUMBITS(1),
         /// CCAIDLE event
         FRAMESTART OFFSET(14) NUMBITS(1),
+        /// EDEND event
+        EDEND OFFSET(15) NUMBITS(1),
+        /// EDSTOPPED event
+        EDSTOPPED OFFSET(16) NUMBITS(1),
         /// CCAIDLE event
         CCAIDLE OFFSET(17) NUMBITS(1),
         /// CCABUSY event
@@ -627,6 +648,14 @@ register_bitfields! [u32,
     MACHeaderMask [
         PATTERN OFFSET(0) NUMBITS(32)
     ],
+    EdCount [
+        /// Number of 128 us energy detect iterations, less one
+        EDCNT OFFSET(0) NUMBITS(21)
+    ],
+    EdSample [
+        /// Peak energy detect level of the measurement
+        EDLVL OFFSET(0) NUMBITS(8)
+    ],
     CCAControl [
         CCAMODE OFFSET(0) NUMBITS(3) [
             ED_MODE = 0,
@@ -673,6 +702,18 @@ pub struct Radio {
     random_nonce: Cell<u32>,
     channel: Cell<RadioChannel>,
     transmitting: Cell<bool>,
+    checking: Cell<bool>,
+    check_client: OptionalCell<&'static dyn radio::ChannelCheckClient>,
+    /// Channels still to scan, one bit per channel number, and the one being
+    /// scanned.
+    scan_channels: Cell<u32>,
+    scan_channel: OptionalCell<RadioChannel>,
+    scan_iterations: Cell<u32>,
+    scan_client: OptionalCell<&'static dyn radio::EnergyScanClient>,
+    fem: OptionalCell<&'static dyn radio::FrontEndModule>,
+    antenna_mode: Cell<radio::Antenna>,
+    antenna: Cell<u8>,
+    timestamp_source: OptionalCell<&'static dyn radio::TimestampSource>,
 }
 
 pub static mut RADIO: Radio = Radio::new();
@@ -694,9 +735,24 @@ impl Radio {
             random_nonce: Cell::new(0xDEADBEEF),
             channel: Cell::new(RadioChannel::DataChannel26),
             transmitting: Cell::new(false),
+            checking: Cell::new(false),
+            check_client: OptionalCell::empty(),
+            scan_channels: Cell::new(0),
+            scan_channel: OptionalCell::empty(),
+            scan_iterations: Cell::new(0),
+            scan_client: OptionalCell::empty(),
+            fem: OptionalCell::empty(),
+            antenna_mode: Cell::new(radio::Antenna::Fixed(0)),
+            antenna: Cell::new(0),
+            timestamp_source: OptionalCell::empty(),
         }
     }
 
+    /// Timestamp received frames with `source`.
+    pub fn set_timestamp_source(&self, source: &'static dyn radio::TimestampSource) {
+        self.timestamp_source.set(source);
+    }
+
     pub fn is_enabled(&self) -> bool {
         self.registers
             .mode
@@ -721,6 +777,7 @@ impl Radio {
             self.rx_buf.replace(self.set_dma_ptr(rbuf));
         }
 
+        self.fem.map(|fem| fem.enable_lna());
         self.registers.task_rxen.write(Task::ENABLE::SET);
 
         self.enable_interrupts();
@@ -745,6 +802,7 @@ impl Radio {
     }
 
     fn radio_off(&self) {
+        self.fem.map(|fem| fem.disable());
         self.registers.power.write(Task::ENABLE::CLEAR);
     }
 
@@ -767,20 +825,28 @@ impl Radio {
         if self.registers.event_ready.is_set(Event::READY) {
             self.registers.event_ready.write(Event::READY::CLEAR);
             self.registers.event_end.write(Event::READY::CLEAR);
-            if self.transmitting.get()
+            if (self.transmitting.get() || self.checking.get())
                 && self.registers.state.get() == nrf5x::constants::RADIO_STATE_RXIDLE
             {
-                if self.cca_count.get() > 0 {
+                if self.transmitting.get() && self.cca_count.get() > 0 {
                     unsafe {
                         ppi::PPI.disable(ppi::Channel::CH21::SET);
                     }
                 }
                 self.registers.task_ccastart.write(Task::ENABLE::SET);
+            } else if self.scan_channel.is_some() {
+                self.registers.task_edstart.write(Task::ENABLE::SET);
             } else {
                 self.registers.task_start.write(Task::ENABLE::SET);
             }
         }
 
+        if self.registers.event_edend.is_set(Event::READY) {
+            self.registers.event_edend.write(Event::READY::CLEAR);
+            let level = self.registers.edsample.read(EdSample::EDLVL) as i16;
+            self.energy_measured(level);
+        }
+
         if self.registers.event_framestart.is_set(Event::READY) {
             self.registers.event_framestart.write(Event::READY::CLEAR);
         }
@@ -789,11 +855,24 @@ impl Radio {
         // THEN start the transmit part of the radio
         if self.registers.event_ccaidle.is_set(Event::READY) {
             self.registers.event_ccaidle.write(Event::READY::CLEAR);
-            self.registers.task_txen.write(Task::ENABLE::SET)
+            if self.transmitting.get() {
+                self.fem.map(|fem| fem.enable_pa());
+                self.registers.task_txen.write(Task::ENABLE::SET)
+            } else {
+                self.registers.task_start.write(Task::ENABLE::SET);
+            }
+            self.channel_checked(true);
+        }
+
+        if self.registers.event_ccabusy.is_set(Event::READY) && !self.transmitting.get() {
+            self.registers.event_ccabusy.write(Event::READY::CLEAR);
+            self.registers.task_start.write(Task::ENABLE::SET);
+            self.channel_checked(false);
         }
 
         if self.registers.event_ccabusy.is_set(Event::READY) {
             self.registers.event_ccabusy.write(Event::READY::CLEAR);
+            self.channel_checked(false);
             //need to back off for a period of time outlined
             //in the IEEE 802.15.4 standard (see Figure 69 in
             //section 7.5.1.4 The CSMA-CA algorithm of the
@@ -832,11 +911,7 @@ impl Radio {
         if self.registers.event_end.is_set(Event::READY) {
             self.registers.event_end.write(Event::READY::CLEAR);
 
-            let result = if self.registers.crcstatus.is_set(Event::READY) {
-                ReturnCode::SUCCESS
-            } else {
-                ReturnCode::FAIL
-            };
+            let crc_valid = self.registers.crcstatus.is_set(Event::READY);
 
             match self.registers.state.get() {
                 nrf5x::constants::RADIO_STATE_TXRU
@@ -858,6 +933,10 @@ impl Radio {
                 | nrf5x::constants::RADIO_STATE_RXIDLE
                 | nrf5x::constants::RADIO_STATE_RXDISABLE
                 | nrf5x::constants::RADIO_STATE_RX => {
+                    if !crc_valid {
+                        self.try_other_antenna();
+                    }
+                    let timestamp = self.timestamp_source.map(|source| source.timestamp());
                     self.rx_client.map(|client| {
                         let rbuf = self.rx_buf.take().expect(
                             "RX Buffer produced error when sending received packet to requestor",
@@ -868,7 +947,9 @@ impl Radio {
                         // And because the length field is directly read from the packet
                         // We need to add 2 to length to get the total length
 
-                        client.receive(rbuf, frame_len, self.registers.crcstatus.get() == 1, result)
+                        // Frames with a bad CRC are still passed up, flagged by
+                        // `crc_valid`, for clients that sniff the channel.
+                        client.receive(rbuf, frame_len, crc_valid, timestamp, ReturnCode::SUCCESS)
                     });
                 }
                 // Radio state - Disabled
@@ -881,13 +962,70 @@ impl Radio {
         self.enable_interrupts();
     }
 
+    // Report a channel check, if the CCA that just finished was one. The
+    // radio keeps receiving.
+    fn channel_checked(&self, clear: bool) {
+        if self.checking.get() {
+            self.checking.set(false);
+            self.check_client
+                .map(|client| client.channel_checked(clear));
+        }
+    }
+
+    // Report the energy on the channel just scanned and move on to the next
+    // one, or back to the configured channel once all are done.
+    fn energy_measured(&self, level: i16) {
+        let channel = match self.scan_channel.take() {
+            Some(channel) => channel.get_channel_index(),
+            None => return,
+        };
+        let dbm = cmp::max(ED_RSSIOFFS + level, i8::min_value() as i16) as i8;
+        self.scan_client
+            .map(|client| client.energy_measured(channel, dbm));
+
+        let done = !self.next_scan_channel();
+        self.radio_off();
+        self.radio_initialize();
+        if done {
+            self.scan_client
+                .map(|client| client.scan_done(ReturnCode::SUCCESS));
+        }
+    }
+
+    // Take the lowest channel left to scan, returning false once none are.
+    fn next_scan_channel(&self) -> bool {
+        let channels = self.scan_channels.get();
+        for index in 11..=26 {
+            if channels & (1 << index) != 0 {
+                self.scan_channels.set(channels & !(1 << index));
+                if let Ok(channel) = RadioChannel::try_from(index as u8) {
+                    self.scan_channel.set(channel);
+                    return true;
+                }
+            }
+        }
+        self.scan_channels.set(0);
+        false
+    }
+
+    // With antenna diversity, a frame that arrived corrupted may do better
+    // on the other antenna.
+    fn try_other_antenna(&self) {
+        if self.antenna_mode.get() == radio::Antenna::Diversity {
+            self.antenna.set(self.antenna.get() ^ 1);
+            let antenna = self.antenna.get();
+            self.fem.map(|fem| fem.select_antenna(antenna));
+        }
+    }
+
     pub fn enable_interrupts(&self) {
         self.registers.intenset.write(
             Interrupt::READY::SET
                 + Interrupt::CCAIDLE::SET
                 + Interrupt::CCABUSY::SET
                 + Interrupt::END::SET
-                + Interrupt::FRAMESTART::SET,
+                + Interrupt::FRAMESTART::SET
+                + Interrupt::EDEND::SET,
         );
     }
 
@@ -923,9 +1061,13 @@ impl Radio {
 
         self.ieee802154_set_cca_config();
 
+        self.registers
+            .edcnt
+            .write(EdCount::EDCNT.val(self.scan_iterations.get()));
+
         self.ieee802154_set_tx_power();
 
-        self.ieee802154_set_channel_freq(self.channel.get());
+        self.ieee802154_set_channel_freq(self.scan_channel.unwrap_or(self.channel.get()));
 
         self.set_tx_address();
         self.set_rx_address();
@@ -1036,6 +1178,8 @@ impl kernel::hil::radio::RadioConfig for Radio {
         ReturnCode::SUCCESS
     }
     fn stop(&self) -> ReturnCode {
+        // A channel check in progress is abandoned with the radio.
+        self.checking.set(false);
         self.radio_off();
         ReturnCode::SUCCESS
     }
@@ -1148,7 +1292,7 @@ impl kernel::hil::radio::RadioData for Radio {
         buf: &'static mut [u8],
         frame_len: usize,
     ) -> (ReturnCode, Option<&'static mut [u8]>) {
-        if self.tx_buf.is_some() || self.transmitting.get() {
+        if self.tx_buf.is_some() || self.transmitting.get() || self.scan_channel.is_some() {
             return (ReturnCode::EBUSY, Some(buf));
         } else if radio::PSDU_OFFSET + frame_len >= buf.len() {
             // Not enough room for CRC
@@ -1168,3 +1312,76 @@ impl kernel::hil::radio::RadioData for Radio {
         (ReturnCode::SUCCESS, None)
     }
 }
+
+impl kernel::hil::radio::RadioChannelCheck for Radio {
+    fn set_channel_check_client(&self, client: &'static dyn radio::ChannelCheckClient) {
+        self.check_client.set(client);
+    }
+
+    fn channel_check(&self) -> ReturnCode {
+        if self.transmitting.get() || self.scan_channel.is_some() {
+            return ReturnCode::EBUSY;
+        } else if self.checking.get() {
+            return ReturnCode::EALREADY;
+        }
+        self.checking.set(true);
+
+        // Once the receiver is ready, the interrupt handler starts the CCA.
+        self.radio_off();
+        self.radio_initialize();
+        ReturnCode::SUCCESS
+    }
+}
+
+impl kernel::hil::radio::RadioEnergyScan for Radio {
+    fn set_energy_scan_client(&self, client: &'static dyn radio::EnergyScanClient) {
+        self.scan_client.set(client);
+    }
+
+    fn energy_scan(&self, channels: u32, duration_ms: u32) -> ReturnCode {
+        if self.transmitting.get() || self.checking.get() {
+            return ReturnCode::EBUSY;
+        } else if self.scan_channel.is_some() {
+            return ReturnCode::EALREADY;
+        }
+        self.scan_channels.set(channels);
+        if !self.next_scan_channel() {
+            return ReturnCode::EINVAL;
+        }
+
+        // Each iteration measures for 128 us; the peak is reported.
+        let iterations = cmp::max(duration_ms.saturating_mul(1000) / 128, 1);
+        self.scan_iterations
+            .set(cmp::min(iterations - 1, 0x1f_ffff));
+        self.radio_off();
+        self.radio_initialize();
+        ReturnCode::SUCCESS
+    }
+}
+
+impl kernel::hil::radio::RadioFrontEnd for Radio {
+    fn set_front_end(&self, fem: &'static dyn radio::FrontEndModule) {
+        self.fem.set(fem);
+        fem.select_antenna(self.antenna.get());
+    }
+
+    fn set_antenna(&self, antenna: radio::Antenna) -> ReturnCode {
+        let antennas = match self.fem.map(|fem| fem.antennas()) {
+            Some(antennas) => antennas,
+            None => return ReturnCode::ENOSUPPORT,
+        };
+        let selected = match antenna {
+            radio::Antenna::Fixed(n) if n < antennas => n,
+            radio::Antenna::Diversity if antennas >= 2 => 0,
+            _ => return ReturnCode::EINVAL,
+        };
+        self.antenna_mode.set(antenna);
+        self.antenna.set(selected);
+        self.fem.map(|fem| fem.select_antenna(selected));
+        ReturnCode::SUCCESS
+    }
+
+    fn get_antenna(&self) -> radio::Antenna {
+        self.antenna_mode.get()
+    }
+}
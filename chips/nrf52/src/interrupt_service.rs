@@ -1,8 +1,10 @@
 use crate::acomp;
 use crate::adc;
 use crate::ble_radio;
+use crate::esb;
 use crate::i2c;
 use crate::ieee802154_radio;
+use crate::nfct;
 use crate::power;
 use crate::spi;
 use crate::uart;
@@ -72,18 +74,19 @@ impl InterruptService for Nrf52InterruptService<'_> {
             peripheral_interrupts::COMP => acomp::ACOMP.handle_interrupt(),
             peripheral_interrupts::ECB => nrf5x::aes::AESECB.handle_interrupt(),
             peripheral_interrupts::GPIOTE => self.gpio_port.handle_interrupt(),
+            peripheral_interrupts::NFCT => nfct::NFCT.handle_interrupt(),
             peripheral_interrupts::POWER_CLOCK => power::POWER.handle_interrupt(),
             peripheral_interrupts::RADIO => {
                 match (
                     ieee802154_radio::RADIO.is_enabled(),
                     ble_radio::RADIO.is_enabled(),
+                    esb::ESB.is_enabled(),
                 ) {
-                    (false, false) => (),
-                    (true, false) => ieee802154_radio::RADIO.handle_interrupt(),
-                    (false, true) => ble_radio::RADIO.handle_interrupt(),
-                    (true, true) => {
-                        debug!("nRF 802.15.4 and BLE radios cannot be simultaneously enabled!")
-                    }
+                    (false, false, false) => (),
+                    (true, false, false) => ieee802154_radio::RADIO.handle_interrupt(),
+                    (false, true, false) => ble_radio::RADIO.handle_interrupt(),
+                    (false, false, true) => esb::ESB.handle_interrupt(),
+                    _ => debug!("Only one of the nRF 802.15.4, BLE and ESB radios can be enabled!"),
                 }
             }
             peripheral_interrupts::RNG => nrf5x::trng::TRNG.handle_interrupt(),
@@ -10,10 +10,12 @@ pub mod chip;
 pub mod clock;
 pub mod crt1;
 mod deferred_call_tasks;
+pub mod esb;
 pub mod ficr;
 pub mod i2c;
 pub mod ieee802154_radio;
 pub mod interrupt_service;
+pub mod nfct;
 pub mod nvmc;
 pub mod power;
 pub mod ppi;
@@ -0,0 +1,430 @@
+//! NFC tag driver, NFCT, NRF52
+//!
+//! Implements `hil::nfc` on the NFCT peripheral, which senses the reader's
+//! field and runs NFC-A anticollision in hardware. Frames are moved by
+//! EasyDMA, with the CRC added and checked by the peripheral. The NFC pins
+//! must be left as antenna pins, and the HFXO must be running while a reader
+//! is in range.
+//!
+//! ```rust
+//! kernel::hil::nfc::NfcTag::set_client(&nrf52::nfct::NFCT, client);
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::registers::{
+    register_bitfields, register_structs, ReadOnly, ReadWrite, WriteOnly,
+};
+use kernel::common::StaticRef;
+use kernel::hil::nfc::{self, TagType};
+use kernel::ReturnCode;
+
+const NFCT_BASE: StaticRef<NfctRegisters> =
+    unsafe { StaticRef::new(0x40005000 as *const NfctRegisters) };
+
+/// The longest frame EasyDMA moves.
+const MAX_FRAME_LEN: usize = 257;
+const CRC_LEN: usize = 2;
+
+/// The HLTA command, which puts the tag to sleep until a reader wakes it.
+const HLTA: [u8; 2] = [0x50, 0x00];
+
+register_structs! {
+    NfctRegisters {
+        (0x000 => task_activate: WriteOnly<u32, Task::Register>),
+        (0x004 => task_disable: WriteOnly<u32, Task::Register>),
+        (0x008 => task_sense: WriteOnly<u32, Task::Register>),
+        (0x00c => task_starttx: WriteOnly<u32, Task::Register>),
+        (0x010 => _reserved0),
+        (0x01c => task_enablerxdata: WriteOnly<u32, Task::Register>),
+        (0x020 => _reserved1),
+        (0x024 => task_goidle: WriteOnly<u32, Task::Register>),
+        (0x028 => task_gosleep: WriteOnly<u32, Task::Register>),
+        (0x02c => _reserved2),
+        (0x100 => event_ready: ReadWrite<u32, Event::Register>),
+        (0x104 => event_fielddetected: ReadWrite<u32, Event::Register>),
+        (0x108 => event_fieldlost: ReadWrite<u32, Event::Register>),
+        (0x10c => event_txframestart: ReadWrite<u32, Event::Register>),
+        (0x110 => event_txframeend: ReadWrite<u32, Event::Register>),
+        (0x114 => event_rxframestart: ReadWrite<u32, Event::Register>),
+        (0x118 => event_rxframeend: ReadWrite<u32, Event::Register>),
+        (0x11c => event_error: ReadWrite<u32, Event::Register>),
+        (0x120 => _reserved3),
+        (0x128 => event_rxerror: ReadWrite<u32, Event::Register>),
+        (0x12c => event_endrx: ReadWrite<u32, Event::Register>),
+        (0x130 => event_endtx: ReadWrite<u32, Event::Register>),
+        (0x134 => _reserved4),
+        (0x138 => event_autocolresstarted: ReadWrite<u32, Event::Register>),
+        (0x13c => _reserved5),
+        (0x148 => event_collision: ReadWrite<u32, Event::Register>),
+        (0x14c => event_selected: ReadWrite<u32, Event::Register>),
+        (0x150 => event_started: ReadWrite<u32, Event::Register>),
+        (0x154 => _reserved6),
+        (0x200 => shorts: ReadWrite<u32, Shorts::Register>),
+        (0x204 => _reserved7),
+        (0x300 => inten: ReadWrite<u32, Interrupt::Register>),
+        (0x304 => intenset: ReadWrite<u32, Interrupt::Register>),
+        (0x308 => intenclr: ReadWrite<u32, Interrupt::Register>),
+        (0x30c => _reserved8),
+        (0x404 => errorstatus: ReadWrite<u32, ErrorStatus::Register>),
+        (0x408 => _reserved9),
+        (0x40c => framestatus_rx: ReadWrite<u32, FrameStatus::Register>),
+        (0x410 => _reserved10),
+        (0x43c => fieldpresent: ReadOnly<u32, FieldPresent::Register>),
+        (0x440 => _reserved11),
+        (0x504 => framedelaymin: ReadWrite<u32>),
+        (0x508 => framedelaymax: ReadWrite<u32>),
+        (0x50c => framedelaymode: ReadWrite<u32, FrameDelayMode::Register>),
+        (0x510 => packetptr: ReadWrite<u32>),
+        (0x514 => maxlen: ReadWrite<u32>),
+        (0x518 => txd_frameconfig: ReadWrite<u32, FrameConfig::Register>),
+        (0x51c => txd_amount: ReadWrite<u32, Amount::Register>),
+        (0x520 => rxd_frameconfig: ReadWrite<u32, FrameConfig::Register>),
+        (0x524 => rxd_amount: ReadOnly<u32, Amount::Register>),
+        (0x528 => _reserved12),
+        (0x590 => nfcid1_last: ReadWrite<u32>),
+        (0x594 => nfcid1_2nd_last: ReadWrite<u32>),
+        (0x598 => nfcid1_3rd_last: ReadWrite<u32>),
+        (0x59c => _reserved13),
+        (0x5a0 => sensres: ReadWrite<u32, SensRes::Register>),
+        (0x5a4 => selres: ReadWrite<u32, SelRes::Register>),
+        (0x5a8 => @END),
+    }
+}
+
+register_bitfields! [u32,
+    Task [
+        ENABLE OFFSET(0) NUMBITS(1) []
+    ],
+    Event [
+        READY OFFSET(0) NUMBITS(1) []
+    ],
+    Shorts [
+        FIELDDETECTED_ACTIVATE OFFSET(0) NUMBITS(1) [],
+        FIELDLOST_SENSE OFFSET(1) NUMBITS(1) []
+    ],
+    Interrupt [
+        READY OFFSET(0) NUMBITS(1) [],
+        FIELDDETECTED OFFSET(1) NUMBITS(1) [],
+        FIELDLOST OFFSET(2) NUMBITS(1) [],
+        TXFRAMESTART OFFSET(3) NUMBITS(1) [],
+        TXFRAMEEND OFFSET(4) NUMBITS(1) [],
+        RXFRAMESTART OFFSET(5) NUMBITS(1) [],
+        RXFRAMEEND OFFSET(6) NUMBITS(1) [],
+        ERROR OFFSET(7) NUMBITS(1) [],
+        RXERROR OFFSET(10) NUMBITS(1) [],
+        ENDRX OFFSET(11) NUMBITS(1) [],
+        ENDTX OFFSET(12) NUMBITS(1) [],
+        AUTOCOLRESSTARTED OFFSET(14) NUMBITS(1) [],
+        COLLISION OFFSET(18) NUMBITS(1) [],
+        SELECTED OFFSET(19) NUMBITS(1) [],
+        STARTED OFFSET(20) NUMBITS(1) []
+    ],
+    ErrorStatus [
+        FRAMEDELAYTIMEOUT OFFSET(0) NUMBITS(1) []
+    ],
+    FrameStatus [
+        CRCERROR OFFSET(0) NUMBITS(1) [],
+        PARITYSTATUS OFFSET(2) NUMBITS(1) [],
+        OVERRUN OFFSET(3) NUMBITS(1) []
+    ],
+    FieldPresent [
+        FIELDPRESENT OFFSET(0) NUMBITS(1) [],
+        LOCKDETECT OFFSET(1) NUMBITS(1) []
+    ],
+    FrameDelayMode [
+        MODE OFFSET(0) NUMBITS(2) [
+            FreeRun = 0,
+            Window = 1,
+            ExactVal = 2,
+            WindowGrid = 3
+        ]
+    ],
+    FrameConfig [
+        PARITY OFFSET(0) NUMBITS(1) [],
+        DISCARDMODE OFFSET(1) NUMBITS(1) [
+            DiscardEnd = 0,
+            DiscardStart = 1
+        ],
+        SOF OFFSET(2) NUMBITS(1) [],
+        CRCMODE OFFSET(4) NUMBITS(1) []
+    ],
+    Amount [
+        DATABITS OFFSET(0) NUMBITS(3) [],
+        DATABYTES OFFSET(3) NUMBITS(9) []
+    ],
+    SensRes [
+        BITFRAMESDD OFFSET(0) NUMBITS(5) [
+            SDD00001 = 1
+        ],
+        NFCIDSIZE OFFSET(6) NUMBITS(2) [
+            Single = 0,
+            Double = 1,
+            Triple = 2
+        ],
+        PLATFCONFIG OFFSET(8) NUMBITS(4) []
+    ],
+    SelRes [
+        CASCADE OFFSET(2) NUMBITS(1) [],
+        PROTOCOL OFFSET(5) NUMBITS(2) [
+            Type2 = 0,
+            IsoDep = 1
+        ]
+    ]
+];
+
+pub static mut NFCT: Nfct<'static> = Nfct::new();
+
+pub struct Nfct<'a> {
+    registers: StaticRef<NfctRegisters>,
+    client: OptionalCell<&'a dyn nfc::Client>,
+    rx_buffer: TakeCell<'static, [u8]>,
+    tx_buffer: TakeCell<'static, [u8]>,
+    enabled: Cell<bool>,
+    selected: Cell<bool>,
+}
+
+impl<'a> Nfct<'a> {
+    const fn new() -> Nfct<'a> {
+        Nfct {
+            registers: NFCT_BASE,
+            client: OptionalCell::empty(),
+            rx_buffer: TakeCell::empty(),
+            tx_buffer: TakeCell::empty(),
+            enabled: Cell::new(false),
+            selected: Cell::new(false),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.get()
+    }
+
+    fn start_receive(&self) {
+        let regs = &*self.registers;
+        self.rx_buffer.map(|buffer| {
+            regs.packetptr.set(buffer.as_ptr() as u32);
+            regs.maxlen
+                .set(cmp::min(buffer.len(), MAX_FRAME_LEN) as u32);
+        });
+        regs.rxd_frameconfig
+            .write(FrameConfig::PARITY::SET + FrameConfig::SOF::SET + FrameConfig::CRCMODE::SET);
+        regs.task_enablerxdata.write(Task::ENABLE::SET);
+    }
+
+    fn start_transmit(
+        &self,
+        buffer: &'static mut [u8],
+        amount: u32,
+        config: u32,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        if !self.selected.get() {
+            return Err((ReturnCode::EOFF, buffer));
+        }
+        if self.rx_buffer.is_some() || self.tx_buffer.is_some() {
+            return Err((ReturnCode::EBUSY, buffer));
+        }
+        let regs = &*self.registers;
+        regs.packetptr.set(buffer.as_ptr() as u32);
+        regs.txd_frameconfig.set(config);
+        regs.txd_amount.set(amount);
+        self.tx_buffer.replace(buffer);
+        regs.task_starttx.write(Task::ENABLE::SET);
+        Ok(())
+    }
+
+    /// Return the buffers given to the peripheral.
+    fn cancel(&self) {
+        self.rx_buffer.take().map(|buffer| {
+            self.client
+                .map(move |client| client.frame_received(buffer, 0, ReturnCode::ECANCEL));
+        });
+        self.tx_buffer.take().map(|buffer| {
+            self.client
+                .map(move |client| client.frame_transmitted(buffer, ReturnCode::ECANCEL));
+        });
+    }
+
+    fn frame_received(&self) {
+        let regs = &*self.registers;
+        let status = regs.framestatus_rx.extract();
+        // The status bits are cleared by writing ones.
+        regs.framestatus_rx.set(status.get());
+        if status.is_set(FrameStatus::CRCERROR)
+            || status.is_set(FrameStatus::PARITYSTATUS)
+            || status.is_set(FrameStatus::OVERRUN)
+        {
+            self.start_receive();
+            return;
+        }
+        // The count includes the CRC.
+        let len = (regs.rxd_amount.read(Amount::DATABYTES) as usize).saturating_sub(CRC_LEN);
+        let halt = self
+            .rx_buffer
+            .map_or(false, |buffer| len == HLTA.len() && buffer[..len] == HLTA);
+        if halt {
+            // The buffer is kept for when a reader selects the tag again.
+            self.selected.set(false);
+            regs.task_gosleep.write(Task::ENABLE::SET);
+            return;
+        }
+        self.rx_buffer.take().map(|buffer| {
+            self.client
+                .map(move |client| client.frame_received(buffer, len, ReturnCode::SUCCESS));
+        });
+    }
+
+    pub fn handle_interrupt(&self) {
+        let regs = &*self.registers;
+
+        if regs.event_fielddetected.is_set(Event::READY) {
+            regs.event_fielddetected.write(Event::READY::CLEAR);
+            self.client.map(|client| client.field_detected());
+        }
+        if regs.event_selected.is_set(Event::READY) {
+            regs.event_selected.write(Event::READY::CLEAR);
+            self.selected.set(true);
+            if self.rx_buffer.is_some() {
+                self.start_receive();
+            }
+            self.client.map(|client| client.selected());
+        }
+        if regs.event_rxerror.is_set(Event::READY) {
+            // The frame status is checked once the frame ends.
+            regs.event_rxerror.write(Event::READY::CLEAR);
+        }
+        if regs.event_rxframeend.is_set(Event::READY) {
+            regs.event_rxframeend.write(Event::READY::CLEAR);
+            self.frame_received();
+        }
+        if regs.event_txframeend.is_set(Event::READY) {
+            regs.event_txframeend.write(Event::READY::CLEAR);
+            self.tx_buffer.take().map(|buffer| {
+                self.client
+                    .map(move |client| client.frame_transmitted(buffer, ReturnCode::SUCCESS));
+            });
+        }
+        if regs.event_error.is_set(Event::READY) {
+            regs.event_error.write(Event::READY::CLEAR);
+            let status = regs.errorstatus.extract();
+            regs.errorstatus.set(status.get());
+            if status.is_set(ErrorStatus::FRAMEDELAYTIMEOUT) {
+                // The answer was not started in time, so it is not sent.
+                self.tx_buffer.take().map(|buffer| {
+                    self.client
+                        .map(move |client| client.frame_transmitted(buffer, ReturnCode::FAIL));
+                });
+            }
+        }
+        if regs.event_fieldlost.is_set(Event::READY) {
+            regs.event_fieldlost.write(Event::READY::CLEAR);
+            self.selected.set(false);
+            self.cancel();
+            self.client.map(|client| client.field_lost());
+        }
+    }
+}
+
+impl<'a> nfc::NfcTag<'a> for Nfct<'a> {
+    fn set_client(&self, client: &'a dyn nfc::Client) {
+        self.client.set(client);
+    }
+
+    fn enable(&self, id: &[u8], tag_type: TagType) -> ReturnCode {
+        let regs = &*self.registers;
+        let word = |bytes: &[u8]| bytes.iter().fold(0, |word, &b| word << 8 | b as u32);
+        match id.len() {
+            4 => {
+                regs.nfcid1_last.set(word(id));
+                regs.sensres
+                    .write(SensRes::BITFRAMESDD::SDD00001 + SensRes::NFCIDSIZE::Single);
+            }
+            7 => {
+                regs.nfcid1_2nd_last.set(word(&id[..3]));
+                regs.nfcid1_last.set(word(&id[3..]));
+                regs.sensres
+                    .write(SensRes::BITFRAMESDD::SDD00001 + SensRes::NFCIDSIZE::Double);
+            }
+            _ => return ReturnCode::EINVAL,
+        }
+        regs.selres.write(match tag_type {
+            TagType::Type2 => SelRes::PROTOCOL::Type2,
+            TagType::Type4 => SelRes::PROTOCOL::IsoDep,
+        });
+
+        // Answers go out on the bit grid, up to about 4.8 ms after a frame.
+        regs.framedelaymode.write(FrameDelayMode::MODE::WindowGrid);
+        regs.framedelaymax.set(0xffff);
+
+        regs.shorts
+            .write(Shorts::FIELDDETECTED_ACTIVATE::SET + Shorts::FIELDLOST_SENSE::SET);
+        regs.intenset.write(
+            Interrupt::FIELDDETECTED::SET
+                + Interrupt::FIELDLOST::SET
+                + Interrupt::SELECTED::SET
+                + Interrupt::RXFRAMEEND::SET
+                + Interrupt::RXERROR::SET
+                + Interrupt::TXFRAMEEND::SET
+                + Interrupt::ERROR::SET,
+        );
+        self.enabled.set(true);
+        regs.task_sense.write(Task::ENABLE::SET);
+        ReturnCode::SUCCESS
+    }
+
+    fn disable(&self) -> ReturnCode {
+        let regs = &*self.registers;
+        regs.intenclr.set(0xffff_ffff);
+        regs.shorts.set(0);
+        regs.task_disable.write(Task::ENABLE::SET);
+        self.enabled.set(false);
+        self.selected.set(false);
+        self.cancel();
+        ReturnCode::SUCCESS
+    }
+
+    fn receive(&self, buffer: &'static mut [u8]) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        if !self.enabled.get() {
+            return Err((ReturnCode::EOFF, buffer));
+        }
+        if self.rx_buffer.is_some() || self.tx_buffer.is_some() {
+            return Err((ReturnCode::EBUSY, buffer));
+        }
+        self.rx_buffer.replace(buffer);
+        if self.selected.get() {
+            self.start_receive();
+        }
+        Ok(())
+    }
+
+    fn transmit(
+        &self,
+        buffer: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        if len == 0 || len > cmp::min(buffer.len(), MAX_FRAME_LEN) {
+            return Err((ReturnCode::ESIZE, buffer));
+        }
+        let amount = Amount::DATABYTES.val(len as u32);
+        let config = FrameConfig::PARITY::SET
+            + FrameConfig::DISCARDMODE::DiscardStart
+            + FrameConfig::SOF::SET
+            + FrameConfig::CRCMODE::SET;
+        self.start_transmit(buffer, amount.value, config.value)
+    }
+
+    fn transmit_bits(
+        &self,
+        buffer: &'static mut [u8],
+        bits: usize,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        if bits == 0 || bits > 7 || buffer.is_empty() {
+            return Err((ReturnCode::ESIZE, buffer));
+        }
+        // Short frames have no parity bit.
+        let amount = Amount::DATABITS.val(bits as u32);
+        let config = FrameConfig::DISCARDMODE::DiscardStart + FrameConfig::SOF::SET;
+        self.start_transmit(buffer, amount.value, config.value)
+    }
+}
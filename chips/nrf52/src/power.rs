@@ -1,10 +1,13 @@
 //! Power management
 
+use core::cell::Cell;
 use kernel::common::cells::OptionalCell;
 use kernel::common::registers::{
     register_bitfields, register_structs, ReadOnly, ReadWrite, WriteOnly,
 };
 use kernel::common::StaticRef;
+use kernel::hil::brown_out::{BrownOutClient, BrownOutDetector};
+use kernel::ReturnCode;
 
 const POWER_BASE: StaticRef<PowerRegisters> =
     unsafe { StaticRef::new(0x40000000 as *const PowerRegisters) };
@@ -230,14 +233,26 @@ register_bitfields! [u32,
     ]
 ];
 
+/// Thresholds of the power failure comparator, in millivolts. The
+/// THRESHOLD field value of each is its index plus 4.
+const POF_THRESHOLDS: [u32; 12] = [
+    1700, 1800, 1900, 2000, 2100, 2200, 2300, 2400, 2500, 2600, 2700, 2800,
+];
+
 /// The USB state machine needs to be notified of power events (USB detected, USB
 /// removed, USB power ready) in order to be initialized and shut down properly.
 /// These events come from the power management registers of this module; that's
 /// this has a USB client to notify.
+///
+/// The power failure comparator is exposed as a `BrownOutDetector`.
 pub struct Power<'a> {
     registers: StaticRef<PowerRegisters>,
     /// A client to which to notify USB plug-in/plug-out/power-ready events.
     usb_client: OptionalCell<&'a dyn PowerClient>,
+    /// A client to which to notify power failure warnings.
+    brown_out_client: OptionalCell<&'a dyn BrownOutClient>,
+    /// Whether the power failure comparator is enabled.
+    pof_enabled: Cell<bool>,
 }
 
 pub enum MainVoltage {
@@ -267,6 +282,8 @@ impl<'a> Power<'a> {
         Power {
             registers: POWER_BASE,
             usb_client: OptionalCell::empty(),
+            brown_out_client: OptionalCell::empty(),
+            pof_enabled: Cell::new(false),
         }
     }
 
@@ -295,8 +312,14 @@ impl<'a> Power<'a> {
                 .map(|client| client.handle_power_event(PowerEvent::UsbPowerReady));
         }
 
+        if self.registers.event_pofwarn.is_set(Event::READY) {
+            self.registers.event_pofwarn.write(Event::READY::CLEAR);
+            if self.pof_enabled.get() {
+                self.brown_out_client.map(|client| client.supply_low());
+            }
+        }
+
         // Clearing unused events
-        self.registers.event_pofwarn.write(Event::READY::CLEAR);
         self.registers.event_sleepenter.write(Event::READY::CLEAR);
         self.registers.event_sleepexit.write(Event::READY::CLEAR);
 
@@ -307,6 +330,9 @@ impl<'a> Power<'a> {
         self.registers.intenset.write(
             Interrupt::USBDETECTED::SET + Interrupt::USBREMOVED::SET + Interrupt::USBPWRRDY::SET,
         );
+        if self.pof_enabled.get() {
+            self.registers.intenset.write(Interrupt::POFWARN::SET);
+        }
     }
 
     pub fn enable_interrupt(&self, intr: u32) {
@@ -344,4 +370,43 @@ impl<'a> Power<'a> {
     }
 }
 
+impl<'a> BrownOutDetector<'a> for Power<'a> {
+    fn set_client(&self, client: &'a dyn BrownOutClient) {
+        self.brown_out_client.set(client);
+    }
+
+    fn thresholds(&self) -> &'static [u32] {
+        &POF_THRESHOLDS
+    }
+
+    /// Sets the threshold for VDD. In high voltage mode the comparator also
+    /// watches VDDH against its own threshold, which is left unchanged.
+    fn set_threshold(&self, millivolts: u32) -> ReturnCode {
+        match POF_THRESHOLDS.iter().position(|&t| t == millivolts) {
+            Some(index) => {
+                self.registers
+                    .pofcon
+                    .modify(PowerFailure::THRESHOLD.val(index as u32 + 4));
+                ReturnCode::SUCCESS
+            }
+            None => ReturnCode::EINVAL,
+        }
+    }
+
+    fn enable(&self) -> ReturnCode {
+        self.registers.event_pofwarn.write(Event::READY::CLEAR);
+        self.pof_enabled.set(true);
+        self.registers.pofcon.modify(PowerFailure::POF::Enabled);
+        self.registers.intenset.write(Interrupt::POFWARN::SET);
+        ReturnCode::SUCCESS
+    }
+
+    fn disable(&self) -> ReturnCode {
+        self.pof_enabled.set(false);
+        self.registers.intenclr.write(Interrupt::POFWARN::SET);
+        self.registers.pofcon.modify(PowerFailure::POF::Disabled);
+        ReturnCode::SUCCESS
+    }
+}
+
 pub static mut POWER: Power<'static> = Power::new();
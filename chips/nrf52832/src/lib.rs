@@ -1,8 +1,9 @@
 #![no_std]
 
 pub use nrf52::{
-    acomp, adc, aes, ble_radio, clock, constants, crt1, ficr, i2c, ieee802154_radio, init, nvmc,
-    peripheral_interrupts, pinmux, ppi, pwm, rtc, spi, temperature, timer, trng, uart, uicr,
+    acomp, adc, aes, ble_radio, clock, constants, crt1, esb, ficr, i2c, ieee802154_radio, init,
+    nfct, nvmc, peripheral_interrupts, pinmux, ppi, pwm, rtc, spi, temperature, timer, trng, uart,
+    uicr,
 };
 pub mod chip;
 pub mod gpio;
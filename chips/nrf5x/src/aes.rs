@@ -3,6 +3,9 @@
 //! Provides a simple driverto encrypt and decrypt
 //! messages using aes128-ctr mode on top of aes128-ecb.
 //!
+//! Authenticated encryption (CCM* and GCM) is provided on top of it by
+//! `capsules::aes_aead`.
+//!
 //! Roughly, the module three buffers with the following content:
 //!
 //! * Key
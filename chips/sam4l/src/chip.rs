@@ -22,6 +22,7 @@ use crate::usbc;
 use core::fmt::Write;
 use cortexm4;
 use kernel::common::deferred_call;
+use kernel::power::SleepState;
 use kernel::Chip;
 
 pub struct Sam4l {
@@ -189,7 +190,11 @@ impl Chip for Sam4l {
     }
 
     fn sleep(&self) {
-        if pm::deep_sleep_ready() {
+        self.sleep_in(SleepState::DeepSleep);
+    }
+
+    fn sleep_in(&self, state: SleepState) {
+        if state == SleepState::DeepSleep && pm::deep_sleep_ready() {
             unsafe {
                 cortexm4::scb::set_sleepdeep();
             }
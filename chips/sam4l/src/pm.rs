@@ -7,9 +7,11 @@ use crate::gpio;
 use crate::scif;
 use core::cell::Cell;
 use core::sync::atomic::Ordering;
+use kernel::clock_manager::FrequencyControl;
 use kernel::common::registers::{register_bitfields, FieldValue, ReadOnly, ReadWrite, WriteOnly};
 use kernel::common::StaticRef;
 use kernel::ClockInterface;
+use kernel::ReturnCode;
 
 /// §10.7 PM::UserInterface from SAM4L Datasheet.
 #[repr(C)]
@@ -564,6 +566,10 @@ pub struct PowerManager {
 
     /// Has setup_system_clock been called once
     system_initial_configs: Cell<bool>,
+
+    /// Clock source the board first set up, used when scaling back up to its
+    /// frequency
+    boot_clock_source: Cell<SystemClockSource>,
 }
 
 pub static mut PM: PowerManager = PowerManager {
@@ -573,6 +579,8 @@ pub static mut PM: PowerManager = PowerManager {
     system_on_clocks: Cell::new(ClockMask::RCSYS as u32),
 
     system_initial_configs: Cell::new(false),
+
+    boot_clock_source: Cell::new(SystemClockSource::RcsysAt115kHz),
 };
 
 impl PowerManager {
@@ -594,6 +602,7 @@ impl PowerManager {
             flashcalw::FLASH_CONTROLLER.enable_high_speed_flash();
 
             self.system_initial_configs.set(true);
+            self.boot_clock_source.set(clock_source);
         }
 
         match clock_source {
@@ -959,22 +968,67 @@ unsafe fn configure_1mhz_rc() {
     PM.system_on_clocks.set(clock_mask | ClockMask::RC1M as u32);
 }
 
+fn clock_source_frequency(clock_source: SystemClockSource) -> u32 {
+    match clock_source {
+        SystemClockSource::RcsysAt115kHz => 115200,
+        SystemClockSource::DfllRc32kAt48MHz => 48000000,
+        SystemClockSource::ExternalOscillator { .. } => 16000000,
+        SystemClockSource::PllExternalOscillatorAt48MHz { .. } => 48000000,
+        SystemClockSource::RC80M => 40000000,
+        SystemClockSource::RCFAST { frequency } => match frequency {
+            RcfastFrequency::Frequency4MHz => 4300000,
+            RcfastFrequency::Frequency8MHz => 8200000,
+            RcfastFrequency::Frequency12MHz => 12000000,
+        },
+        SystemClockSource::RC1M => 1000000,
+    }
+}
+
 pub fn get_system_frequency() -> u32 {
     // Return the current system frequency
-    unsafe {
-        match PM.system_clock_source.get() {
-            SystemClockSource::RcsysAt115kHz => 115200,
-            SystemClockSource::DfllRc32kAt48MHz => 48000000,
-            SystemClockSource::ExternalOscillator { .. } => 16000000,
-            SystemClockSource::PllExternalOscillatorAt48MHz { .. } => 48000000,
-            SystemClockSource::RC80M => 40000000,
-            SystemClockSource::RCFAST { frequency } => match frequency {
-                RcfastFrequency::Frequency4MHz => 4300000,
-                RcfastFrequency::Frequency8MHz => 8200000,
-                RcfastFrequency::Frequency12MHz => 12000000,
+    unsafe { clock_source_frequency(PM.system_clock_source.get()) }
+}
+
+/// Frequencies the system clock can be scaled between at runtime. 48 MHz
+/// uses the clock source the board booted with if that runs at 48 MHz, and
+/// the DFLL otherwise.
+const SCALING_FREQUENCIES: [u32; 5] = [1000000, 4300000, 8200000, 12000000, 48000000];
+
+impl FrequencyControl for PowerManager {
+    fn frequencies(&self) -> &'static [u32] {
+        &SCALING_FREQUENCIES
+    }
+
+    fn frequency(&self) -> u32 {
+        clock_source_frequency(self.system_clock_source.get())
+    }
+
+    fn set_frequency(&self, hz: u32) -> ReturnCode {
+        let clock_source = match hz {
+            1000000 => SystemClockSource::RC1M,
+            4300000 => SystemClockSource::RCFAST {
+                frequency: RcfastFrequency::Frequency4MHz,
             },
-            SystemClockSource::RC1M => 1000000,
+            8200000 => SystemClockSource::RCFAST {
+                frequency: RcfastFrequency::Frequency8MHz,
+            },
+            12000000 => SystemClockSource::RCFAST {
+                frequency: RcfastFrequency::Frequency12MHz,
+            },
+            48000000 => {
+                let boot = self.boot_clock_source.get();
+                if clock_source_frequency(boot) == 48000000 {
+                    boot
+                } else {
+                    SystemClockSource::DfllRc32kAt48MHz
+                }
+            }
+            _ => return ReturnCode::EINVAL,
+        };
+        unsafe {
+            self.change_system_clock(clock_source);
         }
+        ReturnCode::SUCCESS
     }
 }
 
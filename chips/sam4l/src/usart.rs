@@ -5,6 +5,7 @@
 use core::cell::Cell;
 use core::cmp;
 use core::sync::atomic::{AtomicBool, Ordering};
+use kernel::clock_manager::ClockListener;
 use kernel::common::cells::OptionalCell;
 use kernel::common::registers::{register_bitfields, ReadOnly, ReadWrite, WriteOnly};
 use kernel::common::StaticRef;
@@ -379,6 +380,9 @@ pub struct USART<'a> {
     clock: pm::Clock,
 
     usart_mode: Cell<UsartMode>,
+    /// Baud rate last configured, so it can be restored when the system
+    /// clock changes.
+    baud_rate: Cell<u32>,
 
     usart_tx_state: Cell<USARTStateTX>,
     usart_rx_state: Cell<USARTStateRX>,
@@ -433,6 +437,7 @@ impl<'a> USART<'a> {
             clock: pm::Clock::PBA(clock),
 
             usart_mode: Cell::new(UsartMode::Unused),
+            baud_rate: Cell::new(0),
 
             usart_rx_state: Cell::new(USARTStateRX::Idle),
             usart_tx_state: Cell::new(USARTStateTX::Idle),
@@ -686,6 +691,7 @@ impl<'a> USART<'a> {
     }
 
     fn set_baud_rate(&self, usart: &USARTRegManager, baud_rate: u32) {
+        self.baud_rate.set(baud_rate);
         let system_frequency = pm::get_system_frequency();
 
         // The clock divisor is calculated differently in UART and SPI modes.
@@ -929,6 +935,22 @@ impl<'a> uart::Transmit<'a> for USART<'a> {
     }
 }
 
+/// The baud rate divider depends on the system clock, so it is recomputed
+/// whenever the clock changes. A transmission in progress would be garbled, so
+/// the clock is not changed until it finishes.
+impl ClockListener for USART<'_> {
+    fn can_change_frequency(&self) -> bool {
+        self.usart_tx_state.get() == USARTStateTX::Idle
+    }
+
+    fn frequency_changed(&self, _hz: u32) {
+        if self.usart_mode.get() != UsartMode::Unused && self.baud_rate.get() != 0 {
+            let usart = &USARTRegManager::new(&self);
+            self.set_baud_rate(usart, self.baud_rate.get());
+        }
+    }
+}
+
 impl<'a> uart::UartAdvanced<'a> for USART<'a> {}
 impl<'a> uart::Uart<'a> for USART<'a> {}
 
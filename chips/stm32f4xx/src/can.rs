@@ -0,0 +1,513 @@
+//! bxCAN controller
+//!
+//! Implements `hil::can::Can` for CAN1, with one transmit mailbox and
+//! receive FIFO 0. CAN1 gets the first 14 filter banks, each used as one
+//! 32 bit filter in mask mode, so the filter match index of a received frame
+//! is the filter number. The controller leaves bus-off by itself, and
+//! retransmits frames until they are sent. The board must configure the CAN
+//! pins and enable the `CAN1_TX` and `CAN1_RX0` interrupts.
+
+use core::cell::Cell;
+
+use kernel::common::cells::OptionalCell;
+use kernel::common::registers::{register_bitfields, ReadOnly, ReadWrite};
+use kernel::common::StaticRef;
+use kernel::hil::can::{self, Filter, Frame, Id};
+use kernel::ClockInterface;
+use kernel::ReturnCode;
+
+use crate::rcc;
+
+/// A transmit mailbox.
+#[repr(C)]
+struct TxMailbox {
+    /// TX mailbox identifier register
+    tir: ReadWrite<u32, TIR::Register>,
+    /// mailbox data length control and time stamp register
+    tdtr: ReadWrite<u32, TDTR::Register>,
+    /// mailbox data low register
+    tdlr: ReadWrite<u32>,
+    /// mailbox data high register
+    tdhr: ReadWrite<u32>,
+}
+
+/// A receive FIFO mailbox.
+#[repr(C)]
+struct RxMailbox {
+    /// receive FIFO mailbox identifier register
+    rir: ReadOnly<u32, RIR::Register>,
+    /// receive FIFO mailbox data length control and time stamp register
+    rdtr: ReadOnly<u32, RDTR::Register>,
+    /// mailbox data low register
+    rdlr: ReadOnly<u32>,
+    /// mailbox data high register
+    rdhr: ReadOnly<u32>,
+}
+
+/// A filter bank, with the identifier and mask of a 32 bit filter in mask
+/// mode.
+#[repr(C)]
+struct FilterBank {
+    fr1: ReadWrite<u32>,
+    fr2: ReadWrite<u32>,
+}
+
+/// Controller area network
+#[repr(C)]
+struct CanRegisters {
+    /// master control register
+    mcr: ReadWrite<u32, MCR::Register>,
+    /// master status register
+    msr: ReadWrite<u32, MSR::Register>,
+    /// transmit status register
+    tsr: ReadWrite<u32, TSR::Register>,
+    /// receive FIFO 0 register
+    rf0r: ReadWrite<u32, RFR::Register>,
+    /// receive FIFO 1 register
+    rf1r: ReadWrite<u32, RFR::Register>,
+    /// interrupt enable register
+    ier: ReadWrite<u32, IER::Register>,
+    /// error status register
+    esr: ReadWrite<u32>,
+    /// bit timing register
+    btr: ReadWrite<u32, BTR::Register>,
+    _reserved0: [u32; 88],
+    tx: [TxMailbox; 3],
+    rx: [RxMailbox; 2],
+    _reserved1: [u32; 12],
+    /// filter master register
+    fmr: ReadWrite<u32, FMR::Register>,
+    /// filter mode register
+    fm1r: ReadWrite<u32>,
+    _reserved2: u32,
+    /// filter scale register
+    fs1r: ReadWrite<u32>,
+    _reserved3: u32,
+    /// filter FIFO assignment register
+    ffa1r: ReadWrite<u32>,
+    _reserved4: u32,
+    /// filter activation register
+    fa1r: ReadWrite<u32>,
+    _reserved5: [u32; 8],
+    filters: [FilterBank; 28],
+}
+
+register_bitfields![u32,
+    MCR [
+        /// Debug freeze
+        DBF OFFSET(16) NUMBITS(1) [],
+        /// bxCAN software master reset
+        RESET OFFSET(15) NUMBITS(1) [],
+        /// Time triggered communication mode
+        TTCM OFFSET(7) NUMBITS(1) [],
+        /// Automatic bus-off management
+        ABOM OFFSET(6) NUMBITS(1) [],
+        /// Automatic wakeup mode
+        AWUM OFFSET(5) NUMBITS(1) [],
+        /// No automatic retransmission
+        NART OFFSET(4) NUMBITS(1) [],
+        /// Receive FIFO locked mode
+        RFLM OFFSET(3) NUMBITS(1) [],
+        /// Transmit FIFO priority
+        TXFP OFFSET(2) NUMBITS(1) [],
+        /// Sleep mode request
+        SLEEP OFFSET(1) NUMBITS(1) [],
+        /// Initialization request
+        INRQ OFFSET(0) NUMBITS(1) []
+    ],
+    MSR [
+        /// Receive level
+        RX OFFSET(11) NUMBITS(1) [],
+        /// Last sample point
+        SAMP OFFSET(10) NUMBITS(1) [],
+        /// Receive mode
+        RXM OFFSET(9) NUMBITS(1) [],
+        /// Transmit mode
+        TXM OFFSET(8) NUMBITS(1) [],
+        /// Sleep acknowledge interrupt
+        SLAKI OFFSET(4) NUMBITS(1) [],
+        /// Wakeup interrupt
+        WKUI OFFSET(3) NUMBITS(1) [],
+        /// Error interrupt
+        ERRI OFFSET(2) NUMBITS(1) [],
+        /// Sleep acknowledge
+        SLAK OFFSET(1) NUMBITS(1) [],
+        /// Initialization acknowledge
+        INAK OFFSET(0) NUMBITS(1) []
+    ],
+    TSR [
+        /// Transmit mailbox 0 empty
+        TME0 OFFSET(26) NUMBITS(1) [],
+        /// Abort request for mailbox 0
+        ABRQ0 OFFSET(7) NUMBITS(1) [],
+        /// Transmission error of mailbox 0
+        TERR0 OFFSET(3) NUMBITS(1) [],
+        /// Arbitration lost for mailbox 0
+        ALST0 OFFSET(2) NUMBITS(1) [],
+        /// Transmission OK of mailbox 0
+        TXOK0 OFFSET(1) NUMBITS(1) [],
+        /// Request completed mailbox 0
+        RQCP0 OFFSET(0) NUMBITS(1) []
+    ],
+    RFR [
+        /// Release FIFO output mailbox
+        RFOM OFFSET(5) NUMBITS(1) [],
+        /// FIFO overrun
+        FOVR OFFSET(4) NUMBITS(1) [],
+        /// FIFO full
+        FULL OFFSET(3) NUMBITS(1) [],
+        /// FIFO message pending
+        FMP OFFSET(0) NUMBITS(2) []
+    ],
+    IER [
+        /// Error interrupt enable
+        ERRIE OFFSET(15) NUMBITS(1) [],
+        /// Bus-off interrupt enable
+        BOFIE OFFSET(10) NUMBITS(1) [],
+        /// FIFO 0 message pending interrupt enable
+        FMPIE0 OFFSET(1) NUMBITS(1) [],
+        /// Transmit mailbox empty interrupt enable
+        TMEIE OFFSET(0) NUMBITS(1) []
+    ],
+    BTR [
+        /// Silent mode (debug)
+        SILM OFFSET(31) NUMBITS(1) [],
+        /// Loop back mode (debug)
+        LBKM OFFSET(30) NUMBITS(1) [],
+        /// Resynchronization jump width
+        SJW OFFSET(24) NUMBITS(2) [],
+        /// Time segment 2
+        TS2 OFFSET(20) NUMBITS(3) [],
+        /// Time segment 1
+        TS1 OFFSET(16) NUMBITS(4) [],
+        /// Baud rate prescaler
+        BRP OFFSET(0) NUMBITS(10) []
+    ],
+    TIR [
+        /// Standard identifier or extended identifier
+        STID OFFSET(21) NUMBITS(11) [],
+        /// Extended identifier
+        EXID OFFSET(3) NUMBITS(18) [],
+        /// Identifier extension
+        IDE OFFSET(2) NUMBITS(1) [],
+        /// Remote transmission request
+        RTR OFFSET(1) NUMBITS(1) [],
+        /// Transmit mailbox request
+        TXRQ OFFSET(0) NUMBITS(1) []
+    ],
+    TDTR [
+        /// Transmit global time
+        TGT OFFSET(8) NUMBITS(1) [],
+        /// Data length code
+        DLC OFFSET(0) NUMBITS(4) []
+    ],
+    RIR [
+        /// Standard identifier or extended identifier
+        STID OFFSET(21) NUMBITS(11) [],
+        /// Extended identifier
+        EXID OFFSET(3) NUMBITS(18) [],
+        /// Identifier extension
+        IDE OFFSET(2) NUMBITS(1) [],
+        /// Remote transmission request
+        RTR OFFSET(1) NUMBITS(1) []
+    ],
+    RDTR [
+        /// Filter match index
+        FMI OFFSET(8) NUMBITS(8) [],
+        /// Data length code
+        DLC OFFSET(0) NUMBITS(4) []
+    ],
+    FMR [
+        /// CAN2 start bank
+        CAN2SB OFFSET(8) NUMBITS(6) [],
+        /// Filter initialization mode
+        FINIT OFFSET(0) NUMBITS(1) []
+    ]
+];
+
+const CAN1_BASE: StaticRef<CanRegisters> =
+    unsafe { StaticRef::new(0x4000_6400 as *const CanRegisters) };
+
+/// The filter banks of CAN1, with the rest left to CAN2.
+const NUM_FILTERS: usize = 14;
+
+/// How many times a mode change is polled for before giving up.
+const MODE_CHANGE_TRIES: usize = 100_000;
+
+/// The identifier extension bit of filter registers.
+const FILTER_IDE: u32 = 1 << 2;
+
+pub struct Can<'a> {
+    registers: StaticRef<CanRegisters>,
+    clock: CanClock,
+    /// The frequency of APB1, which clocks the controller.
+    clock_frequency: Cell<u32>,
+    enabled: Cell<bool>,
+    sending: Cell<bool>,
+    tx_client: OptionalCell<&'a dyn can::TransmitClient>,
+    rx_client: OptionalCell<&'a dyn can::ReceiveClient>,
+}
+
+pub static mut CAN1: Can = Can::new(
+    CAN1_BASE,
+    CanClock(rcc::PeripheralClock::APB1(rcc::PCLK1::CAN1)),
+);
+
+impl Can<'_> {
+    const fn new(base_addr: StaticRef<CanRegisters>, clock: CanClock) -> Self {
+        Self {
+            registers: base_addr,
+            clock,
+            clock_frequency: Cell::new(16_000_000),
+            enabled: Cell::new(false),
+            sending: Cell::new(false),
+            tx_client: OptionalCell::empty(),
+            rx_client: OptionalCell::empty(),
+        }
+    }
+
+    /// Set the frequency of APB1 that bitrates are computed from, which is
+    /// that of the 16 MHz HSI until the board changes it.
+    pub fn set_clock_frequency(&self, hz: u32) {
+        self.clock_frequency.set(hz);
+    }
+
+    pub fn is_enabled_clock(&self) -> bool {
+        self.clock.is_enabled()
+    }
+
+    pub fn enable_clock(&self) {
+        self.clock.enable();
+    }
+
+    pub fn disable_clock(&self) {
+        self.clock.disable();
+    }
+
+    /// Request initialization mode, or normal mode if `!init`, and wait for
+    /// the controller to acknowledge it.
+    fn request_init(&self, init: bool) -> ReturnCode {
+        if init {
+            self.registers
+                .mcr
+                .modify(MCR::INRQ::SET + MCR::SLEEP::CLEAR);
+        } else {
+            self.registers
+                .mcr
+                .modify(MCR::INRQ::CLEAR + MCR::SLEEP::CLEAR);
+        }
+        for _ in 0..MODE_CHANGE_TRIES {
+            if self.registers.msr.is_set(MSR::INAK) == init {
+                return ReturnCode::SUCCESS;
+            }
+        }
+        ReturnCode::FAIL
+    }
+
+    /// The bit timing for `bitrate`, with 8 to 20 time quanta per bit and
+    /// the sample point near 87.5%.
+    fn bit_timing(&self, bitrate: u32) -> Option<(u32, u32, u32)> {
+        if bitrate == 0 {
+            return None;
+        }
+        let clock = self.clock_frequency.get();
+        (8..=20).rev().find_map(|quanta: u32| {
+            let ticks = bitrate.checked_mul(quanta)?;
+            if clock % ticks != 0 || clock / ticks == 0 || clock / ticks > 1024 {
+                return None;
+            }
+            let ts2 = (quanta + 4) / 8;
+            let ts1 = quanta - 1 - ts2;
+            if ts1 > 16 || ts2 > 8 {
+                return None;
+            }
+            Some((clock / ticks, ts1, ts2))
+        })
+    }
+
+    pub fn handle_transmit_interrupt(&self) {
+        if !self.registers.tsr.is_set(TSR::RQCP0) {
+            return;
+        }
+        let result = if self.registers.tsr.is_set(TSR::TXOK0) {
+            ReturnCode::SUCCESS
+        } else {
+            ReturnCode::ECANCEL
+        };
+        self.registers.tsr.write(TSR::RQCP0::SET);
+        if self.sending.replace(false) {
+            self.tx_client
+                .map(|client| client.transmit_complete(result));
+        }
+    }
+
+    pub fn handle_fifo0_interrupt(&self) {
+        while self.registers.rf0r.read(RFR::FMP) != 0 {
+            let mailbox = &self.registers.rx[0];
+            let rir = mailbox.rir.extract();
+            let rdtr = mailbox.rdtr.extract();
+            let id = if rir.is_set(RIR::IDE) {
+                Id::Extended(rir.read(RIR::STID) << 18 | rir.read(RIR::EXID))
+            } else {
+                Id::Standard(rir.read(RIR::STID) as u16)
+            };
+            let mut data = [0; can::MAX_DATA_LEN];
+            data[..4].copy_from_slice(&mailbox.rdlr.get().to_le_bytes());
+            data[4..].copy_from_slice(&mailbox.rdhr.get().to_le_bytes());
+            let frame = Frame {
+                id: id,
+                remote: rir.is_set(RIR::RTR),
+                len: core::cmp::min(rdtr.read(RDTR::DLC) as u8, can::MAX_DATA_LEN as u8),
+                data: data,
+            };
+            let filter = rdtr.read(RDTR::FMI) as usize;
+            self.registers.rf0r.write(RFR::RFOM::SET);
+            self.rx_client
+                .map(|client| client.frame_received(frame, filter));
+        }
+    }
+}
+
+impl<'a> can::Can<'a> for Can<'a> {
+    fn set_transmit_client(&self, client: &'a dyn can::TransmitClient) {
+        self.tx_client.set(client);
+    }
+
+    fn set_receive_client(&self, client: &'a dyn can::ReceiveClient) {
+        self.rx_client.set(client);
+    }
+
+    fn enable(&self, bitrate: u32) -> ReturnCode {
+        let (prescaler, ts1, ts2) = match self.bit_timing(bitrate) {
+            Some(timing) => timing,
+            None => return ReturnCode::EINVAL,
+        };
+        if !self.is_enabled_clock() {
+            self.enable_clock();
+        }
+        let rcode = self.request_init(true);
+        if rcode != ReturnCode::SUCCESS {
+            return rcode;
+        }
+        self.registers.btr.write(
+            BTR::BRP.val(prescaler - 1)
+                + BTR::TS1.val(ts1 - 1)
+                + BTR::TS2.val(ts2 - 1)
+                + BTR::SJW.val(0),
+        );
+        self.registers
+            .mcr
+            .modify(MCR::ABOM::SET + MCR::TXFP::SET + MCR::NART::CLEAR + MCR::DBF::CLEAR);
+        self.registers.ier.write(IER::TMEIE::SET + IER::FMPIE0::SET);
+        let rcode = self.request_init(false);
+        self.enabled.set(rcode == ReturnCode::SUCCESS);
+        rcode
+    }
+
+    fn disable(&self) -> ReturnCode {
+        self.registers.ier.set(0);
+        let rcode = self.request_init(true);
+        self.enabled.set(false);
+        if self.sending.replace(false) {
+            self.registers.tsr.write(TSR::ABRQ0::SET);
+            self.tx_client
+                .map(|client| client.transmit_complete(ReturnCode::ECANCEL));
+        }
+        rcode
+    }
+
+    fn filter_count(&self) -> usize {
+        NUM_FILTERS
+    }
+
+    fn set_filter(&self, index: usize, filter: Option<Filter>) -> ReturnCode {
+        if index >= NUM_FILTERS {
+            return ReturnCode::EINVAL;
+        }
+        // The identifier and mask, in the layout of `RIR`.
+        let registers = filter.map(|filter| match filter.id {
+            Id::Standard(id) => (
+                (id as u32) << 21,
+                (filter.mask & can::MAX_STANDARD_ID as u32) << 21 | FILTER_IDE,
+                id <= can::MAX_STANDARD_ID,
+            ),
+            Id::Extended(id) => (
+                id << 3 | FILTER_IDE,
+                (filter.mask & can::MAX_EXTENDED_ID) << 3 | FILTER_IDE,
+                id <= can::MAX_EXTENDED_ID,
+            ),
+        });
+        if let Some((_, _, false)) = registers {
+            return ReturnCode::EINVAL;
+        }
+        if !self.is_enabled_clock() {
+            self.enable_clock();
+        }
+
+        let bit = 1 << index;
+        self.registers.fmr.modify(FMR::FINIT::SET);
+        self.registers
+            .fmr
+            .modify(FMR::CAN2SB.val(NUM_FILTERS as u32));
+        self.registers.fa1r.set(self.registers.fa1r.get() & !bit);
+        if let Some((id, mask, _)) = registers {
+            // A 32 bit filter in mask mode, for FIFO 0.
+            self.registers.fm1r.set(self.registers.fm1r.get() & !bit);
+            self.registers.fs1r.set(self.registers.fs1r.get() | bit);
+            self.registers.ffa1r.set(self.registers.ffa1r.get() & !bit);
+            self.registers.filters[index].fr1.set(id);
+            self.registers.filters[index].fr2.set(mask);
+            self.registers.fa1r.set(self.registers.fa1r.get() | bit);
+        }
+        self.registers.fmr.modify(FMR::FINIT::CLEAR);
+        ReturnCode::SUCCESS
+    }
+
+    fn send(&self, frame: Frame) -> ReturnCode {
+        if !self.enabled.get() {
+            return ReturnCode::EOFF;
+        }
+        if self.sending.get() || !self.registers.tsr.is_set(TSR::TME0) {
+            return ReturnCode::EBUSY;
+        }
+        let id = match frame.id {
+            Id::Standard(id) if id <= can::MAX_STANDARD_ID => TIR::STID.val(id as u32),
+            Id::Extended(id) if id <= can::MAX_EXTENDED_ID => {
+                TIR::STID.val(id >> 18) + TIR::EXID.val(id) + TIR::IDE::SET
+            }
+            _ => return ReturnCode::EINVAL,
+        };
+        if frame.len as usize > can::MAX_DATA_LEN {
+            return ReturnCode::ESIZE;
+        }
+        let mailbox = &self.registers.tx[0];
+        mailbox.tdtr.write(TDTR::DLC.val(frame.len as u32));
+        let mut low = [0; 4];
+        let mut high = [0; 4];
+        low.copy_from_slice(&frame.data[..4]);
+        high.copy_from_slice(&frame.data[4..]);
+        mailbox.tdlr.set(u32::from_le_bytes(low));
+        mailbox.tdhr.set(u32::from_le_bytes(high));
+        self.sending.set(true);
+        mailbox
+            .tir
+            .write(id + TIR::RTR.val(frame.remote as u32) + TIR::TXRQ::SET);
+        ReturnCode::SUCCESS
+    }
+}
+
+struct CanClock(rcc::PeripheralClock);
+
+impl ClockInterface for CanClock {
+    fn is_enabled(&self) -> bool {
+        self.0.is_enabled()
+    }
+
+    fn enable(&self) {
+        self.0.enable();
+    }
+
+    fn disable(&self) {
+        self.0.disable();
+    }
+}
@@ -5,6 +5,7 @@ use cortexm4;
 use kernel::Chip;
 
 use crate::adc;
+use crate::can;
 use crate::dma1;
 use crate::exti;
 use crate::i2c;
@@ -69,6 +70,9 @@ impl Chip for Stm32f4xx {
 
                         nvic::SPI3 => spi::SPI3.handle_interrupt(),
 
+                        nvic::CAN1_TX => can::CAN1.handle_transmit_interrupt(),
+                        nvic::CAN1_RX0 => can::CAN1.handle_fifo0_interrupt(),
+
                         nvic::EXTI0 => exti::EXTI.handle_interrupt(),
                         nvic::EXTI1 => exti::EXTI.handle_interrupt(),
                         nvic::EXTI2 => exti::EXTI.handle_interrupt(),
@@ -12,6 +12,7 @@ pub mod nvic;
 
 // Peripherals
 pub mod adc;
+pub mod can;
 pub mod dbg;
 pub mod dma1;
 pub mod exti;
@@ -751,6 +751,22 @@ impl Rcc {
         self.registers.apb1enr.modify(APB1ENR::I2C1EN::CLEAR)
     }
 
+    // CAN1 clock
+
+    fn is_enabled_can1_clock(&self) -> bool {
+        self.registers.apb1enr.is_set(APB1ENR::CAN1EN)
+    }
+
+    fn enable_can1_clock(&self) {
+        self.registers.apb1enr.modify(APB1ENR::CAN1EN::SET);
+        self.registers.apb1rstr.modify(APB1RSTR::CAN1RST::SET);
+        self.registers.apb1rstr.modify(APB1RSTR::CAN1RST::CLEAR);
+    }
+
+    fn disable_can1_clock(&self) {
+        self.registers.apb1enr.modify(APB1ENR::CAN1EN::CLEAR)
+    }
+
     // SPI3 clock
 
     fn is_enabled_spi3_clock(&self) -> bool {
@@ -1002,6 +1018,7 @@ pub enum PCLK1 {
     USART3,
     SPI3,
     I2C1,
+    CAN1,
 }
 
 /// Peripherals clocked by PCLK2
@@ -1030,6 +1047,7 @@ impl ClockInterface for PeripheralClock {
                 PCLK1::USART3 => unsafe { RCC.is_enabled_usart3_clock() },
                 PCLK1::I2C1 => unsafe { RCC.is_enabled_i2c1_clock() },
                 PCLK1::SPI3 => unsafe { RCC.is_enabled_spi3_clock() },
+                PCLK1::CAN1 => unsafe { RCC.is_enabled_can1_clock() },
             },
             &PeripheralClock::APB2(ref v) => match v {
                 PCLK2::ADC1 => unsafe { RCC.is_enabled_adc1_clock() },
@@ -1085,6 +1103,9 @@ impl ClockInterface for PeripheralClock {
                 PCLK1::SPI3 => unsafe {
                     RCC.enable_spi3_clock();
                 },
+                PCLK1::CAN1 => unsafe {
+                    RCC.enable_can1_clock();
+                },
             },
             &PeripheralClock::APB2(ref v) => match v {
                 PCLK2::ADC1 => unsafe {
@@ -1144,6 +1165,9 @@ impl ClockInterface for PeripheralClock {
                 PCLK1::SPI3 => unsafe {
                     RCC.disable_spi3_clock();
                 },
+                PCLK1::CAN1 => unsafe {
+                    RCC.disable_can1_clock();
+                },
             },
             &PeripheralClock::APB2(ref v) => match v {
                 PCLK2::ADC1 => unsafe {
@@ -140,6 +140,22 @@ impl AppId {
         self.identifier
     }
 
+    /// Returns the name of the app from its TBF header, or an empty string if
+    /// the app no longer exists. Unlike `id()`, the name stays the same across
+    /// reboots, so drivers can use it to find the persistent state of an app.
+    pub fn get_process_name(&self) -> &'static str {
+        self.kernel
+            .process_map_or("", *self, |process| process.get_process_name())
+    }
+
+    /// Attribute `nanojoules` of energy to this app. Drivers call this to
+    /// charge an app for the peripherals they use on its behalf, typically
+    /// with `power::energy_nj()`.
+    pub fn charge_energy(&self, nanojoules: u64) {
+        self.kernel
+            .process_map_or((), *self, |process| process.charge_energy(nanojoules));
+    }
+
     /// Returns the full address of the start and end of the flash region that
     /// the app owns and can write to. This includes the app's code and data and
     /// any padding at the end of the app. It does not include the TBF header,
@@ -0,0 +1,158 @@
+//! Runtime scaling of the system clock frequency.
+//!
+//! A board that spends most of its time idle can run the core at a low
+//! frequency and boost it for bursts of work. Chips that can do this implement
+//! `FrequencyControl`. The board wraps that in a `ClockManager`, which is the
+//! only thing that should change the frequency once the kernel is running.
+//!
+//! Drivers whose timing is derived from the system clock, such as baud rate
+//! generators, subscribe to the manager with a `ClockSubscriber`. Before the
+//! frequency changes, every listener is asked whether it can tolerate a change
+//! right now, so that a transfer in flight is not corrupted. Once the clock
+//! has switched, every listener is told the new frequency so it can
+//! recompute its dividers.
+//!
+//! Usage
+//! -----
+//!
+//! ```ignore
+//! # use kernel::static_init;
+//! # use kernel::clock_manager::{ClockManager, ClockSubscriber};
+//!
+//! let clock_manager = static_init!(
+//!     ClockManager<'static>,
+//!     ClockManager::new(&sam4l::pm::PM)
+//! );
+//! let usart0_clock = static_init!(
+//!     ClockSubscriber<'static>,
+//!     ClockSubscriber::new(&sam4l::usart::USART0)
+//! );
+//! clock_manager.subscribe(usart0_clock);
+//!
+//! // Run slowly while idle:
+//! clock_manager.set_frequency(clock_manager.lowest_frequency());
+//! ```
+
+use crate::common::{List, ListLink, ListNode};
+use crate::returncode::ReturnCode;
+
+/// Implemented by chips whose system clock frequency can change at runtime.
+pub trait FrequencyControl {
+    /// The frequencies, in Hz, the system clock can switch between, from
+    /// slowest to fastest.
+    fn frequencies(&self) -> &'static [u32];
+
+    /// The current system clock frequency in Hz.
+    fn frequency(&self) -> u32;
+
+    /// Switch the system clock to `hz`, which must be one of
+    /// `frequencies()`. Returns once the new clock is in use.
+    fn set_frequency(&self, hz: u32) -> ReturnCode;
+}
+
+/// Implemented by drivers whose timing depends on the system clock.
+pub trait ClockListener {
+    /// Whether the frequency may change right now. This must not have side
+    /// effects, as the change may still be refused by another listener.
+    fn can_change_frequency(&self) -> bool {
+        true
+    }
+
+    /// The system clock now runs at `hz`.
+    fn frequency_changed(&self, hz: u32);
+}
+
+/// Links one `ClockListener` into a `ClockManager`.
+pub struct ClockSubscriber<'a> {
+    listener: &'a dyn ClockListener,
+    next: ListLink<'a, ClockSubscriber<'a>>,
+}
+
+impl<'a> ListNode<'a, ClockSubscriber<'a>> for ClockSubscriber<'a> {
+    fn next(&'a self) -> &'a ListLink<'a, ClockSubscriber<'a>> {
+        &self.next
+    }
+}
+
+impl<'a> ClockSubscriber<'a> {
+    pub const fn new(listener: &'a dyn ClockListener) -> ClockSubscriber<'a> {
+        ClockSubscriber {
+            listener: listener,
+            next: ListLink::empty(),
+        }
+    }
+}
+
+pub struct ClockManager<'a> {
+    control: &'a dyn FrequencyControl,
+    subscribers: List<'a, ClockSubscriber<'a>>,
+}
+
+impl<'a> ClockManager<'a> {
+    pub const fn new(control: &'a dyn FrequencyControl) -> ClockManager<'a> {
+        ClockManager {
+            control: control,
+            subscribers: List::new(),
+        }
+    }
+
+    /// Tell `subscriber` about frequency changes. Each subscriber must only
+    /// be added once.
+    pub fn subscribe(&self, subscriber: &'a ClockSubscriber<'a>) {
+        self.subscribers.push_head(subscriber);
+    }
+
+    /// The current system clock frequency in Hz.
+    pub fn frequency(&self) -> u32 {
+        self.control.frequency()
+    }
+
+    /// The frequencies the system clock supports, from slowest to fastest.
+    pub fn frequencies(&self) -> &'static [u32] {
+        self.control.frequencies()
+    }
+
+    pub fn lowest_frequency(&self) -> u32 {
+        self.frequencies()
+            .first()
+            .copied()
+            .unwrap_or(self.frequency())
+    }
+
+    pub fn highest_frequency(&self) -> u32 {
+        self.frequencies()
+            .last()
+            .copied()
+            .unwrap_or(self.frequency())
+    }
+
+    /// Switch the system clock to `hz` and notify every subscriber.
+    ///
+    /// Returns `EINVAL` if the chip does not support `hz`, and `EBUSY`,
+    /// without changing the clock, if a subscriber cannot tolerate a change
+    /// right now.
+    pub fn set_frequency(&self, hz: u32) -> ReturnCode {
+        if !self.frequencies().contains(&hz) {
+            return ReturnCode::EINVAL;
+        }
+        if hz == self.frequency() {
+            return ReturnCode::SUCCESS;
+        }
+        if !self
+            .subscribers
+            .iter()
+            .all(|subscriber| subscriber.listener.can_change_frequency())
+        {
+            return ReturnCode::EBUSY;
+        }
+
+        let rcode = self.control.set_frequency(hz);
+        if rcode == ReturnCode::SUCCESS {
+            let now = self.frequency();
+            for subscriber in self.subscribers.iter() {
+                subscriber.listener.frequency_changed(now);
+            }
+        }
+        rcode
+    }
+}
@@ -0,0 +1,213 @@
+//! Constant-time helpers for code that handles secrets.
+//!
+//! Comparing a received MAC or password against the expected value with `==`
+//! or `Iterator::all` returns as soon as the first byte differs, which leaks
+//! how many leading bytes were correct through timing. The functions here
+//! always touch every byte and avoid data-dependent branches, so crypto
+//! capsules and credential checkers can share one audited implementation.
+//!
+//! Secrets should also be erased once they are no longer needed. A plain
+//! assignment of zeros to a buffer that is never read again may be optimized
+//! away, so `wipe()` uses volatile writes. `Secret` wraps a buffer and wipes
+//! it on request and when it is dropped.
+//!
+//! ```rust
+//! use kernel::common::constant_time;
+//!
+//! let expected = [1, 2, 3, 4];
+//! let received = [1, 2, 3, 5];
+//! assert!(!constant_time::eq(&expected, &received));
+//! ```
+
+use core::ops::{Deref, DerefMut};
+use core::ptr;
+use core::sync::atomic::{compiler_fence, Ordering};
+
+/// The result of a constant-time comparison. Internally this is either 0 or
+/// 1, never a `bool`, so that the compiler does not turn uses of it into
+/// branches.
+#[derive(Copy, Clone, Debug)]
+pub struct Choice(u8);
+
+impl Choice {
+    /// Create a `Choice` from a value that must be 0 or 1.
+    pub fn from_bit(bit: u8) -> Choice {
+        Choice(bit & 1)
+    }
+
+    /// Returns the underlying 0 or 1. Branching on the result is where
+    /// timing-dependent behavior may begin, so only do so once the full
+    /// computation is done.
+    pub fn unwrap_u8(self) -> u8 {
+        self.0
+    }
+
+    pub fn and(self, other: Choice) -> Choice {
+        Choice(self.0 & other.0)
+    }
+
+    pub fn or(self, other: Choice) -> Choice {
+        Choice(self.0 | other.0)
+    }
+
+    pub fn not(self) -> Choice {
+        Choice(!self.0 & 1)
+    }
+}
+
+impl From<Choice> for bool {
+    fn from(choice: Choice) -> bool {
+        choice.0 != 0
+    }
+}
+
+/// Types that can be selected between without branching on the condition.
+pub trait ConditionallySelectable: Copy {
+    /// Returns `a` if `choice` is 0 and `b` if `choice` is 1.
+    fn select(a: Self, b: Self, choice: Choice) -> Self;
+}
+
+macro_rules! impl_select {
+    ($t:ty) => {
+        impl ConditionallySelectable for $t {
+            fn select(a: $t, b: $t, choice: Choice) -> $t {
+                // All ones if choice is 1, all zeros otherwise.
+                let mask = (0 as $t).wrapping_sub(choice.0 as $t);
+                a ^ (mask & (a ^ b))
+            }
+        }
+    };
+}
+
+impl_select!(u8);
+impl_select!(u16);
+impl_select!(u32);
+impl_select!(u64);
+impl_select!(usize);
+
+/// Returns 1 if `a == b`, 0 otherwise, in constant time.
+pub fn byte_eq(a: u8, b: u8) -> Choice {
+    let x = a ^ b;
+    // The high bit of `x | -x` is set iff `x` is nonzero.
+    let nonzero = ((x | x.wrapping_neg()) >> 7) & 1;
+    Choice(nonzero ^ 1)
+}
+
+/// Constant-time comparison of two byte slices.
+///
+/// The running time depends only on the length of the slices, not on their
+/// contents. Slices of different lengths are never equal; lengths are not
+/// considered secret.
+pub fn slices_eq(a: &[u8], b: &[u8]) -> Choice {
+    if a.len() != b.len() {
+        return Choice(0);
+    }
+    let mut diff = 0u8;
+    for (x, y) in a.iter().zip(b.iter()) {
+        diff |= x ^ y;
+    }
+    byte_eq(diff, 0)
+}
+
+/// Convenience wrapper around `slices_eq()` that returns a `bool`.
+pub fn eq(a: &[u8], b: &[u8]) -> bool {
+    slices_eq(a, b).into()
+}
+
+/// Copy `src` into `dst` if `choice` is 1, leave `dst` unchanged otherwise.
+/// Both slices must have the same length.
+pub fn conditional_copy(dst: &mut [u8], src: &[u8], choice: Choice) {
+    for (d, s) in dst.iter_mut().zip(src.iter()) {
+        *d = u8::select(*d, *s, choice);
+    }
+}
+
+/// Overwrite `buf` with zeros in a way the compiler will not elide.
+pub fn wipe(buf: &mut [u8]) {
+    for b in buf.iter_mut() {
+        // Safe because `b` is a valid, aligned, exclusive reference.
+        unsafe { ptr::write_volatile(b, 0) };
+    }
+    compiler_fence(Ordering::SeqCst);
+}
+
+/// A buffer holding secret material that is wiped when dropped.
+///
+/// Kernel buffers are usually `'static` and never dropped, so holders should
+/// also call `wipe()` explicitly when the secret is no longer needed.
+pub struct Secret<T: AsMut<[u8]>> {
+    inner: T,
+}
+
+impl<T: AsMut<[u8]>> Secret<T> {
+    pub const fn new(inner: T) -> Secret<T> {
+        Secret { inner: inner }
+    }
+
+    /// Zero the contents of the secret.
+    pub fn wipe(&mut self) {
+        wipe(self.inner.as_mut());
+    }
+}
+
+impl<T: AsMut<[u8]>> Deref for Secret<T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<T: AsMut<[u8]>> DerefMut for Secret<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+impl<T: AsMut<[u8]>> Drop for Secret<T> {
+    fn drop(&mut self) {
+        self.wipe();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_byte_eq() {
+        for a in 0..=255u8 {
+            assert_eq!(byte_eq(a, a).unwrap_u8(), 1);
+            assert_eq!(byte_eq(a, a.wrapping_add(1)).unwrap_u8(), 0);
+        }
+    }
+
+    #[test]
+    fn test_slices_eq() {
+        assert!(eq(&[], &[]));
+        assert!(eq(&[1, 2, 3], &[1, 2, 3]));
+        assert!(!eq(&[1, 2, 3], &[1, 2, 4]));
+        assert!(!eq(&[0x80, 2, 3], &[0, 2, 3]));
+        assert!(!eq(&[1, 2, 3], &[1, 2]));
+    }
+
+    #[test]
+    fn test_select() {
+        assert_eq!(u32::select(5, 9, Choice::from_bit(0)), 5);
+        assert_eq!(u32::select(5, 9, Choice::from_bit(1)), 9);
+        assert_eq!(u8::select(0xff, 0, Choice::from_bit(1)), 0);
+
+        let mut dst = [1, 2, 3];
+        conditional_copy(&mut dst, &[4, 5, 6], Choice::from_bit(0));
+        assert_eq!(dst, [1, 2, 3]);
+        conditional_copy(&mut dst, &[4, 5, 6], Choice::from_bit(1));
+        assert_eq!(dst, [4, 5, 6]);
+    }
+
+    #[test]
+    fn test_wipe() {
+        let mut secret = Secret::new([0xaa; 8]);
+        assert_eq!(*secret, [0xaa; 8]);
+        secret.wipe();
+        assert_eq!(*secret, [0; 8]);
+    }
+}
@@ -17,7 +17,14 @@
 //! [DeferredCallHandle](crate::common::dynamic_deferred_call::DeferredCallHandle)s
 //! may be registered with the instance.
 //! When no more slots are available,
-//! `dynamic_deferred_call.register(some_client)` will return `None`.
+//! `dynamic_deferred_call.register(some_client)` will return `None`. A client
+//! that is done with its deferred call, for example a capsule that is torn
+//! down, can `unregister` its handle to free the slot for another client.
+//!
+//! Capsules, including those out of tree, therefore need no entry in a kernel
+//! enum: they are given the `DynamicDeferredCall` at board init, keep the
+//! handle `register` returns, and `set` it whenever they want to be called
+//! back from the main loop.
 //!
 //! ```
 //! # use core::cell::Cell;
@@ -184,24 +191,58 @@ impl DynamicDeferredCall {
     /// Register a new client
     ///
     /// On success, a `Some(handle)` will be returned. This handle is later
-    /// required to schedule a deferred call.
+    /// required to schedule a deferred call. Returns `None` if every slot of
+    /// the backing array is taken.
     pub fn register(
         &self,
         ddc_client: &'static dyn DynamicDeferredCallClient,
     ) -> Option<DeferredCallHandle> {
+        // Slots freed by `unregister` are reused before new ones.
         let current_counter = self.handle_counter.get();
+        let free = (0..current_counter)
+            .find(|&i| self.client_states[i].client.is_none())
+            .or_else(|| {
+                if current_counter < self.client_states.len() {
+                    self.handle_counter.set(current_counter + 1);
+                    Some(current_counter)
+                } else {
+                    None
+                }
+            });
 
-        if current_counter < self.client_states.len() {
-            let client_state = &self.client_states[current_counter];
+        free.map(|pos| {
+            let client_state = &self.client_states[pos];
             client_state.scheduled.set(false);
             client_state.client.set(ddc_client);
+            DeferredCallHandle(pos)
+        })
+    }
 
-            self.handle_counter.set(current_counter + 1);
+    /// Unregister the client of `handle`, cancelling its scheduled call if
+    /// there is one, so that its slot can be registered again
+    ///
+    /// The handle must not be used afterwards: once the slot is reused, it
+    /// addresses the new client. Returns `false` if no client was registered
+    /// for the handle.
+    pub fn unregister(&self, handle: DeferredCallHandle) -> bool {
+        let DeferredCallHandle(client_pos) = handle;
+        let client_state = &self.client_states[client_pos];
+        client_state.scheduled.set(false);
+        client_state.client.take().is_some()
+    }
 
-            Some(DeferredCallHandle(current_counter))
-        } else {
-            None
-        }
+    /// Cancel a scheduled deferred call
+    ///
+    /// Returns `true` if a call was scheduled for the handle.
+    pub fn cancel(&self, handle: DeferredCallHandle) -> bool {
+        let DeferredCallHandle(client_pos) = handle;
+        self.client_states[client_pos].scheduled.replace(false)
+    }
+
+    /// Check if a deferred call is scheduled for the handle
+    pub fn is_scheduled(&self, handle: DeferredCallHandle) -> bool {
+        let DeferredCallHandle(client_pos) = handle;
+        self.client_states[client_pos].scheduled.get()
     }
 
     /// Check if one or more deferred calls are pending
@@ -48,10 +48,19 @@
 //! Yes the code gets here with value 42
 //! TOCK_DEBUG(0): /tock/capsules/src/sensys.rs:24: got here
 //! ```
+//!
+//! Boards can also place a canary at the base of the kernel stack so that a
+//! kernel stack overflow is reported as such, rather than showing up later as
+//! corrupted memory:
+//!
+//! ```ignore
+//! kernel::debug::set_stack_canary(STACK_MEMORY.as_mut_ptr());
+//! ```
 
 use core::cell::Cell;
 use core::fmt::{write, Arguments, Result, Write};
 use core::panic::PanicInfo;
+use core::ptr;
 use core::str;
 
 use crate::common::cells::NumericCellExt;
@@ -101,6 +110,7 @@ pub unsafe fn panic<L: hil::led::Led, W: Write + IoWrite, C: Chip>(
 ) -> ! {
     panic_begin(nop);
     panic_banner(writer, panic_info);
+    panic_stack_canary(writer);
     // Flush debug buffer if needed
     flush(writer);
     panic_cpu_state(chip, writer);
@@ -133,6 +143,18 @@ pub unsafe fn panic_banner<W: Write>(writer: &mut W, panic_info: &PanicInfo) {
     ));
 }
 
+/// Report whether the kernel stack canary was overwritten.
+///
+/// **NOTE:** The supplied `writer` must be synchronous.
+pub unsafe fn panic_stack_canary<W: Write>(writer: &mut W) {
+    if !stack_canary_intact() {
+        let _ = writer.write_fmt(format_args!(
+            "\tKernel stack overflow: canary at {:#x} overwritten\r\n",
+            STACK_CANARY_BASE as usize
+        ));
+    }
+}
+
 /// Print current machine (CPU) state.
 ///
 /// **NOTE:** The supplied `writer` must be synchronous.
@@ -192,6 +214,54 @@ pub fn panic_blink_forever<L: hil::led::Led>(leds: &mut [&mut L]) -> ! {
 // panic! support routines
 ///////////////////////////////////////////////////////////////////
 
+///////////////////////////////////////////////////////////////////
+// kernel stack canary
+
+/// Pattern written to the lowest bytes of the kernel stack.
+const STACK_CANARY: [u8; 16] = [
+    0x57, 0xac, 0xca, 0x7e, 0xde, 0xad, 0xbe, 0xef, 0x57, 0xac, 0xca, 0x7e, 0xde, 0xad, 0xbe, 0xef,
+];
+
+/// Lowest address of the kernel stack, or null if the board has not set a
+/// canary.
+static mut STACK_CANARY_BASE: *mut u8 = ptr::null_mut();
+
+/// Write the stack canary at `stack_base`, the lowest address of the kernel
+/// stack (for most boards, `STACK_MEMORY.as_mut_ptr()`).
+///
+/// The kernel then checks the canary each time a process stops executing and
+/// when it panics. Boards should call this early in `reset_handler()`, before
+/// the kernel stack can have grown down to its base.
+pub unsafe fn set_stack_canary(stack_base: *mut u8) {
+    for (i, byte) in STACK_CANARY.iter().enumerate() {
+        ptr::write_volatile(stack_base.add(i), *byte);
+    }
+    STACK_CANARY_BASE = stack_base;
+}
+
+/// Returns `false` if a stack canary was set and has since been overwritten.
+pub fn stack_canary_intact() -> bool {
+    unsafe {
+        if STACK_CANARY_BASE.is_null() {
+            return true;
+        }
+        STACK_CANARY
+            .iter()
+            .enumerate()
+            .all(|(i, byte)| ptr::read_volatile(STACK_CANARY_BASE.add(i)) == *byte)
+    }
+}
+
+/// Panic if the kernel stack canary has been overwritten.
+pub(crate) fn check_stack_canary() {
+    if !stack_canary_intact() {
+        panic!("Kernel stack overflow");
+    }
+}
+
+// kernel stack canary
+///////////////////////////////////////////////////////////////////
+
 ///////////////////////////////////////////////////////////////////
 // debug_gpio! support
 
@@ -0,0 +1,53 @@
+//! Interface for battery fuel gauges.
+//!
+//! A fuel gauge estimates how much energy is left in a battery. All readings
+//! are split-phase: a `read_*` call starts the measurement and the result is
+//! delivered to the `BatteryClient`. A gauge can also raise an alert when the
+//! state of charge falls below a threshold.
+
+use crate::returncode::ReturnCode;
+
+/// Whether the battery is being charged.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChargingState {
+    /// The gauge cannot tell, for example because no current is flowing and
+    /// the battery is not full.
+    Unknown = 0,
+    Discharging = 1,
+    Charging = 2,
+    Full = 3,
+}
+
+pub trait Battery<'a> {
+    fn set_client(&self, client: &'a dyn BatteryClient);
+
+    /// Read the state of charge, reported in hundredths of a percent.
+    fn read_state_of_charge(&self) -> ReturnCode;
+
+    /// Read the battery voltage, reported in millivolts.
+    fn read_voltage(&self) -> ReturnCode;
+
+    /// Read whether the battery is charging.
+    fn read_charging_state(&self) -> ReturnCode;
+
+    /// Raise `BatteryClient::low_battery` when the state of charge falls
+    /// below `percent`. Returns `EINVAL` if the gauge does not support that
+    /// threshold. Completion is reported with `threshold_set`.
+    fn set_low_battery_threshold(&self, percent: u8) -> ReturnCode;
+}
+
+pub trait BatteryClient {
+    /// State of charge in hundredths of a percent, so 10000 is full.
+    fn state_of_charge(&self, result: Result<u16, ReturnCode>);
+
+    /// Battery voltage in millivolts.
+    fn voltage(&self, result: Result<u32, ReturnCode>);
+
+    fn charging_state(&self, result: Result<ChargingState, ReturnCode>);
+
+    /// The low-battery threshold has been configured.
+    fn threshold_set(&self, result: ReturnCode);
+
+    /// The state of charge fell below the low-battery threshold.
+    fn low_battery(&self);
+}
@@ -49,15 +49,60 @@
 
 use crate::returncode::ReturnCode;
 
+/// The access address of advertising channel packets, Bluetooth Core
+/// Specification Vol. 6, Part B, section 2.1.2.
+pub const ADVERTISING_ACCESS_ADDRESS: u32 = 0x8e89_bed6;
+
+/// The CRC initialization value of advertising channel packets, Bluetooth
+/// Core Specification Vol. 6, Part B, section 3.1.1.
+pub const ADVERTISING_CRC_INIT: u32 = 0x55_5555;
+
 pub trait BleAdvertisementDriver<'a> {
     fn transmit_advertisement(&self, buf: &'static mut [u8], len: usize, channel: RadioChannel);
     fn receive_advertisement(&self, channel: RadioChannel);
     fn set_receive_client(&self, client: &'a dyn RxClient);
     fn set_transmit_client(&self, client: &'a dyn TxClient);
+
+    /// Transmit `buf` on `channel` so that it starts `delay_us` after the
+    /// previous transmission started, for the packets of Bluetooth 5
+    /// extended advertising whose time an earlier packet announced. Must be
+    /// called from `transmit_event` of that previous transmission.
+    ///
+    /// Radios that cannot time transmissions return the buffer with
+    /// `ENOSUPPORT`.
+    fn transmit_advertisement_delayed(
+        &self,
+        buf: &'static mut [u8],
+        _len: usize,
+        _channel: RadioChannel,
+        _delay_us: u32,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        Err((ReturnCode::ENOSUPPORT, buf))
+    }
 }
 
 pub trait BleConfig {
     fn set_tx_power(&self, power: u8) -> ReturnCode;
+
+    /// Use `phy` for the following transmissions and receptions. Radios
+    /// without the Bluetooth 5 PHYs only support `Phy::Le1M`.
+    fn set_phy(&self, phy: Phy) -> ReturnCode {
+        match phy {
+            Phy::Le1M => ReturnCode::SUCCESS,
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// Use `access_address` and `crc_init` for the following transmissions
+    /// and receptions instead of the advertising channel ones, for the
+    /// packets of periodic advertising trains.
+    fn set_access_address(&self, access_address: u32, crc_init: u32) -> ReturnCode {
+        if access_address == ADVERTISING_ACCESS_ADDRESS && crc_init == ADVERTISING_CRC_INIT {
+            ReturnCode::SUCCESS
+        } else {
+            ReturnCode::ENOSUPPORT
+        }
+    }
 }
 
 pub trait RxClient {
@@ -68,6 +113,20 @@ pub trait TxClient {
     fn transmit_event(&self, buf: &'static mut [u8], result: ReturnCode);
 }
 
+// Bluetooth Core Specification:Vol. 6, Part A, section 2 and Part B, section 2.2
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub enum Phy {
+    /// 1 Mb/s, the only PHY before Bluetooth 5.
+    Le1M,
+    /// 2 Mb/s.
+    Le2M,
+    /// 125 kb/s, coded with S = 8 for four times the range of `Le1M`.
+    LeCodedS8,
+    /// 500 kb/s, coded with S = 2. Receivers on the coded PHY get both
+    /// codings.
+    LeCodedS2,
+}
+
 // Bluetooth Core Specification:Vol. 6. Part B, section 1.4.1 Advertising and Data Channel Indices
 #[derive(PartialEq, Debug, Copy, Clone)]
 pub enum RadioChannel {
@@ -158,4 +217,51 @@ impl RadioChannel {
             RadioChannel::AdvertisingChannel39 => 39,
         }
     }
+
+    /// The channel with index `index`, 0 to 39.
+    pub fn from_channel_index(index: u32) -> Option<RadioChannel> {
+        match index {
+            0 => Some(RadioChannel::DataChannel0),
+            1 => Some(RadioChannel::DataChannel1),
+            2 => Some(RadioChannel::DataChannel2),
+            3 => Some(RadioChannel::DataChannel3),
+            4 => Some(RadioChannel::DataChannel4),
+            5 => Some(RadioChannel::DataChannel5),
+            6 => Some(RadioChannel::DataChannel6),
+            7 => Some(RadioChannel::DataChannel7),
+            8 => Some(RadioChannel::DataChannel8),
+            9 => Some(RadioChannel::DataChannel9),
+            10 => Some(RadioChannel::DataChannel10),
+            11 => Some(RadioChannel::DataChannel11),
+            12 => Some(RadioChannel::DataChannel12),
+            13 => Some(RadioChannel::DataChannel13),
+            14 => Some(RadioChannel::DataChannel14),
+            15 => Some(RadioChannel::DataChannel15),
+            16 => Some(RadioChannel::DataChannel16),
+            17 => Some(RadioChannel::DataChannel17),
+            18 => Some(RadioChannel::DataChannel18),
+            19 => Some(RadioChannel::DataChannel19),
+            20 => Some(RadioChannel::DataChannel20),
+            21 => Some(RadioChannel::DataChannel21),
+            22 => Some(RadioChannel::DataChannel22),
+            23 => Some(RadioChannel::DataChannel23),
+            24 => Some(RadioChannel::DataChannel24),
+            25 => Some(RadioChannel::DataChannel25),
+            26 => Some(RadioChannel::DataChannel26),
+            27 => Some(RadioChannel::DataChannel27),
+            28 => Some(RadioChannel::DataChannel28),
+            29 => Some(RadioChannel::DataChannel29),
+            30 => Some(RadioChannel::DataChannel30),
+            31 => Some(RadioChannel::DataChannel31),
+            32 => Some(RadioChannel::DataChannel32),
+            33 => Some(RadioChannel::DataChannel33),
+            34 => Some(RadioChannel::DataChannel34),
+            35 => Some(RadioChannel::DataChannel35),
+            36 => Some(RadioChannel::DataChannel36),
+            37 => Some(RadioChannel::AdvertisingChannel37),
+            38 => Some(RadioChannel::AdvertisingChannel38),
+            39 => Some(RadioChannel::AdvertisingChannel39),
+            _ => None,
+        }
+    }
 }
@@ -0,0 +1,147 @@
+//! Interface for BLE link layers that accept connections
+//!
+//! `ble_advertising` only moves advertising channel PDUs. A link layer
+//! implementing `BleConnection` also acts as a peripheral: it advertises with
+//! `ADV_IND`, answers scan requests, accepts `CONNECT_IND` and then runs the
+//! connection events, channel map and acknowledgements itself. The host side
+//! only sees whole L2CAP PDUs, which the link layer fragments into and
+//! reassembles from LL data PDUs.
+//!
+//! Advertising stops while a connection is open and resumes once it closes,
+//! until `stop_advertising()` is called.
+//!
+//! Link layers that can also act as a central implement `BleCentral`: they
+//! scan for advertisements and initiate connections, which are then reported
+//! and used through `ConnectionClient` and `BleConnection::send()` like those
+//! that peers open to us.
+//!
+//! `BleEncryption` lets the host, usually the Security Manager, provide the
+//! long term keys the link layer encrypts connections with.
+
+use crate::returncode::ReturnCode;
+
+/// Identifies a connection for as long as it is open.
+pub type ConnectionHandle = u16;
+
+/// A device address, least significant byte first as sent over the air.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct DeviceAddress {
+    pub address: [u8; 6],
+    /// Whether this is a random rather than a public address.
+    pub random: bool,
+}
+
+pub trait BleConnection<'a> {
+    fn set_client(&self, client: &'a dyn ConnectionClient);
+
+    /// Start connectable undirected advertising every `interval_ms`
+    /// milliseconds. `adv_data` (at most 31 bytes) is copied; the link layer
+    /// adds its own address.
+    fn start_advertising(&self, adv_data: &[u8], interval_ms: u32) -> ReturnCode;
+
+    fn stop_advertising(&self) -> ReturnCode;
+
+    /// Send the L2CAP PDU in the first `len` bytes of `buffer`, which must
+    /// not be longer than `max_pdu_len()`. `pdu_sent` is called once the
+    /// peer acknowledged all of it.
+    fn send(
+        &self,
+        handle: ConnectionHandle,
+        buffer: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])>;
+
+    /// Close the connection, telling the peer `reason`, an HCI error code.
+    fn disconnect(&self, handle: ConnectionHandle, reason: u8) -> ReturnCode;
+
+    /// The longest L2CAP PDU, header included, `send()` accepts and
+    /// `pdu_received` delivers.
+    fn max_pdu_len(&self) -> usize;
+
+    /// The address we advertise and connect with.
+    fn local_address(&self) -> DeviceAddress;
+}
+
+pub trait ConnectionClient {
+    /// A central connected to us, or a connection we initiated with
+    /// `BleCentral::connect()` was established.
+    fn connected(&self, handle: ConnectionHandle, peer: DeviceAddress);
+
+    /// The connection closed, for the HCI error code `reason`.
+    fn disconnected(&self, handle: ConnectionHandle, reason: u8);
+
+    /// A complete L2CAP PDU arrived, header included.
+    fn pdu_received(&self, handle: ConnectionHandle, pdu: &[u8]);
+
+    /// The PDU passed to `send()` was acknowledged, or the connection closed
+    /// before it was.
+    fn pdu_sent(&self, handle: ConnectionHandle, buffer: &'static mut [u8], result: ReturnCode);
+}
+
+/// Which advertisements scanning reports and which device `connect()`
+/// connects to.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FilterPolicy {
+    /// Report every advertisement and connect to the given peer.
+    AcceptAll,
+    /// Only report advertisements from, and connect to the first advertising
+    /// device on, the list set with `set_accept_list()`.
+    AcceptListOnly,
+}
+
+pub trait BleCentral<'a> {
+    fn set_central_client(&self, client: &'a dyn CentralClient);
+
+    /// Replace the filter accept list with `peers`, which is copied. Returns
+    /// `ESIZE` if the link layer cannot hold that many, and `EBUSY` while
+    /// scanning or connecting.
+    fn set_accept_list(&self, peers: &[DeviceAddress]) -> ReturnCode;
+
+    /// Start scanning for `window_ms` every `interval_ms` milliseconds. An
+    /// active scan sends scan requests and also reports the responses.
+    fn start_scanning(
+        &self,
+        policy: FilterPolicy,
+        active: bool,
+        interval_ms: u32,
+        window_ms: u32,
+    ) -> ReturnCode;
+
+    fn stop_scanning(&self) -> ReturnCode;
+
+    /// Connect to `peer`, or with `FilterPolicy::AcceptListOnly` to any
+    /// device on the accept list, once it advertises. Connection events
+    /// happen every `interval_ms` milliseconds. Scanning stops meanwhile.
+    fn connect(&self, policy: FilterPolicy, peer: DeviceAddress, interval_ms: u32) -> ReturnCode;
+
+    /// Stop trying to connect. `connection_failed` is called with `ECANCEL`.
+    fn cancel_connect(&self) -> ReturnCode;
+}
+
+pub trait CentralClient {
+    /// An advertising or scan response PDU of type `pdu_type` arrived from
+    /// `peer`. `data` is its advertising data.
+    fn advertisement_received(&self, peer: DeviceAddress, pdu_type: u8, rssi: i8, data: &[u8]);
+
+    /// A `connect()` attempt ended without a connection.
+    fn connection_failed(&self, result: ReturnCode);
+}
+
+pub trait BleEncryption<'a> {
+    fn set_encryption_client(&self, client: &'a dyn EncryptionClient);
+
+    /// Start encrypting a connection we initiated with `ltk`, least
+    /// significant byte first. `encryption_changed` is called once the peer
+    /// agreed.
+    fn start_encryption(&self, handle: ConnectionHandle, ltk: &[u8; 16]) -> ReturnCode;
+}
+
+pub trait EncryptionClient {
+    /// The central of `handle` asked to encrypt the connection, identifying
+    /// the key with `ediv` and `rand`, which are 0 for LE Secure Connections.
+    /// Return the key, least significant byte first, or `None` to refuse.
+    fn long_term_key(&self, handle: ConnectionHandle, ediv: u16, rand: u64) -> Option<[u8; 16]>;
+
+    /// The connection is now encrypted, or encryption failed or stopped.
+    fn encryption_changed(&self, handle: ConnectionHandle, encrypted: bool);
+}
@@ -0,0 +1,46 @@
+//! Interface for block storage devices
+//!
+//! Block devices, such as SD cards, are read and written in whole blocks of
+//! `block_size()` bytes, addressed by block number. Consecutive blocks can be
+//! transferred in one operation, which is usually much faster than
+//! transferring them one at a time. Only one operation runs at a time.
+
+use crate::returncode::ReturnCode;
+
+pub trait BlockStorage<'a> {
+    fn set_client(&self, client: &'a dyn Client);
+
+    /// The size of a block in bytes.
+    fn block_size(&self) -> usize;
+
+    /// The number of blocks on the device, or 0 until it is ready.
+    fn block_count(&self) -> u32;
+
+    /// Read `count` blocks, starting at block `block`, into the start of
+    /// `buf`. Returns `EOFF` if the device is not ready, `EBUSY` during
+    /// another operation, `ESIZE` if `buf` is shorter than `count` blocks and
+    /// `EINVAL` if the blocks are not all on the device.
+    fn read_blocks(
+        &self,
+        buf: &'static mut [u8],
+        block: u32,
+        count: u32,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])>;
+
+    /// Write `count` blocks from the start of `buf`, starting at block
+    /// `block`. Returns the same errors as `read_blocks()`.
+    fn write_blocks(
+        &self,
+        buf: &'static mut [u8],
+        block: u32,
+        count: u32,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])>;
+}
+
+pub trait Client {
+    /// A read finished. On error the contents of `buf` are unspecified.
+    fn read_done(&self, buf: &'static mut [u8], result: ReturnCode);
+
+    /// A write finished. On error some of the blocks may have been written.
+    fn write_done(&self, buf: &'static mut [u8], result: ReturnCode);
+}
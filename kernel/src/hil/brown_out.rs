@@ -0,0 +1,32 @@
+//! Interface for supply voltage supervisors.
+//!
+//! A brown-out detector compares the supply voltage against a threshold and
+//! warns when the supply falls below it, typically because a battery is
+//! running out or external power was removed. Set the threshold high enough
+//! above the chip's minimum operating voltage that clients have time to save
+//! state, such as flushing buffered flash writes, before power collapses.
+
+use crate::returncode::ReturnCode;
+
+pub trait BrownOutDetector<'a> {
+    fn set_client(&self, client: &'a dyn BrownOutClient);
+
+    /// The thresholds, in millivolts, the detector supports, from lowest to
+    /// highest.
+    fn thresholds(&self) -> &'static [u32];
+
+    /// Warn when the supply falls below `millivolts`, which must be one of
+    /// `thresholds()`. Returns `EINVAL` otherwise.
+    fn set_threshold(&self, millivolts: u32) -> ReturnCode;
+
+    /// Start comparing the supply against the threshold.
+    fn enable(&self) -> ReturnCode;
+
+    fn disable(&self) -> ReturnCode;
+}
+
+pub trait BrownOutClient {
+    /// The supply fell below the threshold. Power may fail shortly after, so
+    /// clients should only start work that is needed to preserve state.
+    fn supply_low(&self);
+}
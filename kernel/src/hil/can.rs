@@ -0,0 +1,75 @@
+//! Interface for CAN bus controllers
+//!
+//! Sends and receives classic CAN data and remote frames, with standard
+//! 11 bit or extended 29 bit identifiers and up to 8 data bytes. Frames are
+//! received while the controller is enabled, if they match one of its
+//! acceptance filters; with no filter set, none are. Controllers retransmit
+//! frames that lose arbitration or see an error until they are sent.
+
+use crate::returncode::ReturnCode;
+
+/// The most data bytes in a frame.
+pub const MAX_DATA_LEN: usize = 8;
+
+/// The largest standard identifier.
+pub const MAX_STANDARD_ID: u16 = 0x7ff;
+
+/// The largest extended identifier.
+pub const MAX_EXTENDED_ID: u32 = 0x1fff_ffff;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Id {
+    Standard(u16),
+    Extended(u32),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Frame {
+    pub id: Id,
+    /// A remote frame, which requests the data of `id` and carries none.
+    pub remote: bool,
+    /// The number of data bytes, or for a remote frame the number requested.
+    pub len: u8,
+    pub data: [u8; MAX_DATA_LEN],
+}
+
+/// Accepts the frames with identifiers of the same kind as `id` that are
+/// equal to it in the bits set in `mask`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Filter {
+    pub id: Id,
+    pub mask: u32,
+}
+
+pub trait Can<'a> {
+    fn set_transmit_client(&self, client: &'a dyn TransmitClient);
+    fn set_receive_client(&self, client: &'a dyn ReceiveClient);
+
+    /// Join the bus at `bitrate` bits per second. Returns `EINVAL` for
+    /// bitrates the controller cannot be clocked for.
+    fn enable(&self, bitrate: u32) -> ReturnCode;
+
+    /// Leave the bus. A frame being sent is abandoned and its callback
+    /// called with `ECANCEL`.
+    fn disable(&self) -> ReturnCode;
+
+    /// The number of acceptance filters.
+    fn filter_count(&self) -> usize;
+
+    /// Set acceptance filter `index`, or clear it with `None`. Returns
+    /// `EINVAL` if `index` is out of range or the identifier is too large.
+    fn set_filter(&self, index: usize, filter: Option<Filter>) -> ReturnCode;
+
+    /// Send `frame`. Only one frame is sent at a time; returns `EBUSY` until
+    /// `transmit_complete()` of the last one and `EOFF` if not enabled.
+    fn send(&self, frame: Frame) -> ReturnCode;
+}
+
+pub trait TransmitClient {
+    fn transmit_complete(&self, result: ReturnCode);
+}
+
+pub trait ReceiveClient {
+    /// `frame` matched acceptance filter `filter`.
+    fn frame_received(&self, frame: Frame, filter: usize);
+}
@@ -0,0 +1,126 @@
+//! Interface for real-time clocks that keep calendar date and time.
+
+use crate::returncode::ReturnCode;
+use core::time::Duration;
+
+/// A calendar date and time of day.
+///
+/// Fields use the conventional human ranges: `month` is 1-12, `day` is 1-31,
+/// `day_of_week` is 0 (Sunday) to 6 (Saturday), `hour` is 0-23, and `minute`
+/// and `seconds` are 0-59.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DateTimeValues {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub day_of_week: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub seconds: u8,
+}
+
+impl DateTimeValues {
+    /// Whether every field is within its range. This does not check that
+    /// `day` exists in `month`.
+    pub fn is_valid(&self) -> bool {
+        self.month >= 1
+            && self.month <= 12
+            && self.day >= 1
+            && self.day <= 31
+            && self.day_of_week <= 6
+            && self.hour <= 23
+            && self.minute <= 59
+            && self.seconds <= 59
+    }
+
+    /// The date and time in UTC of `time` since the Unix epoch, 1970-01-01
+    /// 00:00:00 UTC, without leap seconds.
+    pub fn from_unix(time: Duration) -> DateTimeValues {
+        let seconds = time.as_secs();
+        let days = seconds / 86400;
+        let in_day = seconds % 86400;
+
+        // The civil date of a day number, counting years from March so that
+        // leap days come last
+        let z = days + 719_468;
+        let era = z / 146_097;
+        let day_of_era = z % 146_097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let mp = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+        DateTimeValues {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            // The epoch was a Thursday
+            day_of_week: ((days + 4) % 7) as u8,
+            hour: (in_day / 3600) as u8,
+            minute: (in_day / 60 % 60) as u8,
+            seconds: (in_day % 60) as u8,
+        }
+    }
+}
+
+pub trait DateTime<'a> {
+    /// Read the current date and time. The result is delivered with
+    /// `DateTimeClient::get_date_time_done()`.
+    fn get_date_time(&self) -> ReturnCode;
+
+    /// Set the clock to `date_time`. Completion is signalled with
+    /// `DateTimeClient::set_date_time_done()`.
+    fn set_date_time(&self, date_time: DateTimeValues) -> ReturnCode;
+
+    fn set_client(&self, client: &'a dyn DateTimeClient);
+}
+
+pub trait DateTimeClient {
+    fn get_date_time_done(&self, date_time: Result<DateTimeValues, ReturnCode>);
+
+    fn set_date_time_done(&self, result: ReturnCode);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn date_time_of_epoch_and_leap_day() {
+        let epoch = DateTimeValues::from_unix(Duration::from_secs(0));
+        assert_eq!(
+            epoch,
+            DateTimeValues {
+                year: 1970,
+                month: 1,
+                day: 1,
+                day_of_week: 4,
+                hour: 0,
+                minute: 0,
+                seconds: 0,
+            }
+        );
+
+        // 2020-02-29 12:34:56, a Saturday
+        let leap_day = DateTimeValues::from_unix(Duration::from_secs(1_582_979_696));
+        assert_eq!(
+            leap_day,
+            DateTimeValues {
+                year: 2020,
+                month: 2,
+                day: 29,
+                day_of_week: 6,
+                hour: 12,
+                minute: 34,
+                seconds: 56,
+            }
+        );
+        assert_eq!(
+            DateTimeValues::from_unix(Duration::from_secs(1_583_020_800)).month,
+            3
+        );
+    }
+}
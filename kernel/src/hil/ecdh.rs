@@ -0,0 +1,35 @@
+//! Interface for elliptic curve Diffie-Hellman over NIST P-256
+//!
+//! Used by protocols such as BLE Secure Connections pairing, where each side
+//! sends its public key and both derive the same shared secret. Keys and
+//! secrets are big-endian; a public key is its X coordinate followed by its
+//! Y coordinate.
+
+use crate::returncode::ReturnCode;
+
+pub trait EcdhP256<'a> {
+    fn set_client(&self, client: &'a dyn Client);
+
+    /// Generate a new private key and write its public key into
+    /// `public_key`. The private key replaces the previous one and is kept
+    /// by the implementation.
+    fn generate_key_pair(
+        &self,
+        public_key: &'static mut [u8; 64],
+    ) -> Result<(), (ReturnCode, &'static mut [u8; 64])>;
+
+    /// Compute the shared secret, the X coordinate of our private key times
+    /// `peer_public_key`, into `secret`. `peer_public_key` is copied.
+    fn compute_shared_secret(
+        &self,
+        peer_public_key: &[u8; 64],
+        secret: &'static mut [u8; 32],
+    ) -> Result<(), (ReturnCode, &'static mut [u8; 32])>;
+}
+
+pub trait Client {
+    fn key_pair_generated(&self, public_key: &'static mut [u8; 64], result: ReturnCode);
+
+    /// `result` is `EINVAL` if the peer's public key is not on the curve.
+    fn shared_secret_computed(&self, secret: &'static mut [u8; 32], result: ReturnCode);
+}
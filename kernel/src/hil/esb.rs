@@ -0,0 +1,91 @@
+//! Interface for Enhanced ShockBurst radios
+//!
+//! Enhanced ShockBurst (ESB) is the 2.4 GHz packet protocol of Nordic's
+//! nRF24 transceivers. A primary transmitter (PTX) sends packets to one of up
+//! to eight pipes, retransmitting each until the primary receiver (PRX)
+//! acknowledges it, and the acknowledgement can carry a payload back.
+//!
+//! Payloads wait in a queue. In PTX mode they are sent in order once the
+//! radio is started; in PRX mode each is sent in the acknowledgement of the
+//! next packet received on its pipe.
+
+use crate::returncode::ReturnCode;
+
+/// The longest payload, as on nRF24 radios.
+pub const MAX_PAYLOAD_LEN: usize = 32;
+
+/// The number of pipes.
+pub const PIPES: usize = 8;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Mode {
+    /// Primary transmitter.
+    Ptx,
+    /// Primary receiver.
+    Prx,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum DataRate {
+    Kbps250,
+    Mbps1,
+    Mbps2,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Config {
+    pub mode: Mode,
+    /// The frequency is 2400 + `channel` MHz, so 0 to 100.
+    pub channel: u8,
+    pub data_rate: DataRate,
+    pub tx_power_dbm: i8,
+    /// How many times a PTX resends an unacknowledged packet, up to 15.
+    pub retransmits: u8,
+    /// How long a PTX waits for an acknowledgement before resending.
+    pub retransmit_delay_us: u16,
+}
+
+pub trait Esb<'a> {
+    fn set_client(&self, client: &'a dyn Client);
+
+    /// Use `config` from the next `start()` on. Returns `EINVAL` for
+    /// settings the radio does not support and `EBUSY` while started.
+    fn configure(&self, config: Config) -> ReturnCode;
+
+    /// Set the address of `pipe`: a four byte base and a one byte prefix.
+    /// As on nRF24 radios, pipe 0 has a base of its own while pipes 1 to 7
+    /// share one, so setting the base of one of them sets it for all of
+    /// them.
+    fn set_address(&self, pipe: usize, base: [u8; 4], prefix: u8) -> ReturnCode;
+
+    /// Receive on the pipes whose bits are set in `pipes`. Only used in PRX
+    /// mode; a PTX receives acknowledgements on the pipe it sent to.
+    fn enable_pipes(&self, pipes: u8) -> ReturnCode;
+
+    /// Queue the first `len` bytes of `buffer` for `pipe`. A PTX asks for an
+    /// acknowledgement unless `ack` is false; `ack` is ignored in PRX mode.
+    /// Returns `EBUSY` if the queue is full.
+    fn write(
+        &self,
+        pipe: usize,
+        buffer: &'static mut [u8],
+        len: usize,
+        ack: bool,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])>;
+
+    /// Start sending the queue in PTX mode, or listening in PRX mode.
+    fn start(&self) -> ReturnCode;
+
+    /// Stop once the packet on air, if any, is done.
+    fn stop(&self) -> ReturnCode;
+}
+
+pub trait Client {
+    /// A queued payload was sent. In PTX mode `result` is `ENOACK` if no
+    /// acknowledgement arrived after all retransmits.
+    fn transmitted(&self, buffer: &'static mut [u8], result: ReturnCode);
+
+    /// A payload arrived on `pipe`: in PRX mode from a PTX, and in PTX mode
+    /// in an acknowledgement. Retransmitted duplicates are not passed up.
+    fn received(&self, pipe: usize, payload: &[u8], rssi_dbm: i16);
+}
@@ -0,0 +1,80 @@
+//! Interface for wired Ethernet MACs
+//!
+//! A MAC sends and receives Ethernet II frames: destination and source MAC
+//! addresses, EtherType and payload, without the frame check sequence, which
+//! the MAC computes and checks. Frames shorter than the 60 byte minimum are
+//! padded when sent.
+//!
+//! Which received frames are passed up is set with a `Filter`. Frames for the
+//! MAC address of the interface always are.
+
+use crate::returncode::ReturnCode;
+
+/// The length of a MAC address.
+pub const ADDRESS_LEN: usize = 6;
+
+/// The length of the destination and source addresses and EtherType.
+pub const HEADER_LEN: usize = 14;
+
+/// The longest frame: a 14 byte header and a 1500 byte payload.
+pub const MAX_FRAME_LEN: usize = 1514;
+
+/// The broadcast MAC address.
+pub const BROADCAST: [u8; ADDRESS_LEN] = [0xff; ADDRESS_LEN];
+
+/// The received frames passed up besides those for the MAC address.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Filter {
+    /// Frames sent to the broadcast address.
+    pub broadcast: bool,
+    /// Frames sent to any multicast address.
+    pub multicast: bool,
+    /// All frames with a valid frame check sequence.
+    pub promiscuous: bool,
+}
+
+impl Filter {
+    /// The filter MACs start with: broadcast frames only.
+    pub const DEFAULT: Filter = Filter {
+        broadcast: true,
+        multicast: false,
+        promiscuous: false,
+    };
+}
+
+pub trait Mac<'a> {
+    fn set_transmit_client(&self, client: &'a dyn TxClient);
+
+    fn set_receive_client(&self, client: &'a dyn RxClient);
+
+    /// The MAC address of the interface.
+    fn mac_address(&self) -> [u8; ADDRESS_LEN];
+
+    /// Set the MAC address used to filter received frames. Sent frames carry
+    /// the source address they are given.
+    fn set_mac_address(&self, address: [u8; ADDRESS_LEN]) -> ReturnCode;
+
+    /// Set which other frames are received.
+    fn set_filter(&self, filter: Filter) -> ReturnCode;
+
+    /// The longest frame that can be sent and received, at most
+    /// `MAX_FRAME_LEN`.
+    fn max_frame_len(&self) -> usize;
+
+    /// Send the first `len` bytes of `frame`. On success, `frame` is returned
+    /// in `transmit_done()`.
+    fn transmit(
+        &self,
+        frame: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])>;
+}
+
+pub trait TxClient {
+    fn transmit_done(&self, frame: &'static mut [u8], result: ReturnCode);
+}
+
+pub trait RxClient {
+    /// A frame passing the filter arrived.
+    fn frame_received(&self, frame: &[u8]);
+}
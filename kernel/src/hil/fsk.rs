@@ -0,0 +1,67 @@
+//! Interface for FSK and OOK packet radios
+//!
+//! Covers sub-GHz transceivers such as the RFM69 and CC1101 in packet mode:
+//! packets start with a preamble and sync word and carry a length byte and a
+//! CRC, which the radio adds and checks. The modulation is set with
+//! `configure()` and applies to the following `transmit()` and `receive()`
+//! calls.
+
+use crate::returncode::ReturnCode;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Modulation {
+    /// Frequency shift keying with the given deviation in Hz.
+    Fsk(u32),
+    /// On-off keying.
+    Ook,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Config {
+    pub frequency_hz: u32,
+    pub bitrate: u32,
+    pub modulation: Modulation,
+    pub tx_power_dbm: i8,
+    /// The first `sync_word_len` bytes are sent after the preamble, and
+    /// packets without them are ignored.
+    pub sync_word: [u8; 8],
+    pub sync_word_len: u8,
+}
+
+pub trait Radio<'a> {
+    fn set_transmit_client(&self, client: &'a dyn TransmitClient);
+    fn set_receive_client(&self, client: &'a dyn ReceiveClient);
+
+    /// Use `config` from the next transmission or reception on. Returns
+    /// `EINVAL` for settings the radio does not support.
+    fn configure(&self, config: Config) -> ReturnCode;
+
+    /// The longest payload the radio can send or receive, limited by its
+    /// FIFO.
+    fn max_payload_len(&self) -> usize;
+
+    /// Send the first `len` bytes of `buffer` as one packet.
+    fn transmit(
+        &self,
+        buffer: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])>;
+
+    /// Listen until one packet arrives. `buffer` should hold
+    /// `max_payload_len()` bytes.
+    fn receive(&self, buffer: &'static mut [u8]) -> Result<(), (ReturnCode, &'static mut [u8])>;
+
+    /// Put the radio to sleep. A transmission or reception in progress is
+    /// abandoned and its callback called with `ECANCEL`.
+    fn sleep(&self) -> ReturnCode;
+}
+
+pub trait TransmitClient {
+    fn transmitted(&self, buffer: &'static mut [u8], result: ReturnCode);
+}
+
+pub trait ReceiveClient {
+    /// A packet of `len` bytes arrived with the given RSSI. `result` is
+    /// `FAIL` if its CRC was wrong and `ECANCEL` if reception was stopped.
+    fn received(&self, buffer: &'static mut [u8], len: usize, rssi_dbm: i16, result: ReturnCode);
+}
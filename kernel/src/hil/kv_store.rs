@@ -0,0 +1,58 @@
+//! Interface for a persistent key-value store.
+//!
+//! Values are stored under a key within a namespace, so that users of the
+//! same store, such as the apps using the store through a syscall driver,
+//! cannot overwrite each other's keys. Keys are up to `MAX_KEY_LEN` bytes
+//! long. Reads complete immediately, while writes and deletes complete with
+//! a callback, after which the change persists across reboots.
+
+use crate::returncode::ReturnCode;
+
+/// The longest key.
+pub const MAX_KEY_LEN: usize = 32;
+
+/// The namespace of the keys of the kernel. Namespaces from `1` up to
+/// `0x7fff_ffff` are free for capsules, and the others are left for the apps
+/// of a syscall driver.
+pub const KERNEL_NAMESPACE: u32 = 0;
+
+pub trait KVStore<'a> {
+    /// Set the client, which is called when writes and deletes complete.
+    fn set_client(&'a self, client: &'a dyn KVStoreClient);
+
+    /// Copies the value of `key` in `namespace` into `value`, and returns the
+    /// length of the value, which may be longer than `value`.
+    ///
+    /// Returns `ENOSUPPORT` if there is no such key, `EBUSY` if a write is in
+    /// progress, and `ESIZE` if `key` is longer than `MAX_KEY_LEN`.
+    fn get(&self, namespace: u32, key: &[u8], value: &mut [u8]) -> Result<usize, ReturnCode>;
+
+    /// Stores the first `length` bytes of `value` under `key` in `namespace`,
+    /// replacing any previous value. `set_done` is called with `value` when
+    /// the value is stored.
+    ///
+    /// Returns `EBUSY` if another write is in progress, and `ESIZE` if the
+    /// key is too long or the value too large for the store.
+    fn set(
+        &'a self,
+        namespace: u32,
+        key: &[u8],
+        value: &'static mut [u8],
+        length: usize,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])>;
+
+    /// Removes `key` from `namespace`. `delete_done` is called when the key
+    /// is removed. Returns `ENOSUPPORT` if there is no such key, and `EBUSY`
+    /// if a write is in progress.
+    fn delete(&'a self, namespace: u32, key: &[u8]) -> ReturnCode;
+}
+
+/// Receive callbacks from `KVStore`.
+pub trait KVStoreClient {
+    /// The value passed to `set` was stored, unless `result` is an error.
+    /// `ENOMEM` means the store is full.
+    fn set_done(&self, value: &'static mut [u8], result: ReturnCode);
+
+    /// The key passed to `delete` was removed, unless `result` is an error.
+    fn delete_done(&self, result: ReturnCode);
+}
@@ -0,0 +1,95 @@
+//! Interface for LoRa radios
+//!
+//! Sends and receives single LoRa packets in explicit header mode. The
+//! modulation is set with `configure()` and applies to the following
+//! `transmit()` and `receive()` calls, so a MAC layer such as LoRaWAN can
+//! change frequency and data rate between packets.
+
+use crate::returncode::ReturnCode;
+
+/// The longest LoRa payload.
+pub const MAX_PAYLOAD_LEN: usize = 255;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Bandwidth {
+    Khz125,
+    Khz250,
+    Khz500,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Config {
+    pub frequency_hz: u32,
+    /// 7 to 12.
+    pub spreading_factor: u8,
+    pub bandwidth: Bandwidth,
+    /// The denominator of the coding rate 4/5 to 4/8, so 5 to 8.
+    pub coding_rate: u8,
+    pub tx_power_dbm: i8,
+    /// Whether transmitted packets carry a payload CRC.
+    pub crc: bool,
+    /// Invert I and Q, as LoRaWAN gateways do when transmitting, so that
+    /// devices do not hear each other.
+    pub invert_iq: bool,
+    /// 0x34 for public LoRaWAN networks, 0x12 for private ones.
+    pub sync_word: u8,
+}
+
+impl Config {
+    /// The length of a symbol in microseconds.
+    pub fn symbol_us(&self) -> u32 {
+        let khz = match self.bandwidth {
+            Bandwidth::Khz125 => 125,
+            Bandwidth::Khz250 => 250,
+            Bandwidth::Khz500 => 500,
+        };
+        (1000 << self.spreading_factor) / khz
+    }
+}
+
+pub trait Radio<'a> {
+    fn set_transmit_client(&self, client: &'a dyn TransmitClient);
+    fn set_receive_client(&self, client: &'a dyn ReceiveClient);
+
+    /// Use `config` from the next transmission or reception on. Returns
+    /// `EINVAL` for settings the radio does not support.
+    fn configure(&self, config: Config) -> ReturnCode;
+
+    /// Send the first `len` bytes of `buffer`.
+    fn transmit(
+        &self,
+        buffer: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])>;
+
+    /// Listen for one packet, giving up if no preamble is found within
+    /// `timeout_symbols` symbols. `buffer` should hold `MAX_PAYLOAD_LEN`
+    /// bytes.
+    fn receive(
+        &self,
+        buffer: &'static mut [u8],
+        timeout_symbols: u16,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])>;
+
+    /// Put the radio to sleep. A transmission or reception in progress is
+    /// abandoned and its callback called with `ECANCEL`.
+    fn sleep(&self) -> ReturnCode;
+}
+
+pub trait TransmitClient {
+    fn transmitted(&self, buffer: &'static mut [u8], result: ReturnCode);
+}
+
+pub trait ReceiveClient {
+    /// A packet of `len` bytes arrived with the given RSSI and SNR.
+    /// `result` is `ECANCEL` if nothing arrived before the timeout and `FAIL`
+    /// if the payload CRC was wrong.
+    fn received(
+        &self,
+        buffer: &'static mut [u8],
+        len: usize,
+        rssi_dbm: i16,
+        snr_db: i8,
+        result: ReturnCode,
+    );
+}
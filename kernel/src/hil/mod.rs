@@ -2,19 +2,34 @@
 
 pub mod adc;
 pub mod analog_comparator;
+pub mod battery;
 pub mod ble_advertising;
+pub mod ble_connection;
+pub mod block_storage;
+pub mod brown_out;
+pub mod can;
 pub mod crc;
 pub mod dac;
+pub mod date_time;
 pub mod digest;
+pub mod ecdh;
 pub mod eic;
 pub mod entropy;
+pub mod esb;
+pub mod ethernet;
 pub mod flash;
+pub mod fsk;
 pub mod gpio;
 pub mod gpio_async;
 pub mod i2c;
+pub mod kv_store;
 pub mod led;
 pub mod log;
+pub mod lora;
+pub mod monotonic_counter;
+pub mod nfc;
 pub mod nonvolatile_storage;
+pub mod public_key_crypto;
 pub mod pwm;
 pub mod radio;
 pub mod rng;
@@ -27,6 +42,7 @@ pub mod touch;
 pub mod uart;
 pub mod usb;
 pub mod usb_hid;
+pub mod wifi;
 
 /// Shared interface for configuring components.
 pub trait Controller {
@@ -0,0 +1,34 @@
+//! Interface for increment-only counters that persist across reboots.
+//!
+//! Monotonic counters are used for anti-rollback protection: the updater
+//! refuses images whose version is lower than the counter and advances the
+//! counter once a new image is accepted. A counter can never be decreased
+//! through this interface, whether it is backed by hardware (e.g. OTP fuses)
+//! or by a region of flash.
+
+use crate::returncode::ReturnCode;
+
+pub trait MonotonicCounter<'a> {
+    /// Set the client to be used for callbacks.
+    fn set_client(&self, client: &'a dyn Client);
+
+    /// Return the current value of the counter. Returns `EOFF` if the counter
+    /// has not been loaded from its backing store yet.
+    fn get(&self) -> Result<u32, ReturnCode>;
+
+    /// Increase the counter by one. Completion is signalled with
+    /// `Client::counter_updated()`. Returns `ESIZE` once the counter has
+    /// reached the highest value it can hold.
+    fn increment(&self) -> ReturnCode;
+
+    /// Raise the counter to `value`. Returns `EINVAL` if `value` is lower than
+    /// the current value, `EALREADY` if it is equal (in which case there is
+    /// no callback), and `ESIZE` if it is higher than the counter can hold.
+    fn advance_to(&self, value: u32) -> ReturnCode;
+}
+
+pub trait Client {
+    /// An update finished. On `SUCCESS`, `value` is the new value of the
+    /// counter; otherwise the counter is unchanged.
+    fn counter_updated(&self, result: ReturnCode, value: u32);
+}
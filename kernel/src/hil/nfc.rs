@@ -0,0 +1,70 @@
+//! Interface for NFC tag emulation
+//!
+//! An NFC tag answers a reader's field as an NFC-A (ISO 14443-3A) target.
+//! The hardware handles sensing the field and anticollision; once a reader
+//! has selected the tag, the tag type's protocol runs on frames exchanged
+//! through this interface. A reader halting the tag is handled below it.
+//!
+//! Frames are half duplex: a buffer is handed down for each frame received
+//! and for each frame sent, and is returned in the matching callback.
+
+use crate::returncode::ReturnCode;
+
+/// The tag type announced in anticollision.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TagType {
+    /// NFC Forum Type 2, which is read and written in 4 byte blocks.
+    Type2,
+    /// NFC Forum Type 4A, which speaks ISO-DEP.
+    Type4,
+}
+
+pub trait NfcTag<'a> {
+    fn set_client(&self, client: &'a dyn Client);
+
+    /// Start answering readers as a tag of `tag_type` with the 4 or 7 byte
+    /// `id`. Returns `EINVAL` for other lengths.
+    fn enable(&self, id: &[u8], tag_type: TagType) -> ReturnCode;
+
+    /// Stop answering readers. Buffers given to the tag are returned with
+    /// `ECANCEL`.
+    fn disable(&self) -> ReturnCode;
+
+    /// Receive the next frame from the reader into `buffer`, once the tag is
+    /// selected. Frames with CRC or parity errors are dropped.
+    fn receive(&self, buffer: &'static mut [u8]) -> Result<(), (ReturnCode, &'static mut [u8])>;
+
+    /// Answer the reader with the first `len` bytes of `buffer`, followed by a
+    /// CRC.
+    fn transmit(
+        &self,
+        buffer: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])>;
+
+    /// Answer the reader with the low `bits` bits of `buffer[0]`, up to 7,
+    /// and no CRC, such as the 4 bit ACK of Type 2 tags.
+    fn transmit_bits(
+        &self,
+        buffer: &'static mut [u8],
+        bits: usize,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])>;
+}
+
+pub trait Client {
+    /// A reader's field appeared.
+    fn field_detected(&self);
+
+    /// The field went away. Buffers given to the tag have been returned with
+    /// `ECANCEL` first.
+    fn field_lost(&self);
+
+    /// A reader selected the tag, so the tag type's protocol starts.
+    fn selected(&self);
+
+    fn frame_received(&self, buffer: &'static mut [u8], len: usize, result: ReturnCode);
+
+    /// A frame was sent. `result` is `FAIL` if the answer was too late for
+    /// the reader.
+    fn frame_transmitted(&self, buffer: &'static mut [u8], result: ReturnCode);
+}
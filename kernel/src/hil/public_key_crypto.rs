@@ -0,0 +1,85 @@
+//! Interfaces for making and verifying public key signatures
+//!
+//! Used to check that data, such as a process image, was signed by whoever
+//! holds the private key that goes with a known public key, or to sign data
+//! with a private key of this device. Signatures are made and verified over
+//! a hash of the data, which the caller computes, so neither side needs to
+//! read the data itself.
+//!
+//! An implementation handles signatures of one algorithm, and gives the
+//! lengths of its keys, hashes and signatures. For ECDSA over NIST P-256,
+//! as in `ecdh`, a public key is its X coordinate followed by its Y
+//! coordinate, a signature is `r` followed by `s`, and both are big-endian.
+
+use crate::returncode::ReturnCode;
+
+pub trait SignatureVerify<'a> {
+    fn set_client(&self, client: &'a dyn ClientVerify);
+
+    /// The length of the public keys, in bytes.
+    fn public_key_len(&self) -> usize;
+
+    /// The length of the hashes signatures are made over, in bytes.
+    fn hash_len(&self) -> usize;
+
+    /// The length of the signatures, in bytes.
+    fn signature_len(&self) -> usize;
+
+    /// Set the public key signatures are verified with. `key` is copied.
+    /// Returns `ESIZE` if `key` is not `public_key_len()` bytes long, and
+    /// `EINVAL` if it is not a valid key.
+    fn set_public_key(&self, key: &[u8]) -> ReturnCode;
+
+    /// Verify that `signature` is a signature over `hash` with the private
+    /// key of the public key. The buffers are given back in
+    /// `verification_done()`. Returns `EBUSY` while another verification is
+    /// in progress, `ESIZE` if a buffer is too short, and `EOFF` if no
+    /// public key is set.
+    fn verify(
+        &self,
+        hash: &'static mut [u8],
+        signature: &'static mut [u8],
+    ) -> Result<(), (ReturnCode, &'static mut [u8], &'static mut [u8])>;
+}
+
+pub trait ClientVerify {
+    /// `result` is `Ok(true)` if the signature is valid, and `Ok(false)` if
+    /// it is not.
+    fn verification_done(
+        &self,
+        result: Result<bool, ReturnCode>,
+        hash: &'static mut [u8],
+        signature: &'static mut [u8],
+    );
+}
+
+pub trait SignatureSign<'a> {
+    fn set_client(&self, client: &'a dyn ClientSign);
+
+    /// The length of the hashes signatures are made over, in bytes.
+    fn hash_len(&self) -> usize;
+
+    /// The length of the signatures, in bytes.
+    fn signature_len(&self) -> usize;
+
+    /// Sign `hash` with the private key into `signature`. How the private
+    /// key is provisioned is up to the implementation. The buffers are
+    /// given back in `signing_done()`. Returns `EBUSY` while another
+    /// signature is in progress, `ESIZE` if a buffer is too short, and
+    /// `EOFF` if there is no private key.
+    fn sign(
+        &self,
+        hash: &'static mut [u8],
+        signature: &'static mut [u8],
+    ) -> Result<(), (ReturnCode, &'static mut [u8], &'static mut [u8])>;
+}
+
+pub trait ClientSign {
+    /// `signature` holds the signature if `result` is `SUCCESS`.
+    fn signing_done(
+        &self,
+        result: ReturnCode,
+        hash: &'static mut [u8],
+        signature: &'static mut [u8],
+    );
+}
@@ -7,21 +7,40 @@
 //! for address recognition. This must be committed to hardware with a call to
 //! config_commit. Please see the relevant TRD for more details.
 
+use crate::hil::time::{Ticks, Time};
 use crate::returncode::ReturnCode;
 pub trait TxClient {
     fn send_done(&self, buf: &'static mut [u8], acked: bool, result: ReturnCode);
 }
 
 pub trait RxClient {
+    /// A frame was received. `timestamp` is the time the radio's receive
+    /// interrupt for the frame was handled, read from the radio's
+    /// `TimestampSource`, or `None` if the radio has no timestamp source.
     fn receive(
         &self,
         buf: &'static mut [u8],
         frame_len: usize,
         crc_valid: bool,
+        timestamp: Option<u32>,
         result: ReturnCode,
     );
 }
 
+/// Clock used by radio drivers to timestamp received frames. Any `Time` is a
+/// timestamp source; boards usually use the counter behind the userspace
+/// alarm so that timestamps can be compared with alarm times.
+pub trait TimestampSource {
+    /// The current time, in ticks of the source.
+    fn timestamp(&self) -> u32;
+}
+
+impl<T: Time> TimestampSource for T {
+    fn timestamp(&self) -> u32 {
+        self.now().into_u32()
+    }
+}
+
 pub trait ConfigClient {
     fn config_done(&self, result: ReturnCode);
 }
@@ -111,3 +130,84 @@ pub trait RadioData {
         frame_len: usize,
     ) -> (ReturnCode, Option<&'static mut [u8]>);
 }
+
+/// Clear channel assessment outside of transmissions, such as the periodic
+/// channel checks of a duty-cycled MAC.
+pub trait RadioChannelCheck {
+    fn set_channel_check_client(&self, client: &'static dyn ChannelCheckClient);
+
+    /// Sample the channel once, turning the radio on if needed. The radio is
+    /// left receiving afterwards. Returns `EBUSY` while a frame is being
+    /// transmitted or an energy scan runs.
+    fn channel_check(&self) -> ReturnCode;
+}
+
+pub trait ChannelCheckClient {
+    /// The channel check finished. `clear` is false if energy or a frame was
+    /// detected.
+    fn channel_checked(&self, clear: bool);
+}
+
+/// Energy detection, to find the quietest channel.
+pub trait RadioEnergyScan {
+    fn set_energy_scan_client(&self, client: &'static dyn EnergyScanClient);
+
+    /// Measure the energy on each channel in `channels`, a bit mask with bit
+    /// `n` set to scan channel `n`, for `duration_ms` each, lowest channel
+    /// first. The radio returns to its configured channel afterwards. Returns
+    /// `EINVAL` if no channel in the mask exists and `EBUSY` while a frame is
+    /// being transmitted or the channel checked. Frames cannot be sent during
+    /// the scan.
+    fn energy_scan(&self, channels: u32, duration_ms: u32) -> ReturnCode;
+}
+
+pub trait EnergyScanClient {
+    /// The peak energy on `channel` during its measurement, in dBm.
+    fn energy_measured(&self, channel: u8, energy: i8);
+
+    /// Every channel of the scan was measured.
+    fn scan_done(&self, result: ReturnCode);
+}
+
+/// Which antenna the radio uses.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Antenna {
+    /// Always use the given antenna.
+    Fixed(u8),
+    /// Switch between antennas 0 and 1 after every frame received with a bad
+    /// CRC, staying on the antenna that last received a good one. Frames are
+    /// sent on the current antenna.
+    Diversity,
+}
+
+/// The control lines of an external front-end module (FEM) and antenna
+/// switch. A radio driver switches them around every transmission and
+/// reception, so that its power amplifier (PA) is only on while sending and
+/// its low noise amplifier (LNA) only while listening.
+pub trait FrontEndModule {
+    /// Turn the PA on and the LNA off, before the radio ramps up to send.
+    fn enable_pa(&self);
+
+    /// Turn the LNA on and the PA off, before the radio ramps up to listen.
+    fn enable_lna(&self);
+
+    /// Turn both off, as the radio turns off.
+    fn disable(&self);
+
+    /// Route the radio to `antenna`.
+    fn select_antenna(&self, antenna: u8);
+
+    /// How many antennas can be selected.
+    fn antennas(&self) -> u8;
+}
+
+/// Radios that drive an external front-end module.
+pub trait RadioFrontEnd {
+    fn set_front_end(&self, fem: &'static dyn FrontEndModule);
+
+    /// Returns `EINVAL` for antennas the front end does not have, and
+    /// `ENOSUPPORT` without a front end.
+    fn set_antenna(&self, antenna: Antenna) -> ReturnCode;
+
+    fn get_antenna(&self) -> Antenna;
+}
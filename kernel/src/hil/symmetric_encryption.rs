@@ -2,6 +2,7 @@
 //!
 //! see boards/imix/src/aes_test.rs for example usage
 
+use crate::common::leasable_buffer::LeasableBuffer;
 use crate::returncode::ReturnCode;
 
 /// Implement this trait and use `set_client()` in order to receive callbacks from an `AES128`
@@ -135,3 +136,77 @@ pub trait AES128CCM<'a> {
         encrypting: bool,
     ) -> (ReturnCode, Option<&'static mut [u8]>);
 }
+
+/// The modes of authenticated encryption with associated data an
+/// `AES128Aead` can be set to.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum AeadMode {
+    /// CCM* (IEEE 802.15.4-2015, B.4.1), the CCM of RFC 3610 that also
+    /// allows encryption without a tag. Nonces are 7 to 13 bytes long, and
+    /// tags 0, 4, 6, 8, 10, 12, 14 or 16.
+    Ccm,
+    /// GCM (NIST SP 800-38D). Nonces are `GCM_NONCE_LENGTH` bytes long, and
+    /// tags 4, 8, or 12 to 16.
+    Gcm,
+}
+
+pub const GCM_NONCE_LENGTH: usize = 12;
+pub const AEAD_MAX_NONCE_LENGTH: usize = 13;
+pub const AEAD_MAX_TAG_LENGTH: usize = 16;
+
+pub trait AeadClient {
+    /// `res` and `tag_is_valid` are as for `CCMClient`. The buffers passed
+    /// to `crypt()` are given back whole.
+    fn crypt_done(
+        &self,
+        aad: &'static mut [u8],
+        payload: &'static mut [u8],
+        tag: &'static mut [u8],
+        res: ReturnCode,
+        tag_is_valid: bool,
+    );
+}
+
+/// Authenticated encryption with associated data, with the associated data,
+/// the payload and the tag in separate buffers, so callers do not have to
+/// lay out a message in one buffer.
+pub trait AES128Aead<'a> {
+    /// Set the client instance which will receive `crypt_done()` callbacks
+    fn set_client(&'a self, client: &'a dyn AeadClient);
+
+    /// Set the mode of the next operations.
+    /// Returns `ENOSUPPORT` if the mode is not implemented
+    fn set_mode(&self, mode: AeadMode) -> ReturnCode;
+
+    /// Set the key.
+    /// Returns `EINVAL` if length is not `AES128_KEY_SIZE`
+    fn set_key(&self, key: &[u8]) -> ReturnCode;
+
+    /// Set the nonce, which is copied.
+    /// Returns `EINVAL` if the mode does not take nonces of its length
+    fn set_nonce(&self, nonce: &[u8]) -> ReturnCode;
+
+    /// Authenticate `aad` and `payload`, and encrypt or decrypt `payload` in
+    /// place. When encrypting, the tag is written to `tag`; when decrypting,
+    /// it is checked against `tag`. The length of `tag` is the length of the
+    /// tag.
+    ///
+    /// Returns `EBUSY` if an operation is in progress, and `EINVAL` if the
+    /// mode does not take tags of the length of `tag`, or a payload of the
+    /// length of `payload` with the nonce set.
+    fn crypt(
+        &self,
+        aad: LeasableBuffer<'static, u8>,
+        payload: LeasableBuffer<'static, u8>,
+        tag: LeasableBuffer<'static, u8>,
+        encrypting: bool,
+    ) -> Result<
+        (),
+        (
+            ReturnCode,
+            &'static mut [u8],
+            &'static mut [u8],
+            &'static mut [u8],
+        ),
+    >;
+}
@@ -14,6 +14,7 @@
 use crate::ReturnCode;
 use core::cmp::{Eq, Ord, Ordering, PartialOrd};
 use core::fmt;
+use core::time::Duration;
 
 /// An integer type defining the width of a time value, which allows
 /// clients to know when wraparound will occur.
@@ -31,6 +32,14 @@ pub trait Ticks: Clone + Copy + From<u32> + fmt::Debug + Ord + PartialOrd + Eq {
     /// are 32 bits.
     fn into_u32(self) -> u32;
 
+    /// Converts the type into a `u64`. No `Ticks` type is wider than 64 bits,
+    /// so this never loses information.
+    fn into_u64(self) -> u64;
+
+    /// Converts a `u64` into this type, keeping only the low bits that fit in
+    /// its width.
+    fn from_u64_wrapping(val: u64) -> Self;
+
     /// Add two values, wrapping around on overflow using standard
     /// unsigned arithmetic.
     fn wrapping_add(self, other: Self) -> Self;
@@ -94,16 +103,43 @@ pub trait Time {
         let val: u64 = Self::Frequency::frequency() as u64 * us as u64;
         ticks_from_val(val / 1_000_000)
     }
+
+    /// Returns the number of whole seconds in `ticks`.
+    fn ticks_to_seconds(ticks: Self::Ticks) -> u64 {
+        ticks_to_units::<Self::Frequency, _>(ticks, 1)
+    }
+
+    /// Returns the number of whole milliseconds in `ticks`.
+    fn ticks_to_ms(ticks: Self::Ticks) -> u64 {
+        ticks_to_units::<Self::Frequency, _>(ticks, 1000)
+    }
+
+    /// Returns the number of whole microseconds in `ticks`.
+    fn ticks_to_us(ticks: Self::Ticks) -> u64 {
+        ticks_to_units::<Self::Frequency, _>(ticks, 1_000_000)
+    }
 }
 
 fn ticks_from_val<T: Ticks>(val: u64) -> T {
-    if val <= T::max_value().into_u32() as u64 {
-        T::from(val as u32)
+    if val <= T::max_value().into_u64() {
+        T::from_u64_wrapping(val)
     } else {
         T::max_value()
     }
 }
 
+/// Convert `ticks` of a clock running at `F` into units of
+/// `1/units_per_second` seconds. The remainder is converted separately so the
+/// multiplication cannot overflow for any tick value below `u64::MAX`; results
+/// too large for a `u64` saturate.
+fn ticks_to_units<F: Frequency, T: Ticks>(ticks: T, units_per_second: u64) -> u64 {
+    let freq = F::frequency() as u64;
+    let ticks = ticks.into_u64();
+    (ticks / freq)
+        .saturating_mul(units_per_second)
+        .saturating_add((ticks % freq) * units_per_second / freq)
+}
+
 /// Represents a static moment in time, that does not change over
 /// repeated calls to `Time::now`.
 pub trait Timestamp: Time {}
@@ -210,6 +246,98 @@ pub trait Alarm<'a>: Time {
     fn minimum_dt(&self) -> Self::Ticks;
 }
 
+/// An `Alarm` that can re-arm itself after firing.
+///
+/// A periodic alarm fires at `reference + period`, `reference + 2 * period`,
+/// and so on until it is disarmed. Each expiration is computed from the
+/// previous expiration rather than from when the callback ran, so callback
+/// latency does not accumulate into drift. If the callback runs later than a
+/// whole period, the missed expirations fire as soon as possible, one per
+/// callback.
+pub trait PeriodicAlarm<'a>: Alarm<'a> {
+    /// Arm the alarm to fire every `period` ticks, the first time at
+    /// `reference + period`. This replaces any pending alarm. A later call to
+    /// `set_alarm` makes the alarm one-shot again. Returns `EINVAL`, leaving
+    /// the alarm as it was, if `period` is zero.
+    fn set_periodic_alarm(&self, reference: Self::Ticks, period: Self::Ticks) -> ReturnCode;
+
+    /// Return the period if the alarm is armed and periodic.
+    fn period(&self) -> Option<Self::Ticks>;
+}
+
+/// A 64-bit counter, which does not wrap around within the lifetime of a
+/// device at any practical frequency. Times can then be compared and offset
+/// with ordinary arithmetic, and durations converted to ticks once, rather
+/// than clients having to track the wraparound of a narrower `Ticks`.
+///
+/// Hardware with a narrower counter can provide this interface through an
+/// adapter that counts the wraps of an `Alarm`, such as
+/// `capsules::alarm64::Alarm64Adapter`.
+pub trait Time64 {
+    /// The number of ticks per second
+    type Frequency: Frequency;
+
+    /// Returns the number of ticks since the counter started.
+    fn now(&self) -> Ticks64;
+
+    /// Returns the number of ticks in `duration`, rounding down any fraction
+    /// of a tick and saturating at `Ticks64::max_value()`.
+    fn ticks_from_duration(duration: Duration) -> Ticks64 {
+        let freq = Self::Frequency::frequency() as u64;
+        Ticks64(
+            duration
+                .as_secs()
+                .saturating_mul(freq)
+                .saturating_add(duration.subsec_nanos() as u64 * freq / 1_000_000_000),
+        )
+    }
+
+    /// Returns the duration of `ticks`, rounding down to a nanosecond.
+    fn ticks_to_duration(ticks: Ticks64) -> Duration {
+        let freq = Self::Frequency::frequency() as u64;
+        Duration::new(
+            ticks.0 / freq,
+            ((ticks.0 % freq) * 1_000_000_000 / freq) as u32,
+        )
+    }
+}
+
+/// An alarm on a `Time64` counter. Alarms are set at an absolute time, or at
+/// a duration from now; since the counter does not wrap, a time that has
+/// passed is unambiguous and fires the alarm as soon as possible.
+pub trait Alarm64<'a>: Time64 {
+    /// Specify the callback for when the counter reaches the alarm
+    /// value. If there was a previously installed callback this call
+    /// replaces it.
+    fn set_alarm_client(&'a self, client: &'a dyn AlarmClient);
+
+    /// Arm the alarm to fire once `Time64::now()` reaches `ticks`, replacing
+    /// any pending alarm. As with `Alarm`, the callback may be delayed but
+    /// never runs early.
+    fn set_alarm_at(&self, ticks: Ticks64);
+
+    /// Arm the alarm to fire once `duration` has passed from now.
+    fn set_alarm_in(&self, duration: Duration) {
+        let ticks = Self::ticks_from_duration(duration);
+        self.set_alarm_at(Ticks64(self.now().0.saturating_add(ticks.0)));
+    }
+
+    /// Return the time of the last alarm set.
+    fn get_alarm(&self) -> Ticks64;
+
+    /// Disable the alarm and stop it from firing in the future.
+    /// Valid `ReturnCode` codes are:
+    ///   - `ReturnCode::SUCCESS` the alarm has been disarmed and will not invoke
+    ///   the callback in the future
+    ///   - `ReturnCode::FAIL` the alarm could not be disarmed and will invoke
+    ///   the callback in the future
+    fn disarm(&self) -> ReturnCode;
+
+    /// Returns whether the alarm is currently armed, with the same caveat as
+    /// `Alarm::is_armed`.
+    fn is_armed(&self) -> bool;
+}
+
 /// Callback handler for when a timer fires.
 pub trait TimerClient {
     fn timer(&self);
@@ -337,6 +465,14 @@ impl Ticks for Ticks32 {
         self.0
     }
 
+    fn into_u64(self) -> u64 {
+        self.0 as u64
+    }
+
+    fn from_u64_wrapping(val: u64) -> Self {
+        Ticks32(val as u32)
+    }
+
     fn wrapping_add(self, other: Self) -> Self {
         Ticks32(self.0.wrapping_add(other.0))
     }
@@ -388,6 +524,14 @@ impl Ticks for Ticks24 {
         self.0
     }
 
+    fn into_u64(self) -> u64 {
+        self.0 as u64
+    }
+
+    fn from_u64_wrapping(val: u64) -> Self {
+        Ticks24((val & 0x00FFFFFF) as u32)
+    }
+
     fn wrapping_add(self, other: Self) -> Self {
         Ticks24(self.0.wrapping_add(other.0) & 0x00FFFFFF)
     }
@@ -451,6 +595,14 @@ impl Ticks for Ticks16 {
         self.0 as u32
     }
 
+    fn into_u64(self) -> u64 {
+        self.0 as u64
+    }
+
+    fn from_u64_wrapping(val: u64) -> Self {
+        Ticks16(val as u16)
+    }
+
     fn wrapping_add(self, other: Self) -> Self {
         Ticks16(self.0.wrapping_add(other.0))
     }
@@ -514,6 +666,14 @@ impl Ticks for Ticks64 {
         self.0 as u32
     }
 
+    fn into_u64(self) -> u64 {
+        self.0
+    }
+
+    fn from_u64_wrapping(val: u64) -> Self {
+        Ticks64(val)
+    }
+
     fn wrapping_add(self, other: Self) -> Self {
         Ticks64(self.0.wrapping_add(other.0))
     }
@@ -545,3 +705,189 @@ impl PartialEq for Ticks64 {
 }
 
 impl Eq for Ticks64 {}
+
+/// An alarm whose time only moves when a test advances it.
+///
+/// `MockAlarm` runs at 1 kHz, so one tick is one millisecond. Calling
+/// `advance()` moves the time forward, stopping at each expiration on the way
+/// to call the client with `now()` equal to that expiration. A client that
+/// re-arms the alarm from its callback is therefore fired again within the
+/// same `advance()` if the new expiration falls inside it.
+#[cfg(any(test, feature = "mock_time"))]
+pub struct MockAlarm<'a> {
+    now: core::cell::Cell<Ticks32>,
+    alarm: core::cell::Cell<Option<(Ticks32, Ticks32)>>,
+    client: crate::common::cells::OptionalCell<&'a dyn AlarmClient>,
+}
+
+#[cfg(any(test, feature = "mock_time"))]
+impl<'a> MockAlarm<'a> {
+    pub const fn new() -> MockAlarm<'a> {
+        MockAlarm {
+            now: core::cell::Cell::new(Ticks32(0)),
+            alarm: core::cell::Cell::new(None),
+            client: crate::common::cells::OptionalCell::empty(),
+        }
+    }
+
+    /// Set the current time without firing any alarm.
+    pub fn set_now(&self, now: Ticks32) {
+        self.now.set(now);
+    }
+
+    /// Move the time forward by `ticks`, firing the alarm at each expiration
+    /// passed on the way.
+    pub fn advance(&self, ticks: Ticks32) {
+        let mut left = ticks;
+        while let Some((reference, dt)) = self.alarm.get() {
+            let elapsed = self.now.get().wrapping_sub(reference);
+            let remaining = if elapsed.0 >= dt.0 {
+                Ticks32(0)
+            } else {
+                dt.wrapping_sub(elapsed)
+            };
+            if remaining > left {
+                break;
+            }
+            self.now.set(self.now.get().wrapping_add(remaining));
+            left = left.wrapping_sub(remaining);
+            self.alarm.set(None);
+            self.client.map(|client| client.alarm());
+        }
+        self.now.set(self.now.get().wrapping_add(left));
+    }
+
+    pub fn advance_ms(&self, ms: u32) {
+        self.advance(Ticks32(ms));
+    }
+}
+
+#[cfg(any(test, feature = "mock_time"))]
+impl Time for MockAlarm<'_> {
+    type Frequency = Freq1KHz;
+    type Ticks = Ticks32;
+
+    fn now(&self) -> Ticks32 {
+        self.now.get()
+    }
+}
+
+#[cfg(any(test, feature = "mock_time"))]
+impl<'a> Alarm<'a> for MockAlarm<'a> {
+    fn set_alarm_client(&'a self, client: &'a dyn AlarmClient) {
+        self.client.set(client);
+    }
+
+    fn set_alarm(&self, reference: Ticks32, dt: Ticks32) {
+        self.alarm.set(Some((reference, dt)));
+    }
+
+    fn get_alarm(&self) -> Ticks32 {
+        self.alarm
+            .get()
+            .map_or(Ticks32(0), |(reference, dt)| reference.wrapping_add(dt))
+    }
+
+    fn disarm(&self) -> ReturnCode {
+        self.alarm.set(None);
+        ReturnCode::SUCCESS
+    }
+
+    fn is_armed(&self) -> bool {
+        self.alarm.get().is_some()
+    }
+
+    fn minimum_dt(&self) -> Ticks32 {
+        Ticks32(1)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use core::cell::Cell;
+
+    struct Periodic<'a> {
+        alarm: &'a MockAlarm<'a>,
+        fired: Cell<u32>,
+        times: Cell<[u32; 4]>,
+    }
+
+    impl AlarmClient for Periodic<'_> {
+        fn alarm(&self) {
+            let now = self.alarm.now();
+            let mut times = self.times.get();
+            times[self.fired.get() as usize % 4] = now.into_u32();
+            self.times.set(times);
+            self.fired.set(self.fired.get() + 1);
+            self.alarm.set_alarm(now, Ticks32::from(10));
+        }
+    }
+
+    #[test]
+    fn fires_at_each_expiration() {
+        let alarm = MockAlarm::new();
+        let client = Periodic {
+            alarm: &alarm,
+            fired: Cell::new(0),
+            times: Cell::new([0; 4]),
+        };
+        alarm.set_alarm_client(&client);
+        alarm.set_alarm(alarm.now(), Ticks32::from(10));
+
+        alarm.advance_ms(9);
+        assert_eq!(client.fired.get(), 0);
+        alarm.advance_ms(26);
+        assert_eq!(client.fired.get(), 3);
+        assert_eq!(client.times.get()[..3], [10, 20, 30]);
+        assert_eq!(alarm.now().into_u32(), 35);
+        assert_eq!(alarm.get_alarm().into_u32(), 40);
+    }
+
+    struct Clock64;
+
+    impl Time64 for Clock64 {
+        type Frequency = Freq32KHz;
+
+        fn now(&self) -> Ticks64 {
+            Ticks64(0)
+        }
+    }
+
+    #[test]
+    fn duration_ticks_round_trip() {
+        let ticks = Clock64::ticks_from_duration(Duration::from_millis(1500));
+        assert_eq!(ticks.into_u64(), 49152);
+        assert_eq!(
+            Clock64::ticks_to_duration(ticks),
+            Duration::from_millis(1500)
+        );
+
+        // A fraction of a tick is rounded down both ways.
+        let tick = Duration::new(0, 30517);
+        assert_eq!(Clock64::ticks_from_duration(tick).into_u64(), 0);
+        assert_eq!(Clock64::ticks_to_duration(Ticks64(1)), tick);
+
+        let forever = Clock64::ticks_from_duration(Duration::new(u64::MAX, 0));
+        assert_eq!(forever, Ticks64::max_value());
+    }
+
+    #[test]
+    fn past_alarm_fires_immediately() {
+        let alarm = MockAlarm::new();
+        let client = Periodic {
+            alarm: &alarm,
+            fired: Cell::new(0),
+            times: Cell::new([0; 4]),
+        };
+        alarm.set_alarm_client(&client);
+        alarm.set_now(Ticks32::from(100));
+        alarm.set_alarm(Ticks32::from(50), Ticks32::from(10));
+
+        alarm.advance_ms(0);
+        assert_eq!(client.fired.get(), 1);
+        assert_eq!(client.times.get()[0], 100);
+        assert!(alarm.disarm() == ReturnCode::SUCCESS);
+        assert!(!alarm.is_armed());
+    }
+}
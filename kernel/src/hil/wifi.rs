@@ -0,0 +1,38 @@
+//! Interface for WiFi network interfaces
+//!
+//! A WiFi station joins one access point. Once joined, it sends and
+//! receives frames through `hil::ethernet::Mac`, as Ethernet II frames, the
+//! way WiFi co-processors such as the ESP32 present them, so the layers
+//! above Ethernet work over WiFi unchanged. Its `transmit()` returns `EOFF`
+//! while the station has not joined an access point.
+
+use crate::returncode::ReturnCode;
+
+/// The longest SSID.
+pub const MAX_SSID_LEN: usize = 32;
+
+/// The longest WPA2 passphrase.
+pub const MAX_PASSPHRASE_LEN: usize = 63;
+
+pub trait Station<'a> {
+    fn set_client(&self, client: &'a dyn Client);
+
+    /// The MAC address of the interface, once the device has reported it.
+    fn mac_address(&self) -> Option<[u8; 6]>;
+
+    /// Join the access point `ssid`, using WPA2 with `passphrase` or no
+    /// security if it is empty. `joined()` is called with the result.
+    fn join(&self, ssid: &[u8], passphrase: &[u8]) -> ReturnCode;
+
+    /// Leave the access point. `left()` is called once done.
+    fn leave(&self) -> ReturnCode;
+}
+
+pub trait Client {
+    /// The station joined an access point, or failed to.
+    fn joined(&self, result: ReturnCode);
+
+    /// The station left its access point, because of `leave()` or because
+    /// the link was lost.
+    fn left(&self);
+}
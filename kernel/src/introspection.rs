@@ -11,6 +11,7 @@
 //! correct capabilities to can use it.
 
 use core::cell::Cell;
+use core::slice;
 
 use crate::callback::AppId;
 use crate::capabilities::ProcessManagementCapability;
@@ -123,6 +124,27 @@ impl KernelInfo {
             .process_map_or(0, app, |process| process.debug_timeslice_expiration_count())
     }
 
+    /// Returns the number of syscalls from this app that were rejected
+    /// because its TBF header does not permit access to the driver.
+    pub fn number_app_denied_syscalls(
+        &self,
+        app: AppId,
+        _capability: &dyn ProcessManagementCapability,
+    ) -> usize {
+        self.kernel
+            .process_map_or(0, app, |process| process.debug_denied_syscall_count())
+    }
+
+    /// Returns the estimated energy, in nanojoules, the app has consumed.
+    pub fn app_energy_consumed(
+        &self,
+        app: AppId,
+        _capability: &dyn ProcessManagementCapability,
+    ) -> u64 {
+        self.kernel
+            .process_map_or(0, app, |process| process.energy_consumed())
+    }
+
     /// Returns a tuple of the (the number of grants in the grant region this
     /// app has allocated, total number of grants that exist in the system).
     pub fn number_app_grant_uses(
@@ -150,6 +172,25 @@ impl KernelInfo {
         (used, number_of_grants)
     }
 
+    /// Returns the name and the flash region (TBF header and application
+    /// binary) of the `index`th loaded process, or `None` if fewer processes
+    /// are loaded. This allows capsules such as boot measurement to read
+    /// process images without requiring `unsafe`.
+    pub fn process_flash_region(
+        &self,
+        index: usize,
+        _capability: &dyn ProcessManagementCapability,
+    ) -> Option<(&'static str, &'static [u8])> {
+        self.kernel.get_process_iter().nth(index).map(|process| {
+            let start = process.flash_start();
+            let len = process.flash_end() as usize - start as usize;
+            // Process flash is `'static` and never written by the kernel
+            // through this reference.
+            let flash = unsafe { slice::from_raw_parts(start, len) };
+            (process.get_process_name(), flash)
+        })
+    }
+
     /// Returns the total number of times all processes have exceeded
     /// their timeslices.
     pub fn timeslice_expirations(&self, _capability: &dyn ProcessManagementCapability) -> usize {
@@ -159,4 +200,24 @@ impl KernelInfo {
         });
         count.get()
     }
+
+    /// Returns the total number of syscalls from all processes that were
+    /// rejected because of driver permissions.
+    pub fn denied_syscalls(&self, _capability: &dyn ProcessManagementCapability) -> usize {
+        let count: Cell<usize> = Cell::new(0);
+        self.kernel.process_each(|proc| {
+            count.add(proc.debug_denied_syscall_count());
+        });
+        count.get()
+    }
+
+    /// Returns the estimated energy, in nanojoules, all processes have
+    /// consumed.
+    pub fn energy_consumed(&self, _capability: &dyn ProcessManagementCapability) -> u64 {
+        let total: Cell<u64> = Cell::new(0);
+        self.kernel.process_each(|proc| {
+            total.set(total.get().saturating_add(proc.energy_consumed()));
+        });
+        total.get()
+    }
 }
@@ -2,12 +2,92 @@
 //!
 //! This is a special syscall driver that allows userspace applications to
 //! share memory.
+//!
+//! Sender records
+//! --------------
+//!
+//! The first argument of an IPC callback is the identifier of the app that
+//! sent the notification. That identifier changes every time an app restarts,
+//! so services that want to authorize requests can instead ask the kernel to
+//! tag each notification with a record of who sent it. To do so an app shares
+//! a buffer with itself (calls allow with its own identifier). Before any IPC
+//! callback is delivered to that app, the kernel writes a record into the
+//! buffer:
+//!
+//! ```text
+//! 0         4       5          6          8                 24
+//! +---------+-------+----------+----------+--------...------+--------...-+
+//! | sender  | flags | name_len | reserved | mac             | name       |
+//! +---------+-------+----------+----------+--------...------+--------...-+
+//! ```
+//!
+//! - `sender`: the sender's identifier as passed to the callback, as a
+//!   little-endian `u32`.
+//! - `flags`: bit 0 is set if `mac` is valid.
+//! - `name_len`: length of `name`, truncated to fit the buffer.
+//! - `mac`: if the board configured a `MessageAuthenticator`, a tag computed
+//!   by the kernel over the sender's name and the contents of the buffer the
+//!   sender shared with the receiver at the time of the notification. A
+//!   service can pass it to a party that shares the authenticator's key to
+//!   prove which app issued a request.
+//! - `name`: the sender's package name from its TBF header.
+//!
+//! Because the record is written into the receiver's own memory, the sender
+//! cannot modify it.
+//!
+//! Named services
+//! --------------
+//!
+//! Besides being found by its package name, a process can register up to
+//! `MAX_SERVICES` named services, each with a version, so that a daemon can
+//! offer several interfaces and clients can check that they understand the
+//! one they find. The services are managed with commands to target `0`,
+//! which take the name from the last buffer the process allowed with target
+//! `0`:
+//!
+//! - command `(0, 0, version)`: register the named service at `version`, or
+//!   change its version. Returns `EBUSY` if another process registered the
+//!   name, and `ENOMEM` if the process already has `MAX_SERVICES` services.
+//! - command `(0, 1, _)`: unregister the named service.
+//! - command `(0, 2, min_version)`: find the named service. Returns the
+//!   identifier of the process that registered it, `ENOSUPPORT` if its
+//!   version is lower than `min_version`, or `EINVAL` if no process
+//!   registered it.
+//!
+//! Allowing a buffer with target `0` also finds registered services, of any
+//! version, if no process has the name as its package name. Services are
+//! unregistered when the process that registered them stops.
+//!
+//! Read-only sharing
+//! -----------------
+//!
+//! A buffer shared with allow is mapped read-write into the process it is
+//! shared with when a notification is delivered. A process that hands out
+//! data in place, such as a network daemon passing received packets to its
+//! clients, can instead share it read-only with command `(0, 3, target_id)`
+//! after the allow, so that the target can read the buffer without copying
+//! it but cannot change it. Sharing a buffer with allow again makes it
+//! read-write.
+//!
+//! Coalescing
+//! ----------
+//!
+//! While a notification is waiting to be delivered to a process, further
+//! notifications of the same type from the same sender succeed without
+//! queueing another callback. A sender that notifies faster than the target
+//! runs cannot fill the target's task queue, and the target sees one
+//! callback for everything the sender has put in the shared buffer.
+
+use core::cell::Cell;
+use core::cmp;
 
 use crate::callback::{AppId, Callback};
 use crate::capabilities::MemoryAllocationCapability;
+use crate::common::cells::OptionalCell;
 use crate::driver::Driver;
 use crate::grant::Grant;
 use crate::mem::{AppSlice, Shared};
+use crate::platform::mpu;
 use crate::process;
 use crate::returncode::ReturnCode;
 use crate::sched::Kernel;
@@ -26,6 +106,49 @@ pub enum IPCCallbackType {
     Client,
 }
 
+/// Length of the MAC in a sender record.
+pub const IPC_MAC_LEN: usize = 16;
+
+/// Offset of the sender's name in a sender record.
+const RECORD_NAME_OFFSET: usize = 8 + IPC_MAC_LEN;
+
+/// The most services a process can register.
+pub const MAX_SERVICES: usize = 4;
+
+/// The longest name of a service.
+pub const MAX_SERVICE_NAME_LEN: usize = 32;
+
+/// Operations of commands to target `0`.
+pub mod service_ops {
+    pub const REGISTER: usize = 0;
+    pub const UNREGISTER: usize = 1;
+    pub const DISCOVER: usize = 2;
+    pub const SHARE_READ_ONLY: usize = 3;
+}
+
+/// A service registered by a process.
+#[derive(Copy, Clone)]
+struct Service {
+    name: [u8; MAX_SERVICE_NAME_LEN],
+    name_len: usize,
+    version: usize,
+}
+
+impl Service {
+    fn name(&self) -> &[u8] {
+        &self.name[..self.name_len]
+    }
+}
+
+/// Computes the MAC the kernel attaches to sender records.
+///
+/// Implementations hold a key that is never exposed to apps, and must run
+/// synchronously as they are called while IPC callbacks are delivered.
+pub trait MessageAuthenticator {
+    /// Compute a MAC over `sender_name` and `message` into `mac`.
+    fn authenticate(&self, sender_name: &[u8], message: &[u8], mac: &mut [u8; IPC_MAC_LEN]);
+}
+
 /// State that is stored in each process's grant region to support IPC.
 #[derive(Default)]
 struct IPCData {
@@ -37,21 +160,219 @@ struct IPCData {
     client_callbacks: [Option<Callback>; 8],
     /// The callback setup by a service. Each process can only be one service.
     callback: Option<Callback>,
+    /// The buffer with the name of the service to register or find.
+    service_name: Option<AppSlice<Shared, u8>>,
+    /// The services this process registered.
+    services: [Option<Service>; MAX_SERVICES],
+    /// Bit `i` is set if `shared_memory[i]` is shared read-only.
+    read_only: u8,
+    /// Bit `i` is set while a service notification from the process with
+    /// index `i` is waiting to be delivered.
+    pending_service: u32,
+    /// Bit `i` is set while a client notification from the process with
+    /// index `i` is waiting to be delivered.
+    pending_client: u32,
+}
+
+impl IPCData {
+    fn pending(&mut self, cb_type: IPCCallbackType) -> &mut u32 {
+        match cb_type {
+            IPCCallbackType::Service => &mut self.pending_service,
+            IPCCallbackType::Client => &mut self.pending_client,
+        }
+    }
+}
+
+/// The bit of a process in the pending notification masks, if its index
+/// fits.
+fn pending_bit(appid: AppId) -> Option<u32> {
+    match appid.index() {
+        Some(i) if i < 32 => Some(1 << i),
+        _ => None,
+    }
 }
 
 /// The IPC mechanism struct.
 pub struct IPC {
     /// The grant regions for each process that holds the per-process IPC data.
     data: Grant<IPCData>,
+    /// Optional MAC engine for sender records.
+    authenticator: OptionalCell<&'static dyn MessageAuthenticator>,
 }
 
 impl IPC {
     pub fn new(kernel: &'static Kernel, capability: &dyn MemoryAllocationCapability) -> IPC {
         IPC {
             data: kernel.create_grant(capability),
+            authenticator: OptionalCell::empty(),
         }
     }
 
+    /// Have the kernel MAC every sender record it writes.
+    pub fn set_authenticator(&self, authenticator: &'static dyn MessageAuthenticator) {
+        self.authenticator.set(authenticator);
+    }
+
+    /// Copies the name of the service the process allowed with target `0`.
+    fn service_name(
+        &self,
+        appid: AppId,
+    ) -> Result<([u8; MAX_SERVICE_NAME_LEN], usize), ReturnCode> {
+        self.data
+            .enter(appid, |data, _| {
+                let slice = data.service_name.as_ref().ok_or(ReturnCode::EINVAL)?;
+                let name = slice.as_ref();
+                if name.is_empty() {
+                    return Err(ReturnCode::EINVAL);
+                }
+                if name.len() > MAX_SERVICE_NAME_LEN {
+                    return Err(ReturnCode::ESIZE);
+                }
+                let mut buf = [0; MAX_SERVICE_NAME_LEN];
+                buf[..name.len()].copy_from_slice(name);
+                Ok((buf, name.len()))
+            })
+            .unwrap_or(Err(ReturnCode::ENOMEM))
+    }
+
+    /// Returns the identifier of the process that registered the service
+    /// `name`, and its version.
+    fn find_service(&self, name: &[u8]) -> Option<(usize, usize)> {
+        let found = Cell::new(None);
+        self.data.each(|data| {
+            for service in data.services.iter().filter_map(|s| s.as_ref()) {
+                if service.name() == name {
+                    found.set(Some((data.appid().id() + 1, service.version)));
+                }
+            }
+        });
+        found.get()
+    }
+
+    /// Registers, finds or unregisters a service, or shares a buffer
+    /// read-only, for a command to target `0`.
+    fn service_command(&self, op: usize, arg: usize, appid: AppId) -> ReturnCode {
+        if op == service_ops::SHARE_READ_ONLY {
+            return self.share_read_only(arg, appid);
+        }
+        let (name, name_len) = match self.service_name(appid) {
+            Ok(name) => name,
+            Err(rcode) => return rcode,
+        };
+        let owner = self.find_service(&name[..name_len]);
+        match op {
+            service_ops::REGISTER => {
+                if owner.map_or(false, |(id, _)| id != appid.id() + 1) {
+                    return ReturnCode::EBUSY;
+                }
+                self.data
+                    .enter(appid, |data, _| {
+                        let service = Service {
+                            name: name,
+                            name_len: name_len,
+                            version: arg,
+                        };
+                        // Change the version of a registered service, or
+                        // take a free entry
+                        let entry = match data
+                            .services
+                            .iter()
+                            .position(|s| s.map_or(false, |s| s.name() == service.name()))
+                        {
+                            Some(i) => Some(i),
+                            None => data.services.iter().position(|s| s.is_none()),
+                        };
+                        match entry {
+                            Some(i) => {
+                                data.services[i] = Some(service);
+                                ReturnCode::SUCCESS
+                            }
+                            None => ReturnCode::ENOMEM,
+                        }
+                    })
+                    .unwrap_or(ReturnCode::ENOMEM)
+            }
+            service_ops::UNREGISTER => self
+                .data
+                .enter(appid, |data, _| {
+                    for entry in data.services.iter_mut() {
+                        if entry.map_or(false, |s| s.name() == &name[..name_len]) {
+                            *entry = None;
+                            return ReturnCode::SUCCESS;
+                        }
+                    }
+                    ReturnCode::EINVAL
+                })
+                .unwrap_or(ReturnCode::ENOMEM),
+            service_ops::DISCOVER => match owner {
+                Some((id, version)) if version >= arg => ReturnCode::SuccessWithValue { value: id },
+                Some(_) => ReturnCode::ENOSUPPORT,
+                None => ReturnCode::EINVAL,
+            },
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// Makes the buffer the process shared with `target_id` read-only for
+    /// the target.
+    fn share_read_only(&self, target_id: usize, appid: AppId) -> ReturnCode {
+        let otherapp = match target_id.checked_sub(1) {
+            Some(app_identifier) => self.data.kernel.lookup_app_by_identifier(app_identifier),
+            None => None,
+        };
+        let i = match otherapp.map_or(None, |oa| oa.index()) {
+            Some(i) => i,
+            None => return ReturnCode::EINVAL,
+        };
+        self.data
+            .enter(appid, |data, _| match data.shared_memory.get(i) {
+                Some(Some(_)) => {
+                    data.read_only |= 1 << i;
+                    ReturnCode::SUCCESS
+                }
+                _ => ReturnCode::EINVAL,
+            })
+            .unwrap_or(ReturnCode::EBUSY)
+    }
+
+    /// Fill in the sender record in `record` for a notification from `sender`
+    /// carrying `message`.
+    fn write_sender_record(
+        &self,
+        record: &mut AppSlice<Shared, u8>,
+        sender: AppId,
+        message: Option<&AppSlice<Shared, u8>>,
+    ) {
+        let record = record.as_mut();
+        if record.len() < RECORD_NAME_OFFSET {
+            return;
+        }
+        let name = self
+            .data
+            .kernel
+            .process_map_or("", sender, |p| p.get_process_name())
+            .as_bytes();
+        let name_len = cmp::min(
+            cmp::min(name.len(), record.len() - RECORD_NAME_OFFSET),
+            u8::MAX as usize,
+        );
+
+        let mut mac = [0; IPC_MAC_LEN];
+        let flags = self.authenticator.map_or(0, |authenticator| {
+            authenticator.authenticate(name, message.map_or(&[], |m| m.as_ref()), &mut mac);
+            1
+        });
+
+        record[0..4].copy_from_slice(&((sender.id() + 1) as u32).to_le_bytes());
+        record[4] = flags;
+        record[5] = name_len as u8;
+        record[6] = 0;
+        record[7] = 0;
+        record[8..RECORD_NAME_OFFSET].copy_from_slice(&mac);
+        record[RECORD_NAME_OFFSET..RECORD_NAME_OFFSET + name_len]
+            .copy_from_slice(&name[..name_len]);
+    }
+
     /// Schedule an IPC callback for a process. This is called by the main
     /// scheduler loop if an IPC task was queued for the process.
     pub(crate) unsafe fn schedule_callback(
@@ -62,6 +383,11 @@ impl IPC {
     ) {
         self.data
             .enter(appid, |mydata, _| {
+                // Later notifications from the sender queue a callback again
+                if let Some(bit) = pending_bit(otherapp) {
+                    *mydata.pending(cb_type) &= !bit;
+                }
+
                 let callback = match cb_type {
                     IPCCallbackType::Service => mydata.callback,
                     IPCCallbackType::Client => match otherapp.index() {
@@ -82,9 +408,25 @@ impl IPC {
                                         return;
                                     }
 
+                                    // If we shared a buffer with ourselves,
+                                    // tell us who is calling.
+                                    if let Some(record) = mydata.shared_memory[i].as_mut() {
+                                        self.write_sender_record(
+                                            record,
+                                            otherapp,
+                                            otherdata.shared_memory[i].as_ref(),
+                                        );
+                                    }
+
                                     match otherdata.shared_memory[i] {
                                         Some(ref slice) => {
-                                            slice.expose_to(appid);
+                                            let permissions = if otherdata.read_only & (1 << i) != 0
+                                            {
+                                                mpu::Permissions::ReadOnly
+                                            } else {
+                                                mpu::Permissions::ReadWriteOnly
+                                            };
+                                            slice.expose_to(appid, permissions);
                                             callback.schedule(
                                                 otherapp.id() + 1,
                                                 slice.len(),
@@ -170,16 +512,25 @@ impl Driver for IPC {
     /// Notifying an IPC service is done by setting client_or_svc to 0,
     /// and notifying an IPC client is done by setting client_or_svc to 1.
     /// In either case, the target_id is the same number as provided in a notify
-    /// callback or as returned by allow.
+    /// callback or as returned by allow. A notification while an earlier one
+    /// from this process is waiting to be delivered is coalesced with it.
+    ///
+    /// If command is called with target_id == 0, it manages named services
+    /// or read-only sharing (see the module documentation), with the
+    /// operation in client_or_svc.
     ///
     /// Returns EINVAL if the other process doesn't exist.
     fn command(
         &self,
         target_id: usize,
         client_or_svc: usize,
-        _: usize,
+        arg: usize,
         appid: AppId,
     ) -> ReturnCode {
+        if target_id == 0 {
+            return self.service_command(client_or_svc, arg, appid);
+        }
+
         let cb_type = if client_or_svc == 0 {
             IPCCallbackType::Service
         } else {
@@ -192,13 +543,38 @@ impl Driver for IPC {
             .kernel
             .lookup_app_by_identifier(app_identifier)
             .map_or(ReturnCode::EINVAL, |otherapp| {
+                // Mark the notification pending for the target, unless it
+                // already is
+                let bit = pending_bit(appid);
+                if let Some(bit) = bit {
+                    let already_pending = self
+                        .data
+                        .enter(otherapp, |data, _| {
+                            let pending = data.pending(cb_type);
+                            let already_pending = *pending & bit != 0;
+                            *pending |= bit;
+                            already_pending
+                        })
+                        .unwrap_or(false);
+                    if already_pending {
+                        return ReturnCode::SUCCESS;
+                    }
+                }
+
                 self.data
                     .kernel
                     .process_map_or(ReturnCode::EINVAL, otherapp, |target| {
                         let ret = target.enqueue_task(process::Task::IPC((appid, cb_type)));
                         match ret {
                             true => ReturnCode::SUCCESS,
-                            false => ReturnCode::FAIL,
+                            false => {
+                                if let Some(bit) = bit {
+                                    let _ = self.data.enter(otherapp, |data, _| {
+                                        *data.pending(cb_type) &= !bit;
+                                    });
+                                }
+                                ReturnCode::FAIL
+                            }
                         }
                     })
             })
@@ -209,14 +585,18 @@ impl Driver for IPC {
     ///
     /// If allow is called with target_id == 0, it is an IPC service discover
     /// call. The contents of the slice should be the string name of the IPC
-    /// service. If this mechanism can find that service, allow will return
-    /// an ID that can be used to notify that service. Otherwise an error will
-    /// be returned.
+    /// service, either a package name or a registered service. If this
+    /// mechanism can find that service, allow will return an ID that can be
+    /// used to notify that service. Otherwise an error will be returned. The
+    /// slice is kept as the name for service commands.
     ///
     /// If allow is called with target_id >= 1, it is a share command where the
     /// application is explicitly sharing a slice with an IPC service (as
     /// specified by the target_id). allow() simply allows both processes to
     /// access the buffer, it does not signal the service.
+    ///
+    /// If the target_id is the caller's own identifier, the slice is used to
+    /// receive sender records (see the module documentation).
     fn allow(
         &self,
         appid: AppId,
@@ -224,8 +604,8 @@ impl Driver for IPC {
         slice: Option<AppSlice<Shared, u8>>,
     ) -> ReturnCode {
         if target_id == 0 {
-            match slice {
-                Some(slice_data) => {
+            let ret = match slice {
+                Some(ref slice_data) => {
                     let ret = self.data.kernel.process_until(|p| {
                         let s = p.get_process_name().as_bytes();
                         // are slices equal?
@@ -240,13 +620,21 @@ impl Driver for IPC {
                         }
                     });
                     if ret != ReturnCode::FAIL {
-                        return ret;
+                        ret
+                    } else {
+                        self.find_service(slice_data.as_ref())
+                            .map_or(ReturnCode::EINVAL, |(id, _)| ReturnCode::SuccessWithValue {
+                                value: id,
+                            })
                     }
                 }
-                None => {}
-            }
+                None => ReturnCode::EINVAL, /* AppSlice must have non-zero length */
+            };
 
-            return ReturnCode::EINVAL; /* AppSlice must have non-zero length */
+            let _ = self.data.enter(appid, |data, _| {
+                data.service_name = slice;
+            });
+            return ret;
         }
         self.data
             .enter(appid, |data, _| {
@@ -257,16 +645,12 @@ impl Driver for IPC {
                 let otherapp = self.data.kernel.lookup_app_by_identifier(app_identifier);
 
                 match otherapp.map_or(None, |oa| oa.index()) {
-                    Some(i) => {
-                        data.shared_memory.get_mut(i).map_or(
-                            ReturnCode::EINVAL, /* Target process does not exist */
-                            |smem| {
-                                *smem = slice;
-                                ReturnCode::SUCCESS
-                            },
-                        )
+                    Some(i) if i < data.shared_memory.len() => {
+                        data.shared_memory[i] = slice;
+                        data.read_only &= !(1 << i);
+                        ReturnCode::SUCCESS
                     }
-                    None => ReturnCode::EINVAL,
+                    _ => ReturnCode::EINVAL, /* Target process does not exist */
                 }
             })
             .unwrap_or(ReturnCode::EBUSY)
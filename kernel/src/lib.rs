@@ -90,12 +90,16 @@
 #![no_std]
 
 pub mod capabilities;
+pub mod clock_manager;
 pub mod common;
 pub mod component;
 pub mod debug;
 pub mod hil;
 pub mod introspection;
 pub mod ipc;
+pub mod liveness;
+pub mod log;
+pub mod power;
 pub mod syscall;
 
 mod callback;
@@ -106,6 +110,7 @@ mod mem;
 mod memop;
 mod platform;
 mod process;
+mod process_loader;
 mod returncode;
 mod sched;
 mod tbfheader;
@@ -136,4 +141,7 @@ pub mod procs {
         Process, ProcessLoadError, ProcessRestartPolicy, ProcessType, State, Task,
         ThresholdRestart, ThresholdRestartThenPanic,
     };
+    pub use crate::process_loader::{
+        DynamicProcessLoader, ProcessImage, ProcessLoader, MAX_LOADED_PROCESSES,
+    };
 }
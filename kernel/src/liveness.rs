@@ -0,0 +1,187 @@
+//! Subsystem liveness for the watchdog.
+//!
+//! The kernel loop tickles the chip's `WatchDog` on every iteration, which only
+//! catches a kernel that hangs outright. A capsule or radio driver whose state
+//! machine is wedged still lets the loop run, so the watchdog never fires.
+//!
+//! A subsystem that should make progress regularly owns a `LivenessSource`,
+//! registered with the board's `LivenessMonitor` along with how long the
+//! subsystem may go without checking in. The subsystem calls `check_in()`
+//! whenever it makes progress, for example from a periodic alarm or when a
+//! transmission completes. Once the kernel has a monitor, it only tickles the
+//! watchdog while every source has checked in within its timeout, and leaves
+//! the watchdog running through sleep while one has not, so that the chip is
+//! reset. A source with nothing to do, such as a radio that is turned off, is
+//! suspended so that it does not hold up the others.
+//!
+//! Usage
+//! -----
+//!
+//! ```ignore
+//! # use kernel::static_init;
+//! # use kernel::liveness::{LivenessMonitor, LivenessSource};
+//!
+//! let liveness = static_init!(
+//!     LivenessMonitor<'static, sam4l::ast::Ast>,
+//!     LivenessMonitor::new(&sam4l::ast::AST)
+//! );
+//! board_kernel.set_liveness(liveness);
+//!
+//! let radio_liveness = static_init!(
+//!     LivenessSource<'static>,
+//!     LivenessSource::new("radio", 5000)
+//! );
+//! liveness.register(radio_liveness);
+//!
+//! // Each time the radio finishes a transmission:
+//! radio_liveness.check_in();
+//! ```
+
+use core::cell::Cell;
+
+use crate::common::{List, ListLink, ListNode};
+use crate::hil::time::{Ticks, Time};
+
+/// Whether every subsystem is making progress. The kernel asks this before
+/// tickling the watchdog.
+pub trait Liveness {
+    fn is_live(&self) -> bool;
+}
+
+/// One subsystem that must check in regularly, owned by its driver.
+pub struct LivenessSource<'a> {
+    name: &'static str,
+    timeout_ms: u32,
+    /// Set by `check_in()`, and cleared when the monitor records the time.
+    checked_in: Cell<bool>,
+    /// The time of the last check in the monitor recorded, in the ticks of
+    /// its clock.
+    last: Cell<u64>,
+    suspended: Cell<bool>,
+    next: ListLink<'a, LivenessSource<'a>>,
+}
+
+impl<'a> ListNode<'a, LivenessSource<'a>> for LivenessSource<'a> {
+    fn next(&'a self) -> &'a ListLink<'a, LivenessSource<'a>> {
+        &self.next
+    }
+}
+
+impl<'a> LivenessSource<'a> {
+    /// Create a source that must check in at least every `timeout_ms`
+    /// milliseconds. `name` identifies the owner in reports. The first
+    /// timeout starts when the source is registered.
+    pub const fn new(name: &'static str, timeout_ms: u32) -> LivenessSource<'a> {
+        LivenessSource {
+            name: name,
+            timeout_ms: timeout_ms,
+            checked_in: Cell::new(true),
+            last: Cell::new(0),
+            suspended: Cell::new(false),
+            next: ListLink::empty(),
+        }
+    }
+
+    /// Record that the subsystem made progress.
+    pub fn check_in(&self) {
+        self.checked_in.set(true);
+    }
+
+    /// Stop requiring check ins until `resume()` is called.
+    pub fn suspend(&self) {
+        self.suspended.set(true);
+    }
+
+    /// Require check ins again, the first one within the timeout from now.
+    pub fn resume(&self) {
+        self.checked_in.set(true);
+        self.suspended.set(false);
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+}
+
+/// Collects the liveness sources of a board, and checks them against the time
+/// of `clock`.
+pub struct LivenessMonitor<'a, T: Time> {
+    clock: &'a T,
+    sources: List<'a, LivenessSource<'a>>,
+}
+
+impl<'a, T: Time> LivenessMonitor<'a, T> {
+    pub fn new(clock: &'a T) -> LivenessMonitor<'a, T> {
+        LivenessMonitor {
+            clock: clock,
+            sources: List::new(),
+        }
+    }
+
+    /// Add a source. Each source must only be registered once.
+    pub fn register(&self, source: &'a LivenessSource<'a>) {
+        source.check_in();
+        self.sources.push_head(source);
+    }
+
+    /// Records a check in of `source` since the last call, and returns whether
+    /// it has gone longer than its timeout without one.
+    fn stalled_at(&self, source: &LivenessSource<'a>, now: T::Ticks) -> bool {
+        if source.checked_in.replace(false) {
+            source.last.set(now.into_u64());
+        }
+        let last = T::Ticks::from_u64_wrapping(source.last.get());
+        !source.suspended.get() && T::ticks_to_ms(now.wrapping_sub(last)) > source.timeout_ms as u64
+    }
+
+    /// The sources that have gone longer than their timeout without checking
+    /// in.
+    pub fn stalled(&'a self) -> impl Iterator<Item = &'a LivenessSource<'a>> {
+        let now = self.clock.now();
+        self.sources
+            .iter()
+            .filter(move |source| self.stalled_at(source, now))
+    }
+}
+
+impl<'a, T: Time> Liveness for LivenessMonitor<'a, T> {
+    fn is_live(&self) -> bool {
+        let now = self.clock.now();
+        // Every source is checked so that all check ins are recorded.
+        self.sources
+            .iter()
+            .fold(true, |live, source| !self.stalled_at(source, now) && live)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::hil::time::MockAlarm;
+
+    #[test]
+    fn stalls_after_timeout() {
+        let clock = MockAlarm::new();
+        let monitor = LivenessMonitor::new(&clock);
+        let radio = LivenessSource::new("radio", 100);
+        let sensor = LivenessSource::new("sensor", 1000);
+        monitor.register(&radio);
+        monitor.register(&sensor);
+        assert!(monitor.is_live());
+
+        clock.advance_ms(90);
+        radio.check_in();
+        assert!(monitor.is_live());
+        clock.advance_ms(100);
+        assert!(monitor.is_live());
+        clock.advance_ms(1);
+        assert!(!monitor.is_live());
+        assert_eq!(monitor.stalled().map(|s| s.name()).next(), Some("radio"));
+
+        radio.suspend();
+        assert!(monitor.is_live());
+        radio.resume();
+        clock.advance_ms(100);
+        assert!(monitor.is_live());
+    }
+}
@@ -0,0 +1,347 @@
+//! Leveled kernel log.
+//!
+//! `debug!` writes every message to the debug output at once, which is too
+//! much for messages that are printed for every packet or every interrupt.
+//! The `log_error!`, `log_warn!`, `log_info!` and `log_trace!` macros instead
+//! give each message a `Level`. A board that sets a `Log` with `set_log`
+//! keeps the messages up to the level of the log in the ring buffer of the
+//! log, where the oldest messages are dropped to make room for new ones,
+//! and also prints those up to the echo level of the log. The messages in
+//! the buffer can be printed later, for example with the `log` command of
+//! the process console. Without a log, messages up to `DEFAULT_LEVEL` are
+//! printed with `debug!`, and the others are dropped.
+//!
+//! Usage
+//! -----
+//!
+//! ```ignore
+//! # use kernel::{log_trace, log_warn, static_init};
+//! # use kernel::common::RingBuffer;
+//! # use kernel::log::{self, Level, Log};
+//!
+//! let buffer = static_init!([u8; 1024], [0; 1024]);
+//! let ring_buffer = static_init!(RingBuffer<'static, u8>, RingBuffer::new(buffer));
+//! let log = static_init!(Log, Log::new(ring_buffer));
+//! log.set_level(Level::Trace);
+//! log.set_echo_level(Some(Level::Error));
+//! log::set_log(log);
+//!
+//! log_warn!("Neighbor not resolved");
+//! log_trace!("Received a frame of {} bytes", len);
+//! ```
+
+use core::cell::Cell;
+use core::fmt::{write, Arguments, Result, Write};
+
+use crate::common::cells::TakeCell;
+use crate::common::{Queue, RingBuffer};
+
+/// How important a message is. A lower level is more important.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    /// Something failed that should not have.
+    Error = 0,
+    /// Something failed that can, such as a packet that could not be sent.
+    Warn = 1,
+    /// Events that are rare enough to print, such as a state change.
+    Info = 2,
+    /// Events that happen for every packet or interrupt.
+    Trace = 3,
+}
+
+/// The most verbose level printed when the board sets no `Log`.
+pub const DEFAULT_LEVEL: Level = Level::Info;
+
+impl Level {
+    pub fn name(self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Trace => "trace",
+        }
+    }
+
+    /// The level with the name `name`, as returned by `name()`.
+    pub fn from_name(name: &str) -> Option<Level> {
+        match name {
+            "error" => Some(Level::Error),
+            "warn" => Some(Level::Warn),
+            "info" => Some(Level::Info),
+            "trace" => Some(Level::Trace),
+            _ => None,
+        }
+    }
+
+    fn from_u8(level: u8) -> Level {
+        match level {
+            0 => Level::Error,
+            1 => Level::Warn,
+            2 => Level::Info,
+            _ => Level::Trace,
+        }
+    }
+}
+
+/// The longest message kept. Longer messages are cut off.
+pub const MAX_MESSAGE_LEN: usize = 128;
+
+/// A ring buffer of log messages. Each message is stored as its level,
+/// followed by its text and a newline, so the ring buffer should hold more
+/// than `MAX_MESSAGE_LEN + 2` bytes for the longest messages to fit.
+pub struct Log {
+    ring_buffer: TakeCell<'static, RingBuffer<'static, u8>>,
+    level: Cell<Level>,
+    echo_level: Cell<Option<Level>>,
+}
+
+impl Log {
+    /// Creates a log that keeps the messages up to `Level::Info`, and prints
+    /// those up to `Level::Warn`.
+    pub fn new(ring_buffer: &'static mut RingBuffer<'static, u8>) -> Log {
+        Log {
+            ring_buffer: TakeCell::new(ring_buffer),
+            level: Cell::new(Level::Info),
+            echo_level: Cell::new(Some(Level::Warn)),
+        }
+    }
+
+    /// Keep the messages up to `level`, and drop the others.
+    pub fn set_level(&self, level: Level) {
+        self.level.set(level);
+    }
+
+    pub fn level(&self) -> Level {
+        self.level.get()
+    }
+
+    /// Also print the messages kept up to `level` with `debug!`, or none if
+    /// it is `None`.
+    pub fn set_echo_level(&self, level: Option<Level>) {
+        self.echo_level.set(level);
+    }
+
+    pub fn echo_level(&self) -> Option<Level> {
+        self.echo_level.get()
+    }
+
+    /// Whether messages of `level` are kept.
+    pub fn enabled(&self, level: Level) -> bool {
+        level <= self.level.get()
+    }
+
+    /// Keeps the message `args` of `level`, if it is enabled.
+    pub fn record(&self, level: Level, args: Arguments) {
+        if !self.enabled(level) {
+            return;
+        }
+        self.ring_buffer.map(|ring_buffer| {
+            let mut writer = MessageWriter {
+                ring_buffer: ring_buffer,
+                len: 0,
+            };
+            writer.push(level as u8);
+            let _ = write(&mut writer, args);
+            writer.push(b'\n');
+        });
+        if self.echo_level.get().map_or(false, |echo| level <= echo) {
+            crate::debug::begin_debug_fmt(args);
+        }
+    }
+
+    /// Calls `f` with the level and text of each message kept, oldest
+    /// first. The messages stay in the log.
+    pub fn each_message<F: FnMut(Level, &str)>(&self, mut f: F) {
+        self.ring_buffer.map(|ring_buffer| {
+            let mut line = [0; MAX_MESSAGE_LEN];
+            let mut line_len = 0;
+            let mut level = None;
+            // Rotate through the whole buffer, so the messages are back in
+            // place at the end.
+            for _ in 0..ring_buffer.len() {
+                let byte = match ring_buffer.dequeue() {
+                    Some(byte) => byte,
+                    None => break,
+                };
+                ring_buffer.enqueue(byte);
+                match level {
+                    None => level = Some(Level::from_u8(byte)),
+                    Some(message_level) if byte == b'\n' => {
+                        // A message cut off in the middle of a character
+                        // loses the whole character.
+                        let text = match core::str::from_utf8(&line[..line_len]) {
+                            Ok(text) => text,
+                            Err(e) => core::str::from_utf8(&line[..e.valid_up_to()]).unwrap_or(""),
+                        };
+                        f(message_level, text);
+                        line_len = 0;
+                        level = None;
+                    }
+                    Some(_) => {
+                        if line_len < line.len() {
+                            line[line_len] = byte;
+                            line_len += 1;
+                        }
+                    }
+                }
+            }
+        });
+    }
+
+    /// Prints the messages kept with `debug!`.
+    pub fn dump(&self) {
+        self.each_message(|level, text| {
+            crate::debug::begin_debug_fmt(format_args!("[{}] {}", level.name(), text));
+        });
+    }
+
+    /// Drops all messages.
+    pub fn clear(&self) {
+        self.ring_buffer.map(|ring_buffer| ring_buffer.empty());
+    }
+}
+
+/// Writes a message to the ring buffer of a log, dropping the oldest messages
+/// as it needs room.
+struct MessageWriter<'a> {
+    ring_buffer: &'a mut RingBuffer<'static, u8>,
+    /// The length of the text written so far.
+    len: usize,
+}
+
+impl MessageWriter<'_> {
+    fn push(&mut self, byte: u8) {
+        if self.ring_buffer.is_full() {
+            // Drop the oldest message as a whole, so the buffer always starts
+            // at the level of a message.
+            while let Some(dropped) = self.ring_buffer.dequeue() {
+                if dropped == b'\n' {
+                    break;
+                }
+            }
+        }
+        self.ring_buffer.enqueue(byte);
+    }
+}
+
+impl Write for MessageWriter<'_> {
+    fn write_str(&mut self, s: &str) -> Result {
+        for &byte in s.as_bytes().iter().take(MAX_MESSAGE_LEN - self.len) {
+            // A newline would end the message early.
+            self.push(if byte == b'\n' { b' ' } else { byte });
+            self.len += 1;
+        }
+        Ok(())
+    }
+}
+
+static mut LOG: Option<&'static Log> = None;
+
+/// Function used by board main.rs to set the log.
+pub unsafe fn set_log(log: &'static Log) {
+    LOG = Some(log);
+}
+
+/// The log the board set, if any.
+pub fn get_log() -> Option<&'static Log> {
+    unsafe { LOG }
+}
+
+pub fn log_fmt(level: Level, args: Arguments) {
+    match get_log() {
+        Some(log) => log.record(level, args),
+        None => {
+            if level <= DEFAULT_LEVEL {
+                crate::debug::begin_debug_fmt(args);
+            }
+        }
+    }
+}
+
+/// Logs a message of `Level::Error`.
+#[macro_export]
+macro_rules! log_error {
+    ($($arg:tt)+) => ({
+        $crate::log::log_fmt($crate::log::Level::Error, format_args!($($arg)+))
+    });
+}
+
+/// Logs a message of `Level::Warn`.
+#[macro_export]
+macro_rules! log_warn {
+    ($($arg:tt)+) => ({
+        $crate::log::log_fmt($crate::log::Level::Warn, format_args!($($arg)+))
+    });
+}
+
+/// Logs a message of `Level::Info`.
+#[macro_export]
+macro_rules! log_info {
+    ($($arg:tt)+) => ({
+        $crate::log::log_fmt($crate::log::Level::Info, format_args!($($arg)+))
+    });
+}
+
+/// Logs a message of `Level::Trace`.
+#[macro_export]
+macro_rules! log_trace {
+    ($($arg:tt)+) => ({
+        $crate::log::log_fmt($crate::log::Level::Trace, format_args!($($arg)+))
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn messages(log: &Log) -> ([(Level, [u8; 8], usize); 4], usize) {
+        let mut found = [(Level::Error, [0; 8], 0); 4];
+        let mut count = 0;
+        log.each_message(|level, text| {
+            found[count].0 = level;
+            found[count].1[..text.len()].copy_from_slice(text.as_bytes());
+            found[count].2 = text.len();
+            count += 1;
+        });
+        (found, count)
+    }
+
+    #[test]
+    fn drops_oldest_and_filtered_messages() {
+        static mut BUFFER: [u8; 16] = [0; 16];
+        static mut RING_BUFFER: Option<RingBuffer<'static, u8>> = None;
+        let ring_buffer = unsafe {
+            RING_BUFFER = Some(RingBuffer::new(&mut BUFFER));
+            RING_BUFFER.as_mut().unwrap()
+        };
+        let log = Log::new(ring_buffer);
+        log.set_echo_level(None);
+
+        log.record(Level::Warn, format_args!("one"));
+        log.record(Level::Trace, format_args!("skip"));
+        log.record(Level::Info, format_args!("two {}", 2));
+        let (found, count) = messages(&log);
+        assert_eq!(count, 2);
+        assert_eq!(
+            (found[0].0, &found[0].1[..found[0].2]),
+            (Level::Warn, &b"one"[..])
+        );
+        assert_eq!(
+            (found[1].0, &found[1].1[..found[1].2]),
+            (Level::Info, &b"two 2"[..])
+        );
+
+        // The buffer holds 15 bytes, so this drops "one" but not "two 2".
+        log.record(Level::Error, format_args!("three"));
+        let (found, count) = messages(&log);
+        assert_eq!(count, 2);
+        assert_eq!(&found[0].1[..found[0].2], b"two 2");
+        assert_eq!(
+            (found[1].0, &found[1].1[..found[1].2]),
+            (Level::Error, &b"three"[..])
+        );
+
+        log.clear();
+        assert_eq!(messages(&log).1, 0);
+    }
+}
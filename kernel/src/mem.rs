@@ -6,6 +6,7 @@ use core::slice;
 
 use crate::callback::AppId;
 use crate::capabilities;
+use crate::platform::mpu;
 
 /// Type for specifying an AppSlice is hidden from the kernel.
 #[derive(Debug)]
@@ -87,16 +88,21 @@ impl<L, T> AppSlice<L, T> {
         self.ptr.ptr.as_ptr()
     }
 
-    /// Provide access to one app's AppSlice to another app. This is used for
-    /// IPC.
-    pub(crate) unsafe fn expose_to(&self, appid: AppId) -> bool {
+    /// Provide access to one app's AppSlice to another app, with
+    /// `permissions`. This is used for IPC.
+    pub(crate) unsafe fn expose_to(&self, appid: AppId, permissions: mpu::Permissions) -> bool {
         if appid != self.ptr.process {
             self.ptr
                 .process
                 .kernel
                 .process_map_or(false, appid, |process| {
                     process
-                        .add_mpu_region(self.ptr() as *const u8, self.len(), self.len())
+                        .add_mpu_region(
+                            self.ptr() as *const u8,
+                            self.len(),
+                            self.len(),
+                            permissions,
+                        )
                         .is_some()
                 })
         } else {
@@ -1,6 +1,7 @@
 //! Interface for chips and boards.
 
 use crate::driver::Driver;
+use crate::power::SleepState;
 use crate::process;
 use crate::returncode;
 use crate::syscall;
@@ -119,6 +120,14 @@ pub trait Chip {
     /// chip and resumes the scheduler.
     fn sleep(&self);
 
+    /// Enter a low power sleep state no deeper than `state`, which is never
+    /// `SleepState::Active`. Chips with more than one sleep state should
+    /// implement this so that they only enter their deep sleep state when
+    /// `state` is `SleepState::DeepSleep`; the default calls `sleep()`.
+    fn sleep_in(&self, _state: SleepState) {
+        self.sleep();
+    }
+
     /// Run a function in an atomic state, which means that interrupts are
     /// disabled so that an interrupt will not fire during the passed in
     /// function's execution.
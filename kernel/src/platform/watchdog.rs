@@ -4,6 +4,8 @@
 /// This trait is called from the `kernel_loop()` code to setup
 /// and maintain the watchdog timer.
 /// It is up to the specific `Chip` how it will handle watchdog interrupts.
+/// If the board gives the kernel a `liveness::Liveness`, `tickle()` is only
+/// called while every subsystem is making progress.
 pub trait WatchDog {
     /// This function must enable the watchdog timer and configure it to
     /// trigger regulary. The period of the timer is left to the implementation
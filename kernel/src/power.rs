@@ -0,0 +1,146 @@
+//! System-wide sleep state selection.
+//!
+//! Drivers that cannot tolerate some sleep states, for example because a
+//! peripheral loses its clock in deep sleep or a transfer needs a short wakeup
+//! latency, each own a `PowerConstraint` registered with the board's
+//! `PowerManager`. A driver raises its constraint while it needs the chip to
+//! stay shallow and releases it when it is done. When the kernel has nothing
+//! to do, it asks the chip to sleep in the deepest state that no constraint
+//! forbids.
+//!
+//! The kernel also estimates the energy each process consumes. Processes are
+//! charged for the time they spend on the CPU, at the power set with
+//! `Kernel::set_cpu_active_power()`, and drivers charge them with
+//! `AppId::charge_energy()` for peripherals used on their behalf.
+//!
+//! Usage
+//! -----
+//!
+//! ```ignore
+//! # use kernel::static_init;
+//! # use kernel::power::{PowerConstraint, PowerManager, SleepState};
+//!
+//! let power_manager = static_init!(PowerManager<'static>, PowerManager::new());
+//! board_kernel.set_power_manager(power_manager);
+//!
+//! let spi_constraint = static_init!(PowerConstraint<'static>, PowerConstraint::new("spi"));
+//! power_manager.register(spi_constraint);
+//!
+//! // While a transfer is in progress:
+//! spi_constraint.require(SleepState::Sleep);
+//! // Once it finishes:
+//! spi_constraint.release();
+//! ```
+
+use core::cell::Cell;
+use core::fmt;
+
+use crate::common::{List, ListLink, ListNode};
+
+/// Sleep states, from shallowest to deepest. What each state means in terms
+/// of clocks and wakeup latency is chip specific.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SleepState {
+    /// The chip must not sleep at all.
+    Active,
+    /// The core may stop, but peripheral clocks keep running.
+    Sleep,
+    /// The deepest state the chip supports.
+    DeepSleep,
+}
+
+impl fmt::Display for SleepState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            SleepState::Active => "active",
+            SleepState::Sleep => "sleep",
+            SleepState::DeepSleep => "deep sleep",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Energy in nanojoules used by drawing `power_uw` microwatts for
+/// `duration_us` microseconds.
+pub fn energy_nj(power_uw: u32, duration_us: u32) -> u64 {
+    (power_uw as u64 * duration_us as u64) / 1000
+}
+
+/// A limit on how deeply the chip may sleep, owned by one driver.
+pub struct PowerConstraint<'a> {
+    name: &'static str,
+    deepest: Cell<SleepState>,
+    next: ListLink<'a, PowerConstraint<'a>>,
+}
+
+impl<'a> ListNode<'a, PowerConstraint<'a>> for PowerConstraint<'a> {
+    fn next(&'a self) -> &'a ListLink<'a, PowerConstraint<'a>> {
+        &self.next
+    }
+}
+
+impl<'a> PowerConstraint<'a> {
+    /// Create a constraint that does not limit sleep. `name` identifies the
+    /// owner in reports.
+    pub const fn new(name: &'static str) -> PowerConstraint<'a> {
+        PowerConstraint {
+            name: name,
+            deepest: Cell::new(SleepState::DeepSleep),
+            next: ListLink::empty(),
+        }
+    }
+
+    /// Do not let the chip sleep deeper than `state` until `release()` is
+    /// called. This replaces any previous limit from this constraint.
+    pub fn require(&self, state: SleepState) {
+        self.deepest.set(state);
+    }
+
+    /// Remove this constraint's limit.
+    pub fn release(&self) {
+        self.deepest.set(SleepState::DeepSleep);
+    }
+
+    /// The deepest state this constraint allows.
+    pub fn deepest(&self) -> SleepState {
+        self.deepest.get()
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+}
+
+/// Collects the constraints of all drivers on a board.
+pub struct PowerManager<'a> {
+    constraints: List<'a, PowerConstraint<'a>>,
+}
+
+impl<'a> PowerManager<'a> {
+    pub const fn new() -> PowerManager<'a> {
+        PowerManager {
+            constraints: List::new(),
+        }
+    }
+
+    /// Add a constraint. Each constraint must only be registered once.
+    pub fn register(&self, constraint: &'a PowerConstraint<'a>) {
+        self.constraints.push_head(constraint);
+    }
+
+    /// The deepest sleep state that every constraint allows.
+    pub fn deepest_sleep_state(&self) -> SleepState {
+        self.constraints
+            .iter()
+            .map(|constraint| constraint.deepest())
+            .min()
+            .unwrap_or(SleepState::DeepSleep)
+    }
+
+    /// The constraints that currently keep the chip out of deep sleep.
+    pub fn blocking(&self) -> impl Iterator<Item = &'a PowerConstraint<'a>> {
+        self.constraints
+            .iter()
+            .filter(|constraint| constraint.deepest() < SleepState::DeepSleep)
+    }
+}
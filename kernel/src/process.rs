@@ -10,6 +10,7 @@ use core::{mem, ptr, slice, str};
 use crate::callback::{AppId, CallbackId};
 use crate::capabilities::ProcessManagementCapability;
 use crate::common::cells::{MapCell, NumericCellExt};
+use crate::common::constant_time;
 use crate::common::{Queue, RingBuffer};
 use crate::config;
 use crate::debug;
@@ -56,6 +57,13 @@ pub enum ProcessLoadError {
         expected_address: u32,
     },
 
+    /// The image is padding, or an app that is not enabled, so no process
+    /// was created from it.
+    NotAnApp,
+
+    /// There is no free slot for another process.
+    NoProcessSlot,
+
     /// Process loading error due (likely) to a bug in the kernel. If you get
     /// this error please open a bug report.
     InternalError,
@@ -109,6 +117,10 @@ impl fmt::Debug for ProcessLoadError {
                 actual_address, expected_address
             ),
 
+            ProcessLoadError::NotAnApp => write!(f, "Image is padding or a disabled app"),
+
+            ProcessLoadError::NoProcessSlot => write!(f, "No free slot for another process"),
+
             ProcessLoadError::InternalError => write!(f, "Error in kernel. Likely a bug."),
         }
     }
@@ -275,6 +287,19 @@ pub trait ProcessType {
     /// Returns whether this process is ready to execute.
     fn ready(&self) -> bool;
 
+    /// Stop delivering queued tasks to this process until `release_tasks()`
+    /// is called. Tasks enqueued in the meantime are kept, so they are all
+    /// delivered together when the process is released. While its tasks are
+    /// held a process that has yielded does not count as work for the
+    /// kernel, so the chip can sleep.
+    fn hold_tasks(&self);
+
+    /// Resume delivering tasks held with `hold_tasks()`.
+    fn release_tasks(&self);
+
+    /// Whether this process's tasks are being held.
+    fn tasks_held(&self) -> bool;
+
     /// Remove the scheduled operation from the front of the queue and return it
     /// to be handled by the scheduler.
     ///
@@ -312,9 +337,25 @@ pub trait ProcessType {
     /// `FaultResponse` for this process to occur.
     fn set_fault_state(&self);
 
+    /// Stop the process and free its grants, queued tasks and memory. The
+    /// process does not run again unless it is restarted.
+    fn terminate(&self);
+
+    /// Terminate the process and start it again from its entry point with a
+    /// new identifier, whatever its `FaultResponse`. Returns `false`, leaving
+    /// the process terminated, if it could not be started again.
+    fn force_restart(&self) -> bool;
+
     /// Returns how many times this process has been restarted.
     fn get_restart_count(&self) -> usize;
 
+    /// Returns the estimated energy, in nanojoules, this process has consumed
+    /// since the kernel booted. This includes energy used before any restart.
+    fn energy_consumed(&self) -> u64;
+
+    /// Attribute `nanojoules` of energy to this process.
+    fn charge_energy(&self, nanojoules: u64);
+
     /// Get the name of the process. Used for IPC.
     fn get_process_name(&self) -> &'static str;
 
@@ -360,6 +401,10 @@ pub trait ProcessType {
     /// writeable flash region.
     fn get_writeable_flash_region(&self, region_index: usize) -> (u32, u32);
 
+    /// Whether the TBF header of this process allows it to access the driver
+    /// with number `driver_number`.
+    fn permits_driver(&self, driver_number: usize) -> bool;
+
     /// Debug function to update the kernel on where the stack starts for this
     /// process. Processes are not required to call this through the memop
     /// system call, but it aids in debugging the process.
@@ -408,8 +453,8 @@ pub trait ProcessType {
     fn setup_mpu(&self);
 
     /// Allocate a new MPU region for the process that is at least
-    /// `min_region_size` bytes and lies within the specified stretch of
-    /// unallocated memory.
+    /// `min_region_size` bytes, lies within the specified stretch of
+    /// unallocated memory and gives the process `permissions` to it.
     ///
     /// It is not valid to call this function when the process is inactive (i.e.
     /// the process will not run again).
@@ -418,6 +463,7 @@ pub trait ProcessType {
         unallocated_memory_start: *const u8,
         unallocated_memory_size: usize,
         min_region_size: usize,
+        permissions: mpu::Permissions,
     ) -> Option<mpu::Region>;
 
     // grants
@@ -489,6 +535,14 @@ pub trait ProcessType {
     /// Increment the number of times the process has exceeded its timeslice.
     fn debug_timeslice_expired(&self);
 
+    /// Returns how many syscalls of this process were rejected because the
+    /// process is not permitted to access the driver.
+    fn debug_denied_syscall_count(&self) -> usize;
+
+    /// Increment the number of syscalls of this process that were rejected
+    /// because of its driver permissions.
+    fn debug_syscall_denied(&self);
+
     /// Increment the number of times the process called a syscall and record
     /// the last syscall that was called.
     fn debug_syscall_called(&self, last_syscall: Syscall);
@@ -771,6 +825,10 @@ struct ProcessDebug {
     /// How many times this process has been paused because it exceeded its
     /// timeslice.
     timeslice_expiration_count: usize,
+
+    /// How many syscalls were rejected because the process is not permitted
+    /// to access the driver.
+    denied_syscall_count: usize,
 }
 
 /// A type for userspace processes in Tock.
@@ -881,6 +939,15 @@ pub struct Process<'a, C: 'static + Chip> {
     /// determine if the process should be restarted or not.
     restart_count: Cell<usize>,
 
+    /// Estimated energy in nanojoules attributed to this process, both from
+    /// the time it spent on the CPU and from the peripherals drivers used on
+    /// its behalf.
+    energy_consumed: Cell<u64>,
+
+    /// Whether tasks are held back from this process. Held tasks are not
+    /// counted in the kernel's work counter.
+    tasks_held: Cell<bool>,
+
     /// Name of the app.
     process_name: &'static str,
 
@@ -908,7 +975,7 @@ impl<C: Chip> ProcessType for Process<'_, C> {
             self.debug.map(|debug| {
                 debug.dropped_callback_count += 1;
             });
-        } else {
+        } else if !self.tasks_held.get() {
             self.kernel.increment_work();
         }
 
@@ -916,10 +983,34 @@ impl<C: Chip> ProcessType for Process<'_, C> {
     }
 
     fn ready(&self) -> bool {
-        self.tasks.map_or(false, |ring_buf| ring_buf.has_elements())
+        (!self.tasks_held.get() && self.tasks.map_or(false, |ring_buf| ring_buf.has_elements()))
             || self.state.get() == State::Running
     }
 
+    fn hold_tasks(&self) {
+        if !self.tasks_held.get() {
+            self.tasks_held.set(true);
+            let tasks_len = self.tasks.map_or(0, |tasks| tasks.len());
+            for _ in 0..tasks_len {
+                self.kernel.decrement_work();
+            }
+        }
+    }
+
+    fn release_tasks(&self) {
+        if self.tasks_held.get() {
+            self.tasks_held.set(false);
+            let tasks_len = self.tasks.map_or(0, |tasks| tasks.len());
+            for _ in 0..tasks_len {
+                self.kernel.increment_work();
+            }
+        }
+    }
+
+    fn tasks_held(&self) -> bool {
+        self.tasks_held.get()
+    }
+
     fn remove_pending_callbacks(&self, callback_id: CallbackId) {
         self.tasks.map(|tasks| {
             let count_before = tasks.len();
@@ -932,7 +1023,9 @@ impl<C: Chip> ProcessType for Process<'_, C> {
                         if id != callback_id {
                             true
                         } else {
-                            self.kernel.decrement_work();
+                            if !self.tasks_held.get() {
+                                self.kernel.decrement_work();
+                            }
                             false
                         }
                     }
@@ -1000,11 +1093,66 @@ impl<C: Chip> ProcessType for Process<'_, C> {
         }
     }
 
+    /// Stop and clear a process's state.
+    ///
+    /// This will end the process, but does not reset it such that it could be
+    /// restarted and run again. This function instead frees grants and any
+    /// queued tasks for this process and zeroes its memory, but leaves the
+    /// debug information about the process and other state intact.
+    fn terminate(&self) {
+        // Remove the tasks that were scheduled for the app from the
+        // amount of work queue.
+        // Held tasks were already removed from it.
+        if !self.tasks_held.get() {
+            let tasks_len = self.tasks.map_or(0, |tasks| tasks.len());
+            for _ in 0..tasks_len {
+                self.kernel.decrement_work();
+            }
+        }
+        self.tasks_held.set(false);
+
+        // And remove those tasks
+        self.tasks.map(|tasks| {
+            tasks.empty();
+        });
+
+        // Clear any grant regions this app has setup with any capsules.
+        unsafe {
+            self.grant_ptrs_reset();
+        }
+
+        // Erase everything the process and capsules stored in its memory so
+        // that no secrets survive into a restarted instance of the process.
+        unsafe {
+            self.zero_memory();
+        }
+
+        // Mark the app as stopped so the scheduler won't try to run it.
+        self.state.update(State::StoppedFaulted);
+    }
+
+    fn force_restart(&self) -> bool {
+        self.terminate();
+        self.start_again()
+    }
+
     fn get_restart_count(&self) -> usize {
         self.restart_count.get()
     }
 
+    fn energy_consumed(&self) -> u64 {
+        self.energy_consumed.get()
+    }
+
+    fn charge_energy(&self, nanojoules: u64) {
+        self.energy_consumed
+            .set(self.energy_consumed.get().saturating_add(nanojoules));
+    }
+
     fn dequeue_task(&self) -> Option<Task> {
+        if self.tasks_held.get() {
+            return None;
+        }
         self.tasks.map_or(None, |tasks| {
             tasks.dequeue().map(|cb| {
                 self.kernel.decrement_work();
@@ -1045,6 +1193,10 @@ impl<C: Chip> ProcessType for Process<'_, C> {
         self.header.get_writeable_flash_region(region_index)
     }
 
+    fn permits_driver(&self, driver_number: usize) -> bool {
+        self.header.permits_driver(driver_number)
+    }
+
     fn update_stack_start_pointer(&self, stack_pointer: *const u8) {
         if stack_pointer >= self.mem_start() && stack_pointer < self.mem_end() {
             self.debug.map(|debug| {
@@ -1076,13 +1228,14 @@ impl<C: Chip> ProcessType for Process<'_, C> {
         unallocated_memory_start: *const u8,
         unallocated_memory_size: usize,
         min_region_size: usize,
+        permissions: mpu::Permissions,
     ) -> Option<mpu::Region> {
         self.mpu_config.and_then(|mut config| {
             let new_region = self.chip.mpu().allocate_region(
                 unallocated_memory_start,
                 unallocated_memory_size,
                 min_region_size,
-                mpu::Permissions::ReadWriteOnly,
+                permissions,
                 &mut config,
             );
 
@@ -1373,6 +1526,14 @@ impl<C: Chip> ProcessType for Process<'_, C> {
             .map(|debug| debug.timeslice_expiration_count += 1);
     }
 
+    fn debug_denied_syscall_count(&self) -> usize {
+        self.debug.map_or(0, |debug| debug.denied_syscall_count)
+    }
+
+    fn debug_syscall_denied(&self) {
+        self.debug.map(|debug| debug.denied_syscall_count += 1);
+    }
+
     fn debug_syscall_called(&self, last_syscall: Syscall) {
         self.debug.map(|debug| {
             debug.syscall_count += 1;
@@ -1916,6 +2077,8 @@ impl<C: 'static + Chip> Process<'_, C> {
         process.state = ProcessStateCell::new(process.kernel);
         process.fault_response = fault_response;
         process.restart_count = Cell::new(0);
+        process.energy_consumed = Cell::new(0);
+        process.tasks_held = Cell::new(false);
 
         process.mpu_config = MapCell::new(mpu_config);
         process.mpu_regions = [
@@ -1939,6 +2102,7 @@ impl<C: 'static + Chip> Process<'_, C> {
             last_syscall: None,
             dropped_callback_count: 0,
             timeslice_expiration_count: 0,
+            denied_syscall_count: 0,
         });
 
         let flash_protected_size = process.header.get_protected_size() as usize;
@@ -2039,6 +2203,13 @@ impl<C: 'static + Chip> Process<'_, C> {
             }
         }
 
+        self.start_again();
+    }
+
+    /// Start a terminated process again from its entry point. Returns `false`
+    /// if the process could not be started, in which case it is left in the
+    /// state it was terminated in.
+    fn start_again(&self) -> bool {
         // We need a new process identifier for this process since the restarted
         // version is in effect a new process. This is also necessary to
         // invalidate any stored `AppId`s that point to the old version of the
@@ -2055,6 +2226,7 @@ impl<C: 'static + Chip> Process<'_, C> {
             debug.last_syscall = None;
             debug.dropped_callback_count = 0;
             debug.timeslice_expiration_count = 0;
+            debug.denied_syscall_count = 0;
         });
 
         // We are going to start this process over again, so need the init_fn
@@ -2096,7 +2268,7 @@ impl<C: 'static + Chip> Process<'_, C> {
                 // point the app is no longer valid. The best thing we
                 // can do now is leave the app as still faulted and not
                 // schedule it.
-                return;
+                return false;
             }
         };
 
@@ -2124,34 +2296,7 @@ impl<C: 'static + Chip> Process<'_, C> {
 
         // Mark that the process is ready to run.
         self.kernel.increment_work();
-    }
-
-    /// Stop and clear a process's state.
-    ///
-    /// This will end the process, but does not reset it such that it could be
-    /// restarted and run again. This function instead frees grants and any
-    /// queued tasks for this process, but leaves the debug information about
-    /// the process and other state intact.
-    fn terminate(&self) {
-        // Remove the tasks that were scheduled for the app from the
-        // amount of work queue.
-        let tasks_len = self.tasks.map_or(0, |tasks| tasks.len());
-        for _ in 0..tasks_len {
-            self.kernel.decrement_work();
-        }
-
-        // And remove those tasks
-        self.tasks.map(|tasks| {
-            tasks.empty();
-        });
-
-        // Clear any grant regions this app has setup with any capsules.
-        unsafe {
-            self.grant_ptrs_reset();
-        }
-
-        // Mark the app as stopped so the scheduler won't try to run it.
-        self.state.update(State::StoppedFaulted);
+        true
     }
 
     /// Get the current stack pointer as a pointer.
@@ -2196,6 +2341,22 @@ impl<C: 'static + Chip> Process<'_, C> {
         }
     }
 
+    /// Overwrite the process's RAM and the grant memory allocated in it with
+    /// zeros, and reset the saved register state.
+    ///
+    /// The memory above `original_kernel_memory_break` holds the `Process`
+    /// struct, its task queue and the grant pointers, so it is left alone.
+    /// Grants must have been cleared with `grant_ptrs_reset()` first so that
+    /// no capsule can still reach the erased grant memory.
+    unsafe fn zero_memory(&self) {
+        let len = self.original_kernel_memory_break as usize - self.mem_start() as usize;
+        constant_time::wipe(slice::from_raw_parts_mut(self.mem_start() as *mut u8, len));
+
+        self.stored_state.map(|stored_state| {
+            *stored_state = Default::default();
+        });
+    }
+
     fn debug_set_max_stack_depth(&self) {
         self.debug.map(|debug| {
             if self.current_stack_pointer.get() < debug.min_stack_pointer {
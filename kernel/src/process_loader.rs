@@ -0,0 +1,282 @@
+//! Loading processes from flash after the kernel has booted.
+//!
+//! `load_processes()` creates the processes that are in flash when the board
+//! boots. `DynamicProcessLoader` instead manages a region of flash that
+//! process images are written to later, for example by an update received
+//! over the network: it checks the TBF header of an image in the region,
+//! creates and starts a process from it in a free slot of the processes
+//! array, and unloads and restarts processes, so apps can be updated without
+//! reflashing the kernel.
+//!
+//! Loading an image whose package name is the name of an existing process
+//! replaces that process. Loaded processes are given memory from a region
+//! the board reserves for the loader, in the order they are loaded. The
+//! memory of an unloaded process is reused once every process loaded after
+//! it has been unloaded as well, so replacing the most recently loaded
+//! process does not use more memory.
+//!
+//! Capsules use the loader through the `ProcessLoader` trait, which the
+//! loader implements with the `ProcessManagementCapability` it was created
+//! with. A process must not be unloaded or restarted, or replaced, while one
+//! of its system calls is being handled.
+//!
+//! Usage
+//! -----
+//!
+//! ```ignore
+//! use kernel::procs::{DynamicProcessLoader, FaultResponse};
+//!
+//! let process_loader = static_init!(
+//!     DynamicProcessLoader<nrf52840::chip::Chip>,
+//!     DynamicProcessLoader::new(
+//!         board_kernel,
+//!         chip,
+//!         &mut PROCESSES,
+//!         &DYNAMIC_APP_FLASH,
+//!         &mut DYNAMIC_APP_MEMORY,
+//!         FaultResponse::Panic,
+//!         &process_management_capability,
+//!     )
+//! );
+//! ```
+
+use core::cell::Cell;
+use core::convert::TryInto;
+use core::slice;
+
+use crate::callback::AppId;
+use crate::capabilities::ProcessManagementCapability;
+use crate::common::cells::MapCell;
+use crate::platform::Chip;
+use crate::process::{FaultResponse, Process, ProcessLoadError, ProcessType};
+use crate::returncode::ReturnCode;
+use crate::sched::Kernel;
+use crate::tbfheader;
+
+/// The most processes a `DynamicProcessLoader` keeps loaded at once.
+pub const MAX_LOADED_PROCESSES: usize = 8;
+
+/// A process image in the flash region of a loader.
+#[derive(Copy, Clone, Debug)]
+pub struct ProcessImage {
+    /// The offset of the image in the region.
+    pub offset: usize,
+    /// The length of the image, including its TBF header.
+    pub len: usize,
+    /// The package name of the process, if its header has one.
+    pub name: Option<&'static str>,
+    /// The flash of the image, `len` bytes long.
+    pub flash: &'static [u8],
+}
+
+/// Loads and manages processes after the kernel has booted.
+pub trait ProcessLoader {
+    /// Checks the TBF header of the image at `offset` in the flash region.
+    /// Returns `NotAnApp` if the image is padding or a disabled app.
+    fn image(&self, offset: usize) -> Result<ProcessImage, ProcessLoadError>;
+
+    /// Creates a process from the image at `offset`, which starts running
+    /// at once, replacing any process with the same package name.
+    fn load(&self, offset: usize) -> Result<AppId, ProcessLoadError>;
+
+    /// Terminates `app` and removes it from the processes array.
+    fn unload(&self, app: AppId) -> ReturnCode;
+
+    /// Terminates `app` and starts it again from its entry point, with a new
+    /// identifier.
+    fn restart(&self, app: AppId) -> ReturnCode;
+
+    /// Returns the process with `identifier`, as returned by `AppId::id()`.
+    fn lookup_app(&self, identifier: usize) -> Option<AppId>;
+}
+
+/// A loaded process, and the offset of the end of its memory in the loader's
+/// memory.
+#[derive(Copy, Clone)]
+struct Allocation {
+    index: usize,
+    end: usize,
+}
+
+pub struct DynamicProcessLoader<C: 'static + Chip> {
+    kernel: &'static Kernel,
+    chip: &'static C,
+    procs: MapCell<&'static mut [Option<&'static dyn ProcessType>]>,
+    flash: &'static [u8],
+    memory: *mut u8,
+    memory_len: usize,
+    /// The processes this loader created.
+    allocations: [Cell<Option<Allocation>>; MAX_LOADED_PROCESSES],
+    fault_response: FaultResponse,
+}
+
+impl<C: 'static + Chip> DynamicProcessLoader<C> {
+    /// Creates a loader for the images in `flash`, which are given memory
+    /// from `memory`. `procs` must be the processes array of `kernel`.
+    pub fn new(
+        kernel: &'static Kernel,
+        chip: &'static C,
+        procs: &'static mut [Option<&'static dyn ProcessType>],
+        flash: &'static [u8],
+        memory: &'static mut [u8],
+        fault_response: FaultResponse,
+        _capability: &dyn ProcessManagementCapability,
+    ) -> DynamicProcessLoader<C> {
+        DynamicProcessLoader {
+            kernel: kernel,
+            chip: chip,
+            procs: MapCell::new(procs),
+            flash: flash,
+            memory: memory.as_mut_ptr(),
+            memory_len: memory.len(),
+            allocations: Default::default(),
+            fault_response: fault_response,
+        }
+    }
+
+    /// Parses the image at `offset`, returning its flash, the length and
+    /// version of its header, and the header.
+    fn parse(
+        &self,
+        offset: usize,
+    ) -> Result<(&'static [u8], usize, u16, tbfheader::TbfHeader), ProcessLoadError> {
+        let flash = self
+            .flash
+            .get(offset..)
+            .ok_or(ProcessLoadError::NotEnoughFlash)?;
+        let lengths = flash
+            .get(0..8)
+            .ok_or(ProcessLoadError::NotEnoughFlash)?
+            .try_into()
+            .or(Err(ProcessLoadError::InternalError))?;
+        let (version, header_length, entry_length) =
+            tbfheader::parse_tbf_header_lengths(lengths).or(Err(ProcessLoadError::NotAnApp))?;
+        let entry_flash = flash
+            .get(0..entry_length as usize)
+            .ok_or(ProcessLoadError::NotEnoughFlash)?;
+        let header = tbfheader::parse_tbf_header(
+            entry_flash
+                .get(0..header_length as usize)
+                .ok_or(ProcessLoadError::NotEnoughFlash)?,
+            version,
+        )?;
+        if !header.is_app() || !header.enabled() {
+            return Err(ProcessLoadError::NotAnApp);
+        }
+        Ok((entry_flash, header_length as usize, version, header))
+    }
+
+    /// The offset of the memory after every loaded process.
+    fn memory_used(&self) -> usize {
+        self.allocations
+            .iter()
+            .filter_map(|allocation| allocation.get())
+            .map(|allocation| allocation.end)
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+impl<C: 'static + Chip> ProcessLoader for DynamicProcessLoader<C> {
+    fn image(&self, offset: usize) -> Result<ProcessImage, ProcessLoadError> {
+        let (entry_flash, _, _, header) = self.parse(offset)?;
+        Ok(ProcessImage {
+            offset: offset,
+            len: entry_flash.len(),
+            name: header.get_package_name(),
+            flash: entry_flash,
+        })
+    }
+
+    fn load(&self, offset: usize) -> Result<AppId, ProcessLoadError> {
+        let (entry_flash, header_length, version, header) = self.parse(offset)?;
+
+        // Replace an earlier version of the process
+        if let Some(name) = header.get_package_name() {
+            let old = self.procs.map_or(None, |procs| {
+                procs
+                    .iter()
+                    .flatten()
+                    .find(|process| process.get_process_name() == name)
+                    .map(|process| process.appid())
+            });
+            if let Some(app) = old {
+                self.unload(app);
+            }
+        }
+
+        let allocation = self
+            .allocations
+            .iter()
+            .find(|allocation| allocation.get().is_none())
+            .ok_or(ProcessLoadError::NoProcessSlot)?;
+        self.procs
+            .map_or(Err(ProcessLoadError::InternalError), |procs| {
+                let index = procs
+                    .iter()
+                    .position(|process| process.is_none())
+                    .ok_or(ProcessLoadError::NoProcessSlot)?;
+                let start = self.memory_used();
+                let (process, unused_memory) = unsafe {
+                    // No loaded process uses the memory after `start`
+                    let memory =
+                        slice::from_raw_parts_mut(self.memory.add(start), self.memory_len - start);
+                    Process::create(
+                        self.kernel,
+                        self.chip,
+                        entry_flash,
+                        header_length,
+                        version,
+                        memory,
+                        self.fault_response,
+                        index,
+                    )?
+                };
+                let process = process.ok_or(ProcessLoadError::NotAnApp)?;
+                allocation.set(Some(Allocation {
+                    index: index,
+                    end: unused_memory.as_ptr() as usize - self.memory as usize,
+                }));
+                procs[index] = Some(process);
+                Ok(process.appid())
+            })
+    }
+
+    fn unload(&self, app: AppId) -> ReturnCode {
+        let index = match app.index() {
+            Some(index) => index,
+            None => return ReturnCode::EINVAL,
+        };
+        self.procs.map_or(ReturnCode::FAIL, |procs| {
+            match procs.get_mut(index) {
+                Some(slot) => {
+                    if let Some(process) = slot.take() {
+                        process.terminate();
+                    }
+                }
+                None => return ReturnCode::EINVAL,
+            }
+            for allocation in self.allocations.iter() {
+                if allocation.get().map_or(false, |a| a.index == index) {
+                    allocation.set(None);
+                }
+            }
+            ReturnCode::SUCCESS
+        })
+    }
+
+    fn restart(&self, app: AppId) -> ReturnCode {
+        self.kernel
+            .process_map_or(ReturnCode::EINVAL, app, |process| {
+                if process.force_restart() {
+                    ReturnCode::SUCCESS
+                } else {
+                    ReturnCode::FAIL
+                }
+            })
+    }
+
+    fn lookup_app(&self, identifier: usize) -> Option<AppId> {
+        self.kernel.lookup_app_by_identifier(identifier)
+    }
+}
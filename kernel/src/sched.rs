@@ -13,17 +13,19 @@ use core::ptr::NonNull;
 
 use crate::callback::{AppId, Callback, CallbackId};
 use crate::capabilities;
-use crate::common::cells::NumericCellExt;
+use crate::common::cells::{NumericCellExt, OptionalCell};
 use crate::common::dynamic_deferred_call::DynamicDeferredCall;
 use crate::config;
 use crate::debug;
 use crate::grant::Grant;
 use crate::ipc;
+use crate::liveness::Liveness;
 use crate::memop;
 use crate::platform::mpu::MPU;
 use crate::platform::scheduler_timer::SchedulerTimer;
 use crate::platform::watchdog::WatchDog;
 use crate::platform::{Chip, Platform};
+use crate::power::{self, PowerManager, SleepState};
 use crate::process::{self, Task};
 use crate::returncode::ReturnCode;
 use crate::syscall::{ContextSwitchReason, Syscall};
@@ -141,6 +143,18 @@ pub struct Kernel {
     /// created and the data structures for grants have already been
     /// established.
     grants_finalized: Cell<bool>,
+
+    /// Constraints on how deeply the chip may sleep when idle. Without a
+    /// power manager the chip may sleep as deeply as it likes.
+    power_manager: OptionalCell<&'static PowerManager<'static>>,
+
+    /// Power the CPU draws while running a process, in microwatts, used to
+    /// charge processes for the time they execute.
+    cpu_active_power_uw: Cell<u32>,
+
+    /// Whether the subsystems are making progress, checked before tickling
+    /// the watchdog. Without it the watchdog is tickled on every loop.
+    liveness: OptionalCell<&'static dyn Liveness>,
 }
 
 /// Enum used to inform scheduler why a process stopped executing (aka why
@@ -176,9 +190,52 @@ impl Kernel {
             process_identifier_max: Cell::new(0),
             grant_counter: Cell::new(0),
             grants_finalized: Cell::new(false),
+            power_manager: OptionalCell::empty(),
+            cpu_active_power_uw: Cell::new(0),
+            liveness: OptionalCell::empty(),
         }
     }
 
+    /// Use `power_manager` to choose the sleep state when the kernel is idle.
+    pub fn set_power_manager(&self, power_manager: &'static PowerManager<'static>) {
+        self.power_manager.set(power_manager);
+    }
+
+    /// The power manager set with `set_power_manager()`, if any.
+    pub fn power_manager(&self) -> Option<&'static PowerManager<'static>> {
+        self.power_manager.map(|power_manager| *power_manager)
+    }
+
+    /// Charge processes `microwatts` for every microsecond they run. Until this
+    /// is set processes are only charged for what drivers report with
+    /// `AppId::charge_energy()`.
+    ///
+    /// CPU time is only measured when the scheduler gives processes a
+    /// timeslice.
+    pub fn set_cpu_active_power(&self, microwatts: u32) {
+        self.cpu_active_power_uw.set(microwatts);
+    }
+
+    /// Only tickle the watchdog while `liveness` reports that every subsystem
+    /// is making progress.
+    pub fn set_liveness(&self, liveness: &'static dyn Liveness) {
+        self.liveness.set(liveness);
+    }
+
+    /// Whether every subsystem is making progress, so the watchdog may be
+    /// tickled.
+    fn is_live(&self) -> bool {
+        self.liveness.map_or(true, |liveness| liveness.is_live())
+    }
+
+    /// The deepest state the chip may sleep in right now.
+    fn sleep_state(&self) -> SleepState {
+        self.power_manager
+            .map_or(SleepState::DeepSleep, |power_manager| {
+                power_manager.deepest_sleep_state()
+            })
+    }
+
     /// Something was scheduled for a process, so there is more work to do.
     ///
     /// This is only exposed in the core kernel crate.
@@ -464,7 +521,10 @@ impl Kernel {
     ) -> ! {
         chip.watchdog().setup();
         loop {
-            chip.watchdog().tickle();
+            let live = self.is_live();
+            if live {
+                chip.watchdog().tickle();
+            }
             unsafe {
                 // Ask the scheduler if we should do tasks inside of the kernel,
                 // such as handle interrupts. A scheduler may want to prioritize
@@ -489,6 +549,12 @@ impl Kernel {
                                         ipc,
                                         timeslice_us,
                                     );
+                                    time_executed.map(|us| {
+                                        process.charge_energy(power::energy_nj(
+                                            self.cpu_active_power_uw.get(),
+                                            us,
+                                        ))
+                                    });
                                     scheduler.result(reason, time_executed);
                                 });
                             }
@@ -507,9 +573,20 @@ impl Kernel {
                                         && !DynamicDeferredCall::global_instance_calls_pending()
                                             .unwrap_or(false)
                                     {
-                                        chip.watchdog().suspend();
-                                        chip.sleep();
-                                        chip.watchdog().resume();
+                                        let state = self.sleep_state();
+                                        if state != SleepState::Active {
+                                            // While a subsystem is stalled the
+                                            // watchdog keeps running, so that
+                                            // it resets the chip even if the
+                                            // kernel mostly sleeps.
+                                            if live {
+                                                chip.watchdog().suspend();
+                                                chip.sleep_in(state);
+                                                chip.watchdog().resume();
+                                            } else {
+                                                chip.sleep_in(state);
+                                            }
+                                        }
                                     }
                                 });
                             }
@@ -619,6 +696,10 @@ impl Kernel {
                     scheduler_timer.disarm();
                     chip.mpu().disable_app_mpu();
 
+                    // Catch a kernel stack overflow as close as possible to
+                    // where it happened.
+                    debug::check_stack_canary();
+
                     // Now the process has returned back to the kernel. Check
                     // why and handle the process as appropriate.
                     match context_switch_reason {
@@ -650,6 +731,25 @@ impl Kernel {
                                 }
                             }
 
+                            // Processes whose TBF header lists the drivers
+                            // they may use cannot reach any other driver. To
+                            // the process a denied driver looks the same as
+                            // one the board does not provide.
+                            if let Some(driver_number) = syscall.driver_number() {
+                                if !process.permits_driver(driver_number) {
+                                    process.debug_syscall_denied();
+                                    if config::CONFIG.trace_syscalls {
+                                        debug!(
+                                            "[{:?}] denied access to driver {:#x}",
+                                            process.appid(),
+                                            driver_number
+                                        );
+                                    }
+                                    process.set_syscall_return_value(ReturnCode::ENODEVICE.into());
+                                    continue;
+                                }
+                            }
+
                             // Handle each of the syscalls.
                             match syscall {
                                 Syscall::MEMOP { operand, arg0 } => {
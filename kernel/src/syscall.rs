@@ -49,6 +49,19 @@ pub enum Syscall {
     MEMOP { operand: usize, arg0: usize },
 }
 
+impl Syscall {
+    /// The driver number this syscall is directed at, if it is one of the
+    /// syscalls that is handled by a driver.
+    pub fn driver_number(&self) -> Option<usize> {
+        match *self {
+            Syscall::SUBSCRIBE { driver_number, .. }
+            | Syscall::COMMAND { driver_number, .. }
+            | Syscall::ALLOW { driver_number, .. } => Some(driver_number),
+            Syscall::YIELD | Syscall::MEMOP { .. } => None,
+        }
+    }
+}
+
 /// Why the process stopped executing and execution returned to the kernel.
 #[derive(PartialEq, Copy, Clone)]
 pub enum ContextSwitchReason {
@@ -117,6 +117,7 @@ pub(crate) enum TbfHeaderTypes {
     TbfHeaderWriteableFlashRegions = 2,
     TbfHeaderPackageName = 3,
     TbfHeaderFixedAddresses = 5,
+    TbfHeaderPermissions = 6,
 
     /// Some field in the header that we do not understand. Since the TLV format
     /// specifies the length of each section, if we get a field we do not
@@ -176,6 +177,30 @@ pub(crate) struct TbfHeaderV2FixedAddresses {
     start_process_flash: u32,
 }
 
+/// The maximum number of drivers a `Permissions` entry can list.
+const MAX_PERMITTED_DRIVERS: usize = 16;
+
+/// Optional list of the drivers this process is allowed to access.
+///
+/// If this header is omitted the process may use every driver the board
+/// exposes. If it is included, any subscribe, command, or allow to a driver
+/// number that is not in the list is rejected by the kernel before it reaches
+/// the driver. An empty list therefore restricts the process to yield and
+/// memop.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct TbfHeaderV2Permissions {
+    length: usize,
+    driver_numbers: [u32; MAX_PERMITTED_DRIVERS],
+}
+
+impl TbfHeaderV2Permissions {
+    fn permits(&self, driver_number: usize) -> bool {
+        self.driver_numbers[..self.length]
+            .iter()
+            .any(|&num| num as usize == driver_number)
+    }
+}
+
 // Conversion functions from slices to the various TBF fields.
 
 impl core::convert::TryFrom<&[u8]> for TbfHeaderV2Base {
@@ -221,6 +246,7 @@ impl core::convert::TryFrom<u16> for TbfHeaderTypes {
             2 => Ok(TbfHeaderTypes::TbfHeaderWriteableFlashRegions),
             3 => Ok(TbfHeaderTypes::TbfHeaderPackageName),
             5 => Ok(TbfHeaderTypes::TbfHeaderFixedAddresses),
+            6 => Ok(TbfHeaderTypes::TbfHeaderPermissions),
             _ => Ok(TbfHeaderTypes::Unknown),
         }
     }
@@ -308,6 +334,28 @@ impl core::convert::TryFrom<&[u8]> for TbfHeaderV2FixedAddresses {
     }
 }
 
+impl core::convert::TryFrom<&[u8]> for TbfHeaderV2Permissions {
+    type Error = TbfParseError;
+
+    fn try_from(b: &[u8]) -> Result<TbfHeaderV2Permissions, Self::Error> {
+        let number_drivers = b.len() / 4;
+        if b.len() % 4 != 0 || number_drivers > MAX_PERMITTED_DRIVERS {
+            return Err(TbfParseError::BadTlvEntry(
+                TbfHeaderTypes::TbfHeaderPermissions as usize,
+            ));
+        }
+
+        let mut permissions = TbfHeaderV2Permissions {
+            length: number_drivers,
+            driver_numbers: [0; MAX_PERMITTED_DRIVERS],
+        };
+        for (i, chunk) in b.chunks_exact(4).enumerate() {
+            permissions.driver_numbers[i] = u32::from_le_bytes(chunk.try_into()?);
+        }
+        Ok(permissions)
+    }
+}
+
 /// Single header that can contain all parts of a v2 header.
 ///
 /// Note, this struct limits the number of writeable regions an app can have to
@@ -320,6 +368,7 @@ pub(crate) struct TbfHeaderV2 {
     package_name: Option<&'static str>,
     writeable_regions: Option<[Option<TbfHeaderV2WriteableFlashRegion>; 4]>,
     fixed_addresses: Option<TbfHeaderV2FixedAddresses>,
+    permissions: Option<TbfHeaderV2Permissions>,
 }
 
 /// Type that represents the fields of the Tock Binary Format header.
@@ -446,6 +495,19 @@ impl TbfHeader {
             start => Some(start),
         }
     }
+
+    /// Return whether this process is allowed to access the driver with
+    /// number `driver_number`. Processes without a permissions entry in their
+    /// header may access all drivers.
+    pub(crate) fn permits_driver(&self, driver_number: usize) -> bool {
+        match self {
+            TbfHeader::TbfHeaderV2(hd) => hd
+                .permissions
+                .as_ref()
+                .map_or(true, |permissions| permissions.permits(driver_number)),
+            _ => false,
+        }
+    }
 }
 
 /// Parse the TBF header length and the entire length of the TBF binary.
@@ -563,6 +625,7 @@ pub(crate) fn parse_tbf_header(
                     Default::default();
                 let mut app_name_str = "";
                 let mut fixed_address_pointer: Option<TbfHeaderV2FixedAddresses> = None;
+                let mut permissions_pointer: Option<TbfHeaderV2Permissions> = None;
 
                 // Iterate the remainder of the header looking for TLV entries.
                 while remaining.len() > 0 {
@@ -643,6 +706,13 @@ pub(crate) fn parse_tbf_header(
                             }
                         }
 
+                        TbfHeaderTypes::TbfHeaderPermissions => {
+                            let permissions_buf = remaining
+                                .get(0..tlv_header.length as usize)
+                                .ok_or(TbfParseError::NotEnoughFlash)?;
+                            permissions_pointer = Some(permissions_buf.try_into()?);
+                        }
+
                         _ => {}
                     }
 
@@ -660,6 +730,7 @@ pub(crate) fn parse_tbf_header(
                     package_name: Some(app_name_str),
                     writeable_regions: Some(wfr_pointer),
                     fixed_addresses: fixed_address_pointer,
+                    permissions: permissions_pointer,
                 };
 
                 Ok(TbfHeader::TbfHeaderV2(tbf_header))